@@ -0,0 +1,169 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use fsync::{
+    audit::{self, Record, Undo},
+    fmt::{human_bytes, Unit},
+    loc::inst,
+    path::PathBuf,
+    Action, DeletionMethod, Operation, Progress, StorageLoc,
+};
+use fsync_client::utils::ctx;
+
+use crate::utils;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    /// Only show the actions since this date (YYYY-MM-DD) or time (RFC 3339)
+    #[clap(long, value_parser = parse_since)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only show the actions on this path and its descendants
+    #[clap(long)]
+    path: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Undo the action recorded in the given entry, if possible
+    Undo {
+        /// Identifier of the entry, as shown by `fsynctl audit`
+        id: u64,
+    },
+}
+
+fn parse_since(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|err| err.to_string())?;
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    Local
+        .from_local_datetime(&midnight)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| format!("invalid local date: {s}"))
+}
+
+pub async fn main(args: Args) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let records = audit::read_records(&inst::audit_log_file(&instance_name)?)?;
+
+    match args.command {
+        Some(Command::Undo { id }) => undo(&instance_name, &records, id).await,
+        None => {
+            let records = records.iter().filter(|record| {
+                args.since.is_none_or(|since| record.time >= since)
+                    && args.path.as_ref().is_none_or(|path| {
+                        path == &record.path || path.is_ancestor_of(&record.path)
+                    })
+            });
+            for record in records {
+                print_record(record);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn print_record(record: &Record) {
+    let time = record
+        .time
+        .with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S");
+    let bytes = if record.bytes > 0 {
+        format!(" ({})", human_bytes(record.bytes as _, Unit::Binary))
+    } else {
+        String::new()
+    };
+    println!(
+        "{id:>6}  {time}  {action} {path}{bytes}",
+        id = record.id,
+        action = action_desc(&record.action),
+        path = record.path,
+    );
+    if let Undo::Unavailable(reason) = &record.undo {
+        println!("        cannot be undone: {reason}");
+    }
+}
+
+fn action_desc(action: &Action) -> String {
+    match action {
+        Action::Mkdir(loc) => format!("created folder on the {loc}:"),
+        Action::Copy(dir) => format!("copied from {dir}:"),
+        Action::Replace(dir) => format!("replaced from {dir}:"),
+        Action::CopyLocalAndReplace => "kept a local copy and replaced from remote drive:".into(),
+        Action::MergeText => "merged the local and remote edits:".into(),
+        Action::Delete(loc) => format!("deleted from {loc}:"),
+        Action::Fail(err) => format!("failed ({err}):"),
+        Action::SkipTooLarge | Action::SkipWithheld => "skipped:".into(),
+        Action::Forget => "removed from the tree, deleted on both sides:".into(),
+        Action::SharePublic => "shared with anyone with the link:".into(),
+        Action::Pair => "paired, same content on both sides:".into(),
+    }
+}
+
+async fn undo(instance_name: &str, records: &[Record], id: u64) -> anyhow::Result<()> {
+    let Some(record) = records.iter().find(|record| record.id == id) else {
+        anyhow::bail!("No audit entry {id}, it may have been rotated out of the log");
+    };
+    let loc = match &record.undo {
+        Undo::DeleteCopy(loc) => *loc,
+        Undo::Unavailable(reason) => anyhow::bail!("Entry {id} cannot be undone: {reason}"),
+    };
+    let path = record.path.clone();
+
+    let client = utils::instance_client(instance_name).await?;
+    // a single child tells whether the folder is empty
+    let Some(node) = client
+        .entry_node_page(ctx(), path.clone(), None, 1)
+        .await??
+    else {
+        anyhow::bail!("Entry {id} cannot be undone: {path} is not in the tree anymore");
+    };
+    if !node.entry().is_sync() {
+        anyhow::bail!("Entry {id} cannot be undone: {path} is not on both drives anymore");
+    }
+    let copy = node
+        .entry()
+        .clone()
+        .into_metadata(loc)
+        .expect("sync entry should be on both drives");
+    if copy.is_dir() && !node.children().is_empty() {
+        anyhow::bail!("Entry {id} cannot be undone: the folder {path} is not empty anymore");
+    }
+    if copy.is_file() && copy.size() != Some(record.bytes) {
+        anyhow::bail!("Entry {id} cannot be undone: {path} was modified since");
+    }
+
+    let method = match loc {
+        StorageLoc::Local => DeletionMethod::LocalIfSyncNoConflict,
+        StorageLoc::Remote => DeletionMethod::RemoteIfSyncNoConflict,
+    };
+    let mut progress = client
+        .operate(ctx(), Operation::Delete(path.clone(), method))
+        .await??;
+    loop {
+        match &progress {
+            Progress::Done | Progress::DoneWithReport(..) => break,
+            Progress::Err(err) => anyhow::bail!(err.clone()),
+            Progress::Failed(failure) => anyhow::bail!("{failure}"),
+            _ => (),
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        match client.progress(ctx(), path.clone()).await?? {
+            Some(p) => progress = p,
+            None => break,
+        }
+    }
+    println!("{path} deleted from the {loc}");
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use fsync::{loc::inst, path::FsPath};
+use fsync_client::utils::ctx;
+use serde::Serialize;
+
+use crate::utils::{self, Format};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Print the disk usage of the caches
+    Usage,
+    /// Clear the caches. Without option, all of them are cleared.
+    Clear {
+        /// Clear the cached content of the remote files
+        #[clap(long)]
+        content: bool,
+        /// Clear the metadata of the remote storage (the daemon must be stopped)
+        #[clap(long)]
+        metadata: bool,
+    },
+}
+
+/// The usage printed in JSON, with the lookups in the content cache
+#[derive(Serialize)]
+struct Usage {
+    #[serde(flatten)]
+    usage: fsync::CacheUsage,
+    #[serde(flatten)]
+    lookups: Option<fsync::CacheLookups>,
+}
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    match args.command {
+        Command::Usage => {
+            let client = utils::instance_client(&instance_name).await?;
+            let stats = client.instance_stats(ctx()).await??;
+            let Some(usage) = stats.cache else {
+                anyhow::bail!("The {instance_name} instance does not report its cache usage");
+            };
+            let lookups = client.cache_lookups(ctx()).await??;
+            if format == Format::Json {
+                return utils::print_json(&Usage { usage, lookups });
+            }
+            println!("metadata:   {:>12} bytes", usage.metadata);
+            println!("content:    {:>12} bytes", usage.content);
+            println!("quarantine: {:>12} bytes", usage.quarantine);
+            println!("temporary:  {:>12} bytes", usage.temp);
+            println!("total:      {:>12} bytes", usage.total());
+            if let Some(budget) = usage.budget {
+                println!("budget:     {budget:>12} bytes");
+            }
+            if let Some(lookups) = lookups {
+                println!("hits:       {:>12}", lookups.hits);
+                println!("misses:     {:>12}", lookups.misses);
+            }
+        }
+        Command::Clear { content, metadata } => {
+            let (content, metadata) = if content || metadata {
+                (content, metadata)
+            } else {
+                (true, true)
+            };
+            let freed = if inst::runtime_port_file(&instance_name)?.exists() {
+                let client = utils::instance_client(&instance_name).await?;
+                client.clear_cache(ctx(), content, metadata).await??
+            } else {
+                clear_stopped(&instance_name, content, metadata)?
+            };
+            if format == Format::Json {
+                return utils::print_json(&serde_json::json!({ "freed": freed }));
+            }
+            println!("{freed} bytes freed");
+        }
+    }
+    Ok(())
+}
+
+/// Clear the caches of an instance that is not running, by deleting the files directly
+fn clear_stopped(instance_name: &str, content: bool, metadata: bool) -> anyhow::Result<u64> {
+    let mut freed = 0;
+    if content {
+        for dir in [
+            inst::content_cache_dir(instance_name)?,
+            inst::temp_dir(instance_name)?,
+        ] {
+            if dir.exists() {
+                freed += dir_size(&dir)?;
+                std::fs::remove_dir_all(&dir)?;
+            }
+        }
+    }
+    if metadata {
+        let file = inst::remote_cache_file(instance_name)?;
+        if file.exists() {
+            freed += std::fs::metadata(&file)?.len();
+            std::fs::remove_file(&file)?;
+        }
+    }
+    Ok(freed)
+}
+
+fn dir_size(dir: &FsPath) -> anyhow::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let md = entry.metadata()?;
+        if md.is_dir() {
+            let path = fsync::path::FsPathBuf::try_from(entry.path())?;
+            size += dir_size(&path)?;
+        } else {
+            size += md.len();
+        }
+    }
+    Ok(size)
+}
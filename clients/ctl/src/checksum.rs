@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use fsync::{path::PathBuf, HashAlgo};
+use fsync_client::utils::ctx_with_timeout;
+use serde::Serialize;
+
+use crate::utils::{self, Format};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    /// Hash algorithm
+    #[clap(long, short = 'a', value_enum, default_value_t = Algo::Sha256)]
+    algo: Algo,
+
+    /// Paths of the files, in the tree
+    #[clap(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Algo {
+    /// MD5, as reported by Google Drive
+    Md5,
+    /// SHA-256
+    Sha256,
+}
+
+impl From<Algo> for HashAlgo {
+    fn from(value: Algo) -> Self {
+        match value {
+            Algo::Md5 => HashAlgo::Md5,
+            Algo::Sha256 => HashAlgo::Sha256,
+        }
+    }
+}
+
+/// Time given to the daemon to read a file, instead of the timeout of the other requests
+const TIMEOUT: Duration = Duration::from_secs(3600);
+
+#[derive(Serialize)]
+struct Line {
+    path: PathBuf,
+    #[serde(flatten)]
+    checksum: fsync::Checksum,
+}
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let client = utils::instance_client(&instance_name).await?;
+    let mut lines = Vec::with_capacity(args.paths.len());
+    for path in args.paths {
+        let checksum = client
+            .checksum(ctx_with_timeout(TIMEOUT), path.clone(), args.algo.into())
+            .await??;
+        if format == Format::Text {
+            // same layout as md5sum and sha256sum
+            println!("{checksum}  {path}");
+        } else {
+            lines.push(Line { path, checksum });
+        }
+    }
+    if format == Format::Json {
+        utils::print_json(&lines)?;
+    }
+    Ok(())
+}
@@ -1,61 +1,186 @@
-use fsync::Conflict;
-use tarpc::context;
+use fsync::{filter::EntryFilter, path::PathBuf, Conflict};
+use fsync_client::utils::ctx;
 
-use crate::utils;
+use crate::{
+    exit,
+    utils::{self, Format},
+};
 
 #[derive(clap::Args, Debug)]
 pub struct Args {
     /// Name of the fsyncd instance
     #[clap(long, short = 'n')]
     instance_name: Option<String>,
+
+    /// List the files skipped for being larger than the size limit instead of the conflicts
+    #[clap(long)]
+    too_large: bool,
+
+    /// List the entries that anyone with the link can access instead of the conflicts
+    #[clap(long, conflicts_with = "too_large")]
+    shared_publicly: bool,
+
+    /// Only list the publicly shared entries under this path
+    #[clap(long, requires = "shared_publicly")]
+    path: Option<PathBuf>,
+
+    /// Check the listed conflicts again in the storages, to leave out the ones resolved outside of fsync
+    #[clap(long, conflicts_with_all = ["too_large", "shared_publicly"])]
+    revalidate: bool,
+
+    /// Only list the conflicts under this path, to which the --filter globs are anchored
+    #[clap(long, conflicts_with_all = ["too_large", "shared_publicly"])]
+    under: Option<PathBuf>,
+
+    /// Only list the conflicts matching this glob, anchored to the --under path
+    /// (e.g. '*.docx' or 'reports/'). Can be repeated, the last matching glob decides
+    /// and a glob starting with '!' leaves its matches out.
+    #[clap(long = "filter", value_name = "GLOB", conflicts_with_all = ["too_large", "shared_publicly"])]
+    filters: Vec<String>,
+
+    /// Only list the conflicts of this kind. Can be repeated.
+    #[clap(long = "conflict-type", value_enum, value_name = "KIND", conflicts_with_all = ["too_large", "shared_publicly"])]
+    conflict_types: Vec<ConflictType>,
 }
 
-fn ctx() -> context::Context {
-    context::current()
+/// The kinds of conflict selected by `--conflict-type`
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ConflictType {
+    /// The local file is more recent
+    LocalNewer,
+    /// The remote file is more recent
+    LocalOlder,
+    /// The local file is bigger, with the same modification time
+    LocalBigger,
+    /// The local file is smaller, with the same modification time
+    LocalSmaller,
+    /// A local file where the remote has a directory
+    LocalFileRemoteDir,
+    /// A local directory where the remote has a file
+    LocalDirRemoteFile,
+    /// Same size and modification time, different content
+    ContentMismatch,
 }
 
-pub async fn main(args: Args) -> anyhow::Result<()> {
-    let instance_name = match &args.instance_name {
-        Some(name) => name.clone(),
-        None => {
-            let name = utils::single_instance_name()?;
-            if let Some(name) = name {
-                name
-            } else {
-                anyhow::bail!("Could not find a single share, please specify --share-name command line argument");
-            }
+impl From<ConflictType> for Conflict {
+    fn from(value: ConflictType) -> Self {
+        match value {
+            ConflictType::LocalNewer => Conflict::LocalNewer,
+            ConflictType::LocalOlder => Conflict::LocalOlder,
+            ConflictType::LocalBigger => Conflict::LocalBigger,
+            ConflictType::LocalSmaller => Conflict::LocalSmaller,
+            ConflictType::LocalFileRemoteDir => Conflict::LocalFileRemoteDir,
+            ConflictType::LocalDirRemoteFile => Conflict::LocalDirRemoteFile,
+            ConflictType::ContentMismatch => Conflict::ContentMismatch,
         }
-    };
+    }
+}
+
+/// The filter of the `--filter` and `--conflict-type` options, with their globs anchored to `under`
+pub fn entry_filter(
+    under: Option<PathBuf>,
+    filters: Vec<String>,
+    conflict_types: &[ConflictType],
+) -> EntryFilter {
+    EntryFilter {
+        under,
+        patterns: filters,
+        conflicts: conflict_types.iter().map(|&c| c.into()).collect(),
+    }
+}
+
+/// Maximum number of conflicts listed
+const MAX_CONFLICTS: usize = 100;
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
 
     let client = utils::instance_client(&instance_name).await?;
 
-    let conflicts = client.conflicts(ctx(), None, 100).await.unwrap()?;
-
-    println!("{} conflicts found!", conflicts.len());
-
-    for entry in conflicts {
-        let path = entry.path();
-        let c = entry.conflict().unwrap();
-        match c {
-            Conflict::LocalBigger => {
-                println!("C {path} local is bigger (but same mtime)");
-            }
-            Conflict::LocalSmaller => {
-                println!("C {path} local is smaller (but same mtime)");
-            }
-            Conflict::LocalNewer => {
-                println!("C {path} local is newer");
-            }
-            Conflict::LocalOlder => {
-                println!("C {path} local is older");
-            }
-            Conflict::LocalFileRemoteDir => {
-                println!("C {path} local is file, remote is dir");
-            }
-            Conflict::LocalDirRemoteFile => {
-                println!("C {path} local is dir, remote is file");
-            }
+    if args.too_large {
+        let entries = client.too_large(ctx(), None, 100).await??;
+        if format == Format::Json {
+            return utils::print_json(&entries);
+        }
+        println!("{} files larger than the size limit found!", entries.len());
+        for entry in entries {
+            println!("S {} {} bytes", entry.path(), entry.size());
+        }
+        return Ok(());
+    }
+
+    if args.shared_publicly {
+        let path = args.path.unwrap_or_else(PathBuf::root);
+        let entries = client.publicly_shared(ctx(), path, 100).await??;
+        if format == Format::Json {
+            return utils::print_json(&entries);
+        }
+        let paths = entries.iter().map(|e| e.path().to_owned()).collect();
+        let sharing = client.sharing(ctx(), paths).await??;
+        println!("{} publicly shared entries found!", entries.len());
+        for (entry, sharing) in entries.iter().zip(sharing) {
+            let sharing = sharing.unwrap_or_default();
+            println!("P {} {sharing}", entry.path());
+        }
+        return Ok(());
+    }
+
+    let under = args.under.clone().unwrap_or_else(PathBuf::root);
+    let filter = entry_filter(args.under, args.filters, &args.conflict_types);
+    let mut conflicts = if filter.is_empty() {
+        client.conflicts(ctx(), None, MAX_CONFLICTS as _).await??
+    } else {
+        filtered_conflicts(&client, filter).await?
+    };
+    let mut resolved = Vec::new();
+    if args.revalidate {
+        let paths = conflicts.iter().map(|c| c.path().to_owned()).collect();
+        resolved = client.revalidate_conflicts(ctx(), paths).await??;
+        conflicts.retain(|c| !resolved.iter().any(|path| path == c.path()));
+    }
+    if format == Format::Json {
+        utils::print_json(&conflicts)?;
+    } else {
+        for path in &resolved {
+            println!("R {path} resolved outside of fsync");
+        }
+        println!("{} conflicts found!", conflicts.len());
+
+        for entry in &conflicts {
+            let path = entry.path();
+            let c = entry.conflict().unwrap();
+            println!("C {path} {}", c.summary());
         }
     }
+    // the scripts tell from the exit code whether there are conflicts
+    if !conflicts.is_empty() {
+        return Err(exit::conflicts(conflicts.len(), under));
+    }
     Ok(())
 }
+
+/// The conflicts selected by `filter`, read a page at a time from the daemon
+async fn filtered_conflicts(
+    client: &utils::Client,
+    filter: EntryFilter,
+) -> anyhow::Result<Vec<fsync::tree::Entry>> {
+    let mut conflicts: Vec<fsync::tree::Entry> = Vec::new();
+    while conflicts.len() < MAX_CONFLICTS {
+        // each page starts at the last conflict of the previous one, included
+        let start = conflicts.last().map(|c| c.path().to_owned());
+        let max_len = MAX_CONFLICTS - conflicts.len() + start.is_some() as usize;
+        let page = client
+            .conflicts_filtered(ctx(), start.clone(), max_len as _, filter.clone())
+            .await??;
+        let page_len = page.len();
+        let new = page
+            .into_iter()
+            .skip_while(|c| Some(c.path()) == start.as_deref());
+        let len = conflicts.len();
+        conflicts.extend(new);
+        if page_len < max_len || conflicts.len() == len {
+            break;
+        }
+    }
+    Ok(conflicts)
+}
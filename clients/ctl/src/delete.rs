@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use fsync::{
+    path::{Path, PathBuf},
+    DeletionMethod, OperateOptions, Operation, Progress,
+};
+use fsync_client::utils::ctx;
+
+use crate::{
+    exit,
+    utils::{self, Client},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    /// Storage from which the entries are deleted
+    #[clap(long, value_enum)]
+    from: From,
+
+    /// Only delete the entries that are synchronized, so that a copy is left in the other storage
+    #[clap(long)]
+    if_sync: bool,
+
+    /// With --if-sync, also keep the entries in conflict
+    #[clap(long, requires = "if_sync")]
+    keep_conflicts: bool,
+
+    /// Confirm the deletion of a large sub-tree without asking
+    #[clap(long, short = 'y')]
+    yes: bool,
+
+    /// Path to the entry to delete
+    path: PathBuf,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum From {
+    /// Delete the local entries
+    Local,
+    /// Delete the remote entries
+    Remote,
+    /// Delete the entries from both storages, losing their content
+    Both,
+}
+
+impl Args {
+    fn method(&self) -> DeletionMethod {
+        match (self.from, self.if_sync, self.keep_conflicts) {
+            (From::Local, false, _) => DeletionMethod::Local,
+            (From::Local, true, false) => DeletionMethod::LocalIfSync,
+            (From::Local, true, true) => DeletionMethod::LocalIfSyncNoConflict,
+            (From::Remote, false, _) => DeletionMethod::Remote,
+            (From::Remote, true, false) => DeletionMethod::RemoteIfSync,
+            (From::Remote, true, true) => DeletionMethod::RemoteIfSyncNoConflict,
+            (From::Both, ..) => DeletionMethod::All,
+        }
+    }
+}
+
+pub async fn main(args: Args) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    if args.from == From::Both && args.if_sync {
+        anyhow::bail!("--if-sync requires to keep the entries in one of the storages");
+    }
+
+    let client = utils::instance_client(&instance_name).await?;
+    delete_deep(&client, &args.path, args.method(), args.yes).await?;
+    println!("{} deleted", args.path);
+    Ok(())
+}
+
+/// Delete the sub-tree at `path` with `method`, and wait for the deletion to complete.
+/// The deletion of a large sub-tree is confirmed on the terminal, unless `yes` is set.
+pub async fn delete_deep(
+    client: &Client,
+    path: &Path,
+    method: DeletionMethod,
+    yes: bool,
+) -> anyhow::Result<()> {
+    let path = path.to_owned();
+    let operation = Operation::DeleteDeep(path.clone(), method);
+
+    // the daemon asks to confirm the deletion of the large sub-trees
+    let mut options = OperateOptions::default();
+    let mut progress = loop {
+        match client
+            .operate_with(ctx(), operation.clone(), options.clone())
+            .await?
+        {
+            Ok(progress) => break progress,
+            Err(fsync::Error::ConfirmationRequired(token, summary))
+                if options.confirmation.is_none() =>
+            {
+                if !yes && !utils::ask(&format!("{summary}. Proceed?"))? {
+                    return Err(exit::aborted());
+                }
+                options.confirmation = Some(token);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    };
+    loop {
+        match &progress {
+            Progress::Done | Progress::DoneWithReport(..) => break,
+            Progress::Err(err) => anyhow::bail!(err.clone()),
+            Progress::Failed(failure) => anyhow::bail!("{failure}"),
+            _ => (),
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        match client.progress(ctx(), path.clone()).await?? {
+            Some(p) => progress = p,
+            None => break,
+        }
+    }
+    utils::check_failures(client, &path, progress.report()).await
+}
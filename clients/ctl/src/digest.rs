@@ -0,0 +1,40 @@
+use fsync_client::utils::ctx;
+
+use crate::utils;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Deliver the digest now, through the channels of the config
+    SendNow {
+        /// Skip the digest if nothing changed since the previous one, as the scheduled digests
+        #[clap(long)]
+        if_changed: bool,
+    },
+}
+
+pub async fn main(args: Args) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    match args.command {
+        Command::SendNow { if_changed } => {
+            let client = utils::instance_client(&instance_name).await?;
+            let sent = client.send_digest(ctx(), !if_changed).await??;
+            if sent {
+                println!("Digest delivered");
+            } else {
+                println!("Nothing new since the previous digest");
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use fsync::{
+    fmt::{human_bytes, Unit},
+    loc::inst,
+    overlap,
+};
+use fsync_client::{config, utils::ctx};
+use inquire::Confirm;
+
+use crate::utils;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    /// Remove from the tree the entries that were deleted on both sides,
+    /// or delete the instance if its creation failed
+    #[clap(long)]
+    fix: bool,
+
+    /// Compare the tree of fsyncd with fresh listings of the storages
+    #[clap(long)]
+    check_tree: bool,
+
+    /// Fail if the remote root overlaps with the one of another instance
+    #[clap(long)]
+    strict: bool,
+}
+
+pub async fn main(args: Args) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    if config::partial_instances()?.contains(&instance_name) {
+        return delete_partial(&instance_name, args.fix).await;
+    }
+
+    let config = fsync::Config::load_from_file(&inst::config_file(&instance_name)?).await?;
+    println!("secrets protection: {}", config.secrets);
+    if config.encryption {
+        println!("content encryption: enabled (names and folders in clear)");
+        println!("  the remote contents can't be recovered if the passphrase is lost");
+    }
+
+    match overlap::recorded_root(&instance_name)? {
+        Some(root) => {
+            println!("remote root: {root}");
+            let overlaps = overlap::overlaps(&instance_name, &root)?;
+            for overlap in &overlaps {
+                println!("  overlaps: {overlap}");
+            }
+            if args.strict && !overlaps.is_empty() {
+                anyhow::bail!("The remote root of {instance_name} overlaps with other instances");
+            }
+        }
+        None => println!("remote root: not resolved yet"),
+    }
+
+    let client = utils::instance_client(&instance_name).await?;
+
+    let stats = client.instance_stats(ctx()).await??;
+    if let Some(percent) = stats.quota.and_then(|quota| quota.percent()) {
+        let warn = if stats.quota_warning {
+            " (nearly full)"
+        } else {
+            ""
+        };
+        println!("remote quota: {percent:.0}% used{warn}");
+    }
+
+    let status = client.status(ctx()).await??;
+    match status.fs_caps {
+        Some(caps) => {
+            let yes_no = |b: bool| if b { "yes" } else { "no" };
+            println!("local filesystem:");
+            println!("  case sensitive: {}", yes_no(caps.case_sensitive));
+            println!(
+                "  unicode normalization sensitive: {}",
+                yes_no(caps.normalization_sensitive)
+            );
+            println!(
+                "  unicode normalization preserving: {}",
+                yes_no(caps.normalization_preserving)
+            );
+            println!("  maximum name length: {} bytes", caps.max_name_len);
+            println!(
+                "  trailing dots and spaces: {}",
+                yes_no(caps.trailing_dots_spaces)
+            );
+        }
+        None => println!("local filesystem: capabilities unknown"),
+    }
+
+    match status.policy.on_battery {
+        Some(true) => println!("power: on battery"),
+        Some(false) => println!("power: on AC"),
+        None => println!("power: unknown"),
+    }
+    if let Some(reason) = &status.policy.deferred {
+        println!("scheduled work postponed: {reason}");
+    }
+    if let Some(resumed) = &status.resumed {
+        let resyncing = if resumed.resyncing { ", resyncing" } else { "" };
+        println!(
+            "resumed from {}s of sleep on {}{resyncing}",
+            resumed.asleep_secs,
+            resumed
+                .at
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M"),
+        );
+    }
+    if let Some(checkpoint) = &status.checkpoint {
+        println!(
+            "{} stopped by its time budget on {}: {} entries ({}) left, resume with fsynctl sync --resume",
+            checkpoint.operation.path(),
+            checkpoint.created.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"),
+            checkpoint.remaining,
+            human_bytes(checkpoint.remaining_bytes, Unit::Binary),
+        );
+    }
+
+    for corrupt in &status.corrupt_files {
+        println!(
+            "corrupt {} set aside at startup: {}\n  moved to {}",
+            corrupt.kind, corrupt.error, corrupt.moved_to
+        );
+    }
+
+    let conflicts = client.conflicts(ctx(), None, 100).await??;
+    println!("{} conflicts", conflicts.len());
+
+    if args.check_tree {
+        let discrepancies = fsync_client::utils::self_check(&client).await?;
+        for discrepancy in &discrepancies {
+            println!("X {discrepancy}");
+        }
+        println!("{} discrepancies in the tree", discrepancies.len());
+    }
+
+    if !args.fix {
+        println!("Run with --fix to remove the entries deleted on both sides from the tree");
+        return Ok(());
+    }
+
+    let removed = client.gc_tree(ctx()).await??;
+    for path in &removed {
+        println!("D {path}");
+    }
+    println!("{} entries removed from the tree", removed.len());
+    Ok(())
+}
+
+/// Offer to delete the partial instance `instance_name`, or delete it straight away with `fix`
+async fn delete_partial(instance_name: &str, fix: bool) -> anyhow::Result<()> {
+    let config_dir = inst::config_dir(instance_name)?;
+    println!("{config_dir} has no configuration file, its creation failed");
+    let delete = fix
+        || Confirm::new(&format!("Delete {config_dir}?"))
+            .with_default(false)
+            .prompt()?;
+    if delete {
+        config::delete_partial(instance_name).await?;
+        println!("{instance_name} deleted");
+    }
+    Ok(())
+}
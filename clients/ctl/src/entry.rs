@@ -1,9 +1,12 @@
-use std::net::{IpAddr, Ipv6Addr};
+use chrono::{DateTime, Utc};
+use fsync::{
+    fmt::{human_bytes, human_mtime, Unit},
+    path::PathBuf,
+    tree,
+};
+use fsync_client::utils::ctx;
 
-use fsync::{path::PathBuf, tree, Conflict, FsyncClient};
-use tarpc::{client, context, tokio_serde::formats::Bincode};
-
-use crate::utils;
+use crate::utils::{self, Format};
 
 #[derive(clap::Args)]
 pub struct Args {
@@ -15,45 +18,33 @@ pub struct Args {
     path: Option<PathBuf>,
 }
 
-pub async fn main(args: Args) -> anyhow::Result<()> {
-    let instance_name = match args.instance_name {
-        Some(name) => name,
-        None => {
-            let name = utils::single_instance_name()?;
-            if let Some(name) = name {
-                name
-            } else {
-                anyhow::bail!("Could not find a single share, please specify --share-name command line argument");
-            }
-        }
-    };
-
-    let port = utils::instance_port(&instance_name)?;
-
-    let addr = (IpAddr::V6(Ipv6Addr::LOCALHOST), port);
-    let mut transport = tarpc::serde_transport::tcp::connect(addr, Bincode::default);
-    transport.config_mut().max_frame_length(usize::MAX);
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
 
-    let client = FsyncClient::new(client::Config::default(), transport.await?).spawn();
+    let client = utils::instance_client(&instance_name).await?;
     let path = args.path.unwrap_or_else(PathBuf::root);
-    let entry = client
-        .entry_node(context::current(), path.clone())
-        .await
-        .unwrap()
-        .unwrap();
+    let entry = client.entry_node(ctx(), path.clone()).await??;
 
-    if entry.is_none() {
-        println!("No such entry: {path}");
+    if format == Format::Json {
+        utils::print_json(&entry)?;
+    }
+    let Some(entry) = entry else {
+        return Err(fsync::Error::Path(fsync::PathError::NotFound(path, None)).into());
+    };
+    if format == Format::Json {
         return Ok(());
     }
-    let entry = entry.unwrap();
+
+    let now = Utc::now();
 
     match entry.entry() {
         tree::Entry::Local(entry) => {
             println!("L {}", entry.path());
+            print_metadata("local", entry, now);
         }
         tree::Entry::Remote(entry) => {
             println!("R {}", entry.path());
+            print_metadata("remote", entry, now);
         }
         tree::Entry::Sync {
             local,
@@ -63,26 +54,57 @@ pub async fn main(args: Args) -> anyhow::Result<()> {
             assert_eq!(local.path(), remote.path());
             let path = local.path();
             match conflict {
-                None => {
-                    println!("S {path}")
-                }
-                Some(Conflict::LocalBigger) => {
-                    println!("C {path:<40} local is bigger than remote")
-                }
-                Some(Conflict::LocalSmaller) => {
-                    println!("C {path:<40} local is smaller than remote")
-                }
-                Some(Conflict::LocalNewer) => println!("C {path:<40} local is newer than remote"),
-                Some(Conflict::LocalOlder) => println!("C {path:<40} local is older than remote"),
-                Some(Conflict::LocalDirRemoteFile) => {
-                    println!("C {path:<40} local is a directory and remote a file")
-                }
-                Some(Conflict::LocalFileRemoteDir) => {
-                    println!("C {path:<40} local is a file and remote a directory")
+                None => println!("S {path}"),
+                Some(conflict) => {
+                    println!("C {path:<40} {}", conflict.summary());
+                    println!("  {}", conflict.explanation(local, remote));
                 }
             }
+            print_metadata("local", local, now);
+            print_metadata("remote", remote, now);
+        }
+    }
+
+    if !entry.entry().is_local_only() {
+        let sharing = client
+            .sharing(ctx(), vec![path.clone()])
+            .await
+            .unwrap()?
+            .pop()
+            .flatten();
+        if let Some(sharing) = sharing {
+            println!("  {:<8} {sharing}", "");
+        }
+        let view_only = client
+            .view_only(ctx(), vec![path])
+            .await
+            .unwrap()?
+            .pop()
+            .flatten();
+        if let Some(view_only) = view_only {
+            match view_only.web_view_link {
+                Some(link) => println!("  {:<8} view only, open at {link}", ""),
+                None => println!("  {:<8} view only", ""),
+            }
         }
     }
 
     Ok(())
 }
+
+fn print_metadata(loc: &str, metadata: &fsync::Metadata, now: DateTime<Utc>) {
+    let size = metadata
+        .stat()
+        .map(|stat| human_bytes(stat.data.max(0) as u64, Unit::Binary))
+        .unwrap_or_default();
+    let mtime = metadata
+        .mtime()
+        .map(|mtime| human_mtime(mtime, now))
+        .unwrap_or_default();
+    let implausible = if metadata.is_implausible() {
+        "  (implausible metadata)"
+    } else {
+        ""
+    };
+    println!("  {loc:<8} {size:>10}  {mtime}{implausible}");
+}
@@ -0,0 +1,321 @@
+//! The exit codes of fsynctl, and the report of its errors on the standard error.
+//!
+//! The scripts wrapping fsynctl rely on the codes, that must stay stable:
+//!
+//! | code | kind             | meaning                                                    |
+//! |------|------------------|------------------------------------------------------------|
+//! | 0    |                  | success                                                    |
+//! | 1    | `failure`        | any other failure                                          |
+//! | 2    | `usage`          | invalid arguments, or no instance to operate on            |
+//! | 3    | `conflicts`      | conflicts were listed, or left by the operation            |
+//! | 4    | `unreachable`    | the daemon is not running, or did not answer               |
+//! | 5    | `authRequired`   | the access to the remote storage must be authorized again  |
+//! | 6    | `partialFailure` | the operation completed, but some entries failed           |
+//! | 7    | `notFound`       | the entry does not exist                                   |
+//! | 8    | `refused`        | the daemon refused the operation, e.g. pinned entries      |
+//! | 9    | `unavailable`    | the remote storage is unavailable, try again later         |
+//!
+//! All the errors of the commands are mapped by [`report`], from the [`Failure`]s returned by
+//! the commands, the [`fsync::Error`]s of the daemon and the failures to reach it.
+
+use std::{fmt, process::ExitCode};
+
+use fsync::path::PathBuf;
+use fsync_client::ConnectError;
+use serde::Serialize;
+
+/// Format of the errors printed on the standard error
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// The message of the error
+    #[default]
+    Text,
+    /// A JSON object on a single line, with the exit code, the kind of error, the path,
+    /// the message and a hint
+    Json,
+}
+
+impl ErrorFormat {
+    /// The format given on the command line, for the errors reported before the arguments are parsed
+    pub fn from_args() -> Self {
+        let mut args = std::env::args_os().skip(1);
+        while let Some(arg) = args.next() {
+            let value = match arg.to_str() {
+                Some("--error-format") => args.next(),
+                Some(arg) => match arg.strip_prefix("--error-format=") {
+                    Some(value) => Some(value.into()),
+                    None => continue,
+                },
+                None => continue,
+            };
+            if value.as_deref().and_then(|v| v.to_str()) == Some("json") {
+                return Self::Json;
+            }
+        }
+        Self::Text
+    }
+}
+
+/// The kind of an error, that determines the exit code
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Kind {
+    Failure,
+    Usage,
+    Conflicts,
+    Unreachable,
+    AuthRequired,
+    PartialFailure,
+    NotFound,
+    Refused,
+    Unavailable,
+}
+
+impl Kind {
+    pub fn code(self) -> u8 {
+        match self {
+            Self::Failure => 1,
+            Self::Usage => 2,
+            Self::Conflicts => 3,
+            Self::Unreachable => 4,
+            Self::AuthRequired => 5,
+            Self::PartialFailure => 6,
+            Self::NotFound => 7,
+            Self::Refused => 8,
+            Self::Unavailable => 9,
+        }
+    }
+}
+
+/// An error of a command, with its kind
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub kind: Kind,
+    pub message: String,
+    pub path: Option<PathBuf>,
+    pub hint: Option<String>,
+}
+
+impl Failure {
+    pub fn new(kind: Kind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            path: None,
+            hint: None,
+        }
+    }
+
+    pub fn with_path(self, path: PathBuf) -> Self {
+        Self {
+            path: Some(path),
+            ..self
+        }
+    }
+
+    pub fn with_hint(self, hint: impl Into<String>) -> Self {
+        Self {
+            hint: Some(hint.into()),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// The error object printed with `--error-format json`
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    code: u8,
+    kind: Kind,
+    path: Option<&'a PathBuf>,
+    message: String,
+    hint: Option<&'a str>,
+}
+
+/// Map `err` to its failure, looking for the known errors in its chain
+pub fn classify(err: &anyhow::Error) -> Failure {
+    let message = err.to_string();
+    for cause in err.chain() {
+        if let Some(failure) = cause.downcast_ref::<Failure>() {
+            return Failure {
+                message,
+                ..failure.clone()
+            };
+        }
+        if let Some(err) = cause.downcast_ref::<fsync::Error>() {
+            return classify_fsync(err, message);
+        }
+        if let Some(err) = cause.downcast_ref::<ConnectError>() {
+            return match err {
+                ConnectError::NoSuchInstance(..) => Failure::new(Kind::Usage, message)
+                    .with_hint("list the instances with `fsynctl list`"),
+                ConnectError::NotRunning(name) => {
+                    let config_dir = match fsync::loc::user::root() {
+                        Ok(Some(root)) => format!("--config-dir {root} "),
+                        _ => String::new(),
+                    };
+                    Failure::new(Kind::Unreachable, message).with_hint(format!(
+                        "start the daemon, e.g. with `fsyncd {config_dir}{name}`"
+                    ))
+                }
+            };
+        }
+        if let Some(err) = cause.downcast_ref::<tarpc::client::RpcError>() {
+            let hint = match err {
+                tarpc::client::RpcError::DeadlineExceeded => {
+                    "the daemon is busy, try again with a longer --timeout"
+                }
+                _ => "check that the daemon is still running",
+            };
+            return Failure::new(Kind::Unreachable, message).with_hint(hint);
+        }
+        if let Some(err) = cause.downcast_ref::<clap::Error>() {
+            let message = err.to_string();
+            let message = message.lines().next().unwrap_or_default();
+            let message = message.strip_prefix("error: ").unwrap_or(message);
+            return Failure::new(Kind::Usage, message).with_hint("see `fsynctl help`");
+        }
+    }
+    Failure::new(Kind::Failure, message)
+}
+
+fn classify_fsync(err: &fsync::Error, message: String) -> Failure {
+    use fsync::{Error, PathError};
+
+    let failure = |kind| Failure::new(kind, message);
+    match err {
+        Error::Path(PathError::NotFound(path, _)) => {
+            failure(Kind::NotFound).with_path(path.clone())
+        }
+        Error::Path(PathError::Illegal(path, _)) => failure(Kind::Usage).with_path(path.clone()),
+        Error::Path(PathError::Only(path, _) | PathError::Unexpected(path, _)) => {
+            failure(Kind::Failure).with_path(path.clone())
+        }
+        Error::Conflict(path) | Error::Unresolved(path, _) => failure(Kind::Conflicts)
+            .with_path(path.clone())
+            .with_hint("list the conflicts with `fsynctl conflicts`"),
+        Error::Auth(..) | Error::DeviceCode(..) | Error::AuthTimeout(..) => {
+            failure(Kind::AuthRequired).with_hint("authorize fsyncd again with `fsynctl sync`")
+        }
+        Error::Unauthorized(..) => failure(Kind::Unreachable)
+            .with_hint("the daemon was restarted with another token, try again"),
+        Error::RemoteInitializing | Error::Unavailable(..) => failure(Kind::Unavailable),
+        Error::Pinned(path) | Error::Withheld(path, _) => {
+            failure(Kind::Refused).with_path(path.clone())
+        }
+        Error::RootChanged(..) => {
+            failure(Kind::Refused).with_hint("migrate the instance with `fsynctl root`")
+        }
+        Error::ConfirmationRequired(..) => {
+            failure(Kind::Refused).with_hint("run the command again with --yes")
+        }
+        Error::NotDownloadable { path, .. } | Error::IllegalSymlink { path, .. } => {
+            failure(Kind::Failure).with_path(path.clone())
+        }
+        Error::NotEmpty(path) => failure(Kind::Failure).with_path(path.clone()),
+        _ => failure(Kind::Failure),
+    }
+}
+
+/// Print `err` on the standard error in `format`, and return its exit code
+pub fn report(err: &anyhow::Error, format: ErrorFormat) -> ExitCode {
+    let failure = classify(err);
+    match format {
+        ErrorFormat::Text => {
+            eprintln!("{}", failure.message);
+            if let Some(hint) = &failure.hint {
+                eprintln!("hint: {hint}");
+            }
+        }
+        ErrorFormat::Json => {
+            let report = Report {
+                code: failure.kind.code(),
+                kind: failure.kind,
+                path: failure.path.as_ref(),
+                message: failure.message.clone(),
+                hint: failure.hint.as_deref(),
+            };
+            match serde_json::to_string(&report) {
+                Ok(json) => eprintln!("{json}"),
+                Err(_) => eprintln!("{}", failure.message),
+            }
+        }
+    }
+    ExitCode::from(failure.kind.code())
+}
+
+/// A usage error, e.g. no instance given while several exist
+pub fn usage(message: impl Into<String>) -> anyhow::Error {
+    Failure::new(Kind::Usage, message).into()
+}
+
+/// The error of a command whose confirmation was declined
+pub fn aborted() -> anyhow::Error {
+    Failure::new(Kind::Refused, "Aborted").into()
+}
+
+/// The error of a command that lists or leaves `count` conflicts under `path`
+pub fn conflicts(count: usize, path: PathBuf) -> anyhow::Error {
+    Failure::new(Kind::Conflicts, format!("{count} conflicts found"))
+        .with_path(path)
+        .into()
+}
+
+/// The error of an operation on `path` that completed with `failed` entries in failure
+pub fn partial_failure(failed: usize, path: PathBuf) -> anyhow::Error {
+    Failure::new(Kind::PartialFailure, format!("{failed} entries failed"))
+        .with_path(path)
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_classified() {
+        let kind = |err: anyhow::Error| classify(&err).kind;
+
+        assert_eq!(kind(anyhow::anyhow!("any")), Kind::Failure);
+        assert_eq!(kind(usage("no instance")), Kind::Usage);
+        let not_found = fsync::Error::Path(fsync::PathError::NotFound(PathBuf::from("/a"), None));
+        assert_eq!(kind(not_found.clone().into()), Kind::NotFound);
+        assert_eq!(
+            kind(anyhow::Error::from(not_found).context("Could not sync")),
+            Kind::NotFound
+        );
+        assert_eq!(
+            kind(fsync::Error::Conflict(PathBuf::from("/a")).into()),
+            Kind::Conflicts
+        );
+        assert_eq!(
+            kind(fsync::Error::AuthTimeout(60).into()),
+            Kind::AuthRequired
+        );
+        assert_eq!(
+            kind(ConnectError::NotRunning("drive".into()).into()),
+            Kind::Unreachable
+        );
+        assert_eq!(
+            kind(tarpc::client::RpcError::DeadlineExceeded.into()),
+            Kind::Unreachable
+        );
+    }
+
+    #[test]
+    fn context_is_kept_in_the_message() {
+        let err = partial_failure(2, PathBuf::from("/dir")).context("Could not sync /dir");
+        let failure = classify(&err);
+        assert_eq!(failure.kind, Kind::PartialFailure);
+        assert_eq!(failure.kind.code(), 6);
+        assert_eq!(failure.message, "Could not sync /dir");
+        assert_eq!(failure.path, Some(PathBuf::from("/dir")));
+    }
+}
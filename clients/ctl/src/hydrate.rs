@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+use fsync::{path::PathBuf, Operation, Progress};
+use fsync_client::utils::ctx;
+
+use crate::utils;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    /// Path to the remote-only file to download
+    path: PathBuf,
+}
+
+pub async fn main(args: Args) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let client = utils::instance_client(&instance_name).await?;
+    let path = args.path;
+    let mut progress = client
+        .operate(ctx(), Operation::Hydrate(path.clone()))
+        .await??;
+    loop {
+        match &progress {
+            Progress::Done | Progress::DoneWithReport(..) => break,
+            Progress::Err(err) => anyhow::bail!(err.clone()),
+            Progress::Failed(failure) => anyhow::bail!("{failure}"),
+            _ => (),
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        match client.progress(ctx(), path.clone()).await?? {
+            Some(p) => progress = p,
+            None => break,
+        }
+    }
+    utils::check_failures(&client, &path, progress.report()).await?;
+    println!("{path} downloaded");
+    Ok(())
+}
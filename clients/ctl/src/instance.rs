@@ -0,0 +1,90 @@
+use fsync::{loc::user, path::FsPathBuf};
+use fsync_client::utils::ctx;
+use inquire::Text;
+
+use crate::new::{map_validation_result, validate_name, validate_path};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Rename an instance, keeping its caches and authorization
+    Rename {
+        /// Current name of the instance
+        old: String,
+        /// New name of the instance
+        new: String,
+    },
+    /// Create an instance with the configuration of another one.
+    /// The new instance has to be authorized again.
+    Clone {
+        /// Name of the instance to clone
+        src: String,
+        /// Name of the new instance
+        dst: String,
+        /// The directory to synchronize on the local file system
+        #[clap(long, short = 'p')]
+        local_dir: Option<FsPathBuf>,
+    },
+    /// Forget the authorization of a Drive instance, to authorize it again,
+    /// possibly with another account
+    Reauth {
+        /// Name of the instance
+        name: String,
+    },
+    /// Apply the changes of the configuration that don't need a restart of the daemon,
+    /// such as the deferral policy
+    Reload {
+        /// Name of the instance
+        name: String,
+    },
+}
+
+pub async fn main(args: Args) -> anyhow::Result<()> {
+    match args.command {
+        Command::Rename { old, new } => {
+            map_validation_result(validate_name(&new))?;
+            fsync_client::config::rename(&old, &new).await?;
+            println!("Renamed `{old}` to `{new}`");
+        }
+        Command::Clone {
+            src,
+            dst,
+            local_dir,
+        } => {
+            map_validation_result(validate_name(&dst))?;
+            let local_dir = if let Some(local_dir) = local_dir {
+                map_validation_result(validate_path(local_dir.as_str()))?;
+                local_dir
+            } else {
+                let def = user::home_dir()?.join(&dst);
+                Text::new("Local directory path?")
+                    .with_default(def.as_str())
+                    .with_validator(validate_path)
+                    .prompt()
+                    .map(FsPathBuf::from)?
+            };
+            fsync_client::config::clone(&src, &dst, &local_dir).await?;
+            println!("Cloned `{src}` to `{dst}`, synchronized in {local_dir}");
+            println!("Start the daemon of `{dst}` to authorize it");
+        }
+        Command::Reauth { name } => {
+            fsync_client::config::reauthorize(&name).await?;
+            println!("Start the daemon of `{name}` to authorize it again");
+        }
+        Command::Reload { name } => {
+            let client = crate::utils::instance_client(&name).await?;
+            client.reload_config(ctx()).await??;
+            let policy = client.status(ctx()).await??.policy;
+            match policy.deferred {
+                Some(reason) => println!("Reloaded `{name}`, scheduled work postponed: {reason}"),
+                None => println!("Reloaded `{name}`"),
+            }
+        }
+    }
+    Ok(())
+}
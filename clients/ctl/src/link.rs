@@ -0,0 +1,76 @@
+use fsync::{path::PathBuf, RemoteLink};
+use fsync_client::utils::ctx;
+
+use crate::{
+    exit,
+    utils::{self, Format},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    /// Open the link in the browser
+    #[clap(long)]
+    open: bool,
+
+    /// Let anyone with the link read the file, after confirmation
+    #[clap(long)]
+    create_public: bool,
+
+    /// Share the file publicly without asking
+    #[clap(long, short = 'y', requires = "create_public")]
+    yes: bool,
+
+    /// Path of the remote file
+    path: PathBuf,
+}
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let client = utils::instance_client(&instance_name).await?;
+    let path = args.path.clone();
+    let link = if args.create_public {
+        // the daemon always asks to confirm the public sharing
+        let mut confirmation = None;
+        loop {
+            match client
+                .share_public(ctx(), path.clone(), confirmation.clone())
+                .await?
+            {
+                Ok(link) => break link,
+                Err(fsync::Error::ConfirmationRequired(token, summary))
+                    if confirmation.is_none() =>
+                {
+                    if !args.yes && !utils::ask(&format!("{summary}. Proceed?"))? {
+                        return Err(exit::aborted());
+                    }
+                    confirmation = Some(token);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    } else {
+        client.remote_link(ctx(), path).await??
+    };
+
+    if format == Format::Json {
+        utils::print_json(&link)?;
+    } else {
+        print_link(&link);
+    }
+    if args.open {
+        webbrowser::open(&link.web_view)?;
+    }
+    Ok(())
+}
+
+fn print_link(link: &RemoteLink) {
+    println!("{}", link.web_view);
+    if let Some(web_content) = &link.web_content {
+        println!("download: {web_content}");
+    }
+}
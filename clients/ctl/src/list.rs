@@ -1,4 +1,18 @@
-use fsync::loc::user;
+use fsync::{
+    loc::{inst, user},
+    overlap,
+};
+use fsync_client::{config, Instance};
+use serde::Serialize;
+
+use crate::utils::{self, Format};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Also print whether the daemon runs, and whether it loaded the current config
+    #[clap(long, short = 'l')]
+    long: bool,
+}
 
 pub fn list_drives() -> anyhow::Result<Vec<String>> {
     let config_dir = user::config_dir()?;
@@ -9,15 +23,64 @@ pub fn list_drives() -> anyhow::Result<Vec<String>> {
     let mut drives = Vec::new();
     for di in dirent {
         let di = di?;
-        if di.file_type()?.is_dir() {
-            drives.push(di.file_name().into());
+        let name = di.file_name();
+        if !di.file_type()?.is_dir() || config::is_creating_dir(name) {
+            continue;
+        }
+        if inst::config_file(name)?.exists() {
+            drives.push(name.into());
         }
     }
     Ok(drives)
 }
 
-pub fn main() -> anyhow::Result<()> {
+/// Warn once about each pair of instances whose remote roots overlap
+fn warn_overlaps(drives: &[String]) {
+    for (i, name) in drives.iter().enumerate() {
+        let Ok(Some(root)) = overlap::recorded_root(name) else {
+            continue;
+        };
+        let Ok(overlaps) = overlap::overlaps(name, &root) else {
+            continue;
+        };
+        // the pairs with the instances listed before were reported with them
+        for overlap in overlaps
+            .iter()
+            .filter(|o| !drives[..i].contains(&o.instance))
+        {
+            log::warn!("The remote root of {name} overlaps: {overlap}");
+        }
+    }
+}
+
+/// An instance as listed with `--long`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LongEntry {
+    name: String,
+    running: bool,
+    pid: Option<u32>,
+    /// Whether the config file changed since the daemon loaded it, if known
+    config_changed: Option<bool>,
+}
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    if let Some(root) = user::root()? {
+        log::info!("Using the config root {root}");
+    }
     let drives = list_drives()?;
+    for name in config::partial_instances()? {
+        log::warn!(
+            "{name} is left over by a failed creation, run `fsynctl doctor -n {name}` to delete it"
+        );
+    }
+    warn_overlaps(&drives);
+    if args.long {
+        return print_long(drives, format).await;
+    }
+    if format == Format::Json {
+        return utils::print_json(&drives);
+    }
     if drives.is_empty() {
         println!("(no fsync service yet)");
     }
@@ -26,3 +89,45 @@ pub fn main() -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+async fn print_long(drives: Vec<String>, format: Format) -> anyhow::Result<()> {
+    let mut entries = Vec::new();
+    for name in drives {
+        let Some(instance) = Instance::get(&name)? else {
+            continue;
+        };
+        let config_changed = match instance.config_changed().await {
+            Ok(changed) => changed,
+            Err(err) => {
+                log::warn!("Could not compare the config of {name}: {err:#}");
+                None
+            }
+        };
+        entries.push(LongEntry {
+            running: instance.running(),
+            pid: instance.port_file().and_then(|file| file.pid),
+            config_changed,
+            name,
+        });
+    }
+    if format == Format::Json {
+        return utils::print_json(&entries);
+    }
+    if entries.is_empty() {
+        println!("(no fsync service yet)");
+    }
+    for entry in entries {
+        let state = match (entry.running, entry.pid) {
+            (false, _) => "stopped".to_string(),
+            (true, Some(pid)) => format!("running (pid {pid})"),
+            (true, None) => "running".to_string(),
+        };
+        let config = match entry.config_changed {
+            Some(true) => ", config changed since loaded: reload or restart the daemon",
+            Some(false) => ", config up to date",
+            None => "",
+        };
+        println!("{}  {state}{config}", entry.name);
+    }
+    Ok(())
+}
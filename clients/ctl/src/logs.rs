@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use fsync::{LogLevel, LogRecord};
+use fsync_client::utils::ctx;
+
+use crate::utils::{self, Format};
+
+/// Delay between two polls of the log when following it
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    /// Keep printing the records as they are logged
+    #[clap(long, short = 'f')]
+    follow: bool,
+
+    /// Number of recent records to print first
+    #[clap(long, short = 'm', default_value_t = 100)]
+    max: u32,
+
+    /// Most verbose level printed
+    #[clap(long, short = 'l', value_enum, default_value_t = Level::Info)]
+    level: Level,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<Level> for LogLevel {
+    fn from(value: Level) -> Self {
+        match value {
+            Level::Error => LogLevel::Error,
+            Level::Warn => LogLevel::Warn,
+            Level::Info => LogLevel::Info,
+            Level::Debug => LogLevel::Debug,
+            Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let client = utils::instance_client(&instance_name).await?;
+    let level = LogLevel::from(args.level);
+    let records = client.logs_tail(ctx(), args.max, level).await??;
+
+    if !args.follow {
+        if format == Format::Json {
+            return utils::print_json(&records);
+        }
+        for record in &records {
+            print_record(record);
+        }
+        return Ok(());
+    }
+
+    // one JSON document per line when following
+    let print = |record: &LogRecord| -> anyhow::Result<()> {
+        if format == Format::Json {
+            println!("{}", serde_json::to_string(record)?);
+        } else {
+            print_record(record);
+        }
+        Ok(())
+    };
+    let mut cursor = records.last().map_or(0, |r| r.seq);
+    for record in &records {
+        print(record)?;
+    }
+    loop {
+        let (next, records) = client.logs_since(ctx(), cursor, 1000, level).await??;
+        for record in &records {
+            print(record)?;
+        }
+        cursor = next;
+        if records.is_empty() {
+            tokio::time::sleep(FOLLOW_INTERVAL).await;
+        }
+    }
+}
+
+fn print_record(record: &LogRecord) {
+    let time = record.time.with_timezone(&chrono::Local);
+    let operation = record
+        .operation
+        .map(|op| format!(" op#{op}"))
+        .unwrap_or_default();
+    println!(
+        "{} {:<5} {}{operation}: {}",
+        time.format("%H:%M:%S%.3f"),
+        record.level,
+        record.target,
+        record.message
+    );
+}
@@ -1,19 +1,85 @@
-use std::process;
+use std::{fs, path::PathBuf, process, time::Duration};
 
 use clap::Parser;
+use log::LevelFilter;
 
+mod audit;
+mod cache;
+mod checksum;
 mod conflicts;
+mod delete;
+mod digest;
+mod doctor;
 mod entry;
+mod exit;
+mod hydrate;
+mod instance;
+mod link;
 mod list;
+mod logs;
+mod maintenance;
+mod move_across;
 mod nav;
 mod new;
+mod pin;
+mod rescan;
+mod restore;
+mod revisions;
+mod root;
+mod stats;
+mod sync;
+mod takeout;
 mod tree;
 mod utils;
+mod verify;
+mod width;
+
+const EXIT_CODES: &str = "Exit codes:
+  0  success
+  1  failure
+  2  invalid arguments, or no instance to operate on
+  3  conflicts listed, or left by the operation
+  4  the daemon is not running, or did not answer
+  5  the access to the remote storage must be authorized again
+  6  the operation completed, but some entries failed
+  7  no such entry
+  8  the daemon refused the operation
+  9  the remote storage is unavailable, try again later";
 
 #[derive(Parser)]
 #[command(name = "fsynctl")]
 #[command(author, version, about, long_about=None)]
+#[command(after_help = EXIT_CODES)]
 struct Cli {
+    /// Print more diagnostics: -v to log the calls to fsyncd, -vv for everything
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only print the output of the command, without any diagnostic
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Write the diagnostics to this file instead of the standard error, with at least -v
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Format of the output of the commands listing entries
+    #[arg(long, global = true, value_enum, default_value_t = utils::Format::Text)]
+    format: utils::Format,
+
+    /// Format of the errors printed on the standard error, see the exit codes in `fsynctl help`
+    #[arg(long, global = true, value_enum, default_value_t = exit::ErrorFormat::Text)]
+    error_format: exit::ErrorFormat,
+
+    /// Root of the config, cache and runtime directories, instead of the ones of the user.
+    /// Defaults to the FSYNC_CONFIG_DIR environment variable.
+    #[arg(long, global = true, value_name = "DIR")]
+    config_dir: Option<fsync::path::FsPathBuf>,
+
+    /// Seconds given to fsyncd to reply to each request
+    #[arg(long, global = true, value_name = "SECONDS", default_value_t = fsync::DEFAULT_RPC_TIMEOUT.as_secs())]
+    timeout: u64,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -21,38 +87,151 @@ struct Cli {
 #[derive(clap::Subcommand)]
 enum Commands {
     /// List all installed services
-    List,
+    List(list::Args),
     /// Navigate in the repository
     Nav(nav::Args),
     /// Create a new synchronization service
     New(new::Args),
+    /// Rename or clone a synchronization service
+    Instance(instance::Args),
     /// Get the status of an entry
     Entry(entry::Args),
     /// Print the tree status
     Tree(tree::Args),
     /// List conflicts
     Conflicts(conflicts::Args),
+    /// Synchronize an entry and its descendants
+    Sync(sync::Args),
+    /// Delete an entry and its descendants, from one or both storages
+    Delete(delete::Args),
+    /// Check the tree and remove entries deleted on both sides
+    Doctor(doctor::Args),
+    /// Catch up with the local changes made while the daemon was running
+    Rescan(rescan::Args),
+    /// Show the actions performed on the drives, and undo them
+    Audit(audit::Args),
+    /// Pin entries, to protect them from deletion and overwriting
+    Pin(pin::Args),
+    /// Download a remote-only file, replacing its placeholder
+    Hydrate(hydrate::Args),
+    /// Show the disk usage of the caches, and clear them
+    Cache(cache::Args),
+    /// Move a sub-tree to another instance, streaming the files between the daemons
+    MoveAcross(move_across::Args),
+    /// Copy the entries missing from one side of a sub-tree, without deleting anything
+    Restore(restore::Args),
+    /// Deliver the digest of the conflicts and failed operations
+    Digest(digest::Args),
+    /// Clean up the quarantine, the temporary files and the audit log
+    Maintenance(maintenance::Args),
+    /// Compute the checksum of local files
+    Checksum(checksum::Args),
+    /// Verify that the local and remote copies of the synchronized files have the same content
+    Verify(verify::Args),
+    /// Import a Google Takeout export of the drive in the local directory
+    Takeout(takeout::Args),
+    /// Show the activity counters since the daemon started and over the instance lifetime
+    Stats(stats::Args),
+    /// Check the remote root of the config, and migrate the instance after a change
+    Root(root::Args),
+    /// List the revisions of a remote file, and download them
+    Revisions(revisions::Args),
+    /// Print the link to open a remote file in the browser, and share it publicly
+    Link(link::Args),
+    /// Print the recent records of the daemon log, and follow it
+    Logs(logs::Args),
 }
 
 #[tokio::main]
 async fn main() -> process::ExitCode {
-    let cli = Cli::parse();
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        // the help and the version
+        Err(err) if !err.use_stderr() => {
+            let _ = err.print();
+            return process::ExitCode::SUCCESS;
+        }
+        Err(err) => match exit::ErrorFormat::from_args() {
+            exit::ErrorFormat::Text => {
+                let _ = err.print();
+                return process::ExitCode::from(exit::Kind::Usage.code());
+            }
+            format => return exit::report(&err.into(), format),
+        },
+    };
+    let error_format = cli.error_format;
+    if let Err(err) = init_logger(&cli) {
+        return exit::report(&err, error_format);
+    }
+    if let Err(err) = fsync::loc::user::init_root(cli.config_dir.clone()) {
+        return exit::report(&exit::usage(format!("{err:#}")), error_format);
+    }
     match main2(cli).await {
         Ok(()) => process::ExitCode::SUCCESS,
-        Err(err) => {
-            eprintln!("{err}");
-            process::ExitCode::FAILURE
+        Err(err) => exit::report(&err, error_format),
+    }
+}
+
+fn init_logger(cli: &Cli) -> anyhow::Result<()> {
+    let level = match (cli.quiet, cli.verbose) {
+        (true, _) => LevelFilter::Off,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    let mut builder = env_logger::Builder::new();
+    if let Some(path) = &cli.log_file {
+        let file = fs::File::create(path)?;
+        builder
+            .filter_level(level.max(LevelFilter::Debug))
+            .target(env_logger::Target::Pipe(Box::new(file)));
+    } else {
+        builder.filter_level(level);
+        if cli.verbose == 0 {
+            // the diagnostics read as plain messages, unless asked for details
+            builder
+                .format_timestamp(None)
+                .format_level(false)
+                .format_target(false);
+        }
+        if !cli.quiet {
+            builder.parse_default_env();
         }
     }
+    builder.try_init()?;
+    Ok(())
 }
 
 async fn main2(cli: Cli) -> anyhow::Result<()> {
+    let format = cli.format;
+    fsync_client::utils::set_timeout(Duration::from_secs(cli.timeout));
     match cli.command {
-        Commands::List => list::main(),
+        Commands::List(args) => list::main(args, format).await,
         Commands::Nav(args) => nav::main(args).await,
         Commands::New(args) => new::main(args).await,
-        Commands::Entry(args) => entry::main(args).await,
+        Commands::Instance(args) => instance::main(args).await,
+        Commands::Entry(args) => entry::main(args, format).await,
         Commands::Tree(args) => tree::main(args).await,
-        Commands::Conflicts(args) => conflicts::main(args).await,
+        Commands::Conflicts(args) => conflicts::main(args, format).await,
+        Commands::Sync(args) => sync::main(args).await,
+        Commands::Delete(args) => delete::main(args).await,
+        Commands::Doctor(args) => doctor::main(args).await,
+        Commands::Rescan(args) => rescan::main(args, format).await,
+        Commands::Audit(args) => audit::main(args).await,
+        Commands::Pin(args) => pin::main(args, format).await,
+        Commands::Hydrate(args) => hydrate::main(args).await,
+        Commands::Cache(args) => cache::main(args, format).await,
+        Commands::MoveAcross(args) => move_across::main(args).await,
+        Commands::Restore(args) => restore::main(args).await,
+        Commands::Digest(args) => digest::main(args).await,
+        Commands::Maintenance(args) => maintenance::main(args, format).await,
+        Commands::Checksum(args) => checksum::main(args, format).await,
+        Commands::Verify(args) => verify::main(args, format).await,
+        Commands::Takeout(args) => takeout::main(args, format).await,
+        Commands::Stats(args) => stats::main(args, format).await,
+        Commands::Root(args) => root::main(args, format).await,
+        Commands::Revisions(args) => revisions::main(args, format).await,
+        Commands::Link(args) => link::main(args, format).await,
+        Commands::Logs(args) => logs::main(args, format).await,
     }
 }
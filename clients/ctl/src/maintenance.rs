@@ -0,0 +1,53 @@
+use fsync::fmt::{human_bytes, Unit};
+use fsync_client::utils::ctx;
+
+use crate::utils::{self, Format};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Clean up now, as the scheduled maintenance of the config
+    Run {
+        /// Only print what would be cleaned up
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    match args.command {
+        Command::Run { dry_run } => {
+            let client = utils::instance_client(&instance_name).await?;
+            let report = client.run_maintenance(ctx(), dry_run).await??;
+            if format == Format::Json {
+                return utils::print_json(&report);
+            }
+            for cleanup in &report.cleanups {
+                println!(
+                    "{:<16} {:>10}  {}",
+                    cleanup.kind.to_string(),
+                    human_bytes(cleanup.bytes, Unit::Binary),
+                    cleanup.path
+                );
+            }
+            let verb = if dry_run { "would be freed" } else { "freed" };
+            println!(
+                "{} cleanups, {} {verb}",
+                report.cleanups.len(),
+                human_bytes(report.freed(), Unit::Binary)
+            );
+        }
+    }
+    Ok(())
+}
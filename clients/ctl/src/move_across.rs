@@ -0,0 +1,331 @@
+//! Move a sub-tree from an instance to another, by streaming the content of the files
+//! from one daemon to the other.
+//!
+//! The files moved are recorded in a journal of the user cache, so that an interrupted move
+//! resumes where it stopped when the same command is run again.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+};
+
+use anyhow::Context;
+use fsync::{
+    loc::user,
+    path::{FsPathBuf, Path, PathBuf},
+    DeletionMethod, SortOrder, MAX_STREAM_CHUNK,
+};
+use fsync_client::utils::{ctx, node_and_children};
+use md5::Digest as _;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    delete, exit,
+    utils::{self, Client},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Sub-tree to move, as INSTANCE:PATH
+    #[clap(long, value_name = "INSTANCE:PATH", value_parser = parse_target)]
+    from: Target,
+
+    /// Destination of the sub-tree, as INSTANCE:PATH. Its parent must be a remote directory.
+    #[clap(long, value_name = "INSTANCE:PATH", value_parser = parse_target)]
+    to: Target,
+
+    /// Delete the sub-tree from both storages of the source once all the files are moved
+    #[clap(long)]
+    delete: bool,
+
+    /// Confirm the deletion of a large sub-tree without asking
+    #[clap(long, short = 'y', requires = "delete")]
+    yes: bool,
+}
+
+#[derive(Clone, Debug)]
+struct Target {
+    instance: String,
+    path: PathBuf,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.instance, self.path)
+    }
+}
+
+fn parse_target(arg: &str) -> Result<Target, String> {
+    let (instance, path) = arg
+        .split_once(':')
+        .ok_or_else(|| format!("expected INSTANCE:PATH, got {arg}"))?;
+    if instance.is_empty() {
+        return Err(format!("no instance in {arg}"));
+    }
+    let path = PathBuf::from(path);
+    if !path.is_absolute() {
+        return Err(format!("{path} is not an absolute path"));
+    }
+    let path = path.normalize().map_err(|err| format!("{path}: {err}"))?;
+    Ok(Target {
+        instance: instance.to_owned(),
+        path,
+    })
+}
+
+pub async fn main(args: Args) -> anyhow::Result<()> {
+    let Args {
+        from,
+        to,
+        delete,
+        yes,
+    } = args;
+    if from.instance == to.instance {
+        return Err(exit::usage(
+            "--from and --to are the same instance, move the entries in its local directory",
+        ));
+    }
+
+    let source = utils::instance_client(&from.instance).await?;
+    let dest = utils::instance_client(&to.instance).await?;
+
+    let (dirs, files) = walk(&source, &from.path).await?;
+    let mut journal = Journal::open(&from, &to)?;
+    let resuming = !journal.done.is_empty();
+    if resuming {
+        log::info!(
+            "Resuming the move, {} of {} files already moved",
+            journal.done.len(),
+            files.len()
+        );
+    }
+
+    // the skeleton first, so that each file has its parent
+    for dir in &dirs {
+        dest.receive_dir(ctx(), dest_path(&from.path, &to.path, dir))
+            .await??;
+    }
+
+    let mut moved = 0;
+    for file in &files {
+        if journal.done.contains(file) {
+            continue;
+        }
+        let file_dest = dest_path(&from.path, &to.path, file);
+        let md5 = transfer(&source, &dest, file, &file_dest, resuming)
+            .await
+            .with_context(|| format!("Could not move {file} to {}:{file_dest}", to.instance))?;
+        journal.record(file, md5)?;
+        log::info!("{file} moved to {}:{file_dest}", to.instance);
+        moved += 1;
+    }
+    println!(
+        "{from} moved to {to}: {} directories, {moved} files",
+        dirs.len()
+    );
+
+    if delete {
+        delete::delete_deep(&source, &from.path, DeletionMethod::All, yes).await?;
+        println!("{from} deleted");
+    }
+    journal.remove()
+}
+
+/// The path in the destination of the entry at `path` in the source
+fn dest_path(from: &Path, to: &Path, path: &Path) -> PathBuf {
+    let rel = path.as_str()[from.as_str().len()..].trim_start_matches('/');
+    if rel.is_empty() {
+        to.to_owned()
+    } else {
+        to.join(rel)
+    }
+}
+
+/// The directories and files of the sub-tree at `path`, the parents before their children.
+/// Fails if the sub-tree has conflicts, as they don't tell which side to move.
+async fn walk(client: &Client, path: &Path) -> anyhow::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    let mut conflicts = 0;
+
+    let (node, _) = node_and_children(client, path, SortOrder::Raw).await?;
+    if node.entry().is_conflict() {
+        return Err(exit::conflicts(1, path.to_owned()));
+    }
+    if !node.entry().is_safe_dir() {
+        files.push(path.to_owned());
+        return Ok((dirs, files));
+    }
+
+    let mut stack = vec![path.to_owned()];
+    while let Some(dir) = stack.pop() {
+        let (_, children) = node_and_children(client, &dir, SortOrder::Raw).await?;
+        dirs.push(dir);
+        for child in children {
+            let entry = child.entry();
+            if entry.is_conflict() {
+                log::error!("{}: conflict", entry.path());
+                conflicts += 1;
+            } else if entry.is_safe_dir() {
+                stack.push(entry.path().to_owned());
+            } else {
+                files.push(entry.path().to_owned());
+            }
+        }
+    }
+    if conflicts > 0 {
+        return Err(exit::conflicts(conflicts, path.to_owned()));
+    }
+    Ok((dirs, files))
+}
+
+/// Stream the content of `path` in `source` to the new remote file at `dest_path` in `dest`,
+/// and return its MD5, or `None` if a previous run had created the file.
+async fn transfer(
+    source: &Client,
+    dest: &Client,
+    path: &Path,
+    dest_path: &Path,
+    resuming: bool,
+) -> anyhow::Result<Option<String>> {
+    let mut chunk = source
+        .read_stream(ctx(), path.to_owned(), 0, MAX_STREAM_CHUNK)
+        .await??;
+    let metadata = chunk.metadata.clone();
+    let size = metadata.size().unwrap_or_default();
+
+    if resuming {
+        // the run was interrupted between the creation of the file and its record
+        let existing = dest
+            .entry_node_page(ctx(), dest_path.to_owned(), None, 0)
+            .await??
+            .and_then(|node| node.into_entry().into_remote_metadata());
+        if let Some(existing) = existing {
+            if existing.is_file() && existing.size() == Some(size) {
+                return Ok(None);
+            }
+            anyhow::bail!("{dest_path} exists, and differs from {path}");
+        }
+    }
+
+    let handle = dest
+        .receive_begin(ctx(), metadata.with_path(dest_path.to_owned()))
+        .await??;
+    let res = async move {
+        let mut hasher = md5::Md5::new();
+        let mut offset = 0;
+        loop {
+            if chunk.metadata != metadata {
+                anyhow::bail!("{path} was modified during the move");
+            }
+            if chunk.data.is_empty() && offset < size {
+                anyhow::bail!("{path} ended {} bytes early", size - offset);
+            }
+            hasher.update(&chunk.data);
+            offset += chunk.data.len() as u64;
+            dest.receive_chunk(ctx(), handle, chunk.data).await??;
+            if offset >= size {
+                break;
+            }
+            chunk = source
+                .read_stream(ctx(), path.to_owned(), offset, MAX_STREAM_CHUNK)
+                .await??;
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+    .await;
+
+    match res {
+        Ok(md5) => {
+            dest.receive_finish(ctx(), handle, md5.clone()).await??;
+            Ok(Some(md5))
+        }
+        Err(err) => {
+            // the reception ends short of the content, and leaves no file
+            let _ = dest.receive_finish(ctx(), handle, String::new()).await;
+            Err(err)
+        }
+    }
+}
+
+/// A file moved, as recorded in the journal
+#[derive(Serialize, Deserialize)]
+struct Record {
+    path: PathBuf,
+    md5: Option<String>,
+}
+
+/// The files already moved from a source to a destination, one JSON record per line
+struct Journal {
+    path: FsPathBuf,
+    file: fs::File,
+    done: HashSet<PathBuf>,
+}
+
+impl Journal {
+    fn open(from: &Target, to: &Target) -> anyhow::Result<Self> {
+        let dir = user::cache_dir()?.join("move-across");
+        fs::create_dir_all(&dir)?;
+        let key = hex::encode(md5::Md5::digest(format!("{from}\n{to}")));
+        let path = dir.join(format!("{key}.jsonl"));
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err).with_context(|| format!("Could not read {path}")),
+        };
+        // the last record may have been cut by the interruption
+        let done = content
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Record>(line).ok())
+            .map(|record| record.path)
+            .collect();
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if !content.is_empty() && !content.ends_with('\n') {
+            writeln!(file)?;
+        }
+        Ok(Self { path, file, done })
+    }
+
+    fn record(&mut self, path: &Path, md5: Option<String>) -> anyhow::Result<()> {
+        let record = Record {
+            path: path.to_owned(),
+            md5,
+        };
+        writeln!(self.file, "{}", serde_json::to_string(&record)?)?;
+        self.file.sync_data()?;
+        self.done.insert(record.path);
+        Ok(())
+    }
+
+    /// Remove the journal of a move that completed
+    fn remove(self) -> anyhow::Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path).with_context(|| format!("Could not remove {}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn move_across_paths() {
+        let target = parse_target("team:/Shared/2019/").unwrap();
+        assert_eq!(target.instance, "team");
+        assert_eq!(target.path, PathBuf::from("/Shared/2019"));
+        assert!(parse_target("/Archive").is_err());
+        assert!(parse_target(":/Archive").is_err());
+        assert!(parse_target("team:Archive").is_err());
+
+        let from = Path::new("/Archive/2019");
+        let to = Path::new("/Shared/2019");
+        assert_eq!(dest_path(from, to, from), to.to_owned());
+        assert_eq!(
+            dest_path(from, to, Path::new("/Archive/2019/a/b.txt")),
+            PathBuf::from("/Shared/2019/a/b.txt")
+        );
+    }
+}
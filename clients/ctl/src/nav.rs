@@ -1,6 +1,10 @@
-use std::{io, panic, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    io, panic,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::Context;
 use crossterm::{
     cursor,
     event::{self, EventStream},
@@ -9,11 +13,11 @@ use crossterm::{
 use fsync::{
     path::{Path, PathBuf},
     tree::EntryNode,
-    FsyncClient,
+    SortOrder,
 };
-use futures::{future, FutureExt, StreamExt};
-use tarpc::context;
-use tokio::time;
+use fsync_client::{cache::NodeCache, utils::ctx};
+use futures::{FutureExt, StreamExt};
+use tokio::{sync::watch, time};
 
 use crate::utils;
 
@@ -31,29 +35,19 @@ pub struct Args {
     #[clap(long, short = 'n')]
     instance_name: Option<String>,
 
+    /// Order in which the entries of each directory are listed
+    #[clap(long, value_enum, default_value_t = utils::Sort::Natural)]
+    sort: utils::Sort,
+
     /// A path to navigate to (defaults to '/')
     path: Option<PathBuf>,
 }
 
-fn ctx() -> context::Context {
-    context::current()
-}
-
 pub async fn main(args: Args) -> anyhow::Result<()> {
-    let instance_name = match &args.instance_name {
-        Some(name) => name.clone(),
-        None => {
-            let name = utils::single_instance_name()?;
-            if let Some(name) = name {
-                name
-            } else {
-                anyhow::bail!("Could not find a single share, please specify --share-name command line argument");
-            }
-        }
-    };
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
 
     let path = args.path.unwrap_or_else(PathBuf::root);
-    let client = utils::instance_client(&instance_name).await?;
+    let (client, conn) = utils::instance_connection(&instance_name).await?;
 
     let mut out = io::stdout();
 
@@ -68,7 +62,7 @@ pub async fn main(args: Args) -> anyhow::Result<()> {
     )?;
     terminal::enable_raw_mode().expect("Should enable raw mode");
 
-    let res = panic::AssertUnwindSafe(navigate(client, path))
+    let res = panic::AssertUnwindSafe(navigate(client, conn.subscribe(), path, args.sort.into()))
         .catch_unwind()
         .await;
 
@@ -91,7 +85,12 @@ pub async fn main(args: Args) -> anyhow::Result<()> {
     }
 }
 
-async fn navigate(client: Arc<FsyncClient>, path: PathBuf) -> anyhow::Result<()> {
+async fn navigate(
+    client: Arc<utils::Client>,
+    mut generation: watch::Receiver<u64>,
+    path: PathBuf,
+    order: SortOrder,
+) -> anyhow::Result<()> {
     use HandlerResult::*;
 
     // it is possible to receive start-up events, so we need to clear them.
@@ -104,7 +103,7 @@ async fn navigate(client: Arc<FsyncClient>, path: PathBuf) -> anyhow::Result<()>
         }
     }
 
-    let mut nav = Navigator::new(client, &path).await?;
+    let mut nav = Navigator::new(client, &path, order).await?;
     let mut render_state = render::State::default();
     let mut reader = EventStream::new();
     let mut last_frame = time::Instant::now();
@@ -132,11 +131,17 @@ async fn navigate(client: Arc<FsyncClient>, path: PathBuf) -> anyhow::Result<()>
                         _ => Continue,
                     }
                 }
+                Ok(()) = generation.changed() => nav.daemon_restarted(),
             }
         } else {
-            match event.await {
-                Some(Ok(event)) => nav.handle_event(event).await?,
-                _ => Continue,
+            tokio::select! {
+                maybe_event = event => {
+                    match maybe_event {
+                        Some(Ok(event)) => nav.handle_event(event).await?,
+                        _ => Continue,
+                    }
+                }
+                Ok(()) = generation.changed() => nav.daemon_restarted(),
             }
         };
 
@@ -146,7 +151,16 @@ async fn navigate(client: Arc<FsyncClient>, path: PathBuf) -> anyhow::Result<()>
 
         last_frame = time::Instant::now();
 
-        let (node, children) = node_and_children(&nav.client, &nav.path).await?;
+        // while operations are in progress, the listing is fetched live
+        let bypass = nav.refresh || animate;
+        let (node, children) = nav
+            .cache
+            .node_and_children(&nav.client, &nav.path, nav.order, bypass)
+            .await?;
+        nav.refresh = false;
+        nav.pinned = nav.fetch_pinned().await?;
+        nav.aggregation = nav.fetch_aggregation(&node).await?;
+        nav.implausible = nav.fetch_implausible(&node, &children).await?;
         nav.node = node;
         nav.children = children;
         if let Some(set_cur_child) = &nav.set_cur_child {
@@ -164,27 +178,6 @@ async fn navigate(client: Arc<FsyncClient>, path: PathBuf) -> anyhow::Result<()>
     Ok(())
 }
 
-async fn node_and_children(
-    client: &FsyncClient,
-    path: &Path,
-) -> anyhow::Result<(EntryNode, Vec<EntryNode>)> {
-    let node = client
-        .entry_node(ctx(), path.to_owned())
-        .await
-        .unwrap()?
-        .with_context(|| format!("No entry found at {path}"))?;
-    let child_futs = node.children().iter().map(|name| {
-        let child_path = path.join(name);
-        client.entry_node(ctx(), child_path)
-    });
-    let children: Result<Vec<_>, _> = future::try_join_all(child_futs)
-        .await?
-        .into_iter()
-        .map(|c| c.map(|c| c.expect("No entry found at child")))
-        .collect();
-    Ok((node, children?))
-}
-
 // use std::{fs::File, io::Write, sync::Mutex};
 
 // static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
@@ -204,7 +197,11 @@ async fn node_and_children(
 // }
 
 struct Navigator {
-    client: Arc<FsyncClient>,
+    client: Arc<utils::Client>,
+    cache: NodeCache,
+    order: SortOrder,
+    /// Fetch the listing live at the next iteration
+    refresh: bool,
 
     size: Size,
     focus: bool,
@@ -218,14 +215,29 @@ struct Navigator {
     node: EntryNode,
     children: Vec<EntryNode>,
     set_cur_child: Option<String>,
+    /// The entries pinned by the user
+    pinned: HashSet<PathBuf>,
+    /// How complete the remote stats of the current directory are
+    aggregation: fsync::stat::Aggregation,
+    /// The number of entries of implausible metadata below the current directory
+    /// and its children, for those that have some
+    implausible: HashMap<PathBuf, i64>,
 }
 
 impl Navigator {
-    async fn new(client: Arc<FsyncClient>, path: &Path) -> anyhow::Result<Self> {
-        let (node, children) = node_and_children(&client, path).await?;
+    async fn new(
+        client: Arc<utils::Client>,
+        path: &Path,
+        order: SortOrder,
+    ) -> anyhow::Result<Self> {
+        let cache = NodeCache::new();
+        let (node, children) = cache.node_and_children(&client, path, order, false).await?;
 
         let mut nav = Self {
             client,
+            cache,
+            order,
+            refresh: false,
 
             size: terminal::size()?.into(),
             focus: true,
@@ -238,7 +250,13 @@ impl Navigator {
             node,
             children,
             set_cur_child: None,
+            pinned: HashSet::new(),
+            aggregation: fsync::stat::Aggregation::Exact,
+            implausible: HashMap::new(),
         };
+        nav.pinned = nav.fetch_pinned().await?;
+        nav.aggregation = nav.fetch_aggregation(&nav.node).await?;
+        nav.implausible = nav.fetch_implausible(&nav.node, &nav.children).await?;
 
         nav.check_cur_node();
         nav.check_cur_child();
@@ -246,7 +264,62 @@ impl Navigator {
         Ok(nav)
     }
 
+    async fn fetch_pinned(&self) -> anyhow::Result<HashSet<PathBuf>> {
+        let pinned = self.client.pinned(ctx()).await??;
+        Ok(pinned.into_iter().collect())
+    }
+
+    fn is_pinned(&self, node: &EntryNode) -> bool {
+        self.pinned.contains(node.path())
+    }
+
+    async fn fetch_aggregation(
+        &self,
+        node: &EntryNode,
+    ) -> anyhow::Result<fsync::stat::Aggregation> {
+        let paths = vec![node.path().to_owned()];
+        let aggregation = self.client.aggregation(ctx(), paths).await??;
+        Ok(aggregation
+            .into_iter()
+            .next()
+            .unwrap_or(fsync::stat::Aggregation::Exact))
+    }
+
+    async fn fetch_implausible(
+        &self,
+        node: &EntryNode,
+        children: &[EntryNode],
+    ) -> anyhow::Result<HashMap<PathBuf, i64>> {
+        let paths: Vec<_> = std::iter::once(node)
+            .chain(children)
+            .map(|node| node.path().to_owned())
+            .collect();
+        let stats = self
+            .client
+            .implausible_stats(ctx(), paths.clone())
+            .await??;
+        Ok(paths
+            .into_iter()
+            .zip(stats)
+            .filter(|(_, stat)| stat.count > 0)
+            .map(|(path, stat)| (path, stat.count))
+            .collect())
+    }
+
+    /// The number of entries of implausible metadata in the sub-tree of `node`
+    fn implausible(&self, node: &EntryNode) -> i64 {
+        self.implausible.get(node.path()).copied().unwrap_or(0)
+    }
+
     fn cur_child_node(&self) -> Option<&EntryNode> {
         self.children.get(self.cur_child)
     }
+
+    /// The listings obtained from the previous daemon are discarded,
+    /// and the current node is fetched again
+    fn daemon_restarted(&mut self) -> HandlerResult {
+        self.cache.clear();
+        self.refresh = true;
+        HandlerResult::Continue
+    }
 }
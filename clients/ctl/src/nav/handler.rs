@@ -1,7 +1,9 @@
+use std::io::{self, Write};
+
+use base64::prelude::*;
 use crossterm::event;
 
 use super::{menu::Action, render::Size};
-use crate::nav::ctx;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HandlerResult {
@@ -16,7 +18,7 @@ impl super::Navigator {
         // super::log_msg(&format!("{:?}", event));
 
         match event {
-            event::Event::Key(key_event) => return Ok(self.handle_key_event(key_event).await?),
+            event::Event::Key(key_event) => return self.handle_key_event(key_event).await,
             event::Event::Resize(width, height) => {
                 self.size = Size { width, height };
             }
@@ -39,7 +41,7 @@ impl super::Navigator {
 
         if let Some(action) = action {
             if self.menu.is_enabled(action) {
-                return Ok(self.execute_action(action).await?);
+                return self.execute_action(action).await;
             }
         }
 
@@ -76,21 +78,44 @@ impl super::Navigator {
             Action::Back => {
                 self.open_parent();
             }
+            Action::Refresh => {
+                self.refresh = true;
+            }
+            Action::Aggregate => {
+                let path = self.node.path().to_owned();
+                self.client.aggregate_now(super::ctx(), path).await??;
+                self.refresh = true;
+            }
             Action::Sync => {
                 let child = self.cur_child_node();
                 if let Some(child) = child {
                     let path = child.entry().path().to_owned();
                     if !child.is_sync() {
                         let _progress = self
-                            .client
-                            .operate(ctx(), fsync::Operation::Sync(path.clone()))
-                            .await
-                            .unwrap()?;
+                            .cache
+                            .operate(&self.client, fsync::Operation::Sync(path.clone()))
+                            .await?;
                         // super::log_msg(&format!("Progress of {path}: {:?}", progress));
                     }
                 }
             }
             Action::SyncAll => {}
+            Action::Pin => {
+                if let Some(child) = self.cur_child_node() {
+                    let path = child.path().to_owned();
+                    let pinned = !self.is_pinned(child);
+                    self.cache.invalidate(&path);
+                    self.client.set_pinned(super::ctx(), path, pinned).await??;
+                    self.refresh = true;
+                }
+            }
+            Action::CopyLink => {
+                if let Some(child) = self.cur_child_node() {
+                    let path = child.path().to_owned();
+                    let link = self.client.remote_link(super::ctx(), path).await??;
+                    copy_to_clipboard(&link.web_view)?;
+                }
+            }
         }
 
         Ok(Continue)
@@ -115,13 +140,35 @@ impl super::Navigator {
         if let Some(node) = node {
             let is_dir = node.entry().is_safe_dir();
             let is_not_sync = node.entry().is_local_only() || node.entry().is_remote_only();
+            let is_remote_file = node
+                .entry()
+                .clone()
+                .into_remote_metadata()
+                .is_some_and(|md| md.is_file());
             self.menu.enable(Action::Enter, is_dir);
             self.menu.enable(Action::Sync, is_not_sync);
             self.menu.enable(Action::SyncAll, is_not_sync && is_dir);
+            self.menu.enable(Action::CopyLink, is_remote_file);
         }
     }
 
     pub fn check_cur_node(&mut self) {
         self.menu.enable(Action::Back, !self.node.path().is_root());
+        let is_remote_dir = self
+            .node
+            .entry()
+            .clone()
+            .into_remote_metadata()
+            .is_some_and(|md| md.is_dir());
+        self.menu.enable(Action::Aggregate, is_remote_dir);
     }
 }
+
+/// Copy `text` to the clipboard of the terminal, with an OSC 52 sequence.
+/// It works over SSH, but some terminals ignore it.
+fn copy_to_clipboard(text: &str) -> io::Result<()> {
+    let text = BASE64_STANDARD.encode(text);
+    let mut out = io::stdout();
+    write!(out, "\x1b]52;c;{text}\x07")?;
+    out.flush()
+}
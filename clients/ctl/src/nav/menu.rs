@@ -17,10 +17,14 @@ pub enum Action {
     Details,
     Enter,
     Back,
+    Refresh,
+    Aggregate,
     Exit,
     // Operations
     Sync,
     SyncAll,
+    Pin,
+    CopyLink,
 }
 
 impl Action {
@@ -31,9 +35,13 @@ impl Action {
             Action::Details => "details",
             Action::Enter => "enter",
             Action::Back => "go back",
+            Action::Refresh => "refresh",
+            Action::Aggregate => "sizes now",
             Action::Exit => "exit",
             Action::Sync => "sync.",
             Action::SyncAll => "sync. all",
+            Action::Pin => "pin/unpin",
+            Action::CopyLink => "copy link",
         }
     }
 }
@@ -61,9 +69,13 @@ impl KeyActionDesc {
             KeyCode::Down => "↓",
             KeyCode::Esc => "esc",
             KeyCode::Char(' ') => "space",
+            KeyCode::Char('a') => "a",
             KeyCode::Char('j') => "j",
             KeyCode::Char('k') => "k",
+            KeyCode::Char('l') => "l",
+            KeyCode::Char('p') => "p",
             KeyCode::Char('q') => "q",
+            KeyCode::Char('r') => "r",
             KeyCode::Char('s') => "s",
             KeyCode::Char('S') => "S",
             _ => unreachable!(),
@@ -146,9 +158,13 @@ impl Menu {
             MenuItem::new_action(Action::Enter, KeyAction(&[KeyCode::Enter])),
             MenuItem::new_action(Action::Back, KeyAction(&[KeyCode::Backspace])),
             MenuItem::new_action(Action::Details, KeyAction(&[KeyCode::Char(' ')])),
+            MenuItem::new_action(Action::Refresh, KeyAction(&[KeyCode::Char('r')])),
+            MenuItem::new_action(Action::Aggregate, KeyAction(&[KeyCode::Char('a')])),
             MenuItem::new_sep(),
             MenuItem::new_action(Action::Sync, KeyAction(&[KeyCode::Char('s')])),
             MenuItem::new_action(Action::SyncAll, KeyAction(&[KeyCode::Char('S')])),
+            MenuItem::new_action(Action::Pin, KeyAction(&[KeyCode::Char('p')])),
+            MenuItem::new_action(Action::CopyLink, KeyAction(&[KeyCode::Char('l')])),
             MenuItem::new_sep(),
             MenuItem::new_action(Action::Exit, KeyAction(&[KeyCode::Esc, KeyCode::Char('q')])),
         ];
@@ -181,10 +197,10 @@ impl Menu {
             return None;
         }
         // emulate ctrl-c which doesn't work in raw mode
-        if matches!(key_event.code, KeyCode::Char('c') | KeyCode::Char('C')) {
-            if key_event.modifiers.contains(KeyModifiers::CONTROL) {
-                return Some(Action::Exit);
-            }
+        if matches!(key_event.code, KeyCode::Char('c') | KeyCode::Char('C'))
+            && key_event.modifiers.contains(KeyModifiers::CONTROL)
+        {
+            return Some(Action::Exit);
         }
 
         for mi in self.items.iter() {
@@ -195,7 +211,7 @@ impl Menu {
             }
         }
 
-        return None;
+        None
     }
 
     pub fn max_size(&self) -> Size {
@@ -302,9 +318,13 @@ impl From<Action> for KeyAction {
             Action::Details => &[KeyCode::Char(' ')],
             Action::Enter => &[KeyCode::Enter],
             Action::Back => &[KeyCode::Backspace],
+            Action::Refresh => &[KeyCode::Char('r')],
+            Action::Aggregate => &[KeyCode::Char('a')],
             Action::Exit => &[KeyCode::Esc, KeyCode::Char('q')],
             Action::Sync => &[KeyCode::Char('s')],
             Action::SyncAll => &[KeyCode::Char('S')],
+            Action::Pin => &[KeyCode::Char('p')],
+            Action::CopyLink => &[KeyCode::Char('l')],
         })
     }
 }
@@ -9,15 +9,25 @@ use crossterm::{
     queue,
     style::{Color, Print, PrintStyledContent, Stylize},
 };
-use fsync::tree::{Entry, EntryNode};
+use fsync::{
+    fmt::{human_bytes, Unit},
+    tree::{Entry, EntryNode},
+};
 
-use crate::utils;
+use crate::width::{elided, Width};
 
 const LOCAL_COLOR: Color = Color::Reset;
 const REMOTE_COLOR: Color = Color::Cyan;
 const NODE_COLOR: Color = Color::Magenta;
 const CONFLICT_COLOR: Color = Color::Red;
 const SYNC_COLOR: Color = Color::Green;
+const QUOTA_WARNING_COLOR: Color = Color::Yellow;
+const PIN_COLOR: Color = Color::Yellow;
+/// Mark of the pinned entries
+const PIN_MARK: char = '⚑';
+
+/// Width of the quota bar in the footer
+const QUOTA_WIDTH: u16 = 26;
 
 fn entry_print_path(entry: &Entry) -> String {
     let path = entry.path().to_string();
@@ -44,19 +54,6 @@ fn entry_print_name(entry: &Entry, max_width: Option<u16>) -> String {
     }
 }
 
-fn elided(name: String, max_width: u16) -> String {
-    assert!(max_width >= 5);
-    if name.width() > max_width {
-        let start_width = max_width / 2 - 1;
-        let end_width = max_width - start_width - 3;
-        let start: String = name.chars().take(start_width as usize).collect();
-        let end: String = name.chars().rev().take(end_width as usize).collect();
-        format!("{start}...{end}")
-    } else {
-        name
-    }
-}
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct Tag {
     color: Color,
@@ -237,10 +234,6 @@ impl Rect {
     }
 }
 
-trait Width {
-    fn width(&self) -> u16;
-}
-
 impl Width for Size {
     fn width(&self) -> u16 {
         self.width
@@ -253,24 +246,6 @@ impl Width for Rect {
     }
 }
 
-impl Width for str {
-    fn width(&self) -> u16 {
-        self.chars().count() as u16
-    }
-}
-
-impl Width for String {
-    fn width(&self) -> u16 {
-        self.chars().count() as u16
-    }
-}
-
-impl<S: AsRef<str>> Width for Option<S> {
-    fn width(&self) -> u16 {
-        self.as_ref().map(|s| s.as_ref().width()).unwrap_or(0)
-    }
-}
-
 /// Animation ticks per second
 pub const ANIM_TPS: f32 = 30.0;
 
@@ -359,10 +334,9 @@ impl super::Navigator {
         };
 
         let progress = self
-            .client
-            .progresses(super::ctx(), self.node.path().to_owned())
-            .await
-            .unwrap()?;
+            .cache
+            .progresses(&self.client, self.node.path())
+            .await?;
 
         if self.node.entry().is_safe_dir() {
             self.render_dir(&viewport, state, &progress).await?;
@@ -380,7 +354,21 @@ impl super::Navigator {
                 height: 1,
             },
         };
-        self.render_stats(&footer_vp, &self.node.stats())?;
+        let instance_stats = self.client.instance_stats(super::ctx()).await.unwrap()?;
+        let has_quota = instance_stats.quota.is_some_and(|q| q.limit.is_some());
+        if has_quota && footer_vp.width() >= 2 * QUOTA_WIDTH {
+            self.render_stats(
+                &footer_vp.crop_right(QUOTA_WIDTH),
+                &self.node.stats(),
+                self.implausible(&self.node),
+            )?;
+            self.render_quota(
+                &footer_vp.crop_left(footer_vp.width() - QUOTA_WIDTH),
+                &instance_stats,
+            )?;
+        } else {
+            self.render_stats(&footer_vp, &self.node.stats(), self.implausible(&self.node))?;
+        }
 
         out.flush()?;
 
@@ -482,29 +470,6 @@ fn print_progress_bar(width: u16, progress: f32) -> String {
     s
 }
 
-#[cfg(test)]
-mod tests {
-    use super::print_progress_bar;
-
-    #[test]
-    fn test_progress_bar() {
-        assert_eq!(print_progress_bar(10, 0.00000), "          ");
-        assert_eq!(print_progress_bar(10, 0.50000), "█████     ");
-        assert_eq!(print_progress_bar(10, 0.50624), "█████     ");
-        assert_eq!(print_progress_bar(10, 0.50626), "█████▏    ");
-        assert_eq!(print_progress_bar(10, 0.51250), "█████▏    ");
-        assert_eq!(print_progress_bar(10, 0.51874), "█████▏    ");
-        assert_eq!(print_progress_bar(10, 0.51876), "█████▎    ");
-        assert_eq!(print_progress_bar(10, 0.52500), "█████▎    ");
-        assert_eq!(print_progress_bar(10, 0.53124), "█████▎    ");
-        assert_eq!(print_progress_bar(10, 0.53126), "█████▍    ");
-        assert_eq!(print_progress_bar(10, 0.54374), "█████▍    ");
-        assert_eq!(print_progress_bar(10, 0.54376), "█████▌    ");
-        assert_eq!(print_progress_bar(10, 0.55624), "█████▌    ");
-        assert_eq!(print_progress_bar(10, 1.00000), "██████████");
-    }
-}
-
 impl super::Navigator {
     fn render_legend(&self, viewport: Rect) -> anyhow::Result<()> {
         let mut out = io::stdout();
@@ -583,10 +548,20 @@ impl super::Navigator {
             Print(&path),
         )?;
 
-        let mut w = path.width() as u16 + 3;
+        let mut w = path.width() + 3;
 
-        if self.node.children_have_conflicts() {
-            let cf = format!("    [{}]", node.children_conflicts());
+        let sizes = match self.aggregation {
+            fsync::stat::Aggregation::Exact => None,
+            fsync::stat::Aggregation::Partial => Some("  (sizes partial)"),
+            fsync::stat::Aggregation::Pending => Some("  (sizes pending)"),
+        };
+        if let Some(sizes) = sizes {
+            queue!(out, PrintStyledContent(sizes.with(Color::Grey).dim()))?;
+            w += sizes.len() as u16;
+        }
+
+        if self.node.has_conflicts_below() {
+            let cf = format!("    [{}]", node.conflicts_below());
             queue!(out, PrintStyledContent(cf.as_str().with(Color::Red)))?;
             w += cf.len() as u16;
         }
@@ -651,6 +626,7 @@ impl super::Navigator {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn render_child_node(
         &self,
         idx: usize,
@@ -677,17 +653,21 @@ impl super::Navigator {
             let mut w = 0;
             let tag = Tag::from(child.entry());
 
-            let mut spin = ' ';
+            // the pin mark is replaced by the spinner during operations
+            let mut spin = if self.is_pinned(child) { PIN_MARK } else { ' ' };
+            let mut spin_col = if self.is_pinned(child) {
+                PIN_COLOR
+            } else {
+                Color::Green
+            };
             let mut bar = None;
             for prog in progress {
                 if child.path() == prog.0 || child.path().is_ancestor_of(&prog.0) {
                     spin = state.spinner.get();
-                    match prog.1 {
-                        fsync::Progress::Progress { progress, total } => {
-                            let p = progress as f32 / total as f32;
-                            bar = Some(format!(" ║{}║ ", print_progress_bar(10, p)));
-                        }
-                        _ => (),
+                    spin_col = Color::Green;
+                    if let fsync::Progress::Progress { progress, total } = prog.1 {
+                        let p = progress as f32 / total as f32;
+                        bar = Some(format!(" ║{}║ ", print_progress_bar(10, p)));
                     }
                     break;
                 }
@@ -701,7 +681,7 @@ impl super::Navigator {
                 out,
                 abs_pos.move_to(),
                 tag.print(),
-                PrintStyledContent(spin.with(Color::Green)),
+                PrintStyledContent(spin.with(spin_col)),
                 Print(" ")
             )?;
             w += 3;
@@ -716,8 +696,8 @@ impl super::Navigator {
             };
             w += 2;
 
-            let mut conflict_str = if child.children_have_conflicts() {
-                Some(format!(" [{}]", child.children_conflicts()))
+            let mut conflict_str = if child.has_conflicts_below() {
+                Some(format!(" [{}]", child.conflicts_below()))
             } else {
                 None
             };
@@ -770,7 +750,7 @@ impl super::Navigator {
             let offset = 6u16;
             let blank = " ".repeat(offset as usize);
             for y in 0..vp.height() {
-                queue!(out, vp.move_to(Pos { x: 0, y: y as u16 }), Print(&blank),)?;
+                queue!(out, vp.move_to(Pos { x: 0, y }), Print(&blank),)?;
             }
             let vp = vp.crop_left(offset);
             self.render_child_details(child, &vp)?;
@@ -781,11 +761,44 @@ impl super::Navigator {
 
     fn render_child_details(&self, child: &EntryNode, viewport: &Rect) -> anyhow::Result<()> {
         let stat = child.stats();
-        self.render_stats(&viewport, &stat)?;
+        let implausible = self.implausible(child);
+        if let Entry::Sync {
+            local,
+            remote,
+            conflict: Some(conflict),
+        } = child.entry()
+        {
+            // the conflict replaces the two first lines of stats
+            let width = viewport.width() as usize;
+            let summary: String = conflict.summary().chars().take(width).collect();
+            let explanation: String = conflict
+                .explanation(local, remote)
+                .chars()
+                .take(width)
+                .collect();
+            let mut out = io::stdout();
+            queue!(
+                out,
+                viewport.move_to(Pos { x: 0, y: 0 }),
+                PrintStyledContent(summary.as_str().with(CONFLICT_COLOR)),
+                Print(" ".repeat(width - summary.width() as usize)),
+                viewport.move_to(Pos { x: 0, y: 1 }),
+                Print(&explanation),
+                Print(" ".repeat(width - explanation.width() as usize)),
+            )?;
+            self.render_stats(&viewport.crop_top(2), &stat, implausible)?;
+            return Ok(());
+        }
+        self.render_stats(viewport, &stat, implausible)?;
         Ok(())
     }
 
-    fn render_stats(&self, viewport: &Rect, stat: &fsync::stat::Tree) -> anyhow::Result<()> {
+    fn render_stats(
+        &self,
+        viewport: &Rect,
+        stat: &fsync::stat::Tree,
+        implausible: i64,
+    ) -> anyhow::Result<()> {
         debug_assert!(
             viewport.height() == 1 || viewport.height() == 3,
             "only 1 or 3 lines are supported"
@@ -799,24 +812,24 @@ impl super::Navigator {
 
         fn dir_stat(stat: &fsync::stat::Dir, len_tag: u16) -> String {
             match len_tag {
-                SHORT => format!("{data:.1}", data = utils::adjusted_byte(stat.data as _),),
+                SHORT => human_bytes(stat.data.max(0) as u64, Unit::Binary),
                 MEDIUM => format!(
-                    "d:{dirs} f:{files} {data:.1}",
+                    "d:{dirs} f:{files} {data}",
                     dirs = stat.dirs,
                     files = stat.files,
-                    data = utils::adjusted_byte(stat.data as _),
+                    data = human_bytes(stat.data.max(0) as u64, Unit::Binary),
                 ),
                 LONG => format!(
-                    "dirs:{dirs}  files:{files}  data:{data:.2}",
+                    "dirs:{dirs}  files:{files}  data:{data}",
                     dirs = stat.dirs,
                     files = stat.files,
-                    data = utils::adjusted_byte(stat.data as _),
+                    data = human_bytes(stat.data.max(0) as u64, Unit::Binary),
                 ),
                 _ => unreachable!(),
             }
         }
 
-        fn node_stat(name: &str, stat: i32, len_tag: u16) -> String {
+        fn node_stat(name: &str, stat: i64, len_tag: u16) -> String {
             match len_tag {
                 SHORT => format!("{}", stat),
                 MEDIUM => format!("{}", stat),
@@ -825,6 +838,16 @@ impl super::Navigator {
             }
         }
 
+        // the entries with corrupt metadata are reported after the conflicts, when there are some
+        fn conflicts_stat(stat: &fsync::stat::Node, implausible: i64, len_tag: u16) -> String {
+            let conflicts = node_stat("conflicts", stat.conflicts, len_tag);
+            match (implausible, len_tag) {
+                (0, _) => conflicts,
+                (implausible, LONG) => format!("{conflicts}  implausible:{implausible}"),
+                (implausible, _) => format!("{conflicts} !{implausible}"),
+            }
+        }
+
         let sep1 = " | ";
         let sep3 = "  ";
         let sep = if viewport.height() == 1 { sep1 } else { sep3 };
@@ -836,7 +859,7 @@ impl super::Navigator {
             let remote = dir_stat(&stat.remote, len_tag);
             let nodes = node_stat("nodes", stat.node.nodes, len_tag);
             let sync = node_stat("sync", stat.node.sync, len_tag);
-            let conflicts = node_stat("conflicts", stat.node.conflicts, len_tag);
+            let conflicts = conflicts_stat(&stat.node, implausible, len_tag);
 
             let fits = if viewport.height() == 3 {
                 local.width() <= viewport.width()
@@ -927,7 +950,7 @@ impl super::Navigator {
                 queue!(
                     out,
                     Print(
-                        " ".repeat((viewport.width() - len as u16 - 12) as usize)
+                        " ".repeat(viewport.width().saturating_sub(len + 12) as usize)
                             .as_str(),
                     )
                 )?;
@@ -935,4 +958,48 @@ impl super::Navigator {
         }
         Ok(())
     }
+
+    fn render_quota(&self, viewport: &Rect, stats: &fsync::InstanceStats) -> anyhow::Result<()> {
+        debug_assert!(viewport.width() == QUOTA_WIDTH);
+
+        let mut out = io::stdout();
+
+        let pct = stats.quota.and_then(|q| q.percent()).unwrap_or(0.0);
+        let color = if stats.quota_warning {
+            QUOTA_WARNING_COLOR
+        } else {
+            REMOTE_COLOR
+        };
+        let bar = print_progress_bar(10, pct as f32 / 100.0);
+        queue!(
+            out,
+            viewport.move_to(Pos { x: 0, y: 0 }),
+            Print(" | "),
+            PrintStyledContent(format!("quota ║{bar}║{pct:>4.0}%").with(color)),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::print_progress_bar;
+
+    #[test]
+    fn test_progress_bar() {
+        assert_eq!(print_progress_bar(10, 0.00000), "          ");
+        assert_eq!(print_progress_bar(10, 0.50000), "█████     ");
+        assert_eq!(print_progress_bar(10, 0.50624), "█████     ");
+        assert_eq!(print_progress_bar(10, 0.50626), "█████▏    ");
+        assert_eq!(print_progress_bar(10, 0.51250), "█████▏    ");
+        assert_eq!(print_progress_bar(10, 0.51874), "█████▏    ");
+        assert_eq!(print_progress_bar(10, 0.51876), "█████▎    ");
+        assert_eq!(print_progress_bar(10, 0.52500), "█████▎    ");
+        assert_eq!(print_progress_bar(10, 0.53124), "█████▎    ");
+        assert_eq!(print_progress_bar(10, 0.53126), "█████▍    ");
+        assert_eq!(print_progress_bar(10, 0.54374), "█████▍    ");
+        assert_eq!(print_progress_bar(10, 0.54376), "█████▌    ");
+        assert_eq!(print_progress_bar(10, 0.55624), "█████▌    ");
+        assert_eq!(print_progress_bar(10, 1.00000), "██████████");
+    }
 }
@@ -2,7 +2,7 @@ use fsync::{
     loc::{inst, user},
     path::FsPathBuf,
 };
-use fsync_client::config::ProviderOpts;
+use fsync_client::config::{validate, ProviderOpts};
 use inquire::{
     validator::{ErrorMessage, Validation},
     Confirm, CustomUserError, Select, Text,
@@ -29,6 +29,12 @@ pub struct Args {
     /// The directory to synchronize on the local file system
     #[clap(long, short = 'p')]
     local_dir: Option<FsPathBuf>,
+
+    /// Encrypt the file contents before they reach the remote storage.
+    /// The daemon prompts the passphrase when it starts, or reads it from
+    /// the FSYNCD_CONTENT_PASSPHRASE environment variable.
+    #[clap(long)]
+    encrypt: bool,
 }
 
 pub async fn main(args: Args) -> anyhow::Result<()> {
@@ -42,9 +48,14 @@ pub async fn main(args: Args) -> anyhow::Result<()> {
             .prompt()?
     };
 
-    println!("Creating new synchronized file service: `{name}`");
+    log::info!("Creating new synchronized file service: `{name}`");
 
     let config_dir = inst::config_dir(&name)?;
+    if config_dir.exists() && !inst::config_file(&name)?.exists() {
+        anyhow::bail!(
+            "{config_dir} is left over by a failed creation, run `fsynctl doctor -n {name}` to delete it"
+        );
+    }
     if config_dir.exists() {
         anyhow::bail!("Configuration already exists: {config_dir}");
     }
@@ -67,19 +78,20 @@ pub async fn main(args: Args) -> anyhow::Result<()> {
     });
     let provider = provider.await.unwrap()?;
 
+    if args.encrypt && !matches!(provider, fsync::Provider::GoogleDrive) {
+        anyhow::bail!("The encryption of the contents requires the Google Drive provider");
+    }
+
     let opts = prompt_provider_opts(provider).await?;
 
-    let create_res = fsync_client::config::create(&name, &local_dir, &opts).await;
-    match create_res {
-        Ok(()) => {
-            println!("Success!");
-        }
-        Err(_) => {
-            if config_dir.exists() {
-                println!("Deleting {config_dir} because of error");
-                std::fs::remove_dir_all(config_dir)?;
-            }
-        }
+    // nothing is left behind on error, and the directory of a concurrent creation is not ours
+    fsync_client::config::create(&name, &local_dir, &opts, args.encrypt).await?;
+    log::info!("Success!");
+    if args.encrypt {
+        println!(
+            "The contents will be encrypted with the passphrase chosen at the first start of the daemon.\n\
+             Without it, the remote contents can't be recovered."
+        );
     }
 
     if !local_dir.exists() {
@@ -100,9 +112,7 @@ async fn prompt_provider_opts(provider: fsync::Provider) -> anyhow::Result<Provi
     }
 }
 
-fn validate_chars(mut invalid_chars: Vec<&str>) -> Result<Validation, CustomUserError> {
-    invalid_chars.sort_unstable();
-    invalid_chars.dedup();
+fn validate_chars(invalid_chars: Vec<&str>) -> Result<Validation, CustomUserError> {
     if invalid_chars.is_empty() {
         Ok(Validation::Valid)
     } else {
@@ -113,23 +123,8 @@ fn validate_chars(mut invalid_chars: Vec<&str>) -> Result<Validation, CustomUser
     }
 }
 
-fn validate_name(input: &str) -> Result<Validation, CustomUserError> {
-    let mut invalid_chars = Vec::new();
-    for c in input.as_bytes() {
-        match *c {
-            b'/' => invalid_chars.push("/"),
-            b'\\' => invalid_chars.push("\\"),
-            b'<' => invalid_chars.push("<"),
-            b'>' => invalid_chars.push(">"),
-            b':' => invalid_chars.push(":"),
-            b'|' => invalid_chars.push("|"),
-            b'?' => invalid_chars.push("?"),
-            b'*' => invalid_chars.push("*"),
-            0..=31 => invalid_chars.push("<ctrl>"),
-            _ => (),
-        }
-    }
-    validate_chars(invalid_chars)
+pub(crate) fn validate_name(input: &str) -> Result<Validation, CustomUserError> {
+    validate_chars(validate::invalid_name_chars(input))
 }
 
 fn map_error_message(msg: ErrorMessage) -> anyhow::Error {
@@ -138,7 +133,9 @@ fn map_error_message(msg: ErrorMessage) -> anyhow::Error {
         ErrorMessage::Custom(msg) => anyhow::anyhow!("{msg}"),
     }
 }
-fn map_validation_result(res: anyhow::Result<Validation, CustomUserError>) -> anyhow::Result<()> {
+pub(crate) fn map_validation_result(
+    res: anyhow::Result<Validation, CustomUserError>,
+) -> anyhow::Result<()> {
     match res {
         Ok(Validation::Valid) => Ok(()),
         Ok(Validation::Invalid(msg)) => Err(map_error_message(msg)),
@@ -146,24 +143,6 @@ fn map_validation_result(res: anyhow::Result<Validation, CustomUserError>) -> an
     }
 }
 
-fn validate_path(input: &str) -> Result<Validation, CustomUserError> {
-    let mut invalid_chars = Vec::new();
-    for c in input.as_bytes() {
-        match *c {
-            b'<' => invalid_chars.push("<"),
-            b'>' => invalid_chars.push(">"),
-            b'|' => invalid_chars.push("|"),
-            b'?' => invalid_chars.push("?"),
-            b'*' => invalid_chars.push("*"),
-            0..=31 => invalid_chars.push("<ctrl>"),
-
-            #[cfg(not(target_os = "windows"))]
-            b':' => invalid_chars.push(":"),
-            #[cfg(not(target_os = "windows"))]
-            b'\\' => invalid_chars.push("\\"),
-
-            _ => (),
-        }
-    }
-    validate_chars(invalid_chars)
+pub(crate) fn validate_path(input: &str) -> Result<Validation, CustomUserError> {
+    validate_chars(validate::invalid_path_chars(input))
 }
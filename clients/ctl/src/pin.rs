@@ -0,0 +1,56 @@
+use fsync::path::PathBuf;
+use fsync_client::utils::ctx;
+
+use crate::utils::{self, Format};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Pin an entry, so that it is never deleted nor overwritten
+    Add {
+        /// Path of the entry to pin
+        path: PathBuf,
+    },
+    /// Unpin an entry
+    Remove {
+        /// Path of the entry to unpin
+        path: PathBuf,
+    },
+    /// List the pinned entries
+    List,
+}
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let client = utils::instance_client(&instance_name).await?;
+    match args.command {
+        Command::Add { path } => {
+            client.set_pinned(ctx(), path.clone(), true).await??;
+            println!("{path} pinned");
+        }
+        Command::Remove { path } => {
+            client.set_pinned(ctx(), path.clone(), false).await??;
+            println!("{path} unpinned");
+        }
+        Command::List => {
+            let pinned = client.pinned(ctx()).await??;
+            if format == Format::Json {
+                return utils::print_json(&pinned);
+            }
+            for path in pinned {
+                println!("{path}");
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use fsync::path::PathBuf;
+use fsync_client::utils::ctx;
+
+use crate::utils::{self, Format};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    /// Rescan the sub-directories as well
+    #[clap(long, short = 'd')]
+    deep: bool,
+
+    /// Path to the local directory to rescan
+    path: Option<PathBuf>,
+}
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let client = utils::instance_client(&instance_name).await?;
+    let path = args.path.unwrap_or_else(PathBuf::root);
+    let report = client.rescan(ctx(), path.clone(), args.deep).await??;
+
+    if format == Format::Json {
+        return utils::print_json(&report);
+    }
+    for path in &report.added {
+        println!("A {path}");
+    }
+    for path in &report.modified {
+        println!("M {path}");
+    }
+    for path in &report.removed {
+        println!("D {path}");
+    }
+    if report.is_empty() {
+        println!("No local change in {path}");
+    }
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use fsync::{path::PathBuf, Action, Operation, Progress};
+use fsync_client::utils::ctx;
+
+use crate::utils;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    /// Storage from which the entries are restored
+    #[clap(long, value_enum, default_value_t = From::Remote)]
+    from: From,
+
+    /// Print the actions without performing them
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Path to the entry to restore
+    path: PathBuf,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum From {
+    /// Restore the local entries from the remote storage
+    Remote,
+    /// Restore the remote entries from the local storage
+    Local,
+}
+
+pub async fn main(args: Args) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let client = utils::instance_client(&instance_name).await?;
+    let path = args.path;
+    let operation = match args.from {
+        From::Remote => Operation::RestoreLocal(path.clone()),
+        From::Local => Operation::RestoreRemote(path.clone()),
+    };
+
+    if args.dry_run {
+        let plan = client.plan(ctx(), operation).await??;
+        let mut count = 0;
+        loop {
+            let actions = client.plan_next(ctx(), plan, 100).await??;
+            if actions.is_empty() {
+                break;
+            }
+            for planned in actions {
+                let what = match &planned.action {
+                    Action::Mkdir(..) => "mkdir".to_string(),
+                    Action::Copy(..) => "copy".to_string(),
+                    Action::Replace(..) => "replace".to_string(),
+                    Action::SkipTooLarge => "skip (too large)".to_string(),
+                    Action::SkipWithheld => "skip (withheld by the sync mode)".to_string(),
+                    Action::Fail(err) => format!("fail: {err}"),
+                    action => format!("{action:?}"),
+                };
+                println!("{what} {}", planned.path);
+                count += 1;
+            }
+        }
+        println!("{count} actions planned");
+        return Ok(());
+    }
+
+    let mut progress = client.operate(ctx(), operation).await??;
+    loop {
+        match &progress {
+            Progress::Done | Progress::DoneWithReport(..) => break,
+            Progress::Err(err) => anyhow::bail!(err.clone()),
+            Progress::Failed(failure) => anyhow::bail!("{failure}"),
+            _ => (),
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        match client.progress(ctx(), path.clone()).await?? {
+            Some(p) => progress = p,
+            None => break,
+        }
+    }
+    utils::check_failures(&client, &path, progress.report()).await?;
+    println!("{path} restored");
+    Ok(())
+}
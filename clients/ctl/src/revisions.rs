@@ -0,0 +1,91 @@
+use chrono::Local;
+use fsync::{
+    fmt::{human_bytes, Unit},
+    path::PathBuf,
+    Revision,
+};
+use fsync_client::utils::ctx;
+
+use crate::utils::{self, Format};
+
+#[derive(clap::Args, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n', global = true)]
+    instance_name: Option<String>,
+
+    /// Path of the remote file to list the revisions of
+    path: Option<PathBuf>,
+
+    /// Maximum number of revisions listed, the most recent first
+    #[clap(long, default_value_t = 20)]
+    max: u32,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Download a revision to a new local file, next to the file by default
+    Get {
+        /// Path of the remote file
+        path: PathBuf,
+        /// Id of the revision, as listed
+        id: String,
+        /// Path of the new local file
+        #[clap(long)]
+        dest: Option<PathBuf>,
+    },
+}
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let client = utils::instance_client(&instance_name).await?;
+    match (args.command, args.path) {
+        (Some(Command::Get { path, id, dest }), _) => {
+            let dest = match dest {
+                Some(dest) => dest,
+                None => {
+                    let revisions = client
+                        .remote_revisions(ctx(), path.clone(), u32::MAX)
+                        .await??;
+                    let Some(revision) = revisions.iter().find(|r| r.id == id) else {
+                        anyhow::bail!("No revision {id} of {path}");
+                    };
+                    revision.default_dest(&path)
+                }
+            };
+            let metadata = client
+                .download_revision(ctx(), path.clone(), id.clone(), dest)
+                .await??;
+            println!("revision {id} of {path} downloaded to {}", metadata.path());
+        }
+        (None, Some(path)) => {
+            let revisions = client.remote_revisions(ctx(), path, args.max).await??;
+            if format == Format::Json {
+                return utils::print_json(&revisions);
+            }
+            for revision in revisions {
+                print_revision(&revision);
+            }
+        }
+        (None, None) => anyhow::bail!("Expected the path of a remote file"),
+    }
+    Ok(())
+}
+
+fn print_revision(revision: &Revision) {
+    let time = revision
+        .mtime
+        .with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S");
+    let size = revision
+        .size
+        .map(|size| human_bytes(size, Unit::Binary))
+        .unwrap_or_else(|| "-".to_string());
+    let author = revision.author.as_deref().unwrap_or_default();
+    println!("{id}  {time}  {size:>10}  {author}", id = revision.id);
+}
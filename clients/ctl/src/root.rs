@@ -0,0 +1,105 @@
+use fsync::MigrationChoice;
+use fsync_client::utils::ctx;
+
+use crate::{
+    exit,
+    utils::{self, Format},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Print whether the remote root of the config changed since the last synchronization
+    Status,
+    /// Proceed after a change of the remote root
+    Migrate {
+        #[clap(value_enum)]
+        choice: Choice,
+        /// Do not ask for confirmation before clearing the local directory
+        #[clap(long)]
+        yes: bool,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Choice {
+    /// Pair the local directory with the new root, the local files winning the conflicts
+    KeepLocal,
+    /// Delete the content of the local directory, to download the new root afresh
+    ResetLocal,
+    /// Write the previous root back in the config, and restart the daemon to use it
+    RevertConfig,
+}
+
+impl From<Choice> for MigrationChoice {
+    fn from(value: Choice) -> Self {
+        match value {
+            Choice::KeepLocal => MigrationChoice::KeepLocal,
+            Choice::ResetLocal => MigrationChoice::ResetLocal,
+            Choice::RevertConfig => MigrationChoice::RevertConfig,
+        }
+    }
+}
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let client = utils::instance_client(&instance_name).await?;
+    let change = client.root_change(ctx()).await??;
+    match args.command {
+        Command::Status => {
+            if format == Format::Json {
+                return utils::print_json(&change);
+            }
+            match change {
+                Some(change) => {
+                    println!("The remote root changed");
+                    println!("  synchronized with: {}", change.recorded);
+                    println!("  configured:        {}", change.resolved);
+                    println!("Run `fsynctl root migrate` to choose how to proceed");
+                }
+                None => println!("The remote root did not change"),
+            }
+        }
+        Command::Migrate { choice, yes } => {
+            let Some(change) = change else {
+                anyhow::bail!("The remote root of {instance_name} did not change");
+            };
+            if matches!(choice, Choice::ResetLocal) && !yes {
+                let local_dir = client.local_path(ctx(), None).await??;
+                let question = format!(
+                    "Delete the content of {local_dir} to download {} afresh?",
+                    change.resolved.config
+                );
+                if !utils::ask(&question)? {
+                    return Err(exit::aborted());
+                }
+            }
+            client.migrate_root(ctx(), choice.into()).await??;
+            match choice {
+                Choice::KeepLocal => println!(
+                    "Paired with {}, the conflicts are being resolved with the local files",
+                    change.resolved
+                ),
+                Choice::ResetLocal => println!(
+                    "Paired with {}, the local directory was cleared",
+                    change.resolved
+                ),
+                Choice::RevertConfig => println!(
+                    "The config was reverted to {}, restart the daemon to use it",
+                    change.recorded.config
+                ),
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,328 @@
+use chrono::Utc;
+use fsync::fmt::{human_bytes, human_mtime, Unit};
+use fsync_client::utils::ctx;
+use serde::Serialize;
+
+use crate::utils::{self, Format};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    /// Print the lifetime counters in the Prometheus text exposition format
+    #[clap(long)]
+    prometheus: bool,
+
+    /// Print the percentiles of the durations of the expensive phases of the daemon,
+    /// recorded when it is started with --timings or --profile
+    #[clap(long, conflicts_with = "prometheus")]
+    timings: bool,
+}
+
+/// The counters printed in JSON, with the transfers by cause and the repairs
+#[derive(Serialize)]
+struct Report {
+    #[serde(flatten)]
+    counters: fsync::InstanceCounters,
+    transfers: fsync::InstanceTransfers,
+    repairs: fsync::InstanceRepairs,
+}
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let client = utils::instance_client(&instance_name).await?;
+    if args.timings {
+        let timings = client.timings(ctx()).await??;
+        if format == Format::Json {
+            return utils::print_json(&timings);
+        }
+        if !timings.enabled {
+            println!("The timings are not recorded, start fsyncd with --timings or --profile");
+            return Ok(());
+        }
+        print!("{}", timings_table(&timings));
+        return Ok(());
+    }
+    let counters = client.counters(ctx()).await??;
+    let transfers = client.transfers(ctx()).await??;
+    let repairs = client.repairs(ctx()).await??;
+    if args.prometheus {
+        print!(
+            "{}",
+            prometheus(
+                &instance_name,
+                &counters.lifetime,
+                repairs.lifetime,
+                &transfers.lifetime
+            )
+        );
+        return Ok(());
+    }
+    if format == Format::Json {
+        return utils::print_json(&Report {
+            counters,
+            transfers,
+            repairs,
+        });
+    }
+
+    let now = Utc::now();
+    println!(
+        "{:<18} {:>14} {:>14}",
+        "",
+        format!("since {}", human_mtime(counters.boot, now)),
+        format!("since {}", human_mtime(counters.lifetime_start, now)),
+    );
+    let (boot, lifetime) = (&counters.since_boot, &counters.lifetime);
+    let bytes = |b| human_bytes(b, Unit::Binary);
+    let rows = [
+        (
+            "uploaded",
+            bytes(boot.bytes_uploaded),
+            bytes(lifetime.bytes_uploaded),
+        ),
+        (
+            "downloaded",
+            bytes(boot.bytes_downloaded),
+            bytes(lifetime.bytes_downloaded),
+        ),
+        (
+            "operations",
+            boot.operations.to_string(),
+            lifetime.operations.to_string(),
+        ),
+        (
+            "failures",
+            boot.failures.to_string(),
+            lifetime.failures.to_string(),
+        ),
+        (
+            "retries",
+            boot.retries.to_string(),
+            lifetime.retries.to_string(),
+        ),
+        (
+            "repairs",
+            repairs.since_boot.to_string(),
+            repairs.lifetime.to_string(),
+        ),
+    ];
+    for (name, boot, lifetime) in rows {
+        println!("{name:<18} {boot:>14} {lifetime:>14}");
+    }
+    print!("\n{}", transfers_table(&transfers.lifetime));
+    if let Some(buffers) = client.buffer_usage(ctx()).await?? {
+        println!(
+            "\nbuffers: {} of {} in use, {} transfers waiting",
+            bytes(buffers.in_use),
+            bytes(buffers.budget),
+            buffers.waiters
+        );
+    }
+    Ok(())
+}
+
+/// One row per phase, with the durations in milliseconds
+fn timings_table(timings: &fsync::Timings) -> String {
+    let ms = |us: u64| format!("{:.1}", us as f64 / 1000.0);
+    let mut table = format!(
+        "{:<16} {:>8} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+        "phase", "count", "p50 ms", "p90 ms", "p99 ms", "max ms", "bytes"
+    );
+    for phase in &timings.phases {
+        table.push_str(&format!(
+            "{:<16} {:>8} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+            phase.phase,
+            phase.count,
+            ms(phase.p50_us),
+            ms(phase.p90_us),
+            ms(phase.p99_us),
+            ms(phase.max_us),
+            human_bytes(phase.bytes, Unit::Binary),
+        ));
+    }
+    table
+}
+
+/// One row per cause of the lifetime transfers, with the retried bytes apart
+fn transfers_table(transfers: &fsync::Transfers) -> String {
+    let bytes = |b| human_bytes(b, Unit::Binary);
+    let mut table = format!(
+        "{:<18} {:>8} {:>14} {:>14}\n",
+        "transferred by", "files", "bytes", "retried"
+    );
+    for cause in fsync::TransferCause::ALL {
+        let counters = transfers.get(cause);
+        table.push_str(&format!(
+            "{:<18} {:>8} {:>14} {:>14}\n",
+            cause.to_string(),
+            counters.files,
+            bytes(counters.bytes),
+            bytes(counters.retried_bytes),
+        ));
+    }
+    table
+}
+
+/// The name, the help and the value of a metric of the transfers of a cause
+type TransferMetric = (
+    &'static str,
+    &'static str,
+    fn(&fsync::TransferCounters) -> u64,
+);
+
+/// The lifetime `counters`, `repairs` and `transfers` of `instance` in the Prometheus text
+/// exposition format, to be served by a textfile collector or any other exporter
+fn prometheus(
+    instance: &str,
+    counters: &fsync::Counters,
+    repairs: u64,
+    transfers: &fsync::Transfers,
+) -> String {
+    let metrics = [
+        (
+            "bytes_uploaded",
+            "Bytes written to the remote storage",
+            counters.bytes_uploaded,
+        ),
+        (
+            "bytes_downloaded",
+            "Bytes written to the local storage from the remote storage",
+            counters.bytes_downloaded,
+        ),
+        (
+            "operations",
+            "Unit operations that succeeded",
+            counters.operations,
+        ),
+        ("failures", "Unit operations that failed", counters.failures),
+        (
+            "retries",
+            "Operations retried after a transient error",
+            counters.retries,
+        ),
+        (
+            "repairs",
+            "Conflicts between identical files marked as synchronized",
+            repairs,
+        ),
+    ];
+    let instance = instance.replace('\\', "\\\\").replace('"', "\\\"");
+    let mut text = String::new();
+    for (name, help, value) in metrics {
+        let name = format!("fsync_{name}_total");
+        text.push_str(&format!("# HELP {name} {help}\n"));
+        text.push_str(&format!("# TYPE {name} counter\n"));
+        text.push_str(&format!("{name}{{instance=\"{instance}\"}} {value}\n"));
+    }
+    let transfer_metrics: [TransferMetric; 3] = [
+        (
+            "transfer_files",
+            "Files transferred, once per direction, by cause",
+            |c| c.files,
+        ),
+        (
+            "transfer_bytes",
+            "Bytes transferred at the first attempt, by cause",
+            |c| c.bytes,
+        ),
+        (
+            "transfer_retried_bytes",
+            "Bytes of content sent again after a failure, by cause",
+            |c| c.retried_bytes,
+        ),
+    ];
+    for (name, help, value) in transfer_metrics {
+        let name = format!("fsync_{name}_total");
+        text.push_str(&format!("# HELP {name} {help}\n"));
+        text.push_str(&format!("# TYPE {name} counter\n"));
+        for cause in fsync::TransferCause::ALL {
+            let value = value(transfers.get(cause));
+            text.push_str(&format!(
+                "{name}{{instance=\"{instance}\",cause=\"{cause}\"}} {value}\n"
+            ));
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_exposition() {
+        let counters = fsync::Counters {
+            bytes_uploaded: 1024,
+            retries: 3,
+            ..Default::default()
+        };
+        let text = prometheus("my\"drive", &counters, 2, &fsync::Transfers::default());
+        assert!(text.contains("# TYPE fsync_bytes_uploaded_total counter\n"));
+        assert!(text.contains("fsync_bytes_uploaded_total{instance=\"my\\\"drive\"} 1024\n"));
+        assert!(text.contains("fsync_retries_total{instance=\"my\\\"drive\"} 3\n"));
+        assert!(text.contains("fsync_failures_total{instance=\"my\\\"drive\"} 0\n"));
+        assert!(text.contains("fsync_repairs_total{instance=\"my\\\"drive\"} 2\n"));
+    }
+
+    #[test]
+    fn transfers_by_cause() {
+        let transfers = fsync::Transfers {
+            conflict: fsync::TransferCounters {
+                files: 2,
+                bytes: 2048,
+                retried_bytes: 512,
+            },
+            ..Default::default()
+        };
+        let text = prometheus("drive", &fsync::Counters::default(), 0, &transfers);
+        assert!(text.contains("# TYPE fsync_transfer_bytes_total counter\n"));
+        assert!(text
+            .contains("fsync_transfer_bytes_total{instance=\"drive\",cause=\"conflict\"} 2048\n"));
+        assert!(text.contains(
+            "fsync_transfer_retried_bytes_total{instance=\"drive\",cause=\"conflict\"} 512\n"
+        ));
+        assert!(text.contains("fsync_transfer_files_total{instance=\"drive\",cause=\"sync\"} 0\n"));
+
+        let table = transfers_table(&transfers);
+        let row = table.lines().find(|l| l.starts_with("conflict")).unwrap();
+        let cols: Vec<_> = row.split_whitespace().collect();
+        assert_eq!(cols, ["conflict", "2", "2.0", "KiB", "512", "B"]);
+        assert_eq!(table.lines().count(), 1 + fsync::TransferCause::ALL.len());
+    }
+
+    #[test]
+    fn timings_in_milliseconds() {
+        let timings = fsync::Timings {
+            enabled: true,
+            phases: vec![fsync::PhaseTimings {
+                phase: "drive.http".into(),
+                count: 12,
+                bytes: 2048,
+                p50_us: 1500,
+                p90_us: 20_000,
+                p99_us: 20_250,
+                max_us: 31_000,
+            }],
+        };
+        let table = timings_table(&timings);
+        let row = table.lines().nth(1).unwrap();
+        let cols: Vec<_> = row.split_whitespace().collect();
+        assert_eq!(
+            cols,
+            [
+                "drive.http",
+                "12",
+                "1.5",
+                "20.0",
+                "20.2",
+                "31.0",
+                "2.0",
+                "KiB"
+            ]
+        );
+    }
+}
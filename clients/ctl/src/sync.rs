@@ -0,0 +1,265 @@
+use std::time::Duration;
+
+use fsync::{
+    filter::EntryFilter, path::PathBuf, tree::Entry, OperateOptions, Operation, OrderBy, Progress,
+    ResolutionMethod,
+};
+use fsync_client::utils::ctx;
+
+use crate::{
+    conflicts::{self, ConflictType},
+    exit, utils,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    /// Order in which the entries of each directory are synchronized
+    #[clap(long, value_enum, default_value_t = Order::Tree)]
+    order: Order,
+
+    /// Synchronize the files larger than the size limit instead of skipping them
+    #[clap(long)]
+    force_large: bool,
+
+    /// Run as a scheduled sync, e.g. from a timer: the sync waits while the deferral policy
+    /// of the instance postpones the scheduled work
+    #[clap(long)]
+    scheduled: bool,
+
+    /// Resume the scheduled sync stopped by its time budget, from the entries it left,
+    /// and without time budget
+    #[clap(long, conflicts_with_all = ["scheduled", "merge_text", "path"])]
+    resume: bool,
+
+    /// Merge the concurrent edits of the small text files in conflict before synchronizing,
+    /// from the version last synchronized. The files whose edits overlap stay in conflict.
+    #[clap(long)]
+    merge_text: bool,
+
+    /// Only synchronize the entries matching this glob, anchored to the synchronized path
+    /// (e.g. '*.docx' or 'reports/'). Can be repeated, the last matching glob decides
+    /// and a glob starting with '!' leaves its matches out.
+    #[clap(long = "filter", value_name = "GLOB", conflicts_with = "resume")]
+    filters: Vec<String>,
+
+    /// Only synchronize the conflicts of this kind. Can be repeated.
+    #[clap(
+        long = "conflict-type",
+        value_enum,
+        value_name = "KIND",
+        conflicts_with = "resume"
+    )]
+    conflict_types: Vec<ConflictType>,
+
+    /// Path to the entry to synchronize (the whole tree by default)
+    path: Option<PathBuf>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Order {
+    /// Alphabetical order
+    Tree,
+    /// Most recently modified files first
+    Newest,
+    /// Smallest entries first
+    Smallest,
+    /// Largest entries first
+    Largest,
+}
+
+impl From<Order> for OrderBy {
+    fn from(value: Order) -> Self {
+        match value {
+            Order::Tree => OrderBy::TreeOrder,
+            Order::Newest => OrderBy::NewestFirst,
+            Order::Smallest => OrderBy::SmallestFirst,
+            Order::Largest => OrderBy::LargestFirst,
+        }
+    }
+}
+
+pub async fn main(args: Args) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let client = utils::instance_client(&instance_name).await?;
+
+    if args.resume {
+        return resume(&client).await;
+    }
+
+    let path = args.path.unwrap_or_else(PathBuf::root);
+    let filtered = !args.filters.is_empty() || !args.conflict_types.is_empty();
+    let filter = conflicts::entry_filter(Some(path.clone()), args.filters, &args.conflict_types);
+    if args.merge_text {
+        merge_text(&client, &filter).await?;
+    }
+    let operation = match args.order.into() {
+        OrderBy::TreeOrder => Operation::SyncDeep(path.clone()),
+        order => Operation::SyncDeepOrdered(path.clone(), order),
+    };
+
+    let filter = filtered.then_some(filter);
+    let options = OperateOptions {
+        force_large: args.force_large,
+        filter,
+        ..Default::default()
+    };
+    let progress = if args.scheduled {
+        client
+            .operate_scheduled(ctx(), operation, options)
+            .await??
+    } else {
+        client.operate_with(ctx(), operation, options).await??
+    };
+    follow(&client, path, progress).await
+}
+
+/// Resume the operation left in the checkpoint of the instance
+async fn resume(client: &utils::Client) -> anyhow::Result<()> {
+    let Some(checkpoint) = client.status(ctx()).await??.checkpoint else {
+        println!("No sync to resume");
+        return Ok(());
+    };
+    let path = checkpoint.operation.path().to_owned();
+    println!(
+        "Resuming the sync of {path}: {} entries left",
+        checkpoint.remaining
+    );
+    let progress = client.resume(ctx()).await??;
+    follow(client, path, progress).await
+}
+
+/// Poll the progress of the sync of `path` until it completes, and print its report
+async fn follow(
+    client: &utils::Client,
+    path: PathBuf,
+    mut progress: Progress,
+) -> anyhow::Result<()> {
+    let mut prompt_shown = false;
+    let mut waiting = None;
+    loop {
+        match &progress {
+            Progress::Waiting(reason) if waiting.as_ref() != Some(reason) => {
+                println!("{reason}");
+                waiting = Some(reason.clone());
+            }
+            Progress::Done | Progress::DoneWithReport(..) => break,
+            Progress::Err(err) => anyhow::bail!(err.clone()),
+            Progress::Failed(failure) => anyhow::bail!("{failure}"),
+            Progress::OAuth2DeviceCode { url, code } if !prompt_shown => {
+                prompt_shown = true;
+                println!("To authorize fsyncd, visit {url} and enter the code {code}");
+            }
+            Progress::OAuth2Browse(prompt) if !prompt_shown => {
+                prompt_shown = true;
+                utils::print_auth_prompt(prompt);
+            }
+            _ => (),
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        match client.progress(ctx(), path.clone()).await?? {
+            Some(p) => progress = p,
+            None => break,
+        }
+    }
+
+    let report = progress.report();
+    let node = client
+        .entry_node_page(ctx(), path.clone(), None, 0)
+        .await??;
+    let conflicts = node
+        .as_ref()
+        .map_or(0, |node| node.stats().node.conflicts.max(0) as usize);
+    // the entries left in conflict are also counted as failed, their exit code prevails
+    if report.map_or(0, |report| report.failed as usize) > conflicts {
+        utils::check_failures(client, &path, report).await?;
+    }
+    // the report is dropped by the daemon shortly after the operation completes
+    let checkpoint = client.status(ctx()).await??.checkpoint;
+    match checkpoint.filter(|c| c.operation.path() == path) {
+        Some(checkpoint) => println!(
+            "{path} partially synchronized: the time budget elapsed with {} entries left, use --resume to continue",
+            checkpoint.remaining
+        ),
+        None => println!("{path} synchronized"),
+    }
+    if let Some(withheld) = report.map(|r| r.skipped_withheld).filter(|w| *w > 0) {
+        let mode = client.instance_stats(ctx()).await??.sync_mode;
+        println!("{withheld} entries left out of sync by the {mode} mode of the instance");
+    }
+    if let Some(refused) = report
+        .map(|r| r.skipped_not_downloadable)
+        .filter(|n| *n > 0)
+    {
+        println!("{refused} files skipped as they can be viewed but not downloaded");
+    }
+    let too_large = client.too_large_stats(ctx(), vec![path.clone()]).await??;
+    if let Some(too_large) = too_large.first().map(|stat| stat.count).filter(|n| *n > 0) {
+        println!(
+            "{too_large} files skipped for being larger than the size limit, use --force-large to synchronize them"
+        );
+    }
+    if conflicts > 0 {
+        return Err(exit::conflicts(conflicts, path));
+    }
+    Ok(())
+}
+
+/// Merge the text files in conflict selected by `filter`
+async fn merge_text(client: &utils::Client, filter: &EntryFilter) -> anyhow::Result<()> {
+    let mut mergeable = Vec::new();
+    let mut first: Option<PathBuf> = None;
+    loop {
+        // the pages start at the last entry of the previous one
+        let mut page = client
+            .conflicts_filtered(ctx(), first.clone(), 100, filter.clone())
+            .await??;
+        let len = page.len();
+        page.retain(|entry| Some(entry.path()) != first.as_deref());
+        let Some(last) = page.last() else {
+            break;
+        };
+        first = Some(last.path().to_owned());
+        mergeable.extend(page.into_iter().filter_map(|entry| match entry {
+            Entry::Sync { local, remote, .. } if fsync::text::is_mergeable(&local, &remote) => {
+                Some(local.path().to_owned())
+            }
+            _ => None,
+        }));
+        if len < 100 {
+            break;
+        }
+    }
+
+    for conflict in mergeable {
+        let operation = Operation::Resolve(conflict.clone(), ResolutionMethod::MergeText);
+        let mut progress = client.operate(ctx(), operation).await??;
+        loop {
+            match &progress {
+                Progress::Done | Progress::DoneWithReport(..) => {
+                    println!("M {conflict} merged");
+                    break;
+                }
+                Progress::Err(err) => {
+                    println!("C {conflict} {err}");
+                    break;
+                }
+                Progress::Failed(failure) => {
+                    println!("C {conflict} {failure}");
+                    break;
+                }
+                _ => (),
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            match client.progress(ctx(), conflict.clone()).await?? {
+                Some(p) => progress = p,
+                None => break,
+            }
+        }
+    }
+    Ok(())
+}
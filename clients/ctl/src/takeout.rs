@@ -0,0 +1,561 @@
+//! Import of a Google Takeout export of the drive into the local directory.
+//! The files are placed under the names of the remote entries they match, with the same
+//! modification time, so that the rescan pairs them instead of downloading them again.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path as StdPath, PathBuf as StdPathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use fsync::{
+    loc::inst,
+    path::{FsPathBuf, Path, PathBuf},
+    Metadata, SortOrder,
+};
+use fsync_client::utils::ctx_with_timeout;
+use serde::Serialize;
+
+use crate::utils::{self, Client, Format};
+
+mod zip;
+
+/// Time given to the daemon to rescan the imported files
+const RESCAN_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Folder of the drive in a Takeout export
+const DRIVE_DIR: &str = "Takeout/Drive";
+
+/// Maximum size of the metadata files read for the modification times
+const MAX_METADATA_LEN: u64 = 64 * 1024;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Place the files of Takeout archives in the local directory, and rescan it
+    Import {
+        /// Move the files out of the extracted folders instead of copying them
+        #[clap(long = "move")]
+        move_files: bool,
+
+        /// Takeout zip archives, or the folders where they were extracted
+        #[clap(required = true)]
+        sources: Vec<StdPathBuf>,
+    },
+}
+
+/// What the import did with the files of the export
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    /// Placed under the name and modification time of a remote file of the same size
+    matched: Vec<PathBuf>,
+    /// Placed, but no remote file matches them
+    unmatched: Vec<PathBuf>,
+    /// Not placed, a local file already exists at their path
+    skipped: Vec<PathBuf>,
+    rescan: fsync::RescanReport,
+}
+
+/// A file of the export
+struct Item {
+    /// Path relative to the drive folder, with `/` separators
+    path: String,
+    size: u64,
+    origin: Origin,
+}
+
+enum Origin {
+    File(StdPathBuf),
+    Zip { archive: usize, entry: usize },
+}
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let Command::Import {
+        move_files,
+        sources,
+    } = args.command;
+
+    let config = fsync::Config::load_from_file(&inst::config_file(&instance_name)?).await?;
+    let client = utils::instance_client(&instance_name).await?;
+
+    let mut archives = Vec::new();
+    let mut items = Vec::new();
+    for source in &sources {
+        if source.is_dir() {
+            items.extend(dir_items(source)?);
+        } else {
+            let archive = zip::Archive::open(source)?;
+            items.extend(zip_items(&archive, archives.len()));
+            archives.push(archive);
+        }
+    }
+    let mtimes = take_metadata(&mut items, &mut archives)?;
+
+    let mut import = Import {
+        client: &client,
+        local_dir: config.local_dir,
+        listings: HashMap::new(),
+        dirs: HashMap::new(),
+        claimed: HashSet::new(),
+        report: Report::default(),
+    };
+    items.sort_by(|a, b| a.path.cmp(&b.path));
+    for item in &items {
+        import
+            .place(
+                item,
+                mtimes.get(&item.path).copied(),
+                &mut archives,
+                move_files,
+            )
+            .await?;
+    }
+
+    let mut report = import.report;
+    report.rescan = client
+        .rescan(ctx_with_timeout(RESCAN_TIMEOUT), PathBuf::root(), true)
+        .await??;
+
+    if format == Format::Json {
+        return utils::print_json(&report);
+    }
+    for path in &report.matched {
+        println!("P {path}");
+    }
+    for path in &report.unmatched {
+        println!("U {path} (no remote file matches)");
+    }
+    for path in &report.skipped {
+        println!("S {path} (already exists)");
+    }
+    println!(
+        "{} files paired with remote files, {} unmatched, {} skipped",
+        report.matched.len(),
+        report.unmatched.len(),
+        report.skipped.len()
+    );
+    println!(
+        "rescan: {} added, {} modified, {} removed",
+        report.rescan.added.len(),
+        report.rescan.modified.len(),
+        report.rescan.removed.len()
+    );
+    Ok(())
+}
+
+/// The files of an extracted export, whose drive folder is `Takeout/Drive`, `Drive`,
+/// or `source` itself
+fn dir_items(source: &StdPath) -> anyhow::Result<Vec<Item>> {
+    let root = [source.join(DRIVE_DIR), source.join("Drive")]
+        .into_iter()
+        .find(|dir| dir.is_dir())
+        .unwrap_or_else(|| source.to_owned());
+
+    let mut items = Vec::new();
+    let mut stack = vec![(root, String::new())];
+    while let Some((dir, rel)) = stack.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                log::warn!("Skipping {}: not valid UTF-8", entry.path().display());
+                continue;
+            };
+            let path = if rel.is_empty() {
+                name
+            } else {
+                format!("{rel}/{name}")
+            };
+            let md = entry.metadata()?;
+            if md.is_dir() {
+                stack.push((entry.path(), path));
+            } else if md.is_file() {
+                items.push(Item {
+                    path,
+                    size: md.len(),
+                    origin: Origin::File(entry.path()),
+                });
+            }
+        }
+    }
+    Ok(items)
+}
+
+/// Whether `path` stays below the folder it is relative to, once written locally.
+/// Absolute paths, empty, `.` or `..` components, backslashes and drive prefixes are refused.
+fn is_safe_path(path: &str) -> bool {
+    let drive = path
+        .as_bytes()
+        .get(..2)
+        .is_some_and(|p| p[0].is_ascii_alphabetic() && p[1] == b':');
+    !drive
+        && path
+            .split('/')
+            .all(|comp| !comp.is_empty() && comp != "." && comp != ".." && !comp.contains('\\'))
+}
+
+/// The files of the drive folder in a Takeout archive
+fn zip_items(archive: &zip::Archive, index: usize) -> Vec<Item> {
+    let prefix = format!("{DRIVE_DIR}/");
+    archive
+        .entries()
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| !entry.is_dir())
+        .filter_map(|(entry_idx, entry)| {
+            let path = entry.name.strip_prefix(&prefix)?;
+            if !is_safe_path(path) {
+                log::warn!("Skipping {}: the path escapes the Drive folder", entry.name);
+                return None;
+            }
+            Some(Item {
+                path: path.to_string(),
+                size: entry.size,
+                origin: Origin::Zip {
+                    archive: index,
+                    entry: entry_idx,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Remove from `items` the metadata files `<name>.json` of the other items,
+/// and return the modification times they provide
+fn take_metadata(
+    items: &mut Vec<Item>,
+    archives: &mut [zip::Archive],
+) -> anyhow::Result<HashMap<String, DateTime<Utc>>> {
+    let paths: HashSet<String> = items.iter().map(|item| item.path.clone()).collect();
+    let mut mtimes = HashMap::new();
+    let mut metadata_files = HashSet::new();
+    for item in items.iter() {
+        let Some(described) = item.path.strip_suffix(".json") else {
+            continue;
+        };
+        if !paths.contains(described) || item.size > MAX_METADATA_LEN {
+            continue;
+        }
+        let mut json = Vec::new();
+        io::Read::read_to_end(&mut open(item, archives)?, &mut json)?;
+        if let Some(mtime) = metadata_mtime(&json) {
+            mtimes.insert(described.to_string(), mtime);
+            metadata_files.insert(item.path.clone());
+        }
+    }
+    items.retain(|item| !metadata_files.contains(&item.path));
+    Ok(mtimes)
+}
+
+/// The modification time in a Takeout metadata file, if it is one
+fn metadata_mtime(json: &[u8]) -> Option<DateTime<Utc>> {
+    let json: serde_json::Value = serde_json::from_slice(json).ok()?;
+    ["modificationTime", "photoTakenTime", "creationTime"]
+        .iter()
+        .find_map(|field| {
+            let ts = &json[field]["timestamp"];
+            let secs = ts.as_str()?.parse().ok().or_else(|| ts.as_i64())?;
+            DateTime::from_timestamp(secs, 0)
+        })
+}
+
+fn open<'a>(
+    item: &Item,
+    archives: &'a mut [zip::Archive],
+) -> anyhow::Result<Box<dyn io::Read + 'a>> {
+    match &item.origin {
+        Origin::File(path) => Ok(Box::new(fs::File::open(path)?)),
+        Origin::Zip { archive, entry } => {
+            let archive = &mut archives[*archive];
+            let entry = archive.entries()[*entry].clone();
+            archive.reader(&entry)
+        }
+    }
+}
+
+/// Copy the content of `item` to `part`, with `mtime`, or remove `part` on failure.
+/// The content of an archive is complete once copied, as its CRC-32 is checked at the end.
+fn copy_part(
+    item: &Item,
+    archives: &mut [zip::Archive],
+    part: &StdPath,
+    mtime: Option<DateTime<Utc>>,
+) -> anyhow::Result<()> {
+    let mut copy = || -> anyhow::Result<()> {
+        let mut reader = open(item, archives)?;
+        let mut file = fs::File::create(part)?;
+        io::copy(&mut reader, &mut file)?;
+        if let Some(mtime) = mtime {
+            file.set_modified(mtime.into())?;
+        }
+        Ok(())
+    };
+    let res = copy();
+    if res.is_err() {
+        let _ = fs::remove_file(part);
+    }
+    res
+}
+
+fn set_mtime(path: &StdPath, mtime: DateTime<Utc>) -> io::Result<()> {
+    fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(mtime.into())
+}
+
+/// Takeout replaces the characters that are invalid on some file systems
+fn mangle(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '\\' | '/' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// The name without the `(1)` suffix that Takeout adds to the duplicate names
+fn strip_duplicate(name: &str) -> Option<String> {
+    let (stem, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    let stem = stem.strip_suffix(')')?;
+    let open = stem.rfind('(')?;
+    let num = &stem[open + 1..];
+    if num.is_empty() || !num.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!("{}{ext}", &stem[..open]))
+}
+
+/// Whether the name in the export can be the one of the remote entry `remote`
+fn names_match(exported: &str, remote: &str) -> bool {
+    let remote = mangle(remote);
+    remote == exported || strip_duplicate(exported).is_some_and(|name| name == remote)
+}
+
+struct Import<'a> {
+    client: &'a Client,
+    local_dir: FsPathBuf,
+    /// Remote children of the directories
+    listings: HashMap<PathBuf, Vec<Metadata>>,
+    /// Directories of the export, and their path in the drive.
+    /// The boolean tells whether the path exists in the remote drive.
+    dirs: HashMap<String, (PathBuf, bool)>,
+    /// Remote files matched so far
+    claimed: HashSet<PathBuf>,
+    report: Report,
+}
+
+impl Import<'_> {
+    async fn place(
+        &mut self,
+        item: &Item,
+        mtime: Option<DateTime<Utc>>,
+        archives: &mut [zip::Archive],
+        move_files: bool,
+    ) -> anyhow::Result<()> {
+        let (dir, name) = match item.path.rsplit_once('/') {
+            Some((dir, name)) => (dir, name),
+            None => ("", item.path.as_str()),
+        };
+        let (dir, is_remote) = self.resolve_dir(dir).await?;
+
+        let matched = if is_remote {
+            self.list(&dir).await?;
+            let mut candidates = self.listings[&dir].iter().filter(|md| {
+                md.is_file()
+                    && md.size() == Some(item.size)
+                    && names_match(name, md.name())
+                    && !self.claimed.contains(md.path())
+            });
+            // several candidates of the same size cannot be told apart
+            match (candidates.next(), candidates.next()) {
+                (Some(md), None) => Some(md.clone()),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        let (path, mtime) = match &matched {
+            Some(md) => (md.path().to_owned(), md.mtime()),
+            None => (dir.join(name), mtime),
+        };
+        // the remote names are not checked by the archive, e.g. a `..\x` file matches `.._x`
+        if !is_safe_path(path.without_root().as_str()) {
+            log::warn!("Skipping {path}: the path escapes the local directory");
+            return Ok(());
+        }
+        let dest = self.local_dir.join(path.without_root().as_str());
+        if dest.exists() {
+            self.report.skipped.push(path);
+            return Ok(());
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match &item.origin {
+            Origin::File(src) if move_files && fs::rename(src, &dest).is_ok() => {
+                if let Some(mtime) = mtime {
+                    set_mtime(dest.as_std_path(), mtime)?;
+                }
+            }
+            // a copy, or a move across file systems, is written next to `dest` and renamed
+            // once complete, so that a failure doesn't leave a partial file taken as a newer one
+            origin => {
+                let part = FsPathBuf::from(format!("{dest}.fsync-part"));
+                copy_part(item, archives, part.as_std_path(), mtime)?;
+                fs::rename(&part, &dest)?;
+                if let Origin::File(src) = origin {
+                    if move_files {
+                        fs::remove_file(src)?;
+                    }
+                }
+            }
+        }
+
+        match matched {
+            Some(md) => {
+                self.claimed.insert(md.path().to_owned());
+                self.report.matched.push(path);
+            }
+            None => self.report.unmatched.push(path),
+        }
+        Ok(())
+    }
+
+    /// The path in the drive of the directory `dir` of the export, resolved name by name
+    /// against the remote directories
+    async fn resolve_dir(&mut self, dir: &str) -> anyhow::Result<(PathBuf, bool)> {
+        if dir.is_empty() {
+            return Ok((PathBuf::root(), true));
+        }
+        if let Some(resolved) = self.dirs.get(dir) {
+            return Ok(resolved.clone());
+        }
+        let (parent, name) = match dir.rsplit_once('/') {
+            Some((parent, name)) => (parent, name),
+            None => ("", dir),
+        };
+        let (parent, is_remote) = Box::pin(self.resolve_dir(parent)).await?;
+        let resolved = if is_remote {
+            self.list(&parent).await?;
+            let mut candidates = self.listings[&parent]
+                .iter()
+                .filter(|md| md.is_dir() && names_match(name, md.name()));
+            match (candidates.next(), candidates.next()) {
+                (Some(md), None) => (md.path().to_owned(), true),
+                _ => (parent.join(name), false),
+            }
+        } else {
+            (parent.join(name), false)
+        };
+        self.dirs.insert(dir.to_string(), resolved.clone());
+        Ok(resolved)
+    }
+
+    /// Add the remote children of the remote directory at `dir` to the listings
+    async fn list(&mut self, dir: &Path) -> anyhow::Result<()> {
+        if !self.listings.contains_key(dir) {
+            let (_, children) =
+                fsync_client::utils::node_and_children(self.client, dir, SortOrder::Raw).await?;
+            let children = children
+                .into_iter()
+                .filter_map(|child| child.into_entry().into_remote_metadata())
+                .collect();
+            self.listings.insert(dir.to_owned(), children);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takeout_names() {
+        assert!(names_match("report.pdf", "report.pdf"));
+        assert!(names_match("a_b.txt", "a:b.txt"));
+        assert!(names_match("report(1).pdf", "report.pdf"));
+        assert!(names_match("notes(2)", "notes"));
+        assert!(!names_match("report(a).pdf", "report.pdf"));
+        assert!(!names_match("report.pdf", "other.pdf"));
+        assert_eq!(strip_duplicate(".hidden(1)"), Some(".hidden".to_string()));
+        assert_eq!(strip_duplicate("plain.txt"), None);
+    }
+
+    #[test]
+    fn takeout_traversal() {
+        let dir = std::env::temp_dir().join(format!("fsynctl-takeout-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("takeout.zip");
+        fs::write(
+            &path,
+            zip::tests::write_zip(&[
+                ("Takeout/Drive/ok.txt", b"ok", false),
+                ("Takeout/Drive/../../evil.txt", b"evil", false),
+                ("Takeout/Drive//etc/evil.txt", b"evil", false),
+                ("Takeout/Drive/a/./evil.txt", b"evil", false),
+                ("Takeout/Drive/..\\evil.txt", b"evil", false),
+                ("Takeout/Drive/C:/evil.txt", b"evil", false),
+            ]),
+        )
+        .unwrap();
+
+        let archive = zip::Archive::open(&path).unwrap();
+        let items = zip_items(&archive, 0);
+        let paths: Vec<_> = items.iter().map(|item| item.path.as_str()).collect();
+        assert_eq!(paths, ["ok.txt"]);
+        assert!(is_safe_path("dir/file(1).txt"));
+        assert!(is_safe_path("dir/a:b.txt"));
+        assert!(!is_safe_path("dir/.."));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn takeout_corrupt_entry_leaves_no_file() {
+        let dir = std::env::temp_dir().join(format!("fsynctl-takeout-crc-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("takeout.zip");
+        let name = "Takeout/Drive/file.txt";
+        let mut zip = zip::tests::write_zip(&[(name, b"content", false)]);
+        zip[30 + name.len()] ^= 0xff;
+        fs::write(&path, zip).unwrap();
+
+        let mut archives = vec![zip::Archive::open(&path).unwrap()];
+        let items = zip_items(&archives[0], 0);
+        let part = dir.join("file.txt.fsync-part");
+        assert!(copy_part(&items[0], &mut archives, &part, None).is_err());
+        assert!(!part.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn takeout_metadata() {
+        let json = br#"{"title": "a.jpg", "modificationTime": {"timestamp": "1700000000"}}"#;
+        assert_eq!(
+            metadata_mtime(json),
+            DateTime::from_timestamp(1_700_000_000, 0)
+        );
+        assert_eq!(metadata_mtime(br#"{"title": "a.jpg"}"#), None);
+        assert_eq!(metadata_mtime(b"not json"), None);
+    }
+}
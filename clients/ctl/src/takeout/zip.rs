@@ -0,0 +1,368 @@
+//! Minimal reader of the zip archives produced by Google Takeout.
+//! Only the stored and deflated entries are supported, with the zip64 extensions
+//! used by the archives larger than 4 GiB.
+//! The content of the entries is checked against their CRC-32 as it is read.
+
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::Context;
+use flate2::{read::DeflateDecoder, CrcReader};
+
+const EOCD_SIG: u32 = 0x0605_4b50;
+const EOCD64_LOCATOR_SIG: u32 = 0x0706_4b50;
+const EOCD64_SIG: u32 = 0x0606_4b50;
+const CENTRAL_SIG: u32 = 0x0201_4b50;
+const LOCAL_SIG: u32 = 0x0403_4b50;
+
+const EOCD_LEN: usize = 22;
+const MAX_COMMENT_LEN: usize = u16::MAX as usize;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+const TRUNCATED: &str = "truncated archive";
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Path of the entry in the archive, with `/` separators
+    pub name: String,
+    /// Uncompressed size
+    pub size: u64,
+    crc32: u32,
+    compressed_size: u64,
+    method: u16,
+    header_offset: u64,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+}
+
+#[derive(Debug)]
+pub struct Archive {
+    file: fs::File,
+    entries: Vec<Entry>,
+}
+
+impl Archive {
+    /// Open the archive at `path` and read its central directory
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file =
+            fs::File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+        let entries = read_central_directory(&mut file)
+            .with_context(|| format!("{} is not a valid zip archive", path.display()))?;
+        Ok(Self { file, entries })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Read the uncompressed content of `entry`.
+    /// The reader fails at the end of the content if it doesn't match the CRC-32 of the entry.
+    pub fn reader(&mut self, entry: &Entry) -> anyhow::Result<Box<dyn Read + '_>> {
+        self.file.seek(SeekFrom::Start(entry.header_offset))?;
+        let mut header = [0u8; 30];
+        self.file.read_exact(&mut header)?;
+        anyhow::ensure!(
+            u32_at(&header, 0)? == LOCAL_SIG,
+            "{}: invalid local header",
+            entry.name
+        );
+        let skip = u16_at(&header, 26)? as i64 + u16_at(&header, 28)? as i64;
+        self.file.seek(SeekFrom::Current(skip))?;
+        let data = (&mut self.file).take(entry.compressed_size);
+        let data: Box<dyn Read> = match entry.method {
+            METHOD_STORED => Box::new(data),
+            METHOD_DEFLATED => Box::new(DeflateDecoder::new(data)),
+            method => anyhow::bail!("{}: unsupported compression method {method}", entry.name),
+        };
+        Ok(Box::new(Checked {
+            reader: CrcReader::new(data),
+            crc32: entry.crc32,
+            name: entry.name.clone(),
+        }))
+    }
+}
+
+/// Reader of the content of an entry, that checks its CRC-32 when the end is reached
+struct Checked<R> {
+    reader: CrcReader<R>,
+    crc32: u32,
+    name: String,
+}
+
+impl<R: Read> Read for Checked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        if len == 0 && !buf.is_empty() && self.reader.crc().sum() != self.crc32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: CRC-32 mismatch, the archive is corrupt", self.name),
+            ));
+        }
+        Ok(len)
+    }
+}
+
+fn read_central_directory(file: &mut fs::File) -> anyhow::Result<Vec<Entry>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let tail_len = len.min((EOCD_LEN + MAX_COMMENT_LEN) as u64);
+    let tail_start = len - tail_len;
+    file.seek(SeekFrom::Start(tail_start))?;
+    let mut tail = vec![0u8; tail_len as usize];
+    file.read_exact(&mut tail)?;
+    anyhow::ensure!(tail.len() >= EOCD_LEN, TRUNCATED);
+
+    let eocd = (0..=tail.len() - EOCD_LEN)
+        .rev()
+        .find(|&pos| tail[pos..pos + 4] == EOCD_SIG.to_le_bytes())
+        .context("end of central directory not found")?;
+    let mut count = u16_at(&tail, eocd + 10)? as u64;
+    let mut offset = u32_at(&tail, eocd + 16)? as u64;
+
+    // zip64: the locator precedes the end of central directory
+    if count == u16::MAX as u64 || offset == u32::MAX as u64 {
+        anyhow::ensure!(
+            eocd >= 20 && u32_at(&tail, eocd - 20)? == EOCD64_LOCATOR_SIG,
+            "zip64 locator not found"
+        );
+        let eocd64 = u64_at(&tail, eocd - 20 + 8)?;
+        file.seek(SeekFrom::Start(eocd64))?;
+        let mut record = [0u8; 56];
+        file.read_exact(&mut record).context(TRUNCATED)?;
+        anyhow::ensure!(u32_at(&record, 0)? == EOCD64_SIG, "invalid zip64 record");
+        count = u64_at(&record, 32)?;
+        offset = u64_at(&record, 48)?;
+    }
+
+    file.seek(SeekFrom::Start(offset))?;
+    let mut reader = io::BufReader::new(file);
+    let mut entries = Vec::with_capacity(count.min(1 << 20) as usize);
+    for _ in 0..count {
+        entries.push(read_central_header(&mut reader)?);
+    }
+    Ok(entries)
+}
+
+fn read_central_header<R: Read>(reader: &mut R) -> anyhow::Result<Entry> {
+    let mut header = [0u8; 46];
+    reader.read_exact(&mut header).context(TRUNCATED)?;
+    anyhow::ensure!(
+        u32_at(&header, 0)? == CENTRAL_SIG,
+        "invalid central directory header"
+    );
+    let flags = u16_at(&header, 8)?;
+    let method = u16_at(&header, 10)?;
+    let crc32 = u32_at(&header, 16)?;
+    let mut compressed_size = u32_at(&header, 20)? as u64;
+    let mut size = u32_at(&header, 24)? as u64;
+    let name_len = u16_at(&header, 28)? as usize;
+    let extra_len = u16_at(&header, 30)? as usize;
+    let comment_len = u16_at(&header, 32)? as usize;
+    let mut header_offset = u32_at(&header, 42)? as u64;
+
+    let mut var = vec![0u8; name_len + extra_len + comment_len];
+    reader.read_exact(&mut var).context(TRUNCATED)?;
+    let name = &var[..name_len];
+    // names are in UTF-8 with the flag 11, in CP437 otherwise, that Takeout doesn't use
+    let name = if flags & 0x800 != 0 {
+        String::from_utf8(name.to_vec())?
+    } else {
+        String::from_utf8_lossy(name).into_owned()
+    };
+
+    // the zip64 extra field holds the values that overflow, in this order
+    let mut extra = &var[name_len..name_len + extra_len];
+    while extra.len() >= 4 {
+        let (id, len) = (u16_at(extra, 0)?, u16_at(extra, 2)? as usize);
+        let data = extra.get(4..4 + len).context("truncated extra field")?;
+        if id == 0x0001 {
+            let mut values = data.chunks_exact(8);
+            for field in [&mut size, &mut compressed_size, &mut header_offset] {
+                if *field == u32::MAX as u64 {
+                    *field = u64_at(values.next().context("truncated zip64 field")?, 0)?;
+                }
+            }
+        }
+        extra = &extra[4 + len..];
+    }
+
+    Ok(Entry {
+        name,
+        size,
+        crc32,
+        compressed_size,
+        method,
+        header_offset,
+    })
+}
+
+/// The `N` bytes at `pos` in `buf`, that may be cut short by a truncated archive
+fn bytes_at<const N: usize>(buf: &[u8], pos: usize) -> anyhow::Result<[u8; N]> {
+    buf.get(pos..pos.saturating_add(N))
+        .and_then(|bytes| bytes.try_into().ok())
+        .context(TRUNCATED)
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> anyhow::Result<u16> {
+    bytes_at(buf, pos).map(u16::from_le_bytes)
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> anyhow::Result<u32> {
+    bytes_at(buf, pos).map(u32::from_le_bytes)
+}
+
+fn u64_at(buf: &[u8], pos: usize) -> anyhow::Result<u64> {
+    bytes_at(buf, pos).map(u64::from_le_bytes)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::io::Write;
+
+    use flate2::{write::DeflateEncoder, Compression};
+
+    use super::*;
+
+    /// Write an archive with the given entries, deflated or stored
+    pub(crate) fn write_zip(entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut zip = Vec::new();
+        let mut central = Vec::new();
+        for (name, content, deflate) in entries {
+            let (method, data) = if *deflate {
+                let mut enc = DeflateEncoder::new(Vec::new(), Compression::default());
+                enc.write_all(content).unwrap();
+                (METHOD_DEFLATED, enc.finish().unwrap())
+            } else {
+                (METHOD_STORED, content.to_vec())
+            };
+            let mut crc = flate2::Crc::new();
+            crc.update(content);
+            let offset = zip.len() as u32;
+            let fields = |zip: &mut Vec<u8>| {
+                zip.extend(0x800u16.to_le_bytes());
+                zip.extend(method.to_le_bytes());
+                zip.extend([0u8; 4]); // time and date
+                zip.extend(crc.sum().to_le_bytes());
+                zip.extend((data.len() as u32).to_le_bytes());
+                zip.extend((content.len() as u32).to_le_bytes());
+                zip.extend((name.len() as u16).to_le_bytes());
+                zip.extend(0u16.to_le_bytes());
+            };
+            zip.extend(LOCAL_SIG.to_le_bytes());
+            zip.extend(20u16.to_le_bytes());
+            fields(&mut zip);
+            zip.extend(name.as_bytes());
+            zip.extend(&data);
+
+            central.extend(CENTRAL_SIG.to_le_bytes());
+            central.extend([20u8, 0, 20, 0]);
+            fields(&mut central);
+            central.extend([0u8; 10]); // comment, disk and attributes
+            central.extend(offset.to_le_bytes());
+            central.extend(name.as_bytes());
+        }
+        let offset = zip.len() as u32;
+        zip.extend(&central);
+        zip.extend(EOCD_SIG.to_le_bytes());
+        zip.extend([0u8; 4]);
+        zip.extend((entries.len() as u16).to_le_bytes());
+        zip.extend((entries.len() as u16).to_le_bytes());
+        zip.extend((central.len() as u32).to_le_bytes());
+        zip.extend(offset.to_le_bytes());
+        zip.extend(0u16.to_le_bytes());
+        zip
+    }
+
+    #[test]
+    fn read_entries() {
+        let dir = std::env::temp_dir().join(format!("fsynctl-zip-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("takeout.zip");
+        let text = "Lorem ipsum dolor sit amet. ".repeat(100);
+        fs::write(
+            &path,
+            write_zip(&[
+                ("Takeout/Drive/", b"", false),
+                ("Takeout/Drive/stored.txt", b"stored", false),
+                ("Takeout/Drive/été.txt", text.as_bytes(), true),
+            ]),
+        )
+        .unwrap();
+
+        let mut archive = Archive::open(&path).unwrap();
+        let entries = archive.entries().to_vec();
+        let names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "Takeout/Drive/",
+                "Takeout/Drive/stored.txt",
+                "Takeout/Drive/été.txt"
+            ]
+        );
+        assert!(entries[0].is_dir());
+        assert_eq!(entries[2].size, text.len() as u64);
+
+        let mut content = String::new();
+        archive
+            .reader(&entries[1])
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "stored");
+        content.clear();
+        archive
+            .reader(&entries[2])
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, text);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_archive() {
+        let dir = std::env::temp_dir().join(format!("fsynctl-zip-trunc-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("takeout.zip");
+        let zip = write_zip(&[("Takeout/Drive/file.txt", b"content", false)]);
+        for len in [0, 10, zip.len() - 30, zip.len() - 10] {
+            fs::write(&path, &zip[..len]).unwrap();
+            assert!(Archive::open(&path).is_err(), "length {len}");
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_content() {
+        let dir = std::env::temp_dir().join(format!("fsynctl-zip-crc-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("takeout.zip");
+        let mut zip = write_zip(&[("Takeout/Drive/file.txt", b"content", false)]);
+        // the stored content follows the local header and the name
+        zip[30 + "Takeout/Drive/file.txt".len()] ^= 0xff;
+        fs::write(&path, zip).unwrap();
+
+        let mut archive = Archive::open(&path).unwrap();
+        let entry = archive.entries()[0].clone();
+        let mut content = Vec::new();
+        let err = archive
+            .reader(&entry)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
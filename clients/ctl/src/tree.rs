@@ -1,13 +1,31 @@
-use std::{
-    net::{IpAddr, Ipv6Addr},
-    sync::Arc,
+use std::{io::IsTerminal, sync::Arc};
+
+use chrono::{DateTime, Local, Utc};
+use crossterm::style::{Color, Stylize};
+use fsync::{
+    fmt::{human_bytes, human_mtime, Unit},
+    path::PathBuf,
+    tree, Metadata, RemotePhase, SortOrder,
+};
+use fsync_client::utils::ctx;
+use futures::future::BoxFuture;
+
+use crate::{
+    utils,
+    width::{elided, Width},
 };
 
-use fsync::{path::PathBuf, tree, FsyncClient};
-use futures::future::{self, BoxFuture};
-use tarpc::{client, context, tokio_serde::formats::Bincode};
+/// Number of children fetched at once, so that very large directories are printed progressively
+const PAGE_LEN: u32 = 1000;
+
+/// Width of the terminal if it can't be queried
+const DEFAULT_WIDTH: u16 = 80;
+
+/// Width below which the names are not elided further, and the time columns are dropped
+const MIN_NAME_WIDTH: u16 = 24;
 
-use crate::utils;
+/// Separator of the columns in the terminal
+const SEP: &str = "  ";
 
 #[derive(clap::Args)]
 pub struct Args {
@@ -15,33 +33,55 @@ pub struct Args {
     #[clap(long, short = 'n')]
     instance_name: Option<String>,
 
+    /// Order in which the entries of each directory are listed
+    #[clap(long, value_enum, default_value_t = utils::Sort::Natural)]
+    sort: utils::Sort,
+
+    /// Print the sizes and modification times on both sides, in columns
+    #[clap(long, short = 'l')]
+    long: bool,
+
+    /// How the sizes are printed with --long
+    #[clap(long, value_enum, default_value_t = Sizes::Human)]
+    sizes: Sizes,
+
+    /// How the modification times are printed with --long
+    #[clap(long, value_enum, default_value_t = Times::Local)]
+    times: Times,
+
     /// Path to the entry (root if not specified)
     path: Option<PathBuf>,
 }
 
-pub async fn main(args: Args) -> anyhow::Result<()> {
-    let instance_name = match args.instance_name {
-        Some(name) => name,
-        None => {
-            let name = utils::single_instance_name()?;
-            if let Some(name) = name {
-                name
-            } else {
-                anyhow::bail!("Could not find a single share, please specify --share-name command line argument");
-            }
-        }
-    };
+/// Format of the sizes of the long output
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Sizes {
+    /// With the most appropriate binary unit, e.g. "1.5 MiB"
+    Human,
+    /// Exact number of bytes
+    Bytes,
+}
 
-    let port = utils::instance_port(&instance_name)?;
+/// Format of the modification times of the long output
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Times {
+    /// Date and time in the local timezone
+    Local,
+    /// Date and time in UTC
+    Utc,
+    /// Time elapsed since the modification, e.g. "3 h ago"
+    Relative,
+}
 
-    let addr = (IpAddr::V6(Ipv6Addr::LOCALHOST), port);
-    let mut transport = tarpc::serde_transport::tcp::connect(addr, Bincode::default);
-    transport.config_mut().max_frame_length(usize::MAX);
+pub async fn main(args: Args) -> anyhow::Result<()> {
+    let printer = Arc::new(Printer::new(&args));
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
 
-    let client = Arc::new(FsyncClient::new(client::Config::default(), transport.await?).spawn());
+    let client = utils::instance_client(&instance_name).await?;
     let path = args.path.unwrap_or_else(PathBuf::root);
+    // a single child tells whether the root has children
     let node = client
-        .entry_node(context::current(), path.clone())
+        .entry_node_page(ctx(), path.clone(), None, 1)
         .await?
         .unwrap();
 
@@ -50,81 +90,220 @@ pub async fn main(args: Args) -> anyhow::Result<()> {
         return Ok(());
     }
 
+    printer.print_header();
+
     let node = node.unwrap();
-    print_entry_status(true, !node.children().is_empty(), "", node.entry());
+    printer.print(true, !node.children().is_empty(), "", node.entry());
+
+    walk(client.clone(), printer, "".into(), node, args.sort.into()).await?;
 
-    walk(client, "".into(), node).await
+    match client.status(ctx()).await??.remote {
+        RemotePhase::Ready => (),
+        RemotePhase::Initializing => {
+            log::warn!("The remote drive is initializing, remote entries are from the cache")
+        }
+        RemotePhase::Retrying(err) => log::warn!(
+            "The remote drive could not be reached ({err}), remote entries are from the cache"
+        ),
+    }
+    Ok(())
 }
 
 // all special unicode are from "box drawing" block starting at \u{2500}
 
 fn walk(
-    client: Arc<FsyncClient>,
+    client: Arc<utils::Client>,
+    printer: Arc<Printer>,
     prefix: String,
     node: tree::EntryNode,
+    order: SortOrder,
 ) -> BoxFuture<'static, anyhow::Result<()>> {
     Box::pin(async move {
         let dir = node.path();
-        let joinvec: Vec<_> = node
-            .children()
-            .iter()
-            .map(|c| client.entry_node(context::current(), dir.join(c)))
-            .collect();
-        let children = future::try_join_all(joinvec).await?;
-        let mut len = children.len();
-
-        for child in children {
-            let child = child.unwrap();
-            len -= 1;
-            if child.is_none() {
-                continue;
-            }
-            let child = child.unwrap();
-            let has_follower = len != 0;
+        let mut after: Option<String> = None;
+        // a child is printed once it is known whether another one follows
+        let mut held: Option<tree::EntryNode> = None;
 
-            print_entry_status(false, has_follower, &prefix, child.entry());
+        loop {
+            let page = client
+                .entry_nodes(ctx(), dir.to_owned(), Some(order), after.take(), PAGE_LEN)
+                .await??;
+            let done = page.len() < PAGE_LEN as usize;
+            after = page.last().and_then(|c| c.name()).map(ToString::to_string);
 
-            if !child.children().is_empty() {
-                let prefix = if has_follower {
-                    format!("{prefix}│  ")
-                } else {
-                    format!("{prefix}   ")
-                };
-                walk(client.clone(), prefix, child).await?;
+            for child in page {
+                if let Some(prev) = held.replace(child) {
+                    print_child(client.clone(), printer.clone(), &prefix, prev, true, order)
+                        .await?;
+                }
+            }
+            if done {
+                break;
             }
         }
+        if let Some(last) = held {
+            print_child(client, printer, &prefix, last, false, order).await?;
+        }
         Ok(())
     })
 }
 
-fn print_entry_status(first: bool, has_follower: bool, prefix_head: &str, entry: &tree::Entry) {
-    let prefix_tail = match (first, has_follower) {
-        (true, _) => "",
-        (false, true) => "├─ ",
-        (false, false) => "└─ ",
-    };
+async fn print_child(
+    client: Arc<utils::Client>,
+    printer: Arc<Printer>,
+    prefix: &str,
+    child: tree::EntryNode,
+    has_follower: bool,
+    order: SortOrder,
+) -> anyhow::Result<()> {
+    printer.print(false, has_follower, prefix, child.entry());
 
-    let name = entry.path().file_name().unwrap_or(entry.path().as_str());
+    if !child.children().is_empty() {
+        let prefix = if has_follower {
+            format!("{prefix}│  ")
+        } else {
+            format!("{prefix}   ")
+        };
+        walk(client, printer, prefix, child, order).await?;
+    }
+    Ok(())
+}
 
-    match entry {
-        tree::Entry::Local(..) => {
-            println!("L {prefix_head}{prefix_tail}{name}");
+/// Prints the entries, in columns with `--long`.
+/// When the output is not a terminal, the columns are separated by tabs,
+/// the entries are printed with their full path and without colors.
+struct Printer {
+    long: bool,
+    sizes: Sizes,
+    times: Times,
+    terminal: bool,
+    /// Width of the terminal
+    width: u16,
+    /// Whether the time columns fit in the terminal
+    with_times: bool,
+    now: DateTime<Utc>,
+}
+
+impl Printer {
+    fn new(args: &Args) -> Self {
+        let terminal = std::io::stdout().is_terminal();
+        let width = crossterm::terminal::size()
+            .map(|(w, _)| w)
+            .unwrap_or(DEFAULT_WIDTH);
+        let mut printer = Self {
+            long: args.long,
+            sizes: args.sizes,
+            times: args.times,
+            terminal,
+            width,
+            with_times: true,
+            now: Utc::now(),
+        };
+        printer.with_times = !terminal || printer.columns_width() + MIN_NAME_WIDTH <= width;
+        printer
+    }
+
+    fn size_width(&self) -> u16 {
+        match self.sizes {
+            Sizes::Human => 10,
+            Sizes::Bytes => 15,
         }
-        tree::Entry::Remote(..) => {
-            println!("R {prefix_head}{prefix_tail}{name}");
+    }
+
+    fn time_width(&self) -> u16 {
+        match self.times {
+            Times::Local => 16,
+            Times::Utc => 17,
+            Times::Relative => 13,
         }
-        tree::Entry::Sync {
-            local,
-            remote,
-            conflict,
-        } => {
-            assert_eq!(local.path(), remote.path());
+    }
 
-            let conflict = conflict.map(|c| c.to_string());
-            let status = if conflict.is_none() { "S" } else { "C" };
+    /// Width of the columns before the name, separators included
+    fn columns_width(&self) -> u16 {
+        let mut width = 1 + 2 * (SEP.width() + self.size_width());
+        if self.with_times {
+            width += 2 * (SEP.width() + self.time_width());
+        }
+        width + SEP.width()
+    }
 
-            println!("{status} {prefix_head}{prefix_tail}{name}");
+    fn format_size(&self, size: Option<i64>) -> String {
+        match (size, self.sizes) {
+            (None, _) => "-".to_string(),
+            (Some(size), Sizes::Human) => human_bytes(size.max(0) as u64, Unit::Binary),
+            (Some(size), Sizes::Bytes) => size.to_string(),
+        }
+    }
+
+    fn format_time(&self, mtime: Option<DateTime<Utc>>) -> String {
+        let Some(mtime) = mtime else {
+            return "-".to_string();
+        };
+        match self.times {
+            Times::Local => mtime
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string(),
+            Times::Utc => mtime.format("%Y-%m-%d %H:%MZ").to_string(),
+            Times::Relative => human_mtime(mtime, self.now),
+        }
+    }
+
+    /// The columns of `entry` before its name
+    fn columns(&self, entry: &tree::Entry) -> Vec<String> {
+        let (local, remote) = match entry {
+            tree::Entry::Local(local) => (Some(local), None),
+            tree::Entry::Remote(remote) => (None, Some(remote)),
+            tree::Entry::Sync { local, remote, .. } => (Some(local), Some(remote)),
+        };
+        let size = |md: Option<&Metadata>| md.and_then(|md| md.stat()).map(|stat| stat.data);
+        let mtime = |md: Option<&Metadata>| md.and_then(|md| md.mtime());
+        let mut columns = vec![
+            self.format_size(size(local)),
+            self.format_size(size(remote)),
+        ];
+        if self.with_times {
+            columns.push(self.format_time(mtime(local)));
+            columns.push(self.format_time(mtime(remote)));
+        }
+        columns
+    }
 
+    fn print_header(&self) {
+        if !self.long || !self.terminal {
+            return;
+        }
+        let (sw, tw) = (self.size_width() as usize, self.time_width() as usize);
+        let mut header = format!("S{SEP}{:>sw$}{SEP}{:>sw$}", "LOCAL SIZE", "REMOTE SIZE");
+        if self.with_times {
+            header.push_str(&format!(
+                "{SEP}{:<tw$}{SEP}{:<tw$}",
+                "LOCAL MTIME", "REMOTE MTIME"
+            ));
+        }
+        println!("{}{SEP}NAME", header.bold());
+    }
+
+    fn print(&self, first: bool, has_follower: bool, prefix_head: &str, entry: &tree::Entry) {
+        let prefix_tail = match (first, has_follower) {
+            (true, _) => "",
+            (false, true) => "├─ ",
+            (false, false) => "└─ ",
+        };
+
+        let name = entry.path().file_name().unwrap_or(entry.path().as_str());
+        let conflict = entry.conflict().map(|c| c.to_string());
+        let (status, color) = match entry {
+            tree::Entry::Local(..) => ('L', Color::Reset),
+            tree::Entry::Remote(..) => ('R', Color::Cyan),
+            tree::Entry::Sync { conflict: None, .. } => ('S', Color::Green),
+            tree::Entry::Sync {
+                conflict: Some(_), ..
+            } => ('C', Color::Red),
+        };
+
+        if !self.long {
+            println!("{status} {prefix_head}{prefix_tail}{name}");
             if let Some(conflict) = conflict {
                 let prefix_tail = match (first, has_follower) {
                     (true, _) => "",
@@ -133,6 +312,91 @@ fn print_entry_status(first: bool, has_follower: bool, prefix_head: &str, entry:
                 };
                 println!("  {prefix_head}{prefix_tail}  └─ 🗲 {conflict} 🗲",);
             }
+            return;
+        }
+
+        let columns = self.columns(entry);
+        if !self.terminal {
+            let conflict = conflict.unwrap_or_default();
+            println!(
+                "{status}\t{}\t{}\t{conflict}",
+                columns.join("\t"),
+                entry.path()
+            );
+            return;
+        }
+
+        let (sw, tw) = (self.size_width() as usize, self.time_width() as usize);
+        let mut line = String::new();
+        for (i, col) in columns.iter().enumerate() {
+            if i < 2 {
+                line.push_str(&format!("{SEP}{col:>sw$}"));
+            } else {
+                line.push_str(&format!("{SEP}{col:<tw$}"));
+            }
+        }
+
+        let prefix = format!("{prefix_head}{prefix_tail}");
+        let avail = self
+            .width
+            .saturating_sub(self.columns_width() + prefix.width());
+        let name = if avail >= 5 {
+            elided(name.to_string(), avail)
+        } else {
+            name.to_string()
+        };
+        let conflict = conflict
+            .map(|c| format!(" 🗲 {c}"))
+            .filter(|c| name.width() + c.width() <= avail);
+
+        println!(
+            "{}{line}{SEP}{prefix}{name}{}",
+            status.with(color),
+            conflict.unwrap_or_default().with(Color::Red)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn printer(sizes: Sizes, times: Times) -> Printer {
+        Printer {
+            long: true,
+            sizes,
+            times,
+            terminal: false,
+            width: DEFAULT_WIDTH,
+            with_times: true,
+            now: Utc.with_ymd_and_hms(2024, 3, 15, 12, 0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn long_columns_fit_their_width() {
+        let mtime = Utc.with_ymd_and_hms(2024, 3, 15, 9, 30, 0).unwrap();
+        for sizes in [Sizes::Human, Sizes::Bytes] {
+            for times in [Times::Local, Times::Utc, Times::Relative] {
+                let p = printer(sizes, times);
+                assert!(p.format_size(Some(1023 * 1024 * 1024)).width() <= p.size_width());
+                assert!(p.format_size(Some(999_999_999_999_999)).width() <= p.size_width());
+                assert!(p.format_time(Some(mtime)).width() <= p.time_width());
+                assert!(
+                    p.format_time(Some(p.now + chrono::Duration::days(1)))
+                        .width()
+                        <= p.time_width()
+                );
+            }
         }
+        let p = printer(Sizes::Bytes, Times::Utc);
+        assert_eq!(p.format_size(Some(1536)), "1536");
+        assert_eq!(p.format_size(None), "-");
+        assert_eq!(p.format_time(Some(mtime)), "2024-03-15 09:30Z");
+        let p = printer(Sizes::Human, Times::Relative);
+        assert_eq!(p.format_size(Some(1536)), "1.5 KiB");
+        assert_eq!(p.format_time(Some(mtime)), "2 h ago");
     }
 }
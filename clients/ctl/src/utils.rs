@@ -1,14 +1,84 @@
 use std::{
-    net::{IpAddr, Ipv6Addr},
+    io::{self, Write},
     sync::Arc,
+    time::Instant,
 };
 
-use byte_unit::AdjustedByte;
 use fsync::{
-    loc::{inst, user},
-    FsyncClient,
+    loc::user, path::Path, AuthPrompt, FsyncClient, FsyncRequest, FsyncResponse, OperationReport,
+    Progress, SortOrder,
 };
-use tarpc::{client, tokio_serde::formats::Bincode};
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use serde::Serialize;
+use tarpc::{
+    client::{stub::Stub, RpcError},
+    context,
+};
+
+use crate::exit;
+
+/// Client of fsyncd, logging each call
+pub type Client = FsyncClient<Logged>;
+
+/// Connection to fsyncd that logs each call with its duration at debug level
+#[derive(Clone)]
+pub struct Logged(fsync_client::Connection);
+
+impl Stub for Logged {
+    type Req = FsyncRequest;
+    type Resp = FsyncResponse;
+
+    async fn call(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: FsyncRequest,
+    ) -> Result<FsyncResponse, RpcError> {
+        let start = Instant::now();
+        let res = self.0.call(ctx, request_name, request).await;
+        match &res {
+            Ok(_) => log::debug!("{request_name}: {:?}", start.elapsed()),
+            Err(err) => log::debug!("{request_name}: {err} after {:?}", start.elapsed()),
+        }
+        res
+    }
+}
+
+/// Format of the output of the commands listing entries
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Human readable text
+    Text,
+    /// A single JSON document, for the scripts
+    Json,
+}
+
+/// Print `value` as a single JSON document
+pub fn print_json<T: Serialize + ?Sized>(value: &T) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Order in which the entries of a directory are listed
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Sort {
+    /// Alphabetical order ignoring the case, with the numbers compared by value
+    Natural,
+    /// Alphabetical order ignoring the case
+    CaseInsensitive,
+    /// Byte-wise order of the names, as stored in the tree
+    Raw,
+}
+
+impl From<Sort> for SortOrder {
+    fn from(value: Sort) -> Self {
+        match value {
+            Sort::Natural => SortOrder::Natural,
+            Sort::CaseInsensitive => SortOrder::CaseInsensitive,
+            Sort::Raw => SortOrder::Raw,
+        }
+    }
+}
 
 /// If a single instance of fsyncd exists, get its name
 pub fn single_instance_name() -> anyhow::Result<Option<String>> {
@@ -30,34 +100,86 @@ pub fn single_instance_name() -> anyhow::Result<Option<String>> {
     }
 }
 
-pub fn instance_port(instance_name: &str) -> anyhow::Result<u16> {
-    let pf = inst::runtime_port_file(instance_name)?;
-    if !pf.exists() {
-        anyhow::bail!(
-            "Could not find {pf}. Are you sure the fsyncd {instance_name} instance is running?"
-        );
+/// The instance given with `--instance-name`, or the single instance if there is only one
+pub fn instance_name(name: Option<&str>) -> anyhow::Result<String> {
+    if let Some(name) = name {
+        return Ok(name.to_owned());
+    }
+    match single_instance_name()? {
+        Some(name) => Ok(name),
+        None => Err(exit::usage(
+            "Could not find a single share, please specify --instance-name command line argument",
+        )),
     }
-    let content = std::fs::read(&pf)?;
-    let content = String::from_utf8(content).map_err(|err| err.utf8_error())?;
-    let port: u16 = serde_json::from_str(&content)?;
-    Ok(port)
 }
 
-pub async fn instance_client(instance_name: &str) -> anyhow::Result<Arc<FsyncClient>> {
-    let port = instance_port(&instance_name)?;
+pub async fn instance_client(instance_name: &str) -> anyhow::Result<Arc<Client>> {
+    Ok(instance_connection(instance_name).await?.0)
+}
 
-    let addr = (IpAddr::V6(Ipv6Addr::LOCALHOST), port);
-    let mut transport = tarpc::serde_transport::tcp::connect(addr, Bincode::default);
-    transport.config_mut().max_frame_length(usize::MAX);
+/// Same as [`instance_client`], but also return the connection,
+/// to follow the restarts of the daemon
+pub async fn instance_connection(
+    instance_name: &str,
+) -> anyhow::Result<(Arc<Client>, fsync_client::Connection)> {
+    log::debug!("connecting to {instance_name}");
+    let conn = fsync_client::Connection::open(instance_name).await?;
+    Ok((Arc::new(Logged(conn.clone()).into()), conn))
+}
 
-    Ok(Arc::new(
-        FsyncClient::new(client::Config::default(), transport.await?).spawn(),
-    ))
+/// Log the entries that failed during the deep operation on `path`,
+/// and fail if its `report` counts any.
+pub async fn check_failures(
+    client: &Client,
+    path: &Path,
+    report: Option<OperationReport>,
+) -> anyhow::Result<()> {
+    let failed = report.map_or(0, |report| report.failed);
+    if failed == 0 {
+        return Ok(());
+    }
+    let progresses = client
+        .progresses(fsync_client::utils::ctx(), path.to_owned())
+        .await??;
+    for (path, progress) in progresses {
+        if let Progress::Failed(failure) = progress {
+            log::error!("{path}: {failure}");
+        }
+    }
+    Err(exit::partial_failure(failed as usize, path.to_owned()))
 }
 
-pub fn adjusted_byte(val: u64) -> AdjustedByte {
-    use byte_unit::{Byte, UnitType};
+/// Ask `question` on the terminal, and return whether the user answered yes
+pub fn ask(question: &str) -> io::Result<bool> {
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
 
-    let byte = Byte::from(val);
-    byte.get_appropriate_unit(UnitType::Binary)
+/// Print the authorization page of the PKCE flow, with a QR code to scan it
+pub fn print_auth_prompt(prompt: &AuthPrompt) {
+    let url = &prompt.url;
+    if prompt.opened {
+        println!("Authorize fsyncd in the opened browser page, or visit {url}");
+    } else {
+        println!("To authorize fsyncd, visit {url}");
+    }
+    match QrCode::new(url.as_bytes()) {
+        // light modules on dark, as most terminals are
+        Ok(code) => println!(
+            "{}",
+            code.render::<Dense1x2>()
+                .dark_color(Dense1x2::Light)
+                .light_color(Dense1x2::Dark)
+                .build()
+        ),
+        Err(err) => log::debug!("Could not encode the URL in a QR code: {err}"),
+    }
+    let expires_at = prompt.expires_at.with_timezone(&chrono::Local);
+    println!(
+        "Waiting for the authorization until {}",
+        expires_at.format("%H:%M:%S")
+    );
 }
@@ -0,0 +1,96 @@
+use std::time::{Duration, SystemTime};
+
+use fsync::path::PathBuf;
+use fsync_client::utils::{ctx, ctx_with_timeout, timeout};
+
+use crate::{
+    exit,
+    utils::{self, Format},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Name of the fsyncd instance
+    #[clap(long, short = 'n')]
+    instance_name: Option<String>,
+
+    /// Hash the content of the remote files instead of using their checksums.
+    /// Downloads all the files, but also verifies the storages that report no checksum.
+    #[clap(long)]
+    deep: bool,
+
+    /// Resume the last verification where it stopped
+    #[clap(long, conflicts_with_all = ["deep", "path"])]
+    resume: bool,
+
+    /// Path of the sub-tree to verify
+    path: Option<PathBuf>,
+}
+
+/// Time given to the daemon to verify a page, which ends with a file that may be large
+const TIMEOUT: Duration = Duration::from_secs(3600);
+
+pub async fn main(args: Args, format: Format) -> anyhow::Result<()> {
+    let instance_name = utils::instance_name(args.instance_name.as_deref())?;
+
+    let client = utils::instance_client(&instance_name).await?;
+    let (path, deep, mut after) = if args.resume {
+        let Some(checkpoint) = client.verify_checkpoint(ctx()).await?? else {
+            println!("No verification to resume");
+            return Ok(());
+        };
+        log::info!(
+            "Resuming the verification of {} after {}",
+            checkpoint.path,
+            checkpoint.next
+        );
+        (checkpoint.path, checkpoint.deep, Some(checkpoint.next))
+    } else {
+        (args.path.unwrap_or_else(PathBuf::root), args.deep, None)
+    };
+
+    // the verification is recorded after each page, and is resumed if interrupted
+    let mut report = fsync::VerificationReport::default();
+    loop {
+        let deadline = SystemTime::now() + timeout() / 2;
+        let page = client
+            .verify(
+                ctx_with_timeout(TIMEOUT),
+                path.clone(),
+                deep,
+                after,
+                deadline,
+            )
+            .await??;
+        if format == Format::Text {
+            for path in &page.mismatched {
+                println!("M {path}");
+            }
+            for (path, reason) in &page.unverifiable {
+                println!("? {path}: {reason}");
+            }
+        }
+        after = page.next.clone();
+        report.extend(page);
+        if after.is_none() {
+            break;
+        }
+        log::info!("{} files verified", report.verified);
+    }
+
+    if format == Format::Json {
+        utils::print_json(&report)?;
+    } else {
+        println!(
+            "{} files verified, {} differ, {} could not be verified",
+            report.verified,
+            report.mismatched.len(),
+            report.unverifiable.len()
+        );
+    }
+    // the files that differ are left as conflicts
+    if !report.mismatched.is_empty() {
+        return Err(exit::conflicts(report.mismatched.len(), path));
+    }
+    Ok(())
+}
@@ -0,0 +1,52 @@
+//! Width of the text printed in the terminal, in columns.
+//! Shared by the navigator and the column output of the commands.
+
+pub trait Width {
+    fn width(&self) -> u16;
+}
+
+impl Width for str {
+    fn width(&self) -> u16 {
+        self.chars().count() as u16
+    }
+}
+
+impl Width for String {
+    fn width(&self) -> u16 {
+        self.chars().count() as u16
+    }
+}
+
+impl<S: AsRef<str>> Width for Option<S> {
+    fn width(&self) -> u16 {
+        self.as_ref().map(|s| s.as_ref().width()).unwrap_or(0)
+    }
+}
+
+/// Elide the middle of `name` with "..." if it is wider than `max_width`
+pub fn elided(name: String, max_width: u16) -> String {
+    assert!(max_width >= 5);
+    if name.width() > max_width {
+        let start_width = max_width / 2 - 1;
+        let end_width = max_width - start_width - 3;
+        let start: String = name.chars().take(start_width as usize).collect();
+        let end: String = name
+            .chars()
+            .skip(name.chars().count() - end_width as usize)
+            .collect();
+        format!("{start}...{end}")
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::elided;
+
+    #[test]
+    fn test_elided() {
+        assert_eq!(elided("short.txt".into(), 10), "short.txt");
+        assert_eq!(elided("a_very_long_name.txt".into(), 10), "a_ve...txt");
+    }
+}
@@ -0,0 +1,131 @@
+use std::{path::Path, process::Command, sync::Arc, time::Duration};
+
+use fsync::{
+    loc::{inst, user},
+    path::FsPathBuf,
+};
+use fsyncd::{
+    service::{RpcService, Service},
+    storage::fs::FileSystem,
+};
+use futures::stream::AbortHandle;
+
+/// Run fsynctl with the instances of `dir`, and return its exit code and its error object
+fn fsynctl(dir: &Path, args: &[&str]) -> (i32, serde_json::Value) {
+    let output = Command::new(env!("CARGO_BIN_EXE_fsynctl"))
+        .args(["--quiet", "--error-format", "json", "--config-dir"])
+        .arg(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    let error = match output.stderr.is_empty() {
+        true => serde_json::Value::Null,
+        false => serde_json::from_slice(&output.stderr).unwrap(),
+    };
+    (output.status.code().unwrap(), error)
+}
+
+fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("fsynctl-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn create_instance(dir: &Path, name: &str) {
+    let config_dir = dir.join("config").join(name);
+    std::fs::create_dir_all(&config_dir).unwrap();
+    std::fs::write(config_dir.join("config.json"), "{}").unwrap();
+}
+
+#[test]
+fn usage_errors() {
+    let dir = test_dir("usage");
+
+    let (code, error) = fsynctl(&dir, &["sync", "--no-such-flag"]);
+    assert_eq!(code, 2);
+    assert_eq!(error["kind"], "usage");
+    assert_eq!(error["code"], 2);
+
+    // no instance to pick
+    let (code, error) = fsynctl(&dir, &["conflicts"]);
+    assert_eq!(code, 2);
+    assert_eq!(error["kind"], "usage");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn stopped_daemon() {
+    let dir = test_dir("stopped");
+    create_instance(&dir, "drive");
+
+    for args in [
+        &["conflicts", "-n", "drive"][..],
+        &["sync", "-n", "drive"],
+        &["entry", "-n", "drive", "/a"],
+    ] {
+        let (code, error) = fsynctl(&dir, args);
+        assert_eq!(code, 4, "{args:?}");
+        assert_eq!(error["kind"], "unreachable");
+        assert!(error["hint"].is_string());
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn conflicted_instance() {
+    let dir = test_dir("conflicted");
+    create_instance(&dir, "local");
+    // the daemon runs in this process, with the same root as fsynctl
+    let root = FsPathBuf::try_from(dir.clone()).unwrap();
+    user::init_root(Some(root.clone())).unwrap();
+    for side in ["local", "remote"] {
+        std::fs::create_dir_all(root.join(side).join("dir")).unwrap();
+    }
+    std::fs::write(root.join("local/a.txt"), "local content").unwrap();
+    std::fs::write(root.join("remote/a.txt"), "remote content, longer").unwrap();
+    std::fs::write(root.join("local/dir/b.txt"), "same").unwrap();
+    std::fs::write(root.join("remote/dir/b.txt"), "same").unwrap();
+
+    let local = FileSystem::new(root.join("local")).unwrap();
+    let remote = FileSystem::new(root.join("remote")).unwrap();
+    let service = Service::new(local, remote, root.join("local"))
+        .await
+        .unwrap();
+    let (abort_handle, abort_reg) = AbortHandle::new_pair();
+    let rpc = RpcService::new(Arc::new(service), abort_handle.clone()).await;
+    let server = tokio::spawn(async move { rpc.start("local", abort_reg).await });
+    let port_file = inst::runtime_port_file("local").unwrap();
+    while !port_file.exists() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let run = |args: &'static [&'static str]| {
+        let dir = dir.clone();
+        tokio::task::spawn_blocking(move || fsynctl(&dir, args))
+    };
+
+    let (code, error) = run(&["conflicts", "-n", "local"]).await.unwrap();
+    assert_eq!(code, 3);
+    assert_eq!(error["kind"], "conflicts");
+
+    // the sync of the whole tree leaves the conflict
+    let (code, error) = run(&["sync", "-n", "local"]).await.unwrap();
+    assert_eq!(code, 3);
+    assert_eq!(error["path"], "/");
+
+    // the sub-tree without conflict
+    let (code, error) = run(&["sync", "-n", "local", "/dir"]).await.unwrap();
+    assert_eq!(code, 0);
+    assert!(error.is_null());
+
+    let (code, error) = run(&["entry", "-n", "local", "/missing"]).await.unwrap();
+    assert_eq!(code, 7);
+    assert_eq!(error["path"], "/missing");
+
+    abort_handle.abort();
+    let _ = server.await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,90 @@
+use std::process::Command;
+
+use fsync::loc::user;
+
+fn fsynctl(root: &std::path::Path, args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_fsynctl"))
+        .args(args)
+        .env(user::CONFIG_DIR_ENV, root)
+        .env("RUST_LOG", "trace")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn quiet_json_output_parses() {
+    let dir = std::env::temp_dir().join(format!("fsynctl-output-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    for name in ["drive", "photos"] {
+        create_instance(&dir, name);
+    }
+    // left over by a failed creation, only reported on the standard error
+    std::fs::create_dir_all(dir.join("config/partial")).unwrap();
+
+    let output = fsynctl(&dir, &["--quiet", "--format", "json", "list"]);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    let mut names: Vec<String> = serde_json::from_slice(&output.stdout).unwrap();
+    names.sort();
+    assert_eq!(names, ["drive", "photos"]);
+
+    // the global flags are also accepted after the command
+    let output = fsynctl(&dir, &["list", "-q", "--format=json"]);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    serde_json::from_slice::<Vec<String>>(&output.stdout).unwrap();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // an empty configuration still gives a JSON document
+    let output = fsynctl(&dir, &["--quiet", "--format", "json", "list"]);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    let names: Vec<String> = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(names.is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn create_instance(root: &std::path::Path, name: &str) {
+    let config_dir = root.join("config").join(name);
+    std::fs::create_dir_all(&config_dir).unwrap();
+    std::fs::write(config_dir.join("config.json"), "{}").unwrap();
+}
+
+#[test]
+fn config_root_flag_overrides_env() {
+    let dir = std::env::temp_dir().join(format!("fsynctl-root-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let (env_root, flag_root) = (dir.join("env"), dir.join("flag"));
+    create_instance(&env_root, "from-env");
+    create_instance(&flag_root, "from-flag");
+
+    let output = fsynctl(&env_root, &["--format", "json", "list"]);
+    let names: Vec<String> = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(names, ["from-env"]);
+    // the root in use is reported when overridden
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains(env_root.to_str().unwrap()), "{stderr}");
+
+    let flag = flag_root.to_str().unwrap();
+    let output = fsynctl(
+        &env_root,
+        &["--format", "json", "--config-dir", flag, "list"],
+    );
+    let names: Vec<String> = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(names, ["from-flag"]);
+
+    // the root must be absolute
+    let output = fsynctl(&env_root, &["--config-dir", "relative/root", "list"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn quiet_conflicts_with_verbose() {
+    let dir = std::env::temp_dir().join(format!("fsynctl-verbose-{}", std::process::id()));
+    let output = fsynctl(&dir, &["--quiet", "-v", "list"]);
+    assert!(!output.status.success());
+}
@@ -0,0 +1,500 @@
+//! Client side cache of the directory listings, to spare round-trips to the daemon
+//! when navigating back and forth in the tree.
+//!
+//! Each listing is kept for a TTL that doubles each time a live fetch finds it
+//! unchanged, and is reset to the minimum when it changes or is invalidated.
+//! Operations started through [`NodeCache::operate`] invalidate the affected entries,
+//! once when they start and once when their progress is seen completed.
+//! The number of listings is capped, the least recently fetched are evicted first.
+//!
+//! The progresses of the operations are polled with [`NodeCache::progresses`], which only
+//! fetches the progresses that changed since the previous poll of the same path.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use fsync::{
+    path::{Path, PathBuf},
+    tree::EntryNode,
+    FsyncClient, FsyncRequest, FsyncResponse, Operation, Progress, SortOrder,
+};
+
+use tarpc::client::stub::Stub;
+
+use crate::utils::{self, ctx};
+
+/// Default TTL of a listing that was just fetched or that changed
+pub const MIN_TTL: Duration = Duration::from_millis(500);
+/// Default maximum TTL of a listing that does not change
+pub const MAX_TTL: Duration = Duration::from_secs(30);
+/// Default maximum number of listings
+pub const MAX_LISTINGS: usize = 512;
+
+#[derive(Debug, Clone)]
+struct Listing {
+    node: EntryNode,
+    children: Vec<EntryNode>,
+    order: SortOrder,
+    fetched: Instant,
+    ttl: Duration,
+}
+
+impl Listing {
+    fn is_fresh(&self, now: Instant) -> bool {
+        now < self.fetched + self.ttl
+    }
+}
+
+/// The progresses of the operations within `root`, as of `cursor`
+#[derive(Debug)]
+struct Progresses {
+    root: PathBuf,
+    cursor: u64,
+    /// The operations not seen done yet
+    running: BTreeMap<PathBuf, Progress>,
+}
+
+/// Cache of the node and children of directories, keyed by path
+#[derive(Debug)]
+pub struct NodeCache {
+    listings: Mutex<BTreeMap<PathBuf, Listing>>,
+    /// Paths of the operations started and not seen completed yet
+    pending: Mutex<BTreeSet<PathBuf>>,
+    /// The progresses of the last path polled
+    progresses: Mutex<Option<Progresses>>,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    max_listings: usize,
+}
+
+impl Default for NodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NodeCache {
+    pub fn new() -> Self {
+        Self::with_ttl(MIN_TTL, MAX_TTL)
+    }
+
+    pub fn with_ttl(min_ttl: Duration, max_ttl: Duration) -> Self {
+        Self {
+            listings: Mutex::new(BTreeMap::new()),
+            pending: Mutex::new(BTreeSet::new()),
+            progresses: Mutex::new(None),
+            min_ttl,
+            max_ttl,
+            max_listings: MAX_LISTINGS,
+        }
+    }
+
+    /// Cap the number of listings to `max_listings`
+    pub fn with_max_listings(mut self, max_listings: usize) -> Self {
+        self.max_listings = max_listings.max(1);
+        self
+    }
+
+    /// Get the node at `path` and its children sorted with `order`,
+    /// from the cache if fresh and sorted the same way, or from the daemon.
+    /// With `bypass`, the listing is always fetched live, e.g. when the user asks for a refresh.
+    pub async fn node_and_children<S>(
+        &self,
+        client: &FsyncClient<S>,
+        path: &Path,
+        order: SortOrder,
+        bypass: bool,
+    ) -> anyhow::Result<(EntryNode, Vec<EntryNode>)>
+    where
+        S: Stub<Req = FsyncRequest, Resp = FsyncResponse>,
+    {
+        if !bypass {
+            if let Some(listing) = self.get(path, order, Instant::now()) {
+                return Ok(listing);
+            }
+        }
+        let (node, children) = utils::node_and_children(client, path, order).await?;
+        self.put(path, node.clone(), children.clone(), order, Instant::now());
+        Ok((node, children))
+    }
+
+    /// Start `operation` and invalidate the entries it affects
+    pub async fn operate<S>(
+        &self,
+        client: &FsyncClient<S>,
+        operation: Operation,
+    ) -> anyhow::Result<Progress>
+    where
+        S: Stub<Req = FsyncRequest, Resp = FsyncResponse>,
+    {
+        self.on_operate(&operation);
+        Ok(client.operate(ctx(), operation).await??)
+    }
+
+    /// Invalidate the entries affected by `operation`, and again when it completes.
+    /// Called by [`Self::operate`], and to be called for operations started otherwise.
+    pub fn on_operate(&self, operation: &Operation) {
+        let path = operation.path();
+        self.pending.lock().unwrap().insert(path.to_owned());
+        self.invalidate(path);
+    }
+
+    /// Invalidate the entries of the pending operations that `progresses` show completed.
+    /// `progresses` are the ones of the operations within `root`, as returned by
+    /// [`FsyncClient::progresses`]. A pending operation within `root` that is missing
+    /// from them is also considered completed.
+    pub fn on_progresses<'a, I>(&self, root: &Path, progresses: I)
+    where
+        I: IntoIterator<Item = (&'a PathBuf, &'a Progress)>,
+    {
+        let running: BTreeSet<&Path> = progresses
+            .into_iter()
+            .filter(|(_, progress)| !progress.is_done() && !progress.is_failed())
+            .map(|(path, _)| path.as_path())
+            .collect();
+        let completed: Vec<PathBuf> = {
+            let mut pending = self.pending.lock().unwrap();
+            let completed: Vec<PathBuf> = pending
+                .iter()
+                .filter(|path| {
+                    (*path == root || root.is_ancestor_of(path))
+                        && !running.contains(path.as_path())
+                })
+                .cloned()
+                .collect();
+            pending.retain(|path| !completed.contains(path));
+            completed
+        };
+        for path in completed {
+            self.invalidate(&path);
+        }
+    }
+
+    /// The progresses of the operations within `root`, as [`FsyncClient::progresses`].
+    /// Only the progresses that changed since the previous call for the same `root` are
+    /// fetched. The operations that are done are provided once, then forgotten.
+    /// The entries of the operations seen completed are invalidated.
+    pub async fn progresses<S>(
+        &self,
+        client: &FsyncClient<S>,
+        root: &Path,
+    ) -> anyhow::Result<Vec<(PathBuf, Progress)>>
+    where
+        S: Stub<Req = FsyncRequest, Resp = FsyncResponse>,
+    {
+        let cursor = match &*self.progresses.lock().unwrap() {
+            Some(progresses) if progresses.root == root => progresses.cursor,
+            _ => 0,
+        };
+        let (cursor, changed) = client
+            .progresses_since(ctx(), root.to_owned(), cursor)
+            .await??;
+        let progresses = self.merge_progresses(root, cursor, changed);
+        self.on_progresses(
+            root,
+            progresses.iter().map(|(path, progress)| (path, progress)),
+        );
+        Ok(progresses)
+    }
+
+    /// Merge the progresses that `changed` within `root` with the running ones
+    fn merge_progresses(
+        &self,
+        root: &Path,
+        cursor: u64,
+        changed: Vec<(PathBuf, Progress)>,
+    ) -> Vec<(PathBuf, Progress)> {
+        let mut progresses = self.progresses.lock().unwrap();
+        let progresses = match &mut *progresses {
+            Some(progresses) if progresses.root == root => progresses,
+            progresses => progresses.insert(Progresses {
+                root: root.to_owned(),
+                cursor: 0,
+                running: BTreeMap::new(),
+            }),
+        };
+        progresses.cursor = cursor;
+        progresses.running.extend(changed);
+        let all = progresses
+            .running
+            .iter()
+            .map(|(path, progress)| (path.clone(), progress.clone()))
+            .collect();
+        progresses.running.retain(|_, progress| !progress.is_done());
+        all
+    }
+
+    /// Same as [`Self::on_progresses`] for the progress of a single operation at `path`
+    pub fn on_progress(&self, path: &PathBuf, progress: Option<&Progress>) {
+        self.on_progresses(path, progress.map(|progress| (path, progress)));
+    }
+
+    /// Invalidate `path`, its descendants, and its ancestors whose stats depend on it
+    pub fn invalidate(&self, path: &Path) {
+        let mut listings = self.listings.lock().unwrap();
+        listings
+            .retain(|key, _| key != path && !key.is_ancestor_of(path) && !path.is_ancestor_of(key));
+    }
+
+    /// Invalidate all the entries, and fetch all the progresses again
+    pub fn clear(&self) {
+        self.listings.lock().unwrap().clear();
+        *self.progresses.lock().unwrap() = None;
+    }
+
+    fn get(
+        &self,
+        path: &Path,
+        order: SortOrder,
+        now: Instant,
+    ) -> Option<(EntryNode, Vec<EntryNode>)> {
+        let listings = self.listings.lock().unwrap();
+        listings
+            .get(path)
+            .filter(|listing| listing.order == order && listing.is_fresh(now))
+            .map(|listing| (listing.node.clone(), listing.children.clone()))
+    }
+
+    fn put(
+        &self,
+        path: &Path,
+        node: EntryNode,
+        children: Vec<EntryNode>,
+        order: SortOrder,
+        now: Instant,
+    ) {
+        let mut listings = self.listings.lock().unwrap();
+        let ttl = match listings.get(path) {
+            Some(prev) if prev.node == node && prev.children == children => {
+                (prev.ttl * 2).min(self.max_ttl)
+            }
+            _ => self.min_ttl,
+        };
+        listings.insert(
+            path.to_owned(),
+            Listing {
+                node,
+                children,
+                order,
+                fetched: now,
+                ttl,
+            },
+        );
+        while listings.len() > self.max_listings {
+            let oldest = listings
+                .iter()
+                .min_by_key(|(_, listing)| listing.fetched)
+                .map(|(path, _)| path.clone())
+                .unwrap();
+            listings.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fsync::{
+        path::PathBuf,
+        stat,
+        tree::{Entry, EntryNode},
+        DeletionMethod, Metadata,
+    };
+
+    use super::*;
+
+    fn dir_node(path: &str, children: &[&str]) -> EntryNode {
+        let metadata = Metadata::Directory {
+            path: PathBuf::from(path),
+            stat: None,
+        };
+        let children = children.iter().map(|c| c.to_string()).collect();
+        EntryNode::new(Entry::Local(metadata), children, stat::Tree::null())
+    }
+
+    fn ttl(cache: &NodeCache, path: &str) -> Duration {
+        cache.listings.lock().unwrap()[Path::new(path)].ttl
+    }
+
+    #[test]
+    fn ttl_expiry_and_growth() {
+        let cache = NodeCache::with_ttl(Duration::from_secs(1), Duration::from_secs(3));
+        let path = Path::new("/a");
+        let start = Instant::now();
+        let secs = |s: u64| start + Duration::from_secs(s);
+
+        cache.put(
+            path,
+            dir_node("/a", &["b"]),
+            vec![],
+            SortOrder::Natural,
+            start,
+        );
+        assert!(cache.get(path, SortOrder::Natural, start).is_some());
+        // a listing sorted otherwise is fetched again
+        assert!(cache.get(path, SortOrder::Raw, start).is_none());
+        assert!(cache.get(path, SortOrder::Natural, secs(1)).is_none());
+
+        // unchanged listings are kept twice longer each time, up to the maximum
+        cache.put(
+            path,
+            dir_node("/a", &["b"]),
+            vec![],
+            SortOrder::Natural,
+            secs(1),
+        );
+        assert_eq!(ttl(&cache, "/a"), Duration::from_secs(2));
+        assert!(cache.get(path, SortOrder::Natural, secs(2)).is_some());
+        assert!(cache.get(path, SortOrder::Natural, secs(3)).is_none());
+        cache.put(
+            path,
+            dir_node("/a", &["b"]),
+            vec![],
+            SortOrder::Natural,
+            secs(3),
+        );
+        assert_eq!(ttl(&cache, "/a"), Duration::from_secs(3));
+
+        // a change resets the TTL
+        cache.put(
+            path,
+            dir_node("/a", &["b", "c"]),
+            vec![],
+            SortOrder::Natural,
+            secs(6),
+        );
+        assert_eq!(ttl(&cache, "/a"), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn invalidate_on_operate() {
+        let cache = NodeCache::new();
+        let now = Instant::now();
+        for path in ["/", "/a", "/a/b", "/a/b/c", "/d"] {
+            cache.put(
+                Path::new(path),
+                dir_node(path, &[]),
+                vec![],
+                SortOrder::Natural,
+                now,
+            );
+        }
+
+        cache.on_operate(&Operation::Delete(
+            PathBuf::from("/a/b"),
+            DeletionMethod::All,
+        ));
+
+        for path in ["/", "/a", "/a/b", "/a/b/c"] {
+            assert!(
+                cache
+                    .get(Path::new(path), SortOrder::Natural, now)
+                    .is_none(),
+                "{path}"
+            );
+        }
+        assert!(cache
+            .get(Path::new("/d"), SortOrder::Natural, now)
+            .is_some());
+    }
+
+    #[test]
+    fn invalidate_on_completion() {
+        let cache = NodeCache::new();
+        let now = Instant::now();
+        let put = |path: &str| {
+            cache.put(
+                Path::new(path),
+                dir_node(path, &[]),
+                vec![],
+                SortOrder::Natural,
+                now,
+            )
+        };
+        let cached = |path: &str| {
+            cache
+                .get(Path::new(path), SortOrder::Natural, now)
+                .is_some()
+        };
+        let a = PathBuf::from("/a");
+
+        cache.on_operate(&Operation::Sync(a.clone()));
+        // listed while the operation runs
+        put("/a");
+        put("/d");
+        cache.on_progresses(Path::root(), [(&a, &Progress::Compound)]);
+        assert!(cached("/a"));
+
+        cache.on_progresses(Path::root(), [(&a, &Progress::Done)]);
+        assert!(!cached("/a"));
+        assert!(cached("/d"));
+
+        // only once
+        put("/a");
+        cache.on_progresses(Path::root(), [(&a, &Progress::Done)]);
+        assert!(cached("/a"));
+
+        // an operation that is no longer listed is completed
+        cache.on_operate(&Operation::Sync(a.clone()));
+        put("/a");
+        cache.on_progresses(Path::new("/d"), []);
+        assert!(cached("/a"));
+        cache.on_progress(&a, None);
+        assert!(!cached("/a"));
+    }
+
+    #[test]
+    fn progresses_are_merged() {
+        let cache = NodeCache::new();
+        let a = PathBuf::from("/a");
+        let b = PathBuf::from("/a/b");
+        let shown = |progresses: Vec<(PathBuf, Progress)>| -> Vec<String> {
+            progresses
+                .into_iter()
+                .map(|(path, progress)| format!("{path}: {progress:?}"))
+                .collect()
+        };
+
+        let merged = cache.merge_progresses(Path::root(), 3, vec![(a.clone(), Progress::Compound)]);
+        assert_eq!(shown(merged), ["/a: Compound"]);
+
+        // unchanged progresses are still provided
+        let merged = cache.merge_progresses(Path::root(), 5, vec![(b.clone(), Progress::Init)]);
+        assert_eq!(shown(merged), ["/a: Compound", "/a/b: Init"]);
+        assert_eq!(cache.progresses.lock().unwrap().as_ref().unwrap().cursor, 5);
+
+        // the operations done are provided once
+        let merged = cache.merge_progresses(Path::root(), 6, vec![(a.clone(), Progress::Done)]);
+        assert_eq!(shown(merged), ["/a: Done", "/a/b: Init"]);
+        let merged = cache.merge_progresses(Path::root(), 6, vec![]);
+        assert_eq!(shown(merged), ["/a/b: Init"]);
+
+        // another root starts over
+        let merged = cache.merge_progresses(Path::new("/c"), 7, vec![]);
+        assert!(merged.is_empty());
+        cache.clear();
+        assert!(cache.progresses.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn listings_are_capped() {
+        let cache = NodeCache::new().with_max_listings(2);
+        let start = Instant::now();
+        for (i, path) in ["/a", "/b", "/c"].into_iter().enumerate() {
+            cache.put(
+                Path::new(path),
+                dir_node(path, &[]),
+                vec![],
+                SortOrder::Natural,
+                start + Duration::from_millis(i as u64),
+            );
+        }
+        let listings = cache.listings.lock().unwrap();
+        assert_eq!(
+            listings.keys().map(|p| p.as_str()).collect::<Vec<_>>(),
+            ["/b", "/c"]
+        );
+    }
+}
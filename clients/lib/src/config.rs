@@ -1,11 +1,18 @@
+use std::io;
+
+use anyhow::Context;
 use fsync::{
-    loc::inst,
+    caps::FsCaps,
+    loc::{inst, user},
     path::{FsPath, FsPathBuf},
+    SecretsProtection,
 };
 use serde::{Deserialize, Serialize};
 use typescript_type_def::TypeDef;
 
 pub mod drive;
+mod keys;
+pub mod validate;
 
 #[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
 pub enum ProviderOpts {
@@ -37,25 +44,404 @@ impl TryFrom<&ProviderOpts> for fsync::ProviderConfig {
     }
 }
 
+/// Prefix of the directories in which the instances are written before being renamed
+const CREATING_PREFIX: &str = ".creating-";
+
+/// Whether `dir_name`, in the user config dir, is an instance being created
+pub fn is_creating_dir(dir_name: &str) -> bool {
+    dir_name.starts_with(CREATING_PREFIX)
+}
+
+/// Create the instance `instance_name`, synchronizing `local_dir` with the provider of `opts`.
+/// With `encryption`, the daemon prompts the passphrase of the contents at its first start.
+/// The configuration is written in a temporary directory, which is then renamed,
+/// so that a concurrent creation of the same instance fails with
+/// [`io::ErrorKind::AlreadyExists`] rather than leaving a half-written configuration.
 pub async fn create(
     instance_name: &str,
     local_dir: &FsPath,
     opts: &ProviderOpts,
+    encryption: bool,
 ) -> anyhow::Result<()> {
     if instance_name.is_empty() {
         anyhow::bail!("Instance name can't be empty");
     }
     let config_dir = inst::config_dir(instance_name)?;
-    println!("Creating configuration directory: {config_dir}");
-    tokio::fs::create_dir_all(config_dir).await?;
+    if config_dir.exists() {
+        return Err(already_exists(&config_dir));
+    }
+
+    let user_dir = user::config_dir()?;
+    tokio::fs::create_dir_all(&user_dir).await?;
+    let tmp_dir = user_dir.join(format!(
+        "{CREATING_PREFIX}{instance_name}-{}-{:08x}",
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    log::info!("Creating configuration directory: {config_dir}");
+    tokio::fs::create_dir(&tmp_dir).await?;
 
+    let res = async {
+        write_config(&tmp_dir, instance_name, local_dir, opts, encryption).await?;
+        rename_new(&tmp_dir, &config_dir)
+    }
+    .await;
+    if res.is_err() {
+        let _ = tokio::fs::remove_dir_all(&tmp_dir).await;
+    }
+    res
+}
+
+/// Write in `dir` the files of the config dir of the new instance `instance_name`
+async fn write_config(
+    dir: &FsPath,
+    instance_name: &str,
+    local_dir: &FsPath,
+    opts: &ProviderOpts,
+    encryption: bool,
+) -> anyhow::Result<()> {
     let config = fsync::Config {
         local_dir: local_dir.to_owned(),
         provider: opts.try_into()?,
+        min_free_space: None,
+        quota_warning: None,
+        max_file_size: None,
+        secrets: Default::default(),
+        ignore: Vec::new(),
+        placeholders: false,
+        cache_budget: None,
+        hooks: Vec::new(),
+        digest: None,
+        max_retries: None,
+        mappings: Vec::new(),
+        sync_mode: Default::default(),
+        maintenance: Default::default(),
+        conflict_staleness: None,
+        encryption,
+        deferral: Default::default(),
+        status_file: None,
+        delete_guard: Default::default(),
+        log_buffer: Default::default(),
+        scan_concurrency: None,
+        buffer_budget: None,
     };
     let config_json = serde_json::to_string_pretty(&config)?;
     let config_file = inst::config_file(instance_name)?;
-    println!("Writing configuration file: {config_file}");
-    tokio::fs::write(&config_file, config_json).await?;
+    log::info!("Writing configuration file: {config_file}");
+    tokio::fs::write(in_dir(dir, &config_file), config_json).await?;
+
+    // the daemon probes again at startup, so a failure here is not fatal
+    match FsCaps::probe(local_dir) {
+        Ok(caps) => {
+            let caps_file = inst::fs_caps_file(instance_name)?;
+            log::info!("Writing filesystem capabilities: {caps_file}");
+            caps.save(&in_dir(dir, &caps_file)).await?;
+        }
+        Err(err) => log::warn!("Could not probe the filesystem of {local_dir}: {err}"),
+    }
+    Ok(())
+}
+
+/// The path of `file` if it was in `dir` instead of its own directory
+fn in_dir(dir: &FsPath, file: &FsPath) -> FsPathBuf {
+    dir.join(file.file_name().expect("a file name"))
+}
+
+/// Rename `from` to `to`, which must not exist.
+/// Directories are never merged: if `to` appeared in the meantime, the rename fails.
+fn rename_new(from: &FsPath, to: &FsPath) -> anyhow::Result<()> {
+    if to.exists() {
+        return Err(already_exists(to));
+    }
+    match std::fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        // another creation renamed its own directory first
+        Err(_) if to.exists() => Err(already_exists(to)),
+        Err(err) => Err(err).with_context(|| format!("Could not move {from} to {to}")),
+    }
+}
+
+fn already_exists(config_dir: &FsPath) -> anyhow::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("Configuration already exists: {config_dir}"),
+    )
+    .into()
+}
+
+/// Names of the partial instances, which have a config dir but no config file.
+/// These are leftovers of creations interrupted before they were atomic.
+pub fn partial_instances() -> anyhow::Result<Vec<String>> {
+    let user_dir = user::config_dir()?;
+    if !user_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = Vec::new();
+    for entry in user_dir.read_dir_utf8()? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !entry.file_type()?.is_dir() || is_creating_dir(name) {
+            continue;
+        }
+        if !inst::config_file(name)?.exists() {
+            names.push(name.to_owned());
+        }
+    }
+    Ok(names)
+}
+
+/// Delete the config dir of the partial instance `instance_name`
+pub async fn delete_partial(instance_name: &str) -> anyhow::Result<()> {
+    if inst::config_file(instance_name)?.exists() {
+        anyhow::bail!("{instance_name} is not a partial instance");
+    }
+    let config_dir = inst::config_dir(instance_name)?;
+    log::info!("Deleting {config_dir}");
+    tokio::fs::remove_dir_all(&config_dir)
+        .await
+        .with_context(|| format!("Could not delete {config_dir}"))
+}
+
+/// Rename the instance `old` to `new`.
+/// The configuration, the remote cache and the token cache are moved, and so is the key
+/// of the secrets in the OS keyring. Nothing is moved if any step fails.
+/// The instance must not be running.
+pub async fn rename(old: &str, new: &str) -> anyhow::Result<()> {
+    let config = load_stopped(old).await?;
+    check_available(new)?;
+
+    let keyring = config.secrets == SecretsProtection::Keyring && keys::copy(old, new).await?;
+    let moved = move_dirs(old, new);
+    match (&moved, keyring) {
+        (Ok(()), true) => keys::delete(old).await?,
+        (Err(_), true) => keys::delete(new).await?,
+        _ => (),
+    }
+    moved
+}
+
+fn move_dirs(old: &str, new: &str) -> anyhow::Result<()> {
+    let (old_config, new_config) = (inst::config_dir(old)?, inst::config_dir(new)?);
+    let (old_cache, new_cache) = (inst::cache_dir(old)?, inst::cache_dir(new)?);
+
+    std::fs::rename(&old_config, &new_config)
+        .with_context(|| format!("Could not move {old_config} to {new_config}"))?;
+    if old_cache.exists() {
+        if let Err(err) = std::fs::rename(&old_cache, &new_cache) {
+            std::fs::rename(&new_config, &old_config)?;
+            return Err(err).with_context(|| format!("Could not move {old_cache} to {new_cache}"));
+        }
+    }
+    Ok(())
+}
+
+/// Create the instance `dst` with the configuration of `src`, synchronized in `local_dir`.
+/// The token cache is not copied, so that `dst` can be authorized with another account.
+pub async fn clone(src: &str, dst: &str, local_dir: &FsPath) -> anyhow::Result<()> {
+    let mut config = fsync::Config::load_from_file(&inst::config_file(src)?).await?;
+    check_available(dst)?;
+    if local_dir == config.local_dir {
+        anyhow::bail!("{local_dir} is already synchronized by {src}");
+    }
+
+    // the secrets files such as client_secret.json or secrets.salt are copied as well
+    let (src_config, dst_config) = (inst::config_dir(src)?, inst::config_dir(dst)?);
+    tokio::fs::create_dir_all(&dst_config).await?;
+    let res = async {
+        let mut entries = tokio::fs::read_dir(&src_config).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_file() {
+                let dst = dst_config.as_std_path().join(entry.file_name());
+                tokio::fs::copy(entry.path(), dst).await?;
+            }
+        }
+        config.local_dir = local_dir.to_owned();
+        if let fsync::ProviderConfig::GoogleDrive(drive) = &mut config.provider {
+            drive.account = None;
+        }
+        let config_json = serde_json::to_string_pretty(&config)?;
+        tokio::fs::write(inst::config_file(dst)?, config_json).await?;
+        if config.secrets == SecretsProtection::Keyring {
+            keys::copy(src, dst).await?;
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    if res.is_err() {
+        let _ = tokio::fs::remove_dir_all(&dst_config).await;
+    }
+    res
+}
+
+/// Load the config of `instance_name`, checking that the instance is not running
+/// Forget the authorization of the Drive instance `instance_name`: its token cache
+/// and its recorded account. The daemon asks for a new authorization at its next start.
+/// The instance must not be running.
+pub async fn reauthorize(instance_name: &str) -> anyhow::Result<()> {
+    let mut config = load_stopped(instance_name).await?;
+    let fsync::ProviderConfig::GoogleDrive(drive) = &mut config.provider else {
+        anyhow::bail!("{instance_name} does not synchronize a Google Drive");
+    };
+    let token_cache = inst::token_cache_file(instance_name)?;
+    match tokio::fs::remove_file(&token_cache).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(err).with_context(|| format!("Could not delete {token_cache}"));
+        }
+        _ => (),
+    }
+    if let Some(account) = drive.account.take() {
+        log::info!("Forgetting {account} as the account of {instance_name}");
+        let config_json = serde_json::to_string_pretty(&config)?;
+        tokio::fs::write(inst::config_file(instance_name)?, config_json).await?;
+    }
+    Ok(())
+}
+
+async fn load_stopped(instance_name: &str) -> anyhow::Result<fsync::Config> {
+    let config_file = inst::config_file(instance_name)?;
+    if !config_file.exists() {
+        anyhow::bail!("No such instance: {instance_name}");
+    }
+    if inst::runtime_port_file(instance_name)?.exists() {
+        anyhow::bail!("{instance_name} is running, stop its daemon first");
+    }
+    fsync::Config::load_from_file(&config_file).await
+}
+
+/// Check that no instance, or leftover of instance, is named `instance_name`
+fn check_available(instance_name: &str) -> anyhow::Result<()> {
+    if instance_name.is_empty() {
+        anyhow::bail!("Instance name can't be empty");
+    }
+    for dir in [
+        inst::config_dir(instance_name)?,
+        inst::cache_dir(instance_name)?,
+    ] {
+        if dir.exists() {
+            anyhow::bail!("{dir} already exists");
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use fsync::loc::user;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rename_and_clone() {
+        // the only test of the crate that uses the user locations
+        let root = FsPathBuf::try_from(std::env::temp_dir())
+            .unwrap()
+            .join(format!("fsync-client-config-{}", std::process::id()));
+        user::init_root(Some(root.clone())).unwrap();
+
+        let opts = ProviderOpts::LocalFs(root.join("remote"));
+        create("a", &root.join("local"), &opts, false)
+            .await
+            .unwrap();
+        create("b", &root.join("local-b"), &opts, false)
+            .await
+            .unwrap();
+
+        // concurrent creations of the same instance: a single one succeeds
+        let (local_e1, local_e2) = (root.join("local-e1"), root.join("local-e2"));
+        let (e1, e2) = tokio::join!(
+            create("e", &local_e1, &opts, false),
+            create("e", &local_e2, &opts, false)
+        );
+        let err = e1.and(e2).unwrap_err();
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(inst::config_file("e").unwrap().exists());
+        for entry in std::fs::read_dir(root.join("config")).unwrap() {
+            let name = entry.unwrap().file_name();
+            assert!(!is_creating_dir(name.to_str().unwrap()));
+        }
+
+        // a config dir without config file is a partial instance
+        tokio::fs::create_dir_all(inst::config_dir("p").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(partial_instances().unwrap(), ["p"]);
+        assert!(delete_partial("b").await.is_err());
+        delete_partial("p").await.unwrap();
+        assert!(partial_instances().unwrap().is_empty());
+        tokio::fs::create_dir_all(inst::cache_dir("a").unwrap())
+            .await
+            .unwrap();
+        tokio::fs::write(inst::token_cache_file("a").unwrap(), "{}")
+            .await
+            .unwrap();
+
+        assert!(rename("a", "b").await.is_err());
+        assert!(rename("none", "c").await.is_err());
+
+        rename("a", "c").await.unwrap();
+        assert!(!inst::config_dir("a").unwrap().exists());
+        assert!(!inst::cache_dir("a").unwrap().exists());
+        assert!(inst::token_cache_file("c").unwrap().exists());
+        let config = fsync::Config::load_from_file(&inst::config_file("c").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(config.local_dir, root.join("local"));
+
+        // a running instance is not renamed
+        tokio::fs::create_dir_all(root.join("runtime"))
+            .await
+            .unwrap();
+        tokio::fs::write(inst::runtime_port_file("c").unwrap(), "1234")
+            .await
+            .unwrap();
+        assert!(rename("c", "d").await.is_err());
+        tokio::fs::remove_file(inst::runtime_port_file("c").unwrap())
+            .await
+            .unwrap();
+
+        assert!(clone("c", "d", &root.join("local")).await.is_err());
+        clone("c", "d", &root.join("local-d")).await.unwrap();
+        let config = fsync::Config::load_from_file(&inst::config_file("d").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(config.local_dir, root.join("local-d"));
+        assert!(!inst::token_cache_file("d").unwrap().exists());
+        assert!(inst::token_cache_file("c").unwrap().exists());
+
+        // the account of a Drive instance is not cloned, and is forgotten at reauthorization
+        assert!(reauthorize("c").await.is_err());
+        let config_file = inst::config_file("c").unwrap();
+        let mut config = fsync::Config::load_from_file(&config_file).await.unwrap();
+        let opts = drive::Opts {
+            root: None,
+            secret: drive::SecretOpts::Builtin,
+        };
+        let mut drive = fsync::config::drive::Config::try_from(&opts).unwrap();
+        drive.account = Some("me@work.com".into());
+        config.provider = fsync::ProviderConfig::GoogleDrive(drive);
+        let config_json = serde_json::to_string_pretty(&config).unwrap();
+        tokio::fs::write(&config_file, config_json).await.unwrap();
+        let account = |name: &str| {
+            let config_file = inst::config_file(name).unwrap();
+            async move {
+                let config = fsync::Config::load_from_file(&config_file).await.unwrap();
+                match config.provider {
+                    fsync::ProviderConfig::GoogleDrive(drive) => drive.account,
+                    _ => unreachable!(),
+                }
+            }
+        };
+        clone("c", "g", &root.join("local-g")).await.unwrap();
+        assert_eq!(account("g").await, None);
+        assert_eq!(account("c").await.as_deref(), Some("me@work.com"));
+
+        reauthorize("c").await.unwrap();
+        assert!(!inst::token_cache_file("c").unwrap().exists());
+        assert_eq!(account("c").await, None);
+        // nothing left to forget
+        reauthorize("c").await.unwrap();
+
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -7,7 +7,7 @@ use typescript_type_def::TypeDef;
 
 use crate::cipher;
 
-#[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
 #[serde(rename = "DriveSecretOpts")]
 #[serde(rename_all = "camelCase")]
 pub enum SecretOpts {
@@ -72,6 +72,9 @@ impl SecretOpts {
                 token_url: oauth2::TokenUrl::new(
                     "https://oauth2.googleapis.com/token".to_string(),
                 )?,
+                device_auth_url: Some(oauth2::DeviceAuthorizationUrl::new(
+                    oauth2::GOOGLE_DEVICE_AUTH_URL.to_string(),
+                )?),
             }),
         }
     }
@@ -108,6 +111,12 @@ impl TryFrom<&Opts> for fsync::config::drive::Config {
         Ok(fsync::config::drive::Config {
             root: root.map(PathBuf::from),
             secret,
+            auth_flow: oauth2::Flow::default(),
+            max_upload_chunk_size: None,
+            skip_sharing: false,
+            skip_content_cache: false,
+            redirect_server: Default::default(),
+            account: None,
         })
     }
 }
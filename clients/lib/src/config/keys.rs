@@ -0,0 +1,39 @@
+//! Keys of the instances in the OS keyring, used when secrets are protected with
+//! [`SecretsProtection::Keyring`](fsync::SecretsProtection::Keyring).
+
+use anyhow::Context;
+use fsync::SecretsProtection;
+use keyring::Entry;
+
+fn entry(instance_name: &str) -> anyhow::Result<Entry> {
+    Entry::new(SecretsProtection::KEYRING_SERVICE, instance_name)
+        .context("Could not access the OS keyring")
+}
+
+/// Copy the key of `src` to `dst`.
+/// Returns whether a key was copied, as the key is created when the daemon first starts.
+pub async fn copy(src: &str, dst: &str) -> anyhow::Result<bool> {
+    let (src, dst) = (src.to_owned(), dst.to_owned());
+    tokio::task::spawn_blocking(move || {
+        let key = match entry(&src)?.get_password() {
+            Ok(key) => key,
+            Err(keyring::Error::NoEntry) => return Ok(false),
+            Err(err) => return Err(err).context("Could not read the key from the OS keyring"),
+        };
+        entry(&dst)?
+            .set_password(&key)
+            .context("Could not store the key in the OS keyring")?;
+        Ok(true)
+    })
+    .await?
+}
+
+/// Delete the key of `instance_name`, if any
+pub async fn delete(instance_name: &str) -> anyhow::Result<()> {
+    let name = instance_name.to_owned();
+    tokio::task::spawn_blocking(move || match entry(&name)?.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(err) => Err(err).context("Could not delete the key from the OS keyring"),
+    })
+    .await?
+}
@@ -0,0 +1,245 @@
+//! Validation of the fields of a new instance, shared by `fsynctl new` and the creation
+//! wizard of the UI.
+
+use fsync::{
+    loc::inst,
+    path::{FsPath, FsPathBuf},
+};
+use serde::{Deserialize, Serialize};
+use typescript_type_def::TypeDef;
+
+use crate::Instance;
+
+/// The result of the validation of the name of a new instance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum NameCheck {
+    Valid,
+    Empty,
+    /// The name has characters that can't be in a file name
+    InvalidChars(Vec<String>),
+    /// An instance of this name exists
+    Exists,
+    /// A failed creation left the directory of this name, `fsynctl doctor` deletes it
+    Partial,
+}
+
+impl NameCheck {
+    pub fn is_valid(&self) -> bool {
+        matches!(self, Self::Valid)
+    }
+}
+
+/// The result of the validation of the local directory of a new instance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum LocalDirCheck {
+    /// The directory exists, with `entries` entries
+    Exists {
+        entries: u64,
+    },
+    /// The directory does not exist, and will be created
+    Creatable,
+    NotAbsolute,
+    /// The path has characters that can't be in a path
+    InvalidChars(Vec<String>),
+    /// The path exists, and is not a directory
+    NotADirectory,
+    /// The directory can't be created, for the given reason
+    NotCreatable(String),
+    /// The directory is, contains or is inside the local directory of another instance
+    Overlaps {
+        instance: String,
+        #[type_def(type_of = "String")]
+        local_dir: FsPathBuf,
+    },
+}
+
+impl LocalDirCheck {
+    pub fn is_valid(&self) -> bool {
+        matches!(self, Self::Exists { .. } | Self::Creatable)
+    }
+}
+
+/// The state of the authorization of the Drive of a new instance
+#[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum DriveAuthCheck {
+    /// The authorization was not started, or was cancelled
+    NotStarted,
+    /// The authorization waits for the user, who follows the prompt of the progress
+    Pending(fsync::Progress),
+    /// The access is granted to the Drive of `account`
+    Granted { account: Option<String> },
+    /// The authorization failed, for the given reason
+    Failed(String),
+}
+
+/// The characters of `name` that can't be in the name of an instance, sorted
+pub fn invalid_name_chars(name: &str) -> Vec<&'static str> {
+    let mut invalid_chars = Vec::new();
+    for c in name.as_bytes() {
+        match *c {
+            b'/' => invalid_chars.push("/"),
+            b'\\' => invalid_chars.push("\\"),
+            b'<' => invalid_chars.push("<"),
+            b'>' => invalid_chars.push(">"),
+            b':' => invalid_chars.push(":"),
+            b'|' => invalid_chars.push("|"),
+            b'?' => invalid_chars.push("?"),
+            b'*' => invalid_chars.push("*"),
+            0..=31 => invalid_chars.push("<ctrl>"),
+            _ => (),
+        }
+    }
+    invalid_chars.sort_unstable();
+    invalid_chars.dedup();
+    invalid_chars
+}
+
+/// The characters of `path` that can't be in the local directory of an instance, sorted
+pub fn invalid_path_chars(path: &str) -> Vec<&'static str> {
+    let mut invalid_chars = Vec::new();
+    for c in path.as_bytes() {
+        match *c {
+            b'<' => invalid_chars.push("<"),
+            b'>' => invalid_chars.push(">"),
+            b'|' => invalid_chars.push("|"),
+            b'?' => invalid_chars.push("?"),
+            b'*' => invalid_chars.push("*"),
+            0..=31 => invalid_chars.push("<ctrl>"),
+
+            #[cfg(not(target_os = "windows"))]
+            b':' => invalid_chars.push(":"),
+            #[cfg(not(target_os = "windows"))]
+            b'\\' => invalid_chars.push("\\"),
+
+            _ => (),
+        }
+    }
+    invalid_chars.sort_unstable();
+    invalid_chars.dedup();
+    invalid_chars
+}
+
+fn owned(chars: Vec<&str>) -> Vec<String> {
+    chars.into_iter().map(str::to_owned).collect()
+}
+
+/// Check `name` as the name of a new instance
+pub fn check_name(name: &str) -> anyhow::Result<NameCheck> {
+    if name.is_empty() {
+        return Ok(NameCheck::Empty);
+    }
+    let invalid_chars = invalid_name_chars(name);
+    if !invalid_chars.is_empty() {
+        return Ok(NameCheck::InvalidChars(owned(invalid_chars)));
+    }
+    if inst::config_file(name)?.exists() {
+        return Ok(NameCheck::Exists);
+    }
+    if inst::config_dir(name)?.exists() || inst::cache_dir(name)?.exists() {
+        return Ok(NameCheck::Partial);
+    }
+    Ok(NameCheck::Valid)
+}
+
+/// Check `path` as the local directory of a new instance
+pub async fn check_local_dir(path: &FsPath) -> anyhow::Result<LocalDirCheck> {
+    let invalid_chars = invalid_path_chars(path.as_str());
+    if !invalid_chars.is_empty() {
+        return Ok(LocalDirCheck::InvalidChars(owned(invalid_chars)));
+    }
+    if !path.is_absolute() {
+        return Ok(LocalDirCheck::NotAbsolute);
+    }
+    for instance in Instance::get_all()? {
+        // an instance whose config can't be read is reported by `fsynctl doctor`
+        let Ok(config) = instance.load_config().await else {
+            continue;
+        };
+        let local_dir = config.local_dir;
+        if path.starts_with(&local_dir) || local_dir.starts_with(path) {
+            return Ok(LocalDirCheck::Overlaps {
+                instance: instance.into_name(),
+                local_dir,
+            });
+        }
+    }
+    match tokio::fs::metadata(path).await {
+        Ok(md) if md.is_dir() => {
+            let mut entries = 0;
+            let mut dir = tokio::fs::read_dir(path).await?;
+            while dir.next_entry().await?.is_some() {
+                entries += 1;
+            }
+            Ok(LocalDirCheck::Exists { entries })
+        }
+        Ok(_) => Ok(LocalDirCheck::NotADirectory),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(creatable(path)),
+        Err(err) => Ok(LocalDirCheck::NotCreatable(err.to_string())),
+    }
+}
+
+/// Whether the missing directory `path` can be created, by its closest existing ancestor
+fn creatable(path: &FsPath) -> LocalDirCheck {
+    let Some(ancestor) = path.ancestors().skip(1).find(|dir| dir.exists()) else {
+        return LocalDirCheck::NotCreatable("no parent directory exists".into());
+    };
+    match std::fs::metadata(ancestor) {
+        Ok(md) if !md.is_dir() => {
+            LocalDirCheck::NotCreatable(format!("{ancestor} is not a directory"))
+        }
+        Ok(md) if md.permissions().readonly() => {
+            LocalDirCheck::NotCreatable(format!("{ancestor} is read-only"))
+        }
+        Ok(_) => LocalDirCheck::Creatable,
+        Err(err) => LocalDirCheck::NotCreatable(format!("{ancestor}: {err}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_chars_are_sorted_once() {
+        assert!(invalid_name_chars("drive").is_empty());
+        assert_eq!(invalid_name_chars("a/b:c/d"), ["/", ":"]);
+        assert_eq!(invalid_name_chars("a\tb"), ["<ctrl>"]);
+        assert!(invalid_path_chars("/home/user/drive").is_empty());
+        assert_eq!(invalid_path_chars("/home/*/dr?ve"), ["*", "?"]);
+    }
+
+    #[tokio::test]
+    async fn local_dir_checks() {
+        let root = FsPathBuf::try_from(std::env::temp_dir())
+            .unwrap()
+            .join(format!("fsync-client-validate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir/a.txt"), "a").unwrap();
+        std::fs::write(root.join("file"), "file").unwrap();
+
+        let check = |path: FsPathBuf| async move { check_local_dir(&path).await.unwrap() };
+        assert_eq!(
+            check(root.join("dir")).await,
+            LocalDirCheck::Exists { entries: 1 }
+        );
+        assert_eq!(
+            check(root.join("missing/deep")).await,
+            LocalDirCheck::Creatable
+        );
+        assert_eq!(check(root.join("file")).await, LocalDirCheck::NotADirectory);
+        assert!(matches!(
+            check(root.join("file/sub")).await,
+            LocalDirCheck::NotCreatable(..)
+        ));
+        assert_eq!(
+            check(FsPathBuf::from("relative/dir")).await,
+            LocalDirCheck::NotAbsolute
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,398 @@
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    sync::{Arc, Weak},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use fsync::{
+    loc::inst,
+    runtime::{PortFile, PROBE_TIMEOUT},
+    sleep::Detector,
+    FsyncClient, FsyncRequest, FsyncResponse, PROTOCOL_VERSION,
+};
+use tarpc::{
+    client::{self, stub::Stub, RpcError},
+    context,
+};
+use tokio::sync::{watch, Mutex};
+
+use crate::Instance;
+
+/// Read the authentication token of a running instance.
+/// The token file is only readable by the user running the daemon.
+pub fn instance_token(instance_name: &str) -> anyhow::Result<String> {
+    let path = inst::runtime_token_file(instance_name)?;
+    let token = std::fs::read_to_string(&path)
+        .with_context(|| format!("Could not read the token of {instance_name} from {path}"))?;
+    Ok(token.trim().to_string())
+}
+
+/// The channel of a connection to fsyncd
+pub type Channel = client::Channel<FsyncRequest, FsyncResponse>;
+
+/// Connect to the fsyncd instance listening on `port`, and authenticate with `token`.
+///
+/// The protocol version of the daemon is checked, so that a daemon
+/// older than this client is reported with a clear error, rather than
+/// with a deserialization error at the first unknown request.
+pub async fn connect(port: u16, token: &str) -> anyhow::Result<FsyncClient> {
+    Ok(connect_channel(port, token).await?.into())
+}
+
+/// Same as [`connect`], but return the channel, so that the caller can wrap it
+/// in its own [`tarpc::client::stub::Stub`].
+pub async fn connect_channel(port: u16, token: &str) -> anyhow::Result<Channel> {
+    let channel = open_channel(port).await?;
+    authenticate(&channel, token).await?;
+    Ok(channel)
+}
+
+/// Open a channel on `port` and check the protocol version of the daemon
+async fn open_channel(port: u16) -> anyhow::Result<Channel> {
+    let addr = (IpAddr::V6(Ipv6Addr::LOCALHOST), port);
+    let mut transport = tarpc::serde_transport::tcp::connect(addr, fsync::codec);
+    transport.config_mut().max_frame_length(usize::MAX);
+
+    let channel = client::new(client::Config::default(), transport.await?).spawn();
+    let version = FsyncClient::from(channel.clone())
+        .protocol_version(context::current())
+        .await
+        .ok();
+    check_version(version)?;
+    Ok(channel)
+}
+
+async fn authenticate(channel: &Channel, token: &str) -> anyhow::Result<()> {
+    FsyncClient::from(channel.clone())
+        .authenticate(context::current(), token.to_string())
+        .await??;
+    Ok(())
+}
+
+/// Why a [`Connection`] to an instance could not be opened,
+/// so that the clients can tell a daemon to start from an instance to create
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectError {
+    /// No instance has this name
+    NoSuchInstance(String),
+    /// The daemon of the instance is not running, or does not answer
+    NotRunning(String),
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoSuchInstance(name) => write!(f, "The instance {name} no longer exists"),
+            Self::NotRunning(name) => write!(f, "The fsyncd {name} instance is not running"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// Interval between two pings of the daemon by a [`Connection`]
+pub const HEARTBEAT: Duration = Duration::from_secs(5);
+
+/// A connection to an fsyncd instance that survives the restarts of the daemon.
+///
+/// The daemon is pinged every [`HEARTBEAT`], and when a ping or a call fails,
+/// the connection is established again on the port found in the port file of the instance,
+/// which may have changed since the restart.
+/// The call that failed is not retried, as it may have reached the daemon.
+/// The connection is also established again when the system resumes from sleep,
+/// which is told from the clocks read at each heartbeat (see [`fsync::sleep`]).
+///
+/// Each time the daemon answers with a new boot id, the [generation](Self::generation)
+/// is incremented, so that the clients reload the state obtained from the previous daemon.
+///
+/// The [config fingerprint](fsync::Config::fingerprint) of the daemon is read from its port file
+/// at each heartbeat, and from the replies to [`fsync::Fsync::status`] and
+/// [`fsync::Fsync::instance_stats`]. When it changes, the clients are notified through
+/// [`Self::subscribe_config`], so that they fetch again the data derived from the config.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    instance_name: String,
+    state: std::sync::Mutex<State>,
+    /// Serializes the reconnections
+    reconnecting: Mutex<()>,
+    generation: watch::Sender<u64>,
+    config_fingerprint: watch::Sender<Option<String>>,
+}
+
+#[derive(Debug)]
+struct State {
+    channel: Channel,
+    /// Number of times the channel was established again
+    epoch: u64,
+    boot_id: u64,
+}
+
+impl Connection {
+    /// Connect to the running instance `instance_name`, and start pinging it
+    pub async fn open(instance_name: &str) -> anyhow::Result<Self> {
+        let (channel, boot_id) = establish(instance_name).await?;
+        let config_fingerprint = port_file_fingerprint(instance_name);
+        let inner = Arc::new(Inner {
+            instance_name: instance_name.to_owned(),
+            state: std::sync::Mutex::new(State {
+                channel,
+                epoch: 0,
+                boot_id,
+            }),
+            reconnecting: Mutex::new(()),
+            generation: watch::Sender::new(0),
+            config_fingerprint: watch::Sender::new(config_fingerprint),
+        });
+        tokio::spawn(heartbeat(Arc::downgrade(&inner)));
+        Ok(Self { inner })
+    }
+
+    pub fn instance_name(&self) -> &str {
+        &self.inner.instance_name
+    }
+
+    /// Number of times the daemon restarted since the connection was opened
+    pub fn generation(&self) -> u64 {
+        *self.inner.generation.borrow()
+    }
+
+    /// Subscribe to the changes of [generation](Self::generation)
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.inner.generation.subscribe()
+    }
+
+    /// The fingerprint of the config loaded by the daemon, if known
+    pub fn config_fingerprint(&self) -> Option<String> {
+        self.inner.config_fingerprint.borrow().clone()
+    }
+
+    /// Subscribe to the changes of the [config fingerprint](Self::config_fingerprint)
+    pub fn subscribe_config(&self) -> watch::Receiver<Option<String>> {
+        self.inner.config_fingerprint.subscribe()
+    }
+}
+
+impl Stub for Connection {
+    type Req = FsyncRequest;
+    type Resp = FsyncResponse;
+
+    async fn call(
+        &self,
+        ctx: context::Context,
+        request_name: &'static str,
+        request: FsyncRequest,
+    ) -> Result<FsyncResponse, RpcError> {
+        let (channel, epoch) = self.inner.channel();
+        let res = channel.call(ctx, request_name, request).await;
+        match &res {
+            Ok(FsyncResponse::Status(Ok(status))) => self
+                .inner
+                .set_config_fingerprint(status.config_fingerprint.clone()),
+            Ok(FsyncResponse::InstanceStats(Ok(stats))) => self
+                .inner
+                .set_config_fingerprint(stats.config_fingerprint.clone()),
+            _ => (),
+        }
+        if let Err(RpcError::Shutdown | RpcError::Send(_) | RpcError::Receive(_)) = &res {
+            if let Err(err) = self.inner.reconnect(epoch).await {
+                log::warn!(
+                    "Could not reconnect to {}: {err:#}",
+                    self.inner.instance_name
+                );
+            }
+        }
+        res
+    }
+}
+
+impl Inner {
+    fn channel(&self) -> (Channel, u64) {
+        let state = self.state.lock().unwrap();
+        (state.channel.clone(), state.epoch)
+    }
+
+    /// Record the config fingerprint reported by the daemon, and notify its changes.
+    /// An unknown fingerprint, e.g. from an older daemon, changes nothing.
+    fn set_config_fingerprint(&self, fingerprint: Option<String>) {
+        let Some(fingerprint) = fingerprint else {
+            return;
+        };
+        self.config_fingerprint.send_if_modified(|current| {
+            if current.as_ref() == Some(&fingerprint) {
+                return false;
+            }
+            log::info!(
+                "The config of {} changed: {fingerprint}",
+                self.instance_name
+            );
+            *current = Some(fingerprint);
+            true
+        });
+    }
+
+    /// Establish the connection again, unless it was done since `epoch`
+    async fn reconnect(&self, epoch: u64) -> anyhow::Result<()> {
+        let _reconnecting = self.reconnecting.lock().await;
+        if self.state.lock().unwrap().epoch != epoch {
+            return Ok(());
+        }
+        log::info!("Reconnecting to {}", self.instance_name);
+        let (channel, boot_id) = establish(&self.instance_name).await?;
+        let restarted = {
+            let mut state = self.state.lock().unwrap();
+            state.channel = channel;
+            state.epoch += 1;
+            std::mem::replace(&mut state.boot_id, boot_id) != boot_id
+        };
+        if restarted {
+            log::info!("{} was restarted", self.instance_name);
+            self.generation.send_modify(|gen| *gen += 1);
+        }
+        Ok(())
+    }
+}
+
+/// Connect to the instance `name` on the port of its port file,
+/// and get the boot id of the daemon.
+///
+/// A port file on which the daemon that wrote it does not answer within [`PROBE_TIMEOUT`]
+/// was left behind by a crash. It is removed and the instance is reported as not running.
+async fn establish(name: &str) -> anyhow::Result<(Channel, u64)> {
+    let Some(instance) = Instance::get(name)? else {
+        return Err(ConnectError::NoSuchInstance(name.to_owned()).into());
+    };
+    let Some(port_file) = instance.port_file() else {
+        return Err(ConnectError::NotRunning(name.to_owned()).into());
+    };
+    let (channel, boot_id) = match tokio::time::timeout(PROBE_TIMEOUT, probe(port_file)).await {
+        Ok(Ok(Some(res))) => res,
+        Ok(Err(err)) => return Err(err),
+        Ok(Ok(None)) | Err(_) => {
+            remove_stale(name, port_file)?;
+            return Err(ConnectError::NotRunning(name.to_owned()).into());
+        }
+    };
+    let token = instance_token(name)?;
+    authenticate(&channel, &token).await?;
+    Ok((channel, boot_id))
+}
+
+/// Open a channel on the port of `port_file` and ping the daemon, before authentication,
+/// so that a daemon with another token is still recognized.
+/// Returns `None` if the daemon that wrote the file does not answer.
+async fn probe(port_file: &PortFile) -> anyhow::Result<Option<(Channel, u64)>> {
+    let channel = match open_channel(port_file.port).await {
+        Ok(channel) => channel,
+        // nothing listens on the port
+        Err(err) if err.is::<std::io::Error>() => return Ok(None),
+        // only older daemons, that wrote no boot id, can't tell their protocol version
+        Err(_) if port_file.boot_id.is_some() => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let boot_id = match FsyncClient::from(channel.clone())
+        .ping(context::current())
+        .await
+    {
+        Ok(boot_id) if port_file.is_written_by(boot_id) => boot_id,
+        _ => return Ok(None),
+    };
+    Ok(Some((channel, boot_id)))
+}
+
+/// Remove the port file left behind by a daemon of instance `name` that crashed
+fn remove_stale(name: &str, port_file: &PortFile) -> anyhow::Result<()> {
+    let path = inst::runtime_port_file(name)?;
+    match port_file.remove_stale(&path) {
+        Ok(true) => log::info!("Removed the stale port file {path}"),
+        Ok(false) => (),
+        // the next daemon overwrites it anyway
+        Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+            log::warn!("Could not remove the stale port file {path}: {err}")
+        }
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+/// The config fingerprint written by the daemon of instance `name` in its port file
+fn port_file_fingerprint(name: &str) -> Option<String> {
+    let path = inst::runtime_port_file(name).ok()?;
+    PortFile::load(&path).ok()??.config_fingerprint
+}
+
+/// Ping the daemon of `inner` until the connection is dropped,
+/// and establish the connection again when the daemon does not answer
+/// or when the system resumed from sleep
+async fn heartbeat(inner: Weak<Inner>) {
+    let mut interval = tokio::time::interval(HEARTBEAT);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the first tick completes immediately
+    interval.tick().await;
+    let mut detector = Detector::new(HEARTBEAT);
+    loop {
+        interval.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            break;
+        };
+        let (channel, epoch) = inner.channel();
+        // the connection may not have survived the sleep: it is established again at once,
+        // rather than after a ping timed out
+        if let Some(asleep) = detector.check() {
+            log::info!(
+                "Resumed after {}s of sleep, reconnecting to {}",
+                asleep.as_secs(),
+                inner.instance_name
+            );
+            if let Err(err) = inner.reconnect(epoch).await {
+                log::debug!("Could not reconnect to {}: {err:#}", inner.instance_name);
+            }
+            continue;
+        }
+        let mut ctx = context::current();
+        ctx.deadline = SystemTime::now() + HEARTBEAT;
+        if FsyncClient::from(channel).ping(ctx).await.is_ok() {
+            inner.set_config_fingerprint(port_file_fingerprint(&inner.instance_name));
+            continue;
+        }
+        if let Err(err) = inner.reconnect(epoch).await {
+            log::debug!("Could not reconnect to {}: {err:#}", inner.instance_name);
+        }
+    }
+}
+
+/// Check the protocol version of the daemon.
+/// `None` means that the daemon could not answer, which is the case of
+/// daemons that predate protocol versioning.
+fn check_version(daemon_version: Option<u32>) -> anyhow::Result<()> {
+    match daemon_version {
+        Some(version) if version >= PROTOCOL_VERSION => Ok(()),
+        Some(version) => anyhow::bail!(
+            "fsyncd is older than this client (protocol version {version}, expected {PROTOCOL_VERSION}), please upgrade fsyncd"
+        ),
+        None => anyhow::bail!(
+            "fsyncd is older than this client (no protocol version), please upgrade fsyncd"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daemon_version() {
+        assert!(check_version(Some(PROTOCOL_VERSION)).is_ok());
+        assert!(check_version(Some(PROTOCOL_VERSION + 1)).is_ok());
+
+        let err = check_version(Some(PROTOCOL_VERSION - 1)).unwrap_err();
+        assert!(err.to_string().contains("please upgrade fsyncd"));
+        let err = check_version(None).unwrap_err();
+        assert!(err.to_string().contains("please upgrade fsyncd"));
+    }
+}
@@ -0,0 +1,118 @@
+//! Preview of the differences between the local and remote versions of a file.
+
+use fsync::{
+    path::Path,
+    text::{has_binary_extension, is_binary},
+    FsyncClient, FsyncRequest, FsyncResponse, StorageLoc,
+};
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use tarpc::client::stub::Stub;
+use typescript_type_def::TypeDef;
+
+use crate::utils::ctx;
+
+/// Files larger than this are not previewed
+pub const MAX_PREVIEW_SIZE: u64 = 256 * 1024;
+
+/// Why a preview is not available
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum Unavailable {
+    /// One of the files is not a text file
+    Binary,
+    /// One of the files is larger than [MAX_PREVIEW_SIZE]
+    TooLarge,
+}
+
+/// A preview of the differences between local and remote files
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum Preview {
+    /// Unified diff from the local to the remote content.
+    /// Empty if the contents are identical.
+    Diff(String),
+    /// The files can't be previewed
+    Unavailable(Unavailable),
+}
+
+/// Produce a unified diff between the `local` and `remote` contents of `path`.
+pub fn preview(path: &Path, local: &[u8], remote: &[u8]) -> Preview {
+    if local.len() as u64 > MAX_PREVIEW_SIZE || remote.len() as u64 > MAX_PREVIEW_SIZE {
+        return Preview::Unavailable(Unavailable::TooLarge);
+    }
+    if has_binary_extension(path) || is_binary(local) || is_binary(remote) {
+        return Preview::Unavailable(Unavailable::Binary);
+    }
+    let local = String::from_utf8_lossy(local);
+    let remote = String::from_utf8_lossy(remote);
+    let diff = TextDiff::from_lines(&*local, &*remote)
+        .unified_diff()
+        .header(&format!("local{path}"), &format!("remote{path}"))
+        .to_string();
+    Preview::Diff(diff)
+}
+
+/// Fetch both versions of the file at `path` through the daemon and produce a preview.
+pub async fn fetch_preview<S>(client: &FsyncClient<S>, path: &Path) -> anyhow::Result<Preview>
+where
+    S: Stub<Req = FsyncRequest, Resp = FsyncResponse>,
+{
+    let node = client
+        .entry_node_page(ctx(), path.to_owned(), None, 0)
+        .await??;
+    let Some(node) = node else {
+        anyhow::bail!("No entry found at {path}");
+    };
+    let (local_size, remote_size) = match node.entry() {
+        fsync::tree::Entry::Sync { local, remote, .. } => (local.size(), remote.size()),
+        _ => anyhow::bail!("{path} is not present on both drives"),
+    };
+    let (Some(local_size), Some(remote_size)) = (local_size, remote_size) else {
+        return Ok(Preview::Unavailable(Unavailable::Binary));
+    };
+    if local_size > MAX_PREVIEW_SIZE || remote_size > MAX_PREVIEW_SIZE {
+        return Ok(Preview::Unavailable(Unavailable::TooLarge));
+    }
+    let local = client.read_head(ctx(), path.to_owned(), StorageLoc::Local, MAX_PREVIEW_SIZE);
+    let remote = client.read_head(ctx(), path.to_owned(), StorageLoc::Remote, MAX_PREVIEW_SIZE);
+    let (local, remote) = tokio::try_join!(local, remote)?;
+    Ok(preview(path, &local?, &remote?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preview_text_diff() {
+        let path = Path::new("/notes.txt");
+        let res = preview(path, b"one\ntwo\nthree\n", b"one\n2\nthree\n");
+        let Preview::Diff(diff) = res else {
+            panic!("expected a diff, got {res:?}");
+        };
+        assert!(diff.starts_with("--- local/notes.txt\n+++ remote/notes.txt\n"));
+        assert!(diff.contains("-two\n+2\n"));
+
+        let res = preview(path, b"same\n", b"same\n");
+        assert_eq!(res, Preview::Diff(String::new()));
+    }
+
+    #[test]
+    fn preview_unavailable() {
+        let text = Path::new("/notes.txt");
+        assert_eq!(
+            preview(text, b"text\0with nul", b"text"),
+            Preview::Unavailable(Unavailable::Binary)
+        );
+        assert_eq!(
+            preview(Path::new("/image.PNG"), b"text", b"text"),
+            Preview::Unavailable(Unavailable::Binary)
+        );
+        let large = vec![b'a'; MAX_PREVIEW_SIZE as usize + 1];
+        assert_eq!(
+            preview(text, &large, b"text"),
+            Preview::Unavailable(Unavailable::TooLarge)
+        );
+    }
+}
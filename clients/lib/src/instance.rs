@@ -1,12 +1,9 @@
-use std::net::{IpAddr, Ipv6Addr};
-
-use fsync::FsyncClient;
-use tarpc::{client, tokio_serde::formats::Bincode};
+use fsync::{runtime::PortFile, FsyncClient};
 
 #[derive(Debug, Clone)]
 pub struct Instance {
     name: String,
-    port: Option<u16>,
+    port_file: Option<PortFile>,
 }
 
 impl Instance {
@@ -14,12 +11,34 @@ impl Instance {
         &self.name
     }
 
+    /// Whether the instance has a port file.
+    /// The daemon that wrote it may have crashed, which is only detected when connecting.
     pub fn running(&self) -> bool {
-        self.port.is_some()
+        self.port_file.is_some()
     }
 
     pub fn port(&self) -> Option<u16> {
-        self.port
+        self.port_file.as_ref().map(|file| file.port)
+    }
+
+    pub fn port_file(&self) -> Option<&PortFile> {
+        self.port_file.as_ref()
+    }
+
+    /// Whether the config file changed since the running daemon loaded it,
+    /// compared by [fingerprint](fsync::Config::fingerprint) without calling the daemon.
+    /// `None` if the instance is not running, or if its daemon wrote no fingerprint.
+    pub async fn config_changed(&self) -> anyhow::Result<Option<bool>> {
+        let Some(loaded) = self
+            .port_file
+            .as_ref()
+            .and_then(|file| file.config_fingerprint.as_deref())
+        else {
+            return Ok(None);
+        };
+        let path = fsync::loc::inst::config_file(&self.name)?;
+        let config = fsync::Config::load_from_file(&path).await?;
+        Ok(Some(config.fingerprint() != loaded))
     }
 
     pub fn get_all() -> anyhow::Result<Vec<Instance>> {
@@ -38,34 +57,41 @@ impl Instance {
                 continue;
             }
             let name = entry.file_name().to_owned();
-            let cfg_file = loc::inst::config_file(&name)?;
-            if !cfg_file.exists() {
+            if crate::config::is_creating_dir(&name) {
                 continue;
             }
-            let mut port = None;
-            let port_path = loc::inst::runtime_port_file(&name)?;
-            if port_path.exists() && port_path.is_file() {
-                let content = std::fs::read(&port_path)?;
-                let content = String::from_utf8(content)?;
-                port = Some(str::parse(&content)?);
+            if let Some(instance) = Self::get(&name)? {
+                instances.push(instance);
             }
-            instances.push(Instance { name, port });
         }
 
         Ok(instances)
     }
 
+    /// Get the instance `name`, with the port found in its port file.
+    /// Returns `None` if the instance does not exist.
+    pub fn get(name: &str) -> anyhow::Result<Option<Instance>> {
+        use fsync::loc;
+
+        let cfg_file = loc::inst::config_file(name)?;
+        if !cfg_file.exists() {
+            return Ok(None);
+        }
+        let port_file = PortFile::load(&loc::inst::runtime_port_file(name)?)?;
+        Ok(Some(Instance {
+            name: name.to_owned(),
+            port_file,
+        }))
+    }
+
     /// Make a client for this instance.
     ///
     /// # Panics
     /// Panic if this instance is not running.
     pub async fn make_client(&self) -> anyhow::Result<FsyncClient> {
-        let port = self.port.expect("This instance should be running");
-        let addr = (IpAddr::V6(Ipv6Addr::LOCALHOST), port);
-        let mut transport = tarpc::serde_transport::tcp::connect(addr, Bincode::default);
-        transport.config_mut().max_frame_length(usize::MAX);
-
-        Ok(FsyncClient::new(client::Config::default(), transport.await?).spawn())
+        let port = self.port().expect("This instance should be running");
+        let token = crate::instance_token(&self.name)?;
+        crate::connect(port, &token).await
     }
 
     pub fn into_name(self) -> String {
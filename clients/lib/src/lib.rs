@@ -1,8 +1,14 @@
+mod connection;
 mod instance;
 
+pub mod cache;
 pub mod cipher;
 pub mod config;
+pub mod diff;
 pub mod ts;
 pub mod utils;
 
+pub use connection::{
+    connect, connect_channel, instance_token, Channel, ConnectError, Connection, HEARTBEAT,
+};
 pub use instance::Instance;
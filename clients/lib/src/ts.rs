@@ -1,25 +1,122 @@
-use fsync::path::PathBuf;
+use chrono::{DateTime, Utc};
+use fsync::{
+    fmt::{human_bytes, human_mtime, Unit},
+    path::PathBuf,
+};
 use serde::{Deserialize, Serialize};
-use typescript_type_def::TypeDef;
+use std::io;
+use typescript_type_def::{write_definition_file, DefinitionFileOptions, TypeDef};
 
+// TypeDef is implemented for tuples of up to 16 elements
+/// All the types crossing the boundary between the daemon, the clients and the UI frontend
 pub type Types = (
-    fsync::Error,
-    fsync::Provider,
-    fsync::StorageDir,
-    fsync::StorageLoc,
-    fsync::Operation,
-    fsync::Progress,
-    PathProgress,
-    Instance,
-    crate::config::drive::SecretOpts,
-    crate::config::drive::Opts,
-    crate::config::ProviderOpts,
-    fsync::Metadata,
-    EntryType,
-    TreeEntry,
-    NodeAndChildren,
+    (
+        fsync::Error,
+        fsync::PathError,
+        fsync::Provider,
+        fsync::Location,
+        fsync::StorageDir,
+        fsync::StorageLoc,
+        fsync::Metadata,
+        fsync::tree::Entry,
+        fsync::tree::EntryNode,
+        fsync::Conflict,
+        fsync::ConflictDetail,
+        fsync::Sharing,
+        fsync::SharingRole,
+        fsync::SortOrder,
+        fsync::fmt::Unit,
+    ),
+    (
+        fsync::Operation,
+        fsync::ResolutionMethod,
+        fsync::DeletionMethod,
+        fsync::OrderBy,
+        fsync::Action,
+        fsync::PlannedAction,
+        fsync::OperateOptions,
+        fsync::OperationReport,
+        fsync::Progress,
+        fsync::InstanceStats,
+        fsync::CacheUsage,
+        fsync::CacheLookups,
+        fsync::RescanReport,
+        fsync::RemotePhase,
+        fsync::Status,
+        fsync::CorruptFile,
+    ),
+    (
+        fsync::Discrepancy,
+        fsync::SelfCheckPage,
+        fsync::MaintenanceReport,
+        fsync::Cleanup,
+        fsync::CleanupKind,
+        fsync::Checksum,
+        fsync::HashAlgo,
+        fsync::Counters,
+        fsync::InstanceCounters,
+        fsync::InstanceRepairs,
+        fsync::BufferUsage,
+        fsync::VerificationReport,
+        fsync::VerifyCheckpoint,
+        fsync::Resumed,
+        fsync::filter::EntryFilter,
+    ),
+    (
+        fsync::RemoteRoot,
+        fsync::RootChange,
+        fsync::MigrationChoice,
+        fsync::ViewOnly,
+        fsync::PolicyState,
+        fsync::Timings,
+        fsync::PhaseTimings,
+        fsync::Checkpoint,
+        fsync::Revision,
+        fsync::RemoteLink,
+        fsync::LogLevel,
+        fsync::LogRecord,
+        fsync::TransferCause,
+        fsync::TransferCounters,
+        fsync::Transfers,
+        fsync::InstanceTransfers,
+    ),
+    (
+        fsync::stat::Dir,
+        fsync::stat::Node,
+        fsync::stat::Tree,
+        fsync::stat::Quota,
+        fsync::stat::Aggregation,
+        fsync::stat::Flagged,
+        fsync::caps::FsCaps,
+    ),
+    (
+        PathProgress,
+        Instance,
+        crate::config::drive::SecretOpts,
+        crate::config::drive::Opts,
+        crate::config::ProviderOpts,
+        EntryType,
+        TreeEntry,
+        EntryFmt,
+        NodeAndChildren,
+        TransferRow,
+        crate::diff::Preview,
+        crate::config::validate::NameCheck,
+        crate::config::validate::LocalDirCheck,
+        crate::config::validate::DriveAuthCheck,
+    ),
 );
 
+/// Path of the definitions used by the UI frontend, relative to this crate
+pub const DEFINITIONS_FILE: &str = "../ui/src/lib/types.d.ts";
+
+/// Write the Typescript definitions of all [`Types`] in a single file
+pub fn write_definitions<W: io::Write>(writer: W) -> io::Result<()> {
+    write_definition_file::<_, Types>(writer, DefinitionFileOptions::default())
+        .map_err(io::Error::other)?;
+    Ok(())
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
 #[serde(rename_all = "camelCase")]
 pub struct Instance {
@@ -74,6 +171,56 @@ pub struct TreeEntry {
     pub entry: fsync::tree::Entry,
     pub children: Vec<String>,
     pub stats: fsync::stat::Tree,
+    /// 1 if the entry itself is a conflict, 0 otherwise
+    pub conflicts_here: i64,
+    /// The number of conflicts among the descendants, the entry itself excluded
+    pub conflicts_below: i64,
+    pub fmt: EntryFmt,
+    /// The entry is pinned, it is never deleted nor overwritten
+    pub pinned: bool,
+    /// How the remote entry is shared, if it is
+    pub sharing: Option<fsync::Sharing>,
+    /// The remote file can be viewed but not downloaded
+    pub view_only: Option<fsync::ViewOnly>,
+    /// How complete the remote stats are
+    pub aggregation: fsync::stat::Aggregation,
+    /// The entries of implausible metadata in the sub-tree
+    pub implausible: fsync::stat::Flagged,
+    /// The description of the conflict, if the entry is conflicting
+    pub conflict_detail: Option<fsync::ConflictDetail>,
+}
+
+impl TreeEntry {
+    /// Set whether the entry is pinned, as provided by [`fsync::Fsync::pinned`]
+    pub fn with_pinned(mut self, pinned: bool) -> Self {
+        self.pinned = pinned;
+        self
+    }
+
+    /// Set how the remote entry is shared, as provided by [`fsync::Fsync::sharing`]
+    pub fn with_sharing(mut self, sharing: Option<fsync::Sharing>) -> Self {
+        self.fmt.sharing = sharing.map(|sharing| sharing.to_string());
+        self.sharing = sharing;
+        self
+    }
+
+    /// Set whether the remote file can only be viewed, as provided by [`fsync::Fsync::view_only`]
+    pub fn with_view_only(mut self, view_only: Option<fsync::ViewOnly>) -> Self {
+        self.view_only = view_only;
+        self
+    }
+
+    /// Set how complete the remote stats are, as provided by [`fsync::Fsync::aggregation`]
+    pub fn with_aggregation(mut self, aggregation: fsync::stat::Aggregation) -> Self {
+        self.aggregation = aggregation;
+        self
+    }
+
+    /// Set the entries of implausible metadata, as provided by [`fsync::Fsync::implausible_stats`]
+    pub fn with_implausible(mut self, implausible: fsync::stat::Flagged) -> Self {
+        self.implausible = implausible;
+        self
+    }
 }
 
 impl From<fsync::tree::EntryNode> for TreeEntry {
@@ -81,17 +228,107 @@ impl From<fsync::tree::EntryNode> for TreeEntry {
         let path = value.path().to_owned();
         let name = path.file_name().map(|s| s.to_owned());
         let stats = value.stats();
+        let conflicts_here = value.conflicts_here();
+        let conflicts_below = value.conflicts_below();
         let (entry, children, _) = value.into_parts();
+        let fmt = EntryFmt::new(&entry, Utc::now());
+        let conflict_detail = match &entry {
+            fsync::tree::Entry::Sync {
+                local,
+                remote,
+                conflict: Some(conflict),
+            } => Some(fsync::ConflictDetail::new(*conflict, local, remote)),
+            _ => None,
+        };
         TreeEntry {
             path,
             name,
             entry,
-            children,
+            children: children.iter().cloned().collect(),
             stats,
+            conflicts_here,
+            conflicts_below,
+            fmt,
+            pinned: false,
+            sharing: None,
+            view_only: None,
+            aggregation: fsync::stat::Aggregation::Exact,
+            implausible: fsync::stat::Flagged::default(),
+            conflict_detail,
         }
     }
 }
 
+/// Pre-formatted size, modification time and sharing of an entry, for display.
+/// Fields are `None` when the entry doesn't exist at the location,
+/// when there is no modification time (directories), or when the entry is not shared.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct EntryFmt {
+    pub local_size: Option<String>,
+    pub remote_size: Option<String>,
+    pub local_mtime: Option<String>,
+    pub remote_mtime: Option<String>,
+    pub sharing: Option<String>,
+}
+
+impl EntryFmt {
+    pub fn new(entry: &fsync::tree::Entry, now: DateTime<Utc>) -> Self {
+        let size = |md: &fsync::Metadata| {
+            let data = md.stat().map(|s| s.data).unwrap_or(0);
+            Some(human_bytes(data.max(0) as u64, Unit::Binary))
+        };
+        let mtime = |md: &fsync::Metadata| md.mtime().map(|mtime| human_mtime(mtime, now));
+        match entry {
+            fsync::tree::Entry::Local(local) => Self {
+                local_size: size(local),
+                local_mtime: mtime(local),
+                ..Default::default()
+            },
+            fsync::tree::Entry::Remote(remote) => Self {
+                remote_size: size(remote),
+                remote_mtime: mtime(remote),
+                ..Default::default()
+            },
+            fsync::tree::Entry::Sync { local, remote, .. } => Self {
+                local_size: size(local),
+                remote_size: size(remote),
+                local_mtime: mtime(local),
+                remote_mtime: mtime(remote),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// The transfers of a cause, with pre-formatted sizes for display
+#[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferRow {
+    pub cause: fsync::TransferCause,
+    pub files: u64,
+    pub bytes: String,
+    pub retried_bytes: String,
+}
+
+impl TransferRow {
+    /// One row per cause of `transfers`
+    pub fn rows(transfers: &fsync::Transfers) -> Vec<Self> {
+        fsync::TransferCause::ALL
+            .into_iter()
+            .map(|cause| {
+                let counters = transfers.get(cause);
+                Self {
+                    cause,
+                    files: counters.files,
+                    bytes: human_bytes(counters.bytes, Unit::Binary),
+                    retried_bytes: human_bytes(counters.retried_bytes, Unit::Binary),
+                }
+            })
+            .collect()
+    }
+}
+
 /// A struct gathering a node and its children
 #[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
 #[serde(rename_all = "camelCase")]
@@ -113,3 +350,42 @@ impl From<(PathBuf, fsync::Progress)> for PathProgress {
         Self { path, progress }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path};
+
+    use super::{write_definitions, DEFINITIONS_FILE};
+
+    #[test]
+    fn write_types() {
+        let mut buf = Vec::new();
+        write_definitions(&mut buf).unwrap();
+        let defs = String::from_utf8(buf).unwrap();
+        assert!(defs.contains("export type Preview"));
+        assert!(defs.contains("export type InstanceStats"));
+        assert!(defs.contains("export type PlannedAction"));
+        assert!(defs.contains("export type DeletionMethod"));
+    }
+
+    /// The checked-in definitions must match the Rust types.
+    /// Run with `FSYNC_UPDATE_TYPES=1` to update them.
+    #[test]
+    fn definitions_up_to_date() {
+        let mut buf = Vec::new();
+        write_definitions(&mut buf).unwrap();
+        let defs = String::from_utf8(buf).unwrap();
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(DEFINITIONS_FILE);
+        if env::var_os("FSYNC_UPDATE_TYPES").is_some() {
+            fs::write(&path, &defs).unwrap();
+            return;
+        }
+        let checked_in = fs::read_to_string(&path).unwrap_or_default();
+        assert!(
+            checked_in == defs,
+            "{} is out of date, run the tests with FSYNC_UPDATE_TYPES=1 to update it",
+            path.display()
+        );
+    }
+}
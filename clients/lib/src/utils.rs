@@ -1,29 +1,90 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
 use anyhow::Context;
-use fsync::{path::Path, tree::EntryNode, FsyncClient};
-use futures::future;
-use tarpc::context;
+use fsync::{path::Path, tree::EntryNode, FsyncClient, FsyncRequest, FsyncResponse, SortOrder};
+use tarpc::{client::stub::Stub, context};
+
+/// Number of child nodes requested at once
+const PAGE_LEN: u32 = 1000;
+
+/// The timeout of the requests, in milliseconds
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(fsync::DEFAULT_RPC_TIMEOUT.as_millis() as u64);
 
+/// Set the time given to the daemon to reply to the requests of [`ctx`].
+/// Defaults to [`fsync::DEFAULT_RPC_TIMEOUT`].
+pub fn set_timeout(timeout: Duration) {
+    TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// The time given to the daemon to reply to the requests of [`ctx`]
+pub fn timeout() -> Duration {
+    Duration::from_millis(TIMEOUT_MS.load(Ordering::Relaxed))
+}
+
+/// The context of a request, with the deadline set by [`set_timeout`]
 pub fn ctx() -> context::Context {
-    context::current()
+    ctx_with_timeout(timeout())
+}
+
+/// The context of a request that may take longer than the others
+pub fn ctx_with_timeout(timeout: Duration) -> context::Context {
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + timeout;
+    ctx
 }
 
-pub async fn node_and_children(
-    client: &FsyncClient,
+/// Get the node at `path` and its children, sorted with `order`.
+/// The children are requested by pages, and the names list of the node is left empty.
+pub async fn node_and_children<S>(
+    client: &FsyncClient<S>,
     path: &Path,
-) -> anyhow::Result<(EntryNode, Vec<EntryNode>)> {
+    order: SortOrder,
+) -> anyhow::Result<(EntryNode, Vec<EntryNode>)>
+where
+    S: Stub<Req = FsyncRequest, Resp = FsyncResponse>,
+{
     let node = client
-        .entry_node(ctx(), path.to_owned())
+        .entry_node_page(ctx(), path.to_owned(), None, 0)
         .await
         .unwrap()?
         .with_context(|| format!("No entry found at {path}"))?;
-    let child_futs = node.children().iter().map(|name| {
-        let child_path = path.join(name);
-        client.entry_node(ctx(), child_path)
-    });
-    let children: Result<Vec<_>, _> = future::try_join_all(child_futs)
-        .await?
-        .into_iter()
-        .map(|c| c.map(|c| c.expect("No entry found at child")))
-        .collect();
-    Ok((node, children?))
+    let mut children = Vec::new();
+    loop {
+        let after = children
+            .last()
+            .and_then(|c: &EntryNode| c.name())
+            .map(ToString::to_string);
+        let page = client
+            .entry_nodes(ctx(), path.to_owned(), Some(order), after, PAGE_LEN)
+            .await??;
+        let done = page.len() < PAGE_LEN as usize;
+        children.extend(page);
+        if done {
+            break;
+        }
+    }
+    Ok((node, children))
+}
+
+/// Compare the whole tree of the daemon with the storages, by pages that each fit
+/// in half the timeout of the requests
+pub async fn self_check<S>(client: &FsyncClient<S>) -> anyhow::Result<Vec<fsync::Discrepancy>>
+where
+    S: Stub<Req = FsyncRequest, Resp = FsyncResponse>,
+{
+    let mut discrepancies = Vec::new();
+    let mut after = None;
+    loop {
+        let deadline = SystemTime::now() + timeout() / 2;
+        let page = client.self_check_until(ctx(), after, deadline).await??;
+        discrepancies.extend(page.discrepancies);
+        if page.next.is_none() {
+            break;
+        }
+        after = page.next;
+    }
+    Ok(discrepancies)
 }
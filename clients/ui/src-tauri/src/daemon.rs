@@ -1,17 +1,23 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
 
 use anyhow::Context;
 use fsync::{
     path::{FsPathBuf, Path, PathBuf},
-    FsyncClient,
-};
-use fsync_client::{
-    ts,
-    utils::{ctx, node_and_children},
-    Instance,
+    FsyncClient, SortOrder, StorageLoc,
 };
+use fsync_client::{cache::NodeCache, diff, ts, utils::ctx, Instance};
 use serde::{Deserialize, Serialize};
-use tokio::{fs, sync::Mutex};
+use tauri::{AppHandle, Emitter, Runtime};
+use tokio::{
+    fs,
+    sync::{broadcast, Mutex},
+};
+
+/// Client of a daemon, that follows its restarts
+pub type Client = FsyncClient<fsync_client::Connection>;
 
 #[tauri::command]
 pub async fn open_path(
@@ -23,7 +29,7 @@ pub async fn open_path(
         .await
         .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
     let local_path = client.local_path(ctx(), path).await.unwrap()?;
-    println!("Opening {:?}", local_path); 
+    println!("Opening {:?}", local_path);
     open::that(local_path)?;
     Ok(())
 }
@@ -51,15 +57,50 @@ pub async fn daemon_connect(
 pub async fn daemon_node_and_children(
     daemon: tauri::State<'_, Daemon>,
     path: Option<PathBuf>,
+    sort: Option<SortOrder>,
+    bypass: Option<bool>,
 ) -> fsync::Result<ts::NodeAndChildren> {
-    let client = daemon
-        .client()
+    let (client, cache) = daemon
+        .connection()
         .await
         .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
-    let (node, children) =
-        node_and_children(&client, path.as_deref().unwrap_or(Path::root())).await?;
-    let node = node.into();
-    let children = children.into_iter().map(|node| node.into()).collect();
+    let path = path.as_deref().unwrap_or(Path::root());
+    let (node, children) = cache
+        .node_and_children(
+            &client,
+            path,
+            sort.unwrap_or_default(),
+            bypass.unwrap_or(false),
+        )
+        .await?;
+    let paths = std::iter::once(&node)
+        .chain(&children)
+        .map(|node| node.path().to_owned())
+        .collect();
+    let sharing = client.sharing(ctx(), paths.clone()).await.unwrap()?;
+    let view_only = client.view_only(ctx(), paths.clone()).await.unwrap()?;
+    let aggregation = client.aggregation(ctx(), paths.clone()).await.unwrap()?;
+    let implausible = client.implausible_stats(ctx(), paths).await.unwrap()?;
+    let pinned: BTreeSet<PathBuf> = client.pinned(ctx()).await.unwrap()?.into_iter().collect();
+    let mut entries = sharing
+        .into_iter()
+        .zip(view_only)
+        .zip(aggregation)
+        .zip(implausible)
+        .zip(std::iter::once(node).chain(children))
+        .map(
+            |((((sharing, view_only), aggregation), implausible), node)| {
+                let is_pinned = pinned.contains(node.path());
+                ts::TreeEntry::from(node)
+                    .with_pinned(is_pinned)
+                    .with_sharing(sharing)
+                    .with_view_only(view_only)
+                    .with_aggregation(aggregation)
+                    .with_implausible(implausible)
+            },
+        );
+    let node = entries.next().expect("node should be listed");
+    let children = entries.collect();
     Ok(ts::NodeAndChildren { node, children })
 }
 
@@ -67,12 +108,84 @@ pub async fn daemon_node_and_children(
 pub async fn daemon_operate(
     daemon: tauri::State<'_, Daemon>,
     operation: fsync::Operation,
+    confirmation: Option<String>,
 ) -> fsync::Result<fsync::Progress> {
-    let client = daemon
-        .client()
+    let (client, cache) = daemon
+        .connection()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    cache.on_operate(&operation);
+    let options = fsync::OperateOptions {
+        confirmation,
+        ..Default::default()
+    };
+    client
+        .operate_with(ctx(), operation, options)
+        .await
+        .unwrap()
+}
+
+#[tauri::command]
+pub async fn daemon_invalidate(
+    daemon: tauri::State<'_, Daemon>,
+    path: Option<PathBuf>,
+) -> fsync::Result<()> {
+    let (_, cache) = daemon
+        .connection()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    match path {
+        Some(path) => cache.invalidate(&path),
+        None => cache.clear(),
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn daemon_rescan(
+    daemon: tauri::State<'_, Daemon>,
+    path: PathBuf,
+    deep: bool,
+) -> fsync::Result<fsync::RescanReport> {
+    let (client, cache) = daemon
+        .connection()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    let report = client.rescan(ctx(), path.clone(), deep).await.unwrap()?;
+    cache.invalidate(&path);
+    Ok(report)
+}
+
+#[tauri::command]
+pub async fn daemon_aggregate_now(
+    daemon: tauri::State<'_, Daemon>,
+    path: PathBuf,
+) -> fsync::Result<()> {
+    let (client, cache) = daemon
+        .connection()
         .await
         .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
-    client.operate(ctx(), operation).await.unwrap()
+    client.aggregate_now(ctx(), path.clone()).await.unwrap()?;
+    cache.invalidate(&path);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn daemon_set_pinned(
+    daemon: tauri::State<'_, Daemon>,
+    path: PathBuf,
+    pinned: bool,
+) -> fsync::Result<()> {
+    let (client, cache) = daemon
+        .connection()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    client
+        .set_pinned(ctx(), path.clone(), pinned)
+        .await
+        .unwrap()?;
+    cache.invalidate(&path);
+    Ok(())
 }
 
 #[tauri::command]
@@ -80,11 +193,16 @@ pub async fn daemon_progress(
     daemon: tauri::State<'_, Daemon>,
     path: PathBuf,
 ) -> fsync::Result<Option<fsync::Progress>> {
-    let client = daemon
-        .client()
+    let (client, cache) = daemon
+        .connection()
         .await
         .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
-    client.progress(ctx(), path).await.unwrap()
+    let progress = client.progress(ctx(), path.clone()).await.unwrap()?;
+    cache.on_progress(&path, progress.as_ref());
+    if let Some(progress) = &progress {
+        daemon.open_auth_prompts([(&path, progress)]).await;
+    }
+    Ok(progress)
 }
 
 #[tauri::command]
@@ -92,20 +210,184 @@ pub async fn daemon_progresses(
     daemon: tauri::State<'_, Daemon>,
     path: PathBuf,
 ) -> fsync::Result<Vec<ts::PathProgress>> {
+    let (client, cache) = daemon
+        .connection()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    let progresses = cache.progresses(&client, &path).await?;
+    daemon
+        .open_auth_prompts(progresses.iter().map(|(path, progress)| (path, progress)))
+        .await;
+    Ok(progresses.into_iter().map(|p| p.into()).collect())
+}
+
+#[tauri::command]
+pub async fn daemon_instance_stats(
+    daemon: tauri::State<'_, Daemon>,
+) -> fsync::Result<fsync::InstanceStats> {
+    let client = daemon
+        .client()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    client.instance_stats(ctx()).await.unwrap()
+}
+
+/// The lifetime transfers of the instance, by cause
+#[tauri::command]
+pub async fn daemon_transfers(
+    daemon: tauri::State<'_, Daemon>,
+) -> fsync::Result<Vec<ts::TransferRow>> {
+    let client = daemon
+        .client()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    let transfers = client.transfers(ctx()).await.unwrap()?;
+    Ok(ts::TransferRow::rows(&transfers.lifetime))
+}
+
+#[tauri::command]
+pub async fn daemon_root_change(
+    daemon: tauri::State<'_, Daemon>,
+) -> fsync::Result<Option<fsync::RootChange>> {
+    let client = daemon
+        .client()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    client.root_change(ctx()).await.unwrap()
+}
+
+#[tauri::command]
+pub async fn daemon_migrate_root(
+    daemon: tauri::State<'_, Daemon>,
+    choice: fsync::MigrationChoice,
+) -> fsync::Result<()> {
+    let (client, cache) = daemon
+        .connection()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    client.migrate_root(ctx(), choice).await.unwrap()?;
+    // the tree was built again
+    cache.clear();
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn daemon_status(daemon: tauri::State<'_, Daemon>) -> fsync::Result<fsync::Status> {
+    let client = daemon
+        .client()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    client.status(ctx()).await.unwrap()
+}
+
+#[tauri::command]
+pub async fn daemon_file_head(
+    daemon: tauri::State<'_, Daemon>,
+    path: PathBuf,
+    loc: StorageLoc,
+    max_bytes: u64,
+) -> fsync::Result<Vec<u8>> {
+    let client = daemon
+        .client()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    client.read_head(ctx(), path, loc, max_bytes).await.unwrap()
+}
+
+#[tauri::command]
+pub async fn daemon_file_preview(
+    daemon: tauri::State<'_, Daemon>,
+    path: PathBuf,
+) -> fsync::Result<diff::Preview> {
+    let client = daemon
+        .client()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    Ok(diff::fetch_preview(&client, &path).await?)
+}
+
+#[tauri::command]
+pub async fn daemon_remote_revisions(
+    daemon: tauri::State<'_, Daemon>,
+    path: PathBuf,
+    max: u32,
+) -> fsync::Result<Vec<fsync::Revision>> {
+    let client = daemon
+        .client()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    client.remote_revisions(ctx(), path, max).await.unwrap()
+}
+
+/// Download `revision` next to the file at `path`, see [`fsync::Revision::default_dest`],
+/// and return the path of the new file
+#[tauri::command]
+pub async fn daemon_download_revision(
+    daemon: tauri::State<'_, Daemon>,
+    path: PathBuf,
+    revision: fsync::Revision,
+) -> fsync::Result<PathBuf> {
+    let client = daemon
+        .client()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    let dest = revision.default_dest(&path);
+    let metadata = client
+        .download_revision(ctx(), path, revision.id, dest)
+        .await
+        .unwrap()?;
+    Ok(metadata.path().to_owned())
+}
+
+#[tauri::command]
+pub async fn daemon_remote_link(
+    daemon: tauri::State<'_, Daemon>,
+    path: PathBuf,
+) -> fsync::Result<fsync::RemoteLink> {
+    let client = daemon
+        .client()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    client.remote_link(ctx(), path).await.unwrap()
+}
+
+#[tauri::command]
+pub async fn daemon_logs_tail(
+    daemon: tauri::State<'_, Daemon>,
+    max: u32,
+    min_level: fsync::LogLevel,
+) -> fsync::Result<Vec<fsync::LogRecord>> {
+    let client = daemon
+        .client()
+        .await
+        .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
+    client.logs_tail(ctx(), max, min_level).await.unwrap()
+}
+
+#[tauri::command]
+pub async fn daemon_logs_since(
+    daemon: tauri::State<'_, Daemon>,
+    cursor: u64,
+    max: u32,
+    min_level: fsync::LogLevel,
+) -> fsync::Result<(u64, Vec<fsync::LogRecord>)> {
     let client = daemon
         .client()
         .await
         .ok_or_else(|| fsync::other_error!("daemon not connected"))?;
     client
-        .progresses(ctx(), path)
+        .logs_since(ctx(), cursor, max, min_level)
         .await
         .unwrap()
-        .map(|v| v.into_iter().map(|p| p.into()).collect())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Persistent {
-    instance_name: String,
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Persistent {
+    #[serde(default)]
+    instance_name: Option<String>,
+    /// Keep the application running in the tray when the window is closed
+    #[serde(default)]
+    pub background: bool,
 }
 
 impl Persistent {
@@ -117,7 +399,7 @@ impl Persistent {
         Ok(file)
     }
 
-    async fn load() -> anyhow::Result<Option<Self>> {
+    pub async fn load() -> anyhow::Result<Option<Self>> {
         let path = Self::disk_file()?;
         match fs::read(path).await {
             Ok(contents) => {
@@ -129,7 +411,7 @@ impl Persistent {
         }
     }
 
-    async fn _save(&self) -> anyhow::Result<()> {
+    pub async fn save(&self) -> anyhow::Result<()> {
         let path = Self::disk_file()?;
         let dir = path
             .parent()
@@ -144,40 +426,210 @@ impl Persistent {
 }
 
 #[derive(Debug, Clone)]
+struct Connection {
+    client: Client,
+    cache: Arc<NodeCache>,
+}
+
+impl Connection {
+    /// Connect to `instance`. When its daemon restarts, the listing cache is cleared
+    /// and the name of the instance is sent to `restarted`.
+    /// When the daemon loads another config, the name is sent to `config_changed`.
+    async fn open(
+        instance: &Instance,
+        restarted: broadcast::Sender<String>,
+        config_changed: broadcast::Sender<String>,
+    ) -> anyhow::Result<Self> {
+        let conn = fsync_client::Connection::open(instance.name()).await?;
+        let cache = Arc::new(NodeCache::new());
+
+        let mut generation = conn.subscribe();
+        let name = instance.name().to_owned();
+        let weak_cache = Arc::downgrade(&cache);
+        // ends when the connection is dropped
+        tokio::spawn(async move {
+            while generation.changed().await.is_ok() {
+                if let Some(cache) = weak_cache.upgrade() {
+                    cache.clear();
+                }
+                let _ = restarted.send(name.clone());
+            }
+        });
+
+        let mut fingerprint = conn.subscribe_config();
+        let name = instance.name().to_owned();
+        let weak_cache = Arc::downgrade(&cache);
+        tokio::spawn(async move {
+            while fingerprint.changed().await.is_ok() {
+                if let Some(cache) = weak_cache.upgrade() {
+                    cache.clear();
+                }
+                let _ = config_changed.send(name.clone());
+            }
+        });
+
+        Ok(Self {
+            client: conn.into(),
+            cache,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
 struct Inner {
-    instance_name: String,
-    client: FsyncClient,
+    /// Name of the instance shown in the window
+    current: Option<String>,
+    /// Connections to all the connected instances
+    connections: BTreeMap<String, Connection>,
+    /// The authorization pages already opened in the system browser
+    auth_urls: BTreeSet<String>,
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Clone)]
 pub struct Daemon {
-    // daemon instance name and client name
-    inner: Arc<Mutex<Option<Inner>>>,
+    inner: Arc<Mutex<Inner>>,
+    /// Names of the instances whose daemon restarted
+    restarted: broadcast::Sender<String>,
+    /// Names of the instances whose daemon loaded another config
+    config_changed: broadcast::Sender<String>,
+}
+
+impl Default for Daemon {
+    fn default() -> Self {
+        Self {
+            inner: Default::default(),
+            restarted: broadcast::channel(16).0,
+            config_changed: broadcast::channel(16).0,
+        }
+    }
+}
+
+/// Payload of the `daemon-restarted` event, emitted when the daemon of an instance restarts,
+/// so that the frontend fetches the state of the instance again
+#[derive(Debug, Clone, Serialize)]
+struct DaemonRestarted(String);
+
+/// Emit the `daemon-restarted` events
+pub async fn emit_restarts<R: Runtime>(app: AppHandle<R>, daemon: Daemon) {
+    let mut restarted = daemon.restarted.subscribe();
+    loop {
+        match restarted.recv().await {
+            Ok(name) => {
+                println!("Daemon of {name} restarted");
+                let _ = app.emit("daemon-restarted", DaemonRestarted(name));
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Payload of the `config-changed` event, emitted when the daemon of an instance
+/// loads another config, so that the frontend fetches the data derived from the config again
+#[derive(Debug, Clone, Serialize)]
+struct ConfigChanged(String);
+
+/// Emit the `config-changed` events
+pub async fn emit_config_changes<R: Runtime>(app: AppHandle<R>, daemon: Daemon) {
+    let mut changed = daemon.config_changed.subscribe();
+    loop {
+        match changed.recv().await {
+            Ok(name) => {
+                println!("Config of {name} changed");
+                let _ = app.emit("config-changed", ConfigChanged(name));
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
 }
 
 impl Daemon {
     pub async fn try_auto_connect(&self) {
         let persistent = Persistent::load().await.expect("Should not fail");
-        let name = persistent.as_ref().map(|p| p.instance_name.as_str());
+        let name = persistent.as_ref().and_then(|p| p.instance_name.as_deref());
 
-        let _ = self.connect(name).await;
+        // the instance may have been renamed or removed since
+        if self.connect(name).await.is_err() && name.is_some() {
+            let _ = self.connect(None).await;
+        }
     }
 
     pub async fn connected(&self) -> bool {
         let inner = self.inner.lock().await;
-        inner.is_some()
+        inner.current.is_some()
     }
 
     pub async fn instance_name(&self) -> Option<String> {
         let inner = self.inner.lock().await;
-        inner.as_ref().map(|inner| inner.instance_name.clone())
+        inner.current.clone()
+    }
+
+    pub async fn client(&self) -> Option<Client> {
+        self.connection().await.map(|(client, _)| client)
     }
 
-    pub async fn client(&self) -> Option<fsync::FsyncClient> {
+    /// The client and the listing cache of the current instance
+    pub async fn connection(&self) -> Option<(Client, Arc<NodeCache>)> {
         let inner = self.inner.lock().await;
-        inner.as_ref().map(|inner| inner.client.clone())
+        let name = inner.current.as_ref()?;
+        inner
+            .connections
+            .get(name)
+            .map(|conn| (conn.client.clone(), conn.cache.clone()))
     }
 
+    /// Open in the system browser the authorization pages of `progresses`
+    /// that the daemon could not open itself, once each
+    async fn open_auth_prompts<'a, I>(&self, progresses: I)
+    where
+        I: IntoIterator<Item = (&'a PathBuf, &'a fsync::Progress)>,
+    {
+        let mut inner = self.inner.lock().await;
+        for (path, progress) in progresses {
+            let fsync::Progress::OAuth2Browse(prompt) = progress else {
+                continue;
+            };
+            if prompt.opened || !inner.auth_urls.insert(prompt.url.clone()) {
+                continue;
+            }
+            println!("Opening the authorization page for {path}");
+            if let Err(err) = open::that(&prompt.url) {
+                eprintln!("Could not open the authorization page: {err}");
+            }
+        }
+    }
+
+    /// The names and clients of all the connected instances
+    pub async fn clients(&self) -> Vec<(String, Client)> {
+        let inner = self.inner.lock().await;
+        inner
+            .connections
+            .iter()
+            .map(|(name, conn)| (name.clone(), conn.client.clone()))
+            .collect()
+    }
+
+    /// Start `operation` on the instance `name`, and invalidate its listing cache accordingly
+    pub async fn operate(
+        &self,
+        name: &str,
+        operation: fsync::Operation,
+    ) -> anyhow::Result<fsync::Progress> {
+        let conn = self
+            .connection_of(name)
+            .await
+            .with_context(|| format!("{name} is not connected"))?;
+        conn.cache.operate(&conn.client, operation).await
+    }
+
+    async fn connection_of(&self, name: &str) -> Option<Connection> {
+        let inner = self.inner.lock().await;
+        inner.connections.get(name).cloned()
+    }
+
+    /// Connect to the instance `name` and make it the current instance.
+    /// If `name` is `None`, connect to the only running instance.
     pub async fn connect(&self, name: Option<&str>) -> fsync::Result<()> {
         if self.connected().await && self.instance_name().await.as_deref() == name {
             return Ok(());
@@ -198,15 +650,60 @@ impl Daemon {
         let instance =
             instance.with_context(|| format!("Could not find running daemon instance"))?;
 
-        let client = instance.make_client().await?;
+        let conn = match self.connection_of(instance.name()).await {
+            Some(conn) => conn,
+            None => {
+                Connection::open(
+                    &instance,
+                    self.restarted.clone(),
+                    self.config_changed.clone(),
+                )
+                .await?
+            }
+        };
         let instance_name = instance.into_name();
 
         let mut inner = self.inner.lock().await;
-        *inner = Some(Inner {
-            instance_name,
-            client,
-        });
+        inner.connections.insert(instance_name.clone(), conn);
+        inner.current = Some(instance_name);
 
         Ok(())
     }
+
+    /// Connect to all the running instances, and drop the clients
+    /// of the instances that are no longer running.
+    pub async fn connect_all(&self) -> fsync::Result<()> {
+        let mut instances = Instance::get_all()?;
+        instances.retain(|i| i.running());
+
+        let mut connections = BTreeMap::new();
+        for instance in instances {
+            let conn = match self.connection_of(instance.name()).await {
+                Some(conn) => conn,
+                None => match Connection::open(
+                    &instance,
+                    self.restarted.clone(),
+                    self.config_changed.clone(),
+                )
+                .await
+                {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        eprintln!("Could not connect to {}: {err}", instance.name());
+                        continue;
+                    }
+                },
+            };
+            connections.insert(instance.into_name(), conn);
+        }
+
+        let mut inner = self.inner.lock().await;
+        if let Some(current) = &inner.current {
+            if !connections.contains_key(current) {
+                inner.current = None;
+            }
+        }
+        inner.connections = connections;
+        Ok(())
+    }
 }
@@ -1,12 +1,14 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use daemon::Daemon;
-use fsync::path::FsPathBuf;
+use daemon::{Daemon, Persistent};
 use fsync_client::ts;
 use serde::Serialize;
+use tauri::{Manager, WindowEvent};
 
 mod daemon;
+mod tray;
+mod wizard;
 
 #[tauri::command]
 fn error_message(err: fsync::Error) -> String {
@@ -18,16 +20,6 @@ async fn instance_get_all() -> fsync::Result<Vec<ts::Instance>> {
     ts::Instance::get_all().await
 }
 
-#[tauri::command]
-async fn instance_create(
-    name: String,
-    local_dir: FsPathBuf,
-    opts: fsync_client::config::ProviderOpts,
-) -> fsync::Result<()> {
-    fsync_client::config::create(&name, &local_dir, &opts).await?;
-    Ok(())
-}
-
 #[derive(Debug, Clone, Serialize)]
 struct AutoConnectDone();
 
@@ -44,22 +36,76 @@ async fn main() {
         })
     };
 
+    let background = Persistent::load()
+        .await
+        .ok()
+        .flatten()
+        .is_some_and(|p| p.background);
+
     let app = tauri::Builder::default()
         .plugin(tauri_plugin_dialog::init())
         .manage(daemon)
+        .manage(tray::Background::default())
+        .manage(wizard::Wizard::default())
+        .setup(move |app| {
+            tray::build(app.handle())?;
+            tray::set_background(app.handle(), background)?;
+            tauri::async_runtime::spawn(tray::poll_status(app.handle().clone()));
+            tauri::async_runtime::spawn(daemon::emit_restarts(
+                app.handle().clone(),
+                app.state::<Daemon>().inner().clone(),
+            ));
+            tauri::async_runtime::spawn(daemon::emit_config_changes(
+                app.handle().clone(),
+                app.state::<Daemon>().inner().clone(),
+            ));
+            Ok(())
+        })
+        .on_window_event(|window, event| {
+            // in background mode, closing the window only hides it
+            if let WindowEvent::CloseRequested { api, .. } = event {
+                if window.state::<tray::Background>().enabled() {
+                    api.prevent_close();
+                    let _ = window.hide();
+                }
+            }
+        })
         .invoke_handler(tauri::generate_handler![
             error_message,
             instance_get_all,
-            instance_create,
-
+            wizard::wizard_validate_name,
+            wizard::wizard_validate_local_dir,
+            wizard::wizard_drive_auth_begin,
+            wizard::wizard_drive_auth_poll,
+            wizard::wizard_drive_list_roots,
+            wizard::wizard_cancel,
+            wizard::wizard_commit,
+            tray::app_background,
+            tray::app_set_background,
             daemon::open_path,
             daemon::daemon_connected,
             daemon::daemon_instance_name,
             daemon::daemon_connect,
             daemon::daemon_node_and_children,
             daemon::daemon_operate,
+            daemon::daemon_invalidate,
+            daemon::daemon_rescan,
+            daemon::daemon_aggregate_now,
+            daemon::daemon_set_pinned,
             daemon::daemon_progress,
             daemon::daemon_progresses,
+            daemon::daemon_instance_stats,
+            daemon::daemon_transfers,
+            daemon::daemon_status,
+            daemon::daemon_root_change,
+            daemon::daemon_migrate_root,
+            daemon::daemon_file_head,
+            daemon::daemon_file_preview,
+            daemon::daemon_remote_revisions,
+            daemon::daemon_download_revision,
+            daemon::daemon_remote_link,
+            daemon::daemon_logs_tail,
+            daemon::daemon_logs_since,
         ])
         .build(tauri::generate_context!())
         .expect("tauri builder should not fail");
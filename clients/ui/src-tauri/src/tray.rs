@@ -0,0 +1,248 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use fsync::{path::PathBuf, Operation};
+use fsync_client::utils::ctx;
+use serde::Serialize;
+use tauri::{
+    menu::{IsMenuItem, Menu, MenuEvent, MenuItem, PredefinedMenuItem, Submenu},
+    tray::TrayIconBuilder,
+    AppHandle, Emitter, Manager, Runtime,
+};
+use tokio::sync::Notify;
+
+use crate::daemon::{Daemon, Persistent};
+
+const TRAY_ID: &str = "main";
+const MAIN_WINDOW: &str = "main";
+
+/// Interval between two status refreshes while the tray is visible
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Maximum number of conflicts counted per instance
+const MAX_CONFLICTS: u32 = 100;
+
+/// State of the background mode.
+/// The tray icon is visible, and the status polled, only in background mode.
+#[derive(Debug, Default)]
+pub struct Background {
+    enabled: AtomicBool,
+    changed: Notify,
+}
+
+impl Background {
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
+
+/// Payload of the `instance-opened` event, emitted when an instance is opened from the tray
+#[derive(Debug, Clone, Serialize)]
+struct InstanceOpened(String);
+
+#[tauri::command]
+pub async fn app_background(background: tauri::State<'_, Background>) -> Result<bool, ()> {
+    Ok(background.enabled())
+}
+
+#[tauri::command]
+pub async fn app_set_background(app: AppHandle, enabled: bool) -> fsync::Result<()> {
+    let mut persistent = Persistent::load().await?.unwrap_or_default();
+    persistent.background = enabled;
+    persistent.save().await?;
+    set_background(&app, enabled).map_err(|err| fsync::other_error!("{err}"))?;
+    Ok(())
+}
+
+/// Build the tray icon, hidden until background mode is enabled
+pub fn build<R: Runtime>(app: &AppHandle<R>) -> tauri::Result<()> {
+    let mut builder = TrayIconBuilder::with_id(TRAY_ID)
+        .tooltip("FSync")
+        .menu(&build_menu(app, &[])?)
+        .on_menu_event(on_menu_event);
+    if let Some(icon) = app.default_window_icon() {
+        builder = builder.icon(icon.clone());
+    }
+    let tray = builder.build(app)?;
+    tray.set_visible(false)?;
+    Ok(())
+}
+
+/// Enable or disable background mode, and show or hide the tray icon accordingly
+pub fn set_background<R: Runtime>(app: &AppHandle<R>, enabled: bool) -> tauri::Result<()> {
+    let background = app.state::<Background>();
+    background.enabled.store(enabled, Ordering::Relaxed);
+    background.changed.notify_one();
+    if let Some(tray) = app.tray_by_id(TRAY_ID) {
+        tray.set_visible(enabled)?;
+    }
+    Ok(())
+}
+
+/// Refresh the tray status at a low rate, while the tray is visible
+pub async fn poll_status<R: Runtime>(app: AppHandle<R>) {
+    loop {
+        let background = app.state::<Background>();
+        if !background.enabled() {
+            background.changed.notified().await;
+            continue;
+        }
+        if let Err(err) = refresh_status(&app).await {
+            eprintln!("Could not refresh tray status: {err}");
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(REFRESH_INTERVAL) => (),
+            _ = background.changed.notified() => (),
+        }
+    }
+}
+
+/// Status of a connected instance
+#[derive(Debug)]
+struct InstanceStatus {
+    name: String,
+    running_ops: usize,
+    conflicts: usize,
+    /// Why the scheduled work of the instance is postponed
+    deferred: Option<String>,
+    /// Whether the daemon is catching up after a resume of the system from sleep
+    resyncing: bool,
+}
+
+async fn refresh_status<R: Runtime>(app: &AppHandle<R>) -> anyhow::Result<()> {
+    let daemon = app.state::<Daemon>();
+    daemon.connect_all().await?;
+
+    let mut statuses = Vec::new();
+    for (name, client) in daemon.clients().await {
+        // instance_stats is also a cheap liveness check of the connection
+        if client.instance_stats(ctx()).await.is_err() {
+            continue;
+        }
+        let running_ops = client
+            .progresses(ctx(), PathBuf::root())
+            .await??
+            .iter()
+            .filter(|(_, progress)| !progress.is_done())
+            .count();
+        let conflicts = client.conflicts(ctx(), None, MAX_CONFLICTS).await??.len();
+        let status = client.status(ctx()).await??;
+        statuses.push(InstanceStatus {
+            name,
+            running_ops,
+            conflicts,
+            deferred: status.policy.deferred,
+            resyncing: status.resumed.is_some_and(|resumed| resumed.resyncing),
+        });
+    }
+
+    if let Some(tray) = app.tray_by_id(TRAY_ID) {
+        tray.set_tooltip(Some(tooltip(&statuses)))?;
+        tray.set_menu(Some(build_menu(app, &statuses)?))?;
+    }
+    Ok(())
+}
+
+fn tooltip(statuses: &[InstanceStatus]) -> String {
+    if statuses.is_empty() {
+        return "FSync: no running instance".to_string();
+    }
+    let running_ops: usize = statuses.iter().map(|s| s.running_ops).sum();
+    let conflicts: usize = statuses.iter().map(|s| s.conflicts).sum();
+    let mut tooltip = format!(
+        "FSync: {} instance(s), {running_ops} operation(s) running, {conflicts} conflict(s)",
+        statuses.len()
+    );
+    if statuses.iter().any(|s| s.deferred.is_some()) {
+        tooltip.push_str(", scheduled syncs postponed");
+    }
+    if statuses.iter().any(|s| s.resyncing) {
+        tooltip.push_str(", resumed, resyncing");
+    }
+    tooltip
+}
+
+fn build_menu<R: Runtime>(
+    app: &AppHandle<R>,
+    statuses: &[InstanceStatus],
+) -> tauri::Result<Menu<R>> {
+    let menu = Menu::new(app)?;
+    for status in statuses {
+        let name = &status.name;
+        let open = MenuItem::with_id(app, format!("open:{name}"), "Open", true, None::<&str>)?;
+        let sync = MenuItem::with_id(app, format!("sync:{name}"), "Sync now", true, None::<&str>)?;
+        let deferred = status
+            .deferred
+            .as_ref()
+            .map(|reason| {
+                let text = format!("Scheduled syncs postponed: {reason}");
+                MenuItem::new(app, text, false, None::<&str>)
+            })
+            .transpose()?;
+        let mut items: Vec<&dyn IsMenuItem<R>> = vec![&open, &sync];
+        if let Some(deferred) = &deferred {
+            items.push(deferred);
+        }
+        let label = if status.conflicts > 0 {
+            format!("{name} ({} conflicts)", status.conflicts)
+        } else {
+            name.clone()
+        };
+        menu.append(&Submenu::with_items(app, label, true, &items)?)?;
+    }
+    if !statuses.is_empty() {
+        menu.append(&PredefinedMenuItem::separator(app)?)?;
+    }
+    menu.append(&MenuItem::with_id(
+        app,
+        "show",
+        "Show window",
+        true,
+        None::<&str>,
+    )?)?;
+    menu.append(&MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?)?;
+    Ok(menu)
+}
+
+fn on_menu_event<R: Runtime>(app: &AppHandle<R>, event: MenuEvent) {
+    let id = event.id().as_ref();
+    match id.split_once(':') {
+        Some(("open", name)) => {
+            let app = app.clone();
+            let name = name.to_string();
+            tauri::async_runtime::spawn(async move {
+                let daemon = app.state::<Daemon>();
+                if let Err(err) = daemon.connect(Some(&name)).await {
+                    eprintln!("Could not connect to {name}: {err}");
+                    return;
+                }
+                show_window(&app);
+                let _ = app.emit("instance-opened", InstanceOpened(name));
+            });
+        }
+        Some(("sync", name)) => {
+            let app = app.clone();
+            let name = name.to_string();
+            tauri::async_runtime::spawn(async move {
+                let daemon = app.state::<Daemon>();
+                let operation = Operation::SyncDeep(PathBuf::root());
+                if let Err(err) = daemon.operate(&name, operation).await {
+                    eprintln!("Could not synchronize {name}: {err}");
+                }
+            });
+        }
+        _ if id == "show" => show_window(app),
+        _ if id == "quit" => app.exit(0),
+        _ => (),
+    }
+}
+
+pub fn show_window<R: Runtime>(app: &AppHandle<R>) {
+    if let Some(window) = app.get_webview_window(MAIN_WINDOW) {
+        let _ = window.show();
+        let _ = window.unminimize();
+        let _ = window.set_focus();
+    }
+}
@@ -0,0 +1,269 @@
+//! The step-by-step creation of an instance, driven by the `new` page of the frontend.
+//!
+//! The Drive authorization is done before the instance exists: its token cache is written
+//! in a staging directory of the user cache, and moved to the cache of the instance
+//! by [`wizard_commit`]. Nothing is left behind if the wizard is abandoned or fails.
+
+use anyhow::Context;
+use fsync::{
+    loc::{inst, user},
+    path::{FsPath, FsPathBuf, PathBuf},
+};
+use fsync_client::config::{
+    self,
+    validate::{self, DriveAuthCheck, LocalDirCheck, NameCheck},
+    ProviderOpts,
+};
+use fsyncd::{
+    oauth2::{self, GetToken},
+    secrets::Sealer,
+    storage::drive::{self, GoogleDrive},
+    PersistCache, SharedProgress,
+};
+use tokio::{sync::Mutex, task::JoinHandle};
+
+/// Name of the token cache in the staging directory
+const TOKEN_CACHE: &str = "token_cache.json";
+
+/// The state of the wizard, shared by its commands
+#[derive(Default)]
+pub struct Wizard {
+    auth: Mutex<Option<DriveAuth>>,
+}
+
+/// An authorization of Drive, in progress or granted
+struct DriveAuth {
+    opts: config::drive::Opts,
+    staging: FsPathBuf,
+    progress: SharedProgress,
+    task: Option<JoinHandle<anyhow::Result<GoogleDrive<oauth2::Client>>>>,
+    result: Option<Result<GoogleDrive<oauth2::Client>, String>>,
+}
+
+impl DriveAuth {
+    async fn begin(opts: config::drive::Opts) -> anyhow::Result<Self> {
+        let staging = user::cache_dir()?.join(format!(
+            ".wizard-{}-{:08x}",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        tokio::fs::create_dir_all(&staging).await?;
+
+        let mut secret = opts.secret.get()?;
+        if secret.device_auth_url.is_none() {
+            secret.device_auth_url = Some(fsync::oauth2::DeviceAuthorizationUrl::new(
+                fsync::oauth2::GOOGLE_DEVICE_AUTH_URL.to_string(),
+            )?);
+        }
+        let client = reqwest::Client::builder().build()?;
+        let auth = oauth2::Client::new(
+            secret,
+            Default::default(),
+            Default::default(),
+            oauth2::TokenPersist::MemoryAndDisk(staging.join(TOKEN_CACHE), Sealer::plain()),
+            Some(client.clone()),
+        )
+        .await?;
+
+        let progress = SharedProgress::new();
+        let task = {
+            let progress = progress.clone();
+            tokio::spawn(async move {
+                auth.get_token(drive::authorization_scopes(), Some(&progress))
+                    .await?;
+                auth.persist_cache().await?;
+                GoogleDrive::new(auth, client, drive::RootSpec::Root).await
+            })
+        };
+        Ok(Self {
+            opts,
+            staging,
+            progress,
+            task: Some(task),
+            result: None,
+        })
+    }
+
+    /// Collect the result of the authorization task, if it finished
+    async fn poll(&mut self) {
+        if !self.task.as_ref().is_some_and(JoinHandle::is_finished) {
+            return;
+        }
+        let task = self.task.take().unwrap();
+        self.result = Some(match task.await {
+            Ok(Ok(drive)) => Ok(drive),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(err) => Err(err.to_string()),
+        });
+    }
+
+    fn check(&self) -> DriveAuthCheck {
+        match &self.result {
+            None => DriveAuthCheck::Pending(self.progress.get()),
+            Some(Ok(drive)) => DriveAuthCheck::Granted {
+                account: drive.account().map(str::to_owned),
+            },
+            Some(Err(err)) => DriveAuthCheck::Failed(err.clone()),
+        }
+    }
+
+    fn drive(&self) -> Option<&GoogleDrive<oauth2::Client>> {
+        self.result.as_ref().and_then(|res| res.as_ref().ok())
+    }
+}
+
+impl Drop for DriveAuth {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+        let _ = std::fs::remove_dir_all(&self.staging);
+    }
+}
+
+#[tauri::command]
+pub fn wizard_validate_name(name: String) -> fsync::Result<NameCheck> {
+    Ok(validate::check_name(&name)?)
+}
+
+#[tauri::command]
+pub async fn wizard_validate_local_dir(path: FsPathBuf) -> fsync::Result<LocalDirCheck> {
+    Ok(validate::check_local_dir(&path).await?)
+}
+
+/// Start the authorization of the Drive of `opts`, replacing any previous one
+#[tauri::command]
+pub async fn wizard_drive_auth_begin(
+    wizard: tauri::State<'_, Wizard>,
+    opts: config::drive::Opts,
+) -> fsync::Result<DriveAuthCheck> {
+    let mut auth = wizard.auth.lock().await;
+    auth.take();
+    let new_auth = DriveAuth::begin(opts).await?;
+    let check = new_auth.check();
+    *auth = Some(new_auth);
+    Ok(check)
+}
+
+#[tauri::command]
+pub async fn wizard_drive_auth_poll(
+    wizard: tauri::State<'_, Wizard>,
+) -> fsync::Result<DriveAuthCheck> {
+    let mut auth = wizard.auth.lock().await;
+    let Some(auth) = auth.as_mut() else {
+        return Ok(DriveAuthCheck::NotStarted);
+    };
+    auth.poll().await;
+    Ok(auth.check())
+}
+
+/// Abandon the authorization, and delete its token
+#[tauri::command]
+pub async fn wizard_cancel(wizard: tauri::State<'_, Wizard>) -> Result<(), ()> {
+    wizard.auth.lock().await.take();
+    Ok(())
+}
+
+/// The folders of the authorized Drive completing `prefix`, as absolute paths.
+/// The folders are listed in the parent of `prefix`, and filtered by its last component.
+#[tauri::command]
+pub async fn wizard_drive_list_roots(
+    wizard: tauri::State<'_, Wizard>,
+    prefix: String,
+) -> fsync::Result<Vec<String>> {
+    let auth = wizard.auth.lock().await;
+    let drive = auth
+        .as_ref()
+        .and_then(DriveAuth::drive)
+        .ok_or_else(|| fsync::other_error!("Drive is not authorized"))?;
+
+    let prefix = format!("/{}", prefix.trim_start_matches('/'));
+    let (parent, partial) = prefix.rsplit_once('/').unwrap_or_default();
+    let parent_path = PathBuf::from(if parent.is_empty() { "/" } else { parent });
+    let names = match drive.folder_names(&parent_path).await {
+        Ok(names) => names,
+        Err(fsync::Error::Path(fsync::PathError::NotFound(..))) => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let partial = partial.to_lowercase();
+    Ok(names
+        .into_iter()
+        .filter(|name| name.to_lowercase().starts_with(&partial))
+        .map(|name| format!("{parent}/{name}"))
+        .collect())
+}
+
+/// Create the instance, after checking all the steps again.
+/// The local directory is created, and the token of the authorization is moved to the
+/// cache of the instance. If any of this fails, the instance is deleted.
+#[tauri::command]
+pub async fn wizard_commit(
+    wizard: tauri::State<'_, Wizard>,
+    name: String,
+    local_dir: FsPathBuf,
+    opts: ProviderOpts,
+    encryption: bool,
+) -> fsync::Result<()> {
+    let name_check = validate::check_name(&name)?;
+    if !name_check.is_valid() {
+        return Err(fsync::other_error!("Invalid name: {name_check:?}"));
+    }
+    let dir_check = validate::check_local_dir(&local_dir).await?;
+    if !dir_check.is_valid() {
+        return Err(fsync::other_error!(
+            "Invalid local directory: {dir_check:?}"
+        ));
+    }
+
+    let mut auth = wizard.auth.lock().await;
+    let token_cache = match &opts {
+        ProviderOpts::GoogleDrive(drive_opts) => {
+            let auth = auth
+                .as_ref()
+                .filter(|auth| auth.opts.secret == drive_opts.secret)
+                .ok_or_else(|| fsync::other_error!("Drive is not authorized"))?;
+            let drive = auth
+                .drive()
+                .ok_or_else(|| fsync::other_error!("Drive is not authorized"))?;
+            if let Some(root) = drive_opts.root.as_deref().filter(|r| *r != "/") {
+                // the folder exists if it can be listed
+                drive.folder_names(&PathBuf::from(root)).await?;
+            }
+            Some(auth.staging.join(TOKEN_CACHE))
+        }
+        ProviderOpts::LocalFs(dir) => {
+            if !dir.is_dir() {
+                return Err(fsync::other_error!("{dir} is not a directory"));
+            }
+            None
+        }
+    };
+
+    config::create(&name, &local_dir, &opts, encryption).await?;
+    if let Err(err) = finish(&name, &local_dir, token_cache.as_deref()).await {
+        let _ = tokio::fs::remove_dir_all(inst::config_dir(&name)?).await;
+        let _ = tokio::fs::remove_dir_all(inst::cache_dir(&name)?).await;
+        return Err(err.into());
+    }
+    // the token was moved, the staging directory is deleted
+    auth.take();
+    Ok(())
+}
+
+async fn finish(
+    name: &str,
+    local_dir: &FsPath,
+    token_cache: Option<&FsPath>,
+) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(local_dir)
+        .await
+        .with_context(|| format!("Could not create {local_dir}"))?;
+    if let Some(token_cache) = token_cache {
+        let dest = inst::token_cache_file(name)?;
+        tokio::fs::create_dir_all(inst::cache_dir(name)?).await?;
+        tokio::fs::rename(token_cache, &dest)
+            .await
+            .with_context(|| format!("Could not move {token_cache} to {dest}"))?;
+    }
+    Ok(())
+}
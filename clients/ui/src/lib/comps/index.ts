@@ -1,2 +1,6 @@
+export { default as DebugConsole } from './DebugConsole.svelte';
 export { default as MatSymIcon } from './MatSymIcon.svelte';
 export { default as NavEntryRow } from './NavEntryRow.svelte';
+export { default as ResolveDialog } from './ResolveDialog.svelte';
+export { default as RootMigrationDialog } from './RootMigrationDialog.svelte';
+export { default as TransfersDialog } from './TransfersDialog.svelte';
@@ -1,9 +1,10 @@
 import { Menu, MenuItem, Submenu } from '@tauri-apps/api/menu';
 import type types from './types';
 import type { EntryStatus } from './model';
-import { openPath } from './ipc';
+import { daemonRemoteLink, openPath } from './ipc';
 
 export type OperateCb = (op: types.Operation) => Promise<void>;
+export type PinCb = (pinned: boolean) => Promise<void>;
 
 /**
  * Show a context menu for the given entry
@@ -13,6 +14,7 @@ export async function showContextMenu(
   type: types.EntryType,
   status: EntryStatus,
   operate: OperateCb,
+  setPinned: PinCb,
 ): Promise<void> {
   if (type === 'inconsistent') {
     console.error('inconsistent entry type');
@@ -33,9 +35,18 @@ export async function showContextMenu(
   }
 
   if (status !== 'syncFull') {
-    const text = type == 'directory' ? 'Synchronize all' : 'Synchronize';
-    const op: SyncOp = type === 'directory' ? 'syncDeep' : 'sync';
-    menu.append(await syncItem(operate, text, entry.path, op));
+    if (type === 'directory') {
+      const sync_menu = await Submenu.new({ text: 'Synchronize all' });
+      sync_menu.append(await Promise.all([
+        syncItem(operate, 'Tree order', entry.path, 'syncDeep'),
+        syncItem(operate, 'Newest first', entry.path, 'syncDeep', 'newestFirst'),
+        syncItem(operate, 'Smallest first', entry.path, 'syncDeep', 'smallestFirst'),
+        syncItem(operate, 'Largest first', entry.path, 'syncDeep', 'largestFirst')
+      ]));
+      menu.append(sync_menu);
+    } else {
+      menu.append(await syncItem(operate, 'Synchronize', entry.path, 'sync'));
+    }
   }
 
   if (status === 'conflict' || status === 'conflictFull') {
@@ -55,12 +66,39 @@ export async function showContextMenu(
     menu.append(resolve_menu);
   }
 
+  menu.append(
+    await MenuItem.new({
+      text: entry.pinned ? 'Unpin' : 'Pin',
+      action: async () => setPinned(!entry.pinned),
+    })
+  );
+
+  const hasRemote = 'remote' in entry.entry || 'sync' in entry.entry;
+
+  if (hasRemote && type === 'regular') {
+    menu.append(
+      await MenuItem.new({
+        text: 'Copy link',
+        action: async () => {
+          const link = await daemonRemoteLink(entry.path);
+          await navigator.clipboard.writeText(link.webView);
+        },
+      })
+    );
+  }
+
   menu.popup();
 }
 
 type SyncOp = 'sync' | 'syncDeep';
 
-async function syncItem(operate: OperateCb, text: string, path: string, op: SyncOp) {
+async function syncItem(
+  operate: OperateCb,
+  text: string,
+  path: string,
+  op: SyncOp,
+  order: types.OrderBy = 'treeOrder',
+) {
   const action =
     op == 'sync'
       ? async () => {
@@ -68,10 +106,16 @@ async function syncItem(operate: OperateCb, text: string, path: string, op: Sync
             sync: path
           });
         }
-      : async () => {
+      : order == 'treeOrder'
+      ? async () => {
           operate({
             syncDeep: path
           });
+        }
+      : async () => {
+          operate({
+            syncDeepOrdered: [path, order]
+          });
         };
   return await MenuItem.new({
     text,
@@ -1,4 +1,5 @@
 import { invoke } from "@tauri-apps/api/core";
+import { ask } from '@tauri-apps/plugin-dialog';
 import type types from "./types";
 
 export async function errorMessage(err: types.Error): Promise<string> {
@@ -11,13 +12,47 @@ export async function instanceGetAll(): Promise<types.Instance[]> {
   return invoke('instance_get_all');
 }
 
-export async function instanceCreate(name: string, localDir: string, opts: types.ProviderOpts) {
-  const args = {
-    name,
-    localDir,
-    opts
-  };
-  return invoke('instance_create', args);
+export async function wizardValidateName(name: string): Promise<types.NameCheck> {
+  return invoke('wizard_validate_name', { name });
+}
+
+export async function wizardValidateLocalDir(path: string): Promise<types.LocalDirCheck> {
+  return invoke('wizard_validate_local_dir', { path });
+}
+
+export async function wizardDriveAuthBegin(opts: types.DriveOpts): Promise<types.DriveAuthCheck> {
+  return invoke('wizard_drive_auth_begin', { opts });
+}
+
+export async function wizardDriveAuthPoll(): Promise<types.DriveAuthCheck> {
+  return invoke('wizard_drive_auth_poll');
+}
+
+export async function wizardDriveListRoots(prefix: string): Promise<string[]> {
+  return invoke('wizard_drive_list_roots', { prefix });
+}
+
+export async function wizardCancel(): Promise<void> {
+  return invoke('wizard_cancel');
+}
+
+export async function wizardCommit(
+  name: string,
+  localDir: string,
+  opts: types.ProviderOpts,
+  encryption: boolean
+): Promise<void> {
+  return invoke('wizard_commit', { name, localDir, opts, encryption });
+}
+
+export async function appBackground(): Promise<boolean> {
+  return invoke('app_background');
+}
+
+export async function appSetBackground(enabled: boolean): Promise<void> {
+  return invoke('app_set_background', {
+    enabled
+  });
 }
 
 export async function daemonConnected(): Promise<boolean> {
@@ -34,14 +69,49 @@ export async function daemonConnect(name?: string): Promise<void> {
   });
 }
 
-export async function daemonNodeAndChildren(path: string | null): Promise<types.NodeAndChildren> {
-  return invoke('daemon_node_and_children', { path });
+export async function daemonNodeAndChildren(
+  path: string | null,
+  bypass?: boolean,
+  sort?: types.SortOrder
+): Promise<types.NodeAndChildren> {
+  return invoke('daemon_node_and_children', {
+    path,
+    sort: sort ?? null,
+    bypass: bypass ?? false
+  });
 }
 
+// The daemon refuses the deletion of large sub-trees until the user confirms it
 export async function daemonOperate(operation: types.Operation): Promise<types.Progress> {
-  return invoke('daemon_operate', {
-    operation
-  });
+  try {
+    return await invoke('daemon_operate', { operation, confirmation: null });
+  } catch (err) {
+    const error = err as types.Error;
+    if (typeof error !== 'object' || !('confirmationRequired' in error)) {
+      throw err;
+    }
+    const [token, summary] = error.confirmationRequired;
+    if (!(await ask(`${summary}.\nThis cannot be undone.`, { title: 'Delete', kind: 'warning' }))) {
+      throw err;
+    }
+    return invoke('daemon_operate', { operation, confirmation: token });
+  }
+}
+
+export async function daemonInvalidate(path: string | null): Promise<void> {
+  return invoke('daemon_invalidate', { path });
+}
+
+export async function daemonRescan(path: string, deep: boolean): Promise<types.RescanReport> {
+  return invoke('daemon_rescan', { path, deep });
+}
+
+export async function daemonAggregateNow(path: string): Promise<void> {
+  return invoke('daemon_aggregate_now', { path });
+}
+
+export async function daemonSetPinned(path: string, pinned: boolean): Promise<void> {
+  return invoke('daemon_set_pinned', { path, pinned });
 }
 
 export async function daemonProgress(path: string): Promise<types.Progress | null> {
@@ -56,6 +126,92 @@ export async function daemonProgresses(path: string): Promise<types.PathProgress
   });
 }
 
+export async function daemonInstanceStats(): Promise<types.InstanceStats> {
+  return invoke('daemon_instance_stats');
+}
+
+export async function daemonTransfers(): Promise<types.TransferRow[]> {
+  return invoke('daemon_transfers');
+}
+
+export async function daemonStatus(): Promise<types.Status> {
+  return invoke('daemon_status');
+}
+
+export async function daemonRootChange(): Promise<types.RootChange | null> {
+  return invoke('daemon_root_change');
+}
+
+export async function daemonMigrateRoot(choice: types.MigrationChoice): Promise<void> {
+  return invoke('daemon_migrate_root', { choice });
+}
+
+export async function daemonFileHead(
+  path: string,
+  loc: types.StorageLoc,
+  maxBytes: number
+): Promise<number[]> {
+  return invoke('daemon_file_head', {
+    path,
+    loc,
+    maxBytes
+  });
+}
+
+export async function daemonFilePreview(path: string): Promise<types.Preview> {
+  return invoke('daemon_file_preview', {
+    path
+  });
+}
+
+export async function daemonRemoteRevisions(
+  path: string,
+  max: number
+): Promise<types.Revision[]> {
+  return invoke('daemon_remote_revisions', {
+    path,
+    max
+  });
+}
+
+export async function daemonDownloadRevision(
+  path: string,
+  revision: types.Revision
+): Promise<string> {
+  return invoke('daemon_download_revision', {
+    path,
+    revision
+  });
+}
+
+export async function daemonRemoteLink(path: string): Promise<types.RemoteLink> {
+  return invoke('daemon_remote_link', {
+    path
+  });
+}
+
+export async function daemonLogsTail(
+  max: number,
+  minLevel: types.LogLevel
+): Promise<types.LogRecord[]> {
+  return invoke('daemon_logs_tail', {
+    max,
+    minLevel
+  });
+}
+
+export async function daemonLogsSince(
+  cursor: number,
+  max: number,
+  minLevel: types.LogLevel
+): Promise<[number, types.LogRecord[]]> {
+  return invoke('daemon_logs_since', {
+    cursor,
+    max,
+    minLevel
+  });
+}
+
 export async function openPath(path: string): Promise<void> {
   return invoke('open_path', {
     path
@@ -46,11 +46,11 @@ export function entryStatus(entry: types.TreeEntry): EntryStatus {
     // sync
     const ns = entry.stats.node;
     const all_conflicts =
-      ns.conflicts == entry.stats.local.files &&
-      ns.conflicts == entry.stats.remote.files;
-    if (ee.sync.conflict || all_conflicts) {
+      entry.conflictsBelow == entry.stats.local.files &&
+      entry.conflictsBelow == entry.stats.remote.files;
+    if (entry.conflictsHere || all_conflicts) {
       return 'conflictFull';
-    } else if (ns.conflicts) {
+    } else if (entry.conflictsBelow) {
       return 'conflict';
     } else if (ns.nodes == ns.sync) {
       return 'syncFull';
@@ -0,0 +1,1768 @@
+// AUTO-GENERATED by typescript-type-def
+
+export default types;
+export namespace types {
+    export type Location = ("local" | "remote" | "both");
+    export type PathError = ({
+        "notFound": [string, (types.Location | null)];
+    } | {
+        "only": [string, types.Location];
+    } | {
+        "unexpected": [string, types.Location];
+    } | {
+        "illegal": [string, (string | null)];
+    });
+
+    /**
+     * Typed failures of the OAuth2 device code flow
+     */
+    export type DeviceCodeError = (
+    /**
+     * The user did not enter the code before it expired
+     */
+"expired" | 
+    /**
+     * The user denied the authorization request
+     */
+"denied");
+    export type U64 = number;
+
+    /**
+     * Direction in which an instance synchronizes its entries
+     */
+    export type SyncMode = (
+    /**
+     * Both storages are kept in sync
+     */
+"bidirectional" | 
+    /**
+     * Only the remote storage is modified, the local files are never touched
+     */
+"uploadOnly" | 
+    /**
+     * Only the local storage is modified, the remote files are never touched
+     */
+"downloadOnly");
+
+    /**
+     * The remote root folder an instance is paired with
+     */
+    export type RemoteRoot = {
+
+        /**
+         * Identity of the root folder in the provider, as its Drive id or its canonical path
+         */
+        "id": string;
+
+        /**
+         * The root as written in the config
+         */
+        "config": string;
+    };
+
+    /**
+     * The remote root of the config no longer resolves to the folder the instance was paired with
+     */
+    export type RootChange = {
+
+        /**
+         * The root the local directory was synchronized with
+         */
+        "recorded": types.RemoteRoot;
+
+        /**
+         * The root the config resolves to now
+         */
+        "resolved": types.RemoteRoot;
+    };
+
+    /**
+     * An error type for RPC results
+     */
+    export type Error = ({
+        "path": types.PathError;
+    } | {
+        "utf8": string;
+    } | {
+        "illegalSymlink": {
+            "path": string;
+            "target": string;
+        };
+    } | {
+        "io": string;
+    } | {
+        "auth": string;
+    } | {
+        "deviceCode": types.DeviceCodeError;
+    } | {
+        "notEmpty": string;
+    } | {
+        "insufficientSpace": {
+            "available": types.U64;
+            "required": types.U64;
+        };
+    } | {
+        "quotaExceeded": {
+            "remaining": types.U64;
+            "required": types.U64;
+        };
+    } | {
+        "conflict": string;
+    } | {
+        "unresolved": [string, string];
+    } | {
+        "api": string;
+    } | {
+        "bug": string;
+    } | {
+        "other": string;
+    } | {
+
+        /**
+         * The client did not authenticate to the daemon, or presented a wrong token
+         */
+        "unauthorized": string;
+    } | {
+
+        /**
+         * The entry is pinned, it cannot be deleted or overwritten
+         */
+        "pinned": string;
+    } | 
+    /**
+     * The remote storage is still being initialized, the operation can be retried later
+     */
+"remoteInitializing" | {
+
+        /**
+         * The storage could not be reached, or answered with a server error or a rate limit.
+         * The operation can be retried later.
+         */
+        "unavailable": string;
+    } | {
+
+        /**
+         * The operation would modify a storage that the sync mode of the instance leaves untouched
+         */
+        "withheld": [string, types.SyncMode];
+    } | {
+
+        /**
+         * The authorization was not completed in the browser within the given seconds.
+         * The operation can be attempted again to get a new authorization page.
+         */
+        "authTimeout": types.U64;
+    } | {
+
+        /**
+         * The remote root of the config changed since the local directory was synchronized.
+         * The operations are refused until the change is migrated, see [`crate::Fsync::migrate_root`].
+         */
+        "rootChanged": types.RootChange;
+    } | {
+
+        /**
+         * The remote file can be viewed but not downloaded, e.g. because its owner disabled
+         * the downloads. The web view link, if known, allows to open it in the browser.
+         */
+        "notDownloadable": {
+            "path": string;
+            "web_view_link": (string | null);
+        };
+    } | {
+
+        /**
+         * The operation deletes a sub-tree larger than the thresholds of the config.
+         * It runs if submitted again with the token in [`crate::OperateOptions::confirmation`]
+         * within [`crate::CONFIRMATION_WINDOW`].
+         * The summary tells the user what would be deleted.
+         */
+        "confirmationRequired": [string, string];
+    } | {
+
+        /**
+         * The storage does not support the feature, e.g. the revisions of the files.
+         * The clients may hide the feature instead of reporting the error.
+         */
+        "unsupported": string;
+    });
+    export type Provider = ("drive" | "fs");
+    export type StorageDir = ("localToRemote" | "remoteToLocal");
+    export type StorageLoc = ("local" | "remote");
+    export type I64 = number;
+
+    /**
+     * Stats for a directory.
+     * This is recursive stats for all children of a directory,
+     * including grand-children and so forth.
+     * 
+     * The counters are signed because the stats are also the differences
+     * applied to the ancestors when the tree changes.
+     * The arithmetic saturates rather than wrapping around.
+     */
+    export type DirStat = {
+
+        /**
+         * The data in the directory, in bytes
+         */
+        "data": types.I64;
+
+        /**
+         * The number of directory entries in this directory
+         */
+        "dirs": types.I64;
+
+        /**
+         * The number of file entries in this directory
+         */
+        "files": types.I64;
+    };
+    export type Metadata = ({
+        "directory": {
+            "path": string;
+            "stat": (types.DirStat | null);
+        };
+    } | {
+        "regular": {
+            "path": string;
+            "size": types.U64;
+            "mtime": types.I64;
+
+            /**
+             * For hard links, the path of the first link seen to the same file.
+             * The data of such entries is not accounted for in the stats.
+             */
+            "link_target"?: (string | null);
+        };
+    });
+    export type Conflict = ("localNewer" | "localOlder" | "localBigger" | "localSmaller" | "localFileRemoteDir" | "localDirRemoteFile" | 
+    /**
+     * The files have the same size and modification time, but their content differs.
+     * Never found by [`Conflict::check`], only by [`crate::Fsync::verify`].
+     */
+"contentMismatch");
+    export type Entry = ({
+        "local": types.Metadata;
+    } | {
+        "remote": types.Metadata;
+    } | {
+        "sync": {
+            "local": types.Metadata;
+            "remote": types.Metadata;
+
+            /**
+             * Conflict for this very entry
+             */
+            "conflict": (types.Conflict | null);
+        };
+    });
+
+    /**
+     * Stats for a Node in the tree structure.
+     * That is, the stats for both local and remote files and directories
+     */
+    export type NodeStat = {
+        "nodes": types.I64;
+        "sync": types.I64;
+
+        /**
+         * The number of conflicting entries of the sub-tree, the node included.
+         * Each conflict is counted once, at its own node, whatever its descendants.
+         */
+        "conflicts": types.I64;
+    };
+    export type EntryNode = {
+        "entry": types.Entry;
+        "children": (string)[];
+        "childrenNodeStat": types.NodeStat;
+    };
+    export type ResolutionMethod = ("replaceOlderByNewer" | "replaceNewerByOlder" | "replaceLocalByRemote" | "replaceRemoteByLocal" | "deleteOlder" | "deleteNewer" | "deleteLocal" | "deleteRemote" | "createLocalCopy" | 
+    /**
+     * Merge the concurrent edits of a text file, line by line, from the version
+     * last synchronized. The conflict stays if the edits overlap.
+     * Only suggested for the files that [`crate::text::is_mergeable`] accepts.
+     * Since protocol version 33.
+     */
+"mergeText");
+
+    /**
+     * A conflict with its user-facing description, for clients that cannot call
+     * the methods of [`Conflict`]
+     */
+    export type ConflictDetail = {
+        "conflict": types.Conflict;
+        "summary": string;
+        "explanation": string;
+
+        /**
+         * Empty if the conflict needs a manual intervention
+         */
+        "suggestedResolutions": (types.ResolutionMethod)[];
+    };
+
+    /**
+     * Role granted by a permission of a shared entry, from the least to the most privileged
+     */
+    export type SharingRole = ("reader" | "commenter" | "writer");
+    export type U32 = number;
+
+    /**
+     * Compact summary of the permissions of a shared remote entry.
+     * It is provided by [`Fsync::sharing`], apart from the metadata of the entry.
+     */
+    export type Sharing = {
+
+        /**
+         * Role granted to anyone with the link
+         */
+        "anyone": (types.SharingRole | null);
+
+        /**
+         * Role granted to the members of a domain
+         */
+        "domain": (types.SharingRole | null);
+
+        /**
+         * Number of users and groups the entry is shared with, besides its owner
+         */
+        "users": types.U32;
+    };
+
+    /**
+     * The order in which the children of a directory are presented to the user.
+     * The tree itself always keeps the byte-wise order of the names.
+     */
+    export type SortOrder = (
+    /**
+     * Byte-wise order of the names, as in the tree.
+     * Uppercase letters come before lowercase ones, and `10` before `2`.
+     */
+"raw" | 
+    /**
+     * Alphabetical order, ignoring the case.
+     */
+"caseInsensitive" | 
+    /**
+     * Alphabetical order ignoring the case, with the numbers compared by value.
+     */
+"natural");
+
+    /**
+     * The unit system of [human_bytes]
+     */
+    export type Unit = (
+    /**
+     * Powers of 1024 (KiB, MiB, ...)
+     */
+"binary" | 
+    /**
+     * Powers of 1000 (kB, MB, ...)
+     */
+"decimal");
+    export type DeletionMethod = (
+    /**
+     * Will delete local files and folders only if they are synced with remote.
+     * Files with conflict will be deleted as well.
+     */
+"localIfSync" | 
+    /**
+     * Will delete remote files and folders only if they are synced locally.
+     * Files with conflict will be deleted as well.
+     */
+"remoteIfSync" | 
+    /**
+     * Will delete local files and folders only if they are synced with remote.
+     * Files with conflict won't be deleted.
+     */
+"localIfSyncNoConflict" | 
+    /**
+     * Will delete remote files and folders only if they are synced locally.
+     * Files with conflict won't be deleted.
+     */
+"remoteIfSyncNoConflict" | 
+    /**
+     * Will delete all local files and folders.
+     */
+"local" | 
+    /**
+     * Will delete all remote files and folders.
+     */
+"remote" | 
+    /**
+     * Will delete both local and remote files and folders, losing all data.
+     */
+"all");
+
+    /**
+     * The order in which the children of a directory are processed by deep operations.
+     */
+    export type OrderBy = (
+    /**
+     * Alphabetical order of the entry names.
+     */
+"treeOrder" | 
+    /**
+     * Most recently modified files first. Directories come last.
+     */
+"newestFirst" | 
+    /**
+     * Smallest files and directories first.
+     */
+"smallestFirst" | 
+    /**
+     * Largest files and directories first.
+     */
+"largestFirst");
+
+    /**
+     * An operation on the entry at a path, and its descendants for the deep variants.
+     * Operations on the root `/` apply to its children only:
+     * the root entry itself is never created, replaced nor deleted.
+     */
+    export type Operation = ({
+        "sync": string;
+    } | {
+        "resolve": [string, types.ResolutionMethod];
+    } | {
+        "delete": [string, types.DeletionMethod];
+    } | {
+        "syncDeep": string;
+    } | {
+        "resolveDeep": [string, types.ResolutionMethod];
+    } | {
+        "deleteDeep": [string, types.DeletionMethod];
+    } | {
+
+        /**
+         * Same as `SyncDeep`, but the children are processed in the given order.
+         */
+        "syncDeepOrdered": [string, types.OrderBy];
+    } | {
+
+        /**
+         * Download a remote-only file, replacing its local placeholder if it has one.
+         * A local file created since is never overwritten.
+         * Since protocol version 7.
+         */
+        "hydrate": string;
+    } | {
+
+        /**
+         * Copy the remote-only entries of the sub-tree to the local storage,
+         * and replace the conflicting local files by the remote ones.
+         * Nothing is deleted, and synchronized entries are left untouched.
+         * Since protocol version 10.
+         */
+        "restoreLocal": string;
+    } | {
+
+        /**
+         * Same as `RestoreLocal`, from the local storage to the remote one.
+         * Since protocol version 10.
+         */
+        "restoreRemote": string;
+    });
+
+    /**
+     * What an operation will do on a single entry
+     */
+    export type Action = ({
+
+        /**
+         * Create the directory in the given storage
+         */
+        "mkdir": types.StorageLoc;
+    } | {
+
+        /**
+         * Copy the file in the given direction
+         */
+        "copy": types.StorageDir;
+    } | {
+
+        /**
+         * Replace the conflicting file in the given direction
+         */
+        "replace": types.StorageDir;
+    } | 
+    /**
+     * Keep a copy of the local file, and replace it by the remote one
+     */
+"copyLocalAndReplace" | 
+    /**
+     * Merge the local and remote versions of the text file, and write the result to both
+     */
+"mergeText" | {
+
+        /**
+         * Delete the entry from the given storage
+         */
+        "delete": types.Location;
+    } | {
+
+        /**
+         * The operation will fail on this entry
+         */
+        "fail": types.Error;
+    } | 
+    /**
+     * Skip the file, larger than the size limit
+     */
+"skipTooLarge" | 
+    /**
+     * Skip the entry, as the sync mode of the instance withholds this direction
+     */
+"skipWithheld" | 
+    /**
+     * Remove the entry from the tree, as it was deleted on both sides outside of fsyncd
+     */
+"forget" | 
+    /**
+     * Let anyone with the link read the remote entry
+     */
+"sharePublic" | 
+    /**
+     * Mark the local and remote files as synchronized, as they have the same content
+     */
+"pair");
+
+    /**
+     * An action planned on an entry by an operation
+     */
+    export type PlannedAction = {
+        "path": string;
+        "action": types.Action;
+
+        /**
+         * Number of bytes to transfer
+         */
+        "size": types.U64;
+    };
+
+    /**
+     * The entries selected by an operation or a listing, see the [module](self) documentation
+     */
+    export type EntryFilter = {
+
+        /**
+         * Only the entries under this path, the path included, or the whole tree if `None`
+         */
+        "under": (string | null);
+
+        /**
+         * The patterns selecting the entries, anchored to `under`. All entries if empty.
+         */
+        "patterns": (string)[];
+
+        /**
+         * Only the conflicts of these kinds. All entries, conflicts or not, if empty.
+         */
+        "conflicts": (types.Conflict)[];
+    };
+
+    /**
+     * Options of an operation started with [`Fsync::operate_with`]
+     */
+    export type OperateOptions = {
+
+        /**
+         * Synchronize the files larger than the size limit instead of skipping them
+         */
+        "forceLarge": boolean;
+
+        /**
+         * The token of a [`crate::Error::ConfirmationRequired`] returned for the same operation.
+         * Since protocol version 32.
+         */
+        "confirmation": (string | null);
+
+        /**
+         * Restrict a deep operation to the entries selected by the filter,
+         * the path of the filter being relative to the tree, not to the operation.
+         * Since protocol version 47.
+         */
+        "filter": (types.EntryFilter | null);
+    };
+
+    /**
+     * What an operation left undone, reported when it completes
+     */
+    export type OperationReport = {
+
+        /**
+         * Number of files skipped for being larger than the size limit
+         */
+        "skippedTooLarge": types.U32;
+
+        /**
+         * Number of entries skipped because they are, or contain, pinned entries
+         */
+        "skippedPinned": types.U32;
+
+        /**
+         * Number of entries of a deep operation that failed with a permanent error,
+         * while the operation continued with the other entries
+         */
+        "failed": types.U32;
+
+        /**
+         * Number of entries skipped because they vanished from a storage since the tree
+         * was built, leaving nothing to do
+         */
+        "skippedVanished": types.U32;
+
+        /**
+         * Number of entries skipped because the sync mode of the instance withholds them
+         */
+        "skippedWithheld": types.U32;
+
+        /**
+         * Number of files skipped because the storage only allows to view them
+         */
+        "skippedNotDownloadable": types.U32;
+
+        /**
+         * Number of entries left for a later run, as the time budget of the scheduled
+         * operation elapsed, see [`crate::Deferral::max_duration`]
+         */
+        "remaining": types.U32;
+    };
+
+    /**
+     * The authorization page of the PKCE flow, to visit in a browser
+     */
+    export type AuthPrompt = {
+        "url": string;
+
+        /**
+         * Time after which the daemon stops waiting for the authorization
+         */
+        "expiresAt": types.I64;
+
+        /**
+         * Whether the daemon opened the page in a browser itself
+         */
+        "opened": boolean;
+    };
+
+    /**
+     * The failure of an operation, once the transient errors were retried
+     */
+    export type OperationFailure = {
+        "error": types.Error;
+
+        /**
+         * Number of times the operation was attempted
+         */
+        "attempts": types.U32;
+
+        /**
+         * Whether the error is transient, see [`crate::Error::is_transient`].
+         * A permanent error needs the user's attention.
+         */
+        "transient": boolean;
+    };
+
+    /**
+     * Progress of an operation.
+     * It is sent in an [envelope](crate::envelope), so that a variant added by a newer
+     * daemon is read as `Unsupported`, wherever the progress is in the response.
+     */
+    export type Progress = ("init" | {
+
+        /**
+         * The user must authorize the application in a browser
+         */
+        "oAuth2Browse": types.AuthPrompt;
+    } | {
+
+        /**
+         * The user must visit `url` and enter `code` to authorize the application
+         */
+        "oAuth2DeviceCode": {
+            "url": string;
+            "code": string;
+        };
+    } | "oAuth2Exchange" | "oAuth2Refresh" | {
+        "progress": {
+            "progress": types.U64;
+            "total": types.U64;
+        };
+    } | "compound" | {
+
+        /**
+         * The operation is paused, and will resume when the condition is resolved
+         */
+        "waiting": string;
+    } | "done" | {
+        "err": types.Error;
+    } | {
+
+        /**
+         * The operation is done, but left some entries undone
+         */
+        "doneWithReport": types.OperationReport;
+    } | {
+
+        /**
+         * The operation failed, after retrying the transient errors
+         */
+        "failed": types.OperationFailure;
+    } | 
+    /**
+     * A progress unknown to this version, sent by a newer daemon.
+     * Must stay the last variant, new variants are added before it.
+     */
+"unsupported");
+
+    /**
+     * Storage quota of a remote drive
+     */
+    export type StorageQuota = {
+
+        /**
+         * The used storage, in bytes
+         */
+        "usage": types.I64;
+
+        /**
+         * The storage limit, in bytes, or `None` if the storage is unlimited
+         */
+        "limit": (types.I64 | null);
+    };
+
+    /**
+     * Disk usage of the caches of an instance, in bytes
+     */
+    export type CacheUsage = {
+
+        /**
+         * The persisted metadata of the remote storage
+         */
+        "metadata": types.U64;
+
+        /**
+         * The cached content of remote files
+         */
+        "content": types.U64;
+
+        /**
+         * The files set aside before being deleted or overwritten
+         */
+        "quarantine": types.U64;
+
+        /**
+         * The files being written
+         */
+        "temp": types.U64;
+
+        /**
+         * The configured budget of all the caches, if any
+         */
+        "budget": (types.U64 | null);
+    };
+
+    /**
+     * Statistics about a running fsyncd instance
+     */
+    export type InstanceStats = {
+
+        /**
+         * The quota of the remote storage, if it has one
+         */
+        "quota": (types.StorageQuota | null);
+
+        /**
+         * Whether the quota usage is above the warning threshold
+         */
+        "quotaWarning": boolean;
+
+        /**
+         * Disk usage of the caches of the instance
+         */
+        "cache": (types.CacheUsage | null);
+
+        /**
+         * The direction in which the instance synchronizes
+         */
+        "syncMode": types.SyncMode;
+
+        /**
+         * Number of entries left out of sync by the sync mode
+         */
+        "withheld": types.U32;
+
+        /**
+         * The [fingerprint](crate::Config::fingerprint) of the config loaded by the daemon,
+         * `None` if it runs without config file
+         */
+        "configFingerprint": (string | null);
+    };
+
+    /**
+     * The reads of remote content looked up in the content cache, since the daemon started
+     */
+    export type CacheLookups = {
+
+        /**
+         * Number of reads of remote content served from the content cache
+         */
+        "hits": types.U64;
+
+        /**
+         * Number of reads of remote content that missed the content cache
+         */
+        "misses": types.U64;
+    };
+
+    /**
+     * The local changes found by [`Fsync::rescan`]
+     */
+    export type RescanReport = {
+
+        /**
+         * Entries that appeared in the local storage
+         */
+        "added": (string)[];
+
+        /**
+         * Entries that disappeared from the local storage, or that are now ignored
+         */
+        "removed": (string)[];
+
+        /**
+         * Entries whose local metadata changed
+         */
+        "modified": (string)[];
+    };
+
+    /**
+     * Phase of the initialization of the remote storage
+     */
+    export type RemotePhase = (
+    /**
+     * The remote storage is being initialized. The operations that need it
+     * fail with [`crate::Error::RemoteInitializing`] until it is ready.
+     */
+"initializing" | {
+
+        /**
+         * The last initialization attempt failed with this message, a new one is scheduled
+         */
+        "retrying": string;
+    } | 
+    /**
+     * The remote storage is ready
+     */
+"ready");
+    export type FsCaps = {
+
+        /**
+         * `name` and `NAME` are different entries
+         */
+        "caseSensitive": boolean;
+
+        /**
+         * The NFC and NFD forms of a name are different entries
+         */
+        "normalizationSensitive": boolean;
+
+        /**
+         * Names are listed in the form they were created with
+         */
+        "normalizationPreserving": boolean;
+
+        /**
+         * Maximum length of a name, in bytes
+         */
+        "maxNameLen": types.U32;
+
+        /**
+         * Names can end with a dot or a space
+         */
+        "trailingDotsSpaces": boolean;
+
+        /**
+         * Granularity of the modification times, in seconds (2 on FAT)
+         */
+        "mtimeGranularity"?: types.U32;
+    };
+
+    /**
+     * A persisted file that could not be read at startup.
+     * It was renamed with a `.corrupt-<timestamp>` suffix and the daemon started without it.
+     */
+    export type CorruptFile = {
+
+        /**
+         * What the file holds, e.g. "token cache"
+         */
+        "kind": string;
+
+        /**
+         * Where the file was moved
+         */
+        "movedTo": string;
+
+        /**
+         * Why the file could not be read
+         */
+        "error": string;
+    };
+
+    /**
+     * State of the [deferral policy](crate::Deferral) of the scheduled work
+     */
+    export type PolicyState = {
+
+        /**
+         * Whether the system runs on battery, `None` if it can't be told on this system
+         */
+        "onBattery": (boolean | null);
+
+        /**
+         * Why the scheduled work is postponed, `None` if it may run now
+         */
+        "deferred": (string | null);
+    };
+
+    /**
+     * A scheduled deep operation stopped by its [time budget](crate::Deferral::max_duration),
+     * to be resumed by its next scheduled run or by [`Fsync::resume`]
+     */
+    export type Checkpoint = {
+        "operation": types.Operation;
+
+        /**
+         * When the budget elapsed
+         */
+        "created": types.I64;
+
+        /**
+         * Number of entries left
+         */
+        "remaining": types.U32;
+
+        /**
+         * Number of bytes left to transfer
+         */
+        "remainingBytes": types.U64;
+    };
+
+    /**
+     * A resume of the system from sleep, detected by the daemon.
+     * The requests in flight at the suspend are retried, and the remote storage
+     * is checked again for the changes made while the system was asleep.
+     */
+    export type Resumed = {
+
+        /**
+         * When the resume was detected
+         */
+        "at": types.I64;
+
+        /**
+         * How long the system was asleep, in seconds
+         */
+        "asleepSecs": types.U64;
+
+        /**
+         * Whether the daemon is still catching up with the changes made during the sleep
+         */
+        "resyncing": boolean;
+    };
+
+    /**
+     * Status of a running fsyncd instance
+     */
+    export type Status = {
+
+        /**
+         * Phase of the initialization of the remote storage
+         */
+        "remote": types.RemotePhase;
+
+        /**
+         * Capabilities of the local filesystem, if they could be probed or loaded
+         */
+        "fsCaps": (types.FsCaps | null);
+
+        /**
+         * Persisted files that could not be read at startup, and that were set aside
+         */
+        "corruptFiles": (types.CorruptFile)[];
+
+        /**
+         * State of the deferral policy of the scheduled work
+         */
+        "policy": types.PolicyState;
+
+        /**
+         * The scheduled deep operation stopped by its time budget, if any
+         */
+        "checkpoint": (types.Checkpoint | null);
+
+        /**
+         * The [fingerprint](crate::Config::fingerprint) of the config loaded by the daemon,
+         * `None` if it runs without config file
+         */
+        "configFingerprint": (string | null);
+
+        /**
+         * The last resume of the system from sleep, if any since the start of the daemon
+         */
+        "resumed": (types.Resumed | null);
+    };
+
+    /**
+     * A difference between the tree of the daemon and the entries listed by the storages,
+     * found by [`Fsync::self_check`]
+     */
+    export type Discrepancy = {
+        "path": string;
+
+        /**
+         * The compared property of the entry: `local`, `remote`, `conflict` or `children`
+         */
+        "field": string;
+
+        /**
+         * The value derived from the storages
+         */
+        "expected": string;
+
+        /**
+         * The value in the tree
+         */
+        "actual": string;
+    };
+
+    /**
+     * A page of the discrepancies found by [`Fsync::self_check_until`]
+     */
+    export type SelfCheckPage = {
+        "discrepancies": (types.Discrepancy)[];
+
+        /**
+         * The name of the last top-level entry checked, to pass as `after` to continue.
+         * `None` once the whole tree is checked.
+         */
+        "next": (string | null);
+    };
+
+    /**
+     * The kinds of files cleaned up by the maintenance task
+     */
+    export type CleanupKind = (
+    /**
+     * A quarantined file older than the retention
+     */
+"quarantine" | 
+    /**
+     * A temporary file of the local tree, left by an interrupted download
+     */
+"tempFile" | 
+    /**
+     * A partial download of the content cache, not written to for a while
+     */
+"partialDownload" | 
+    /**
+     * The oldest records of the audit log, beyond its size cap
+     */
+"auditLog");
+
+    /**
+     * A file deleted, or compacted, by the maintenance task
+     */
+    export type Cleanup = {
+        "kind": types.CleanupKind;
+
+        /**
+         * Path on the local disk, or in the tree for [`CleanupKind::TempFile`]
+         */
+        "path": string;
+
+        /**
+         * Number of bytes freed
+         */
+        "bytes": types.U64;
+    };
+
+    /**
+     * The cleanups of a run of the maintenance task
+     */
+    export type MaintenanceReport = {
+
+        /**
+         * Whether the files were left in place
+         */
+        "dryRun": boolean;
+        "cleanups": (types.Cleanup)[];
+    };
+
+    /**
+     * Algorithm of a [`Checksum`]
+     */
+    export type HashAlgo = ("md5" | "sha256");
+
+    /**
+     * The checksum of the content of a file
+     */
+    export type Checksum = {
+        "algo": types.HashAlgo;
+
+        /**
+         * The digest in lowercase hexadecimal
+         */
+        "hex": string;
+    };
+
+    /**
+     * Activity counters of an fsyncd instance
+     */
+    export type Counters = {
+
+        /**
+         * Bytes written to the remote storage
+         */
+        "bytesUploaded": types.U64;
+
+        /**
+         * Bytes written to the local storage from the remote storage
+         */
+        "bytesDownloaded": types.U64;
+
+        /**
+         * Unit operations that succeeded
+         */
+        "operations": types.U64;
+
+        /**
+         * Unit operations that failed, including the attempts that were retried
+         */
+        "failures": types.U64;
+
+        /**
+         * Attempts of operations that failed with a transient error, and were retried
+         */
+        "retries": types.U64;
+    };
+
+    /**
+     * The activity counters of an instance, since the daemon started and over its lifetime.
+     * The lifetime counters are persisted across the restarts of the daemon.
+     */
+    export type InstanceCounters = {
+        "sinceBoot": types.Counters;
+        "lifetime": types.Counters;
+
+        /**
+         * When the daemon started
+         */
+        "boot": types.I64;
+
+        /**
+         * When the lifetime counters started, at the first start or after their file was lost
+         */
+        "lifetimeStart": types.I64;
+    };
+
+    /**
+     * The conflicts between files of the same content that an instance marked as synchronized
+     * without transfer, e.g. when the daemon stopped between an upload and the update of the tree.
+     * Counted since the daemon started and over its lifetime, as the [`InstanceCounters`].
+     */
+    export type InstanceRepairs = {
+        "sinceBoot": types.U64;
+        "lifetime": types.U64;
+    };
+
+    /**
+     * Memory used by the buffers of the uploads and downloads, in bytes
+     */
+    export type BufferUsage = {
+
+        /**
+         * The configured budget of all the buffers
+         */
+        "budget": types.U64;
+
+        /**
+         * The bytes rented by the transfers in progress
+         */
+        "inUse": types.U64;
+
+        /**
+         * Number of transfers waiting for the budget to be available
+         */
+        "waiters": types.U64;
+    };
+
+    /**
+     * The content checked by [`Fsync::verify`]
+     */
+    export type VerificationReport = {
+
+        /**
+         * Number of files whose local and remote content are the same
+         */
+        "verified": types.U64;
+
+        /**
+         * Files whose local and remote content differ, now [`crate::Conflict::ContentMismatch`]
+         */
+        "mismatched": (string)[];
+
+        /**
+         * Files that could not be verified, and why
+         */
+        "unverifiable": ([string, string])[];
+
+        /**
+         * The last file verified, to pass as `after` to continue.
+         * `None` once the whole sub-tree is verified.
+         */
+        "next": (string | null);
+    };
+
+    /**
+     * Where an interrupted [`Fsync::verify`] stopped
+     */
+    export type VerifyCheckpoint = {
+
+        /**
+         * The sub-tree verified
+         */
+        "path": string;
+        "deep": boolean;
+
+        /**
+         * The last file verified, to pass as `after` to resume
+         */
+        "next": string;
+
+        /**
+         * When the last page was verified
+         */
+        "created": types.I64;
+    };
+
+    /**
+     * How to proceed after a change of the remote root, see [`Fsync::migrate_root`]
+     */
+    export type MigrationChoice = (
+    /**
+     * Pair the local directory with the new root, the local entries winning the conflicts
+     */
+"keepLocal" | 
+    /**
+     * Clear the local directory, to download the new root afresh
+     */
+"resetLocal" | 
+    /**
+     * Write the recorded root back in the config. The daemon must then be restarted.
+     */
+"revertConfig");
+
+    /**
+     * Mark of a remote file that the user can view but not download, e.g. a file shared
+     * with downloads disabled. It is provided by [`Fsync::view_only`], apart from the
+     * metadata of the entry, and such files are skipped by the deep operations.
+     */
+    export type ViewOnly = {
+
+        /**
+         * Link to open the file in the browser, if the storage reports it
+         */
+        "webViewLink": (string | null);
+    };
+
+    /**
+     * Percentiles of the durations of a phase over its most recent samples
+     */
+    export type PhaseTimings = {
+
+        /**
+         * Name of the phase, e.g. `drive.http`
+         */
+        "phase": string;
+
+        /**
+         * Number of samples since the start of the daemon
+         */
+        "count": types.U64;
+
+        /**
+         * Number of bytes moved by the samples that move content
+         */
+        "bytes": types.U64;
+
+        /**
+         * Median duration in microseconds
+         */
+        "p50Us": types.U64;
+        "p90Us": types.U64;
+        "p99Us": types.U64;
+        "maxUs": types.U64;
+    };
+
+    /**
+     * Durations of the expensive phases of the daemon, see [`Fsync::timings`]
+     */
+    export type Timings = {
+
+        /**
+         * Whether the daemon records the timings, with `--timings` or `--profile`
+         */
+        "enabled": boolean;
+
+        /**
+         * The phases that were recorded at least once
+         */
+        "phases": (types.PhaseTimings)[];
+    };
+
+    /**
+     * A past version of a remote file kept by the storage, see [`Fsync::remote_revisions`]
+     */
+    export type Revision = {
+
+        /**
+         * The id of the revision in the storage
+         */
+        "id": string;
+        "mtime": types.I64;
+
+        /**
+         * The size of the content, unknown for some documents
+         */
+        "size": (types.U64 | null);
+
+        /**
+         * The name of the user who made the revision, if reported
+         */
+        "author": (string | null);
+    };
+
+    /**
+     * Links to a remote file, see [`Fsync::remote_link`]
+     */
+    export type RemoteLink = {
+
+        /**
+         * Link to open the file in the browser, for the users it is shared with
+         */
+        "webView": string;
+
+        /**
+         * Link to download the content of the file, if the storage has one for it
+         */
+        "webContent": (string | null);
+    };
+
+    /**
+     * Severity of a record of the daemon log, the most severe first
+     */
+    export type LogLevel = ("error" | "warn" | "info" | "debug" | "trace");
+
+    /**
+     * A record of the daemon log, see [`Fsync::logs_tail`]
+     */
+    export type LogRecord = {
+
+        /**
+         * Position of the record in the log, the cursor of [`Fsync::logs_since`]
+         */
+        "seq": types.U64;
+
+        /**
+         * Milliseconds since the Unix epoch, unlike the modification times kept in seconds
+         */
+        "time": types.I64;
+        "level": types.LogLevel;
+
+        /**
+         * Module or subsystem that logged the record, e.g. `RPC` or `journal`
+         */
+        "target": string;
+        "message": string;
+
+        /**
+         * Identifier of the operation that logged the record, if any
+         */
+        "operation": (types.U64 | null);
+    };
+
+    /**
+     * Why the content of a file was transferred between the storages
+     */
+    export type TransferCause = (
+    /**
+     * The synchronization of new or modified files
+     */
+"sync" | 
+    /**
+     * The resolution of conflicts, the merges of text files included
+     */
+"conflict" | 
+    /**
+     * The repair of the files of which the verification found a content mismatch
+     */
+"verification" | 
+    /**
+     * The deep operations resumed from their checkpoint
+     */
+"resume" | 
+    /**
+     * The requests of the clients, e.g. the reads of remote files and the downloads of revisions
+     */
+"client");
+
+    /**
+     * The content transferred for a [`TransferCause`], uploads and downloads together
+     */
+    export type TransferCounters = {
+
+        /**
+         * Files transferred, once per direction
+         */
+        "files": types.U64;
+
+        /**
+         * Bytes transferred at the first attempt
+         */
+        "bytes": types.U64;
+
+        /**
+         * Bytes of content that was already sent: the transfers that follow a failed attempt,
+         * and the parts that the storage sent again after a failure
+         */
+        "retriedBytes": types.U64;
+    };
+
+    /**
+     * The [`TransferCounters`] of each [`TransferCause`]
+     */
+    export type Transfers = {
+        "sync": types.TransferCounters;
+        "conflict": types.TransferCounters;
+        "verification": types.TransferCounters;
+        "resume": types.TransferCounters;
+        "client": types.TransferCounters;
+    };
+
+    /**
+     * The content transferred by an instance, by cause, since the daemon started
+     * and over its lifetime. The lifetime transfers are persisted with the [`InstanceCounters`].
+     */
+    export type InstanceTransfers = {
+        "sinceBoot": types.Transfers;
+        "lifetime": types.Transfers;
+    };
+
+    /**
+     * Stats for the whole diff tree structure.
+     * That is, the stats for both local and remote files and directories
+     */
+    export type TreeStat = {
+        "local": types.DirStat;
+        "remote": types.DirStat;
+        "node": types.NodeStat;
+    };
+
+    /**
+     * How complete the remote stats of a folder are, see [`crate::Fsync::aggregation`]
+     */
+    export type Aggregation = (
+    /**
+     * The folder and all its sub-folders were listed from the remote storage
+     */
+"exact" | 
+    /**
+     * The folder was listed, some of its sub-folders are not yet
+     */
+"partial" | 
+    /**
+     * The folder is not listed yet, its stats are the ones of the persisted cache
+     */
+"pending");
+
+    /**
+     * The entries of a sub-tree flagged by the daemon,
+     * see [`crate::Fsync::implausible_stats`] and [`crate::Fsync::too_large_stats`]
+     */
+    export type Flagged = {
+
+        /**
+         * The entry itself is flagged
+         */
+        "here": boolean;
+
+        /**
+         * The number of flagged entries in the sub-tree, the entry included
+         */
+        "count": types.I64;
+    };
+
+    /**
+     * A progress struct
+     */
+    export type PathProgress = {
+        "path": string;
+        "progress": types.Progress;
+    };
+    export type Instance = {
+        "name": string;
+        "running": boolean;
+        "provider": types.Provider;
+        "localDir": string;
+    };
+    export type DriveSecretOpts = (
+    /**
+     * Use built-in google-drive app
+     */
+"builtin" | {
+
+        /**
+         * Use custom google-drive app (path to client_secret.json)
+         */
+        "jsonPath": string;
+    } | {
+
+        /**
+         * Use custom google-drive app (content of client_secret.json)
+         */
+        "jsonContent": string;
+    } | {
+
+        /**
+         * Use custom google-drive app (client credentials)
+         */
+        "credentials": {
+            "client_id": string;
+            "client_secret": string;
+        };
+    });
+    export type DriveOpts = {
+        "root": (string | null);
+        "secret": types.DriveSecretOpts;
+    };
+    export type ProviderOpts = ({
+        "drive": types.DriveOpts;
+    } | {
+        "fs": string;
+    });
+    export type EntryType = (
+    /**
+     * Entry is a directory
+     */
+"directory" | 
+    /**
+     * Entry is a regular file
+     */
+"regular" | 
+    /**
+     * Entry type is not consistent accross remote and local storage
+     */
+"inconsistent");
+
+    /**
+     * Pre-formatted size, modification time and sharing of an entry, for display.
+     * Fields are `None` when the entry doesn't exist at the location,
+     * when there is no modification time (directories), or when the entry is not shared.
+     */
+    export type EntryFmt = {
+        "localSize": (string | null);
+        "remoteSize": (string | null);
+        "localMtime": (string | null);
+        "remoteMtime": (string | null);
+        "sharing": (string | null);
+    };
+    export type TreeEntry = {
+        "path": string;
+        "name": (string | null);
+        "entry": types.Entry;
+        "children": (string)[];
+        "stats": types.TreeStat;
+
+        /**
+         * 1 if the entry itself is a conflict, 0 otherwise
+         */
+        "conflictsHere": types.I64;
+
+        /**
+         * The number of conflicts among the descendants, the entry itself excluded
+         */
+        "conflictsBelow": types.I64;
+        "fmt": types.EntryFmt;
+
+        /**
+         * The entry is pinned, it is never deleted nor overwritten
+         */
+        "pinned": boolean;
+
+        /**
+         * How the remote entry is shared, if it is
+         */
+        "sharing": (types.Sharing | null);
+
+        /**
+         * The remote file can be viewed but not downloaded
+         */
+        "viewOnly": (types.ViewOnly | null);
+
+        /**
+         * How complete the remote stats are
+         */
+        "aggregation": types.Aggregation;
+
+        /**
+         * The entries of implausible metadata in the sub-tree
+         */
+        "implausible": types.Flagged;
+
+        /**
+         * The description of the conflict, if the entry is conflicting
+         */
+        "conflictDetail": (types.ConflictDetail | null);
+    };
+
+    /**
+     * A struct gathering a node and its children
+     */
+    export type NodeAndChildren = {
+        "node": types.TreeEntry;
+        "children": (types.TreeEntry)[];
+    };
+
+    /**
+     * The transfers of a cause, with pre-formatted sizes for display
+     */
+    export type TransferRow = {
+        "cause": types.TransferCause;
+        "files": types.U64;
+        "bytes": string;
+        "retriedBytes": string;
+    };
+
+    /**
+     * Why a preview is not available
+     */
+    export type Unavailable = (
+    /**
+     * One of the files is not a text file
+     */
+"binary" | 
+    /**
+     * One of the files is larger than [MAX_PREVIEW_SIZE]
+     */
+"tooLarge");
+
+    /**
+     * A preview of the differences between local and remote files
+     */
+    export type Preview = ({
+
+        /**
+         * Unified diff from the local to the remote content.
+         * Empty if the contents are identical.
+         */
+        "diff": string;
+    } | {
+
+        /**
+         * The files can't be previewed
+         */
+        "unavailable": types.Unavailable;
+    });
+
+    /**
+     * The result of the validation of the name of a new instance
+     */
+    export type NameCheck = ("valid" | "empty" | {
+
+        /**
+         * The name has characters that can't be in a file name
+         */
+        "invalidChars": (string)[];
+    } | 
+    /**
+     * An instance of this name exists
+     */
+"exists" | 
+    /**
+     * A failed creation left the directory of this name, `fsynctl doctor` deletes it
+     */
+"partial");
+
+    /**
+     * The result of the validation of the local directory of a new instance
+     */
+    export type LocalDirCheck = ({
+
+        /**
+         * The directory exists, with `entries` entries
+         */
+        "exists": {
+            "entries": types.U64;
+        };
+    } | 
+    /**
+     * The directory does not exist, and will be created
+     */
+"creatable" | "notAbsolute" | {
+
+        /**
+         * The path has characters that can't be in a path
+         */
+        "invalidChars": (string)[];
+    } | 
+    /**
+     * The path exists, and is not a directory
+     */
+"notADirectory" | {
+
+        /**
+         * The directory can't be created, for the given reason
+         */
+        "notCreatable": string;
+    } | {
+
+        /**
+         * The directory is, contains or is inside the local directory of another instance
+         */
+        "overlaps": {
+            "instance": string;
+            "local_dir": string;
+        };
+    });
+
+    /**
+     * The state of the authorization of the Drive of a new instance
+     */
+    export type DriveAuthCheck = (
+    /**
+     * The authorization was not started, or was cancelled
+     */
+"notStarted" | {
+
+        /**
+         * The authorization waits for the user, who follows the prompt of the progress
+         */
+        "pending": types.Progress;
+    } | {
+
+        /**
+         * The access is granted to the Drive of `account`
+         */
+        "granted": {
+            "account": (string | null);
+        };
+    } | {
+
+        /**
+         * The authorization failed, for the given reason
+         */
+        "failed": string;
+    });
+}
@@ -0,0 +1,80 @@
+//! Matching of the paths of a tree against glob sets, as in the ignore rules of the daemon.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use fsync::path::{glob::PathGlobSet, PathBuf};
+
+/// Number of paths matched per iteration
+const TREE_SIZE: usize = 100_000;
+
+/// Patterns typical of the ignore files
+const PATTERNS: &[&str] = &[
+    "*.tmp",
+    "*.log",
+    "!keep.log",
+    "*~",
+    ".DS_Store",
+    "Thumbs.db",
+    "/build/",
+    "node_modules/",
+    "target/",
+    "doc/**/*.html",
+    "/photos/**/[Rr][Aa][Ww]",
+    "cache-??",
+    "*.[oa]",
+];
+
+fn tree_paths(size: usize) -> Vec<(PathBuf, bool)> {
+    let mut paths = Vec::with_capacity(size);
+    let mut dirs = vec![PathBuf::root()];
+    let mut i = 0;
+    while paths.len() < size {
+        let dir = dirs[i % dirs.len()].clone();
+        for j in 0..16 {
+            if paths.len() >= size {
+                break;
+            }
+            let is_dir = j % 4 == 0;
+            let name = match j {
+                3 => "notes.log".to_string(),
+                5 => "main.o".to_string(),
+                _ if is_dir => format!("dir-{j:02}"),
+                _ => format!("entry-{j:02}.txt"),
+            };
+            let path = dir.join(name);
+            if is_dir {
+                dirs.push(path.clone());
+            }
+            paths.push((path, is_dir));
+        }
+        i += 1;
+    }
+    paths
+}
+
+fn bench_match(c: &mut Criterion) {
+    let paths = tree_paths(TREE_SIZE);
+    let literal = PathGlobSet::new([".DS_Store", "Thumbs.db", "/build/"]).unwrap();
+    let set = PathGlobSet::new(PATTERNS).unwrap();
+
+    let mut group = c.benchmark_group("glob");
+    group.bench_function("literal", |b| {
+        b.iter(|| {
+            paths
+                .iter()
+                .filter(|(p, is_dir)| literal.is_match(black_box(p), *is_dir))
+                .count()
+        })
+    });
+    group.bench_function("set", |b| {
+        b.iter(|| {
+            paths
+                .iter()
+                .filter(|(p, is_dir)| set.is_match(black_box(p), *is_dir))
+                .count()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_match);
+criterion_main!(benches);
@@ -0,0 +1,118 @@
+//! Lookups and insertions in maps keyed by paths, as in the tree of the daemon.
+//! The keys are compared either component-wise ([`PathBuf`])
+//! or on their bytes ([`NormalizedPathBuf`]).
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use fsync::path::{NormalizedPath, NormalizedPathBuf, PathBuf};
+
+/// Number of nodes of the benchmarked trees
+const TREE_SIZE: usize = 300_000;
+
+/// The paths of a tree of `size` nodes, with a few deep and long shared prefixes
+fn tree_paths(size: usize) -> Vec<PathBuf> {
+    let mut paths = Vec::with_capacity(size);
+    let mut dirs = vec![PathBuf::root()];
+    let mut i = 0;
+    while paths.len() < size {
+        let dir = dirs[i % dirs.len()].clone();
+        for j in 0..16 {
+            if paths.len() >= size {
+                break;
+            }
+            let path = dir.join(format!(
+                "entry-{j:02}.{}",
+                if j % 4 == 0 { "d" } else { "txt" }
+            ));
+            if j % 4 == 0 {
+                dirs.push(path.clone());
+            }
+            paths.push(path);
+        }
+        i += 1;
+    }
+    paths
+}
+
+fn normalized(paths: &[PathBuf]) -> Vec<NormalizedPathBuf> {
+    paths
+        .iter()
+        .map(|p| NormalizedPathBuf::new(p.clone()).unwrap())
+        .collect()
+}
+
+fn bench_lookup(c: &mut Criterion) {
+    let paths = tree_paths(TREE_SIZE);
+    let keys = normalized(&paths);
+
+    let btree: BTreeMap<PathBuf, ()> = paths.iter().map(|p| (p.clone(), ())).collect();
+    let btree_norm: BTreeMap<NormalizedPathBuf, ()> =
+        keys.iter().map(|k| (k.clone(), ())).collect();
+    let hash: HashMap<PathBuf, ()> = paths.iter().map(|p| (p.clone(), ())).collect();
+    let hash_norm: HashMap<NormalizedPathBuf, ()> = keys.iter().map(|k| (k.clone(), ())).collect();
+
+    let mut group = c.benchmark_group("lookup");
+    group.bench_function("btree/path", |b| {
+        b.iter(|| {
+            paths
+                .iter()
+                .all(|p| btree.contains_key(black_box(p.as_path())))
+        })
+    });
+    group.bench_function("btree/normalized", |b| {
+        b.iter(|| {
+            paths.iter().all(|p| {
+                let key = NormalizedPath::new(black_box(p)).unwrap();
+                btree_norm.contains_key(key)
+            })
+        })
+    });
+    group.bench_function("hash/path", |b| {
+        b.iter(|| {
+            paths
+                .iter()
+                .all(|p| hash.contains_key(black_box(p.as_path())))
+        })
+    });
+    group.bench_function("hash/normalized", |b| {
+        b.iter(|| {
+            paths.iter().all(|p| {
+                let key = NormalizedPath::new(black_box(p)).unwrap();
+                hash_norm.contains_key(key)
+            })
+        })
+    });
+    group.finish();
+}
+
+fn insert_all<K: Ord + Hash + Clone>(keys: &[K]) -> (BTreeMap<K, ()>, HashMap<K, ()>) {
+    let mut btree = BTreeMap::new();
+    let mut hash = HashMap::new();
+    for k in keys {
+        btree.insert(k.clone(), ());
+        hash.insert(k.clone(), ());
+    }
+    (btree, hash)
+}
+
+fn bench_insert(c: &mut Criterion) {
+    let paths = tree_paths(TREE_SIZE);
+    let keys = normalized(&paths);
+
+    let mut group = c.benchmark_group("insert");
+    group.sample_size(10);
+    group.bench_function("path", |b| {
+        b.iter_batched(|| paths.clone(), |p| insert_all(&p), BatchSize::LargeInput)
+    });
+    group.bench_function("normalized", |b| {
+        b.iter_batched(|| keys.clone(), |k| insert_all(&k), BatchSize::LargeInput)
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_lookup, bench_insert);
+criterion_main!(benches);
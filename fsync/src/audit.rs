@@ -0,0 +1,134 @@
+//! Audit log of the actions performed by fsyncd on the storages.
+//!
+//! The log is written by the daemon, one JSON record per line, and read by the clients.
+//! When it grows above a size limit, it is moved to the [rotated] file, replacing the
+//! previous one.
+
+use std::io::{self, BufRead};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    path::{FsPath, FsPathBuf, PathBuf},
+    Action, Location, Operation, StorageDir, StorageLoc,
+};
+
+/// An action performed on an entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    /// Identifier of the record, increasing for each action of the instance
+    pub id: u64,
+    pub time: DateTime<Utc>,
+    /// The operation requested by the user, which caused the action,
+    /// or `None` for the clean up of the tree by [`crate::Fsync::gc_tree`]
+    /// and for the public sharing of a file by [`crate::Fsync::share_public`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operation: Option<Operation>,
+    /// Path of the entry acted on
+    pub path: PathBuf,
+    pub action: Action,
+    /// Number of bytes copied or deleted
+    pub bytes: u64,
+    pub undo: Undo,
+}
+
+/// How an action can be undone
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Undo {
+    /// Delete the entry created in the given storage, the other storage still has it
+    DeleteCopy(StorageLoc),
+    /// The action cannot be undone, for the given reason
+    Unavailable(String),
+}
+
+impl Undo {
+    pub fn for_action(action: &Action) -> Self {
+        match action {
+            Action::Mkdir(loc) => Self::DeleteCopy(*loc),
+            Action::Copy(StorageDir::LocalToRemote) => Self::DeleteCopy(StorageLoc::Remote),
+            Action::Copy(StorageDir::RemoteToLocal) => Self::DeleteCopy(StorageLoc::Local),
+            Action::Replace(dir) => Self::Unavailable(format!(
+                "the previous content on the {} was overwritten",
+                Location::from(dir.dest())
+            )),
+            Action::CopyLocalAndReplace => Self::Unavailable(
+                "the previous local content was kept in a copy next to the file".into(),
+            ),
+            Action::MergeText => Self::Unavailable(
+                "the previous contents on both sides were overwritten by the merge".into(),
+            ),
+            Action::Delete(loc) => {
+                Self::Unavailable(format!("deletions on the {loc} are permanent"))
+            }
+            Action::Fail(..) | Action::SkipTooLarge | Action::SkipWithheld => {
+                Self::Unavailable("nothing was changed".into())
+            }
+            Action::Forget => {
+                Self::Unavailable("the entry was already deleted on both sides".into())
+            }
+            Action::SharePublic => {
+                Self::Unavailable("the sharing is removed from the remote drive".into())
+            }
+            Action::Pair => Self::Unavailable("nothing was transferred".into()),
+        }
+    }
+}
+
+/// The file of the previous records, once the log was rotated
+pub fn rotated(path: &FsPath) -> FsPathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push_str(".1");
+    path.with_file_name(name)
+}
+
+/// Read the records of the log at `path`, including the rotated ones, in order.
+/// Lines that cannot be parsed, such as a line cut by a crash, are skipped.
+pub fn read_records(path: &FsPath) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for path in [rotated(path), path.to_owned()] {
+        let file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        for line in io::BufReader::new(file).lines() {
+            let line = line?;
+            if let Ok(record) = serde_json::from_str(&line) {
+                records.push(record);
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undo_of_actions() {
+        assert_eq!(
+            Undo::for_action(&Action::Copy(StorageDir::LocalToRemote)),
+            Undo::DeleteCopy(StorageLoc::Remote)
+        );
+        assert_eq!(
+            Undo::for_action(&Action::Mkdir(StorageLoc::Local)),
+            Undo::DeleteCopy(StorageLoc::Local)
+        );
+        assert!(matches!(
+            Undo::for_action(&Action::Delete(Location::Remote)),
+            Undo::Unavailable(..)
+        ));
+    }
+
+    #[test]
+    fn rotated_name() {
+        assert_eq!(
+            rotated(FsPath::new("/cache/inst/audit.jsonl")),
+            FsPathBuf::from("/cache/inst/audit.jsonl.1")
+        );
+    }
+}
@@ -0,0 +1,254 @@
+//! Capabilities of the local filesystem.
+//!
+//! How a filesystem compares and stores names does not follow from the OS:
+//! a Linux machine can mount a case-insensitive volume, and macOS volumes can be
+//! case-sensitive. The capabilities are therefore probed in the local directory,
+//! by creating and removing a few files in a temporary sub-directory.
+
+use std::{
+    borrow::Cow,
+    fs, io,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use typescript_type_def::TypeDef;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::path::{FsPath, FsPathBuf};
+
+/// Upper bound of the probed name length, in bytes
+const MAX_PROBED_NAME_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct FsCaps {
+    /// `name` and `NAME` are different entries
+    pub case_sensitive: bool,
+    /// The NFC and NFD forms of a name are different entries
+    pub normalization_sensitive: bool,
+    /// Names are listed in the form they were created with
+    pub normalization_preserving: bool,
+    /// Maximum length of a name, in bytes
+    pub max_name_len: u32,
+    /// Names can end with a dot or a space
+    pub trailing_dots_spaces: bool,
+    /// Granularity of the modification times, in seconds (2 on FAT)
+    #[serde(default = "default_mtime_granularity")]
+    pub mtime_granularity: u32,
+}
+
+fn default_mtime_granularity() -> u32 {
+    1
+}
+
+/// The most permissive filesystem, with which no name can collide
+impl Default for FsCaps {
+    fn default() -> Self {
+        Self {
+            case_sensitive: true,
+            normalization_sensitive: true,
+            normalization_preserving: true,
+            max_name_len: 255,
+            trailing_dots_spaces: true,
+            mtime_granularity: 1,
+        }
+    }
+}
+
+impl FsCaps {
+    /// Probe the filesystem of `dir`.
+    /// The probe files are removed, whether probing succeeds or not.
+    pub fn probe(dir: &FsPath) -> io::Result<Self> {
+        let probe = ProbeDir::create(dir)?;
+
+        let case_sensitive = {
+            probe.touch("fsync-case")?;
+            !probe.exists("FSYNC-CASE")
+        };
+
+        let (normalization_sensitive, normalization_preserving) = {
+            let nfc = "fsync-\u{e9}";
+            let nfd = "fsync-e\u{301}";
+            probe.touch(nfc)?;
+            let sensitive = !probe.exists(nfd);
+            let preserving = probe.names()?.iter().any(|name| name == nfc);
+            probe.remove(nfc)?;
+            (sensitive, preserving)
+        };
+
+        let trailing_dots_spaces = {
+            let names = ["fsync-dot.", "fsync-space "];
+            let mut supported = true;
+            for name in names {
+                supported &= probe.touch(name).is_ok();
+            }
+            let listed = probe.names()?;
+            supported && names.iter().all(|name| listed.iter().any(|l| l == name))
+        };
+
+        // binary search of the longest name that can be created
+        let (mut ok, mut ko) = (1, MAX_PROBED_NAME_LEN + 1);
+        while ko - ok > 1 {
+            let len = (ok + ko) / 2;
+            let name = "f".repeat(len);
+            if probe.touch(&name).is_ok() {
+                probe.remove(&name)?;
+                ok = len;
+            } else {
+                ko = len;
+            }
+        }
+
+        // an odd second with a fraction is kept as is, truncated, or rounded to 2 seconds
+        let mtime_granularity = {
+            let mtime = std::time::UNIX_EPOCH + Duration::from_millis(1_700_000_001_500);
+            let stored = probe.set_mtime("fsync-mtime", mtime)?;
+            let secs = |t: SystemTime| {
+                t.duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default()
+            };
+            if secs(stored) == secs(mtime) {
+                1
+            } else {
+                2
+            }
+        };
+
+        Ok(Self {
+            case_sensitive,
+            normalization_sensitive,
+            normalization_preserving,
+            max_name_len: ok as u32,
+            trailing_dots_spaces,
+            mtime_granularity,
+        })
+    }
+
+    /// Load the capabilities recorded at `path`
+    pub async fn load(path: &FsPath) -> anyhow::Result<Self> {
+        let json = tokio::fs::read(path).await?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    /// Record the capabilities at `path`
+    pub async fn save(&self, path: &FsPath) -> anyhow::Result<()> {
+        tokio::fs::write(path, serde_json::to_vec_pretty(self)?).await?;
+        Ok(())
+    }
+
+    /// The key under which `name` is looked up by the filesystem.
+    /// Two names with the same key designate the same entry.
+    pub fn name_key<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let mut key = Cow::Borrowed(name);
+        if !self.normalization_sensitive {
+            key = Cow::Owned(key.nfc().collect());
+        }
+        if !self.case_sensitive {
+            key = Cow::Owned(key.to_lowercase());
+        }
+        key
+    }
+
+    /// Whether the filesystem can store an entry named `name`
+    pub fn supports_name(&self, name: &str) -> bool {
+        name.len() <= self.max_name_len as usize
+            && (self.trailing_dots_spaces || !name.ends_with(['.', ' ']))
+    }
+}
+
+/// Temporary directory of the probe files, removed on drop
+struct ProbeDir(FsPathBuf);
+
+impl ProbeDir {
+    fn create(parent: &FsPath) -> io::Result<Self> {
+        let path = parent.join(format!(".fsync-probe-{}", std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir(&path)?;
+        Ok(Self(path))
+    }
+
+    fn touch(&self, name: &str) -> io::Result<()> {
+        fs::File::create(self.0.join(name))?;
+        Ok(())
+    }
+
+    /// Set the modification time of a new file, and read back the stored time
+    fn set_mtime(&self, name: &str, mtime: SystemTime) -> io::Result<SystemTime> {
+        let file = fs::File::create(self.0.join(name))?;
+        file.set_modified(mtime)?;
+        drop(file);
+        fs::metadata(self.0.join(name))?.modified()
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.0.join(name).exists()
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.0.join(name))
+    }
+
+    fn names(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.0)? {
+            if let Ok(name) = entry?.file_name().into_string() {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+}
+
+impl Drop for ProbeDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_cleans_up() {
+        let dir = std::env::temp_dir().join(format!("fsync-caps-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let caps = FsCaps::probe(&dir).unwrap();
+        assert!(caps.max_name_len >= 100);
+        assert!(matches!(caps.mtime_granularity, 1 | 2));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn name_keys() {
+        let caps = FsCaps {
+            case_sensitive: false,
+            normalization_sensitive: false,
+            ..FsCaps::default()
+        };
+        assert_eq!(caps.name_key("Caf\u{e9}"), caps.name_key("cafe\u{301}"));
+        assert_ne!(caps.name_key("cafe"), caps.name_key("caf\u{e9}"));
+
+        let caps = FsCaps::default();
+        assert_ne!(caps.name_key("Readme"), caps.name_key("README"));
+
+        let caps = FsCaps {
+            max_name_len: 8,
+            trailing_dots_spaces: false,
+            ..FsCaps::default()
+        };
+        assert!(caps.supports_name("file.txt"));
+        assert!(!caps.supports_name("file.text"));
+        assert!(!caps.supports_name("file."));
+        assert!(!caps.supports_name("file "));
+    }
+}
@@ -0,0 +1,113 @@
+//! Comparison of entry names for presentation, see [`crate::SortOrder`].
+//!
+//! The tree keeps its children in the byte-wise order of their names,
+//! which lists `Zebra.txt` before `apple.txt` and `10.txt` before `2.txt`.
+//! The comparators of this module fold the case and the accents of the letters,
+//! a portable approximation of the collation of most locales.
+//! They are only meant to sort the entries shown to the user.
+//! They are total orders: names that only differ by case, accents or leading zeros
+//! are ordered byte-wise, so that paginated listings are stable.
+
+use std::{cmp::Ordering, iter::Peekable};
+
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// The characters of `s` compared at the first level: lowercase, without accents
+fn fold(s: &str) -> impl Iterator<Item = char> + '_ {
+    s.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+}
+
+/// Compare `a` and `b` ignoring the case and the accents of their letters
+pub fn case_insensitive_cmp(a: &str, b: &str) -> Ordering {
+    fold(a).cmp(fold(b)).then_with(|| a.cmp(b))
+}
+
+/// Compare `a` and `b` ignoring the case and the accents of their letters,
+/// with the sequences of ASCII digits compared by their numeric value.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = fold(a).peekable();
+    let mut b_chars = fold(b).peekable();
+    loop {
+        let (ac, bc) = match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(ac), Some(bc)) => (*ac, *bc),
+        };
+        let ord = if ac.is_ascii_digit() && bc.is_ascii_digit() {
+            let a_num = take_number(&mut a_chars);
+            let b_num = take_number(&mut b_chars);
+            cmp_numbers(&a_num, &b_num)
+        } else {
+            a_chars.next();
+            b_chars.next();
+            ac.cmp(&bc)
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+}
+
+fn take_number(chars: &mut Peekable<impl Iterator<Item = char>>) -> String {
+    let mut num = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        num.push(c);
+    }
+    num
+}
+
+/// Compare two sequences of digits by value, without overflow on long sequences
+fn cmp_numbers(a: &str, b: &str) -> Ordering {
+    let a = a.trim_start_matches('0');
+    let b = b.trim_start_matches('0');
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(names: &[&str], cmp: fn(&str, &str) -> Ordering) -> Vec<String> {
+        let mut names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        names.sort_by(|a, b| cmp(a, b));
+        names
+    }
+
+    #[test]
+    fn case_insensitive() {
+        assert_eq!(
+            sorted(
+                &["Zebra.txt", "apple.txt", "Apple.txt", "b.txt"],
+                case_insensitive_cmp
+            ),
+            ["Apple.txt", "apple.txt", "b.txt", "Zebra.txt"]
+        );
+        assert_eq!(
+            sorted(&["zèbre", "Été", "ete", "été"], case_insensitive_cmp),
+            ["ete", "Été", "été", "zèbre"]
+        );
+    }
+
+    #[test]
+    fn natural() {
+        assert_eq!(
+            sorted(&["10.txt", "2.txt", "1.txt", "Zebra", "apple"], natural_cmp),
+            ["1.txt", "2.txt", "10.txt", "apple", "Zebra"]
+        );
+        assert_eq!(
+            sorted(&["img12b", "img12a", "IMG2", "img012", "img"], natural_cmp),
+            ["img", "IMG2", "img012", "img12a", "img12b"]
+        );
+        // longer than any integer type
+        assert_eq!(
+            natural_cmp("v123456789012345678901234567890", "v99"),
+            Ordering::Greater
+        );
+        // equal values fall back to the byte-wise order
+        assert_eq!(natural_cmp("a01", "a1"), Ordering::Less);
+        assert_eq!(natural_cmp("a1", "a1"), Ordering::Equal);
+    }
+}
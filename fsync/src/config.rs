@@ -1,33 +1,530 @@
 use anyhow::Context;
-use glob::{MatchOptions, Pattern, PatternError};
 use serde::{Deserialize, Serialize};
 
-use crate::path::{FsPath, FsPathBuf, Path};
+use crate::{
+    path::{FsPath, FsPathBuf, Path, PathBuf},
+    LogLevel, SyncMode,
+};
 
-#[derive(Default)]
-pub struct PatternList(Vec<Pattern>, MatchOptions);
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Config {
+    pub local_dir: FsPathBuf,
+    pub provider: ProviderConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_free_space: Option<MinFreeSpace>,
+    /// Percentage of the remote quota above which a warning is emitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_warning: Option<f64>,
+    /// Size in bytes above which files are skipped by synchronization, unless forced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
+    /// Protection of the secrets stored on disk
+    #[serde(default, skip_serializing_if = "SecretsProtection::is_plain")]
+    pub secrets: SecretsProtection,
+    /// Patterns of entries excluded from the synchronization, in the syntax of `.fsyncignore` files.
+    /// They have a lower precedence than the `.fsyncignore` files of the tree.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignore: Vec<String>,
+    /// Create local placeholder files for the remote-only files, instead of leaving them out
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub placeholders: bool,
+    /// Size in bytes that the caches of the instance may use on disk.
+    /// The least recently used file contents are evicted to stay within it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_budget: Option<u64>,
+    /// Commands and URLs notified of the events of the daemon
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<Hook>,
+    /// Periodic digest of the conflicts and of the failed operations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<Digest>,
+    /// Number of times an operation failing with a transient error,
+    /// such as a network failure, is retried before being reported as failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    /// Remote sub-trees synchronized with local folders at other paths
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mappings: Vec<Mapping>,
+    /// Direction of the synchronization. The entries that would need the other
+    /// direction are left out of sync.
+    #[serde(default, skip_serializing_if = "SyncMode::is_bidirectional")]
+    pub sync_mode: SyncMode,
+    /// Periodic cleanup of the files left behind by the daemon
+    #[serde(default, skip_serializing_if = "Maintenance::is_default")]
+    pub maintenance: Maintenance,
+    /// Seconds after which a conflict fetched by a client is checked again in the storages,
+    /// to notice a resolution made outside of fsync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conflict_staleness: Option<u64>,
+    /// Encrypt the file contents before they reach the remote storage, with a key derived
+    /// from a passphrase prompted when the daemon starts.
+    /// The remote contents can't be recovered if the passphrase is lost.
+    /// The names, the folders, the sizes and the modification times are not encrypted.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encryption: bool,
+    /// Conditions under which the scheduled work is postponed.
+    /// Reloaded by [`crate::Fsync::reload_config`].
+    #[serde(default, skip_serializing_if = "Deferral::is_default")]
+    pub deferral: Deferral,
+    /// Keep a status file at the root of the share, for the users without fsync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_file: Option<StatusFile>,
+    /// Size of the sub-trees above which a deep deletion must be confirmed
+    #[serde(default, skip_serializing_if = "DeleteGuard::is_default")]
+    pub delete_guard: DeleteGuard,
+    /// Records of the daemon log kept in memory for the clients, see [`crate::Fsync::logs_tail`]
+    #[serde(default, skip_serializing_if = "LogBuffer::is_default")]
+    pub log_buffer: LogBuffer,
+    /// Number of local entries read concurrently when listing the local directory,
+    /// twice the number of CPUs by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scan_concurrency: Option<usize>,
+    /// Size in bytes of the memory that the buffers of the uploads and downloads
+    /// may use at once, 256 MiB by default. The transfers wait for their buffers
+    /// when it is exhausted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buffer_budget: Option<u64>,
+}
 
-impl PatternList {
-    pub fn _new<I>(patterns: I, opts: MatchOptions) -> Result<PatternList, PatternError>
-    where
-        I: Iterator,
-        I::Item: AsRef<str>,
-    {
-        let patterns: Result<Vec<_>, _> = patterns.map(|p| Pattern::new(p.as_ref())).collect();
-        Ok(PatternList(patterns?, opts))
+/// A status file kept at the root of the share, in both storages, so that the users
+/// of the folder who don't run fsync can see its synchronization state,
+/// e.g. in the web interface of the drive.
+/// It is updated after the deep operations, and is never synchronized as an entry.
+/// With the [encryption](Config::encryption), the remote copy is encrypted as the other files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusFile {
+    /// Minimum minutes between two updates of the file
+    #[serde(default = "StatusFile::default_interval")]
+    pub interval: u64,
+}
+
+impl StatusFile {
+    fn default_interval() -> u64 {
+        10
     }
 
-    pub fn matches_with<P: AsRef<Path>>(&self, path: P) -> bool {
-        self.0
-            .iter()
-            .any(|p| p.matches_with(path.as_ref().as_str(), self.1))
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.interval * 60)
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Config {
-    pub local_dir: FsPathBuf,
-    pub provider: ProviderConfig,
+impl Default for StatusFile {
+    fn default() -> Self {
+        Self {
+            interval: Self::default_interval(),
+        }
+    }
+}
+
+/// A remote sub-tree synchronized with a local folder at another path.
+/// The tree keeps the remote paths, the local storage translates them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mapping {
+    /// Path of the sub-tree in the remote storage, e.g. `/Work/ProjectX`
+    pub remote: PathBuf,
+    /// Path of the folder in the local directory, e.g. `/projects/x`
+    pub local: PathBuf,
+}
+
+impl Mapping {
+    /// Check that the mappings are normalized absolute paths other than the root,
+    /// and that none of the mapped paths is within another one.
+    pub fn check_all(mappings: &[Mapping]) -> anyhow::Result<()> {
+        let paths = || mappings.iter().flat_map(|m| [&m.remote, &m.local]);
+        for path in paths() {
+            if !path.is_absolute() || !path.is_normalized() || path.is_root() {
+                anyhow::bail!("Invalid mapping path: \"{path}\"");
+            }
+        }
+        for (i, a) in paths().enumerate() {
+            for b in paths().skip(i + 1) {
+                if a == b || a.is_ancestor_of(b) || b.is_ancestor_of(a) {
+                    anyhow::bail!("Overlapping mapping paths: {a} and {b}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The path in the local directory of the entry at `path` of the tree,
+    /// if it is in the mapped sub-tree
+    pub fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        if path != self.remote && !self.remote.is_ancestor_of(path) {
+            return None;
+        }
+        let rest = &path.as_str()[self.remote.as_str().len()..];
+        Some(PathBuf::from(format!("{}{rest}", self.local)))
+    }
+
+    /// The path in the local directory of the entry at `path` of the tree.
+    /// Fails if `path` is within a mapped local folder at its own location,
+    /// as the folder is only in the tree at the location of its remote sub-tree.
+    pub fn map_path(mappings: &[Mapping], path: &Path) -> crate::Result<PathBuf> {
+        for mapping in mappings {
+            if let Some(local) = mapping.local_path(path) {
+                return Ok(local);
+            }
+            if path == mapping.local || mapping.local.is_ancestor_of(path) {
+                return Err(crate::Error::Path(crate::PathError::Illegal(
+                    path.to_owned(),
+                    Some(format!("Mapped to {}", mapping.remote)),
+                )));
+            }
+        }
+        Ok(path.to_owned())
+    }
+}
+
+/// A command run, or a URL posted to, on each event of a kind.
+/// The event is passed as JSON, on the standard input of the command
+/// or as the body of the request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hook {
+    pub on: HookEvent,
+    /// The program to run, followed by its arguments
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exec: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Time in seconds after which the command or the request is aborted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+/// A summary of the pending conflicts and of the recently failed operations,
+/// delivered every day through the configured channels.
+/// A digest whose conflicts did not change since the previous one, and that has
+/// no new failure, is not delivered again.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Digest {
+    /// Local time of the day at which the digest is delivered, e.g. `"09:00"`
+    pub at: chrono::NaiveTime,
+    /// A program that receives the plain text digest on its standard input,
+    /// followed by its arguments
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exec: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smtp: Option<Smtp>,
+}
+
+/// Delivery of the [`Digest`] by email
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Smtp {
+    pub server: String,
+    /// Port of the server, if not the default of `security`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Password of `username`, sealed by the daemon if secrets protection is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Schedule and retention of the maintenance task, that deletes the quarantined files,
+/// the orphaned temporary files and the stale partial downloads, and compacts the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Maintenance {
+    /// Hours between two runs, or 0 to only run it on demand
+    #[serde(default = "Maintenance::default_interval")]
+    pub interval: u64,
+    /// Days the quarantined files are kept
+    #[serde(default = "Maintenance::default_quarantine_retention")]
+    pub quarantine_retention: u64,
+    /// Size in bytes above which the oldest records of the audit log are dropped
+    #[serde(default = "Maintenance::default_audit_max_size")]
+    pub audit_max_size: u64,
+}
+
+impl Maintenance {
+    fn default_interval() -> u64 {
+        24
+    }
+
+    fn default_quarantine_retention() -> u64 {
+        30
+    }
+
+    fn default_audit_max_size() -> u64 {
+        4 * 1024 * 1024
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Delay between two scheduled runs, if scheduled
+    pub fn interval(&self) -> Option<std::time::Duration> {
+        (self.interval > 0).then(|| std::time::Duration::from_secs(self.interval * 3600))
+    }
+
+    pub fn quarantine_retention(&self) -> chrono::Duration {
+        chrono::Duration::days(self.quarantine_retention as i64)
+    }
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            interval: Self::default_interval(),
+            quarantine_retention: Self::default_quarantine_retention(),
+            audit_max_size: Self::default_audit_max_size(),
+        }
+    }
+}
+
+/// Thresholds above which a deep deletion is refused with
+/// [`crate::Error::ConfirmationRequired`], until the client submits it again with the
+/// returned token. They apply to each storage that the deletion affects.
+/// A threshold of zero is not checked, so that both at zero disable the guard.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteGuard {
+    /// Number of files deleted from a storage
+    #[serde(default = "DeleteGuard::default_max_files")]
+    pub max_files: u64,
+    /// Size in bytes of the files deleted from a storage
+    #[serde(default = "DeleteGuard::default_max_bytes")]
+    pub max_bytes: u64,
+}
+
+impl DeleteGuard {
+    fn default_max_files() -> u64 {
+        1000
+    }
+
+    fn default_max_bytes() -> u64 {
+        1024 * 1024 * 1024
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.max_files == 0 && self.max_bytes == 0
+    }
+
+    /// Whether deleting `files` files of `bytes` bytes from a storage needs a confirmation
+    pub fn exceeded_by(&self, files: u64, bytes: u64) -> bool {
+        (self.max_files > 0 && files > self.max_files)
+            || (self.max_bytes > 0 && bytes > self.max_bytes)
+    }
+}
+
+impl Default for DeleteGuard {
+    fn default() -> Self {
+        Self {
+            max_files: Self::default_max_files(),
+            max_bytes: Self::default_max_bytes(),
+        }
+    }
+}
+
+/// The records of the daemon log kept in memory, for the clients to display them.
+/// The records more verbose than warnings are only kept while a client reads them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogBuffer {
+    /// Number of records kept, the oldest are dropped first
+    #[serde(default = "LogBuffer::default_capacity")]
+    pub capacity: usize,
+    /// Most verbose level of the records kept
+    #[serde(default = "LogBuffer::default_level")]
+    pub level: LogLevel,
+}
+
+impl LogBuffer {
+    fn default_capacity() -> usize {
+        1000
+    }
+
+    fn default_level() -> LogLevel {
+        LogLevel::Debug
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        Self {
+            capacity: Self::default_capacity(),
+            level: Self::default_level(),
+        }
+    }
+}
+
+/// Conditions under which the scheduled work, such as the maintenance and the syncs
+/// started with [`crate::Fsync::operate_scheduled`], is postponed.
+/// The operations started by the user always run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Deferral {
+    /// Postpone while the system runs on battery
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub defer_on_battery: bool,
+    /// Hours during which the system is in use, and the scheduled work postponed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_hours: Option<HourRange>,
+    /// Minutes after which a scheduled deep operation stops starting new entries.
+    /// The entries left are recorded in a checkpoint, from which the next scheduled run
+    /// of the same operation resumes, see [`crate::Fsync::resume`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_duration: Option<u64>,
+}
+
+impl Deferral {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn max_duration(&self) -> Option<std::time::Duration> {
+        self.max_duration
+            .map(|minutes| std::time::Duration::from_secs(minutes * 60))
+    }
+}
+
+/// A daily range of hours, written `"HH:MM-HH:MM"`.
+/// A range that ends before it starts spans midnight, e.g. `"22:00-07:00"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct HourRange {
+    pub start: chrono::NaiveTime,
+    pub end: chrono::NaiveTime,
+}
+
+impl HourRange {
+    pub fn contains(&self, time: chrono::NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl std::str::FromStr for HourRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |time: &str| chrono::NaiveTime::parse_from_str(time.trim(), "%H:%M");
+        let range = s
+            .split_once('-')
+            .and_then(|(start, end)| Some((parse(start).ok()?, parse(end).ok()?)));
+        match range {
+            Some((start, end)) => Ok(Self { start, end }),
+            None => anyhow::bail!("Invalid range of hours: \"{s}\", expected \"HH:MM-HH:MM\""),
+        }
+    }
+}
+
+impl TryFrom<String> for HourRange {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<HourRange> for String {
+    fn from(value: HourRange) -> Self {
+        value.to_string()
+    }
+}
+
+impl std::fmt::Display for HourRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// Encryption of the connection to the SMTP server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SmtpSecurity {
+    /// TLS from the start of the connection, on port 465 by default
+    Tls,
+    /// Upgrade to TLS with STARTTLS, on port 587 by default
+    #[default]
+    StartTls,
+    /// No encryption, on port 25 by default. Only suitable for a local relay.
+    None,
+}
+
+/// The kinds of events that trigger a [`Hook`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    /// A unit operation was performed on an entry
+    OperationDone,
+    /// An entry became a conflict
+    ConflictDetected,
+    /// An operation failed
+    Error,
+}
+
+/// Protection at rest of the OAuth2 client secret and of the cached tokens
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SecretsProtection {
+    /// Encrypted with a key stored in the OS keyring
+    Keyring,
+    /// Encrypted with a key derived from a passphrase prompted when the daemon starts
+    Passphrase,
+    /// Stored in clear text
+    #[default]
+    Plain,
+}
+
+impl SecretsProtection {
+    /// Service of the keys stored in the OS keyring, whose user is the instance name
+    pub const KEYRING_SERVICE: &'static str = "fsyncd";
+
+    pub fn is_plain(&self) -> bool {
+        matches!(self, Self::Plain)
+    }
+}
+
+impl std::fmt::Display for SecretsProtection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Keyring => f.write_str("keyring"),
+            Self::Passphrase => f.write_str("passphrase"),
+            Self::Plain => f.write_str("plain"),
+        }
+    }
+}
+
+/// Minimum free space to keep on the local disk
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MinFreeSpace {
+    /// Absolute number of bytes
+    Bytes(u64),
+    /// Percentage of the disk total size
+    Percent(f64),
+}
+
+impl MinFreeSpace {
+    /// The minimum free space in bytes for a disk of `total` bytes
+    pub fn bytes(&self, total: u64) -> u64 {
+        match self {
+            Self::Bytes(bytes) => *bytes,
+            Self::Percent(pct) => (total as f64 * pct.clamp(0.0, 100.0) / 100.0) as u64,
+        }
+    }
 }
 
 impl Config {
@@ -38,9 +535,20 @@ impl Config {
         let config_json = std::str::from_utf8(&config_json)?;
         Ok(serde_json::from_str(config_json)?)
     }
+
+    /// A hash of the config, which changes with any setting but not with the formatting
+    /// of the file. The JSON of the config is canonicalized by sorting the keys of its objects.
+    pub fn fingerprint(&self) -> String {
+        use sha2::{Digest, Sha256};
+
+        let value = serde_json::to_value(self).expect("Config should serialize");
+        let digest = Sha256::digest(value.to_string().as_bytes());
+        hex::encode(&digest[..8])
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
 pub enum ProviderConfig {
     #[serde(rename = "drive")]
     GoogleDrive(drive::Config),
@@ -57,5 +565,122 @@ pub mod drive {
     pub struct Config {
         pub root: Option<PathBuf>,
         pub secret: oauth2::Secret,
+        #[serde(default)]
+        pub auth_flow: oauth2::Flow,
+        /// Maximum size in bytes of the chunks of the uploads,
+        /// rounded down to a multiple of 256 KiB
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub max_upload_chunk_size: Option<u64>,
+        /// Do not request the permissions of the files.
+        /// The sharing of the remote entries is then unknown, but the API responses are smaller.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub skip_sharing: bool,
+        /// Do not keep the content of the remote files read by the daemon in the disk cache.
+        /// The content cache is only used within a `cache_budget`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub skip_content_cache: bool,
+        /// The local server receiving the browser redirection of the PKCE flow
+        #[serde(default, skip_serializing_if = "oauth2::RedirectServer::is_default")]
+        pub redirect_server: oauth2::RedirectServer,
+        /// Email address of the authorized account, recorded after the first authorization.
+        /// The daemon refuses to start the storage with the token of another account.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub account: Option<String>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(remote: &str, local: &str) -> Mapping {
+        Mapping {
+            remote: PathBuf::from(remote),
+            local: PathBuf::from(local),
+        }
+    }
+
+    #[test]
+    fn mappings() {
+        let mappings = [mapping("/Work/ProjectX", "/projects/x")];
+        assert!(Mapping::check_all(&mappings).is_ok());
+        let map = |path: &str| Mapping::map_path(&mappings, Path::new(path));
+        assert_eq!(map("/Work/ProjectX").unwrap(), Path::new("/projects/x"));
+        assert_eq!(
+            map("/Work/ProjectX/a.txt").unwrap(),
+            Path::new("/projects/x/a.txt")
+        );
+        assert_eq!(
+            map("/Work/ProjectXY").unwrap(),
+            Path::new("/Work/ProjectXY")
+        );
+        assert_eq!(map("/projects/y").unwrap(), Path::new("/projects/y"));
+        assert!(map("/projects/x/a.txt").is_err());
+
+        let invalid = [
+            vec![mapping("/", "/a")],
+            vec![mapping("/a/../b", "/c")],
+            vec![mapping("a", "/b")],
+            vec![mapping("/a", "/a/b")],
+            vec![mapping("/a", "/b"), mapping("/c", "/b/d")],
+            vec![mapping("/a", "/b"), mapping("/a/c", "/d")],
+        ];
+        for mappings in invalid {
+            assert!(Mapping::check_all(&mappings).is_err(), "{mappings:?}");
+        }
+    }
+
+    #[test]
+    fn hour_ranges() {
+        let time = |s: &str| chrono::NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+
+        let day: HourRange = "09:00-18:00".parse().unwrap();
+        assert!(day.contains(time("09:00")));
+        assert!(day.contains(time("12:30")));
+        assert!(!day.contains(time("18:00")));
+        assert!(!day.contains(time("23:00")));
+
+        let night: HourRange = "22:00-07:00".parse().unwrap();
+        assert!(night.contains(time("23:00")));
+        assert!(night.contains(time("03:00")));
+        assert!(!night.contains(time("07:00")));
+        assert!(!night.contains(time("12:00")));
+
+        let deferral: Deferral =
+            serde_json::from_str(r#"{"defer_on_battery": true, "active_hours": "22:00-07:00"}"#)
+                .unwrap();
+        assert_eq!(deferral.active_hours, Some(night));
+        assert_eq!(
+            serde_json::to_string(&deferral).unwrap(),
+            r#"{"defer_on_battery":true,"active_hours":"22:00-07:00"}"#
+        );
+        assert_eq!(deferral.max_duration(), None);
+        let deferral: Deferral = serde_json::from_str(r#"{"max_duration": 90}"#).unwrap();
+        assert_eq!(
+            deferral.max_duration(),
+            Some(std::time::Duration::from_secs(90 * 60))
+        );
+
+        for invalid in ["", "22:00", "25:00-07:00", "9h-18h"] {
+            assert!(invalid.parse::<HourRange>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn fingerprint() {
+        let json = r#"{
+            "local_dir": "/home/user/drive",
+            "provider": { "fs": "/mnt/remote" },
+            "placeholders": true
+        }"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+        let reordered = r#"{"placeholders":true,"provider":{"fs":"/mnt/remote"},"local_dir":"/home/user/drive"}"#;
+        let reordered: Config = serde_json::from_str(reordered).unwrap();
+        assert_eq!(config.fingerprint(), reordered.fingerprint());
+        assert_eq!(config.fingerprint().len(), 16);
+
+        let mut changed = config.clone();
+        changed.local_dir = FsPathBuf::from("/home/user/other");
+        assert_ne!(config.fingerprint(), changed.fingerprint());
     }
 }
@@ -1,9 +1,15 @@
 use std::{cmp::Ordering, fmt};
 
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use typescript_type_def::TypeDef;
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize, TypeDef)]
+use crate::{
+    fmt::{human_bytes, human_mtime, Unit},
+    Metadata, ResolutionMethod,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, TypeDef)]
 #[serde(rename_all = "camelCase")]
 pub enum Conflict {
     LocalNewer,
@@ -12,10 +18,23 @@ pub enum Conflict {
     LocalSmaller,
     LocalFileRemoteDir,
     LocalDirRemoteFile,
+    /// The files have the same size and modification time, but their content differs.
+    /// Never found by [`Conflict::check`], only by [`crate::Fsync::verify`].
+    ContentMismatch,
 }
 
 impl Conflict {
     pub fn check(local: &crate::Metadata, remote: &crate::Metadata) -> Option<Self> {
+        Self::check_within(local, remote, 1)
+    }
+
+    /// Same as [`Conflict::check`], with the modification times compared
+    /// with a granularity of `mtime_granularity` seconds (see [`crate::compare_mtime_within`])
+    pub fn check_within(
+        local: &crate::Metadata,
+        remote: &crate::Metadata,
+        mtime_granularity: u32,
+    ) -> Option<Self> {
         use crate::Metadata::{Directory, Regular};
         debug_assert_eq!(local.path(), remote.path());
 
@@ -39,7 +58,7 @@ impl Conflict {
                 let rem_sz = *rem_sz;
                 let loc_mt = *loc_mt;
                 let rem_mt = *rem_mt;
-                match crate::compare_mtime(loc_mt, rem_mt) {
+                match crate::compare_mtime_within(loc_mt, rem_mt, mtime_granularity) {
                     Ordering::Less => Some(Self::LocalOlder),
                     Ordering::Greater => Some(Self::LocalNewer),
                     Ordering::Equal if loc_sz < rem_sz => Some(Conflict::LocalSmaller),
@@ -49,17 +68,252 @@ impl Conflict {
             }
         }
     }
+
+    /// A short description of the conflict, e.g. "local is newer"
+    pub fn summary(&self) -> &'static str {
+        match self {
+            Self::LocalNewer => "local is newer",
+            Self::LocalOlder => "local is older",
+            Self::LocalBigger => "local is bigger (but modified at same time)",
+            Self::LocalSmaller => "local is smaller (but modified at same time)",
+            Self::LocalFileRemoteDir => "local is file, remote is dir",
+            Self::LocalDirRemoteFile => "local is dir, remote is file",
+            Self::ContentMismatch => "content differs (but same size and time)",
+        }
+    }
+
+    /// A complete explanation of the conflict between `local` and `remote`,
+    /// with their sizes and modification times
+    pub fn explanation(&self, local: &Metadata, remote: &Metadata) -> String {
+        let now = Utc::now();
+        let size = |md: &Metadata| human_bytes(md.size().unwrap_or(0), Unit::Binary);
+        let mtime = |md: &Metadata| {
+            md.mtime()
+                .map(|mtime| human_mtime(mtime, now))
+                .unwrap_or_default()
+        };
+        match self {
+            Self::LocalNewer | Self::LocalOlder => {
+                let ((newer, newer_md), (older, older_md)) = if *self == Self::LocalNewer {
+                    (("local", local), ("remote", remote))
+                } else {
+                    (("remote", remote), ("local", local))
+                };
+                format!(
+                    "Both files were modified since the last synchronization. \
+                     The {newer} file ({}, modified {}) is newer than the {older} file ({}, modified {}).",
+                    size(newer_md),
+                    mtime(newer_md),
+                    size(older_md),
+                    mtime(older_md),
+                )
+            }
+            Self::LocalBigger | Self::LocalSmaller => format!(
+                "Both files were modified {}, but the local file has {} and the remote file has {}. \
+                 The modification times cannot tell which one is the most recent.",
+                mtime(local),
+                size(local),
+                size(remote),
+            ),
+            Self::LocalFileRemoteDir => format!(
+                "The local entry is a file ({}, modified {}) and the remote entry is a folder. \
+                 One of them must be renamed or deleted by hand.",
+                size(local),
+                mtime(local),
+            ),
+            Self::LocalDirRemoteFile => format!(
+                "The local entry is a folder and the remote entry is a file ({}, modified {}). \
+                 One of them must be renamed or deleted by hand.",
+                size(remote),
+                mtime(remote),
+            ),
+            Self::ContentMismatch => format!(
+                "Both files have {} and were modified {}, but the verification found that \
+                 their content differs. One of them was altered without changing its metadata.",
+                size(local),
+                mtime(local),
+            ),
+        }
+    }
+
+    /// The resolution methods that apply to the conflict, the most likely first.
+    /// Deletions are left out, as they apply to any conflict.
+    /// An empty list means that the conflict needs a manual intervention,
+    /// see [`Conflict::needs_manual_intervention`].
+    pub fn suggested_resolutions(&self) -> &'static [ResolutionMethod] {
+        match self {
+            Self::LocalNewer | Self::LocalOlder => &[
+                ResolutionMethod::ReplaceOlderByNewer,
+                ResolutionMethod::ReplaceNewerByOlder,
+                ResolutionMethod::ReplaceLocalByRemote,
+                ResolutionMethod::ReplaceRemoteByLocal,
+                ResolutionMethod::CreateLocalCopy,
+            ],
+            // the modification times are the same, newer and older are meaningless
+            Self::LocalBigger | Self::LocalSmaller | Self::ContentMismatch => &[
+                ResolutionMethod::ReplaceLocalByRemote,
+                ResolutionMethod::ReplaceRemoteByLocal,
+                ResolutionMethod::CreateLocalCopy,
+            ],
+            Self::LocalFileRemoteDir | Self::LocalDirRemoteFile => &[],
+        }
+    }
+
+    /// Whether none of the resolution methods apply to the conflict
+    pub fn needs_manual_intervention(&self) -> bool {
+        self.suggested_resolutions().is_empty()
+    }
 }
 
 impl fmt::Display for Conflict {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::LocalBigger => f.write_str("local is bigger (but modified at same time)"),
-            Self::LocalSmaller => f.write_str("local is smaller (but modified at same time)"),
-            Self::LocalNewer => f.write_str("local is newer"),
-            Self::LocalOlder => f.write_str("local is older"),
-            Self::LocalFileRemoteDir => f.write_str("local is file, remote is dir"),
-            Self::LocalDirRemoteFile => f.write_str("local is dir, remote is file"),
+        f.write_str(self.summary())
+    }
+}
+
+/// A conflict with its user-facing description, for clients that cannot call
+/// the methods of [`Conflict`]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct ConflictDetail {
+    pub conflict: Conflict,
+    pub summary: String,
+    pub explanation: String,
+    /// Empty if the conflict needs a manual intervention
+    pub suggested_resolutions: Vec<ResolutionMethod>,
+}
+
+impl ConflictDetail {
+    /// The detail of `conflict`, where merging is suggested first for the text files
+    pub fn new(conflict: Conflict, local: &Metadata, remote: &Metadata) -> Self {
+        let mut suggested_resolutions = Vec::new();
+        if !conflict.needs_manual_intervention() && crate::text::is_mergeable(local, remote) {
+            suggested_resolutions.push(ResolutionMethod::MergeText);
+        }
+        suggested_resolutions.extend_from_slice(conflict.suggested_resolutions());
+        Self {
+            conflict,
+            summary: conflict.summary().to_string(),
+            explanation: conflict.explanation(local, remote),
+            suggested_resolutions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+    use crate::path::PathBuf;
+
+    #[test]
+    fn descriptions() {
+        let mtime = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let file = |size, mtime| Metadata::Regular {
+            path: PathBuf::from("/a"),
+            size,
+            mtime,
+            link_target: None,
+        };
+        let dir = Metadata::Directory {
+            path: PathBuf::from("/a"),
+            stat: None,
+        };
+        let cases = [
+            (file(10, mtime + Duration::hours(1)), file(10, mtime)),
+            (file(10, mtime), file(10, mtime + Duration::hours(1))),
+            (file(20, mtime), file(10, mtime)),
+            (file(10, mtime), file(20, mtime)),
+            (file(10, mtime), dir.clone()),
+            (dir.clone(), file(10, mtime)),
+        ];
+        for (local, remote) in cases {
+            let conflict = Conflict::check(&local, &remote).unwrap();
+            assert!(!conflict.summary().is_empty());
+            assert!(!conflict.explanation(&local, &remote).is_empty());
+            assert!(
+                !conflict.suggested_resolutions().is_empty()
+                    || conflict.needs_manual_intervention()
+            );
+            let detail = ConflictDetail::new(conflict, &local, &remote);
+            assert_eq!(detail.summary, conflict.to_string());
         }
     }
+
+    #[test]
+    fn merge_suggested_for_text_files() {
+        let mtime = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let file = |path: &str, mtime| Metadata::Regular {
+            path: PathBuf::from(path),
+            size: 10,
+            mtime,
+            link_target: None,
+        };
+        let later = mtime + Duration::hours(1);
+        let (local, remote) = (file("/notes.txt", later), file("/notes.txt", mtime));
+        let conflict = Conflict::check(&local, &remote).unwrap();
+        let detail = ConflictDetail::new(conflict, &local, &remote);
+        assert_eq!(detail.suggested_resolutions[0], ResolutionMethod::MergeText);
+
+        let (local, remote) = (file("/photo.jpg", later), file("/photo.jpg", mtime));
+        let detail = ConflictDetail::new(conflict, &local, &remote);
+        assert!(!detail
+            .suggested_resolutions
+            .contains(&ResolutionMethod::MergeText));
+    }
+
+    #[test]
+    fn same_mtime_suggestions() {
+        for conflict in [Conflict::LocalBigger, Conflict::LocalSmaller] {
+            assert!(!conflict.suggested_resolutions().iter().any(|m| matches!(
+                m,
+                ResolutionMethod::ReplaceOlderByNewer | ResolutionMethod::ReplaceNewerByOlder
+            )));
+        }
+    }
+
+    #[test]
+    fn coarse_mtimes() {
+        let mtime = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 1).unwrap();
+        let file = |mtime| Metadata::Regular {
+            path: PathBuf::from("/a"),
+            size: 10,
+            mtime,
+            link_target: None,
+        };
+        // FAT rounds up to the next even second
+        let (local, remote) = (file(mtime + Duration::seconds(1)), file(mtime));
+        assert_eq!(Conflict::check(&local, &remote), Some(Conflict::LocalNewer));
+        assert_eq!(Conflict::check_within(&local, &remote, 2), None);
+
+        let (local, remote) = (file(mtime + Duration::seconds(2)), file(mtime));
+        assert_eq!(
+            Conflict::check_within(&local, &remote, 2),
+            Some(Conflict::LocalNewer)
+        );
+
+        let dst = mtime + Duration::hours(1);
+        assert!(crate::is_timezone_shift(dst, mtime, 1));
+        assert!(crate::is_timezone_shift(
+            mtime,
+            dst + Duration::seconds(1),
+            2
+        ));
+        assert!(!crate::is_timezone_shift(
+            mtime,
+            dst + Duration::seconds(1),
+            1
+        ));
+        assert!(!crate::is_timezone_shift(
+            mtime,
+            mtime + Duration::seconds(1),
+            2
+        ));
+        assert!(!crate::is_timezone_shift(
+            mtime,
+            mtime + Duration::days(2),
+            2
+        ));
+    }
 }
@@ -0,0 +1,73 @@
+//! Envelope of the enums that gain variants over the protocol versions.
+//!
+//! In binary formats, such as the Bincode of the RPC transport, a value of an [`Extensible`]
+//! enum is sent as a length-prefixed byte array holding its own encoding. A receiver that
+//! does not know the variant skips the whole envelope and gets the fallback of the enum,
+//! wherever the value is in the message. Human-readable formats encode the enum as is.
+
+use bincode::Options;
+use serde::{ser, Deserialize, Deserializer, Serialize, Serializer};
+
+/// An enum sent in an envelope.
+/// The representation functions are typically derived with `#[serde(remote = "Self")]`.
+pub trait Extensible: Sized {
+    fn serialize_repr<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>;
+
+    fn deserialize_repr<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>;
+
+    /// The value of a variant unknown to this version
+    fn unsupported() -> Self;
+}
+
+/// Serialize `value`, in an envelope if the format is binary
+pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Extensible,
+    S: Serializer,
+{
+    if serializer.is_human_readable() {
+        return value.serialize_repr(serializer);
+    }
+    let bytes = options()
+        .serialize(&Repr(value))
+        .map_err(ser::Error::custom)?;
+    bytes.serialize(serializer)
+}
+
+/// Deserialize a value written by [`serialize`].
+/// A value that this version can't read is deserialized as [`Extensible::unsupported`].
+pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: Extensible,
+    D: Deserializer<'de>,
+{
+    if deserializer.is_human_readable() {
+        return T::deserialize_repr(deserializer);
+    }
+    let bytes = Vec::<u8>::deserialize(deserializer)?;
+    Ok(options()
+        .deserialize::<Owned<T>>(&bytes)
+        .map(|owned| owned.0)
+        .unwrap_or_else(|_| T::unsupported()))
+}
+
+/// Options of the encoding within the envelope
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+struct Repr<'a, T>(&'a T);
+
+impl<T: Extensible> Serialize for Repr<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize_repr(serializer)
+    }
+}
+
+struct Owned<T>(T);
+
+impl<'de, T: Extensible> Deserialize<'de> for Owned<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize_repr(deserializer).map(Owned)
+    }
+}
@@ -39,21 +39,93 @@ impl fmt::Display for PathError {
 
 impl error::Error for PathError {}
 
+/// Typed failures of the OAuth2 device code flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum DeviceCodeError {
+    /// The user did not enter the code before it expired
+    Expired,
+    /// The user denied the authorization request
+    Denied,
+}
+
+impl fmt::Display for DeviceCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Expired => f.write_str("The device code has expired"),
+            Self::Denied => f.write_str("The authorization request was denied"),
+        }
+    }
+}
+
+impl error::Error for DeviceCodeError {}
+
 /// An error type for RPC results
 #[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
 #[serde(rename_all = "camelCase")]
 pub enum Error {
     Path(PathError),
     Utf8(String),
-    IllegalSymlink { path: PathBuf, target: String },
+    IllegalSymlink {
+        path: PathBuf,
+        target: String,
+    },
     Io(String),
     Auth(String),
+    DeviceCode(DeviceCodeError),
     NotEmpty(PathBuf),
+    InsufficientSpace {
+        available: u64,
+        required: u64,
+    },
+    QuotaExceeded {
+        remaining: u64,
+        required: u64,
+    },
     Conflict(PathBuf),
     Unresolved(PathBuf, String),
     Api(String),
     Bug(String),
     Other(String),
+    /// The client did not authenticate to the daemon, or presented a wrong token
+    Unauthorized(String),
+    /// The entry is pinned, it cannot be deleted or overwritten
+    Pinned(PathBuf),
+    /// The remote storage is still being initialized, the operation can be retried later
+    RemoteInitializing,
+    /// The storage could not be reached, or answered with a server error or a rate limit.
+    /// The operation can be retried later.
+    Unavailable(String),
+    /// The operation would modify a storage that the sync mode of the instance leaves untouched
+    Withheld(PathBuf, crate::SyncMode),
+    /// The authorization was not completed in the browser within the given seconds.
+    /// The operation can be attempted again to get a new authorization page.
+    AuthTimeout(u64),
+    /// The remote root of the config changed since the local directory was synchronized.
+    /// The operations are refused until the change is migrated, see [`crate::Fsync::migrate_root`].
+    RootChanged(Box<crate::RootChange>),
+    /// The remote file can be viewed but not downloaded, e.g. because its owner disabled
+    /// the downloads. The web view link, if known, allows to open it in the browser.
+    NotDownloadable {
+        path: PathBuf,
+        web_view_link: Option<String>,
+    },
+    /// The operation deletes a sub-tree larger than the thresholds of the config.
+    /// It runs if submitted again with the token in [`crate::OperateOptions::confirmation`]
+    /// within [`crate::CONFIRMATION_WINDOW`].
+    /// The summary tells the user what would be deleted.
+    ConfirmationRequired(String, String),
+    /// The storage does not support the feature, e.g. the revisions of the files.
+    /// The clients may hide the feature instead of reporting the error.
+    Unsupported(String),
+}
+
+impl Error {
+    /// Whether the error is expected to go away by itself, so that the operation
+    /// may succeed if retried. Other errors need the user's attention.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::RemoteInitializing | Self::Unavailable(..))
+    }
 }
 
 impl fmt::Display for Error {
@@ -65,8 +137,23 @@ impl fmt::Display for Error {
                 write!(f, "Illegal symlink: {path} -> {target}")
             }
             Self::Auth(msg) => write!(f, "Authorization error: {msg}"),
+            Self::DeviceCode(err) => write!(f, "Authorization error: {err}"),
             Self::Io(msg) => write!(f, "IO error: {msg}"),
             Self::NotEmpty(path) => write!(f, "Directory not empty: {path}"),
+            Self::InsufficientSpace {
+                available,
+                required,
+            } => write!(
+                f,
+                "Insufficient space: {available} bytes available, {required} bytes required"
+            ),
+            Self::QuotaExceeded {
+                remaining,
+                required,
+            } => write!(
+                f,
+                "Remote quota exceeded: {remaining} bytes remaining, {required} bytes required"
+            ),
             Self::Conflict(path) => {
                 write!(f, "Could not complete operation due to conflict on {path}")
             }
@@ -76,6 +163,41 @@ impl fmt::Display for Error {
             Self::Api(msg) => write!(f, "API error: {msg}"),
             Self::Bug(msg) => write!(f, "Fsync bug error: {msg}"),
             Self::Other(msg) => f.write_str(msg),
+            Self::Unauthorized(msg) => write!(f, "Unauthorized: {msg}"),
+            Self::Pinned(path) => {
+                write!(f, "Pinned entry cannot be deleted or overwritten: {path}")
+            }
+            Self::RemoteInitializing => {
+                f.write_str("The remote storage is still initializing, try again later")
+            }
+            Self::Unavailable(msg) => write!(f, "Storage unavailable: {msg}"),
+            Self::Withheld(path, mode) => {
+                write!(f, "Entry withheld by the {mode} sync mode: {path}")
+            }
+            Self::AuthTimeout(secs) => write!(
+                f,
+                "The authorization was not completed within {secs} seconds, try again to get a new authorization page"
+            ),
+            Self::RootChanged(change) => write!(
+                f,
+                "The remote root changed from {} to {}, migrate the instance before operating",
+                change.recorded, change.resolved
+            ),
+            Self::NotDownloadable {
+                path,
+                web_view_link: None,
+            } => write!(f, "The file can be viewed but not downloaded: {path}"),
+            Self::NotDownloadable {
+                path,
+                web_view_link: Some(link),
+            } => write!(
+                f,
+                "The file can be viewed but not downloaded: {path}, open it at {link}"
+            ),
+            Self::ConfirmationRequired(_, summary) => {
+                write!(f, "Confirmation required: {summary}")
+            }
+            Self::Unsupported(feature) => write!(f, "The storage does not support {feature}"),
         }
     }
 }
@@ -84,6 +206,7 @@ impl error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
             Error::Path(err) => Some(err),
+            Error::DeviceCode(err) => Some(err),
             _ => None,
         }
     }
@@ -95,6 +218,12 @@ impl From<PathError> for Error {
     }
 }
 
+impl From<DeviceCodeError> for Error {
+    fn from(value: DeviceCodeError) -> Self {
+        Self::DeviceCode(value)
+    }
+}
+
 impl From<NormalizeError> for Error {
     fn from(value: NormalizeError) -> Self {
         Self::Path(value.into())
@@ -115,13 +244,24 @@ impl From<FromPathBufError> for Error {
 
 impl From<io::Error> for Error {
     fn from(value: io::Error) -> Self {
-        Self::Io(value.to_string())
+        match value.kind() {
+            io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected => Self::Unavailable(value.to_string()),
+            _ => Self::Io(value.to_string()),
+        }
     }
 }
 
 impl From<anyhow::Error> for Error {
     fn from(value: anyhow::Error) -> Self {
-        Self::Other(value.to_string())
+        // an fsync error passed through anyhow keeps its variant, unless a context was added
+        match value.downcast_ref::<Error>() {
+            Some(err) if value.chain().count() == 1 => err.clone(),
+            _ => Self::Other(value.to_string()),
+        }
     }
 }
 
@@ -131,25 +271,6 @@ impl From<String> for Error {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::Error;
-
-    #[test]
-    fn serialize_other_error() {
-        let err = Error::Other("An error message".into());
-        let json_err = serde_json::to_string(&err).unwrap();
-        assert_eq!(json_err, r#"{"other":"An error message"}"#);
-    }
-
-    #[test]
-    fn deserialize_other_error() {
-        let json_err = r#"{"other":"An error message"}"#;
-        let err: Error = serde_json::from_str(json_err).unwrap();
-        assert_eq!(err.to_string(), "An error message");
-    }
-}
-
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[macro_export]
@@ -207,3 +328,38 @@ macro_rules! other_error {
         $crate::Error::Other(format!($($t)*))
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+
+    #[test]
+    fn serialize_other_error() {
+        let err = Error::Other("An error message".into());
+        let json_err = serde_json::to_string(&err).unwrap();
+        assert_eq!(json_err, r#"{"other":"An error message"}"#);
+    }
+
+    #[test]
+    fn deserialize_other_error() {
+        let json_err = r#"{"other":"An error message"}"#;
+        let err: Error = serde_json::from_str(json_err).unwrap();
+        assert_eq!(err.to_string(), "An error message");
+    }
+
+    #[test]
+    fn transient_errors() {
+        let timeout = std::io::Error::from(std::io::ErrorKind::TimedOut);
+        assert!(Error::from(timeout).is_transient());
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert!(!Error::from(denied).is_transient());
+        assert!(Error::RemoteInitializing.is_transient());
+        assert!(!Error::NotEmpty("/dir".into()).is_transient());
+
+        // kept through anyhow, unless given a context
+        let err = anyhow::Error::from(Error::Unavailable("503".into()));
+        assert!(Error::from(err).is_transient());
+        let err = anyhow::Error::from(Error::Unavailable("503".into())).context("Upload");
+        assert!(!Error::from(err).is_transient());
+    }
+}
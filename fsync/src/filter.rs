@@ -0,0 +1,169 @@
+//! Selection of the entries by pattern and by kind of conflict, for the users who address
+//! their entries in groups, e.g. the `*.docx` conflicts first.
+//!
+//! The patterns are the [globs](crate::path::glob) of the ignore rules, anchored to the
+//! [`EntryFilter::under`] path: `--under /docs --filter 'reports/*.docx'` selects the files
+//! of `/docs/reports`, while `*.docx` selects the files of that name at any depth under `/docs`.
+//! An entry is selected by the last pattern that matches it or, if none does, by the last
+//! pattern that matches its nearest ancestor, so that `photos/` selects the whole sub-tree,
+//! and `photos/` followed by `!*.raw` selects it without the raw files.
+
+use serde::{Deserialize, Serialize};
+use typescript_type_def::TypeDef;
+
+use crate::{
+    path::{
+        glob::{Glob, GlobError, PathGlobSet},
+        Path, PathBuf,
+    },
+    tree, Conflict,
+};
+
+/// The entries selected by an operation or a listing, see the [module](self) documentation
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct EntryFilter {
+    /// Only the entries under this path, the path included, or the whole tree if `None`
+    pub under: Option<PathBuf>,
+    /// The patterns selecting the entries, anchored to `under`. All entries if empty.
+    pub patterns: Vec<String>,
+    /// Only the conflicts of these kinds. All entries, conflicts or not, if empty.
+    pub conflicts: Vec<Conflict>,
+}
+
+impl EntryFilter {
+    /// Whether the filter selects all the entries
+    pub fn is_empty(&self) -> bool {
+        self.under.as_deref().unwrap_or(Path::root()).is_root()
+            && self.patterns.is_empty()
+            && self.conflicts.is_empty()
+    }
+
+    /// Compile the patterns of the filter
+    pub fn compile(&self) -> Result<EntryMatcher, GlobError> {
+        let under = self.under.clone().unwrap_or_else(PathBuf::root);
+        let globs = self
+            .patterns
+            .iter()
+            .map(|pattern| Glob::with_base(&under, pattern))
+            .collect::<Result<_, _>>()?;
+        Ok(EntryMatcher {
+            under,
+            globs,
+            conflicts: self.conflicts.clone(),
+        })
+    }
+}
+
+/// A compiled [`EntryFilter`]
+#[derive(Debug, Clone)]
+pub struct EntryMatcher {
+    under: PathBuf,
+    globs: PathGlobSet,
+    conflicts: Vec<Conflict>,
+}
+
+impl EntryMatcher {
+    /// The path under which the entries are selected
+    pub fn under(&self) -> &Path {
+        &self.under
+    }
+
+    /// Whether `path` is the path of the filter or under it
+    pub fn is_under(&self, path: &Path) -> bool {
+        path == self.under.as_path() || self.under.is_ancestor_of(path)
+    }
+
+    /// Whether the entry at `path` is selected by the patterns
+    pub fn matches_path(&self, path: &Path, is_dir: bool) -> bool {
+        if !self.is_under(path) {
+            return false;
+        }
+        if self.globs.is_empty() {
+            return true;
+        }
+        if let Some(glob) = self.globs.last_match(path, is_dir) {
+            return !glob.is_negated();
+        }
+        let mut ancestor = path.parent();
+        while let Some(dir) = ancestor.filter(|dir| self.is_under(dir)) {
+            if let Some(glob) = self.globs.last_match(dir, true) {
+                return !glob.is_negated();
+            }
+            ancestor = dir.parent();
+        }
+        false
+    }
+
+    /// Whether a conflict of the kind `conflict`, or no conflict if `None`, is selected
+    pub fn matches_conflict(&self, conflict: Option<Conflict>) -> bool {
+        self.conflicts.is_empty() || conflict.is_some_and(|c| self.conflicts.contains(&c))
+    }
+
+    /// Whether `entry` is selected by the filter
+    pub fn matches(&self, entry: &tree::Entry) -> bool {
+        self.matches_conflict(entry.conflict())
+            && self.matches_path(entry.path(), entry.is_safe_dir())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(under: Option<&str>, patterns: &[&str]) -> EntryMatcher {
+        EntryFilter {
+            under: under.map(PathBuf::from),
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            conflicts: Vec::new(),
+        }
+        .compile()
+        .unwrap()
+    }
+
+    #[test]
+    fn patterns_are_anchored_under_the_path() {
+        let docx = matcher(None, &["*.docx"]);
+        assert!(docx.matches_path(Path::new("/a.docx"), false));
+        assert!(docx.matches_path(Path::new("/dir/b.docx"), false));
+        assert!(!docx.matches_path(Path::new("/dir/b.pdf"), false));
+
+        let reports = matcher(Some("/docs"), &["reports/*.docx"]);
+        assert!(reports.matches_path(Path::new("/docs/reports/q1.docx"), false));
+        assert!(!reports.matches_path(Path::new("/reports/q1.docx"), false));
+        assert!(!reports.matches_path(Path::new("/docs/old/reports/q1.docx"), false));
+
+        // without pattern, everything under the path
+        let under = matcher(Some("/docs"), &[]);
+        assert!(under.matches_path(Path::new("/docs"), true));
+        assert!(under.matches_path(Path::new("/docs/a.txt"), false));
+        assert!(!under.matches_path(Path::new("/docs-old/a.txt"), false));
+    }
+
+    #[test]
+    fn directories_select_their_sub_tree() {
+        let photos = matcher(None, &["photos/", "!*.raw"]);
+        assert!(photos.matches_path(Path::new("/photos"), true));
+        assert!(photos.matches_path(Path::new("/photos/2024/a.jpg"), false));
+        assert!(!photos.matches_path(Path::new("/photos/2024/a.raw"), false));
+        assert!(!photos.matches_path(Path::new("/videos/a.mp4"), false));
+        // a file named as the directory is not selected
+        assert!(!photos.matches_path(Path::new("/photos"), false));
+    }
+
+    #[test]
+    fn conflicts_are_selected_by_kind() {
+        let filter = EntryFilter {
+            conflicts: vec![Conflict::LocalNewer],
+            ..Default::default()
+        };
+        let matcher = filter.compile().unwrap();
+        assert!(matcher.matches_conflict(Some(Conflict::LocalNewer)));
+        assert!(!matcher.matches_conflict(Some(Conflict::LocalOlder)));
+        assert!(!matcher.matches_conflict(None));
+
+        let all = EntryFilter::default();
+        assert!(all.is_empty());
+        assert!(all.compile().unwrap().matches_conflict(None));
+    }
+}
@@ -0,0 +1,102 @@
+//! Human readable formatting of sizes and times, shared by all clients.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use typescript_type_def::TypeDef;
+
+/// The unit system of [human_bytes]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum Unit {
+    /// Powers of 1024 (KiB, MiB, ...)
+    #[default]
+    Binary,
+    /// Powers of 1000 (kB, MB, ...)
+    Decimal,
+}
+
+/// Format a number of bytes with the most appropriate unit, e.g. "1.5 MiB".
+/// Values below 1 kilo are printed without decimals, e.g. "512 B".
+pub fn human_bytes(bytes: u64, unit: Unit) -> String {
+    let (base, units) = match unit {
+        Unit::Binary => (1024.0, ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"]),
+        Unit::Decimal => (1000.0, ["kB", "MB", "GB", "TB", "PB", "EB"]),
+    };
+    if (bytes as f64) < base {
+        return format!("{bytes} B");
+    }
+    let mut val = bytes as f64 / base;
+    let mut idx = 0;
+    // compare the rounded value, so that e.g. 1048575 is "1.0 MiB" rather than "1024.0 KiB"
+    while round_1(val) >= base && idx < units.len() - 1 {
+        val /= base;
+        idx += 1;
+    }
+    format!("{val:.1} {}", units[idx])
+}
+
+/// Round to one decimal, as printed by [human_bytes]
+fn round_1(val: f64) -> f64 {
+    (val * 10.0).round() / 10.0
+}
+
+/// Format a modification time relative to `now`, e.g. "3 h ago".
+/// Times older than 30 days are printed as a date.
+pub fn human_mtime(mtime: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let secs = (now - mtime).num_seconds();
+    if secs < -60 {
+        return "in the future".to_string();
+    }
+    match secs {
+        ..=59 => "just now".to_string(),
+        60..=3599 => format!("{} min ago", secs / 60),
+        3600..=86399 => format!("{} h ago", secs / 3600),
+        86400..=172799 => "1 day ago".to_string(),
+        172800..=2591999 => format!("{} days ago", secs / 86400),
+        _ => mtime.format("%Y-%m-%d").to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!(human_bytes(0, Unit::Binary), "0 B");
+        assert_eq!(human_bytes(1023, Unit::Binary), "1023 B");
+        assert_eq!(human_bytes(1024, Unit::Binary), "1.0 KiB");
+        assert_eq!(human_bytes(1536, Unit::Binary), "1.5 KiB");
+        assert_eq!(human_bytes(5 * 1024 * 1024, Unit::Binary), "5.0 MiB");
+        assert_eq!(human_bytes(1048575, Unit::Binary), "1.0 MiB");
+        assert_eq!(
+            human_bytes(1024 * 1024 - 52 * 1024, Unit::Binary),
+            "972.0 KiB"
+        );
+        assert_eq!(human_bytes(u64::MAX, Unit::Binary), "16.0 EiB");
+
+        assert_eq!(human_bytes(999, Unit::Decimal), "999 B");
+        assert_eq!(human_bytes(1000, Unit::Decimal), "1.0 kB");
+        assert_eq!(human_bytes(2_500_000, Unit::Decimal), "2.5 MB");
+        assert_eq!(human_bytes(999_999, Unit::Decimal), "1.0 MB");
+    }
+
+    #[test]
+    fn test_human_mtime() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 15, 12, 0, 0).unwrap();
+        let ago = |secs: i64| human_mtime(now - chrono::Duration::seconds(secs), now);
+
+        assert_eq!(ago(0), "just now");
+        assert_eq!(ago(-30), "just now");
+        assert_eq!(ago(-3600), "in the future");
+        assert_eq!(ago(59), "just now");
+        assert_eq!(ago(60), "1 min ago");
+        assert_eq!(ago(45 * 60), "45 min ago");
+        assert_eq!(ago(3 * 3600), "3 h ago");
+        assert_eq!(ago(30 * 3600), "1 day ago");
+        assert_eq!(ago(5 * 86400), "5 days ago");
+        assert_eq!(ago(60 * 86400), "2024-01-15");
+    }
+}
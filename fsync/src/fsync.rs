@@ -1,10 +1,13 @@
+use std::time::{Duration, SystemTime};
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use tarpc::tokio_serde::formats::Bincode;
 use typescript_type_def::TypeDef;
 
 use crate::{
-    path::{Path, PathBuf, FsPathBuf},
-    stat,
+    path::{FsPathBuf, Path, PathBuf},
+    stat, Location, StorageDir, StorageLoc,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
@@ -20,9 +23,22 @@ pub enum Metadata {
         #[type_def(type_of = "i64")]
         #[serde(with = "ms_since_epoch")]
         mtime: DateTime<Utc>,
+        /// For hard links, the path of the first link seen to the same file.
+        /// The data of such entries is not accounted for in the stats.
+        #[serde(default)]
+        link_target: Option<PathBuf>,
     },
 }
 
+/// Size above which the size of a file is deemed corrupt (1 PiB)
+pub const MAX_PLAUSIBLE_SIZE: u64 = 1 << 50;
+
+/// Modification time, in seconds since the Unix epoch, before which a file is deemed corrupt (1980-01-01)
+const MIN_PLAUSIBLE_MTIME: i64 = 315_532_800;
+
+/// How far in the future a modification time is still plausible, in seconds, for the clock skews
+const MTIME_FUTURE_TOLERANCE: i64 = 24 * 3600;
+
 /// Serialize a `DateTime` in milliseconds since the Unix epoch (to fit with Javascript representation).
 /// The milliseconds are rounded down to the nearest second however.
 /// This is because some provider do not provide millisecond granularity in the timestamps.
@@ -65,10 +81,16 @@ impl Metadata {
     pub fn with_path(&self, path: PathBuf) -> Self {
         match self {
             Self::Directory { stat, .. } => Self::Directory { path, stat: *stat },
-            Self::Regular { size, mtime, .. } => Self::Regular {
+            Self::Regular {
+                size,
+                mtime,
+                link_target,
+                ..
+            } => Self::Regular {
                 path,
                 size: *size,
                 mtime: *mtime,
+                link_target: link_target.clone(),
             },
         }
     }
@@ -99,6 +121,14 @@ impl Metadata {
         }
     }
 
+    /// The first path seen linking to the same file, if this is a hard link
+    pub fn link_target(&self) -> Option<&Path> {
+        match self {
+            Self::Regular { link_target, .. } => link_target.as_deref(),
+            _ => None,
+        }
+    }
+
     pub fn has_stat(&self) -> bool {
         matches!(self, Self::Directory { stat, .. } if stat.is_some())
     }
@@ -110,11 +140,31 @@ impl Metadata {
         }
     }
 
+    /// Whether the metadata looks corrupt: a file larger than [`MAX_PLAUSIBLE_SIZE`]
+    /// (such as a negative size received from an API), or modified before 1980 or in the future.
+    /// Such entries are still synchronized, but are flagged in [`stat::Node::implausible`].
+    pub fn is_implausible(&self) -> bool {
+        match self {
+            Self::Directory { .. } => false,
+            Self::Regular { size, mtime, .. } => {
+                *size > MAX_PLAUSIBLE_SIZE
+                    || mtime.timestamp() < MIN_PLAUSIBLE_MTIME
+                    || mtime.timestamp() > Utc::now().timestamp() + MTIME_FUTURE_TOLERANCE
+            }
+        }
+    }
+
     pub fn stat(&self) -> Option<stat::Dir> {
         match self {
-            Self::Directory { stat, .. } => stat.map(|s| s.with_dirs(s.dirs + 1)),
-            Self::Regular { size, .. } => Some(stat::Dir {
-                data: *size as _,
+            Self::Directory { stat, .. } => stat.map(|s| s.with_dirs(s.dirs.saturating_add(1))),
+            Self::Regular {
+                size, link_target, ..
+            } => Some(stat::Dir {
+                data: if link_target.is_some() {
+                    0
+                } else {
+                    i64::try_from(*size).unwrap_or(i64::MAX)
+                },
                 dirs: 0,
                 files: 1,
             }),
@@ -133,15 +183,77 @@ impl Metadata {
     }
 }
 
+/// Role granted by a permission of a shared entry, from the least to the most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum SharingRole {
+    Reader,
+    Commenter,
+    Writer,
+}
+
+impl std::fmt::Display for SharingRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reader => f.write_str("reader"),
+            Self::Commenter => f.write_str("commenter"),
+            Self::Writer => f.write_str("writer"),
+        }
+    }
+}
+
+/// Compact summary of the permissions of a shared remote entry.
+/// It is provided by [`Fsync::sharing`], apart from the metadata of the entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct Sharing {
+    /// Role granted to anyone with the link
+    pub anyone: Option<SharingRole>,
+    /// Role granted to the members of a domain
+    pub domain: Option<SharingRole>,
+    /// Number of users and groups the entry is shared with, besides its owner
+    pub users: u32,
+}
+
+impl Sharing {
+    /// Whether anyone with the link can access the entry
+    pub fn is_public(&self) -> bool {
+        self.anyone.is_some()
+    }
+}
+
+impl std::fmt::Display for Sharing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(role) = self.anyone {
+            parts.push(format!("anyone with link ({role})"));
+        }
+        if let Some(role) = self.domain {
+            parts.push(format!("domain ({role})"));
+        }
+        match self.users {
+            0 => (),
+            1 => parts.push("1 user".to_string()),
+            n => parts.push(format!("{n} users")),
+        }
+        if parts.is_empty() {
+            f.write_str("not shared")
+        } else {
+            write!(f, "shared with {}", parts.join(", "))
+        }
+    }
+}
+
 pub mod tree {
-    use std::mem;
+    use std::{iter, mem, slice, sync::Arc};
 
-    use serde::{Deserialize, Serialize};
+    use chrono::{DateTime, Utc};
+    use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
     use typescript_type_def::TypeDef;
 
-    use crate::{path::Path, stat, Conflict, StorageLoc};
+    use crate::{path::Path, stat, Conflict, SortOrder, StorageLoc};
 
-    #[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
     #[serde(rename_all = "camelCase")]
     pub enum Entry {
         Local(super::Metadata),
@@ -156,7 +268,17 @@ pub mod tree {
 
     impl Entry {
         pub fn new_sync(local: super::Metadata, remote: super::Metadata) -> Self {
-            let conflict = Conflict::check(&local, &remote);
+            Self::new_sync_within(local, remote, 1)
+        }
+
+        /// A synced entry whose conflict is checked with a modification time
+        /// granularity of `mtime_granularity` seconds
+        pub fn new_sync_within(
+            local: super::Metadata,
+            remote: super::Metadata,
+            mtime_granularity: u32,
+        ) -> Self {
+            let conflict = Conflict::check_within(&local, &remote, mtime_granularity);
             Self::Sync {
                 local,
                 remote,
@@ -288,6 +410,22 @@ pub mod tree {
             }
         }
 
+        /// The most recent modification time of the entry, if it is a file at any location.
+        pub fn mtime(&self) -> Option<DateTime<Utc>> {
+            match self {
+                Self::Local(md) | Self::Remote(md) => md.mtime(),
+                Self::Sync { local, remote, .. } => local.mtime().max(remote.mtime()),
+            }
+        }
+
+        /// The largest data size of the entry across locations.
+        /// For directories, this is the size of the whole sub-tree.
+        pub fn size(&self) -> i64 {
+            let local = self.local_stat().map(|s| s.data).unwrap_or(0);
+            let remote = self.remote_stat().map(|s| s.data).unwrap_or(0);
+            local.max(remote)
+        }
+
         pub fn local_stat(&self) -> Option<stat::Dir> {
             match self {
                 Self::Local(local) => local.stat(),
@@ -305,12 +443,193 @@ pub mod tree {
         }
     }
 
-    #[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
+    /// Maximum number of names in a page of [`Children`]
+    pub const CHILDREN_PAGE_LEN: usize = 10_000;
+
+    /// The names of the children of a node, in byte-wise order.
+    /// They are split in pages of at most [`CHILDREN_PAGE_LEN`] names, shared between
+    /// the clones of the node, so that a folder with a huge number of children
+    /// is cheap to clone, and to update one child at a time.
+    /// Serialized as the flat list of the names.
+    #[derive(Clone, Default)]
+    pub struct Children {
+        pages: Vec<Arc<Vec<String>>>,
+        len: usize,
+    }
+
+    pub type ChildrenIter<'a> = iter::FlatMap<
+        slice::Iter<'a, Arc<Vec<String>>>,
+        slice::Iter<'a, String>,
+        fn(&'a Arc<Vec<String>>) -> slice::Iter<'a, String>,
+    >;
+
+    impl Children {
+        pub fn new(mut names: Vec<String>) -> Self {
+            names.sort_unstable();
+            let len = names.len();
+            let mut names = names.into_iter();
+            let mut pages = Vec::new();
+            loop {
+                let page: Vec<String> = names.by_ref().take(CHILDREN_PAGE_LEN).collect();
+                if page.is_empty() {
+                    break;
+                }
+                pages.push(Arc::new(page));
+            }
+            Self { pages, len }
+        }
+
+        pub fn len(&self) -> usize {
+            self.len
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len == 0
+        }
+
+        pub fn iter(&self) -> ChildrenIter<'_> {
+            self.pages.iter().flat_map(|page| page.iter())
+        }
+
+        pub fn contains(&self, name: &str) -> bool {
+            self.locate(name).is_ok()
+        }
+
+        /// The names that come after `after`, or all names if `None`
+        pub fn after(&self, after: Option<&str>) -> impl Iterator<Item = &String> {
+            let (page, idx) = match after.map(|after| self.locate(after)) {
+                None => (0, 0),
+                Some(Ok((page, idx))) => (page, idx + 1),
+                Some(Err((page, idx))) => (page, idx),
+            };
+            let first = self
+                .pages
+                .get(page)
+                .map_or(&[][..], |p| &p[idx.min(p.len())..]);
+            let rest = self.pages.get(page + 1..).unwrap_or_default();
+            first.iter().chain(rest.iter().flat_map(|page| page.iter()))
+        }
+
+        pub fn insert(&mut self, name: String) {
+            let (page, idx) = match self.locate(&name) {
+                Ok(_) => return,
+                Err(pos) => pos,
+            };
+            if self.pages.is_empty() {
+                self.pages.push(Arc::default());
+            }
+            let names = Arc::make_mut(&mut self.pages[page]);
+            names.insert(idx, name);
+            if names.len() > CHILDREN_PAGE_LEN {
+                let tail = names.split_off(names.len() / 2);
+                self.pages.insert(page + 1, Arc::new(tail));
+            }
+            self.len += 1;
+        }
+
+        pub fn remove(&mut self, name: &str) {
+            let Ok((page, idx)) = self.locate(name) else {
+                return;
+            };
+            let names = Arc::make_mut(&mut self.pages[page]);
+            names.remove(idx);
+            if names.is_empty() {
+                self.pages.remove(page);
+            }
+            self.len -= 1;
+        }
+
+        /// The page and index of `name` if it is found,
+        /// otherwise where it would be inserted.
+        fn locate(&self, name: &str) -> Result<(usize, usize), (usize, usize)> {
+            let page = self
+                .pages
+                .partition_point(|page| page.last().is_some_and(|last| last.as_str() < name));
+            if page == self.pages.len() {
+                let page = page.saturating_sub(1);
+                let len = self.pages.get(page).map_or(0, |p| p.len());
+                return Err((page, len));
+            }
+            self.pages[page]
+                .binary_search_by(|n| n.as_str().cmp(name))
+                .map(|idx| (page, idx))
+                .map_err(|idx| (page, idx))
+        }
+    }
+
+    impl<'a> IntoIterator for &'a Children {
+        type Item = &'a String;
+        type IntoIter = ChildrenIter<'a>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            self.iter()
+        }
+    }
+
+    impl PartialEq for Children {
+        fn eq(&self, other: &Self) -> bool {
+            self.len == other.len && self.iter().eq(other.iter())
+        }
+    }
+
+    impl Eq for Children {}
+
+    impl<S: AsRef<str>, const N: usize> PartialEq<[S; N]> for Children {
+        fn eq(&self, other: &[S; N]) -> bool {
+            self.len == N && self.iter().zip(other).all(|(a, b)| a == b.as_ref())
+        }
+    }
+
+    impl std::fmt::Debug for Children {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_list().entries(self.iter()).finish()
+        }
+    }
+
+    impl Serialize for Children {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(Some(self.len))?;
+            for name in self.iter() {
+                seq.serialize_element(name)?;
+            }
+            seq.end()
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Children {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            Vec::<String>::deserialize(deserializer).map(Self::new)
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
     #[serde(rename_all = "camelCase")]
     pub struct EntryNode {
         entry: Entry,
-        children: Vec<String>,
+        #[type_def(type_of = "Vec<String>")]
+        children: Children,
         children_node_stat: stat::Node,
+        /// The entry is a file larger than the size limit, skipped by synchronization.
+        /// Not sent over the wire, it is provided by [`crate::Fsync::too_large_stats`].
+        #[serde(skip)]
+        too_large: bool,
+        /// The name of the remote entry is not supported by the local filesystem,
+        /// or collides there with a sibling. It is not synchronized.
+        /// Not sent over the wire, it is provided by [`crate::Fsync::name_clashes`].
+        #[serde(skip)]
+        name_clash: bool,
+        /// The metadata of the entry looks corrupt, see [`crate::Metadata::is_implausible`].
+        /// Evaluated when the entry changes, so that the stats do not change with the time.
+        /// Not sent over the wire, it is provided by [`crate::Fsync::implausible_stats`].
+        #[serde(skip)]
+        implausible: bool,
+    }
+
+    fn is_implausible(entry: &Entry) -> bool {
+        match entry {
+            Entry::Local(metadata) | Entry::Remote(metadata) => metadata.is_implausible(),
+            Entry::Sync { local, remote, .. } => local.is_implausible() || remote.is_implausible(),
+        }
     }
 
     impl EntryNode {
@@ -370,17 +689,41 @@ pub mod tree {
             }
 
             Self {
+                implausible: is_implausible(&entry),
                 entry,
-                children,
+                children: Children::new(children),
                 children_node_stat: children_stat.node,
+                too_large: false,
+                name_clash: false,
+            }
+        }
+
+        /// Mark the entry as larger than the size limit.
+        /// Only entries that are not synchronized yet can be marked.
+        pub fn with_too_large(self, too_large: bool) -> Self {
+            Self {
+                too_large: too_large && !self.entry.is_sync(),
+                ..self
+            }
+        }
+
+        /// Mark the entry as clashing with the local filesystem.
+        /// Only remote entries can be marked.
+        pub fn with_name_clash(self, name_clash: bool) -> Self {
+            Self {
+                name_clash: name_clash && matches!(self.entry, Entry::Remote(..)),
+                ..self
             }
         }
 
         pub fn without_children(self) -> Self {
             Self {
                 entry: self.entry,
-                children: Vec::new(),
+                children: Children::default(),
                 children_node_stat: stat::Node::null(),
+                too_large: self.too_large,
+                name_clash: self.name_clash,
+                implausible: self.implausible,
             }
         }
 
@@ -396,28 +739,53 @@ pub mod tree {
             let invalid: Entry = unsafe { mem::MaybeUninit::zeroed().assume_init() };
             let valid = mem::replace(&mut self.entry, invalid);
             self.entry = op(valid);
+            if self.entry.is_sync() {
+                self.too_large = false;
+            }
+            if !matches!(self.entry, Entry::Remote(..)) {
+                self.name_clash = false;
+            }
+            self.implausible = is_implausible(&self.entry);
         }
 
         pub fn into_entry(self) -> Entry {
             self.entry
         }
 
-        pub fn into_parts(self) -> (Entry, Vec<String>, stat::Node) {
+        /// Keep only the `max_len` children after the one named `after`.
+        /// The stats still account for all the children.
+        pub fn with_children_page(self, after: Option<&str>, max_len: usize) -> Self {
+            let page = self.children.after(after).take(max_len).cloned().collect();
+            Self {
+                children: Children::new(page),
+                ..self
+            }
+        }
+
+        pub fn into_parts(self) -> (Entry, Children, stat::Node) {
             (self.entry, self.children, self.children_node_stat)
         }
 
-        pub fn children(&self) -> &[String] {
+        pub fn children(&self) -> &Children {
             &self.children
         }
 
+        /// The names of the children, sorted for presentation
+        pub fn children_sorted(&self, order: SortOrder) -> Vec<&str> {
+            let mut children: Vec<&str> = self.children.iter().map(String::as_str).collect();
+            if order != SortOrder::Raw {
+                children.sort_by(|a, b| order.compare(a, b));
+            }
+            children
+        }
+
         pub fn add_child(&mut self, child: String) {
             debug_assert!(!self.children.contains(&child));
-            self.children.push(child);
-            self.children.sort_unstable();
+            self.children.insert(child);
         }
 
         pub fn remove_child(&mut self, child: &str) {
-            self.children.retain(|c| c != child);
+            self.children.remove(child);
         }
 
         pub fn path(&self) -> &Path {
@@ -428,6 +796,18 @@ pub mod tree {
             self.path().file_name()
         }
 
+        pub fn is_too_large(&self) -> bool {
+            self.too_large
+        }
+
+        pub fn is_name_clash(&self) -> bool {
+            self.name_clash
+        }
+
+        pub fn is_implausible(&self) -> bool {
+            self.implausible
+        }
+
         pub fn is_local_only(&self) -> bool {
             self.entry.is_local_only()
         }
@@ -448,12 +828,18 @@ pub mod tree {
             self.entry.is_sync()
         }
 
-        pub fn children_conflicts(&self) -> u32 {
-            self.children_node_stat.conflicts as _
+        /// 1 if the entry itself is a conflict, 0 otherwise
+        pub fn conflicts_here(&self) -> i64 {
+            self.entry.is_conflict() as i64
+        }
+
+        /// The number of conflicts among the descendants, the entry itself excluded
+        pub fn conflicts_below(&self) -> i64 {
+            self.children_node_stat.conflicts
         }
 
-        pub fn children_have_conflicts(&self) -> bool {
-            self.children_conflicts() > 0
+        pub fn has_conflicts_below(&self) -> bool {
+            self.conflicts_below() > 0
         }
 
         /// Get the stat for this node.
@@ -471,6 +857,8 @@ pub mod tree {
                         nodes: 1,
                         sync: 1,
                         conflicts: if conflict.is_some() { 1 } else { 0 },
+                        too_large: 0,
+                        implausible: self.implausible as i64,
                     };
                     stat::Tree {
                         local: local.stat().expect("local stat should be valid"),
@@ -483,6 +871,8 @@ pub mod tree {
                         nodes: 1,
                         sync: 0,
                         conflicts: 0,
+                        too_large: self.too_large as i64,
+                        implausible: self.implausible as i64,
                     };
                     stat::Tree {
                         local: entry.stat().expect("local stat should be valid"),
@@ -495,6 +885,8 @@ pub mod tree {
                         nodes: 1,
                         sync: 0,
                         conflicts: 0,
+                        too_large: self.too_large as i64,
+                        implausible: self.implausible as i64,
                     };
                     stat::Tree {
                         local: stat::Dir::null(),
@@ -523,7 +915,7 @@ pub mod tree {
     }
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize, TypeDef)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
 #[serde(rename_all = "camelCase")]
 pub enum ResolutionMethod {
     ReplaceOlderByNewer,
@@ -535,9 +927,14 @@ pub enum ResolutionMethod {
     DeleteLocal,
     DeleteRemote,
     CreateLocalCopy,
+    /// Merge the concurrent edits of a text file, line by line, from the version
+    /// last synchronized. The conflict stays if the edits overlap.
+    /// Only suggested for the files that [`crate::text::is_mergeable`] accepts.
+    /// Since protocol version 33.
+    MergeText,
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize, TypeDef)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
 #[serde(rename_all = "camelCase")]
 pub enum DeletionMethod {
     /// Will delete local files and folders only if they are synced with remote.
@@ -587,7 +984,68 @@ impl DeletionMethod {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
+/// The order in which the children of a directory are processed by deep operations.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum OrderBy {
+    /// Alphabetical order of the entry names.
+    #[default]
+    TreeOrder,
+    /// Most recently modified files first. Directories come last.
+    NewestFirst,
+    /// Smallest files and directories first.
+    SmallestFirst,
+    /// Largest files and directories first.
+    LargestFirst,
+}
+
+impl OrderBy {
+    /// Sort `nodes` in place according to this ordering.
+    /// The sort is stable, so that entries with the same key keep the tree order.
+    pub fn sort(&self, nodes: &mut [tree::EntryNode]) {
+        match self {
+            OrderBy::TreeOrder => (),
+            OrderBy::NewestFirst => {
+                nodes.sort_by_key(|node| std::cmp::Reverse(node.entry().mtime()));
+            }
+            OrderBy::SmallestFirst => nodes.sort_by_key(|node| node.entry().size()),
+            OrderBy::LargestFirst => {
+                nodes.sort_by_key(|node| std::cmp::Reverse(node.entry().size()));
+            }
+        }
+    }
+}
+
+/// The order in which the children of a directory are presented to the user.
+/// The tree itself always keeps the byte-wise order of the names.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum SortOrder {
+    /// Byte-wise order of the names, as in the tree.
+    /// Uppercase letters come before lowercase ones, and `10` before `2`.
+    Raw,
+    /// Alphabetical order, ignoring the case.
+    CaseInsensitive,
+    /// Alphabetical order ignoring the case, with the numbers compared by value.
+    #[default]
+    Natural,
+}
+
+impl SortOrder {
+    /// Compare the names `a` and `b` according to this ordering
+    pub fn compare(&self, a: &str, b: &str) -> std::cmp::Ordering {
+        match self {
+            SortOrder::Raw => a.cmp(b),
+            SortOrder::CaseInsensitive => crate::collate::case_insensitive_cmp(a, b),
+            SortOrder::Natural => crate::collate::natural_cmp(a, b),
+        }
+    }
+}
+
+/// An operation on the entry at a path, and its descendants for the deep variants.
+/// Operations on the root `/` apply to its children only:
+/// the root entry itself is never created, replaced nor deleted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
 #[serde(rename_all = "camelCase")]
 pub enum Operation {
     Sync(PathBuf),
@@ -597,6 +1055,23 @@ pub enum Operation {
     SyncDeep(PathBuf),
     ResolveDeep(PathBuf, ResolutionMethod),
     DeleteDeep(PathBuf, DeletionMethod),
+
+    /// Same as `SyncDeep`, but the children are processed in the given order.
+    SyncDeepOrdered(PathBuf, OrderBy),
+
+    /// Download a remote-only file, replacing its local placeholder if it has one.
+    /// A local file created since is never overwritten.
+    /// Since protocol version 7.
+    Hydrate(PathBuf),
+
+    /// Copy the remote-only entries of the sub-tree to the local storage,
+    /// and replace the conflicting local files by the remote ones.
+    /// Nothing is deleted, and synchronized entries are left untouched.
+    /// Since protocol version 10.
+    RestoreLocal(PathBuf),
+    /// Same as `RestoreLocal`, from the local storage to the remote one.
+    /// Since protocol version 10.
+    RestoreRemote(PathBuf),
 }
 
 impl Operation {
@@ -609,21 +1084,49 @@ impl Operation {
             Operation::SyncDeep(path) => path,
             Operation::ResolveDeep(path, _) => path,
             Operation::DeleteDeep(path, _) => path,
+            Operation::SyncDeepOrdered(path, _) => path,
+
+            Operation::Hydrate(path) => path,
+
+            Operation::RestoreLocal(path) => path,
+            Operation::RestoreRemote(path) => path,
         }
     }
 
     pub const fn is_deep(&self) -> bool {
         matches!(
             self,
-            Operation::SyncDeep(..) | Operation::ResolveDeep(..) | Operation::DeleteDeep(..)
+            Operation::SyncDeep(..)
+                | Operation::ResolveDeep(..)
+                | Operation::DeleteDeep(..)
+                | Operation::SyncDeepOrdered(..)
+                | Operation::RestoreLocal(..)
+                | Operation::RestoreRemote(..)
         )
     }
 
+    /// The order in which the children are processed
+    pub const fn order(&self) -> OrderBy {
+        match self {
+            Operation::SyncDeepOrdered(_, order) => *order,
+            _ => OrderBy::TreeOrder,
+        }
+    }
+
+    /// Whether a deep operation processes a directory before its children.
+    /// Deletion processes the children first.
+    pub const fn is_parent_first(&self) -> bool {
+        !matches!(self, Operation::Delete(..) | Operation::DeleteDeep(..))
+    }
+
     pub fn not_deep(self) -> Self {
         match self {
             Operation::SyncDeep(path) => Operation::Sync(path),
             Operation::ResolveDeep(path, method) => Operation::Resolve(path, method),
             Operation::DeleteDeep(path, method) => Operation::Delete(path, method),
+            Operation::SyncDeepOrdered(path, _) => Operation::Sync(path),
+            // a restore has no unit counterpart, it is applied entry by entry as is
+            op @ (Operation::RestoreLocal(..) | Operation::RestoreRemote(..)) => op,
             op => panic!("Not a deep operation: {op:?}"),
         }
     }
@@ -637,43 +1140,1645 @@ impl Operation {
             Operation::SyncDeep(_) => Operation::SyncDeep(path),
             Operation::ResolveDeep(_, method) => Operation::ResolveDeep(path, *method),
             Operation::DeleteDeep(_, method) => Operation::DeleteDeep(path, *method),
+            Operation::SyncDeepOrdered(_, order) => Operation::SyncDeepOrdered(path, *order),
+
+            Operation::Hydrate(_) => Operation::Hydrate(path),
+
+            Operation::RestoreLocal(_) => Operation::RestoreLocal(path),
+            Operation::RestoreRemote(_) => Operation::RestoreRemote(path),
+        }
+    }
+}
+
+/// What an operation will do on a single entry
+#[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum Action {
+    /// Create the directory in the given storage
+    Mkdir(StorageLoc),
+    /// Copy the file in the given direction
+    Copy(StorageDir),
+    /// Replace the conflicting file in the given direction
+    Replace(StorageDir),
+    /// Keep a copy of the local file, and replace it by the remote one
+    CopyLocalAndReplace,
+    /// Merge the local and remote versions of the text file, and write the result to both
+    MergeText,
+    /// Delete the entry from the given storage
+    Delete(Location),
+    /// The operation will fail on this entry
+    Fail(crate::Error),
+    /// Skip the file, larger than the size limit
+    SkipTooLarge,
+    /// Skip the entry, as the sync mode of the instance withholds this direction
+    SkipWithheld,
+    /// Remove the entry from the tree, as it was deleted on both sides outside of fsyncd
+    Forget,
+    /// Let anyone with the link read the remote entry
+    SharePublic,
+    /// Mark the local and remote files as synchronized, as they have the same content
+    Pair,
+}
+
+/// An action planned on an entry by an operation
+#[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedAction {
+    pub path: PathBuf,
+    pub action: Action,
+    /// Number of bytes to transfer
+    pub size: u64,
+}
+
+/// Delay within which an operation refused with [`crate::Error::ConfirmationRequired`]
+/// can be submitted again with its token
+pub const CONFIRMATION_WINDOW: Duration = Duration::from_secs(120);
+
+/// Options of an operation started with [`Fsync::operate_with`]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct OperateOptions {
+    /// Synchronize the files larger than the size limit instead of skipping them
+    pub force_large: bool,
+    /// The token of a [`crate::Error::ConfirmationRequired`] returned for the same operation.
+    /// Since protocol version 32.
+    pub confirmation: Option<String>,
+    /// Restrict a deep operation to the entries selected by the filter,
+    /// the path of the filter being relative to the tree, not to the operation.
+    /// Since protocol version 47.
+    pub filter: Option<crate::filter::EntryFilter>,
+}
+
+/// Direction in which an instance synchronizes its entries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum SyncMode {
+    /// Both storages are kept in sync
+    #[default]
+    Bidirectional,
+    /// Only the remote storage is modified, the local files are never touched
+    UploadOnly,
+    /// Only the local storage is modified, the remote files are never touched
+    DownloadOnly,
+}
+
+impl SyncMode {
+    pub fn is_bidirectional(&self) -> bool {
+        matches!(self, Self::Bidirectional)
+    }
+
+    /// The storage modified by the mode, or `None` if both are
+    pub fn target(&self) -> Option<StorageLoc> {
+        match self {
+            Self::Bidirectional => None,
+            Self::UploadOnly => Some(StorageLoc::Remote),
+            Self::DownloadOnly => Some(StorageLoc::Local),
+        }
+    }
+
+    /// Whether the action only modifies the storages allowed by the mode
+    pub fn allows(&self, action: &Action) -> bool {
+        let Some(target) = self.target() else {
+            return true;
+        };
+        match action {
+            Action::Mkdir(loc) => *loc == target,
+            Action::Copy(dir) | Action::Replace(dir) => dir.dest() == target,
+            Action::CopyLocalAndReplace => target == StorageLoc::Local,
+            Action::Delete(Location::Local) => target == StorageLoc::Local,
+            Action::Delete(Location::Remote) | Action::SharePublic => target == StorageLoc::Remote,
+            Action::Delete(Location::Both) | Action::MergeText => false,
+            Action::Fail(..)
+            | Action::SkipTooLarge
+            | Action::SkipWithheld
+            | Action::Forget
+            | Action::Pair => true,
         }
     }
 }
 
+impl std::fmt::Display for SyncMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bidirectional => f.write_str("bidirectional"),
+            Self::UploadOnly => f.write_str("upload-only"),
+            Self::DownloadOnly => f.write_str("download-only"),
+        }
+    }
+}
+
+/// Mark of a remote file that the user can view but not download, e.g. a file shared
+/// with downloads disabled. It is provided by [`Fsync::view_only`], apart from the
+/// metadata of the entry, and such files are skipped by the deep operations.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct ViewOnly {
+    /// Link to open the file in the browser, if the storage reports it
+    pub web_view_link: Option<String>,
+}
+
+/// Links to a remote file, see [`Fsync::remote_link`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteLink {
+    /// Link to open the file in the browser, for the users it is shared with
+    pub web_view: String,
+    /// Link to download the content of the file, if the storage has one for it
+    pub web_content: Option<String>,
+}
+
+/// Maximum number of bytes of a chunk of [`Fsync::read_stream`] and [`Fsync::receive_chunk`]
+pub const MAX_STREAM_CHUNK: u32 = 4 * 1024 * 1024;
+
+/// Time after which a reception started by [`Fsync::receive_begin`] is abandoned
+/// if no chunk is received, see [`Fsync::receive_chunk`]
+pub const RECEPTION_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A chunk of the content of a file, see [`Fsync::read_stream`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamChunk {
+    /// The bytes of the file from the offset of the request, empty at the end of the file
+    pub data: Vec<u8>,
+    /// The metadata of the file read, to check that it did not change between the chunks
+    pub metadata: Metadata,
+    /// The storage read, the local one when the local copy is synchronized
+    pub loc: StorageLoc,
+}
+
+/// Severity of a record of the daemon log, the most severe first
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    TypeDef,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    /// Whether the records of this level pass the `min_level` filter,
+    /// that is whether they are at least as severe as `min_level`
+    pub fn passes(self, min_level: LogLevel) -> bool {
+        self <= min_level
+    }
+}
+
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // padded, for the records to be aligned
+        f.pad(match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+            Self::Trace => "TRACE",
+        })
+    }
+}
+
+/// A record of the daemon log, see [`Fsync::logs_tail`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct LogRecord {
+    /// Position of the record in the log, the cursor of [`Fsync::logs_since`]
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch, unlike the modification times kept in seconds
+    #[type_def(type_of = "i64")]
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub time: DateTime<Utc>,
+    pub level: LogLevel,
+    /// Module or subsystem that logged the record, e.g. `RPC` or `journal`
+    pub target: String,
+    pub message: String,
+    /// Identifier of the operation that logged the record, if any
+    pub operation: Option<u64>,
+}
+
+/// What an operation left undone, reported when it completes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationReport {
+    /// Number of files skipped for being larger than the size limit
+    pub skipped_too_large: u32,
+    /// Number of entries skipped because they are, or contain, pinned entries
+    pub skipped_pinned: u32,
+    /// Number of entries of a deep operation that failed with a permanent error,
+    /// while the operation continued with the other entries
+    pub failed: u32,
+    /// Number of entries skipped because they vanished from a storage since the tree
+    /// was built, leaving nothing to do
+    pub skipped_vanished: u32,
+    /// Number of entries skipped because the sync mode of the instance withholds them
+    pub skipped_withheld: u32,
+    /// Number of files skipped because the storage only allows to view them
+    pub skipped_not_downloadable: u32,
+    /// Number of entries left for a later run, as the time budget of the scheduled
+    /// operation elapsed, see [`crate::Deferral::max_duration`]
+    pub remaining: u32,
+}
+
+impl OperationReport {
+    pub fn is_empty(&self) -> bool {
+        self.skipped_too_large == 0
+            && self.skipped_pinned == 0
+            && self.failed == 0
+            && self.skipped_vanished == 0
+            && self.skipped_withheld == 0
+            && self.skipped_not_downloadable == 0
+            && self.remaining == 0
+    }
+}
+
+impl std::ops::AddAssign for OperationReport {
+    fn add_assign(&mut self, rhs: Self) {
+        self.skipped_too_large += rhs.skipped_too_large;
+        self.skipped_pinned += rhs.skipped_pinned;
+        self.failed += rhs.failed;
+        self.skipped_vanished += rhs.skipped_vanished;
+        self.skipped_withheld += rhs.skipped_withheld;
+        self.skipped_not_downloadable += rhs.skipped_not_downloadable;
+        self.remaining += rhs.remaining;
+    }
+}
+
+/// The failure of an operation, once the transient errors were retried
 #[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
 #[serde(rename_all = "camelCase")]
+pub struct OperationFailure {
+    pub error: crate::Error,
+    /// Number of times the operation was attempted
+    pub attempts: u32,
+    /// Whether the error is transient, see [`crate::Error::is_transient`].
+    /// A permanent error needs the user's attention.
+    pub transient: bool,
+}
+
+impl OperationFailure {
+    pub fn new(error: crate::Error, attempts: u32) -> Self {
+        Self {
+            transient: error.is_transient(),
+            error,
+            attempts,
+        }
+    }
+}
+
+impl std::fmt::Display for OperationFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.transient, self.attempts) {
+            (true, 0 | 1) => write!(f, "{}: failed (transient)", self.error),
+            (true, attempts) => write!(
+                f,
+                "{}: failed after {} retries (transient)",
+                self.error,
+                attempts - 1
+            ),
+            (false, _) => write!(f, "{}: failed (needs attention)", self.error),
+        }
+    }
+}
+
+/// The authorization page of the PKCE flow, to visit in a browser
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthPrompt {
+    pub url: String,
+    /// Time after which the daemon stops waiting for the authorization
+    #[type_def(type_of = "i64")]
+    #[serde(with = "ms_since_epoch")]
+    pub expires_at: DateTime<Utc>,
+    /// Whether the daemon opened the page in a browser itself
+    pub opened: bool,
+}
+
+/// A difference between the tree of the daemon and the entries listed by the storages,
+/// found by [`Fsync::self_check`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct Discrepancy {
+    pub path: PathBuf,
+    /// The compared property of the entry: `local`, `remote`, `conflict` or `children`
+    pub field: String,
+    /// The value derived from the storages
+    pub expected: String,
+    /// The value in the tree
+    pub actual: String,
+}
+
+impl Discrepancy {
+    pub fn new(path: &Path, field: &str, expected: &str, actual: &str) -> Self {
+        Self {
+            path: path.to_owned(),
+            field: field.to_string(),
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        }
+    }
+}
+
+/// A page of the discrepancies found by [`Fsync::self_check_until`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct SelfCheckPage {
+    pub discrepancies: Vec<Discrepancy>,
+    /// The name of the last top-level entry checked, to pass as `after` to continue.
+    /// `None` once the whole tree is checked.
+    pub next: Option<String>,
+}
+
+impl std::fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} is {}, expected {}",
+            self.path, self.field, self.actual, self.expected
+        )
+    }
+}
+
+/// The kinds of files cleaned up by the maintenance task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum CleanupKind {
+    /// A quarantined file older than the retention
+    Quarantine,
+    /// A temporary file of the local tree, left by an interrupted download
+    TempFile,
+    /// A partial download of the content cache, not written to for a while
+    PartialDownload,
+    /// The oldest records of the audit log, beyond its size cap
+    AuditLog,
+}
+
+impl std::fmt::Display for CleanupKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Quarantine => f.write_str("quarantine"),
+            Self::TempFile => f.write_str("temporary file"),
+            Self::PartialDownload => f.write_str("partial download"),
+            Self::AuditLog => f.write_str("audit log"),
+        }
+    }
+}
+
+/// A file deleted, or compacted, by the maintenance task
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct Cleanup {
+    pub kind: CleanupKind,
+    /// Path on the local disk, or in the tree for [`CleanupKind::TempFile`]
+    pub path: String,
+    /// Number of bytes freed
+    pub bytes: u64,
+}
+
+/// The cleanups of a run of the maintenance task
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceReport {
+    /// Whether the files were left in place
+    pub dry_run: bool,
+    pub cleanups: Vec<Cleanup>,
+}
+
+impl MaintenanceReport {
+    pub fn freed(&self) -> u64 {
+        self.cleanups.iter().map(|cleanup| cleanup.bytes).sum()
+    }
+}
+
+/// Algorithm of a [`Checksum`]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, TypeDef,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum HashAlgo {
+    Md5,
+    Sha256,
+}
+
+impl std::fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Md5 => f.write_str("MD5"),
+            Self::Sha256 => f.write_str("SHA-256"),
+        }
+    }
+}
+
+/// The checksum of the content of a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct Checksum {
+    pub algo: HashAlgo,
+    /// The digest in lowercase hexadecimal
+    pub hex: String,
+}
+
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.hex)
+    }
+}
+
+/// Handle to a plan created with [`Fsync::plan`]
+pub type PlanId = u64;
+
+/// Progress of an operation.
+/// It is sent in an [envelope](crate::envelope), so that a variant added by a newer
+/// daemon is read as `Unsupported`, wherever the progress is in the response.
+#[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
+#[serde(remote = "Self", rename_all = "camelCase")]
+#[derive(Default)]
 pub enum Progress {
+    #[default]
     Init,
-    OAuth2Browse(String),
+    /// The user must authorize the application in a browser
+    OAuth2Browse(AuthPrompt),
+    /// The user must visit `url` and enter `code` to authorize the application
+    OAuth2DeviceCode {
+        url: String,
+        code: String,
+    },
     OAuth2Exchange,
     OAuth2Refresh,
-    Progress { progress: u64, total: u64 },
+    Progress {
+        progress: u64,
+        total: u64,
+    },
     Compound,
+    /// The operation is paused, and will resume when the condition is resolved
+    Waiting(String),
     Done,
     Err(crate::Error),
+    /// The operation is done, but left some entries undone
+    DoneWithReport(OperationReport),
+    /// The operation failed, after retrying the transient errors
+    Failed(OperationFailure),
+    /// A progress unknown to this version, sent by a newer daemon.
+    /// Must stay the last variant, new variants are added before it.
+    #[serde(other)]
+    Unsupported,
+}
+
+impl crate::envelope::Extensible for Progress {
+    fn serialize_repr<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Progress::serialize(self, serializer)
+    }
+
+    fn deserialize_repr<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        Progress::deserialize(deserializer)
+    }
+
+    fn unsupported() -> Self {
+        Progress::Unsupported
+    }
+}
+
+impl Serialize for Progress {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        crate::envelope::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Progress {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        crate::envelope::deserialize(deserializer)
+    }
 }
 
 impl Progress {
+    /// The progress of a completed operation
+    pub fn done(report: OperationReport) -> Self {
+        if report.is_empty() {
+            Self::Done
+        } else {
+            Self::DoneWithReport(report)
+        }
+    }
+
     pub fn is_done(&self) -> bool {
-        matches!(self, Self::Done)
+        matches!(self, Self::Done | Self::DoneWithReport(..))
+    }
+
+    /// The progress of a failed operation
+    pub fn failed(error: crate::Error, attempts: u32) -> Self {
+        Self::Failed(OperationFailure::new(error, attempts))
+    }
+
+    /// Whether the operation failed, and will not make any more progress
+    pub fn is_failed(&self) -> bool {
+        matches!(self, Self::Err(..) | Self::Failed(..))
+    }
+
+    /// The error of a failed operation
+    pub fn error(&self) -> Option<&crate::Error> {
+        match self {
+            Self::Err(err) => Some(err),
+            Self::Failed(failure) => Some(&failure.error),
+            _ => None,
+        }
+    }
+
+    /// The report of a completed operation
+    pub fn report(&self) -> Option<OperationReport> {
+        match self {
+            Self::Done => Some(OperationReport::default()),
+            Self::DoneWithReport(report) => Some(*report),
+            _ => None,
+        }
+    }
+}
+
+/// Statistics about a running fsyncd instance
+#[derive(Debug, Clone, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceStats {
+    /// The quota of the remote storage, if it has one
+    pub quota: Option<stat::Quota>,
+    /// Whether the quota usage is above the warning threshold
+    pub quota_warning: bool,
+    /// Disk usage of the caches of the instance
+    pub cache: Option<CacheUsage>,
+    /// The direction in which the instance synchronizes
+    pub sync_mode: SyncMode,
+    /// Number of entries left out of sync by the sync mode
+    pub withheld: u32,
+    /// The [fingerprint](crate::Config::fingerprint) of the config loaded by the daemon,
+    /// `None` if it runs without config file
+    pub config_fingerprint: Option<String>,
+}
+
+/// Memory used by the buffers of the uploads and downloads, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct BufferUsage {
+    /// The configured budget of all the buffers
+    pub budget: u64,
+    /// The bytes rented by the transfers in progress
+    pub in_use: u64,
+    /// Number of transfers waiting for the budget to be available
+    pub waiters: u64,
+}
+
+/// Disk usage of the caches of an instance, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheUsage {
+    /// The persisted metadata of the remote storage
+    pub metadata: u64,
+    /// The cached content of remote files
+    pub content: u64,
+    /// The files set aside before being deleted or overwritten
+    pub quarantine: u64,
+    /// The files being written
+    pub temp: u64,
+    /// The configured budget of all the caches, if any
+    pub budget: Option<u64>,
+}
+
+impl CacheUsage {
+    pub fn total(&self) -> u64 {
+        self.metadata + self.content + self.quarantine + self.temp
+    }
+}
+
+/// The reads of remote content looked up in the content cache, since the daemon started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheLookups {
+    /// Number of reads of remote content served from the content cache
+    pub hits: u64,
+    /// Number of reads of remote content that missed the content cache
+    pub misses: u64,
+}
+
+/// Activity counters of an fsyncd instance
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct Counters {
+    /// Bytes written to the remote storage
+    pub bytes_uploaded: u64,
+    /// Bytes written to the local storage from the remote storage
+    pub bytes_downloaded: u64,
+    /// Unit operations that succeeded
+    pub operations: u64,
+    /// Unit operations that failed, including the attempts that were retried
+    pub failures: u64,
+    /// Attempts of operations that failed with a transient error, and were retried
+    pub retries: u64,
+}
+
+impl std::ops::Add for Counters {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            bytes_uploaded: self.bytes_uploaded + rhs.bytes_uploaded,
+            bytes_downloaded: self.bytes_downloaded + rhs.bytes_downloaded,
+            operations: self.operations + rhs.operations,
+            failures: self.failures + rhs.failures,
+            retries: self.retries + rhs.retries,
+        }
+    }
+}
+
+/// Why the content of a file was transferred between the storages
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, TypeDef,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum TransferCause {
+    /// The synchronization of new or modified files
+    Sync,
+    /// The resolution of conflicts, the merges of text files included
+    Conflict,
+    /// The repair of the files of which the verification found a content mismatch
+    Verification,
+    /// The deep operations resumed from their checkpoint
+    Resume,
+    /// The requests of the clients, e.g. the reads of remote files and the downloads of revisions
+    Client,
+}
+
+impl TransferCause {
+    pub const ALL: [Self; 5] = [
+        Self::Sync,
+        Self::Conflict,
+        Self::Verification,
+        Self::Resume,
+        Self::Client,
+    ];
+}
+
+impl std::fmt::Display for TransferCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sync => f.write_str("sync"),
+            Self::Conflict => f.write_str("conflict"),
+            Self::Verification => f.write_str("verification"),
+            Self::Resume => f.write_str("resume"),
+            Self::Client => f.write_str("client"),
+        }
+    }
+}
+
+/// The content transferred for a [`TransferCause`], uploads and downloads together
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferCounters {
+    /// Files transferred, once per direction
+    pub files: u64,
+    /// Bytes transferred at the first attempt
+    pub bytes: u64,
+    /// Bytes of content that was already sent: the transfers that follow a failed attempt,
+    /// and the parts that the storage sent again after a failure
+    pub retried_bytes: u64,
+}
+
+impl std::ops::Add for TransferCounters {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            files: self.files + rhs.files,
+            bytes: self.bytes + rhs.bytes,
+            retried_bytes: self.retried_bytes + rhs.retried_bytes,
+        }
+    }
+}
+
+/// The [`TransferCounters`] of each [`TransferCause`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct Transfers {
+    pub sync: TransferCounters,
+    pub conflict: TransferCounters,
+    pub verification: TransferCounters,
+    pub resume: TransferCounters,
+    pub client: TransferCounters,
+}
+
+impl Transfers {
+    pub fn get(&self, cause: TransferCause) -> &TransferCounters {
+        match cause {
+            TransferCause::Sync => &self.sync,
+            TransferCause::Conflict => &self.conflict,
+            TransferCause::Verification => &self.verification,
+            TransferCause::Resume => &self.resume,
+            TransferCause::Client => &self.client,
+        }
+    }
+
+    pub fn get_mut(&mut self, cause: TransferCause) -> &mut TransferCounters {
+        match cause {
+            TransferCause::Sync => &mut self.sync,
+            TransferCause::Conflict => &mut self.conflict,
+            TransferCause::Verification => &mut self.verification,
+            TransferCause::Resume => &mut self.resume,
+            TransferCause::Client => &mut self.client,
+        }
+    }
+}
+
+impl std::ops::Add for Transfers {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            sync: self.sync + rhs.sync,
+            conflict: self.conflict + rhs.conflict,
+            verification: self.verification + rhs.verification,
+            resume: self.resume + rhs.resume,
+            client: self.client + rhs.client,
+        }
+    }
+}
+
+/// The activity counters of an instance, since the daemon started and over its lifetime.
+/// The lifetime counters are persisted across the restarts of the daemon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceCounters {
+    pub since_boot: Counters,
+    pub lifetime: Counters,
+    /// When the daemon started
+    #[type_def(type_of = "i64")]
+    #[serde(with = "ms_since_epoch")]
+    pub boot: DateTime<Utc>,
+    /// When the lifetime counters started, at the first start or after their file was lost
+    #[type_def(type_of = "i64")]
+    #[serde(with = "ms_since_epoch")]
+    pub lifetime_start: DateTime<Utc>,
+}
+
+/// The content transferred by an instance, by cause, since the daemon started
+/// and over its lifetime. The lifetime transfers are persisted with the [`InstanceCounters`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceTransfers {
+    pub since_boot: Transfers,
+    pub lifetime: Transfers,
+}
+
+/// The conflicts between files of the same content that an instance marked as synchronized
+/// without transfer, e.g. when the daemon stopped between an upload and the update of the tree.
+/// Counted since the daemon started and over its lifetime, as the [`InstanceCounters`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceRepairs {
+    pub since_boot: u64,
+    pub lifetime: u64,
+}
+
+/// The remote root folder an instance is paired with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteRoot {
+    /// Identity of the root folder in the provider, as its Drive id or its canonical path
+    pub id: String,
+    /// The root as written in the config
+    pub config: String,
+}
+
+impl std::fmt::Display for RemoteRoot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.config, self.id)
+    }
+}
+
+/// The remote root of the config no longer resolves to the folder the instance was paired with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct RootChange {
+    /// The root the local directory was synchronized with
+    pub recorded: RemoteRoot,
+    /// The root the config resolves to now
+    pub resolved: RemoteRoot,
+}
+
+/// How to proceed after a change of the remote root, see [`Fsync::migrate_root`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum MigrationChoice {
+    /// Pair the local directory with the new root, the local entries winning the conflicts
+    KeepLocal,
+    /// Clear the local directory, to download the new root afresh
+    ResetLocal,
+    /// Write the recorded root back in the config. The daemon must then be restarted.
+    RevertConfig,
+}
+
+/// Phase of the initialization of the remote storage
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum RemotePhase {
+    /// The remote storage is being initialized. The operations that need it
+    /// fail with [`crate::Error::RemoteInitializing`] until it is ready.
+    Initializing,
+    /// The last initialization attempt failed with this message, a new one is scheduled
+    Retrying(String),
+    /// The remote storage is ready
+    Ready,
+}
+
+/// Status of a running fsyncd instance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    /// Phase of the initialization of the remote storage
+    pub remote: RemotePhase,
+    /// Capabilities of the local filesystem, if they could be probed or loaded
+    pub fs_caps: Option<crate::caps::FsCaps>,
+    /// Persisted files that could not be read at startup, and that were set aside
+    pub corrupt_files: Vec<CorruptFile>,
+    /// State of the deferral policy of the scheduled work
+    pub policy: PolicyState,
+    /// The scheduled deep operation stopped by its time budget, if any
+    pub checkpoint: Option<Checkpoint>,
+    /// The [fingerprint](crate::Config::fingerprint) of the config loaded by the daemon,
+    /// `None` if it runs without config file
+    pub config_fingerprint: Option<String>,
+    /// The last resume of the system from sleep, if any since the start of the daemon
+    pub resumed: Option<Resumed>,
+}
+
+/// A resume of the system from sleep, detected by the daemon.
+/// The requests in flight at the suspend are retried, and the remote storage
+/// is checked again for the changes made while the system was asleep.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct Resumed {
+    /// When the resume was detected
+    #[type_def(type_of = "i64")]
+    #[serde(with = "ms_since_epoch")]
+    pub at: DateTime<Utc>,
+    /// How long the system was asleep, in seconds
+    pub asleep_secs: u64,
+    /// Whether the daemon is still catching up with the changes made during the sleep
+    pub resyncing: bool,
+}
+
+/// A scheduled deep operation stopped by its [time budget](crate::Deferral::max_duration),
+/// to be resumed by its next scheduled run or by [`Fsync::resume`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct Checkpoint {
+    pub operation: Operation,
+    /// When the budget elapsed
+    #[type_def(type_of = "i64")]
+    #[serde(with = "ms_since_epoch")]
+    pub created: DateTime<Utc>,
+    /// Number of entries left
+    pub remaining: u32,
+    /// Number of bytes left to transfer
+    pub remaining_bytes: u64,
+}
+
+/// A past version of a remote file kept by the storage, see [`Fsync::remote_revisions`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct Revision {
+    /// The id of the revision in the storage
+    pub id: String,
+    #[type_def(type_of = "i64")]
+    #[serde(with = "ms_since_epoch")]
+    pub mtime: DateTime<Utc>,
+    /// The size of the content, unknown for some documents
+    pub size: Option<u64>,
+    /// The name of the user who made the revision, if reported
+    pub author: Option<String>,
+}
+
+impl Revision {
+    /// The path where the revision of the file at `path` is downloaded by default:
+    /// next to the file, with the time of the revision appended to the stem,
+    /// e.g. `/notes-20240101-120000.txt`
+    pub fn default_dest(&self, path: &Path) -> PathBuf {
+        let parent = path.parent().unwrap_or(Path::root());
+        let stem = path.file_stem().unwrap_or_default();
+        let mut res = parent.join(format!("{stem}-{}", self.mtime.format("%Y%m%d-%H%M%S")));
+        if let Some(ext) = path.extension() {
+            res.set_extension(ext);
+        }
+        res
+    }
+}
+
+/// State of the [deferral policy](crate::Deferral) of the scheduled work
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyState {
+    /// Whether the system runs on battery, `None` if it can't be told on this system
+    pub on_battery: Option<bool>,
+    /// Why the scheduled work is postponed, `None` if it may run now
+    pub deferred: Option<String>,
+}
+
+/// Durations of the expensive phases of the daemon, see [`Fsync::timings`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct Timings {
+    /// Whether the daemon records the timings, with `--timings` or `--profile`
+    pub enabled: bool,
+    /// The phases that were recorded at least once
+    pub phases: Vec<PhaseTimings>,
+}
+
+/// Percentiles of the durations of a phase over its most recent samples
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct PhaseTimings {
+    /// Name of the phase, e.g. `drive.http`
+    pub phase: String,
+    /// Number of samples since the start of the daemon
+    pub count: u64,
+    /// Number of bytes moved by the samples that move content
+    pub bytes: u64,
+    /// Median duration in microseconds
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// A persisted file that could not be read at startup.
+/// It was renamed with a `.corrupt-<timestamp>` suffix and the daemon started without it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct CorruptFile {
+    /// What the file holds, e.g. "token cache"
+    pub kind: String,
+    /// Where the file was moved
+    pub moved_to: String,
+    /// Why the file could not be read
+    pub error: String,
+}
+
+/// The local changes found by [`Fsync::rescan`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct RescanReport {
+    /// Entries that appeared in the local storage
+    pub added: Vec<PathBuf>,
+    /// Entries that disappeared from the local storage, or that are now ignored
+    pub removed: Vec<PathBuf>,
+    /// Entries whose local metadata changed
+    pub modified: Vec<PathBuf>,
+}
+
+impl RescanReport {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
     }
 }
 
-impl Default for Progress {
-    fn default() -> Self {
-        Self::Init
+/// The content checked by [`Fsync::verify`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationReport {
+    /// Number of files whose local and remote content are the same
+    pub verified: u64,
+    /// Files whose local and remote content differ, now [`crate::Conflict::ContentMismatch`]
+    pub mismatched: Vec<PathBuf>,
+    /// Files that could not be verified, and why
+    pub unverifiable: Vec<(PathBuf, String)>,
+    /// The last file verified, to pass as `after` to continue.
+    /// `None` once the whole sub-tree is verified.
+    pub next: Option<PathBuf>,
+}
+
+impl VerificationReport {
+    /// Append the files of the next page
+    pub fn extend(&mut self, page: VerificationReport) {
+        self.verified += page.verified;
+        self.mismatched.extend(page.mismatched);
+        self.unverifiable.extend(page.unverifiable);
+        self.next = page.next;
     }
 }
 
+/// Where an interrupted [`Fsync::verify`] stopped
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyCheckpoint {
+    /// The sub-tree verified
+    pub path: PathBuf,
+    pub deep: bool,
+    /// The last file verified, to pass as `after` to resume
+    pub next: PathBuf,
+    /// When the last page was verified
+    #[type_def(type_of = "i64")]
+    #[serde(with = "ms_since_epoch")]
+    pub created: DateTime<Utc>,
+}
+
+/// Version of the RPC protocol.
+/// It is bumped each time an RPC or an enum variant is added.
+/// Existing RPCs and variants are never modified, so that a daemon
+/// can serve clients of the same or an older version.
+/// Version 16 reports how complete the remote stats of the folders are.
+/// Version 25 checks the tree by pages bounded by a deadline.
+/// Version 26 reports the activity counters, persisted across restarts.
+/// Version 27 detects the changes of the remote root and migrates the instance.
+/// Version 28 checks again the conflicts that may have been resolved outside of fsync.
+/// Version 29 reports the remote files that can be viewed but not downloaded.
+/// Version 30 postpones the scheduled operations according to the deferral policy.
+/// Version 31 reports the timings of the expensive phases of the daemon.
+/// Version 32 asks the confirmation of the deletion of large sub-trees.
+/// Version 33 merges the concurrent edits of text files.
+/// Version 34 stops the scheduled deep operations at the end of their time budget, and resumes them.
+/// Version 35 lists and downloads the revisions of the remote files.
+/// Version 36 reports the fingerprint of the config loaded by the daemon.
+/// Version 37 reports the lookups in the content cache.
+/// Version 38 reports the progresses changed since a cursor.
+/// Version 39 reports the links to the remote files, and shares them publicly.
+/// Version 40 streams the recent records of the daemon log.
+/// Version 41 widens the counters of the stats to 64 bits, and reports the implausible metadata.
+/// Version 42 reports the repairs of the conflicts between identical files.
+/// Version 43 streams the content of the files between the daemons.
+/// Version 44 limits the memory used by the buffers of the transfers, and reports it.
+/// Version 45 verifies the content of the synchronized files.
+/// Version 46 reports the resumes of the system from sleep.
+/// Version 47 filters the conflicts and the deep operations by pattern and by kind of conflict.
+/// Version 48 reports the transferred content by cause.
+pub const PROTOCOL_VERSION: u32 = 48;
+
+/// Default time given by the clients to the daemon to reply to a request.
+/// The RPCs that can take longer either reply early with a progress to poll,
+/// as [`Fsync::operate`], or accept a deadline and reply with a partial result,
+/// as [`Fsync::self_check_until`].
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time after which [`Fsync::operate`] replies with the current progress
+/// if the operation is not complete
+pub const OPERATE_REPLY_DELAY: Duration = Duration::from_millis(50);
+
+/// Options of the Bincode codec of the RPC transport
+pub type CodecOptions = bincode::DefaultOptions;
+
+/// The codec of the RPC transport, for both the daemon and the clients.
+/// The enums that gain variants are sent in an [envelope](crate::envelope),
+/// so that an unknown variant degrades to its fallback instead of a transport error.
+pub fn codec<Item, SinkItem>() -> Bincode<Item, SinkItem, CodecOptions> {
+    Bincode::from(bincode::DefaultOptions::new())
+}
+
 #[tarpc::service]
 pub trait Fsync {
+    /// The [`PROTOCOL_VERSION`] of the daemon.
+    /// Must stay the first RPC, so that its wire representation never changes.
+    async fn protocol_version() -> u32;
     async fn conflicts(first: Option<PathBuf>, max_len: u32) -> crate::Result<Vec<tree::Entry>>;
     async fn entry_node(path: PathBuf) -> crate::Result<Option<tree::EntryNode>>;
     async fn local_path(path: Option<PathBuf>) -> crate::Result<FsPathBuf>;
+    /// Start `operation`. The reply is the final progress if the operation completes
+    /// within [`OPERATE_REPLY_DELAY`], and the current progress otherwise, while the
+    /// operation goes on in the background. It is then polled with `progress`.
+    /// The reply never waits for the operation, so it never exceeds [`DEFAULT_RPC_TIMEOUT`].
+    /// The deletion of a sub-tree larger than the [`crate::DeleteGuard`] of the config fails
+    /// with [`crate::Error::ConfirmationRequired`], to be confirmed with `operate_with`.
     async fn operate(operation: Operation) -> crate::Result<Progress>;
     /// Provide the progress of the operation on the given path.
     async fn progress(path: PathBuf) -> crate::Result<Option<Progress>>;
     /// Provide the progress of all operations of the given path and its descendants.
     async fn progresses(path: PathBuf) -> crate::Result<Vec<(PathBuf, Progress)>>;
+    /// Provide statistics about the instance, such as the remote quota.
+    async fn instance_stats() -> crate::Result<InstanceStats>;
+    /// Read at most `max_bytes` from the start of the file at `path` in the given storage.
+    async fn read_head(path: PathBuf, loc: StorageLoc, max_bytes: u64) -> crate::Result<Vec<u8>>;
+    /// Remove from the tree the entries that were deleted on both sides.
+    /// Returns the paths of the removed entries.
+    async fn gc_tree() -> crate::Result<Vec<PathBuf>>;
+    /// Plan what `operation` would do, without performing it.
+    /// The actions are computed lazily and retrieved with `plan_next`.
+    async fn plan(operation: Operation) -> crate::Result<PlanId>;
+    /// Provide at most `max_len` next actions of the plan.
+    /// An empty list means that the plan is complete, and the handle is released.
+    async fn plan_next(plan: PlanId, max_len: u32) -> crate::Result<Vec<PlannedAction>>;
+
+    /// Same as `operate`, with options.
+    /// Since protocol version 2.
+    async fn operate_with(operation: Operation, options: OperateOptions)
+        -> crate::Result<Progress>;
+    /// Provide at most `max_len` entries skipped for being larger than the size limit,
+    /// in tree order, starting at `first`.
+    /// Since protocol version 2.
+    async fn too_large(first: Option<PathBuf>, max_len: u32) -> crate::Result<Vec<tree::Entry>>;
+
+    /// Provide the files larger than the size limit in the sub-trees at `paths`, in the same order.
+    /// The entries not found in the tree are reported without any.
+    /// Since protocol version 2.
+    async fn too_large_stats(paths: Vec<PathBuf>) -> crate::Result<Vec<stat::Flagged>>;
+
+    /// Authenticate the connection with the token of the instance.
+    /// All the RPCs but `protocol_version` fail with `Error::Unauthorized` until then.
+    /// Since protocol version 3.
+    async fn authenticate(token: String) -> crate::Result<()>;
+
+    /// List again the local directory at `path`, and its descendants if `deep`,
+    /// to catch up with the changes made outside of fsyncd without rebuilding the whole tree.
+    /// Since protocol version 4.
+    async fn rescan(path: PathBuf, deep: bool) -> crate::Result<RescanReport>;
+
+    /// Pin or unpin the entry at `path`. A pinned entry is never deleted nor overwritten,
+    /// on either side. The entry does not need to exist.
+    /// Since protocol version 5.
+    async fn set_pinned(path: PathBuf, pinned: bool) -> crate::Result<()>;
+    /// Provide the pinned paths, in tree order.
+    /// Since protocol version 5.
+    async fn pinned() -> crate::Result<Vec<PathBuf>>;
+
+    /// Provide the status of the instance, such as whether the remote storage is ready.
+    /// Since protocol version 6, with the local filesystem capabilities since version 9,
+    /// and the corrupt files since version 14, and the deferral policy since version 30.
+    async fn status() -> crate::Result<Status>;
+
+    /// Delete the cached content of remote files if `content`, and the persisted metadata
+    /// of the remote storage if `metadata`. The entries still needed by an operation are kept.
+    /// Returns the number of bytes freed.
+    /// Since protocol version 8.
+    async fn clear_cache(content: bool, metadata: bool) -> crate::Result<u64>;
+
+    /// Provide whether the names of the remote entries at `paths` clash with the local filesystem,
+    /// in the same order. Such entries are not synchronized.
+    /// Since protocol version 9.
+    async fn name_clashes(paths: Vec<PathBuf>) -> crate::Result<Vec<bool>>;
+
+    /// Provide at most `max_len` entries under `path` that anyone with the link can access,
+    /// in tree order. Their sharing is provided by [`Fsync::sharing`].
+    /// Since protocol version 11.
+    async fn publicly_shared(path: PathBuf, max_len: u32) -> crate::Result<Vec<tree::Entry>>;
+
+    /// Provide how the remote entries at `paths` are shared, in the same order.
+    /// `None` is provided for the entries that are not shared, not remote,
+    /// or whose sharing is not reported by the storage.
+    /// Since protocol version 11.
+    async fn sharing(paths: Vec<PathBuf>) -> crate::Result<Vec<Option<Sharing>>>;
+
+    /// Deliver the digest of the pending conflicts and of the recently failed operations.
+    /// Unless `force` is set, a digest with nothing new since the previous one is skipped.
+    /// Returns whether the digest was delivered.
+    /// Since protocol version 12.
+    async fn send_digest(force: bool) -> crate::Result<bool>;
+
+    /// Provide at most `max_len` child nodes of the directory at `path`, sorted with `order`,
+    /// or in natural order if not specified. The listing starts after the child named `after`,
+    /// the name of the last child of the previous page.
+    /// Since protocol version 13.
+    async fn entry_nodes(
+        path: PathBuf,
+        order: Option<SortOrder>,
+        after: Option<String>,
+        max_len: u32,
+    ) -> crate::Result<Vec<tree::EntryNode>>;
+
+    /// Provide how complete the remote stats of the folders at `paths` are, in the same order.
+    /// The entries that are not remote folders are reported exact.
+    /// Since protocol version 16.
+    async fn aggregation(paths: Vec<PathBuf>) -> crate::Result<Vec<stat::Aggregation>>;
+
+    /// Aggregate the remote stats of the sub-tree at `path` before the rest of the tree.
+    /// Returns immediately, the progress is reported by [`Fsync::aggregation`].
+    /// Since protocol version 16.
+    async fn aggregate_now(path: PathBuf) -> crate::Result<()>;
+
+    /// Compare the whole tree with fresh listings of the storages.
+    /// Returns the discrepancies found, expected to be empty.
+    /// Since protocol version 20.
+    async fn self_check() -> crate::Result<Vec<Discrepancy>>;
+
+    /// Run the maintenance task now, as scheduled by [`crate::Maintenance`].
+    /// With `dry_run`, the files that would be cleaned up are reported but left in place.
+    /// Since protocol version 21.
+    async fn run_maintenance(dry_run: bool) -> crate::Result<MaintenanceReport>;
+
+    /// Same as `entry_node`, with at most `max_len` of the children names,
+    /// starting after the child named `after`, in byte-wise order.
+    /// Large directories are listed with successive calls rather than at once.
+    /// Since protocol version 22.
+    async fn entry_node_page(
+        path: PathBuf,
+        after: Option<String>,
+        max_len: u32,
+    ) -> crate::Result<Option<tree::EntryNode>>;
+
+    /// Compute the checksum of the local copy of the file at `path`.
+    /// The checksums are cached until the size or the modification time of the file changes.
+    /// Since protocol version 23.
+    async fn checksum(path: PathBuf, algo: HashAlgo) -> crate::Result<Checksum>;
+
+    /// The boot id of the daemon, drawn at random when it starts.
+    /// A different id tells the clients that the daemon restarted.
+    /// Does not require authentication, so that it can serve as a heartbeat.
+    /// Since protocol version 24.
+    async fn ping() -> u64;
+
+    /// Same as `self_check`, by pages of top-level entries, starting after the one named `after`.
+    /// The page ends with the first top-level entry checked past `deadline`, so that a large
+    /// tree is checked with successive calls instead of exceeding the deadline of the request.
+    /// Since protocol version 25.
+    async fn self_check_until(
+        after: Option<String>,
+        deadline: SystemTime,
+    ) -> crate::Result<SelfCheckPage>;
+
+    /// The activity counters of the instance, since the daemon started and over its lifetime.
+    /// Since protocol version 26.
+    async fn counters() -> crate::Result<InstanceCounters>;
+
+    /// The change of the remote root detected at startup, if any.
+    /// Until it is migrated, the operations fail with [`crate::Error::RootChanged`].
+    /// Since protocol version 27.
+    async fn root_change() -> crate::Result<Option<RootChange>>;
+
+    /// Proceed after a change of the remote root as chosen by the user.
+    /// The reset of the local directory deletes its content, the client must confirm it first.
+    /// Since protocol version 27.
+    async fn migrate_root(accept: MigrationChoice) -> crate::Result<()>;
+
+    /// Check the conflicts at `paths` again in the storages, to notice the conflicts
+    /// resolved outside of fsync, and return the paths that are no longer conflicts.
+    /// The conflicts that were checked a few seconds ago are not checked again.
+    /// The conflicts fetched with [`Fsync::conflicts`] and [`Fsync::entry_node`] are also
+    /// checked in the background when their state gets older than the configured staleness.
+    /// Since protocol version 28.
+    async fn revalidate_conflicts(paths: Vec<PathBuf>) -> crate::Result<Vec<PathBuf>>;
+
+    /// Provide whether the remote files at `paths` can only be viewed, in the same order.
+    /// `None` is provided for the entries that can be downloaded, that are not remote,
+    /// or whose restriction was not reported by the storage yet.
+    /// Since protocol version 29.
+    async fn view_only(paths: Vec<PathBuf>) -> crate::Result<Vec<Option<ViewOnly>>>;
+
+    /// Same as [`Fsync::operate_with`], for the operations started on schedule, e.g. by a timer.
+    /// The operation waits with [`Progress::Waiting`] while the deferral policy of the
+    /// config postpones the scheduled work.
+    /// Since protocol version 30.
+    async fn operate_scheduled(
+        operation: Operation,
+        options: OperateOptions,
+    ) -> crate::Result<Progress>;
+
+    /// Read the config file again, and apply the settings that can change while running,
+    /// currently the deferral policy.
+    /// Since protocol version 30.
+    async fn reload_config() -> crate::Result<()>;
+
+    /// The durations of the expensive phases of the daemon over their most recent samples.
+    /// Empty unless the daemon was started with `--timings` or `--profile`.
+    /// Since protocol version 31.
+    async fn timings() -> crate::Result<Timings>;
+
+    /// Resume the scheduled deep operation stopped by its time budget,
+    /// from the entries left in its checkpoint, and without time budget.
+    /// Replies as [`Fsync::operate`], the progress is polled on the path of the operation.
+    /// Since protocol version 34.
+    async fn resume() -> crate::Result<Progress>;
+
+    /// The revisions of the remote file at `path` kept by the storage, most recent first,
+    /// and at most `max` of them.
+    /// Fails with [`crate::Error::Unsupported`] if the storage does not keep revisions.
+    /// Since protocol version 35.
+    async fn remote_revisions(path: PathBuf, max: u32) -> crate::Result<Vec<Revision>>;
+
+    /// Download the revision `revision_id` of the remote file at `path` to the new local
+    /// file at `dest`, which is then synchronized as any local file.
+    /// Fails if an entry exists at `dest`, see [`Revision::default_dest`].
+    /// Since protocol version 35.
+    async fn download_revision(
+        path: PathBuf,
+        revision_id: String,
+        dest: PathBuf,
+    ) -> crate::Result<Metadata>;
+
+    /// The reads of remote content looked up in the content cache since the daemon started,
+    /// `None` if the instance has no cache.
+    /// Since protocol version 37.
+    async fn cache_lookups() -> crate::Result<Option<CacheLookups>>;
+
+    /// Same as `progresses`, with only the progresses that changed since `cursor`,
+    /// and the cursor of the current state to pass to the next call.
+    /// The first call passes 0 to get all the progresses. An operation that completed since
+    /// `cursor` is reported with its final progress, even if it is no longer tracked.
+    /// Since protocol version 38.
+    async fn progresses_since(
+        path: PathBuf,
+        cursor: u64,
+    ) -> crate::Result<(u64, Vec<(PathBuf, Progress)>)>;
+
+    /// The links to the remote file at `path`.
+    /// Fails with [`crate::PathError::NotFound`] if the file is not in the remote drive,
+    /// and with [`crate::Error::Unsupported`] if the storage has no links.
+    /// Since protocol version 39.
+    async fn remote_link(path: PathBuf) -> crate::Result<RemoteLink>;
+
+    /// Let anyone with the link read the remote file at `path`, and return its links.
+    /// The first call fails with [`crate::Error::ConfirmationRequired`], and the client
+    /// calls again with the token once the user confirmed. The sharing is recorded in the audit log.
+    /// Since protocol version 39.
+    async fn share_public(path: PathBuf, confirmation: Option<String>)
+        -> crate::Result<RemoteLink>;
+
+    /// The `max` most recent records of the daemon log of `min_level` or more severe,
+    /// the oldest first. The daemon keeps a bounded number of records in memory,
+    /// and the verbose ones only while a client reads them.
+    /// Since protocol version 40.
+    async fn logs_tail(max: u32, min_level: LogLevel) -> crate::Result<Vec<LogRecord>>;
+
+    /// Same as `logs_tail`, with the records logged after `cursor`, the oldest first,
+    /// and the cursor to pass to the next call. The first call passes the `seq` of the last
+    /// record returned by `logs_tail`, or 0. Up to `max` records are returned,
+    /// the next call returns the following ones.
+    /// Since protocol version 40.
+    async fn logs_since(
+        cursor: u64,
+        max: u32,
+        min_level: LogLevel,
+    ) -> crate::Result<(u64, Vec<LogRecord>)>;
+
+    /// Provide the entries of implausible metadata in the sub-trees at `paths`, in the same order.
+    /// The entries not found in the tree are reported without any.
+    /// Since protocol version 41.
+    async fn implausible_stats(paths: Vec<PathBuf>) -> crate::Result<Vec<stat::Flagged>>;
+
+    /// The conflicts between identical files marked as synchronized by the instance,
+    /// since the daemon started and over its lifetime.
+    /// Since protocol version 42.
+    async fn repairs() -> crate::Result<InstanceRepairs>;
+
+    /// Read at most `max_bytes` of the file at `path`, starting at `offset`, from its local
+    /// copy if it is synchronized, and from the remote storage otherwise.
+    /// A file is read by successive calls, the last one returns an empty chunk.
+    /// `max_bytes` is capped to [`MAX_STREAM_CHUNK`].
+    /// Since protocol version 43.
+    async fn read_stream(path: PathBuf, offset: u64, max_bytes: u32) -> crate::Result<StreamChunk>;
+
+    /// Create the remote directory at `path`, and its missing parents.
+    /// Since protocol version 43.
+    async fn receive_dir(path: PathBuf) -> crate::Result<()>;
+
+    /// Start the creation of the remote file of `metadata`, whose content is then sent
+    /// with `receive_chunk`, and return the handle of the reception.
+    /// Fails if an entry exists at the path of the file.
+    /// A reception idle for [`RECEPTION_TIMEOUT`] is abandoned, and leaves no file.
+    /// Since protocol version 43.
+    async fn receive_begin(metadata: Metadata) -> crate::Result<u64>;
+
+    /// Send the next chunk of the content of the reception `handle`,
+    /// of at most [`MAX_STREAM_CHUNK`] bytes.
+    /// Since protocol version 43.
+    async fn receive_chunk(handle: u64, data: Vec<u8>) -> crate::Result<()>;
+
+    /// Complete the reception `handle`, once all the content was sent.
+    /// The file is deleted unless the MD5 of the content received is `md5`, in lowercase
+    /// hexadecimal. Returns the metadata of the file, added to the tree as a remote entry.
+    /// Since protocol version 43.
+    async fn receive_finish(handle: u64, md5: String) -> crate::Result<Metadata>;
+
+    /// Memory used by the buffers of the transfers, `None` if it is not limited.
+    /// Since protocol version 44.
+    async fn buffer_usage() -> crate::Result<Option<BufferUsage>>;
+
+    /// Compare the content of the local and remote copies of the synchronized files
+    /// of the sub-tree at `path`, in tree order, starting after the file at `after`.
+    /// The local files are hashed, and compared to the checksums reported by the remote
+    /// storage, or, if `deep`, to the hash of the remote content, which is downloaded.
+    /// The files that differ become [`crate::Conflict::ContentMismatch`].
+    /// The page ends with the first file verified past `deadline`, and is recorded
+    /// as the [checkpoint](Fsync::verify_checkpoint) of the verification.
+    /// Since protocol version 45.
+    async fn verify(
+        path: PathBuf,
+        deep: bool,
+        after: Option<PathBuf>,
+        deadline: SystemTime,
+    ) -> crate::Result<VerificationReport>;
+
+    /// Where the last verification stopped, `None` if it verified the whole sub-tree.
+    /// Since protocol version 45.
+    async fn verify_checkpoint() -> crate::Result<Option<VerifyCheckpoint>>;
+
+    /// Same as `conflicts`, for the conflicts selected by `filter`.
+    /// The `max_len` conflicts are the first selected ones, so that the pages follow each other.
+    /// Since protocol version 47.
+    async fn conflicts_filtered(
+        first: Option<PathBuf>,
+        max_len: u32,
+        filter: crate::filter::EntryFilter,
+    ) -> crate::Result<Vec<tree::Entry>>;
+
+    /// The content transferred by the instance, by cause, since the daemon started
+    /// and over its lifetime.
+    /// Since protocol version 48.
+    async fn transfers() -> crate::Result<InstanceTransfers>;
+}
+
+#[cfg(test)]
+mod tests {
+    use tarpc::tokio_serde::{Deserializer, Serializer};
+
+    use super::*;
+
+    /// `Ok(Progress::Progress { progress: 3, total: 10 })` in its envelope
+    const PROGRESS: &[u8] = &[0, 3, 5, 3, 10];
+    /// `FsyncResponse::ProtocolVersion(1)`
+    const PROTOCOL_VERSION_V1: &[u8] = &[0, 1];
+    /// The remote directory "/a" with a child "b" and stats, as sent by a version 1 daemon
+    const ENTRY_NODE_V1: &[u8] = &[1, 0, 2, b'/', b'a', 1, 0, 0, 0, 1, 1, b'b', 6, 4, 2];
+    /// An `Ok` progress of a future version, variant 42 with a string payload
+    const PROGRESS_NEXT: &[u8] = &[0, 6, 42, 4, b'n', b'e', b'x', b't'];
+
+    fn decode<T: for<'de> Deserialize<'de>>(data: &[u8]) -> std::io::Result<T> {
+        let codec = std::pin::pin!(codec::<T, T>());
+        codec.deserialize(&data.into())
+    }
+
+    fn encode<T: Serialize>(item: &T) -> Vec<u8> {
+        let codec = std::pin::pin!(codec::<T, T>());
+        codec.serialize(item).unwrap().to_vec()
+    }
+
+    #[test]
+    fn wire_format_is_stable() {
+        let progress: crate::Result<Progress> = decode(PROGRESS).unwrap();
+        assert!(matches!(
+            progress,
+            Ok(Progress::Progress {
+                progress: 3,
+                total: 10
+            })
+        ));
+        let progress: crate::Result<Progress> = Ok(Progress::Progress {
+            progress: 3,
+            total: 10,
+        });
+        assert_eq!(encode(&progress), PROGRESS);
+
+        let resp: FsyncResponse = decode(PROTOCOL_VERSION_V1).unwrap();
+        assert!(matches!(resp, FsyncResponse::ProtocolVersion(1)));
+        assert_eq!(encode(&FsyncRequest::ProtocolVersion {}), &[0]);
+
+        // the entry nodes and the node stats of version 1, the flags are provided apart
+        let metadata = Metadata::Directory {
+            path: PathBuf::from("/a"),
+            stat: None,
+        };
+        let node_stat = stat::Node::null()
+            .with_nodes(3)
+            .with_sync(2)
+            .with_conflicts(1)
+            .with_too_large(1);
+        let children_stat = stat::Tree {
+            node: node_stat,
+            ..stat::Tree::null()
+        };
+        let node = tree::EntryNode::new(
+            tree::Entry::Remote(metadata),
+            vec!["b".to_string()],
+            children_stat,
+        )
+        .with_too_large(true)
+        .with_name_clash(true);
+        assert_eq!(encode(&node), ENTRY_NODE_V1);
+        assert_eq!(encode(&node_stat), &ENTRY_NODE_V1[12..]);
+        let decoded: tree::EntryNode = decode(ENTRY_NODE_V1).unwrap();
+        assert_eq!(decoded.entry(), node.entry());
+        assert_eq!(decoded.children().len(), 1);
+        assert_eq!(
+            decoded.stats().node,
+            stat::Node::null()
+                .with_nodes(4)
+                .with_sync(2)
+                .with_conflicts(1)
+        );
+    }
+
+    #[test]
+    fn unknown_progress_is_unsupported() {
+        let progress: crate::Result<Progress> = decode(PROGRESS_NEXT).unwrap();
+        assert!(matches!(progress, Ok(Progress::Unsupported)));
+    }
+
+    #[test]
+    fn unknown_progress_within_a_list() {
+        // Ok(vec![("a", <next>), ("b", Progress { progress: 3, total: 10 })])
+        let mut data = vec![0, 2, 1, b'a'];
+        data.extend_from_slice(&PROGRESS_NEXT[1..]);
+        data.extend_from_slice(&[1, b'b']);
+        data.extend_from_slice(&PROGRESS[1..]);
+
+        let list: crate::Result<Vec<(PathBuf, Progress)>> = decode(&data).unwrap();
+        let list = list.unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].0, PathBuf::from("a"));
+        assert!(matches!(list[0].1, Progress::Unsupported));
+        assert_eq!(list[1].0, PathBuf::from("b"));
+        assert!(matches!(
+            list[1].1,
+            Progress::Progress {
+                progress: 3,
+                total: 10
+            }
+        ));
+    }
+
+    #[test]
+    fn progress_json_has_no_envelope() {
+        let json = serde_json::to_string(&Progress::Progress {
+            progress: 3,
+            total: 10,
+        })
+        .unwrap();
+        assert_eq!(json, r#"{"progress":{"progress":3,"total":10}}"#);
+        let progress: Progress = serde_json::from_str(r#""somethingNew""#).unwrap();
+        assert!(matches!(progress, Progress::Unsupported));
+    }
+
+    #[test]
+    fn children_pages() {
+        use tree::{Children, CHILDREN_PAGE_LEN};
+
+        const LEN: usize = 200_000;
+        let name = |i: usize| format!("photo{i:06}.jpg");
+        let mut children = Children::new((0..LEN).rev().map(name).collect());
+        assert_eq!(children.len(), LEN);
+        assert!(children.iter().map(|n| n.as_str()).eq((0..LEN)
+            .map(name)
+            .collect::<Vec<_>>()
+            .iter()
+            .map(String::as_str)));
+
+        // the pages are shared by the clones
+        let clone = children.clone();
+        assert_eq!(clone, children);
+
+        // boundaries of the pages
+        for i in [0, CHILDREN_PAGE_LEN - 1, CHILDREN_PAGE_LEN, LEN - 1] {
+            let next: Vec<&String> = children.after(Some(&name(i))).take(2).collect();
+            let expected: Vec<String> = (i + 1..LEN.min(i + 3)).map(name).collect();
+            assert_eq!(next, expected.iter().collect::<Vec<_>>());
+        }
+        assert_eq!(children.after(None).next(), Some(&name(0)));
+        assert_eq!(children.after(Some("zzz")).next(), None);
+
+        // a page overflowing is split, an emptied page is dropped
+        for i in 0..CHILDREN_PAGE_LEN {
+            children.insert(format!("photo{:06}.jpg~", i));
+        }
+        children.insert(name(0));
+        assert_eq!(children.len(), LEN + CHILDREN_PAGE_LEN);
+        for i in 0..CHILDREN_PAGE_LEN {
+            children.remove(&name(i));
+            children.remove(&format!("photo{:06}.jpg~", i));
+        }
+        assert_eq!(children.len(), LEN - CHILDREN_PAGE_LEN);
+        assert_eq!(children.after(None).next(), Some(&name(CHILDREN_PAGE_LEN)));
+        assert!(children.contains(&name(LEN - 1)));
+        assert!(!children.contains(&name(0)));
+        assert_eq!(clone.len(), LEN);
+
+        // serialized as a flat list
+        let names = vec!["a".to_string(), "b".to_string()];
+        let children = Children::new(names.clone());
+        assert_eq!(encode(&children), encode(&names));
+        assert_eq!(decode::<Children>(&encode(&names)).unwrap(), ["a", "b"]);
+    }
+
+    #[test]
+    fn revision_default_dest() {
+        let revision = Revision {
+            id: "r1".into(),
+            mtime: DateTime::parse_from_rfc3339("2024-01-01T12:30:05Z")
+                .unwrap()
+                .into(),
+            size: Some(12),
+            author: None,
+        };
+        assert_eq!(
+            revision.default_dest(Path::new("/docs/notes.txt")),
+            Path::new("/docs/notes-20240101-123005.txt")
+        );
+        assert_eq!(
+            revision.default_dest(Path::new("/Makefile")),
+            Path::new("/Makefile-20240101-123005")
+        );
+    }
 }
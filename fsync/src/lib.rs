@@ -1,22 +1,35 @@
 #![allow(async_fn_in_trait)]
 
-use std::{cmp, fmt, str};
+use std::{cmp, str};
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use typescript_type_def::TypeDef;
 
+pub mod audit;
+pub mod caps;
+pub mod collate;
 pub mod config;
+pub mod envelope;
+pub mod filter;
+pub mod fmt;
 pub mod loc;
 pub mod oauth2;
+pub mod overlap;
+pub mod runtime;
+pub mod sleep;
+pub mod text;
 
 mod conflict;
 mod error;
 mod fsync;
 
 pub use crate::{
-    config::{Config, ProviderConfig},
-    conflict::Conflict,
+    config::{
+        Config, Deferral, DeleteGuard, Digest, Hook, HookEvent, HourRange, LogBuffer, Maintenance,
+        Mapping, MinFreeSpace, ProviderConfig, SecretsProtection, Smtp, SmtpSecurity, StatusFile,
+    },
+    conflict::{Conflict, ConflictDetail},
     error::*,
     fsync::*,
 };
@@ -24,7 +37,7 @@ pub use crate::{
 pub mod path;
 pub mod stat;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, TypeDef)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
 #[serde(rename_all = "camelCase")]
 pub enum StorageLoc {
     Local,
@@ -40,8 +53,8 @@ impl StorageLoc {
     }
 }
 
-impl fmt::Display for StorageLoc {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl std::fmt::Display for StorageLoc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             StorageLoc::Local => f.write_str("local drive"),
             StorageLoc::Remote => f.write_str("remote drive"),
@@ -79,8 +92,8 @@ impl StorageDir {
     }
 }
 
-impl fmt::Display for StorageDir {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl std::fmt::Display for StorageDir {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             StorageDir::LocalToRemote => f.write_str("local to remote drive"),
             StorageDir::RemoteToLocal => f.write_str("remote to local drive"),
@@ -105,8 +118,8 @@ impl From<StorageLoc> for Location {
     }
 }
 
-impl fmt::Display for Location {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Location::Local => f.write_str("local drive"),
             Location::Remote => f.write_str("remote drive"),
@@ -123,8 +136,8 @@ pub enum Provider {
     LocalFs,
 }
 
-impl fmt::Display for Provider {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl std::fmt::Display for Provider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Provider::GoogleDrive => f.write_str("Google Drive"),
             Provider::LocalFs => f.write_str("Local FileSystem"),
@@ -143,7 +156,36 @@ impl From<config::ProviderConfig> for Provider {
 
 /// Compares with second granularity as some providers do not provide milliseconds granularity
 pub fn compare_mtime(lhs: DateTime<Utc>, rhs: DateTime<Utc>) -> cmp::Ordering {
-    lhs.timestamp().cmp(&rhs.timestamp())
+    compare_mtime_within(lhs, rhs, 1)
+}
+
+/// Compares with a granularity of `granularity` seconds: the times that are less than
+/// `granularity` seconds apart are equal. Some filesystems (e.g. FAT) round the times
+/// to 2 seconds, and the exact time is lost when a file is copied there.
+pub fn compare_mtime_within(
+    lhs: DateTime<Utc>,
+    rhs: DateTime<Utc>,
+    granularity: u32,
+) -> cmp::Ordering {
+    let diff = lhs.timestamp() - rhs.timestamp();
+    if diff.abs() < granularity.max(1) as i64 {
+        cmp::Ordering::Equal
+    } else {
+        diff.cmp(&0)
+    }
+}
+
+/// Whether `lhs` and `rhs` differ by a whole number of hours, within `granularity` seconds,
+/// as when a filesystem that stores local times (e.g. FAT) is read across a DST or timezone change.
+pub fn is_timezone_shift(lhs: DateTime<Utc>, rhs: DateTime<Utc>, granularity: u32) -> bool {
+    const HOUR: i64 = 3600;
+    // timezone offsets range from -12h to +14h
+    const MAX_SHIFT_HOURS: i64 = 26;
+
+    let diff = (lhs.timestamp() - rhs.timestamp()).abs();
+    let hours = (diff + HOUR / 2) / HOUR;
+    let off = (diff - hours * HOUR).abs();
+    (1..=MAX_SHIFT_HOURS).contains(&hours) && off < granularity.max(1) as i64
 }
 
 pub fn compare_mtime_opt(
@@ -1,8 +1,67 @@
 //! Locations module
 
-/// Locations for the user
+/// Locations for the user.
+///
+/// The locations can be moved under a single root, given with `--config-dir` to fsyncd and fsynctl
+/// or with [`CONFIG_DIR_ENV`], e.g. for portable installs, tests or independent setups
+/// on the same account. The root has the `config`, `cache` and `runtime` sub-directories.
 pub mod user {
-    use crate::path::FsPathBuf;
+    use std::sync::OnceLock;
+
+    use crate::path::{FsPath, FsPathBuf};
+
+    /// Environment variable giving the root of the user locations, see [`init_root`]
+    pub const CONFIG_DIR_ENV: &str = "FSYNC_CONFIG_DIR";
+
+    /// The root of the user locations, `None` for the default locations of the platform
+    static ROOT: OnceLock<Option<FsPathBuf>> = OnceLock::new();
+
+    /// Set the root of the user locations to `dir`, given on the command line,
+    /// or else to the directory of [`CONFIG_DIR_ENV`].
+    /// The binaries call it before reading any location, the root can't change afterwards.
+    /// The root is created if needed, and must be absolute and writable.
+    pub fn init_root(dir: Option<FsPathBuf>) -> anyhow::Result<Option<&'static FsPath>> {
+        let dir = match dir {
+            Some(dir) => Some(dir),
+            None => match std::env::var_os(CONFIG_DIR_ENV) {
+                Some(dir) if !dir.is_empty() => {
+                    Some(FsPathBuf::try_from(std::path::PathBuf::from(dir))?)
+                }
+                _ => None,
+            },
+        };
+        if let Some(dir) = &dir {
+            check_root(dir)?;
+        }
+        if let Err(dir) = ROOT.set(dir) {
+            if ROOT.get() != Some(&dir) {
+                anyhow::bail!("The config root is already set to another directory");
+            }
+        }
+        Ok(ROOT.get().and_then(Option::as_deref))
+    }
+
+    fn check_root(dir: &FsPath) -> anyhow::Result<()> {
+        if !dir.is_absolute() {
+            anyhow::bail!("The config root must be an absolute path: {dir}");
+        }
+        std::fs::create_dir_all(dir)
+            .map_err(|err| anyhow::anyhow!("Can't create the config root {dir}: {err}"))?;
+        let probe = dir.join(format!(".write-probe-{}", std::process::id()));
+        std::fs::write(&probe, b"")
+            .map_err(|err| anyhow::anyhow!("The config root {dir} is not writable: {err}"))?;
+        let _ = std::fs::remove_file(&probe);
+        Ok(())
+    }
+
+    /// The root of the user locations, if overridden.
+    /// Initialized from the environment if the binary did not call [`init_root`].
+    pub fn root() -> anyhow::Result<Option<&'static FsPath>> {
+        match ROOT.get() {
+            Some(root) => Ok(root.as_deref()),
+            None => init_root(None),
+        }
+    }
 
     pub fn home_dir() -> anyhow::Result<FsPathBuf> {
         let dir = dirs::home_dir().ok_or_else(|| anyhow::anyhow!("Can't get HOME directory"))?;
@@ -11,11 +70,17 @@ pub mod user {
 
     #[cfg(target_os = "windows")]
     pub fn runtime_dir() -> anyhow::Result<FsPathBuf> {
+        if let Some(root) = root()? {
+            return Ok(root.join("runtime"));
+        }
         cache_dir()
     }
 
     #[cfg(not(target_os = "windows"))]
     pub fn runtime_dir() -> anyhow::Result<FsPathBuf> {
+        if let Some(root) = root()? {
+            return Ok(root.join("runtime"));
+        }
         let dir = dirs::runtime_dir()
             .ok_or_else(|| anyhow::anyhow!("Can't get the user runtime directory"))?;
         let dir = FsPathBuf::try_from(dir)?;
@@ -23,6 +88,9 @@ pub mod user {
     }
 
     pub fn config_dir() -> anyhow::Result<FsPathBuf> {
+        if let Some(root) = root()? {
+            return Ok(root.join("config"));
+        }
         let dir =
             dirs::config_dir().ok_or_else(|| anyhow::anyhow!("Can't get config directory"))?;
         let dir = FsPathBuf::try_from(dir)?;
@@ -30,6 +98,9 @@ pub mod user {
     }
 
     pub fn cache_dir() -> anyhow::Result<FsPathBuf> {
+        if let Some(root) = root()? {
+            return Ok(root.join("cache"));
+        }
         let dir = dirs::cache_dir().ok_or_else(|| anyhow::anyhow!("Can't get cache directory"))?;
         let dir = FsPathBuf::try_from(dir)?;
         Ok(dir.join("fsync"))
@@ -43,6 +114,11 @@ pub mod inst {
         Ok(super::user::runtime_dir()?.join(format!("{instance_name}.port")))
     }
 
+    /// File of the token that clients present to the daemon, only readable by the user
+    pub fn runtime_token_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(super::user::runtime_dir()?.join(format!("{instance_name}.token")))
+    }
+
     pub fn config_dir(instance_name: &str) -> anyhow::Result<FsPathBuf> {
         Ok(super::user::config_dir()?.join(instance_name))
     }
@@ -51,6 +127,21 @@ pub mod inst {
         Ok(config_dir(instance_name)?.join("config.json"))
     }
 
+    /// Paths pinned by the user, never deleted nor overwritten
+    pub fn pins_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(config_dir(instance_name)?.join("pins.json"))
+    }
+
+    /// Remote-only entries for which a local placeholder was created
+    pub fn placeholders_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(config_dir(instance_name)?.join("placeholders.json"))
+    }
+
+    /// Capabilities of the filesystem of the local directory
+    pub fn fs_caps_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(config_dir(instance_name)?.join("fs_caps.json"))
+    }
+
     pub fn oauth_secret_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
         Ok(config_dir(instance_name)?.join("client_secret.json"))
     }
@@ -63,7 +154,68 @@ pub mod inst {
         Ok(cache_dir(instance_name)?.join("token_cache.json"))
     }
 
+    /// Cached content of remote files
+    pub fn content_cache_dir(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(cache_dir(instance_name)?.join("content"))
+    }
+
+    /// The versions of the text files last synchronized, used as base to merge their conflicts
+    pub fn merge_bases_dir(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(cache_dir(instance_name)?.join("merge-bases"))
+    }
+
+    /// Files set aside before being deleted or overwritten
+    pub fn quarantine_dir(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(cache_dir(instance_name)?.join("quarantine"))
+    }
+
+    /// Files being written to the caches
+    pub fn temp_dir(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(cache_dir(instance_name)?.join("tmp"))
+    }
+
     pub fn remote_cache_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
         Ok(cache_dir(instance_name)?.join("remote.bin"))
     }
+
+    /// Log of the actions performed on the storages, see [`crate::audit`]
+    pub fn audit_log_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(cache_dir(instance_name)?.join("audit.jsonl"))
+    }
+
+    /// Write-ahead journal of the unit operations, see the `journal` module of fsyncd
+    pub fn journal_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(cache_dir(instance_name)?.join("journal.jsonl"))
+    }
+
+    /// Conflicts reported by the last delivered digest, see [`crate::Digest`]
+    pub fn digest_state_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(cache_dir(instance_name)?.join("digest.json"))
+    }
+
+    /// Checksums of the local files, see the `storage::hash` module of fsyncd
+    pub fn checksums_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(cache_dir(instance_name)?.join("checksums.json"))
+    }
+
+    /// Lifetime activity counters, see the `counters` module of fsyncd
+    pub fn counters_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(cache_dir(instance_name)?.join("counters.json"))
+    }
+
+    /// The entries left by a scheduled deep operation stopped by its time budget,
+    /// see the `checkpoint` module of fsyncd
+    pub fn checkpoint_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(cache_dir(instance_name)?.join("checkpoint.json"))
+    }
+
+    /// Where the last verification of the content stopped, see the `checkpoint` module of fsyncd
+    pub fn verify_checkpoint_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(cache_dir(instance_name)?.join("verify-checkpoint.json"))
+    }
+
+    /// The remote root the local directory is synchronized with, see the `root` module of fsyncd
+    pub fn remote_root_file(instance_name: &str) -> anyhow::Result<FsPathBuf> {
+        Ok(cache_dir(instance_name)?.join("root.json"))
+    }
 }
@@ -1,4 +1,9 @@
-pub use oauth2::{AuthUrl, ClientId, ClientSecret, TokenUrl};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+pub use oauth2::{AuthUrl, ClientId, ClientSecret, DeviceAuthorizationUrl, TokenUrl};
 use serde::{Deserialize, Serialize};
 use tokio::fs;
 
@@ -10,6 +15,82 @@ pub struct Secret {
     pub client_secret: ClientSecret,
     pub auth_url: AuthUrl,
     pub token_url: TokenUrl,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_auth_url: Option<DeviceAuthorizationUrl>,
+}
+
+pub const GOOGLE_DEVICE_AUTH_URL: &str = "https://oauth2.googleapis.com/device/code";
+
+/// The OAuth2 flow used to obtain a token interactively
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Flow {
+    /// Device code flow if no display is detected and the provider supports it, PKCE otherwise
+    #[default]
+    Auto,
+    /// Installed application flow with PKCE. Needs a local browser.
+    Pkce,
+    /// Device authorization grant. The user enters a code on any device with a browser.
+    DeviceCode,
+}
+
+/// The local server to which the browser is redirected at the end of the PKCE flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RedirectServer {
+    /// Address the server binds to, e.g. `0.0.0.0` to be reachable through
+    /// the port forwarding of a container or of WSL
+    #[serde(default = "RedirectServer::default_address")]
+    pub address: IpAddr,
+    /// Port the server listens on, or 0 for any free port
+    #[serde(default)]
+    pub port: u16,
+    /// Seconds to wait for the browser to be redirected before abandoning the authorization
+    #[serde(default = "RedirectServer::default_timeout")]
+    pub timeout: u64,
+}
+
+impl RedirectServer {
+    fn default_address() -> IpAddr {
+        Ipv4Addr::LOCALHOST.into()
+    }
+
+    fn default_timeout() -> u64 {
+        300
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn bind_addr(&self) -> SocketAddr {
+        (self.address, self.port).into()
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    /// The URL of the browser redirection to the server listening on `addr`.
+    /// A server bound to all the interfaces is reached through the loopback address.
+    pub fn redirect_url(addr: SocketAddr) -> String {
+        match addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => {
+                format!("http://{}:{}", Ipv4Addr::LOCALHOST, addr.port())
+            }
+            IpAddr::V6(ip) if ip.is_unspecified() => format!("http://[::1]:{}", addr.port()),
+            _ => format!("http://{addr}"),
+        }
+    }
+}
+
+impl Default for RedirectServer {
+    fn default() -> Self {
+        Self {
+            address: Self::default_address(),
+            port: 0,
+            timeout: Self::default_timeout(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -27,6 +108,9 @@ pub async fn load_google_secret(path: &FsPath) -> anyhow::Result<Secret> {
             client_secret: ClientSecret::new(secret.client_secret),
             auth_url: AuthUrl::new(secret.auth_uri)?,
             token_url: TokenUrl::new(secret.token_uri)?,
+            device_auth_url: Some(DeviceAuthorizationUrl::new(
+                GOOGLE_DEVICE_AUTH_URL.to_string(),
+            )?),
         }),
         GoogleAppSecret::Web(_) => anyhow::bail!(
             "Wrong kind of secret file. Please get a secret file with an \"installed\" field"
@@ -57,6 +141,25 @@ enum GoogleAppSecret {
     Web(GoogleSecret),
 }
 
+#[test]
+fn test_redirect_server() -> anyhow::Result<()> {
+    let server: RedirectServer = serde_json::from_str("{}")?;
+    assert!(server.is_default());
+    assert_eq!(server.bind_addr(), SocketAddr::from(([127, 0, 0, 1], 0)));
+
+    let server: RedirectServer = serde_json::from_str(r#"{"address": "0.0.0.0", "port": 8085}"#)?;
+    assert_eq!(server.timeout(), Duration::from_secs(300));
+    assert_eq!(
+        RedirectServer::redirect_url(server.bind_addr()),
+        "http://127.0.0.1:8085"
+    );
+    assert_eq!(
+        RedirectServer::redirect_url(SocketAddr::from(([192, 168, 1, 2], 8085))),
+        "http://192.168.1.2:8085"
+    );
+    Ok(())
+}
+
 #[test]
 fn test_google_secret_serialization() -> anyhow::Result<()> {
     let secret = GoogleAppSecret::Installed(GoogleSecret {
@@ -0,0 +1,205 @@
+//! Detection of the instances that synchronize overlapping remote roots.
+//!
+//! Each daemon records the remote root of its instance along with the folders containing it,
+//! see [`RecordedRoot::ancestry`]. When the root of an instance is the root of another one,
+//! or is inside it, both daemons write the same remote files and race with each other.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    loc::{inst, user},
+    RemoteRoot,
+};
+
+/// The remote root recorded by the daemon of an instance, in [`inst::remote_root_file`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedRoot {
+    #[serde(flatten)]
+    pub root: RemoteRoot,
+    /// The ids of the root folder and of the folders containing it, up to the top of its drive
+    /// (the folder of My Drive, or the shared drive). Unlike the id of the root,
+    /// the first one is never an alias. Empty if they could not be resolved,
+    /// and in the records written before they were.
+    #[serde(default)]
+    pub ancestry: Vec<String>,
+}
+
+impl RecordedRoot {
+    /// The canonical id of the root folder
+    pub fn key(&self) -> &str {
+        self.ancestry.first().unwrap_or(&self.root.id)
+    }
+}
+
+impl std::fmt::Display for RecordedRoot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.root.fmt(f)
+    }
+}
+
+/// Where the remote root of another instance is, relatively to the root of an instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Nesting {
+    /// Both instances synchronize the same folder
+    Same,
+    /// The other root contains the root of the instance
+    Contains,
+    /// The other root is inside the root of the instance
+    Inside,
+}
+
+/// The remote root of another instance, that overlaps with the root of an instance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Overlap {
+    /// Name of the other instance
+    pub instance: String,
+    /// The remote root recorded by the other instance
+    pub root: RemoteRoot,
+    pub nesting: Nesting,
+}
+
+impl std::fmt::Display for Overlap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let nesting = match self.nesting {
+            Nesting::Same => "the same remote folder",
+            Nesting::Contains => "a remote folder that contains this one",
+            Nesting::Inside => "a remote folder inside this one",
+        };
+        write!(
+            f,
+            "instance {} synchronizes {nesting}: {}",
+            self.instance, self.root
+        )
+    }
+}
+
+/// Where `other` is relatively to `root`, or `None` if they don't overlap
+pub fn nesting(root: &RecordedRoot, other: &RecordedRoot) -> Option<Nesting> {
+    if root.root.id == other.root.id || root.key() == other.key() {
+        Some(Nesting::Same)
+    } else if other.ancestry.iter().skip(1).any(|id| id == root.key()) {
+        Some(Nesting::Inside)
+    } else if root.ancestry.iter().skip(1).any(|id| id == other.key()) {
+        Some(Nesting::Contains)
+    } else {
+        None
+    }
+}
+
+/// The remote root recorded by the daemon of `instance_name`, if it started once
+pub fn recorded_root(instance_name: &str) -> anyhow::Result<Option<RecordedRoot>> {
+    let path = inst::remote_root_file(instance_name)?;
+    match std::fs::read(&path) {
+        Ok(data) => Ok(serde_json::from_slice(&data).ok()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The roots recorded by the other instances of the user that overlap with `root`,
+/// the remote root of `instance_name`
+pub fn overlaps(instance_name: &str, root: &RecordedRoot) -> anyhow::Result<Vec<Overlap>> {
+    let config_dir = user::config_dir()?;
+    if !config_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut overlaps = Vec::new();
+    for entry in config_dir.read_dir_utf8()? {
+        let entry = entry?;
+        let name = entry.file_name();
+        // the instances being created start with a dot
+        if name == instance_name || name.starts_with('.') || !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let Some(other) = recorded_root(name)? else {
+            continue;
+        };
+        if let Some(nesting) = nesting(root, &other) {
+            overlaps.push(Overlap {
+                instance: name.to_owned(),
+                root: other.root,
+                nesting,
+            });
+        }
+    }
+    overlaps.sort_by(|a, b| a.instance.cmp(&b.instance));
+    Ok(overlaps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root(id: &str, ancestry: &[&str]) -> RecordedRoot {
+        RecordedRoot {
+            root: RemoteRoot {
+                id: id.to_string(),
+                config: id.to_string(),
+            },
+            ancestry: ancestry.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn nested_roots() {
+        // "root" is the alias of the folder of My Drive
+        let my_drive = root("root", &["mydrive"]);
+        let photos = root("photos", &["photos", "mydrive"]);
+        let trips = root("trips", &["trips", "photos", "mydrive"]);
+        let work = root("work", &["work", "mydrive"]);
+
+        assert_eq!(nesting(&my_drive, &photos), Some(Nesting::Inside));
+        assert_eq!(nesting(&photos, &my_drive), Some(Nesting::Contains));
+        assert_eq!(nesting(&trips, &my_drive), Some(Nesting::Contains));
+        assert_eq!(nesting(&photos, &trips), Some(Nesting::Inside));
+        assert_eq!(nesting(&photos, &work), None);
+        assert_eq!(
+            nesting(&my_drive, &root("mydrive", &["mydrive"])),
+            Some(Nesting::Same)
+        );
+    }
+
+    #[test]
+    fn shared_drive_roots() {
+        // the chain of a shared drive ends at the drive id
+        let drive = root("0Adrive", &["0Adrive"]);
+        let folder = root("folder", &["folder", "0Adrive"]);
+        let my_drive = root("root", &["mydrive"]);
+
+        assert_eq!(nesting(&drive, &folder), Some(Nesting::Inside));
+        assert_eq!(nesting(&my_drive, &folder), None);
+        assert_eq!(nesting(&my_drive, &drive), None);
+    }
+
+    #[test]
+    fn records_without_ancestry() {
+        let record: RecordedRoot =
+            serde_json::from_str(r#"{"id":"photos","config":"/Photos"}"#).unwrap();
+        assert!(record.ancestry.is_empty());
+        assert_eq!(record.key(), "photos");
+
+        let record = root("photos", &["photos", "mydrive"]);
+        let json = serde_json::to_string(&record).unwrap();
+        assert_eq!(serde_json::from_str::<RecordedRoot>(&json).unwrap(), record);
+        // the root is still readable without its ancestry
+        assert_eq!(
+            serde_json::from_str::<RemoteRoot>(&json).unwrap(),
+            record.root
+        );
+    }
+
+    #[test]
+    fn unresolved_ancestry() {
+        // recorded before the ancestry was resolved: only the same id is detected
+        let old = root("photos", &[]);
+        assert_eq!(
+            nesting(&old, &root("photos", &["photos", "mydrive"])),
+            Some(Nesting::Same)
+        );
+        assert_eq!(nesting(&old, &root("root", &["mydrive"])), None);
+        assert_eq!(nesting(&root("root", &["mydrive"]), &old), None);
+    }
+}
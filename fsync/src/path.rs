@@ -9,6 +9,8 @@ pub use camino::{Utf8Path as FsPath, Utf8PathBuf as FsPathBuf};
 use serde::{Deserialize, Serialize};
 use typescript_type_def::{type_expr, TypeDef};
 
+pub mod glob;
+
 /// Error of normalization
 #[derive(Clone, Debug)]
 pub struct NormalizeError(pub PathBuf);
@@ -488,7 +490,7 @@ impl Path {
     /// the current directory.
     ///
     /// * A path is absolute if it starts with the root, so
-    /// `is_absolute` and [`has_root`] are equivalent.
+    ///   `is_absolute` and [`has_root`] are equivalent.
     ///
     /// # Examples
     ///
@@ -856,6 +858,28 @@ impl Path {
         Ok(res)
     }
 
+    /// Checks whether the path is in the form returned by [`Path::normalize`]:
+    /// without `.` nor `..` components, repeated separators or trailing separator.
+    ///
+    /// # Examples
+    /// ```
+    /// use fsync::path::Path;
+    ///
+    /// assert!(Path::new("/").is_normalized());
+    /// assert!(Path::new("/some/path").is_normalized());
+    /// assert!(!Path::new("/some//path").is_normalized());
+    /// assert!(!Path::new("/some/path/").is_normalized());
+    /// assert!(!Path::new("/some/./path").is_normalized());
+    /// ```
+    pub fn is_normalized(&self) -> bool {
+        let bytes = self.inner.as_bytes();
+        let body = if has_root(bytes) { &bytes[1..] } else { bytes };
+        body.is_empty()
+            || body
+                .split(|&b| is_sep_byte(b))
+                .all(|c| !c.is_empty() && c != b"." && c != b"..")
+    }
+
     /// Checks whether self is an ancestor of the other path
     ///
     /// # Examples
@@ -883,7 +907,7 @@ impl Path {
             }
             otherc = other.next();
         }
-        return otherc.is_some();
+        otherc.is_some()
     }
 }
 
@@ -1552,3 +1576,258 @@ impl TypeDef for PathBuf {
         r#ref: type_expr::TypeExpr::ident(type_expr::Ident("string")),
     });
 }
+
+/// A [`Path`] known to be normalized, see [`Path::is_normalized`].
+///
+/// Normalized paths have a single representation, so they are compared and hashed
+/// on their bytes, without parsing their components.
+/// The ordering is the same as the one of [`Path`].
+#[repr(transparent)]
+pub struct NormalizedPath {
+    inner: Path,
+}
+
+impl NormalizedPath {
+    /// `path` as a normalized path, or `None` if it isn't normalized
+    pub fn new(path: &Path) -> Option<&NormalizedPath> {
+        path.is_normalized()
+            .then(|| unsafe { &*(path as *const Path as *const NormalizedPath) })
+    }
+
+    pub fn as_path(&self) -> &Path {
+        &self.inner
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        self.inner.inner.as_bytes()
+    }
+}
+
+/// Compare the bytes of two normalized paths.
+/// The separator sorts before any other byte, so that a component sorts before
+/// the longer ones it is a prefix of, as in the component-wise comparison.
+fn compare_normalized(left: &[u8], right: &[u8]) -> cmp::Ordering {
+    match left.iter().zip(right).position(|(a, b)| a != b) {
+        None => left.len().cmp(&right.len()),
+        Some(diff) => {
+            let key = |b: u8| if is_sep_byte(b) { 0 } else { b as u16 + 1 };
+            key(left[diff]).cmp(&key(right[diff]))
+        }
+    }
+}
+
+impl ops::Deref for NormalizedPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl AsRef<Path> for NormalizedPath {
+    fn as_ref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl fmt::Debug for NormalizedPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+impl fmt::Display for NormalizedPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+impl PartialEq for NormalizedPath {
+    #[inline]
+    fn eq(&self, other: &NormalizedPath) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for NormalizedPath {}
+
+impl hash::Hash for NormalizedPath {
+    #[inline]
+    fn hash<H: hash::Hasher>(&self, h: &mut H) {
+        self.as_bytes().hash(h)
+    }
+}
+
+impl PartialOrd for NormalizedPath {
+    #[inline]
+    fn partial_cmp(&self, other: &NormalizedPath) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NormalizedPath {
+    #[inline]
+    fn cmp(&self, other: &NormalizedPath) -> cmp::Ordering {
+        compare_normalized(self.as_bytes(), other.as_bytes())
+    }
+}
+
+impl ToOwned for NormalizedPath {
+    type Owned = NormalizedPathBuf;
+    #[inline]
+    fn to_owned(&self) -> NormalizedPathBuf {
+        NormalizedPathBuf {
+            inner: self.inner.to_path_buf(),
+        }
+    }
+}
+
+/// An owned [`NormalizedPath`]
+#[derive(Clone, Default)]
+pub struct NormalizedPathBuf {
+    inner: PathBuf,
+}
+
+impl NormalizedPathBuf {
+    /// Normalize `path`, which is not copied if it is already normalized
+    pub fn new(path: PathBuf) -> Result<Self, NormalizeError> {
+        let inner = if path.is_normalized() {
+            path
+        } else {
+            path.normalize()?
+        };
+        Ok(Self { inner })
+    }
+
+    pub fn as_normalized_path(&self) -> &NormalizedPath {
+        unsafe { &*(self.inner.as_path() as *const Path as *const NormalizedPath) }
+    }
+
+    pub fn into_path_buf(self) -> PathBuf {
+        self.inner
+    }
+}
+
+impl ops::Deref for NormalizedPathBuf {
+    type Target = NormalizedPath;
+
+    fn deref(&self) -> &NormalizedPath {
+        self.as_normalized_path()
+    }
+}
+
+impl borrow::Borrow<NormalizedPath> for NormalizedPathBuf {
+    fn borrow(&self) -> &NormalizedPath {
+        self.as_normalized_path()
+    }
+}
+
+// compared as the borrowed form, as required by `Borrow`
+impl PartialEq for NormalizedPathBuf {
+    #[inline]
+    fn eq(&self, other: &NormalizedPathBuf) -> bool {
+        self.as_normalized_path() == other.as_normalized_path()
+    }
+}
+
+impl Eq for NormalizedPathBuf {}
+
+impl hash::Hash for NormalizedPathBuf {
+    #[inline]
+    fn hash<H: hash::Hasher>(&self, h: &mut H) {
+        self.as_normalized_path().hash(h)
+    }
+}
+
+impl PartialOrd for NormalizedPathBuf {
+    #[inline]
+    fn partial_cmp(&self, other: &NormalizedPathBuf) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NormalizedPathBuf {
+    #[inline]
+    fn cmp(&self, other: &NormalizedPathBuf) -> cmp::Ordering {
+        self.as_normalized_path().cmp(other.as_normalized_path())
+    }
+}
+
+impl AsRef<Path> for NormalizedPathBuf {
+    fn as_ref(&self) -> &Path {
+        self.inner.as_path()
+    }
+}
+
+impl From<NormalizedPathBuf> for PathBuf {
+    fn from(path: NormalizedPathBuf) -> PathBuf {
+        path.into_path_buf()
+    }
+}
+
+impl fmt::Debug for NormalizedPathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+impl fmt::Display for NormalizedPathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Normalized paths, with names chosen to share prefixes
+    /// and to contain bytes on both sides of the separator
+    fn normalized_path() -> impl Strategy<Value = PathBuf> {
+        let name = prop::string::string_regex("[a-c.\\-+ ]{1,4}")
+            .unwrap()
+            .prop_filter("not a dot component", |n| n != "." && n != "..");
+        (any::<bool>(), prop::collection::vec(name, 0..5)).prop_map(|(absolute, names)| {
+            let mut path = if absolute || names.is_empty() {
+                PathBuf::root()
+            } else {
+                PathBuf::new()
+            };
+            for name in names {
+                path.push(name);
+            }
+            path
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn normalized_ordering_agrees(a in normalized_path(), b in normalized_path()) {
+            let na = NormalizedPath::new(&a).unwrap();
+            let nb = NormalizedPath::new(&b).unwrap();
+            prop_assert_eq!(na.cmp(nb), a.cmp(&b));
+            prop_assert_eq!(na == nb, a == b);
+        }
+
+        #[test]
+        fn normalized_buf_is_borrowed_consistently(a in normalized_path(), b in normalized_path()) {
+            use std::hash::{BuildHasher, RandomState};
+
+            let state = RandomState::new();
+            let na = NormalizedPath::new(&a).unwrap();
+            let nb = NormalizedPathBuf::new(b.clone()).unwrap();
+            prop_assert_eq!(state.hash_one(na), state.hash_one(na.to_owned()));
+            prop_assert_eq!(na.cmp(&nb), na.to_owned().cmp(&nb));
+        }
+
+        #[test]
+        fn normalized_buf_is_normalize(a in normalized_path(), b in normalized_path()) {
+            let path = a.join(".").join(b.as_str().trim_start_matches('/'));
+            let normalized = NormalizedPathBuf::new(path.clone()).unwrap();
+            prop_assert!(normalized.is_normalized());
+            prop_assert_eq!(normalized.into_path_buf(), path.normalize().unwrap());
+        }
+    }
+}
@@ -0,0 +1,612 @@
+//! Glob patterns over repository paths, shared by the features that select entries by pattern,
+//! such as the ignore rules.
+//!
+//! The syntax is the one of gitignore patterns:
+//!  - `*` matches any sequence of characters within a component, never a separator
+//!  - `?` matches a single character, never a separator
+//!  - `[abc]` and `[a-z]` match a character of the class, `[!a-z]` and `[^a-z]` one out of it
+//!  - `**` as a whole component matches any number of components, `a/**` matches
+//!    the entries under `a` but not `a` itself
+//!  - `\` escapes the next character
+//!  - a leading `/` anchors the pattern to the base directory, as does a `/` in the middle,
+//!    otherwise the pattern matches the entry name at any depth
+//!  - a trailing `/` restricts the pattern to directories
+//!  - a leading `!` negates the pattern
+//!
+//! The characters are compared on their code points, without case folding nor locale,
+//! so that a pattern selects the same entries on every system.
+//! When several globs of a [`PathGlobSet`] match an entry, the last one wins.
+
+use std::{fmt, iter::Peekable, str::Chars};
+
+use super::{Component, Path, PathBuf};
+
+/// Error of a pattern that can't be compiled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobError {
+    pub pattern: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for GlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid pattern \"{}\": {}", self.pattern, self.reason)
+    }
+}
+
+impl std::error::Error for GlobError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    /// `*`
+    Any,
+    /// `?`
+    One,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Token {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Char(t) => *t == c,
+            Token::Any | Token::One => true,
+            Token::Class { negated, ranges } => {
+                ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated
+            }
+        }
+    }
+}
+
+/// A component of a pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Wild(Vec<Token>),
+    /// `**`
+    AnyPath,
+}
+
+impl Segment {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Segment::Literal(lit) => lit == name,
+            Segment::Wild(tokens) => match_name(tokens, name),
+            Segment::AnyPath => true,
+        }
+    }
+}
+
+/// A compiled glob pattern
+#[derive(Debug, Clone)]
+pub struct Glob {
+    pattern: String,
+    base: PathBuf,
+    segments: Vec<Segment>,
+    negated: bool,
+    dir_only: bool,
+}
+
+impl Glob {
+    /// Compile `pattern`, anchored to the root
+    pub fn new(pattern: &str) -> Result<Self, GlobError> {
+        Self::with_base(Path::root(), pattern)
+    }
+
+    /// Compile `pattern`, anchored to the `base` directory.
+    /// The glob only matches the entries under `base`.
+    pub fn with_base(base: &Path, pattern: &str) -> Result<Self, GlobError> {
+        let error = |reason| GlobError {
+            pattern: pattern.to_string(),
+            reason,
+        };
+        let (negated, rest) = match pattern.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let (dir_only, rest) = match rest.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let anchored = rest.contains('/');
+        let rest = rest.strip_prefix('/').unwrap_or(rest);
+        if rest.is_empty() {
+            return Err(error("empty pattern"));
+        }
+
+        let mut segments = Vec::new();
+        if !anchored {
+            segments.push(Segment::AnyPath);
+        }
+        for comp in rest.split('/') {
+            let segment = match comp {
+                "" => return Err(error("empty component")),
+                "**" => Segment::AnyPath,
+                comp => parse_segment(comp).map_err(error)?,
+            };
+            if segment == Segment::AnyPath && segments.last() == Some(&Segment::AnyPath) {
+                continue;
+            }
+            segments.push(segment);
+        }
+        if segments.last() == Some(&Segment::AnyPath) {
+            // at least one component under the directory
+            segments.push(Segment::Wild(vec![Token::Any]));
+        }
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            base: base.to_owned(),
+            segments,
+            negated,
+            dir_only,
+        })
+    }
+
+    /// The pattern as written
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn base(&self) -> &Path {
+        &self.base
+    }
+
+    pub fn is_negated(&self) -> bool {
+        self.negated
+    }
+
+    pub fn is_dir_only(&self) -> bool {
+        self.dir_only
+    }
+
+    /// Whether the pattern matches the entry at `path`, regardless of the negation
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        let names: Vec<&str> = normal_names(path).collect();
+        self.matches_names(&names, is_dir)
+    }
+
+    fn matches_names(&self, names: &[&str], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let rel = if self.base.is_root() {
+            names
+        } else {
+            let mut names = names.iter();
+            for name in normal_names(&self.base) {
+                if names.next() != Some(&name) {
+                    return false;
+                }
+            }
+            names.as_slice()
+        };
+        match (&self.segments[..], rel) {
+            (_, []) => false,
+            // the common unanchored pattern of a name
+            ([Segment::AnyPath, segment], [.., name]) if *segment != Segment::AnyPath => {
+                segment.matches(name)
+            }
+            (segments, rel) => match_segments(segments, rel),
+        }
+    }
+}
+
+impl fmt::Display for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// An ordered set of globs, where the last matching glob wins
+#[derive(Debug, Clone, Default)]
+pub struct PathGlobSet {
+    globs: Vec<Glob>,
+}
+
+impl PathGlobSet {
+    /// Compile `patterns`, anchored to the root
+    pub fn new<I>(patterns: I) -> Result<Self, GlobError>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        patterns
+            .into_iter()
+            .map(|pattern| Glob::new(pattern.as_ref()))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.globs.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.globs.len()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Glob> {
+        self.globs.iter()
+    }
+
+    /// Add `glob`, with a higher precedence than the current ones
+    pub fn push(&mut self, glob: Glob) {
+        self.globs.push(glob);
+    }
+
+    /// The last glob that matches the entry at `path`, negated or not
+    pub fn last_match(&self, path: &Path, is_dir: bool) -> Option<&Glob> {
+        let names: Vec<&str> = normal_names(path).collect();
+        self.globs
+            .iter()
+            .rev()
+            .find(|glob| glob.matches_names(&names, is_dir))
+    }
+
+    /// Whether the entry at `path` is selected by the set:
+    /// a glob matches it and the last one is not negated
+    pub fn is_match(&self, path: &Path, is_dir: bool) -> bool {
+        self.last_match(path, is_dir)
+            .is_some_and(|glob| !glob.is_negated())
+    }
+}
+
+impl FromIterator<Glob> for PathGlobSet {
+    fn from_iter<I: IntoIterator<Item = Glob>>(iter: I) -> Self {
+        Self {
+            globs: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl Extend<Glob> for PathGlobSet {
+    fn extend<I: IntoIterator<Item = Glob>>(&mut self, iter: I) {
+        self.globs.extend(iter);
+    }
+}
+
+impl<'a> IntoIterator for &'a PathGlobSet {
+    type Item = &'a Glob;
+    type IntoIter = std::slice::Iter<'a, Glob>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+fn normal_names(path: &Path) -> impl Iterator<Item = &str> {
+    path.components().filter_map(|comp| match comp {
+        Component::Normal(name) => Some(name),
+        _ => None,
+    })
+}
+
+fn parse_segment(comp: &str) -> Result<Segment, &'static str> {
+    let mut tokens = Vec::new();
+    let mut chars = comp.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '\\' => Token::Char(chars.next().ok_or("trailing escape")?),
+            '*' => {
+                if tokens.last() == Some(&Token::Any) {
+                    continue;
+                }
+                Token::Any
+            }
+            '?' => Token::One,
+            '[' => parse_class(&mut chars)?,
+            c => Token::Char(c),
+        };
+        tokens.push(token);
+    }
+    if tokens.iter().all(|t| matches!(t, Token::Char(_))) {
+        let literal = tokens
+            .iter()
+            .map(|t| match t {
+                Token::Char(c) => *c,
+                _ => unreachable!(),
+            })
+            .collect();
+        Ok(Segment::Literal(literal))
+    } else {
+        Ok(Segment::Wild(tokens))
+    }
+}
+
+/// Parse a class after its opening `[`
+fn parse_class(chars: &mut Peekable<Chars>) -> Result<Token, &'static str> {
+    let negated = chars.next_if(|c| matches!(c, '!' | '^')).is_some();
+    let mut ranges = Vec::new();
+    let mut first = true;
+    loop {
+        let lo = match chars.next() {
+            None => return Err("unterminated character class"),
+            Some(']') if !first => break,
+            Some('\\') => chars.next().ok_or("trailing escape")?,
+            Some(c) => c,
+        };
+        first = false;
+        let mut lookahead = chars.clone();
+        let hi = match (lookahead.next(), lookahead.next()) {
+            (Some('-'), Some(hi)) if hi != ']' => {
+                chars.next();
+                chars.next();
+                if hi == '\\' {
+                    chars.next().ok_or("trailing escape")?
+                } else {
+                    hi
+                }
+            }
+            _ => lo,
+        };
+        if hi < lo {
+            return Err("reversed range in character class");
+        }
+        ranges.push((lo, hi));
+    }
+    Ok(Token::Class { negated, ranges })
+}
+
+/// Match the tokens of a component against a name.
+/// On a mismatch, the last `*` absorbs one more character, as with the segments.
+fn match_name(tokens: &[Token], name: &str) -> bool {
+    let (mut t, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < tokens.len() || n < name.len() {
+        if let Some(token) = tokens.get(t) {
+            if *token == Token::Any {
+                star = Some((t + 1, n));
+                t += 1;
+                continue;
+            }
+            if let Some(c) = name[n..].chars().next() {
+                if token.matches(c) {
+                    t += 1;
+                    n += c.len_utf8();
+                    continue;
+                }
+            }
+        }
+        match star {
+            Some((st, sn)) if sn < name.len() => {
+                let sn = sn + name[sn..].chars().next().unwrap().len_utf8();
+                star = Some((st, sn));
+                t = st;
+                n = sn;
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Match the segments of a pattern against the names of a path.
+/// On a mismatch, the last `**` absorbs one more component.
+fn match_segments(segments: &[Segment], names: &[&str]) -> bool {
+    let (mut s, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while s < segments.len() || n < names.len() {
+        if let Some(segment) = segments.get(s) {
+            if *segment == Segment::AnyPath {
+                star = Some((s + 1, n));
+                s += 1;
+                continue;
+            }
+            if n < names.len() && segment.matches(names[n]) {
+                s += 1;
+                n += 1;
+                continue;
+            }
+        }
+        match star {
+            Some((ss, sn)) if sn < names.len() => {
+                star = Some((ss, sn + 1));
+                s = ss;
+                n = sn + 1;
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        Glob::new(pattern).unwrap().matches(Path::new(path), false)
+    }
+
+    #[test]
+    fn wildcards_stay_in_their_component() {
+        assert!(matches("*.txt", "/a.txt"));
+        assert!(matches("*.txt", "/deep/down/a.txt"));
+        assert!(matches("*", "/.hidden"));
+        assert!(!matches("/*.txt", "/dir/a.txt"));
+        assert!(!matches("/a*b", "/a/b"));
+        assert!(matches("/a?c", "/abc"));
+        assert!(!matches("/a?c", "/a/c"));
+        assert!(!matches("/a?c", "/ac"));
+        assert!(matches("/a***c", "/abbc"));
+    }
+
+    #[test]
+    fn any_path() {
+        assert!(matches("/a/**/b", "/a/b"));
+        assert!(matches("/a/**/b", "/a/x/y/b"));
+        assert!(!matches("/a/**/b", "/a/x/y/c"));
+        assert!(matches("**/b", "/b"));
+        assert!(matches("**/b", "/x/b"));
+        assert!(matches("/a/**", "/a/x"));
+        assert!(matches("/a/**", "/a/x/y"));
+        assert!(!matches("/a/**", "/a"));
+        assert!(!matches("/a/**", "/b/x"));
+        // not a whole component: a plain star
+        assert!(matches("/a**b", "/axxb"));
+        assert!(!matches("/a**b", "/ax/xb"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("/[abc]", "/b"));
+        assert!(!matches("/[abc]", "/d"));
+        assert!(matches("/[a-c]x", "/bx"));
+        assert!(matches("/[!a-c]x", "/dx"));
+        assert!(matches("/[^a-c]x", "/dx"));
+        assert!(!matches("/[!a-c]x", "/ax"));
+        assert!(matches("/[]]", "/]"));
+        assert!(matches("/[a-]", "/-"));
+        assert!(matches("/[\\]a]", "/]"));
+        assert!(matches("/[*?]", "/*"));
+        assert!(!matches("/[*?]", "/a"));
+
+        for invalid in ["/[abc", "/[z-a]", "/a\\", "", "/", "a//b", "!"] {
+            assert!(Glob::new(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn anchoring() {
+        assert!(matches("/build", "/build"));
+        assert!(!matches("/build", "/src/build"));
+        assert!(matches("doc/*.html", "/doc/index.html"));
+        assert!(!matches("doc/*.html", "/src/doc/index.html"));
+        assert!(!matches("doc/*.html", "/doc/api/index.html"));
+
+        let glob = Glob::with_base(Path::new("/a"), "sub/*.o").unwrap();
+        assert!(glob.matches(Path::new("/a/sub/main.o"), false));
+        assert!(!glob.matches(Path::new("/sub/main.o"), false));
+        assert!(!glob.matches(Path::new("/a/b/sub/main.o"), false));
+
+        let glob = Glob::with_base(Path::new("/a"), "*").unwrap();
+        assert!(glob.matches(Path::new("/a/b/c"), false));
+        assert!(!glob.matches(Path::new("/a"), true));
+        assert!(!glob.matches(Path::new("/ab/c"), false));
+    }
+
+    #[test]
+    fn dir_only_and_negation() {
+        let glob = Glob::new("cache/").unwrap();
+        assert!(glob.is_dir_only());
+        assert!(glob.matches(Path::new("/x/cache"), true));
+        assert!(!glob.matches(Path::new("/x/cache"), false));
+
+        let set = PathGlobSet::new(["*.log", "!keep.log", "/logs/keep.log", "\\!bang"]).unwrap();
+        assert!(set.is_match(Path::new("/a.log"), false));
+        assert!(!set.is_match(Path::new("/keep.log"), false));
+        assert!(set.is_match(Path::new("/logs/keep.log"), false));
+        assert!(!set.is_match(Path::new("/a.txt"), false));
+        assert!(set.is_match(Path::new("/!bang"), false));
+        assert_eq!(
+            set.last_match(Path::new("/x/keep.log"), false)
+                .map(Glob::as_str),
+            Some("!keep.log")
+        );
+    }
+
+    #[test]
+    fn unicode_and_special_names() {
+        assert!(matches("/caf?", "/café"));
+        assert!(matches("/*é", "/café"));
+        assert!(matches("/[à-ÿ]t?", "/été"));
+        assert!(matches("/日本/*.txt", "/日本/語.txt"));
+        assert!(matches("/🦀?", "/🦀🦀"));
+        // no normalization nor case folding
+        assert!(!matches("/cafe\u{301}", "/café"));
+        assert!(!matches("/README", "/readme"));
+
+        // characters that Drive allows in names but that file systems reserve
+        // or that need an escape in a pattern
+        assert!(matches("/a\\\\b", "/a\\b"));
+        assert!(matches("/a:b|c", "/a:b|c"));
+        assert!(matches("/\\*\\?\\[x]", "/*?[x]"));
+        assert!(!matches("/\\*", "/a"));
+        assert!(matches("/%2F*", "/%2Fname"));
+        assert!(matches("/\\#notes", "/#notes"));
+        assert!(matches("/ lead and trail ", "/ lead and trail "));
+    }
+
+    /// A naive matcher on the whole strings, used as a reference
+    fn reference(pattern: &[char], path: &[char]) -> bool {
+        let class = |pattern: &[char], c: char| -> Option<(bool, usize)> {
+            let end = pattern.iter().skip(2).position(|p| *p == ']')? + 2;
+            let (negated, body) = match pattern[1] {
+                '!' => (true, &pattern[2..end]),
+                _ => (false, &pattern[1..end]),
+            };
+            let inside = if body.len() == 3 && body[1] == '-' {
+                (body[0]..=body[2]).contains(&c)
+            } else {
+                body.contains(&c)
+            };
+            Some((inside != negated, end + 1))
+        };
+        match pattern {
+            [] => path.is_empty(),
+            ['*', '*', '/', rest @ ..] => {
+                reference(rest, path)
+                    || (0..path.len())
+                        .filter(|i| path[*i] == '/')
+                        .any(|i| reference(rest, &path[i + 1..]))
+            }
+            ['*', '*'] => !path.is_empty(),
+            ['*', rest @ ..] => (0..=path.len())
+                .take_while(|i| *i == 0 || path[i - 1] != '/')
+                .any(|i| reference(rest, &path[i..])),
+            ['?', rest @ ..] => {
+                matches!(path.first(), Some(c) if *c != '/') && reference(rest, &path[1..])
+            }
+            ['[', ..] => match path.first() {
+                Some(c) if *c != '/' => match class(pattern, *c) {
+                    Some((true, len)) => reference(&pattern[len..], &path[1..]),
+                    _ => false,
+                },
+                _ => false,
+            },
+            [p, rest @ ..] => path.first() == Some(p) && reference(rest, &path[1..]),
+        }
+    }
+
+    fn pattern() -> impl Strategy<Value = String> {
+        let segment = prop::sample::select(vec![
+            "a", "b", "ab", "*", "?", "a*", "*b", "?b", "**", "[ab]", "[!a]", "[b-c]", "a[!b]*",
+        ]);
+        (any::<bool>(), prop::collection::vec(segment, 1..5)).prop_map(|(anchored, segments)| {
+            let pattern = segments.join("/");
+            if anchored || segments.len() > 1 {
+                format!("/{pattern}")
+            } else {
+                pattern
+            }
+        })
+    }
+
+    fn path() -> impl Strategy<Value = String> {
+        let name = prop::string::string_regex("[a-c]{1,3}").unwrap();
+        prop::collection::vec(name, 1..6).prop_map(|names| format!("/{}", names.join("/")))
+    }
+
+    proptest! {
+        #[test]
+        fn agrees_with_reference(pattern in pattern(), path in path()) {
+            let glob = Glob::new(&pattern).unwrap();
+            let anchored = match pattern.strip_prefix('/') {
+                Some(pattern) => pattern.to_string(),
+                None => format!("**/{pattern}"),
+            };
+            let pattern: Vec<char> = anchored.chars().collect();
+            let rel: Vec<char> = path[1..].chars().collect();
+            prop_assert_eq!(
+                glob.matches(Path::new(&path), false),
+                reference(&pattern, &rel),
+                "{} on {}", anchored, path
+            );
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! The files published by a running daemon in the runtime directory
+
+use std::{
+    io,
+    net::{IpAddr, Ipv6Addr},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tarpc::{client, context};
+
+use crate::{path::FsPath, FsyncClient};
+
+/// Delay after which a daemon that does not answer on the port of its port file is considered gone
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The content of the [port file](crate::loc::inst::runtime_port_file) of a daemon.
+///
+/// A daemon that crashed leaves its port file behind, possibly with a port now owned
+/// by another process, so the clients check that the daemon that wrote the file
+/// still answers before trusting it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PortFile {
+    pub port: u16,
+    /// The process id of the daemon, `None` for the files written by older daemons
+    #[serde(default)]
+    pub pid: Option<u32>,
+    /// The boot id that the daemon returns from `Fsync::ping`,
+    /// `None` for the files written by older daemons
+    #[serde(default)]
+    pub boot_id: Option<u64>,
+    /// The [fingerprint](crate::Config::fingerprint) of the config loaded by the daemon,
+    /// `None` for the files written by older daemons
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_fingerprint: Option<String>,
+}
+
+impl PortFile {
+    pub fn new(port: u16, boot_id: u64) -> Self {
+        Self {
+            port,
+            pid: Some(std::process::id()),
+            boot_id: Some(boot_id),
+            config_fingerprint: None,
+        }
+    }
+
+    pub fn with_config_fingerprint(self, config_fingerprint: Option<String>) -> Self {
+        Self {
+            config_fingerprint,
+            ..self
+        }
+    }
+
+    /// Parse the content of a port file.
+    /// Older daemons only wrote the port number.
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let content = content.trim();
+        if let Ok(port) = content.parse() {
+            return Ok(Self {
+                port,
+                pid: None,
+                boot_id: None,
+                config_fingerprint: None,
+            });
+        }
+        Ok(serde_json::from_str(content)?)
+    }
+
+    /// Load the port file at `path`, `None` if there is no such file
+    pub fn load(path: &FsPath) -> anyhow::Result<Option<Self>> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Self::parse(&content)
+                .with_context(|| format!("Invalid port file {path}"))
+                .map(Some),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Port file should serialize")
+    }
+
+    /// Whether `boot_id`, as answered by the daemon on the port, is the one of the daemon
+    /// that wrote this file. Always true for the files written by older daemons.
+    pub fn is_written_by(&self, boot_id: u64) -> bool {
+        self.boot_id.is_none_or(|id| id == boot_id)
+    }
+
+    /// Check that the daemon that wrote this file still answers on its port,
+    /// within [`PROBE_TIMEOUT`].
+    pub async fn is_live(&self) -> bool {
+        let probe = async {
+            let addr = (IpAddr::V6(Ipv6Addr::LOCALHOST), self.port);
+            let transport = tarpc::serde_transport::tcp::connect(addr, crate::codec).await?;
+            let client = FsyncClient::new(client::Config::default(), transport).spawn();
+            let mut ctx = context::current();
+            ctx.deadline = SystemTime::now() + PROBE_TIMEOUT;
+            anyhow::Ok(client.ping(ctx).await?)
+        };
+        match tokio::time::timeout(PROBE_TIMEOUT, probe).await {
+            Ok(Ok(boot_id)) => self.is_written_by(boot_id),
+            _ => false,
+        }
+    }
+
+    /// Remove the port file at `path`, left behind by a daemon that is gone.
+    ///
+    /// The file is kept if another daemon replaced it in the meantime.
+    /// Returns whether the file was removed.
+    pub fn remove_stale(&self, path: &FsPath) -> io::Result<bool> {
+        match Self::load(path) {
+            Ok(Some(current)) if current == *self => (),
+            Ok(_) => return Ok(false),
+            // unreadable, so not the file of another daemon either
+            Err(_) => (),
+        }
+        match std::fs::remove_file(path) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::FsPathBuf;
+
+    #[test]
+    fn parse_port_file() {
+        let file = PortFile::parse("1234\n").unwrap();
+        assert_eq!(
+            file,
+            PortFile {
+                port: 1234,
+                pid: None,
+                boot_id: None,
+                config_fingerprint: None,
+            }
+        );
+        assert!(file.is_written_by(42));
+
+        let file = PortFile::new(1234, 42);
+        assert_eq!(PortFile::parse(&file.to_json()).unwrap(), file);
+        assert!(file.is_written_by(42));
+        assert!(!file.is_written_by(43));
+
+        let file = file.with_config_fingerprint(Some("0123456789abcdef".into()));
+        assert_eq!(PortFile::parse(&file.to_json()).unwrap(), file);
+
+        assert!(PortFile::parse("not a port").is_err());
+    }
+
+    #[tokio::test]
+    async fn crash_leftover_is_not_live() {
+        // a port that nothing listens on anymore
+        let listener = std::net::TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let file = PortFile::new(port, 42);
+        assert!(!file.is_live().await);
+
+        let dir = std::env::temp_dir().join(format!("fsync-port-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = FsPathBuf::try_from(dir.join("leftover.port")).unwrap();
+
+        // replaced by a new daemon: kept
+        std::fs::write(&path, PortFile::new(port.wrapping_add(1), 43).to_json()).unwrap();
+        assert!(!file.remove_stale(&path).unwrap());
+        assert!(path.exists());
+
+        std::fs::write(&path, file.to_json()).unwrap();
+        assert!(file.remove_stale(&path).unwrap());
+        assert!(!path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+//! Detection of the sleeps of the system, from the clocks.
+//!
+//! No portable notification of the resume from sleep exists, so the daemon and the clients
+//! compare the wall clock to the monotonic clock at regular ticks. The monotonic clock stops
+//! while the system sleeps on Linux and macOS, so the wall clock runs ahead of it across
+//! a sleep. Where it doesn't stop, the tick that follows the resume arrives long after
+//! it was due.
+
+use std::time::{Duration, Instant, SystemTime};
+
+/// Minimum gap of the clocks reported as a sleep.
+/// Well above the delays of the ticks of a busy system and the adjustments of the wall clock.
+pub const MIN_SLEEP: Duration = Duration::from_secs(30);
+
+/// Tells the sleeps of the system from the clocks read at each tick
+#[derive(Debug, Clone, Copy)]
+pub struct Detector {
+    /// Expected interval between two ticks
+    tick: Duration,
+    wall: SystemTime,
+    mono: Instant,
+}
+
+impl Detector {
+    /// Start from the clocks read now, for ticks every `tick`
+    pub fn new(tick: Duration) -> Self {
+        Self::with_clocks(tick, SystemTime::now(), Instant::now())
+    }
+
+    /// Start from the given clocks, for ticks every `tick`
+    pub fn with_clocks(tick: Duration, wall: SystemTime, mono: Instant) -> Self {
+        Self { tick, wall, mono }
+    }
+
+    /// Check the clocks read now, see [`Self::check_clocks`]
+    pub fn check(&mut self) -> Option<Duration> {
+        self.check_clocks(SystemTime::now(), Instant::now())
+    }
+
+    /// Record the clocks of a tick, and return how long the system slept since
+    /// the previous tick, if it slept at least [`MIN_SLEEP`].
+    /// A wall clock set forward by the user is also reported as a sleep.
+    pub fn check_clocks(&mut self, wall: SystemTime, mono: Instant) -> Option<Duration> {
+        let mono_elapsed = mono.saturating_duration_since(self.mono);
+        // the wall clock goes back when it is set back
+        let wall_elapsed = wall.duration_since(self.wall).unwrap_or_default();
+        self.wall = wall;
+        self.mono = mono;
+        let stopped = wall_elapsed.saturating_sub(mono_elapsed);
+        let late = mono_elapsed.saturating_sub(self.tick);
+        let asleep = stopped.max(late);
+        (asleep >= MIN_SLEEP).then_some(asleep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn clock_jumps() {
+        let (mut wall, mut mono) = (SystemTime::UNIX_EPOCH + TICK, Instant::now());
+        let mut detector = Detector::with_clocks(TICK, wall, mono);
+        let mut tick = |wall_step: Duration, mono_step: Duration| {
+            wall += wall_step;
+            mono += mono_step;
+            detector.check_clocks(wall, mono)
+        };
+
+        // regular and slightly late ticks
+        assert_eq!(tick(TICK, TICK), None);
+        assert_eq!(tick(TICK * 3, TICK * 3), None);
+
+        // the monotonic clock stopped during one hour of sleep
+        let hour = Duration::from_secs(3600);
+        assert_eq!(tick(hour + TICK, TICK), Some(hour));
+        assert_eq!(tick(TICK, TICK), None);
+
+        // the monotonic clock ran during the sleep, the tick is late
+        assert_eq!(tick(hour, hour), Some(hour - TICK));
+
+        // the wall clock set back, then adjusted by a few seconds
+        assert_eq!(tick(Duration::ZERO, TICK), None);
+        assert_eq!(tick(TICK * 2, TICK), None);
+    }
+}
@@ -7,16 +7,20 @@ use crate::StorageLoc;
 
 /// Stats for a directory.
 /// This is recursive stats for all children of a directory,
-/// including grand-children and so forth
+/// including grand-children and so forth.
+///
+/// The counters are signed because the stats are also the differences
+/// applied to the ancestors when the tree changes.
+/// The arithmetic saturates rather than wrapping around.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
 #[serde(rename = "DirStat")]
 pub struct Dir {
     /// The data in the directory, in bytes
     pub data: i64,
     /// The number of directory entries in this directory
-    pub dirs: i32,
+    pub dirs: i64,
     /// The number of file entries in this directory
-    pub files: i32,
+    pub files: i64,
 }
 
 impl Dir {
@@ -36,19 +40,19 @@ impl Dir {
         self.data >= 0 && self.dirs >= 0 && self.files >= 0
     }
 
-    pub fn entries(&self) -> i32 {
-        self.dirs + self.files
+    pub fn entries(&self) -> i64 {
+        self.dirs.saturating_add(self.files)
     }
 
     pub fn with_data(self, data: i64) -> Self {
         Self { data, ..self }
     }
 
-    pub fn with_dirs(self, dirs: i32) -> Self {
+    pub fn with_dirs(self, dirs: i64) -> Self {
         Self { dirs, ..self }
     }
 
-    pub fn with_files(self, files: i32) -> Self {
+    pub fn with_files(self, files: i64) -> Self {
         Self { files, ..self }
     }
 }
@@ -57,18 +61,16 @@ impl ops::Add for Dir {
     type Output = Self;
     fn add(self, rhs: Self) -> Self::Output {
         Self {
-            data: self.data + rhs.data,
-            dirs: self.dirs + rhs.dirs,
-            files: self.files + rhs.files,
+            data: self.data.saturating_add(rhs.data),
+            dirs: self.dirs.saturating_add(rhs.dirs),
+            files: self.files.saturating_add(rhs.files),
         }
     }
 }
 
 impl ops::AddAssign for Dir {
     fn add_assign(&mut self, rhs: Self) {
-        self.data += rhs.data;
-        self.dirs += rhs.dirs;
-        self.files += rhs.files;
+        *self = *self + rhs;
     }
 }
 
@@ -76,18 +78,16 @@ impl ops::Sub for Dir {
     type Output = Self;
     fn sub(self, rhs: Self) -> Self::Output {
         Self {
-            data: self.data - rhs.data,
-            dirs: self.dirs - rhs.dirs,
-            files: self.files - rhs.files,
+            data: self.data.saturating_sub(rhs.data),
+            dirs: self.dirs.saturating_sub(rhs.dirs),
+            files: self.files.saturating_sub(rhs.files),
         }
     }
 }
 
 impl ops::SubAssign for Dir {
     fn sub_assign(&mut self, rhs: Self) {
-        self.data -= rhs.data;
-        self.dirs -= rhs.dirs;
-        self.files -= rhs.files;
+        *self = *self - rhs;
     }
 }
 
@@ -95,9 +95,9 @@ impl ops::Neg for Dir {
     type Output = Self;
     fn neg(self) -> Self::Output {
         Self {
-            data: -self.data,
-            dirs: -self.dirs,
-            files: -self.files,
+            data: self.data.saturating_neg(),
+            dirs: self.dirs.saturating_neg(),
+            files: self.files.saturating_neg(),
         }
     }
 }
@@ -107,9 +107,20 @@ impl ops::Neg for Dir {
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
 #[serde(rename = "NodeStat")]
 pub struct Node {
-    pub nodes: i32,
-    pub sync: i32,
-    pub conflicts: i32,
+    pub nodes: i64,
+    pub sync: i64,
+    /// The number of conflicting entries of the sub-tree, the node included.
+    /// Each conflict is counted once, at its own node, whatever its descendants.
+    pub conflicts: i64,
+    /// The number of files skipped by synchronization for being larger than the size limit.
+    /// Not sent over the wire, it is provided by [`crate::Fsync::too_large_stats`].
+    #[serde(skip)]
+    pub too_large: i64,
+    /// The number of entries of which the metadata looks corrupt, see [`crate::Metadata::is_implausible`].
+    /// The other stats may be wrong when it is not zero.
+    /// Not sent over the wire, it is provided by [`crate::Fsync::implausible_stats`].
+    #[serde(skip)]
+    pub implausible: i64,
 }
 
 impl Node {
@@ -118,50 +129,71 @@ impl Node {
             nodes: 0,
             sync: 0,
             conflicts: 0,
+            too_large: 0,
+            implausible: 0,
         }
     }
 
     pub fn is_null(&self) -> bool {
-        self.nodes == 0 && self.sync == 0 && self.conflicts == 0
+        self.nodes == 0
+            && self.sync == 0
+            && self.conflicts == 0
+            && self.too_large == 0
+            && self.implausible == 0
     }
 
     pub fn is_positive(&self) -> bool {
-        self.nodes >= 0 && self.sync >= 0 && self.conflicts >= 0
+        self.nodes >= 0
+            && self.sync >= 0
+            && self.conflicts >= 0
+            && self.too_large >= 0
+            && self.implausible >= 0
     }
 
-    pub fn entries(&self) -> i32 {
-        self.sync + self.conflicts
+    pub fn entries(&self) -> i64 {
+        self.sync.saturating_add(self.conflicts)
     }
 
-    pub fn with_nodes(self, nodes: i32) -> Self {
+    pub fn with_nodes(self, nodes: i64) -> Self {
         Self { nodes, ..self }
     }
 
-    pub fn with_sync(self, sync: i32) -> Self {
+    pub fn with_sync(self, sync: i64) -> Self {
         Self { sync, ..self }
     }
 
-    pub fn with_conflicts(self, conflicts: i32) -> Self {
+    pub fn with_conflicts(self, conflicts: i64) -> Self {
         Self { conflicts, ..self }
     }
+
+    pub fn with_too_large(self, too_large: i64) -> Self {
+        Self { too_large, ..self }
+    }
+
+    pub fn with_implausible(self, implausible: i64) -> Self {
+        Self {
+            implausible,
+            ..self
+        }
+    }
 }
 
 impl ops::Add for Node {
     type Output = Self;
     fn add(self, rhs: Self) -> Self::Output {
         Self {
-            nodes: self.nodes + rhs.nodes,
-            sync: self.sync + rhs.sync,
-            conflicts: self.conflicts + rhs.conflicts,
+            nodes: self.nodes.saturating_add(rhs.nodes),
+            sync: self.sync.saturating_add(rhs.sync),
+            conflicts: self.conflicts.saturating_add(rhs.conflicts),
+            too_large: self.too_large.saturating_add(rhs.too_large),
+            implausible: self.implausible.saturating_add(rhs.implausible),
         }
     }
 }
 
 impl ops::AddAssign for Node {
     fn add_assign(&mut self, rhs: Self) {
-        self.nodes += rhs.nodes;
-        self.sync += rhs.sync;
-        self.conflicts += rhs.conflicts;
+        *self = *self + rhs;
     }
 }
 
@@ -169,18 +201,18 @@ impl ops::Sub for Node {
     type Output = Self;
     fn sub(self, rhs: Self) -> Self::Output {
         Self {
-            nodes: self.nodes - rhs.nodes,
-            sync: self.sync - rhs.sync,
-            conflicts: self.conflicts - rhs.conflicts,
+            nodes: self.nodes.saturating_sub(rhs.nodes),
+            sync: self.sync.saturating_sub(rhs.sync),
+            conflicts: self.conflicts.saturating_sub(rhs.conflicts),
+            too_large: self.too_large.saturating_sub(rhs.too_large),
+            implausible: self.implausible.saturating_sub(rhs.implausible),
         }
     }
 }
 
 impl ops::SubAssign for Node {
     fn sub_assign(&mut self, rhs: Self) {
-        self.nodes -= rhs.nodes;
-        self.sync -= rhs.sync;
-        self.conflicts -= rhs.conflicts;
+        *self = *self - rhs;
     }
 }
 
@@ -188,9 +220,11 @@ impl ops::Neg for Node {
     type Output = Self;
     fn neg(self) -> Self::Output {
         Self {
-            nodes: -self.nodes,
-            sync: -self.sync,
-            conflicts: -self.conflicts,
+            nodes: self.nodes.saturating_neg(),
+            sync: self.sync.saturating_neg(),
+            conflicts: self.conflicts.saturating_neg(),
+            too_large: self.too_large.saturating_neg(),
+            implausible: self.implausible.saturating_neg(),
         }
     }
 }
@@ -278,3 +312,192 @@ impl ops::Neg for Tree {
         }
     }
 }
+
+/// How complete the remote stats of a folder are, see [`crate::Fsync::aggregation`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub enum Aggregation {
+    /// The folder and all its sub-folders were listed from the remote storage
+    Exact,
+    /// The folder was listed, some of its sub-folders are not yet
+    Partial,
+    /// The folder is not listed yet, its stats are the ones of the persisted cache
+    Pending,
+}
+
+/// The entries of a sub-tree flagged by the daemon,
+/// see [`crate::Fsync::implausible_stats`] and [`crate::Fsync::too_large_stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename_all = "camelCase")]
+pub struct Flagged {
+    /// The entry itself is flagged
+    pub here: bool,
+    /// The number of flagged entries in the sub-tree, the entry included
+    pub count: i64,
+}
+
+/// Storage quota of a remote drive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+#[serde(rename = "StorageQuota")]
+pub struct Quota {
+    /// The used storage, in bytes
+    pub usage: i64,
+    /// The storage limit, in bytes, or `None` if the storage is unlimited
+    pub limit: Option<i64>,
+}
+
+impl Quota {
+    /// The remaining storage in bytes, or `None` if the storage is unlimited
+    pub fn remaining(&self) -> Option<i64> {
+        self.limit.map(|limit| (limit - self.usage).max(0))
+    }
+
+    /// The used storage, in percent of the limit
+    pub fn percent(&self) -> Option<f64> {
+        match self.limit {
+            Some(limit) if limit > 0 => Some(self.usage as f64 * 100.0 / limit as f64),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::*;
+    use crate::{
+        path::PathBuf,
+        tree::{Entry, EntryNode},
+        Metadata,
+    };
+
+    fn file(name: &str, size: u64, secs: i64) -> Metadata {
+        Metadata::Regular {
+            path: PathBuf::from(format!("/{name}")),
+            size,
+            mtime: DateTime::from_timestamp(secs, 0).unwrap(),
+            link_target: None,
+        }
+    }
+
+    #[test]
+    fn counters_go_beyond_32_bits() {
+        let many = Dir::null()
+            .with_dirs(i32::MAX as i64)
+            .with_files(i32::MAX as i64);
+        let sum = many + many + many;
+        assert_eq!(sum.files, 3 * i32::MAX as i64);
+        assert_eq!(sum.entries(), 6 * i32::MAX as i64);
+        assert!(sum.is_positive());
+        assert_eq!(sum - many - many - many, Dir::null());
+    }
+
+    #[test]
+    fn aggregation_saturates() {
+        let huge = Dir::null().with_data(i64::MAX - 1).with_files(i64::MAX);
+        let mut sum = huge;
+        sum += huge;
+        assert_eq!(sum.data, i64::MAX);
+        assert_eq!(sum.files, i64::MAX);
+        assert!(sum.is_positive());
+
+        let neg = -Dir::null().with_data(i64::MIN);
+        assert_eq!(neg.data, i64::MAX);
+        let mut diff = Dir::null().with_data(i64::MIN + 1);
+        diff -= huge;
+        assert_eq!(diff.data, i64::MIN);
+
+        let node = Node::null().with_nodes(i64::MAX).with_implausible(1);
+        assert_eq!((node + node).nodes, i64::MAX);
+        assert_eq!((node + node).implausible, 2);
+    }
+
+    #[test]
+    fn oversized_files_saturate_the_data() {
+        // a negative size received from an API
+        let md = file("neg", -1i64 as u64, 1_700_000_000);
+        assert_eq!(md.stat().unwrap().data, i64::MAX);
+        let sum = md.stat().unwrap() + md.stat().unwrap();
+        assert_eq!(sum.data, i64::MAX);
+        assert_eq!(sum.files, 2);
+    }
+
+    #[test]
+    fn implausible_metadata() {
+        assert!(!file("ok", 12, 1_700_000_000).is_implausible());
+        assert!(!file("big", crate::MAX_PLAUSIBLE_SIZE, 1_700_000_000).is_implausible());
+        assert!(file("huge", crate::MAX_PLAUSIBLE_SIZE + 1, 1_700_000_000).is_implausible());
+        assert!(file("neg", -1i64 as u64, 1_700_000_000).is_implausible());
+        assert!(file("epoch", 12, 0).is_implausible());
+        let next_year = Utc::now().timestamp() + 365 * 24 * 3600;
+        assert!(file("future", 12, next_year).is_implausible());
+        assert!(!Metadata::root().is_implausible());
+    }
+
+    #[test]
+    fn implausible_entries_are_counted() {
+        let ok = EntryNode::new(
+            Entry::Local(file("ok", 12, 1_700_000_000)),
+            vec![],
+            Tree::null(),
+        );
+        let neg = EntryNode::new(
+            Entry::Remote(file("neg", -1i64 as u64, 1_700_000_000)),
+            vec![],
+            Tree::null(),
+        );
+        let old = EntryNode::new(
+            Entry::Sync {
+                local: file("old", 12, 1_700_000_000),
+                remote: file("old", 12, 0),
+                conflict: None,
+            },
+            vec![],
+            Tree::null(),
+        );
+        assert_eq!(ok.stats().node.implausible, 0);
+        assert_eq!(neg.stats().node.implausible, 1);
+        assert_eq!(old.stats().node.implausible, 1);
+
+        let children = ok.stats() + neg.stats() + old.stats();
+        let root = EntryNode::new(
+            Entry::Sync {
+                local: Metadata::root(),
+                remote: Metadata::root(),
+                conflict: None,
+            },
+            vec!["neg".into(), "ok".into(), "old".into()],
+            children,
+        );
+        let stats = root.stats();
+        assert_eq!(stats.node.implausible, 2);
+        assert_eq!(stats.node.nodes, 4);
+        assert_eq!(stats.remote.data, i64::MAX);
+        assert_eq!(stats.local.data, 24);
+    }
+
+    #[test]
+    fn implausibility_follows_the_entry() {
+        let mut node = EntryNode::new(
+            Entry::Local(file("a", 12, 1_700_000_000)),
+            vec![],
+            Tree::null(),
+        );
+        assert!(!node.is_implausible());
+
+        let remote = file("a", 12, 0);
+        node.op_entry(|entry| Entry::Sync {
+            local: entry.into_metadata(StorageLoc::Local).unwrap(),
+            remote: remote.clone(),
+            conflict: None,
+        });
+        assert!(node.is_implausible());
+        assert_eq!(node.stats().node.implausible, 1);
+        let mut node = node.without_children();
+        assert_eq!(node.stats().node.implausible, 1);
+
+        node.op_entry(|_| Entry::Remote(file("a", 12, 1_700_000_000)));
+        assert_eq!(node.stats().node.implausible, 0);
+    }
+}
@@ -0,0 +1,93 @@
+//! Detection of the text files, that the clients preview and that the daemon can merge.
+
+use crate::{path::Path, Metadata};
+
+/// Files larger than this are not merged
+pub const MAX_MERGE_SIZE: u64 = 256 * 1024;
+
+/// Number of bytes inspected to detect binary content
+const SNIFF_LEN: usize = 8000;
+
+/// Extensions of files that are never handled as text
+const BINARY_EXTENSIONS: &[&str] = &[
+    "7z", "avi", "bin", "bmp", "bz2", "class", "dll", "doc", "docx", "exe", "flac", "gif", "gz",
+    "ico", "iso", "jar", "jpeg", "jpg", "mkv", "mov", "mp3", "mp4", "o", "odp", "ods", "odt",
+    "ogg", "pdf", "png", "ppt", "pptx", "so", "sqlite", "tar", "tif", "tiff", "wav", "webm",
+    "webp", "xls", "xlsx", "xz", "zip", "zst",
+];
+
+/// Whether `data` looks like binary content
+pub fn is_binary(data: &[u8]) -> bool {
+    let sniff = &data[..data.len().min(SNIFF_LEN)];
+    if sniff.contains(&0) {
+        return true;
+    }
+    match std::str::from_utf8(data) {
+        Ok(_) => false,
+        // a multi-byte character can be cut at the end of a file head
+        Err(err) => err.error_len().is_some(),
+    }
+}
+
+/// Whether the extension of `path` is one of a binary format
+pub fn has_binary_extension(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.to_ascii_lowercase())
+        .is_some_and(|ext| BINARY_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// Whether the conflicting `local` and `remote` versions of a file may be merged
+/// with [`crate::ResolutionMethod::MergeText`].
+/// Their content is only checked by the merge itself.
+pub fn is_mergeable(local: &Metadata, remote: &Metadata) -> bool {
+    let small_file = |md: &Metadata| {
+        md.is_file()
+            && md.link_target().is_none()
+            && md.size().is_some_and(|size| size <= MAX_MERGE_SIZE)
+    };
+    small_file(local) && small_file(remote) && !has_binary_extension(local.path())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::path::PathBuf;
+
+    #[test]
+    fn truncated_utf8_is_text() {
+        let data = "é".as_bytes();
+        assert!(!is_binary(&data[..1]));
+        assert!(is_binary(&[0xff, 0xfe, b'a']));
+        assert!(is_binary(b"text\0with nul"));
+    }
+
+    #[test]
+    fn mergeable() {
+        let file = |path: &str, size| Metadata::Regular {
+            path: PathBuf::from(path),
+            size,
+            mtime: Utc::now(),
+            link_target: None,
+        };
+        let dir = Metadata::Directory {
+            path: PathBuf::from("/notes.txt"),
+            stat: None,
+        };
+        assert!(is_mergeable(
+            &file("/notes.txt", 10),
+            &file("/notes.txt", 20)
+        ));
+        assert!(is_mergeable(&file("/Makefile", 10), &file("/Makefile", 20)));
+        assert!(!is_mergeable(
+            &file("/photo.JPG", 10),
+            &file("/photo.JPG", 20)
+        ));
+        assert!(!is_mergeable(
+            &file("/notes.txt", MAX_MERGE_SIZE + 1),
+            &file("/notes.txt", 20)
+        ));
+        assert!(!is_mergeable(&file("/notes.txt", 10), &dir));
+    }
+}
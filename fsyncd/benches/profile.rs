@@ -0,0 +1,41 @@
+//! Overhead of the spans of the [profile](fsyncd::profile) module around a small unit of work.
+//! Disabled, a span must cost no more than the noise of the baseline.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use fsyncd::profile::{self, Phase, Span};
+
+/// A unit of work of a few tens of nanoseconds
+fn work(data: &[u64]) -> u64 {
+    data.iter()
+        .fold(0, |acc, x| acc.wrapping_mul(31).wrapping_add(*x))
+}
+
+fn bench_span(c: &mut Criterion) {
+    let data: Vec<u64> = (0..64).collect();
+    let path = "/some/dir/file.txt";
+
+    let mut group = c.benchmark_group("span");
+    group.bench_function("baseline", |b| b.iter(|| work(black_box(&data))));
+    group.bench_function("disabled", |b| {
+        b.iter(|| {
+            let _span = Span::enter(Phase::Unit)
+                .with_detail(|| path.to_string())
+                .with_bytes(64);
+            work(black_box(&data))
+        })
+    });
+    // recording can't be disabled again, so this comes last
+    profile::enable();
+    group.bench_function("enabled", |b| {
+        b.iter(|| {
+            let _span = Span::enter(Phase::Unit)
+                .with_detail(|| path.to_string())
+                .with_bytes(64);
+            work(black_box(&data))
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_span);
+criterion_main!(benches);
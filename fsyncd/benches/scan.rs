@@ -0,0 +1,77 @@
+//! Wall-clock time of the listing of a deep and wide local tree, with the entries read
+//! one at a time and with the default concurrency of [`FileSystem`].
+//! The fixture stays in the page cache between the iterations,
+//! the gain is larger on a cold cache where each read waits for the disk.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use fsync::path::{FsPathBuf, Path, PathBuf};
+use fsyncd::storage::{
+    fs::{self, FileSystem},
+    DirEntries,
+};
+use futures::{future::BoxFuture, TryStreamExt};
+
+/// Directories of each level
+const WIDTH: usize = 10;
+/// Levels of directories under the root
+const DEPTH: usize = 3;
+/// Files in each directory
+const FILES: usize = 30;
+
+/// A tree of 1110 directories and 33330 files, created once
+fn fixture() -> FsPathBuf {
+    let root = std::env::temp_dir().join(format!("fsyncd-bench-scan-{WIDTH}-{DEPTH}-{FILES}"));
+    let root = FsPathBuf::try_from(root).unwrap();
+    let complete = root.join(".complete");
+    if complete.exists() {
+        return root;
+    }
+    let _ = std::fs::remove_dir_all(&root);
+    let mut dirs = vec![root.clone()];
+    for _ in 0..DEPTH {
+        dirs = dirs
+            .iter()
+            .flat_map(|dir| (0..WIDTH).map(move |i| dir.join(format!("dir{i}"))))
+            .collect();
+        for dir in &dirs {
+            std::fs::create_dir_all(dir).unwrap();
+            for i in 0..FILES {
+                std::fs::write(dir.join(format!("file{i}.txt")), "content").unwrap();
+            }
+        }
+    }
+    std::fs::write(complete, "").unwrap();
+    root
+}
+
+/// List the sub-tree of `path`, the directories concurrently as the tree build does
+fn walk<'a>(fs: &'a FileSystem, path: PathBuf) -> BoxFuture<'a, usize> {
+    Box::pin(async move {
+        let entries: Vec<_> = fs.dir_entries(&path, None).try_collect().await.unwrap();
+        let dirs = entries
+            .iter()
+            .filter(|entry| entry.is_dir())
+            .map(|entry| walk(fs, entry.path().to_owned()));
+        entries.len() + futures::future::join_all(dirs).await.iter().sum::<usize>()
+    })
+}
+
+fn bench_scan(c: &mut Criterion) {
+    let root = fixture();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("scan");
+    group.sample_size(10);
+    for concurrency in [1, fs::default_scan_concurrency()] {
+        let fs = FileSystem::new(&root)
+            .unwrap()
+            .with_scan_concurrency(concurrency);
+        group.bench_with_input(BenchmarkId::from_parameter(concurrency), &fs, |b, fs| {
+            b.iter(|| rt.block_on(walk(fs, Path::root().to_owned())))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_scan);
+criterion_main!(benches);
@@ -1,11 +1,35 @@
-use std::{ffi::OsString, process::ExitCode, sync::Arc};
+use std::{ffi::OsString, process::ExitCode, sync::Arc, time::Duration};
 
+use anyhow::Context;
 use clap::Parser;
-use fsync::{loc::inst, path::FsPathBuf};
+use fsync::{
+    caps::FsCaps,
+    loc::{inst, user},
+    path::{FsPath, FsPathBuf},
+};
 use fsyncd::{
+    aggregate::Aggregator,
+    audit::AuditLog,
+    buffers::{self, BufferPool},
+    checkpoint::Checkpoints,
+    counters::Counters,
+    digest::Digest,
+    disk_cache::{CachePaths, DiskCache},
+    hooks::Hooks,
+    ignore::IgnoreRules,
+    journal::Journal,
+    merge::{self, Bases},
     oauth2,
-    service::{RpcService, Service},
+    pins::Pins,
+    placeholders::Placeholders,
+    policy::{self, Policy},
+    profile,
+    resume::Resumes,
+    root::{self, RootGuard},
+    secrets,
+    service::{self, RpcService, Service},
     storage::{self, cache::CachePersist},
+    tree::BuildOptions,
     ShutdownObj,
 };
 use futures::stream::AbortHandle;
@@ -46,10 +70,13 @@ impl ShutdownRef {
 
     async fn shutdown(&self) -> anyhow::Result<()> {
         let read = self.inner.read().await;
-        match &*read {
+        let res = match &*read {
             Some(inner) => inner.shutdown_obj().await,
             None => Ok(()),
-        }
+        };
+        // after the shutdown, so that the trace has the last save of the caches
+        write_trace();
+        res
     }
 }
 
@@ -60,14 +87,76 @@ struct Cli {
     /// Name of the fsyncd instance
     instance: String,
 
+    #[clap(long, value_name = "DIR")]
+    /// Root of the config, cache and runtime directories, instead of the ones of the user.
+    /// Defaults to the FSYNC_CONFIG_DIR environment variable.
+    config_dir: Option<FsPathBuf>,
+
     #[clap(long)]
     /// Ignore the cache of the remote drive
     ignore_remote_cache: bool,
+
+    #[clap(long)]
+    /// Compare the tree with fresh listings of the storages after each operation.
+    /// This is expensive, and meant to catch the bugs that corrupt the tree.
+    self_check: bool,
+
+    #[clap(long)]
+    /// Record the timings of the expensive phases, reported by `fsynctl stats --timings`
+    timings: bool,
+
+    #[clap(long, value_name = "PATH")]
+    /// Write a Chrome trace of the expensive phases to PATH, implies --timings.
+    /// The trace covers the first minutes of the daemon, see --profile-minutes.
+    profile: Option<FsPathBuf>,
+
+    #[clap(
+        long,
+        value_name = "MINUTES",
+        default_value_t = 5,
+        requires = "profile"
+    )]
+    /// Duration of the trace written by --profile
+    profile_minutes: u64,
+
+    #[clap(long)]
+    /// Refuse to start when the remote root overlaps with the one of another instance,
+    /// instead of warning about it
+    strict: bool,
+}
+
+/// Write the trace of `--profile`, if not written yet
+fn write_trace() {
+    match profile::write_trace() {
+        Ok(Some(path)) => log::info!("Wrote the trace of the daemon to {path}"),
+        Ok(None) => (),
+        Err(err) => log::error!("Could not write the trace of the daemon: {err}"),
+    }
 }
 
 async fn run(args: Vec<OsString>, shutdown_ref: ShutdownRef) -> anyhow::Result<()> {
     let cli = Cli::parse_from(args);
 
+    if let Some(root) = user::init_root(cli.config_dir.clone())? {
+        log::info!("Using the config root {root}");
+    }
+
+    if cli.timings || cli.profile.is_some() {
+        profile::enable();
+    }
+    if let Some(path) = &cli.profile {
+        let duration = Duration::from_secs(cli.profile_minutes * 60);
+        log::info!(
+            "Tracing the first {} minutes of the daemon to {path}",
+            cli.profile_minutes
+        );
+        profile::start_trace(path.clone(), duration);
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            write_trace();
+        });
+    }
+
     let config_file = inst::config_file(&cli.instance)?;
 
     if !&config_file.exists() {
@@ -76,13 +165,110 @@ async fn run(args: Vec<OsString>, shutdown_ref: ShutdownRef) -> anyhow::Result<(
 
     log::info!("Found config file: {config_file}");
 
-    let config = fsync::Config::load_from_file(&config_file).await?;
+    let mut config = fsync::Config::load_from_file(&config_file).await?;
     log::trace!("Loaded config: {config:?}");
+    fsyncd::logs::configure(&config.log_buffer);
+
+    log::info!("Secrets protection: {}", config.secrets);
+    let sealer = secrets::Sealer::new(config.secrets, &cli.instance).await?;
+    secrets::migrate_config(&config_file, &mut config, &sealer).await?;
 
     let local_root = config.local_dir.clone();
-    let local = storage::fs::FileSystem::new(&config.local_dir)?;
+    let mut local = storage::fs::FileSystem::new(&config.local_dir)?;
+    if let Some(min_free_space) = config.min_free_space {
+        log::info!(
+            "Keeping {min_free_space:?} of free space in {}",
+            config.local_dir
+        );
+        local = local.with_space_guard(storage::fs::SpaceGuard::new(min_free_space));
+    }
+    if config.placeholders {
+        log::info!("Creating placeholders for the remote-only files");
+        local = local.with_placeholders();
+    }
+    if let Some(scan_concurrency) = config.scan_concurrency {
+        log::info!("Reading {scan_concurrency} local entries concurrently");
+        local = local.with_scan_concurrency(scan_concurrency);
+    }
+    let buffers = BufferPool::new(config.buffer_budget.unwrap_or(buffers::DEFAULT_BUDGET));
+    if let Some(budget) = config.buffer_budget {
+        log::info!("Limiting the buffers of the transfers to {budget} bytes");
+    }
+    local = local.with_buffer_pool(buffers.clone());
+    if !config.mappings.is_empty() {
+        local = local.with_mappings(config.mappings.clone())?;
+    }
 
+    let mut digest = config.digest.clone();
+    let password = digest
+        .as_mut()
+        .and_then(|digest| digest.smtp.as_mut())
+        .and_then(|smtp| smtp.password.as_mut());
+    if let Some(password) = password {
+        *password = sealer.open_str(password)?;
+    }
+    let resumes = Resumes::default();
+    tokio::spawn(resumes.clone().run());
+    let disk_cache =
+        match DiskCache::open(CachePaths::instance(&cli.instance)?, config.cache_budget).await {
+            Ok(cache) => Some(Arc::new(cache)),
+            Err(err) => {
+                log::error!("Could not open the disk cache: {err:#}");
+                None
+            }
+        };
+    let mut options = ServiceOptions {
+        quota_warning: config.quota_warning,
+        placeholders: config.placeholders,
+        disk_cache: disk_cache.clone(),
+        hooks: config.hooks.clone(),
+        digest,
+        corrupt_files: Vec::new(),
+        max_retries: config.max_retries,
+        conflict_staleness: config.conflict_staleness,
+        mappings: config.mappings.clone(),
+        sync_mode: config.sync_mode,
+        maintenance: config.maintenance,
+        deferral: config.deferral,
+        status_file: config.status_file,
+        delete_guard: config.delete_guard,
+        root_guard: None,
+        config_fingerprint: config.fingerprint(),
+        buffers: buffers.clone(),
+        resumes: resumes.clone(),
+    };
+    let tree_options = BuildOptions {
+        ignore: IgnoreRules::global(&config.ignore)?,
+        max_file_size: config.max_file_size,
+        fs_caps: local_fs_caps(&cli.instance, &config.local_dir).await,
+    };
+    if let Some(max_file_size) = config.max_file_size {
+        log::info!("Skipping files larger than {max_file_size} bytes");
+    }
+    if !config.sync_mode.is_bidirectional() {
+        log::info!("Synchronizing in {} mode", config.sync_mode);
+    }
     let token_cache_path = &inst::token_cache_file(&cli.instance)?;
+    // not optional as the audit log: pairing the local directory with another root
+    // would report bogus conflicts, whose resolution could delete the local files
+    let root_guard = RootGuard::open(inst::remote_root_file(&cli.instance)?, config_file.clone())
+        .await
+        .context("Could not read the recorded remote root")?;
+    // the root of the last start, the remote one is resolved in the background
+    if let Some(recorded) = root_guard.recorded() {
+        root::check_overlaps(&cli.instance, &recorded, cli.strict)?;
+    }
+    let root_guard = Arc::new(root_guard);
+    options.root_guard = Some(root_guard.clone());
+    let content_key = if config.encryption {
+        if !matches!(config.provider, fsync::ProviderConfig::GoogleDrive(_)) {
+            anyhow::bail!("The encryption of the contents requires the Google Drive provider");
+        }
+        log::info!("Encrypting the file contents in the remote storage");
+        Some(secrets::content_key(&cli.instance).await?)
+    } else {
+        None
+    };
 
     match &config.provider {
         fsync::ProviderConfig::GoogleDrive(config) => {
@@ -91,32 +277,204 @@ async fn run(args: Vec<OsString>, shutdown_ref: ShutdownRef) -> anyhow::Result<(
                 config.secret.client_id.as_str()
             );
 
+            let mut secret = config.secret.clone();
+            secret.client_secret = sealer.open_client_secret(&secret.client_secret)?;
+            if secret.device_auth_url.is_none() {
+                secret.device_auth_url = Some(fsync::oauth2::DeviceAuthorizationUrl::new(
+                    fsync::oauth2::GOOGLE_DEVICE_AUTH_URL.to_string(),
+                )?);
+            }
+
             let client = reqwest::Client::builder().build()?;
             let auth = oauth2::Client::new(
-                config.secret.clone(),
-                oauth2::TokenPersist::MemoryAndDisk(token_cache_path.into()),
+                secret,
+                config.auth_flow,
+                config.redirect_server,
+                oauth2::TokenPersist::MemoryAndDisk(token_cache_path.into(), sealer),
                 Some(client.clone()),
             )
             .await?;
-            let remote =
-                storage::drive::GoogleDrive::new(auth, client, config.root.as_deref().into())
-                    .await?;
-            start_cache_service(cli, local, remote, local_root, shutdown_ref).await
+            options.corrupt_files.extend(auth.corrupt_cache().await);
+            {
+                // the token may have expired during the sleep, it is refreshed before the requests
+                // need it
+                let auth = auth.clone();
+                let mut resumed = resumes.subscribe();
+                tokio::spawn(async move {
+                    while resumed.changed().await.is_ok() {
+                        if let Err(err) = auth.refresh_expiring(oauth2::RESUME_MARGIN).await {
+                            log::warn!("Could not refresh the token after the resume: {err}");
+                        }
+                    }
+                });
+            }
+            // the Drive storage is initialized in the background, so that the cached
+            // tree is served without waiting for the network
+            let root = config.root.clone();
+            let max_chunk_size = config.max_upload_chunk_size;
+            let buffers = buffers.clone();
+            let fetch_sharing = !config.skip_sharing;
+            if !fetch_sharing {
+                log::info!("Not fetching the sharing of the remote entries");
+            }
+            let content_cache = match disk_cache {
+                _ if config.skip_content_cache => None,
+                Some(cache) if cache.budget().is_some() => Some(cache),
+                Some(_) => {
+                    log::info!("Not caching the content of the remote files without cache budget");
+                    None
+                }
+                None => None,
+            };
+            let instance = cli.instance.clone();
+            let strict = cli.strict;
+            let config_file = config_file.clone();
+            let account = config.account.clone();
+            if let Some(account) = &account {
+                log::info!("Expecting the token of {account}");
+            }
+            let remote = storage::lazy::Lazy::new(move || {
+                let auth = auth.clone();
+                let client = client.clone();
+                let root = root.clone();
+                let instance = instance.clone();
+                let config_file = config_file.clone();
+                let account = account.clone();
+                let root_guard = root_guard.clone();
+                let content_key = content_key.clone();
+                let content_cache = content_cache.clone();
+                let buffers = buffers.clone();
+                async move {
+                    let drive =
+                        storage::drive::GoogleDrive::new(auth, client, root.as_deref().into())
+                            .await?;
+                    let id = drive.root_id().to_string();
+                    let ancestry = match root_guard.recorded_ancestry(&id) {
+                        Some(ancestry) => ancestry,
+                        None => match drive.root_ancestry().await {
+                            Ok(ancestry) => ancestry.iter().map(ToString::to_string).collect(),
+                            Err(err) => {
+                                log::warn!("Could not resolve the folders containing the remote root: {err}");
+                                Vec::new()
+                            }
+                        },
+                    };
+                    let resolved = fsync::overlap::RecordedRoot {
+                        root: fsync::RemoteRoot {
+                            id,
+                            config: root::drive_config_root(root.as_deref()),
+                        },
+                        ancestry,
+                    };
+                    root::check_overlaps(&instance, &resolved, strict)?;
+                    root_guard.check(resolved).await?;
+                    storage::drive::check_account(&instance, account.as_deref(), drive.account())?;
+                    if let (None, Some(authorized)) = (&account, drive.account()) {
+                        match storage::drive::record_account(&config_file, authorized).await {
+                            Ok(()) => {
+                                log::info!("Recorded {authorized} as the account of {instance}")
+                            }
+                            Err(err) => {
+                                log::warn!("Could not record the account of {instance}: {err:#}")
+                            }
+                        }
+                    }
+                    let drive = drive
+                        .with_max_chunk_size(max_chunk_size)
+                        .with_buffer_pool(buffers)
+                        .with_sharing(fetch_sharing)
+                        .with_content_cache(content_cache);
+                    Ok(storage::crypt::Crypt::new(drive, content_key))
+                }
+            });
+            start_cache_service(
+                cli,
+                local,
+                remote,
+                local_root,
+                options,
+                tree_options,
+                shutdown_ref,
+            )
+            .await
         }
         fsync::ProviderConfig::LocalFs(path) => {
             log::info!("Initializing Local File system storage in {path}",);
 
-            let remote = storage::fs::FileSystem::new(path)?;
-            start_service(cli, local, remote, local_root, shutdown_ref).await
+            let remote = storage::fs::FileSystem::new(path)?.with_buffer_pool(buffers.clone());
+            let id = tokio::fs::canonicalize(path)
+                .await
+                .with_context(|| format!("Could not resolve {path}"))?;
+            let resolved = fsync::overlap::RecordedRoot {
+                root: fsync::RemoteRoot {
+                    id: id.to_string_lossy().into_owned(),
+                    config: path.to_string(),
+                },
+                ancestry: id
+                    .ancestors()
+                    .map(|dir| dir.to_string_lossy().into_owned())
+                    .collect(),
+            };
+            root::check_overlaps(&cli.instance, &resolved, cli.strict)?;
+            root_guard.check(resolved).await?;
+            let service = Service::new_with(local, remote, local_root, tree_options).await?;
+            start_service(cli, service, options, shutdown_ref).await
         }
     }
 }
 
+/// Probe the capabilities of the local filesystem and record them.
+/// If probing fails, the last recorded capabilities are used.
+async fn local_fs_caps(instance_name: &str, local_dir: &FsPath) -> Option<FsCaps> {
+    let record = inst::fs_caps_file(instance_name).ok()?;
+    match FsCaps::probe(local_dir) {
+        Ok(caps) => {
+            log::info!("Local filesystem capabilities: {caps:?}");
+            if let Err(err) = caps.save(&record).await {
+                log::warn!("Could not record the local filesystem capabilities: {err:#}");
+            }
+            Some(caps)
+        }
+        Err(err) => {
+            log::warn!("Could not probe the local filesystem: {err}");
+            FsCaps::load(&record).await.ok()
+        }
+    }
+}
+
+/// Options of the service, from the config
+struct ServiceOptions {
+    quota_warning: Option<f64>,
+    placeholders: bool,
+    disk_cache: Option<Arc<DiskCache>>,
+    hooks: Vec<fsync::Hook>,
+    /// The digest config, with the SMTP password in clear text
+    digest: Option<fsync::Digest>,
+    /// The persisted files set aside because they could not be read
+    corrupt_files: Vec<fsync::CorruptFile>,
+    max_retries: Option<u32>,
+    conflict_staleness: Option<u64>,
+    mappings: Vec<fsync::Mapping>,
+    sync_mode: fsync::SyncMode,
+    maintenance: fsync::Maintenance,
+    deferral: fsync::Deferral,
+    status_file: Option<fsync::StatusFile>,
+    delete_guard: fsync::DeleteGuard,
+    root_guard: Option<Arc<RootGuard>>,
+    config_fingerprint: String,
+    /// The budget of the buffers of the transfers, shared by both storages
+    buffers: BufferPool,
+    /// The resumes of the system from sleep
+    resumes: Resumes,
+}
+
 async fn start_cache_service<L, R>(
     cli: Cli,
     local: L,
-    remote: R,
+    remote: storage::lazy::Lazy<R>,
     local_root: FsPathBuf,
+    mut options: ServiceOptions,
+    tree_options: BuildOptions,
     shutdown_ref: ShutdownRef,
 ) -> anyhow::Result<()>
 where
@@ -128,31 +486,198 @@ where
     log::trace!("mkdir -p {remote_cache_dir}");
     tokio::fs::create_dir_all(remote_cache_dir).await.unwrap();
 
-    let persist = CachePersist::MemoryAndDisk{
-        path: remote_cache_path,
-        ignore_initial_cache: cli.ignore_remote_cache,
+    let persist = |ignore_initial_cache| CachePersist::MemoryAndDisk {
+        path: remote_cache_path.clone(),
+        ignore_initial_cache,
+    };
+    let load_cache = !cli.ignore_remote_cache && remote_cache_path.exists();
+    let cached = if load_cache {
+        storage::cache::CacheStorage::new(remote.clone(), persist(false))
+            .await
+            .inspect_err(|err| log::warn!("Could not load the remote cache: {err:#}"))
+            .ok()
+    } else {
+        None
+    };
+    let loaded = cached.is_some();
+    let cached = match cached {
+        Some(cached) => cached,
+        None => {
+            log::info!("Waiting for the remote storage to populate its cache");
+            remote.ready().await?;
+            storage::cache::CacheStorage::new(remote.clone(), persist(true)).await?
+        }
     };
-    let remote = storage::cache::CacheStorage::new(remote, persist).await?;
 
-    start_service(cli, local, remote, local_root, shutdown_ref).await
+    options.corrupt_files.extend(cached.corrupt_file().cloned());
+
+    let mut service = Service::new_with(local, cached, local_root, tree_options)
+        .await?
+        .with_remote_phase(remote.phase());
+    if loaded {
+        // the tree is as fresh as the persisted cache, until the remote folders are listed again
+        service = service.with_aggregation(Aggregator::default());
+    }
+    start_service(cli, service, options, shutdown_ref).await
 }
 
 async fn start_service<L, R>(
     cli: Cli,
-    local: L,
-    remote: R,
-    local_root: FsPathBuf,
+    mut service: Service<L, R>,
+    options: ServiceOptions,
     shutdown_ref: ShutdownRef,
 ) -> anyhow::Result<()>
 where
     L: storage::LocalStorage,
     R: storage::Storage,
 {
-    let service = Service::new(local, remote.clone(), local_root).await?;
+    if let Some(quota_warning) = options.quota_warning {
+        service = service.with_quota_warning(quota_warning);
+    }
+    if let Some(max_retries) = options.max_retries {
+        service = service.with_retries(max_retries, service::DEFAULT_RETRY_DELAY);
+    }
+    if let Some(staleness) = options.conflict_staleness {
+        service = service.with_conflict_staleness(Duration::from_secs(staleness));
+    }
+    service = service
+        .with_corrupt_files(options.corrupt_files)
+        .with_mappings(options.mappings)
+        .with_sync_mode(options.sync_mode)
+        .with_maintenance(options.maintenance)
+        .with_policy(Policy::new(options.deferral, policy::system_probe()))
+        .with_delete_guard(options.delete_guard)
+        .with_config_file(
+            inst::config_file(&cli.instance)?,
+            options.config_fingerprint,
+        );
+    if options.delete_guard.is_disabled() {
+        log::warn!("The deletions of large sub-trees are not confirmed");
+    }
+    if !options.deferral.is_default() {
+        log::info!("Postponing the scheduled work: {:?}", options.deferral);
+    }
+    if let Some(status_file) = options.status_file {
+        log::info!(
+            "Writing the status file of the share at most every {} minutes",
+            status_file.interval
+        );
+        service = service.with_status_file(status_file.interval());
+    }
+    if let Some(root_guard) = options.root_guard {
+        service = service.with_root_guard(root_guard);
+    }
+    if cli.self_check {
+        log::warn!(
+            "Self-check mode: the tree is checked against the storages after each operation"
+        );
+        service = service.with_self_check();
+    }
+    let audit_file = inst::audit_log_file(&cli.instance)?;
+    match AuditLog::open(audit_file).await {
+        Ok(audit) => service = service.with_audit_log(audit),
+        Err(err) => log::error!("Could not open the audit log, actions won't be recorded: {err:#}"),
+    }
+    match Journal::open(inst::journal_file(&cli.instance)?).await {
+        Ok(journal) => service = service.with_journal(journal),
+        Err(err) => {
+            log::error!("Could not open the journal, operations won't be recoverable: {err:#}")
+        }
+    }
+    match Bases::open(inst::merge_bases_dir(&cli.instance)?, merge::BASES_BUDGET).await {
+        Ok(bases) => service = service.with_merge_bases(bases),
+        Err(err) => {
+            log::error!("Could not open the merge bases, text files won't be merged: {err:#}")
+        }
+    }
+    // not optional as the audit log: ignoring the pins would expose the pinned entries
+    let pins = Pins::open(inst::pins_file(&cli.instance)?)
+        .await
+        .context("Could not read the pinned entries")?;
+    let mut service = service.with_pins(pins);
+    if options.placeholders {
+        let placeholders = Placeholders::open(inst::placeholders_file(&cli.instance)?)
+            .await
+            .context("Could not read the placeholders record")?;
+        service = service.with_placeholders(placeholders);
+    }
+    match storage::hash::Cache::open(inst::checksums_file(&cli.instance)?).await {
+        Ok(cache) => service = service.with_checksums(cache),
+        Err(err) => log::error!("Could not open the checksums cache: {err:#}"),
+    }
+    match Counters::open(inst::counters_file(&cli.instance)?).await {
+        Ok(counters) => service = service.with_counters(counters),
+        Err(err) => log::error!("Could not read the lifetime counters: {err:#}"),
+    }
+    let checkpoints = Checkpoints::open(
+        inst::checkpoint_file(&cli.instance)?,
+        inst::verify_checkpoint_file(&cli.instance)?,
+    );
+    match checkpoints.await {
+        Ok(checkpoints) => service = service.with_checkpoints(checkpoints),
+        Err(err) => log::error!("Could not read the checkpoints: {err:#}"),
+    }
+    if let Some(cache) = options.disk_cache {
+        service = service.with_disk_cache(cache);
+    }
+    service = service
+        .with_buffer_pool(options.buffers)
+        .with_resumes(options.resumes);
+    if !options.hooks.is_empty() {
+        log::info!("Running {} hooks on events", options.hooks.len());
+        service = service.with_hooks(Hooks::spawn(options.hooks));
+    }
+    if let Some(digest) = options.digest {
+        log::info!(
+            "Delivering a digest every day at {}",
+            digest.at.format("%H:%M")
+        );
+        match Digest::open(digest, inst::digest_state_file(&cli.instance)?).await {
+            Ok(digest) => service = service.with_digest(digest),
+            Err(err) => log::error!("Could not read the digest state: {err:#}"),
+        }
+    }
+    // before serving any operation, which could otherwise be applied twice
+    match service.recover().await {
+        Ok(0) => (),
+        Ok(count) => log::warn!("Recovered {count} incomplete operations from the journal"),
+        Err(err) => log::error!("Could not recover the incomplete operations: {err}"),
+    }
+    match service.repair_shifted_mtimes().await {
+        Ok(repaired) if repaired.is_empty() => (),
+        Ok(repaired) => log::info!(
+            "Repaired {} local modification times shifted by a timezone change",
+            repaired.len()
+        ),
+        Err(err) => log::error!("Could not repair the shifted modification times: {err}"),
+    }
+    match service.repair_identical_conflicts().await {
+        Ok(repaired) if repaired.is_empty() => (),
+        Ok(repaired) => log::info!(
+            "Paired {} conflicting files with the same content on both sides",
+            repaired.len()
+        ),
+        Err(err) => log::error!("Could not repair the conflicts of identical files: {err}"),
+    }
     let service = Arc::new(service);
 
     shutdown_ref.set(service.clone()).await;
 
+    {
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(err) = service.update_placeholders().await {
+                log::error!("Could not update the placeholders: {err}");
+            }
+        });
+    }
+
+    tokio::spawn(service.clone().run_digest());
+    tokio::spawn(service.clone().run_maintenance());
+    tokio::spawn(service.clone().run_aggregation());
+    tokio::spawn(service.clone().run_counters());
+    tokio::spawn(service.clone().run_resumes());
+
     let (abort_handle, abort_reg) = AbortHandle::new_pair();
 
     let rpc = RpcService::new(service, abort_handle).await;
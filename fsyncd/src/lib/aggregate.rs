@@ -0,0 +1,142 @@
+//! Background aggregation of the remote folder sizes.
+//!
+//! A tree built from the persisted remote cache is only as fresh as the last population
+//! of the cache. The aggregator walks the remote folders at low priority, lists each of them
+//! again from the remote storage and updates the tree, so that the stats of the folders
+//! get exact. A folder that the user is looking at is prioritized with
+//! [`fsync::Fsync::aggregate_now`].
+
+use std::{collections::HashSet, sync::Mutex, time::Duration};
+
+use fsync::{
+    path::{Path, PathBuf},
+    stat::Aggregation,
+};
+use tokio::sync::mpsc;
+
+/// Default minimum delay between two listings of the remote storage
+pub const DEFAULT_MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Delay before checking again whether the operations are done
+pub const BUSY_RETRY: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct Aggregator {
+    min_interval: Duration,
+    state: Mutex<State>,
+    requests_tx: mpsc::UnboundedSender<PathBuf>,
+    requests_rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<PathBuf>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The folders listed since the start
+    listed: HashSet<PathBuf>,
+    /// The folders whose whole sub-tree was listed since the start
+    exact: HashSet<PathBuf>,
+}
+
+impl Default for Aggregator {
+    fn default() -> Self {
+        Self::new(DEFAULT_MIN_INTERVAL)
+    }
+}
+
+impl Aggregator {
+    /// An aggregator that lists the remote storage at most once per `min_interval`
+    pub fn new(min_interval: Duration) -> Self {
+        let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+        Self {
+            min_interval,
+            state: Mutex::default(),
+            requests_tx,
+            requests_rx: tokio::sync::Mutex::new(requests_rx),
+        }
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        self.min_interval
+    }
+
+    /// The aggregation status of the remote folder at `path`
+    pub fn status(&self, path: &Path) -> Aggregation {
+        let state = self.state.lock().unwrap();
+        if state.exact.contains(path) {
+            Aggregation::Exact
+        } else if state.listed.contains(path) {
+            Aggregation::Partial
+        } else {
+            Aggregation::Pending
+        }
+    }
+
+    pub fn is_exact(&self, path: &Path) -> bool {
+        self.state.lock().unwrap().exact.contains(path)
+    }
+
+    /// Ask to aggregate the sub-tree at `path` before the rest of the tree
+    pub fn request(&self, path: PathBuf) {
+        // the receiver lives as long as the aggregator
+        let _ = self.requests_tx.send(path);
+    }
+
+    /// The next request, if one was sent since the last call
+    pub(crate) async fn try_next_request(&self) -> Option<PathBuf> {
+        self.requests_rx.lock().await.try_recv().ok()
+    }
+
+    /// Wait for the next request
+    pub(crate) async fn next_request(&self) -> Option<PathBuf> {
+        self.requests_rx.lock().await.recv().await
+    }
+
+    pub(crate) fn set_listed(&self, path: &Path) {
+        self.state.lock().unwrap().listed.insert(path.to_owned());
+    }
+
+    pub(crate) fn set_exact(&self, path: &Path) {
+        self.state.lock().unwrap().exact.insert(path.to_owned());
+    }
+
+    /// Forget the status of `path` and of its descendants, to list them again.
+    /// The ancestors of `path` are no longer exact until it is.
+    pub(crate) fn restart(&self, path: &Path) {
+        self.forget(path);
+        let mut state = self.state.lock().unwrap();
+        let mut ancestor = path.parent();
+        while let Some(dir) = ancestor {
+            state.exact.remove(dir);
+            ancestor = dir.parent();
+        }
+    }
+
+    /// Forget the status of `path` and of its descendants, that are no longer in the remote storage
+    pub(crate) fn forget(&self, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        let keep = |p: &PathBuf| p != path && !path.is_ancestor_of(p);
+        state.listed.retain(keep);
+        state.exact.retain(keep);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status() {
+        let aggregator = Aggregator::default();
+        let path = Path::new("/a");
+        assert_eq!(aggregator.status(path), Aggregation::Pending);
+        aggregator.set_listed(path);
+        assert_eq!(aggregator.status(path), Aggregation::Partial);
+        aggregator.set_listed(Path::new("/a/b"));
+        aggregator.set_exact(Path::new("/a/b"));
+        aggregator.set_exact(path);
+        assert_eq!(aggregator.status(path), Aggregation::Exact);
+
+        aggregator.forget(path);
+        assert_eq!(aggregator.status(path), Aggregation::Pending);
+        assert_eq!(aggregator.status(Path::new("/a/b")), Aggregation::Pending);
+    }
+}
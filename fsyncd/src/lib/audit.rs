@@ -0,0 +1,211 @@
+//! Writing of the audit log, see [`fsync::audit`]
+
+use chrono::Utc;
+use fsync::{
+    audit::{self, Record, Undo},
+    path::{FsPath, FsPathBuf, Path},
+    Action, Operation,
+};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+/// Size above which the log is rotated
+pub const MAX_LOG_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct AuditLog {
+    path: FsPathBuf,
+    max_size: u64,
+    next_id: Mutex<u64>,
+}
+
+impl AuditLog {
+    /// Open the log at `path`, continuing the numbering of its records
+    pub async fn open(path: FsPathBuf) -> anyhow::Result<Self> {
+        Self::open_with(path, MAX_LOG_SIZE).await
+    }
+
+    pub async fn open_with(path: FsPathBuf, max_size: u64) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let read_path = path.clone();
+        let records =
+            tokio::task::spawn_blocking(move || audit::read_records(&read_path)).await??;
+        let next_id = records.last().map(|r| r.id + 1).unwrap_or(1);
+        Ok(Self {
+            path,
+            max_size,
+            next_id: Mutex::new(next_id),
+        })
+    }
+
+    /// Append the record of `action`, performed on `path` by `operation`
+    pub async fn append(
+        &self,
+        operation: Option<&Operation>,
+        path: &Path,
+        action: &Action,
+        bytes: u64,
+    ) -> anyhow::Result<()> {
+        let mut next_id = self.next_id.lock().await;
+        let record = Record {
+            id: *next_id,
+            time: Utc::now(),
+            operation: operation.cloned(),
+            path: path.to_owned(),
+            action: action.clone(),
+            bytes,
+            undo: Undo::for_action(action),
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        let size = match fs::metadata(&self.path).await {
+            Ok(md) => md.len(),
+            Err(_) => 0,
+        };
+        if size > 0 && size + line.len() as u64 > self.max_size {
+            fs::rename(&self.path, audit::rotated(&self.path)).await?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&line).await?;
+        file.flush().await?;
+
+        *next_id += 1;
+        Ok(())
+    }
+
+    /// Drop the oldest records, until the log and its rotated records fit in `max_size` bytes.
+    /// The current log is kept whole, as it is rotated at its own size cap.
+    /// Returns the number of bytes dropped, or that would be dropped with `dry_run`.
+    pub async fn compact(&self, max_size: u64, dry_run: bool) -> anyhow::Result<u64> {
+        // no record is appended meanwhile
+        let _next_id = self.next_id.lock().await;
+        let rotated = audit::rotated(&self.path);
+        let size = file_size(&self.path).await;
+        let rotated_size = file_size(&rotated).await;
+        let excess = (size + rotated_size).saturating_sub(max_size);
+        if excess == 0 || rotated_size == 0 {
+            return Ok(0);
+        }
+        let content = fs::read(&rotated).await?;
+        // records are dropped whole, up to the end of the line that covers the excess
+        let cut = match excess as usize {
+            excess if excess < content.len() => content[excess - 1..]
+                .iter()
+                .position(|b| *b == b'\n')
+                .map(|pos| excess + pos)
+                .unwrap_or(content.len()),
+            _ => content.len(),
+        };
+        if !dry_run {
+            if cut == content.len() {
+                fs::remove_file(&rotated).await?;
+            } else {
+                let mut name = rotated.file_name().unwrap_or_default().to_owned();
+                name.push_str(".tmp");
+                let tmp = rotated.with_file_name(name);
+                fs::write(&tmp, &content[cut..]).await?;
+                fs::rename(&tmp, &rotated).await?;
+            }
+        }
+        Ok(cut as u64)
+    }
+
+    pub fn path(&self) -> &FsPath {
+        &self.path
+    }
+}
+
+async fn file_size(path: &FsPath) -> u64 {
+    fs::metadata(path).await.map(|md| md.len()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use fsync::{path::PathBuf, StorageDir};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn append_and_rotate() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-audit-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let operation = Operation::SyncDeep(PathBuf::root());
+        let action = Action::Copy(StorageDir::LocalToRemote);
+
+        let log = AuditLog::open_with(path.clone(), 600).await.unwrap();
+        for i in 0..4 {
+            let entry = PathBuf::from(format!("/file{i}.txt"));
+            log.append(Some(&operation), &entry, &action, 10)
+                .await
+                .unwrap();
+        }
+        assert!(audit::rotated(&path).exists());
+
+        // the numbering continues across rotations and reopening
+        let log = AuditLog::open_with(path.clone(), 600).await.unwrap();
+        log.append(Some(&operation), Path::new("/last.txt"), &action, 10)
+            .await
+            .unwrap();
+        let records = audit::read_records(&path).unwrap();
+        let ids: Vec<_> = records.iter().map(|r| r.id).collect();
+        assert_eq!(records.last().unwrap().id, 5);
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(records.last().unwrap().path, PathBuf::from("/last.txt"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn compact_drops_the_oldest_records() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-audit-compact-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let operation = Operation::SyncDeep(PathBuf::root());
+        let action = Action::Copy(StorageDir::LocalToRemote);
+
+        let log = AuditLog::open_with(path.clone(), 1000).await.unwrap();
+        for i in 0..8 {
+            let entry = PathBuf::from(format!("/file{i}.txt"));
+            log.append(Some(&operation), &entry, &action, 10)
+                .await
+                .unwrap();
+        }
+        let size = |path: &FsPath| std::fs::metadata(path).map(|md| md.len()).unwrap_or(0);
+        let total = size(&path) + size(&audit::rotated(&path));
+        let ids = |path: &FsPath| -> Vec<u64> {
+            audit::read_records(path)
+                .unwrap()
+                .iter()
+                .map(|r| r.id)
+                .collect()
+        };
+        let before = ids(&path);
+
+        assert_eq!(log.compact(total, false).await.unwrap(), 0);
+        let dropped = log.compact(total - 1, true).await.unwrap();
+        assert!(dropped > 0);
+        assert_eq!(ids(&path), before);
+
+        assert_eq!(log.compact(total - 1, false).await.unwrap(), dropped);
+        let after = ids(&path);
+        assert_eq!(after[..], before[1..]);
+
+        // the current log is kept whole
+        log.compact(0, false).await.unwrap();
+        assert!(!audit::rotated(&path).exists());
+        assert_eq!(after.last(), ids(&path).last());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
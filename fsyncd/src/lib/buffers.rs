@@ -0,0 +1,312 @@
+//! A memory budget shared by the buffers of all the transfers of the daemon.
+//!
+//! The uploads and downloads rent their buffers from a [`BufferPool`], and wait when
+//! the budget is exhausted, so that many concurrent transfers throttle themselves instead
+//! of allocating without bound. The budget is accounted in units of [`ALIGN`] bytes.
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::Semaphore;
+
+/// Budget of the buffers when the config doesn't set one
+pub const DEFAULT_BUDGET: u64 = 256 * 1024 * 1024;
+/// The rentals are rounded up to a multiple of this size
+pub const ALIGN: u64 = 64 * 1024;
+
+/// The budget of memory of the buffers, shared by its clones
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    units: u32,
+    semaphore: Semaphore,
+    /// The units leased. The permits of the semaphore are also taken by the waiters,
+    /// as they become available.
+    leased: AtomicU64,
+    waiters: AtomicU64,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUDGET)
+    }
+}
+
+impl BufferPool {
+    /// A pool of `budget` bytes, rounded down to a multiple of [`ALIGN`]
+    pub fn new(budget: u64) -> Self {
+        let units = (budget / ALIGN).clamp(1, u32::MAX as u64) as u32;
+        Self {
+            inner: Arc::new(Inner {
+                units,
+                semaphore: Semaphore::new(units as usize),
+                leased: AtomicU64::new(0),
+                waiters: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn budget(&self) -> u64 {
+        self.inner.units as u64 * ALIGN
+    }
+
+    /// Reserve `bytes` of the budget, waiting for them to be available.
+    /// A reservation larger than the budget is reduced to the whole budget.
+    pub async fn lease(&self, bytes: u64) -> Lease {
+        let units = self.units(bytes);
+        self.acquire(units).await;
+        Lease {
+            pool: Some(self.clone()),
+            units,
+        }
+    }
+
+    /// Rent a zeroed buffer of `len` bytes, waiting for them to be available.
+    /// `len` is reduced to the budget if it is larger.
+    pub async fn rent(&self, len: usize) -> Buffer {
+        let lease = self.lease(len as u64).await;
+        let len = len.min(lease.bytes() as usize);
+        Buffer {
+            data: vec![0; len],
+            _lease: lease,
+        }
+    }
+
+    pub fn usage(&self) -> fsync::BufferUsage {
+        fsync::BufferUsage {
+            budget: self.budget(),
+            in_use: self.inner.leased.load(Ordering::Relaxed) * ALIGN,
+            waiters: self.inner.waiters.load(Ordering::Relaxed),
+        }
+    }
+
+    fn units(&self, bytes: u64) -> u32 {
+        bytes.div_ceil(ALIGN).min(self.inner.units as u64) as u32
+    }
+
+    async fn acquire(&self, units: u32) {
+        if let Ok(permit) = self.inner.semaphore.try_acquire_many(units) {
+            permit.forget();
+        } else {
+            self.inner.waiters.fetch_add(1, Ordering::Relaxed);
+            let _waiting = Waiting(&self.inner.waiters);
+            // the semaphore is never closed
+            let permit = self.inner.semaphore.acquire_many(units).await.unwrap();
+            permit.forget();
+        }
+        self.inner.leased.fetch_add(units as u64, Ordering::Relaxed);
+    }
+
+    fn release(&self, units: u32) {
+        self.inner.leased.fetch_sub(units as u64, Ordering::Relaxed);
+        self.inner.semaphore.add_permits(units as usize);
+    }
+}
+
+/// Decrements the waiters when the wait completes or is cancelled
+struct Waiting<'a>(&'a AtomicU64);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A part of the budget, given back when dropped
+#[derive(Debug)]
+pub struct Lease {
+    /// `None` if the memory is not limited
+    pool: Option<BufferPool>,
+    units: u32,
+}
+
+impl Lease {
+    /// The number of bytes of the lease, or `u64::MAX` if the memory is not limited
+    pub fn bytes(&self) -> u64 {
+        match &self.pool {
+            Some(_) => self.units as u64 * ALIGN,
+            None => u64::MAX,
+        }
+    }
+
+    /// Extend the lease to `bytes` if they are available without waiting,
+    /// and return whether the lease covers `bytes`.
+    /// Waiting while holding a lease could block the transfers on each other.
+    pub fn try_grow(&mut self, bytes: u64) -> bool {
+        let Some(pool) = &self.pool else {
+            return true;
+        };
+        let units = pool.units(bytes);
+        if bytes > pool.budget() {
+            return false;
+        }
+        if units <= self.units {
+            return true;
+        }
+        match pool.inner.semaphore.try_acquire_many(units - self.units) {
+            Ok(permit) => {
+                permit.forget();
+                let grown = units - self.units;
+                pool.inner.leased.fetch_add(grown as u64, Ordering::Relaxed);
+                self.units = units;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.release(self.units);
+        }
+    }
+}
+
+/// Reserve `bytes` in `pool`, or leave the memory unlimited without pool
+pub async fn lease(pool: Option<&BufferPool>, bytes: u64) -> Lease {
+    match pool {
+        Some(pool) => pool.lease(bytes).await,
+        None => Lease {
+            pool: None,
+            units: 0,
+        },
+    }
+}
+
+/// Rent a zeroed buffer of `len` bytes from `pool`, or allocate it without pool
+pub async fn rent(pool: Option<&BufferPool>, len: usize) -> Buffer {
+    match pool {
+        Some(pool) => pool.rent(len).await,
+        None => Buffer {
+            data: vec![0; len],
+            _lease: lease(None, 0).await,
+        },
+    }
+}
+
+/// A buffer rented from a [`BufferPool`], given back when dropped
+#[derive(Debug)]
+pub struct Buffer {
+    data: Vec<u8>,
+    _lease: Lease,
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn leases_are_aligned_and_given_back() {
+        let pool = BufferPool::new(10 * ALIGN + 100);
+        assert_eq!(pool.budget(), 10 * ALIGN);
+
+        assert_eq!(pool.lease(100 * ALIGN).await.bytes(), 10 * ALIGN);
+
+        let a = pool.lease(1).await;
+        let b = pool.rent(3 * ALIGN as usize - 1).await;
+        assert_eq!(a.bytes(), ALIGN);
+        assert_eq!(b.len(), 3 * ALIGN as usize - 1);
+        assert_eq!(pool.usage().in_use, 4 * ALIGN);
+
+        let mut c = pool.lease(6 * ALIGN).await;
+        assert_eq!(c.bytes(), 6 * ALIGN);
+        assert!(!c.try_grow(7 * ALIGN));
+        drop(a);
+        assert!(c.try_grow(7 * ALIGN));
+        assert!(!c.try_grow(11 * ALIGN));
+
+        drop(b);
+        drop(c);
+        assert_eq!(pool.usage().in_use, 0);
+    }
+
+    #[tokio::test]
+    async fn waiters_are_counted() {
+        let pool = BufferPool::new(4 * ALIGN);
+        let held = pool.lease(3 * ALIGN).await;
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.lease(2 * ALIGN).await.bytes() }
+        });
+        while pool.usage().waiters == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(pool.usage().in_use, 3 * ALIGN);
+        drop(held);
+        assert_eq!(waiting.await.unwrap(), 2 * ALIGN);
+        assert_eq!(
+            pool.usage(),
+            fsync::BufferUsage {
+                budget: 4 * ALIGN,
+                in_use: 0,
+                waiters: 0,
+            }
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn rentals_stay_within_the_budget() {
+        const BUDGET: u64 = 32 * ALIGN;
+
+        let pool = BufferPool::new(BUDGET);
+        let rented = Arc::new(AtomicU64::new(0));
+        let peak = Arc::new(AtomicU64::new(0));
+
+        // simulated transfers renting buffers of various sizes, some growing them
+        let transfers = (0..64u64).map(|t| {
+            let pool = pool.clone();
+            let rented = rented.clone();
+            let peak = peak.clone();
+            tokio::spawn(async move {
+                for i in 0..20u64 {
+                    let len = ((t * 7 + i * 13) % 12 + 1) * ALIGN / 2;
+                    let mut lease = pool.lease(len).await;
+                    let mut bytes = lease.bytes();
+                    if i % 3 == 0 && lease.try_grow(2 * len) {
+                        bytes = lease.bytes();
+                    }
+                    let now = rented.fetch_add(bytes, Ordering::SeqCst) + bytes;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    assert!(pool.usage().in_use <= BUDGET);
+                    tokio::task::yield_now().await;
+                    rented.fetch_sub(bytes, Ordering::SeqCst);
+                }
+            })
+        });
+        for transfer in transfers.collect::<Vec<_>>() {
+            transfer.await.unwrap();
+        }
+
+        assert!(peak.load(Ordering::SeqCst) <= BUDGET);
+        assert!(peak.load(Ordering::SeqCst) > BUDGET / 2);
+        assert_eq!(pool.usage().in_use, 0);
+        assert_eq!(pool.usage().waiters, 0);
+    }
+}
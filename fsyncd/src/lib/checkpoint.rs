@@ -0,0 +1,255 @@
+//! Checkpoint of the scheduled deep operations stopped by their time budget,
+//! see [`fsync::Deferral::max_duration`].
+//!
+//! When the budget elapses, the operation stops starting new entries, and the entries
+//! left are recorded in the order of the walk. The next scheduled run of the same operation,
+//! or [`fsync::Fsync::resume`], operates on these entries only instead of walking the whole
+//! sub-tree again. The tree may change in between: the entries that were removed,
+//! or that no longer need work, are skipped by the resumed run.
+//! A checkpoint that can't be read is discarded, the next run then walks the whole sub-tree.
+//!
+//! The verification of the content, see [`fsync::Fsync::verify`], records the last file
+//! verified in a checkpoint of its own, from which `fsynctl verify --resume` continues.
+
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use fsync::{
+    filter::EntryFilter,
+    path::{FsPathBuf, PathBuf},
+    OperateOptions, Operation,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::persist;
+
+/// The persisted checkpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Checkpoint {
+    pub operation: Operation,
+    pub force_large: bool,
+    /// The filter of the operation, applied again to the sub-trees of the entries left
+    #[serde(default)]
+    pub filter: Option<EntryFilter>,
+    pub created: DateTime<Utc>,
+    /// The entries left, in the order of the walk
+    pub paths: Vec<PathBuf>,
+    /// The bytes left to transfer
+    pub bytes: u64,
+}
+
+impl Checkpoint {
+    pub fn options(&self) -> OperateOptions {
+        OperateOptions {
+            force_large: self.force_large,
+            filter: self.filter.clone(),
+            ..OperateOptions::default()
+        }
+    }
+
+    pub fn summary(&self) -> fsync::Checkpoint {
+        fsync::Checkpoint {
+            operation: self.operation.clone(),
+            created: self.created,
+            remaining: self.paths.len() as u32,
+            remaining_bytes: self.bytes,
+        }
+    }
+}
+
+/// A value persisted as JSON if opened from a file
+#[derive(Debug)]
+struct Slot<T> {
+    path: Option<FsPathBuf>,
+    current: Mutex<Option<T>>,
+}
+
+impl<T> Default for Slot<T> {
+    fn default() -> Self {
+        Self {
+            path: None,
+            current: Mutex::new(None),
+        }
+    }
+}
+
+impl<T> Slot<T>
+where
+    T: Clone + Serialize + DeserializeOwned,
+{
+    /// Open the value persisted at `path`, which does not need to exist
+    async fn open(path: FsPathBuf) -> anyhow::Result<Self> {
+        let current = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data)
+                .inspect_err(|err| log::warn!("Discarding the checkpoint {path}: {err}"))
+                .ok(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            current: Mutex::new(current),
+        })
+    }
+
+    fn get(&self) -> Option<T> {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Replace the value, and persist it
+    async fn set(&self, value: Option<T>) -> anyhow::Result<()> {
+        let data = value.as_ref().map(serde_json::to_vec).transpose()?;
+        *self.current.lock().unwrap() = value;
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        match data {
+            Some(data) => {
+                tokio::task::spawn_blocking(move || persist::atomic_write(&path, &data)).await??
+            }
+            None => match tokio::fs::remove_file(&path).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => (),
+            },
+        }
+        Ok(())
+    }
+}
+
+/// The checkpoints of the instance, persisted if opened from files
+#[derive(Debug, Default)]
+pub struct Checkpoints {
+    operation: Slot<Checkpoint>,
+    verification: Slot<fsync::VerifyCheckpoint>,
+}
+
+impl Checkpoints {
+    /// Open the checkpoints persisted at `path` and `verification_path`,
+    /// which do not need to exist
+    pub async fn open(path: FsPathBuf, verification_path: FsPathBuf) -> anyhow::Result<Self> {
+        Ok(Self {
+            operation: Slot::open(path).await?,
+            verification: Slot::open(verification_path).await?,
+        })
+    }
+
+    pub fn get(&self) -> Option<Checkpoint> {
+        self.operation.get()
+    }
+
+    /// The checkpoint left by a previous run of `operation`, if any
+    pub fn of(&self, operation: &Operation) -> Option<Checkpoint> {
+        self.get().filter(|c| c.operation == *operation)
+    }
+
+    pub fn summary(&self) -> Option<fsync::Checkpoint> {
+        self.operation
+            .current
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(Checkpoint::summary)
+    }
+
+    /// Replace the checkpoint, and persist it
+    pub async fn set(&self, checkpoint: Option<Checkpoint>) -> anyhow::Result<()> {
+        self.operation.set(checkpoint).await
+    }
+
+    /// Drop the checkpoint of `operation`, once a run of it completed within its budget
+    pub async fn complete(&self, operation: &Operation) -> anyhow::Result<()> {
+        if self.of(operation).is_none() {
+            return Ok(());
+        }
+        log::info!("{}: checkpoint completed", operation.path());
+        self.set(None).await
+    }
+
+    /// Where the last verification stopped
+    pub fn verification(&self) -> Option<fsync::VerifyCheckpoint> {
+        self.verification.get()
+    }
+
+    /// Replace the checkpoint of the verification, and persist it
+    pub async fn set_verification(
+        &self,
+        checkpoint: Option<fsync::VerifyCheckpoint>,
+    ) -> anyhow::Result<()> {
+        self.verification.set(checkpoint).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fsync::path::Path;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn persisted_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-checkpoint-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let path = dir.join("checkpoint.json");
+        let verify_path = dir.join("verify-checkpoint.json");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let operation = Operation::SyncDeep(PathBuf::root());
+        let checkpoints = Checkpoints::open(path.clone(), verify_path.clone())
+            .await
+            .unwrap();
+        assert!(checkpoints.get().is_none());
+        let checkpoint = Checkpoint {
+            operation: operation.clone(),
+            force_large: false,
+            filter: None,
+            created: Utc::now(),
+            paths: vec![PathBuf::from("/b.txt"), PathBuf::from("/a.txt")],
+            bytes: 12,
+        };
+        checkpoints.set(Some(checkpoint)).await.unwrap();
+
+        let checkpoints = Checkpoints::open(path.clone(), verify_path.clone())
+            .await
+            .unwrap();
+        let summary = checkpoints.summary().unwrap();
+        assert_eq!((summary.remaining, summary.remaining_bytes), (2, 12));
+        assert!(checkpoints
+            .of(&Operation::SyncDeep(PathBuf::from("/a")))
+            .is_none());
+        assert_eq!(
+            checkpoints.of(&operation).unwrap().paths[0],
+            Path::new("/b.txt")
+        );
+
+        checkpoints.complete(&operation).await.unwrap();
+        assert!(!path.exists());
+
+        // an unreadable checkpoint is discarded
+        std::fs::write(&path, b"{").unwrap();
+        let checkpoints = Checkpoints::open(path, verify_path.clone()).await.unwrap();
+        assert!(checkpoints.get().is_none());
+
+        let verification = fsync::VerifyCheckpoint {
+            path: PathBuf::from("/a"),
+            deep: true,
+            next: PathBuf::from("/a/b.txt"),
+            // persisted to the second
+            created: DateTime::from_timestamp(Utc::now().timestamp(), 0).unwrap(),
+        };
+        checkpoints
+            .set_verification(Some(verification.clone()))
+            .await
+            .unwrap();
+        let checkpoints = Checkpoints::open(dir.join("missing.json"), verify_path.clone())
+            .await
+            .unwrap();
+        assert!(checkpoints.get().is_none());
+        assert_eq!(checkpoints.verification(), Some(verification));
+        checkpoints.set_verification(None).await.unwrap();
+        assert!(!verify_path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+//! Confirmation of the deletion of large sub-trees, see [`fsync::DeleteGuard`],
+//! and of the public sharing of the remote files.
+//!
+//! A deletion above the thresholds is refused with a one-time token bound to the operation.
+//! The client shows the summary to the user, and submits the same operation again
+//! with the token within [`fsync::CONFIRMATION_WINDOW`].
+//! A token is consumed by the operation it confirms, and never confirms another one.
+//! The public sharing of a file is always refused first.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use fsync::{
+    fmt::{human_bytes, Unit},
+    path::Path,
+    stat, DeleteGuard, DeletionMethod, Error, Operation, StorageLoc, CONFIRMATION_WINDOW,
+};
+use tokio::time::Instant;
+
+#[derive(Debug)]
+pub struct Confirmations {
+    guard: DeleteGuard,
+    /// The operations refused, by token
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    operation: String,
+    expires: Instant,
+}
+
+impl Default for Confirmations {
+    fn default() -> Self {
+        Self::new(DeleteGuard::default())
+    }
+}
+
+impl Confirmations {
+    pub fn new(guard: DeleteGuard) -> Self {
+        Self {
+            guard,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check that `operation`, on the sub-tree of `stats`, may run with the `confirmation`
+    /// token of the client. Fails with [`Error::ConfirmationRequired`] and a new token otherwise.
+    pub fn check(
+        &self,
+        operation: &Operation,
+        stats: &stat::Tree,
+        confirmation: Option<&str>,
+    ) -> fsync::Result<()> {
+        let Some(method) = guarded_method(operation) else {
+            return Ok(());
+        };
+        if self.guard.is_disabled() {
+            return Ok(());
+        }
+        let sides = affected_sides(method, stats);
+        let exceeded = sides.iter().any(|(_, dir)| {
+            self.guard
+                .exceeded_by(dir.files.max(0) as u64, dir.data.max(0) as u64)
+        });
+        if !exceeded {
+            return Ok(());
+        }
+
+        let what = format!("the deletion of {}", operation.path());
+        self.confirm(format!("{operation:?}"), &what, confirmation, || {
+            summary(operation.path(), &sides, stats.node.conflicts)
+        })
+    }
+
+    /// Check that the remote file at `path` may be shared with anyone with the link,
+    /// with the `confirmation` token of the client.
+    /// Fails with [`Error::ConfirmationRequired`] and a new token otherwise.
+    pub fn check_share_public(&self, path: &Path, confirmation: Option<&str>) -> fsync::Result<()> {
+        let what = format!("the public sharing of {path}");
+        self.confirm(format!("SharePublic({path})"), &what, confirmation, || {
+            format!("Share {path} with anyone with the link")
+        })
+    }
+
+    /// Consume the `confirmation` token if it was returned for the operation `key`,
+    /// or fail with a new token and the summary of the operation
+    fn confirm(
+        &self,
+        key: String,
+        what: &str,
+        confirmation: Option<&str>,
+        summary: impl FnOnce() -> String,
+    ) -> fsync::Result<()> {
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.expires > now);
+        if let Some(token) = confirmation {
+            if pending.get(token).is_some_and(|p| p.operation == key) {
+                pending.remove(token);
+                log::info!("Confirmed {what}");
+                return Ok(());
+            }
+            log::warn!("Invalid or expired confirmation of {what}");
+        }
+        let token = make_token();
+        pending.insert(
+            token.clone(),
+            Pending {
+                operation: key,
+                expires: now + CONFIRMATION_WINDOW,
+            },
+        );
+        Err(Error::ConfirmationRequired(token, summary()))
+    }
+}
+
+/// Whether the guard applies to `operation`
+pub fn is_guarded(operation: &Operation) -> bool {
+    guarded_method(operation).is_some()
+}
+
+/// The deletion method of the operations that the guard applies to
+fn guarded_method(operation: &Operation) -> Option<DeletionMethod> {
+    match operation {
+        Operation::DeleteDeep(_, method) | Operation::Delete(_, method @ DeletionMethod::All) => {
+            Some(*method)
+        }
+        _ => None,
+    }
+}
+
+fn affected_sides(method: DeletionMethod, stats: &stat::Tree) -> Vec<(StorageLoc, stat::Dir)> {
+    if method.is_local() {
+        vec![(StorageLoc::Local, stats.local)]
+    } else if method.is_remote() {
+        vec![(StorageLoc::Remote, stats.remote)]
+    } else {
+        vec![
+            (StorageLoc::Local, stats.local),
+            (StorageLoc::Remote, stats.remote),
+        ]
+    }
+}
+
+/// E.g. "Delete /photos: 1523 local files (4.2 GiB), including 3 conflicts"
+fn summary(path: &Path, sides: &[(StorageLoc, stat::Dir)], conflicts: i64) -> String {
+    let sides: Vec<_> = sides
+        .iter()
+        .map(|(loc, dir)| {
+            let loc = match loc {
+                StorageLoc::Local => "local",
+                StorageLoc::Remote => "remote",
+            };
+            format!(
+                "{} {loc} files ({})",
+                dir.files,
+                human_bytes(dir.data.max(0) as u64, Unit::Binary)
+            )
+        })
+        .collect();
+    let mut summary = format!("Delete {path}: {}", sides.join(" and "));
+    if conflicts > 0 {
+        summary.push_str(&format!(", including {conflicts} conflicts"));
+    }
+    summary
+}
+
+fn make_token() -> String {
+    use aes_gcm::aead::{rand_core::RngCore, OsRng};
+
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,353 @@
+//! Activity counters of the instance, see [`fsync::InstanceCounters`],
+//! [`fsync::InstanceTransfers`] and [`fsync::InstanceRepairs`].
+//!
+//! The counters since boot start from zero each time the daemon starts.
+//! The lifetime counters are loaded from a file of the instance, to which they are
+//! persisted periodically and at shutdown. A file that can't be read is discarded,
+//! so that the lifetime counters start again from zero rather than preventing the startup.
+//!
+//! The content transferred is also counted by [`fsync::TransferCause`]. The transfer of a file
+//! that follows a failed transfer of the same file is a retry, of which the bytes are counted
+//! apart from the first attempts, with the parts that the storages sent again after a failure.
+
+use std::{
+    collections::HashSet,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use fsync::{
+    path::{FsPathBuf, Path, PathBuf},
+    TransferCause,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::persist;
+
+/// Delay between two persistences of the lifetime counters
+pub const PERSIST_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Maximum number of files remembered with a failed transfer.
+/// When exceeded, the retries of the other files count as first attempts.
+const MAX_FAILED_TRANSFERS: usize = 10_000;
+
+tokio::task_local! {
+    static RESUMED: ();
+}
+
+/// Run `fut`, an operation resumed from its checkpoint, so that its transfers
+/// are counted as [`TransferCause::Resume`]
+pub fn resumed_scope<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    RESUMED.scope((), fut)
+}
+
+/// Whether the current task runs an operation resumed from its checkpoint
+pub fn is_resumed() -> bool {
+    RESUMED.try_with(|_| ()).is_ok()
+}
+
+/// The persisted lifetime counters
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct State {
+    start: DateTime<Utc>,
+    counters: fsync::Counters,
+    #[serde(default)]
+    transfers: fsync::Transfers,
+    #[serde(default)]
+    repairs: u64,
+}
+
+#[derive(Debug)]
+pub struct Counters {
+    path: Option<FsPathBuf>,
+    boot: DateTime<Utc>,
+    /// The lifetime counters when the daemon started
+    lifetime: State,
+    bytes_uploaded: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    operations: AtomicU64,
+    failures: AtomicU64,
+    retries: AtomicU64,
+    repairs: AtomicU64,
+    transfers: std::sync::Mutex<fsync::Transfers>,
+    /// The files of which the last transfer failed
+    failed_transfers: std::sync::Mutex<HashSet<PathBuf>>,
+    /// Serializes the writes of the file
+    write: Mutex<()>,
+}
+
+impl Default for Counters {
+    /// Counters that are not persisted
+    fn default() -> Self {
+        let now = Utc::now();
+        Self::new(
+            None,
+            State {
+                start: now,
+                counters: fsync::Counters::default(),
+                transfers: fsync::Transfers::default(),
+                repairs: 0,
+            },
+        )
+    }
+}
+
+impl Counters {
+    fn new(path: Option<FsPathBuf>, lifetime: State) -> Self {
+        Self {
+            path,
+            boot: Utc::now(),
+            lifetime,
+            bytes_uploaded: AtomicU64::new(0),
+            bytes_downloaded: AtomicU64::new(0),
+            operations: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            repairs: AtomicU64::new(0),
+            transfers: std::sync::Mutex::new(fsync::Transfers::default()),
+            failed_transfers: std::sync::Mutex::new(HashSet::new()),
+            write: Mutex::new(()),
+        }
+    }
+
+    /// Open the lifetime counters persisted at `path`, which does not need to exist.
+    pub async fn open(path: FsPathBuf) -> anyhow::Result<Self> {
+        let fresh = || State {
+            start: Utc::now(),
+            counters: fsync::Counters::default(),
+            transfers: fsync::Transfers::default(),
+            repairs: 0,
+        };
+        let lifetime = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+                log::warn!("Discarding the lifetime counters of {path}, starting from zero: {err}");
+                fresh()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                log::info!("No lifetime counters at {path}, starting from zero");
+                fresh()
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self::new(Some(path), lifetime))
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.bytes_uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.bytes_downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a unit operation that completed, successfully or not
+    pub fn add_operation(&self, success: bool) {
+        if success {
+            self.operations.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a conflict between identical files, marked as synchronized
+    pub fn add_repair(&self) {
+        self.repairs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a transfer of content for `cause`
+    pub fn add_transfer(&self, cause: TransferCause, transfer: fsync::TransferCounters) {
+        let mut transfers = self.transfers.lock().unwrap();
+        let counters = transfers.get_mut(cause);
+        *counters = *counters + transfer;
+    }
+
+    /// Remember that the transfer of the file at `path` failed
+    pub fn fail_transfer(&self, path: &Path) {
+        let mut failed = self.failed_transfers.lock().unwrap();
+        if failed.len() < MAX_FAILED_TRANSFERS {
+            failed.insert(path.to_owned());
+        }
+    }
+
+    /// Whether a transfer of the file at `path` is the retry of a failed one
+    pub fn is_retry(&self, path: &Path) -> bool {
+        self.failed_transfers.lock().unwrap().contains(path)
+    }
+
+    /// Forget the failed transfer of the file at `path`, once it succeeded
+    pub fn end_transfer(&self, path: &Path) {
+        self.failed_transfers.lock().unwrap().remove(path);
+    }
+
+    pub fn since_boot(&self) -> fsync::Counters {
+        fsync::Counters {
+            bytes_uploaded: self.bytes_uploaded.load(Ordering::Relaxed),
+            bytes_downloaded: self.bytes_downloaded.load(Ordering::Relaxed),
+            operations: self.operations.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn report(&self) -> fsync::InstanceCounters {
+        let since_boot = self.since_boot();
+        fsync::InstanceCounters {
+            since_boot,
+            lifetime: self.lifetime.counters + since_boot,
+            boot: self.boot,
+            lifetime_start: self.lifetime.start,
+        }
+    }
+
+    pub fn transfers(&self) -> fsync::InstanceTransfers {
+        let since_boot = *self.transfers.lock().unwrap();
+        fsync::InstanceTransfers {
+            since_boot,
+            lifetime: self.lifetime.transfers + since_boot,
+        }
+    }
+
+    pub fn repairs(&self) -> fsync::InstanceRepairs {
+        let since_boot = self.repairs.load(Ordering::Relaxed);
+        fsync::InstanceRepairs {
+            since_boot,
+            lifetime: self.lifetime.repairs + since_boot,
+        }
+    }
+
+    /// Write the lifetime counters to their file, if they have one
+    pub async fn persist(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let _write = self.write.lock().await;
+        let state = State {
+            start: self.lifetime.start,
+            counters: self.report().lifetime,
+            transfers: self.transfers().lifetime,
+            repairs: self.repairs().lifetime,
+        };
+        let data = serde_json::to_vec(&state)?;
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || persist::atomic_write(&path, &data)).await??;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> FsPathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("fsyncd-counters-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        FsPathBuf::try_from(dir).unwrap()
+    }
+
+    #[tokio::test]
+    async fn lifetime_across_restarts() {
+        let dir = temp_dir("restarts");
+        let path = dir.join("counters.json");
+
+        let counters = Counters::open(path.clone()).await.unwrap();
+        counters.add_uploaded(100);
+        counters.add_downloaded(20);
+        counters.add_operation(true);
+        counters.add_operation(false);
+        counters.add_retry();
+        let transfer = fsync::TransferCounters {
+            files: 1,
+            bytes: 100,
+            retried_bytes: 0,
+        };
+        counters.add_transfer(TransferCause::Sync, transfer);
+        counters.add_repair();
+        counters.persist().await.unwrap();
+        let start = counters.report().lifetime_start;
+
+        let counters = Counters::open(path).await.unwrap();
+        counters.add_uploaded(5);
+        counters.add_operation(true);
+        let report = counters.report();
+        assert_eq!(
+            report.since_boot,
+            fsync::Counters {
+                bytes_uploaded: 5,
+                operations: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            report.lifetime,
+            fsync::Counters {
+                bytes_uploaded: 105,
+                bytes_downloaded: 20,
+                operations: 2,
+                failures: 1,
+                retries: 1,
+            }
+        );
+        assert_eq!(report.lifetime_start, start);
+        let transfers = counters.transfers();
+        assert_eq!(transfers.since_boot, fsync::Transfers::default());
+        assert_eq!(transfers.lifetime.sync, transfer);
+        let repairs = counters.repairs();
+        assert_eq!((repairs.since_boot, repairs.lifetime), (0, 1));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_transfers_are_retried() {
+        let counters = Counters::default();
+        let path = Path::new("/a.txt");
+        assert!(!counters.is_retry(path));
+        counters.fail_transfer(path);
+        assert!(counters.is_retry(path));
+        counters.end_transfer(path);
+        assert!(!counters.is_retry(path));
+
+        let transfer = fsync::TransferCounters {
+            files: 1,
+            bytes: 100,
+            retried_bytes: 8,
+        };
+        counters.add_transfer(TransferCause::Conflict, transfer);
+        counters.add_transfer(TransferCause::Conflict, transfer);
+        let transfers = counters.transfers().since_boot;
+        assert_eq!(transfers.conflict, transfer + transfer);
+        assert_eq!(transfers.sync, fsync::TransferCounters::default());
+    }
+
+    #[tokio::test]
+    async fn transfers_of_resumed_operations() {
+        assert!(!is_resumed());
+        assert!(resumed_scope(async { is_resumed() }).await);
+    }
+
+    #[tokio::test]
+    async fn corrupt_file() {
+        let dir = temp_dir("corrupt");
+        let path = dir.join("counters.json");
+        std::fs::write(&path, b"{\"start\": 12, \"coun").unwrap();
+
+        let counters = Counters::open(path.clone()).await.unwrap();
+        assert_eq!(counters.report().lifetime, fsync::Counters::default());
+        counters.add_operation(true);
+        counters.persist().await.unwrap();
+
+        let counters = Counters::open(path).await.unwrap();
+        assert_eq!(counters.report().lifetime.operations, 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,454 @@
+//! Periodic digest of the pending conflicts and of the failed operations, see [`fsync::Digest`].
+//!
+//! The service records the failed operations and renders them with its conflicts
+//! into a [`Report`]. The report is delivered to the command and to the SMTP server
+//! of the config, unless its conflicts were already reported and it has no new failure.
+//! The conflicts of the last delivered report are persisted, so that restarting
+//! the daemon does not notify them again.
+
+use std::{fmt::Write, process::Stdio, time::Duration};
+
+use chrono::{DateTime, Local, Utc};
+use fsync::{
+    path::{FsPathBuf, PathBuf},
+    Conflict, Operation, Smtp, SmtpSecurity,
+};
+use lettre::{
+    message::MultiPart, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt, process::Command, sync::Mutex};
+
+/// Number of failed operations kept for the next digest
+pub const MAX_FAILURES: usize = 100;
+
+/// Time after which the command or the SMTP transaction is aborted
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A failed operation, reported by the next digest
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub time: DateTime<Utc>,
+    pub operation: Operation,
+    pub path: PathBuf,
+    pub error: String,
+}
+
+/// The content of a digest
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub time: DateTime<Utc>,
+    pub conflicts: Vec<(PathBuf, Conflict)>,
+    pub failures: Vec<Failure>,
+}
+
+impl Report {
+    pub fn new(conflicts: Vec<(PathBuf, Conflict)>, failures: Vec<Failure>) -> Self {
+        Self {
+            time: Utc::now(),
+            conflicts,
+            failures,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conflicts.is_empty() && self.failures.is_empty()
+    }
+
+    pub fn subject(&self) -> String {
+        format!(
+            "fsync: {} pending conflict{}, {} failed operation{}",
+            self.conflicts.len(),
+            plural(self.conflicts.len()),
+            self.failures.len(),
+            plural(self.failures.len()),
+        )
+    }
+
+    /// The digest in plain text
+    pub fn text(&self) -> String {
+        let mut text = format!("{}\n", self.subject());
+        if !self.conflicts.is_empty() {
+            text.push_str("\nConflicts:\n");
+            for (path, conflict) in &self.conflicts {
+                let _ = writeln!(text, "  {path}: {conflict}");
+            }
+        }
+        if !self.failures.is_empty() {
+            text.push_str("\nFailed operations:\n");
+            for failure in &self.failures {
+                let _ = writeln!(
+                    text,
+                    "  {} {}: {}",
+                    failure.time.with_timezone(&Local).format("%F %T"),
+                    failure.path,
+                    failure.error
+                );
+            }
+        }
+        text
+    }
+
+    /// The digest in HTML
+    pub fn html(&self) -> String {
+        let mut html = format!("<h1>{}</h1>\n", escape(&self.subject()));
+        if !self.conflicts.is_empty() {
+            html.push_str("<h2>Conflicts</h2>\n<ul>\n");
+            for (path, conflict) in &self.conflicts {
+                let _ = writeln!(
+                    html,
+                    "<li><code>{}</code>: {}</li>",
+                    escape(path.as_str()),
+                    escape(&conflict.to_string())
+                );
+            }
+            html.push_str("</ul>\n");
+        }
+        if !self.failures.is_empty() {
+            html.push_str("<h2>Failed operations</h2>\n<ul>\n");
+            for failure in &self.failures {
+                let _ = writeln!(
+                    html,
+                    "<li>{} <code>{}</code>: {}</li>",
+                    failure.time.with_timezone(&Local).format("%F %T"),
+                    escape(failure.path.as_str()),
+                    escape(&failure.error)
+                );
+            }
+            html.push_str("</ul>\n");
+        }
+        html
+    }
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Persisted state of the digest
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    /// Time of the last delivery
+    sent: Option<DateTime<Utc>>,
+    /// Conflicts of the last delivered digest, or none if they were all resolved since
+    conflicts: Vec<(PathBuf, Conflict)>,
+}
+
+/// Delivers the digests according to the config
+#[derive(Debug)]
+pub struct Digest {
+    config: fsync::Digest,
+    state_path: FsPathBuf,
+    state: Mutex<State>,
+}
+
+impl Digest {
+    /// Open the digest state persisted at `state_path`, which does not need to exist.
+    /// The SMTP password of `config` must be in clear text.
+    pub async fn open(config: fsync::Digest, state_path: FsPathBuf) -> anyhow::Result<Self> {
+        let state = match fs::read(&state_path).await {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(err) => return Err(err.into()),
+        };
+        if config.exec.is_empty() && config.smtp.is_none() {
+            log::warn!(target: "digest", "The digest has no delivery channel");
+        }
+        Ok(Self {
+            config,
+            state_path,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Time to wait from `now` until the next scheduled digest
+    pub fn next_delay(&self, now: DateTime<Local>) -> Duration {
+        let now = now.naive_local();
+        let mut next = now.date().and_time(self.config.at);
+        if next <= now {
+            next = next + chrono::Days::new(1);
+        }
+        (next - now).to_std().unwrap_or_default()
+    }
+
+    /// Deliver `report` to the configured channels.
+    /// Unless `force` is set, the report is not delivered if it is empty, or if it has
+    /// the same conflicts as the previous one and no failure.
+    /// Returns whether the report was delivered to at least one channel.
+    pub async fn deliver(&self, report: &Report, force: bool) -> anyhow::Result<bool> {
+        let mut state = self.state.lock().await;
+        let unchanged = state.conflicts == report.conflicts && report.failures.is_empty();
+        if !force && (report.is_empty() || unchanged) {
+            log::debug!(target: "digest", "Nothing new to report, digest skipped");
+            if report.conflicts.is_empty() && !state.conflicts.is_empty() {
+                // conflicts that reappear later must be reported again
+                state.conflicts.clear();
+                self.persist(&state).await?;
+            }
+            return Ok(false);
+        }
+
+        let conflicts = report.conflicts.len();
+        let failures = report.failures.len();
+        let mut delivered = false;
+        let mut last_err = None;
+
+        if let Some((program, args)) = self.config.exec.split_first() {
+            let res = tokio::time::timeout(TIMEOUT, exec(program, args, &report.text())).await;
+            match res {
+                Ok(Ok(())) => {
+                    log::info!(target: "digest", channel = "exec", program, conflicts, failures; "Digest delivered to {program}");
+                    delivered = true;
+                }
+                Ok(Err(err)) => {
+                    log::warn!(target: "digest", channel = "exec", program; "Could not deliver digest to {program}: {err:#}");
+                    last_err = Some(err);
+                }
+                Err(_) => {
+                    log::warn!(target: "digest", channel = "exec", program; "Digest command {program} timed out after {TIMEOUT:?}");
+                    last_err = Some(anyhow::anyhow!("{program} timed out"));
+                }
+            }
+        }
+
+        if let Some(smtp) = &self.config.smtp {
+            let server = smtp.server.as_str();
+            match send_mail(smtp, report).await {
+                Ok(()) => {
+                    log::info!(target: "digest", channel = "smtp", server, conflicts, failures; "Digest sent to {}", smtp.to.join(", "));
+                    delivered = true;
+                }
+                Err(err) => {
+                    log::warn!(target: "digest", channel = "smtp", server; "Could not send digest through {server}: {err:#}");
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        if !delivered {
+            return match last_err {
+                Some(err) => Err(err),
+                None => Ok(false),
+            };
+        }
+        state.sent = Some(report.time);
+        state.conflicts = report.conflicts.clone();
+        self.persist(&state).await?;
+        Ok(true)
+    }
+
+    async fn persist(&self, state: &State) -> anyhow::Result<()> {
+        if let Some(dir) = self.state_path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        fs::write(&self.state_path, serde_json::to_vec_pretty(state)?).await?;
+        Ok(())
+    }
+}
+
+/// Run `program` with `text` on its standard input
+async fn exec(program: &str, args: &[String], text: &str) -> anyhow::Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes()).await?;
+    }
+    let status = child.wait().await?;
+    if !status.success() {
+        anyhow::bail!("{program} failed: {status}");
+    }
+    Ok(())
+}
+
+async fn send_mail(smtp: &Smtp, report: &Report) -> anyhow::Result<()> {
+    let mut message = Message::builder()
+        .from(smtp.from.parse()?)
+        .subject(report.subject());
+    for to in &smtp.to {
+        message = message.to(to.parse()?);
+    }
+    let message = message.multipart(MultiPart::alternative_plain_html(
+        report.text(),
+        report.html(),
+    ))?;
+
+    let mut transport = match smtp.security {
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.server)?,
+        SmtpSecurity::StartTls => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.server)?
+        }
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.server),
+    };
+    if let Some(port) = smtp.port {
+        transport = transport.port(port);
+    }
+    if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+        transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    transport
+        .timeout(Some(TIMEOUT))
+        .build()
+        .send(message)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Accept SMTP transactions on a local port, and send the received messages to the channel
+    async fn smtp_server() -> (u16, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut lines = BufReader::new(read).lines();
+                    write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+                    let mut data: Option<String> = None;
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if let Some(msg) = data.as_mut() {
+                            if line == "." {
+                                tx.send(data.take().unwrap()).unwrap();
+                                write.write_all(b"250 OK\r\n").await.unwrap();
+                            } else {
+                                msg.push_str(&line);
+                                msg.push('\n');
+                            }
+                            continue;
+                        }
+                        let reply: &[u8] = match &line[..4.min(line.len())] {
+                            "EHLO" | "HELO" => b"250 localhost\r\n",
+                            "DATA" => {
+                                data = Some(String::new());
+                                b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                            }
+                            "QUIT" => {
+                                let _ = write.write_all(b"221 Bye\r\n").await;
+                                break;
+                            }
+                            _ => b"250 OK\r\n",
+                        };
+                        write.write_all(reply).await.unwrap();
+                    }
+                });
+            }
+        });
+        (port, rx)
+    }
+
+    fn state_path(name: &str) -> FsPathBuf {
+        let dir = std::env::temp_dir().join(format!("fsyncd-digest-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        FsPathBuf::try_from(dir).unwrap().join("digest.json")
+    }
+
+    fn failure(path: &str) -> Failure {
+        Failure {
+            time: Utc::now(),
+            operation: Operation::Sync(PathBuf::from(path)),
+            path: PathBuf::from(path),
+            error: "IO error: disk full".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn smtp_delivery_is_deduplicated() {
+        let (port, mut rx) = smtp_server().await;
+        let config = fsync::Digest {
+            at: "09:00".parse().unwrap(),
+            exec: Vec::new(),
+            smtp: Some(Smtp {
+                server: "127.0.0.1".into(),
+                port: Some(port),
+                security: SmtpSecurity::None,
+                username: None,
+                password: None,
+                from: "fsyncd@localhost".into(),
+                to: vec!["user@localhost".into()],
+            }),
+        };
+        let state_path = state_path("smtp");
+        let digest = Digest::open(config.clone(), state_path.clone())
+            .await
+            .unwrap();
+
+        let conflicts = vec![(PathBuf::from("/doc.txt"), Conflict::LocalNewer)];
+        let report = Report::new(conflicts.clone(), vec![failure("/big.bin")]);
+        assert!(digest.deliver(&report, false).await.unwrap());
+        let message = rx.recv().await.unwrap();
+        assert!(message.contains("Subject: fsync: 1 pending conflict, 1 failed operation"));
+        assert!(message.contains("/doc.txt: local is newer"));
+        assert!(message.contains("/big.bin: IO error: disk full"));
+
+        // same conflicts, no new failure, also after a restart
+        let digest = Digest::open(config, state_path.clone()).await.unwrap();
+        let report = Report::new(conflicts.clone(), Vec::new());
+        assert!(!digest.deliver(&report, false).await.unwrap());
+        assert!(digest.deliver(&report, true).await.unwrap());
+        rx.recv().await.unwrap();
+
+        // resolved, then back
+        assert!(!digest
+            .deliver(&Report::new(Vec::new(), Vec::new()), false)
+            .await
+            .unwrap());
+        assert!(digest.deliver(&report, false).await.unwrap());
+        rx.recv().await.unwrap();
+
+        std::fs::remove_dir_all(state_path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn next_delay() {
+        let config = fsync::Digest {
+            at: "09:00".parse().unwrap(),
+            exec: Vec::new(),
+            smtp: None,
+        };
+        let digest = Digest::open(config, state_path("delay")).await.unwrap();
+        let at = |s: &str| {
+            chrono::NaiveDateTime::parse_from_str(s, "%F %T")
+                .unwrap()
+                .and_local_timezone(Local)
+                .unwrap()
+        };
+        assert_eq!(
+            digest.next_delay(at("2024-03-12 08:30:00")),
+            Duration::from_secs(30 * 60)
+        );
+        assert_eq!(
+            digest.next_delay(at("2024-03-12 09:00:00")),
+            Duration::from_secs(24 * 3600)
+        );
+        assert_eq!(
+            digest.next_delay(at("2024-03-12 10:00:00")),
+            Duration::from_secs(23 * 3600)
+        );
+    }
+}
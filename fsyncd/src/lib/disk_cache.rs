@@ -0,0 +1,420 @@
+//! Disk usage of the caches of an instance.
+//!
+//! The [`DiskCache`] tracks the size of each category of cached data, and keeps the
+//! total under the configured budget by evicting the least recently used entries of
+//! the content cache. The other categories cannot be evicted: when they leave no room,
+//! the content cache refuses to grow.
+//! Entries leased by an in-flight operation are never evicted nor cleared.
+
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use fsync::{
+    loc::inst,
+    path::{FsPath, FsPathBuf, Path},
+    CacheLookups, CacheUsage,
+};
+use tokio::fs;
+
+/// Locations of the caches on disk
+#[derive(Debug, Clone)]
+pub struct CachePaths {
+    /// File of the persisted metadata of the remote storage
+    pub metadata: FsPathBuf,
+    /// Directory of the cached content of remote files
+    pub content: FsPathBuf,
+    /// Directory of the files set aside before being deleted or overwritten
+    pub quarantine: FsPathBuf,
+    /// Directory of the files being written
+    pub temp: FsPathBuf,
+}
+
+impl CachePaths {
+    pub fn instance(instance_name: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            metadata: inst::remote_cache_file(instance_name)?,
+            content: inst::content_cache_dir(instance_name)?,
+            quarantine: inst::quarantine_dir(instance_name)?,
+            temp: inst::temp_dir(instance_name)?,
+        })
+    }
+}
+
+/// Key of the cached content of a remote file.
+/// It changes with the file, so that stale content is never served,
+/// and is left to be evicted.
+pub fn content_key(path: &Path, size: u64, mtime: DateTime<Utc>, variant: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    (path.as_str(), size, mtime.timestamp_millis(), variant).hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+#[derive(Debug)]
+pub struct DiskCache {
+    paths: CachePaths,
+    budget: Option<u64>,
+    state: Mutex<State>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    /// Number of in-flight operations using each entry
+    leases: HashMap<String, usize>,
+    clock: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    size: u64,
+    last_used: u64,
+}
+
+impl State {
+    fn content_size(&self) -> u64 {
+        self.entries.values().map(|entry| entry.size).sum()
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.last_used = self.clock;
+        }
+    }
+
+    /// Remove the entries that are not leased, least recently used first,
+    /// until `done` returns true. Returns the removed keys and their total size.
+    fn evict(&mut self, mut done: impl FnMut(u64) -> bool) -> (Vec<String>, u64) {
+        let mut candidates: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(key, _)| !self.leases.contains_key(*key))
+            .map(|(key, entry)| (entry.last_used, key.clone()))
+            .collect();
+        candidates.sort();
+
+        let mut evicted = Vec::new();
+        let mut freed = 0;
+        for (_, key) in candidates {
+            if done(freed) {
+                break;
+            }
+            let entry = self.entries.remove(&key).unwrap();
+            freed += entry.size;
+            evicted.push(key);
+        }
+        (evicted, freed)
+    }
+}
+
+/// Protects a content entry from eviction while it is used
+struct Lease<'a> {
+    cache: &'a DiskCache,
+    key: String,
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        let mut state = self.cache.state.lock().unwrap();
+        if let Some(count) = state.leases.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                state.leases.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl DiskCache {
+    /// Open the caches at `paths`, within `budget` bytes if specified.
+    /// The leftovers of interrupted writes are deleted.
+    pub async fn open(paths: CachePaths, budget: Option<u64>) -> anyhow::Result<Self> {
+        if paths.temp.exists() {
+            fs::remove_dir_all(&paths.temp).await?;
+        }
+        fs::create_dir_all(&paths.temp).await?;
+        fs::create_dir_all(&paths.content).await?;
+
+        // the least recently used entries of the previous run are the oldest files
+        let mut files = Vec::new();
+        let mut read_dir = fs::read_dir(&paths.content).await?;
+        while let Some(direntry) = read_dir.next_entry().await? {
+            let metadata = direntry.metadata().await?;
+            let Some(key) = direntry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            if metadata.is_file() {
+                files.push((metadata.modified()?, key, metadata.len()));
+            }
+        }
+        files.sort();
+        let mut state = State::default();
+        for (_, key, size) in files {
+            state.clock += 1;
+            let last_used = state.clock;
+            state.entries.insert(key, Entry { size, last_used });
+        }
+
+        let cache = Self {
+            paths,
+            budget,
+            state: Mutex::new(state),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        };
+        let usage = cache.usage().await;
+        if let Some(budget) = budget {
+            log::info!(
+                "Caches use {} bytes of a {budget} bytes budget",
+                usage.total()
+            );
+            if usage.metadata > budget {
+                log::warn!(
+                    "The remote metadata alone ({} bytes) exceeds the cache budget",
+                    usage.metadata
+                );
+            }
+        }
+        Ok(cache)
+    }
+
+    pub fn paths(&self) -> &CachePaths {
+        &self.paths
+    }
+
+    /// The budget of the caches, if any
+    pub fn budget(&self) -> Option<u64> {
+        self.budget
+    }
+
+    /// Count a read of remote content that was served from the cache or not
+    pub fn record_lookup(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub async fn usage(&self) -> CacheUsage {
+        let metadata = fs::metadata(&self.paths.metadata)
+            .await
+            .map(|md| md.len())
+            .unwrap_or(0);
+        let content = self.state.lock().unwrap().content_size();
+        let quarantine = dir_size(&self.paths.quarantine).await;
+        let temp = dir_size(&self.paths.temp).await;
+        CacheUsage {
+            metadata,
+            content,
+            quarantine,
+            temp,
+            budget: self.budget,
+        }
+    }
+
+    pub fn lookups(&self) -> CacheLookups {
+        CacheLookups {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The content cached under `key`, if any
+    pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let lease = {
+            let mut state = self.state.lock().unwrap();
+            if !state.entries.contains_key(key) {
+                return None;
+            }
+            state.touch(key);
+            self.lease(&mut state, key)
+        };
+        let data = fs::read(self.paths.content.join(key)).await;
+        drop(lease);
+        match data {
+            Ok(data) => Some(data),
+            Err(err) => {
+                log::warn!("could not read cached content {key}: {err}");
+                self.state.lock().unwrap().entries.remove(key);
+                None
+            }
+        }
+    }
+
+    /// Cache `data` under `key`, evicting the least recently used entries to stay
+    /// within the budget. Fails with [`fsync::Error::InsufficientSpace`] if
+    /// the budget can't fit `data`.
+    pub async fn put(&self, key: &str, data: &[u8]) -> fsync::Result<()> {
+        let size = data.len() as u64;
+        if let Some(budget) = self.budget {
+            let usage = self.usage().await;
+            let others = usage.total() - usage.content;
+            let (evicted, fits) = {
+                let mut state = self.state.lock().unwrap();
+                let content = state.content_size();
+                let required = (others + content + size).saturating_sub(budget);
+                let (evicted, freed) = state.evict(|freed| freed >= required);
+                (evicted, freed >= required)
+            };
+            self.remove_files(&evicted).await;
+            if !fits {
+                let content = self.state.lock().unwrap().content_size();
+                return Err(fsync::Error::InsufficientSpace {
+                    available: budget.saturating_sub(others + content),
+                    required: size,
+                });
+            }
+        }
+
+        let temp = self.paths.temp.join(key);
+        fs::write(&temp, data).await?;
+        fs::rename(&temp, self.paths.content.join(key)).await?;
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let last_used = state.clock;
+        state
+            .entries
+            .insert(key.to_owned(), Entry { size, last_used });
+        Ok(())
+    }
+
+    /// Delete the content cached under `key`, unless it is in use
+    pub async fn remove(&self, key: &str) {
+        let removed = {
+            let mut state = self.state.lock().unwrap();
+            !state.leases.contains_key(key) && state.entries.remove(key).is_some()
+        };
+        if removed {
+            self.remove_files(&[key.to_owned()]).await;
+        }
+    }
+
+    /// Delete the cached content that is not in use.
+    /// Returns the number of bytes freed.
+    pub async fn clear_content(&self) -> u64 {
+        let (evicted, freed) = self.state.lock().unwrap().evict(|_| false);
+        self.remove_files(&evicted).await;
+        log::info!("Cleared {freed} bytes of cached content");
+        freed
+    }
+
+    fn lease(&self, state: &mut State, key: &str) -> Lease<'_> {
+        *state.leases.entry(key.to_owned()).or_default() += 1;
+        Lease {
+            cache: self,
+            key: key.to_owned(),
+        }
+    }
+
+    async fn remove_files(&self, keys: &[String]) {
+        for key in keys {
+            log::debug!("evicting cached content {key}");
+            if let Err(err) = fs::remove_file(self.paths.content.join(key)).await {
+                log::warn!("could not delete cached content {key}: {err}");
+            }
+        }
+    }
+}
+
+/// Total size of the files within `dir`, 0 if it does not exist
+async fn dir_size(dir: &FsPath) -> u64 {
+    let dir = dir.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let mut size = 0;
+        let mut stack = vec![dir.into_std_path_buf()];
+        while let Some(dir) = stack.pop() {
+            let Ok(read_dir) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for direntry in read_dir.flatten() {
+                match direntry.metadata() {
+                    Ok(md) if md.is_dir() => stack.push(direntry.path()),
+                    Ok(md) => size += md.len(),
+                    Err(_) => (),
+                }
+            }
+        }
+        size
+    })
+    .await
+    .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(name: &str) -> CachePaths {
+        let dir = std::env::temp_dir().join(format!("fsyncd-{name}-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("remote.bin"), [0u8; 100]).unwrap();
+        CachePaths {
+            metadata: dir.join("remote.bin"),
+            content: dir.join("content"),
+            quarantine: dir.join("quarantine"),
+            temp: dir.join("tmp"),
+        }
+    }
+
+    #[tokio::test]
+    async fn lru_eviction_within_budget() {
+        let paths = paths("cache-lru");
+        let cache = DiskCache::open(paths.clone(), Some(400)).await.unwrap();
+
+        cache.put("a", &[1; 100]).await.unwrap();
+        cache.put("b", &[2; 100]).await.unwrap();
+        cache.put("c", &[3; 100]).await.unwrap();
+        assert_eq!(cache.usage().await.total(), 400);
+
+        // "a" becomes more recent than "b"
+        assert_eq!(cache.get("a").await.unwrap(), vec![1; 100]);
+        cache.put("d", &[4; 100]).await.unwrap();
+        assert!(cache.get("b").await.is_none());
+        assert!(cache.get("a").await.is_some());
+        assert!(cache.get("c").await.is_some());
+
+        // larger than the whole budget
+        let res = cache.put("e", &[5; 400]).await;
+        assert!(matches!(res, Err(fsync::Error::InsufficientSpace { .. })));
+
+        let usage = cache.usage().await;
+        assert_eq!(usage.metadata, 100);
+        assert!(usage.total() <= 400);
+
+        // the entries and their recency are restored from disk
+        let cache = DiskCache::open(paths.clone(), Some(400)).await.unwrap();
+        assert_eq!(cache.usage().await.content, usage.content);
+
+        std::fs::remove_dir_all(paths.metadata.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn leased_entries_are_kept() {
+        let paths = paths("cache-lease");
+        let cache = DiskCache::open(paths.clone(), None).await.unwrap();
+        cache.put("a", &[1; 10]).await.unwrap();
+        cache.put("b", &[2; 10]).await.unwrap();
+
+        let lease = {
+            let mut state = cache.state.lock().unwrap();
+            cache.lease(&mut state, "a")
+        };
+        assert_eq!(cache.clear_content().await, 10);
+        assert_eq!(cache.usage().await.content, 10);
+        drop(lease);
+        assert_eq!(cache.clear_content().await, 10);
+        assert_eq!(cache.usage().await.content, 0);
+
+        std::fs::remove_dir_all(paths.metadata.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+//! Hooks run on the events of the daemon, see [`fsync::Hook`].
+//!
+//! The service publishes its events to a bounded queue, consumed by a runner task
+//! that starts the hooks of each event. Publishing never waits: when the queue is
+//! full, the event is dropped. A hook that fails or times out is only logged,
+//! so hooks can't affect the operations.
+
+use std::{process::Stdio, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use fsync::{path::PathBuf, Action, Conflict, Hook, HookEvent, Operation};
+use serde::Serialize;
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    sync::{mpsc, Semaphore},
+};
+
+/// Number of events queued before new ones are dropped
+const QUEUE_LEN: usize = 256;
+
+/// Maximum number of hooks running at the same time
+const MAX_CONCURRENT_HOOKS: usize = 4;
+
+/// Timeout of the hooks that do not specify one
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An event passed to the hooks
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    OperationDone {
+        time: DateTime<Utc>,
+        operation: Operation,
+        path: PathBuf,
+        action: Action,
+    },
+    ConflictDetected {
+        time: DateTime<Utc>,
+        path: PathBuf,
+        conflict: Conflict,
+    },
+    Error {
+        time: DateTime<Utc>,
+        operation: Operation,
+        path: PathBuf,
+        error: String,
+    },
+}
+
+impl Event {
+    pub fn kind(&self) -> HookEvent {
+        match self {
+            Self::OperationDone { .. } => HookEvent::OperationDone,
+            Self::ConflictDetected { .. } => HookEvent::ConflictDetected,
+            Self::Error { .. } => HookEvent::Error,
+        }
+    }
+}
+
+/// Publisher of the events to the hooks runner
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    tx: mpsc::Sender<Event>,
+}
+
+impl Hooks {
+    /// Spawn the task running `hooks`
+    pub fn spawn(hooks: Vec<Hook>) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(run(hooks, rx));
+        Self { tx }
+    }
+
+    /// Queue `event` for the hooks, or drop it if the queue is full
+    pub fn publish(&self, event: Event) {
+        if let Err(err) = self.tx.try_send(event) {
+            log::warn!(target: "hook", "Hook event dropped: {err}");
+        }
+    }
+}
+
+async fn run(hooks: Vec<Hook>, mut rx: mpsc::Receiver<Event>) {
+    let hooks: Vec<Arc<Hook>> = hooks.into_iter().map(Arc::new).collect();
+    let client = reqwest::Client::new();
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_HOOKS));
+
+    while let Some(event) = rx.recv().await {
+        let kind = event.kind();
+        let json = match serde_json::to_vec(&event) {
+            Ok(json) => Arc::new(json),
+            Err(err) => {
+                log::error!(target: "hook", "Could not serialize {event:?}: {err}");
+                continue;
+            }
+        };
+        for hook in hooks.iter().filter(|hook| hook.on == kind) {
+            let Ok(permit) = permits.clone().acquire_owned().await else {
+                return;
+            };
+            let hook = hook.clone();
+            let json = json.clone();
+            let client = client.clone();
+            tokio::spawn(async move {
+                run_hook(&hook, &json, &client).await;
+                drop(permit);
+            });
+        }
+    }
+}
+
+async fn run_hook(hook: &Hook, json: &[u8], client: &reqwest::Client) {
+    let timeout = hook
+        .timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TIMEOUT);
+    let event = format!("{:?}", hook.on);
+
+    if let Some((program, args)) = hook.exec.split_first() {
+        match tokio::time::timeout(timeout, exec(program, args, json)).await {
+            Ok(Ok(status)) if status.success() => {
+                log::info!(target: "hook", event, program, status = status.code(); "Hook {program} succeeded")
+            }
+            Ok(Ok(status)) => {
+                log::warn!(target: "hook", event, program, status = status.code(); "Hook {program} failed: {status}")
+            }
+            Ok(Err(err)) => {
+                log::warn!(target: "hook", event, program; "Could not run hook {program}: {err}")
+            }
+            Err(_) => {
+                log::warn!(target: "hook", event, program; "Hook {program} timed out after {timeout:?}")
+            }
+        }
+    }
+
+    if let Some(url) = &hook.url {
+        let res = client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(json.to_vec())
+            .timeout(timeout)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+        match res {
+            Ok(resp) => {
+                log::info!(target: "hook", event, url, status = resp.status().as_u16(); "Hook {url} succeeded")
+            }
+            Err(err) => {
+                log::warn!(target: "hook", event, url; "Hook {url} failed: {err}")
+            }
+        }
+    }
+}
+
+/// Run `program` with the event `json` on its standard input
+async fn exec(
+    program: &str,
+    args: &[String],
+    json: &[u8],
+) -> std::io::Result<std::process::ExitStatus> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // a command that does not read its input is not an error
+        let _ = stdin.write_all(json).await;
+    }
+    child.wait().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_receives_the_event() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-hooks-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("event.json");
+
+        let hooks = Hooks::spawn(vec![
+            Hook {
+                on: HookEvent::ConflictDetected,
+                exec: vec!["sh".into(), "-c".into(), format!("cat > {}", out.display())],
+                url: None,
+                timeout: None,
+            },
+            Hook {
+                on: HookEvent::ConflictDetected,
+                exec: vec!["sleep".into(), "10".into()],
+                url: None,
+                timeout: Some(0),
+            },
+        ]);
+        hooks.publish(Event::ConflictDetected {
+            time: Utc::now(),
+            path: PathBuf::from("/file.txt"),
+            conflict: Conflict::LocalNewer,
+        });
+
+        let mut json = serde_json::Value::Null;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if let Ok(Ok(value)) = std::fs::read(&out).map(|c| serde_json::from_slice(&c)) {
+                json = value;
+                break;
+            }
+        }
+        assert_eq!(json["event"], "conflict_detected");
+        assert_eq!(json["path"], "/file.txt");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
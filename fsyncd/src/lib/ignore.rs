@@ -0,0 +1,163 @@
+//! Ignore rules, from the global config and from the `.fsyncignore` files of the tree.
+//!
+//! The syntax is the one of gitignore files: blank lines and lines starting with `#` are skipped,
+//! and the other lines are [globs](fsync::path::glob) anchored to the directory of the rules,
+//! where a leading `!` re-includes entries excluded by a previous rule.
+//!
+//! When several rules match an entry, the last one wins. The global rules come first,
+//! then the rules of each `.fsyncignore` file from the root down to the entry.
+//! As with git, entries under an excluded directory cannot be re-included.
+//!
+//! The files that fsync writes in the tree, such as the [status file](crate::status_file),
+//! are always ignored, whatever the rules.
+
+use std::sync::Arc;
+
+use fsync::path::{
+    glob::{Glob, PathGlobSet},
+    Path,
+};
+
+/// Name of the per-directory ignore files
+pub const IGNORE_FILE: &str = ".fsyncignore";
+
+/// Parse a line of the rules defined in `base`, `None` for the blank lines and the comments
+fn parse_rule(base: &Path, line: &str) -> anyhow::Result<Option<Glob>> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    Ok(Some(Glob::with_base(base, line)?))
+}
+
+/// The ignore rules applying in a directory, from the lowest to the highest precedence.
+/// Cloning is cheap, so that the rules can be passed down the tree.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    rules: Arc<PathGlobSet>,
+}
+
+impl IgnoreRules {
+    /// The global rules, anchored to the root of the tree
+    pub fn global<I>(patterns: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        let mut rules = PathGlobSet::default();
+        for pattern in patterns {
+            rules.extend(parse_rule(Path::root(), pattern.as_ref())?);
+        }
+        Ok(Self {
+            rules: Arc::new(rules),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Add the rules of the ignore file of `dir`, with a higher precedence than the current ones.
+    /// Invalid patterns are skipped with a warning, so that a typo does not stop the daemon.
+    pub fn with_file(&self, dir: &Path, content: &str) -> Self {
+        let mut added = Vec::new();
+        for line in content.lines() {
+            match parse_rule(dir, line) {
+                Ok(rule) => added.extend(rule),
+                Err(err) => log::warn!("{}: {err}", dir.join(IGNORE_FILE)),
+            }
+        }
+        if added.is_empty() {
+            return self.clone();
+        }
+        let mut rules = PathGlobSet::clone(&self.rules);
+        rules.extend(added);
+        Self {
+            rules: Arc::new(rules),
+        }
+    }
+
+    /// Whether the entry at `path` is ignored
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if is_always_ignored(path, is_dir) {
+            return true;
+        }
+        self.rules.is_match(path, is_dir)
+    }
+}
+
+/// Whether the entry at `path` is one of the files written by fsync in the tree
+pub fn is_always_ignored(path: &Path, is_dir: bool) -> bool {
+    crate::status_file::is_status_file(path, is_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(rules: &IgnoreRules, path: &str) -> bool {
+        rules.is_ignored(Path::new(path), false)
+    }
+
+    #[test]
+    fn syntax() {
+        let rules =
+            IgnoreRules::global(["# comment", "", "*.tmp", "/build/", "doc/*.html", "\\!bang"])
+                .unwrap();
+
+        assert!(ignored(&rules, "/a.tmp"));
+        assert!(ignored(&rules, "/deep/down/a.tmp"));
+        assert!(!ignored(&rules, "/a.txt"));
+
+        assert!(rules.is_ignored(Path::new("/build"), true));
+        assert!(!rules.is_ignored(Path::new("/build"), false));
+        assert!(!rules.is_ignored(Path::new("/src/build"), true));
+
+        assert!(ignored(&rules, "/doc/index.html"));
+        assert!(!ignored(&rules, "/doc/api/index.html"));
+        assert!(!ignored(&rules, "/src/doc/index.html"));
+
+        assert!(ignored(&rules, "/!bang"));
+    }
+
+    #[test]
+    fn status_file_is_always_ignored() {
+        let rules = IgnoreRules::global(["!.fsync-status.json"]).unwrap();
+        assert!(ignored(&rules, crate::status_file::PATH));
+        assert!(ignored(&IgnoreRules::default(), crate::status_file::PATH));
+        assert!(!ignored(&rules, "/dir/.fsync-status.json"));
+        assert!(!rules.is_ignored(Path::new(crate::status_file::PATH), true));
+    }
+
+    #[test]
+    fn file_rules_are_anchored_to_their_dir() {
+        let rules = IgnoreRules::default().with_file(Path::new("/a"), "/out\nsub/*.o\n");
+        assert!(rules.is_ignored(Path::new("/a/out"), true));
+        assert!(!rules.is_ignored(Path::new("/out"), true));
+        assert!(!rules.is_ignored(Path::new("/a/b/out"), true));
+        assert!(ignored(&rules, "/a/sub/main.o"));
+        assert!(!ignored(&rules, "/a/b/sub/main.o"));
+    }
+
+    #[test]
+    fn precedence() {
+        let global = IgnoreRules::global(["*.log", "!keep.log", "cache/"]).unwrap();
+        assert!(ignored(&global, "/a.log"));
+        assert!(!ignored(&global, "/keep.log"));
+
+        // a file re-includes what the global rules exclude
+        let root = global.with_file(Path::root(), "!debug.log\n*.bak\n");
+        assert!(!ignored(&root, "/debug.log"));
+        assert!(ignored(&root, "/a.log"));
+        assert!(ignored(&root, "/a.bak"));
+
+        // and deeper files override the upper ones, with rules cascading down
+        let sub = root.with_file(Path::new("/sub"), "!important.bak\nkeep.log\n");
+        assert!(!ignored(&sub, "/sub/important.bak"));
+        assert!(!ignored(&sub, "/sub/deep/important.bak"));
+        assert!(ignored(&sub, "/sub/deep/other.bak"));
+        assert!(ignored(&sub, "/sub/keep.log"));
+        assert!(!ignored(&root, "/keep.log"));
+        assert!(sub.is_ignored(Path::new("/sub/cache"), true));
+    }
+}
@@ -0,0 +1,319 @@
+//! Write-ahead journal of the unit operations.
+//!
+//! A unit operation is recorded with the entries it targets before the storages are
+//! touched. Each effect performed on a storage is then recorded with the resulting
+//! metadata, before being applied to the tree. The operation is marked complete once
+//! the tree is up-to-date, or once the tree was refreshed from the storages after a failure.
+//!
+//! An operation left incomplete, typically because the daemon stopped in the middle of it,
+//! is recovered at the next start by checking again the state of its targets in the storages.
+//! Recovery only reads the storages, so it can be interrupted and run again.
+
+use std::collections::BTreeMap;
+
+use fsync::{
+    path::{FsPathBuf, PathBuf},
+    Metadata, StorageLoc,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+use crate::persist;
+
+/// Identifier of a journaled operation
+pub type Id = u64;
+
+/// An entry of a storage that an operation may modify
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Target {
+    pub loc: StorageLoc,
+    pub path: PathBuf,
+}
+
+/// The result of a storage operation, to be applied to the tree
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "effect", rename_all = "camelCase")]
+pub enum Effect {
+    /// The missing parents of `path` were created
+    Parents { loc: StorageLoc, path: PathBuf },
+    /// An entry was created or written
+    Added { loc: StorageLoc, metadata: Metadata },
+    /// A file was copied to a path that was not in the tree
+    Copied { loc: StorageLoc, metadata: Metadata },
+    /// An entry was deleted
+    Removed { loc: StorageLoc, path: PathBuf },
+    /// An entry was deleted from both storages
+    RemovedBoth { path: PathBuf },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "camelCase")]
+enum Record {
+    Begin { id: Id, targets: Vec<Target> },
+    Effect { id: Id, effect: Effect },
+    Done { id: Id },
+}
+
+/// An operation that is not complete
+#[derive(Debug, Clone)]
+pub struct Pending {
+    pub id: Id,
+    pub targets: Vec<Target>,
+    /// The effects performed on the storages, as far as they were recorded
+    pub effects: Vec<Effect>,
+}
+
+/// Points of an operation at which the tests simulate a crash of the daemon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// The operation is recorded, the storages are not touched yet
+    Begun,
+    /// An effect was performed on a storage, it is not applied to the tree yet
+    Performed,
+    /// The effects are applied to the tree, the operation is not marked complete yet
+    Applied,
+}
+
+#[derive(Debug)]
+struct Inner {
+    next_id: Id,
+    pending: BTreeMap<Id, Pending>,
+}
+
+/// The journal, persisted as JSON lines.
+/// The file is truncated each time no operation is pending, and compacted when opened.
+#[derive(Debug)]
+pub struct Journal {
+    path: FsPathBuf,
+    inner: Mutex<Inner>,
+    #[cfg(test)]
+    crash: std::sync::Mutex<Option<(Stage, usize)>>,
+}
+
+impl Journal {
+    /// Open the journal at `path`, which does not need to exist.
+    /// The operations it left incomplete are reported by [`Self::pending`].
+    pub async fn open(path: FsPathBuf) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let data = match fs::read(&path).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err.into()),
+        };
+        let mut pending = BTreeMap::new();
+        let mut next_id = 1;
+        for line in data.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+            let record = match serde_json::from_slice(line) {
+                Ok(record) => record,
+                Err(err) => {
+                    // a crash while writing leaves the last line truncated
+                    log::warn!(target: "journal", "{path}: skipping invalid record: {err}");
+                    continue;
+                }
+            };
+            match record {
+                Record::Begin { id, targets } => {
+                    next_id = next_id.max(id + 1);
+                    pending.insert(
+                        id,
+                        Pending {
+                            id,
+                            targets,
+                            effects: vec![],
+                        },
+                    );
+                }
+                Record::Effect { id, effect } => {
+                    if let Some(pending) = pending.get_mut(&id) {
+                        pending.effects.push(effect);
+                    }
+                }
+                Record::Done { id } => {
+                    pending.remove(&id);
+                }
+            }
+        }
+        // rewritten with the pending operations only, also dropping a truncated record
+        let mut data = vec![];
+        for pending in pending.values() {
+            let begin = Record::Begin {
+                id: pending.id,
+                targets: pending.targets.clone(),
+            };
+            let effects = pending.effects.iter().map(|effect| Record::Effect {
+                id: pending.id,
+                effect: effect.clone(),
+            });
+            for record in std::iter::once(begin).chain(effects) {
+                serde_json::to_writer(&mut data, &record)?;
+                data.push(b'\n');
+            }
+        }
+        let write_path = path.clone();
+        tokio::task::spawn_blocking(move || persist::atomic_write(&write_path, &data)).await??;
+
+        Ok(Self {
+            path,
+            inner: Mutex::new(Inner { next_id, pending }),
+            #[cfg(test)]
+            crash: std::sync::Mutex::new(None),
+        })
+    }
+
+    /// The operations that are not complete
+    pub async fn pending(&self) -> Vec<Pending> {
+        self.inner.lock().await.pending.values().cloned().collect()
+    }
+
+    /// Record an operation on `targets`, before the storages are touched
+    pub async fn begin(&self, targets: Vec<Target>) -> anyhow::Result<Id> {
+        let mut inner = self.inner.lock().await;
+        let id = inner.next_id;
+        self.write(
+            &Record::Begin {
+                id,
+                targets: targets.clone(),
+            },
+            true,
+        )
+        .await?;
+        inner.next_id += 1;
+        inner.pending.insert(
+            id,
+            Pending {
+                id,
+                targets,
+                effects: vec![],
+            },
+        );
+        Ok(id)
+    }
+
+    /// Record `effect`, performed on a storage by the operation `id`
+    pub async fn effect(&self, id: Id, effect: &Effect) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        self.write(
+            &Record::Effect {
+                id,
+                effect: effect.clone(),
+            },
+            true,
+        )
+        .await?;
+        if let Some(pending) = inner.pending.get_mut(&id) {
+            pending.effects.push(effect.clone());
+        }
+        Ok(())
+    }
+
+    /// Mark the operation `id` complete
+    pub async fn complete(&self, id: Id) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        if inner.pending.remove(&id).is_none() {
+            return Ok(());
+        }
+        if inner.pending.is_empty() {
+            let file = fs::OpenOptions::new().write(true).open(&self.path).await?;
+            file.set_len(0).await?;
+        } else {
+            self.write(&Record::Done { id }, false).await?;
+        }
+        Ok(())
+    }
+
+    async fn write(&self, record: &Record, sync: bool) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&line).await?;
+        if sync {
+            file.sync_data().await?;
+        } else {
+            file.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Simulate a crash the next time an operation reaches `stage`,
+    /// after letting it pass `skip` times
+    #[cfg(test)]
+    pub fn crash_at(&self, stage: Stage, skip: usize) {
+        *self.crash.lock().unwrap() = Some((stage, skip));
+    }
+
+    /// Panic if a crash is simulated at `stage`
+    #[cfg(test)]
+    pub fn crash_point(&self, stage: Stage) {
+        let mut crash = self.crash.lock().unwrap();
+        match &mut *crash {
+            Some((crash_stage, 0)) if *crash_stage == stage => {
+                *crash = None;
+                drop(crash);
+                panic!("simulated crash at {stage:?}");
+            }
+            Some((crash_stage, skip)) if *crash_stage == stage => *skip -= 1,
+            _ => (),
+        }
+    }
+
+    #[cfg(not(test))]
+    #[inline]
+    pub fn crash_point(&self, _stage: Stage) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn incomplete_operations_are_reopened() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-journal-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let path = dir.join("journal.jsonl");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let target = |path: &str| Target {
+            loc: StorageLoc::Remote,
+            path: PathBuf::from(path),
+        };
+        let journal = Journal::open(path.clone()).await.unwrap();
+        let first = journal.begin(vec![target("/a.txt")]).await.unwrap();
+        let second = journal.begin(vec![target("/b.txt")]).await.unwrap();
+        let effect = Effect::Removed {
+            loc: StorageLoc::Remote,
+            path: PathBuf::from("/b.txt"),
+        };
+        journal.effect(second, &effect).await.unwrap();
+        journal.complete(first).await.unwrap();
+
+        let journal = Journal::open(path.clone()).await.unwrap();
+        let pending = journal.pending().await;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, second);
+        assert_eq!(pending[0].targets, vec![target("/b.txt")]);
+        assert_eq!(pending[0].effects, vec![effect]);
+
+        // a truncated record is skipped, and the numbering continues
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        std::io::Write::write_all(&mut file, b"{\"record\":\"begin\",\"id\":").unwrap();
+        let journal = Journal::open(path.clone()).await.unwrap();
+        let third = journal.begin(vec![target("/c.txt")]).await.unwrap();
+        assert!(third > second);
+
+        journal.complete(second).await.unwrap();
+        journal.complete(third).await.unwrap();
+        assert!(journal.pending().await.is_empty());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,31 +1,91 @@
-use std::sync::{Arc, RwLock};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
+};
 
 use futures::{
     future::{self, BoxFuture},
     Future,
 };
 
+pub mod aggregate;
+pub mod audit;
+pub mod buffers;
+pub mod checkpoint;
+pub mod confirm;
+pub mod counters;
+pub mod digest;
+pub mod disk_cache;
+pub mod hooks;
+pub mod ignore;
+pub mod journal;
+pub mod logs;
+pub mod maintenance;
+pub mod merge;
+pub mod pins;
+pub mod placeholders;
+pub mod plan;
+pub mod policy;
+pub mod profile;
+pub mod reception;
+pub mod resume;
+pub mod revalidate;
+pub mod root;
+pub mod secrets;
 pub mod service;
+pub mod status_file;
 pub mod storage;
 pub mod tree;
+pub mod verify;
 
 pub mod oauth2;
 
+mod persist;
+
+/// Counter of the changes of all the progresses, bumped by each change of any of them.
+/// The lock keeps the counter consistent with the [stamps](SharedProgress::changed)
+/// of the progresses, see [`SharedProgress::with_changes`].
+static PROGRESS_CHANGES: Mutex<u64> = Mutex::new(0);
+/// The value of [`PROGRESS_CHANGES`], that can be read without lock
+static LAST_PROGRESS_CHANGE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+struct ProgressState {
+    progress: RwLock<fsync::Progress>,
+    /// The value of the change counter at the last change of the progress
+    changed: AtomicU64,
+    /// Bytes sent again by the storage, not yet counted
+    retransmitted: AtomicU64,
+}
+
 #[derive(Debug, Clone)]
 pub struct SharedProgress {
-    inner: Arc<RwLock<fsync::Progress>>,
+    inner: Arc<ProgressState>,
+}
+
+impl Default for SharedProgress {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SharedProgress {
     pub fn new() -> Self {
-        Self {
-            inner: Arc::new(RwLock::new(fsync::Progress::Init)),
-        }
+        let progress = Self {
+            inner: Arc::new(ProgressState {
+                progress: RwLock::new(fsync::Progress::Init),
+                changed: AtomicU64::new(0),
+                retransmitted: AtomicU64::new(0),
+            }),
+        };
+        progress.bump();
+        progress
     }
 
     /// Get the state
     pub fn get(&self) -> fsync::Progress {
         self.inner
+            .progress
             .read()
             .expect("Lock shouldn't be poisoned")
             .clone()
@@ -33,15 +93,62 @@ impl SharedProgress {
 
     /// Set the state
     pub fn set(&self, progress: fsync::Progress) {
-        *self.inner.write().expect("Lock shouldn't be poisoned") = progress;
+        *self
+            .inner
+            .progress
+            .write()
+            .expect("Lock shouldn't be poisoned") = progress;
+        self.bump();
     }
 
     /// Set the state and get previous one
     pub fn swap(&self, mut progress: fsync::Progress) -> fsync::Progress {
-        let mut inner = self.inner.write().expect("Lock shouldn't be poisoned");
+        let mut inner = self
+            .inner
+            .progress
+            .write()
+            .expect("Lock shouldn't be poisoned");
         std::mem::swap(&mut *inner, &mut progress);
+        drop(inner);
+        self.bump();
         progress
     }
+
+    /// The value of the change counter at the last change of the progress
+    pub fn changed(&self) -> u64 {
+        self.inner.changed.load(Ordering::Acquire)
+    }
+
+    /// The current value of the change counter of all the progresses, without lock
+    pub fn changes() -> u64 {
+        LAST_PROGRESS_CHANGE.load(Ordering::Acquire)
+    }
+
+    /// Call `f` with the current value of the change counter of all the progresses,
+    /// while no progress can record a change. The progresses that [changed](Self::changed)
+    /// after a previous value are then exactly the ones that changed since that value.
+    pub fn with_changes<T>(f: impl FnOnce(u64) -> T) -> T {
+        let changes = PROGRESS_CHANGES.lock().expect("Lock shouldn't be poisoned");
+        f(*changes)
+    }
+
+    /// Report that the storage sent `bytes` again, e.g. the chunks of an upload resent
+    /// after a failure, so that they are counted apart from the first attempts
+    pub fn add_retransmitted(&self, bytes: u64) {
+        self.inner.retransmitted.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// The bytes [sent again](Self::add_retransmitted) since the last call
+    pub fn take_retransmitted(&self) -> u64 {
+        self.inner.retransmitted.swap(0, Ordering::Relaxed)
+    }
+
+    fn bump(&self) {
+        let mut changes = PROGRESS_CHANGES.lock().expect("Lock shouldn't be poisoned");
+        *changes += 1;
+        self.inner.changed.store(*changes, Ordering::Release);
+        LAST_PROGRESS_CHANGE.store(*changes, Ordering::Release);
+    }
 }
 
 pub mod uri {
@@ -78,15 +185,20 @@ mod error {
         fsync::Error::Auth(err.to_string())
     }
 
-    /// Maps error to fsync::Error::Api (to be used in `map_err`)
-    pub fn api<E: std::error::Error>(err: E) -> fsync::Error {
-        fsync::Error::Api(err.to_string())
-    }
-
     /// Maps error to fsync::Error::Io (to be used in `map_err`)
     pub fn io<E: std::error::Error>(err: E) -> fsync::Error {
         fsync::Error::Io(err.to_string())
     }
+
+    /// Maps a failed HTTP request to fsync::Error::Unavailable if the server could not be reached
+    /// or the connection was lost, to fsync::Error::Api otherwise (to be used in `map_err`)
+    pub fn request(err: reqwest::Error) -> fsync::Error {
+        if err.is_connect() || err.is_timeout() || err.is_request() || err.is_body() {
+            fsync::Error::Unavailable(err.to_string())
+        } else {
+            fsync::Error::Api(err.to_string())
+        }
+    }
 }
 
 pub trait PersistCache {
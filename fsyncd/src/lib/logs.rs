@@ -0,0 +1,334 @@
+//! The recent records of the daemon log, kept in memory for the clients,
+//! see [`fsync::Fsync::logs_tail`].
+//!
+//! The [`install`]ed logger passes the records to the logger of the platform
+//! (the console, the systemd journal or the Windows event log) and copies them
+//! in a ring buffer. The warnings and errors are always copied. The more verbose records
+//! are only formatted and copied while a client follows the log, so that the hot paths
+//! don't pay for records that nobody reads.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use chrono::Utc;
+use fsync::{LogLevel, LogRecord};
+use log::LevelFilter;
+
+/// The log is followed while a client read the buffer within this delay
+const FOLLOW_TIMEOUT: Duration = Duration::from_secs(30);
+
+static BUFFER: Buffer = Buffer::new(1000, LogLevel::Debug);
+
+/// Level of the records passed to the logger of the platform
+static PLATFORM_LEVEL: OnceLock<LevelFilter> = OnceLock::new();
+
+static NEXT_OPERATION: AtomicU64 = AtomicU64::new(1);
+
+tokio::task_local! {
+    static OPERATION: u64;
+}
+
+/// The buffer of the installed logger
+pub fn buffer() -> &'static Buffer {
+    &BUFFER
+}
+
+/// Install the logger, passing the records up to `level` to `platform`
+pub fn install(platform: Box<dyn log::Log>, level: LevelFilter) -> Result<(), log::SetLoggerError> {
+    let _ = PLATFORM_LEVEL.set(level);
+    log::set_boxed_logger(Box::new(Logger { platform }))?;
+    update_max_level();
+    Ok(())
+}
+
+/// Apply the capacity and the level of the config to the buffer
+pub fn configure(config: &fsync::LogBuffer) {
+    BUFFER.configure(config.capacity, config.level);
+    update_max_level();
+}
+
+/// Run `fut` as a new operation, whose identifier is recorded with the records it logs
+pub fn operation_scope<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    OPERATION.scope(NEXT_OPERATION.fetch_add(1, Ordering::Relaxed), fut)
+}
+
+fn update_max_level() {
+    let platform = PLATFORM_LEVEL.get().copied().unwrap_or(LevelFilter::Off);
+    log::set_max_level(platform.max(BUFFER.max_level()));
+}
+
+fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    }
+}
+
+fn log_level(level: log::Level) -> LogLevel {
+    match level {
+        log::Level::Error => LogLevel::Error,
+        log::Level::Warn => LogLevel::Warn,
+        log::Level::Info => LogLevel::Info,
+        log::Level::Debug => LogLevel::Debug,
+        log::Level::Trace => LogLevel::Trace,
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A ring buffer of the recent log records
+#[derive(Debug)]
+pub struct Buffer {
+    /// `LevelFilter` of the records kept, as `usize`
+    max_level: AtomicUsize,
+    capacity: AtomicUsize,
+    /// Seconds since the Unix epoch of the last read
+    last_read: AtomicU64,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    records: VecDeque<LogRecord>,
+    /// Sequence number of the last record, including the ones dropped or not kept
+    last_seq: u64,
+}
+
+impl Buffer {
+    pub const fn new(capacity: usize, level: LogLevel) -> Self {
+        Self {
+            max_level: AtomicUsize::new(level as usize + 1),
+            capacity: AtomicUsize::new(capacity),
+            last_read: AtomicU64::new(0),
+            inner: Mutex::new(Inner {
+                records: VecDeque::new(),
+                last_seq: 0,
+            }),
+        }
+    }
+
+    pub fn configure(&self, capacity: usize, level: LogLevel) {
+        self.max_level
+            .store(level_filter(level) as usize, Ordering::Relaxed);
+        self.capacity.store(capacity, Ordering::Relaxed);
+        let mut inner = self.inner.lock().unwrap();
+        let excess = inner.records.len().saturating_sub(capacity);
+        inner.records.drain(..excess);
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        match self.max_level.load(Ordering::Relaxed) {
+            0 => LevelFilter::Off,
+            1 => LevelFilter::Error,
+            2 => LevelFilter::Warn,
+            3 => LevelFilter::Info,
+            4 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    /// Whether a client read the buffer recently
+    fn is_followed(&self) -> bool {
+        let last_read = self.last_read.load(Ordering::Relaxed);
+        now_secs().saturating_sub(last_read) < FOLLOW_TIMEOUT.as_secs()
+    }
+
+    /// Whether the records of `level` are kept
+    pub fn captures(&self, level: log::Level) -> bool {
+        level as usize <= self.max_level.load(Ordering::Relaxed)
+            && (level <= log::Level::Warn || self.is_followed())
+    }
+
+    pub fn push(&self, record: &log::Record) {
+        let mut entry = LogRecord {
+            seq: 0,
+            time: Utc::now(),
+            level: log_level(record.level()),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            operation: OPERATION.try_with(|id| *id).ok(),
+        };
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_seq += 1;
+        entry.seq = inner.last_seq;
+        if capacity == 0 {
+            return;
+        }
+        while inner.records.len() >= capacity {
+            inner.records.pop_front();
+        }
+        inner.records.push_back(entry);
+    }
+
+    /// The `max` most recent records of `min_level` or more severe, the oldest first
+    pub fn tail(&self, max: usize, min_level: LogLevel) -> Vec<LogRecord> {
+        self.last_read.store(now_secs(), Ordering::Relaxed);
+        let inner = self.inner.lock().unwrap();
+        let mut records: Vec<LogRecord> = inner
+            .records
+            .iter()
+            .rev()
+            .filter(|r| r.level.passes(min_level))
+            .take(max)
+            .cloned()
+            .collect();
+        records.reverse();
+        records
+    }
+
+    /// Up to `max` records logged after `cursor`, of `min_level` or more severe,
+    /// with the cursor of the next call
+    pub fn since(&self, cursor: u64, max: usize, min_level: LogLevel) -> (u64, Vec<LogRecord>) {
+        self.last_read.store(now_secs(), Ordering::Relaxed);
+        let inner = self.inner.lock().unwrap();
+        // a cursor of a previous run of the daemon
+        let cursor = if cursor > inner.last_seq { 0 } else { cursor };
+        let mut records = Vec::new();
+        let mut next = inner.last_seq;
+        for record in inner.records.iter().filter(|r| r.seq > cursor) {
+            if !record.level.passes(min_level) {
+                continue;
+            }
+            if records.len() == max {
+                next = records.last().map_or(cursor, |r: &LogRecord| r.seq);
+                break;
+            }
+            records.push(record.clone());
+        }
+        (next, records)
+    }
+}
+
+/// Passes the records to the logger of the platform, and copies them in [`BUFFER`]
+struct Logger {
+    platform: Box<dyn log::Log>,
+}
+
+impl Logger {
+    fn to_platform(&self, metadata: &log::Metadata) -> bool {
+        PLATFORM_LEVEL
+            .get()
+            .is_some_and(|level| metadata.level() <= *level)
+            && self.platform.enabled(metadata)
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.to_platform(metadata) || BUFFER.captures(metadata.level())
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.to_platform(record.metadata()) {
+            self.platform.log(record);
+        }
+        if BUFFER.captures(record.level()) {
+            BUFFER.push(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.platform.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(buffer: &Buffer, level: log::Level, message: &str) {
+        buffer.push(
+            &log::Record::builder()
+                .level(level)
+                .target("test")
+                .args(format_args!("{message}"))
+                .build(),
+        );
+    }
+
+    fn messages(records: &[LogRecord]) -> Vec<&str> {
+        records.iter().map(|r| r.message.as_str()).collect()
+    }
+
+    #[test]
+    fn verbose_records_are_kept_while_followed() {
+        let buffer = Buffer::new(10, LogLevel::Debug);
+        assert!(buffer.captures(log::Level::Warn));
+        assert!(!buffer.captures(log::Level::Info));
+
+        buffer.tail(10, LogLevel::Trace);
+        assert!(buffer.captures(log::Level::Debug));
+        assert!(!buffer.captures(log::Level::Trace));
+
+        buffer.configure(10, LogLevel::Warn);
+        assert!(!buffer.captures(log::Level::Info));
+    }
+
+    #[test]
+    fn oldest_records_are_dropped() {
+        let buffer = Buffer::new(3, LogLevel::Trace);
+        for i in 0..5 {
+            push(&buffer, log::Level::Info, &i.to_string());
+        }
+        let records = buffer.tail(10, LogLevel::Trace);
+        assert_eq!(messages(&records), ["2", "3", "4"]);
+        assert_eq!(records[2].seq, 5);
+        assert_eq!(messages(&buffer.tail(2, LogLevel::Trace)), ["3", "4"]);
+
+        buffer.configure(1, LogLevel::Trace);
+        assert_eq!(messages(&buffer.tail(10, LogLevel::Trace)), ["4"]);
+    }
+
+    #[test]
+    fn records_since_cursor() {
+        let buffer = Buffer::new(10, LogLevel::Trace);
+        push(&buffer, log::Level::Error, "error");
+        push(&buffer, log::Level::Debug, "debug");
+        push(&buffer, log::Level::Warn, "warn");
+        push(&buffer, log::Level::Info, "info");
+
+        let (cursor, records) = buffer.since(0, 10, LogLevel::Warn);
+        assert_eq!(messages(&records), ["error", "warn"]);
+        assert_eq!(cursor, 4);
+        let (cursor, records) = buffer.since(cursor, 10, LogLevel::Trace);
+        assert!(records.is_empty());
+        assert_eq!(cursor, 4);
+
+        // the next call returns the records left out by `max`
+        let (cursor, records) = buffer.since(0, 2, LogLevel::Trace);
+        assert_eq!(messages(&records), ["error", "debug"]);
+        let (cursor, records) = buffer.since(cursor, 2, LogLevel::Trace);
+        assert_eq!(messages(&records), ["warn", "info"]);
+        assert_eq!(cursor, 4);
+
+        // the daemon restarted
+        let (_, records) = buffer.since(100, 1, LogLevel::Trace);
+        assert_eq!(messages(&records), ["error"]);
+    }
+
+    #[tokio::test]
+    async fn records_carry_the_operation() {
+        let buffer = Buffer::new(10, LogLevel::Trace);
+        push(&buffer, log::Level::Info, "outside");
+        operation_scope(async { push(&buffer, log::Level::Info, "inside") }).await;
+        let records = buffer.tail(10, LogLevel::Trace);
+        assert_eq!(records[0].operation, None);
+        assert!(records[1].operation.is_some());
+    }
+}
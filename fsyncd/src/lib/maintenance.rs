@@ -0,0 +1,123 @@
+//! Cleanup of the files left behind by the daemon, see [`fsync::Maintenance`].
+//!
+//! The service runs the maintenance on schedule, once no operation is in flight,
+//! or on demand through [`fsync::Fsync::run_maintenance`].
+//! The files written by an in-flight operation are never cleaned up:
+//! the temporary files of the tree are kept while an operation covers their target,
+//! and the partial downloads are only considered stale after [`STALE_PARTIAL`].
+
+use std::time::{Duration, SystemTime};
+
+use fsync::{
+    path::{FsPath, FsPathBuf},
+    Cleanup,
+};
+
+/// Time without write after which a partial download is considered abandoned
+pub const STALE_PARTIAL: Duration = Duration::from_secs(3600);
+
+/// Delay before checking again whether the operations are done
+pub const BUSY_RETRY: Duration = Duration::from_secs(60);
+
+/// Suffix of the temporary files written in the tree before being renamed to their target.
+/// A number is appended if a file with the suffix already exists.
+pub const TEMP_SUFFIX: &str = ".fsync-part";
+
+/// The name of the target of the temporary file named `name`, if it is one
+pub fn temp_target(name: &str) -> Option<&str> {
+    let (target, rest) = name.rsplit_once(TEMP_SUFFIX)?;
+    let numbered = rest
+        .strip_prefix('.')
+        .is_some_and(|num| !num.is_empty() && num.bytes().all(|b| b.is_ascii_digit()));
+    (!target.is_empty() && (rest.is_empty() || numbered)).then_some(target)
+}
+
+/// The files within `dir` last modified before `cutoff`, with their size
+pub async fn files_older_than(dir: &FsPath, cutoff: SystemTime) -> Vec<(FsPathBuf, u64)> {
+    let dir = dir.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let mut files = Vec::new();
+        let mut stack = vec![dir.into_std_path_buf()];
+        while let Some(dir) = stack.pop() {
+            let Ok(read_dir) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for direntry in read_dir.flatten() {
+                match direntry.metadata() {
+                    Ok(md) if md.is_dir() => stack.push(direntry.path()),
+                    Ok(md) if md.modified().is_ok_and(|mtime| mtime < cutoff) => {
+                        if let Ok(path) = FsPathBuf::try_from(direntry.path()) {
+                            files.push((path, md.len()));
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        files.sort();
+        files
+    })
+    .await
+    .unwrap_or_default()
+}
+
+/// Record `cleanup` in the log
+pub fn log_cleanup(cleanup: &Cleanup, dry_run: bool) {
+    let verb = if dry_run {
+        "Would clean up"
+    } else {
+        "Cleaned up"
+    };
+    log::info!(
+        target: "maintenance",
+        "{verb} {} {}: {} bytes",
+        cleanup.kind,
+        cleanup.path,
+        cleanup.bytes
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_targets() {
+        assert_eq!(temp_target("file.txt.fsync-part"), Some("file.txt"));
+        assert_eq!(temp_target("file.txt.fsync-part.12"), Some("file.txt"));
+        assert_eq!(temp_target("file.txt"), None);
+        assert_eq!(temp_target(".fsync-part"), None);
+        assert_eq!(temp_target("file.txt.fsync-part."), None);
+        assert_eq!(temp_target("file.txt.fsync-part.bak"), None);
+        assert_eq!(temp_target("file.txt.fsync-partial"), None);
+    }
+
+    #[tokio::test]
+    async fn files_older_than_cutoff() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-maintenance-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+
+        let old = SystemTime::now() - Duration::from_secs(7200);
+        for (name, mtime) in [("old", Some(old)), ("sub/old", Some(old)), ("new", None)] {
+            let file = std::fs::File::create(dir.join(name)).unwrap();
+            file.set_len(10).unwrap();
+            if let Some(mtime) = mtime {
+                file.set_modified(mtime).unwrap();
+            }
+        }
+
+        let cutoff = SystemTime::now() - STALE_PARTIAL;
+        let files = files_older_than(&dir, cutoff).await;
+        assert_eq!(
+            files,
+            vec![(dir.join("old"), 10), (dir.join("sub/old"), 10)]
+        );
+        assert!(files_older_than(&dir.join("missing"), cutoff)
+            .await
+            .is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,371 @@
+//! Merge of the concurrent edits of text files, see [`fsync::ResolutionMethod::MergeText`].
+//!
+//! The local and remote versions are merged line by line from their common base,
+//! the version last synchronized, as `diff3` does: the changes of a single side are taken,
+//! and the changes of both sides to the same or to adjacent lines must be identical.
+//!
+//! The bases are kept by [`Bases`] in the cache directory of the instance, one file per path,
+//! named after the hashes of the path and of the content. The least recently synchronized
+//! are evicted beyond the budget, so a conflict on an old file may have no base to merge from.
+
+use std::{collections::HashMap, fmt, io, ops::Range, time::SystemTime};
+
+use fsync::{
+    path::{FsPathBuf, Path},
+    text,
+};
+use sha2::{Digest, Sha256};
+use similar::{Algorithm, DiffTag};
+use tokio::sync::Mutex;
+
+use crate::persist;
+
+/// Size that the bases may use on disk
+pub const BASES_BUDGET: u64 = 16 * 1024 * 1024;
+
+/// Why a merge failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    /// The version last synchronized is not in the bases
+    NoBase,
+    /// One of the versions is not text
+    NotText,
+    /// Both sides changed the same lines, given as 1-based inclusive ranges of the local version
+    Overlaps(Vec<(usize, usize)>),
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoBase => f.write_str("merge failed: the version last synchronized is unknown"),
+            Self::NotText => f.write_str("merge failed: not a text file"),
+            Self::Overlaps(lines) => {
+                let lines: Vec<_> = lines
+                    .iter()
+                    .map(|(first, last)| match first == last {
+                        true => format!("line {first}"),
+                        false => format!("lines {first}–{last}"),
+                    })
+                    .collect();
+                write!(
+                    f,
+                    "merge failed: overlapping changes on {}",
+                    lines.join(", ")
+                )
+            }
+        }
+    }
+}
+
+/// The changed lines of a side, from the base
+#[derive(Debug)]
+struct Hunk {
+    base: Range<usize>,
+    side: Range<usize>,
+}
+
+fn hunks(base: &[&str], side: &[&str]) -> Vec<Hunk> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    for op in similar::capture_diff_slices(Algorithm::Myers, base, side) {
+        let (tag, base, side) = op.as_tag_tuple();
+        if tag == DiffTag::Equal {
+            continue;
+        }
+        match hunks.last_mut() {
+            // a deletion followed by an insertion is a single replacement
+            Some(last) if last.base.end == base.start && last.side.end == side.start => {
+                last.base.end = base.end;
+                last.side.end = side.end;
+            }
+            _ => hunks.push(Hunk { base, side }),
+        }
+    }
+    hunks
+}
+
+/// The lines of a side matching the lines `start..end` of the base,
+/// where `offset` is the shift of the side before `start`, and `hunks` are the changes within.
+/// Returns the range and the shift after `end`.
+fn side_range(start: usize, end: usize, offset: isize, hunks: &[Hunk]) -> (Range<usize>, isize) {
+    let after = hunks.iter().fold(offset, |offset, hunk| {
+        offset + hunk.side.len() as isize - hunk.base.len() as isize
+    });
+    let range = (start as isize + offset) as usize..(end as isize + after) as usize;
+    (range, after)
+}
+
+/// Merge the `local` and `remote` versions of a text file from their common `base`
+pub fn merge(base: &str, local: &str, remote: &str) -> Result<String, MergeError> {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let local: Vec<&str> = local.split_inclusive('\n').collect();
+    let remote: Vec<&str> = remote.split_inclusive('\n').collect();
+    let local_hunks = hunks(&base, &local);
+    let remote_hunks = hunks(&base, &remote);
+
+    let mut merged = String::new();
+    let mut overlaps = Vec::new();
+    let (mut li, mut ri) = (0, 0);
+    let (mut local_offset, mut remote_offset) = (0, 0);
+    let mut pos = 0;
+    loop {
+        let start = match (local_hunks.get(li), remote_hunks.get(ri)) {
+            (None, None) => break,
+            (Some(l), None) => l.base.start,
+            (None, Some(r)) => r.base.start,
+            (Some(l), Some(r)) => l.base.start.min(r.base.start),
+        };
+        // the hunks of both sides that touch each other
+        let (l0, r0) = (li, ri);
+        let mut end = start;
+        loop {
+            if let Some(l) = local_hunks.get(li).filter(|l| l.base.start <= end) {
+                end = end.max(l.base.end);
+                li += 1;
+            } else if let Some(r) = remote_hunks.get(ri).filter(|r| r.base.start <= end) {
+                end = end.max(r.base.end);
+                ri += 1;
+            } else {
+                break;
+            }
+        }
+        merged.extend(base[pos..start].iter().copied());
+        let (l, after) = side_range(start, end, local_offset, &local_hunks[l0..li]);
+        local_offset = after;
+        let (r, after) = side_range(start, end, remote_offset, &remote_hunks[r0..ri]);
+        remote_offset = after;
+        if r0 == ri || local[l.clone()] == remote[r.clone()] {
+            merged.extend(local[l].iter().copied());
+        } else if l0 == li {
+            merged.extend(remote[r].iter().copied());
+        } else {
+            overlaps.push((l.start + 1, l.end.max(l.start + 1)));
+        }
+        pos = end;
+    }
+    if !overlaps.is_empty() {
+        return Err(MergeError::Overlaps(overlaps));
+    }
+    merged.extend(base[pos..].iter().copied());
+    Ok(merged)
+}
+
+/// Merge the contents of a file, failing with [`MergeError::NotText`] if one of them is binary
+pub fn merge_bytes(
+    base: Option<&[u8]>,
+    local: &[u8],
+    remote: &[u8],
+) -> Result<Vec<u8>, MergeError> {
+    let text = |data| match text::is_binary(data) {
+        true => Err(MergeError::NotText),
+        false => std::str::from_utf8(data).map_err(|_| MergeError::NotText),
+    };
+    let (local, remote) = (text(local)?, text(remote)?);
+    if local == remote {
+        return Ok(local.as_bytes().to_vec());
+    }
+    let base = text(base.ok_or(MergeError::NoBase)?)?;
+    merge(base, local, remote).map(String::into_bytes)
+}
+
+#[derive(Debug, Clone)]
+struct Base {
+    file_name: String,
+    size: u64,
+    time: SystemTime,
+}
+
+/// The versions of the text files last synchronized, by path
+#[derive(Debug)]
+pub struct Bases {
+    dir: FsPathBuf,
+    budget: u64,
+    /// By hash of the path
+    index: Mutex<HashMap<String, Base>>,
+}
+
+impl Bases {
+    /// Open the bases kept in `dir`, created if needed
+    pub async fn open(dir: FsPathBuf, budget: u64) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(&dir).await?;
+        let mut index = HashMap::new();
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(file_name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let Some((key, _)) = file_name.split_once('-') else {
+                continue;
+            };
+            let metadata = entry.metadata().await?;
+            let base = Base {
+                file_name: file_name.clone(),
+                size: metadata.len(),
+                time: metadata.modified()?,
+            };
+            // a duplicate left by a crash: the other one is kept
+            if let Some(dup) = index.insert(key.to_string(), base) {
+                let _ = tokio::fs::remove_file(dir.join(&dup.file_name)).await;
+            }
+        }
+        Ok(Self {
+            dir,
+            budget,
+            index: Mutex::new(index),
+        })
+    }
+
+    fn hash(data: &[u8]) -> String {
+        hex::encode(&Sha256::digest(data)[..16])
+    }
+
+    /// Record `content` as the version of `path` last synchronized.
+    /// Nothing is recorded for the files that can't be merged, and their previous base is dropped.
+    pub async fn record(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let key = Self::hash(path.as_str().as_bytes());
+        let mut index = self.index.lock().await;
+        let mergeable = content.len() as u64 <= text::MAX_MERGE_SIZE
+            && !text::has_binary_extension(path)
+            && !text::is_binary(content);
+        let file_name = format!("{key}-{}", Self::hash(content));
+        let previous = index.remove(&key);
+        if let Some(previous) = previous.filter(|p| p.file_name != file_name || !mergeable) {
+            let _ = tokio::fs::remove_file(self.dir.join(&previous.file_name)).await;
+        }
+        if !mergeable {
+            return Ok(());
+        }
+        let file = self.dir.join(&file_name);
+        let data = content.to_vec();
+        tokio::task::spawn_blocking(move || persist::atomic_write(&file, &data)).await??;
+        let base = Base {
+            file_name,
+            size: content.len() as u64,
+            time: SystemTime::now(),
+        };
+        index.insert(key, base);
+        self.evict(&mut index).await;
+        Ok(())
+    }
+
+    /// The version of `path` last synchronized, if it is known
+    pub async fn get(&self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+        let key = Self::hash(path.as_str().as_bytes());
+        let index = self.index.lock().await;
+        let Some(base) = index.get(&key) else {
+            return Ok(None);
+        };
+        let data = tokio::fs::read(self.dir.join(&base.file_name)).await?;
+        // the hash of the content is checked, as a truncated base would merge silently wrong
+        if !base.file_name.ends_with(&Self::hash(&data)) {
+            log::warn!("{path}: discarding the corrupted base of the merge");
+            return Ok(None);
+        }
+        Ok(Some(data))
+    }
+
+    async fn evict(&self, index: &mut HashMap<String, Base>) {
+        let mut total: u64 = index.values().map(|b| b.size).sum();
+        if total <= self.budget {
+            return;
+        }
+        let mut by_age: Vec<_> = index.iter().map(|(k, b)| (b.time, k.clone())).collect();
+        by_age.sort();
+        for (_, key) in by_age {
+            if total <= self.budget {
+                break;
+            }
+            let base = index.remove(&key).expect("key should be indexed");
+            total -= base.size;
+            let _ = tokio::fs::remove_file(self.dir.join(&base.file_name)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "one\ntwo\nthree\nfour\nfive\nsix\n";
+
+    #[test]
+    fn merge_independent_edits() {
+        let local = "one\n2\nthree\nfour\nfive\nsix\n";
+        let remote = "one\ntwo\nthree\nfour\nfive\nsix\nseven\n";
+        assert_eq!(
+            merge(BASE, local, remote).unwrap(),
+            "one\n2\nthree\nfour\nfive\nsix\nseven\n"
+        );
+
+        // the same change on both sides, and a deletion
+        let local = "zero\none\ntwo\nthree\nfive\nsix\n";
+        let remote = "zero\none\ntwo\nthree\nfour\nfive\nsix\n";
+        assert_eq!(
+            merge(BASE, local, remote).unwrap(),
+            "zero\none\ntwo\nthree\nfive\nsix\n"
+        );
+    }
+
+    #[test]
+    fn overlapping_edits() {
+        let local = "one\nTWO\nTHREE\nfour\nfive\nsix\n";
+        let remote = "one\n2\nthree\nfour\nfive\n6\n";
+        let err = merge(BASE, local, remote).unwrap_err();
+        assert_eq!(err, MergeError::Overlaps(vec![(2, 3)]));
+        assert_eq!(
+            err.to_string(),
+            "merge failed: overlapping changes on lines 2–3"
+        );
+
+        // adjacent edits are overlapping as well
+        let local = "one\nTWO\nthree\nfour\nfive\nsix\n";
+        let remote = "one\ntwo\n3\nfour\nfive\nsix\n";
+        assert_eq!(
+            merge(BASE, local, remote).unwrap_err(),
+            MergeError::Overlaps(vec![(2, 3)])
+        );
+    }
+
+    #[test]
+    fn merge_bytes_fallbacks() {
+        let local = b"one\n2\n";
+        assert_eq!(merge_bytes(None, local, local).unwrap(), local);
+        assert_eq!(
+            merge_bytes(None, local, b"one\ntwo\n"),
+            Err(MergeError::NoBase)
+        );
+        assert_eq!(
+            merge_bytes(Some(b"one\n"), b"bin\0ary", b"one\n"),
+            Err(MergeError::NotText)
+        );
+    }
+
+    #[tokio::test]
+    async fn bases_are_recorded_and_evicted() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-bases-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let bases = Bases::open(dir.clone(), 10).await.unwrap();
+        let (a, b) = (Path::new("/a.txt"), Path::new("/b.txt"));
+
+        bases.record(a, b"first\n").await.unwrap();
+        bases.record(a, b"second\n").await.unwrap();
+        assert_eq!(bases.get(a).await.unwrap().unwrap(), b"second\n");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // reopened from the directory
+        drop(bases);
+        let bases = Bases::open(dir.clone(), 10).await.unwrap();
+        assert_eq!(bases.get(a).await.unwrap().unwrap(), b"second\n");
+
+        // over the budget, the oldest is evicted
+        bases.record(b, b"other\n").await.unwrap();
+        assert_eq!(bases.get(a).await.unwrap(), None);
+        assert_eq!(bases.get(b).await.unwrap().unwrap(), b"other\n");
+
+        // binary content drops the base
+        bases.record(b, b"\0").await.unwrap();
+        assert_eq!(bases.get(b).await.unwrap(), None);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,15 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use fsync::Progress;
+use fsync::{
+    oauth2::{Flow, RedirectServer},
+    Progress,
+};
 use futures::prelude::*;
 use oauth2::{basic::BasicClient, HttpRequest, HttpResponse, TokenResponse};
 pub use oauth2::{AccessToken, RefreshToken, Scope};
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 
+mod device;
 mod pkce;
 mod server;
 mod token_cache;
@@ -13,6 +17,9 @@ mod token_cache;
 pub use self::token_cache::{CacheResult, TokenCache, TokenMap, TokenPersist};
 use crate::{error, PersistCache, SharedProgress};
 
+/// The tokens expiring within this margin are refreshed at the resume from sleep
+pub const RESUME_MARGIN: Duration = Duration::from_secs(5 * 60);
+
 pub trait GetToken: Send + Sync + 'static {
     fn get_token(
         &self,
@@ -24,8 +31,13 @@ pub trait GetToken: Send + Sync + 'static {
 #[derive(Debug)]
 struct Inner {
     cache: RwLock<TokenCache>,
+    /// Serializes the refreshes and authorizations, so that the requests that find
+    /// the token expired together, e.g. at the resume from sleep, get it once
+    refreshing: Mutex<()>,
     http: reqwest::Client,
     oauth2: BasicClient,
+    flow: Flow,
+    redirect: RedirectServer,
 }
 
 #[derive(Clone, Debug)]
@@ -36,6 +48,8 @@ pub struct Client {
 impl Client {
     pub async fn new(
         secret: fsync::oauth2::Secret,
+        flow: Flow,
+        redirect: RedirectServer,
         persist: TokenPersist,
         http: Option<reqwest::Client>,
     ) -> anyhow::Result<Self> {
@@ -47,17 +61,49 @@ impl Client {
             secret.auth_url,
             Some(secret.token_url),
         );
-        let http = http.unwrap_or_else(|| reqwest::Client::new());
+        let oauth2 = match secret.device_auth_url {
+            Some(url) => oauth2.set_device_authorization_url(url),
+            None => oauth2,
+        };
+        let flow = match flow {
+            Flow::Auto if oauth2.device_authorization_url().is_some() && !has_display() => {
+                Flow::DeviceCode
+            }
+            Flow::Auto => Flow::Pkce,
+            flow => flow,
+        };
+        log::trace!("OAuth2 interactive flow: {flow:?}");
+        let http = http.unwrap_or_default();
 
         Ok(Self {
             inner: Arc::new(Inner {
                 cache,
+                refreshing: Mutex::new(()),
                 http,
                 oauth2,
+                flow,
+                redirect,
             }),
         })
     }
 
+    /// The token cache file that was set aside at startup, if it could not be read
+    pub async fn corrupt_cache(&self) -> Option<fsync::CorruptFile> {
+        self.inner.cache.read().await.corrupt_file().cloned()
+    }
+
+    /// Refresh the cached tokens that expire within `margin`, e.g. at the resume from sleep,
+    /// before the requests need them. The tokens that can't be refreshed are left to the
+    /// next request, which may authorize again interactively.
+    pub async fn refresh_expiring(&self, margin: Duration) -> fsync::Result<()> {
+        let _refreshing = self.inner.refreshing.lock().await;
+        let expiring = self.inner.cache.read().await.expiring(margin);
+        for (refresh_token, scopes) in expiring {
+            self.refresh_token(refresh_token, scopes, None).await?;
+        }
+        Ok(())
+    }
+
     async fn refresh_token(
         &self,
         refresh_token: RefreshToken,
@@ -87,12 +133,15 @@ impl Client {
         Ok(access)
     }
 
-    async fn pkce_and_cache(
+    async fn authorize_and_cache(
         &self,
         scopes: Vec<Scope>,
         progress: Option<&SharedProgress>,
     ) -> fsync::Result<AccessToken> {
-        let resp = self.fetch_token_pkce(scopes, progress).await?;
+        let resp = match self.inner.flow {
+            Flow::DeviceCode => self.fetch_token_device_code(scopes, progress).await?,
+            _ => self.fetch_token_pkce(scopes, progress).await?,
+        };
         let mut cache = self.inner.cache.write().await;
         cache.put(&resp);
         Ok(resp.access_token().clone())
@@ -130,7 +179,7 @@ impl Client {
         Ok(HttpResponse {
             status_code,
             headers,
-            body: body.into(),
+            body,
         })
     }
 }
@@ -141,19 +190,33 @@ impl GetToken for Client {
         scopes: Vec<Scope>,
         progress: Option<&SharedProgress>,
     ) -> fsync::Result<AccessToken> {
+        if let CacheResult::Ok(access_token) = self.inner.cache.read().await.check(&scopes) {
+            return Ok(access_token);
+        }
+        let _refreshing = self.inner.refreshing.lock().await;
+        // refreshed by another request while this one waited
         let cache = self.inner.cache.read().await.check(&scopes);
         match cache {
             CacheResult::Ok(access_token) => Ok(access_token),
             CacheResult::Expired(refresh_token, scopes) => {
                 self.refresh_token(refresh_token, scopes.clone(), progress)
-                    .or_else(|_err| self.pkce_and_cache(scopes, progress))
+                    .or_else(|_err| self.authorize_and_cache(scopes, progress))
                     .await
             }
-            CacheResult::None => self.pkce_and_cache(scopes, progress).await,
+            CacheResult::None => self.authorize_and_cache(scopes, progress).await,
         }
     }
 }
 
+/// Whether a graphical session is available to open a browser
+fn has_display() -> bool {
+    if cfg!(all(unix, not(target_os = "macos"))) {
+        std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+    } else {
+        true
+    }
+}
+
 impl PersistCache for Client {
     async fn persist_cache(&self) -> anyhow::Result<()> {
         self.inner.cache.read().await.persist_cache().await?;
@@ -0,0 +1,100 @@
+use fsync::{DeviceCodeError, Progress};
+use oauth2::{
+    basic::BasicTokenResponse,
+    devicecode::{DeviceCodeErrorResponse, DeviceCodeErrorResponseType},
+    RequestTokenError, Scope, StandardDeviceAuthorizationResponse,
+};
+
+use super::Client;
+use crate::{error, SharedProgress};
+
+impl Client {
+    /// Fetch a token with the device authorization grant (RFC 8628).
+    /// The user is given a URL and a code to enter on any device with a browser,
+    /// while the token endpoint is polled until the authorization completes.
+    pub async fn fetch_token_device_code(
+        &self,
+        scopes: Vec<Scope>,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<BasicTokenResponse> {
+        log::info!("Starting device code flow for scopes {scopes:?}");
+
+        let details: StandardDeviceAuthorizationResponse = self
+            .inner
+            .oauth2
+            .exchange_device_code()
+            .map_err(error::auth)?
+            .add_scopes(scopes)
+            .request_async(|req| async { self.http(req).await })
+            .await
+            .map_err(error::auth)?;
+
+        let url = details.verification_uri().to_string();
+        let code = details.user_code().secret().to_string();
+        log::warn!("To authorize fsyncd, visit {url} and enter the code {code}");
+        if let Some(progress) = progress {
+            progress.set(Progress::OAuth2DeviceCode { url, code });
+        }
+
+        // polling interval and slow_down responses are handled by the oauth2 crate
+        let token_response = self
+            .inner
+            .oauth2
+            .exchange_device_access_token(&details)
+            .request_async(
+                |req| async { self.http(req).await },
+                tokio::time::sleep,
+                None,
+            )
+            .await
+            .map_err(device_code_error)?;
+
+        if let Some(progress) = progress {
+            progress.set(Progress::OAuth2Exchange);
+        }
+
+        Ok(token_response)
+    }
+}
+
+fn device_code_error(
+    err: RequestTokenError<reqwest::Error, DeviceCodeErrorResponse>,
+) -> fsync::Error {
+    match &err {
+        RequestTokenError::ServerResponse(resp) => match resp.error() {
+            DeviceCodeErrorResponseType::ExpiredToken => DeviceCodeError::Expired.into(),
+            DeviceCodeErrorResponseType::AccessDenied => DeviceCodeError::Denied.into(),
+            _ => error::auth(err),
+        },
+        _ => error::auth(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use oauth2::StandardErrorResponse;
+
+    use super::*;
+
+    fn server_error(typ: DeviceCodeErrorResponseType) -> fsync::Error {
+        device_code_error(RequestTokenError::ServerResponse(
+            StandardErrorResponse::new(typ, None, None),
+        ))
+    }
+
+    #[test]
+    fn device_code_typed_errors() {
+        assert!(matches!(
+            server_error(DeviceCodeErrorResponseType::ExpiredToken),
+            fsync::Error::DeviceCode(DeviceCodeError::Expired)
+        ));
+        assert!(matches!(
+            server_error(DeviceCodeErrorResponseType::AccessDenied),
+            fsync::Error::DeviceCode(DeviceCodeError::Denied)
+        ));
+        assert!(matches!(
+            server_error(DeviceCodeErrorResponseType::SlowDown),
+            fsync::Error::Auth(_)
+        ));
+    }
+}
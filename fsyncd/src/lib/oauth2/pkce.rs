@@ -1,7 +1,5 @@
-use std::net::SocketAddr;
-
 use chrono::Utc;
-use fsync::Progress;
+use fsync::{oauth2::RedirectServer, AuthPrompt, Progress};
 use oauth2::{
     basic::BasicTokenResponse, AuthorizationCode, CsrfToken, PkceCodeChallenge, RedirectUrl, Scope,
 };
@@ -18,11 +16,12 @@ impl Client {
     ) -> fsync::Result<BasicTokenResponse> {
         log::info!("Starting PKCE flow for scopes {scopes:?}");
 
-        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
-        let listener = net::TcpListener::bind(&addr).await?;
+        let server = self.inner.redirect;
+        let listener = net::TcpListener::bind(server.bind_addr()).await?;
         let redirect_addr = listener.local_addr()?;
 
-        let redirect_url = RedirectUrl::new(format!("http://{redirect_addr}")).expect("Valid URL");
+        let redirect_url =
+            RedirectUrl::new(RedirectServer::redirect_url(redirect_addr)).expect("Valid URL");
         let redirect_url = std::borrow::Cow::Borrowed(&redirect_url);
 
         let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
@@ -36,21 +35,20 @@ impl Client {
             .set_pkce_challenge(pkce_challenge)
             .url();
 
+        let prompt = open_browser(auth_url.to_string(), &server).await;
         if let Some(progress) = progress {
-            progress.set(Progress::OAuth2Exchange);
+            progress.set(Progress::OAuth2Browse(prompt));
         }
 
-        log::info!("Opening browser to {auth_url}.");
-        tokio::task::spawn_blocking(move || webbrowser::open(auth_url.as_str()));
-
         log::trace!("starting local server on {redirect_addr}");
-        let (socket, addr) = listener.accept().await?;
-
-        log::trace!("incoming request from {addr:#?}");
-        let (reader, writer) = io::split(socket);
-        let reader = io::BufReader::new(reader);
-        let writer = io::BufWriter::new(writer);
-        let req = server::parse_request(reader).await?;
+        let Ok(redirect) = tokio::time::timeout(server.timeout(), accept(&listener)).await else {
+            log::error!(
+                "The browser was not redirected to {redirect_addr} within {}s",
+                server.timeout
+            );
+            return Err(fsync::Error::AuthTimeout(server.timeout));
+        };
+        let (req, writer) = redirect?;
         let query = uri::QueryMap::parse(req.uri().query())?;
 
         let code = query
@@ -115,3 +113,74 @@ impl Client {
         Ok(token_response)
     }
 }
+
+/// Open the authorization page in a browser, if the daemon runs in a graphical session
+async fn open_browser(url: String, server: &RedirectServer) -> AuthPrompt {
+    let expires_at = Utc::now() + server.timeout();
+    let open_url = url.clone();
+    let res = tokio::task::spawn_blocking(move || webbrowser::open(&open_url))
+        .await
+        .map_err(|err| err.to_string())
+        .and_then(|res| res.map_err(|err| err.to_string()));
+    let opened = match res {
+        Ok(()) => {
+            log::info!("Opened browser to {url}");
+            true
+        }
+        Err(err) => {
+            log::warn!("Could not open a browser ({err}). To authorize fsyncd, visit {url}");
+            false
+        }
+    };
+    AuthPrompt {
+        url,
+        expires_at,
+        opened,
+    }
+}
+
+type Writer = io::BufWriter<io::WriteHalf<net::TcpStream>>;
+
+/// Accept the redirection of the browser and read its request
+async fn accept(listener: &net::TcpListener) -> fsync::Result<(http::Request<Vec<u8>>, Writer)> {
+    let (socket, addr) = listener.accept().await?;
+    log::trace!("incoming request from {addr:#?}");
+    let (reader, writer) = io::split(socket);
+    let reader = io::BufReader::new(reader);
+    let writer = io::BufWriter::new(writer);
+    let req = server::parse_request(reader).await?;
+    Ok((req, writer))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn accept_redirect_within_timeout() {
+        let server = RedirectServer::default();
+        let listener = net::TcpListener::bind(server.bind_addr()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // a speculative connection of the browser, that sends nothing
+        let _idle = net::TcpStream::connect(addr).await.unwrap();
+        let res = tokio::time::timeout(Duration::from_millis(100), accept(&listener)).await;
+        assert!(res.is_err());
+
+        let mut browser = net::TcpStream::connect(addr).await.unwrap();
+        browser
+            .write_all(b"GET /?code=abc&state=xyz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let (req, _) = tokio::time::timeout(Duration::from_secs(5), accept(&listener))
+            .await
+            .unwrap()
+            .unwrap();
+        let query = uri::QueryMap::parse(req.uri().query()).unwrap();
+        assert_eq!(query.get("code"), Some("abc"));
+    }
+}
@@ -33,10 +33,10 @@ where
             break;
         }
         let header = parse_header(&buf)?;
-        if str::eq_ignore_ascii_case(&header.0, "transfer-encoding") {
+        if str::eq_ignore_ascii_case(header.0, "transfer-encoding") {
             anyhow::bail!("Unsupported header: Transfer-Encoding")
         }
-        if str::eq_ignore_ascii_case(&header.0, "content-length") {
+        if str::eq_ignore_ascii_case(header.0, "content-length") {
             content_length = Some(header.1.parse()?);
         }
         let (name, value) = parse_header(&buf)?;
@@ -82,7 +82,7 @@ pub(super) fn parse_command(line: &[u8]) -> anyhow::Result<(Method, Uri)> {
 pub(super) fn parse_header(line: &[u8]) -> anyhow::Result<(&str, &str)> {
     let line = str::from_utf8(line)?;
     let (name, value) = line
-        .split_once(|b| b == ':')
+        .split_once(':')
         .with_context(|| format!("Invalid header: {line}"))?;
     let name = name.trim();
     let value = value.trim();
@@ -106,29 +106,29 @@ where
 
     tokio::pin!(writer);
     writer
-        .write(format!("{:?} {}\r\n", parts.version, parts.status).as_bytes())
+        .write_all(format!("{:?} {}\r\n", parts.version, parts.status).as_bytes())
         .await?;
     if !has_date {
         writer
-            .write(format!("Date: {}\r\n", Utc::now().to_rfc2822()).as_bytes())
+            .write_all(format!("Date: {}\r\n", Utc::now().to_rfc2822()).as_bytes())
             .await?;
     }
     if !has_server {
-        writer.write(b"Server: fsync::http::server\r\n").await?;
+        writer.write_all(b"Server: fsync::http::server\r\n").await?;
     }
     if has_body && !has_content_length {
         writer
-            .write(format!("Content-Length: {}\r\n", body.as_ref().len()).as_bytes())
+            .write_all(format!("Content-Length: {}\r\n", body.as_ref().len()).as_bytes())
             .await?;
     }
     for (name, value) in parts.headers.iter() {
-        writer.write(format!("{name}: ").as_bytes()).await?;
-        writer.write(value.as_bytes()).await?;
-        writer.write(b"\r\n").await?;
+        writer.write_all(format!("{name}: ").as_bytes()).await?;
+        writer.write_all(value.as_bytes()).await?;
+        writer.write_all(b"\r\n").await?;
     }
-    writer.write(b"\r\n").await?;
+    writer.write_all(b"\r\n").await?;
     if has_body {
-        writer.write(body.as_ref()).await?;
+        writer.write_all(body.as_ref()).await?;
     }
     writer.flush().await?;
     Ok(())
@@ -149,7 +149,7 @@ mod util {
     {
         use io::AsyncBufReadExt;
 
-        debug_assert!(pattern.len() > 0);
+        debug_assert!(!pattern.is_empty());
         tokio::pin!(reader);
         let mut bb: [u8; 1] = [0];
         let mut len = 0;
@@ -1,11 +1,15 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
 
 use chrono::{DateTime, Utc};
 use fsync::path::{FsPath, FsPathBuf};
 use oauth2::{AccessToken, RefreshToken, Scope, TokenResponse, TokenType};
 use serde::{Deserialize, Serialize};
 
-use crate::PersistCache;
+use crate::{
+    persist,
+    secrets::{self, Sealer},
+    PersistCache,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TokenMapEntry<T> {
@@ -79,14 +83,15 @@ pub enum TokenPersist {
     Memory,
     /// Load from disk, when program starts.
     /// Persist in memory for the duration of the program.
-    /// Saves to disk in PersistCache implementation.
-    MemoryAndDisk(FsPathBuf),
+    /// Saves to disk in PersistCache implementation,
+    /// sealed with the given sealer.
+    MemoryAndDisk(FsPathBuf, Sealer),
 }
 
 impl TokenPersist {
-    fn try_path(&self) -> Option<&FsPath> {
+    fn try_path(&self) -> Option<(&FsPath, &Sealer)> {
         match self {
-            Self::MemoryAndDisk(path) => Some(path),
+            Self::MemoryAndDisk(path, sealer) => Some((path, sealer)),
             _ => None,
         }
     }
@@ -95,7 +100,7 @@ impl TokenPersist {
         match self {
             Self::None => false,
             Self::Memory => true,
-            Self::MemoryAndDisk(_) => true,
+            Self::MemoryAndDisk(..) => true,
         }
     }
 }
@@ -118,25 +123,72 @@ pub enum CacheResult {
 pub struct TokenCache {
     persist: TokenPersist,
     map: TokenMap<CacheToken>,
+    corrupt: Option<fsync::CorruptFile>,
 }
 
 impl TokenCache {
+    /// Load the cache according to `persist`.
+    /// A cache file that can't be read back is set aside, and the cache starts empty,
+    /// so that the tokens are requested again through the normal flow.
     pub async fn new(persist: TokenPersist) -> anyhow::Result<Self> {
-        let map: Option<TokenMap<CacheToken>> = if let Some(path) = persist.try_path() {
+        let mut migrate = false;
+        let mut map = None;
+        let mut corrupt = None;
+        if let Some((path, sealer)) = persist.try_path() {
             log::info!("reading cached tokens from {path}");
-            let json = tokio::fs::read_to_string(path).await;
-            if let Ok(json) = json {
-                serde_json::from_str(&json)?
-            } else {
-                None
+            let read_path = path.to_owned();
+            // the caches of the versions without checksum are in clear text
+            let data = tokio::task::spawn_blocking(move || {
+                persist::read_checked(&read_path, |data| {
+                    serde_json::from_slice::<TokenMap<CacheToken>>(data).is_ok()
+                })
+            })
+            .await?;
+            let loaded = match data {
+                Ok(data) => {
+                    migrate = sealer.is_encrypted() && !secrets::is_sealed(&data);
+                    sealer
+                        .open(&data)
+                        .and_then(|json| Ok(serde_json::from_slice(&json)?))
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::InvalidData => Err(err.into()),
+                Err(err) => {
+                    log::warn!("could not read cached tokens: {err}");
+                    Ok(None)
+                }
+            };
+            match loaded {
+                Ok(loaded) => map = loaded,
+                Err(err) => {
+                    migrate = false;
+                    corrupt = match persist::quarantine(path, "token cache", &err) {
+                        Ok(corrupt) => Some(corrupt),
+                        Err(err) => {
+                            log::error!("could not set aside the corrupt token cache: {err}");
+                            None
+                        }
+                    };
+                }
             }
-        } else {
-            None
-        };
+        }
         let map = map.unwrap_or_else(|| TokenMap {
             entries: Vec::new(),
         });
-        Ok(Self { persist, map })
+        let cache = Self {
+            persist,
+            map,
+            corrupt,
+        };
+        if migrate {
+            log::info!("encrypting cached tokens");
+            cache.persist_cache().await?;
+        }
+        Ok(cache)
+    }
+
+    /// The cache file that was set aside at load, if it could not be read
+    pub fn corrupt_file(&self) -> Option<&fsync::CorruptFile> {
+        self.corrupt.as_ref()
     }
 
     pub fn put<T, TT>(&mut self, tok: &T)
@@ -199,15 +251,197 @@ impl TokenCache {
 
         res
     }
+
+    /// The refresh tokens of the access tokens that expire within `margin`, with their scopes
+    pub fn expiring(&self, margin: Duration) -> Vec<(RefreshToken, Vec<Scope>)> {
+        let deadline = Utc::now() + margin;
+        self.map
+            .entries
+            .iter()
+            .filter_map(|ent| {
+                let expiration = ent.token.expiration?;
+                let refresh_token = ent.token.refresh_token.as_ref()?;
+                (expiration < deadline).then(|| (refresh_token.clone(), ent.scopes.clone()))
+            })
+            .collect()
+    }
 }
 
 impl PersistCache for TokenCache {
     async fn persist_cache(&self) -> anyhow::Result<()> {
-        if let Some(path) = self.persist.try_path() {
+        if let Some((path, sealer)) = self.persist.try_path() {
             log::info!("caching tokens to {path}");
-            let json = serde_json::to_string_pretty(&self.map)?;
-            tokio::fs::write(path, json).await?;
+            let json = serde_json::to_vec_pretty(&self.map)?;
+            let data = sealer.seal(&json);
+            let path = path.to_owned();
+            let encrypted = sealer.is_encrypted();
+            tokio::task::spawn_blocking(move || {
+                // the copy moved to the backup by the write may be in clear text too
+                if encrypted {
+                    scrub_plaintext(&path)?;
+                }
+                persist::write_checked(&path, &data)?;
+                if encrypted {
+                    scrub_plaintext(&path)?;
+                }
+                Ok::<_, std::io::Error>(())
+            })
+            .await??;
         }
         Ok(())
     }
 }
+
+/// Scrub the copies of the cache at `path` that hold tokens in clear text:
+/// the backup of the previous content, and the corrupt files set aside.
+fn scrub_plaintext(path: &FsPath) -> std::io::Result<()> {
+    let copies = std::iter::once(persist::backup_path(path)).chain(persist::quarantined(path));
+    for copy in copies {
+        match std::fs::read(&copy) {
+            Ok(content) if !secrets::is_sealed(&content) => {
+                log::info!("scrubbing {copy}, that holds tokens in clear text");
+                persist::scrub(&copy)?;
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn corrupt_cache_is_set_aside() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-token-cache-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("token_cache.json");
+        let open = || TokenCache::new(TokenPersist::MemoryAndDisk(path.clone(), Sealer::plain()));
+
+        let inputs: [(&str, &[u8], bool); 4] = [
+            ("empty file", b"", false),
+            ("garbage without checksum", b"{\"entries\": [", false),
+            (
+                "truncated JSON",
+                b"{\"entries\": [{\"scopes_hash\": 12",
+                true,
+            ),
+            ("wrong schema", b"{\"tokens\": {}}", true),
+        ];
+        for (name, data, checked) in inputs {
+            if checked {
+                persist::write_checked(&path, data).unwrap();
+            } else {
+                std::fs::write(&path, data).unwrap();
+            }
+            let cache = open().await.unwrap();
+            assert!(cache.map.entries.is_empty(), "{name}");
+            let corrupt = cache.corrupt_file().expect(name);
+            assert_eq!(corrupt.kind, "token cache");
+            assert!(!path.exists(), "{name}");
+            assert!(std::fs::exists(&corrupt.moved_to).unwrap(), "{name}");
+        }
+
+        // a cache written before the checksums is loaded, and written again with one
+        std::fs::write(&path, b"{\"entries\": []}").unwrap();
+        let cache = open().await.unwrap();
+        assert!(cache.corrupt_file().is_none());
+        assert!(path.exists());
+        assert!(persist::read_checked(&path, |_| false).is_ok());
+
+        // a valid cache is loaded as is
+        let mut cache = open().await.unwrap();
+        cache.map.insert(
+            vec![Scope::new("drive".into())],
+            CacheToken {
+                access_token: AccessToken::new("token".into()),
+                refresh_token: None,
+                expiration: None,
+            },
+        );
+        cache.persist_cache().await.unwrap();
+        let cache = open().await.unwrap();
+        assert!(cache.corrupt_file().is_none());
+        assert!(matches!(
+            cache.check(&[Scope::new("drive".into())]),
+            CacheResult::Ok(..)
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expiring_tokens() {
+        let mut cache = TokenCache::new(TokenPersist::Memory).await.unwrap();
+        let token = |name: &str, expires_in: Option<i64>, refresh: bool| CacheToken {
+            access_token: AccessToken::new(name.into()),
+            refresh_token: refresh.then(|| RefreshToken::new(format!("{name}-refresh"))),
+            expiration: expires_in.map(|secs| Utc::now() + chrono::Duration::seconds(secs)),
+        };
+        let scopes = |name: &str| vec![Scope::new(name.into())];
+        cache
+            .map
+            .insert(scopes("expired"), token("expired", Some(-10), true));
+        cache
+            .map
+            .insert(scopes("soon"), token("soon", Some(60), true));
+        cache
+            .map
+            .insert(scopes("later"), token("later", Some(3600), true));
+        cache
+            .map
+            .insert(scopes("forever"), token("forever", None, true));
+        cache
+            .map
+            .insert(scopes("stuck"), token("stuck", Some(-10), false));
+
+        let expiring = cache.expiring(Duration::from_secs(300));
+        let names: Vec<&str> = expiring
+            .iter()
+            .map(|(refresh_token, _)| refresh_token.secret().as_str())
+            .collect();
+        assert_eq!(names, ["expired-refresh", "soon-refresh"]);
+        assert_eq!(expiring[1].1, scopes("soon"));
+    }
+
+    #[tokio::test]
+    async fn sealing_scrubs_the_plaintext_copies() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-token-seal-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("token_cache.json");
+        let plain = b"{\"entries\": [{\"scopes_hash\": 1, \"scopes\": [], \"token\": {\"access_token\": \"secret-token\", \"refresh_token\": null, \"expiration\": null}}]}";
+
+        // a corrupt copy set aside, and a cache of a version without checksum, both in clear text
+        let corrupt = dir.join("token_cache.json.corrupt-20240101T000000");
+        std::fs::write(
+            &corrupt,
+            b"{\"entries\": [{\"access_token\": \"secret-token\"",
+        )
+        .unwrap();
+        std::fs::write(&path, plain).unwrap();
+
+        let sealer = Sealer::with_key([7u8; 32]);
+        let cache = TokenCache::new(TokenPersist::MemoryAndDisk(path.clone(), sealer))
+            .await
+            .unwrap();
+        assert!(matches!(cache.check(&[]), CacheResult::Ok(..)));
+
+        let mut files = 0;
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let content = std::fs::read(entry.unwrap().path()).unwrap();
+            assert!(secrets::is_sealed(&content));
+            assert!(!content.windows(12).any(|w| w == b"secret-token"));
+            files += 1;
+        }
+        assert_eq!(files, 1);
+        assert!(!corrupt.exists());
+        assert!(!persist::backup_path(&path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
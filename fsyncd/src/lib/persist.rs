@@ -0,0 +1,349 @@
+//! Crash-safe file persistence.
+//!
+//! Files are never written in place. The content goes to a temporary file
+//! in the same directory, which is synced to disk and renamed over the target.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+};
+
+use fsync::path::{FsPath, FsPathBuf};
+
+const FOOTER_MAGIC: &[u8; 8] = b"FSYNCCHK";
+const FOOTER_LEN: usize = 24;
+
+/// Atomically replace the content of `path` with `data`.
+pub fn atomic_write(path: &FsPath, data: &[u8]) -> io::Result<()> {
+    let tmp = sibling(path, "tmp");
+    write_synced(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+    sync_parent(path)
+}
+
+/// Same as [`atomic_write`], with a file only readable and writable by its owner.
+pub fn atomic_write_private(path: &FsPath, data: &[u8]) -> io::Result<()> {
+    let tmp = sibling(path, "tmp");
+    let mut f = create_private(&tmp)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_parent(path)
+}
+
+/// Atomically replace the content of `path` with `data`, followed by a checksum footer.
+/// The previous file, if any, is kept with a `.bak` extension.
+/// The file must be read back with [`read_checked`].
+pub fn write_checked(path: &FsPath, data: &[u8]) -> io::Result<()> {
+    let tmp = sibling(path, "tmp");
+    let mut content = Vec::with_capacity(data.len() + FOOTER_LEN);
+    content.extend_from_slice(data);
+    content.extend_from_slice(&footer(data));
+    write_synced(&tmp, &content)?;
+    if path.exists() {
+        fs::rename(path, sibling(path, "bak"))?;
+    }
+    fs::rename(&tmp, path)?;
+    sync_parent(path)
+}
+
+/// Read a file written with [`write_checked`].
+/// If `path` is missing or fails verification, the `.bak` file is tried instead.
+/// In case both fail, the error related to `path` is returned.
+///
+/// A file without footer at all, written before the footers were introduced,
+/// is accepted if `legacy` validates its content, and it is written again with a footer.
+pub fn read_checked<F>(path: &FsPath, legacy: F) -> io::Result<Vec<u8>>
+where
+    F: FnOnce(&[u8]) -> bool,
+{
+    let err = match read_verified(path) {
+        Ok(data) => return Ok(data),
+        Err(err) => err,
+    };
+    if let Some(data) = read_legacy(path, legacy) {
+        log::info!("{path}: adding a checksum to the content written by a previous version");
+        write_checked(path, &data)?;
+        return Ok(data);
+    }
+    let bak = sibling(path, "bak");
+    match read_verified(&bak) {
+        Ok(data) => {
+            log::warn!("{path}: {err}. Recovered content from {bak}");
+            Ok(data)
+        }
+        Err(_) => Err(err),
+    }
+}
+
+/// Set aside the file at `path`, that holds `kind` and could not be read because of `err`.
+/// The file is renamed with a `.corrupt-<timestamp>` suffix, so that the caller
+/// can start without it while the content is kept for inspection.
+pub fn quarantine(
+    path: &FsPath,
+    kind: &str,
+    err: &dyn std::fmt::Display,
+) -> io::Result<fsync::CorruptFile> {
+    let stamp = chrono::Utc::now().format("%Y%m%dT%H%M%S");
+    let moved_to = sibling(path, &format!("corrupt-{stamp}"));
+    fs::rename(path, &moved_to)?;
+    log::warn!("{path}: could not read the {kind}: {err}. The file was moved to {moved_to}");
+    Ok(fsync::CorruptFile {
+        kind: kind.to_string(),
+        moved_to: moved_to.into_string(),
+        error: err.to_string(),
+    })
+}
+
+/// The file where [`write_checked`] keeps the previous content of `path`
+pub fn backup_path(path: &FsPath) -> FsPathBuf {
+    sibling(path, "bak")
+}
+
+/// The files where [`quarantine`] set aside the content of `path`
+pub fn quarantined(path: &FsPath) -> Vec<FsPathBuf> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Vec::new();
+    };
+    let prefix = format!("{name}.corrupt-");
+    let Ok(entries) = dir.read_dir_utf8() else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().starts_with(&prefix))
+        .map(|entry| entry.path().to_owned())
+        .collect()
+}
+
+/// Overwrite the content of the file at `path` with zeros before removing it,
+/// so that a sensitive content is not left on the disk. A missing file is ignored.
+pub fn scrub(path: &FsPath) -> io::Result<()> {
+    let mut f = match fs::OpenOptions::new().write(true).open(path) {
+        Ok(f) => f,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let len = f.metadata()?.len();
+    io::copy(&mut io::repeat(0).take(len), &mut f)?;
+    f.sync_all()?;
+    drop(f);
+    fs::remove_file(path)?;
+    sync_parent(path)
+}
+
+fn sibling(path: &FsPath, ext: &str) -> FsPathBuf {
+    let name = path.file_name().unwrap_or_default();
+    path.with_file_name(format!("{name}.{ext}"))
+}
+
+fn write_synced(path: &FsPath, data: &[u8]) -> io::Result<()> {
+    let mut f = fs::File::create(path)?;
+    f.write_all(data)?;
+    f.sync_all()
+}
+
+#[cfg(unix)]
+fn create_private(path: &FsPath) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &FsPath) -> io::Result<fs::File> {
+    fs::File::create(path)
+}
+
+#[cfg(unix)]
+fn sync_parent(path: &FsPath) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_str().is_empty() => fs::File::open(parent)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &FsPath) -> io::Result<()> {
+    Ok(())
+}
+
+fn read_verified(path: &FsPath) -> io::Result<Vec<u8>> {
+    let mut content = fs::read(path)?;
+    if content.len() < FOOTER_LEN {
+        return Err(invalid_data(path, "file too short"));
+    }
+    let data_len = content.len() - FOOTER_LEN;
+    if content[data_len..] != footer(&content[..data_len]) {
+        return Err(invalid_data(path, "checksum mismatch"));
+    }
+    content.truncate(data_len);
+    Ok(content)
+}
+
+/// The content of `path` if it has no footer, and `legacy` accepts it
+fn read_legacy<F>(path: &FsPath, legacy: F) -> Option<Vec<u8>>
+where
+    F: FnOnce(&[u8]) -> bool,
+{
+    let content = fs::read(path).ok()?;
+    if content.ends_with(FOOTER_MAGIC) || !legacy(&content) {
+        return None;
+    }
+    Some(content)
+}
+
+fn invalid_data(path: &FsPath, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{path}: {msg}"))
+}
+
+/// Footer layout: data length (u64 LE), FNV-1a checksum (u64 LE), magic bytes.
+fn footer(data: &[u8]) -> [u8; FOOTER_LEN] {
+    let mut footer = [0u8; FOOTER_LEN];
+    footer[..8].copy_from_slice(&(data.len() as u64).to_le_bytes());
+    footer[8..16].copy_from_slice(&fnv1a(data).to_le_bytes());
+    footer[16..].copy_from_slice(FOOTER_MAGIC);
+    footer
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    data.iter()
+        .fold(OFFSET, |hash, b| (hash ^ *b as u64).wrapping_mul(PRIME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> FsPathBuf {
+        let dir = std::env::temp_dir().join(format!("fsyncd-persist-{name}"));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        FsPathBuf::try_from(dir).unwrap()
+    }
+
+    #[test]
+    fn scrub_removes_the_file() {
+        let dir = test_dir("scrub");
+        let path = dir.join("secret");
+        fs::write(&path, b"secret").unwrap();
+        scrub(&path).unwrap();
+        assert!(!path.exists());
+        scrub(&path).unwrap();
+    }
+
+    #[test]
+    fn atomic_write_replaces_content() {
+        let dir = test_dir("atomic");
+        let path = dir.join("port");
+        atomic_write(&path, b"1234").unwrap();
+        atomic_write(&path, b"5678").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"5678");
+        assert!(!sibling(&path, "tmp").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn atomic_write_private_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("private");
+        let path = dir.join("token");
+        atomic_write_private(&path, b"secret").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"secret");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn read_checked_roundtrip() {
+        let dir = test_dir("roundtrip");
+        let path = dir.join("cache.bin");
+        write_checked(&path, b"first").unwrap();
+        write_checked(&path, b"second").unwrap();
+        assert_eq!(read_checked(&path, |_| false).unwrap(), b"second");
+        assert_eq!(read_verified(&sibling(&path, "bak")).unwrap(), b"first");
+    }
+
+    #[test]
+    fn read_checked_falls_back_on_partial_write() {
+        let dir = test_dir("partial");
+        let path = dir.join("cache.bin");
+        write_checked(&path, b"first content").unwrap();
+        write_checked(&path, b"second content").unwrap();
+
+        // simulate a file truncated by a crash
+        let content = fs::read(&path).unwrap();
+        fs::write(&path, &content[..content.len() - 5]).unwrap();
+
+        assert_eq!(read_checked(&path, |_| false).unwrap(), b"first content");
+    }
+
+    #[test]
+    fn read_checked_detects_corruption() {
+        let dir = test_dir("corrupt");
+        let path = dir.join("cache.bin");
+        write_checked(&path, b"some content").unwrap();
+
+        let mut content = fs::read(&path).unwrap();
+        content[0] = b'S';
+        fs::write(&path, &content).unwrap();
+
+        let err = read_checked(&path, |_| false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_checked_accepts_legacy_content_once() {
+        let dir = test_dir("legacy");
+        let path = dir.join("cache.json");
+        fs::write(&path, b"{}").unwrap();
+
+        // rejected content is not rewritten
+        let err = read_checked(&path, |_| false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(&path).unwrap(), b"{}");
+
+        assert_eq!(read_checked(&path, |data| data == b"{}").unwrap(), b"{}");
+        assert_eq!(read_verified(&path).unwrap(), b"{}");
+
+        // a truncated checked file is not taken for legacy content
+        write_checked(&path, b"[1, 2]").unwrap();
+        let content = fs::read(&path).unwrap();
+        fs::write(&path, &content[..content.len() - 3]).unwrap();
+        let is_list = |data: &[u8]| data.starts_with(b"[") && data.ends_with(b"]");
+        assert_eq!(read_checked(&path, is_list).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn quarantine_renames() {
+        let dir = test_dir("quarantine");
+        let path = dir.join("cache.bin");
+        fs::write(&path, b"garbage").unwrap();
+
+        let corrupt = quarantine(&path, "cache", &"checksum mismatch").unwrap();
+        assert!(!path.exists());
+        assert!(corrupt.moved_to.starts_with(&format!("{path}.corrupt-")));
+        assert_eq!(fs::read(&corrupt.moved_to).unwrap(), b"garbage");
+        assert_eq!(corrupt.error, "checksum mismatch");
+    }
+
+    #[test]
+    fn read_checked_ignores_stale_tmp() {
+        let dir = test_dir("stale");
+        let path = dir.join("cache.bin");
+        write_checked(&path, b"content").unwrap();
+
+        // simulate a crash before the rename
+        fs::write(sibling(&path, "tmp"), b"cont").unwrap();
+
+        assert_eq!(read_checked(&path, |_| false).unwrap(), b"content");
+    }
+}
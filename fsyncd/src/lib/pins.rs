@@ -0,0 +1,98 @@
+//! Entries pinned by the user, that fsyncd never deletes nor overwrites
+
+use std::collections::BTreeSet;
+
+use fsync::path::{FsPathBuf, Path, PathBuf};
+use tokio::{fs, sync::RwLock};
+
+/// The set of pinned paths, persisted as a JSON array if opened from a file
+#[derive(Debug, Default)]
+pub struct Pins {
+    path: Option<FsPathBuf>,
+    pins: RwLock<BTreeSet<PathBuf>>,
+}
+
+impl Pins {
+    /// Open the pins persisted at `path`, which does not need to exist
+    pub async fn open(path: FsPathBuf) -> anyhow::Result<Self> {
+        let pins = match fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            pins: RwLock::new(pins),
+        })
+    }
+
+    pub async fn list(&self) -> Vec<PathBuf> {
+        self.pins.read().await.iter().cloned().collect()
+    }
+
+    pub async fn is_pinned(&self, path: &Path) -> bool {
+        self.pins.read().await.contains(path)
+    }
+
+    /// A pinned path that is `path` or one of its descendants
+    pub async fn pinned_within(&self, path: &Path) -> Option<PathBuf> {
+        self.pins
+            .read()
+            .await
+            .iter()
+            .find(|pin| *pin == path || path.is_ancestor_of(pin))
+            .cloned()
+    }
+
+    /// Pin or unpin `path`, and persist the change
+    pub async fn set(&self, path: PathBuf, pinned: bool) -> anyhow::Result<()> {
+        let mut pins = self.pins.write().await;
+        let changed = if pinned {
+            pins.insert(path)
+        } else {
+            pins.remove(&path)
+        };
+        if !changed {
+            return Ok(());
+        }
+        if let Some(file) = &self.path {
+            if let Some(dir) = file.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            fs::write(file, serde_json::to_vec_pretty(&*pins)?).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn persisted_pins() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-pins-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let path = dir.join("pins.json");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let pins = Pins::open(path.clone()).await.unwrap();
+        pins.set(PathBuf::from("/dir/keep.txt"), true)
+            .await
+            .unwrap();
+        pins.set(PathBuf::from("/other.txt"), true).await.unwrap();
+        pins.set(PathBuf::from("/other.txt"), false).await.unwrap();
+
+        let pins = Pins::open(path).await.unwrap();
+        assert_eq!(pins.list().await, vec![PathBuf::from("/dir/keep.txt")]);
+        assert!(pins.is_pinned(Path::new("/dir/keep.txt")).await);
+        assert_eq!(
+            pins.pinned_within(Path::new("/dir")).await,
+            Some(PathBuf::from("/dir/keep.txt"))
+        );
+        assert_eq!(pins.pinned_within(Path::new("/di")).await, None);
+        assert_eq!(pins.pinned_within(Path::new("/other.txt")).await, None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
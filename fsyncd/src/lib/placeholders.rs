@@ -0,0 +1,151 @@
+//! Placeholder files of the remote-only entries.
+//!
+//! In the placeholder mode, each remote-only regular file gets a local placeholder,
+//! named after it with the [`SUFFIX`], that contains a JSON [`Descriptor`].
+//! The local storage hides the placeholders from its listings, so that they never
+//! enter the tree. A placeholder deleted by the user is not created again,
+//! and the remote file is left untouched.
+
+use std::collections::BTreeSet;
+
+use chrono::{DateTime, Utc};
+use fsync::path::{FsPathBuf, Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::RwLock};
+
+/// Suffix of the placeholder files
+pub const SUFFIX: &str = ".fsyncph";
+
+/// Whether `name` is the name of a placeholder file
+pub fn is_placeholder(name: &str) -> bool {
+    name.len() > SUFFIX.len() && name.ends_with(SUFFIX)
+}
+
+/// Path of the placeholder of the entry at `path`
+pub fn placeholder_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{path}{SUFFIX}"))
+}
+
+/// Content of a placeholder file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Descriptor {
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: DateTime<Utc>,
+}
+
+impl Descriptor {
+    /// The descriptor of a remote regular file, `None` for a directory
+    pub fn new(remote: &fsync::Metadata) -> Option<Self> {
+        match remote {
+            fsync::Metadata::Regular {
+                path, size, mtime, ..
+            } => Some(Self {
+                path: path.clone(),
+                size: *size,
+                mtime: *mtime,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The paths for which a placeholder was created, persisted as a JSON array
+/// if opened from a file.
+/// A path stays recorded after its placeholder is deleted by the user,
+/// so that the placeholder is not created again.
+#[derive(Debug, Default)]
+pub struct Placeholders {
+    path: Option<FsPathBuf>,
+    created: RwLock<BTreeSet<PathBuf>>,
+}
+
+impl Placeholders {
+    /// Open the record persisted at `path`, which does not need to exist
+    pub async fn open(path: FsPathBuf) -> anyhow::Result<Self> {
+        let created = match fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            created: RwLock::new(created),
+        })
+    }
+
+    pub async fn list(&self) -> Vec<PathBuf> {
+        self.created.read().await.iter().cloned().collect()
+    }
+
+    pub async fn contains(&self, path: &Path) -> bool {
+        self.created.read().await.contains(path)
+    }
+
+    /// Record or forget `path`, and persist the change
+    pub async fn set(&self, path: PathBuf, created: bool) -> anyhow::Result<()> {
+        let mut paths = self.created.write().await;
+        let changed = if created {
+            paths.insert(path)
+        } else {
+            paths.remove(&path)
+        };
+        if !changed {
+            return Ok(());
+        }
+        if let Some(file) = &self.path {
+            if let Some(dir) = file.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            fs::write(file, serde_json::to_vec_pretty(&*paths)?).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholder_names() {
+        assert!(is_placeholder("report.pdf.fsyncph"));
+        assert!(!is_placeholder(".fsyncph"));
+        assert!(!is_placeholder("report.pdf"));
+        assert_eq!(
+            placeholder_path(Path::new("/dir/report.pdf")),
+            PathBuf::from("/dir/report.pdf.fsyncph")
+        );
+    }
+
+    #[tokio::test]
+    async fn persisted_placeholders() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-placeholders-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let path = dir.join("placeholders.json");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let placeholders = Placeholders::open(path.clone()).await.unwrap();
+        placeholders
+            .set(PathBuf::from("/dir/report.pdf"), true)
+            .await
+            .unwrap();
+        placeholders
+            .set(PathBuf::from("/other.txt"), true)
+            .await
+            .unwrap();
+        placeholders
+            .set(PathBuf::from("/other.txt"), false)
+            .await
+            .unwrap();
+
+        let placeholders = Placeholders::open(path).await.unwrap();
+        assert_eq!(
+            placeholders.list().await,
+            vec![PathBuf::from("/dir/report.pdf")]
+        );
+        assert!(placeholders.contains(Path::new("/dir/report.pdf")).await);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,591 @@
+//! Planning of operations.
+//!
+//! Deep operations are planned by walking the tree lazily,
+//! so that the memory used does not depend on the size of the sub-tree.
+
+use fsync::{
+    tree::Entry, Action, Conflict, DeletionMethod, Error, Location, OperateOptions, Operation,
+    PlannedAction, ResolutionMethod, StorageDir, StorageLoc, SyncMode,
+};
+
+use crate::tree::{DiffTree, EntryNode, Step, Walk};
+
+/// The action of the unit `operation` on `node`, or `None` if there is nothing to do.
+/// The actions modifying a storage left untouched by `mode` are withheld.
+pub fn unit_action(
+    operation: &Operation,
+    node: &EntryNode,
+    options: &OperateOptions,
+    mode: SyncMode,
+) -> Option<Action> {
+    match any_action(operation, node, options)? {
+        action if mode.allows(&action) => Some(action),
+        _ => Some(Action::SkipWithheld),
+    }
+}
+
+fn any_action(operation: &Operation, node: &EntryNode, options: &OperateOptions) -> Option<Action> {
+    // the root is the share itself: operations on it apply to its children only
+    if node.path().is_root() {
+        return None;
+    }
+    match operation {
+        Operation::Sync(..) | Operation::RestoreLocal(..) | Operation::RestoreRemote(..)
+            if node.is_too_large() && !options.force_large =>
+        {
+            Some(Action::SkipTooLarge)
+        }
+        Operation::Sync(..) | Operation::RestoreLocal(..) if node.is_name_clash() => {
+            Some(Action::Fail(Error::Other(format!(
+                "{}: the name is not supported by the local filesystem, or collides with a sibling",
+                node.path()
+            ))))
+        }
+        Operation::Sync(..) => sync_action(node),
+        Operation::Resolve(_, method) => resolve_action(node, *method),
+        Operation::Delete(_, method) => delete_action(node, *method),
+        Operation::Hydrate(..) => hydrate_action(node),
+        Operation::RestoreLocal(..) => restore_action(node, StorageDir::RemoteToLocal),
+        Operation::RestoreRemote(..) => restore_action(node, StorageDir::LocalToRemote),
+        _ => panic!("Not a unit operation: {operation:?}"),
+    }
+}
+
+fn sync_action(node: &EntryNode) -> Option<Action> {
+    match node.entry() {
+        Entry::Local(metadata) if metadata.is_dir() => Some(Action::Mkdir(StorageLoc::Remote)),
+        Entry::Local(..) => Some(Action::Copy(StorageDir::LocalToRemote)),
+        Entry::Remote(metadata) if metadata.is_dir() => Some(Action::Mkdir(StorageLoc::Local)),
+        Entry::Remote(..) => Some(Action::Copy(StorageDir::RemoteToLocal)),
+        Entry::Sync { conflict: None, .. } => None,
+        Entry::Sync { .. } => Some(Action::Fail(Error::Conflict(node.path().to_owned()))),
+    }
+}
+
+fn hydrate_action(node: &EntryNode) -> Option<Action> {
+    match node.entry() {
+        Entry::Remote(metadata) if metadata.is_dir() => Some(Action::Fail(Error::Other(format!(
+            "{} is a folder, only files can be hydrated",
+            node.path()
+        )))),
+        Entry::Remote(..) => Some(Action::Copy(StorageDir::RemoteToLocal)),
+        // the local file was created since the placeholder, it is kept as is
+        Entry::Sync { conflict: None, .. } | Entry::Local(..) => None,
+        Entry::Sync { .. } => Some(Action::Fail(Error::Conflict(node.path().to_owned()))),
+    }
+}
+
+/// Copy the entries missing from the destination of `dir`,
+/// and replace the conflicting files there. Nothing is deleted.
+fn restore_action(node: &EntryNode, dir: StorageDir) -> Option<Action> {
+    let src = dir.src();
+    match node.entry() {
+        Entry::Sync { conflict: None, .. } => None,
+        Entry::Sync {
+            conflict: Some(Conflict::LocalDirRemoteFile | Conflict::LocalFileRemoteDir),
+            ..
+        } => Some(Action::Fail(Error::Unresolved(
+            node.path().to_owned(),
+            "local and remote are not of the same type, restoring would delete one of them"
+                .to_string(),
+        ))),
+        Entry::Sync { .. } => Some(Action::Replace(dir)),
+        entry if entry.is_only_at_loc(src) && entry.is_safe_dir() => {
+            Some(Action::Mkdir(dir.dest()))
+        }
+        entry if entry.is_only_at_loc(src) => Some(Action::Copy(dir)),
+        // only in the destination, left as is
+        _ => None,
+    }
+}
+
+fn resolve_action(node: &EntryNode, method: ResolutionMethod) -> Option<Action> {
+    let Entry::Sync {
+        local,
+        remote,
+        conflict: Some(conflict),
+    } = node.entry()
+    else {
+        return None;
+    };
+    let unresolved = |reason: &str| {
+        Action::Fail(Error::Unresolved(
+            node.path().to_owned(),
+            reason.to_string(),
+        ))
+    };
+    let action = match (method, conflict) {
+        (ResolutionMethod::DeleteRemote, _)
+        | (ResolutionMethod::DeleteOlder, Conflict::LocalNewer)
+        | (ResolutionMethod::DeleteNewer, Conflict::LocalOlder) => Action::Delete(Location::Remote),
+        (ResolutionMethod::DeleteLocal, _)
+        | (ResolutionMethod::DeleteOlder, Conflict::LocalOlder)
+        | (ResolutionMethod::DeleteNewer, Conflict::LocalNewer) => Action::Delete(Location::Local),
+        (ResolutionMethod::ReplaceRemoteByLocal, _)
+        | (ResolutionMethod::ReplaceOlderByNewer, Conflict::LocalNewer)
+        | (ResolutionMethod::ReplaceNewerByOlder, Conflict::LocalOlder) => {
+            Action::Replace(StorageDir::LocalToRemote)
+        }
+        (ResolutionMethod::ReplaceLocalByRemote, _)
+        | (ResolutionMethod::ReplaceOlderByNewer, Conflict::LocalOlder)
+        | (ResolutionMethod::ReplaceNewerByOlder, Conflict::LocalNewer) => {
+            Action::Replace(StorageDir::RemoteToLocal)
+        }
+        (ResolutionMethod::CreateLocalCopy, _) => Action::CopyLocalAndReplace,
+        (ResolutionMethod::MergeText, _) if fsync::text::is_mergeable(local, remote) => {
+            Action::MergeText
+        }
+        (ResolutionMethod::MergeText, _) => unresolved("only the small text files can be merged. "),
+        (_, Conflict::LocalBigger) | (_, Conflict::LocalSmaller) => {
+            unresolved("local and remote have same mtime but different size. ")
+        }
+        (_, Conflict::LocalDirRemoteFile) => unresolved("local is dir and remote is file. "),
+        (_, Conflict::LocalFileRemoteDir) => unresolved("local is file and remote is dir. "),
+        (_, Conflict::ContentMismatch) => {
+            unresolved("local and remote have same mtime and size but different content. ")
+        }
+    };
+    Some(action)
+}
+
+fn delete_action(node: &EntryNode, method: DeletionMethod) -> Option<Action> {
+    if !node.children().is_empty() {
+        return Some(Action::Fail(Error::NotEmpty(node.path().to_owned())));
+    }
+
+    match (node.entry(), method) {
+        // Delete only locally
+        (Entry::Local(..), DeletionMethod::Local | DeletionMethod::All)
+        | (
+            Entry::Sync { conflict: None, .. },
+            DeletionMethod::Local
+            | DeletionMethod::LocalIfSync
+            | DeletionMethod::LocalIfSyncNoConflict,
+        )
+        | (
+            Entry::Sync {
+                conflict: Some(_), ..
+            },
+            DeletionMethod::Local | DeletionMethod::LocalIfSync,
+        ) => Some(Action::Delete(Location::Local)),
+
+        // Delete only remotely
+        (Entry::Remote(..), DeletionMethod::Remote | DeletionMethod::All)
+        | (
+            Entry::Sync { conflict: None, .. },
+            DeletionMethod::Remote
+            | DeletionMethod::RemoteIfSync
+            | DeletionMethod::RemoteIfSyncNoConflict,
+        )
+        | (
+            Entry::Sync {
+                conflict: Some(_), ..
+            },
+            DeletionMethod::Remote | DeletionMethod::RemoteIfSync,
+        ) => Some(Action::Delete(Location::Remote)),
+
+        // Delete everywhere
+        (Entry::Sync { .. }, DeletionMethod::All) => Some(Action::Delete(Location::Both)),
+
+        // Nothing to do
+        (Entry::Local(..), deletion) if deletion.is_remote() => None,
+        (Entry::Remote(..), deletion) if deletion.is_local() => None,
+        // Not synchronized
+        (Entry::Local(..), DeletionMethod::LocalIfSync | DeletionMethod::LocalIfSyncNoConflict)
+        | (
+            Entry::Remote(..),
+            DeletionMethod::RemoteIfSync | DeletionMethod::RemoteIfSyncNoConflict,
+        ) => None,
+
+        // Conflict error
+        (
+            Entry::Sync {
+                conflict: Some(_), ..
+            },
+            deletion,
+        ) if deletion.no_conflict() => Some(Action::Fail(Error::Conflict(node.path().to_owned()))),
+
+        (entry, method) => unreachable!("missing delete_unit case:{entry:#?}, {method:#?}"),
+    }
+}
+
+/// Number of bytes transferred by `action` on `node`
+pub fn action_size(action: &Action, node: &EntryNode) -> u64 {
+    let loc = match action {
+        Action::Copy(dir) | Action::Replace(dir) => dir.src(),
+        Action::CopyLocalAndReplace => StorageLoc::Remote,
+        Action::MergeText => {
+            let size = |loc| node.entry().clone().into_metadata(loc)?.size();
+            return size(StorageLoc::Local).unwrap_or(0) + size(StorageLoc::Remote).unwrap_or(0);
+        }
+        _ => return 0,
+    };
+    node.entry()
+        .clone()
+        .into_metadata(loc)
+        .and_then(|metadata| metadata.size())
+        .unwrap_or(0)
+}
+
+/// Growth of the remote storage caused by `action` on `node`, in bytes.
+/// A replaced remote file counts as the difference between the new and the old size.
+pub fn remote_growth(action: &Action, node: &EntryNode) -> i64 {
+    let size = |loc| {
+        node.entry()
+            .clone()
+            .into_metadata(loc)
+            .and_then(|metadata| metadata.size())
+            .unwrap_or(0) as i64
+    };
+    match action {
+        Action::Copy(StorageDir::LocalToRemote) => size(StorageLoc::Local),
+        Action::Replace(StorageDir::LocalToRemote) => {
+            size(StorageLoc::Local) - size(StorageLoc::Remote)
+        }
+        _ => 0,
+    }
+}
+
+/// The direction in which the deep sync `operation` bootstraps the sub-tree of `node`,
+/// if the destination has none of it while the source has entries in it.
+/// There is then nothing to compare: the sub-tree is copied in bulk, see
+/// [`crate::service::Service::with_bulk_bootstrap`].
+pub fn bootstrap_dir(
+    operation: &Operation,
+    node: &EntryNode,
+    mode: SyncMode,
+) -> Option<StorageDir> {
+    if !matches!(operation, Operation::SyncDeep(..)) {
+        return None;
+    }
+    let dir = match node.entry() {
+        Entry::Local(metadata) if metadata.is_dir() => StorageDir::LocalToRemote,
+        Entry::Remote(metadata) if metadata.is_dir() => StorageDir::RemoteToLocal,
+        Entry::Sync {
+            local,
+            remote,
+            conflict: None,
+        } if local.is_dir() && remote.is_dir() => {
+            // the stats of a directory count the directory itself
+            let stats = node.stats();
+            match (stats.local.entries(), stats.remote.entries()) {
+                (local, 1) if local > 1 => StorageDir::LocalToRemote,
+                (1, remote) if remote > 1 => StorageDir::RemoteToLocal,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let bulk = node.stats().by_loc(dir.src()).entries() > 1
+        && mode.allows(&Action::Copy(dir))
+        && node.stats().node.implausible == 0;
+    bulk.then_some(dir)
+}
+
+/// Whether the unit operation is performed at this step of a walk.
+/// The entry passed to the unit operation is returned.
+pub fn unit_step(step: Step, parent_first: bool) -> Option<EntryNode> {
+    match step {
+        Step::Leaf(node) => Some(node),
+        Step::Enter(node) if parent_first => Some(node),
+        Step::Leave(node) if !parent_first => Some(node.without_children()),
+        _ => None,
+    }
+}
+
+/// The plan of an operation, computed lazily.
+/// Plans are computed with the default options, so files larger than the size limit are skipped.
+#[derive(Debug)]
+pub struct Plan {
+    unit: Operation,
+    mode: SyncMode,
+    parent_first: bool,
+    walk: Option<Walk>,
+    single: bool,
+}
+
+impl Plan {
+    pub fn new(operation: Operation, mode: SyncMode) -> Self {
+        let path = operation.path().to_owned();
+        let parent_first = operation.is_parent_first();
+        if operation.is_deep() {
+            let walk = Walk::new(path, operation.order());
+            Self {
+                unit: operation.not_deep(),
+                mode,
+                parent_first,
+                walk: Some(walk),
+                single: false,
+            }
+        } else {
+            Self {
+                unit: operation,
+                mode,
+                parent_first,
+                walk: None,
+                single: true,
+            }
+        }
+    }
+
+    /// Compute at most `max_len` next actions of the plan.
+    /// An empty list is returned when the plan is complete.
+    pub fn next_actions(&mut self, tree: &DiffTree, max_len: usize) -> Vec<PlannedAction> {
+        let mut actions = Vec::new();
+        while actions.len() < max_len {
+            let Some(node) = self.next_node(tree) else {
+                break;
+            };
+            let unit = self.unit.with_path(node.path().to_owned());
+            if let Some(action) = unit_action(&unit, &node, &OperateOptions::default(), self.mode) {
+                actions.push(PlannedAction {
+                    path: node.path().to_owned(),
+                    size: action_size(&action, &node),
+                    action,
+                });
+            }
+        }
+        actions
+    }
+
+    fn next_node(&mut self, tree: &DiffTree) -> Option<EntryNode> {
+        if self.single {
+            self.single = false;
+            return tree.entry(self.unit.path());
+        }
+        let walk = self.walk.as_mut()?;
+        loop {
+            let step = walk.next(tree)?;
+            if let Some(node) = unit_step(step, self.parent_first) {
+                return Some(node);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use fsync::{
+        path::{Path, PathBuf},
+        stat, Metadata,
+    };
+
+    use super::*;
+
+    fn local_tree(dirs: usize, files: usize) -> DiffTree {
+        let tree = DiffTree::new_root();
+        let mtime = Utc::now();
+        for d in 0..dirs {
+            let dir = PathBuf::from(format!("/dir-{d:03}"));
+            let metadata = Metadata::Directory {
+                path: dir.clone(),
+                stat: None,
+            };
+            let node = EntryNode::new(Entry::Local(metadata), vec![], stat::Tree::null());
+            tree.insert(&dir, node);
+            for f in 0..files {
+                let path = dir.join(format!("file-{f:04}.txt").as_str());
+                let metadata = Metadata::Regular {
+                    path: path.clone(),
+                    size: 1024,
+                    mtime,
+                    link_target: None,
+                };
+                let node = EntryNode::new(Entry::Local(metadata), vec![], stat::Tree::null());
+                tree.insert(&path, node);
+            }
+        }
+        tree
+    }
+
+    #[test]
+    fn plan_sync_deep_pages() {
+        let tree = local_tree(2, 3);
+        let mut plan = Plan::new(Operation::SyncDeep(PathBuf::root()), SyncMode::default());
+
+        let first = plan.next_actions(&tree, 3);
+        let paths: Vec<_> = first.iter().map(|a| a.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/dir-000",
+                "/dir-000/file-0000.txt",
+                "/dir-000/file-0001.txt"
+            ]
+        );
+        assert!(matches!(first[0].action, Action::Mkdir(StorageLoc::Remote)));
+        assert!(matches!(
+            first[1].action,
+            Action::Copy(StorageDir::LocalToRemote)
+        ));
+        assert_eq!(first[0].size, 0);
+        assert_eq!(first[1].size, 1024);
+
+        let rest = plan.next_actions(&tree, 100);
+        assert_eq!(rest.len(), 5);
+        assert!(plan.next_actions(&tree, 100).is_empty());
+    }
+
+    #[test]
+    fn plan_sync_skips_too_large() {
+        let tree = DiffTree::new_root();
+        for (name, size) in [("/at-limit.bin", 1000), ("/above-limit.bin", 1001)] {
+            let path = PathBuf::from(name);
+            let metadata = Metadata::Regular {
+                path: path.clone(),
+                size,
+                mtime: Utc::now(),
+                link_target: None,
+            };
+            let node = EntryNode::new(Entry::Local(metadata), vec![], stat::Tree::null())
+                .with_too_large(size > 1000);
+            tree.insert(&path, node);
+        }
+
+        let mut plan = Plan::new(Operation::SyncDeep(PathBuf::root()), SyncMode::default());
+        let actions = plan.next_actions(&tree, 100);
+        let actions: Vec<_> = actions
+            .iter()
+            .map(|a| (a.path.as_str(), &a.action, a.size))
+            .collect();
+        assert!(matches!(
+            actions[..],
+            [
+                ("/above-limit.bin", Action::SkipTooLarge, 0),
+                (
+                    "/at-limit.bin",
+                    Action::Copy(StorageDir::LocalToRemote),
+                    1000
+                ),
+            ]
+        ));
+
+        let node = tree.entry(Path::new("/above-limit.bin")).unwrap();
+        let unit = Operation::Sync(node.path().to_owned());
+        let force = OperateOptions {
+            force_large: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            unit_action(&unit, &node, &force, SyncMode::default()),
+            Some(Action::Copy(StorageDir::LocalToRemote))
+        ));
+    }
+
+    #[test]
+    fn bootstrap_of_the_absent_sub_trees() {
+        let tree = local_tree(2, 3);
+        let root = tree.entry(Path::root()).unwrap();
+        let dir = tree.entry(Path::new("/dir-000")).unwrap();
+        let deep = |path: &str| Operation::SyncDeep(PathBuf::from(path));
+        let mode = SyncMode::default();
+
+        assert!(matches!(
+            bootstrap_dir(&deep("/"), &root, mode),
+            Some(StorageDir::LocalToRemote)
+        ));
+        assert!(matches!(
+            bootstrap_dir(&deep("/dir-000"), &dir, mode),
+            Some(StorageDir::LocalToRemote)
+        ));
+        // not when the order of the walk is requested, nor when the mode withholds the copies
+        let ordered = Operation::SyncDeepOrdered(PathBuf::root(), fsync::OrderBy::NewestFirst);
+        assert!(bootstrap_dir(&ordered, &root, mode).is_none());
+        assert!(bootstrap_dir(&deep("/"), &root, SyncMode::DownloadOnly).is_none());
+
+        // not once the remote has entries
+        let path = PathBuf::from("/remote.txt");
+        let metadata = Metadata::Regular {
+            path: path.clone(),
+            size: 10,
+            mtime: Utc::now(),
+            link_target: None,
+        };
+        let node = EntryNode::new(Entry::Remote(metadata), vec![], stat::Tree::null());
+        tree.insert(&path, node);
+        let root = tree.entry(Path::root()).unwrap();
+        assert!(bootstrap_dir(&deep("/"), &root, mode).is_none());
+        assert!(matches!(
+            bootstrap_dir(&deep("/dir-000"), &dir, mode),
+            Some(StorageDir::LocalToRemote)
+        ));
+    }
+
+    #[test]
+    fn plan_delete_deep_children_first() {
+        let tree = local_tree(1, 2);
+        let mut plan = Plan::new(
+            Operation::DeleteDeep(PathBuf::from("/dir-000"), DeletionMethod::All),
+            SyncMode::default(),
+        );
+        let actions = plan.next_actions(&tree, 100);
+        let paths: Vec<_> = actions.iter().map(|a| a.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/dir-000/file-0000.txt",
+                "/dir-000/file-0001.txt",
+                "/dir-000"
+            ]
+        );
+        assert!(actions
+            .iter()
+            .all(|a| matches!(a.action, Action::Delete(Location::Local))));
+    }
+
+    #[test]
+    fn plan_withholds_against_sync_mode() {
+        let tree = local_tree(1, 1);
+        let path = PathBuf::from("/remote.txt");
+        let metadata = Metadata::Regular {
+            path: path.clone(),
+            size: 10,
+            mtime: Utc::now(),
+            link_target: None,
+        };
+        let node = EntryNode::new(Entry::Remote(metadata), vec![], stat::Tree::null());
+        tree.insert(&path, node);
+
+        let plan_actions = |mode| {
+            let mut plan = Plan::new(Operation::SyncDeep(PathBuf::root()), mode);
+            plan.next_actions(&tree, 100)
+                .into_iter()
+                .map(|a| (a.path.to_string(), a.action))
+                .collect::<Vec<_>>()
+        };
+
+        let actions = plan_actions(SyncMode::UploadOnly);
+        assert_eq!(actions.len(), 3);
+        assert!(matches!(actions[0].1, Action::Mkdir(StorageLoc::Remote)));
+        assert!(matches!(
+            actions[1].1,
+            Action::Copy(StorageDir::LocalToRemote)
+        ));
+        assert_eq!(actions[2].0, "/remote.txt");
+        assert!(matches!(actions[2].1, Action::SkipWithheld));
+
+        let actions = plan_actions(SyncMode::DownloadOnly);
+        assert_eq!(actions.len(), 3);
+        assert!(actions[..2]
+            .iter()
+            .all(|(_, action)| matches!(action, Action::SkipWithheld)));
+        assert!(matches!(
+            actions[2].1,
+            Action::Copy(StorageDir::RemoteToLocal)
+        ));
+
+        let node = tree.entry(Path::new("/dir-000")).unwrap();
+        let delete = Operation::Delete(node.path().to_owned(), DeletionMethod::Local);
+        let options = OperateOptions::default();
+        assert!(matches!(
+            unit_action(
+                &delete,
+                &node.without_children(),
+                &options,
+                SyncMode::UploadOnly
+            ),
+            Some(Action::SkipWithheld)
+        ));
+    }
+}
@@ -0,0 +1,213 @@
+//! Deferral of the scheduled work, see [`fsync::Deferral`].
+//!
+//! The scheduled maintenance and the operations started with [`fsync::Fsync::operate_scheduled`]
+//! wait while the policy postpones them, and the operations started by the user always run.
+//! The power state of the system is sampled through a [`PowerProbe`], read from sysfs on Linux,
+//! and unknown on the other systems, where only the active hours apply.
+
+use std::{fmt, io, sync::RwLock, time::Duration};
+
+use chrono::NaiveTime;
+use fsync::path::{FsPath, FsPathBuf};
+
+/// Delay before checking again whether the postponed work may run
+pub const RETRY: Duration = Duration::from_secs(60);
+
+/// Probe of the power state of the system
+pub trait PowerProbe: fmt::Debug + Send + Sync {
+    /// Whether the system runs on battery, `None` if it can't be told
+    fn on_battery(&self) -> Option<bool>;
+}
+
+/// The probe of the systems whose power state is not supported
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoProbe;
+
+impl PowerProbe for NoProbe {
+    fn on_battery(&self) -> Option<bool> {
+        None
+    }
+}
+
+/// Probe of the power supplies listed by the Linux kernel in `/sys/class/power_supply`
+#[derive(Debug, Clone)]
+pub struct SysfsProbe {
+    dir: FsPathBuf,
+}
+
+impl SysfsProbe {
+    pub const DIR: &'static str = "/sys/class/power_supply";
+
+    pub fn new(dir: FsPathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn read(&self) -> io::Result<bool> {
+        let attr = |supply: &FsPath, name: &str| {
+            std::fs::read_to_string(supply.join(name))
+                .map(|value| value.trim().to_owned())
+                .unwrap_or_default()
+        };
+        let mut battery = false;
+        let mut mains_online = false;
+        for entry in self.dir.read_dir_utf8()? {
+            let supply = entry?.into_path();
+            match attr(&supply, "type").as_str() {
+                "Battery" => {
+                    if attr(&supply, "status") == "Discharging" {
+                        return Ok(true);
+                    }
+                    battery = true;
+                }
+                "Mains" => mains_online |= attr(&supply, "online") == "1",
+                _ => (),
+            }
+        }
+        Ok(battery && !mains_online)
+    }
+}
+
+impl PowerProbe for SysfsProbe {
+    fn on_battery(&self) -> Option<bool> {
+        self.read().ok()
+    }
+}
+
+/// The probe supported by the system
+pub fn system_probe() -> Box<dyn PowerProbe> {
+    if cfg!(target_os = "linux") {
+        Box::new(SysfsProbe::new(SysfsProbe::DIR.into()))
+    } else {
+        Box::new(NoProbe)
+    }
+}
+
+#[derive(Debug)]
+pub struct Policy {
+    deferral: RwLock<fsync::Deferral>,
+    probe: Box<dyn PowerProbe>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self::new(fsync::Deferral::default(), system_probe())
+    }
+}
+
+impl Policy {
+    pub fn new(deferral: fsync::Deferral, probe: Box<dyn PowerProbe>) -> Self {
+        Self {
+            deferral: RwLock::new(deferral),
+            probe,
+        }
+    }
+
+    /// Replace the deferral, as read again from the config
+    pub fn set_deferral(&self, deferral: fsync::Deferral) {
+        *self.deferral.write().unwrap() = deferral;
+    }
+
+    /// The state of the policy at the current local time
+    pub fn state(&self) -> fsync::PolicyState {
+        self.state_at(chrono::Local::now().time())
+    }
+
+    /// Time that a scheduled deep operation may run before it stops starting new entries
+    pub fn max_duration(&self) -> Option<Duration> {
+        self.deferral.read().unwrap().max_duration()
+    }
+
+    /// Why the scheduled work is postponed at the current local time, `None` if it may run
+    pub fn deferred(&self) -> Option<String> {
+        self.state().deferred
+    }
+
+    fn state_at(&self, now: NaiveTime) -> fsync::PolicyState {
+        let deferral = *self.deferral.read().unwrap();
+        let on_battery = self.probe.on_battery();
+        let deferred = if deferral.defer_on_battery && on_battery == Some(true) {
+            Some("the system runs on battery".to_string())
+        } else {
+            deferral
+                .active_hours
+                .filter(|hours| hours.contains(now))
+                .map(|hours| format!("within the active hours {hours}"))
+        };
+        fsync::PolicyState {
+            on_battery,
+            deferred,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Fixed(Option<bool>);
+
+    impl PowerProbe for Fixed {
+        fn on_battery(&self) -> Option<bool> {
+            self.0
+        }
+    }
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn deferral() {
+        let deferral = fsync::Deferral {
+            defer_on_battery: true,
+            active_hours: Some("22:00-07:00".parse().unwrap()),
+            max_duration: None,
+        };
+        let policy = Policy::new(deferral, Box::new(Fixed(Some(true))));
+        let state = policy.state_at(time("12:00"));
+        assert_eq!(state.on_battery, Some(true));
+        assert!(state.deferred.unwrap().contains("battery"));
+
+        let policy = Policy::new(deferral, Box::new(Fixed(Some(false))));
+        assert_eq!(policy.state_at(time("12:00")).deferred, None);
+        let deferred = policy.state_at(time("23:00")).deferred.unwrap();
+        assert!(deferred.contains("22:00-07:00"), "{deferred}");
+
+        // unknown power state: only the active hours apply
+        let policy = Policy::new(deferral, Box::new(NoProbe));
+        assert_eq!(policy.state_at(time("12:00")).deferred, None);
+
+        // hot reloaded
+        policy.set_deferral(fsync::Deferral::default());
+        assert_eq!(policy.state_at(time("23:00")).deferred, None);
+    }
+
+    #[test]
+    fn sysfs_probe() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-power-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let supply = |name: &str, attrs: &[(&str, &str)]| {
+            let supply = dir.join(name);
+            std::fs::create_dir_all(&supply).unwrap();
+            for (attr, value) in attrs {
+                std::fs::write(supply.join(attr), format!("{value}\n")).unwrap();
+            }
+        };
+        let probe = SysfsProbe::new(dir.clone());
+        assert_eq!(probe.on_battery(), None);
+
+        // a desktop
+        supply("AC", &[("type", "Mains"), ("online", "1")]);
+        assert_eq!(probe.on_battery(), Some(false));
+
+        // a laptop on AC, then unplugged
+        supply("BAT0", &[("type", "Battery"), ("status", "Charging")]);
+        assert_eq!(probe.on_battery(), Some(false));
+        supply("AC", &[("online", "0")]);
+        supply("BAT0", &[("status", "Discharging")]);
+        assert_eq!(probe.on_battery(), Some(true));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
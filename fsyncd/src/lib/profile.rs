@@ -0,0 +1,524 @@
+//! Timings of the expensive phases of the daemon, to tell where a slow sync spends its time.
+//!
+//! A [`Span`] measures a [`Phase`] from its creation to its drop.
+//! Nothing is recorded until [`enable`] is called, by the `--timings` and `--profile`
+//! flags of the daemon, so that a span costs a relaxed atomic load when disabled
+//! (see the `profile` benchmark).
+//! The durations are kept per phase over a rolling window, summarized by [`timings`],
+//! and the spans ending within the window of a [trace](start_trace) are written
+//! as a Chrome trace, that `chrome://tracing`, Perfetto or speedscope can open.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    io::{self, Write},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
+
+use fsync::path::{FsPath, FsPathBuf};
+use serde::Serialize;
+use tarpc::{context::Context, server::Serve, ServerError};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Number of most recent durations kept per phase for the percentiles
+pub const WINDOW: usize = 1024;
+
+/// Maximum number of spans kept for a trace, the later ones are dropped
+pub const MAX_TRACE_SPANS: usize = 1 << 20;
+
+/// The expensive phases of the daemon
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Loading of the cache of a remote storage
+    CacheLoad,
+    /// Persistence of the cache of a remote storage
+    CacheSave,
+    /// Build of the tree, one span per top level directory
+    TreeBuild,
+    /// Operation of a single entry of the tree
+    Unit,
+    /// HTTP request to the Drive API, until the response headers
+    DriveHttp,
+    /// IO on the local file system
+    LocalIo,
+    /// Wait for the lock of the conflicts of the tree
+    TreeLock,
+    /// Handling of a request of a client
+    Rpc,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 8] = [
+        Phase::CacheLoad,
+        Phase::CacheSave,
+        Phase::TreeBuild,
+        Phase::Unit,
+        Phase::DriveHttp,
+        Phase::LocalIo,
+        Phase::TreeLock,
+        Phase::Rpc,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::CacheLoad => "cache.load",
+            Phase::CacheSave => "cache.save",
+            Phase::TreeBuild => "tree.build",
+            Phase::Unit => "operation.unit",
+            Phase::DriveHttp => "drive.http",
+            Phase::LocalIo => "local.io",
+            Phase::TreeLock => "tree.lock",
+            Phase::Rpc => "rpc",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static PROFILER: LazyLock<Profiler> = LazyLock::new(Profiler::default);
+
+/// Start recording the spans
+pub fn enable() {
+    LazyLock::force(&PROFILER);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Measures a phase until dropped
+#[must_use = "the span measures until it is dropped"]
+#[derive(Debug)]
+pub struct Span {
+    // boxed, so that a disabled span is a null pointer moved around
+    active: Option<Box<Active>>,
+}
+
+#[derive(Debug)]
+struct Active {
+    phase: Phase,
+    start: Instant,
+    detail: Option<String>,
+    bytes: u64,
+}
+
+impl Span {
+    #[inline]
+    pub fn enter(phase: Phase) -> Self {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Self { active: None };
+        }
+        Self {
+            active: Some(Box::new(Active {
+                phase,
+                start: Instant::now(),
+                detail: None,
+                bytes: 0,
+            })),
+        }
+    }
+
+    /// Name the span in the trace, e.g. with the path of the entry.
+    /// `detail` is only called when recording.
+    #[inline]
+    pub fn with_detail(mut self, detail: impl FnOnce() -> String) -> Self {
+        if let Some(active) = &mut self.active {
+            active.detail = Some(detail());
+        }
+        self
+    }
+
+    /// Record the number of bytes moved during the span
+    #[inline]
+    pub fn with_bytes(mut self, bytes: u64) -> Self {
+        if let Some(active) = &mut self.active {
+            active.bytes = bytes;
+        }
+        self
+    }
+}
+
+impl Drop for Span {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(active) = self.active.take() {
+            PROFILER.record(*active, Instant::now());
+        }
+    }
+}
+
+/// Measures a phase that is interleaved with others, e.g. the local writes of a download
+/// between the reads of the network. The measured durations are summed,
+/// and recorded as a single span ending when dropped.
+#[derive(Debug)]
+pub struct Elapsed {
+    active: Option<Box<Active>>,
+    total: Duration,
+}
+
+impl Elapsed {
+    #[inline]
+    pub fn new(phase: Phase) -> Self {
+        let mut span = Span::enter(phase);
+        Self {
+            active: span.active.take(),
+            total: Duration::ZERO,
+        }
+    }
+
+    /// See [`Span::with_detail`]
+    #[inline]
+    pub fn with_detail(mut self, detail: impl FnOnce() -> String) -> Self {
+        if let Some(active) = &mut self.active {
+            active.detail = Some(detail());
+        }
+        self
+    }
+
+    /// The start of a measure, to be passed to [`Self::add`]
+    #[inline]
+    pub fn start(&self) -> Option<Instant> {
+        self.active.as_ref().map(|_| Instant::now())
+    }
+
+    /// Add the time elapsed since `start`, that moved `bytes`
+    #[inline]
+    pub fn add(&mut self, start: Option<Instant>, bytes: u64) {
+        if let (Some(active), Some(start)) = (&mut self.active, start) {
+            self.total += start.elapsed();
+            active.bytes += bytes;
+        }
+    }
+}
+
+impl Drop for Elapsed {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(mut active) = self.active.take() {
+            let end = Instant::now();
+            active.start = end.checked_sub(self.total).unwrap_or(end);
+            PROFILER.record(*active, end);
+        }
+    }
+}
+
+/// A reader whose reads are measured with an [`Elapsed`]
+#[derive(Debug)]
+pub struct TimedRead<R> {
+    inner: R,
+    elapsed: Elapsed,
+}
+
+impl<R> TimedRead<R> {
+    pub fn new(inner: R, elapsed: Elapsed) -> Self {
+        Self { inner, elapsed }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TimedRead<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = self.elapsed.start();
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - filled;
+        self.elapsed.add(start, read as u64);
+        res
+    }
+}
+
+/// Measure `fut` with `span`, until it completes
+pub async fn instrument<F: Future>(span: Span, fut: F) -> F::Output {
+    let _span = span;
+    fut.await
+}
+
+/// A tarpc service that measures the handling of each request in a [`Phase::Rpc`] span
+#[derive(Debug, Clone)]
+pub struct TimedServe<S>(pub S);
+
+impl<S: Serve> Serve for TimedServe<S> {
+    type Req = S::Req;
+    type Resp = S::Resp;
+
+    async fn serve(self, ctx: Context, req: Self::Req) -> Result<Self::Resp, ServerError> {
+        let method = self.0.method(&req);
+        let span = Span::enter(Phase::Rpc).with_detail(|| method.unwrap_or("?").to_string());
+        instrument(span, self.0.serve(ctx, req)).await
+    }
+
+    fn method(&self, req: &Self::Req) -> Option<&'static str> {
+        self.0.method(req)
+    }
+}
+
+/// Summary of the recorded phases
+pub fn timings() -> fsync::Timings {
+    let phases = PROFILER.phases.lock().unwrap();
+    fsync::Timings {
+        enabled: is_enabled(),
+        phases: Phase::ALL
+            .iter()
+            .zip(phases.iter())
+            .filter(|(_, samples)| samples.count > 0)
+            .map(|(phase, samples)| samples.timings(*phase))
+            .collect(),
+    }
+}
+
+/// Record the spans ending within `duration` from now, to be written at `path`
+/// by [`write_trace`]
+pub fn start_trace(path: FsPathBuf, duration: Duration) {
+    *PROFILER.trace.lock().unwrap() = Some(Trace {
+        path,
+        until: Instant::now() + duration,
+        spans: Vec::new(),
+        dropped: 0,
+    });
+}
+
+/// Write the trace started by [`start_trace`], if not written yet.
+/// Returns the path of the file.
+pub fn write_trace() -> io::Result<Option<FsPathBuf>> {
+    let Some(trace) = PROFILER.trace.lock().unwrap().take() else {
+        return Ok(None);
+    };
+    if trace.dropped > 0 {
+        log::warn!(
+            "Dropped {} spans beyond the first {MAX_TRACE_SPANS} of the trace",
+            trace.dropped
+        );
+    }
+    trace.write(&trace.path)?;
+    Ok(Some(trace.path))
+}
+
+struct Profiler {
+    /// Time origin of the trace
+    origin: Instant,
+    /// Samples of each phase, in the order of [`Phase::ALL`]
+    phases: Mutex<Vec<Samples>>,
+    trace: Mutex<Option<Trace>>,
+    next_id: AtomicU64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self {
+            origin: Instant::now(),
+            phases: Mutex::new(vec![Samples::default(); Phase::ALL.len()]),
+            trace: Mutex::new(None),
+            next_id: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Profiler {
+    fn record(&self, active: Active, end: Instant) {
+        let duration = end - active.start;
+        {
+            let mut phases = self.phases.lock().unwrap();
+            phases[active.phase as usize].push(duration, active.bytes);
+        }
+
+        let mut trace = self.trace.lock().unwrap();
+        let Some(trace) = trace.as_mut().filter(|trace| end <= trace.until) else {
+            return;
+        };
+        if trace.spans.len() >= MAX_TRACE_SPANS {
+            trace.dropped += 1;
+            return;
+        }
+        trace.spans.push(TraceSpan {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            phase: active.phase,
+            detail: active.detail,
+            bytes: active.bytes,
+            start: active.start.saturating_duration_since(self.origin),
+            end: end.saturating_duration_since(self.origin),
+        });
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Samples {
+    count: u64,
+    bytes: u64,
+    recent: VecDeque<Duration>,
+}
+
+impl Samples {
+    fn push(&mut self, duration: Duration, bytes: u64) {
+        self.count += 1;
+        self.bytes += bytes;
+        if self.recent.len() == WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(duration);
+    }
+
+    fn timings(&self, phase: Phase) -> fsync::PhaseTimings {
+        let mut sorted: Vec<_> = self.recent.iter().copied().collect();
+        sorted.sort_unstable();
+        let percentile = |p: usize| {
+            let idx = (sorted.len() * p).div_ceil(100).saturating_sub(1);
+            sorted.get(idx).map_or(0, |d| d.as_micros() as u64)
+        };
+        fsync::PhaseTimings {
+            phase: phase.name().to_string(),
+            count: self.count,
+            bytes: self.bytes,
+            p50_us: percentile(50),
+            p90_us: percentile(90),
+            p99_us: percentile(99),
+            max_us: percentile(100),
+        }
+    }
+}
+
+struct Trace {
+    path: FsPathBuf,
+    until: Instant,
+    spans: Vec<TraceSpan>,
+    dropped: usize,
+}
+
+struct TraceSpan {
+    id: u64,
+    phase: Phase,
+    detail: Option<String>,
+    bytes: u64,
+    start: Duration,
+    end: Duration,
+}
+
+/// An event of the Chrome trace format.
+/// The spans overlap within a phase, so they are written as pairs of async events,
+/// grouped in a track per phase.
+#[derive(Serialize)]
+struct Event<'a> {
+    name: &'a str,
+    cat: &'static str,
+    ph: &'static str,
+    id: u64,
+    ts: u128,
+    pid: u32,
+    tid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<Args>,
+}
+
+#[derive(Serialize)]
+struct Args {
+    bytes: u64,
+}
+
+impl Trace {
+    fn write(&self, path: &FsPath) -> io::Result<()> {
+        let pid = std::process::id();
+        let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+        file.write_all(b"{\"traceEvents\":[\n")?;
+        for (i, span) in self.spans.iter().enumerate() {
+            let name = span.detail.as_deref().unwrap_or(span.phase.name());
+            let event = |ph, ts: Duration| Event {
+                name,
+                cat: span.phase.name(),
+                ph,
+                id: span.id,
+                ts: ts.as_micros(),
+                pid,
+                tid: span.phase as u32,
+                args: (ph == "b" && span.bytes > 0).then_some(Args { bytes: span.bytes }),
+            };
+            if i > 0 {
+                file.write_all(b",\n")?;
+            }
+            serde_json::to_writer(&mut file, &event("b", span.start))?;
+            file.write_all(b",\n")?;
+            serde_json::to_writer(&mut file, &event("e", span.end))?;
+        }
+        file.write_all(b"\n],\"displayTimeUnit\":\"ms\"}\n")?;
+        file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut samples = Samples::default();
+        for ms in 1..=100 {
+            samples.push(Duration::from_millis(ms), 10);
+        }
+        let timings = samples.timings(Phase::DriveHttp);
+        assert_eq!(timings.phase, "drive.http");
+        assert_eq!(timings.count, 100);
+        assert_eq!(timings.bytes, 1000);
+        assert_eq!(timings.p50_us, 50_000);
+        assert_eq!(timings.p90_us, 90_000);
+        assert_eq!(timings.p99_us, 99_000);
+        assert_eq!(timings.max_us, 100_000);
+
+        // only the most recent samples count
+        for _ in 0..WINDOW {
+            samples.push(Duration::from_millis(1), 0);
+        }
+        let timings = samples.timings(Phase::DriveHttp);
+        assert_eq!(timings.count, 100 + WINDOW as u64);
+        assert_eq!(timings.max_us, 1_000);
+    }
+
+    #[test]
+    fn trace_is_chrome_json() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-profile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = FsPathBuf::try_from(dir.join("trace.json")).unwrap();
+
+        let trace = Trace {
+            path: path.clone(),
+            until: Instant::now(),
+            spans: vec![TraceSpan {
+                id: 0,
+                phase: Phase::Unit,
+                detail: Some("/a/b.txt".into()),
+                bytes: 42,
+                start: Duration::from_micros(10),
+                end: Duration::from_micros(30),
+            }],
+            dropped: 0,
+        };
+        trace.write(&path).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let events = json["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["ph"], "b");
+        assert_eq!(events[0]["name"], "/a/b.txt");
+        assert_eq!(events[0]["cat"], "operation.unit");
+        assert_eq!(events[0]["args"]["bytes"], 42);
+        assert_eq!(events[1]["ph"], "e");
+        assert_eq!(events[1]["ts"], 30);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+//! The files received from another daemon, see [`fsync::Fsync::receive_begin`].
+//!
+//! The content of a reception is piped to the creation of the remote file, which runs
+//! in the background while the chunks arrive, so that the file is never staged on disk.
+//! The MD5 of the content is computed on the way, and compared to the one of the sender
+//! when the reception completes. A reception abandoned by the sender ends the content early,
+//! which fails the creation of the file.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    task::{ready, Context, Poll},
+};
+
+use fsync::{Metadata, RECEPTION_TIMEOUT};
+use md5::Digest as _;
+use tokio::{
+    io::{self, AsyncRead, AsyncWriteExt, DuplexStream, ReadBuf},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::storage;
+
+/// Capacity of the pipe between the chunks received and the creation of the file
+const PIPE_CAPACITY: usize = 256 * 1024;
+
+/// The receptions in progress, by handle
+#[derive(Debug, Default)]
+pub struct Receptions {
+    next: AtomicU64,
+    pending: Mutex<HashMap<u64, Reception>>,
+}
+
+#[derive(Debug)]
+struct Reception {
+    metadata: Metadata,
+    pipe: DuplexStream,
+    hasher: md5::Md5,
+    received: u64,
+    last_chunk: Instant,
+    creation: JoinHandle<fsync::Result<Metadata>>,
+}
+
+impl Receptions {
+    /// Start the creation of the file of `metadata` in `storage`, and return its handle.
+    /// The receptions idle for [`RECEPTION_TIMEOUT`] are abandoned.
+    /// Fails if the file is already being received.
+    pub fn begin<S>(&self, storage: &S, metadata: Metadata) -> fsync::Result<u64>
+    where
+        S: storage::CreateFile + storage::Delete + Clone + Send + Sync + 'static,
+    {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|handle, reception| {
+            let idle = reception.last_chunk.elapsed() < RECEPTION_TIMEOUT;
+            if !idle {
+                log::warn!(
+                    "Abandoning the reception {handle} of {}",
+                    reception.metadata.path()
+                );
+            }
+            idle
+        });
+        let path = metadata.path();
+        if pending
+            .values()
+            .any(|reception| reception.metadata.path() == path)
+        {
+            fsync::other_bail!("{path} is already being received");
+        }
+
+        let (pipe, read) = io::duplex(PIPE_CAPACITY);
+        let size = metadata.size().unwrap_or_default();
+        let creation = {
+            let storage = storage.clone();
+            let metadata = metadata.clone();
+            tokio::spawn(async move {
+                let read = Exact {
+                    inner: read,
+                    remaining: size,
+                };
+                let res = storage.create_file(&metadata, read, None).await;
+                if res.is_err() {
+                    // the storages may have written a part of the file
+                    let _ = storage.delete(metadata.path(), None).await;
+                }
+                res
+            })
+        };
+
+        let handle = self.next.fetch_add(1, Ordering::Relaxed) + 1;
+        pending.insert(
+            handle,
+            Reception {
+                metadata,
+                pipe,
+                hasher: md5::Md5::new(),
+                received: 0,
+                last_chunk: Instant::now(),
+                creation,
+            },
+        );
+        Ok(handle)
+    }
+
+    /// Pass `data` to the creation of the file of `handle`
+    pub async fn chunk(&self, handle: u64, data: &[u8]) -> fsync::Result<()> {
+        // the reception is taken out while the pipe is written
+        let mut reception = self.take(handle)?;
+        let size = reception.metadata.size().unwrap_or_default();
+        if reception.received + data.len() as u64 > size {
+            fsync::other_bail!(
+                "{} is larger than the {size} bytes announced",
+                reception.metadata.path()
+            );
+        }
+        if let Err(err) = reception.pipe.write_all(data).await {
+            // the creation failed, its error is more telling
+            drop(reception.pipe);
+            return match reception.creation.await {
+                Ok(Err(err)) => Err(err),
+                _ => Err(err.into()),
+            };
+        }
+        reception.hasher.update(data);
+        reception.received += data.len() as u64;
+        reception.last_chunk = Instant::now();
+        self.pending.lock().unwrap().insert(handle, reception);
+        Ok(())
+    }
+
+    /// Complete the reception `handle`, and return the metadata of the file created
+    /// and the MD5 of the content received, in lowercase hexadecimal
+    pub async fn finish(&self, handle: u64) -> fsync::Result<(Metadata, String)> {
+        let mut reception = self.take(handle)?;
+        reception.pipe.shutdown().await?;
+        drop(reception.pipe);
+        let metadata = reception
+            .creation
+            .await
+            .map_err(|err| fsync::Error::Bug(err.to_string()))??;
+        Ok((metadata, hex::encode(reception.hasher.finalize())))
+    }
+
+    fn take(&self, handle: u64) -> fsync::Result<Reception> {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(&handle)
+            .ok_or_else(|| fsync::other_error!("No such reception: {handle}"))
+    }
+}
+
+/// Reads `inner`, and fails if it ends before `remaining` bytes
+struct Exact<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R> AsyncRead for Exact<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = (buf.filled().len() - before) as u64;
+        if read == 0 && buf.remaining() > 0 && self.remaining > 0 {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("the content ended {} bytes early", self.remaining),
+            )));
+        }
+        self.remaining = self.remaining.saturating_sub(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fsync::path::PathBuf;
+
+    use super::*;
+    use crate::storage::{mem::MemStorage, Exists};
+
+    fn file(path: &str, size: u64) -> Metadata {
+        Metadata::Regular {
+            path: PathBuf::from(path),
+            size,
+            mtime: chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            link_target: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn content_is_piped_to_the_storage() {
+        let storage = MemStorage::default();
+        let receptions = Receptions::default();
+        let handle = receptions.begin(&storage, file("/a.txt", 11)).unwrap();
+        assert!(receptions.begin(&storage, file("/a.txt", 11)).is_err());
+        receptions.chunk(handle, b"hello ").await.unwrap();
+        receptions.chunk(handle, b"world").await.unwrap();
+        let (metadata, md5) = receptions.finish(handle).await.unwrap();
+        assert_eq!(metadata.size(), Some(11));
+        assert_eq!(md5, "5eb63bbbe01eeed093cb22bb8f5acdc3");
+        assert!(receptions.finish(handle).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn short_content_leaves_no_file() {
+        let storage = MemStorage::default();
+        let receptions = Receptions::default();
+        let handle = receptions.begin(&storage, file("/a.txt", 11)).unwrap();
+        receptions.chunk(handle, b"hello").await.unwrap();
+        assert!(receptions.chunk(handle, b" world!").await.is_err());
+
+        let handle = receptions.begin(&storage, file("/b.txt", 11)).unwrap();
+        receptions.chunk(handle, b"hello").await.unwrap();
+        assert!(receptions.finish(handle).await.is_err());
+        assert!(!storage.exists(&PathBuf::from("/b.txt")).await.unwrap());
+    }
+}
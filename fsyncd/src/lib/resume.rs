@@ -0,0 +1,114 @@
+//! The resumes of the system from sleep, detected with [`fsync::sleep::Detector`].
+//!
+//! The connections to the storages don't survive a long sleep, and the requests in flight
+//! at the suspend hang until their timeout. [`Resumes`] notifies its subscribers of each resume,
+//! so that the operations retry the attempt in flight, the OAuth2 client refreshes the token
+//! that expired during the sleep, and the service checks the remote storage again.
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use fsync::sleep::Detector;
+use tokio::{sync::watch, time::MissedTickBehavior};
+
+/// Interval between two checks of the clocks
+pub const TICK: Duration = Duration::from_secs(5);
+
+/// A resume of the system from sleep
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resume {
+    /// When the resume was detected
+    pub at: DateTime<Utc>,
+    /// How long the system was asleep
+    pub asleep: Duration,
+}
+
+/// The resumes detected, shared by its clones
+#[derive(Debug, Clone)]
+pub struct Resumes {
+    last: Arc<watch::Sender<Option<Resume>>>,
+}
+
+impl Default for Resumes {
+    fn default() -> Self {
+        Self {
+            last: Arc::new(watch::Sender::new(None)),
+        }
+    }
+}
+
+impl Resumes {
+    /// Check the clocks every [`TICK`], and notify the resumes
+    pub async fn run(self) {
+        let mut detector = Detector::new(TICK);
+        let mut interval = tokio::time::interval(TICK);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Some(asleep) = detector.check() {
+                self.notify(asleep);
+            }
+        }
+    }
+
+    /// Notify the subscribers that the system resumed after `asleep`
+    pub fn notify(&self, asleep: Duration) {
+        log::info!(
+            "The system resumed after {}s of sleep, resyncing",
+            asleep.as_secs()
+        );
+        self.last.send_replace(Some(Resume {
+            at: Utc::now(),
+            asleep,
+        }));
+    }
+
+    /// The last resume, if any
+    pub fn last(&self) -> Option<Resume> {
+        *self.last.borrow()
+    }
+
+    /// Subscribe to the resumes that follow
+    pub fn subscribe(&self) -> watch::Receiver<Option<Resume>> {
+        self.last.subscribe()
+    }
+
+    /// Run `fut`, and fail it with a transient error if the system resumes from sleep
+    /// before it completes, as its requests may hang on dead connections
+    pub fn interrupting<F, T>(&self, fut: F) -> impl Future<Output = fsync::Result<T>>
+    where
+        F: Future<Output = fsync::Result<T>>,
+    {
+        let mut resumed = self.subscribe();
+        async move {
+            tokio::select! {
+                res = fut => res,
+                Ok(()) = resumed.changed() => Err(fsync::Error::Unavailable(
+                    "interrupted by the sleep of the system".into(),
+                )),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resumes_interrupt_the_futures_in_flight() {
+        let resumes = Resumes::default();
+        let hour = Duration::from_secs(3600);
+        resumes.notify(hour);
+        assert_eq!(resumes.last().unwrap().asleep, hour);
+
+        // the resumes before the future don't interrupt it
+        let res = resumes.interrupting(async { Ok(1) }).await;
+        assert_eq!(res.unwrap(), 1);
+
+        let hanging = resumes.interrupting(std::future::pending::<fsync::Result<()>>());
+        let (res, ()) = tokio::join!(hanging, async { resumes.notify(hour) });
+        let err = res.unwrap_err();
+        assert!(err.is_transient(), "{err}");
+    }
+}
@@ -0,0 +1,64 @@
+//! Bookkeeping of the conflicts checked again in the storages, see [`fsync::Fsync::revalidate_conflicts`].
+//!
+//! A conflict resolved outside of fsync, e.g. by deleting the local file in a file manager,
+//! is only noticed when its path is checked again. The conflicts fetched by the clients are
+//! checked once their state is older than the staleness, and a per-path cooldown prevents
+//! the clients that poll or force the check from triggering a storm of storage requests.
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use fsync::path::{Path, PathBuf};
+use tokio::time::Instant;
+
+/// Default age of the state of a conflict above which it is checked again
+pub const DEFAULT_STALENESS: Duration = Duration::from_secs(30);
+
+/// Minimum delay between two checks of the same path, even when forced
+pub const COOLDOWN: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct Revalidation {
+    staleness: Duration,
+    /// When the tree was built, which is the age of the paths never checked.
+    /// A forced check of such a path is not delayed.
+    start: Instant,
+    /// When the paths were last checked
+    checked: Mutex<HashMap<PathBuf, Instant>>,
+}
+
+impl Default for Revalidation {
+    fn default() -> Self {
+        Self::new(DEFAULT_STALENESS)
+    }
+}
+
+impl Revalidation {
+    /// The staleness can't be below the cooldown
+    pub fn new(staleness: Duration) -> Self {
+        Self {
+            staleness: staleness.max(COOLDOWN),
+            start: Instant::now(),
+            checked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the path must be checked again, in which case it is marked as checked now.
+    /// A `forced` check only waits for the cooldown.
+    pub fn is_due(&self, path: &Path, forced: bool) -> bool {
+        let now = Instant::now();
+        let mut checked = self.checked.lock().unwrap();
+        // the paths checked before the staleness are due anyway
+        checked.retain(|_, at| now.duration_since(*at) < self.staleness);
+        let last = checked.get(path).copied();
+        let (last, delay) = if forced {
+            (last, COOLDOWN)
+        } else {
+            (Some(last.unwrap_or(self.start)), self.staleness)
+        };
+        if last.is_some_and(|last| now.duration_since(last) < delay) {
+            return false;
+        }
+        checked.insert(path.to_owned(), now);
+        true
+    }
+}
@@ -0,0 +1,284 @@
+//! Detection of the changes of the remote root, see [`fsync::RootChange`].
+//!
+//! The remote root resolved at the first start is recorded in a file of the instance.
+//! When the config resolves to another folder at a later start, the local directory would be
+//! paired with an unrelated remote tree, and the operations are refused until the user chooses
+//! how to migrate with [`fsync::Fsync::migrate_root`].
+
+use std::sync::Mutex;
+
+use fsync::{
+    overlap::RecordedRoot,
+    path::{FsPath, FsPathBuf, PathBuf},
+    RemoteRoot, RootChange,
+};
+
+use crate::persist;
+
+#[derive(Debug)]
+pub struct RootGuard {
+    path: FsPathBuf,
+    config_file: FsPathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    recorded: Option<RecordedRoot>,
+    /// The root resolved, if it is not the recorded one
+    changed: Option<RecordedRoot>,
+}
+
+impl RootGuard {
+    /// Open the root recorded at `path`, which does not need to exist.
+    /// A file that can't be read is discarded, and the next resolved root is recorded instead.
+    /// `config_file` is the config of the instance, in which the recorded root can be written back.
+    pub async fn open(path: FsPathBuf, config_file: FsPathBuf) -> anyhow::Result<Self> {
+        let recorded = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data)
+                .inspect_err(|err| log::warn!("Discarding the remote root of {path}: {err}"))
+                .ok(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            path,
+            config_file,
+            state: Mutex::new(State {
+                recorded,
+                changed: None,
+            }),
+        })
+    }
+
+    /// Check the root that the config `resolved` to against the recorded one.
+    /// The first resolved root is recorded, a different one is kept as a change to migrate.
+    pub async fn check(&self, resolved: RecordedRoot) -> anyhow::Result<()> {
+        let record = {
+            let mut state = self.state.lock().unwrap();
+            match &state.recorded {
+                None => true,
+                Some(recorded) if recorded.root.id == resolved.root.id => {
+                    // the config may spell the same folder differently,
+                    // and the folder may have moved in the drive
+                    recorded.root.config != resolved.root.config
+                        || (!resolved.ancestry.is_empty() && recorded.ancestry != resolved.ancestry)
+                }
+                Some(recorded) => {
+                    log::error!(
+                        "The remote root changed from {recorded} to {resolved}, the operations are refused until it is migrated"
+                    );
+                    state.changed = Some(resolved);
+                    return Ok(());
+                }
+            }
+        };
+        if record {
+            self.record(resolved).await?;
+        }
+        Ok(())
+    }
+
+    /// The root recorded at a previous start, if any
+    pub fn recorded(&self) -> Option<RecordedRoot> {
+        self.state.lock().unwrap().recorded.clone()
+    }
+
+    /// The ancestry recorded for the root `id`, if it was resolved at a previous start
+    pub fn recorded_ancestry(&self, id: &str) -> Option<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        state
+            .recorded
+            .as_ref()
+            .filter(|recorded| recorded.root.id == id && !recorded.ancestry.is_empty())
+            .map(|recorded| recorded.ancestry.clone())
+    }
+
+    /// The change of the remote root, if one was detected and not migrated yet
+    pub fn change(&self) -> Option<RootChange> {
+        let state = self.state.lock().unwrap();
+        match (&state.recorded, &state.changed) {
+            (Some(recorded), Some(changed)) => Some(RootChange {
+                recorded: recorded.root.clone(),
+                resolved: changed.root.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Fail with [`fsync::Error::RootChanged`] if the remote root changed
+    pub fn ensure_unchanged(&self) -> fsync::Result<()> {
+        match self.change() {
+            Some(change) => Err(fsync::Error::RootChanged(Box::new(change))),
+            None => Ok(()),
+        }
+    }
+
+    /// Pair the instance with the new root
+    pub async fn accept(&self) -> anyhow::Result<()> {
+        let Some(changed) = self.state.lock().unwrap().changed.clone() else {
+            return Ok(());
+        };
+        self.record(changed).await?;
+        self.state.lock().unwrap().changed = None;
+        Ok(())
+    }
+
+    /// Write the recorded root back in the config.
+    /// The change remains until the daemon is restarted with the reverted config.
+    pub async fn revert_config(&self) -> anyhow::Result<()> {
+        let Some(change) = self.change() else {
+            return Ok(());
+        };
+        revert_config(&self.config_file, &change.recorded).await
+    }
+
+    async fn record(&self, root: RecordedRoot) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(&root)?;
+        if let Some(dir) = self.path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || persist::atomic_write(&path, &data)).await??;
+        log::info!("Recorded {root} as the remote root");
+        self.state.lock().unwrap().recorded = Some(root);
+        Ok(())
+    }
+}
+
+/// Warn about the other instances of the user whose remote root overlaps with `root`,
+/// the one of `instance_name`. If `strict`, fail instead.
+pub fn check_overlaps(
+    instance_name: &str,
+    root: &RecordedRoot,
+    strict: bool,
+) -> anyhow::Result<()> {
+    let overlaps = fsync::overlap::overlaps(instance_name, root)?;
+    for overlap in &overlaps {
+        log::warn!("The remote root {root} overlaps with another instance: {overlap}");
+    }
+    if strict && !overlaps.is_empty() {
+        anyhow::bail!(
+            "The remote root {root} overlaps with the one of {}, refusing to start",
+            overlaps
+                .iter()
+                .map(|o| o.instance.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// The remote root of a Drive config
+pub fn drive_config_root(root: Option<&fsync::path::Path>) -> String {
+    root.map_or_else(|| "/".to_string(), ToString::to_string)
+}
+
+/// Write `root` as the remote root of the config in `config_file`
+async fn revert_config(config_file: &FsPath, root: &RemoteRoot) -> anyhow::Result<()> {
+    let mut config = fsync::Config::load_from_file(config_file).await?;
+    match &mut config.provider {
+        fsync::ProviderConfig::GoogleDrive(drive) => {
+            let path = PathBuf::from(root.config.as_str());
+            drive.root = (!path.is_root()).then_some(path);
+        }
+        fsync::ProviderConfig::LocalFs(dir) => *dir = FsPathBuf::from(root.config.as_str()),
+    }
+    let json = serde_json::to_vec_pretty(&config)?;
+    let path = config_file.to_owned();
+    tokio::task::spawn_blocking(move || persist::atomic_write(&path, &json)).await??;
+    log::info!(
+        "Reverted the remote root of {config_file} to {}",
+        root.config
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote_root(id: &str, config: &str) -> RemoteRoot {
+        RemoteRoot {
+            id: id.to_string(),
+            config: config.to_string(),
+        }
+    }
+
+    fn root(id: &str, config: &str) -> RecordedRoot {
+        RecordedRoot {
+            root: remote_root(id, config),
+            ancestry: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn root_changes() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-root-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("root.json");
+        let config_file = dir.join("config.json");
+        let open = || RootGuard::open(path.clone(), config_file.clone());
+
+        // the first root is recorded
+        let guard = open().await.unwrap();
+        guard.check(root("id1", "/Photos")).await.unwrap();
+        assert!(guard.ensure_unchanged().is_ok());
+
+        // the same folder, spelled differently
+        let guard = open().await.unwrap();
+        guard.check(root("id1", "/photos")).await.unwrap();
+        assert_eq!(guard.change(), None);
+
+        let guard = open().await.unwrap();
+        guard.check(root("id2", "/Work")).await.unwrap();
+        let change = RootChange {
+            recorded: remote_root("id1", "/photos"),
+            resolved: remote_root("id2", "/Work"),
+        };
+        assert_eq!(guard.change(), Some(change.clone()));
+        assert!(matches!(
+            guard.ensure_unchanged(),
+            Err(fsync::Error::RootChanged(c)) if *c == change
+        ));
+        // still changed at the next start
+        let guard = open().await.unwrap();
+        guard.check(root("id2", "/Work")).await.unwrap();
+        assert!(guard.change().is_some());
+
+        guard.accept().await.unwrap();
+        assert_eq!(guard.change(), None);
+        let guard = open().await.unwrap();
+        guard.check(root("id2", "/Work")).await.unwrap();
+        assert_eq!(guard.change(), None);
+
+        // the ancestry is recorded for the same root, and kept when it could not be resolved
+        assert_eq!(guard.recorded_ancestry("id2"), None);
+        let ancestry = vec!["id2".to_string(), "top".to_string()];
+        guard
+            .check(RecordedRoot {
+                ancestry: ancestry.clone(),
+                ..root("id2", "/Work")
+            })
+            .await
+            .unwrap();
+        let guard = open().await.unwrap();
+        guard.check(root("id2", "/Work")).await.unwrap();
+        assert_eq!(guard.recorded_ancestry("id2"), Some(ancestry));
+        assert_eq!(guard.recorded_ancestry("id3"), None);
+
+        // a corrupt record is replaced
+        std::fs::write(&path, b"{\"id\":").unwrap();
+        let guard = open().await.unwrap();
+        guard.check(root("id3", "/")).await.unwrap();
+        assert_eq!(guard.change(), None);
+        let guard = open().await.unwrap();
+        guard.check(root("id2", "/Work")).await.unwrap();
+        assert_eq!(guard.change().unwrap().recorded, remote_root("id3", "/"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
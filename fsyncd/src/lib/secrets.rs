@@ -0,0 +1,350 @@
+//! Protection at rest of the OAuth2 client secret and of the cached tokens.
+//!
+//! Sealed data is encrypted with AES-256-GCM and starts with a magic header,
+//! so that clear text written by previous versions is detected and migrated.
+//! The key is either stored in the OS keyring, or derived from a passphrase with Argon2.
+//!
+//! The key of the file contents of the encrypted instances is also derived from
+//! a passphrase, see [`content_key`].
+
+use std::fmt;
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::Context;
+use base64::prelude::*;
+use fsync::{
+    loc::inst,
+    oauth2::ClientSecret,
+    path::{FsPath, FsPathBuf},
+    Config, ProviderConfig, SecretsProtection,
+};
+
+use crate::{persist, storage::crypt::ContentKey};
+
+const MAGIC: &[u8; 8] = b"FSYNCSEC";
+const NONCE_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+/// Prefix of sealed strings, such as the client secret in the config file
+const STR_PREFIX: &str = "sealed:";
+
+/// Environment variable providing the passphrase when the daemon has no terminal
+pub const PASSPHRASE_ENV: &str = "FSYNCD_PASSPHRASE";
+
+/// Environment variable providing the passphrase of the contents when the daemon has no terminal
+pub const CONTENT_PASSPHRASE_ENV: &str = "FSYNCD_CONTENT_PASSPHRASE";
+/// Sealed in the check file of the content key
+const CONTENT_CHECK: &[u8] = b"fsync content key";
+
+/// Encrypts and decrypts secrets with the key of an instance
+#[derive(Clone)]
+pub struct Sealer {
+    key: Option<Key<Aes256Gcm>>,
+}
+
+impl fmt::Debug for Sealer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sealer")
+            .field("encrypted", &self.is_encrypted())
+            .finish()
+    }
+}
+
+impl Sealer {
+    /// A sealer that leaves the secrets in clear text
+    pub fn plain() -> Self {
+        Self { key: None }
+    }
+
+    pub fn with_key(key: [u8; 32]) -> Self {
+        Self {
+            key: Some(key.into()),
+        }
+    }
+
+    /// Get the key of `instance_name` according to `protection`.
+    /// The key is created if needed.
+    pub async fn new(protection: SecretsProtection, instance_name: &str) -> anyhow::Result<Self> {
+        match protection {
+            SecretsProtection::Plain => Ok(Self::plain()),
+            SecretsProtection::Keyring => {
+                let name = instance_name.to_owned();
+                let key = tokio::task::spawn_blocking(move || keyring_key(&name)).await??;
+                Ok(Self::with_key(key))
+            }
+            SecretsProtection::Passphrase => {
+                let salt_path = inst::config_dir(instance_name)?.join("secrets.salt");
+                let name = instance_name.to_owned();
+                let key = tokio::task::spawn_blocking(move || {
+                    let prompt = format!("Passphrase for {name}: ");
+                    passphrase_key(&salt_path, PASSPHRASE_ENV, &prompt)
+                })
+                .await??;
+                Ok(Self::with_key(key))
+            }
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Seal `data`. Without key, `data` is returned as is.
+    pub fn seal(&self, data: &[u8]) -> Vec<u8> {
+        let Some(key) = &self.key else {
+            return data.to_vec();
+        };
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(key)
+            .encrypt(&nonce, data)
+            .expect("encryption should not fail");
+        let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Open data sealed with [`Self::seal`]. Clear text is returned as is.
+    pub fn open(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if !is_sealed(data) {
+            return Ok(data.to_vec());
+        }
+        let Some(key) = &self.key else {
+            anyhow::bail!("Secrets are encrypted, but secrets protection is disabled");
+        };
+        let data = &data[MAGIC.len()..];
+        if data.len() < NONCE_LEN {
+            anyhow::bail!("Encrypted secrets are truncated");
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        Aes256Gcm::new(key)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Could not decrypt secrets: wrong key or passphrase"))
+    }
+
+    /// Seal the string `secret` if it is in clear text.
+    /// Returns whether the secret was modified.
+    pub fn seal_str(&self, secret: &mut String) -> bool {
+        if !self.is_encrypted() || secret.starts_with(STR_PREFIX) {
+            return false;
+        }
+        let sealed = self.seal(secret.as_bytes());
+        *secret = format!("{STR_PREFIX}{}", BASE64_STANDARD_NO_PAD.encode(sealed));
+        true
+    }
+
+    /// The string `secret` in clear text
+    pub fn open_str(&self, secret: &str) -> anyhow::Result<String> {
+        let Some(sealed) = secret.strip_prefix(STR_PREFIX) else {
+            return Ok(secret.to_owned());
+        };
+        let sealed = BASE64_STANDARD_NO_PAD
+            .decode(sealed)
+            .context("Invalid sealed secret")?;
+        Ok(String::from_utf8(self.open(&sealed)?)?)
+    }
+
+    /// Seal the client secret if it is in clear text.
+    /// Returns whether the secret was modified.
+    pub fn seal_client_secret(&self, secret: &mut ClientSecret) -> bool {
+        let mut sealed = secret.secret().clone();
+        if !self.seal_str(&mut sealed) {
+            return false;
+        }
+        *secret = ClientSecret::new(sealed);
+        true
+    }
+
+    /// The client secret in clear text
+    pub fn open_client_secret(&self, secret: &ClientSecret) -> anyhow::Result<ClientSecret> {
+        Ok(ClientSecret::new(self.open_str(secret.secret())?))
+    }
+}
+
+/// Whether `data` was sealed with [`Sealer::seal`]
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Seal the client secret and the SMTP password of `config` if they are in clear text,
+/// and rewrite the config file accordingly.
+pub async fn migrate_config(
+    config_file: &FsPath,
+    config: &mut Config,
+    sealer: &Sealer,
+) -> anyhow::Result<()> {
+    let mut modified = false;
+    if let ProviderConfig::GoogleDrive(drive) = &mut config.provider {
+        if sealer.seal_client_secret(&mut drive.secret.client_secret) {
+            log::info!("Encrypting client secret in {config_file}");
+            modified = true;
+        }
+    }
+    let password = config
+        .digest
+        .as_mut()
+        .and_then(|digest| digest.smtp.as_mut())
+        .and_then(|smtp| smtp.password.as_mut());
+    if let Some(password) = password {
+        if sealer.seal_str(password) {
+            log::info!("Encrypting SMTP password in {config_file}");
+            modified = true;
+        }
+    }
+    if modified {
+        let json = serde_json::to_vec_pretty(&config)?;
+        let path = config_file.to_owned();
+        tokio::task::spawn_blocking(move || persist::atomic_write(&path, &json)).await??;
+    }
+    Ok(())
+}
+
+/// Derive the key of the file contents of `instance_name` from its passphrase.
+/// The key is checked against the one of the first start, so that the contents are never
+/// written with a mistyped passphrase. Without the passphrase, they can't be recovered.
+pub async fn content_key(instance_name: &str) -> anyhow::Result<ContentKey> {
+    let config_dir = inst::config_dir(instance_name)?;
+    let salt_path = config_dir.join("content.salt");
+    let check_path = config_dir.join("content.check");
+    let name = instance_name.to_owned();
+    let key = tokio::task::spawn_blocking(move || {
+        let prompt = format!("Content passphrase for {name}: ");
+        let key = passphrase_key(&salt_path, CONTENT_PASSPHRASE_ENV, &prompt)?;
+        check_content_key(&check_path, key)?;
+        anyhow::Ok(key)
+    })
+    .await??;
+    Ok(ContentKey::new(key))
+}
+
+/// Check `key` with the file at `check_path`, which is created with the first key
+fn check_content_key(check_path: &FsPath, key: [u8; 32]) -> anyhow::Result<()> {
+    let sealer = Sealer::with_key(key);
+    if !check_path.exists() {
+        persist::atomic_write(check_path, &sealer.seal(CONTENT_CHECK))?;
+        return Ok(());
+    }
+    let sealed = std::fs::read(check_path)?;
+    match sealer.open(&sealed) {
+        Ok(check) if check == CONTENT_CHECK => Ok(()),
+        _ => anyhow::bail!("Wrong content passphrase"),
+    }
+}
+
+fn keyring_key(instance_name: &str) -> anyhow::Result<[u8; 32]> {
+    let entry = keyring::Entry::new(SecretsProtection::KEYRING_SERVICE, instance_name)
+        .context("Could not access the OS keyring")?;
+    match entry.get_password() {
+        Ok(encoded) => {
+            let key = BASE64_STANDARD_NO_PAD
+                .decode(encoded)
+                .context("Invalid key in the OS keyring")?;
+            key.try_into()
+                .map_err(|_| anyhow::anyhow!("Invalid key length in the OS keyring"))
+        }
+        Err(keyring::Error::NoEntry) => {
+            log::info!("Creating secrets key for {instance_name} in the OS keyring");
+            let key: [u8; 32] = Aes256Gcm::generate_key(&mut OsRng).into();
+            entry
+                .set_password(&BASE64_STANDARD_NO_PAD.encode(key))
+                .context("Could not store the key in the OS keyring")?;
+            Ok(key)
+        }
+        Err(err) => Err(err).context("Could not read the key from the OS keyring"),
+    }
+}
+
+/// Derive a key from the passphrase provided by the `env` variable, or prompted with `prompt`.
+/// The passphrase is confirmed when the salt is created.
+fn passphrase_key(salt_path: &FsPathBuf, env: &str, prompt: &str) -> anyhow::Result<[u8; 32]> {
+    let (salt, created) = if salt_path.exists() {
+        let salt = std::fs::read(salt_path)?;
+        if salt.len() != SALT_LEN {
+            anyhow::bail!("Invalid salt in {salt_path}");
+        }
+        (salt, false)
+    } else {
+        let mut salt = vec![0u8; SALT_LEN];
+        aes_gcm::aead::rand_core::RngCore::fill_bytes(&mut OsRng, &mut salt);
+        (salt, true)
+    };
+
+    let passphrase = match std::env::var(env) {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            let passphrase = rpassword::prompt_password(prompt)?;
+            if created {
+                let confirm = rpassword::prompt_password("Confirm passphrase: ")?;
+                if confirm != passphrase {
+                    anyhow::bail!("Passphrases do not match");
+                }
+            }
+            passphrase
+        }
+    };
+
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+        .map_err(|err| anyhow::anyhow!("Could not derive key from passphrase: {err}"))?;
+
+    if created {
+        persist::atomic_write(salt_path, &salt)?;
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_roundtrip() {
+        let sealer = Sealer::with_key([7u8; 32]);
+        let sealed = sealer.seal(b"{\"tokens\": []}");
+        assert!(is_sealed(&sealed));
+        assert_ne!(sealer.seal(b"{\"tokens\": []}"), sealed);
+        assert_eq!(sealer.open(&sealed).unwrap(), b"{\"tokens\": []}");
+    }
+
+    #[test]
+    fn open_clear_text_and_wrong_key() {
+        let sealer = Sealer::with_key([7u8; 32]);
+        assert_eq!(sealer.open(b"{}").unwrap(), b"{}");
+
+        let sealed = sealer.seal(b"secret");
+        assert!(Sealer::with_key([8u8; 32]).open(&sealed).is_err());
+        assert!(Sealer::plain().open(&sealed).is_err());
+        assert_eq!(Sealer::plain().seal(b"secret"), b"secret");
+    }
+
+    #[test]
+    fn client_secret_roundtrip() {
+        let sealer = Sealer::with_key([7u8; 32]);
+        let mut secret = ClientSecret::new("GOCSPX-secret".to_string());
+        assert!(sealer.seal_client_secret(&mut secret));
+        assert!(secret.secret().starts_with(STR_PREFIX));
+        assert!(!sealer.seal_client_secret(&mut secret));
+        assert_eq!(
+            sealer.open_client_secret(&secret).unwrap().secret(),
+            "GOCSPX-secret"
+        );
+    }
+
+    #[test]
+    fn content_key_is_checked() {
+        let dir = std::env::temp_dir().join("fsyncd-secrets-content-check");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let check_path = FsPathBuf::try_from(dir.join("content.check")).unwrap();
+
+        check_content_key(&check_path, [7u8; 32]).unwrap();
+        assert!(check_path.exists());
+        check_content_key(&check_path, [7u8; 32]).unwrap();
+        let err = check_content_key(&check_path, [8u8; 32]).unwrap_err();
+        assert_eq!(err.to_string(), "Wrong content passphrase");
+    }
+}
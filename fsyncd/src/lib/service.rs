@@ -1,51 +1,194 @@
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
     net::{IpAddr, Ipv6Addr},
     ops::Bound,
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{self, AtomicBool, AtomicU64},
+        Arc,
+    },
+    time::{Duration, SystemTime},
 };
 
 use async_read_progress::TokioAsyncReadProgressExt;
+use chrono::Utc;
 use fsync::{
     self,
+    filter::{EntryFilter, EntryMatcher},
     loc::inst,
-    path::{FsPathBuf, Path, PathBuf},
+    path::{FsPath, FsPathBuf, Path, PathBuf},
+    runtime::PortFile,
     stat,
     tree::EntryNode,
-    DeletionMethod, Error, Fsync, Metadata, Operation, PathError, Progress, ResolutionMethod,
-    StorageDir, StorageLoc,
+    Action, Checksum, Error, Fsync, Location, Metadata, OperateOptions, Operation, OperationReport,
+    PathError, PlanId, PlannedAction, Progress, SortOrder, StorageDir, StorageLoc, TransferCause,
 };
 use futures::{
-    future::{self, BoxFuture},
+    future,
     prelude::*,
-    stream::{AbortHandle, AbortRegistration, Abortable},
+    stream::{self, AbortHandle, AbortRegistration, Abortable},
 };
 use tarpc::{
     context::Context,
     server::{self, incoming::Incoming, Channel},
-    tokio_serde::formats::Bincode,
 };
 use tokio::{
     io,
-    sync::{mpsc, RwLock},
+    sync::{mpsc, watch, Mutex, RwLock},
 };
 
 use crate::{
+    aggregate::{self, Aggregator},
+    audit::AuditLog,
+    buffers::BufferPool,
+    checkpoint::{Checkpoint, Checkpoints},
+    confirm::{self, Confirmations},
+    counters::{self, Counters},
+    digest::{self, Digest},
+    disk_cache::{self, DiskCache},
+    hooks::{self, Hooks},
+    journal::{self, Effect, Journal},
+    logs, maintenance,
+    merge::{self, Bases},
+    persist,
+    pins::Pins,
+    placeholders::{self, Placeholders},
+    plan::{self, Plan},
+    policy::{self, Policy},
+    profile::{self, Phase, Span, TimedServe},
+    reception::Receptions,
+    resume::Resumes,
+    revalidate::Revalidation,
+    root::RootGuard,
+    status_file::{self, StatusFile},
     storage,
-    tree::{self, DiffTree},
+    tree::{self, BuildOptions, DiffTree},
+    verify::{self, Outcome},
     SharedProgress,
 };
 
+/// Maximum number of bytes returned by [`Fsync::read_head`]
+const MAX_READ_HEAD: u64 = 1024 * 1024;
+
+/// Maximum number of unit operations performed concurrently by a deep operation
+const MAX_CONCURRENT_UNITS: usize = 8;
+
+/// Number of entries created at once by a bulk bootstrap, see [`Service::with_bulk_bootstrap`]:
+/// they are journaled as one operation, and applied at once to the tree
+const BOOTSTRAP_BATCH: usize = 256;
+
+/// How an operation was started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Start {
+    /// By the user, it runs at once and without time budget
+    User,
+    /// On schedule, see [`fsync::Fsync::operate_scheduled`]
+    Scheduled,
+    /// To resume the checkpoint of a scheduled operation, see [`fsync::Fsync::resume`]
+    Resume,
+}
+
+/// The time budget of a deep operation, and the entries left by a previous run
+#[derive(Debug, Default)]
+struct DeepRun {
+    /// When the operation stops starting unit operations
+    deadline: Option<tokio::time::Instant>,
+    /// The entries to operate instead of the whole sub-tree, in this order
+    resume_from: Option<Vec<PathBuf>>,
+}
+
+/// Maximum number of discrepancies kept by the self-check mode until they are retrieved.
+/// When exceeded, the oldest discrepancy is dropped.
+const MAX_DISCREPANCIES: usize = 1000;
+
+/// Number of conflicts read at once by [`Service::conflicts_filtered`]
+const FILTERED_CONFLICTS_BATCH: usize = 256;
+
+/// Number of new conflicts from which they are merged at once into the conflicts,
+/// rather than inserted one by one
+const BULK_CONFLICTS: usize = 1024;
+
+/// Maximum number of plans kept at the same time.
+/// When exceeded, the oldest plan is released.
+const MAX_PLANS: usize = 16;
+
+/// Maximum number of completed progresses kept for [`Service::progresses_since`],
+/// so that the clients polling the changes see their final state.
+/// When exceeded, the oldest progress is dropped.
+const MAX_FINISHED_PROGRESSES: usize = 64;
+
+/// Maximum number of log records returned by a call of [`Fsync::logs_tail`] or [`Fsync::logs_since`]
+const MAX_LOG_RECORDS: u32 = 1000;
+
+/// Default percentage of the remote quota above which a warning is emitted
+pub const DEFAULT_QUOTA_WARNING: f64 = 90.0;
+
+/// Default number of times an operation failing with a transient error is retried
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// Default delay before the first retry of an operation, doubled at each retry
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Maximum delay between two attempts of an operation
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 pub struct Service<L, R> {
     local: L,
     remote: R,
     tree: DiffTree,
+    tree_options: BuildOptions,
     conflicts: RwLock<BTreeSet<PathBuf>>,
     abort_handle: RwLock<Option<AbortHandle>>,
     progresses: Arc<RwLock<Vec<(PathBuf, SharedProgress)>>>,
+    /// The progresses removed from `progresses` once completed, oldest first
+    finished_progresses: Arc<std::sync::Mutex<VecDeque<(PathBuf, SharedProgress)>>>,
     local_root: FsPathBuf,
+    quota_warning: f64,
+    max_retries: u32,
+    retry_delay: Duration,
+    plans: Mutex<BTreeMap<PlanId, Plan>>,
+    plan_id: AtomicU64,
+    audit: Option<AuditLog>,
+    pins: Pins,
+    placeholders: Option<Placeholders>,
+    remote_phase: Option<watch::Receiver<fsync::RemotePhase>>,
+    disk_cache: Option<Arc<DiskCache>>,
+    buffers: Option<BufferPool>,
+    hooks: Option<Hooks>,
+    digest: Option<Digest>,
+    failures: Mutex<VecDeque<digest::Failure>>,
+    journal: Option<Journal>,
+    corrupt_files: Vec<fsync::CorruptFile>,
+    mappings: Vec<fsync::Mapping>,
+    sync_mode: fsync::SyncMode,
+    self_check: bool,
+    /// Whether the sub-trees absent from a storage are synchronized in bulk
+    bulk_bootstrap: bool,
+    discrepancies: Mutex<VecDeque<fsync::Discrepancy>>,
+    maintenance: fsync::Maintenance,
+    checksums: Option<storage::hash::Cache>,
+    aggregator: Option<Aggregator>,
+    counters: Counters,
+    root_guard: Option<Arc<RootGuard>>,
+    revalidation: Revalidation,
+    policy: Policy,
+    config_file: Option<FsPathBuf>,
+    config_fingerprint: std::sync::Mutex<Option<String>>,
+    /// The port file written by [`RpcService::start`], and its path
+    port_file: std::sync::Mutex<Option<(FsPathBuf, PortFile)>>,
+    confirmations: Confirmations,
+    status_file: Option<StatusFile>,
+    merge_bases: Option<Bases>,
+    checkpoints: Checkpoints,
+    receptions: Receptions,
+    /// Spaces the checksums requested by the verifications
+    verify_fetches: verify::Limiter,
+    /// The resumes of the system from sleep, which interrupt the attempts in flight
+    resumes: Resumes,
+    /// The last resume, and whether the remote storage was checked again since
+    resumed: std::sync::Mutex<Option<fsync::Resumed>>,
+    /// Serializes the checks of the remote storage after the resumes
+    resyncing: Mutex<()>,
 }
 
 impl<L, R> Service<L, R>
@@ -54,36 +197,134 @@ where
     R: storage::Storage,
 {
     pub async fn new(local: L, remote: R, local_root: FsPathBuf) -> anyhow::Result<Self> {
-        let tree = DiffTree::build(&local, &remote).await?;
-
-        let mut conflicts = BTreeSet::new();
+        Self::new_with(local, remote, local_root, BuildOptions::default()).await
+    }
 
-        for node in tree.entries() {
-            if let tree::Entry::Sync {
-                conflict: Some(_), ..
-            } = node.entry()
-            {
-                let path = node.key().to_path_buf();
-                conflicts.insert(path);
-            }
-        }
+    /// Create the service, with the tree built according to `options`
+    pub async fn new_with(
+        local: L,
+        remote: R,
+        local_root: FsPathBuf,
+        options: BuildOptions,
+    ) -> anyhow::Result<Self> {
+        let tree = DiffTree::build_with(&local, &remote, options.clone()).await?;
+        let conflicts = tree_conflicts(&tree);
 
         Ok(Self {
             local,
             remote,
             tree,
+            tree_options: options,
             conflicts: RwLock::new(conflicts),
             abort_handle: RwLock::new(None),
             progresses: Arc::new(RwLock::new(vec![])),
+            finished_progresses: Arc::default(),
             local_root,
+            quota_warning: DEFAULT_QUOTA_WARNING,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            plans: Mutex::new(BTreeMap::new()),
+            plan_id: AtomicU64::new(1),
+            audit: None,
+            pins: Pins::default(),
+            placeholders: None,
+            remote_phase: None,
+            disk_cache: None,
+            buffers: None,
+            hooks: None,
+            digest: None,
+            failures: Mutex::new(VecDeque::new()),
+            journal: None,
+            corrupt_files: Vec::new(),
+            mappings: Vec::new(),
+            sync_mode: Default::default(),
+            self_check: false,
+            bulk_bootstrap: true,
+            discrepancies: Mutex::new(VecDeque::new()),
+            maintenance: Default::default(),
+            checksums: None,
+            aggregator: None,
+            counters: Counters::default(),
+            root_guard: None,
+            revalidation: Revalidation::default(),
+            policy: Policy::default(),
+            config_file: None,
+            config_fingerprint: std::sync::Mutex::new(None),
+            port_file: std::sync::Mutex::new(None),
+            confirmations: Confirmations::default(),
+            status_file: None,
+            merge_bases: None,
+            checkpoints: Checkpoints::default(),
+            receptions: Receptions::default(),
+            verify_fetches: verify::Limiter::default(),
+            resumes: Resumes::default(),
+            resumed: std::sync::Mutex::new(None),
+            resyncing: Mutex::new(()),
         })
     }
 }
 
+/// Whether an entry as listed by a storage is the entry known by the tree.
+/// The stats of the directories are computed by the tree, only their kind is compared.
+fn same_state(found: Option<&Metadata>, known: Option<&Metadata>) -> bool {
+    match (found, known) {
+        (None, None) => true,
+        (Some(found), Some(known)) if found.is_dir() || known.is_dir() => {
+            found.is_dir() && known.is_dir()
+        }
+        (Some(found), Some(known)) => {
+            found.size() == known.size()
+                && found.mtime() == known.mtime()
+                && found.link_target() == known.link_target()
+        }
+        _ => false,
+    }
+}
+
+fn tree_conflicts(tree: &DiffTree) -> BTreeSet<PathBuf> {
+    let conflicts = tree.entries().filter_map(|node| match node.entry() {
+        tree::Entry::Sync {
+            conflict: Some(_), ..
+        } => Some(node.key().to_path_buf()),
+        _ => None,
+    });
+    conflict_set(conflicts)
+}
+
+/// The set of the normalized `paths`, built at once rather than by inserting them one by one.
+/// Comparing normalized paths component-wise is comparing their bytes with the separator
+/// ordered first, which is much cheaper, so they are sorted that way before building the set.
+/// The matcher of the filter of `options`, if it has one
+fn entry_matcher(options: &OperateOptions) -> fsync::Result<Option<EntryMatcher>> {
+    let Some(filter) = &options.filter else {
+        return Ok(None);
+    };
+    let matcher = filter
+        .compile()
+        .map_err(|err| fsync::other_error!("{err}"))?;
+    Ok(Some(matcher))
+}
+
+fn conflict_set(paths: impl IntoIterator<Item = PathBuf>) -> BTreeSet<PathBuf> {
+    let separator_first = |path: &PathBuf| -> Vec<u8> {
+        path.as_str()
+            .bytes()
+            .map(|b| if b == b'/' { 0 } else { b })
+            .collect()
+    };
+    let mut paths: Vec<(Vec<u8>, PathBuf)> = paths
+        .into_iter()
+        .map(|path| (separator_first(&path), path))
+        .collect();
+    paths.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    // already sorted, the set only checks the order
+    paths.into_iter().map(|(_, path)| path).collect()
+}
+
 async fn get_tmp_path<S: storage::Exists>(path: &Path, storage: &S) -> PathBuf {
     let base = path.parent().expect("This path should have a parent");
     let file_name = path.file_name().expect("This path should have a name");
-    let attempt1_name = format!("{file_name}.fsync-part");
+    let attempt1_name = format!("{file_name}{}", maintenance::TEMP_SUFFIX);
     let attempt1 = base.join(attempt1_name.as_str());
     if !storage.exists(&attempt1).await.unwrap_or(false) {
         return attempt1;
@@ -125,26 +366,65 @@ where
     Ok(read)
 }
 
+async fn read_head(read: impl io::AsyncRead, max_bytes: u64) -> io::Result<Vec<u8>> {
+    use tokio::io::AsyncReadExt;
+
+    let read = read.take(max_bytes);
+    tokio::pin!(read);
+    let mut head = Vec::new();
+    read.read_to_end(&mut head).await?;
+    Ok(head)
+}
+
 impl<L, R> Service<L, R>
 where
-    L: storage::CreateFile + storage::Exists + storage::MoveEntry + storage::MkDir + storage::Delete,
+    L: storage::CreateFile
+        + storage::Exists
+        + storage::MoveEntry
+        + storage::MkDir
+        + storage::Delete,
     R: storage::ReadFile,
 {
     async fn do_sync_remote_file_to_local(
         &self,
         metadata: &fsync::Metadata,
+        cause: TransferCause,
         progress: &SharedProgress,
+        id: Option<journal::Id>,
     ) -> fsync::Result<()> {
         let path = metadata.path();
         let tmp_path = get_tmp_path(path, &self.local).await;
 
-        debug_assert!(!self.local.exists(path).await.unwrap());
+        debug_assert!(!self.local.exists(path).await.unwrap_or(false));
 
         let read = read_file_with_progress(&self.remote, metadata, progress).await?;
 
-        self.do_ensure_parents(&tmp_path, &self.local, StorageLoc::Local, progress)
+        self.do_ensure_parents(&tmp_path, &self.local, StorageLoc::Local, progress, id)
+            .await?;
+
+        let metadata = self
+            .create_local_file(metadata, tmp_path, read, cause, progress)
             .await?;
+        self.apply(
+            id,
+            Effect::Added {
+                loc: StorageLoc::Local,
+                metadata,
+            },
+        )
+        .await
+    }
 
+    /// Create the local file of `metadata` with the content of `read`, written at `tmp_path`
+    /// then moved in place. The parents must exist, and the tree is left to the caller.
+    async fn create_local_file(
+        &self,
+        metadata: &fsync::Metadata,
+        tmp_path: PathBuf,
+        read: impl io::AsyncRead + Send,
+        cause: TransferCause,
+        progress: &SharedProgress,
+    ) -> fsync::Result<fsync::Metadata> {
         let tmp_metadata = metadata.with_path(tmp_path);
 
         let create_res = self
@@ -162,14 +442,17 @@ where
 
         let metadata = self
             .local
-            .move_entry(&created.path(), metadata.path(), None)
+            .move_entry(created.path(), metadata.path(), None)
             .await?;
-
-        let is_conflict =
-            self.tree
-                .add_to_storage_check_conflict(path, metadata, StorageLoc::Local);
-        self.check_conflict(path, is_conflict).await;
-        Ok(())
+        let size = metadata.size().unwrap_or(0);
+        self.count_transfer(
+            cause,
+            metadata.path(),
+            StorageDir::RemoteToLocal,
+            size,
+            Some(progress),
+        );
+        Ok(metadata)
     }
 }
 
@@ -181,7 +464,9 @@ where
     async fn do_sync_local_file_to_remote(
         &self,
         metadata: &fsync::Metadata,
+        cause: TransferCause,
         progress: &SharedProgress,
+        id: Option<journal::Id>,
     ) -> fsync::Result<()> {
         let path = metadata.path();
 
@@ -189,19 +474,23 @@ where
 
         log::debug!("reporting progress on {path}");
 
-        self.do_ensure_parents(path, &self.remote, fsync::StorageLoc::Remote, progress)
+        self.do_ensure_parents(path, &self.remote, StorageLoc::Remote, progress, id)
             .await?;
 
         let metadata = self
             .remote
-            .create_file(&metadata, read, Some(progress))
-            .await
-            .unwrap();
-        let is_conflict = self
-            .tree
-            .add_to_storage_check_conflict(path, metadata, fsync::StorageLoc::Remote);
-        self.check_conflict(path, is_conflict).await;
-        Ok(())
+            .create_file(metadata, read, Some(progress))
+            .await?;
+        let size = metadata.size().unwrap_or(0);
+        self.count_transfer(cause, path, StorageDir::LocalToRemote, size, Some(progress));
+        self.apply(
+            id,
+            Effect::Added {
+                loc: StorageLoc::Remote,
+                metadata,
+            },
+        )
+        .await
     }
 }
 
@@ -217,20 +506,163 @@ impl<L, R> Service<L, R> {
         }
     }
 
+    /// The write lock of the conflicts, with the wait measured as [`Phase::TreeLock`]
+    async fn conflicts_mut(&self) -> tokio::sync::RwLockWriteGuard<'_, BTreeSet<PathBuf>> {
+        let _span = Span::enter(Phase::TreeLock);
+        self.conflicts.write().await
+    }
+
+    /// Count the `bytes` of the file at `path` transferred in the direction `dir` for `cause`,
+    /// as a retry if its previous transfer failed, with the bytes that the storage sent again
+    fn count_transfer(
+        &self,
+        cause: TransferCause,
+        path: &Path,
+        dir: StorageDir,
+        bytes: u64,
+        progress: Option<&SharedProgress>,
+    ) {
+        match dir {
+            StorageDir::LocalToRemote => self.counters.add_uploaded(bytes),
+            StorageDir::RemoteToLocal => self.counters.add_downloaded(bytes),
+        }
+        let retransmitted = progress.map_or(0, SharedProgress::take_retransmitted);
+        let transfer = if self.counters.is_retry(path) {
+            fsync::TransferCounters {
+                files: 1,
+                bytes: 0,
+                retried_bytes: bytes + retransmitted,
+            }
+        } else {
+            fsync::TransferCounters {
+                files: 1,
+                bytes,
+                retried_bytes: retransmitted,
+            }
+        };
+        self.counters.add_transfer(cause, transfer);
+    }
+
     fn check_node(&self, path: &Path) -> fsync::Result<tree::EntryNode> {
         let path = Self::check_path(path)?;
         let node = self.tree.entry(&path);
-        let node = node.ok_or_else(|| fsync::PathError::NotFound(path, None))?;
+        let node = node.ok_or(fsync::PathError::NotFound(path, None))?;
         Ok(node)
     }
 
-    async fn check_conflict(&self, path: &Path, is_conflict: bool) {
-        let mut conflicts = self.conflicts.write().await;
-        if is_conflict {
-            conflicts.insert(path.to_owned());
+    /// Record whether each path of `updates` is a conflict, under a single write lock.
+    /// The new conflicts are published once the lock is released.
+    async fn check_conflicts_bulk(&self, updates: impl IntoIterator<Item = (PathBuf, bool)>) {
+        let (inserted, removed): (Vec<_>, Vec<_>) = updates
+            .into_iter()
+            .partition(|(_, is_conflict)| *is_conflict);
+        let inserted = inserted.into_iter().map(|(path, _)| path);
+        let detected: Vec<PathBuf> = if inserted.len() < BULK_CONFLICTS {
+            let mut conflicts = self.conflicts_mut().await;
+            for (path, _) in removed {
+                conflicts.remove(&path);
+            }
+            inserted
+                .filter(|path| conflicts.insert(path.clone()))
+                .collect()
         } else {
-            conflicts.remove(path);
+            // sorted before taking the lock, to merge them in linear time
+            let mut inserted = conflict_set(inserted);
+            let mut conflicts = self.conflicts_mut().await;
+            for (path, _) in removed {
+                conflicts.remove(&path);
+            }
+            let detected = match &self.hooks {
+                Some(_) => inserted.difference(&conflicts).cloned().collect(),
+                None => Vec::new(),
+            };
+            conflicts.append(&mut inserted);
+            detected
+        };
+        for path in detected {
+            self.publish_conflict(&path);
+        }
+    }
+
+    fn publish_conflict(&self, path: &Path) {
+        let Some(hooks) = &self.hooks else {
+            return;
+        };
+        let conflict = self
+            .tree
+            .entry(path)
+            .and_then(|node| node.entry().conflict());
+        if let Some(conflict) = conflict {
+            hooks.publish(hooks::Event::ConflictDetected {
+                time: Utc::now(),
+                path: path.to_owned(),
+                conflict,
+            });
+        }
+    }
+
+    /// Apply to the tree `effect`, performed on a storage by the journaled operation `id`
+    async fn apply(&self, id: Option<journal::Id>, effect: Effect) -> fsync::Result<()> {
+        self.apply_all(id, vec![effect]).await
+    }
+
+    /// Apply `effects` in order, as [`Self::apply`] does.
+    /// The conflicts are updated at once after the last effect.
+    async fn apply_all(&self, id: Option<journal::Id>, effects: Vec<Effect>) -> fsync::Result<()> {
+        let mut conflicts = Vec::new();
+        let mut res = Ok(());
+        for effect in effects {
+            res = self.apply_to_tree(id, effect, &mut conflicts).await;
+            if res.is_err() {
+                break;
+            }
+        }
+        self.check_conflicts_bulk(conflicts).await;
+        res
+    }
+
+    /// Apply `effect` to the tree, and push to `conflicts` whether the entries it
+    /// touched are conflicts
+    async fn apply_to_tree(
+        &self,
+        id: Option<journal::Id>,
+        effect: Effect,
+        conflicts: &mut Vec<(PathBuf, bool)>,
+    ) -> fsync::Result<()> {
+        if let (Some(journal), Some(id)) = (&self.journal, id) {
+            journal
+                .effect(id, &effect)
+                .await
+                .map_err(|err| Error::Io(format!("Could not write the journal: {err:#}")))?;
+            journal.crash_point(journal::Stage::Performed);
+        }
+        match effect {
+            Effect::Parents { loc, path } => {
+                conflicts.extend(self.tree.ensure_parents(&path, loc));
+            }
+            Effect::Added { loc, metadata } => {
+                let path = metadata.path().to_owned();
+                let is_conflict = self
+                    .tree
+                    .add_to_storage_check_conflict(&path, metadata, loc);
+                conflicts.push((path, is_conflict));
+            }
+            Effect::Copied { loc, metadata } => {
+                let path = metadata.path().to_owned();
+                let entry = fsync::tree::Entry::new_at(metadata, loc);
+                let node = fsync::tree::EntryNode::new(entry, vec![], stat::Tree::null());
+                self.tree.insert(&path, node);
+            }
+            Effect::Removed { loc, path } => {
+                self.tree.remove_from_storage(&path, loc);
+                conflicts.push((path, false));
+            }
+            Effect::RemovedBoth { path } => {
+                self.tree.remove_subtree(&path);
+                conflicts.push((path, false));
+            }
         }
+        Ok(())
     }
 
     async fn do_ensure_parents<S>(
@@ -239,6 +671,7 @@ impl<L, R> Service<L, R> {
         storage: &S,
         loc: StorageLoc,
         progress: &SharedProgress,
+        id: Option<journal::Id>,
     ) -> fsync::Result<()>
     where
         S: storage::MkDir,
@@ -247,13 +680,14 @@ impl<L, R> Service<L, R> {
         storage
             .mkdir(path.parent().unwrap(), true, Some(progress))
             .await?;
-        let conflicts = self.tree.ensure_parents(path, loc);
-
-        for (path, is_conflict) in conflicts {
-            self.check_conflict(&path, is_conflict).await;
-        }
-
-        Ok(())
+        self.apply(
+            id,
+            Effect::Parents {
+                loc,
+                path: path.to_owned(),
+            },
+        )
+        .await
     }
 
     async fn do_copy<S>(
@@ -263,6 +697,7 @@ impl<L, R> Service<L, R> {
         storage: &S,
         loc: StorageLoc,
         progress: &SharedProgress,
+        id: Option<journal::Id>,
     ) -> fsync::Result<()>
     where
         S: storage::MkDir + storage::CopyFile,
@@ -271,19 +706,20 @@ impl<L, R> Service<L, R> {
         debug_assert!(path.is_absolute() && !path.is_root());
         debug_assert!(metadata_from.is_file());
         debug_assert!(to.is_absolute() && !to.is_root());
-        debug_assert!(!self.tree.has_entry(to));
+        if self.tree.has_entry(to) {
+            // e.g. copied by a previous attempt that did not complete
+            return Err(Error::Other(format!(
+                "{to} already exists, {path} can't be copied there"
+            )));
+        }
 
-        self.do_ensure_parents(path, storage, loc, progress).await?;
+        self.do_ensure_parents(path, storage, loc, progress, id)
+            .await?;
 
         let metadata = storage
             .copy_file(metadata_from.path(), to, Some(progress))
             .await?;
-
-        let entry = fsync::tree::Entry::new_at(metadata, loc);
-        let node = fsync::tree::EntryNode::new(entry, vec![], stat::Tree::null());
-        self.tree.insert(to, node);
-
-        Ok(())
+        self.apply(id, Effect::Copied { loc, metadata }).await
     }
 
     async fn do_mkdir<S>(
@@ -292,6 +728,7 @@ impl<L, R> Service<L, R> {
         storage: &S,
         loc: StorageLoc,
         progress: &SharedProgress,
+        id: Option<journal::Id>,
     ) -> fsync::Result<()>
     where
         S: storage::MkDir,
@@ -300,25 +737,27 @@ impl<L, R> Service<L, R> {
         debug_assert!(path.is_absolute() && !path.is_root());
         debug_assert!(metadata.is_dir());
 
-        self.do_ensure_parents(path, storage, loc, progress).await?;
+        self.do_ensure_parents(path, storage, loc, progress, id)
+            .await?;
 
         storage.mkdir(path, false, Some(progress)).await?;
         let metadata = Metadata::Directory {
             path: path.to_path_buf(),
             stat: Some(stat::Dir::null()),
         };
-        let is_conflict = self.tree.add_to_storage_check_conflict(path, metadata, loc);
-        self.check_conflict(path, is_conflict).await;
-        Ok(())
+        self.apply(id, Effect::Added { loc, metadata }).await
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn do_replace<S, D>(
         &self,
         metadata: &fsync::Metadata,
         src: &S,
         dest: &D,
         dir: StorageDir,
+        cause: TransferCause,
         progress: &SharedProgress,
+        id: Option<journal::Id>,
     ) -> fsync::Result<()>
     where
         S: storage::ReadFile,
@@ -335,11 +774,16 @@ impl<L, R> Service<L, R> {
             });
         });
         let written = dest.write_file(metadata, data, Some(progress)).await?;
-        let is_conflict = self
-            .tree
-            .add_to_storage_check_conflict(path, written, dir.dest());
-        self.check_conflict(path, is_conflict).await;
-        Ok(())
+        let size = written.size().unwrap_or(0);
+        self.count_transfer(cause, path, dir, size, Some(progress));
+        self.apply(
+            id,
+            Effect::Added {
+                loc: dir.dest(),
+                metadata: written,
+            },
+        )
+        .await
     }
 
     async fn do_delete<S>(
@@ -348,14 +792,20 @@ impl<L, R> Service<L, R> {
         storage: &S,
         loc: StorageLoc,
         progress: &SharedProgress,
+        id: Option<journal::Id>,
     ) -> fsync::Result<()>
     where
         S: storage::Delete,
     {
         storage.delete(path, Some(progress)).await?;
-        self.tree.remove_from_storage(path, loc);
-        self.check_conflict(path, false).await;
-        Ok(())
+        self.apply(
+            id,
+            Effect::Removed {
+                loc,
+                path: path.to_owned(),
+            },
+        )
+        .await
     }
 }
 
@@ -366,9 +816,12 @@ where
 {
     /// Poll progresses until all progresses are done.
     /// The progresses are polled every 100ms.
-    /// When a progress is done, it is removed from the list.
+    /// When a progress is done, it is moved from the list to the finished progresses.
     /// The loop exits when the list is empty.
-    async fn progress_poll_loop(progresses: Arc<RwLock<Vec<(PathBuf, SharedProgress)>>>) {
+    async fn progress_poll_loop(
+        progresses: Arc<RwLock<Vec<(PathBuf, SharedProgress)>>>,
+        finished: Arc<std::sync::Mutex<VecDeque<(PathBuf, SharedProgress)>>>,
+    ) {
         log::trace!("Entering progress poll loop");
         let start = std::time::Instant::now();
         loop {
@@ -380,7 +833,11 @@ where
             for i in (0..len).rev() {
                 if progresses[i].1.get().is_done() {
                     log::info!("operation on {} is done", progresses[i].0);
-                    progresses.remove(i);
+                    let mut finished = finished.lock().unwrap();
+                    if finished.len() >= MAX_FINISHED_PROGRESSES {
+                        finished.pop_front();
+                    }
+                    finished.push_back(progresses.remove(i));
                     removed += 1;
                 }
             }
@@ -399,678 +856,7497 @@ where
     async fn add_progress(&self, path: PathBuf, progress: SharedProgress) {
         log::info!("Logging operation progress on {path}");
         let mut progresses = self.progresses.write().await;
+        // the progress of a previous operation on the same path is replaced
+        if let Some(prev) = progresses.iter_mut().find(|(p, _)| *p == path) {
+            prev.1 = progress;
+            return;
+        }
         progresses.push((path, progress));
         if progresses.len() == 1 {
-            tokio::spawn(Self::progress_poll_loop(self.progresses.clone()));
+            tokio::spawn(Self::progress_poll_loop(
+                self.progresses.clone(),
+                self.finished_progresses.clone(),
+            ));
         }
     }
 }
 
-impl<L, R> Service<L, R> {
-    pub fn local(&self) -> &L {
-        &self.local
-    }
-
-    pub fn remote(&self) -> &R {
-        &self.remote
-    }
-
-    pub async fn entry_node(&self, path: &Path) -> Result<Option<fsync::tree::EntryNode>, Error> {
-        let path = Self::check_path(path)?;
-        Ok(self.tree.entry(&path))
+impl<L, R> Service<L, R>
+where
+    R: storage::Relist,
+{
+    /// Record the resumes of the system from sleep, and check the remote storage again after each.
+    /// The attempts of the operations in flight are retried on their own.
+    pub async fn run_resumes(self: Arc<Self>) {
+        let mut resumes = self.resumes.subscribe();
+        while resumes.changed().await.is_ok() {
+            let Some(resume) = *resumes.borrow_and_update() else {
+                continue;
+            };
+            *self.resumed.lock().unwrap() = Some(fsync::Resumed {
+                at: resume.at,
+                asleep_secs: resume.asleep.as_secs(),
+                resyncing: true,
+            });
+            if let Err(err) = self.resync().await {
+                log::warn!("Could not check the remote storage after the resume: {err}");
+            }
+        }
     }
 
-    pub async fn local_path(&self, path: Option<&Path>) -> Result<FsPathBuf, Error> {
-        let path = path.unwrap_or_else(|| Path::root());
-        let path = Self::check_path(path)?;
-        let node = self.tree.entry(&path);
-        if node.is_none() {
-            return Err(Error::Path(PathError::NotFound(path.to_owned(), None)));
+    /// Check whether the remote storage changed while the system was asleep, unless it was
+    /// done since the last resume. The remote folders are then listed again by the aggregation.
+    async fn resync(&self) -> fsync::Result<()> {
+        let _resyncing = self.resyncing.lock().await;
+        let pending = self
+            .resumed
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|resumed| resumed.resyncing);
+        if !pending {
+            return Ok(());
         }
-        let node = node.unwrap();
-        let local_path = match node.entry() {
-            tree::Entry::Local(path) => Some(path.path()),
-            tree::Entry::Sync { local, .. } => Some(local.path()),
-            _ => None,
-        };
-        if local_path.is_none() {
-            return Err(Error::Path(PathError::NotFound(
-                path.to_owned(),
-                Some(fsync::Location::Local),
-            )));
+        if !self.remote.is_current().await? {
+            log::info!("The remote storage changed while the system was asleep");
+            if let Some(aggregator) = &self.aggregator {
+                aggregator.restart(Path::root());
+                aggregator.request(PathBuf::root());
+            }
         }
-        let local_path = local_path.unwrap();
-        Ok(self.local_root.join(local_path.without_root().as_str()))
+        if let Some(resumed) = self.resumed.lock().unwrap().as_mut() {
+            resumed.resyncing = false;
+        }
+        Ok(())
     }
+}
 
-    pub async fn conflicts(
+impl<L, R> Service<L, R>
+where
+    R: storage::Shared,
+{
+    /// The entries under `path` that anyone with the link can access, in tree order
+    pub async fn publicly_shared(
         &self,
-        start: Option<&Path>,
+        path: &Path,
         max_len: usize,
     ) -> fsync::Result<Vec<fsync::tree::Entry>> {
-        let start = start.map(Self::check_path).transpose()?;
-        let conflicts = self.conflicts.read().await;
-        let start_bound = start.map(Bound::Included).unwrap_or(Bound::Unbounded);
-        let conflicts = conflicts
-            .range((start_bound, Bound::Unbounded))
-            .take(max_len)
+        let path = Self::check_path(path)?;
+        let mut entries = vec![];
+        let mut stack = vec![path];
+        while let Some(path) = stack.pop() {
+            if entries.len() >= max_len {
+                break;
+            }
+            let Some(node) = self.tree.entry(&path) else {
+                continue;
+            };
+            stack.extend(node.children().iter().rev().map(|c| path.join(c)));
+            if node.entry().is_local_only() {
+                continue;
+            }
+            if self.remote.sharing(&path).is_some_and(|s| s.is_public()) {
+                entries.push(node.into_entry());
+            }
+        }
+        Ok(entries)
+    }
+
+    /// How the remote entries at `paths` are shared
+    pub fn sharing(&self, paths: &[PathBuf]) -> fsync::Result<Vec<Option<fsync::Sharing>>> {
+        paths
+            .iter()
             .map(|path| {
-                self.tree
-                    .entry(path)
-                    .expect("conflict path should point to valid entry")
-                    .into_entry()
+                let path = Self::check_path(path)?;
+                Ok(self.remote.sharing(&path))
             })
-            .collect();
-        Ok(conflicts)
+            .collect()
     }
 
-    pub async fn progress(&self, path: &Path) -> fsync::Result<Option<fsync::Progress>> {
-        let path = Self::check_path(path)?;
-        let progress = self.progresses.read().await.iter().find_map(|(p, prog)| {
-            if p == &path {
-                Some(prog.get())
-            } else {
-                None
-            }
-        });
-        Ok(progress)
+    /// Whether the remote files at `paths` can be viewed but not downloaded
+    pub fn view_only(&self, paths: &[PathBuf]) -> fsync::Result<Vec<Option<fsync::ViewOnly>>> {
+        paths
+            .iter()
+            .map(|path| {
+                let path = Self::check_path(path)?;
+                Ok(self.remote.view_only(&path))
+            })
+            .collect()
     }
+}
 
-    pub async fn progresses(&self, path: &Path) -> fsync::Result<Vec<(PathBuf, fsync::Progress)>> {
-        let progresses = self.progresses.read().await.clone();
-        Ok(progresses
-            .into_iter()
-            .filter(|(p, _)| path == p || path.is_ancestor_of(p))
-            .map(|(path, prog)| (path, prog.get()))
-            .collect())
+impl<L, R> Service<L, R> {
+    /// Set the percentage of the remote quota above which a warning is emitted
+    pub fn with_quota_warning(self, percent: f64) -> Self {
+        Self {
+            quota_warning: percent,
+            ..self
+        }
     }
-}
 
-async fn track_progress<F, Fut>(
-    path: PathBuf,
-    tx: mpsc::Sender<(PathBuf, SharedProgress)>,
-    f: F,
-) -> fsync::Result<()>
-where
-    F: FnOnce(SharedProgress) -> Fut,
-    Fut: Future<Output = fsync::Result<()>> + Send,
-{
-    let progress = SharedProgress::new();
+    /// Retry `max_retries` times the operations failing with a transient error,
+    /// waiting `first_delay` before the first retry
+    pub fn with_retries(self, max_retries: u32, first_delay: Duration) -> Self {
+        Self {
+            max_retries,
+            retry_delay: first_delay,
+            ..self
+        }
+    }
 
-    tx.send((path, progress.clone()))
-        .await
-        .expect("tx should not be closed");
+    /// Record the actions performed on the storages in `audit`
+    pub fn with_audit_log(self, audit: AuditLog) -> Self {
+        Self {
+            audit: Some(audit),
+            ..self
+        }
+    }
 
-    let res = f(progress.clone()).await;
+    /// Protect the entries pinned in `pins` from deletion and overwriting
+    pub fn with_pins(self, pins: Pins) -> Self {
+        Self { pins, ..self }
+    }
 
-    match res {
-        Ok(()) => {
-            progress.set(Progress::Done);
-            Ok(())
+    /// Create local placeholders for the remote-only files, recorded in `placeholders`.
+    /// The local storage is expected to hide them from its listings.
+    pub fn with_placeholders(self, placeholders: Placeholders) -> Self {
+        Self {
+            placeholders: Some(placeholders),
+            ..self
         }
-        Err(err) => {
-            progress.set(Progress::Err(err.clone()));
-            Err(err)
+    }
+
+    /// Report the initialization phase of the remote storage from `phase`.
+    /// Without it, the remote storage is reported ready.
+    pub fn with_remote_phase(self, phase: watch::Receiver<fsync::RemotePhase>) -> Self {
+        Self {
+            remote_phase: Some(phase),
+            ..self
         }
     }
-}
 
-impl<L, R> Service<L, R>
-where
-    L: storage::LocalStorage,
-    R: storage::Storage,
-{
-    async fn sync_unit(
-        &self,
-        path: &Path,
-        node: &EntryNode,
-        progress: &SharedProgress,
-    ) -> Result<(), Error> {
-        match node.entry() {
-            tree::Entry::Local(metadata) => {
-                if metadata.is_dir() {
-                    self.do_mkdir(metadata, &self.remote, StorageLoc::Remote, progress)
-                        .await
-                } else {
-                    self.do_sync_local_file_to_remote(metadata, progress).await
-                }
-            }
-            tree::Entry::Remote(metadata) => {
-                if metadata.is_dir() {
-                    self.do_mkdir(metadata, &self.local, StorageLoc::Local, progress)
-                        .await
-                } else {
-                    self.do_sync_remote_file_to_local(metadata, progress).await
-                }
-            }
-            tree::Entry::Sync { conflict: None, .. } => Ok(()),
-            tree::Entry::Sync { .. } => Err(fsync::Error::Conflict(path.to_owned())),
+    /// Account the disk usage of the caches with `cache`,
+    /// which also caches the content of remote files read by [`Self::read_head`].
+    pub fn with_disk_cache(self, cache: Arc<DiskCache>) -> Self {
+        Self {
+            disk_cache: Some(cache),
+            ..self
         }
     }
 
-    async fn resolve_unit(
-        &self,
-        path: &Path,
-        node: &EntryNode,
-        method: ResolutionMethod,
-        progress: &SharedProgress,
-    ) -> Result<(), Error> {
-        match node.entry() {
-            tree::Entry::Sync {
-                local,
-                remote,
-                conflict: Some(conflict),
-            } => match (method, conflict) {
-                (ResolutionMethod::DeleteRemote, _)
-                | (ResolutionMethod::DeleteOlder, fsync::Conflict::LocalNewer)
-                | (ResolutionMethod::DeleteNewer, fsync::Conflict::LocalOlder) => {
-                    self.do_delete(path, &self.remote, StorageLoc::Remote, progress)
-                        .await
-                }
-                (ResolutionMethod::DeleteLocal, _)
-                | (ResolutionMethod::DeleteOlder, fsync::Conflict::LocalOlder)
-                | (ResolutionMethod::DeleteNewer, fsync::Conflict::LocalNewer) => {
-                    self.do_delete(path, &self.local, StorageLoc::Local, progress)
-                        .await
-                }
-                (ResolutionMethod::ReplaceRemoteByLocal, _)
-                | (ResolutionMethod::ReplaceOlderByNewer, fsync::Conflict::LocalNewer)
-                | (ResolutionMethod::ReplaceNewerByOlder, fsync::Conflict::LocalOlder) => {
-                    self.do_replace(
-                        local,
+    /// Report the usage of the buffers of the transfers rented from `pool`
+    pub fn with_buffer_pool(self, pool: BufferPool) -> Self {
+        Self {
+            buffers: Some(pool),
+            ..self
+        }
+    }
+
+    /// Retry the attempts of the operations in flight when the system resumes from sleep,
+    /// see [`Self::run_resumes`]
+    pub fn with_resumes(self, resumes: Resumes) -> Self {
+        Self { resumes, ..self }
+    }
+
+    /// Publish the events of the service to `hooks`
+    pub fn with_hooks(self, hooks: Hooks) -> Self {
+        Self {
+            hooks: Some(hooks),
+            ..self
+        }
+    }
+
+    /// Deliver the digests of the conflicts and failed operations with `digest`
+    pub fn with_digest(self, digest: Digest) -> Self {
+        Self {
+            digest: Some(digest),
+            ..self
+        }
+    }
+
+    /// Record the unit operations in `journal` before performing them,
+    /// so that they can be recovered with [`Self::recover`]
+    pub fn with_journal(self, journal: Journal) -> Self {
+        Self {
+            journal: Some(journal),
+            ..self
+        }
+    }
+
+    /// Report in the status the persisted files that were set aside at startup
+    pub fn with_corrupt_files(self, corrupt_files: Vec<fsync::CorruptFile>) -> Self {
+        Self {
+            corrupt_files,
+            ..self
+        }
+    }
+
+    /// Translate the local paths of the remote sub-trees of `mappings`,
+    /// as done by the local storage
+    pub fn with_mappings(self, mappings: Vec<fsync::Mapping>) -> Self {
+        Self { mappings, ..self }
+    }
+
+    /// Withhold the operations modifying a storage that `sync_mode` leaves untouched
+    pub fn with_sync_mode(self, sync_mode: fsync::SyncMode) -> Self {
+        Self { sync_mode, ..self }
+    }
+
+    /// Compare the tree with fresh listings of the storages after each unit operation.
+    /// This is expensive, and meant to catch the bugs that corrupt the tree.
+    pub fn with_self_check(self) -> Self {
+        Self {
+            self_check: true,
+            ..self
+        }
+    }
+
+    /// Whether the deep syncs of a sub-tree that one of the storages has none of, typically
+    /// the first sync of an existing directory, copy it in bulk (the default): the directories
+    /// are created first, the tree is updated by batches without checking the conflicts,
+    /// and the progress is reported by directory rather than by entry.
+    pub fn with_bulk_bootstrap(self, bulk_bootstrap: bool) -> Self {
+        Self {
+            bulk_bootstrap,
+            ..self
+        }
+    }
+
+    /// Schedule and retention of the maintenance task, see [`Self::run_maintenance`]
+    pub fn with_maintenance(self, maintenance: fsync::Maintenance) -> Self {
+        Self {
+            maintenance,
+            ..self
+        }
+    }
+
+    /// Keep the checksums computed by [`Self::checksum`] in `cache`
+    pub fn with_checksums(self, cache: storage::hash::Cache) -> Self {
+        Self {
+            checksums: Some(cache),
+            ..self
+        }
+    }
+
+    /// Keep the versions of the text files last synchronized in `bases`,
+    /// so that their conflicts can be merged
+    pub fn with_merge_bases(self, bases: Bases) -> Self {
+        Self {
+            merge_bases: Some(bases),
+            ..self
+        }
+    }
+
+    /// Aggregate the remote folder sizes in the background with `aggregator`,
+    /// see [`Self::run_aggregation`]
+    pub fn with_aggregation(self, aggregator: Aggregator) -> Self {
+        Self {
+            aggregator: Some(aggregator),
+            ..self
+        }
+    }
+
+    /// Count the activity of the instance with `counters`, persisted by [`Self::run_counters`]
+    pub fn with_counters(self, counters: Counters) -> Self {
+        Self { counters, ..self }
+    }
+
+    /// Refuse the operations while `guard` reports a change of the remote root,
+    /// see [`Self::migrate_root`]
+    pub fn with_root_guard(self, guard: Arc<RootGuard>) -> Self {
+        Self {
+            root_guard: Some(guard),
+            ..self
+        }
+    }
+
+    /// Check the conflicts fetched by the clients again once their state is older than `staleness`
+    pub fn with_conflict_staleness(self, staleness: Duration) -> Self {
+        Self {
+            revalidation: Revalidation::new(staleness),
+            ..self
+        }
+    }
+
+    /// Postpone the scheduled work according to `policy`
+    pub fn with_policy(self, policy: Policy) -> Self {
+        Self { policy, ..self }
+    }
+
+    /// Record the entries left by the scheduled deep operations stopped by their time budget
+    /// in `checkpoints`, see [`crate::checkpoint`]
+    pub fn with_checkpoints(self, checkpoints: Checkpoints) -> Self {
+        Self {
+            checkpoints,
+            ..self
+        }
+    }
+
+    /// Ask the confirmation of the deletions above the thresholds of `guard`,
+    /// see [`confirm`]
+    pub fn with_delete_guard(self, guard: fsync::DeleteGuard) -> Self {
+        Self {
+            confirmations: Confirmations::new(guard),
+            ..self
+        }
+    }
+
+    /// Read the settings applied by [`Self::reload_config`] from `config_file`,
+    /// whose config was loaded with `fingerprint`
+    pub fn with_config_file(self, config_file: FsPathBuf, fingerprint: String) -> Self {
+        Self {
+            config_file: Some(config_file),
+            config_fingerprint: std::sync::Mutex::new(Some(fingerprint)),
+            ..self
+        }
+    }
+
+    /// Keep the status file of the share, written at most once per `interval`,
+    /// see [`status_file`]
+    pub fn with_status_file(self, interval: Duration) -> Self {
+        Self {
+            status_file: Some(StatusFile::new(interval)),
+            ..self
+        }
+    }
+
+    pub fn local(&self) -> &L {
+        &self.local
+    }
+
+    pub fn remote(&self) -> &R {
+        &self.remote
+    }
+
+    pub async fn entry_node(&self, path: &Path) -> Result<Option<fsync::tree::EntryNode>, Error> {
+        let path = Self::check_path(path)?;
+        Ok(self.tree.entry(&path))
+    }
+
+    /// The node at `path`, with at most `max_len` of its children after the one named `after`
+    pub async fn entry_node_page(
+        &self,
+        path: &Path,
+        after: Option<&str>,
+        max_len: usize,
+    ) -> Result<Option<fsync::tree::EntryNode>, Error> {
+        let node = self.entry_node(path).await?;
+        Ok(node.map(|node| node.with_children_page(after, max_len)))
+    }
+
+    /// At most `max_len` child nodes of the directory at `path`, sorted with `order`,
+    /// starting after the child named `after`.
+    /// The sort is applied before the pagination, so that the pages follow each other.
+    pub async fn entry_nodes(
+        &self,
+        path: &Path,
+        order: SortOrder,
+        after: Option<&str>,
+        max_len: usize,
+    ) -> fsync::Result<Vec<EntryNode>> {
+        let node = self.check_node(path)?;
+        let names = node.children_sorted(order);
+        let start = after.map_or(0, |after| {
+            names.partition_point(|name| order.compare(name, after).is_le())
+        });
+        let mut nodes = Vec::new();
+        for name in names[start..].iter().take(max_len) {
+            let child_path = node.path().join(name);
+            if let Some(child) = self.tree.entry(&child_path) {
+                nodes.push(child);
+            }
+        }
+        Ok(nodes)
+    }
+
+    pub async fn set_pinned(&self, path: &Path, pinned: bool) -> fsync::Result<()> {
+        let path = Self::check_path(path)?;
+        self.pins.set(path, pinned).await?;
+        Ok(())
+    }
+
+    pub async fn pinned(&self) -> Vec<PathBuf> {
+        self.pins.list().await
+    }
+
+    pub fn status(&self) -> fsync::Status {
+        let remote = self
+            .remote_phase
+            .as_ref()
+            .map(|phase| phase.borrow().clone())
+            .unwrap_or(fsync::RemotePhase::Ready);
+        fsync::Status {
+            remote,
+            fs_caps: self.tree_options.fs_caps,
+            corrupt_files: self.corrupt_files.clone(),
+            policy: self.policy.state(),
+            checkpoint: self.checkpoints.summary(),
+            config_fingerprint: self.config_fingerprint(),
+            resumed: self.resumed.lock().unwrap().clone(),
+        }
+    }
+
+    pub fn config_fingerprint(&self) -> Option<String> {
+        self.config_fingerprint.lock().unwrap().clone()
+    }
+
+    /// Read the config file again, and apply the settings that can change while running
+    pub async fn reload_config(&self) -> fsync::Result<()> {
+        let Some(config_file) = &self.config_file else {
+            return Err(Error::Other("The service has no config file".into()));
+        };
+        let config = fsync::Config::load_from_file(config_file)
+            .await
+            .map_err(|err| Error::Other(format!("{err:#}")))?;
+        log::info!("Reloaded the deferral policy: {:?}", config.deferral);
+        self.policy.set_deferral(config.deferral);
+
+        let fingerprint = config.fingerprint();
+        log::info!("Config fingerprint: {fingerprint}");
+        *self.config_fingerprint.lock().unwrap() = Some(fingerprint.clone());
+        let port_file = {
+            let mut port_file = self.port_file.lock().unwrap();
+            port_file.as_mut().map(|(path, file)| {
+                file.config_fingerprint = Some(fingerprint);
+                (path.clone(), file.clone())
+            })
+        };
+        if let Some((path, file)) = port_file {
+            write_port_file(path, &file).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn local_path(&self, path: Option<&Path>) -> Result<FsPathBuf, Error> {
+        let path = path.unwrap_or_else(|| Path::root());
+        let path = Self::check_path(path)?;
+        let node = self.tree.entry(&path);
+        if node.is_none() {
+            return Err(Error::Path(PathError::NotFound(path.to_owned(), None)));
+        }
+        let node = node.unwrap();
+        let local_path = match node.entry() {
+            tree::Entry::Local(path) => Some(path.path()),
+            tree::Entry::Sync { local, .. } => Some(local.path()),
+            _ => None,
+        };
+        if local_path.is_none() {
+            return Err(Error::Path(PathError::NotFound(
+                path.to_owned(),
+                Some(fsync::Location::Local),
+            )));
+        }
+        let local_path = fsync::Mapping::map_path(&self.mappings, local_path.unwrap())?;
+        Ok(self.local_root.join(local_path.without_root().as_str()))
+    }
+
+    pub async fn conflicts(
+        &self,
+        start: Option<&Path>,
+        max_len: usize,
+    ) -> fsync::Result<Vec<fsync::tree::Entry>> {
+        let start = start.map(Self::check_path).transpose()?;
+        let start_bound = start.map(Bound::Included).unwrap_or(Bound::Unbounded);
+        // the tree is looked up after releasing the lock, not to delay the updates
+        let paths: Vec<PathBuf> = self
+            .conflicts
+            .read()
+            .await
+            .range((start_bound, Bound::Unbounded))
+            .take(max_len)
+            .cloned()
+            .collect();
+        let conflicts = paths
+            .iter()
+            .filter_map(|path| self.tree.entry(path))
+            .map(|node| node.into_entry())
+            .collect();
+        Ok(conflicts)
+    }
+
+    /// Same as [`Self::conflicts`], for the conflicts selected by `filter`.
+    /// The conflicts are read by batches, and looked up in the tree after releasing the lock.
+    pub async fn conflicts_filtered(
+        &self,
+        start: Option<&Path>,
+        max_len: usize,
+        filter: &EntryFilter,
+    ) -> fsync::Result<Vec<fsync::tree::Entry>> {
+        let matcher = filter
+            .compile()
+            .map_err(|err| fsync::other_error!("{err}"))?;
+        let start = start.map(Self::check_path).transpose()?;
+        let mut bound = start.map(Bound::Included).unwrap_or(Bound::Unbounded);
+        let mut conflicts = Vec::new();
+        while conflicts.len() < max_len {
+            let paths: Vec<PathBuf> = self
+                .conflicts
+                .read()
+                .await
+                .range((bound, Bound::Unbounded))
+                .filter(|path| matcher.is_under(path))
+                .take(FILTERED_CONFLICTS_BATCH)
+                .cloned()
+                .collect();
+            let Some(last) = paths.last() else {
+                break;
+            };
+            bound = Bound::Excluded(last.clone());
+            let left = max_len - conflicts.len();
+            conflicts.extend(
+                paths
+                    .iter()
+                    .filter_map(|path| self.tree.entry(path))
+                    .map(|node| node.into_entry())
+                    .filter(|entry| matcher.matches(entry))
+                    .take(left),
+            );
+            if paths.len() < FILTERED_CONFLICTS_BATCH {
+                break;
+            }
+        }
+        Ok(conflicts)
+    }
+
+    /// The entries skipped for being larger than the size limit, in tree order.
+    /// Only the sub-trees that contain such entries are walked.
+    pub async fn too_large(
+        &self,
+        start: Option<&Path>,
+        max_len: usize,
+    ) -> fsync::Result<Vec<fsync::tree::Entry>> {
+        let start = start.map(Self::check_path).transpose()?;
+        let mut entries = vec![];
+        let mut stack = vec![PathBuf::root()];
+        while let Some(path) = stack.pop() {
+            if entries.len() >= max_len {
+                break;
+            }
+            let Some(node) = self.tree.entry(&path) else {
+                continue;
+            };
+            if node.stats().node.too_large == 0 {
+                continue;
+            }
+            if node.is_too_large() && start.as_ref().is_none_or(|start| &path >= start) {
+                entries.push(node.into_entry());
+                continue;
+            }
+            stack.extend(node.children().iter().rev().map(|c| path.join(c)));
+        }
+        Ok(entries)
+    }
+
+    /// The entries of implausible metadata in the sub-trees at `paths`
+    pub fn implausible_stats(&self, paths: &[PathBuf]) -> fsync::Result<Vec<stat::Flagged>> {
+        paths
+            .iter()
+            .map(|path| {
+                let path = Self::check_path(path)?;
+                let Some(node) = self.tree.entry(&path) else {
+                    return Ok(stat::Flagged::default());
+                };
+                Ok(stat::Flagged {
+                    here: node.is_implausible(),
+                    count: node.stats().node.implausible,
+                })
+            })
+            .collect()
+    }
+
+    /// The files larger than the size limit in the sub-trees at `paths`
+    pub fn too_large_stats(&self, paths: &[PathBuf]) -> fsync::Result<Vec<stat::Flagged>> {
+        paths
+            .iter()
+            .map(|path| {
+                let path = Self::check_path(path)?;
+                let Some(node) = self.tree.entry(&path) else {
+                    return Ok(stat::Flagged::default());
+                };
+                Ok(stat::Flagged {
+                    here: node.is_too_large(),
+                    count: node.stats().node.too_large,
+                })
+            })
+            .collect()
+    }
+
+    /// Whether the names of the remote entries at `paths` clash with the local filesystem
+    pub fn name_clashes(&self, paths: &[PathBuf]) -> fsync::Result<Vec<bool>> {
+        paths
+            .iter()
+            .map(|path| {
+                let path = Self::check_path(path)?;
+                Ok(self
+                    .tree
+                    .entry(&path)
+                    .is_some_and(|node| node.is_name_clash()))
+            })
+            .collect()
+    }
+
+    /// Deliver the digest of the pending conflicts and of the operations that failed
+    /// since the last digest. Unless `force` is set, a digest with nothing new is skipped.
+    /// Returns whether the digest was delivered.
+    pub async fn send_digest(&self, force: bool) -> fsync::Result<bool> {
+        let Some(digest) = &self.digest else {
+            return Err(Error::Other("No digest is configured".into()));
+        };
+        let conflicts = self
+            .conflicts
+            .read()
+            .await
+            .iter()
+            .filter_map(|path| {
+                let conflict = self.tree.entry(path)?.entry().conflict()?;
+                Some((path.clone(), conflict))
+            })
+            .collect();
+        let failures = self.failures.lock().await.iter().cloned().collect();
+        let report = digest::Report::new(conflicts, failures);
+        let sent = digest
+            .deliver(&report, force)
+            .await
+            .map_err(|err| Error::Other(format!("Could not deliver the digest: {err:#}")))?;
+        if sent {
+            self.failures
+                .lock()
+                .await
+                .retain(|failure| failure.time > report.time);
+        }
+        Ok(sent)
+    }
+
+    /// Deliver the digests at the time of the config, until the service is dropped
+    pub async fn run_digest(self: Arc<Self>) {
+        let Some(digest) = &self.digest else {
+            return;
+        };
+        loop {
+            let delay = digest.next_delay(chrono::Local::now());
+            log::debug!(target: "digest", "Next digest in {delay:?}");
+            tokio::time::sleep(delay).await;
+            if let Err(err) = self.send_digest(false).await {
+                log::error!(target: "digest", "{err}");
+            }
+        }
+    }
+
+    /// Persist the lifetime counters every [`counters::PERSIST_INTERVAL`]
+    pub async fn run_counters(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(counters::PERSIST_INTERVAL).await;
+            if let Err(err) = self.counters.persist().await {
+                log::error!("Could not persist the counters: {err:#}");
+            }
+        }
+    }
+
+    pub fn counters(&self) -> fsync::InstanceCounters {
+        self.counters.report()
+    }
+
+    pub fn repairs(&self) -> fsync::InstanceRepairs {
+        self.counters.repairs()
+    }
+
+    pub fn transfers(&self) -> fsync::InstanceTransfers {
+        self.counters.transfers()
+    }
+
+    pub async fn progress(&self, path: &Path) -> fsync::Result<Option<fsync::Progress>> {
+        let path = Self::check_path(path)?;
+        let progress = self.progresses.read().await.iter().find_map(|(p, prog)| {
+            if p == &path {
+                Some(prog.get())
+            } else {
+                None
+            }
+        });
+        Ok(progress)
+    }
+
+    pub async fn progresses(&self, path: &Path) -> fsync::Result<Vec<(PathBuf, fsync::Progress)>> {
+        let progresses = self.progresses.read().await.clone();
+        Ok(progresses
+            .into_iter()
+            .filter(|(p, _)| path == p || path.is_ancestor_of(p))
+            .map(|(path, prog)| (path, prog.get()))
+            .collect())
+    }
+
+    /// The progresses of the operations on `path` and its descendants that changed
+    /// since `cursor`, with the cursor of the current state.
+    /// The completed operations are reported until [`MAX_FINISHED_PROGRESSES`] others complete.
+    /// Nothing is locked nor allocated if no progress changed at all.
+    pub async fn progresses_since(
+        &self,
+        path: &Path,
+        cursor: u64,
+    ) -> fsync::Result<(u64, Vec<(PathBuf, fsync::Progress)>)> {
+        if SharedProgress::changes() == cursor {
+            return Ok((cursor, Vec::new()));
+        }
+        let progresses = self.progresses.read().await;
+        let finished = self.finished_progresses.lock().unwrap();
+        let (changes, changed) = SharedProgress::with_changes(|changes| {
+            // a cursor of a previous run of the daemon
+            let cursor = if cursor > changes { 0 } else { cursor };
+            let changed: Vec<(PathBuf, SharedProgress)> = finished
+                .iter()
+                .chain(progresses.iter())
+                .filter(|(p, prog)| {
+                    prog.changed() > cursor && (path == p || path.is_ancestor_of(p))
+                })
+                .cloned()
+                .collect();
+            (changes, changed)
+        });
+        drop(finished);
+        drop(progresses);
+        let changed = changed
+            .into_iter()
+            .map(|(path, prog)| (path, prog.get()))
+            .collect();
+        Ok((changes, changed))
+    }
+}
+
+/// Add the result of the unit operation on `path` to the report of a deep operation.
+/// The failures that the other entries may not hit are counted, the others are returned.
+fn tally_unit(
+    report: &mut OperationReport,
+    path: &Path,
+    res: fsync::Result<OperationReport>,
+) -> fsync::Result<()> {
+    match res {
+        Ok(unit_report) => *report += unit_report,
+        Err(Error::Pinned(pinned)) => {
+            log::warn!("{path}: {pinned} is pinned, skipped");
+            report.skipped_pinned += 1;
+        }
+        Err(Error::NotDownloadable { .. }) => {
+            log::info!("{path}: can be viewed but not downloaded, skipped");
+            report.skipped_not_downloadable += 1;
+        }
+        Err(err) if !err.is_transient() => {
+            log::error!("{path}: {err}, continuing with the other entries");
+            report.failed += 1;
+        }
+        Err(err) => return Err(err),
+    }
+    Ok(())
+}
+
+/// Why `operation` transfers the content of the file of `node`
+fn transfer_cause(operation: &Operation, node: &EntryNode) -> TransferCause {
+    if node.entry().conflict() == Some(fsync::Conflict::ContentMismatch) {
+        TransferCause::Verification
+    } else if counters::is_resumed() {
+        TransferCause::Resume
+    } else if matches!(
+        operation,
+        Operation::Resolve(..) | Operation::ResolveDeep(..)
+    ) {
+        TransferCause::Conflict
+    } else {
+        TransferCause::Sync
+    }
+}
+
+/// The progress of a step of a deep operation.
+/// The progress of the operation root is reused, others are sent to be tracked.
+async fn step_progress(
+    root: &Path,
+    path: &Path,
+    root_progress: &SharedProgress,
+    tx: &mpsc::Sender<(PathBuf, SharedProgress)>,
+) -> SharedProgress {
+    if path == root {
+        return root_progress.clone();
+    }
+    let progress = SharedProgress::new();
+    tx.send((path.to_owned(), progress.clone()))
+        .await
+        .expect("tx should not be closed");
+    progress
+}
+
+/// Track the progress of the operation on `path`, attempted by calling `f` with the
+/// progress and the attempt number.
+/// The operation is retried at most `max_retries` times while it fails with a transient error,
+/// waiting `delay` before the first retry, and twice as long before each following one.
+async fn track_progress<F, Fut>(
+    path: PathBuf,
+    tx: mpsc::Sender<(PathBuf, SharedProgress)>,
+    max_retries: u32,
+    mut delay: Duration,
+    mut f: F,
+) -> fsync::Result<OperationReport>
+where
+    F: FnMut(SharedProgress, u32) -> Fut,
+    Fut: Future<Output = fsync::Result<OperationReport>> + Send,
+{
+    let progress = SharedProgress::new();
+
+    tx.send((path.clone(), progress.clone()))
+        .await
+        .expect("tx should not be closed");
+
+    let mut attempt = 1;
+    loop {
+        match f(progress.clone(), attempt).await {
+            Ok(report) => {
+                progress.set(Progress::done(report));
+                return Ok(report);
+            }
+            Err(err) if err.is_transient() && attempt <= max_retries => {
+                log::warn!("{path}: {err}, retrying in {delay:?}");
+                progress.set(Progress::Waiting(format!("{err}, retrying in {delay:?}")));
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+                attempt += 1;
+            }
+            Err(err) => {
+                progress.set(Progress::failed(err.clone(), attempt));
+                return Err(err);
+            }
+        }
+    }
+}
+
+impl<L, R> Service<L, R>
+where
+    L: storage::LocalStorage,
+    R: storage::Storage,
+{
+    async fn operate_unit(
+        &self,
+        operation: Operation,
+        node: EntryNode,
+        options: OperateOptions,
+        progress: SharedProgress,
+    ) -> fsync::Result<OperationReport> {
+        log::trace!("Operate unit: {operation:?}");
+        let path = operation.path();
+        let _span = Span::enter(Phase::Unit)
+            .with_detail(|| format!("{operation:?}"))
+            .with_bytes(node.entry().size().max(0) as u64);
+        let mut res = self.act(&operation, &node, &options, &progress).await;
+        if let Err(err) = &res {
+            let refused = matches!(err, Error::NotDownloadable { .. });
+            if !refused && self.revalidate(&operation, &node, &options).await {
+                // planned again for what is left of the entry
+                match self.tree.entry(path) {
+                    Some(node)
+                        if plan::unit_action(&operation, &node, &options, self.sync_mode)
+                            .is_some() =>
+                    {
+                        log::warn!("{path}: {err}. The entry changed since the tree was built, operating again");
+                        res = self.act(&operation, &node, &options, &progress).await;
+                    }
+                    _ => {
+                        log::warn!("{path}: {err}. The entry vanished since the tree was built, nothing left to do");
+                        res = Ok(OperationReport {
+                            skipped_vanished: 1,
+                            ..OperationReport::default()
+                        });
+                    }
+                }
+            }
+        }
+        if let Err(err) = self.drop_stale_placeholder(path).await {
+            log::warn!("{path}: could not delete the placeholder: {err}");
+        }
+        let res = match res {
+            Err(err)
+                if self
+                    .collect_ghost(Some(&operation), path)
+                    .await
+                    .unwrap_or(false) =>
+            {
+                log::warn!("{path}: {err}. Entry was deleted on both sides, nothing left to do");
+                Ok(OperationReport::default())
+            }
+            // a restriction of the storage, not a failure to report
+            Err(err @ Error::NotDownloadable { .. }) => Err(err),
+            Err(err) => {
+                let failure = digest::Failure {
+                    time: Utc::now(),
+                    operation: operation.clone(),
+                    path: path.to_owned(),
+                    error: err.to_string(),
+                };
+                if let Some(hooks) = &self.hooks {
+                    hooks.publish(hooks::Event::Error {
+                        time: failure.time,
+                        operation: failure.operation.clone(),
+                        path: failure.path.clone(),
+                        error: failure.error.clone(),
+                    });
+                }
+                if self.digest.is_some() {
+                    let mut failures = self.failures.lock().await;
+                    if failures.len() == digest::MAX_FAILURES {
+                        failures.pop_front();
+                    }
+                    failures.push_back(failure);
+                }
+                Err(err)
+            }
+            Ok(report) => Ok(report),
+        };
+        self.counters.add_operation(res.is_ok());
+        if self.self_check {
+            self.check_entry(path).await;
+        }
+        res
+    }
+
+    /// Compare the entry at `path` and its children with fresh listings of the storages.
+    /// The discrepancies are logged, and kept for [`Self::self_check`].
+    async fn check_entry(&self, path: &Path) {
+        let found = self
+            .tree
+            .check(&self.local, &self.remote, path, false, &self.tree_options)
+            .await;
+        let found = match found {
+            Ok(found) => found,
+            Err(err) => {
+                log::warn!("{path}: could not check the tree: {err:#}");
+                return;
+            }
+        };
+        let mut discrepancies = self.discrepancies.lock().await;
+        for discrepancy in found {
+            log::error!("Self-check: {discrepancy}");
+            if discrepancies.len() == MAX_DISCREPANCIES {
+                discrepancies.pop_front();
+            }
+            discrepancies.push_back(discrepancy);
+        }
+    }
+
+    /// Perform the action planned for `operation` on `node`, if any
+    async fn act(
+        &self,
+        operation: &Operation,
+        node: &EntryNode,
+        options: &OperateOptions,
+        progress: &SharedProgress,
+    ) -> fsync::Result<OperationReport> {
+        let path = operation.path();
+        let Some(action) = plan::unit_action(operation, node, options, self.sync_mode) else {
+            return Ok(OperationReport::default());
+        };
+        if matches!(action, Action::SkipTooLarge) {
+            log::warn!("{path}: larger than the size limit, skipped");
+            return Ok(OperationReport {
+                skipped_too_large: 1,
+                ..OperationReport::default()
+            });
+        }
+        if matches!(action, Action::SkipWithheld) {
+            log::debug!("{path}: withheld by the {} sync mode", self.sync_mode);
+            return Ok(OperationReport {
+                skipped_withheld: 1,
+                ..OperationReport::default()
+            });
+        }
+        let downloads = match &action {
+            Action::Copy(dir) | Action::Replace(dir) => dir.dest() == StorageLoc::Local,
+            Action::CopyLocalAndReplace | Action::MergeText => true,
+            _ => false,
+        };
+        if downloads {
+            // refused by the storage anyway
+            if let Some(view_only) = self.remote.view_only(path) {
+                return Err(Error::NotDownloadable {
+                    path: path.to_owned(),
+                    web_view_link: view_only.web_view_link,
+                });
+            }
+        }
+        let destructive = matches!(
+            action,
+            Action::Delete(..)
+                | Action::Replace(..)
+                | Action::CopyLocalAndReplace
+                | Action::MergeText
+        );
+        if destructive {
+            // checked before touching the storages
+            if let Some(pinned) = self.pins.pinned_within(path).await {
+                return Err(Error::Pinned(pinned));
+            }
+        }
+        let cause = transfer_cause(operation, node);
+        let res = self
+            .perform(path, node, action.clone(), cause, progress)
+            .await;
+        if let Err(err) = res {
+            let transfers = matches!(
+                action,
+                Action::Copy(..)
+                    | Action::Replace(..)
+                    | Action::CopyLocalAndReplace
+                    | Action::MergeText
+            );
+            if transfers {
+                // the next transfer of the file sends its content again
+                self.counters.fail_transfer(path);
+            }
+            return Err(err);
+        }
+        self.counters.end_transfer(path);
+        self.acted(operation, node, action).await;
+        Ok(OperationReport::default())
+    }
+
+    /// Record `action`, performed by `operation` on `node`
+    async fn acted(&self, operation: &Operation, node: &EntryNode, action: Action) {
+        let path = operation.path();
+        self.record_merge_base(path, node, &action).await;
+        self.audit(Some(operation), path, node, &action).await;
+        if let Some(hooks) = &self.hooks {
+            hooks.publish(hooks::Event::OperationDone {
+                time: Utc::now(),
+                operation: operation.clone(),
+                path: path.to_owned(),
+                action,
+            });
+        }
+    }
+
+    /// Check, after the action planned for `operation` on `node` failed, that the
+    /// files it reads or deletes are still in their storage. A file deleted since the
+    /// tree was built, e.g. by the user while the operation was queued, is removed
+    /// from the tree. Returns whether the tree changed, in which case the operation
+    /// must be planned again.
+    async fn revalidate(
+        &self,
+        operation: &Operation,
+        node: &EntryNode,
+        options: &OperateOptions,
+    ) -> bool {
+        let path = operation.path();
+        let sources = match plan::unit_action(operation, node, options, self.sync_mode) {
+            Some(Action::Copy(dir) | Action::Replace(dir)) => vec![dir.src()],
+            Some(Action::Delete(Location::Local)) => vec![StorageLoc::Local],
+            Some(Action::Delete(Location::Remote)) => vec![StorageLoc::Remote],
+            Some(
+                Action::CopyLocalAndReplace | Action::MergeText | Action::Delete(Location::Both),
+            ) => {
+                vec![StorageLoc::Local, StorageLoc::Remote]
+            }
+            _ => return false,
+        };
+        let mut changed = false;
+        for loc in sources {
+            // a vanished directory leaves its descendants to the other units
+            let is_file = self
+                .tree
+                .entry(path)
+                .and_then(|node| node.into_entry().into_metadata(loc))
+                .is_some_and(|md| !md.is_dir());
+            if !is_file {
+                continue;
+            }
+            let found = match loc {
+                StorageLoc::Local => tree::storage_entry(&self.local, path).await,
+                StorageLoc::Remote => tree::storage_entry(&self.remote, path).await,
+            };
+            match found {
+                Ok(Some(_)) => (),
+                Ok(None) => {
+                    log::warn!(
+                        "{path}: no longer in the {loc:?} storage, removing it from the tree"
+                    );
+                    let effect = Effect::Removed {
+                        loc,
+                        path: path.to_owned(),
+                    };
+                    match self.apply(None, effect).await {
+                        Ok(()) => changed = true,
+                        Err(err) => log::error!("{path}: could not update the tree: {err}"),
+                    }
+                }
+                Err(err) => log::debug!("{path}: could not check the {loc:?} storage: {err}"),
+            }
+        }
+        changed
+    }
+
+    /// Perform `action` on the storages and apply its effects to the tree, in the journal.
+    /// If the action fails, the tree is refreshed from the storages at the targets of the
+    /// action, so that it reflects what was done before the failure.
+    async fn perform(
+        &self,
+        path: &Path,
+        node: &EntryNode,
+        action: Action,
+        cause: TransferCause,
+        progress: &SharedProgress,
+    ) -> fsync::Result<()> {
+        let targets = journal_targets(path, &action);
+        let journal = self.journal.as_ref().filter(|_| !targets.is_empty());
+        let Some(journal) = journal else {
+            return self
+                .perform_effects(path, node, action, cause, progress, None)
+                .await;
+        };
+        let id = journal
+            .begin(targets.clone())
+            .await
+            .map_err(|err| Error::Io(format!("Could not write the journal: {err:#}")))?;
+        journal.crash_point(journal::Stage::Begun);
+
+        let res = self
+            .perform_effects(path, node, action, cause, progress, Some(id))
+            .await;
+        if let Err(err) = &res {
+            log::debug!(target: "journal", "{path}: {err}, refreshing the tree");
+            if let Err(err) = self.refresh(&targets).await {
+                // left pending, the refresh is done again at the next start
+                log::error!(target: "journal", "{path}: could not refresh the tree: {err}");
+                return res;
+            }
+        }
+        journal.crash_point(journal::Stage::Applied);
+        if let Err(err) = journal.complete(id).await {
+            log::error!(target: "journal", "Could not write the journal: {err:#}");
+        }
+        res
+    }
+
+    async fn perform_effects(
+        &self,
+        path: &Path,
+        node: &EntryNode,
+        action: Action,
+        cause: TransferCause,
+        progress: &SharedProgress,
+        id: Option<journal::Id>,
+    ) -> fsync::Result<()> {
+        let metadata = |loc| {
+            node.entry()
+                .clone()
+                .into_metadata(loc)
+                .expect("action should be consistent with the entry")
+        };
+        match action {
+            Action::Mkdir(StorageLoc::Remote) => {
+                let local = metadata(StorageLoc::Local);
+                self.do_mkdir(&local, &self.remote, StorageLoc::Remote, progress, id)
+                    .await
+            }
+            Action::Mkdir(StorageLoc::Local) => {
+                let remote = metadata(StorageLoc::Remote);
+                self.do_mkdir(&remote, &self.local, StorageLoc::Local, progress, id)
+                    .await
+            }
+            Action::Copy(StorageDir::LocalToRemote) => {
+                let local = metadata(StorageLoc::Local);
+                self.do_sync_local_file_to_remote(&local, cause, progress, id)
+                    .await
+            }
+            Action::Copy(StorageDir::RemoteToLocal) => {
+                let remote = metadata(StorageLoc::Remote);
+                self.do_sync_remote_file_to_local(&remote, cause, progress, id)
+                    .await
+            }
+            Action::Replace(StorageDir::LocalToRemote) => {
+                if self
+                    .do_patch_identical(node, StorageDir::LocalToRemote, id)
+                    .await?
+                {
+                    return Ok(());
+                }
+                let local = metadata(StorageLoc::Local);
+                self.do_replace(
+                    &local,
+                    &self.local,
+                    &self.remote,
+                    StorageDir::LocalToRemote,
+                    cause,
+                    progress,
+                    id,
+                )
+                .await
+            }
+            Action::Replace(StorageDir::RemoteToLocal) => {
+                if self
+                    .do_patch_identical(node, StorageDir::RemoteToLocal, id)
+                    .await?
+                {
+                    return Ok(());
+                }
+                let remote = metadata(StorageLoc::Remote);
+                self.do_replace(
+                    &remote,
+                    &self.remote,
+                    &self.local,
+                    StorageDir::RemoteToLocal,
+                    cause,
+                    progress,
+                    id,
+                )
+                .await
+            }
+            Action::CopyLocalAndReplace => {
+                let local = metadata(StorageLoc::Local);
+                self.do_copy(
+                    &local,
+                    &copy_path(path),
+                    &self.local,
+                    StorageLoc::Local,
+                    progress,
+                    id,
+                )
+                .await?;
+                let remote = metadata(StorageLoc::Remote);
+                self.do_replace(
+                    &remote,
+                    &self.remote,
+                    &self.local,
+                    StorageDir::RemoteToLocal,
+                    cause,
+                    progress,
+                    id,
+                )
+                .await
+            }
+            Action::MergeText => self.do_merge_text(path, cause, progress, id).await,
+            Action::Delete(Location::Local) => {
+                self.do_delete(path, &self.local, StorageLoc::Local, progress, id)
+                    .await
+            }
+            Action::Delete(Location::Remote) => {
+                self.do_delete(path, &self.remote, StorageLoc::Remote, progress, id)
+                    .await
+            }
+            Action::Delete(Location::Both) => {
+                let local = self.local().delete(path, Some(progress));
+                let remote = self.remote().delete(path, Some(progress));
+                futures::try_join!(local, remote)?;
+                self.apply(
+                    id,
+                    Effect::RemovedBoth {
+                        path: path.to_owned(),
+                    },
+                )
+                .await
+            }
+            Action::Fail(err) => Err(err),
+            Action::SkipTooLarge | Action::SkipWithheld => unreachable!("skipped by operate_unit"),
+            Action::Forget | Action::SharePublic | Action::Pair => {
+                unreachable!("not planned by the operations")
+            }
+        }
+    }
+
+    /// Merge the local and remote versions of the text file at `path` from the version
+    /// last synchronized, and write the result to both storages.
+    /// The conflict is left as is if the merge fails.
+    async fn do_merge_text(
+        &self,
+        path: &Path,
+        cause: TransferCause,
+        progress: &SharedProgress,
+        id: Option<journal::Id>,
+    ) -> fsync::Result<()> {
+        let max_bytes = fsync::text::MAX_MERGE_SIZE + 1;
+        let local = self
+            .local
+            .read_file(path.to_owned(), Some(progress))
+            .await?;
+        let local = read_head(local, max_bytes).await?;
+        let remote = self
+            .remote
+            .read_file(path.to_owned(), Some(progress))
+            .await?;
+        let remote = read_head(remote, max_bytes).await?;
+        let read = remote.len() as u64;
+        self.count_transfer(cause, path, StorageDir::RemoteToLocal, read, Some(progress));
+
+        let base = match &self.merge_bases {
+            Some(bases) => bases.get(path).await.unwrap_or_else(|err| {
+                log::warn!("{path}: could not read the base of the merge: {err}");
+                None
+            }),
+            None => None,
+        };
+        let merged = merge::merge_bytes(base.as_deref(), &local, &remote)
+            .map_err(|err| Error::Unresolved(path.to_owned(), err.to_string()))?;
+
+        let metadata = Metadata::Regular {
+            path: path.to_owned(),
+            size: merged.len() as u64,
+            mtime: Utc::now(),
+            link_target: None,
+        };
+        let local = self
+            .local
+            .write_file(&metadata, &merged[..], Some(progress))
+            .await?;
+        self.apply(
+            id,
+            Effect::Added {
+                loc: StorageLoc::Local,
+                metadata: local.clone(),
+            },
+        )
+        .await?;
+        let remote = self
+            .remote
+            .write_file(&local, &merged[..], Some(progress))
+            .await?;
+        let written = merged.len() as u64;
+        self.count_transfer(
+            cause,
+            path,
+            StorageDir::LocalToRemote,
+            written,
+            Some(progress),
+        );
+        self.apply(
+            id,
+            Effect::Added {
+                loc: StorageLoc::Remote,
+                metadata: remote,
+            },
+        )
+        .await?;
+        log::info!("{path}: local and remote edits merged");
+        Ok(())
+    }
+
+    /// Keep the content of the text file at `path` after `action` synchronized it,
+    /// as base of the merge of its next conflict
+    async fn record_merge_base(&self, path: &Path, node: &EntryNode, action: &Action) {
+        let Some(bases) = &self.merge_bases else {
+            return;
+        };
+        let src = match action {
+            Action::Copy(dir) | Action::Replace(dir) => dir.src(),
+            Action::CopyLocalAndReplace => StorageLoc::Remote,
+            _ => return,
+        };
+        let candidate = node
+            .entry()
+            .clone()
+            .into_metadata(src)
+            .filter(|md| md.is_file() && !fsync::text::has_binary_extension(path))
+            .and_then(|md| md.size())
+            .is_some_and(|size| size <= fsync::text::MAX_MERGE_SIZE);
+        if !candidate {
+            return;
+        }
+        let res = async {
+            let read = self.local.read_file(path.to_owned(), None).await?;
+            let content = read_head(read, fsync::text::MAX_MERGE_SIZE + 1).await?;
+            bases.record(path, &content).await?;
+            fsync::Result::Ok(())
+        };
+        if let Err(err) = res.await {
+            log::debug!("{path}: could not record the base of the merge: {err}");
+        }
+    }
+
+    /// Refresh the tree from the operations left incomplete in the journal,
+    /// typically because the daemon stopped in the middle of them.
+    /// Returns the number of recovered operations.
+    pub async fn recover(&self) -> fsync::Result<usize> {
+        let Some(journal) = &self.journal else {
+            return Ok(0);
+        };
+        let pending = journal.pending().await;
+        for op in &pending {
+            log::warn!(
+                target: "journal",
+                "Recovering incomplete operation {} ({} effects performed)",
+                op.id,
+                op.effects.len()
+            );
+            self.refresh(&op.targets).await?;
+            journal
+                .complete(op.id)
+                .await
+                .map_err(|err| Error::Io(format!("Could not write the journal: {err:#}")))?;
+        }
+        Ok(pending.len())
+    }
+
+    /// Update the tree according to the state of `targets` and of their ancestors in the storages
+    async fn refresh(&self, targets: &[journal::Target]) -> fsync::Result<()> {
+        for target in targets {
+            match target.loc {
+                StorageLoc::Local => self.refresh_at(&self.local, target).await?,
+                StorageLoc::Remote => self.refresh_at(&self.remote, target).await?,
+            }
+        }
+        Ok(())
+    }
+
+    async fn refresh_at<S>(&self, storage: &S, target: &journal::Target) -> fsync::Result<()>
+    where
+        S: storage::DirEntries,
+    {
+        let loc = target.loc;
+        let mut paths = vec![];
+        let mut path = Some(target.path.as_path());
+        while let Some(p) = path.filter(|p| !p.is_root()) {
+            paths.push(p);
+            path = p.parent();
+        }
+        // from the top, as an entry can only be in the tree if its parent is
+        for path in paths.into_iter().rev() {
+            let metadata = tree::storage_entry(storage, path)
+                .await?
+                .map(|md| match md {
+                    // the stats of the directory are accounted by its children
+                    Metadata::Directory { path, .. } => Metadata::Directory {
+                        path,
+                        stat: Some(stat::Dir::null()),
+                    },
+                    md => md,
+                });
+            let node = self.tree.entry(path);
+            match (metadata, node) {
+                (Some(metadata), Some(node)) => {
+                    if metadata.is_dir() && node.entry().is_at_loc(loc) {
+                        continue;
+                    }
+                    self.apply(None, Effect::Added { loc, metadata }).await?;
+                }
+                (Some(metadata), None) => {
+                    log::info!(target: "journal", "{path}: found in the {loc:?} storage");
+                    self.apply(None, Effect::Copied { loc, metadata }).await?;
+                }
+                (None, Some(node)) if node.entry().is_at_loc(loc) => {
+                    log::info!(target: "journal", "{path}: missing from the {loc:?} storage");
+                    let path = path.to_owned();
+                    self.apply(None, Effect::Removed { loc, path }).await?;
+                    break;
+                }
+                (None, _) => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Repair the conflicts only due to a local modification time shifted by whole hours,
+    /// as when a filesystem storing local times (e.g. FAT) is read across a DST change.
+    /// The local files with the same size and content as the remote file get
+    /// the remote modification time, nothing is transferred.
+    /// Returns the repaired paths.
+    pub async fn repair_shifted_mtimes(&self) -> fsync::Result<Vec<PathBuf>> {
+        let granularity = self.tree.mtime_granularity();
+        let shifted: Vec<(Metadata, Metadata)> = self
+            .conflicts
+            .read()
+            .await
+            .iter()
+            .filter_map(|path| match self.tree.entry(path)?.into_entry() {
+                tree::Entry::Sync {
+                    local,
+                    remote,
+                    conflict: Some(fsync::Conflict::LocalNewer | fsync::Conflict::LocalOlder),
+                } if local.size() == remote.size()
+                    && fsync::is_timezone_shift(local.mtime()?, remote.mtime()?, granularity) =>
+                {
+                    Some((local, remote))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let mut repaired = Vec::new();
+        for (local, remote) in shifted {
+            let path = local.path().to_owned();
+            match self.same_content(&local, &remote).await {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(err) => {
+                    log::warn!("{path}: could not compare the local and remote contents: {err}");
+                    continue;
+                }
+            }
+            let mtime = remote.mtime().expect("a file should have a mtime");
+            log::info!(
+                "{path}: local modification time shifted from {}, set back to {mtime}",
+                local.mtime().unwrap()
+            );
+            let patch = storage::MetadataPatch::mtime(mtime);
+            let patched = self.local.patch(&path, &patch).await?;
+            let metadata = patch.merge(&local, &patched);
+            self.apply(
+                None,
+                Effect::Added {
+                    loc: StorageLoc::Local,
+                    metadata,
+                },
+            )
+            .await?;
+            repaired.push(path);
+        }
+        Ok(repaired)
+    }
+
+    /// Pair the conflicting files of the same size and content, as left when the daemon
+    /// stopped after an upload committed the remote file, but before the tree was updated.
+    /// The local files get the remote modification time, nothing is transferred.
+    /// The repairs are counted, and recorded in the audit log.
+    /// Returns the repaired paths.
+    pub async fn repair_identical_conflicts(&self) -> fsync::Result<Vec<PathBuf>> {
+        let paths: Vec<PathBuf> = self.conflicts.read().await.iter().cloned().collect();
+        self.repair_identical(&paths).await
+    }
+
+    /// Same as [`Self::repair_identical_conflicts`], for the entries at `paths`
+    async fn repair_identical(&self, paths: &[PathBuf]) -> fsync::Result<Vec<PathBuf>> {
+        let mut repaired = Vec::new();
+        for path in paths {
+            let Some(node) = self.tree.entry(path) else {
+                continue;
+            };
+            let (local, remote) = match node.into_entry() {
+                tree::Entry::Sync {
+                    local,
+                    remote,
+                    conflict: Some(fsync::Conflict::LocalNewer | fsync::Conflict::LocalOlder),
+                } if local.size() == remote.size() => (local, remote),
+                _ => continue,
+            };
+            match self.same_content(&local, &remote).await {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(err) => {
+                    log::warn!("{path}: could not compare the local and remote contents: {err}");
+                    continue;
+                }
+            }
+            let mtime = remote.mtime().expect("a file should have a mtime");
+            log::info!("{path}: same content on both sides, paired without transfer");
+            let patch = storage::MetadataPatch::mtime(mtime);
+            let patched = self.local.patch(path, &patch).await?;
+            let metadata = patch.merge(&local, &patched);
+            self.apply(
+                None,
+                Effect::Added {
+                    loc: StorageLoc::Local,
+                    metadata,
+                },
+            )
+            .await?;
+            self.counters.add_repair();
+            if let Some(node) = self.tree.entry(path) {
+                self.audit(None, path, &node, &Action::Pair).await;
+            }
+            repaired.push(path.clone());
+        }
+        Ok(repaired)
+    }
+
+    /// Whether the local and remote files have the same content, by their MD5 checksum.
+    /// The remote file is read only if the remote storage reports no checksum.
+    async fn same_content(&self, local: &Metadata, remote: &Metadata) -> fsync::Result<bool> {
+        let remote_md5 = match self.remote_md5(remote).await? {
+            Some(md5) => md5,
+            None => {
+                let algo = fsync::HashAlgo::Md5;
+                storage::hash::hash_file(&self.remote, remote, algo, None, None, None)
+                    .await?
+                    .hex
+            }
+        };
+        self.local_matches(local, &remote_md5).await
+    }
+
+    /// The MD5 checksum of the `remote` file reported by the remote storage, if any
+    async fn remote_md5(&self, remote: &Metadata) -> fsync::Result<Option<String>> {
+        match self.remote.known_md5(remote.path()) {
+            Some(md5) => Ok(Some(md5)),
+            None => self.remote.fetch_md5(remote.path()).await,
+        }
+    }
+
+    /// Whether the content of the `local` file has the MD5 checksum `md5`
+    async fn local_matches(&self, local: &Metadata, md5: &str) -> fsync::Result<bool> {
+        let local = storage::hash::hash_file(
+            &self.local,
+            local,
+            fsync::HashAlgo::Md5,
+            self.checksums.as_ref(),
+            None,
+            None,
+        )
+        .await?;
+        Ok(local.hex.eq_ignore_ascii_case(md5))
+    }
+
+    /// Replace the file of the entry `node` in the direction `dir` by only setting
+    /// the modification time of the destination file, if it has the same content as
+    /// the source file. The content is compared with the checksum reported by the
+    /// remote storage, and not compared if there is none.
+    /// Returns whether the file was patched, in which case nothing was transferred.
+    async fn do_patch_identical(
+        &self,
+        node: &EntryNode,
+        dir: StorageDir,
+        id: Option<journal::Id>,
+    ) -> fsync::Result<bool> {
+        let entry = node.entry();
+        let (Some(local), Some(remote)) = (
+            entry.clone().into_metadata(StorageLoc::Local),
+            entry.clone().into_metadata(StorageLoc::Remote),
+        ) else {
+            return Ok(false);
+        };
+        if local.size() != remote.size() {
+            return Ok(false);
+        }
+        let path = local.path();
+        let same = match self.remote_md5(&remote).await {
+            Ok(Some(md5)) => self.local_matches(&local, &md5).await,
+            Ok(None) => Ok(false),
+            Err(err) => Err(err),
+        };
+        match same {
+            Ok(true) => (),
+            Ok(false) => return Ok(false),
+            Err(err) => {
+                log::warn!("{path}: could not compare the local and remote contents: {err}");
+                return Ok(false);
+            }
+        }
+
+        let (src, dest) = match dir {
+            StorageDir::LocalToRemote => (&local, &remote),
+            StorageDir::RemoteToLocal => (&remote, &local),
+        };
+        let mtime = src.mtime().expect("a file should have a mtime");
+        log::info!("{path}: same content on both sides, setting the modification time to {mtime}");
+        let patch = storage::MetadataPatch::mtime(mtime);
+        let patched = match dir {
+            StorageDir::LocalToRemote => self.remote.patch(path, &patch).await?,
+            StorageDir::RemoteToLocal => self.local.patch(path, &patch).await?,
+        };
+        let metadata = patch.merge(dest, &patched);
+        self.apply(
+            id,
+            Effect::Added {
+                loc: dir.dest(),
+                metadata,
+            },
+        )
+        .await?;
+        Ok(true)
+    }
+
+    /// Record a performed action in the audit log, if there is one.
+    /// Failing to write the log does not fail the operation.
+    async fn audit(
+        &self,
+        operation: Option<&Operation>,
+        path: &Path,
+        node: &EntryNode,
+        action: &Action,
+    ) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        let size = |loc| {
+            node.entry()
+                .clone()
+                .into_metadata(loc)
+                .and_then(|md| md.size())
+                .unwrap_or(0)
+        };
+        let data = |loc| node.stats().by_loc(loc).data.max(0) as u64;
+        let bytes = match action {
+            Action::Copy(dir) | Action::Replace(dir) => size(dir.src()),
+            Action::CopyLocalAndReplace | Action::MergeText => {
+                size(StorageLoc::Local) + size(StorageLoc::Remote)
+            }
+            Action::Delete(Location::Local) => data(StorageLoc::Local),
+            Action::Delete(Location::Remote) => data(StorageLoc::Remote),
+            Action::Delete(Location::Both) => data(StorageLoc::Local) + data(StorageLoc::Remote),
+            _ => 0,
+        };
+        if let Err(err) = audit.append(operation, path, action, bytes).await {
+            log::error!("Could not write the audit log: {err:#}");
+        }
+    }
+
+    /// Check whether the entry at `path` still exists in the storages it is recorded in.
+    /// An entry that exists in none of them was deleted on both sides outside of fsyncd.
+    /// It is then removed, with its descendants, from the tree and from the conflicts.
+    /// Returns whether the entry was removed.
+    async fn collect_ghost(
+        &self,
+        operation: Option<&Operation>,
+        path: &Path,
+    ) -> fsync::Result<bool> {
+        if path.is_root() {
+            return Ok(false);
+        }
+        let Some(node) = self.tree.entry(path) else {
+            return Ok(false);
+        };
+        let entry = node.entry().clone();
+        if let Some(local) = entry.clone().into_local_metadata() {
+            if self.local.exists(local.path()).await? {
+                return Ok(false);
+            }
+        }
+        if let Some(remote) = entry.into_remote_metadata() {
+            if self.remote.exists(remote.path()).await? {
+                return Ok(false);
+            }
+        }
+        self.forget_ghost(operation, &node).await;
+        Ok(true)
+    }
+
+    /// Remove `node`, deleted on both sides outside of fsyncd, with its descendants
+    /// from the tree and from the conflicts, and record it in the audit log.
+    async fn forget_ghost(&self, operation: Option<&Operation>, node: &EntryNode) {
+        let path = node.path();
+        log::warn!("{path} was deleted on both sides, removing it from the tree");
+        let removed = self.tree.remove_subtree(path);
+        {
+            let mut conflicts = self.conflicts_mut().await;
+            for path in removed {
+                conflicts.remove(&path);
+            }
+        }
+        self.audit(operation, path, node, &Action::Forget).await;
+    }
+
+    /// Clean up the files left behind: the quarantined files older than the retention,
+    /// the temporary files of the tree that no operation in flight will rename,
+    /// the stale partial downloads, and the oldest records of the audit log beyond its cap.
+    /// With `dry_run`, the files are only reported.
+    pub async fn maintain(&self, dry_run: bool) -> fsync::Result<fsync::MaintenanceReport> {
+        use fsync::{Cleanup, CleanupKind};
+
+        let mut cleanups = Vec::new();
+        if let Some(cache) = &self.disk_cache {
+            let now = SystemTime::now();
+            let quarantine_cutoff = now
+                .checked_sub(
+                    self.maintenance
+                        .quarantine_retention()
+                        .to_std()
+                        .unwrap_or_default(),
+                )
+                .unwrap_or(std::time::UNIX_EPOCH);
+            let partial_cutoff = now - maintenance::STALE_PARTIAL;
+            for (kind, dir, cutoff) in [
+                (
+                    CleanupKind::Quarantine,
+                    &cache.paths().quarantine,
+                    quarantine_cutoff,
+                ),
+                (
+                    CleanupKind::PartialDownload,
+                    &cache.paths().temp,
+                    partial_cutoff,
+                ),
+            ] {
+                for (path, bytes) in maintenance::files_older_than(dir, cutoff).await {
+                    if !dry_run {
+                        if let Err(err) = tokio::fs::remove_file(&path).await {
+                            log::warn!(target: "maintenance", "could not delete {path}: {err}");
+                            continue;
+                        }
+                    }
+                    cleanups.push(Cleanup {
+                        kind,
+                        path: path.into_string(),
+                        bytes,
+                    });
+                }
+            }
+        }
+
+        for (path, bytes) in self.temp_files() {
+            let name = path.file_name().unwrap_or_default();
+            let target = path
+                .parent()
+                .expect("Non-root path should have a parent")
+                .join(maintenance::temp_target(name).unwrap_or_default());
+            if self.is_in_flight(&target).await {
+                continue;
+            }
+            if !dry_run {
+                if let Err(err) = self.local.delete(&path, None).await {
+                    log::warn!(target: "maintenance", "could not delete {path}: {err}");
+                    continue;
+                }
+                self.tree.remove_subtree(&path);
+            }
+            cleanups.push(Cleanup {
+                kind: CleanupKind::TempFile,
+                path: path.into_string(),
+                bytes,
+            });
+        }
+
+        if let Some(audit) = &self.audit {
+            match audit
+                .compact(self.maintenance.audit_max_size, dry_run)
+                .await
+            {
+                Ok(0) => (),
+                Ok(bytes) => cleanups.push(Cleanup {
+                    kind: CleanupKind::AuditLog,
+                    path: audit.path().to_string(),
+                    bytes,
+                }),
+                Err(err) => {
+                    log::warn!(target: "maintenance", "could not compact the audit log: {err:#}")
+                }
+            }
+        }
+
+        for cleanup in &cleanups {
+            maintenance::log_cleanup(cleanup, dry_run);
+        }
+        Ok(fsync::MaintenanceReport { dry_run, cleanups })
+    }
+
+    /// Run the maintenance at the interval of the config, until the service is dropped.
+    /// A run is postponed until the operations in flight are done,
+    /// and while the deferral policy postpones the scheduled work.
+    pub async fn run_maintenance(self: Arc<Self>) {
+        let Some(interval) = self.maintenance.interval() else {
+            return;
+        };
+        loop {
+            log::debug!(target: "maintenance", "Next maintenance in {interval:?}");
+            tokio::time::sleep(interval).await;
+            loop {
+                if self.is_operating().await {
+                    tokio::time::sleep(maintenance::BUSY_RETRY).await;
+                } else if let Some(reason) = self.policy.deferred() {
+                    log::debug!(target: "maintenance", "Postponed: {reason}");
+                    tokio::time::sleep(policy::RETRY).await;
+                } else {
+                    break;
+                }
+            }
+            match self.maintain(false).await {
+                Ok(report) => log::info!(
+                    target: "maintenance",
+                    "Freed {} bytes in {} cleanups",
+                    report.freed(),
+                    report.cleanups.len()
+                ),
+                Err(err) => log::error!(target: "maintenance", "{err}"),
+            }
+        }
+    }
+
+    /// How complete the remote stats of the folders at `paths` are.
+    /// The entries that are not remote folders, and all the entries
+    /// when the aggregation is off, are reported exact.
+    pub fn aggregation(&self, paths: &[PathBuf]) -> fsync::Result<Vec<stat::Aggregation>> {
+        paths
+            .iter()
+            .map(|path| {
+                let path = Self::check_path(path)?;
+                Ok(match &self.aggregator {
+                    Some(aggregator) if self.is_remote_dir(&path) => aggregator.status(&path),
+                    _ => stat::Aggregation::Exact,
+                })
+            })
+            .collect()
+    }
+
+    /// List again the remote sub-tree at `path` before the rest of the tree.
+    /// Does nothing if the aggregation is off.
+    pub fn aggregate_now(&self, path: &Path) -> fsync::Result<()> {
+        let path = Self::check_path(path)?;
+        if !self.is_remote_dir(&path) {
+            return Err(Error::Path(PathError::NotFound(
+                path,
+                Some(fsync::Location::Remote),
+            )));
+        }
+        if let Some(aggregator) = &self.aggregator {
+            log::info!(target: "aggregate", "Aggregating {path} first");
+            aggregator.restart(&path);
+            aggregator.request(path);
+        }
+        Ok(())
+    }
+
+    /// Aggregate the remote folder sizes in the background, until the service is dropped.
+    /// The remote folders are listed again depth-first, at most once per the minimum
+    /// interval of the aggregator, the folders requested with [`Self::aggregate_now`] first.
+    /// The aggregation pauses while operations are in flight.
+    pub async fn run_aggregation(self: Arc<Self>) {
+        let Some(aggregator) = &self.aggregator else {
+            return;
+        };
+        // the sub-folders of an expanded folder are above it in the stack
+        let mut stack = vec![(PathBuf::root(), false)];
+        let mut last_listing: Option<tokio::time::Instant> = None;
+        loop {
+            if let Some(path) = aggregator.try_next_request().await {
+                stack.push((path, false));
+            }
+            let Some((path, expanded)) = stack.pop() else {
+                log::info!(target: "aggregate", "The remote folder sizes are exact");
+                match aggregator.next_request().await {
+                    Some(path) => stack.push((path, false)),
+                    None => return,
+                }
+                continue;
+            };
+            if expanded {
+                self.check_exact(aggregator, &path);
+                continue;
+            }
+            if aggregator.is_exact(&path) || !self.is_remote_dir(&path) {
+                continue;
+            }
+            if aggregator.status(&path) == stat::Aggregation::Pending {
+                while self.is_operating().await {
+                    tokio::time::sleep(aggregate::BUSY_RETRY).await;
+                }
+                if let Some(last) = last_listing {
+                    tokio::time::sleep_until(last + aggregator.min_interval()).await;
+                }
+                last_listing = Some(tokio::time::Instant::now());
+                match self.aggregate_dir(aggregator, &path).await {
+                    Ok(()) => (),
+                    Err(err) if err.is_transient() => {
+                        let delay = self.retry_delay;
+                        log::warn!(target: "aggregate", "{path}: {err}, retrying in {delay:?}");
+                        tokio::time::sleep(delay).await;
+                        stack.push((path, false));
+                        continue;
+                    }
+                    Err(err) => {
+                        log::error!(target: "aggregate", "{path}: {err}");
+                        continue;
+                    }
+                }
+            }
+            let subdirs = self.remote_subdirs(&path);
+            stack.push((path, true));
+            stack.extend(subdirs.into_iter().rev().map(|dir| (dir, false)));
+        }
+    }
+
+    /// List again the remote folder at `path` and update the tree with the listing
+    async fn aggregate_dir(&self, aggregator: &Aggregator, path: &Path) -> fsync::Result<()> {
+        let loc = StorageLoc::Remote;
+        let listed = self.remote.relist(path).await?;
+        let names: HashSet<String> = listed.iter().map(|md| md.name().to_owned()).collect();
+        let mut effects = Vec::new();
+        // the remote files found at the path of local-only entries
+        let mut unexpected = Vec::new();
+        for metadata in listed {
+            let child = metadata.path().to_owned();
+            if self
+                .tree_options
+                .ignore
+                .is_ignored(&child, metadata.is_dir())
+            {
+                continue;
+            }
+            let node = self.tree.entry(&child);
+            let current = node
+                .as_ref()
+                .and_then(|node| node.entry().clone().into_remote_metadata());
+            if current.is_none() && node.is_some() && metadata.is_file() {
+                // e.g. uploaded by an operation that did not update the tree
+                unexpected.push(child.clone());
+            }
+            match (node, current) {
+                (None, _) => {
+                    log::info!(target: "aggregate", "{child}: found in the remote storage");
+                    let metadata = match metadata {
+                        Metadata::Directory { path, .. } => {
+                            Metadata::Directory { path, stat: None }
+                        }
+                        md => md,
+                    };
+                    effects.push(Effect::Copied { loc, metadata });
+                }
+                (Some(_), Some(current)) if current.is_dir() && metadata.is_dir() => (),
+                (Some(_), Some(current))
+                    if current.is_file()
+                        && metadata.is_file()
+                        && current.size() == metadata.size()
+                        && current.mtime() == metadata.mtime() => {}
+                (Some(_), _) => {
+                    log::info!(target: "aggregate", "{child}: changed in the remote storage");
+                    let metadata = match metadata {
+                        // the stats of the directory are accounted by its children
+                        Metadata::Directory { path, .. } => Metadata::Directory {
+                            path,
+                            stat: Some(stat::Dir::null()),
+                        },
+                        md => md,
+                    };
+                    effects.push(Effect::Added { loc, metadata });
+                }
+            }
+        }
+        let children: Vec<String> = self
+            .tree
+            .entry(path)
+            .map(|node| node.children().iter().map(|c| c.to_string()).collect())
+            .unwrap_or_default();
+        for name in children.into_iter().filter(|name| !names.contains(name)) {
+            let child = path.join(&name);
+            let is_remote = self
+                .tree
+                .entry(&child)
+                .is_some_and(|node| node.entry().is_at_loc(loc));
+            if is_remote {
+                log::info!(target: "aggregate", "{child}: missing from the remote storage");
+                aggregator.forget(&child);
+                effects.push(Effect::Removed { loc, path: child });
+            }
+        }
+        self.apply_all(None, effects).await?;
+        for path in self.repair_identical(&unexpected).await? {
+            log::info!(target: "aggregate", "{path}: paired with the local file");
+        }
+        aggregator.set_listed(path);
+        Ok(())
+    }
+
+    /// Mark the listed folder at `path` exact if all its remote sub-folders are,
+    /// and then its ancestors likewise
+    fn check_exact(&self, aggregator: &Aggregator, path: &Path) {
+        let mut path = Some(path);
+        while let Some(dir) = path {
+            let is_exact = aggregator.status(dir) != stat::Aggregation::Pending
+                && self
+                    .remote_subdirs(dir)
+                    .iter()
+                    .all(|sub| aggregator.is_exact(sub));
+            if !is_exact {
+                break;
+            }
+            aggregator.set_exact(dir);
+            path = dir.parent();
+        }
+    }
+
+    /// Whether the entry at `path` is a folder in the remote storage, according to the tree
+    fn is_remote_dir(&self, path: &Path) -> bool {
+        self.tree.entry(path).is_some_and(|node| {
+            node.into_entry()
+                .into_remote_metadata()
+                .is_some_and(|md| md.is_dir())
+        })
+    }
+
+    /// The remote sub-folders of the folder at `path`, according to the tree
+    fn remote_subdirs(&self, path: &Path) -> Vec<PathBuf> {
+        let Some(node) = self.tree.entry(path) else {
+            return Vec::new();
+        };
+        node.children()
+            .iter()
+            .map(|c| path.join(c))
+            .filter(|child| self.is_remote_dir(child))
+            .collect()
+    }
+
+    /// The local-only temporary files of the tree, with their size
+    fn temp_files(&self) -> Vec<(PathBuf, u64)> {
+        let mut files = Vec::new();
+        let mut stack = vec![PathBuf::root()];
+        while let Some(path) = stack.pop() {
+            let Some(node) = self.tree.entry(&path) else {
+                continue;
+            };
+            let children = node.children().iter().map(|c| path.join(c));
+            stack.extend(children);
+            if let fsync::tree::Entry::Local(metadata) = node.entry() {
+                if metadata.is_file() && maintenance::temp_target(metadata.name()).is_some() {
+                    files.push((path, metadata.size().unwrap_or(0)));
+                }
+            }
+        }
+        files.sort();
+        files
+    }
+
+    /// Whether an operation in flight covers `path`
+    async fn is_in_flight(&self, path: &Path) -> bool {
+        self.progresses
+            .read()
+            .await
+            .iter()
+            .any(|(p, prog)| (p == path || p.is_ancestor_of(path)) && !prog.get().is_done())
+    }
+
+    /// Remove from the tree all entries that were deleted on both sides outside of fsyncd.
+    /// Returns the removed entries, not including their descendants.
+    /// Calling it again without changes in the storages removes nothing.
+    ///
+    /// The children of each folder are checked at once in each storage,
+    /// rather than with a request per entry.
+    pub async fn gc_tree(&self) -> fsync::Result<Vec<PathBuf>> {
+        let mut removed = vec![];
+        let mut stack = vec![PathBuf::root()];
+        while let Some(dir) = stack.pop() {
+            let Some(node) = self.tree.entry(&dir) else {
+                continue;
+            };
+            let names: Vec<String> = node.children().iter().map(|c| c.to_string()).collect();
+            if names.is_empty() {
+                continue;
+            }
+            let entry = node.into_entry();
+            let (local, remote) = future::try_join(
+                existing_children(&self.local, entry.clone().into_local_metadata(), &names),
+                existing_children(&self.remote, entry.into_remote_metadata(), &names),
+            )
+            .await?;
+            for name in names {
+                let path = dir.join(&name);
+                let Some(child) = self.tree.entry(&path) else {
+                    continue;
+                };
+                let in_local = child.entry().is_at_loc(StorageLoc::Local) && local.contains(&name);
+                let in_remote =
+                    child.entry().is_at_loc(StorageLoc::Remote) && remote.contains(&name);
+                if in_local || in_remote {
+                    stack.push(path);
+                } else {
+                    self.forget_ghost(None, &child).await;
+                    removed.push(path);
+                }
+            }
+        }
+        removed.sort_unstable();
+        log::info!("Tree garbage collection removed {} entries", removed.len());
+        Ok(removed)
+    }
+
+    /// The discrepancies found after the operations since the previous call, in the self-check mode
+    pub async fn take_discrepancies(&self) -> Vec<fsync::Discrepancy> {
+        self.discrepancies.lock().await.drain(..).collect()
+    }
+
+    /// Whether an operation is running, and may update the tree
+    async fn is_operating(&self) -> bool {
+        self.progresses.read().await.iter().any(|(_, prog)| {
+            !matches!(
+                prog.get(),
+                Progress::Done
+                    | Progress::DoneWithReport(..)
+                    | Progress::Err(..)
+                    | Progress::Failed(..)
+            )
+        })
+    }
+
+    /// Compare the whole tree with fresh listings of the storages.
+    /// Returns the discrepancies found after the operations since the previous call,
+    /// in the self-check mode, followed by those of the whole tree.
+    /// Refused while an operation runs, as the tree would lag behind the storages.
+    pub async fn self_check(&self) -> fsync::Result<Vec<fsync::Discrepancy>> {
+        if self.is_operating().await {
+            return Err(Error::Other(
+                "Cannot check the tree while an operation is running".into(),
+            ));
+        }
+        let mut found = self.take_discrepancies().await;
+        let whole = self
+            .tree
+            .check(
+                &self.local,
+                &self.remote,
+                Path::root(),
+                true,
+                &self.tree_options,
+            )
+            .await?;
+        for discrepancy in &whole {
+            log::error!("Self-check: {discrepancy}");
+        }
+        log::info!("Self-check: {} discrepancies in the tree", whole.len());
+        found.extend(whole);
+        Ok(found)
+    }
+
+    /// Same as [`Self::self_check`], by pages of top-level entries starting after `after`.
+    /// The page ends with the first top-level entry checked past `deadline`, so that at
+    /// least one entry is checked by each call.
+    pub async fn self_check_until(
+        &self,
+        after: Option<&str>,
+        deadline: SystemTime,
+    ) -> fsync::Result<fsync::SelfCheckPage> {
+        if self.is_operating().await {
+            return Err(Error::Other(
+                "Cannot check the tree while an operation is running".into(),
+            ));
+        }
+        let mut page = fsync::SelfCheckPage::default();
+        if after.is_none() {
+            page.discrepancies = self.take_discrepancies().await;
+            // the top-level entries themselves, the folders are checked deep below
+            let top = self
+                .tree
+                .check(
+                    &self.local,
+                    &self.remote,
+                    Path::root(),
+                    false,
+                    &self.tree_options,
+                )
+                .await?;
+            page.discrepancies.extend(top);
+        }
+        // the other top-level entries were compared above
+        let mut dirs: Vec<String> = self
+            .tree
+            .entry(Path::root())
+            .map(|node| node.children().iter().cloned().collect())
+            .unwrap_or_default();
+        dirs.retain(|name| {
+            self.tree
+                .entry(&Path::root().join(name))
+                .is_some_and(|node| node.entry().is_safe_dir())
+        });
+        dirs.sort_unstable();
+        let start = after.map_or(0, |after| {
+            dirs.partition_point(|name| name.as_str() <= after)
+        });
+        let dirs = &dirs[start..];
+        for (idx, name) in dirs.iter().enumerate() {
+            let path = Path::root().join(name);
+            let found = self
+                .tree
+                .check(&self.local, &self.remote, &path, true, &self.tree_options)
+                .await?;
+            // the folder itself was compared with the top-level entries
+            page.discrepancies
+                .extend(found.into_iter().filter(|d| d.path != path));
+            if SystemTime::now() >= deadline && idx + 1 < dirs.len() {
+                page.next = Some(name.clone());
+                break;
+            }
+        }
+        for discrepancy in &page.discrepancies {
+            log::error!("Self-check: {discrepancy}");
+        }
+        Ok(page)
+    }
+
+    /// Catch up with the local changes made outside of fsyncd in the directory at `path`.
+    /// Refused while an operation runs, as it would race with the tree updates.
+    pub async fn rescan(&self, path: &Path, deep: bool) -> fsync::Result<fsync::RescanReport> {
+        let path = Self::check_path(path)?;
+        if self.is_operating().await {
+            return Err(Error::Other(
+                "Cannot rescan while an operation is running".into(),
+            ));
+        }
+        let is_local_dir = self.tree.entry(&path).is_some_and(|node| {
+            node.into_entry()
+                .into_local_metadata()
+                .is_some_and(|md| md.is_dir())
+        });
+        if !is_local_dir {
+            return Err(Error::Path(PathError::NotFound(
+                path,
+                Some(fsync::Location::Local),
+            )));
+        }
+        let report = self
+            .tree
+            .rescan_local(&self.local, &self.remote, &path, deep, &self.tree_options)
+            .await?;
+        if !report.is_empty() {
+            *self.conflicts_mut().await = tree_conflicts(&self.tree);
+            self.repair_shifted_mtimes().await?;
+            self.repair_identical_conflicts().await?;
+        }
+        self.update_placeholders().await?;
+        Ok(report)
+    }
+
+    /// Verify the content of the synchronized files of the sub-tree at `path`, in tree order,
+    /// starting after the file at `after`. The page ends with the first file verified past
+    /// `deadline`, and is recorded as the checkpoint of the verification.
+    /// The files that differ are marked as [`fsync::Conflict::ContentMismatch`].
+    pub async fn verify(
+        &self,
+        path: &Path,
+        deep: bool,
+        after: Option<&Path>,
+        deadline: SystemTime,
+    ) -> fsync::Result<fsync::VerificationReport> {
+        let path = self.check_node(path)?.path().to_owned();
+        if self.is_operating().await {
+            return Err(Error::Other(
+                "Cannot verify while an operation is running".into(),
+            ));
+        }
+        let mut walk = match after {
+            Some(after) => tree::Walk::after(path.clone(), &Self::check_path(after)?),
+            None => tree::Walk::new(path.clone(), fsync::OrderBy::TreeOrder),
+        };
+        let mut report = fsync::VerificationReport::default();
+        while let Some(step) = walk.next(&self.tree) {
+            let tree::Step::Leaf(node) = step else {
+                continue;
+            };
+            let file = node.path();
+            if let tree::Entry::Sync {
+                local,
+                remote,
+                conflict: None,
+            } = node.entry()
+            {
+                // a hard link has the content of the first link, which is verified on its own
+                if local.is_file() && remote.is_file() && local.link_target().is_none() {
+                    let outcome = verify::verify_file(
                         &self.local,
                         &self.remote,
-                        StorageDir::LocalToRemote,
-                        progress,
+                        local,
+                        remote,
+                        deep,
+                        &self.verify_fetches,
                     )
+                    .await;
+                    match outcome {
+                        Ok(Outcome::Same) => report.verified += 1,
+                        Ok(Outcome::Differ) => {
+                            log::warn!("{file}: the local and remote content differ");
+                            if self.tree.mark_content_mismatch(file) {
+                                self.check_conflicts_bulk([(file.to_owned(), true)]).await;
+                            }
+                            report.mismatched.push(file.to_owned());
+                        }
+                        Ok(Outcome::Unverifiable(reason)) => {
+                            report.unverifiable.push((file.to_owned(), reason))
+                        }
+                        Err(err) => report.unverifiable.push((file.to_owned(), err.to_string())),
+                    }
+                }
+            }
+            if SystemTime::now() >= deadline {
+                report.next = Some(file.to_owned());
+                break;
+            }
+        }
+        let checkpoint = report.next.clone().map(|next| fsync::VerifyCheckpoint {
+            path: path.clone(),
+            deep,
+            next,
+            created: Utc::now(),
+        });
+        self.checkpoints.set_verification(checkpoint).await?;
+        log::info!(
+            "{path}: {} files verified, {} differ, {} could not be verified",
+            report.verified,
+            report.mismatched.len(),
+            report.unverifiable.len()
+        );
+        Ok(report)
+    }
+
+    /// Where the last verification stopped
+    pub fn verify_checkpoint(&self) -> Option<fsync::VerifyCheckpoint> {
+        self.checkpoints.verification()
+    }
+
+    /// Check again in the storages the conflicts at `paths` whose state is stale,
+    /// or all of them if `forced`, except the ones checked during the cooldown.
+    /// The tree is updated where the storages changed, e.g. because the user resolved the
+    /// conflict outside of fsyncd. Returns the paths that are no longer conflicts.
+    pub async fn revalidate_conflicts(
+        &self,
+        paths: &[PathBuf],
+        forced: bool,
+    ) -> fsync::Result<Vec<PathBuf>> {
+        if self.is_operating().await {
+            if forced {
+                return Err(Error::Other(
+                    "Cannot revalidate the conflicts while an operation is running".into(),
+                ));
+            }
+            // checked again at the next fetch
+            return Ok(Vec::new());
+        }
+        let mut resolved = Vec::new();
+        for path in paths {
+            let path = Self::check_path(path)?;
+            if !self.conflicts.read().await.contains(&path) {
+                continue;
+            }
+            if !self.revalidation.is_due(&path, forced) {
+                continue;
+            }
+            self.revalidate_conflict(&path).await?;
+            if !self.conflicts.read().await.contains(&path) {
+                log::info!("{path}: the conflict was resolved outside of fsyncd");
+                resolved.push(path);
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Check the conflicts at `paths` in the background if their state is stale,
+    /// so that the next fetch reflects the conflicts resolved outside of fsyncd
+    pub fn schedule_revalidation(self: &Arc<Self>, paths: Vec<PathBuf>) {
+        if paths.is_empty() {
+            return;
+        }
+        let service = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = service.revalidate_conflicts(&paths, false).await {
+                log::warn!("Could not check the conflicts again: {err}");
+            }
+        });
+    }
+
+    /// Refresh the tree at `path` in the storages whose entry differs from the tree.
+    /// The remote entry is checked in the cache of the remote storage, if it has one.
+    async fn revalidate_conflict(&self, path: &Path) -> fsync::Result<()> {
+        let Some(node) = self.tree.entry(path) else {
+            return Ok(());
+        };
+        let mut targets = Vec::new();
+        for loc in [StorageLoc::Local, StorageLoc::Remote] {
+            let found = match loc {
+                StorageLoc::Local => tree::storage_entry(&self.local, path).await?,
+                StorageLoc::Remote => tree::storage_entry(&self.remote, path).await?,
+            };
+            let known = node.entry().clone().into_metadata(loc);
+            if !same_state(found.as_ref(), known.as_ref()) {
+                log::info!("{path}: changed in the {loc:?} storage outside of fsyncd");
+                targets.push(journal::Target {
+                    loc,
+                    path: path.to_owned(),
+                });
+            }
+        }
+        self.refresh(&targets).await
+    }
+
+    /// Create the placeholders of the remote-only files whose folder exists locally,
+    /// and delete the placeholders that became stale.
+    /// A placeholder deleted by the user is not created again.
+    /// Does nothing if the placeholder mode is off.
+    pub async fn update_placeholders(&self) -> fsync::Result<()> {
+        let Some(placeholders) = &self.placeholders else {
+            return Ok(());
+        };
+        for path in placeholders.list().await {
+            self.drop_stale_placeholder(&path).await?;
+        }
+
+        let remote_only: Vec<_> = self
+            .tree
+            .entries()
+            .filter_map(|node| match node.entry() {
+                tree::Entry::Remote(metadata) if metadata.is_file() => Some(metadata.clone()),
+                _ => None,
+            })
+            .collect();
+        for remote in remote_only {
+            let path = remote.path();
+            if placeholders.contains(path).await {
+                continue;
+            }
+            let parent = path.parent().expect("Non-root path should have a parent");
+            let parent_is_local = self.tree.entry(parent).is_some_and(|node| {
+                node.into_entry()
+                    .into_local_metadata()
+                    .is_some_and(|md| md.is_dir())
+            });
+            if !parent_is_local {
+                continue;
+            }
+            let descriptor =
+                placeholders::Descriptor::new(&remote).expect("Should be a regular file");
+            let data = serde_json::to_vec_pretty(&descriptor)
+                .map_err(|err| Error::Bug(err.to_string()))?;
+            let metadata = fsync::Metadata::Regular {
+                path: placeholders::placeholder_path(path),
+                size: data.len() as _,
+                mtime: descriptor.mtime,
+                link_target: None,
+            };
+            if !self.local.exists(metadata.path()).await? {
+                log::info!("{path}: creating placeholder");
+                self.local.create_file(&metadata, &data[..], None).await?;
+            }
+            placeholders.set(path.to_owned(), true).await?;
+        }
+        Ok(())
+    }
+
+    /// Delete the placeholder of `path` if the entry is no longer a remote-only file,
+    /// typically because it was downloaded or created locally since.
+    async fn drop_stale_placeholder(&self, path: &Path) -> fsync::Result<()> {
+        let Some(placeholders) = &self.placeholders else {
+            return Ok(());
+        };
+        if !placeholders.contains(path).await {
+            return Ok(());
+        }
+        let remote_only = self
+            .tree
+            .entry(path)
+            .is_some_and(|node| matches!(node.entry(), tree::Entry::Remote(md) if md.is_file()));
+        if remote_only {
+            return Ok(());
+        }
+        let placeholder = placeholders::placeholder_path(path);
+        if self.local.exists(&placeholder).await? {
+            log::info!("{path}: deleting stale placeholder");
+            self.local.delete(&placeholder, None).await?;
+        }
+        placeholders.set(path.to_owned(), false).await?;
+        Ok(())
+    }
+
+    /// Perform a deep operation by walking the sub-tree lazily.
+    /// At most [`MAX_CONCURRENT_UNITS`] unit operations run concurrently.
+    /// A unit operation waits for the operations on its ancestors to complete,
+    /// or on its descendants if the children are processed first.
+    /// An entry failing with a permanent error is counted in the report, and the operation
+    /// continues with the others. The operation stops at the first transient error,
+    /// once the running unit operations complete, so that it can be attempted again.
+    /// Past the deadline of `run`, no unit operation is started anymore, and the entries
+    /// left are recorded in a checkpoint, see [`crate::checkpoint`].
+    async fn operate_deep(
+        self: Arc<Self>,
+        operation: Operation,
+        options: OperateOptions,
+        progress: SharedProgress,
+        tx: mpsc::Sender<(PathBuf, SharedProgress)>,
+        attempt: u32,
+        run: DeepRun,
+    ) -> fsync::Result<OperationReport> {
+        log::trace!("Operate deep: {operation:?}");
+        progress.set(Progress::Compound);
+
+        let root = operation.path().to_owned();
+        let unit = operation.clone().not_deep();
+        let parent_first = operation.is_parent_first();
+        let matcher = entry_matcher(&options)?;
+        let resumed = run.resume_from.is_some();
+        let mut walk = match run.resume_from {
+            Some(paths) => {
+                log::info!("{root}: resuming from the {} entries left", paths.len());
+                tree::Walk::of_paths(paths)
+            }
+            None => tree::Walk::new(root.clone(), operation.order()),
+        };
+        let mut blocked: Option<tree::Step> = None;
+        let mut expired = false;
+        let mut report = OperationReport::default();
+
+        let bootstrap = self
+            .tree
+            .entry(&root)
+            .filter(|_| self.bulk_bootstrap && matcher.is_none())
+            .filter(|_| run.deadline.is_none() && !resumed)
+            .and_then(|node| {
+                Some((
+                    plan::bootstrap_dir(&operation, &node, self.sync_mode)?,
+                    node,
+                ))
+            });
+        if let Some((dir, node)) = bootstrap {
+            let res = self
+                .operate_bootstrap(node, dir, &options, &progress, &tx, &mut report)
+                .await;
+            match res {
+                // nothing left to walk
+                Ok(()) => walk = tree::Walk::of_paths(Vec::new()),
+                Err(err) => log::warn!("{root}: {err}, the deep sync continues entry by entry"),
+            }
+        }
+
+        // directories with operations pending in their sub-tree
+        let mut dirs: Vec<(PathBuf, SharedProgress)> = Vec::new();
+        // directories fully walked, complete when no operation runs in their sub-tree
+        let mut walked: Vec<PathBuf> = Vec::new();
+        let mut running = stream::FuturesUnordered::new();
+        let mut in_flight: Vec<PathBuf> = Vec::new();
+
+        loop {
+            while running.len() < MAX_CONCURRENT_UNITS {
+                if run
+                    .deadline
+                    .is_some_and(|deadline| tokio::time::Instant::now() >= deadline)
+                {
+                    expired = true;
+                    break;
+                }
+                let Some(step) = blocked.take().or_else(|| walk.next(&self.tree)) else {
+                    break;
+                };
+                let path = step.node().path().to_owned();
+                let wait = if parent_first {
+                    in_flight.iter().any(|p| p.is_ancestor_of(&path))
+                } else {
+                    matches!(step, tree::Step::Leave(..))
+                        && in_flight.iter().any(|p| path.is_ancestor_of(p))
+                };
+                if wait {
+                    blocked = Some(step);
+                    break;
+                }
+                // the directories are walked for the selected entries of their sub-tree
+                let selected = matcher
+                    .as_ref()
+                    .is_none_or(|m| m.matches(step.node().entry()));
+
+                let (node, progress, is_dir) = match step {
+                    tree::Step::Enter(node) => {
+                        let progress = step_progress(&root, &path, &progress, &tx).await;
+                        progress.set(Progress::Compound);
+                        dirs.push((path.clone(), progress.clone()));
+                        if !parent_first || !selected {
+                            continue;
+                        }
+                        (node, progress, true)
+                    }
+                    tree::Step::Leave(..) if parent_first => {
+                        walked.push(path);
+                        continue;
+                    }
+                    tree::Step::Leave(node) => {
+                        let idx = dirs
+                            .iter()
+                            .position(|(p, _)| p == &path)
+                            .expect("directory should have been entered");
+                        let (_, progress) = dirs.swap_remove(idx);
+                        if !selected {
+                            progress.set(Progress::Done);
+                            continue;
+                        }
+                        (node.without_children(), progress, false)
+                    }
+                    tree::Step::Leaf(..) if !selected => continue,
+                    // the directories resumed from a checkpoint are leaves of the walk
+                    tree::Step::Leaf(node) if !parent_first => {
+                        let progress = step_progress(&root, &path, &progress, &tx).await;
+                        (node.without_children(), progress, false)
+                    }
+                    tree::Step::Leaf(node) => {
+                        let progress = step_progress(&root, &path, &progress, &tx).await;
+                        (node, progress, false)
+                    }
+                };
+
+                let this = self.clone();
+                let unit = unit.with_path(path.clone());
+                let options = options.clone();
+                in_flight.push(path.clone());
+                running.push(async move {
+                    let res = this
+                        .operate_unit(unit, node, options, progress.clone())
+                        .await;
+                    match &res {
+                        Ok(_) if is_dir => progress.set(Progress::Compound),
+                        Ok(report) => progress.set(Progress::done(*report)),
+                        Err(err) => progress.set(Progress::failed(err.clone(), attempt)),
+                    }
+                    (path, is_dir, res)
+                });
+            }
+
+            let Some((path, is_dir, res)) = running.next().await else {
+                debug_assert!(expired || blocked.is_none());
+                break;
+            };
+            in_flight.retain(|p| p != &path);
+            if let Err(err) = tally_unit(&mut report, &path, res) {
+                // let the running units complete, so that the tree stays
+                // consistent with the storages
+                while running.next().await.is_some() {}
+                for (_, progress) in dirs {
+                    progress.set(Progress::failed(err.clone(), attempt));
+                }
+                return Err(err);
+            }
+            if is_dir && !self.tree.has_entry(&path) {
+                // collected as deleted on both sides
+                walked.push(path);
+            }
+            let (complete, pending): (Vec<_>, Vec<_>) = walked
+                .into_iter()
+                .partition(|dir| !in_flight.iter().any(|p| p == dir || dir.is_ancestor_of(p)));
+            walked = pending;
+            if !complete.is_empty() {
+                // the sub-trees are done once their batched requests are sent
+                if let Err(err) = self.flush().await {
+                    while running.next().await.is_some() {}
+                    for (_, progress) in dirs {
+                        progress.set(Progress::failed(err.clone(), attempt));
+                    }
+                    return Err(err);
+                }
+                for dir in complete {
+                    if let Some(idx) = dirs.iter().position(|(p, _)| p == &dir) {
+                        dirs.swap_remove(idx).1.set(Progress::Done);
+                    }
+                }
+            }
+        }
+
+        if let Err(err) = self.flush().await {
+            for (_, progress) in dirs {
+                progress.set(Progress::failed(err.clone(), attempt));
+            }
+            return Err(err);
+        }
+        for (_, progress) in dirs {
+            progress.set(Progress::Done);
+        }
+        let steps = blocked
+            .into_iter()
+            .chain(std::iter::from_fn(|| walk.next(&self.tree)));
+        let res = if expired {
+            self.record_checkpoint(&operation, &unit, &options, steps, &mut report)
+                .await
+        } else {
+            self.checkpoints.complete(&operation).await
+        };
+        if let Err(err) = res {
+            log::error!("{root}: could not record the checkpoint: {err:#}");
+        }
+        if report.skipped_too_large > 0 {
+            log::warn!(
+                "{root}: {} file(s) larger than the size limit skipped",
+                report.skipped_too_large
+            );
+        }
+        if report.skipped_pinned > 0 {
+            log::warn!(
+                "{root}: {} entries skipped to preserve pinned entries",
+                report.skipped_pinned
+            );
+        }
+        if report.skipped_vanished > 0 {
+            log::warn!(
+                "{root}: {} entries vanished since the tree was built, skipped",
+                report.skipped_vanished
+            );
+        }
+        if report.skipped_withheld > 0 {
+            log::info!(
+                "{root}: {} entries withheld by the {} sync mode",
+                report.skipped_withheld,
+                self.sync_mode
+            );
+        }
+        if report.skipped_not_downloadable > 0 {
+            log::warn!(
+                "{root}: {} file(s) that can be viewed but not downloaded skipped",
+                report.skipped_not_downloadable
+            );
+        }
+        if report.failed > 0 {
+            log::warn!("{root}: {} entries failed", report.failed);
+        }
+        Ok(report)
+    }
+
+    /// Synchronize in bulk the sub-tree of `node`, of which the destination of `dir` has
+    /// nothing, see [`Self::with_bulk_bootstrap`]. The directories are created a level at a
+    /// time, then the files are copied by batches. The entries that are not plain copies,
+    /// e.g. the files larger than the size limit, and the copies that fail, are left to the
+    /// unit operations. On error, the rest of the sub-tree is left to the walk of the deep sync.
+    async fn operate_bootstrap(
+        &self,
+        node: EntryNode,
+        dir: StorageDir,
+        options: &OperateOptions,
+        progress: &SharedProgress,
+        tx: &mpsc::Sender<(PathBuf, SharedProgress)>,
+        report: &mut OperationReport,
+    ) -> fsync::Result<()> {
+        let root = node.path().to_owned();
+        let dest = dir.dest();
+        log::info!("{root}: bootstrapping the {dest:?} storage in bulk");
+        if !node.entry().is_at_loc(dest) {
+            let unit = Operation::Sync(root.clone());
+            let node = node.clone().without_children();
+            *report += self
+                .operate_unit(unit, node, options.clone(), progress.clone())
+                .await?;
+        }
+
+        // the skeleton first, as the directories of a level only need the ones above
+        let mut dirs: BTreeMap<PathBuf, SharedProgress> = BTreeMap::new();
+        let mut levels: Vec<Vec<PathBuf>> = Vec::new();
+        let depth = |path: &Path| path.components().count();
+        let mut walk = tree::Walk::new(root.clone(), fsync::OrderBy::TreeOrder);
+        while let Some(step) = walk.next(&self.tree) {
+            let node = step.node();
+            if matches!(step, tree::Step::Leave(..)) || !node.entry().is_safe_dir() {
+                continue;
+            }
+            let path = node.path().to_owned();
+            let progress = step_progress(&root, &path, progress, tx).await;
+            progress.set(Progress::Compound);
+            if path != root && !node.entry().is_at_loc(dest) {
+                let level = depth(&path) - depth(&root) - 1;
+                if levels.len() == level {
+                    levels.push(Vec::new());
+                }
+                levels[level].push(path.clone());
+            }
+            dirs.insert(path, progress);
+        }
+        for level in levels {
+            for paths in level.chunks(BOOTSTRAP_BATCH) {
+                match dest {
+                    StorageLoc::Local => {
+                        self.bootstrap_mkdirs(&self.local, dest, paths, &dirs)
+                            .await?
+                    }
+                    StorageLoc::Remote => {
+                        self.bootstrap_mkdirs(&self.remote, dest, paths, &dirs)
+                            .await?
+                    }
+                }
+            }
+        }
+        self.flush().await?;
+
+        // the directories are done once the batch of their last file is
+        let mut files = Vec::with_capacity(BOOTSTRAP_BATCH);
+        let mut walked = Vec::new();
+        let mut walk = tree::Walk::new(root.clone(), fsync::OrderBy::TreeOrder);
+        loop {
+            let step = walk.next(&self.tree);
+            let end = step.is_none();
+            match step {
+                Some(tree::Step::Leaf(node)) if !node.entry().is_safe_dir() => files.push(node),
+                Some(step @ (tree::Step::Leaf(..) | tree::Step::Leave(..))) => {
+                    walked.push(step.node().path().to_owned());
+                }
+                _ => (),
+            }
+            if files.len() == BOOTSTRAP_BATCH || (end && !files.is_empty()) {
+                let batch = std::mem::replace(&mut files, Vec::with_capacity(BOOTSTRAP_BATCH));
+                self.bootstrap_files(batch, dir, options, &root, tx, report)
+                    .await?;
+            }
+            if files.is_empty() {
+                for path in walked.drain(..) {
+                    if let Some(progress) = dirs.remove(&path) {
+                        progress.set(Progress::Done);
+                    }
+                }
+            }
+            if end {
+                break;
+            }
+        }
+        if self.self_check {
+            self.check_entry(&root).await;
+        }
+        Ok(())
+    }
+
+    /// Create the directories at `paths` in `storage`, of which the parents exist
+    async fn bootstrap_mkdirs<S>(
+        &self,
+        storage: &S,
+        loc: StorageLoc,
+        paths: &[PathBuf],
+        dirs: &BTreeMap<PathBuf, SharedProgress>,
+    ) -> fsync::Result<()>
+    where
+        S: storage::MkDir + Sync,
+    {
+        let id = self.begin_bulk(loc, paths).await?;
+        // the futures are built before the stream, the closure must not be held across awaits
+        let mkdirs: Vec<_> = paths
+            .iter()
+            .map(|path| storage.mkdir(path, false, dirs.get(path)))
+            .collect();
+        let results: Vec<fsync::Result<()>> = stream::iter(mkdirs)
+            .buffered(MAX_CONCURRENT_UNITS)
+            .collect()
+            .await;
+        let mut res = Ok(());
+        let mut created = Vec::with_capacity(paths.len());
+        for (path, mkdir) in paths.iter().zip(results) {
+            match mkdir {
+                Ok(()) => created.push(Metadata::Directory {
+                    path: path.clone(),
+                    stat: Some(stat::Dir::null()),
+                }),
+                Err(err) => res = res.and(Err(err)),
+            }
+        }
+        self.apply_bulk(loc, created).await;
+        self.complete_bulk(id).await;
+        res
+    }
+
+    /// Copy the files of `nodes` in bulk, with the tree updated at once.
+    /// The other entries, and the files of which the copy failed, are operated as units.
+    async fn bootstrap_files(
+        &self,
+        nodes: Vec<EntryNode>,
+        dir: StorageDir,
+        options: &OperateOptions,
+        root: &Path,
+        tx: &mpsc::Sender<(PathBuf, SharedProgress)>,
+        report: &mut OperationReport,
+    ) -> fsync::Result<()> {
+        let dest = dir.dest();
+        let (copies, mut units): (Vec<_>, Vec<_>) = nodes.into_iter().partition(|node| {
+            let unit = Operation::Sync(node.path().to_owned());
+            let copy = plan::unit_action(&unit, node, options, self.sync_mode);
+            matches!(copy, Some(Action::Copy(d)) if d.dest() == dest)
+                && (dest == StorageLoc::Remote || self.remote.view_only(node.path()).is_none())
+        });
+
+        let paths: Vec<PathBuf> = copies.iter().map(|node| node.path().to_owned()).collect();
+        let id = self.begin_bulk(dest, &paths).await?;
+        let copying: Vec<_> = copies
+            .iter()
+            .map(|node| self.bootstrap_copy(node, dir))
+            .collect();
+        let results: Vec<fsync::Result<Metadata>> = stream::iter(copying)
+            .buffered(MAX_CONCURRENT_UNITS)
+            .collect()
+            .await;
+        let mut copied = Vec::with_capacity(copies.len());
+        for (node, res) in copies.into_iter().zip(results) {
+            match res {
+                Ok(metadata) => copied.push((node, metadata)),
+                Err(err) => {
+                    log::debug!("{}: {err}, copied again as a unit", node.path());
+                    self.counters.fail_transfer(node.path());
+                    units.push(node);
+                }
+            }
+        }
+        let metadatas = copied.iter().map(|(_, md)| md.clone()).collect();
+        self.apply_bulk(dest, metadatas).await;
+        self.complete_bulk(id).await;
+        for (node, _) in copied {
+            self.counters.end_transfer(node.path());
+            let unit = Operation::Sync(node.path().to_owned());
+            self.acted(&unit, &node, Action::Copy(dir)).await;
+            self.counters.add_operation(true);
+        }
+
+        for node in units {
+            let path = node.path().to_owned();
+            let progress = step_progress(root, &path, &SharedProgress::new(), tx).await;
+            let unit = Operation::Sync(path.clone());
+            let res = self
+                .operate_unit(unit, node, options.clone(), progress.clone())
+                .await;
+            match &res {
+                Ok(report) => progress.set(Progress::done(*report)),
+                // the destination was empty: this is the first attempt
+                Err(err) => progress.set(Progress::failed(err.clone(), 1)),
+            }
+            tally_unit(report, &path, res)?;
+        }
+        Ok(())
+    }
+
+    /// Copy the file of `node` to the destination of `dir`, where its parent exists,
+    /// without updating the tree
+    async fn bootstrap_copy(&self, node: &EntryNode, dir: StorageDir) -> fsync::Result<Metadata> {
+        let metadata = node
+            .entry()
+            .clone()
+            .into_metadata(dir.src())
+            .expect("the file should be in the source storage");
+        let progress = SharedProgress::new();
+        match dir {
+            StorageDir::LocalToRemote => {
+                let read = read_file_with_progress(&self.local, &metadata, &progress).await?;
+                let created = self
+                    .remote
+                    .create_file(&metadata, read, Some(&progress))
+                    .await?;
+                let size = created.size().unwrap_or(0);
+                let progress = Some(&progress);
+                self.count_transfer(TransferCause::Sync, created.path(), dir, size, progress);
+                Ok(created)
+            }
+            StorageDir::RemoteToLocal => {
+                let tmp_path = get_tmp_path(metadata.path(), &self.local).await;
+                let read = read_file_with_progress(&self.remote, &metadata, &progress).await?;
+                self.create_local_file(&metadata, tmp_path, read, TransferCause::Sync, &progress)
                     .await
+            }
+        }
+    }
+
+    /// Journal the creation in bulk of the entries at `paths` in `loc`, as one operation
+    async fn begin_bulk(
+        &self,
+        loc: StorageLoc,
+        paths: &[PathBuf],
+    ) -> fsync::Result<Option<journal::Id>> {
+        let Some(journal) = self.journal.as_ref().filter(|_| !paths.is_empty()) else {
+            return Ok(None);
+        };
+        let targets = paths
+            .iter()
+            .map(|path| journal::Target {
+                loc,
+                path: path.clone(),
+            })
+            .collect();
+        let id = journal
+            .begin(targets)
+            .await
+            .map_err(|err| Error::Io(format!("Could not write the journal: {err:#}")))?;
+        Ok(Some(id))
+    }
+
+    /// Mark complete the bulk creation journaled as `id`, once applied to the tree
+    async fn complete_bulk(&self, id: Option<journal::Id>) {
+        let (Some(journal), Some(id)) = (&self.journal, id) else {
+            return;
+        };
+        if let Err(err) = journal.complete(id).await {
+            log::error!(target: "journal", "Could not write the journal: {err:#}");
+        }
+    }
+
+    /// Add to `loc` in the tree the entries created there in bulk.
+    /// The effects are not journaled one by one: the targets of the operation are checked
+    /// again in the storages if it is left incomplete.
+    async fn apply_bulk(&self, loc: StorageLoc, metadatas: Vec<Metadata>) {
+        if metadatas.is_empty() {
+            return;
+        }
+        let conflicts = self.tree.add_all_to_storage(metadatas, loc);
+        if !conflicts.is_empty() {
+            let updates = conflicts.into_iter().map(|path| (path, true));
+            self.check_conflicts_bulk(updates).await;
+        }
+    }
+
+    /// Record the entries left to `unit` in `steps`, once the time budget of the deep
+    /// `operation` elapsed. The entries that need no work are left out.
+    async fn record_checkpoint(
+        &self,
+        operation: &Operation,
+        unit: &Operation,
+        options: &OperateOptions,
+        steps: impl Iterator<Item = tree::Step>,
+        report: &mut OperationReport,
+    ) -> anyhow::Result<()> {
+        let parent_first = operation.is_parent_first();
+        let matcher = entry_matcher(options)?;
+        let mut paths = Vec::new();
+        let mut bytes = 0;
+        for step in steps {
+            let node = match step {
+                tree::Step::Enter(node) if parent_first => node,
+                tree::Step::Leave(node) if !parent_first => node.without_children(),
+                tree::Step::Leaf(node) if !parent_first => node.without_children(),
+                tree::Step::Leaf(node) => node,
+                _ => continue,
+            };
+            if matcher.as_ref().is_some_and(|m| !m.matches(node.entry())) {
+                continue;
+            }
+            let path = node.path().to_owned();
+            let unit = unit.with_path(path.clone());
+            if let Some(action) = plan::unit_action(&unit, &node, options, self.sync_mode) {
+                bytes += plan::action_size(&action, &node);
+                paths.push(path);
+            }
+        }
+        let root = operation.path();
+        if paths.is_empty() {
+            return self.checkpoints.complete(operation).await;
+        }
+        log::info!(
+            "{root}: time budget elapsed, {} entries left for the next run",
+            paths.len()
+        );
+        report.remaining = paths.len() as u32;
+        let checkpoint = Checkpoint {
+            operation: operation.clone(),
+            force_large: options.force_large,
+            filter: options.filter.clone(),
+            created: Utc::now(),
+            paths,
+            bytes,
+        };
+        self.checkpoints.set(Some(checkpoint)).await
+    }
+
+    /// Create a plan of `operation`, to be retrieved with [`Self::plan_next`]
+    pub async fn plan(&self, operation: Operation) -> fsync::Result<PlanId> {
+        self.check_node(operation.path())?;
+        let id = self.plan_id.fetch_add(1, atomic::Ordering::Relaxed);
+        let mut plans = self.plans.lock().await;
+        if plans.len() >= MAX_PLANS {
+            plans.pop_first();
+        }
+        plans.insert(id, Plan::new(operation, self.sync_mode));
+        Ok(id)
+    }
+
+    /// Provide at most `max_len` next actions of a plan.
+    /// The plan is released when it is complete.
+    pub async fn plan_next(&self, id: PlanId, max_len: usize) -> fsync::Result<Vec<PlannedAction>> {
+        let mut plans = self.plans.lock().await;
+        let Some(plan) = plans.get_mut(&id) else {
+            fsync::other_bail!("No such plan: {id}");
+        };
+        let actions = plan.next_actions(&self.tree, max_len);
+        if actions.is_empty() {
+            plans.remove(&id);
+        }
+        Ok(actions)
+    }
+
+    pub async fn read_head(
+        &self,
+        path: &Path,
+        loc: StorageLoc,
+        max_bytes: u64,
+    ) -> fsync::Result<Vec<u8>> {
+        let node = self.check_node(path)?;
+        let metadata = node
+            .into_entry()
+            .into_metadata(loc)
+            .ok_or_else(|| PathError::NotFound(path.to_owned(), Some(loc.into())))?;
+        if !metadata.is_file() {
+            fsync::io_bail!("{path} is not a file");
+        }
+        let path = metadata.path().to_owned();
+        let head = match loc {
+            StorageLoc::Local => {
+                read_head(
+                    self.local.read_file_range(path, 0, max_bytes, None).await?,
+                    max_bytes,
+                )
+                .await?
+            }
+            StorageLoc::Remote => {
+                let Some(cache) = &self.disk_cache else {
+                    return Ok(read_head(
+                        self.remote
+                            .read_file_range(path, 0, max_bytes, None)
+                            .await?,
+                        max_bytes,
+                    )
+                    .await?);
+                };
+                let key = disk_cache::content_key(
+                    &path,
+                    metadata.size().unwrap_or_default(),
+                    metadata.mtime().unwrap_or_default(),
+                    &format!("head-{max_bytes}"),
+                );
+                let head = cache.get(&key).await;
+                cache.record_lookup(head.is_some());
+                if let Some(head) = head {
+                    return Ok(head);
+                }
+                let head = read_head(
+                    self.remote
+                        .read_file_range(path, 0, max_bytes, None)
+                        .await?,
+                    max_bytes,
+                )
+                .await?;
+                if let Err(err) = cache.put(&key, &head).await {
+                    log::warn!("Could not cache the head of {}: {err}", metadata.path());
+                }
+                head
+            }
+        };
+        Ok(head)
+    }
+
+    /// The metadata of the remote file at `path`
+    fn remote_file(&self, path: &Path) -> fsync::Result<Metadata> {
+        let node = self.check_node(path)?;
+        let metadata = node
+            .into_entry()
+            .into_metadata(StorageLoc::Remote)
+            .ok_or_else(|| PathError::NotFound(path.to_owned(), Some(Location::Remote)))?;
+        if !metadata.is_file() {
+            fsync::io_bail!("{path} is not a file");
+        }
+        Ok(metadata)
+    }
+
+    /// The revisions of the remote file at `path`, most recent first and at most `max`
+    pub async fn remote_revisions(
+        &self,
+        path: &Path,
+        max: u32,
+    ) -> fsync::Result<Vec<fsync::Revision>> {
+        let metadata = self.remote_file(path)?;
+        let mut revisions = self.remote.revisions(metadata.path()).await?;
+        revisions.reverse();
+        revisions.truncate(max as usize);
+        Ok(revisions)
+    }
+
+    /// Download the revision `revision_id` of the remote file at `path` to the new local
+    /// file at `dest`, and add it to the tree as a local-only entry.
+    pub async fn download_revision(
+        &self,
+        path: &Path,
+        revision_id: &str,
+        dest: &Path,
+    ) -> fsync::Result<Metadata> {
+        let metadata = self.remote_file(path)?;
+        let dest = Self::check_path(dest)?;
+        let Some(parent) = dest.parent() else {
+            return Err(PathError::Illegal(dest, Some("Expected a file path".into())).into());
+        };
+        let parent_is_local = self.tree.entry(parent).is_some_and(|node| {
+            node.into_entry()
+                .into_local_metadata()
+                .is_some_and(|md| md.is_dir())
+        });
+        if !parent_is_local {
+            return Err(PathError::NotFound(parent.to_owned(), Some(Location::Local)).into());
+        }
+        if self.tree.has_entry(&dest) || self.local.exists(&dest).await? {
+            return Err(Error::Other(format!(
+                "{dest} already exists, the revision can't be downloaded there"
+            )));
+        }
+
+        let revisions = self.remote.revisions(metadata.path()).await?;
+        let Some(revision) = revisions.into_iter().find(|r| r.id == revision_id) else {
+            fsync::other_bail!("No revision {revision_id} of {path}");
+        };
+        let Some(size) = revision.size else {
+            fsync::other_bail!("The revision {revision_id} of {path} has no content to download");
+        };
+        log::info!("{path}: downloading revision {revision_id} to {dest}");
+        let data = self
+            .remote
+            .read_revision(metadata.path(), revision_id, None)
+            .await?;
+        let tmp_metadata = Metadata::Regular {
+            path: get_tmp_path(&dest, &self.local).await,
+            size,
+            mtime: revision.mtime,
+            link_target: None,
+        };
+        let created = match self.local.create_file(&tmp_metadata, data, None).await {
+            Ok(created) => created,
+            Err(err) => {
+                let _ = self.local.delete(tmp_metadata.path(), None).await;
+                return Err(err);
+            }
+        };
+        let metadata = self.local.move_entry(created.path(), &dest, None).await?;
+        let dir = StorageDir::RemoteToLocal;
+        self.count_transfer(TransferCause::Client, &dest, dir, size, None);
+        self.apply(
+            None,
+            Effect::Copied {
+                loc: StorageLoc::Local,
+                metadata: metadata.clone(),
+            },
+        )
+        .await?;
+        Ok(metadata)
+    }
+
+    /// Read at most `max_bytes` of the file at `path` from `offset`,
+    /// from the local copy if it is synchronized, and from the remote storage otherwise
+    pub async fn read_stream(
+        &self,
+        path: &Path,
+        offset: u64,
+        max_bytes: u64,
+    ) -> fsync::Result<fsync::StreamChunk> {
+        let node = self.check_node(path)?;
+        if node.entry().is_conflict() {
+            return Err(Error::Conflict(path.to_owned()));
+        }
+        let loc = match node.entry().is_remote_only() {
+            true => StorageLoc::Remote,
+            false => StorageLoc::Local,
+        };
+        let metadata = node
+            .into_entry()
+            .into_metadata(loc)
+            .ok_or_else(|| PathError::NotFound(path.to_owned(), Some(loc.into())))?;
+        if !metadata.is_file() {
+            fsync::io_bail!("{path} is not a file");
+        }
+        let size = metadata.size().unwrap_or_default();
+        let len = max_bytes.min(size.saturating_sub(offset));
+        let data = if len == 0 {
+            Vec::new()
+        } else {
+            let file_path = metadata.path().to_owned();
+            let read = match loc {
+                StorageLoc::Local => {
+                    read_head(
+                        self.local
+                            .read_file_range(file_path, offset, len, None)
+                            .await?,
+                        len,
+                    )
+                    .await?
+                }
+                StorageLoc::Remote => {
+                    read_head(
+                        self.remote
+                            .read_file_range(file_path, offset, len, None)
+                            .await?,
+                        len,
+                    )
+                    .await?
+                }
+            };
+            if loc == StorageLoc::Remote {
+                let read = read.len() as u64;
+                self.counters.add_downloaded(read);
+                // a file streamed by parts is counted once
+                let transfer = fsync::TransferCounters {
+                    files: u64::from(offset == 0),
+                    bytes: read,
+                    retried_bytes: 0,
+                };
+                self.counters.add_transfer(TransferCause::Client, transfer);
+            }
+            read
+        };
+        Ok(fsync::StreamChunk {
+            data,
+            metadata,
+            loc,
+        })
+    }
+
+    /// Create the remote directory at `path`, and its missing parents
+    pub async fn receive_dir(&self, path: &Path) -> fsync::Result<()> {
+        let path = Self::check_path(path)?;
+        if path.is_root() {
+            return Ok(());
+        }
+        if let Some(node) = self.tree.entry(&path) {
+            if node.entry().is_remote_dir() {
+                return Ok(());
+            }
+            fsync::other_bail!("{path} already exists");
+        }
+        // the missing parents are created from the top, as an entry can only be inserted in
+        // the tree if its parent is
+        let mut dirs = vec![path];
+        while let Some(parent) = dirs.last().and_then(|dir| dir.parent()) {
+            if parent.is_root() || self.tree.has_entry(parent) {
+                break;
+            }
+            dirs.push(parent.to_owned());
+        }
+        let progress = SharedProgress::new();
+        let top = dirs.last().expect("the directory itself should be missing");
+        self.do_ensure_parents(top, &self.remote, StorageLoc::Remote, &progress, None)
+            .await?;
+        for path in dirs.into_iter().rev() {
+            self.remote.mkdir(&path, false, Some(&progress)).await?;
+            let metadata = Metadata::Directory { path, stat: None };
+            self.apply(
+                None,
+                Effect::Copied {
+                    loc: StorageLoc::Remote,
+                    metadata,
+                },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Start the creation of the remote file of `metadata`, whose content is received
+    /// by chunks, and return the handle of the reception
+    pub async fn receive_begin(&self, metadata: Metadata) -> fsync::Result<u64> {
+        let path = Self::check_path(metadata.path())?;
+        if !metadata.is_file() {
+            fsync::other_bail!("{path} is not a file");
+        }
+        if self.tree.has_entry(&path) || self.remote.exists(&path).await? {
+            fsync::other_bail!("{path} already exists");
+        }
+        let parent_is_remote = path.parent().is_some_and(|parent| {
+            self.tree
+                .entry(parent)
+                .is_some_and(|node| node.entry().is_remote_dir())
+        });
+        if !parent_is_remote {
+            let parent = path.parent().unwrap_or(Path::root()).to_owned();
+            return Err(PathError::NotFound(parent, Some(Location::Remote)).into());
+        }
+        log::info!(
+            "{path}: receiving {} bytes",
+            metadata.size().unwrap_or_default()
+        );
+        self.receptions
+            .begin(&self.remote, metadata.with_path(path))
+    }
+
+    /// Pass the next chunk of the content of the reception `handle`
+    pub async fn receive_chunk(&self, handle: u64, data: &[u8]) -> fsync::Result<()> {
+        self.receptions.chunk(handle, data).await
+    }
+
+    /// Complete the reception `handle`, and check that the MD5 of its content is `md5`.
+    /// The file is deleted if it is not.
+    pub async fn receive_finish(&self, handle: u64, md5: &str) -> fsync::Result<Metadata> {
+        let (metadata, received) = self.receptions.finish(handle).await?;
+        if !received.eq_ignore_ascii_case(md5) {
+            let path = metadata.path();
+            log::error!("{path}: received content of MD5 {received}, expected {md5}");
+            self.remote.delete(path, None).await?;
+            fsync::other_bail!("{path}: the content received does not match the one sent");
+        }
+        let size = metadata.size().unwrap_or_default();
+        let dir = StorageDir::LocalToRemote;
+        self.count_transfer(TransferCause::Client, metadata.path(), dir, size, None);
+        self.apply(
+            None,
+            Effect::Copied {
+                loc: StorageLoc::Remote,
+                metadata: metadata.clone(),
+            },
+        )
+        .await?;
+        Ok(metadata)
+    }
+
+    /// The links to the remote file at `path`
+    pub async fn remote_link(&self, path: &Path) -> fsync::Result<fsync::RemoteLink> {
+        let metadata = self.remote_file(path)?;
+        self.remote.link(metadata.path()).await
+    }
+
+    /// Let anyone with the link read the remote file at `path`, once the client confirmed it,
+    /// and record it in the audit log
+    pub async fn share_public(
+        &self,
+        path: &Path,
+        confirmation: Option<&str>,
+    ) -> fsync::Result<fsync::RemoteLink> {
+        let metadata = self.remote_file(path)?;
+        self.confirmations
+            .check_share_public(metadata.path(), confirmation)?;
+        let link = self.remote.share_public(metadata.path()).await?;
+        let node = self.check_node(path)?;
+        self.audit(None, path, &node, &Action::SharePublic).await;
+        Ok(link)
+    }
+
+    /// Compute the checksum of the local copy of the file at `path`
+    pub async fn checksum(&self, path: &Path, algo: fsync::HashAlgo) -> fsync::Result<Checksum> {
+        let node = self.check_node(path)?;
+        let metadata = node
+            .into_entry()
+            .into_metadata(StorageLoc::Local)
+            .ok_or_else(|| PathError::NotFound(path.to_owned(), Some(StorageLoc::Local.into())))?;
+        if !metadata.is_file() {
+            fsync::io_bail!("{path} is not a file");
+        }
+        storage::hash::hash_file(
+            &self.local,
+            &metadata,
+            algo,
+            self.checksums.as_ref(),
+            None,
+            None,
+        )
+        .await
+    }
+
+    pub async fn instance_stats(&self) -> fsync::Result<fsync::InstanceStats> {
+        let quota = self.remote.quota().await?;
+        let percent = quota.and_then(|q| q.percent());
+        let quota_warning = percent.is_some_and(|pct| pct >= self.quota_warning);
+        if quota_warning {
+            log::warn!("Remote storage is {:.1}% full", percent.unwrap());
+        }
+        let cache = match &self.disk_cache {
+            Some(cache) => Some(cache.usage().await),
+            None => None,
+        };
+        Ok(fsync::InstanceStats {
+            quota,
+            quota_warning,
+            cache,
+            sync_mode: self.sync_mode,
+            withheld: self.withheld(),
+            config_fingerprint: self.config_fingerprint(),
+        })
+    }
+
+    pub fn cache_lookups(&self) -> Option<fsync::CacheLookups> {
+        self.disk_cache.as_ref().map(|cache| cache.lookups())
+    }
+
+    pub fn buffer_usage(&self) -> Option<fsync::BufferUsage> {
+        self.buffers.as_ref().map(BufferPool::usage)
+    }
+
+    /// Number of entries that a sync of the whole tree would withhold
+    fn withheld(&self) -> u32 {
+        if self.sync_mode.is_bidirectional() {
+            return 0;
+        }
+        let mut plan = Plan::new(Operation::SyncDeep(PathBuf::root()), self.sync_mode);
+        let mut count = 0;
+        loop {
+            let actions = plan.next_actions(&self.tree, 1000);
+            if actions.is_empty() {
+                break count;
+            }
+            count += actions
+                .iter()
+                .filter(|a| matches!(a.action, Action::SkipWithheld))
+                .count() as u32;
+        }
+    }
+
+    /// Clear the cached `content` and/or `metadata`, and return the number of bytes freed.
+    /// The metadata of the remote storage is in use while the daemon runs,
+    /// so it can only be cleared when the daemon is stopped.
+    pub async fn clear_cache(&self, content: bool, metadata: bool) -> fsync::Result<u64> {
+        if metadata {
+            fsync::other_bail!(
+                "The metadata cache is in use by the daemon, stop it to clear the metadata cache"
+            );
+        }
+        let freed = match (&self.disk_cache, content) {
+            (Some(cache), true) => cache.clear_content().await,
+            _ => 0,
+        };
+        Ok(freed)
+    }
+
+    /// Send the requests that the storages queued for batching
+    /// Schedule a write of the status file, unless it is disabled or already scheduled
+    fn schedule_status_file(self: &Arc<Self>) {
+        let Some(status_file) = &self.status_file else {
+            return;
+        };
+        let Some(delay) = status_file.schedule() else {
+            return;
+        };
+        let this = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Some(status_file) = &this.status_file {
+                status_file.start_write();
+            }
+            if let Err(err) = this.write_status_file().await {
+                log::warn!("Could not write the status file: {err}");
+            }
+        });
+    }
+
+    /// Write the status file in both storages
+    async fn write_status_file(&self) -> fsync::Result<()> {
+        let stats = self.check_node(Path::root())?.stats();
+        let content = {
+            let conflicts = self.conflicts.read().await;
+            status_file::Content::new(stats, conflicts.len(), conflicts.iter().cloned())
+        };
+        let data = content.to_json();
+        let metadata = Metadata::Regular {
+            path: PathBuf::from(status_file::PATH),
+            size: data.len() as u64,
+            mtime: Utc::now(),
+            link_target: None,
+        };
+        log::debug!("Writing the status file {}", status_file::PATH);
+        write_whole(&self.local, &metadata, &data).await?;
+        write_whole(&self.remote, &metadata, &data).await?;
+        self.flush().await
+    }
+
+    async fn flush(&self) -> fsync::Result<()> {
+        future::try_join(self.local.flush(), self.remote.flush()).await?;
+        Ok(())
+    }
+
+    /// Check that the data uploaded by `operation` fits in the remote quota.
+    /// The check is advisory: the operation proceeds if the quota can't be fetched.
+    async fn check_quota(
+        &self,
+        operation: &Operation,
+        options: &OperateOptions,
+    ) -> fsync::Result<()> {
+        if self.sync_mode == fsync::SyncMode::DownloadOnly {
+            return Ok(());
+        }
+        match operation {
+            Operation::Sync(..)
+            | Operation::SyncDeep(..)
+            | Operation::SyncDeepOrdered(..)
+            | Operation::Resolve(..)
+            | Operation::ResolveDeep(..)
+            | Operation::RestoreRemote(..) => (),
+            _ => return Ok(()),
+        }
+        let Some(node) = self.tree.entry(operation.path()) else {
+            return Ok(());
+        };
+        let deep = operation.is_deep();
+        let unit = if deep {
+            operation.clone().not_deep()
+        } else {
+            operation.clone()
+        };
+        let required = self.planned_upload(&unit, &node, deep, options);
+        if required <= 0 {
+            return Ok(());
+        }
+        let required = required as u64;
+        let quota = match self.remote.quota().await {
+            Ok(quota) => quota,
+            Err(err) => {
+                log::warn!("Could not check the remote quota before {operation:?}: {err}");
+                return Ok(());
+            }
+        };
+        match quota.and_then(|q| q.remaining()) {
+            Some(remaining) if required > remaining as u64 => Err(fsync::Error::QuotaExceeded {
+                remaining: remaining as _,
+                required,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The growth of the remote storage caused by the `unit` operation on `node`,
+    /// and on its sub-tree if `deep` is set.
+    /// Files larger than the size limit are not uploaded unless `options.force_large` is set.
+    fn planned_upload(
+        &self,
+        unit: &Operation,
+        node: &EntryNode,
+        deep: bool,
+        options: &OperateOptions,
+    ) -> i64 {
+        let action = plan::unit_action(
+            &unit.with_path(node.path().to_owned()),
+            node,
+            options,
+            self.sync_mode,
+        );
+        let own = action
+            .as_ref()
+            .map_or(0, |action| plan::remote_growth(action, node));
+        if !deep {
+            return own;
+        }
+        match (node.entry(), &action) {
+            // a new local folder is uploaded whole, except its too large files
+            (tree::Entry::Local(metadata), Some(Action::Mkdir(StorageLoc::Remote)))
+                if options.force_large || node.stats().node.too_large == 0 =>
+            {
+                metadata.children_stat().map_or(0, |stat| stat.data.max(0))
+            }
+            _ => {
+                own + node
+                    .children()
+                    .iter()
+                    .filter_map(|name| self.tree.entry(&node.path().join(name)))
+                    .map(|child| self.planned_upload(unit, &child, deep, options))
+                    .sum::<i64>()
+            }
+        }
+    }
+
+    pub fn root_change(&self) -> Option<fsync::RootChange> {
+        self.root_guard.as_ref().and_then(|guard| guard.change())
+    }
+
+    /// Proceed after a change of the remote root, as chosen with `choice`:
+    /// - `KeepLocal` pairs the instance with the new root and resolves the conflicts
+    ///   in favor of the local entries, in an operation polled on the root
+    /// - `ResetLocal` deletes the content of the local directory and pairs the instance with the new root
+    /// - `RevertConfig` writes the recorded root back in the config, to be used at the next start
+    pub async fn migrate_root(
+        self: Arc<Self>,
+        choice: fsync::MigrationChoice,
+    ) -> fsync::Result<()> {
+        let Some(guard) = self.root_guard.clone() else {
+            fsync::other_bail!("The remote root did not change");
+        };
+        let Some(change) = guard.change() else {
+            fsync::other_bail!("The remote root did not change");
+        };
+        if self.is_operating().await {
+            fsync::other_bail!("Cannot migrate the remote root while an operation is running");
+        }
+        log::warn!(
+            "Migrating from the remote root {} to {} with {choice:?}",
+            change.recorded,
+            change.resolved
+        );
+        match choice {
+            fsync::MigrationChoice::RevertConfig => {
+                guard.revert_config().await.map_err(|err| {
+                    fsync::Error::Other(format!("Could not revert the config: {err:#}"))
+                })?;
+                return Ok(());
+            }
+            fsync::MigrationChoice::ResetLocal => {
+                // listed parents first, deleted children first
+                let mut entries = Vec::new();
+                let mut dirs = vec![PathBuf::root()];
+                while let Some(dir) = dirs.pop() {
+                    for md in self.local.relist(&dir).await? {
+                        if md.is_dir() {
+                            dirs.push(md.path().to_owned());
+                        }
+                        entries.push(md.path().to_owned());
+                    }
+                }
+                for path in entries.iter().rev() {
+                    self.local.delete(path, None).await?;
+                }
+            }
+            fsync::MigrationChoice::KeepLocal => (),
+        }
+        self.rebuild_tree().await?;
+        guard.accept().await.map_err(|err| {
+            fsync::Error::Other(format!("Could not record the new root: {err:#}"))
+        })?;
+        if choice == fsync::MigrationChoice::KeepLocal {
+            let resolve = Operation::ResolveDeep(
+                PathBuf::root(),
+                fsync::ResolutionMethod::ReplaceRemoteByLocal,
+            );
+            self.operate(resolve).await?;
+        }
+        Ok(())
+    }
+
+    /// Build the tree again from fresh listings of the storages
+    async fn rebuild_tree(&self) -> fsync::Result<()> {
+        let mut dirs = vec![PathBuf::root()];
+        while let Some(dir) = dirs.pop() {
+            for md in self.remote.relist(&dir).await? {
+                if md.is_dir() {
+                    dirs.push(md.path().to_owned());
+                }
+            }
+        }
+        let tree = DiffTree::build_with(&self.local, &self.remote, self.tree_options.clone())
+            .await
+            .map_err(|err| fsync::Error::Other(format!("Could not build the tree: {err:#}")))?;
+        self.tree.replace_with(tree);
+        *self.conflicts_mut().await = tree_conflicts(&self.tree);
+        if let Some(aggregator) = &self.aggregator {
+            aggregator.restart(Path::root());
+        }
+        Ok(())
+    }
+
+    pub async fn operate(self: Arc<Self>, operation: Operation) -> fsync::Result<Progress> {
+        self.operate_with(operation, OperateOptions::default())
+            .await
+    }
+
+    pub async fn operate_with(
+        self: Arc<Self>,
+        operation: Operation,
+        options: OperateOptions,
+    ) -> fsync::Result<Progress> {
+        self.start_operation(operation, options, Start::User).await
+    }
+
+    /// Same as [`Self::operate_with`], but the operation waits while the deferral policy
+    /// postpones the scheduled work
+    pub async fn operate_scheduled(
+        self: Arc<Self>,
+        operation: Operation,
+        options: OperateOptions,
+    ) -> fsync::Result<Progress> {
+        self.start_operation(operation, options, Start::Scheduled)
+            .await
+    }
+
+    /// Resume the scheduled deep operation stopped by its time budget,
+    /// from the entries left in its checkpoint
+    pub async fn resume(self: Arc<Self>) -> fsync::Result<Progress> {
+        let Some(checkpoint) = self.checkpoints.get() else {
+            fsync::other_bail!("No operation to resume");
+        };
+        let options = checkpoint.options();
+        self.start_operation(checkpoint.operation, options, Start::Resume)
+            .await
+    }
+
+    /// The time budget of the deep `operation` started with `start`, and the entries it resumes from.
+    /// `started` holds the start of the first attempt of the operation.
+    fn deep_run(
+        &self,
+        operation: &Operation,
+        start: Start,
+        started: &std::sync::OnceLock<tokio::time::Instant>,
+    ) -> DeepRun {
+        let deadline = match start {
+            Start::Scheduled => self
+                .policy
+                .max_duration()
+                .map(|max| *started.get_or_init(tokio::time::Instant::now) + max),
+            Start::User | Start::Resume => None,
+        };
+        let resume_from = match start {
+            Start::User => None,
+            Start::Scheduled | Start::Resume => self.checkpoints.of(operation).map(|c| c.paths),
+        };
+        DeepRun {
+            deadline,
+            resume_from,
+        }
+    }
+
+    async fn start_operation(
+        self: Arc<Self>,
+        operation: Operation,
+        options: OperateOptions,
+        start: Start,
+    ) -> fsync::Result<Progress> {
+        if let Some(guard) = &self.root_guard {
+            guard.ensure_unchanged()?;
+        }
+        entry_matcher(&options)?;
+        if let Err(err) = self.resync().await {
+            log::warn!("Could not check the remote storage after the resume: {err}");
+        }
+        // the checkpoint was recorded by an operation that passed the guard
+        if confirm::is_guarded(&operation) && start != Start::Resume {
+            let stats = self.check_node(operation.path())?.stats();
+            self.confirmations
+                .check(&operation, &stats, options.confirmation.as_deref())?;
+        }
+        if !operation.is_deep() {
+            let node = self.check_node(operation.path())?;
+            let action = plan::unit_action(&operation, &node, &options, self.sync_mode);
+            if matches!(action, Some(Action::SkipWithheld)) {
+                return Err(Error::Withheld(operation.path().to_owned(), self.sync_mode));
+            }
+        }
+        self.check_quota(&operation, &options).await?;
+
+        let (tx, mut rx) = mpsc::channel::<(PathBuf, SharedProgress)>(32);
+        // the budget spans the retries
+        let started = Arc::new(std::sync::OnceLock::new());
+
+        let join = {
+            let this = self.clone();
+            tokio::spawn(logs::operation_scope(async move {
+                let path = operation.path().to_owned();
+                let (max_retries, delay) = (this.max_retries, this.retry_delay);
+                track_progress(
+                    path,
+                    tx.clone(),
+                    max_retries,
+                    delay,
+                    move |progress, attempt| {
+                        let this = this.clone();
+                        let operation = operation.clone();
+                        let options = options.clone();
+                        let tx = tx.clone();
+                        let started = started.clone();
+                        // the requests in flight at a suspend hang on dead connections
+                        let resumes = this.resumes.clone();
+                        resumes.interrupting(async move {
+                            if attempt > 1 {
+                                this.counters.add_retry();
+                            }
+                            let scheduled = start == Start::Scheduled;
+                            while let Some(reason) =
+                                scheduled.then(|| this.policy.deferred()).flatten()
+                            {
+                                log::debug!("{}: postponed, {reason}", operation.path());
+                                progress.set(Progress::Waiting(format!("Postponed: {reason}")));
+                                tokio::time::sleep(policy::RETRY).await;
+                            }
+                            let node = this.check_node(operation.path())?;
+                            let deep = operation.is_deep();
+                            let res = if deep {
+                                let run = this.deep_run(&operation, start, &started);
+                                let resumed = run.resume_from.is_some();
+                                let operate = this
+                                    .clone()
+                                    .operate_deep(operation, options, progress, tx, attempt, run);
+                                if resumed {
+                                    counters::resumed_scope(operate).await
+                                } else {
+                                    operate.await
+                                }
+                            } else {
+                                this.operate_unit(operation, node, options, progress).await
+                            };
+                            let flushed = this.flush().await;
+                            let report = res?;
+                            if deep {
+                                this.schedule_status_file();
+                            }
+                            flushed.map(|()| report)
+                        })
+                    },
+                )
+                .await
+            }))
+        };
+
+        // see the contract of `Fsync::operate`
+        let sleep = tokio::time::sleep(fsync::OPERATE_REPLY_DELAY);
+
+        tokio::select! {
+            res = join => {
+                log::trace!("Operation completed within {:?}", fsync::OPERATE_REPLY_DELAY);
+                match res {
+                    Ok(Ok(report)) => {
+                        if cfg!(debug_assertions) {
+                            let (_, prog) = rx.try_recv().expect("should receive at least root progress");
+                            debug_assert!(prog.get().is_done());
+                        }
+                        // the failed entries are kept, for the clients to list them
+                        while let Ok((path, prog)) = rx.try_recv() {
+                            if prog.get().is_failed() {
+                                self.add_progress(path, prog).await;
+                            }
+                        }
+                        Ok(Progress::done(report))
+                    },
+                    Ok(Err(e)) => Err(e),
+                    Err(err) => Err(fsync::Error::Bug(err.to_string())),
+                }
+            },
+            _ = sleep => {
+                log::trace!("Operation still running after {:?}", fsync::OPERATE_REPLY_DELAY);
+
+                let (path, first_progress) = rx
+                    .try_recv()
+                    .expect("should receive at least root progress");
+
+                self.add_progress(path, first_progress.clone()).await;
+
+                tokio::spawn(async move {
+                    while let Some((path, progress)) = rx.recv().await {
+                        self.add_progress(path, progress).await;
+                    }
+                });
+                Ok(first_progress.get())
+            },
+        }
+    }
+}
+
+impl<L, R> crate::Shutdown for Service<L, R>
+where
+    L: storage::Storage,
+    R: storage::Storage,
+{
+    async fn shutdown(&self) -> anyhow::Result<()> {
+        log::info!("Shutting service down");
+        {
+            let abort_handle = self.abort_handle.read().await;
+            if let Some(abort_handle) = &*abort_handle {
+                abort_handle.abort();
+            }
+        }
+        if let Err(err) = self.counters.persist().await {
+            log::error!("Could not persist the counters: {err:#}");
+        }
+        let fut1 = self.local.shutdown();
+        let fut2 = self.remote.shutdown();
+        tokio::try_join!(fut1, fut2)?;
+        Ok(())
+    }
+}
+
+async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
+    tokio::spawn(fut);
+}
+
+/// Number of random bytes of the authentication token
+const TOKEN_LEN: usize = 32;
+
+/// Maximum number of channels served at once
+const MAX_CHANNELS: usize = 10;
+
+#[derive(Clone, Debug)]
+pub struct RpcService<L, R> {
+    inner: Arc<Service<L, R>>,
+    /// Token that clients must present with [`Fsync::authenticate`]
+    token: Arc<String>,
+    /// Whether the client of the channel is authenticated.
+    /// Each channel gets its own flag.
+    authenticated: Arc<AtomicBool>,
+    /// Returned by [`Fsync::ping`], drawn at random when the daemon starts
+    boot_id: u64,
+}
+
+impl<L, R> RpcService<L, R>
+where
+    L: storage::LocalStorage,
+    R: storage::Storage,
+{
+    pub async fn new(service: Arc<Service<L, R>>, abort_handle: AbortHandle) -> Self {
+        debug_assert!(
+            service.abort_handle.read().await.is_none(),
+            "Cannot share Service among multiple RpcService"
+        );
+        *service.abort_handle.write().await = Some(abort_handle);
+        Self {
+            inner: service,
+            token: Arc::new(make_token()),
+            authenticated: Arc::new(AtomicBool::new(false)),
+            boot_id: make_boot_id(),
+        }
+    }
+
+    /// A service for a new channel, whose client is not authenticated yet
+    fn for_channel(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            token: self.token.clone(),
+            authenticated: Arc::new(AtomicBool::new(false)),
+            boot_id: self.boot_id,
+        }
+    }
+
+    fn check_auth(&self, rpc: &str) -> fsync::Result<()> {
+        if self.authenticated.load(atomic::Ordering::Relaxed) {
+            Ok(())
+        } else {
+            log::warn!(target: "RPC", "Refused Fsync::{rpc}: client is not authenticated");
+            Err(Error::Unauthorized("client is not authenticated".into()))
+        }
+    }
+
+    pub async fn start(
+        &self,
+        instance_name: &str,
+        abort_reg: AbortRegistration,
+    ) -> anyhow::Result<()> {
+        let port_path = inst::runtime_port_file(instance_name)?;
+        check_port_file(instance_name, &port_path).await?;
+
+        let server_addr = (IpAddr::V6(Ipv6Addr::LOCALHOST), 0);
+
+        let mut listener = tarpc::serde_transport::tcp::listen(&server_addr, fsync::codec).await?;
+
+        log::info!("Listening on port {}", listener.local_addr().port());
+
+        tokio::fs::create_dir_all(port_path.parent().unwrap()).await?;
+
+        // the token is written first, so that clients finding the port can authenticate
+        let token_path = inst::runtime_token_file(instance_name)?;
+        log::trace!("Creating file {token_path}");
+        {
+            let token_path = token_path.clone();
+            let token = self.token.clone();
+            tokio::task::spawn_blocking(move || {
+                persist::atomic_write_private(&token_path, token.as_bytes())
+            })
+            .await??;
+        }
+
+        let port_file = PortFile::new(listener.local_addr().port(), self.boot_id)
+            .with_config_fingerprint(self.inner.config_fingerprint());
+        log::trace!("Creating file {port_path}");
+        write_port_file(port_path.clone(), &port_file).await?;
+        *self.inner.port_file.lock().unwrap() = Some((port_path.clone(), port_file.clone()));
+
+        listener.config_mut().max_frame_length(usize::MAX);
+        let fut = listener
+            // Ignore accept errors.
+            .filter_map(|r| future::ready(r.ok()))
+            .map(server::BaseChannel::with_defaults)
+            // The clients all connect from the loopback address: the limit per IP is the total,
+            // so that a client connected does not make the probes of the port file fail.
+            .max_channels_per_key(MAX_CHANNELS as u32, |t| {
+                t.transport().peer_addr().unwrap().ip()
+            })
+            // serve is generated by the service attribute. It takes as input any type implementing
+            // the generated Fsync trait.
+            .map(|channel| {
+                channel
+                    .execute(TimedServe(self.for_channel().serve()))
+                    .for_each(spawn)
+            })
+            .buffer_unordered(MAX_CHANNELS)
+            .for_each(|_| async {});
+
+        let _ = Abortable::new(fut, abort_reg).await;
+
+        log::trace!("Removing file {port_path}");
+        // as rewritten by the reloads of the config, unless a daemon started since then replaced it
+        let port_file = self.inner.port_file.lock().unwrap().take();
+        if let Some((_, port_file)) = port_file {
+            port_file.remove_stale(&port_path)?;
+        }
+        log::trace!("Removing file {token_path}");
+        tokio::fs::remove_file(&token_path).await?;
+        Ok(())
+    }
+}
+
+impl<L, R> Fsync for RpcService<L, R>
+where
+    L: storage::LocalStorage,
+    R: storage::Storage,
+{
+    async fn protocol_version(self, _: Context) -> u32 {
+        fsync::PROTOCOL_VERSION
+    }
+
+    async fn conflicts(
+        self,
+        _: Context,
+        start: Option<PathBuf>,
+        max_len: u32,
+    ) -> fsync::Result<Vec<fsync::tree::Entry>> {
+        self.check_auth("conflicts")?;
+        let max_len = max_len.min(100);
+        let res = self.inner.conflicts(start.as_deref(), max_len as _).await;
+        log::trace!(target: "RPC", "Fsync::conflicts({start:?}, {max_len}) -> {res:#?}");
+        if let Ok(conflicts) = &res {
+            let paths = conflicts.iter().map(|c| c.path().to_owned()).collect();
+            self.inner.schedule_revalidation(paths);
+        }
+        res
+    }
+
+    async fn conflicts_filtered(
+        self,
+        _: Context,
+        start: Option<PathBuf>,
+        max_len: u32,
+        filter: EntryFilter,
+    ) -> fsync::Result<Vec<fsync::tree::Entry>> {
+        self.check_auth("conflicts_filtered")?;
+        let max_len = max_len.min(100);
+        let res = self
+            .inner
+            .conflicts_filtered(start.as_deref(), max_len as _, &filter)
+            .await;
+        log::trace!(target: "RPC", "Fsync::conflicts_filtered({start:?}, {max_len}, {filter:?}) -> {res:#?}");
+        if let Ok(conflicts) = &res {
+            let paths = conflicts.iter().map(|c| c.path().to_owned()).collect();
+            self.inner.schedule_revalidation(paths);
+        }
+        res
+    }
+
+    async fn entry_node(
+        self,
+        _: Context,
+        path: PathBuf,
+    ) -> fsync::Result<Option<fsync::tree::EntryNode>> {
+        self.check_auth("entry_node")?;
+        let res = self.inner.entry_node(&path).await;
+        log::trace!(target: "RPC", "Fsync::entry(path: {path:?}) -> {res:#?}");
+        if let Ok(Some(node)) = &res {
+            if node.entry().is_conflict() {
+                self.inner.schedule_revalidation(vec![path]);
+            }
+        }
+        res
+    }
+
+    async fn local_path(self, _: Context, path: Option<PathBuf>) -> fsync::Result<FsPathBuf> {
+        self.check_auth("local_path")?;
+        let res = self.inner.local_path(path.as_deref()).await;
+        log::trace!(target: "RPC", "Fsync::local_path(path: {path:?}) -> {res:#?}");
+        res
+    }
+
+    async fn operate(self, _: Context, operation: fsync::Operation) -> fsync::Result<Progress> {
+        self.check_auth("operate")?;
+        if log::log_enabled!(log::Level::Trace) {
+            let op = operation.clone();
+            let res = self.inner.operate(operation).await;
+            log::trace!(target: "RPC", "Fsync::operate({op:?}) -> {res:#?}");
+            res
+        } else {
+            self.inner.operate(operation).await
+        }
+    }
+
+    async fn operate_with(
+        self,
+        _: Context,
+        operation: fsync::Operation,
+        options: OperateOptions,
+    ) -> fsync::Result<Progress> {
+        self.check_auth("operate_with")?;
+        if log::log_enabled!(log::Level::Trace) {
+            let op = operation.clone();
+            let res = self.inner.operate_with(operation, options.clone()).await;
+            log::trace!(target: "RPC", "Fsync::operate_with({op:?}, {options:?}) -> {res:#?}");
+            res
+        } else {
+            self.inner.operate_with(operation, options).await
+        }
+    }
+
+    async fn too_large(
+        self,
+        _: Context,
+        start: Option<PathBuf>,
+        max_len: u32,
+    ) -> fsync::Result<Vec<fsync::tree::Entry>> {
+        self.check_auth("too_large")?;
+        let max_len = max_len.min(100);
+        let res = self.inner.too_large(start.as_deref(), max_len as _).await;
+        log::trace!(target: "RPC", "Fsync::too_large({start:?}, {max_len}) -> {res:#?}");
+        res
+    }
+
+    async fn too_large_stats(
+        self,
+        _: Context,
+        paths: Vec<PathBuf>,
+    ) -> fsync::Result<Vec<stat::Flagged>> {
+        self.check_auth("too_large_stats")?;
+        let res = self.inner.too_large_stats(&paths);
+        log::trace!(target: "RPC", "Fsync::too_large_stats({paths:?}) -> {res:#?}");
+        res
+    }
+
+    async fn name_clashes(self, _: Context, paths: Vec<PathBuf>) -> fsync::Result<Vec<bool>> {
+        self.check_auth("name_clashes")?;
+        let res = self.inner.name_clashes(&paths);
+        log::trace!(target: "RPC", "Fsync::name_clashes({paths:?}) -> {res:#?}");
+        res
+    }
+
+    async fn progress(self, _: Context, path: PathBuf) -> fsync::Result<Option<fsync::Progress>> {
+        self.check_auth("progress")?;
+        let res = self.inner.progress(&path).await;
+        log::trace!(target: "RPC", "Fsync::progress(path: {path:?}) -> {res:#?}");
+        res
+    }
+
+    async fn progresses(
+        self,
+        _: Context,
+        path: PathBuf,
+    ) -> fsync::Result<Vec<(PathBuf, fsync::Progress)>> {
+        self.check_auth("progresses")?;
+        let res = self.inner.progresses(&path).await;
+        log::trace!(target: "RPC", "Fsync::progresses({path:#?}) -> {res:#?}");
+        res
+    }
+
+    async fn progresses_since(
+        self,
+        _: Context,
+        path: PathBuf,
+        cursor: u64,
+    ) -> fsync::Result<(u64, Vec<(PathBuf, fsync::Progress)>)> {
+        self.check_auth("progresses_since")?;
+        let res = self.inner.progresses_since(&path, cursor).await;
+        log::trace!(target: "RPC", "Fsync::progresses_since({path:#?}, {cursor}) -> {res:#?}");
+        res
+    }
+
+    async fn remote_link(self, _: Context, path: PathBuf) -> fsync::Result<fsync::RemoteLink> {
+        self.check_auth("remote_link")?;
+        let res = self.inner.remote_link(&path).await;
+        log::trace!(target: "RPC", "Fsync::remote_link({path:?}) -> {res:#?}");
+        res
+    }
+
+    async fn share_public(
+        self,
+        _: Context,
+        path: PathBuf,
+        confirmation: Option<String>,
+    ) -> fsync::Result<fsync::RemoteLink> {
+        self.check_auth("share_public")?;
+        let res = self
+            .inner
+            .share_public(&path, confirmation.as_deref())
+            .await;
+        log::trace!(target: "RPC", "Fsync::share_public({path:?}, ..) -> {res:#?}");
+        res
+    }
+
+    async fn logs_tail(
+        self,
+        _: Context,
+        max: u32,
+        min_level: fsync::LogLevel,
+    ) -> fsync::Result<Vec<fsync::LogRecord>> {
+        self.check_auth("logs_tail")?;
+        let max = max.min(MAX_LOG_RECORDS);
+        let records = logs::buffer().tail(max as _, min_level);
+        // not the records, that would be logged again at each call
+        log::trace!(target: "RPC", "Fsync::logs_tail({max}, {min_level:?}) -> {} records", records.len());
+        Ok(records)
+    }
+
+    async fn logs_since(
+        self,
+        _: Context,
+        cursor: u64,
+        max: u32,
+        min_level: fsync::LogLevel,
+    ) -> fsync::Result<(u64, Vec<fsync::LogRecord>)> {
+        self.check_auth("logs_since")?;
+        let max = max.min(MAX_LOG_RECORDS);
+        let (next, records) = logs::buffer().since(cursor, max as _, min_level);
+        log::trace!(
+            target: "RPC",
+            "Fsync::logs_since({cursor}, {max}, {min_level:?}) -> ({next}, {} records)",
+            records.len()
+        );
+        Ok((next, records))
+    }
+
+    async fn read_stream(
+        self,
+        _: Context,
+        path: PathBuf,
+        offset: u64,
+        max_bytes: u32,
+    ) -> fsync::Result<fsync::StreamChunk> {
+        self.check_auth("read_stream")?;
+        let max_bytes = max_bytes.min(fsync::MAX_STREAM_CHUNK);
+        let res = self
+            .inner
+            .read_stream(&path, offset, max_bytes as u64)
+            .await;
+        log::trace!(
+            target: "RPC",
+            "Fsync::read_stream({path:?}, {offset}, {max_bytes}) -> {:?}",
+            res.as_ref().map(|chunk| chunk.data.len())
+        );
+        res
+    }
+
+    async fn receive_dir(self, _: Context, path: PathBuf) -> fsync::Result<()> {
+        self.check_auth("receive_dir")?;
+        let res = self.inner.receive_dir(&path).await;
+        log::trace!(target: "RPC", "Fsync::receive_dir({path:?}) -> {res:?}");
+        res
+    }
+
+    async fn receive_begin(self, _: Context, metadata: Metadata) -> fsync::Result<u64> {
+        self.check_auth("receive_begin")?;
+        let res = self.inner.receive_begin(metadata.clone()).await;
+        log::trace!(target: "RPC", "Fsync::receive_begin({metadata:?}) -> {res:?}");
+        res
+    }
+
+    async fn receive_chunk(self, _: Context, handle: u64, data: Vec<u8>) -> fsync::Result<()> {
+        self.check_auth("receive_chunk")?;
+        if data.len() > fsync::MAX_STREAM_CHUNK as usize {
+            fsync::other_bail!("Chunks are limited to {} bytes", fsync::MAX_STREAM_CHUNK);
+        }
+        let res = self.inner.receive_chunk(handle, &data).await;
+        log::trace!(
+            target: "RPC",
+            "Fsync::receive_chunk({handle}, {} bytes) -> {res:?}",
+            data.len()
+        );
+        res
+    }
+
+    async fn receive_finish(self, _: Context, handle: u64, md5: String) -> fsync::Result<Metadata> {
+        self.check_auth("receive_finish")?;
+        let res = self.inner.receive_finish(handle, &md5).await;
+        log::trace!(target: "RPC", "Fsync::receive_finish({handle}, {md5:?}) -> {res:#?}");
+        res
+    }
+
+    async fn verify(
+        self,
+        _: Context,
+        path: PathBuf,
+        deep: bool,
+        after: Option<PathBuf>,
+        deadline: SystemTime,
+    ) -> fsync::Result<fsync::VerificationReport> {
+        self.check_auth("verify")?;
+        let res = self
+            .inner
+            .verify(&path, deep, after.as_deref(), deadline)
+            .await;
+        log::trace!(target: "RPC", "Fsync::verify({path:?}, {deep}, {after:?}, {deadline:?}) -> {res:#?}");
+        res
+    }
+
+    async fn verify_checkpoint(self, _: Context) -> fsync::Result<Option<fsync::VerifyCheckpoint>> {
+        self.check_auth("verify_checkpoint")?;
+        let res = Ok(self.inner.verify_checkpoint());
+        log::trace!(target: "RPC", "Fsync::verify_checkpoint() -> {res:#?}");
+        res
+    }
+
+    async fn instance_stats(self, _: Context) -> fsync::Result<fsync::InstanceStats> {
+        self.check_auth("instance_stats")?;
+        let res = self.inner.instance_stats().await;
+        log::trace!(target: "RPC", "Fsync::instance_stats() -> {res:#?}");
+        res
+    }
+
+    async fn plan(self, _: Context, operation: Operation) -> fsync::Result<PlanId> {
+        self.check_auth("plan")?;
+        let res = self.inner.plan(operation.clone()).await;
+        log::trace!(target: "RPC", "Fsync::plan({operation:?}) -> {res:#?}");
+        res
+    }
+
+    async fn plan_next(
+        self,
+        _: Context,
+        plan: PlanId,
+        max_len: u32,
+    ) -> fsync::Result<Vec<PlannedAction>> {
+        self.check_auth("plan_next")?;
+        let res = self.inner.plan_next(plan, max_len as _).await;
+        log::trace!(
+            target: "RPC",
+            "Fsync::plan_next({plan}, {max_len}) -> {:?}",
+            res.as_ref().map(|actions| actions.len())
+        );
+        res
+    }
+
+    async fn gc_tree(self, _: Context) -> fsync::Result<Vec<PathBuf>> {
+        self.check_auth("gc_tree")?;
+        let res = self.inner.gc_tree().await;
+        log::trace!(target: "RPC", "Fsync::gc_tree() -> {res:#?}");
+        res
+    }
+
+    async fn read_head(
+        self,
+        _: Context,
+        path: PathBuf,
+        loc: StorageLoc,
+        max_bytes: u64,
+    ) -> fsync::Result<Vec<u8>> {
+        self.check_auth("read_head")?;
+        let max_bytes = max_bytes.min(MAX_READ_HEAD);
+        let res = self.inner.read_head(&path, loc, max_bytes).await;
+        log::trace!(
+            target: "RPC",
+            "Fsync::read_head({path:?}, {loc:?}, {max_bytes}) -> {:?}",
+            res.as_ref().map(|head| head.len())
+        );
+        res
+    }
+
+    async fn rescan(
+        self,
+        _: Context,
+        path: PathBuf,
+        deep: bool,
+    ) -> fsync::Result<fsync::RescanReport> {
+        self.check_auth("rescan")?;
+        let res = self.inner.rescan(&path, deep).await;
+        log::trace!(target: "RPC", "Fsync::rescan({path:?}, {deep}) -> {res:#?}");
+        res
+    }
+
+    async fn set_pinned(self, _: Context, path: PathBuf, pinned: bool) -> fsync::Result<()> {
+        self.check_auth("set_pinned")?;
+        let res = self.inner.set_pinned(&path, pinned).await;
+        log::trace!(target: "RPC", "Fsync::set_pinned({path:?}, {pinned}) -> {res:#?}");
+        res
+    }
+
+    async fn pinned(self, _: Context) -> fsync::Result<Vec<PathBuf>> {
+        self.check_auth("pinned")?;
+        let res = self.inner.pinned().await;
+        log::trace!(target: "RPC", "Fsync::pinned() -> {res:#?}");
+        Ok(res)
+    }
+
+    async fn status(self, _: Context) -> fsync::Result<fsync::Status> {
+        self.check_auth("status")?;
+        let res = self.inner.status();
+        log::trace!(target: "RPC", "Fsync::status() -> {res:#?}");
+        Ok(res)
+    }
+
+    async fn clear_cache(self, _: Context, content: bool, metadata: bool) -> fsync::Result<u64> {
+        self.check_auth("clear_cache")?;
+        let res = self.inner.clear_cache(content, metadata).await;
+        log::trace!(target: "RPC", "Fsync::clear_cache({content}, {metadata}) -> {res:#?}");
+        res
+    }
+
+    async fn publicly_shared(
+        self,
+        _: Context,
+        path: PathBuf,
+        max_len: u32,
+    ) -> fsync::Result<Vec<fsync::tree::Entry>> {
+        self.check_auth("publicly_shared")?;
+        let max_len = max_len.min(100);
+        let res = self.inner.publicly_shared(&path, max_len as _).await;
+        log::trace!(target: "RPC", "Fsync::publicly_shared({path:?}, {max_len}) -> {res:#?}");
+        res
+    }
+
+    async fn sharing(
+        self,
+        _: Context,
+        paths: Vec<PathBuf>,
+    ) -> fsync::Result<Vec<Option<fsync::Sharing>>> {
+        self.check_auth("sharing")?;
+        let res = self.inner.sharing(&paths);
+        log::trace!(target: "RPC", "Fsync::sharing({paths:?}) -> {res:#?}");
+        res
+    }
+
+    async fn send_digest(self, _: Context, force: bool) -> fsync::Result<bool> {
+        self.check_auth("send_digest")?;
+        let res = self.inner.send_digest(force).await;
+        log::trace!(target: "RPC", "Fsync::send_digest({force}) -> {res:#?}");
+        res
+    }
+
+    async fn entry_nodes(
+        self,
+        _: Context,
+        path: PathBuf,
+        order: Option<SortOrder>,
+        after: Option<String>,
+        max_len: u32,
+    ) -> fsync::Result<Vec<EntryNode>> {
+        self.check_auth("entry_nodes")?;
+        let max_len = max_len.min(1000);
+        let order = order.unwrap_or_default();
+        let res = self
+            .inner
+            .entry_nodes(&path, order, after.as_deref(), max_len as _)
+            .await;
+        log::trace!(target: "RPC", "Fsync::entry_nodes({path:?}, {order:?}, {after:?}, {max_len}) -> {res:#?}");
+        res
+    }
+
+    async fn run_maintenance(
+        self,
+        _: Context,
+        dry_run: bool,
+    ) -> fsync::Result<fsync::MaintenanceReport> {
+        self.check_auth("run_maintenance")?;
+        let res = self.inner.maintain(dry_run).await;
+        log::trace!(target: "RPC", "Fsync::run_maintenance({dry_run}) -> {res:#?}");
+        res
+    }
+
+    async fn entry_node_page(
+        self,
+        _: Context,
+        path: PathBuf,
+        after: Option<String>,
+        max_len: u32,
+    ) -> fsync::Result<Option<fsync::tree::EntryNode>> {
+        self.check_auth("entry_node_page")?;
+        let max_len = (max_len as usize).min(tree::CHILDREN_PAGE_LEN);
+        let res = self
+            .inner
+            .entry_node_page(&path, after.as_deref(), max_len)
+            .await;
+        log::trace!(target: "RPC", "Fsync::entry_node_page({path:?}, {after:?}, {max_len}) -> {res:#?}");
+        res
+    }
+
+    async fn checksum(
+        self,
+        _: Context,
+        path: PathBuf,
+        algo: fsync::HashAlgo,
+    ) -> fsync::Result<Checksum> {
+        self.check_auth("checksum")?;
+        let res = self.inner.checksum(&path, algo).await;
+        log::trace!(target: "RPC", "Fsync::checksum({path:?}, {algo:?}) -> {res:#?}");
+        res
+    }
+
+    async fn ping(self, _: Context) -> u64 {
+        self.boot_id
+    }
+
+    async fn aggregation(
+        self,
+        _: Context,
+        paths: Vec<PathBuf>,
+    ) -> fsync::Result<Vec<stat::Aggregation>> {
+        self.check_auth("aggregation")?;
+        let res = self.inner.aggregation(&paths);
+        log::trace!(target: "RPC", "Fsync::aggregation({paths:?}) -> {res:#?}");
+        res
+    }
+
+    async fn aggregate_now(self, _: Context, path: PathBuf) -> fsync::Result<()> {
+        self.check_auth("aggregate_now")?;
+        let res = self.inner.aggregate_now(&path);
+        log::trace!(target: "RPC", "Fsync::aggregate_now({path:?}) -> {res:#?}");
+        res
+    }
+
+    async fn self_check(self, _: Context) -> fsync::Result<Vec<fsync::Discrepancy>> {
+        self.check_auth("self_check")?;
+        let res = self.inner.self_check().await;
+        log::trace!(target: "RPC", "Fsync::self_check() -> {res:#?}");
+        res
+    }
+
+    async fn self_check_until(
+        self,
+        _: Context,
+        after: Option<String>,
+        deadline: SystemTime,
+    ) -> fsync::Result<fsync::SelfCheckPage> {
+        self.check_auth("self_check_until")?;
+        let res = self
+            .inner
+            .self_check_until(after.as_deref(), deadline)
+            .await;
+        log::trace!(target: "RPC", "Fsync::self_check_until({after:?}, {deadline:?}) -> {res:#?}");
+        res
+    }
+
+    async fn counters(self, _: Context) -> fsync::Result<fsync::InstanceCounters> {
+        self.check_auth("counters")?;
+        let res = self.inner.counters();
+        log::trace!(target: "RPC", "Fsync::counters() -> {res:#?}");
+        Ok(res)
+    }
+
+    async fn cache_lookups(self, _: Context) -> fsync::Result<Option<fsync::CacheLookups>> {
+        self.check_auth("cache_lookups")?;
+        let res = self.inner.cache_lookups();
+        log::trace!(target: "RPC", "Fsync::cache_lookups() -> {res:#?}");
+        Ok(res)
+    }
+
+    async fn implausible_stats(
+        self,
+        _: Context,
+        paths: Vec<PathBuf>,
+    ) -> fsync::Result<Vec<stat::Flagged>> {
+        self.check_auth("implausible_stats")?;
+        let res = self.inner.implausible_stats(&paths);
+        log::trace!(target: "RPC", "Fsync::implausible_stats({paths:?}) -> {res:#?}");
+        res
+    }
+
+    async fn repairs(self, _: Context) -> fsync::Result<fsync::InstanceRepairs> {
+        self.check_auth("repairs")?;
+        let res = self.inner.repairs();
+        log::trace!(target: "RPC", "Fsync::repairs() -> {res:#?}");
+        Ok(res)
+    }
+
+    async fn buffer_usage(self, _: Context) -> fsync::Result<Option<fsync::BufferUsage>> {
+        self.check_auth("buffer_usage")?;
+        let res = self.inner.buffer_usage();
+        log::trace!(target: "RPC", "Fsync::buffer_usage() -> {res:#?}");
+        Ok(res)
+    }
+
+    async fn transfers(self, _: Context) -> fsync::Result<fsync::InstanceTransfers> {
+        self.check_auth("transfers")?;
+        let res = self.inner.transfers();
+        log::trace!(target: "RPC", "Fsync::transfers() -> {res:#?}");
+        Ok(res)
+    }
+
+    async fn root_change(self, _: Context) -> fsync::Result<Option<fsync::RootChange>> {
+        self.check_auth("root_change")?;
+        let res = self.inner.root_change();
+        log::trace!(target: "RPC", "Fsync::root_change() -> {res:#?}");
+        Ok(res)
+    }
+
+    async fn migrate_root(self, _: Context, accept: fsync::MigrationChoice) -> fsync::Result<()> {
+        self.check_auth("migrate_root")?;
+        let res = self.inner.clone().migrate_root(accept).await;
+        log::trace!(target: "RPC", "Fsync::migrate_root({accept:?}) -> {res:#?}");
+        res
+    }
+
+    async fn revalidate_conflicts(
+        self,
+        _: Context,
+        paths: Vec<PathBuf>,
+    ) -> fsync::Result<Vec<PathBuf>> {
+        self.check_auth("revalidate_conflicts")?;
+        let res = self.inner.revalidate_conflicts(&paths, true).await;
+        log::trace!(target: "RPC", "Fsync::revalidate_conflicts({paths:?}) -> {res:#?}");
+        res
+    }
+
+    async fn authenticate(self, _: Context, token: String) -> fsync::Result<()> {
+        if tokens_match(&token, &self.token) {
+            self.authenticated.store(true, atomic::Ordering::Relaxed);
+            log::trace!(target: "RPC", "Fsync::authenticate() -> Ok");
+            Ok(())
+        } else {
+            log::warn!(target: "RPC", "Refused authentication of a client with a wrong token");
+            Err(Error::Unauthorized("wrong token".into()))
+        }
+    }
+
+    async fn view_only(
+        self,
+        _: Context,
+        paths: Vec<PathBuf>,
+    ) -> fsync::Result<Vec<Option<fsync::ViewOnly>>> {
+        self.check_auth("view_only")?;
+        let res = self.inner.view_only(&paths);
+        log::trace!(target: "RPC", "Fsync::view_only({paths:?}) -> {res:#?}");
+        res
+    }
+
+    async fn operate_scheduled(
+        self,
+        _: Context,
+        operation: fsync::Operation,
+        options: OperateOptions,
+    ) -> fsync::Result<Progress> {
+        self.check_auth("operate_scheduled")?;
+        if log::log_enabled!(log::Level::Trace) {
+            let op = operation.clone();
+            let res = self
+                .inner
+                .operate_scheduled(operation, options.clone())
+                .await;
+            log::trace!(target: "RPC", "Fsync::operate_scheduled({op:?}, {options:?}) -> {res:#?}");
+            res
+        } else {
+            self.inner.operate_scheduled(operation, options).await
+        }
+    }
+
+    async fn reload_config(self, _: Context) -> fsync::Result<()> {
+        self.check_auth("reload_config")?;
+        let res = self.inner.reload_config().await;
+        log::trace!(target: "RPC", "Fsync::reload_config() -> {res:#?}");
+        res
+    }
+
+    async fn timings(self, _: Context) -> fsync::Result<fsync::Timings> {
+        self.check_auth("timings")?;
+        let res = profile::timings();
+        log::trace!(target: "RPC", "Fsync::timings() -> {res:#?}");
+        Ok(res)
+    }
+
+    async fn resume(self, _: Context) -> fsync::Result<Progress> {
+        self.check_auth("resume")?;
+        let res = self.inner.resume().await;
+        log::trace!(target: "RPC", "Fsync::resume() -> {res:#?}");
+        res
+    }
+
+    async fn remote_revisions(
+        self,
+        _: Context,
+        path: PathBuf,
+        max: u32,
+    ) -> fsync::Result<Vec<fsync::Revision>> {
+        self.check_auth("remote_revisions")?;
+        let res = self.inner.remote_revisions(&path, max).await;
+        log::trace!(target: "RPC", "Fsync::remote_revisions({path:?}, {max}) -> {res:#?}");
+        res
+    }
+
+    async fn download_revision(
+        self,
+        _: Context,
+        path: PathBuf,
+        revision_id: String,
+        dest: PathBuf,
+    ) -> fsync::Result<Metadata> {
+        self.check_auth("download_revision")?;
+        let res = self
+            .inner
+            .download_revision(&path, &revision_id, &dest)
+            .await;
+        log::trace!(
+            target: "RPC",
+            "Fsync::download_revision({path:?}, {revision_id:?}, {dest:?}) -> {res:#?}"
+        );
+        res
+    }
+}
+
+async fn write_port_file(path: FsPathBuf, port_file: &PortFile) -> fsync::Result<()> {
+    let content = port_file.to_json();
+    tokio::task::spawn_blocking(move || persist::atomic_write(&path, content.as_bytes()))
+        .await
+        .map_err(|err| Error::Bug(err.to_string()))??;
+    Ok(())
+}
+
+/// A random token, hex encoded
+fn make_token() -> String {
+    use aes_gcm::aead::{rand_core::RngCore, OsRng};
+
+    let mut bytes = [0u8; TOKEN_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Create or overwrite the file of `metadata` in `storage` with `data`
+async fn write_whole<S>(storage: &S, metadata: &Metadata, data: &[u8]) -> fsync::Result<()>
+where
+    S: storage::Storage,
+{
+    if storage.exists(metadata.path()).await? {
+        storage.write_file(metadata, data, None).await?;
+    } else {
+        storage.create_file(metadata, data, None).await?;
+    }
+    Ok(())
+}
+
+/// Check the port file left at `port_path` by a previous daemon of `instance_name`.
+/// Fails if that daemon still answers, otherwise the file is stale and will be overwritten.
+async fn check_port_file(instance_name: &str, port_path: &FsPath) -> anyhow::Result<()> {
+    let port_file = match PortFile::load(port_path) {
+        Ok(Some(port_file)) => port_file,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            log::warn!("Overwriting the port file: {err:#}");
+            return Ok(());
+        }
+    };
+    if port_file.is_live().await {
+        anyhow::bail!(
+            "fsyncd {instance_name} is already running on port {}",
+            port_file.port
+        );
+    }
+    match port_file.pid {
+        Some(pid) => log::warn!("Overwriting the stale port file of process {pid}: {port_path}"),
+        None => log::warn!("Overwriting the stale port file {port_path}"),
+    }
+    Ok(())
+}
+
+/// A random id, telling apart the successive runs of the daemon
+fn make_boot_id() -> u64 {
+    use aes_gcm::aead::{rand_core::RngCore, OsRng};
+
+    OsRng.next_u64()
+}
+
+/// Compare tokens in a time that does not depend on where they differ
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// The names among `names` of the children that exist in `storage`
+/// of the folder described by `dir`, or none if the folder is not in `storage`
+async fn existing_children<S: storage::ExistingChildren>(
+    storage: &S,
+    dir: Option<Metadata>,
+    names: &[String],
+) -> fsync::Result<HashSet<String>> {
+    match dir {
+        Some(dir) if dir.is_dir() => storage.existing_children(dir.path(), names).await,
+        _ => Ok(HashSet::new()),
+    }
+}
+
+/// The entries of the storages that `action` on `path` may modify
+fn journal_targets(path: &Path, action: &Action) -> Vec<journal::Target> {
+    let target = |loc| journal::Target {
+        loc,
+        path: path.to_owned(),
+    };
+    match action {
+        Action::Mkdir(loc) => vec![target(*loc)],
+        Action::Copy(dir) | Action::Replace(dir) => vec![target(dir.dest())],
+        Action::CopyLocalAndReplace => vec![
+            journal::Target {
+                loc: StorageLoc::Local,
+                path: copy_path(path),
+            },
+            target(StorageLoc::Local),
+        ],
+        Action::Delete(Location::Local) => vec![target(StorageLoc::Local)],
+        Action::Delete(Location::Remote) => vec![target(StorageLoc::Remote)],
+        Action::Delete(Location::Both) | Action::MergeText => {
+            vec![target(StorageLoc::Local), target(StorageLoc::Remote)]
+        }
+        Action::Fail(..)
+        | Action::SkipTooLarge
+        | Action::SkipWithheld
+        | Action::Forget
+        | Action::SharePublic
+        | Action::Pair => vec![],
+    }
+}
+
+fn copy_path(path: &Path) -> PathBuf {
+    debug_assert!(!path.is_root());
+    let parent = path
+        .parent()
+        .expect("Expected path to be copied to have a parent");
+    let stem = path
+        .file_stem()
+        .expect("Expected path to be copied to have a name");
+    let ext = path.extension();
+
+    let cap = parent.as_str().len() + stem.len() + ext.map_or(0, |e| e.len() + 1) + 6;
+
+    let mut res = PathBuf::with_capacity(cap);
+    res.push(parent);
+    res.push(format!("{stem}-copy"));
+    if let Some(ext) = ext {
+        res.set_extension(ext);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        ops::Bound,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use chrono::{DateTime, Utc};
+    use fsync::{
+        filter::EntryFilter,
+        path::{FsPathBuf, Path, PathBuf},
+        Conflict, DeletionMethod, Error, Fsync, Metadata, OperateOptions, Operation, Progress,
+        ResolutionMethod, SortOrder, StorageDir, StorageLoc,
+    };
+    use futures::{stream::AbortHandle, FutureExt, StreamExt};
+    use proptest::prelude::*;
+    use tarpc::{context, server::Channel};
+
+    use super::{tokens_match, tree_conflicts, DeepRun, RpcService, Service};
+    use crate::{
+        journal::{Journal, Stage},
+        policy::{self, Policy},
+        resume::Resumes,
+        status_file,
+        storage::mem::MemStorage,
+        tree::DiffTree,
+        SharedProgress,
+    };
+
+    #[test]
+    fn test_copy_path() {
+        use super::copy_path;
+
+        assert_eq!(
+            Path::new("/parent/stem-copy.ext"),
+            copy_path(Path::new("/parent/stem.ext"))
+        );
+
+        assert_eq!(
+            Path::new("/parent/noext-copy"),
+            copy_path(Path::new("/parent/noext"))
+        );
+
+        assert_eq!(
+            Path::new("/file_at_root-copy.ext"),
+            copy_path(Path::new("/file_at_root.ext"))
+        );
+    }
+
+    fn build_test_conflicts() -> BTreeSet<PathBuf> {
+        let mut set = BTreeSet::new();
+
+        set.insert(PathBuf::from("/a/a/a"));
+        set.insert(PathBuf::from("/a/a/b"));
+        set.insert(PathBuf::from("/a/b/a"));
+        set.insert(PathBuf::from("/a/b/b"));
+
+        set.insert(PathBuf::from("/b/a/a"));
+        set.insert(PathBuf::from("/b/a/b"));
+        set.insert(PathBuf::from("/b/b/a"));
+        set.insert(PathBuf::from("/b/b/b"));
+
+        set.insert(PathBuf::from("/c/a/a"));
+        set.insert(PathBuf::from("/c/a/b"));
+        set.insert(PathBuf::from("/c/b/a"));
+        set.insert(PathBuf::from("/c/b/b"));
+
+        set
+    }
+
+    fn conflicts_of(conflicts: &BTreeSet<PathBuf>, path: &Path) -> Vec<PathBuf> {
+        let mut res = Vec::new();
+
+        let start = Bound::Included(path.to_owned());
+        let end = Bound::Unbounded;
+
+        let conf = conflicts.range((start, end));
+        for entry in conf {
+            if entry.as_str().starts_with(path.as_str()) {
+                res.push(entry.clone());
+            } else {
+                break;
+            }
+        }
+
+        res
+    }
+
+    #[test]
+    fn test_dir_contain_conflict() {
+        let conflicts = build_test_conflicts();
+
+        let bs = conflicts_of(&conflicts, Path::new("/b"));
+
+        assert_eq!(bs.len(), 4);
+        assert_eq!(bs[0], PathBuf::from("/b/a/a"));
+        assert_eq!(bs[1], PathBuf::from("/b/a/b"));
+        assert_eq!(bs[2], PathBuf::from("/b/b/a"));
+        assert_eq!(bs[3], PathBuf::from("/b/b/b"));
+    }
+
+    /// Updating 100k conflicts under a single lock, as during a big refresh,
+    /// against updating them one by one
+    #[tokio::test]
+    async fn conflicts_bulk_update() {
+        const LEN: usize = 100_000;
+        let service = Service::new(MemStorage::new(), MemStorage::new(), local_root())
+            .await
+            .unwrap();
+        let paths: Vec<PathBuf> = (0..LEN)
+            .map(|i| PathBuf::from(format!("/dir-{:03}/file-{i:06}", i % 100)))
+            .collect();
+
+        let start = Instant::now();
+        for path in &paths {
+            service.check_conflicts_bulk([(path.clone(), true)]).await;
+        }
+        let one_by_one = start.elapsed();
+        let expected = service.conflicts.write().await.split_off(Path::root());
+
+        let start = Instant::now();
+        let updates = paths.iter().map(|path| (path.clone(), true));
+        service.check_conflicts_bulk(updates).await;
+        let bulk = start.elapsed();
+
+        assert_eq!(*service.conflicts.read().await, expected);
+        assert!(
+            bulk < one_by_one / 2,
+            "bulk update took {bulk:?}, one by one {one_by_one:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn conflicts_resolved_outside_are_revalidated() {
+        let local = MemStorage::new();
+        let remote = MemStorage::new();
+        for path in ["/a.txt", "/b.txt"] {
+            local.put_file(Path::new(path), b"local", mtime(1));
+            remote.put_file(Path::new(path), b"remote", mtime(2));
+        }
+        let service = Service::new(local.clone(), remote, local_root())
+            .await
+            .unwrap()
+            .with_conflict_staleness(Duration::from_secs(60));
+        let paths = [PathBuf::from("/a.txt"), PathBuf::from("/b.txt")];
+        assert_eq!(service.conflicts(None, 10).await.unwrap().len(), 2);
+
+        // the state of the conflicts is not stale yet
+        local.remove(Path::new("/a.txt"));
+        let resolved = service.revalidate_conflicts(&paths, false).await.unwrap();
+        assert!(resolved.is_empty());
+        tokio::time::advance(Duration::from_secs(61)).await;
+        let resolved = service.revalidate_conflicts(&paths, false).await.unwrap();
+        assert_eq!(resolved, [PathBuf::from("/a.txt")]);
+        let node = service.entry_node(Path::new("/a.txt")).await.unwrap();
+        assert!(matches!(
+            node.unwrap().entry(),
+            fsync::tree::Entry::Remote(..)
+        ));
+
+        // forced, the checks only wait for the cooldown
+        let resolved = service.revalidate_conflicts(&paths, true).await.unwrap();
+        assert!(resolved.is_empty());
+        local.remove(Path::new("/b.txt"));
+        let resolved = service.revalidate_conflicts(&paths, true).await.unwrap();
+        assert!(resolved.is_empty());
+        tokio::time::advance(crate::revalidate::COOLDOWN).await;
+        let resolved = service.revalidate_conflicts(&paths, true).await.unwrap();
+        assert_eq!(resolved, [PathBuf::from("/b.txt")]);
+        assert!(service.conflicts(None, 10).await.unwrap().is_empty());
+    }
+
+    /// Conflicts of mixed kinds and extensions, with more of them than a batch of the filtered listing
+    async fn mixed_conflicts() -> Arc<Service<MemStorage, MemStorage>> {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        for (path, local_mtime) in [
+            ("/docs/a.docx", 2),
+            ("/docs/b.docx", 1),
+            ("/docs/c.pdf", 2),
+            ("/other/d.docx", 2),
+        ] {
+            local.put_file(Path::new(path), b"local", mtime(local_mtime));
+            remote.put_file(Path::new(path), b"remote", mtime(3 - local_mtime));
+        }
+        for i in 0..300 {
+            let ext = if i % 2 == 0 { "docx" } else { "pdf" };
+            let path = PathBuf::from(format!("/many/file-{i:03}.{ext}"));
+            local.put_file(&path, b"local", mtime(2));
+            remote.put_file(&path, b"remote", mtime(1));
+        }
+        let service = Service::new(local, remote, local_root()).await.unwrap();
+        Arc::new(service)
+    }
+
+    #[tokio::test]
+    async fn conflicts_are_filtered_by_pattern_and_kind() {
+        let service = mixed_conflicts().await;
+        let filter = |under: Option<&str>, patterns: &[&str], conflicts: &[Conflict]| EntryFilter {
+            under: under.map(PathBuf::from),
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            conflicts: conflicts.to_vec(),
+        };
+        let paths = |entries: Vec<fsync::tree::Entry>| {
+            entries
+                .iter()
+                .map(|e| e.path().as_str().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let docs = filter(Some("/docs"), &["*.docx"], &[]);
+        let listed = service.conflicts_filtered(None, 100, &docs).await.unwrap();
+        assert_eq!(paths(listed), ["/docs/a.docx", "/docs/b.docx"]);
+
+        let newer = filter(Some("/docs"), &[], &[Conflict::LocalNewer]);
+        let listed = service.conflicts_filtered(None, 100, &newer).await.unwrap();
+        assert_eq!(paths(listed), ["/docs/a.docx", "/docs/c.pdf"]);
+
+        // the directories select their sub-tree, and the negated patterns leave out their matches
+        let subtree = filter(None, &["docs/", "other/", "!*.pdf"], &[]);
+        let listed = service
+            .conflicts_filtered(None, 100, &subtree)
+            .await
+            .unwrap();
+        assert_eq!(
+            paths(listed),
+            ["/docs/a.docx", "/docs/b.docx", "/other/d.docx"]
+        );
+
+        // the pages are full, across the batches of the listing
+        let many = filter(Some("/many"), &["*.docx"], &[Conflict::LocalNewer]);
+        let mut listed: Vec<String> = Vec::new();
+        let mut start: Option<PathBuf> = None;
+        loop {
+            let page = service
+                .conflicts_filtered(start.as_deref(), 40, &many)
+                .await
+                .unwrap();
+            let len = page.len();
+            let page = paths(page);
+            let page = page
+                .into_iter()
+                .skip_while(|p| Some(p.as_str()) == start.as_ref().map(|s| s.as_str()));
+            listed.extend(page);
+            if len < 40 {
+                break;
+            }
+            assert_eq!(len, 40);
+            start = listed.last().map(PathBuf::from);
+        }
+        let expected: Vec<String> = (0..300)
+            .step_by(2)
+            .map(|i| format!("/many/file-{i:03}.docx"))
+            .collect();
+        assert_eq!(listed, expected);
+
+        // the conflicts listed without filter are unchanged
+        let all = service.conflicts(None, 100).await.unwrap();
+        assert_eq!(all.len(), 100);
+        let unfiltered = service
+            .conflicts_filtered(None, 100, &EntryFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(paths(unfiltered), paths(all));
+
+        let invalid = filter(None, &["a//b"], &[]);
+        assert!(service
+            .conflicts_filtered(None, 10, &invalid)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn deep_operations_are_restricted_by_the_filter() {
+        let service = mixed_conflicts().await;
+        let options = OperateOptions {
+            filter: Some(EntryFilter {
+                under: None,
+                patterns: vec!["*.docx".to_string(), "!many/*".to_string()],
+                conflicts: vec![Conflict::LocalNewer],
+            }),
+            ..OperateOptions::default()
+        };
+        let resolve =
+            Operation::ResolveDeep(PathBuf::root(), ResolutionMethod::ReplaceOlderByNewer);
+        service
+            .clone()
+            .operate_with(resolve, options)
+            .await
+            .unwrap();
+        while service.progress(Path::root()).await.unwrap().is_some() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let conflicts = service.conflicts.read().await.clone();
+        assert!(!conflicts.contains(Path::new("/docs/a.docx")));
+        assert!(!conflicts.contains(Path::new("/other/d.docx")));
+        assert!(conflicts.contains(Path::new("/docs/b.docx")));
+        assert!(conflicts.contains(Path::new("/docs/c.pdf")));
+        assert_eq!(conflicts.len(), 302);
+
+        // an invalid filter refuses the operation before it starts
+        let options = OperateOptions {
+            filter: Some(EntryFilter {
+                patterns: vec!["a//b".to_string()],
+                ..EntryFilter::default()
+            }),
+            ..OperateOptions::default()
+        };
+        let resolve =
+            Operation::ResolveDeep(PathBuf::root(), ResolutionMethod::ReplaceOlderByNewer);
+        assert!(service
+            .clone()
+            .operate_with(resolve, options)
+            .await
+            .is_err());
+        assert_eq!(service.conflicts.read().await.len(), 302);
+    }
+
+    /// 50k files in 550 directories, only at the local side.
+    /// The modification times are plausible, or the bulk bootstrap is not attempted.
+    fn bootstrap_fixture() -> MemStorage {
+        let local = MemStorage::new();
+        for d in 0..50 {
+            for s in 0..10 {
+                for f in 0..100 {
+                    let path = PathBuf::from(format!("/dir-{d:02}/sub-{s}/file-{f:02}.txt"));
+                    local.put_file(&path, path.as_str().as_bytes(), mtime(1_600_000_000 + f));
                 }
-                (ResolutionMethod::ReplaceLocalByRemote, _)
-                | (ResolutionMethod::ReplaceOlderByNewer, fsync::Conflict::LocalOlder)
-                | (ResolutionMethod::ReplaceNewerByOlder, fsync::Conflict::LocalNewer) => {
-                    self.do_replace(
-                        remote,
-                        &self.remote,
-                        &self.local,
-                        StorageDir::RemoteToLocal,
-                        progress,
-                    )
-                    .await
+            }
+        }
+        local
+    }
+
+    async fn bootstrap_sync(
+        bulk: bool,
+    ) -> (Arc<Service<MemStorage, MemStorage>>, MemStorage, Duration) {
+        let remote = MemStorage::new();
+        let service = Service::new(bootstrap_fixture(), remote.clone(), local_root())
+            .await
+            .unwrap()
+            .with_bulk_bootstrap(bulk);
+        let service = Arc::new(service);
+        remote.set_latency(Duration::from_millis(20));
+        let start = tokio::time::Instant::now();
+        service
+            .clone()
+            .operate(Operation::SyncDeep(PathBuf::root()))
+            .await
+            .unwrap();
+        while service.progress(Path::root()).await.unwrap().is_some() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let elapsed = start.elapsed();
+        remote.clear_faults();
+        (service, remote, elapsed)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bootstrap_is_faster_than_the_deep_sync_with_the_same_result() {
+        let (generic, generic_remote, generic_time) = bootstrap_sync(false).await;
+        let (bulk, bulk_remote, bulk_time) = bootstrap_sync(true).await;
+        assert!(
+            bulk_time * 3 < generic_time * 2,
+            "bootstrap in {bulk_time:?}, deep sync in {generic_time:?}"
+        );
+
+        assert_eq!(
+            bulk_remote.entries().len(),
+            50 * 10 * 100 + 50 * 10 + 50 + 1
+        );
+        assert_eq!(bulk_remote.entries(), generic_remote.entries());
+        let tree = |service: &Service<MemStorage, MemStorage>| {
+            service
+                .tree
+                .entries()
+                .map(|node| (node.key().clone(), node.value().clone()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        assert_eq!(tree(&bulk), tree(&generic));
+        assert!(bulk.conflicts.read().await.is_empty());
+        assert!(generic.conflicts.read().await.is_empty());
+        let root = bulk.tree.entry(Path::root()).unwrap().stats();
+        assert_eq!(root.node.sync, root.node.nodes);
+    }
+
+    #[tokio::test]
+    async fn bootstrap_leaves_the_failed_copies_to_the_units() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        remote.put_file(Path::new("/docs/a.txt"), b"a", mtime(1_600_000_000));
+        remote.put_file(Path::new("/docs/b.txt"), b"b", mtime(1_600_000_000));
+        remote.put_file(Path::new("/docs/sub/c.txt"), b"c", mtime(1_600_000_000));
+        remote.put_dir(Path::new("/docs/empty"));
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap();
+        let service = Arc::new(service);
+        // the first read of b.txt fails, the unit retries it
+        let failed = Arc::new(AtomicUsize::new(0));
+        let count = failed.clone();
+        remote.fail_paths(move |path| {
+            path == Path::new("/docs/b.txt") && count.fetch_add(1, Ordering::SeqCst) == 0
+        });
+
+        service
+            .clone()
+            .operate(Operation::SyncDeep(PathBuf::from("/docs")))
+            .await
+            .unwrap();
+        while service
+            .progress(Path::new("/docs"))
+            .await
+            .unwrap()
+            .is_some()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(failed.load(Ordering::SeqCst) >= 2);
+        assert_eq!(read(&local, "/docs/a.txt").unwrap(), "a");
+        assert_eq!(read(&local, "/docs/b.txt").unwrap(), "b");
+        assert_eq!(read(&local, "/docs/sub/c.txt").unwrap(), "c");
+        assert!(local
+            .entries()
+            .iter()
+            .any(|md| md.path() == Path::new("/docs/empty") && md.is_dir()));
+        let docs = service.tree.entry(Path::new("/docs")).unwrap();
+        assert!(docs.entry().is_sync());
+        assert_eq!(docs.stats().node.sync, 6);
+        assert!(service.conflicts.read().await.is_empty());
+    }
+
+    #[test]
+    fn conflict_set_is_in_path_order() {
+        let paths = [
+            "/a b", "/a/b/c", "/a-b", "/ab", "/a/b", "/a", "/a.b/c", "/a/b-c",
+        ];
+        let paths = paths.map(PathBuf::from);
+        let expected: BTreeSet<PathBuf> = paths.iter().cloned().collect();
+        let set = super::conflict_set(paths);
+        assert!(set.iter().eq(expected.iter()));
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(tokens_match("0a1b", "0a1b"));
+        assert!(!tokens_match("0a1b", "0a1c"));
+        assert!(!tokens_match("0a1b", "0a1"));
+    }
+
+    #[tokio::test]
+    async fn channels_authenticate() {
+        let service = Service::new(MemStorage::new(), MemStorage::new(), local_root())
+            .await
+            .unwrap();
+        let (abort_handle, _) = AbortHandle::new_pair();
+        let rpc = RpcService::new(Arc::new(service), abort_handle).await;
+
+        let channel = rpc.for_channel();
+        let res = channel.clone().gc_tree(context::current()).await;
+        assert!(matches!(res, Err(fsync::Error::Unauthorized(..))));
+        let res = channel
+            .clone()
+            .authenticate(context::current(), "wrong".into())
+            .await;
+        assert!(matches!(res, Err(fsync::Error::Unauthorized(..))));
+        // the version is checked before authentication
+        assert_eq!(
+            channel.clone().protocol_version(context::current()).await,
+            fsync::PROTOCOL_VERSION
+        );
+
+        let token = rpc.token.to_string();
+        channel
+            .clone()
+            .authenticate(context::current(), token)
+            .await
+            .unwrap();
+        assert!(channel.clone().gc_tree(context::current()).await.is_ok());
+
+        // other channels are still not authenticated
+        let res = rpc.for_channel().gc_tree(context::current()).await;
+        assert!(matches!(res, Err(fsync::Error::Unauthorized(..))));
+    }
+
+    #[tokio::test]
+    async fn entry_nodes_are_sorted_before_pagination() {
+        let local = MemStorage::new();
+        for name in ["10.txt", "2.txt", "Zebra.txt", "apple.txt", "1.txt"] {
+            local.put_file(&Path::root().join(name), name.as_bytes(), mtime(1000));
+        }
+        let service = Service::new(local, MemStorage::new(), local_root())
+            .await
+            .unwrap();
+
+        let pages = |order: SortOrder| {
+            let service = &service;
+            async move {
+                let mut pages = vec![];
+                let mut after = None;
+                loop {
+                    let page = service
+                        .entry_nodes(Path::root(), order, after.as_deref(), 2)
+                        .await
+                        .unwrap();
+                    if page.is_empty() {
+                        break;
+                    }
+                    after = page.last().unwrap().name().map(ToString::to_string);
+                    let names: Vec<_> =
+                        page.iter().map(|n| n.name().unwrap().to_string()).collect();
+                    pages.push(names.join(" "));
                 }
-                (ResolutionMethod::CreateLocalCopy, _) => {
-                    self.do_copy(
-                        local,
-                        &copy_path(path),
-                        &self.local,
-                        StorageLoc::Local,
-                        progress,
-                    )
-                    .await?;
-                    self.do_replace(
-                        remote,
-                        &self.remote,
-                        &self.local,
-                        StorageDir::RemoteToLocal,
-                        progress,
-                    )
+                pages
+            }
+        };
+        assert_eq!(
+            pages(SortOrder::Natural).await,
+            ["1.txt 2.txt", "10.txt apple.txt", "Zebra.txt"]
+        );
+        assert_eq!(
+            pages(SortOrder::CaseInsensitive).await,
+            ["1.txt 10.txt", "2.txt apple.txt", "Zebra.txt"]
+        );
+        assert_eq!(
+            pages(SortOrder::Raw).await,
+            ["1.txt 10.txt", "2.txt Zebra.txt", "apple.txt"]
+        );
+        // the tree keeps its order
+        let node = service.entry_node(Path::root()).await.unwrap().unwrap();
+        assert_eq!(
+            *node.children(),
+            ["1.txt", "10.txt", "2.txt", "Zebra.txt", "apple.txt"]
+        );
+    }
+
+    #[tokio::test]
+    async fn entry_node_page_lists_the_names_by_pages() {
+        let local = MemStorage::new();
+        for name in ["a.txt", "b.txt", "c.txt", "d.txt", "e.txt"] {
+            local.put_file(&Path::root().join(name), name.as_bytes(), mtime(1000));
+        }
+        let service = Service::new(local, MemStorage::new(), local_root())
+            .await
+            .unwrap();
+
+        let mut pages = vec![];
+        let mut after: Option<String> = None;
+        loop {
+            let node = service
+                .entry_node_page(Path::root(), after.as_deref(), 2)
+                .await
+                .unwrap()
+                .unwrap();
+            // the stats account for all the children
+            assert_eq!(node.stats().local.files, 5);
+            let Some(last) = node.children().iter().last() else {
+                break;
+            };
+            after = Some(last.clone());
+            pages.push(
+                node.children()
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+        }
+        assert_eq!(pages, ["a.txt b.txt", "c.txt d.txt", "e.txt"]);
+
+        let node = service
+            .entry_node_page(Path::root(), None, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(node.children().is_empty());
+        assert!(service
+            .entry_node_page(Path::new("/missing"), None, 2)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        use std::{sync::atomic::AtomicU32, time::Duration};
+
+        use fsync::{Error, OperationReport, Progress};
+
+        use super::track_progress;
+
+        let delay = Duration::from_millis(1);
+        let unavailable = || Error::Unavailable("503 Service Unavailable".into());
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+
+        // succeeds at the third attempt
+        let res = track_progress("/a".into(), tx.clone(), 3, delay, |_, attempt| async move {
+            if attempt < 3 {
+                Err(unavailable())
+            } else {
+                Ok(OperationReport::default())
+            }
+        })
+        .await;
+        assert!(res.is_ok());
+        let (_, progress) = rx.recv().await.unwrap();
+        assert!(matches!(progress.get(), Progress::Done));
+
+        // the retries are exhausted
+        let attempts = AtomicU32::new(0);
+        let res = track_progress("/b".into(), tx.clone(), 3, delay, |_, _| {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            async { Err(unavailable()) }
+        })
+        .await;
+        assert!(matches!(res, Err(Error::Unavailable(..))));
+        assert_eq!(attempts.into_inner(), 4);
+        let (_, progress) = rx.recv().await.unwrap();
+        let Progress::Failed(failure) = progress.get() else {
+            panic!("should have failed");
+        };
+        assert_eq!((failure.attempts, failure.transient), (4, true));
+        assert_eq!(
+            failure.to_string(),
+            "Storage unavailable: 503 Service Unavailable: failed after 3 retries (transient)"
+        );
+
+        // a permanent failure is not retried
+        let res = track_progress("/c".into(), tx, 3, delay, |_, _| async {
+            Err(Error::NotEmpty("/c".into()))
+        })
+        .await;
+        assert!(res.is_err());
+        let (_, progress) = rx.recv().await.unwrap();
+        let Progress::Failed(failure) = progress.get() else {
+            panic!("should have failed");
+        };
+        assert_eq!((failure.attempts, failure.transient), (1, false));
+    }
+
+    #[tokio::test]
+    async fn gc_tree_records_the_ghosts() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        for storage in [&local, &remote] {
+            storage.put_file(Path::new("/keep.txt"), b"keep", mtime(1000));
+            storage.put_file(Path::new("/dir/a.txt"), b"a", mtime(1000));
+        }
+        local.put_file(Path::new("/dir/b.txt"), b"b", mtime(1000));
+
+        let dir = std::env::temp_dir().join(format!("fsyncd-gc-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let audit_path = dir.join("audit.jsonl");
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap()
+            .with_audit_log(
+                crate::audit::AuditLog::open(audit_path.clone())
                     .await
-                }
-                (_, fsync::Conflict::LocalBigger) | (_, fsync::Conflict::LocalSmaller) => {
-                    Err(fsync::Error::Unresolved(
-                        path.to_owned(),
-                        "local and remote have same mtime but different size. ".to_string(),
-                    ))
-                }
-                (_, fsync::Conflict::LocalDirRemoteFile) => Err(fsync::Error::Unresolved(
-                    path.to_owned(),
-                    "local is dir and remote is file. ".to_string(),
-                )),
-                (_, fsync::Conflict::LocalFileRemoteDir) => Err(fsync::Error::Unresolved(
-                    path.to_owned(),
-                    "local is file and remote is dir. ".to_string(),
-                )),
-            },
-            _ => Ok(()),
+                    .unwrap(),
+            );
+
+        // deleted on both sides, or on the only side it was
+        for storage in [&local, &remote] {
+            storage.remove(Path::new("/dir"));
+        }
+        let removed = service.gc_tree().await.unwrap();
+        assert_eq!(removed, vec![PathBuf::from("/dir")]);
+        assert!(service.tree.has_entry(Path::new("/keep.txt")));
+        assert!(!service.tree.has_entry(Path::new("/dir/b.txt")));
+        assert!(service.gc_tree().await.unwrap().is_empty());
+
+        let records = fsync::audit::read_records(&audit_path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].path, PathBuf::from("/dir"));
+        assert!(matches!(records[0].action, fsync::Action::Forget));
+        assert!(records[0].operation.is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Delete local files after the tree is built, and check that the clone, replace
+    /// and delete units skip what became moot, or do what is left to do
+    #[tokio::test]
+    async fn vanished_sources_are_revalidated() {
+        use fsync::OperationReport;
+
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        let write = |storage: &MemStorage, path: &str, content: &str, secs| {
+            storage.put_file(Path::new(path), content.as_bytes(), mtime(secs))
+        };
+        write(&local, "/push.txt", "local content", 1000);
+        write(&local, "/both.txt", "local version", 2000);
+        write(&remote, "/both.txt", "remote version", 1000);
+        write(&local, "/synced.txt", "synced", 1000);
+        write(&remote, "/synced.txt", "synced", 1000);
+        write(&local, "/gone.txt", "synced", 1000);
+        write(&remote, "/gone.txt", "synced", 1000);
+
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap();
+        for path in ["/push.txt", "/both.txt", "/synced.txt", "/gone.txt"] {
+            local.remove(Path::new(path));
+        }
+        // as the Drive storage, fail to delete what is not there
+        local.fail_paths(|path| ["/synced.txt", "/gone.txt"].contains(&path.as_str()));
+
+        let operate = |operation: Operation| {
+            let node = service.check_node(operation.path()).unwrap();
+            service.operate_unit(
+                operation,
+                node,
+                OperateOptions::default(),
+                SharedProgress::new(),
+            )
+        };
+        let vanished = OperationReport {
+            skipped_vanished: 1,
+            ..OperationReport::default()
+        };
+
+        // nothing left to clone
+        let report = operate(Operation::Sync(PathBuf::from("/push.txt"))).await;
+        assert_eq!(report.unwrap(), vanished);
+        assert!(service.tree.entry(Path::new("/push.txt")).is_none());
+        assert!(read(&remote, "/push.txt").is_none());
+
+        // no conflict left to resolve
+        let report = operate(Operation::Resolve(
+            PathBuf::from("/both.txt"),
+            ResolutionMethod::ReplaceRemoteByLocal,
+        ))
+        .await;
+        assert_eq!(report.unwrap(), vanished);
+        assert_eq!(read(&remote, "/both.txt").unwrap(), "remote version");
+        let node = service.tree.entry(Path::new("/both.txt")).unwrap();
+        assert!(node.entry().is_only_at_loc(StorageLoc::Remote));
+
+        // nothing left to delete locally
+        let report = operate(Operation::Delete(
+            PathBuf::from("/synced.txt"),
+            DeletionMethod::Local,
+        ))
+        .await;
+        assert_eq!(report.unwrap(), vanished);
+        assert_eq!(read(&remote, "/synced.txt").unwrap(), "synced");
+
+        // only the remote file is left to delete
+        let report = operate(Operation::Delete(
+            PathBuf::from("/gone.txt"),
+            DeletionMethod::All,
+        ))
+        .await;
+        assert_eq!(report.unwrap(), OperationReport::default());
+        assert!(read(&remote, "/gone.txt").is_none());
+        assert!(service.tree.entry(Path::new("/gone.txt")).is_none());
+
+        let fresh = DiffTree::build(&local, &remote).await.unwrap();
+        assert_eq!(summary(&service.tree), summary(&fresh));
+    }
+
+    #[tokio::test]
+    async fn sync_mode_withholds_the_other_direction() {
+        use fsync::{Error, OperationReport, SyncMode};
+
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/camera.jpg"), b"picture", mtime(1000));
+        remote.put_file(Path::new("/library.pdf"), b"book", mtime(1000));
+
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap()
+            .with_sync_mode(SyncMode::UploadOnly);
+        let service = Arc::new(service);
+        assert_eq!(service.instance_stats().await.unwrap().withheld, 1);
+
+        let res = service
+            .clone()
+            .operate(Operation::Sync(PathBuf::from("/library.pdf")))
+            .await;
+        assert!(matches!(res, Err(Error::Withheld(_, SyncMode::UploadOnly))));
+        let res = service
+            .clone()
+            .operate(Operation::Delete(
+                PathBuf::from("/camera.jpg"),
+                DeletionMethod::Local,
+            ))
+            .await;
+        assert!(matches!(res, Err(Error::Withheld(..))));
+
+        let progress = service
+            .clone()
+            .operate(Operation::SyncDeep(PathBuf::root()))
+            .await
+            .unwrap();
+        assert_eq!(
+            progress.report(),
+            Some(OperationReport {
+                skipped_withheld: 1,
+                ..OperationReport::default()
+            })
+        );
+        assert_eq!(read(&remote, "/camera.jpg").unwrap(), "picture");
+        assert!(read(&local, "/library.pdf").is_none());
+        // both sides are still shown
+        let node = service.tree.entry(Path::new("/library.pdf")).unwrap();
+        assert!(node.entry().is_only_at_loc(StorageLoc::Remote));
+        assert_eq!(service.instance_stats().await.unwrap().withheld, 1);
+    }
+
+    #[tokio::test]
+    async fn operations_are_counted() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/up.txt"), b"uploaded", mtime(1000));
+        remote.put_file(Path::new("/down.txt"), b"down", mtime(1000));
+        local.put_file(Path::new("/both.txt"), b"newer local", mtime(2000));
+        remote.put_file(Path::new("/both.txt"), b"remote", mtime(1000));
+
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap();
+        let service = Arc::new(service);
+        for path in ["/up.txt", "/down.txt"] {
+            service
+                .clone()
+                .operate(Operation::Sync(PathBuf::from(path)))
+                .await
+                .unwrap();
+        }
+        let res = service
+            .clone()
+            .operate(Operation::Sync(PathBuf::from("/both.txt")))
+            .await;
+        assert!(res.is_err());
+        service
+            .clone()
+            .operate(Operation::Resolve(
+                PathBuf::from("/both.txt"),
+                ResolutionMethod::ReplaceRemoteByLocal,
+            ))
+            .await
+            .unwrap();
+
+        let counters = service.counters();
+        assert_eq!(
+            counters.since_boot,
+            fsync::Counters {
+                bytes_uploaded: 8 + 11,
+                bytes_downloaded: 4,
+                operations: 3,
+                failures: 1,
+                retries: 0,
+            }
+        );
+        // not persisted, the lifetime starts with the service
+        assert_eq!(counters.lifetime, counters.since_boot);
+        let transfers = service.transfers();
+        assert_eq!(
+            transfers.since_boot,
+            fsync::Transfers {
+                sync: fsync::TransferCounters {
+                    files: 2,
+                    bytes: 8 + 4,
+                    retried_bytes: 0,
+                },
+                conflict: fsync::TransferCounters {
+                    files: 1,
+                    bytes: 11,
+                    retried_bytes: 0,
+                },
+                ..Default::default()
+            }
+        );
+        assert_eq!(transfers.lifetime, transfers.since_boot);
+    }
+
+    #[tokio::test]
+    async fn transfers_are_counted_by_cause() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/new.txt"), b"fresh", mtime(1000));
+        local.put_file(Path::new("/rotten.txt"), b"hello", mtime(1000));
+        remote.put_file(Path::new("/rotten.txt"), b"hellp", mtime(1000));
+        let service = Service::new(local, remote.clone(), local_root())
+            .await
+            .unwrap();
+        let service = Arc::new(service);
+
+        // the first upload fails, the second one sends the same content again
+        remote.fail_paths(|path| path == Path::new("/new.txt"));
+        let sync = Operation::Sync(PathBuf::from("/new.txt"));
+        assert!(service.clone().operate(sync.clone()).await.is_err());
+        remote.clear_faults();
+        service.clone().operate(sync).await.unwrap();
+
+        let deadline = SystemTime::now() + Duration::from_secs(60);
+        let report = service
+            .verify(Path::root(), false, None, deadline)
+            .await
+            .unwrap();
+        assert_eq!(report.mismatched, vec![PathBuf::from("/rotten.txt")]);
+        let resolve = Operation::Resolve(
+            PathBuf::from("/rotten.txt"),
+            ResolutionMethod::ReplaceRemoteByLocal,
+        );
+        service.clone().operate(resolve).await.unwrap();
+        assert_eq!(read(&remote, "/rotten.txt").unwrap(), "hello");
+
+        let transfers = service.transfers().since_boot;
+        assert_eq!(
+            transfers.sync,
+            fsync::TransferCounters {
+                files: 1,
+                bytes: 0,
+                retried_bytes: 5,
+            }
+        );
+        assert_eq!(
+            transfers.verification,
+            fsync::TransferCounters {
+                files: 1,
+                bytes: 5,
+                retried_bytes: 0,
+            }
+        );
+        assert_eq!(transfers.conflict, fsync::TransferCounters::default());
+    }
+
+    #[tokio::test]
+    async fn identical_replacements_only_patch_the_mtime() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        // touched on one side, the content is the same
+        local.put_file(Path::new("/touched.txt"), b"touched", mtime(2000));
+        remote.put_file(Path::new("/touched.txt"), b"touched", mtime(1000));
+        local.put_file(Path::new("/restored.txt"), b"restored", mtime(1000));
+        remote.put_file(Path::new("/restored.txt"), b"restored", mtime(2000));
+        // same size, but edited
+        local.put_file(Path::new("/edited.txt"), b"after!", mtime(2000));
+        remote.put_file(Path::new("/edited.txt"), b"before", mtime(1000));
+
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap();
+        let service = Arc::new(service);
+        assert_eq!(service.conflicts.read().await.len(), 3);
+        for (path, method) in [
+            ("/touched.txt", ResolutionMethod::ReplaceRemoteByLocal),
+            ("/restored.txt", ResolutionMethod::ReplaceLocalByRemote),
+            ("/edited.txt", ResolutionMethod::ReplaceRemoteByLocal),
+        ] {
+            service
+                .clone()
+                .operate(Operation::Resolve(PathBuf::from(path), method))
+                .await
+                .unwrap();
+        }
+        assert!(service.conflicts.read().await.is_empty());
+
+        let touched = crate::tree::storage_entry(&remote, Path::new("/touched.txt"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(touched.mtime(), Some(mtime(2000)));
+        let restored = crate::tree::storage_entry(&local, Path::new("/restored.txt"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(restored.mtime(), Some(mtime(2000)));
+        assert_eq!(
+            remote.content(Path::new("/edited.txt")).unwrap(),
+            b"after!".to_vec()
+        );
+
+        // only the edited file was transferred
+        assert_eq!(remote.transferred(), 6);
+        let counters = service.counters().since_boot;
+        assert_eq!(counters.bytes_uploaded, 6);
+        assert_eq!(counters.bytes_downloaded, 0);
+    }
+
+    #[tokio::test]
+    async fn patch_identical_sets_the_mtime_without_transfer() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/same.txt"), b"same", mtime(2000));
+        remote.put_file(Path::new("/same.txt"), b"same", mtime(1000));
+        local.put_file(Path::new("/other.txt"), b"left", mtime(2000));
+        remote.put_file(Path::new("/other.txt"), b"rite", mtime(1000));
+
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap();
+        let node = |path| service.tree.entry(Path::new(path)).unwrap();
+
+        let patched = service
+            .do_patch_identical(&node("/same.txt"), StorageDir::LocalToRemote, None)
+            .await
+            .unwrap();
+        assert!(patched);
+        let same = crate::tree::storage_entry(&remote, Path::new("/same.txt"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(same.mtime(), Some(mtime(2000)));
+        let entry = node("/same.txt").into_entry();
+        let remote_mtime = entry.into_metadata(StorageLoc::Remote).unwrap().mtime();
+        assert_eq!(remote_mtime, Some(mtime(2000)));
+
+        // same size, other content: left for the transfer
+        let patched = service
+            .do_patch_identical(&node("/other.txt"), StorageDir::LocalToRemote, None)
+            .await
+            .unwrap();
+        assert!(!patched);
+        let other = crate::tree::storage_entry(&remote, Path::new("/other.txt"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(other.mtime(), Some(mtime(1000)));
+
+        // the local files are only read to compare their checksum
+        assert_eq!(remote.transferred(), 0);
+    }
+
+    #[tokio::test]
+    async fn reload_updates_the_config_fingerprint() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-fingerprint-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_file = dir.join("config.json");
+        std::fs::write(
+            &config_file,
+            r#"{"local_dir": "/local", "provider": {"fs": "/remote"}}"#,
+        )
+        .unwrap();
+        let config = fsync::Config::load_from_file(&config_file).await.unwrap();
+
+        let service = Service::new(MemStorage::new(), MemStorage::new(), local_root())
+            .await
+            .unwrap()
+            .with_config_file(config_file.clone(), config.fingerprint());
+        assert_eq!(
+            service.status().config_fingerprint,
+            Some(config.fingerprint())
+        );
+
+        std::fs::write(
+            &config_file,
+            r#"{"local_dir": "/local", "provider": {"fs": "/remote"}, "deferral": {"defer_on_battery": true}}"#,
+        )
+        .unwrap();
+        service.reload_config().await.unwrap();
+        let fingerprint = service.status().config_fingerprint.unwrap();
+        assert_ne!(fingerprint, config.fingerprint());
+        let reloaded = fsync::Config::load_from_file(&config_file).await.unwrap();
+        assert_eq!(fingerprint, reloaded.fingerprint());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn revisions_are_never_downloaded_over_an_entry() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/notes.txt"), b"local", mtime(1000));
+        remote.put_file(Path::new("/notes.txt"), b"remote", mtime(1000));
+        local.put_file(Path::new("/local.txt"), b"local", mtime(1000));
+
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap();
+
+        let res = service.remote_revisions(Path::new("/local.txt"), 10).await;
+        assert!(matches!(
+            res,
+            Err(Error::Path(fsync::PathError::NotFound(..)))
+        ));
+        // never overwrites an entry
+        let res = service
+            .download_revision(Path::new("/notes.txt"), "r1", Path::new("/local.txt"))
+            .await;
+        assert!(matches!(res, Err(Error::Other(..))));
+        let res = service
+            .download_revision(Path::new("/notes.txt"), "r1", Path::new("/none/r1.txt"))
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::Path(fsync::PathError::NotFound(..)))
+        ));
+
+        // the memory storage keeps no revisions
+        let res = service.remote_revisions(Path::new("/notes.txt"), 10).await;
+        assert!(matches!(res, Err(Error::Unsupported(..))));
+        let res = service
+            .download_revision(Path::new("/notes.txt"), "r1", Path::new("/r1.txt"))
+            .await;
+        assert!(matches!(res, Err(Error::Unsupported(..))));
+        assert!(!service.tree.has_entry(Path::new("/r1.txt")));
+    }
+
+    #[tokio::test]
+    async fn public_sharing_needs_a_confirmation() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        remote.put_file(Path::new("/notes.txt"), b"remote", mtime(1000));
+        remote.put_file(Path::new("/other.txt"), b"other", mtime(1000));
+        local.put_file(Path::new("/local.txt"), b"local", mtime(1000));
+
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap();
+
+        // a local-only file has no link
+        let res = service.remote_link(Path::new("/local.txt")).await;
+        assert!(matches!(
+            res,
+            Err(Error::Path(fsync::PathError::NotFound(..)))
+        ));
+        let res = service.share_public(Path::new("/local.txt"), None).await;
+        assert!(matches!(
+            res,
+            Err(Error::Path(fsync::PathError::NotFound(..)))
+        ));
+
+        let token = match service.share_public(Path::new("/notes.txt"), None).await {
+            Err(Error::ConfirmationRequired(token, summary)) => {
+                assert!(summary.contains("/notes.txt"));
+                token
+            }
+            res => panic!("expected a confirmation request, got {res:?}"),
+        };
+        // a token only confirms the sharing of the file it was returned for
+        let res = service
+            .share_public(Path::new("/other.txt"), Some(&token))
+            .await;
+        assert!(matches!(res, Err(Error::ConfirmationRequired(..))));
+
+        // the memory storage has no links
+        let res = service
+            .share_public(Path::new("/notes.txt"), Some(&token))
+            .await;
+        assert!(matches!(res, Err(Error::Unsupported(..))));
+        let res = service.remote_link(Path::new("/notes.txt")).await;
+        assert!(matches!(res, Err(Error::Unsupported(..))));
+    }
+
+    /// Edit synchronized text files on both sides, and merge them from their last synchronized version
+    #[tokio::test]
+    async fn text_conflicts_are_merged() {
+        use crate::merge::{self, Bases};
+
+        let dir = std::env::temp_dir().join(format!("fsyncd-merge-bases-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/notes.txt"), b"a\nb\nc\nd\n", mtime(1000));
+        local.put_file(Path::new("/other.txt"), b"a\nb\nc\n", mtime(1000));
+        local.put_file(Path::new("/new.txt"), b"local\n", mtime(1000));
+        remote.put_file(Path::new("/new.txt"), b"remote\n", mtime(2000));
+
+        let bases = Bases::open(dir.clone(), merge::BASES_BUDGET).await.unwrap();
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap()
+            .with_merge_bases(bases);
+        let service = Arc::new(service);
+        for path in ["/notes.txt", "/other.txt"] {
+            service
+                .clone()
+                .operate(Operation::Sync(PathBuf::from(path)))
+                .await
+                .unwrap();
         }
+        drop(service);
+
+        local.put_file(Path::new("/notes.txt"), b"A\nb\nc\nd\n", mtime(2000));
+        remote.put_file(Path::new("/notes.txt"), b"a\nb\nc\nD\n", mtime(3000));
+        local.put_file(Path::new("/other.txt"), b"a\nlocal\nc\n", mtime(2000));
+        remote.put_file(Path::new("/other.txt"), b"a\nremote\nc\n", mtime(3000));
+
+        let bases = Bases::open(dir.clone(), merge::BASES_BUDGET).await.unwrap();
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap()
+            .with_merge_bases(bases);
+        let service = Arc::new(service);
+        let merge = |path: &str| {
+            service.clone().operate(Operation::Resolve(
+                PathBuf::from(path),
+                ResolutionMethod::MergeText,
+            ))
+        };
+        let is_conflict = |path: &str| {
+            let node = service.tree.entry(Path::new(path)).unwrap();
+            node.entry().is_conflict()
+        };
+
+        merge("/notes.txt").await.unwrap();
+        assert_eq!(read(&local, "/notes.txt").unwrap(), "A\nb\nc\nD\n");
+        assert_eq!(read(&remote, "/notes.txt").unwrap(), "A\nb\nc\nD\n");
+        assert!(service
+            .tree
+            .entry(Path::new("/notes.txt"))
+            .unwrap()
+            .entry()
+            .is_sync());
+
+        let err = merge("/other.txt").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Could not resolve conflict on /other.txt: merge failed: overlapping changes on line 2"
+        );
+        assert!(is_conflict("/other.txt"));
+        assert_eq!(read(&local, "/other.txt").unwrap(), "a\nlocal\nc\n");
+
+        let err = merge("/new.txt").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Could not resolve conflict on /new.txt: merge failed: the version last synchronized is unknown"
+        );
+        assert!(is_conflict("/new.txt"));
+
+        let _ = std::fs::remove_dir_all(&dir);
     }
 
-    async fn delete_unit(
-        &self,
-        path: &Path,
-        node: &EntryNode,
-        method: DeletionMethod,
-        progress: &SharedProgress,
-    ) -> fsync::Result<()> {
-        if !node.children().is_empty() {
-            return Err(fsync::Error::NotEmpty(path.to_owned()));
-        }
-
-        match (node.entry(), method) {
-            // Delete only locally
-            (tree::Entry::Local(..), DeletionMethod::Local | DeletionMethod::All)
-            | (
-                tree::Entry::Sync { conflict: None, .. },
-                DeletionMethod::Local
-                | DeletionMethod::LocalIfSync
-                | DeletionMethod::LocalIfSyncNoConflict,
-            )
-            | (
-                tree::Entry::Sync {
-                    conflict: Some(_), ..
-                },
-                DeletionMethod::Local | DeletionMethod::LocalIfSync,
-            ) => {
-                self.do_delete(path, &self.local, StorageLoc::Local, progress)
-                    .await
-            }
+    /// Switch the root of a LocalFs instance to another folder
+    #[tokio::test]
+    async fn root_switch_is_migrated() {
+        use fsync::{overlap::RecordedRoot, Error, MigrationChoice, RemoteRoot};
 
-            // Delete only remotely
-            (tree::Entry::Remote(..), DeletionMethod::Remote | DeletionMethod::All)
-            | (
-                tree::Entry::Sync { conflict: None, .. },
-                DeletionMethod::Remote
-                | DeletionMethod::RemoteIfSync
-                | DeletionMethod::RemoteIfSyncNoConflict,
-            )
-            | (
-                tree::Entry::Sync {
-                    conflict: Some(_), ..
-                },
-                DeletionMethod::Remote | DeletionMethod::RemoteIfSync,
-            ) => {
-                self.do_delete(path, &self.remote, StorageLoc::Remote, progress)
+        use crate::{root::RootGuard, storage::fs::FileSystem};
+
+        let dir = std::env::temp_dir().join(format!("fsyncd-root-switch-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let write = |path: &str, content: &str| {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+        write("local/a.txt", "local a");
+        write("local/dir/c.txt", "local c");
+        write("photos/a.txt", "a");
+        write("work/a.txt", "work a, longer");
+        write("work/b.txt", "work b");
+        let remote_root = |name: &str| RemoteRoot {
+            id: dir.join(name).to_string(),
+            config: dir.join(name).to_string(),
+        };
+        let recorded = |name: &str| RecordedRoot {
+            root: remote_root(name),
+            ancestry: Vec::new(),
+        };
+
+        let service = || {
+            let dir = dir.clone();
+            async move {
+                let guard = RootGuard::open(dir.join("root.json"), dir.join("config.json"))
+                    .await
+                    .unwrap();
+                guard.check(recorded("work")).await.unwrap();
+                assert!(guard.change().is_some());
+                let local = FileSystem::new(dir.join("local")).unwrap();
+                let remote = FileSystem::new(dir.join("work")).unwrap();
+                let service = Service::new(local, remote, dir.join("local"))
                     .await
+                    .unwrap()
+                    .with_root_guard(Arc::new(guard));
+                Arc::new(service)
             }
+        };
+        let record = RootGuard::open(dir.join("root.json"), dir.join("config.json"))
+            .await
+            .unwrap();
+        record.check(recorded("photos")).await.unwrap();
 
-            // Delete everywhere
-            (tree::Entry::Sync { .. }, DeletionMethod::All) => {
-                let local = self.local().delete(path, Some(progress));
-                let remote = self.remote().delete(path, Some(progress));
-                futures::try_join!(local, remote)?;
-                self.tree.remove(path);
-                self.check_conflict(path, false).await;
-                Ok(())
+        // the operations are refused until the change is migrated
+        let refused = service().await;
+        let res = refused
+            .clone()
+            .operate(Operation::SyncDeep(PathBuf::root()))
+            .await;
+        assert!(matches!(res, Err(Error::RootChanged(..))));
+        let change = refused.root_change().unwrap();
+        assert_eq!(change.recorded, remote_root("photos"));
+        assert_eq!(change.resolved, remote_root("work"));
+
+        // keeping the local files, that win the conflicts
+        let service_keep = service().await;
+        service_keep
+            .clone()
+            .migrate_root(MigrationChoice::KeepLocal)
+            .await
+            .unwrap();
+        assert_eq!(service_keep.root_change(), None);
+        for _ in 0..100 {
+            if !service_keep.is_operating().await {
+                break;
             }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let read = |path: &str| std::fs::read_to_string(dir.join(path)).unwrap();
+        assert_eq!(read("work/a.txt"), "local a");
+        assert!(service_keep.conflicts.read().await.is_empty());
+        service_keep
+            .clone()
+            .operate(Operation::SyncDeep(PathBuf::root()))
+            .await
+            .unwrap();
+        assert_eq!(read("work/dir/c.txt"), "local c");
+        assert_eq!(read("local/b.txt"), "work b");
 
-            // Nothing to do
-            (tree::Entry::Local(..), deletion) if deletion.is_remote() => Ok(()),
-            (tree::Entry::Remote(..), deletion) if deletion.is_local() => Ok(()),
+        // resetting the local directory, once the root is switched again
+        std::fs::write(dir.join("work/a.txt"), "work a, again").unwrap();
+        std::fs::remove_file(dir.join("root.json")).unwrap();
+        let record = RootGuard::open(dir.join("root.json"), dir.join("config.json"))
+            .await
+            .unwrap();
+        record.check(recorded("photos")).await.unwrap();
+        let service_reset = service().await;
+        service_reset
+            .clone()
+            .migrate_root(MigrationChoice::ResetLocal)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_dir(dir.join("local")).unwrap().count(), 0);
+        let node = service_reset.tree.entry(Path::new("/a.txt")).unwrap();
+        assert!(node.entry().is_only_at_loc(StorageLoc::Remote));
 
-            // Conflict error
-            (
-                tree::Entry::Sync {
-                    conflict: Some(_), ..
-                },
-                deletion,
-            ) if deletion.no_conflict() => Err(fsync::Error::Conflict(path.to_owned())),
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-            (entry, method) => unreachable!("missing delete_unit case:{entry:#?}, {method:#?}"),
-        }
+    #[tokio::test]
+    async fn self_check_finds_the_tree_drift() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/dir/a.txt"), b"a", mtime(1000));
+        remote.put_file(Path::new("/dir/b.txt"), b"b", mtime(1000));
+
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap()
+            .with_self_check();
+        let service = Arc::new(service);
+        service
+            .clone()
+            .operate(Operation::SyncDeep(PathBuf::root()))
+            .await
+            .unwrap();
+        assert!(service.take_discrepancies().await.is_empty());
+        assert!(service.self_check().await.unwrap().is_empty());
+
+        // changed behind the back of the service
+        local.put_file(Path::new("/dir/a.txt"), b"changed", mtime(2000));
+        remote.put_file(Path::new("/dir/c.txt"), b"c", mtime(1000));
+        let found = service.self_check().await.unwrap();
+        let found: Vec<_> = found
+            .iter()
+            .map(|d| (d.path.as_str(), d.field.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("/dir/a.txt", "local"),
+                ("/dir/a.txt", "conflict"),
+                ("/dir/c.txt", "remote"),
+            ]
+        );
     }
 
-    async fn operate_unit(
-        &self,
-        operation: Operation,
-        node: EntryNode,
-        progress: SharedProgress,
-    ) -> fsync::Result<()> {
-        log::trace!("Operate unit: {operation:?}");
-        match operation {
-            Operation::Sync(path) => self.sync_unit(path.as_ref(), &node, &progress).await,
-            Operation::Resolve(path, method) => {
-                self.resolve_unit(path.as_ref(), &node, method, &progress)
-                    .await
-            }
-            Operation::Delete(path, method) => {
-                self.delete_unit(path.as_ref(), &node, method, &progress)
-                    .await
+    #[tokio::test]
+    async fn self_check_by_pages() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        for dir in ["a", "b", "c"] {
+            local.put_file(&Path::root().join(dir).join("l.txt"), b"l", mtime(1000));
+            remote.put_file(&Path::root().join(dir).join("r.txt"), b"r", mtime(1000));
+        }
+        local.put_file(Path::new("/top.txt"), b"top", mtime(1000));
+
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap();
+        local.put_file(Path::new("/a/l.txt"), b"changed", mtime(2000));
+        remote.put_file(Path::new("/c/new.txt"), b"new", mtime(1000));
+        remote.put_file(Path::new("/new.txt"), b"new", mtime(1000));
+
+        // a deadline in the past checks a single folder per page
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let page = service
+                .self_check_until(after.as_deref(), SystemTime::UNIX_EPOCH)
+                .await
+                .unwrap();
+            pages.push(page.discrepancies);
+            after = page.next;
+            if after.is_none() {
+                break;
             }
-            _ => panic!("Not a unit operation: {operation:?}"),
         }
+        assert_eq!(pages.len(), 3);
+        let sorted = |mut found: Vec<fsync::Discrepancy>| {
+            found.sort_by(|a, b| a.path.cmp(&b.path));
+            found
+        };
+        let paged = sorted(pages.into_iter().flatten().collect());
+        assert_eq!(paged, sorted(service.self_check().await.unwrap()));
+        let paths: Vec<_> = paged.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, vec!["/a/l.txt", "/c/new.txt", "/new.txt"]);
     }
 
-    fn operate_deep<'a>(
-        self: Arc<Self>,
-        operation: Operation,
-        node: EntryNode,
-        progress: SharedProgress,
-        tx: mpsc::Sender<(PathBuf, SharedProgress)>,
-    ) -> BoxFuture<'a, fsync::Result<()>> {
-        Box::pin(async move {
-            log::trace!("Operate deep: {operation:?}");
-            progress.set(Progress::Compound);
+    #[tokio::test]
+    async fn verify_by_pages_marks_the_mismatches() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        for path in ["/a/rotten.txt", "/a/same.txt", "/b.txt"] {
+            local.put_file(Path::new(path), b"hello", mtime(1000));
+            remote.put_file(Path::new(path), b"hello", mtime(1000));
+        }
+        // same size and time, as left by a corruption of the remote copy
+        remote.put_file(Path::new("/a/rotten.txt"), b"hellp", mtime(1000));
+        let service = Service::new(local, remote, local_root()).await.unwrap();
+        assert!(service.conflicts.read().await.is_empty());
 
-            let path = operation.path();
+        // a deadline in the past verifies a single file per page
+        let mut report = fsync::VerificationReport::default();
+        loop {
+            let page = service
+                .verify(
+                    Path::root(),
+                    false,
+                    report.next.as_deref(),
+                    SystemTime::UNIX_EPOCH,
+                )
+                .await
+                .unwrap();
+            assert!(page.verified + page.mismatched.len() as u64 <= 1);
+            report.extend(page);
+            let Some(next) = &report.next else {
+                break;
+            };
+            assert_eq!(service.verify_checkpoint().unwrap().next, *next);
+        }
+        assert_eq!(report.verified, 2);
+        assert_eq!(report.mismatched, vec![PathBuf::from("/a/rotten.txt")]);
+        assert!(report.unverifiable.is_empty());
+        assert!(service.verify_checkpoint().is_none());
 
-            let parent_first = matches!(
-                operation,
-                Operation::SyncDeep(..) | Operation::ResolveDeep(..)
-            );
-            if parent_first {
-                self.operate_unit(operation.clone().not_deep(), node.clone(), progress.clone())
-                    .await?;
+        let node = service
+            .entry_node(Path::new("/a/rotten.txt"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            node.entry().conflict(),
+            Some(fsync::Conflict::ContentMismatch)
+        );
+        assert!(service
+            .conflicts
+            .read()
+            .await
+            .contains(Path::new("/a/rotten.txt")));
+        // a mismatch is not a discrepancy of the tree
+        assert!(service.self_check().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_operations_never_time_out_the_client() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/big.bin"), b"big", mtime(1000));
+        let service = Service::new(local, remote.clone(), local_root())
+            .await
+            .unwrap();
+        let (abort_handle, _) = AbortHandle::new_pair();
+        let rpc = RpcService::new(Arc::new(service), abort_handle).await;
+        let token = rpc.token.to_string();
+
+        let (client_transport, server_transport) = tarpc::transport::channel::unbounded();
+        let server = tarpc::server::BaseChannel::with_defaults(server_transport);
+        tokio::spawn(
+            server
+                .execute(rpc.for_channel().serve())
+                .for_each(super::spawn),
+        );
+        let client =
+            fsync::FsyncClient::new(tarpc::client::Config::default(), client_transport).spawn();
+        let ctx = || {
+            let mut ctx = context::current();
+            ctx.deadline = SystemTime::now() + fsync::DEFAULT_RPC_TIMEOUT;
+            ctx
+        };
+        client.authenticate(ctx(), token).await.unwrap().unwrap();
+
+        // each call to the remote storage outlasts the deadline of the requests
+        remote.set_latency(fsync::DEFAULT_RPC_TIMEOUT * 3);
+        let start = tokio::time::Instant::now();
+        let progress = client
+            .operate(ctx(), Operation::Sync(PathBuf::from("/big.bin")))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(start.elapsed() < fsync::DEFAULT_RPC_TIMEOUT);
+        assert!(!progress.is_done());
+
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let progress = client
+                .progress(ctx(), PathBuf::from("/big.bin"))
+                .await
+                .unwrap()
+                .unwrap();
+            match progress {
+                Some(progress) if progress.is_done() => break,
+                None => break,
+                Some(progress) => assert!(progress.error().is_none(), "{progress:?}"),
             }
+        }
+        assert_eq!(remote.content(Path::new("/big.bin")).unwrap(), b"big");
+    }
 
-            let mut joinvec = Vec::new();
-            for child_name in node.children() {
-                let child_path = path.join(child_name);
-                let child_node = self.check_node(&child_path)?;
-                let child_op = operation.with_path(child_path.clone());
-                let this = self.clone();
-                let tx2 = tx.clone();
-                joinvec.push(track_progress(child_path, tx.clone(), |progress| async {
-                    this.operate_deep(child_op, child_node, progress, tx2).await
-                }));
+    /// Hang the remote storage as on the dead connections of a sleep of the system,
+    /// and check that the resume interrupts the upload in flight to retry it
+    #[tokio::test(start_paused = true)]
+    async fn resumes_retry_the_operations_in_flight() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/a.txt"), b"a", mtime(1000));
+        let resumes = Resumes::default();
+        let service = Service::new(local, remote.clone(), local_root())
+            .await
+            .unwrap()
+            .with_resumes(resumes.clone());
+        let service = Arc::new(service);
+        tokio::spawn(service.clone().run_resumes());
+
+        remote.set_latency(Duration::from_secs(24 * 3600));
+        let a = PathBuf::from("/a.txt");
+        let progress = service
+            .clone()
+            .operate(Operation::Sync(a.clone()))
+            .await
+            .unwrap();
+        assert!(!progress.is_done());
+
+        // the connections are established again after the resume
+        remote.clear_faults();
+        resumes.notify(Duration::from_secs(3600));
+        loop {
+            match service.progress(&a).await.unwrap() {
+                Some(progress) if !progress.is_done() => {
+                    assert!(!progress.is_failed(), "{progress:?}");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                _ => break,
             }
-            future::try_join_all(joinvec).await?;
+        }
+        assert_eq!(remote.content(&a).unwrap(), b"a");
+        let resumed = service.status().resumed.unwrap();
+        assert_eq!(resumed.asleep_secs, 3600);
+        assert!(!resumed.resyncing);
+    }
 
-            if !parent_first {
-                debug_assert!(matches!(operation, Operation::DeleteDeep(..)));
-                self.operate_unit(operation.not_deep(), node.without_children(), progress)
-                    .await?;
+    #[tokio::test(start_paused = true)]
+    async fn scheduled_operations_wait_for_the_policy() {
+        #[derive(Debug)]
+        struct OnBattery;
+
+        impl policy::PowerProbe for OnBattery {
+            fn on_battery(&self) -> Option<bool> {
+                Some(true)
             }
+        }
 
-            Ok(())
-        })
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/a.txt"), b"a", mtime(1000));
+        local.put_file(Path::new("/b.txt"), b"b", mtime(1000));
+        let deferral = fsync::Deferral {
+            defer_on_battery: true,
+            active_hours: None,
+            max_duration: None,
+        };
+        let service = Service::new(local, remote.clone(), local_root())
+            .await
+            .unwrap()
+            .with_policy(Policy::new(deferral, Box::new(OnBattery)));
+        let service = Arc::new(service);
+        assert!(service.status().policy.deferred.is_some());
+
+        // the operations started by the user always run
+        let progress = service
+            .clone()
+            .operate(Operation::Sync(PathBuf::from("/a.txt")))
+            .await
+            .unwrap();
+        assert!(progress.is_done());
+
+        let b = PathBuf::from("/b.txt");
+        let progress = service
+            .clone()
+            .operate_scheduled(Operation::Sync(b.clone()), OperateOptions::default())
+            .await
+            .unwrap();
+        assert!(
+            matches!(progress, fsync::Progress::Waiting(..)),
+            "{progress:?}"
+        );
+        tokio::time::sleep(policy::RETRY * 3).await;
+        assert!(remote.content(&b).is_none());
+
+        // reloaded without the deferral
+        service.policy.set_deferral(fsync::Deferral::default());
+        tokio::time::sleep(policy::RETRY * 2).await;
+        assert_eq!(remote.content(&b).unwrap(), b"b");
     }
 
-    pub async fn operate(self: Arc<Self>, operation: Operation) -> fsync::Result<Progress> {
-        let (tx, mut rx) = mpsc::channel::<(PathBuf, SharedProgress)>(32);
+    /// Run a scheduled sync with a time budget on a slow storage,
+    /// and resume it from its checkpoint until it completes
+    #[tokio::test(start_paused = true)]
+    async fn scheduled_syncs_resume_from_their_checkpoint() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        for i in 0..40 {
+            let path = PathBuf::from(format!("/dir{}/{i:02}.txt", i % 4));
+            local.put_file(&path, format!("file {i}").as_bytes(), mtime(1000));
+        }
+        let deferral = fsync::Deferral {
+            max_duration: Some(1),
+            ..fsync::Deferral::default()
+        };
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap()
+            .with_policy(Policy::new(deferral, Box::new(policy::NoProbe)));
+        let service = Arc::new(service);
+        remote.set_latency(Duration::from_secs(15));
 
-        let join = {
-            let this = self.clone();
-            tokio::spawn(async move {
-                let path = operation.path().to_owned();
-                track_progress(path, tx.clone(), move |progress| async move {
-                    let node = this.check_node(operation.path())?;
-                    if operation.is_deep() {
-                        this.operate_deep(operation, node, progress, tx).await
-                    } else {
-                        this.operate_unit(operation, node, progress).await
-                    }
-                })
-                .await
-            })
+        let operation = Operation::SyncDeep(PathBuf::root());
+        // the number of entries left by the run
+        let run = |operation: Operation| {
+            let service = service.clone();
+            async move {
+                let progress = service
+                    .clone()
+                    .operate_scheduled(operation, OperateOptions::default())
+                    .await
+                    .unwrap();
+                assert!(!progress.is_done());
+                while service.progress(Path::root()).await.unwrap().is_some() {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                service.status().checkpoint.map_or(0, |c| c.remaining)
+            }
         };
 
-        let sleep = tokio::time::sleep(Duration::from_millis(50));
+        let remaining = run(operation.clone()).await;
+        assert!(remaining > 0);
+        let left = service.checkpoints.get().unwrap().paths;
+        assert_eq!(left.len(), remaining as usize);
+        assert!(left.iter().all(|path| remote.content(path).is_none()));
 
-        tokio::select! {
-            res = join => {
-                log::trace!("Operation completed within 50ms");
-                match res {
-                    Ok(Ok(())) => {
-                        if cfg!(debug_assertions) {
-                            let (_, prog) = rx.try_recv().expect("should receive at least root progress");
-                            debug_assert!(matches!(prog.get(), Progress::Done));
-                        }
-                        Ok(Progress::Done)
-                    },
-                    Ok(Err(e)) => Err(e),
-                    Err(err) => Err(fsync::Error::Bug(err.to_string())),
-                }
-            },
-            _ = sleep => {
-                log::trace!("Operation completed within 50ms");
+        // the tree changes between the runs: an entry is synchronized by the user
+        let first = left.iter().find(|path| path.parent() != Some(Path::root()));
+        service
+            .clone()
+            .operate(Operation::Sync(first.unwrap().clone()))
+            .await
+            .unwrap();
 
-                let (path, first_progress) = rx
-                    .try_recv()
-                    .expect("should receive at least root progress");
+        let mut runs = 1;
+        let mut remaining = remaining;
+        while remaining > 0 {
+            let left = run(operation.clone()).await;
+            assert!(left < remaining, "{left} >= {remaining}");
+            remaining = left;
+            runs += 1;
+        }
+        assert!(runs > 2, "{runs}");
+        assert_eq!(remaining, 0);
+        for i in 0..40 {
+            let path = PathBuf::from(format!("/dir{}/{i:02}.txt", i % 4));
+            assert_eq!(read(&remote, path.as_str()).unwrap(), format!("file {i}"));
+        }
+    }
 
-                self.add_progress(path, first_progress.clone()).await;
+    #[tokio::test(start_paused = true)]
+    async fn large_deletions_need_a_confirmation() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        for i in 0..5 {
+            local.put_file(&PathBuf::from(format!("/big/{i}.txt")), b"big", mtime(1000));
+        }
+        local.put_file(Path::new("/small/a.txt"), b"small", mtime(1000));
+        let guard = fsync::DeleteGuard {
+            max_files: 3,
+            max_bytes: 0,
+        };
+        let service = Service::new(local.clone(), remote, local_root())
+            .await
+            .unwrap()
+            .with_delete_guard(guard);
+        let service = Arc::new(service);
+        let delete = |path: &str| Operation::DeleteDeep(PathBuf::from(path), DeletionMethod::Local);
+        let confirmed = |token: &str| OperateOptions {
+            confirmation: Some(token.to_string()),
+            ..OperateOptions::default()
+        };
+        let token = |res: fsync::Result<Progress>| match res {
+            Err(Error::ConfirmationRequired(token, summary)) => (token, summary),
+            res => panic!("expected a confirmation request, got {res:?}"),
+        };
 
-                tokio::spawn(async move {
-                    while let Some((path, progress)) = rx.recv().await {
-                        self.add_progress(path, progress).await;
-                    }
-                });
-                Ok(first_progress.get())
+        // below the thresholds
+        let progress = service.clone().operate(delete("/small")).await.unwrap();
+        assert!(progress.is_done(), "{progress:?}");
+        assert!(local.content(Path::new("/small/a.txt")).is_none());
+
+        let (first, summary) = token(service.clone().operate(delete("/big")).await);
+        assert!(summary.contains("5 local files"), "{summary}");
+
+        // a token only confirms the operation it was returned for
+        let other = Operation::DeleteDeep(PathBuf::from("/big"), DeletionMethod::All);
+        let (second, _) = token(service.clone().operate_with(other, confirmed(&first)).await);
+        assert_ne!(first, second);
+
+        // expired
+        tokio::time::sleep(fsync::CONFIRMATION_WINDOW + Duration::from_secs(1)).await;
+        let (third, _) = token(
+            service
+                .clone()
+                .operate_with(delete("/big"), confirmed(&first))
+                .await,
+        );
+        assert!(local.content(Path::new("/big/0.txt")).is_some());
+
+        service
+            .clone()
+            .operate_with(delete("/big"), confirmed(&third))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(local.content(Path::new("/big/0.txt")).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn status_file_is_written_and_never_in_the_tree() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/a.txt"), b"a", mtime(1000));
+        remote.put_file(Path::new("/b.txt"), b"b", mtime(1000));
+        local.put_file(Path::new("/c.txt"), b"local", mtime(1000));
+        remote.put_file(Path::new("/c.txt"), b"remote", mtime(2000));
+        let interval = Duration::from_secs(600);
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap()
+            .with_status_file(interval);
+        let service = Arc::new(service);
+
+        let deep = || Operation::SyncDeep(PathBuf::root());
+        let _ = service.clone().operate(deep()).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let status = read(&local, status_file::PATH).unwrap();
+        assert_eq!(read(&remote, status_file::PATH).unwrap(), status);
+        let json: serde_json::Value = serde_json::from_str(&status).unwrap();
+        assert_eq!(json["conflicts"], 1);
+        assert_eq!(json["conflictPaths"][0], "/c.txt");
+        assert_eq!(json["fsyncVersion"], env!("CARGO_PKG_VERSION"));
+
+        let path = Path::new(status_file::PATH);
+        assert!(!service.tree.has_entry(path));
+        let report = service.rescan(Path::root(), true).await.unwrap();
+        assert!(report.is_empty(), "{report:?}");
+        assert!(!service.tree.has_entry(path));
+        let fresh = DiffTree::build(&local, &remote).await.unwrap();
+        assert!(!fresh.has_entry(path));
+
+        // written again once the interval has elapsed
+        service
+            .clone()
+            .operate(Operation::Resolve(
+                PathBuf::from("/c.txt"),
+                ResolutionMethod::ReplaceRemoteByLocal,
+            ))
+            .await
+            .unwrap();
+        let _ = service.clone().operate(deep()).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(read(&local, status_file::PATH).unwrap(), status);
+        tokio::time::sleep(interval).await;
+        let status = read(&remote, status_file::PATH).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&status).unwrap();
+        assert_eq!(json["conflicts"], 0);
+        assert!(!service.tree.has_entry(path));
+    }
+
+    #[tokio::test]
+    async fn maintenance_deletes_the_orphaned_temp_files() {
+        use fsync::{Cleanup, CleanupKind};
+
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/dir/a.txt.fsync-part"), b"partial", mtime(1000));
+        local.put_file(Path::new("/dir/b.txt.fsync-part.1"), b"part", mtime(1000));
+        local.put_file(Path::new("/dir/c.txt"), b"c", mtime(1000));
+
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap();
+        let expected = vec![
+            Cleanup {
+                kind: CleanupKind::TempFile,
+                path: "/dir/a.txt.fsync-part".into(),
+                bytes: 7,
             },
+            Cleanup {
+                kind: CleanupKind::TempFile,
+                path: "/dir/b.txt.fsync-part.1".into(),
+                bytes: 4,
+            },
+        ];
+
+        let report = service.maintain(true).await.unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.cleanups, expected);
+        assert!(read(&local, "/dir/a.txt.fsync-part").is_some());
+
+        // the temporary file of an operation in flight is kept
+        service
+            .add_progress(PathBuf::from("/dir/b.txt"), SharedProgress::new())
+            .await;
+        let report = service.maintain(false).await.unwrap();
+        assert_eq!(report.cleanups, expected[..1]);
+        assert!(read(&local, "/dir/a.txt.fsync-part").is_none());
+        assert!(read(&local, "/dir/b.txt.fsync-part.1").is_some());
+        assert!(read(&local, "/dir/c.txt").is_some());
+        assert!(!service.tree.has_entry(Path::new("/dir/a.txt.fsync-part")));
+    }
+
+    #[tokio::test]
+    async fn progresses_since_cursor() {
+        let service = Service::new(MemStorage::new(), MemStorage::new(), local_root())
+            .await
+            .unwrap();
+        let a = SharedProgress::new();
+        let other = SharedProgress::new();
+        service
+            .add_progress(PathBuf::from("/dir/a"), a.clone())
+            .await;
+        service
+            .add_progress(PathBuf::from("/other"), other.clone())
+            .await;
+
+        let (cursor, changed) = service
+            .progresses_since(Path::new("/dir"), 0)
+            .await
+            .unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, Path::new("/dir/a"));
+
+        // the progresses of other paths are updated concurrently
+        let stop = Arc::new(AtomicUsize::new(0));
+        let updater = {
+            let stop = stop.clone();
+            let other = other.clone();
+            std::thread::spawn(move || {
+                let mut progress = 0;
+                while stop.load(Ordering::Relaxed) == 0 {
+                    progress += 1;
+                    other.set(Progress::Progress {
+                        progress,
+                        total: u64::MAX,
+                    });
+                }
+            })
+        };
+        let (cursor, changed) = service
+            .progresses_since(Path::new("/dir"), cursor)
+            .await
+            .unwrap();
+        assert!(changed.is_empty());
+
+        // while the updater is held, the cursor is current: nothing is locked nor allocated
+        for _ in 0..100 {
+            SharedProgress::with_changes(|changes| {
+                let res = service
+                    .progresses_since(Path::root(), changes)
+                    .now_or_never()
+                    .expect("the fast path should not wait");
+                let (res_cursor, changed) = res.unwrap();
+                assert_eq!(res_cursor, changes);
+                assert_eq!(changed.capacity(), 0);
+            });
         }
+        stop.store(1, Ordering::Relaxed);
+        updater.join().unwrap();
+
+        // the completed operation is reported after the poll loop removed it
+        a.set(Progress::Done);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(service
+            .progresses(Path::new("/dir"))
+            .await
+            .unwrap()
+            .is_empty());
+        let (_, changed) = service
+            .progresses_since(Path::new("/dir"), cursor)
+            .await
+            .unwrap();
+        assert_eq!(changed.len(), 1);
+        assert!(matches!(changed[0].1, Progress::Done));
+    }
+
+    #[tokio::test]
+    async fn coarse_local_mtimes_are_not_conflicts() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        // copied to FAT, which rounds the times up to the next even second
+        remote.put_file(Path::new("/rounded.txt"), b"rounded", mtime(1001));
+        local.put_file(Path::new("/rounded.txt"), b"rounded", mtime(1002));
+        remote.put_file(Path::new("/dir/rounded.txt"), b"rounded", mtime(1003));
+        local.put_file(Path::new("/dir/rounded.txt"), b"rounded", mtime(1004));
+
+        let options = crate::tree::BuildOptions {
+            fs_caps: Some(fsync::caps::FsCaps {
+                mtime_granularity: 2,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let service = Service::new_with(local.clone(), remote.clone(), local_root(), options)
+            .await
+            .unwrap();
+        assert!(service.conflicts.read().await.is_empty());
+
+        // the rounding happens again when the file is touched
+        local.put_file(Path::new("/dir/rounded.txt"), b"rounded", mtime(1002));
+        service.rescan(Path::root(), true).await.unwrap();
+        assert!(service.conflicts.read().await.is_empty());
+
+        // with the default granularity, every rounding is a conflict
+        let service = Service::new(local, remote, local_root()).await.unwrap();
+        assert_eq!(service.conflicts.read().await.len(), 2);
     }
-}
 
-impl<L, R> crate::Shutdown for Service<L, R>
-where
-    L: storage::Storage,
-    R: storage::Storage,
-{
-    async fn shutdown(&self) -> anyhow::Result<()> {
-        log::info!("Shutting service down");
-        {
-            let abort_handle = self.abort_handle.read().await;
-            if let Some(abort_handle) = &*abort_handle {
-                abort_handle.abort();
-            }
+    #[tokio::test]
+    async fn timezone_shifted_mtimes_are_repaired() {
+        const HOUR: i64 = 3600;
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        // FAT stores local times, read one hour off across a DST change
+        remote.put_file(Path::new("/summer.txt"), b"summer", mtime(1000));
+        local.put_file(Path::new("/summer.txt"), b"summer", mtime(1000 + HOUR));
+        remote.put_file(Path::new("/winter.txt"), b"winter", mtime(1000 + HOUR));
+        local.put_file(Path::new("/winter.txt"), b"winter", mtime(1000));
+        // together with the 2-second rounding
+        remote.put_file(Path::new("/dir/rounded.txt"), b"rounded", mtime(1001));
+        local.put_file(
+            Path::new("/dir/rounded.txt"),
+            b"rounded",
+            mtime(1002 + HOUR),
+        );
+
+        let options = crate::tree::BuildOptions {
+            fs_caps: Some(fsync::caps::FsCaps {
+                mtime_granularity: 2,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let service = Service::new_with(local.clone(), remote.clone(), local_root(), options)
+            .await
+            .unwrap();
+        assert_eq!(service.conflicts.read().await.len(), 3);
+
+        let mut repaired = service.repair_shifted_mtimes().await.unwrap();
+        repaired.sort();
+        assert_eq!(
+            repaired,
+            ["/dir/rounded.txt", "/summer.txt", "/winter.txt"].map(PathBuf::from)
+        );
+        assert!(service.conflicts.read().await.is_empty());
+        // compared with the remote checksums, the remote files are not read
+        assert_eq!(remote.transferred(), 0);
+        for (path, secs) in [("/summer.txt", 1000), ("/winter.txt", 1000 + HOUR)] {
+            let node = service.tree.entry(Path::new(path)).unwrap();
+            assert!(!node.entry().is_conflict());
+            let md = crate::tree::storage_entry(&local, Path::new(path))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(md.mtime(), Some(mtime(secs)));
         }
-        let fut1 = self.local.shutdown();
-        let fut2 = self.remote.shutdown();
-        tokio::try_join!(fut1, fut2)?;
-        Ok(())
+        assert!(service.repair_shifted_mtimes().await.unwrap().is_empty());
     }
-}
-
-async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
-    tokio::spawn(fut);
-}
 
-#[derive(Clone, Debug)]
-pub struct RpcService<L, R> {
-    inner: Arc<Service<L, R>>,
-}
+    #[tokio::test]
+    async fn timezone_shift_keeps_the_edited_files() {
+        const HOUR: i64 = 3600;
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        // same size, one hour apart, but edited
+        remote.put_file(Path::new("/edited.txt"), b"before", mtime(1000));
+        local.put_file(Path::new("/edited.txt"), b"after!", mtime(1000 + HOUR));
+        // not a whole hour
+        remote.put_file(Path::new("/later.txt"), b"later", mtime(1000));
+        local.put_file(Path::new("/later.txt"), b"later", mtime(1000 + HOUR + 60));
 
-impl<L, R> RpcService<L, R>
-where
-    L: storage::LocalStorage,
-    R: storage::Storage,
-{
-    pub async fn new(service: Arc<Service<L, R>>, abort_handle: AbortHandle) -> Self {
-        debug_assert!(
-            service.abort_handle.read().await.is_none(),
-            "Cannot share Service among multiple RpcService"
+        let service = Service::new(local.clone(), remote, local_root())
+            .await
+            .unwrap();
+        assert!(service.repair_shifted_mtimes().await.unwrap().is_empty());
+        assert_eq!(service.conflicts.read().await.len(), 2);
+        assert_eq!(
+            local.content(Path::new("/edited.txt")).unwrap(),
+            b"after!".to_vec()
         );
-        *service.abort_handle.write().await = Some(abort_handle);
-        Self { inner: service }
     }
 
-    pub async fn start(
-        &self,
-        instance_name: &str,
-        abort_reg: AbortRegistration,
-    ) -> anyhow::Result<()> {
-        let server_addr = (IpAddr::V6(Ipv6Addr::LOCALHOST), 0);
+    #[tokio::test]
+    async fn identical_conflicts_are_paired() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        // uploaded before the daemon stopped, the remote file has the time of the upload
+        local.put_file(Path::new("/uploaded.txt"), b"uploaded", mtime(1000));
+        remote.put_file(Path::new("/uploaded.txt"), b"uploaded", mtime(1500));
+        // same size, edited on one side
+        local.put_file(Path::new("/edited.txt"), b"after!", mtime(1000));
+        remote.put_file(Path::new("/edited.txt"), b"before", mtime(1500));
 
-        let mut listener =
-            tarpc::serde_transport::tcp::listen(&server_addr, Bincode::default).await?;
+        let dir = std::env::temp_dir().join(format!("fsyncd-pair-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let audit_path = dir.join("audit.jsonl");
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap()
+            .with_audit_log(
+                crate::audit::AuditLog::open(audit_path.clone())
+                    .await
+                    .unwrap(),
+            );
+        assert_eq!(service.conflicts.read().await.len(), 2);
 
-        log::info!("Listening on port {}", listener.local_addr().port());
+        let repaired = service.repair_identical_conflicts().await.unwrap();
+        assert_eq!(repaired, [PathBuf::from("/uploaded.txt")]);
+        assert_eq!(
+            service
+                .conflicts
+                .read()
+                .await
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>(),
+            [PathBuf::from("/edited.txt")]
+        );
+        let md = crate::tree::storage_entry(&local, Path::new("/uploaded.txt"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(md.mtime(), Some(mtime(1500)));
+        assert_eq!(service.repairs().since_boot, 1);
+        assert_eq!(service.counters().since_boot.bytes_uploaded, 0);
 
-        let port_path = inst::runtime_port_file(instance_name)?;
-        tokio::fs::create_dir_all(port_path.parent().unwrap()).await?;
+        let records = fsync::audit::read_records(&audit_path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].path, PathBuf::from("/uploaded.txt"));
+        assert!(matches!(records[0].action, fsync::Action::Pair));
 
-        let port_str = serde_json::to_string(&listener.local_addr().port())?;
-        log::trace!("Creating file {port_path}");
-        tokio::fs::write(&port_path, port_str.as_bytes()).await?;
+        assert!(service
+            .repair_identical_conflicts()
+            .await
+            .unwrap()
+            .is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-        listener.config_mut().max_frame_length(usize::MAX);
-        let fut = listener
-            // Ignore accept errors.
-            .filter_map(|r| future::ready(r.ok()))
-            .map(server::BaseChannel::with_defaults)
-            // Limit channels to 1 per IP.
-            .max_channels_per_key(1, |t| t.transport().peer_addr().unwrap().ip())
-            // serve is generated by the service attribute. It takes as input any type implementing
-            // the generated Fsync trait.
-            .map(|channel| channel.execute(self.clone().serve()).for_each(spawn))
-            // Max 10 channels.
-            .buffer_unordered(10)
-            .for_each(|_| async {});
+    #[tokio::test]
+    async fn remote_files_found_next_to_local_files_are_paired() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/a.txt"), b"a content", mtime(1000));
+        local.put_file(Path::new("/b.txt"), b"b content", mtime(1000));
+        let service = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap();
+        assert!(service.conflicts.read().await.is_empty());
 
-        let _ = Abortable::new(fut, abort_reg).await;
+        // committed by an upload, the tree was not updated before the daemon stopped
+        remote.put_file(Path::new("/a.txt"), b"a content", mtime(2000));
+        remote.put_file(Path::new("/b.txt"), b"b CONTENT", mtime(2000));
+        let aggregator = crate::aggregate::Aggregator::new(Duration::ZERO);
+        service
+            .aggregate_dir(&aggregator, Path::root())
+            .await
+            .unwrap();
 
-        log::trace!("Removing file {port_path}");
-        tokio::fs::remove_file(&port_path).await?;
-        Ok(())
+        let node = service.tree.entry(Path::new("/a.txt")).unwrap();
+        assert!(node.entry().is_sync());
+        assert!(!node.entry().is_conflict());
+        // a real conflict is left to the user
+        assert_eq!(
+            service
+                .conflicts
+                .read()
+                .await
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>(),
+            [PathBuf::from("/b.txt")]
+        );
+        assert_eq!(service.repairs().since_boot, 1);
     }
-}
 
-impl<L, R> Fsync for RpcService<L, R>
-where
-    L: storage::LocalStorage,
-    R: storage::Storage,
-{
-    async fn conflicts(
-        self,
-        _: Context,
-        start: Option<PathBuf>,
-        max_len: u32,
-    ) -> fsync::Result<Vec<fsync::tree::Entry>> {
-        let max_len = max_len.min(100);
-        let res = self.inner.conflicts(start.as_deref(), max_len as _).await;
-        log::trace!(target: "RPC", "Fsync::conflicts({start:?}, {max_len}) -> {res:#?}");
-        res
-    }
+    type Summary = BTreeMap<PathBuf, (Option<Metadata>, Option<Metadata>, bool)>;
 
-    async fn entry_node(
-        self,
-        _: Context,
-        path: PathBuf,
-    ) -> fsync::Result<Option<fsync::tree::EntryNode>> {
-        let res = self.inner.entry_node(&path).await;
-        log::trace!(target: "RPC", "Fsync::entry(path: {path:?}) -> {res:#?}");
-        res
+    /// The entries of `tree`, without the stats of the directories
+    fn summary(tree: &DiffTree) -> Summary {
+        let md = |md: Option<Metadata>| match md {
+            Some(Metadata::Directory { path, .. }) => {
+                Some(Metadata::Directory { path, stat: None })
+            }
+            md => md,
+        };
+        tree.entries()
+            .map(|node| {
+                let entry = node.entry();
+                let local = md(entry.clone().into_local_metadata());
+                let remote = md(entry.clone().into_remote_metadata());
+                (node.path().to_owned(), (local, remote, entry.is_conflict()))
+            })
+            .collect()
     }
 
-    async fn local_path(self, _: Context, path: Option<PathBuf>) -> fsync::Result<FsPathBuf> {
-        let res = self.inner.local_path(path.as_deref()).await;
-        log::trace!(target: "RPC", "Fsync::local_path(path: {path:?}) -> {res:#?}");
-        res
+    #[tokio::test]
+    async fn files_are_streamed_between_services() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/sync.txt"), b"local copy", mtime(1000));
+        remote.put_file(Path::new("/sync.txt"), b"local copy", mtime(1000));
+        remote.put_file(Path::new("/remote.txt"), b"remote only", mtime(1000));
+        let from = Service::new(local, remote, local_root()).await.unwrap();
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        let to = Service::new(local.clone(), remote.clone(), local_root())
+            .await
+            .unwrap();
+
+        let chunk = from
+            .read_stream(Path::new("/sync.txt"), 0, 5)
+            .await
+            .unwrap();
+        assert_eq!(chunk.loc, StorageLoc::Local);
+        assert_eq!(chunk.data, b"local");
+        let chunk = from
+            .read_stream(Path::new("/remote.txt"), 7, 100)
+            .await
+            .unwrap();
+        assert_eq!(chunk.loc, StorageLoc::Remote);
+        assert_eq!(chunk.data, b"only");
+        let end = from
+            .read_stream(Path::new("/remote.txt"), 11, 100)
+            .await
+            .unwrap();
+        assert!(end.data.is_empty());
+
+        to.receive_dir(Path::new("/dir/sub")).await.unwrap();
+        let dest = chunk
+            .metadata
+            .with_path(PathBuf::from("/dir/sub/remote.txt"));
+        let handle = to.receive_begin(dest.clone()).await.unwrap();
+        assert!(to.receive_begin(dest.clone()).await.is_err());
+        to.receive_chunk(handle, b"remote only").await.unwrap();
+        let md5 = "0123456789abcdef0123456789abcdef";
+        assert!(to.receive_finish(handle, md5).await.is_err());
+        assert!(remote.content(dest.path()).is_none());
+
+        let handle = to.receive_begin(dest.clone()).await.unwrap();
+        to.receive_chunk(handle, b"remote ").await.unwrap();
+        to.receive_chunk(handle, b"only").await.unwrap();
+        let md5 = "3177a882241c5bc52a453e0b5abd0b06";
+        let metadata = to.receive_finish(handle, md5).await.unwrap();
+        assert_eq!(metadata.size(), Some(11));
+        assert_eq!(read(&remote, "/dir/sub/remote.txt").unwrap(), "remote only");
+        assert!(to
+            .tree
+            .entry(Path::new("/dir/sub/remote.txt"))
+            .unwrap()
+            .entry()
+            .is_remote_only());
+        assert!(local.content(dest.path()).is_none());
     }
 
-    async fn operate(self, _: Context, operation: fsync::Operation) -> fsync::Result<Progress> {
-        if log::log_enabled!(log::Level::Trace) {
-            let op = operation.clone();
-            let res = self.inner.operate(operation).await;
-            log::trace!(target: "RPC", "Fsync::operate({op:?}) -> {res:#?}");
-            res
-        } else {
-            self.inner.operate(operation).await
-        }
+    fn local_root() -> FsPathBuf {
+        FsPathBuf::from("/fsyncd-test/local")
     }
 
-    async fn progress(self, _: Context, path: PathBuf) -> fsync::Result<Option<fsync::Progress>> {
-        let res = self.inner.progress(&path).await;
-        log::trace!(target: "RPC", "Fsync::progress(path: {path:?}) -> {res:#?}");
-        res
+    fn mtime(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
     }
 
-    async fn progresses(
-        self,
-        _: Context,
-        path: PathBuf,
-    ) -> fsync::Result<Vec<(PathBuf, fsync::Progress)>> {
-        let res = self.inner.progresses(&path).await;
-        log::trace!(target: "RPC", "Fsync::progresses({path:#?}) -> {res:#?}");
-        res
+    fn read(storage: &MemStorage, path: &str) -> Option<String> {
+        let content = storage.content(Path::new(path))?;
+        Some(String::from_utf8(content).unwrap())
     }
-}
 
-fn copy_path(path: &Path) -> PathBuf {
-    debug_assert!(!path.is_root());
-    let parent = path
-        .parent()
-        .expect("Expected path to be copied to have a parent");
-    let stem = path
-        .file_stem()
-        .expect("Expected path to be copied to have a name");
-    let ext = path.extension();
+    /// Crash a unit operation at each stage, recover it as the daemon does at startup,
+    /// and check that the tree matches the storages and that retrying does not apply twice.
+    #[tokio::test]
+    async fn journal_recovers_crashed_operations() {
+        let root =
+            std::env::temp_dir().join(format!("fsyncd-journal-crash-{}", std::process::id()));
+        let root = FsPathBuf::try_from(root).unwrap();
 
-    let cap = parent.as_str().len() + stem.len() + ext.map_or(0, |e| e.len() + 1) + 6;
+        let operations = [
+            Operation::Sync(PathBuf::from("/dir/pull.txt")),
+            Operation::Sync(PathBuf::from("/push.txt")),
+            Operation::Resolve(
+                PathBuf::from("/both.txt"),
+                ResolutionMethod::CreateLocalCopy,
+            ),
+            Operation::Delete(PathBuf::from("/synced.txt"), DeletionMethod::All),
+        ];
+        let stages = [Stage::Begun, Stage::Performed, Stage::Applied];
 
-    let mut res = PathBuf::with_capacity(cap);
-    res.push(parent);
-    res.push(format!("{stem}-copy"));
-    if let Some(ext) = ext {
-        res.set_extension(ext);
-    }
-    res
-}
+        for operation in operations {
+            for stage in stages {
+                for skip in 0.. {
+                    let _ = std::fs::remove_dir_all(&root);
+                    let (local, remote) = (MemStorage::new(), MemStorage::new());
+                    let write = |storage: &MemStorage, path: &str, content: &str, secs| {
+                        storage.put_file(Path::new(path), content.as_bytes(), mtime(secs))
+                    };
+                    write(&remote, "/dir/pull.txt", "remote content", 1000);
+                    write(&local, "/push.txt", "local content", 1000);
+                    write(&local, "/both.txt", "local version", 2000);
+                    write(&remote, "/both.txt", "remote version", 1000);
+                    write(&local, "/synced.txt", "synced", 1000);
+                    write(&remote, "/synced.txt", "synced", 1000);
 
-#[cfg(test)]
-mod tests {
-    use std::{collections::BTreeSet, ops::Bound};
+                    let journal_path = root.join("journal.jsonl");
+                    let journal = Journal::open(journal_path.clone()).await.unwrap();
+                    journal.crash_at(stage, skip);
+                    let mut service = Service::new(local.clone(), remote.clone(), local_root())
+                        .await
+                        .unwrap()
+                        .with_journal(journal);
 
-    use fsync::path::{Path, PathBuf};
+                    let path = operation.path().to_owned();
+                    let node = service.check_node(&path).unwrap();
+                    let res = std::panic::AssertUnwindSafe(service.operate_unit(
+                        operation.clone(),
+                        node,
+                        OperateOptions::default(),
+                        SharedProgress::new(),
+                    ))
+                    .catch_unwind()
+                    .await;
+                    if res.is_ok() {
+                        // the operation has less than `skip` effects
+                        assert!(skip > 0, "{operation:?} did not reach {stage:?}");
+                        break;
+                    }
 
-    #[test]
-    fn test_copy_path() {
-        use super::copy_path;
+                    // the daemon restarts with the journal left by the crash
+                    let journal = Journal::open(journal_path).await.unwrap();
+                    assert_eq!(journal.pending().await.len(), 1);
+                    service.journal = Some(journal);
+                    assert_eq!(service.recover().await.unwrap(), 1);
+                    assert!(service.journal.as_ref().unwrap().pending().await.is_empty());
 
-        assert_eq!(
-            Path::new("/parent/stem-copy.ext"),
-            copy_path(&Path::new("/parent/stem.ext"))
-        );
+                    let context = format!("{operation:?} crashed at {stage:?} after {skip}");
+                    let fresh = DiffTree::build(&local, &remote).await.unwrap();
+                    assert_eq!(summary(&service.tree), summary(&fresh), "{context}");
+                    assert_eq!(
+                        *service.conflicts.read().await,
+                        tree_conflicts(&fresh),
+                        "{context}"
+                    );
 
-        assert_eq!(
-            Path::new("/parent/noext-copy"),
-            copy_path(&Path::new("/parent/noext"))
-        );
+                    if let Some(node) = service.tree.entry(&path) {
+                        let res = service
+                            .operate_unit(
+                                operation.clone(),
+                                node,
+                                OperateOptions::default(),
+                                SharedProgress::new(),
+                            )
+                            .await;
+                        // crashed between the copy and the replacement of the local file
+                        let copied_before = matches!(operation, Operation::Resolve(..))
+                            && stage == Stage::Performed
+                            && skip == 1;
+                        // the copy is not done twice
+                        assert_eq!(res.is_err(), copied_before, "{context}: {res:?}");
+                    }
+                    let local = |p: &str| read(&local, p);
+                    let remote = |p: &str| read(&remote, p);
+                    match &operation {
+                        Operation::Sync(path) if path.as_str() == "/dir/pull.txt" => {
+                            assert_eq!(local("/dir/pull.txt").unwrap(), "remote content");
+                        }
+                        Operation::Sync(..) => {
+                            assert_eq!(remote("/push.txt").unwrap(), "local content");
+                        }
+                        Operation::Resolve(..) => {
+                            assert_eq!(local("/both-copy.txt").unwrap(), "local version");
+                            assert!(local("/both-copy-copy.txt").is_none());
+                            if !service.tree.entry(&path).unwrap().entry().is_conflict() {
+                                assert_eq!(local("/both.txt").unwrap(), "remote version");
+                            }
+                        }
+                        Operation::Delete(..) => {
+                            assert!(local("/synced.txt").is_none());
+                            assert!(remote("/synced.txt").is_none());
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+            }
+        }
 
-        assert_eq!(
-            Path::new("/file_at_root-copy.ext"),
-            copy_path(&Path::new("/file_at_root.ext"))
-        );
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    fn build_test_conflicts() -> BTreeSet<PathBuf> {
-        let mut set = BTreeSet::new();
+    /// The files and folders of the randomized tests.
+    /// A path is always of the same type, so that each storage is a valid tree.
+    const DIRS: [&str; 2] = ["/d", "/d/e"];
+    const FILES: [&str; 4] = ["/f", "/d/f", "/d/g", "/d/e/f"];
 
-        set.insert(PathBuf::from("/a/a/a"));
-        set.insert(PathBuf::from("/a/a/b"));
-        set.insert(PathBuf::from("/a/b/a"));
-        set.insert(PathBuf::from("/a/b/b"));
+    /// The summary of the tree expected from the entries of the storages
+    fn model(local: &MemStorage, remote: &MemStorage) -> Summary {
+        let mut model = Summary::new();
+        for md in local.entries() {
+            model.insert(md.path().to_owned(), (Some(md), None, false));
+        }
+        for md in remote.entries() {
+            let path = md.path().to_owned();
+            model.entry(path).or_default().1 = Some(md);
+        }
+        for entry in model.values_mut() {
+            entry.2 = match (&entry.0, &entry.1) {
+                (Some(local), Some(remote)) => {
+                    (local.is_dir() != remote.is_dir()) || (!local.is_dir() && local != remote)
+                }
+                _ => false,
+            };
+        }
+        model
+    }
 
-        set.insert(PathBuf::from("/b/a/a"));
-        set.insert(PathBuf::from("/b/a/b"));
-        set.insert(PathBuf::from("/b/b/a"));
-        set.insert(PathBuf::from("/b/b/b"));
+    #[derive(Debug, Clone)]
+    enum Step {
+        Operate(Operation),
+        /// Write a file of `len` bytes in the local storage, then rescan
+        WriteLocal(&'static str, usize, i64),
+        /// Remove an entry of the local storage, then rescan
+        RemoveLocal(&'static str),
+        /// Fail the next calls to a storage
+        Fail(StorageLoc, usize),
+    }
 
-        set.insert(PathBuf::from("/c/a/a"));
-        set.insert(PathBuf::from("/c/a/b"));
-        set.insert(PathBuf::from("/c/b/a"));
-        set.insert(PathBuf::from("/c/b/b"));
+    type Files = Vec<(&'static str, usize, i64)>;
 
-        set
+    fn any_path() -> impl Strategy<Value = &'static str> {
+        let paths: Vec<_> = ["/"].into_iter().chain(DIRS).chain(FILES).collect();
+        prop::sample::select(paths)
     }
 
-    fn conflicts_of(conflicts: &BTreeSet<PathBuf>, path: &Path) -> Vec<PathBuf> {
-        let mut res = Vec::new();
+    fn any_files() -> impl Strategy<Value = Files> {
+        let file = (prop::sample::select(FILES.to_vec()), 1..4usize, 1..4i64);
+        prop::collection::vec(file, 0..4)
+    }
 
-        let start = Bound::Included(path.to_owned());
-        let end = Bound::Unbounded;
+    fn any_step() -> impl Strategy<Value = Step> {
+        use DeletionMethod as D;
+        use ResolutionMethod as R;
 
-        let mut conf = conflicts.range((start, end));
-        while let Some(entry) = conf.next() {
-            if entry.as_str().starts_with(path.as_str()) {
-                res.push(entry.clone());
-            } else {
-                break;
-            }
+        let deletion = prop::sample::select(vec![
+            D::LocalIfSync,
+            D::RemoteIfSync,
+            D::LocalIfSyncNoConflict,
+            D::RemoteIfSyncNoConflict,
+            D::Local,
+            D::Remote,
+            D::All,
+        ]);
+        let resolution = prop::sample::select(vec![
+            R::ReplaceOlderByNewer,
+            R::ReplaceNewerByOlder,
+            R::ReplaceLocalByRemote,
+            R::ReplaceRemoteByLocal,
+            R::DeleteOlder,
+            R::DeleteNewer,
+            R::DeleteLocal,
+            R::DeleteRemote,
+            R::CreateLocalCopy,
+        ]);
+        let path = || any_path().prop_map(PathBuf::from);
+        let operation = prop_oneof![
+            path().prop_map(Operation::Sync),
+            path().prop_map(Operation::SyncDeep),
+            (path(), deletion.clone()).prop_map(|(p, m)| Operation::Delete(p, m)),
+            (path(), deletion).prop_map(|(p, m)| Operation::DeleteDeep(p, m)),
+            (path(), resolution.clone()).prop_map(|(p, m)| Operation::Resolve(p, m)),
+            (path(), resolution).prop_map(|(p, m)| Operation::ResolveDeep(p, m)),
+        ];
+        let loc = prop::sample::select(vec![StorageLoc::Local, StorageLoc::Remote]);
+        prop_oneof![
+            6 => operation.prop_map(Step::Operate),
+            1 => (prop::sample::select(FILES.to_vec()), 1..4usize, 1..4i64)
+                .prop_map(|(path, len, secs)| Step::WriteLocal(path, len, secs)),
+            1 => any_path()
+                .prop_filter("not the root", |p| *p != "/")
+                .prop_map(Step::RemoveLocal),
+            1 => (loc, 1..3usize).prop_map(|(loc, count)| Step::Fail(loc, count)),
+        ]
+    }
+
+    fn storage(dirs: &[&str], files: &Files) -> MemStorage {
+        let storage = MemStorage::new();
+        for dir in dirs {
+            storage.put_dir(Path::new(dir));
+        }
+        for (path, len, secs) in files {
+            storage.put_file(Path::new(path), &vec![b'x'; *len], mtime(*secs));
         }
+        storage
+    }
 
-        res
+    /// Apply `step`. The failures are injected in the next operation only.
+    async fn run_step(service: &Arc<Service<MemStorage, MemStorage>>, step: Step) {
+        let (local, remote) = (service.local(), service.remote());
+        match step {
+            Step::Operate(operation) if operation.is_deep() => {
+                let (tx, _rx) = tokio::sync::mpsc::channel(1024);
+                let progress = SharedProgress::new();
+                let options = OperateOptions::default();
+                let _ = service
+                    .clone()
+                    .operate_deep(operation, options, progress, tx, 1, DeepRun::default())
+                    .await;
+            }
+            Step::Operate(operation) => {
+                if let Ok(node) = service.check_node(operation.path()) {
+                    let options = OperateOptions::default();
+                    let _ = service
+                        .operate_unit(operation, node, options, SharedProgress::new())
+                        .await;
+                }
+            }
+            Step::WriteLocal(path, len, secs) => {
+                local.clear_faults();
+                remote.clear_faults();
+                local.put_file(Path::new(path), &vec![b'x'; len], mtime(secs));
+                service.rescan(Path::root(), true).await.unwrap();
+            }
+            Step::RemoveLocal(path) => {
+                local.clear_faults();
+                remote.clear_faults();
+                local.remove(Path::new(path));
+                service.rescan(Path::root(), true).await.unwrap();
+            }
+            Step::Fail(StorageLoc::Local, count) => return local.fail_next(count),
+            Step::Fail(StorageLoc::Remote, count) => return remote.fail_next(count),
+        }
+        // what could not be refreshed after a failure is recovered at the next start
+        local.clear_faults();
+        remote.clear_faults();
+        service.recover().await.unwrap();
     }
 
-    #[test]
-    fn test_dir_contain_conflict() {
-        let conflicts = build_test_conflicts();
+    static SEQUENCE_CASE: AtomicUsize = AtomicUsize::new(0);
 
-        let bs = conflicts_of(&conflicts, Path::new("/b"));
+    proptest! {
+        /// Apply random sequences of operations, external changes and failures,
+        /// and check after each step that the tree reflects the storages
+        #[test]
+        fn tree_follows_the_storages(
+            local_dirs in prop::collection::vec(prop::sample::select(DIRS.to_vec()), 0..2),
+            local_files in any_files(),
+            remote_dirs in prop::collection::vec(prop::sample::select(DIRS.to_vec()), 0..2),
+            remote_files in any_files(),
+            steps in prop::collection::vec(any_step(), 1..8),
+        ) {
+            let case = SEQUENCE_CASE.fetch_add(1, Ordering::Relaxed);
+            let root = std::env::temp_dir().join(format!(
+                "fsyncd-sequence-{}-{case}",
+                std::process::id()
+            ));
+            let root = FsPathBuf::try_from(root).unwrap();
+            let _ = std::fs::remove_dir_all(&root);
 
-        assert_eq!(bs.len(), 4);
-        assert_eq!(bs[0], PathBuf::from("/b/a/a"));
-        assert_eq!(bs[1], PathBuf::from("/b/a/b"));
-        assert_eq!(bs[2], PathBuf::from("/b/b/a"));
-        assert_eq!(bs[3], PathBuf::from("/b/b/b"));
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(async {
+                let local = storage(&local_dirs, &local_files);
+                let remote = storage(&remote_dirs, &remote_files);
+                let journal = Journal::open(root.join("journal.jsonl")).await.unwrap();
+                let service = Service::new(local.clone(), remote.clone(), local_root())
+                    .await
+                    .unwrap()
+                    .with_journal(journal);
+                let service = Arc::new(service);
+                prop_assert_eq!(summary(&service.tree), model(&local, &remote));
+
+                for (idx, step) in steps.iter().enumerate() {
+                    run_step(&service, step.clone()).await;
+                    let expected = model(&local, &remote);
+                    prop_assert_eq!(summary(&service.tree), expected.clone(), "step {}", idx);
+                    let conflicts: BTreeSet<_> = expected
+                        .into_iter()
+                        .filter(|(_, (_, _, conflict))| *conflict)
+                        .map(|(path, _)| path)
+                        .collect();
+                    prop_assert_eq!(&*service.conflicts.read().await, &conflicts, "step {}", idx);
+                }
+                Ok(())
+            })?;
+
+            std::fs::remove_dir_all(&root).unwrap();
+        }
     }
 }
@@ -0,0 +1,131 @@
+//! The status file of the share, see [`fsync::StatusFile`].
+//!
+//! The file is written at the root of both storages through the storage traits,
+//! like any synchronized file, but it is always ignored by the tree,
+//! so that it is never synchronized nor reported as a conflict.
+//! The writes are debounced: after a deep operation, the file is written at once
+//! if the last write is older than the interval, otherwise once the interval has elapsed.
+
+use std::{sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Utc};
+use fsync::path::PathBuf;
+use serde::Serialize;
+use tokio::time::Instant;
+
+/// Path of the status file in both storages
+pub const PATH: &str = "/.fsync-status.json";
+
+/// Maximum number of conflict paths listed in the file
+pub const MAX_CONFLICTS: usize = 50;
+
+/// The content of the status file
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Content {
+    /// Version of the daemon that wrote the file
+    pub fsync_version: String,
+    /// End of the last deep operation
+    pub last_sync: DateTime<Utc>,
+    /// Stats of the root of the tree
+    pub stats: fsync::stat::Tree,
+    /// Number of conflicts in the tree
+    pub conflicts: usize,
+    /// The first conflicts, at most [`MAX_CONFLICTS`]
+    pub conflict_paths: Vec<PathBuf>,
+}
+
+impl Content {
+    pub fn new(
+        stats: fsync::stat::Tree,
+        conflicts: usize,
+        conflict_paths: impl IntoIterator<Item = PathBuf>,
+    ) -> Self {
+        Self {
+            fsync_version: env!("CARGO_PKG_VERSION").to_string(),
+            last_sync: Utc::now(),
+            stats,
+            conflicts,
+            conflict_paths: conflict_paths.into_iter().take(MAX_CONFLICTS).collect(),
+        }
+    }
+
+    /// Pretty JSON, readable by humans as well
+    pub fn to_json(&self) -> Vec<u8> {
+        let mut json = serde_json::to_vec_pretty(self).expect("status should serialize");
+        json.push(b'\n');
+        json
+    }
+}
+
+/// Debouncing of the writes of the status file
+#[derive(Debug)]
+pub struct StatusFile {
+    interval: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    last_write: Option<Instant>,
+    pending: bool,
+}
+
+impl StatusFile {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Schedule a write, and return the delay before it,
+    /// or `None` if a write is already scheduled
+    pub fn schedule(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        if state.pending {
+            return None;
+        }
+        state.pending = true;
+        let delay = state.last_write.map_or(Duration::ZERO, |last| {
+            (last + self.interval).saturating_duration_since(Instant::now())
+        });
+        Some(delay)
+    }
+
+    /// Record that the scheduled write starts now
+    pub fn start_write(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pending = false;
+        state.last_write = Some(Instant::now());
+    }
+}
+
+/// Whether the entry at `path` is the status file
+pub fn is_status_file(path: &fsync::path::Path, is_dir: bool) -> bool {
+    !is_dir && path.as_str() == PATH
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_are_debounced() {
+        let interval = Duration::from_secs(600);
+        let file = StatusFile::new(interval);
+
+        assert_eq!(file.schedule(), Some(Duration::ZERO));
+        assert_eq!(file.schedule(), None);
+        file.start_write();
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(file.schedule(), Some(Duration::from_secs(540)));
+        assert_eq!(file.schedule(), None);
+        tokio::time::sleep(Duration::from_secs(540)).await;
+        file.start_write();
+
+        tokio::time::sleep(interval * 2).await;
+        assert_eq!(file.schedule(), Some(Duration::ZERO));
+    }
+}
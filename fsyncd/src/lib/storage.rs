@@ -1,16 +1,24 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
 use fsync::{
     path::{Path, PathBuf},
     Metadata,
 };
-use futures::{Future, Stream};
-use tokio::io;
+use futures::{future, Future, Stream, TryStreamExt};
+use tokio::io::{self, AsyncReadExt};
 
 use crate::{SharedProgress, Shutdown};
 
 pub mod cache;
+pub mod crypt;
 pub mod drive;
 pub mod fs;
+pub mod hash;
 pub mod id;
+pub mod lazy;
+#[cfg(test)]
+pub mod mem;
 
 pub trait Exists {
     fn exists(&self, path: &Path) -> impl Future<Output = fsync::Result<bool>> + Send;
@@ -24,6 +32,33 @@ pub trait DirEntries {
     ) -> impl Stream<Item = fsync::Result<Metadata>> + Send;
 }
 
+/// A trait to check at once which children of a folder exist
+pub trait ExistingChildren: Exists + DirEntries + Sync {
+    /// The names among `names` of the children of the folder at `parent` that exist in the storage.
+    /// The default implementation filters a listing of the folder,
+    /// and finds none if the folder does not exist.
+    fn existing_children(
+        &self,
+        parent: &Path,
+        names: &[String],
+    ) -> impl Future<Output = fsync::Result<HashSet<String>>> + Send {
+        async move {
+            if !self.exists(parent).await? {
+                return Ok(HashSet::new());
+            }
+            let entries = self.dir_entries(parent, None);
+            futures::pin_mut!(entries);
+            let mut found = HashSet::new();
+            while let Some(metadata) = entries.try_next().await? {
+                if names.iter().any(|name| name == metadata.name()) {
+                    found.insert(metadata.name().to_owned());
+                }
+            }
+            Ok(found)
+        }
+    }
+}
+
 pub trait ReadFile {
     fn read_file(
         &self,
@@ -32,6 +67,41 @@ pub trait ReadFile {
     ) -> impl Future<Output = fsync::Result<impl io::AsyncRead + Send>> + Send;
 }
 
+/// A trait to read a part of a file
+pub trait ReadFileRange: ReadFile + Sync {
+    /// Reads at most `len` bytes of the file at `path`, starting at `offset`.
+    /// The default implementation reads the whole file and skips the bytes before `offset`.
+    fn read_file_range(
+        &self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+        progress: Option<&SharedProgress>,
+    ) -> impl Future<Output = fsync::Result<impl io::AsyncRead + Send>> + Send {
+        async move {
+            let read = self.read_file(path, progress).await?;
+            read_range(read, offset, len).await
+        }
+    }
+}
+
+/// Skip the first `offset` bytes of `read` and limit it to the next `len` bytes.
+/// The skipped bytes are discarded as they are read, without being buffered.
+pub async fn read_range<R>(
+    read: R,
+    offset: u64,
+    len: u64,
+) -> fsync::Result<impl io::AsyncRead + Send>
+where
+    R: io::AsyncRead + Send,
+{
+    let mut read = Box::pin(read);
+    if offset > 0 {
+        io::copy(&mut (&mut read).take(offset), &mut io::sink()).await?;
+    }
+    Ok(read.take(len))
+}
+
 pub trait MkDir {
     fn mkdir(
         &self,
@@ -81,6 +151,43 @@ pub trait MoveEntry {
     ) -> impl Future<Output = fsync::Result<Metadata>> + Send;
 }
 
+/// The metadata of a file to change without touching its content
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetadataPatch {
+    /// The new modification time
+    pub mtime: Option<DateTime<Utc>>,
+}
+
+impl MetadataPatch {
+    /// A patch of the modification time only
+    pub fn mtime(mtime: DateTime<Utc>) -> Self {
+        Self { mtime: Some(mtime) }
+    }
+
+    /// `metadata` with the fields of the patch taken from `patched`,
+    /// the metadata returned by the storage, and the other fields unchanged.
+    pub fn merge(&self, metadata: &Metadata, patched: &Metadata) -> Metadata {
+        let mut merged = metadata.clone();
+        if let (Some(_), Metadata::Regular { mtime, .. }, Some(patched)) =
+            (self.mtime, &mut merged, patched.mtime())
+        {
+            *mtime = patched;
+        }
+        merged
+    }
+}
+
+/// A trait to change the metadata of files without transferring their content
+pub trait PatchMetadata {
+    /// Applies `patch` to the file at `path`, leaving its content untouched,
+    /// and returns the metadata of the file as reported by the storage.
+    fn patch(
+        &self,
+        path: &Path,
+        patch: &MetadataPatch,
+    ) -> impl Future<Output = fsync::Result<Metadata>> + Send;
+}
+
 /// A trait to delete files or folders
 pub trait Delete {
     /// Deletes the file or folder pointed to by `path`.
@@ -92,16 +199,144 @@ pub trait Delete {
     ) -> impl Future<Output = fsync::Result<()>> + Send;
 }
 
+/// A trait to query the storage quota
+pub trait Quota {
+    /// Get the storage quota, or `None` if the storage has no quota.
+    fn quota(&self) -> impl Future<Output = fsync::Result<Option<fsync::stat::Quota>>> + Send {
+        future::ready(Ok(None))
+    }
+}
+
+/// A trait to send the requests that a storage queues to process them in batches
+pub trait Flush {
+    /// Send the queued requests, or do nothing if the storage does not queue requests.
+    /// A request that fails reports its error to the progress it was queued with.
+    fn flush(&self) -> impl Future<Output = fsync::Result<()>> + Send {
+        future::ready(Ok(()))
+    }
+}
+
+/// A trait to query how the entries are shared with other users
+pub trait Shared {
+    /// How the entry at `path` is shared, or `None` if it is not shared
+    /// or if the storage does not report it.
+    fn sharing(&self, path: &Path) -> Option<fsync::Sharing> {
+        let _ = path;
+        None
+    }
+
+    /// Whether the file at `path` can be viewed but not downloaded,
+    /// or `None` if it can or if the storage does not report it.
+    fn view_only(&self, path: &Path) -> Option<fsync::ViewOnly> {
+        let _ = path;
+        None
+    }
+
+    /// The links to the file at `path`.
+    /// The default implementation fails with [`fsync::Error::Unsupported`].
+    fn link(&self, path: &Path) -> impl Future<Output = fsync::Result<fsync::RemoteLink>> + Send {
+        let _ = path;
+        future::ready(Err(unsupported_links()))
+    }
+
+    /// Let anyone with the link read the file at `path`, and return its links.
+    /// The default implementation fails with [`fsync::Error::Unsupported`].
+    fn share_public(
+        &self,
+        path: &Path,
+    ) -> impl Future<Output = fsync::Result<fsync::RemoteLink>> + Send {
+        let _ = path;
+        future::ready(Err(unsupported_links()))
+    }
+}
+
+pub fn unsupported_links() -> fsync::Error {
+    fsync::Error::Unsupported("the links to the files".to_string())
+}
+
+/// A trait to list a folder again from the storage, bypassing any cache
+pub trait Relist: DirEntries + Sync {
+    /// The entries of the folder at `path`, as currently in the storage.
+    /// A storage that caches its entries updates the cache with the listing.
+    /// The default implementation collects [`DirEntries::dir_entries`].
+    fn relist(&self, path: &Path) -> impl Future<Output = fsync::Result<Vec<Metadata>>> + Send {
+        self.dir_entries(path, None).try_collect()
+    }
+
+    /// Whether the entries cached by the storage are still the ones of the storage,
+    /// as far as it can tell. The default implementation, for the storages without cache,
+    /// returns `true`.
+    fn is_current(&self) -> impl Future<Output = fsync::Result<bool>> + Send {
+        future::ready(Ok(true))
+    }
+}
+
+/// A trait to query the past versions of the files kept by the storage
+pub trait Revisions {
+    /// The revisions of the file at `path`, oldest first.
+    /// The default implementation fails with [`fsync::Error::Unsupported`].
+    fn revisions(
+        &self,
+        path: &Path,
+    ) -> impl Future<Output = fsync::Result<Vec<fsync::Revision>>> + Send {
+        let _ = path;
+        future::ready(Err(unsupported_revisions()))
+    }
+
+    /// Read the content of the revision `revision_id` of the file at `path`.
+    /// The default implementation fails with [`fsync::Error::Unsupported`].
+    fn read_revision(
+        &self,
+        path: &Path,
+        revision_id: &str,
+        progress: Option<&SharedProgress>,
+    ) -> impl Future<Output = fsync::Result<impl io::AsyncRead + Send>> + Send {
+        let _ = (path, revision_id, progress);
+        future::ready(Err::<io::Empty, _>(unsupported_revisions()))
+    }
+}
+
+pub fn unsupported_revisions() -> fsync::Error {
+    fsync::Error::Unsupported("the revisions of the files".to_string())
+}
+
+/// A trait to query the checksums of the files computed by the storage
+pub trait Checksums {
+    /// The MD5 of the content of the file at `path`, in lowercase hexadecimal,
+    /// if the storage already reported it. Nothing is requested from the storage.
+    fn known_md5(&self, path: &Path) -> Option<String> {
+        let _ = path;
+        None
+    }
+
+    /// Request the MD5 of the content of the file at `path` from the storage,
+    /// in lowercase hexadecimal, or `None` if the storage has no checksum for it.
+    fn fetch_md5(&self, path: &Path) -> impl Future<Output = fsync::Result<Option<String>>> + Send {
+        let _ = path;
+        future::ready(Ok(None))
+    }
+}
+
 /// A trait for path-based storage
 pub trait Storage:
     Clone
+    + Exists
     + DirEntries
+    + ExistingChildren
     + ReadFile
+    + ReadFileRange
     + MkDir
     + CreateFile
     + WriteFile
     + CopyFile
     + Delete
+    + Quota
+    + Flush
+    + Shared
+    + Relist
+    + Revisions
+    + Checksums
+    + PatchMetadata
     + Shutdown
     + Send
     + Sync
@@ -110,4 +345,4 @@ pub trait Storage:
 }
 
 /// A trait for local storage
-pub trait LocalStorage : Storage + Exists + MoveEntry {}
+pub trait LocalStorage: Storage + MoveEntry {}
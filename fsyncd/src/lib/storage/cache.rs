@@ -1,8 +1,12 @@
-use std::{mem, sync::Arc};
+//! A path-based storage over an ID-based one.
+//!
+//! [`CacheStorage`] maps the paths to the ids of any [`id::Storage`], persists the mapping
+//! (see the [`disk`] module) and refreshes it from the listings of the storage.
+//! What differs between the providers is given by their implementation of [`Provider`].
+
+use std::{collections::HashSet, future::Future, sync::Arc};
 
 use anyhow::Context;
-use async_stream::try_stream;
-use bincode::Options;
 use dashmap::DashMap;
 use fsync::{
     path::{Component, FsPath, FsPathBuf, Path, PathBuf},
@@ -13,8 +17,45 @@ use serde::{Deserialize, Serialize};
 use tokio::{io, task::JoinSet};
 use tokio_stream::StreamExt;
 
-use super::id::{self, IdBuf};
-use crate::{PersistCache, SharedProgress};
+use super::id::{self, Id, IdBuf};
+use crate::{
+    persist,
+    profile::{Phase, Span},
+    PersistCache, SharedProgress,
+};
+
+mod disk;
+
+use disk::LoadError;
+
+/// The provider specific behavior of the cache.
+/// The defaults suit a provider without change feed, that lists each name once in a folder.
+pub trait Provider {
+    /// How the entries listed with the name of a previous entry of their folder are cached
+    const DUPLICATE_NAMES: DuplicateNames = DuplicateNames::KeepFirst;
+
+    /// Whether `id` can be an id of this provider.
+    /// A persisted cache with an invalid id is set aside as corrupt.
+    fn valid_id(id: &Id) -> bool {
+        !id.as_str().is_empty()
+    }
+
+    /// An opaque token of the state of the storage, that changes whenever an entry changes,
+    /// or `None` if the provider does not report it.
+    /// A persisted cache populated with another token is populated again.
+    fn change_token(&self) -> impl Future<Output = fsync::Result<Option<String>>> + Send {
+        async { Ok(None) }
+    }
+}
+
+/// The policy of a [`Provider`] for the names listed more than once in a folder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateNames {
+    /// The first listed entry is cached, the next ones are skipped
+    KeepFirst,
+    /// The listing fails, as the storage should not have duplicates
+    Fail,
+}
 
 #[derive(Clone, Debug)]
 pub enum CachePersist {
@@ -47,8 +88,14 @@ impl CachePersist {
 #[derive(Debug, Clone)]
 pub struct CacheStorage<S> {
     entries: Arc<DashMap<PathBuf, CacheNode>>,
+    /// The sharing of the shared entries, by id.
+    /// It is persisted in a file of its own, next to the entries.
+    sharing: Arc<DashMap<IdBuf, fsync::Sharing>>,
     storage: Arc<S>,
     persist: CachePersist,
+    corrupt: Option<fsync::CorruptFile>,
+    /// The change token of the storage when the cache was populated
+    token: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -62,26 +109,155 @@ impl<S> CacheStorage<S>
 where
     S: id::Storage,
 {
+    /// Load the cache according to `persist`, or populate it from `storage`.
+    /// A cache file that can't be read back is set aside before populating the cache.
+    /// A cache populated before a change of the storage reported by [`Provider::change_token`]
+    /// is populated again.
     pub async fn new(storage: S, persist: CachePersist) -> anyhow::Result<Self> {
+        let _span = Span::enter(Phase::CacheLoad);
         let storage = Arc::new(storage);
-        let entries = if let Some(path) = persist.try_load_path() {
-            match load_from_disk(path).await {
-                Ok(entries) => entries,
-                Err(LoadError::Io(_)) => populate_from_storage(storage.clone()).await?,
-                Err(LoadError::Bincode(err)) => Err(err)?,
+        let mut corrupt = None;
+        let mut token = storage.change_token().await.unwrap_or_else(|err| {
+            log::warn!("could not get the change token of the storage: {err}");
+            None
+        });
+        let loaded = if let Some(path) = persist.try_load_path() {
+            match disk::load_from_disk(path, S::valid_id).await {
+                Ok(entries) => Some((entries, disk::load_sharing(path).await)),
+                Err(LoadError::Io(err)) if err.kind() != io::ErrorKind::InvalidData => {
+                    log::warn!("could not read cache from {path}: {err}");
+                    None
+                }
+                Err(err @ LoadError::Format(..)) => {
+                    log::info!("the cache {path} was written by another version of fsyncd ({err}), populating it again");
+                    None
+                }
+                Err(err) => {
+                    corrupt = match persist::quarantine(path, "remote cache", &err) {
+                        Ok(corrupt) => Some(corrupt),
+                        Err(err) => {
+                            log::error!("could not set aside the corrupt cache {path}: {err}");
+                            None
+                        }
+                    };
+                    None
+                }
             }
         } else {
-            populate_from_storage(storage.clone()).await?
+            None
+        };
+        let loaded = match (loaded, &token, persist.try_load_path()) {
+            (Some(loaded), Some(token), Some(path)) => {
+                if disk::load_token(path).await.as_ref() == Some(token) {
+                    Some(loaded)
+                } else {
+                    log::info!("the storage changed since {path} was populated");
+                    None
+                }
+            }
+            // the storage can't tell whether it changed, the persisted token is kept
+            (Some(loaded), None, Some(path)) => {
+                token = disk::load_token(path).await;
+                Some(loaded)
+            }
+            (loaded, ..) => loaded,
+        };
+        let (entries, sharing) = match loaded {
+            Some(loaded) => loaded,
+            None => {
+                let entries = populate_from_storage(storage.clone()).await?;
+                let sharing = sharing_from_storage(&entries, &*storage);
+                // the cache is refreshed over a previous one, which tells what sharing changed
+                if let Some(path) = persist.try_save_path().filter(|path| path.exists()) {
+                    let previous = disk::load_sharing(path).await;
+                    log_sharing_changes(&entries, &previous, &sharing);
+                }
+                (entries, Arc::new(sharing))
+            }
         };
         Ok(Self {
             entries,
+            sharing,
             storage,
             persist,
+            corrupt,
+            token,
         })
     }
 }
 
 impl<S> CacheStorage<S> {
+    /// The cache file that was set aside at load, if it could not be read
+    pub fn corrupt_file(&self) -> Option<&fsync::CorruptFile> {
+        self.corrupt.as_ref()
+    }
+
+    /// The storage wrapped by this cache
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Remove `path` and its descendants from the cache
+    fn forget(&self, path: &Path) {
+        if let Some(parent) = path.parent() {
+            if let Some(mut parent) = self.entries.get_mut(parent) {
+                let name = path.file_name().expect("Non-root path should have a name");
+                parent.children.retain(|c| c != name);
+            }
+        }
+        let mut stack = vec![path.to_path_buf()];
+        while let Some(path) = stack.pop() {
+            if let Some((_, node)) = self.entries.remove(&path) {
+                if let Some(id) = &node.id {
+                    self.sharing.remove(id);
+                }
+                stack.extend(node.children.iter().map(|c| path.join(c)));
+            }
+        }
+    }
+
+    /// Update the sharing of the entry with `id` from the storage
+    fn update_sharing(&self, id: &id::Id)
+    where
+        S: id::Shared,
+    {
+        match self.storage.sharing(id) {
+            Some(sharing) => self.sharing.insert(id.to_id_buf(), sharing),
+            None => self.sharing.remove(id).map(|(_, sharing)| sharing),
+        };
+    }
+
+    /// The metadata of the children of `path`, in the order of the cache
+    fn children_metadata(&self, path: &Path) -> Vec<fsync::Metadata> {
+        let Some(parent) = self.entries.get(path) else {
+            return Vec::new();
+        };
+        parent
+            .children
+            .iter()
+            .filter_map(|c| {
+                let child = self.entries.get(&parent.metadata.path().join(c));
+                if child.is_none() {
+                    log::error!("{path}/{c} is listed in its parent but not in the cache");
+                }
+                child.map(|child| child.metadata.clone())
+            })
+            .collect()
+    }
+
+    /// The id of the file at `path`, which must be in the cache.
+    /// The guards of the cache entries are never held while awaiting the storage,
+    /// as another task blocking on them would hold its worker thread.
+    fn file_id(&self, path: &Path) -> fsync::Result<IdBuf> {
+        let Some(node) = self.entries.get(path) else {
+            fsync::other_bail!("No such entry in the cache: {path}");
+        };
+        if !node.metadata.is_file() {
+            fsync::io_bail!("{path} is not a file.");
+        }
+        Ok(node.id.clone().expect("File without Id"))
+    }
+
     fn check_path(path: &Path) -> fsync::Result<PathBuf> {
         debug_assert!(path.is_absolute());
         let path = path.normalize()?;
@@ -108,63 +284,94 @@ where
     Ok(entries)
 }
 
-enum LoadError {
-    Io(io::Error),
-    Bincode(bincode::Error),
-}
-
-impl From<io::Error> for LoadError {
-    fn from(value: io::Error) -> Self {
-        LoadError::Io(value)
+/// Whether the entry with `id` and `metadata` is cached,
+/// given the `names` listed before it in its folder, which it is added to
+fn is_listed_once<S>(
+    names: &mut HashSet<String>,
+    id: &Id,
+    metadata: &Metadata,
+) -> fsync::Result<bool>
+where
+    S: Provider,
+{
+    if names.insert(metadata.name().to_owned()) {
+        return Ok(true);
     }
-}
-
-impl From<bincode::Error> for LoadError {
-    fn from(value: bincode::Error) -> Self {
-        LoadError::Bincode(value)
+    let path = metadata.path();
+    match S::DUPLICATE_NAMES {
+        DuplicateNames::KeepFirst => {
+            log::warn!("{path} is listed more than once, skipping the entry {id}");
+            Ok(false)
+        }
+        DuplicateNames::Fail => fsync::other_bail!("{path} is listed more than once"),
     }
 }
 
-async fn load_from_disk(path: &FsPath) -> Result<Arc<DashMap<PathBuf, CacheNode>>, LoadError> {
-    log::trace!("loading cached entries from {path}");
-
-    let path2 = path.to_owned();
-
-    let handle = tokio::task::spawn_blocking(move || {
-        use std::{fs, io::BufReader};
-
-        let reader = fs::File::open(path2)?;
-        let reader = BufReader::new(reader);
-        let opts = bincode_options();
-        let entries: DashMap<PathBuf, CacheNode> = opts.deserialize_from(reader)?;
-        Ok::<_, LoadError>(entries)
-    });
-
-    let entries = handle.await.unwrap()?;
-    log::info!("loaded {} entries from {path}", entries.len());
-
-    Ok(Arc::new(entries))
+/// The sharing of the cached `entries`, as reported by `storage`
+fn sharing_from_storage<S>(
+    entries: &DashMap<PathBuf, CacheNode>,
+    storage: &S,
+) -> DashMap<IdBuf, fsync::Sharing>
+where
+    S: id::Shared,
+{
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let id = entry.id.clone()?;
+            let sharing = storage.sharing(&id)?;
+            Some((id, sharing))
+        })
+        .collect()
 }
 
-async fn save_to_disc(
-    path: &FsPath,
-    entries: Arc<DashMap<PathBuf, CacheNode>>,
-) -> anyhow::Result<()> {
-    use std::{fs, io::BufWriter};
-
-    log::info!("saving {} entries to {path}", entries.len());
-
-    let path = path.to_owned();
-
-    let handle = tokio::task::spawn_blocking(move || {
-        let writer = fs::File::create(&path)?;
-        let writer = BufWriter::new(writer);
-        let opts = bincode_options();
-        opts.serialize_into(writer, &*entries)?;
-        Ok::<_, anyhow::Error>(())
-    });
+/// Log the `entries` whose sharing differs from `previous`.
+/// An entry that became shared publicly is logged as a warning.
+fn log_sharing_changes(
+    entries: &DashMap<PathBuf, CacheNode>,
+    previous: &DashMap<IdBuf, fsync::Sharing>,
+    sharing: &DashMap<IdBuf, fsync::Sharing>,
+) {
+    for entry in entries.iter() {
+        let path = entry.key();
+        let Some(id) = &entry.id else {
+            continue;
+        };
+        let sharing = sharing.get(id).map(|s| *s);
+        let prev_sharing = previous.get(id).map(|s| *s);
+        if sharing == prev_sharing {
+            continue;
+        }
+        let was_public = prev_sharing.is_some_and(|s| s.is_public());
+        let now = sharing.unwrap_or_default();
+        if now.is_public() && !was_public {
+            log::warn!(target: "sharing", path = path.as_str(); "{path} is now {now}");
+        } else {
+            log::info!(target: "sharing", path = path.as_str(); "{path} is now {now}");
+        }
+    }
+}
 
-    handle.await.unwrap()
+impl<S> super::Exists for CacheStorage<S>
+where
+    S: super::id::Exists + Sync + Send,
+{
+    async fn exists(&self, path: &Path) -> fsync::Result<bool> {
+        let path = Self::check_path(path)?;
+        if path.is_root() {
+            return Ok(true);
+        }
+        let id = match self.entries.get(&path) {
+            Some(node) => node.id.clone().expect("Non-root entry should have Id"),
+            None => return Ok(false),
+        };
+        let exists = self.storage.exists(&id).await?;
+        if !exists {
+            log::info!("{path} is no longer in the storage, removing it from the cache");
+            self.forget(&path);
+        }
+        Ok(exists)
+    }
 }
 
 impl<S> super::DirEntries for CacheStorage<S>
@@ -177,16 +384,55 @@ where
         _progress: Option<&SharedProgress>,
     ) -> impl Stream<Item = fsync::Result<fsync::Metadata>> + Send {
         log::trace!("listing entries for {parent_path}");
-        let parent = self.entries.get(parent_path);
-        try_stream! {
-            if let Some(parent) = parent {
-                for c in parent.children.iter() {
-                    let c_key = parent.metadata.path().join(c);
-                    let c_ent = self.entries.get(&c_key).unwrap();
-                    yield c_ent.metadata.clone();
-                }
+        // collected upfront, as the consumer may await between the entries
+        let children = self.children_metadata(parent_path);
+        futures::stream::iter(children.into_iter().map(Ok))
+    }
+}
+
+/// The children are checked against a fresh listing of the folder in the storage,
+/// rather than one request per child. The cached children that are no longer listed are forgotten.
+impl<S> super::ExistingChildren for CacheStorage<S>
+where
+    S: super::id::Exists + super::id::DirEntries + super::id::Shared + Send + Sync + 'static,
+{
+    async fn existing_children(
+        &self,
+        parent: &Path,
+        names: &[String],
+    ) -> fsync::Result<HashSet<String>> {
+        let parent = Self::check_path(parent)?;
+        let id = match self.entries.get(&parent) {
+            Some(node) if node.metadata.is_dir() => node.id.clone(),
+            _ => return Ok(HashSet::new()),
+        };
+        log::trace!(
+            "listing entries of {parent} to check {} children",
+            names.len()
+        );
+        let mut listed = HashSet::new();
+        {
+            let entries = self.storage.dir_entries(id.as_deref(), &parent, None);
+            tokio::pin!(entries);
+            while let Some(entry) = entries.next().await {
+                let (id, metadata) = entry?;
+                self.update_sharing(&id);
+                listed.insert(metadata.name().to_owned());
             }
         }
+        let mut found = HashSet::new();
+        for name in names {
+            if listed.contains(name) {
+                found.insert(name.clone());
+                continue;
+            }
+            let path = parent.join(name);
+            if self.entries.contains_key(&path) {
+                log::info!("{path} is no longer in the storage, removing it from the cache");
+                self.forget(&path);
+            }
+        }
+        Ok(found)
     }
 }
 
@@ -200,20 +446,49 @@ where
         progress: Option<&SharedProgress>,
     ) -> fsync::Result<impl io::AsyncRead> {
         log::info!("read file {path}");
-        let node = self.entries.get(&path);
-        if let Some(node) = node {
-            if !node.metadata.is_file() {
-                fsync::io_bail!("{path} is not a file.");
-            }
-            let id = node.id.clone();
-            let res = self
-                .storage
-                .read_file(id.expect("File without Id"), progress)
-                .await?;
-            Ok(res)
-        } else {
-            fsync::other_bail!("No such entry in the cache: {path}");
-        }
+        let id = self.file_id(&path)?;
+        let res = self
+            .storage
+            .read_file(id, progress)
+            .await
+            .map_err(|err| not_downloadable_at(err, &path))?;
+        Ok(res)
+    }
+}
+
+impl<S> super::ReadFileRange for CacheStorage<S>
+where
+    S: super::id::ReadFileRange + Sync + Send,
+{
+    async fn read_file_range(
+        &self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead> {
+        log::info!(
+            "read file {path} from {offset} to {}",
+            offset.saturating_add(len)
+        );
+        let id = self.file_id(&path)?;
+        let res = self
+            .storage
+            .read_file_range(id, offset, len, progress)
+            .await
+            .map_err(|err| not_downloadable_at(err, &path))?;
+        Ok(res)
+    }
+}
+
+/// The storage reports the files that can't be downloaded by id, they are reported by `path`
+fn not_downloadable_at(err: fsync::Error, path: &Path) -> fsync::Error {
+    match err {
+        fsync::Error::NotDownloadable { web_view_link, .. } => fsync::Error::NotDownloadable {
+            path: path.to_owned(),
+            web_view_link,
+        },
+        err => err,
     }
 }
 
@@ -271,19 +546,19 @@ where
             }
         } else {
             let parent = path.parent().unwrap();
-            let id = {
-                let mut entry = self
-                    .entries
-                    .get_mut(parent)
-                    .with_context(|| format!("no such entry: {parent}"))?;
-                let parent_id = entry.id.clone();
-                let id = self
-                    .storage
-                    .mkdir(parent_id.as_deref(), path.file_name().unwrap(), progress)
-                    .await?;
+            let parent_id = self
+                .entries
+                .get(parent)
+                .with_context(|| format!("no such entry: {parent}"))?
+                .id
+                .clone();
+            let id = self
+                .storage
+                .mkdir(parent_id.as_deref(), path.file_name().unwrap(), progress)
+                .await?;
+            if let Some(mut entry) = self.entries.get_mut(parent) {
                 entry.children.push(path.file_name().unwrap().to_string());
-                id
-            };
+            }
             let metadata = Metadata::Directory {
                 path: path.clone(),
                 stat: None,
@@ -303,7 +578,7 @@ where
 
 impl<S> super::CreateFile for CacheStorage<S>
 where
-    S: super::id::CreateFile + Send + Sync,
+    S: super::id::CreateFile + super::id::Shared + Send + Sync,
 {
     async fn create_file(
         &self,
@@ -315,18 +590,26 @@ where
 
         debug_assert!(metadata.path().is_absolute() && !metadata.path().is_root());
         let parent = metadata.path().parent().unwrap();
-        let parent = self.entries.get(parent).with_context(|| {
-            format!(
-                "Attempt to create file {} in non-existing parent!",
-                metadata.path()
-            )
-        })?;
+        let parent_id = self
+            .entries
+            .get(parent)
+            .with_context(|| {
+                format!(
+                    "Attempt to create file {} in non-existing parent!",
+                    metadata.path()
+                )
+            })?
+            .id
+            .clone();
         let (id, metadata) = self
             .storage
-            .create_file(parent.id.as_deref(), metadata, data, progress)
+            .create_file(parent_id.as_deref(), metadata, data, progress)
             .await?;
-        mem::drop(parent);
+        if let Some(mut parent) = self.entries.get_mut(metadata.path().parent().unwrap()) {
+            parent.children.push(metadata.name().to_string());
+        }
 
+        self.update_sharing(&id);
         let node = CacheNode {
             id: Some(id),
             metadata: metadata.clone(),
@@ -339,7 +622,7 @@ where
 
 impl<S> super::WriteFile for CacheStorage<S>
 where
-    S: super::id::WriteFile + Send + Sync,
+    S: super::id::WriteFile + super::id::Shared + Send + Sync,
 {
     async fn write_file(
         &self,
@@ -357,21 +640,43 @@ where
                 .expect("Parent node should be defined");
             parent.id.clone()
         };
-        let mut node = self.entries.get_mut(&path).expect("Path should be present");
-        let metadata = {
-            let id = node
-                .id
-                .as_deref()
-                .expect("Id should be set for non-root path");
-            self.storage
-                .write_file(id, parent_id.as_deref(), metadata, data, progress)
-                .await?
-        };
-        node.metadata = metadata.clone();
+        let id = self
+            .entries
+            .get(&path)
+            .expect("Path should be present")
+            .id
+            .clone()
+            .expect("Id should be set for non-root path");
+        let metadata = self
+            .storage
+            .write_file(&id, parent_id.as_deref(), metadata, data, progress)
+            .await?;
+        self.update_sharing(&id);
+        if let Some(mut node) = self.entries.get_mut(&path) {
+            node.metadata = metadata.clone();
+        }
         Ok(metadata)
     }
 }
 
+impl<S> super::PatchMetadata for CacheStorage<S>
+where
+    S: super::id::PatchMetadata + Send + Sync,
+{
+    async fn patch(&self, path: &Path, patch: &super::MetadataPatch) -> fsync::Result<Metadata> {
+        log::info!("patching the metadata of {path}");
+        let path = Self::check_path(path)?;
+        let id = self.file_id(&path)?;
+        let patched = self.storage.patch(&id, &path, patch).await?;
+        // the fields left out of the patch are kept from the cache
+        let Some(mut node) = self.entries.get_mut(&path) else {
+            return Ok(patched);
+        };
+        node.metadata = patch.merge(&node.metadata, &patched);
+        Ok(node.metadata.clone())
+    }
+}
+
 impl<S> super::CopyFile for CacheStorage<S>
 where
     S: super::id::Storage,
@@ -409,11 +714,15 @@ where
                 .storage
                 .copy_file(&src_id, dest_parent_id.as_deref(), &dest, progress)
                 .await?;
+            self.update_sharing(&id);
             let node = CacheNode {
                 id: Some(id),
                 metadata: metadata.clone(),
                 children: Vec::new(),
             };
+            if let Some(mut parent) = self.entries.get_mut(dest.parent().unwrap()) {
+                parent.children.push(metadata.name().to_string());
+            }
             self.entries.insert(dest, node);
             metadata
         };
@@ -442,18 +751,39 @@ where
             node.id.clone().expect("Non-root entry should have Id")
         };
         self.storage.delete(&id, progress).await?;
-        self.entries.remove(&path);
+        self.forget(&path);
         Ok(())
     }
 }
 
+impl<S> super::Quota for CacheStorage<S>
+where
+    S: super::id::Storage,
+{
+    async fn quota(&self) -> fsync::Result<Option<fsync::stat::Quota>> {
+        self.storage.quota().await
+    }
+}
+
+impl<S> super::Flush for CacheStorage<S>
+where
+    S: super::id::Storage,
+{
+    async fn flush(&self) -> fsync::Result<()> {
+        self.storage.flush().await
+    }
+}
+
 impl<S> crate::PersistCache for CacheStorage<S>
 where
     S: super::id::Storage,
 {
     async fn persist_cache(&self) -> anyhow::Result<()> {
         if let Some(path) = self.persist.try_save_path() {
-            save_to_disc(path, self.entries.clone()).await?;
+            let _span = Span::enter(Phase::CacheSave).with_detail(|| path.to_string());
+            disk::save_to_disc(path, self.entries.clone()).await?;
+            disk::save_sharing(path, self.sharing.clone()).await?;
+            disk::save_token(path, self.token.clone()).await?;
         }
         Ok(())
     }
@@ -471,14 +801,159 @@ where
     }
 }
 
-impl<S> super::Storage for CacheStorage<S> where S: super::id::Storage {}
+impl<S> super::Shared for CacheStorage<S>
+where
+    S: super::id::Shared + Sync + Send,
+{
+    fn sharing(&self, path: &Path) -> Option<fsync::Sharing> {
+        let id = self.entries.get(path)?.id.clone()?;
+        self.sharing.get(&id).map(|sharing| *sharing)
+    }
+
+    fn view_only(&self, path: &Path) -> Option<fsync::ViewOnly> {
+        let id = self.entries.get(path)?.id.clone()?;
+        self.storage.view_only(&id)
+    }
+
+    async fn link(&self, path: &Path) -> fsync::Result<fsync::RemoteLink> {
+        let id = self.file_id(path)?;
+        self.storage.link(&id).await
+    }
+
+    async fn share_public(&self, path: &Path) -> fsync::Result<fsync::RemoteLink> {
+        log::info!("share {path} with anyone with the link");
+        let id = self.file_id(path)?;
+        let link = self.storage.share_public(&id).await?;
+        self.update_sharing(&id);
+        Ok(link)
+    }
+}
+
+impl<S> super::Revisions for CacheStorage<S>
+where
+    S: super::id::Revisions + Sync + Send,
+{
+    async fn revisions(&self, path: &Path) -> fsync::Result<Vec<fsync::Revision>> {
+        let id = self.file_id(path)?;
+        self.storage.revisions(&id).await
+    }
+
+    async fn read_revision(
+        &self,
+        path: &Path,
+        revision_id: &str,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead + Send> {
+        log::info!("read revision {revision_id} of {path}");
+        let id = self.file_id(path)?;
+        self.storage.read_revision(id, revision_id, progress).await
+    }
+}
+
+impl<S> super::Checksums for CacheStorage<S>
+where
+    S: super::id::Checksums + Sync + Send,
+{
+    fn known_md5(&self, path: &Path) -> Option<String> {
+        let id = self.entries.get(path)?.id.clone()?;
+        self.storage.known_md5(&id)
+    }
+
+    async fn fetch_md5(&self, path: &Path) -> fsync::Result<Option<String>> {
+        let id = self.file_id(path)?;
+        self.storage.fetch_md5(&id).await
+    }
+}
+
+/// The folder is listed from the storage and the cache is updated with the listing:
+/// the entries that are new or changed are cached, and the ones no longer listed are forgotten.
+/// The cached sub-tree of a folder that is still listed is kept.
+impl<S> super::Relist for CacheStorage<S>
+where
+    S: super::id::DirEntries + super::id::Shared + Provider + Send + Sync + 'static,
+{
+    async fn relist(&self, path: &Path) -> fsync::Result<Vec<Metadata>> {
+        let path = Self::check_path(path)?;
+        let id = match self.entries.get(&path) {
+            Some(node) if node.metadata.is_dir() => node.id.clone(),
+            Some(_) => fsync::io_bail!("{path} is not a folder"),
+            None => fsync::other_bail!("No such entry in the cache: {path}"),
+        };
+        log::trace!("listing again entries of {path}");
+        let mut listed = Vec::new();
+        {
+            let entries = self.storage.dir_entries(id.as_deref(), &path, None);
+            tokio::pin!(entries);
+            let mut names = HashSet::new();
+            while let Some(entry) = entries.next().await {
+                let (id, md) = entry?;
+                if is_listed_once::<S>(&mut names, &id, &md)? {
+                    listed.push((id, md));
+                }
+            }
+        }
+
+        let names: HashSet<&str> = listed.iter().map(|(_, md)| md.name()).collect();
+        let previous = self
+            .entries
+            .get(&path)
+            .map(|node| node.children.clone())
+            .unwrap_or_default();
+        for name in previous.iter().filter(|c| !names.contains(c.as_str())) {
+            let child = path.join(name);
+            log::info!("{child} is no longer in the storage, removing it from the cache");
+            self.forget(&child);
+        }
+
+        let mut children = Vec::with_capacity(listed.len());
+        let mut metadata = Vec::with_capacity(listed.len());
+        for (id, md) in listed {
+            self.update_sharing(&id);
+            let child = md.path().to_owned();
+            // a folder that keeps its id keeps its cached sub-tree
+            let kept = self.entries.get(&child).map(|node| {
+                let same = node.id.as_ref() == Some(&id) && node.metadata.is_dir() && md.is_dir();
+                same.then(|| node.children.clone())
+            });
+            let grand_children = match kept {
+                Some(Some(children)) => children,
+                Some(None) => {
+                    self.forget(&child);
+                    Vec::new()
+                }
+                None => Vec::new(),
+            };
+            children.push(md.name().to_owned());
+            self.entries.insert(
+                child,
+                CacheNode {
+                    id: Some(id),
+                    metadata: md.clone(),
+                    children: grand_children,
+                },
+            );
+            metadata.push(md);
+        }
+        children.sort_unstable();
+        if let Some(mut node) = self.entries.get_mut(&path) {
+            node.children = children;
+        }
+        Ok(metadata)
+    }
 
-fn bincode_options() -> impl bincode::Options {
-    bincode::DefaultOptions::new()
-        .with_fixint_encoding()
-        .allow_trailing_bytes()
+    /// Compare the change token of the storage to the one of the population of the cache.
+    /// The cache is deemed current if the storage reports no token.
+    async fn is_current(&self) -> fsync::Result<bool> {
+        let token = self.storage.change_token().await?;
+        Ok(match (&self.token, token) {
+            (Some(cached), Some(token)) => *cached == token,
+            _ => true,
+        })
+    }
 }
 
+impl<S> super::Storage for CacheStorage<S> where S: super::id::Storage {}
+
 fn populate_recurse<'a, S>(
     dir_id: Option<IdBuf>,
     dir_path: PathBuf,
@@ -486,17 +961,21 @@ fn populate_recurse<'a, S>(
     storage: Arc<S>,
 ) -> BoxFuture<'a, anyhow::Result<Vec<String>>>
 where
-    S: super::id::DirEntries + Send + Sync + 'static,
+    S: super::id::DirEntries + Provider + Send + Sync + 'static,
 {
     Box::pin(async move {
         let dirent = storage.dir_entries(dir_id.as_deref(), &dir_path, None);
         tokio::pin!(dirent);
 
         let mut children: Vec<String> = Vec::new();
+        let mut names = HashSet::new();
         let mut set = JoinSet::new();
 
         while let Some(ent) = dirent.next().await {
             let (id, metadata) = ent?;
+            if !is_listed_once::<S>(&mut names, &id, &metadata)? {
+                continue;
+            }
 
             children.push(
                 metadata
@@ -538,3 +1017,238 @@ where
         Ok(children)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use futures::TryStreamExt;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::storage::{
+        mem::id::MemIdStorage, CreateFile, DirEntries, MetadataPatch, PatchMetadata, Relist,
+        Storage,
+    };
+
+    fn mtime(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    fn temp_dir(name: &str) -> FsPathBuf {
+        let dir = std::env::temp_dir().join(format!("fsyncd-cache-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        FsPathBuf::try_from(dir).unwrap()
+    }
+
+    async fn names<S: Storage>(storage: &S, path: &str) -> Vec<String> {
+        let entries: Vec<_> = storage
+            .dir_entries(Path::new(path), None)
+            .try_collect()
+            .await
+            .unwrap();
+        entries.iter().map(|md| md.name().to_owned()).collect()
+    }
+
+    async fn read<S: Storage>(storage: &S, path: &str) -> String {
+        let mut data = String::new();
+        let file = storage.read_file(PathBuf::from(path), None).await.unwrap();
+        Box::pin(file).read_to_string(&mut data).await.unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn paths_are_mapped_to_ids() {
+        let mem = MemIdStorage::new();
+        let dir = mem.put_dir(None, "dir");
+        let a = mem.put_file(Some(&dir), "a.txt", b"a", mtime(1));
+        mem.put_file(None, "b.txt", b"b", mtime(2));
+
+        let cache = CacheStorage::new(mem.clone(), CachePersist::Memory)
+            .await
+            .unwrap();
+        assert_eq!(names(&cache, "/").await, ["b.txt", "dir"]);
+        assert_eq!(names(&cache, "/dir").await, ["a.txt"]);
+        assert_eq!(read(&cache, "/dir/a.txt").await, "a");
+
+        let metadata = Metadata::Regular {
+            path: PathBuf::from("/dir/c.txt"),
+            size: 1,
+            mtime: mtime(3),
+            link_target: None,
+        };
+        cache.create_file(&metadata, &b"c"[..], None).await.unwrap();
+        assert_eq!(read(&cache, "/dir/c.txt").await, "c");
+
+        // changed outside of the cache
+        mem.put_file(Some(&dir), "d.txt", b"d", mtime(4));
+        mem.remove(&a);
+        cache.relist(Path::new("/dir")).await.unwrap();
+        assert_eq!(names(&cache, "/dir").await, ["c.txt", "d.txt"]);
+    }
+
+    #[tokio::test]
+    async fn duplicate_names_keep_the_first() {
+        let mem = MemIdStorage::new();
+        mem.put_file(None, "dup.txt", b"first", mtime(1));
+        mem.put_file(None, "dup.txt", b"second", mtime(2));
+
+        let cache = CacheStorage::new(mem.clone(), CachePersist::Memory)
+            .await
+            .unwrap();
+        assert_eq!(names(&cache, "/").await, ["dup.txt"]);
+        assert_eq!(read(&cache, "/dup.txt").await, "first");
+
+        let listed = cache.relist(Path::root()).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(read(&cache, "/dup.txt").await, "first");
+    }
+
+    #[tokio::test]
+    async fn patched_metadata_is_merged_in_the_cache() {
+        let mem = MemIdStorage::new();
+        mem.put_file(None, "a.txt", b"abc", mtime(1));
+
+        let cache = CacheStorage::new(mem.clone(), CachePersist::Memory)
+            .await
+            .unwrap();
+        let patched = cache
+            .patch(Path::new("/a.txt"), &MetadataPatch::mtime(mtime(2)))
+            .await
+            .unwrap();
+        assert_eq!(patched.mtime(), Some(mtime(2)));
+        assert_eq!(patched.size(), Some(3));
+
+        let entries: Vec<_> = cache
+            .dir_entries(Path::root(), None)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(entries, std::slice::from_ref(&patched));
+        assert_eq!(read(&cache, "/a.txt").await, "abc");
+        // the storage has the same metadata
+        assert_eq!(cache.relist(Path::root()).await.unwrap(), [patched]);
+    }
+
+    #[tokio::test]
+    async fn persisted_cache_is_loaded_until_the_storage_changes() {
+        let dir = temp_dir("token");
+        let persist = |ignore_initial_cache| CachePersist::MemoryAndDisk {
+            path: dir.join("remote.cache"),
+            ignore_initial_cache,
+        };
+        let mem = MemIdStorage::new();
+        let sub = mem.put_dir(None, "dir");
+        mem.put_file(Some(&sub), "a.txt", b"a", mtime(1));
+
+        // without change token, the persisted cache is loaded as is
+        let cache = CacheStorage::new(mem.clone(), persist(true)).await.unwrap();
+        cache.persist_cache().await.unwrap();
+        let listings = mem.listings();
+        mem.put_file(None, "b.txt", b"b", mtime(2));
+        let cache = CacheStorage::new(mem.clone(), persist(false))
+            .await
+            .unwrap();
+        assert_eq!(mem.listings(), listings);
+        assert_eq!(names(&cache, "/").await, ["dir"]);
+
+        mem.report_changes();
+        let cache = CacheStorage::new(mem.clone(), persist(false))
+            .await
+            .unwrap();
+        assert_eq!(names(&cache, "/").await, ["b.txt", "dir"]);
+        cache.persist_cache().await.unwrap();
+
+        let listings = mem.listings();
+        let cache = CacheStorage::new(mem.clone(), persist(false))
+            .await
+            .unwrap();
+        assert_eq!(mem.listings(), listings);
+        assert_eq!(names(&cache, "/dir").await, ["a.txt"]);
+
+        mem.put_file(Some(&sub), "c.txt", b"c", mtime(3));
+        let cache = CacheStorage::new(mem.clone(), persist(false))
+            .await
+            .unwrap();
+        assert!(mem.listings() > listings);
+        assert_eq!(names(&cache, "/dir").await, ["a.txt", "c.txt"]);
+
+        // e.g. checked at the resume from sleep
+        assert!(cache.is_current().await.unwrap());
+        mem.put_file(Some(&sub), "d.txt", b"d", mtime(4));
+        assert!(!cache.is_current().await.unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_ids_are_set_aside() {
+        let dir = temp_dir("invalid");
+        let path = dir.join("remote.cache");
+        let entries = DashMap::new();
+        entries.insert(
+            PathBuf::root(),
+            CacheNode {
+                id: None,
+                metadata: Metadata::root(),
+                children: vec!["a.txt".to_string()],
+            },
+        );
+        entries.insert(
+            PathBuf::from("/a.txt"),
+            CacheNode {
+                id: Some(IdBuf::from("not-a-number".to_string())),
+                metadata: Metadata::Regular {
+                    path: PathBuf::from("/a.txt"),
+                    size: 1,
+                    mtime: mtime(1),
+                    link_target: None,
+                },
+                children: Vec::new(),
+            },
+        );
+        disk::save_to_disc(&path, Arc::new(entries)).await.unwrap();
+
+        let mem = MemIdStorage::new();
+        mem.put_file(None, "b.txt", b"b", mtime(2));
+        let persist = CachePersist::MemoryAndDisk {
+            path,
+            ignore_initial_cache: false,
+        };
+        let cache = CacheStorage::new(mem, persist).await.unwrap();
+        assert!(cache.corrupt_file().is_some());
+        assert_eq!(names(&cache, "/").await, ["b.txt"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cache_of_another_format_is_populated_again() {
+        let dir = temp_dir("format");
+        let path = dir.join("remote.cache");
+        let entries = DashMap::new();
+        entries.insert(
+            PathBuf::root(),
+            CacheNode {
+                id: None,
+                metadata: Metadata::root(),
+                children: vec!["a.txt".to_string()],
+            },
+        );
+        disk::save_to_disc(&path, Arc::new(entries)).await.unwrap();
+        // as written before the format was versioned
+        std::fs::remove_file(dir.join("remote.cache.format")).unwrap();
+
+        let mem = MemIdStorage::new();
+        mem.put_file(None, "b.txt", b"b", mtime(2));
+        let persist = CachePersist::MemoryAndDisk {
+            path,
+            ignore_initial_cache: false,
+        };
+        let cache = CacheStorage::new(mem, persist).await.unwrap();
+        assert!(cache.corrupt_file().is_none());
+        assert_eq!(names(&cache, "/").await, ["b.txt"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
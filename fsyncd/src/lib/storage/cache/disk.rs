@@ -0,0 +1,229 @@
+//! The persistence format of the cache.
+//!
+//! The entries are serialized with bincode, by path, in the file given by [`super::CachePersist`].
+//! The sharing of the entries and the change token of the storage are persisted in files
+//! of their own next to it, so that the cache file keeps its format whatever the provider.
+//! The version of the format is persisted next to it as well: a cache of another version
+//! is populated again rather than set aside as corrupt.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use bincode::Options;
+use dashmap::DashMap;
+use fsync::path::{FsPath, FsPathBuf, PathBuf};
+use tokio::io;
+
+use super::CacheNode;
+use crate::{
+    persist,
+    storage::id::{Id, IdBuf},
+};
+
+/// Version of the format of the cached entries.
+/// Version 2 widens the counters of the directory stats to 64 bits.
+const FORMAT_VERSION: u32 = 2;
+
+/// Version of the caches written before the format was versioned
+const UNVERSIONED_FORMAT: u32 = 1;
+
+pub(super) enum LoadError {
+    Io(io::Error),
+    Bincode(bincode::Error),
+    /// The cache was written with another version of the format
+    Format(u32),
+    /// An id that the provider rejects, see [`super::Provider::valid_id`]
+    InvalidId(PathBuf, IdBuf),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Io(err) => err.fmt(f),
+            LoadError::Bincode(err) => err.fmt(f),
+            LoadError::Format(version) => write!(
+                f,
+                "cache format version {version}, expected {FORMAT_VERSION}"
+            ),
+            LoadError::InvalidId(path, id) => write!(f, "invalid id \"{id}\" for {path}"),
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(value: io::Error) -> Self {
+        LoadError::Io(value)
+    }
+}
+
+impl From<bincode::Error> for LoadError {
+    fn from(value: bincode::Error) -> Self {
+        LoadError::Bincode(value)
+    }
+}
+
+/// Load the entries cached in `path`, of which the ids are checked with `valid_id`
+pub(super) async fn load_from_disk(
+    path: &FsPath,
+    valid_id: fn(&Id) -> bool,
+) -> Result<Arc<DashMap<PathBuf, CacheNode>>, LoadError> {
+    log::trace!("loading cached entries from {path}");
+
+    let path2 = path.to_owned();
+
+    let handle = tokio::task::spawn_blocking(move || {
+        let version = load_format(&path2)?;
+        if version != FORMAT_VERSION {
+            return Err(LoadError::Format(version));
+        }
+        let opts = bincode_options();
+        // a BTreeMap, that does not trust the length read for its allocation,
+        // checks the content of the caches written without checksum
+        let data = persist::read_checked(&path2, |data| {
+            bincode_options()
+                .deserialize::<BTreeMap<PathBuf, CacheNode>>(data)
+                .is_ok()
+        })?;
+        let entries: DashMap<PathBuf, CacheNode> = opts.deserialize(&data)?;
+        let invalid = entries.iter().find_map(|entry| {
+            let id = entry.id.as_ref()?;
+            (!valid_id(id)).then(|| LoadError::InvalidId(entry.key().clone(), id.clone()))
+        });
+        match invalid {
+            Some(err) => Err(err),
+            None => Ok(entries),
+        }
+    });
+
+    let entries = handle.await.unwrap()?;
+    log::info!("loaded {} entries from {path}", entries.len());
+
+    Ok(Arc::new(entries))
+}
+
+/// The file where the sharing of the entries cached in `path` is persisted
+fn sharing_path(path: &FsPath) -> FsPathBuf {
+    let name = path.file_name().unwrap_or_default();
+    path.with_file_name(format!("{name}.sharing"))
+}
+
+/// Load the sharing persisted next to the cache in `path`.
+/// The sharing is empty if it can't be read, until the entries are listed again.
+pub(super) async fn load_sharing(path: &FsPath) -> Arc<DashMap<IdBuf, fsync::Sharing>> {
+    let path = sharing_path(path);
+    if !path.exists() {
+        return Arc::default();
+    }
+    let path2 = path.clone();
+    let handle = tokio::task::spawn_blocking(move || {
+        let data = persist::read_checked(&path2, |_| false)?;
+        let sharing: DashMap<IdBuf, fsync::Sharing> = bincode_options().deserialize(&data)?;
+        Ok::<_, LoadError>(sharing)
+    });
+    match handle.await.unwrap() {
+        Ok(sharing) => Arc::new(sharing),
+        Err(err) => {
+            log::warn!("could not read the sharing of the cached entries from {path}: {err}");
+            Arc::default()
+        }
+    }
+}
+
+pub(super) async fn save_sharing(
+    path: &FsPath,
+    sharing: Arc<DashMap<IdBuf, fsync::Sharing>>,
+) -> anyhow::Result<()> {
+    let path = sharing_path(path);
+    let handle = tokio::task::spawn_blocking(move || {
+        let data = bincode_options().serialize(&*sharing)?;
+        persist::write_checked(&path, &data)?;
+        Ok::<_, anyhow::Error>(())
+    });
+    handle.await.unwrap()
+}
+
+pub(super) async fn save_to_disc(
+    path: &FsPath,
+    entries: Arc<DashMap<PathBuf, CacheNode>>,
+) -> anyhow::Result<()> {
+    log::info!("saving {} entries to {path}", entries.len());
+
+    let path = path.to_owned();
+
+    let handle = tokio::task::spawn_blocking(move || {
+        let opts = bincode_options();
+        let data = opts.serialize(&*entries)?;
+        persist::write_checked(&path, &data)?;
+        let data = bincode_options().serialize(&FORMAT_VERSION)?;
+        persist::write_checked(&format_path(&path), &data)?;
+        Ok::<_, anyhow::Error>(())
+    });
+
+    handle.await.unwrap()
+}
+
+/// The file where the version of the format of the cache in `path` is persisted
+fn format_path(path: &FsPath) -> FsPathBuf {
+    let name = path.file_name().unwrap_or_default();
+    path.with_file_name(format!("{name}.format"))
+}
+
+/// The version of the format of the cache in `path`
+fn load_format(path: &FsPath) -> Result<u32, LoadError> {
+    let path = format_path(path);
+    if !path.exists() {
+        return Ok(UNVERSIONED_FORMAT);
+    }
+    let data = persist::read_checked(&path, |_| false)?;
+    Ok(bincode_options().deserialize(&data)?)
+}
+
+/// The file where the change token of the storage that populated the cache in `path` is persisted
+fn token_path(path: &FsPath) -> FsPathBuf {
+    let name = path.file_name().unwrap_or_default();
+    path.with_file_name(format!("{name}.token"))
+}
+
+/// Load the change token persisted next to the cache in `path`.
+/// The token is `None` if the provider does not report changes or if it can't be read.
+pub(super) async fn load_token(path: &FsPath) -> Option<String> {
+    let path = token_path(path);
+    if !path.exists() {
+        return None;
+    }
+    let path2 = path.clone();
+    let handle = tokio::task::spawn_blocking(move || {
+        let data = persist::read_checked(&path2, |_| false)?;
+        let token: String = bincode_options().deserialize(&data)?;
+        Ok::<_, LoadError>(token)
+    });
+    match handle.await.unwrap() {
+        Ok(token) => Some(token),
+        Err(err) => {
+            log::warn!("could not read the change token of the cache from {path}: {err}");
+            None
+        }
+    }
+}
+
+/// Persist `token` next to the cache in `path`, or remove the persisted one if there is none
+pub(super) async fn save_token(path: &FsPath, token: Option<String>) -> anyhow::Result<()> {
+    let path = token_path(path);
+    let handle = tokio::task::spawn_blocking(move || {
+        match token {
+            Some(token) => {
+                let data = bincode_options().serialize(&token)?;
+                persist::write_checked(&path, &data)?;
+            }
+            None if path.exists() => std::fs::remove_file(&path)?,
+            None => (),
+        }
+        Ok::<_, anyhow::Error>(())
+    });
+    handle.await.unwrap()
+}
+
+fn bincode_options() -> impl bincode::Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
@@ -0,0 +1,856 @@
+//! Encryption of the file contents, end to end.
+//!
+//! [`Crypt`] wraps an ID-based storage so that the contents are encrypted before they are
+//! written to it, and decrypted as they are read.
+//! Only the contents are protected: the names of the files and the folders, the structure
+//! of the tree, the sizes and the modification times are stored in clear, and remain
+//! visible to the provider.
+//!
+//! An encrypted file starts with a header made of a magic, the version of the format,
+//! a random salt from which the key of the file is derived, and a random nonce prefix.
+//! The content follows in segments of [`SEGMENT_LEN`] bytes, each sealed with AES-256-GCM
+//! under a nonce made of the prefix, the index of the segment and a flag set on the last
+//! segment, so that the segments can't be reordered nor the file truncated unnoticed.
+//! A file without the header is detected as not encrypted when it is read.
+//!
+//! The size of an encrypted file is a function of the size of its content, see
+//! [`encrypted_size`]. The storage reports the size of the content in the metadata,
+//! so that the tree compares the sizes of both sides as for a plain storage.
+//! As almost any size is the one of an encrypted file, the header of a listed file is read
+//! to tell it from a plain one, whose size is reported as is.
+
+use std::{
+    fmt,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use fsync::{path::Path, Metadata};
+use futures::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use tokio::io::{self, AsyncRead, AsyncReadExt, ReadBuf};
+use tokio_util::either::Either;
+
+use super::{
+    cache,
+    id::{self, Id, IdBuf},
+};
+use crate::{SharedProgress, Shutdown};
+
+const MAGIC: &[u8; 8] = b"FSYNCENC";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const PREFIX_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+/// Size of the header of an encrypted file
+pub const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + PREFIX_LEN;
+/// Size of the content sealed in each segment, the last one excepted
+pub const SEGMENT_LEN: usize = 64 * 1024;
+/// Number of headers read at once to tell the encrypted files of a listing
+const MAX_CONCURRENT_PROBES: usize = 8;
+
+/// The key of the contents of an instance, derived from the passphrase of the user
+#[derive(Clone)]
+pub struct ContentKey([u8; 32]);
+
+impl fmt::Debug for ContentKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ContentKey(..)")
+    }
+}
+
+impl ContentKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// The cipher of the file whose header has `salt`
+    fn file_cipher(&self, salt: &[u8]) -> Aes256Gcm {
+        let mut hasher = Sha256::new();
+        hasher.update(b"fsync content");
+        hasher.update(self.0);
+        hasher.update(salt);
+        Aes256Gcm::new(&hasher.finalize())
+    }
+}
+
+/// Size of the encrypted file of `size` bytes of content.
+/// An empty content still has a segment, for its authentication tag.
+pub fn encrypted_size(size: u64) -> u64 {
+    let segments = size.div_ceil(SEGMENT_LEN as u64).max(1);
+    HEADER_LEN as u64 + size + segments * TAG_LEN as u64
+}
+
+/// Size of the content of an encrypted file of `size` bytes,
+/// or `None` if no encrypted file has this size
+pub fn content_size(size: u64) -> Option<u64> {
+    let body = size.checked_sub(HEADER_LEN as u64)?;
+    let segments = body.div_ceil((SEGMENT_LEN + TAG_LEN) as u64);
+    let content = body.checked_sub(segments * TAG_LEN as u64)?;
+    (encrypted_size(content) == size).then_some(content)
+}
+
+/// `metadata` with the size of a file mapped by `map`
+fn map_size(metadata: Metadata, map: impl Fn(u64) -> u64) -> Metadata {
+    match metadata {
+        Metadata::Regular {
+            path,
+            size,
+            mtime,
+            link_target,
+        } => Metadata::Regular {
+            path,
+            size: map(size),
+            mtime,
+            link_target,
+        },
+        dir => dir,
+    }
+}
+
+/// Whether `header`, the first bytes of a stored file, is the header of an encrypted file
+fn is_sealed(header: &[u8]) -> bool {
+    header.len() == HEADER_LEN && header.starts_with(MAGIC)
+}
+
+/// The first bytes of `read`, up to the length of a header
+async fn read_header(read: impl AsyncRead) -> io::Result<Vec<u8>> {
+    tokio::pin!(read);
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    read.take(HEADER_LEN as u64)
+        .read_to_end(&mut header)
+        .await?;
+    Ok(header)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+enum Mode {
+    Seal,
+    Open,
+}
+
+/// Encrypts or decrypts the data read from `inner`, segment by segment
+struct CryptRead<R> {
+    inner: Pin<Box<R>>,
+    mode: Mode,
+    key: ContentKey,
+    /// The cipher and the nonce prefix of the file, once the header is written or read
+    cipher: Option<(Aes256Gcm, [u8; PREFIX_LEN])>,
+    input: Vec<u8>,
+    eof: bool,
+    index: u32,
+    output: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R> CryptRead<R> {
+    fn new(inner: R, mode: Mode, key: ContentKey) -> Self {
+        let mut read = Self {
+            inner: Box::pin(inner),
+            mode,
+            key,
+            cipher: None,
+            input: Vec::new(),
+            eof: false,
+            index: 0,
+            output: Vec::new(),
+            pos: 0,
+            done: false,
+        };
+        if matches!(read.mode, Mode::Seal) {
+            let mut header = [0u8; HEADER_LEN];
+            header[..MAGIC.len()].copy_from_slice(MAGIC);
+            header[MAGIC.len()] = VERSION;
+            OsRng.fill_bytes(&mut header[MAGIC.len() + 1..]);
+            read.start(&header);
+            read.output = header.to_vec();
+        }
+        read
+    }
+
+    /// Set the cipher from the salt and the prefix of `header`
+    fn start(&mut self, header: &[u8]) {
+        let (salt, prefix) = header[MAGIC.len() + 1..HEADER_LEN].split_at(SALT_LEN);
+        let cipher = self.key.file_cipher(salt);
+        self.cipher = Some((cipher, prefix.try_into().unwrap()));
+    }
+
+    /// Number of input bytes needed to process the next segment.
+    /// One byte more than the segment tells whether it is the last one.
+    fn wanted(&self) -> usize {
+        match (&self.mode, &self.cipher) {
+            (Mode::Open, None) => HEADER_LEN,
+            (Mode::Open, Some(_)) => SEGMENT_LEN + TAG_LEN + 1,
+            (Mode::Seal, _) => SEGMENT_LEN + 1,
+        }
+    }
+
+    /// Process the input into the output, once it has the wanted bytes or the end of the data
+    fn process(&mut self) -> io::Result<()> {
+        let Some((cipher, prefix)) = &self.cipher else {
+            let header = &self.input[..];
+            if header.len() < HEADER_LEN || !header.starts_with(MAGIC) {
+                return Err(invalid_data("the file is not encrypted"));
+            }
+            if header[MAGIC.len()] != VERSION {
+                return Err(invalid_data("unsupported version of the encryption format"));
+            }
+            let header = header[..HEADER_LEN].to_vec();
+            self.start(&header);
+            self.input.drain(..HEADER_LEN);
+            return Ok(());
+        };
+        let segment_len = match self.mode {
+            Mode::Seal => SEGMENT_LEN,
+            Mode::Open => SEGMENT_LEN + TAG_LEN,
+        };
+        let last = self.input.len() <= segment_len;
+        let len = self.input.len().min(segment_len);
+        let mut nonce = [0u8; 12];
+        nonce[..PREFIX_LEN].copy_from_slice(prefix);
+        nonce[PREFIX_LEN..11].copy_from_slice(&self.index.to_be_bytes());
+        nonce[11] = last as u8;
+        let nonce = Nonce::from_slice(&nonce);
+        let segment = &self.input[..len];
+        self.output = match self.mode {
+            Mode::Seal => cipher
+                .encrypt(nonce, segment)
+                .expect("encryption should not fail"),
+            Mode::Open => cipher.decrypt(nonce, segment).map_err(|_| {
+                invalid_data("could not decrypt the file: wrong key, or altered content")
+            })?,
+        };
+        self.pos = 0;
+        self.input.drain(..len);
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or_else(|| invalid_data("too many segments"))?;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R> AsyncRead for CryptRead<R>
+where
+    R: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos < this.output.len() {
+                let len = buf.remaining().min(this.output.len() - this.pos);
+                buf.put_slice(&this.output[this.pos..this.pos + len]);
+                this.pos += len;
+                return Poll::Ready(Ok(()));
+            }
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+            let wanted = this.wanted();
+            while !this.eof && this.input.len() < wanted {
+                let len = this.input.len();
+                this.input.resize(wanted, 0);
+                let mut read = ReadBuf::new(&mut this.input[len..]);
+                let res = this.inner.as_mut().poll_read(cx, &mut read);
+                let filled = read.filled().len();
+                this.input.truncate(len + filled);
+                ready!(res)?;
+                this.eof = filled == 0;
+            }
+            this.output.clear();
+            this.process()?;
+        }
+    }
+}
+
+/// Whether a stored file is encrypted, as told by its header
+#[derive(Debug, Clone)]
+struct Probe {
+    /// The MD5 checksum of the stored file, if known by the storage
+    md5: Option<String>,
+    size: u64,
+    mtime: DateTime<Utc>,
+    sealed: bool,
+}
+
+impl Probe {
+    /// Whether the probed file still has the same content.
+    /// The size and the modification time are compared when a checksum is missing.
+    fn holds(&self, md5: Option<&str>, size: u64, mtime: DateTime<Utc>) -> bool {
+        match (self.md5.as_deref(), md5) {
+            (Some(probed), Some(md5)) => probed == md5,
+            _ => self.size == size && self.mtime == mtime,
+        }
+    }
+}
+
+/// A storage whose file contents are encrypted with `key`.
+/// Without key, the contents are written and read as is.
+#[derive(Debug, Clone)]
+pub struct Crypt<S> {
+    storage: S,
+    key: Option<ContentKey>,
+    /// The stored files whose header was read, or that were written encrypted
+    probes: Arc<DashMap<IdBuf, Probe>>,
+}
+
+impl<S> Crypt<S> {
+    pub fn new(storage: S, key: Option<ContentKey>) -> Self {
+        Self {
+            storage,
+            key,
+            probes: Arc::new(DashMap::new()),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// The metadata of the stored file, from the `metadata` of its content
+    fn stored(&self, metadata: &Metadata) -> Metadata {
+        match self.key {
+            Some(_) => map_size(metadata.clone(), encrypted_size),
+            None => metadata.clone(),
+        }
+    }
+
+    /// The metadata of the content, from the `metadata` of the file `id` stored by [`Self::seal`]
+    fn content(&self, id: &Id, metadata: Metadata) -> Metadata
+    where
+        S: id::Checksums,
+    {
+        if self.key.is_none() {
+            return metadata;
+        }
+        if let Metadata::Regular { size, mtime, .. } = &metadata {
+            let probe = Probe {
+                md5: self.storage.known_md5(id),
+                size: *size,
+                mtime: *mtime,
+                sealed: true,
+            };
+            self.probes.insert(id.to_owned(), probe);
+        }
+        map_size(metadata, |size| content_size(size).unwrap_or(size))
+    }
+
+    /// The metadata of the content of the stored file `id`, from its `metadata`.
+    /// The header is read unless it was already for the same content.
+    /// The size of a file that is not encrypted is left as is.
+    async fn listed(&self, id: &Id, metadata: Metadata) -> fsync::Result<Metadata>
+    where
+        S: id::ReadFileRange + id::Checksums,
+    {
+        let (size, mtime) = match (&self.key, &metadata) {
+            (Some(_), Metadata::Regular { size, mtime, .. }) => (*size, *mtime),
+            _ => return Ok(metadata),
+        };
+        let Some(content) = content_size(size) else {
+            return Ok(metadata);
+        };
+        let md5 = self.storage.known_md5(id);
+        let probed = self
+            .probes
+            .get(id)
+            .filter(|probe| probe.holds(md5.as_deref(), size, mtime))
+            .map(|probe| probe.sealed);
+        let sealed = match probed {
+            Some(sealed) => sealed,
+            None => {
+                let read = self
+                    .storage
+                    .read_file_range(id.to_owned(), 0, HEADER_LEN as u64, None)
+                    .await?;
+                let sealed = is_sealed(&read_header(read).await?);
+                let probe = Probe {
+                    md5,
+                    size,
+                    mtime,
+                    sealed,
+                };
+                self.probes.insert(id.to_owned(), probe);
+                sealed
+            }
+        };
+        match sealed {
+            true => Ok(map_size(metadata, |_| content)),
+            false => Ok(metadata),
+        }
+    }
+
+    /// The data to store for the content read from `data`
+    fn seal<R>(&self, data: R) -> Either<R, CryptRead<R>> {
+        match &self.key {
+            Some(key) => Either::Right(CryptRead::new(data, Mode::Seal, key.clone())),
+            None => Either::Left(data),
+        }
+    }
+}
+
+impl<S> id::Exists for Crypt<S>
+where
+    S: id::Exists + Sync,
+{
+    async fn exists(&self, id: &Id) -> fsync::Result<bool> {
+        self.storage.exists(id).await
+    }
+}
+
+/// The header of each listed file is read, to report the size of its content.
+/// The headers are read a few at once, and once for a given content.
+impl<S> id::DirEntries for Crypt<S>
+where
+    S: id::DirEntries + id::ReadFileRange + id::Checksums,
+{
+    fn dir_entries(
+        &self,
+        parent_id: Option<&Id>,
+        parent_path: &Path,
+        progress: Option<&SharedProgress>,
+    ) -> impl Stream<Item = fsync::Result<(IdBuf, Metadata)>> + Send {
+        self.storage
+            .dir_entries(parent_id, parent_path, progress)
+            .map(move |res| async move {
+                let (id, metadata) = res?;
+                let metadata = self.listed(&id, metadata).await?;
+                Ok((id, metadata))
+            })
+            .buffered(MAX_CONCURRENT_PROBES)
+    }
+}
+
+impl<S> id::ReadFile for Crypt<S>
+where
+    S: id::ReadFile + Sync,
+{
+    async fn read_file(
+        &self,
+        id: IdBuf,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead + Send> {
+        let read = self.storage.read_file(id, progress).await?;
+        match &self.key {
+            Some(key) => Ok(Either::Right(CryptRead::new(read, Mode::Open, key.clone()))),
+            None => Ok(Either::Left(read)),
+        }
+    }
+}
+
+impl<S> id::ReadFileRange for Crypt<S>
+where
+    S: id::ReadFileRange + Sync,
+{
+    /// An encrypted file is decrypted from its start
+    async fn read_file_range(
+        &self,
+        id: IdBuf,
+        offset: u64,
+        len: u64,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead + Send> {
+        if self.key.is_none() {
+            let read = self
+                .storage
+                .read_file_range(id, offset, len, progress)
+                .await?;
+            return Ok(Either::Left(read));
+        }
+        let read = id::ReadFile::read_file(self, id, progress).await?;
+        Ok(Either::Right(super::read_range(read, offset, len).await?))
+    }
+}
+
+impl<S> id::MkDir for Crypt<S>
+where
+    S: id::MkDir + Sync,
+{
+    async fn mkdir(
+        &self,
+        parent_id: Option<&Id>,
+        name: &str,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<IdBuf> {
+        self.storage.mkdir(parent_id, name, progress).await
+    }
+}
+
+impl<S> id::CreateFile for Crypt<S>
+where
+    S: id::CreateFile + id::Checksums + Sync,
+{
+    async fn create_file(
+        &self,
+        parent_id: Option<&Id>,
+        metadata: &Metadata,
+        data: impl io::AsyncRead + Send,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<(IdBuf, Metadata)> {
+        let (id, metadata) = self
+            .storage
+            .create_file(parent_id, &self.stored(metadata), self.seal(data), progress)
+            .await?;
+        let metadata = self.content(&id, metadata);
+        Ok((id, metadata))
+    }
+}
+
+impl<S> id::WriteFile for Crypt<S>
+where
+    S: id::WriteFile + id::Checksums + Sync,
+{
+    async fn write_file(
+        &self,
+        id: &Id,
+        parent_id: Option<&Id>,
+        metadata: &Metadata,
+        data: impl io::AsyncRead + Send,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<Metadata> {
+        let metadata = self
+            .storage
+            .write_file(
+                id,
+                parent_id,
+                &self.stored(metadata),
+                self.seal(data),
+                progress,
+            )
+            .await?;
+        Ok(self.content(id, metadata))
+    }
+}
+
+/// The content is left as is, its probe follows the modification time
+impl<S> id::PatchMetadata for Crypt<S>
+where
+    S: id::PatchMetadata + id::ReadFileRange + id::Checksums,
+{
+    async fn patch(
+        &self,
+        id: &Id,
+        path: &Path,
+        patch: &super::MetadataPatch,
+    ) -> fsync::Result<Metadata> {
+        let metadata = self.storage.patch(id, path, patch).await?;
+        if let (Some(mut probe), Some(mtime)) = (self.probes.get_mut(id), metadata.mtime()) {
+            probe.mtime = mtime;
+        }
+        self.listed(id, metadata).await
+    }
+}
+
+/// The copy has the content of the source, it is not probed again
+impl<S> id::CopyFile for Crypt<S>
+where
+    S: id::CopyFile + id::ReadFileRange + id::Checksums,
+{
+    async fn copy_file(
+        &self,
+        src_id: &Id,
+        dest_parent_id: Option<&Id>,
+        dest_path: &Path,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<(IdBuf, Metadata)> {
+        let (id, metadata) = self
+            .storage
+            .copy_file(src_id, dest_parent_id, dest_path, progress)
+            .await?;
+        let probe = self.probes.get(src_id).map(|probe| probe.clone());
+        if let (Some(probe), Some(mtime)) = (probe, metadata.mtime()) {
+            self.probes.insert(id.clone(), Probe { mtime, ..probe });
+        }
+        let metadata = self.listed(&id, metadata).await?;
+        Ok((id, metadata))
+    }
+}
+
+impl<S> id::Delete for Crypt<S>
+where
+    S: id::Delete + Sync,
+{
+    async fn delete(&self, id: &Id, progress: Option<&SharedProgress>) -> fsync::Result<()> {
+        self.storage.delete(id, progress).await?;
+        self.probes.remove(id);
+        Ok(())
+    }
+}
+
+impl<S> super::Quota for Crypt<S>
+where
+    S: super::Quota + Sync,
+{
+    async fn quota(&self) -> fsync::Result<Option<fsync::stat::Quota>> {
+        self.storage.quota().await
+    }
+}
+
+impl<S> super::Flush for Crypt<S>
+where
+    S: super::Flush + Sync,
+{
+    async fn flush(&self) -> fsync::Result<()> {
+        self.storage.flush().await
+    }
+}
+
+impl<S> id::Shared for Crypt<S>
+where
+    S: id::Shared + Sync,
+{
+    fn sharing(&self, id: &Id) -> Option<fsync::Sharing> {
+        self.storage.sharing(id)
+    }
+
+    fn view_only(&self, id: &Id) -> Option<fsync::ViewOnly> {
+        self.storage.view_only(id)
+    }
+
+    async fn link(&self, id: &Id) -> fsync::Result<fsync::RemoteLink> {
+        self.storage.link(id).await
+    }
+
+    async fn share_public(&self, id: &Id) -> fsync::Result<fsync::RemoteLink> {
+        self.storage.share_public(id).await
+    }
+}
+
+/// The revisions are decrypted as the files, and report the size of their content.
+/// The header of each revision is read, as a revision may precede the encryption.
+impl<S> id::Revisions for Crypt<S>
+where
+    S: id::Revisions + Sync,
+{
+    async fn revisions(&self, id: &Id) -> fsync::Result<Vec<fsync::Revision>> {
+        let mut revisions = self.storage.revisions(id).await?;
+        if self.key.is_none() {
+            return Ok(revisions);
+        }
+        for revision in revisions.iter_mut() {
+            let Some(content) = revision.size.and_then(content_size) else {
+                continue;
+            };
+            let read = self
+                .storage
+                .read_revision(id.to_owned(), &revision.id, None)
+                .await?;
+            if is_sealed(&read_header(read).await?) {
+                revision.size = Some(content);
+            }
+        }
+        Ok(revisions)
+    }
+
+    async fn read_revision(
+        &self,
+        id: IdBuf,
+        revision_id: &str,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead + Send> {
+        let read = self
+            .storage
+            .read_revision(id, revision_id, progress)
+            .await?;
+        match &self.key {
+            Some(key) => Ok(Either::Right(CryptRead::new(read, Mode::Open, key.clone()))),
+            None => Ok(Either::Left(read)),
+        }
+    }
+}
+
+/// The checksums of the storage are the ones of the encrypted content,
+/// none is reported when the content is encrypted
+impl<S> id::Checksums for Crypt<S>
+where
+    S: id::Checksums + Sync,
+{
+    fn known_md5(&self, id: &Id) -> Option<String> {
+        if self.key.is_some() {
+            return None;
+        }
+        self.storage.known_md5(id)
+    }
+
+    async fn fetch_md5(&self, id: &Id) -> fsync::Result<Option<String>> {
+        if self.key.is_some() {
+            return Ok(None);
+        }
+        self.storage.fetch_md5(id).await
+    }
+}
+
+impl<S> cache::Provider for Crypt<S>
+where
+    S: cache::Provider + Sync,
+{
+    const DUPLICATE_NAMES: cache::DuplicateNames = S::DUPLICATE_NAMES;
+
+    fn valid_id(id: &Id) -> bool {
+        S::valid_id(id)
+    }
+
+    async fn change_token(&self) -> fsync::Result<Option<String>> {
+        self.storage.change_token().await
+    }
+}
+
+impl<S> Shutdown for Crypt<S>
+where
+    S: Shutdown + Sync,
+{
+    async fn shutdown(&self) -> anyhow::Result<()> {
+        self.storage.shutdown().await
+    }
+}
+
+impl<S> id::Storage for Crypt<S> where S: id::Storage {}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use fsync::path::PathBuf;
+    use futures::TryStreamExt;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::storage::{
+        id::{CreateFile, DirEntries, ReadFile, ReadFileRange},
+        mem::id::MemIdStorage,
+    };
+
+    async fn read_all(read: impl AsyncRead) -> io::Result<Vec<u8>> {
+        tokio::pin!(read);
+        let mut data = Vec::new();
+        read.read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    fn key(byte: u8) -> ContentKey {
+        ContentKey::new([byte; 32])
+    }
+
+    async fn seal(data: &[u8]) -> Vec<u8> {
+        read_all(CryptRead::new(data, Mode::Seal, key(7)))
+            .await
+            .unwrap()
+    }
+
+    async fn open(data: &[u8], byte: u8) -> io::Result<Vec<u8>> {
+        read_all(CryptRead::new(data, Mode::Open, key(byte))).await
+    }
+
+    #[tokio::test]
+    async fn contents_roundtrip() {
+        let lens = [
+            0,
+            1,
+            SEGMENT_LEN - 1,
+            SEGMENT_LEN,
+            SEGMENT_LEN + 1,
+            3 * SEGMENT_LEN + 5,
+        ];
+        for len in lens {
+            let content: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let sealed = seal(&content).await;
+            assert_eq!(sealed.len() as u64, encrypted_size(len as u64));
+            assert_eq!(content_size(sealed.len() as u64), Some(len as u64));
+            assert_ne!(sealed[HEADER_LEN..], content[..]);
+            assert_eq!(open(&sealed, 7).await.unwrap(), content);
+        }
+        // the salt and the nonce prefix are random
+        assert_ne!(seal(b"content").await, seal(b"content").await);
+    }
+
+    #[tokio::test]
+    async fn altered_contents_are_detected() {
+        let content = vec![42u8; 2 * SEGMENT_LEN];
+        let sealed = seal(&content).await;
+
+        assert!(open(&sealed, 8).await.is_err());
+
+        let mut altered = sealed.clone();
+        altered[HEADER_LEN + 10] ^= 1;
+        assert!(open(&altered, 7).await.is_err());
+
+        // truncated at the end of the first segment
+        let truncated = &sealed[..HEADER_LEN + SEGMENT_LEN + TAG_LEN];
+        assert!(open(truncated, 7).await.is_err());
+
+        let err = open(b"plain text", 7).await.unwrap_err();
+        assert_eq!(err.to_string(), "the file is not encrypted");
+    }
+
+    #[test]
+    fn sizes_of_no_encrypted_file() {
+        assert_eq!(content_size(0), None);
+        assert_eq!(content_size(HEADER_LEN as u64 + 15), None);
+        // a second segment can't be shorter than its tag
+        let size = (HEADER_LEN + SEGMENT_LEN + TAG_LEN + 5) as u64;
+        assert_eq!(content_size(size), None);
+    }
+
+    #[tokio::test]
+    async fn storage_holds_encrypted_contents() {
+        let mem = MemIdStorage::new();
+        let crypt = Crypt::new(mem.clone(), Some(key(7)));
+        let metadata = Metadata::Regular {
+            path: PathBuf::from("/file.txt"),
+            size: 11,
+            mtime: Utc::now(),
+            link_target: None,
+        };
+        let (id, written) = crypt
+            .create_file(None, &metadata, &b"secret data"[..], None)
+            .await
+            .unwrap();
+        assert_eq!(written.size(), Some(11));
+
+        let stored = read_all(mem.read_file(id.clone(), None).await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(stored.len() as u64, encrypted_size(11));
+        assert!(stored.starts_with(MAGIC));
+
+        let listed: Vec<_> = crypt
+            .dir_entries(None, Path::root(), None)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(listed[0].1.size(), Some(11));
+
+        let read = crypt.read_file(id.clone(), None).await.unwrap();
+        assert_eq!(read_all(read).await.unwrap(), b"secret data");
+        let read = crypt.read_file_range(id, 7, 10, None).await.unwrap();
+        assert_eq!(read_all(read).await.unwrap(), b"data");
+
+        // a file written without encryption is listed with its size, and detected when read
+        let plain = [b'p'; 100];
+        assert!(content_size(plain.len() as u64).is_some());
+        let id = mem.put_file(None, "plain.txt", &plain, Utc::now());
+        let listed: Vec<_> = crypt
+            .dir_entries(None, Path::root(), None)
+            .try_collect()
+            .await
+            .unwrap();
+        let (_, md) = listed.iter().find(|(i, _)| *i == id).unwrap();
+        assert_eq!(md.size(), Some(100));
+        let read = crypt.read_file(id, None).await.unwrap();
+        assert!(read_all(read).await.is_err());
+    }
+}
@@ -1,17 +1,32 @@
-use std::{str, sync::Arc};
+use std::{
+    collections::HashMap,
+    str,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
 use async_stream::try_stream;
 use fsync::path::{Component, Path, PathBuf};
 use futures::prelude::*;
 use tokio::io;
+use tokio_util::either::Either;
 
 use crate::{
+    buffers::BufferPool,
+    disk_cache::DiskCache,
     oauth2::GetToken,
     storage::id::{Id, IdBuf},
     PersistCache, SharedProgress, Shutdown,
 };
 
+mod batch;
+mod content;
+mod pages;
+mod upload;
+
+pub use upload::UploadStats;
+
 #[derive(Default, Debug)]
 pub enum RootSpec<'a> {
     #[default]
@@ -34,18 +49,86 @@ impl<'a> From<Option<&'a Path>> for RootSpec<'a> {
     }
 }
 
-#[derive(Clone)]
+/// The quota is fetched again after this delay
+const QUOTA_REFRESH_INTERVAL: Duration = Duration::from_secs(600);
+/// The quota is fetched again after this amount of bytes is uploaded
+const QUOTA_REFRESH_BYTES: u64 = 256 * 1024 * 1024;
+/// Guard of the walk of the folders containing the root, in case of a cycle of parents
+const MAX_ANCESTRY: usize = 256;
+
+#[derive(Debug)]
+struct QuotaCache {
+    quota: api::Quota,
+    fetched: Instant,
+    uploaded: u64,
+}
+
+impl QuotaCache {
+    fn new(quota: api::Quota) -> Self {
+        Self {
+            quota,
+            fetched: Instant::now(),
+            uploaded: 0,
+        }
+    }
+
+    fn is_stale(&self) -> bool {
+        self.uploaded >= QUOTA_REFRESH_BYTES || self.fetched.elapsed() >= QUOTA_REFRESH_INTERVAL
+    }
+}
+
 pub struct GoogleDrive<A> {
     client: reqwest::Client,
     auth: Arc<A>,
     base_url: &'static str,
     upload_base_url: &'static str,
+    batch_url: &'static str,
     user_agent: String,
 
     root: IdBuf,
     shared: bool,
     user: api::User,
-    quota: api::Quota,
+    quota: Arc<Mutex<QuotaCache>>,
+    batch: Arc<tokio::sync::Mutex<batch::Queue>>,
+    max_chunk_size: u64,
+    upload_stats: Arc<Mutex<UploadStats>>,
+    /// The budget of the buffers of the uploads
+    buffers: Option<BufferPool>,
+    fetch_sharing: bool,
+    /// The shared entries seen in the responses, by id
+    sharing: Arc<Mutex<HashMap<IdBuf, fsync::Sharing>>>,
+    /// The files seen in the responses that can't be downloaded, by id
+    view_only: Arc<Mutex<HashMap<IdBuf, fsync::ViewOnly>>>,
+    /// The links to the files, fetched on demand, by id
+    links: Arc<Mutex<HashMap<IdBuf, fsync::RemoteLink>>>,
+    content: Option<Arc<content::ContentCache>>,
+}
+
+// not derived, to not require `A: Clone`
+impl<A> Clone for GoogleDrive<A> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            auth: self.auth.clone(),
+            base_url: self.base_url,
+            upload_base_url: self.upload_base_url,
+            batch_url: self.batch_url,
+            user_agent: self.user_agent.clone(),
+            root: self.root.clone(),
+            shared: self.shared,
+            user: self.user.clone(),
+            quota: self.quota.clone(),
+            batch: self.batch.clone(),
+            max_chunk_size: self.max_chunk_size,
+            upload_stats: self.upload_stats.clone(),
+            buffers: self.buffers.clone(),
+            fetch_sharing: self.fetch_sharing,
+            sharing: self.sharing.clone(),
+            view_only: self.view_only.clone(),
+            links: self.links.clone(),
+            content: self.content.clone(),
+        }
+    }
 }
 
 impl<A> GoogleDrive<A>
@@ -59,16 +142,27 @@ where
             client,
             base_url: "https://www.googleapis.com/drive/v3",
             upload_base_url: "https://www.googleapis.com/upload/drive/v3",
+            batch_url: "https://www.googleapis.com/batch/drive/v3",
             user_agent,
             root: IdBuf::from("root"),
             shared: false,
             user: api::User::default(),
-            quota: api::Quota::default(),
+            quota: Arc::new(Mutex::new(QuotaCache::new(api::Quota::default()))),
+            batch: Arc::default(),
+            max_chunk_size: upload::DEFAULT_MAX_CHUNK_SZ,
+            upload_stats: Arc::default(),
+            buffers: None,
+            fetch_sharing: true,
+            sharing: Arc::default(),
+            view_only: Arc::default(),
+            links: Arc::default(),
+            content: None,
         };
 
         let about = drive.about_get().await?;
         drive.user = about.user;
-        drive.quota = about.storage_quota;
+        let quota = about.storage_quota.clone();
+        drive.quota = Arc::new(Mutex::new(QuotaCache::new(about.storage_quota)));
 
         match root {
             RootSpec::Root => (),
@@ -96,20 +190,174 @@ where
                 .map(|em| format!(" <{em}>"))
                 .unwrap_or_default(),
         );
-        if let (&Some(usage), &Some(limit)) = (&drive.quota.usage, &drive.quota.limit) {
-            use byte_unit::{Byte, UnitType};
-            let usage = Byte::from_i64(usage)
-                .expect("positive")
-                .get_appropriate_unit(UnitType::Binary);
-            let limit = Byte::from_i64(limit)
-                .expect("positive")
-                .get_appropriate_unit(UnitType::Binary);
-            log::info!("Usage {usage:#.2} / {limit:#.3}");
+        if let (Some(usage), Some(limit)) = (quota.usage, quota.limit) {
+            use fsync::fmt::{human_bytes, Unit};
+            log::info!(
+                "Usage {} / {}",
+                human_bytes(usage.max(0) as u64, Unit::Binary),
+                human_bytes(limit.max(0) as u64, Unit::Binary)
+            );
         }
 
         Ok(drive)
     }
 
+    /// Limit the size of the chunks of the uploads, or use the default limit if `None`.
+    pub fn with_max_chunk_size(self, max_chunk_size: Option<u64>) -> Self {
+        Self {
+            max_chunk_size: max_chunk_size.unwrap_or(upload::DEFAULT_MAX_CHUNK_SZ),
+            ..self
+        }
+    }
+
+    /// Whether to request the permissions of the files, to report how they are shared.
+    /// Disabling it reduces the size of the responses of the Drive API.
+    pub fn with_sharing(self, fetch_sharing: bool) -> Self {
+        Self {
+            fetch_sharing,
+            ..self
+        }
+    }
+
+    /// Rent the buffers of the uploads from `pool`
+    pub fn with_buffer_pool(self, pool: BufferPool) -> Self {
+        Self {
+            buffers: Some(pool),
+            ..self
+        }
+    }
+
+    /// Keep the content of the files read in `cache`, or do not cache it if `None`
+    pub fn with_content_cache(self, cache: Option<Arc<DiskCache>>) -> Self {
+        Self {
+            content: cache.map(|cache| Arc::new(content::ContentCache::new(cache))),
+            ..self
+        }
+    }
+
+    /// Email address of the account that authorized the access, if the API reports it
+    pub fn account(&self) -> Option<&str> {
+        self.user.email_address.as_deref()
+    }
+
+    /// The id of the root folder, `root` for the root of My Drive
+    pub fn root_id(&self) -> &Id {
+        &self.root
+    }
+
+    /// The ids of the root folder and of the folders containing it, up to the top of its drive.
+    /// The first id is the one of the root folder, resolved if the root is the `root` alias.
+    /// The chain ends at the folder of My Drive, or at the id of a shared drive which has no parent.
+    pub async fn root_ancestry(&self) -> fsync::Result<Vec<IdBuf>> {
+        let mut ancestry: Vec<IdBuf> = Vec::new();
+        let mut id = self.root.clone();
+        loop {
+            let Some(parents) = self.files_get_parents(&id).await? else {
+                return Err(fsync::io_error!("Could not find folder {id}"));
+            };
+            let resolved = parents.id.unwrap_or(id);
+            if ancestry.contains(&resolved) || ancestry.len() >= MAX_ANCESTRY {
+                return Err(fsync::api_error!(
+                    "The folders containing {} don't lead to the top of a drive",
+                    self.root
+                ));
+            }
+            ancestry.push(resolved);
+            // only the first parent is followed, folders have a single one since 2020
+            match parents.parents.into_iter().next() {
+                Some(parent) => id = parent,
+                None => return Ok(ancestry),
+            }
+        }
+    }
+
+    /// Keep how `f` is shared, if its permissions were requested
+    fn record_sharing(&self, f: &api::File) {
+        if !self.fetch_sharing {
+            return;
+        }
+        let Some(id) = f.id.clone() else {
+            return;
+        };
+        let mut sharing = self.sharing.lock().unwrap();
+        match map_sharing(f) {
+            Some(shared) => sharing.insert(id, shared),
+            None => sharing.remove(&id),
+        };
+    }
+
+    /// Keep whether `f` can be downloaded, if its capabilities were reported
+    fn record_view_only(&self, f: &api::File) {
+        let (Some(id), Some(capabilities)) = (f.id.clone(), f.capabilities.as_ref()) else {
+            return;
+        };
+        let mut view_only = self.view_only.lock().unwrap();
+        if capabilities.can_download == Some(false) {
+            let web_view_link = f.web_view_link.clone();
+            view_only.insert(id, fsync::ViewOnly { web_view_link });
+        } else {
+            view_only.remove(&id);
+        }
+    }
+
+    /// Keep the checksum of `f`, invalidating the content cached for a previous version
+    async fn record_content(&self, f: &api::File) {
+        let (Some(content), Some(id)) = (&self.content, f.id.as_deref()) else {
+            return;
+        };
+        let size = f.size.map(|size| size as u64);
+        content
+            .record(id, f.md5_checksum.as_deref(), size, f.modified_time)
+            .await;
+    }
+
+    /// Pass the content of the file `id` read from Drive, caching it if enabled
+    fn tee_content<R>(&self, id: &Id, read: R) -> content::Tee<R> {
+        match &self.content {
+            Some(content) => content.tee(id, read),
+            None => content::Tee::new(read),
+        }
+    }
+
+    /// The content of the file `id` if it is cached
+    async fn cached_content(&self, id: &Id) -> Option<Vec<u8>> {
+        self.content.as_ref()?.get(id).await
+    }
+
+    /// Complete `err` if the download of the file with `id` was refused.
+    /// The file is marked view-only, in case it was not listed with its capabilities.
+    fn refused_download(&self, err: fsync::Error, id: &Id) -> fsync::Error {
+        if !matches!(err, fsync::Error::NotDownloadable { .. }) {
+            return err;
+        }
+        let web_view_link = self
+            .view_only
+            .lock()
+            .unwrap()
+            .entry(id.to_id_buf())
+            .or_default()
+            .web_view_link
+            .clone();
+        fsync::Error::NotDownloadable {
+            path: PathBuf::from(id.as_str()),
+            web_view_link,
+        }
+    }
+
+    /// The fields requested for each file
+    fn file_fields(&self) -> &'static str {
+        if self.fetch_sharing {
+            api::FILE_FIELDS_WITH_SHARING
+        } else {
+            api::FILE_FIELDS
+        }
+    }
+
+    /// Statistics of the uploads since the storage was created
+    pub fn upload_stats(&self) -> UploadStats {
+        *self.upload_stats.lock().unwrap()
+    }
+
     async fn path_to_id<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<Option<IdBuf>> {
         let path = path.as_ref().normalize()?;
         if path.is_relative() {
@@ -141,6 +389,32 @@ where
         Ok(cur_id)
     }
 
+    /// Names of the folders in the folder `path`, relative to the Drive root, sorted.
+    /// This lists the candidates of the remote root of a new instance.
+    pub async fn folder_names(&self, path: &Path) -> fsync::Result<Vec<String>> {
+        let Some(id) = self.path_to_id(path).await? else {
+            return Err(fsync::PathError::NotFound(path.to_owned(), None).into());
+        };
+        let q = format!("'{id}' in parents and mimeType = '{FOLDER_MIMETYPE}' and trashed = false");
+        let mut names = Vec::new();
+        let mut page_token = None;
+        loop {
+            let file_list = self.files_list(q.clone(), page_token, None).await?;
+            names.extend(file_list.files.into_iter().flatten().filter_map(|f| f.name));
+            page_token = file_list.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    fn add_uploaded(&self, bytes: u64) {
+        let mut cache = self.quota.lock().unwrap();
+        cache.uploaded += bytes;
+    }
+
     pub async fn delete_folder_content(&self, id: Option<&Id>, path: &Path) -> anyhow::Result<()> {
         use super::id::DirEntries;
 
@@ -154,6 +428,21 @@ where
     }
 }
 
+/// The OAuth2 scopes to authorize for the storage, which cover all its requests
+pub fn authorization_scopes() -> Vec<crate::oauth2::Scope> {
+    vec![api::Scope::Full.into()]
+}
+
+impl<A> super::id::Exists for GoogleDrive<A>
+where
+    A: GetToken,
+{
+    async fn exists(&self, id: &Id) -> fsync::Result<bool> {
+        self.flush_batch().await?;
+        Ok(self.files_get(id, None).await?.is_some())
+    }
+}
+
 impl<A> super::id::DirEntries for GoogleDrive<A>
 where
     A: GetToken,
@@ -169,23 +458,31 @@ where
             "none Id is for root only"
         );
         log::trace!("listing entries of {parent_path}");
-        let search_id = parent_id.as_deref().unwrap_or(&self.root);
+        let search_id = parent_id.unwrap_or(&self.root);
         let q = format!("'{search_id}' in parents");
-        let mut next_page_token = None;
+        let drive = self.clone();
+        let progress = progress.cloned();
 
         try_stream! {
-            loop {
-                let file_list = self.files_list(q.clone(), next_page_token, progress).await?;
-                next_page_token = file_list.next_page_token;
-                if let Some(files) = file_list.files {
-                    for f in files {
-                        let id = f.id.clone().unwrap_or_default();
-                        let metadata = map_file(parent_path.to_owned(), f)?;
-                        yield (id, metadata);
-                    }
+            self.flush_batch().await?;
+            // the next page is fetched while the entries of the current one are consumed
+            let pages = pages::prefetched(move |page_token| {
+                let drive = drive.clone();
+                let q = q.clone();
+                let progress = progress.clone();
+                async move {
+                    let file_list = drive.files_list(q, page_token, progress.as_ref()).await?;
+                    Ok((file_list.files, file_list.next_page_token))
                 }
-                if next_page_token.is_none() {
-                    break;
+            });
+            for await files in pages {
+                for f in files?.unwrap_or_default() {
+                    let id = f.id.clone().unwrap_or_default();
+                    self.record_sharing(&f);
+                    self.record_view_only(&f);
+                    self.record_content(&f).await;
+                    let metadata = map_file(parent_path.to_owned(), f)?;
+                    yield (id, metadata);
                 }
             }
         }
@@ -202,10 +499,52 @@ where
         progress: Option<&SharedProgress>,
     ) -> fsync::Result<impl io::AsyncRead> {
         log::trace!("reading file {id}");
-        Ok(self
+        if let Some(data) = self.cached_content(&id).await {
+            return Ok(Either::Left(std::io::Cursor::new(data)));
+        }
+        self.flush_batch().await?;
+        match self
             .files_get_media(id.as_str(), progress)
-            .await?
-            .expect("Could not find file"))
+            .await
+            .map_err(|err| self.refused_download(err, &id))?
+        {
+            Some(read) => Ok(Either::Right(self.tee_content(&id, read))),
+            None => fsync::io_bail!("Could not find file {id}"),
+        }
+    }
+}
+
+impl<A> super::id::ReadFileRange for GoogleDrive<A>
+where
+    A: GetToken,
+{
+    async fn read_file_range(
+        &self,
+        id: IdBuf,
+        offset: u64,
+        len: u64,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead> {
+        log::trace!(
+            "reading file {id} from {offset} to {}",
+            offset.saturating_add(len)
+        );
+        if let Some(mut data) = self.cached_content(&id).await {
+            let start = offset.min(data.len() as u64) as usize;
+            let end = offset.saturating_add(len).min(data.len() as u64) as usize;
+            data.truncate(end);
+            data.drain(..start);
+            return Ok(Either::Left(std::io::Cursor::new(data)));
+        }
+        self.flush_batch().await?;
+        match self
+            .files_get_media_range(id.as_str(), offset, len, progress)
+            .await
+            .map_err(|err| self.refused_download(err, &id))?
+        {
+            Some(read) => Ok(Either::Right(read)),
+            None => fsync::io_bail!("Could not find file {id}"),
+        }
     }
 }
 
@@ -231,9 +570,13 @@ where
             size: None,
             mime_type: Some(FOLDER_MIMETYPE.to_string()),
             parents: parent_id.map(|id| vec![id.to_id_buf()]),
+            shared: None,
+            permissions: None,
+            capabilities: None,
+            web_view_link: None,
+            md5_checksum: None,
         };
-        let res = self.files_create(&f, progress).await?;
-        Ok(res.id.context("No ID returned")?)
+        self.queue_create(f, progress).await
     }
 }
 
@@ -254,6 +597,7 @@ where
             metadata.path(),
             metadata.size().unwrap()
         );
+        self.flush_batch().await?;
         let file = map_metadata(parent_id, None, metadata);
         let file = self
             .files_upload(
@@ -264,7 +608,10 @@ where
                 progress,
             )
             .await?;
+        self.add_uploaded(metadata.size().unwrap());
         let id = file.id.clone().unwrap_or_default();
+        self.record_sharing(&file);
+        self.record_content(&file).await;
         let metadata = map_file(metadata.path().parent().unwrap().to_owned(), file)?;
         Ok((id, metadata))
     }
@@ -288,6 +635,7 @@ where
             metadata.path(),
             metadata.size().unwrap()
         );
+        self.flush_batch().await?;
         let file = map_metadata(parent_id, Some(id), metadata);
         let file = self
             .files_upload(
@@ -298,10 +646,36 @@ where
                 progress,
             )
             .await?;
+        self.add_uploaded(metadata.size().unwrap());
+        self.record_sharing(&file);
+        self.record_content(&file).await;
         map_file(metadata.path().parent().unwrap().to_owned(), file)
     }
 }
 
+impl<A> super::id::PatchMetadata for GoogleDrive<A>
+where
+    A: GetToken,
+{
+    async fn patch(
+        &self,
+        id: &Id,
+        path: &Path,
+        patch: &super::MetadataPatch,
+    ) -> fsync::Result<fsync::Metadata> {
+        debug_assert!(path.is_absolute() && !path.is_root());
+        log::info!("patching the metadata of {path}");
+        self.flush_batch().await?;
+        let body = api::FilePatch {
+            modified_time: patch.mtime,
+        };
+        let file = self.files_update(id, &body, None).await?;
+        self.record_sharing(&file);
+        self.record_content(&file).await;
+        map_file(path.parent().unwrap().to_owned(), file)
+    }
+}
+
 impl<A> super::id::CopyFile for GoogleDrive<A>
 where
     A: GetToken,
@@ -325,10 +699,18 @@ where
             size: None,
             mime_type: None,
             parents: dest_parent_id.map(|id| vec![id.to_id_buf()]),
+            shared: None,
+            permissions: None,
+            capabilities: None,
+            web_view_link: None,
+            md5_checksum: None,
         };
 
+        self.flush_batch().await?;
         let file = self.files_copy(src_id, &dest_file, progress).await?;
         let id = file.id.clone().unwrap_or_default();
+        self.record_sharing(&file);
+        self.record_content(&file).await;
         let metadata = map_file(
             dest_path
                 .parent()
@@ -345,7 +727,35 @@ where
     A: GetToken,
 {
     async fn delete(&self, id: &Id, progress: Option<&SharedProgress>) -> fsync::Result<()> {
-        self.files_delete(id, progress).await
+        self.queue_delete(id, progress).await
+    }
+}
+
+impl<A> super::Quota for GoogleDrive<A>
+where
+    A: GetToken,
+{
+    async fn quota(&self) -> fsync::Result<Option<fsync::stat::Quota>> {
+        let stale = self.quota.lock().unwrap().is_stale();
+        if stale {
+            log::trace!("refreshing Drive quota");
+            let about = self.about_get().await?;
+            *self.quota.lock().unwrap() = QuotaCache::new(about.storage_quota);
+        }
+        let cache = self.quota.lock().unwrap();
+        Ok(cache.quota.usage.map(|usage| fsync::stat::Quota {
+            usage,
+            limit: cache.quota.limit,
+        }))
+    }
+}
+
+impl<A> super::Flush for GoogleDrive<A>
+where
+    A: GetToken,
+{
+    async fn flush(&self) -> fsync::Result<()> {
+        self.flush_batch().await
     }
 }
 
@@ -358,68 +768,292 @@ where
     }
 }
 
+impl<A> super::id::Shared for GoogleDrive<A>
+where
+    A: GetToken,
+{
+    fn sharing(&self, id: &Id) -> Option<fsync::Sharing> {
+        self.sharing.lock().unwrap().get(id).copied()
+    }
+
+    fn view_only(&self, id: &Id) -> Option<fsync::ViewOnly> {
+        self.view_only.lock().unwrap().get(id).cloned()
+    }
+
+    async fn link(&self, id: &Id) -> fsync::Result<fsync::RemoteLink> {
+        if let Some(link) = self.links.lock().unwrap().get(id) {
+            return Ok(link.clone());
+        }
+        log::trace!("getting the links of {id}");
+        let Some(links) = self.files_get_links(id).await? else {
+            fsync::io_bail!("Could not find file {id}");
+        };
+        let Some(web_view) = links.web_view_link else {
+            fsync::api_bail!("No link to open the file {id}");
+        };
+        let link = fsync::RemoteLink {
+            web_view,
+            web_content: links.web_content_link,
+        };
+        self.links
+            .lock()
+            .unwrap()
+            .insert(id.to_id_buf(), link.clone());
+        Ok(link)
+    }
+
+    async fn share_public(&self, id: &Id) -> fsync::Result<fsync::RemoteLink> {
+        log::trace!("sharing {id} with anyone with the link");
+        let permission = api::Permission {
+            typ: "anyone".into(),
+            role: "reader".into(),
+        };
+        self.permissions_create(id, &permission).await?;
+        if self.fetch_sharing {
+            let mut sharing = self.sharing.lock().unwrap();
+            let sharing = sharing.entry(id.to_id_buf()).or_default();
+            sharing.anyone = sharing.anyone.max(Some(fsync::SharingRole::Reader));
+        }
+        self.link(id).await
+    }
+}
+
+impl<A> super::id::Revisions for GoogleDrive<A>
+where
+    A: GetToken,
+{
+    async fn revisions(&self, id: &Id) -> fsync::Result<Vec<fsync::Revision>> {
+        log::trace!("listing revisions of {id}");
+        let mut revisions = Vec::new();
+        let mut page_token = None;
+        loop {
+            let list = self.revisions_list(id, page_token).await?;
+            for revision in list.revisions.unwrap_or_default() {
+                revisions.push(map_revision(revision)?);
+            }
+            page_token = list.next_page_token;
+            if page_token.is_none() {
+                break Ok(revisions);
+            }
+        }
+    }
+
+    async fn read_revision(
+        &self,
+        id: IdBuf,
+        revision_id: &str,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead + Send> {
+        log::trace!("reading revision {revision_id} of file {id}");
+        match self
+            .revisions_get_media(&id, revision_id, progress)
+            .await
+            .map_err(|err| self.refused_download(err, &id))?
+        {
+            Some(read) => Ok(read),
+            None => fsync::io_bail!("Could not find revision {revision_id} of file {id}"),
+        }
+    }
+}
+
+/// The checksums are the ones last seen in the responses of Drive if the content is cached,
+/// and are requested otherwise. Google Docs and other files without content have none.
+impl<A> super::id::Checksums for GoogleDrive<A>
+where
+    A: GetToken,
+{
+    fn known_md5(&self, id: &Id) -> Option<String> {
+        self.content.as_ref()?.md5(id)
+    }
+
+    async fn fetch_md5(&self, id: &Id) -> fsync::Result<Option<String>> {
+        log::trace!("getting checksum of {id}");
+        let Some(file) = self.files_get(id, None).await? else {
+            fsync::io_bail!("Could not find file {id}");
+        };
+        self.record_content(&file).await;
+        Ok(file.md5_checksum)
+    }
+}
+
 impl<A> Shutdown for GoogleDrive<A>
 where
-    A: PersistCache + Send + Sync,
+    A: GetToken + PersistCache,
 {
     async fn shutdown(&self) -> anyhow::Result<()> {
+        self.flush_batch().await?;
         self.persist_cache().await
     }
 }
 
+/// Drive lists files of the same name in a folder, of which only the first one is cached.
+/// The changes are not followed, so the persisted cache is refreshed by listing the folders again.
+impl<A> super::cache::Provider for GoogleDrive<A> {
+    const DUPLICATE_NAMES: super::cache::DuplicateNames = super::cache::DuplicateNames::KeepFirst;
+
+    fn valid_id(id: &Id) -> bool {
+        let id = id.as_str();
+        !id.is_empty()
+            && id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    }
+}
+
 impl<A> super::id::Storage for GoogleDrive<A> where A: Clone + GetToken + PersistCache {}
 
 const FOLDER_MIMETYPE: &str = "application/vnd.google-apps.folder";
+/// Prefix of the Google Docs, Sheets, etc. mime types, which have no size
+const GOOGLE_APPS_MIMETYPE_PREFIX: &str = "application/vnd.google-apps.";
+
+/// Name given to the files that have an empty or no name in Drive.
+/// The id makes it unique in the folder, and the file is still addressed by id.
+fn placeholder_name(id: &str) -> String {
+    format!("(unnamed-{id})")
+}
 
 fn map_file(parent_path: PathBuf, f: api::File) -> fsync::Result<fsync::Metadata> {
-    let path = parent_path.join(f.name.as_deref().unwrap());
-    let metadata = if f.mime_type.as_deref() == Some(FOLDER_MIMETYPE) {
+    let id = f.id.as_deref().map(Id::as_str).unwrap_or_default();
+    let path = match f.name.as_deref() {
+        Some(name) if !name.is_empty() => parent_path.join(name),
+        _ => {
+            let name = placeholder_name(id);
+            log::debug!("file {id} in {parent_path} has no name, mapped to \"{name}\"");
+            parent_path.join(name)
+        }
+    };
+    let mime_type = f.mime_type.as_deref();
+    let metadata = if mime_type == Some(FOLDER_MIMETYPE) {
         fsync::Metadata::Directory { path, stat: None }
     } else {
         let mtime = f.modified_time.ok_or_else(|| {
-            fsync::api_error!("Expected to receive modifiedTime from Google for {path}")
+            fsync::api_error!("Expected to receive modifiedTime from Google for {path} (file {id})")
         })?;
-        let size = f
-            .size
-            .ok_or_else(|| fsync::api_error!("Expected to receive size from Google for {path}"))?
-            as _;
-        fsync::Metadata::Regular { path, size, mtime }
+        let size = match f.size {
+            Some(size) if size < 0 => {
+                // kept out of range, so that the stats flag it as implausible
+                log::warn!("negative size {size} received from Google for {path}");
+                u64::MAX
+            }
+            Some(size) => size as _,
+            None if mime_type.is_some_and(|mt| mt.starts_with(GOOGLE_APPS_MIMETYPE_PREFIX)) => {
+                fsync::api_bail!("Expected to receive size from Google for {path} (file {id})")
+            }
+            None => {
+                log::debug!("no size received from Google for {path}, assuming empty file");
+                0
+            }
+        };
+        fsync::Metadata::Regular {
+            path,
+            size,
+            mtime,
+            link_target: None,
+        }
     };
     Ok(metadata)
 }
 
+/// Summarize the permissions of `f`, or `None` if it is not shared or its permissions were not requested
+fn map_revision(revision: api::Revision) -> fsync::Result<fsync::Revision> {
+    let Some(mtime) = revision.modified_time else {
+        fsync::api_bail!("Revision {} without modification time", revision.id);
+    };
+    Ok(fsync::Revision {
+        id: revision.id,
+        mtime,
+        size: revision.size.map(|size| size as u64),
+        author: revision
+            .last_modifying_user
+            .and_then(|user| user.display_name),
+    })
+}
+
+fn map_sharing(f: &api::File) -> Option<fsync::Sharing> {
+    if f.shared == Some(false) {
+        return None;
+    }
+    let mut sharing = fsync::Sharing::default();
+    for perm in f.permissions.iter().flatten() {
+        let role = match perm.role.as_str() {
+            "owner" => continue,
+            "reader" => fsync::SharingRole::Reader,
+            "commenter" => fsync::SharingRole::Commenter,
+            "writer" | "fileOrganizer" | "organizer" => fsync::SharingRole::Writer,
+            // absent or introduced after this was written: the least privileged
+            role => {
+                log::debug!("unknown permission role \"{role}\"");
+                fsync::SharingRole::Reader
+            }
+        };
+        match perm.typ.as_str() {
+            "anyone" => sharing.anyone = sharing.anyone.max(Some(role)),
+            "domain" => sharing.domain = sharing.domain.max(Some(role)),
+            _ => sharing.users += 1,
+        }
+    }
+    (sharing != fsync::Sharing::default()).then_some(sharing)
+}
+
 fn map_metadata(parent_id: Option<&Id>, id: Option<&Id>, metadata: &fsync::Metadata) -> api::File {
     let mime_type = match metadata {
         fsync::Metadata::Directory { .. } => Some(FOLDER_MIMETYPE.to_string()),
         _ => None,
     };
     let parents = parent_id.map(|id| vec![id.to_owned()]);
+    // the placeholder of an unnamed file is not written back to Drive
+    let name = metadata.name();
+    let name = match id {
+        Some(id) if name == placeholder_name(id.as_str()) => None,
+        _ => Some(name.to_owned()),
+    };
     api::File {
         id: id.map(ToOwned::to_owned),
-        name: Some(metadata.name().to_owned()),
+        name,
         size: None,
         modified_time: metadata.mtime(),
         mime_type,
         parents,
+        shared: None,
+        permissions: None,
+        capabilities: None,
+        web_view_link: None,
+        md5_checksum: None,
     }
 }
 
 mod api {
+    use std::time::Instant;
+
     use chrono::{DateTime, Utc};
     use http::StatusCode;
     use serde::{Deserialize, Serialize};
     use tokio::io;
 
-    use super::utils::{check_response, num_from_str, num_to_str};
+    use http::header;
+
+    use super::{
+        upload,
+        utils::{
+            check_media_response, check_response, content_range_start, num_from_str, num_to_str,
+            parse_json,
+        },
+    };
     use crate::{
-        error,
+        buffers, error,
         oauth2::GetToken,
-        storage::id::{Id, IdBuf},
+        storage::{
+            id::{Id, IdBuf},
+            read_range,
+        },
         SharedProgress,
     };
 
+    // The structures of the API ignore the unknown fields, and default the fields
+    // that Google may omit, so that an evolution of the API doesn't break the listings.
+
     #[derive(Default, Clone, Debug, Deserialize, Serialize)]
-    #[serde(rename_all = "camelCase")]
+    #[serde(rename_all = "camelCase", default)]
     pub struct User {
         kind: String,
         pub display_name: String,
@@ -465,14 +1099,17 @@ mod api {
     const ABOUT_FIELDS: &str = "kind,storageQuota,user";
 
     #[derive(Default, Clone, Debug, Deserialize, Serialize)]
-    #[serde(rename_all = "camelCase")]
+    #[serde(rename_all = "camelCase", default)]
     pub struct About {
         kind: String,
         pub storage_quota: Quota,
         pub user: User,
     }
 
-    const FILE_FIELDS: &str = "id,name,size,modifiedTime,mimeType";
+    pub const FILE_FIELDS: &str =
+        "id,name,size,modifiedTime,mimeType,md5Checksum,capabilities(canDownload),webViewLink";
+    pub const FILE_FIELDS_WITH_SHARING: &str = "id,name,size,modifiedTime,mimeType,md5Checksum,\
+        capabilities(canDownload),webViewLink,shared,permissions(type,role)";
 
     #[derive(Default, Clone, Debug, Deserialize, Serialize)]
     #[serde(rename_all = "camelCase")]
@@ -489,6 +1126,44 @@ mod api {
         pub size: Option<i64>,
         pub mime_type: Option<String>,
         pub parents: Option<Vec<IdBuf>>,
+        #[serde(default, skip_serializing)]
+        pub shared: Option<bool>,
+        #[serde(default, skip_serializing)]
+        pub permissions: Option<Vec<Permission>>,
+        #[serde(default, skip_serializing)]
+        pub capabilities: Option<Capabilities>,
+        #[serde(default, skip_serializing)]
+        pub web_view_link: Option<String>,
+        #[serde(default, skip_serializing)]
+        pub md5_checksum: Option<String>,
+    }
+
+    /// The metadata changed by `files.update`, the fields left to `None` are unchanged
+    #[derive(Default, Clone, Debug, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct FilePatch {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub modified_time: Option<DateTime<Utc>>,
+    }
+
+    #[derive(Default, Clone, Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Capabilities {
+        pub can_download: Option<bool>,
+    }
+
+    #[derive(Default, Clone, Debug, Deserialize, Serialize)]
+    #[serde(default)]
+    pub struct Permission {
+        #[serde(rename = "type")]
+        pub typ: String,
+        pub role: String,
+    }
+
+    #[derive(Default, Clone, Debug, Deserialize)]
+    #[serde(rename_all = "camelCase", default)]
+    pub struct GeneratedIds {
+        pub ids: Vec<IdBuf>,
     }
 
     #[derive(Default, Clone, Debug, Deserialize)]
@@ -500,6 +1175,50 @@ mod api {
         pub next_page_token: Option<String>,
     }
 
+    pub const LINK_FIELDS: &str = "webViewLink,webContentLink";
+
+    /// The links to a file, requested apart from the listings
+    #[derive(Default, Clone, Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Links {
+        pub web_view_link: Option<String>,
+        pub web_content_link: Option<String>,
+    }
+
+    /// The parents of a folder, to walk up to the top of its drive
+    #[derive(Default, Clone, Debug, Deserialize)]
+    #[serde(default, rename_all = "camelCase")]
+    pub struct Parents {
+        pub id: Option<IdBuf>,
+        pub parents: Vec<IdBuf>,
+    }
+
+    pub const REVISION_FIELDS: &str =
+        "nextPageToken,revisions(id,modifiedTime,size,lastModifyingUser(displayName))";
+
+    #[derive(Default, Clone, Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Revision {
+        pub id: String,
+        pub modified_time: Option<DateTime<Utc>>,
+        #[serde(default, deserialize_with = "num_from_str")]
+        pub size: Option<i64>,
+        pub last_modifying_user: Option<RevisionUser>,
+    }
+
+    #[derive(Default, Clone, Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RevisionUser {
+        pub display_name: Option<String>,
+    }
+
+    #[derive(Default, Clone, Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RevisionList {
+        pub revisions: Option<Vec<Revision>>,
+        pub next_page_token: Option<String>,
+    }
+
     #[derive(Debug, Clone, Copy)]
     pub enum Scope {
         Full,
@@ -565,7 +1284,11 @@ mod api {
         }
     }
 
-    const UPLOAD_CHUNK_SZ: u64 = 2 * 256 * 1024;
+    /// Maximum number of files returned per page of `files.list`
+    const MAX_PAGE_SIZE: u32 = 1000;
+
+    /// Number of consecutive failures after which an upload is abandoned
+    const MAX_UPLOAD_RETRIES: u32 = 5;
 
     impl<A> super::GoogleDrive<A>
     where
@@ -578,8 +1301,8 @@ mod api {
             let res = self
                 .get_query(&[Scope::MetadataReadOnly], path, query_params, None)
                 .await?;
-            let res = check_response("GET", &path, res).await?;
-            let about: About = res.json().await.map_err(error::api)?;
+            let res = check_response("GET", path, res).await?;
+            let about: About = parse_json("about", res).await?;
             if about.kind != "drive#about" {
                 fsync::api_bail!("/about returned wrong kind!");
             }
@@ -596,8 +1319,12 @@ mod api {
 
             let mut query_params = vec![
                 ("q", q),
-                ("fields", format!("nextPageToken,files({FILE_FIELDS})")),
+                (
+                    "fields",
+                    format!("nextPageToken,files({})", self.file_fields()),
+                ),
                 ("alt", "json".into()),
+                ("pageSize", MAX_PAGE_SIZE.to_string()),
             ];
             if let Some(page_token) = page_token {
                 query_params.push(("pageToken", page_token));
@@ -610,41 +1337,217 @@ mod api {
             let res = self
                 .get_query(&[Scope::MetadataReadOnly], path, query_params, progress)
                 .await?;
-            let res = check_response("GET", &path, res).await?;
+            let res = check_response("GET", path, res).await?;
 
-            let file_list: FileList = res.json().await.map_err(error::api)?;
+            let file_list: FileList = parse_json("file list", res).await?;
 
             Ok(file_list)
         }
 
-        pub async fn files_get_media(
+        pub async fn files_get(
             &self,
-            file_id: &str,
+            file_id: &Id,
             progress: Option<&SharedProgress>,
-        ) -> fsync::Result<Option<impl io::AsyncRead>> {
-            use futures::stream::{StreamExt, TryStreamExt};
-
+        ) -> fsync::Result<Option<File>> {
             let path = format!("/files/{file_id}");
-            let query_params = &[("fields", FILE_FIELDS), ("alt", "media")];
+            let mut query_params = vec![("fields", self.file_fields())];
+            if self.shared {
+                query_params.push(("supportsAllDrives", "true"));
+            }
 
             let res = self
-                .get_query(&[Scope::Full], &path, query_params, progress)
+                .get_query(&[Scope::MetadataReadOnly], &path, query_params, progress)
                 .await?;
             if res.status() == StatusCode::NOT_FOUND {
                 return Ok(None);
             }
             let res = check_response("GET", &path, res).await?;
+            let file: File = parse_json("file", res).await?;
+            Ok(Some(file))
+        }
 
-            let bytes = res.bytes_stream().map(|res| {
-                res.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))
-            });
-            let read = bytes.into_async_read();
+        pub async fn files_get_links(&self, file_id: &Id) -> fsync::Result<Option<Links>> {
+            let path = format!("/files/{file_id}");
+            let mut query_params = vec![("fields", LINK_FIELDS)];
+            if self.shared {
+                query_params.push(("supportsAllDrives", "true"));
+            }
+
+            let res = self
+                .get_query(&[Scope::MetadataReadOnly], &path, query_params, None)
+                .await?;
+            if res.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let res = check_response("GET", &path, res).await?;
+            let links: Links = parse_json("file links", res).await?;
+            Ok(Some(links))
+        }
+
+        pub async fn files_get_parents(&self, file_id: &Id) -> fsync::Result<Option<Parents>> {
+            let path = format!("/files/{file_id}");
+            // the root may be in a shared drive even if the listings are not
+            let query_params = [("fields", "id,parents"), ("supportsAllDrives", "true")];
+
+            let res = self
+                .get_query(&[Scope::MetadataReadOnly], &path, query_params, None)
+                .await?;
+            if res.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let res = check_response("GET", &path, res).await?;
+            let parents: Parents = parse_json("file parents", res).await?;
+            Ok(Some(parents))
+        }
+
+        pub async fn permissions_create(
+            &self,
+            file_id: &Id,
+            permission: &Permission,
+        ) -> fsync::Result<()> {
+            let path = format!("/files/{file_id}/permissions");
+            let mut query_params = vec![("fields", "id")];
+            if self.shared {
+                query_params.push(("supportsAllDrives", "true"));
+            }
+            let res = self
+                .post_json_query(&[Scope::Full], &path, query_params, permission, None)
+                .await?;
+            check_response("POST", &path, res).await?;
+            Ok(())
+        }
+
+        pub async fn files_get_media(
+            &self,
+            file_id: &str,
+            progress: Option<&SharedProgress>,
+        ) -> fsync::Result<Option<impl io::AsyncRead>> {
+            use futures::stream::{StreamExt, TryStreamExt};
+
+            let path = format!("/files/{file_id}");
+            let query_params = &[("fields", FILE_FIELDS), ("alt", "media")];
+
+            let res = self
+                .get_query(&[Scope::Full], &path, query_params, progress)
+                .await?;
+            if res.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let res = check_media_response(&path, res).await?;
+
+            let bytes = res
+                .bytes_stream()
+                .map(|res| res.map_err(|err| std::io::Error::other(err.to_string())));
+            let read = bytes.into_async_read();
+
+            Ok(Some(tokio_util::compat::FuturesAsyncReadCompatExt::compat(
+                read,
+            )))
+        }
+
+        pub async fn revisions_list(
+            &self,
+            file_id: &Id,
+            page_token: Option<String>,
+        ) -> fsync::Result<RevisionList> {
+            let path = format!("/files/{file_id}/revisions");
+            let mut query_params = vec![
+                ("fields", REVISION_FIELDS.to_string()),
+                ("pageSize", MAX_PAGE_SIZE.to_string()),
+            ];
+            if let Some(page_token) = page_token {
+                query_params.push(("pageToken", page_token));
+            }
+
+            let res = self
+                .get_query(&[Scope::MetadataReadOnly], &path, query_params, None)
+                .await?;
+            let res = check_response("GET", &path, res).await?;
+            let list: RevisionList = parse_json("revision list", res).await?;
+            Ok(list)
+        }
+
+        pub async fn revisions_get_media(
+            &self,
+            file_id: &Id,
+            revision_id: &str,
+            progress: Option<&SharedProgress>,
+        ) -> fsync::Result<Option<impl io::AsyncRead>> {
+            use futures::stream::{StreamExt, TryStreamExt};
+
+            let path = format!("/files/{file_id}/revisions/{revision_id}");
+            let query_params = &[("alt", "media")];
+
+            let res = self
+                .get_query(&[Scope::Full], &path, query_params, progress)
+                .await?;
+            if res.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let res = check_media_response(&path, res).await?;
+
+            let bytes = res
+                .bytes_stream()
+                .map(|res| res.map_err(|err| std::io::Error::other(err.to_string())));
+            let read = bytes.into_async_read();
 
             Ok(Some(tokio_util::compat::FuturesAsyncReadCompatExt::compat(
                 read,
             )))
         }
 
+        /// Get at most `len` bytes of the file content, starting at `offset`.
+        /// If the server ignores the `Range` header and sends the whole content,
+        /// the bytes before `offset` are skipped.
+        pub async fn files_get_media_range(
+            &self,
+            file_id: &str,
+            offset: u64,
+            len: u64,
+            progress: Option<&SharedProgress>,
+        ) -> fsync::Result<Option<impl io::AsyncRead + Send>> {
+            use futures::stream::{StreamExt, TryStreamExt};
+
+            let path = format!("/files/{file_id}");
+            let query_params = &[("fields", FILE_FIELDS), ("alt", "media")];
+            // an empty range can't be expressed, the response is truncated instead
+            let last = offset.saturating_add(len.max(1)) - 1;
+            let range = format!("bytes={offset}-{last}");
+
+            let res = self
+                .get_query_with_headers(
+                    &[Scope::Full],
+                    &path,
+                    query_params,
+                    &[(header::RANGE, range.as_str())],
+                    progress,
+                )
+                .await?;
+            let (res, skip, len) = match res.status() {
+                StatusCode::NOT_FOUND => return Ok(None),
+                // the offset is past the end of the file
+                StatusCode::RANGE_NOT_SATISFIABLE => (res, 0, 0),
+                StatusCode::PARTIAL_CONTENT => {
+                    if content_range_start(&res) != Some(offset) {
+                        fsync::api_bail!(
+                            "GET {path} returned range {:?} instead of {range}",
+                            res.headers().get(header::CONTENT_RANGE)
+                        );
+                    }
+                    (res, 0, len)
+                }
+                _ => (check_media_response(&path, res).await?, offset, len),
+            };
+
+            let bytes = res
+                .bytes_stream()
+                .map(|res| res.map_err(|err| std::io::Error::other(err.to_string())));
+            let read =
+                tokio_util::compat::FuturesAsyncReadCompatExt::compat(bytes.into_async_read());
+
+            Ok(Some(read_range(read, skip, len).await?))
+        }
+
         pub async fn files_copy(
             &self,
             id: &Id,
@@ -653,7 +1556,7 @@ mod api {
         ) -> fsync::Result<File> {
             let scopes = &[Scope::Full];
             let path = format!("/files/{id}/copy");
-            let mut query_params = vec![("fields", FILE_FIELDS)];
+            let mut query_params = vec![("fields", self.file_fields())];
             if self.shared {
                 query_params.push(("supportsAllDrives", "true"));
             }
@@ -662,7 +1565,7 @@ mod api {
                 .await?;
             let res = check_response("POST", &path, res).await?;
 
-            let file: File = res.json().await.map_err(error::api)?;
+            let file: File = parse_json("file", res).await?;
             Ok(file)
         }
 
@@ -673,7 +1576,7 @@ mod api {
         ) -> fsync::Result<File> {
             let scopes = &[Scope::Full];
             let path = "/files";
-            let mut query_params = vec![("fields", FILE_FIELDS)];
+            let mut query_params = vec![("fields", self.file_fields())];
             if self.shared {
                 query_params.push(("supportsAllDrives", "true"));
             }
@@ -682,7 +1585,30 @@ mod api {
                 .await?;
             let res = check_response("POST", path, res).await?;
 
-            let file: File = res.json().await.map_err(error::api)?;
+            let file: File = parse_json("file", res).await?;
+            Ok(file)
+        }
+
+        /// Update the metadata of the file `id` with the fields set in `patch`,
+        /// without its content
+        pub async fn files_update(
+            &self,
+            id: &Id,
+            patch: &FilePatch,
+            progress: Option<&SharedProgress>,
+        ) -> fsync::Result<File> {
+            let scopes = &[Scope::Full];
+            let path = format!("/files/{id}");
+            let mut query_params = vec![("fields", self.file_fields())];
+            if self.shared {
+                query_params.push(("supportsAllDrives", "true"));
+            }
+            let res = self
+                .patch_json_query(scopes, &path, query_params, patch, progress)
+                .await?;
+            let res = check_response("PATCH", &path, res).await?;
+
+            let file: File = parse_json("file", res).await?;
             Ok(file)
         }
 
@@ -704,7 +1630,7 @@ mod api {
                 typ: UploadType::Resumable,
                 size: file.size.map(|sz| sz as _),
                 mime_type: file.mime_type.as_deref(),
-                fields: FILE_FIELDS,
+                fields: self.file_fields(),
                 supports_all_drives: self.shared,
             };
             let upload_url = self
@@ -720,39 +1646,114 @@ mod api {
 
             tokio::pin!(data);
 
+            let mut sizer = upload::ChunkSizer::new(self.max_chunk_size);
+            // covers the bytes read from `data` and the copy of the chunk being sent
+            let mut lease =
+                buffers::lease(self.buffers.as_ref(), 2 * sizer.size().min(data_len)).await;
+            // the bytes read from `data` and not yet received by Drive
+            let mut buf: Vec<u8> = Vec::new();
             let mut sent = 0u64;
+            let mut retransmitted = 0u64;
+            let mut failures = 0;
             let file: File = loop {
-                let mut buf: Vec<u8> = Vec::with_capacity(UPLOAD_CHUNK_SZ as _);
-                let sz = data
-                    .as_mut()
-                    .take(UPLOAD_CHUNK_SZ)
-                    .read_to_end(&mut buf)
-                    .await?;
-                log::trace!("uploading {sz} bytes");
+                if !lease.try_grow(2 * sizer.size().min(data_len)) {
+                    // the chunks don't grow while the budget is exhausted
+                    sizer.clamp(lease.bytes() / 2);
+                }
+                let chunk_sz = sizer.size();
+                // read in place, as `read_to_end` would grow the buffer beyond the lease
+                while (buf.len() as u64) < chunk_sz {
+                    let missing = chunk_sz - buf.len() as u64;
+                    buf.reserve_exact(missing as usize);
+                    if data.as_mut().take(missing).read_buf(&mut buf).await? == 0 {
+                        break;
+                    }
+                }
+                let len = buf.len().min(chunk_sz as usize);
+                log::trace!("uploading {len} bytes");
+                let start = Instant::now();
                 let res = self
                     .upload_range(
                         method.clone(),
                         scopes,
                         upload_url.clone(),
-                        buf,
+                        buf[..len].to_vec(),
                         sent,
                         data_len,
                         progress,
                     )
-                    .await?;
-                sent += sz as u64;
-                let status = res.status();
-                if status.is_success() && sent == data_len {
-                    break res.json().await.map_err(error::api)?;
-                } else if status.is_server_error() {
-                    fsync::api_bail!("Upload failed ({status}). No support yet to resume upload");
-                } else if res.status().is_client_error() {
-                    fsync::other_bail!(
-                        "fsyncd bug!! Bad request ({status}): {}",
-                        String::from_utf8_lossy(&res.bytes().await.unwrap())
+                    .await;
+                let elapsed = start.elapsed();
+
+                let failure = match &res {
+                    Ok(res) if res.status().is_server_error() => Some(res.status().to_string()),
+                    Err(err) if is_timeout(err) => Some("timeout".to_string()),
+                    _ => None,
+                };
+                let received = if let Some(failure) = failure {
+                    failures += 1;
+                    if failures > MAX_UPLOAD_RETRIES {
+                        fsync::api_bail!(
+                            "Upload failed after {MAX_UPLOAD_RETRIES} retries ({failure})"
+                        );
+                    }
+                    sizer.on_failure();
+                    log::warn!(
+                        "Upload chunk failed ({failure}), retrying with chunks of {} bytes",
+                        sizer.size()
+                    );
+                    let res = self
+                        .upload_status(scopes, upload_url.clone(), data_len, progress)
+                        .await?;
+                    match upload_progress(res).await? {
+                        UploadProgress::Complete(file) => break file,
+                        UploadProgress::Received(received) => received,
+                    }
+                } else {
+                    let res = res?;
+                    let status = res.status();
+                    if status.is_client_error() {
+                        // the body only details the error, it is left out if it can't be read
+                        let body = res.bytes().await.unwrap_or_default();
+                        fsync::other_bail!(
+                            "fsyncd bug!! Bad request ({status}): {}",
+                            String::from_utf8_lossy(&body)
+                        );
+                    }
+                    match upload_progress(res).await? {
+                        UploadProgress::Complete(file) => break file,
+                        UploadProgress::Received(received) => {
+                            if failures == 0 {
+                                sizer.on_success(len as u64, elapsed);
+                            }
+                            failures = 0;
+                            received
+                        }
+                    }
+                };
+
+                if received < sent || received > sent + len as u64 {
+                    fsync::api_bail!(
+                        "Upload is inconsistent: {received} bytes received, {sent} confirmed before"
                     );
                 }
+                let consumed = (received - sent) as usize;
+                retransmitted += (len - consumed) as u64;
+                buf.drain(..consumed);
+                sent = received;
             };
+
+            log::debug!(
+                "Uploaded {data_len} bytes with chunks of up to {} bytes, {retransmitted} bytes retransmitted",
+                sizer.size()
+            );
+            self.upload_stats
+                .lock()
+                .unwrap()
+                .record(data_len, retransmitted, sizer.size());
+            if let Some(progress) = progress {
+                progress.add_retransmitted(retransmitted);
+            }
             Ok(file)
         }
 
@@ -774,6 +1775,57 @@ mod api {
             check_response("DELETE", &path, res).await?;
             Ok(())
         }
+
+        /// Generate `count` ids to be set on files created later
+        pub async fn files_generate_ids(
+            &self,
+            count: usize,
+            progress: Option<&SharedProgress>,
+        ) -> fsync::Result<Vec<IdBuf>> {
+            let path = "/files/generateIds";
+            let count = count.to_string();
+            let query_params = vec![("count", count.as_str()), ("space", "drive")];
+            let res = self
+                .get_query(&[Scope::Full], path, query_params, progress)
+                .await?;
+            let res = check_response("GET", path, res).await?;
+            let generated: GeneratedIds = parse_json("generated ids", res).await?;
+            Ok(generated.ids)
+        }
+    }
+
+    /// The state of a resumable upload after a chunk or a status query
+    enum UploadProgress {
+        Complete(File),
+        /// Number of bytes received so far
+        Received(u64),
+    }
+
+    async fn upload_progress(res: reqwest::Response) -> fsync::Result<UploadProgress> {
+        let status = res.status();
+        if status.is_success() {
+            let file = parse_json("uploaded file", res).await?;
+            return Ok(UploadProgress::Complete(file));
+        }
+        if status != StatusCode::PERMANENT_REDIRECT {
+            fsync::api_bail!(
+                "Upload returned {status}\n{}",
+                res.text().await.map_err(error::io)?
+            );
+        }
+        let range = res
+            .headers()
+            .get(reqwest::header::RANGE)
+            .and_then(|range| range.to_str().ok());
+        match upload::received_bytes(range) {
+            Some(received) => Ok(UploadProgress::Received(received)),
+            None => fsync::api_bail!("Upload returned an invalid range: {range:?}"),
+        }
+    }
+
+    fn is_timeout(err: &anyhow::Error) -> bool {
+        err.downcast_ref::<reqwest::Error>()
+            .is_some_and(|err| err.is_timeout())
     }
 }
 
@@ -781,11 +1833,29 @@ mod utils {
     use std::borrow::Borrow;
 
     use oauth2::AccessToken;
-    use reqwest::{header, Response, StatusCode, Url};
-    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use reqwest::{header, RequestBuilder, Response, StatusCode, Url};
+    use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
 
     use super::api;
-    use crate::{error, oauth2::GetToken, SharedProgress};
+    use crate::{
+        error,
+        oauth2::GetToken,
+        profile::{Phase, Span},
+        SharedProgress,
+    };
+
+    /// Send `req`, measured until the response headers.
+    /// `bytes` is the length of the uploaded body.
+    async fn send(
+        req: RequestBuilder,
+        detail: impl FnOnce() -> String,
+        bytes: u64,
+    ) -> fsync::Result<Response> {
+        let _span = Span::enter(Phase::DriveHttp)
+            .with_detail(detail)
+            .with_bytes(bytes);
+        req.send().await.map_err(error::request)
+    }
 
     pub fn num_to_str<S>(value: &Option<i64>, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -796,14 +1866,106 @@ mod utils {
         serializer.serialize_str(&value)
     }
 
+    /// Deserialize a 64-bit integer, that Google sends as a string.
+    /// A plain number is accepted as well, and `null` or an empty string are `None`.
     pub fn num_from_str<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        use std::str::FromStr;
+        use serde::de::{self, Unexpected, Visitor};
+
+        struct NumVisitor;
+
+        impl<'de> Visitor<'de> for NumVisitor {
+            type Value = Option<i64>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an integer, or a string of an integer")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                let v = v.trim();
+                if v.is_empty() {
+                    return Ok(None);
+                }
+                v.parse()
+                    .map(Some)
+                    .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                Ok(Some(v))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                i64::try_from(v)
+                    .map(Some)
+                    .map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+                Ok(None)
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+                Ok(None)
+            }
+
+            fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                deserializer.deserialize_any(self)
+            }
+        }
+
+        deserializer.deserialize_any(NumVisitor)
+    }
 
-        let s = String::deserialize(deserializer)?;
-        Ok(Some(i64::from_str(&s).map_err(serde::de::Error::custom)?))
+    /// Parse the JSON body of `res`, a `what` of the API
+    pub async fn parse_json<T: DeserializeOwned>(what: &str, res: Response) -> fsync::Result<T> {
+        let body = res.text().await.map_err(error::io)?;
+        from_json(what, &body)
+    }
+
+    /// Parse `body`, a `what` of the API.
+    /// A failure names the field that could not be parsed, and the id of the file it belongs to.
+    pub fn from_json<T: DeserializeOwned>(what: &str, body: &str) -> fsync::Result<T> {
+        let de = &mut serde_json::Deserializer::from_str(body);
+        serde_path_to_error::deserialize(de).map_err(|err| {
+            let file = offending_id(body, err.path())
+                .map(|id| format!(" of file {id}"))
+                .unwrap_or_default();
+            fsync::api_error!(
+                "could not parse the {what} received from Google: field `{}`{file}: {}",
+                err.path(),
+                err.inner()
+            )
+        })
+    }
+
+    /// The id of the innermost object along `path` in the JSON `body` that has one
+    fn offending_id(body: &str, path: &serde_path_to_error::Path) -> Option<String> {
+        use serde_path_to_error::Segment;
+
+        let root: serde_json::Value = serde_json::from_str(body).ok()?;
+        let mut value = &root;
+        let mut id = None;
+        for segment in path.iter() {
+            if let Some(found) = value.get("id").and_then(serde_json::Value::as_str) {
+                id = Some(found);
+            }
+            let next = match segment {
+                Segment::Seq { index } => value.get(*index),
+                Segment::Map { key } => value.get(key.as_str()),
+                _ => None,
+            };
+            match next {
+                Some(next) => value = next,
+                None => break,
+            }
+        }
+        id.map(str::to_owned)
     }
 
     pub async fn check_response(
@@ -811,14 +1973,69 @@ mod utils {
         path: &str,
         res: Response,
     ) -> fsync::Result<Response> {
-        if !res.status().is_success() {
-            fsync::api_bail!(
-                "{method} {path} returned {}\n{}",
-                res.status(),
-                res.text().await.map_err(error::io)?
-            );
+        let status = res.status();
+        if status.is_success() {
+            return Ok(res);
+        }
+        let msg = format!(
+            "{method} {path} returned {status}\n{}",
+            res.text().await.map_err(error::io)?
+        );
+        let retry_later = status.is_server_error()
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::REQUEST_TIMEOUT;
+        if retry_later {
+            Err(fsync::Error::Unavailable(msg))
+        } else {
+            Err(fsync::Error::Api(msg))
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct ErrorBody {
+        error: ErrorDetail,
+    }
+
+    #[derive(Deserialize)]
+    struct ErrorDetail {
+        #[serde(default)]
+        errors: Vec<ErrorItem>,
+    }
+
+    #[derive(Deserialize)]
+    struct ErrorItem {
+        reason: String,
+    }
+
+    /// Same as [`check_response`] for the download of a file, which fails with
+    /// [`fsync::Error::NotDownloadable`] if the user is only allowed to view the file
+    pub async fn check_media_response(path: &str, res: Response) -> fsync::Result<Response> {
+        if res.status() != StatusCode::FORBIDDEN {
+            return check_response("GET", path, res).await;
         }
-        Ok(res)
+        let status = res.status();
+        let body = res.text().await.map_err(error::io)?;
+        let cannot_download = serde_json::from_str::<ErrorBody>(&body).is_ok_and(|body| {
+            body.error
+                .errors
+                .iter()
+                .any(|err| err.reason == "cannotDownloadFile")
+        });
+        if cannot_download {
+            Err(fsync::Error::NotDownloadable {
+                path: path.into(),
+                web_view_link: None,
+            })
+        } else {
+            fsync::api_bail!("GET {path} returned {status}\n{body}")
+        }
+    }
+
+    /// The first byte of a partial response, from its `Content-Range` header
+    pub fn content_range_start(res: &Response) -> Option<u64> {
+        let range = res.headers().get(header::CONTENT_RANGE)?.to_str().ok()?;
+        let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+        start.parse().ok()
     }
 
     impl<A> super::GoogleDrive<A>
@@ -831,7 +2048,7 @@ mod utils {
             progress: Option<&SharedProgress>,
         ) -> fsync::Result<AccessToken> {
             let scopes = scopes.iter().map(|&s| s.into()).collect();
-            Ok(self.auth.get_token(scopes, progress).await?)
+            self.auth.get_token(scopes, progress).await
         }
 
         pub async fn get_query<Q, K, V>(
@@ -841,6 +2058,24 @@ mod utils {
             query_params: Q,
             progress: Option<&SharedProgress>,
         ) -> fsync::Result<Response>
+        where
+            Q: IntoIterator,
+            Q::Item: Borrow<(K, V)>,
+            K: AsRef<str>,
+            V: AsRef<str>,
+        {
+            self.get_query_with_headers(scopes, path, query_params, &[], progress)
+                .await
+        }
+
+        pub async fn get_query_with_headers<Q, K, V>(
+            &self,
+            scopes: &[api::Scope],
+            path: &str,
+            query_params: Q,
+            headers: &[(header::HeaderName, &str)],
+            progress: Option<&SharedProgress>,
+        ) -> fsync::Result<Response>
         where
             Q: IntoIterator,
             Q::Item: Borrow<(K, V)>,
@@ -850,14 +2085,15 @@ mod utils {
             let token = self.fetch_token(scopes, progress).await?;
             let url = url_with_query(self.base_url, path, query_params);
 
-            let res = self
+            let mut req = self
                 .client
                 .get(url.clone())
                 .header(header::USER_AGENT, &self.user_agent)
-                .bearer_auth(token.secret())
-                .send()
-                .await
-                .map_err(error::api)?;
+                .bearer_auth(token.secret());
+            for (name, value) in headers {
+                req = req.header(name, *value);
+            }
+            let res = send(req, || format!("GET {path}"), 0).await?;
 
             Ok(res)
         }
@@ -879,19 +2115,46 @@ mod utils {
         {
             let token = self.fetch_token(scopes, progress).await?;
             let url = url_with_query(self.base_url, path, query_params);
-            let res = self
+            let req = self
                 .client
                 .post(url)
                 .bearer_auth(token.secret())
                 .header(header::USER_AGENT, &self.user_agent)
                 .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
-                .json(body)
-                .send()
-                .await?;
+                .json(body);
+            let res = send(req, || format!("POST {path}"), 0).await?;
             Ok(res)
         }
 
-        pub async fn upload_request<'a, B>(
+        pub async fn patch_json_query<T, Q, K, V>(
+            &self,
+            scopes: &[api::Scope],
+            path: &str,
+            query_params: Q,
+            body: &T,
+            progress: Option<&SharedProgress>,
+        ) -> anyhow::Result<Response>
+        where
+            T: Serialize,
+            Q: IntoIterator,
+            Q::Item: Borrow<(K, V)>,
+            K: AsRef<str>,
+            V: AsRef<str>,
+        {
+            let token = self.fetch_token(scopes, progress).await?;
+            let url = url_with_query(self.base_url, path, query_params);
+            let req = self
+                .client
+                .patch(url)
+                .bearer_auth(token.secret())
+                .header(header::USER_AGENT, &self.user_agent)
+                .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+                .json(body);
+            let res = send(req, || format!("PATCH {path}"), 0).await?;
+            Ok(res)
+        }
+
+        pub async fn upload_request<B>(
             &self,
             method: reqwest::Method,
             scopes: &[api::Scope],
@@ -923,7 +2186,7 @@ mod utils {
                     //.header(header::CONTENT_LENGTH, body.len())
                     .json(body);
             }
-            let res = req.send().await?;
+            let res = send(req, || format!("POST {path}"), 0).await?;
 
             if res.status() != StatusCode::OK {
                 anyhow::bail!("POST {url} returned {}", res.status());
@@ -932,6 +2195,7 @@ mod utils {
             Ok(Url::parse(location.to_str().unwrap())?)
         }
 
+        #[allow(clippy::too_many_arguments)]
         pub async fn upload_range(
             &self,
             method: reqwest::Method,
@@ -962,7 +2226,27 @@ mod utils {
                     ),
                 );
             }
-            Ok(req.body(data).send().await?)
+            Ok(send(req.body(data), || "PUT upload range".to_string(), data_len).await?)
+        }
+
+        /// Query how many bytes of a resumable upload were received
+        pub async fn upload_status(
+            &self,
+            scopes: &[api::Scope],
+            url: Url,
+            range_len: u64,
+            progress: Option<&SharedProgress>,
+        ) -> anyhow::Result<Response> {
+            let token = self.fetch_token(scopes, progress).await?;
+            let req = self
+                .client
+                .put(url)
+                .bearer_auth(token.secret())
+                .header(header::USER_AGENT, &self.user_agent)
+                .header(header::CONTENT_LENGTH, 0)
+                .header(header::CONTENT_RANGE, format!("bytes */{range_len}"));
+            let res = send(req, || "PUT upload status".to_string(), 0).await?;
+            Ok(res)
         }
 
         pub async fn delete_query<Q, K, V>(
@@ -980,14 +2264,35 @@ mod utils {
         {
             let token = self.fetch_token(scopes, progress).await?;
             let url = url_with_query(self.base_url, path, query_params);
-            let res = self
+            let req = self
                 .client
                 .delete(url)
                 .bearer_auth(token.secret())
                 .header(header::USER_AGENT, &self.user_agent)
-                .header(header::CONTENT_LENGTH, 0)
-                .send()
-                .await?;
+                .header(header::CONTENT_LENGTH, 0);
+            let res = send(req, || format!("DELETE {path}"), 0).await?;
+            Ok(res)
+        }
+
+        /// Post a `multipart/mixed` batch of requests
+        pub async fn post_batch(
+            &self,
+            scopes: &[api::Scope],
+            boundary: &str,
+            body: String,
+        ) -> fsync::Result<Response> {
+            let token = self.fetch_token(scopes, None).await?;
+            let req = self
+                .client
+                .post(self.batch_url)
+                .bearer_auth(token.secret())
+                .header(header::USER_AGENT, &self.user_agent)
+                .header(
+                    header::CONTENT_TYPE,
+                    format!("multipart/mixed; boundary={boundary}"),
+                )
+                .body(body);
+            let res = send(req, || "POST batch".to_string(), 0).await?;
             Ok(res)
         }
     }
@@ -1005,3 +2310,1006 @@ mod utils {
         Url::parse_with_params(&base, query_params).unwrap()
     }
 }
+
+/// Check that the token of `instance_name` belongs to the `expected` account, if one was recorded.
+/// The token cache of an instance may have been filled with another account,
+/// e.g. when the cache directory is shared by several instances.
+pub fn check_account(
+    instance_name: &str,
+    expected: Option<&str>,
+    actual: Option<&str>,
+) -> anyhow::Result<()> {
+    match (expected, actual) {
+        (Some(expected), Some(actual)) if !expected.eq_ignore_ascii_case(actual) => {
+            anyhow::bail!(
+                "The token belongs to {actual}, expected {expected}. \
+                 Run `fsynctl instance reauth {instance_name}` to authorize {expected} again"
+            )
+        }
+        (Some(expected), None) => {
+            log::warn!("Could not check that the token belongs to {expected}");
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Record `account` as the authorized account of the Drive config in `config_file`
+pub async fn record_account(
+    config_file: &fsync::path::FsPath,
+    account: &str,
+) -> anyhow::Result<()> {
+    let mut config = fsync::Config::load_from_file(config_file).await?;
+    let fsync::ProviderConfig::GoogleDrive(drive) = &mut config.provider else {
+        anyhow::bail!("{config_file} is not the config of a Drive instance");
+    };
+    drive.account = Some(account.to_string());
+    let json = serde_json::to_vec_pretty(&config)?;
+    let path = config_file.to_owned();
+    tokio::task::spawn_blocking(move || crate::persist::atomic_write(&path, &json)).await??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    fn file(id: &str, name: Option<&str>, size: Option<i64>, mime_type: &str) -> api::File {
+        api::File {
+            id: Some(IdBuf::from(id)),
+            name: name.map(ToString::to_string),
+            modified_time: Some(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()),
+            size,
+            mime_type: Some(mime_type.to_string()),
+            parents: None,
+            shared: None,
+            permissions: None,
+            capabilities: None,
+            web_view_link: None,
+            md5_checksum: None,
+        }
+    }
+
+    #[test]
+    fn token_account_is_checked() {
+        let work = Some("me@work.com");
+        let personal = Some("me@home.org");
+        assert!(check_account("work", None, personal).is_ok());
+        assert!(check_account("work", work, work).is_ok());
+        assert!(check_account("work", work, Some("Me@Work.com")).is_ok());
+        assert!(check_account("work", work, None).is_ok());
+
+        let err = check_account("work", work, personal)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("belongs to me@home.org, expected me@work.com"));
+        assert!(err.contains("fsynctl instance reauth work"));
+    }
+
+    #[test]
+    fn map_file_missing_size() {
+        let f = file("f1", Some("empty.txt"), None, "text/plain");
+        let metadata = map_file(PathBuf::from("/dir"), f).unwrap();
+        assert_eq!(metadata.path().as_str(), "/dir/empty.txt");
+        assert_eq!(metadata.size(), Some(0));
+
+        let f = file(
+            "f2",
+            Some("doc"),
+            None,
+            "application/vnd.google-apps.document",
+        );
+        assert!(map_file(PathBuf::from("/dir"), f).is_err());
+
+        let f = file("f3", Some("folder"), None, FOLDER_MIMETYPE);
+        let metadata = map_file(PathBuf::from("/dir"), f).unwrap();
+        assert!(metadata.is_dir());
+    }
+
+    #[test]
+    fn map_file_unnamed() {
+        let f = file("f1", None, Some(12), "text/plain");
+        let metadata = map_file(PathBuf::root(), f).unwrap();
+        assert_eq!(metadata.path().as_str(), "/(unnamed-f1)");
+        assert_eq!(metadata.size(), Some(12));
+
+        let f = file("f2", Some(""), Some(0), FOLDER_MIMETYPE);
+        let metadata = map_file(PathBuf::from("/dir"), f).unwrap();
+        assert_eq!(metadata.path().as_str(), "/dir/(unnamed-f2)");
+        assert!(metadata.is_dir());
+    }
+
+    #[test]
+    fn map_metadata_keeps_drive_name_of_unnamed() {
+        let f = file("f1", None, Some(12), "text/plain");
+        let metadata = map_file(PathBuf::root(), f).unwrap();
+
+        let id = IdBuf::from("f1");
+        let written = map_metadata(None, Some(&id), &metadata);
+        assert_eq!(written.id, Some(id));
+        assert!(written.name.is_none());
+
+        // a new file that happens to have the name of a placeholder is created as is
+        let created = map_metadata(None, None, &metadata);
+        assert_eq!(created.name.as_deref(), Some("(unnamed-f1)"));
+    }
+
+    #[test]
+    fn map_file_sharing() {
+        let perm = |typ: &str, role: &str| api::Permission {
+            typ: typ.to_string(),
+            role: role.to_string(),
+        };
+
+        let mut f = file("f1", Some("public.txt"), Some(12), "text/plain");
+        f.shared = Some(true);
+        f.permissions = Some(vec![
+            perm("user", "owner"),
+            perm("anyone", "reader"),
+            perm("anyone", "writer"),
+            perm("user", "commenter"),
+            perm("group", "reader"),
+        ]);
+        let sharing = map_sharing(&f).unwrap();
+        assert!(sharing.is_public());
+        assert_eq!(sharing.anyone, Some(fsync::SharingRole::Writer));
+        assert_eq!(sharing.users, 2);
+        assert_eq!(
+            sharing.to_string(),
+            "shared with anyone with link (writer), 2 users"
+        );
+
+        let mut f = file("f2", Some("private.txt"), Some(12), "text/plain");
+        f.shared = Some(false);
+        f.permissions = Some(vec![perm("user", "owner")]);
+        assert!(map_sharing(&f).is_none());
+    }
+
+    fn fixture<T: serde::de::DeserializeOwned>(what: &str, json: &str) -> T {
+        utils::from_json(what, json).unwrap()
+    }
+
+    fn list_fixture(json: &str) -> Vec<api::File> {
+        let list: api::FileList = fixture("file list", json);
+        list.files.unwrap()
+    }
+
+    #[test]
+    fn fixture_my_drive_listing() {
+        let files = list_fixture(include_str!("drive/fixtures/files_list.json"));
+        assert_eq!(files.len(), 5);
+
+        let pdf = map_file(PathBuf::root(), files[0].clone()).unwrap();
+        assert_eq!(pdf.size(), Some(482133));
+        assert_eq!(
+            files[0].md5_checksum.as_deref(),
+            Some("9e107d9d372bb6826bd81d3542a419d6")
+        );
+
+        let folder = map_file(PathBuf::root(), files[1].clone()).unwrap();
+        assert!(folder.is_dir());
+        assert_eq!(folder.name(), "Photos 🏖️ été 2023");
+
+        let png = map_file(PathBuf::from("/dir"), files[2].clone()).unwrap();
+        assert_eq!(png.path().as_str(), "/dir/🎉 party-invite.png");
+
+        let doc = map_file(PathBuf::root(), files[3].clone()).unwrap();
+        assert_eq!(doc.size(), Some(1024));
+
+        // a shortcut has no size, its own fields are ignored
+        let shortcut = &files[4];
+        assert_eq!(
+            shortcut.mime_type.as_deref(),
+            Some("application/vnd.google-apps.shortcut")
+        );
+        assert_eq!(shortcut.size, None);
+        let err = map_file(PathBuf::root(), shortcut.clone()).unwrap_err();
+        assert!(err.to_string().contains("1ShOrTcUt0123456789abcdef"));
+    }
+
+    #[test]
+    fn fixture_shared_drive_listing() {
+        let json = include_str!("drive/fixtures/files_list_shared_drive.json");
+        let list: api::FileList = fixture("file list", json);
+        assert!(list.next_page_token.is_some());
+        let files = list.files.unwrap();
+
+        let sharing = map_sharing(&files[0]).unwrap();
+        assert_eq!(sharing.domain, Some(fsync::SharingRole::Reader));
+        assert_eq!(sharing.users, 2);
+        let sharing = map_sharing(&files[1]).unwrap();
+        assert_eq!(sharing.anyone, Some(fsync::SharingRole::Reader));
+
+        let capabilities = files[2].capabilities.as_ref().unwrap();
+        assert_eq!(capabilities.can_download, Some(false));
+        for f in files {
+            map_file(PathBuf::root(), f).unwrap();
+        }
+    }
+
+    #[test]
+    fn fixture_about_and_revisions() {
+        let about: api::About = fixture("about", include_str!("drive/fixtures/about.json"));
+        assert_eq!(about.storage_quota.limit, None);
+        assert_eq!(about.storage_quota.usage, Some(8233102831));
+        assert_eq!(
+            about.user.email_address.as_deref(),
+            Some("jane.doe@example.com")
+        );
+
+        let json = include_str!("drive/fixtures/revisions.json");
+        let list: api::RevisionList = fixture("revision list", json);
+        let revisions = list.revisions.unwrap();
+        assert_eq!(revisions.len(), 3);
+        assert_eq!(revisions[1].size, Some(482133));
+        assert_eq!(revisions[2].size, None);
+        assert!(revisions[2].last_modifying_user.is_none());
+    }
+
+    #[test]
+    fn fixture_quirks_are_tolerated() {
+        let files = list_fixture(include_str!("drive/fixtures/files_list_quirks.json"));
+        assert_eq!(files[0].size, None);
+        assert_eq!(files[1].size, None);
+        assert_eq!(files[2].size, Some(42));
+
+        let sharing = map_sharing(&files[3]).unwrap();
+        assert_eq!(sharing.anyone, Some(fsync::SharingRole::Reader));
+        assert_eq!(sharing.users, 1);
+    }
+
+    #[test]
+    fn parse_failure_names_the_field_and_the_file() {
+        let json = r#"{"files": [
+            {"id": "ok", "name": "a.txt", "size": "1"},
+            {"id": "1BaDsIzE", "name": "b.txt", "size": "twelve"}
+        ]}"#;
+        let err = utils::from_json::<api::FileList>("file list", json)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("files[1].size"), "{err}");
+        assert!(err.contains("1BaDsIzE"), "{err}");
+    }
+
+    struct StaticToken;
+
+    impl GetToken for StaticToken {
+        async fn get_token(
+            &self,
+            _scopes: Vec<crate::oauth2::Scope>,
+            _progress: Option<&SharedProgress>,
+        ) -> fsync::Result<crate::oauth2::AccessToken> {
+            Ok(crate::oauth2::AccessToken::new("token".into()))
+        }
+    }
+
+    fn test_drive(base_url: &'static str) -> GoogleDrive<StaticToken> {
+        GoogleDrive {
+            client: reqwest::Client::new(),
+            auth: Arc::new(StaticToken),
+            base_url,
+            upload_base_url: base_url,
+            batch_url: base_url,
+            user_agent: "fsyncd-test".into(),
+            root: IdBuf::from("root"),
+            shared: false,
+            user: api::User::default(),
+            quota: Arc::new(Mutex::new(QuotaCache::new(api::Quota::default()))),
+            batch: Arc::default(),
+            max_chunk_size: upload::DEFAULT_MAX_CHUNK_SZ,
+            upload_stats: Arc::default(),
+            buffers: None,
+            fetch_sharing: true,
+            sharing: Arc::default(),
+            view_only: Arc::default(),
+            links: Arc::default(),
+            content: None,
+        }
+    }
+
+    /// Serve `content` on a local port, and send the received `Range` headers to the channel.
+    /// The range is honored with a partial response only if `partial` is set.
+    async fn media_server(
+        content: &'static [u8],
+        partial: bool,
+    ) -> (
+        &'static str,
+        tokio::sync::mpsc::UnboundedReceiver<Option<String>>,
+    ) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                let mut range = None;
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(": ") {
+                        if name.eq_ignore_ascii_case("range") {
+                            range = Some(value.to_string());
+                        }
+                    }
+                }
+                let total = content.len() as u64;
+                let (status, content_range, body) = match range.as_deref() {
+                    Some(range) if partial => {
+                        let (start, end) = range
+                            .strip_prefix("bytes=")
+                            .and_then(|r| r.split_once('-'))
+                            .unwrap();
+                        let start: u64 = start.parse().unwrap();
+                        let end = end.parse::<u64>().unwrap().min(total.saturating_sub(1));
+                        if start >= total {
+                            (
+                                "416 Range Not Satisfiable",
+                                format!("bytes */{total}"),
+                                &b""[..],
+                            )
+                        } else {
+                            (
+                                "206 Partial Content",
+                                format!("bytes {start}-{end}/{total}"),
+                                &content[start as usize..=end as usize],
+                            )
+                        }
+                    }
+                    _ => ("200 OK", String::new(), content),
+                };
+                tx.send(range).unwrap();
+                let mut head = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    body.len()
+                );
+                if !content_range.is_empty() {
+                    head.push_str(&format!("Content-Range: {content_range}\r\n"));
+                }
+                head.push_str("\r\n");
+                write.write_all(head.as_bytes()).await.unwrap();
+                write.write_all(body).await.unwrap();
+                write.shutdown().await.unwrap();
+            }
+        });
+        let base_url = Box::leak(format!("http://127.0.0.1:{port}").into_boxed_str());
+        (base_url, rx)
+    }
+
+    async fn read_range(drive: &GoogleDrive<StaticToken>, offset: u64, len: u64) -> Vec<u8> {
+        use tokio::io::AsyncReadExt;
+
+        let read = super::super::id::ReadFileRange::read_file_range(
+            drive,
+            IdBuf::from("f1"),
+            offset,
+            len,
+            None,
+        )
+        .await
+        .unwrap();
+        tokio::pin!(read);
+        let mut data = Vec::new();
+        read.read_to_end(&mut data).await.unwrap();
+        data
+    }
+
+    const CONTENT: &[u8] = b"0123456789abcdefghij";
+
+    #[tokio::test]
+    async fn read_file_range_partial_content() {
+        let (base_url, mut ranges) = media_server(CONTENT, true).await;
+        let drive = test_drive(base_url);
+
+        assert_eq!(read_range(&drive, 5, 4).await, b"5678");
+        assert_eq!(ranges.recv().await.unwrap().as_deref(), Some("bytes=5-8"));
+
+        assert_eq!(read_range(&drive, 15, 100).await, b"fghij");
+        assert_eq!(
+            ranges.recv().await.unwrap().as_deref(),
+            Some("bytes=15-114")
+        );
+
+        assert_eq!(read_range(&drive, 0, 0).await, b"");
+        assert_eq!(ranges.recv().await.unwrap().as_deref(), Some("bytes=0-0"));
+
+        assert_eq!(read_range(&drive, 30, 4).await, b"");
+        assert_eq!(ranges.recv().await.unwrap().as_deref(), Some("bytes=30-33"));
+    }
+
+    #[tokio::test]
+    async fn read_file_range_full_content() {
+        let (base_url, mut ranges) = media_server(CONTENT, false).await;
+        let drive = test_drive(base_url);
+
+        assert_eq!(read_range(&drive, 5, 4).await, b"5678");
+        assert_eq!(ranges.recv().await.unwrap().as_deref(), Some("bytes=5-8"));
+
+        assert_eq!(read_range(&drive, 15, 100).await, b"fghij");
+        assert_eq!(read_range(&drive, 30, 4).await, b"");
+    }
+
+    async fn content_cache(name: &str, budget: u64) -> Arc<DiskCache> {
+        let dir = std::env::temp_dir().join(format!("fsyncd-{name}-{}", std::process::id()));
+        let dir = fsync::path::FsPathBuf::try_from(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let paths = crate::disk_cache::CachePaths {
+            metadata: dir.join("remote.bin"),
+            content: dir.join("content"),
+            quarantine: dir.join("quarantine"),
+            temp: dir.join("tmp"),
+        };
+        Arc::new(DiskCache::open(paths, Some(budget)).await.unwrap())
+    }
+
+    /// List the file `id` with `CONTENT`, or another content if `!same`
+    async fn list_content(drive: &GoogleDrive<StaticToken>, id: &str, same: bool) {
+        use md5::Digest;
+
+        let mut f = file(id, Some(id), Some(CONTENT.len() as _), "text/plain");
+        let md5 = md5::Md5::digest(if same { CONTENT } else { b"other" });
+        f.md5_checksum = Some(hex::encode(md5));
+        drive.record_content(&f).await;
+    }
+
+    async fn read_all(drive: &GoogleDrive<StaticToken>, id: &str) -> Vec<u8> {
+        use tokio::io::AsyncReadExt;
+
+        let read = super::super::id::ReadFile::read_file(drive, IdBuf::from(id), None)
+            .await
+            .unwrap();
+        tokio::pin!(read);
+        let mut data = Vec::new();
+        read.read_to_end(&mut data).await.unwrap();
+        data
+    }
+
+    /// Wait until the content cached in the background has `size` bytes
+    async fn cached_size(disk: &DiskCache, size: u64) {
+        for _ in 0..100 {
+            if disk.usage().await.content == size {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the cached content should be {size} bytes");
+    }
+
+    fn content_files(disk: &DiskCache) -> Vec<std::path::PathBuf> {
+        std::fs::read_dir(&disk.paths().content)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn cached_content_is_verified() {
+        let (base_url, mut requests) = media_server(CONTENT, false).await;
+        let disk = content_cache("content-verified", 1024).await;
+        let drive = test_drive(base_url).with_content_cache(Some(disk.clone()));
+        list_content(&drive, "f1", true).await;
+
+        assert_eq!(read_all(&drive, "f1").await, CONTENT);
+        assert!(requests.recv().await.is_some());
+        cached_size(&disk, CONTENT.len() as u64).await;
+
+        assert_eq!(read_all(&drive, "f1").await, CONTENT);
+        assert_eq!(read_range(&drive, 5, 4).await, b"5678");
+        assert!(requests.try_recv().is_err());
+
+        // a tampered content is downloaded again
+        for path in content_files(&disk) {
+            std::fs::write(path, b"abcdefghij0123456789").unwrap();
+        }
+        assert_eq!(read_all(&drive, "f1").await, CONTENT);
+        assert!(requests.recv().await.is_some());
+        let lookups = disk.lookups();
+        assert_eq!((lookups.hits, lookups.misses), (2, 2));
+
+        // another version of the file invalidates its content
+        cached_size(&disk, CONTENT.len() as u64).await;
+        list_content(&drive, "f1", false).await;
+        assert_eq!(disk.usage().await.content, 0);
+
+        std::fs::remove_dir_all(disk.paths().content.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cached_content_is_evicted_within_budget() {
+        let (base_url, mut requests) = media_server(CONTENT, false).await;
+        // room for a single file
+        let disk = content_cache("content-evicted", 30).await;
+        let drive = test_drive(base_url).with_content_cache(Some(disk.clone()));
+        list_content(&drive, "f1", true).await;
+        list_content(&drive, "f2", true).await;
+
+        assert_eq!(read_all(&drive, "f1").await, CONTENT);
+        cached_size(&disk, CONTENT.len() as u64).await;
+        let f1_files = content_files(&disk);
+        assert_eq!(read_all(&drive, "f2").await, CONTENT);
+        assert!(requests.recv().await.is_some());
+        assert!(requests.recv().await.is_some());
+
+        // f1 is evicted to cache f2
+        for _ in 0..100 {
+            let files = content_files(&disk);
+            if !files.is_empty() && files != f1_files {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(read_all(&drive, "f2").await, CONTENT);
+        assert!(requests.try_recv().is_err());
+        assert_eq!(read_all(&drive, "f1").await, CONTENT);
+        assert!(requests.recv().await.is_some());
+
+        assert!(disk.usage().await.total() <= 30);
+        let lookups = disk.lookups();
+        assert_eq!((lookups.hits, lookups.misses), (1, 3));
+
+        std::fs::remove_dir_all(disk.paths().content.parent().unwrap()).unwrap();
+    }
+
+    /// Serve the files of `LIST`, one of which is view-only, and refuse all the downloads
+    /// with the error sent by Drive for the files that can't be downloaded.
+    async fn view_only_server() -> &'static str {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        const LIST: &str = r#"{
+            "files": [
+                {
+                    "id": "f1",
+                    "name": "view-only.pdf",
+                    "size": "12",
+                    "modifiedTime": "2024-01-01T00:00:00Z",
+                    "mimeType": "application/pdf",
+                    "capabilities": { "canDownload": false },
+                    "webViewLink": "https://drive.google.com/file/d/f1/view"
+                },
+                {
+                    "id": "f2",
+                    "name": "regular.pdf",
+                    "size": "12",
+                    "modifiedTime": "2024-01-01T00:00:00Z",
+                    "mimeType": "application/pdf",
+                    "capabilities": { "canDownload": true },
+                    "webViewLink": "https://drive.google.com/file/d/f2/view"
+                }
+            ]
+        }"#;
+        const REFUSED: &str = r#"{
+            "error": {
+                "errors": [
+                    {
+                        "domain": "global",
+                        "reason": "cannotDownloadFile",
+                        "message": "The user does not have sufficient permissions for this file."
+                    }
+                ],
+                "code": 403,
+                "message": "The user does not have sufficient permissions for this file."
+            }
+        }"#;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                let request = lines.next_line().await.unwrap().unwrap_or_default();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line.is_empty() {
+                        break;
+                    }
+                }
+                let (status, body) = if request.contains("alt=media") {
+                    ("403 Forbidden", REFUSED)
+                } else {
+                    ("200 OK", LIST)
+                };
+                let head = format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                write.write_all(head.as_bytes()).await.unwrap();
+                write.write_all(body.as_bytes()).await.unwrap();
+                write.shutdown().await.unwrap();
+            }
+        });
+        Box::leak(format!("http://127.0.0.1:{port}").into_boxed_str())
+    }
+
+    async fn read_error(drive: &GoogleDrive<StaticToken>, id: &str) -> fsync::Error {
+        match super::super::id::ReadFile::read_file(drive, IdBuf::from(id), None).await {
+            Ok(_) => panic!("{id} should not be downloadable"),
+            Err(err) => err,
+        }
+    }
+
+    #[tokio::test]
+    async fn view_only_files_are_not_downloadable() {
+        use super::super::id::{DirEntries, Shared};
+
+        let drive = test_drive(view_only_server().await);
+
+        let entries: Vec<_> = drive
+            .dir_entries(None, Path::root(), None)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        let link = "https://drive.google.com/file/d/f1/view";
+        assert_eq!(
+            drive.view_only(&IdBuf::from("f1")),
+            Some(fsync::ViewOnly {
+                web_view_link: Some(link.to_string())
+            })
+        );
+        assert_eq!(drive.view_only(&IdBuf::from("f2")), None);
+
+        match read_error(&drive, "f1").await {
+            fsync::Error::NotDownloadable {
+                path,
+                web_view_link,
+            } => {
+                assert_eq!(path.as_str(), "f1");
+                assert_eq!(web_view_link.as_deref(), Some(link));
+            }
+            err => panic!("unexpected error: {err}"),
+        }
+
+        // a file not listed yet is marked once its download is refused
+        let err = read_error(&drive, "f3").await;
+        assert!(matches!(
+            err,
+            fsync::Error::NotDownloadable {
+                web_view_link: None,
+                ..
+            }
+        ));
+        assert!(drive.view_only(&IdBuf::from("f3")).is_some());
+    }
+
+    /// Serve two pages of revisions of the file `f1`, and the content of the revision `r2`
+    async fn revisions_server() -> &'static str {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        const PAGE1: &str = r#"{
+            "nextPageToken": "p2",
+            "revisions": [
+                {
+                    "id": "r1",
+                    "modifiedTime": "2024-01-01T00:00:00Z",
+                    "size": "3",
+                    "lastModifyingUser": { "displayName": "Alice" }
+                }
+            ]
+        }"#;
+        const PAGE2: &str = r#"{
+            "revisions": [
+                { "id": "r2", "modifiedTime": "2024-01-02T00:00:00Z", "size": "5" }
+            ]
+        }"#;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                let request = lines.next_line().await.unwrap().unwrap_or_default();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line.is_empty() {
+                        break;
+                    }
+                }
+                let (status, body) = if request.contains("/files/f1/revisions/r2?alt=media") {
+                    ("200 OK", "older")
+                } else if request.contains("/files/f1/revisions?") {
+                    match request.contains("pageToken=p2") {
+                        true => ("200 OK", PAGE2),
+                        false => ("200 OK", PAGE1),
+                    }
+                } else {
+                    ("404 Not Found", "")
+                };
+                let head = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                write.write_all(head.as_bytes()).await.unwrap();
+                write.write_all(body.as_bytes()).await.unwrap();
+                write.shutdown().await.unwrap();
+            }
+        });
+        Box::leak(format!("http://127.0.0.1:{port}").into_boxed_str())
+    }
+
+    #[tokio::test]
+    async fn revisions_are_listed_and_read() {
+        use tokio::io::AsyncReadExt;
+
+        use super::super::id::Revisions;
+
+        let drive = test_drive(revisions_server().await);
+
+        let revisions = drive.revisions(Id::new("f1")).await.unwrap();
+        let ids: Vec<_> = revisions.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, ["r1", "r2"]);
+        assert_eq!(revisions[0].author.as_deref(), Some("Alice"));
+        assert_eq!(revisions[1].size, Some(5));
+        assert_eq!(revisions[1].author, None);
+
+        let read = drive
+            .read_revision(IdBuf::from("f1"), "r2", None)
+            .await
+            .unwrap();
+        tokio::pin!(read);
+        let mut data = Vec::new();
+        read.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"older");
+
+        assert!(drive
+            .read_revision(IdBuf::from("f1"), "r3", None)
+            .await
+            .is_err());
+    }
+
+    /// Serve the parents of the folders of My Drive under `mydrive`,
+    /// of a shared drive `0Adrive`, and of a cycle of folders
+    async fn parents_server() -> &'static str {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                let request = lines.next_line().await.unwrap().unwrap_or_default();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line.is_empty() {
+                        break;
+                    }
+                }
+                let file = |id: &str| format!("/files/{id}?");
+                let (status, body) =
+                    if request.contains(&file("root")) || request.contains(&file("mydrive")) {
+                        ("200 OK", r#"{ "id": "mydrive" }"#)
+                    } else if request.contains(&file("trips")) {
+                        ("200 OK", r#"{ "id": "trips", "parents": ["photos"] }"#)
+                    } else if request.contains(&file("photos")) {
+                        ("200 OK", r#"{ "id": "photos", "parents": ["mydrive"] }"#)
+                    } else if request.contains(&file("folder")) {
+                        ("200 OK", r#"{ "id": "folder", "parents": ["0Adrive"] }"#)
+                    } else if request.contains(&file("0Adrive")) {
+                        ("200 OK", r#"{ "id": "0Adrive" }"#)
+                    } else if request.contains(&file("loop1")) {
+                        ("200 OK", r#"{ "id": "loop1", "parents": ["loop2"] }"#)
+                    } else if request.contains(&file("loop2")) {
+                        ("200 OK", r#"{ "id": "loop2", "parents": ["loop1"] }"#)
+                    } else {
+                        ("404 Not Found", "")
+                    };
+                let head = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                write.write_all(head.as_bytes()).await.unwrap();
+                write.write_all(body.as_bytes()).await.unwrap();
+                write.shutdown().await.unwrap();
+            }
+        });
+        Box::leak(format!("http://127.0.0.1:{port}").into_boxed_str())
+    }
+
+    #[tokio::test]
+    async fn root_ancestry_is_walked() {
+        let base_url = parents_server().await;
+        let ancestry = |root: &str, shared: bool| {
+            let mut drive = test_drive(base_url);
+            drive.root = IdBuf::from(root);
+            drive.shared = shared;
+            async move { drive.root_ancestry().await }
+        };
+        let ids = |ancestry: Vec<IdBuf>| -> Vec<String> {
+            ancestry.into_iter().map(|id| id.to_string()).collect()
+        };
+
+        // the alias of My Drive is resolved
+        assert_eq!(ids(ancestry("root", false).await.unwrap()), ["mydrive"]);
+        assert_eq!(
+            ids(ancestry("trips", false).await.unwrap()),
+            ["trips", "photos", "mydrive"]
+        );
+        // the chain of a shared drive ends at the drive id
+        assert_eq!(
+            ids(ancestry("folder", true).await.unwrap()),
+            ["folder", "0Adrive"]
+        );
+        assert_eq!(ids(ancestry("0Adrive", true).await.unwrap()), ["0Adrive"]);
+
+        assert!(ancestry("loop1", false).await.is_err());
+        assert!(ancestry("missing", false).await.is_err());
+    }
+
+    /// Serve the links of the file `f1` once, and create its permissions.
+    /// The bodies of the permissions created are sent to the channel.
+    async fn links_server(tx: tokio::sync::mpsc::UnboundedSender<String>) -> &'static str {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+        const LINKS: &str = r#"{
+            "webViewLink": "https://drive.google.com/file/d/f1/view",
+            "webContentLink": "https://drive.google.com/uc?id=f1&export=download"
+        }"#;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut links_served = false;
+            while let Ok((stream, _)) = listener.accept().await {
+                let (read, mut write) = stream.into_split();
+                let mut read = BufReader::new(read);
+                let mut request = String::new();
+                read.read_line(&mut request).await.unwrap();
+                let mut len = 0;
+                loop {
+                    let mut line = String::new();
+                    read.read_line(&mut line).await.unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            len = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; len];
+                read.read_exact(&mut body).await.unwrap();
+
+                let (status, body) = if request.starts_with("POST /files/f1/permissions") {
+                    tx.send(String::from_utf8(body).unwrap()).unwrap();
+                    ("200 OK", r#"{ "id": "p1" }"#)
+                } else if request.starts_with("GET /files/f1?") && !links_served {
+                    links_served = true;
+                    ("200 OK", LINKS)
+                } else {
+                    ("404 Not Found", "")
+                };
+                let head = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                write.write_all(head.as_bytes()).await.unwrap();
+                write.write_all(body.as_bytes()).await.unwrap();
+                write.shutdown().await.unwrap();
+            }
+        });
+        Box::leak(format!("http://127.0.0.1:{port}").into_boxed_str())
+    }
+
+    #[tokio::test]
+    async fn links_are_fetched_once_and_shared() {
+        use super::super::id::Shared;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let drive = test_drive(links_server(tx).await);
+
+        let link = drive.link(Id::new("f1")).await.unwrap();
+        assert_eq!(link.web_view, "https://drive.google.com/file/d/f1/view");
+        assert!(link.web_content.is_some());
+        // the links are not requested again
+        assert_eq!(drive.link(Id::new("f1")).await.unwrap(), link);
+        assert!(drive.link(Id::new("f2")).await.is_err());
+
+        assert!(drive.sharing(Id::new("f1")).is_none());
+        assert_eq!(drive.share_public(Id::new("f1")).await.unwrap(), link);
+        let permission: serde_json::Value =
+            serde_json::from_str(&rx.recv().await.unwrap()).unwrap();
+        assert_eq!(permission["type"], "anyone");
+        assert_eq!(permission["role"], "reader");
+        assert!(drive.sharing(Id::new("f1")).is_some_and(|s| s.is_public()));
+    }
+
+    /// Answer the updates of the metadata of the file f1, and send the received requests,
+    /// with their body, to `tx`
+    async fn update_server(
+        tx: tokio::sync::mpsc::UnboundedSender<(String, String)>,
+    ) -> &'static str {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+        const FILE: &str = r#"{
+            "id": "f1",
+            "name": "file.txt",
+            "size": "5",
+            "modifiedTime": "2024-03-01T12:00:00Z",
+            "mimeType": "text/plain",
+            "md5Checksum": "5d41402abc4b2a76b9719d911017c592"
+        }"#;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (read, mut write) = stream.into_split();
+                let mut read = BufReader::new(read);
+                let mut request = String::new();
+                read.read_line(&mut request).await.unwrap();
+                let mut len = 0;
+                loop {
+                    let mut line = String::new();
+                    read.read_line(&mut line).await.unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            len = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; len];
+                read.read_exact(&mut body).await.unwrap();
+
+                let (status, answer) = if request.starts_with("PATCH /files/f1?") {
+                    ("200 OK", FILE)
+                } else {
+                    ("404 Not Found", "")
+                };
+                let request = request.trim_end().to_string();
+                tx.send((request, String::from_utf8(body).unwrap()))
+                    .unwrap();
+                let head = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    answer.len()
+                );
+                write.write_all(head.as_bytes()).await.unwrap();
+                write.write_all(answer.as_bytes()).await.unwrap();
+                write.shutdown().await.unwrap();
+            }
+        });
+        Box::leak(format!("http://127.0.0.1:{port}").into_boxed_str())
+    }
+
+    #[tokio::test]
+    async fn patch_updates_the_metadata_without_content() {
+        use super::super::{id::PatchMetadata, MetadataPatch};
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let drive = test_drive(update_server(tx).await);
+
+        let mtime = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let metadata = drive
+            .patch(
+                Id::new("f1"),
+                Path::new("/dir/file.txt"),
+                &MetadataPatch::mtime(mtime),
+            )
+            .await
+            .unwrap();
+        assert_eq!(metadata.path(), Path::new("/dir/file.txt"));
+        assert_eq!(metadata.mtime(), Some(mtime));
+        assert_eq!(metadata.size(), Some(5));
+
+        let (request, body) = rx.recv().await.unwrap();
+        assert!(request.starts_with("PATCH /files/f1?"), "{request}");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "modifiedTime": "2024-03-01T12:00:00Z" })
+        );
+        // a single request, to the metadata endpoint, and no content uploaded
+        assert!(rx.try_recv().is_err());
+        assert_eq!(drive.upload_stats().bytes, 0);
+        assert_eq!(drive.quota.lock().unwrap().uploaded, 0);
+    }
+}
@@ -0,0 +1,410 @@
+//! Batching of the metadata-only requests to Drive.
+//!
+//! Folder creations and deletions are queued and sent in `multipart/mixed` batches
+//! of up to [`MAX_BATCH_LEN`] requests, which spares the per-request overhead when
+//! operating on many small entries. Folders are created with ids generated in advance,
+//! so that the id is known before the folder exists.
+//!
+//! The queue is flushed when full, before any request that depends on its content
+//! (listings, uploads, downloads...), and by the service at the boundaries of the
+//! sub-trees of deep operations. Drive may process the requests of a batch in any
+//! order, so requests that depend on a folder created in the same flush are sent in
+//! a later batch. The failure of an item is reported to the progress of its entry.
+
+use std::{
+    collections::HashMap,
+    mem,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use http::StatusCode;
+
+use super::{api, GoogleDrive};
+use crate::{
+    oauth2::GetToken,
+    storage::id::{Id, IdBuf},
+    SharedProgress,
+};
+
+/// Maximum number of requests in a batch, as allowed by Drive
+pub const MAX_BATCH_LEN: usize = 100;
+
+/// A queued request
+#[derive(Debug, Clone)]
+pub enum Request {
+    /// Create a folder with a generated id
+    Create(api::File),
+    Delete(IdBuf),
+}
+
+impl Request {
+    /// Ids that must exist before the request is processed
+    fn dependencies(&self) -> Vec<&Id> {
+        match self {
+            Request::Create(file) => file.parents.iter().flatten().map(|id| id.as_id()).collect(),
+            Request::Delete(id) => vec![id.as_id()],
+        }
+    }
+
+    fn created(&self) -> Option<&Id> {
+        match self {
+            Request::Create(file) => file.id.as_deref(),
+            Request::Delete(..) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Item {
+    pub request: Request,
+    pub progress: Option<SharedProgress>,
+}
+
+/// The queue of requests, and the ids generated for the folders to create
+#[derive(Debug, Default)]
+pub struct Queue {
+    items: Vec<Item>,
+    ids: Vec<IdBuf>,
+}
+
+impl<A> GoogleDrive<A>
+where
+    A: GetToken,
+{
+    /// Queue the creation of `file` and return its id
+    pub async fn queue_create(
+        &self,
+        mut file: api::File,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<IdBuf> {
+        let mut queue = self.batch.lock().await;
+        if queue.ids.is_empty() {
+            queue.ids = self.files_generate_ids(MAX_BATCH_LEN, progress).await?;
+        }
+        let Some(id) = queue.ids.pop() else {
+            fsync::api_bail!("Drive did not generate any id");
+        };
+        file.id = Some(id.clone());
+        self.queue(&mut queue, Request::Create(file), progress)
+            .await?;
+        Ok(id)
+    }
+
+    pub async fn queue_delete(
+        &self,
+        id: &Id,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<()> {
+        let mut queue = self.batch.lock().await;
+        self.queue(&mut queue, Request::Delete(id.to_owned()), progress)
+            .await
+    }
+
+    /// Send all the queued requests
+    pub async fn flush_batch(&self) -> fsync::Result<()> {
+        let mut queue = self.batch.lock().await;
+        self.send_queue(&mut queue).await
+    }
+
+    async fn queue(
+        &self,
+        queue: &mut Queue,
+        request: Request,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<()> {
+        queue.items.push(Item {
+            request,
+            progress: progress.cloned(),
+        });
+        if queue.items.len() >= MAX_BATCH_LEN {
+            self.send_queue(queue).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_queue(&self, queue: &mut Queue) -> fsync::Result<()> {
+        let items = mem::take(&mut queue.items);
+        if items.is_empty() {
+            return Ok(());
+        }
+        log::trace!("sending {} batched request(s)", items.len());
+
+        let mut failed = Vec::new();
+        for wave in split_waves(items) {
+            for chunk in wave.chunks(MAX_BATCH_LEN) {
+                let results = self.send_batch(chunk).await;
+                for (item, res) in chunk.iter().zip(results) {
+                    if let Err(err) = res {
+                        log::error!("{err}");
+                        if let Some(progress) = &item.progress {
+                            progress.set(fsync::Progress::Err(err.clone()));
+                        }
+                        failed.push(err);
+                    }
+                }
+            }
+        }
+        match failed.len() {
+            0 => Ok(()),
+            1 => Err(failed.pop().unwrap()),
+            len => Err(fsync::api_error!(
+                "{len} batched requests failed, first error: {}",
+                failed[0]
+            )),
+        }
+    }
+
+    /// Send a batch and return the result of each item
+    async fn send_batch(&self, items: &[Item]) -> Vec<fsync::Result<()>> {
+        let boundary = make_boundary();
+        let body = build_body(&boundary, &self.api_path(), self.shared, items);
+        let res = match self.post_batch(&[api::Scope::Full], &boundary, body).await {
+            Ok(res) => res,
+            Err(err) => return vec![Err(err); items.len()],
+        };
+        let status = res.status();
+        let content_type = res
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|ct| ct.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let text = match res.text().await {
+            Ok(text) => text,
+            Err(err) => return vec![Err(fsync::api_error!("{err}")); items.len()],
+        };
+        if !status.is_success() {
+            let err = fsync::api_error!("POST batch returned {status}\n{text}");
+            return vec![Err(err); items.len()];
+        }
+        let Some(responses) = parse_response(&content_type, &text) else {
+            let err = fsync::api_error!("Could not parse batch response ({content_type})");
+            return vec![Err(err); items.len()];
+        };
+        items
+            .iter()
+            .enumerate()
+            .map(|(idx, item)| match responses.get(&idx) {
+                Some((status, body)) => item_result(&item.request, *status, body),
+                None => Err(fsync::api_error!("No response in batch for item {idx}")),
+            })
+            .collect()
+    }
+
+    /// The path of the API, that prefixes the paths of the batched requests
+    fn api_path(&self) -> String {
+        url::Url::parse(self.base_url)
+            .map(|url| url.path().to_string())
+            .unwrap_or_default()
+    }
+}
+
+fn item_result(request: &Request, status: StatusCode, body: &str) -> fsync::Result<()> {
+    match request {
+        _ if status.is_success() => Ok(()),
+        // deleted along with its parent folder, in the same batch
+        Request::Delete(..) if status == StatusCode::NOT_FOUND => Ok(()),
+        Request::Create(file) => fsync::api_bail!(
+            "Could not create folder {}: {status}\n{body}",
+            file.name.as_deref().unwrap_or_default()
+        ),
+        Request::Delete(id) => fsync::api_bail!("Could not delete {id}: {status}\n{body}"),
+    }
+}
+
+fn make_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("fsync_batch_{nanos:x}")
+}
+
+/// Split `items` in successive waves, where no item depends on a folder created in the same wave.
+/// The order of the items is kept within each wave.
+fn split_waves(items: Vec<Item>) -> Vec<Vec<Item>> {
+    let mut created: HashMap<String, usize> = HashMap::new();
+    let mut waves: Vec<Vec<Item>> = Vec::new();
+    for item in items {
+        let wave = item
+            .request
+            .dependencies()
+            .into_iter()
+            .filter_map(|id| created.get(id.as_str()).map(|w| w + 1))
+            .max()
+            .unwrap_or(0);
+        if let Some(id) = item.request.created() {
+            created.insert(id.as_str().to_string(), wave);
+        }
+        if waves.len() <= wave {
+            waves.resize_with(wave + 1, Vec::new);
+        }
+        waves[wave].push(item);
+    }
+    waves
+}
+
+fn build_body(boundary: &str, api_path: &str, shared: bool, items: &[Item]) -> String {
+    let query = if shared {
+        "?supportsAllDrives=true"
+    } else {
+        ""
+    };
+    let mut body = String::new();
+    for (idx, item) in items.iter().enumerate() {
+        body.push_str(&format!(
+            "--{boundary}\r\nContent-Type: application/http\r\nContent-ID: <item-{idx}>\r\n\r\n"
+        ));
+        match &item.request {
+            Request::Create(file) => {
+                let json = serde_json::to_string(file).expect("File should serialize");
+                body.push_str(&format!(
+                    "POST {api_path}/files{query} HTTP/1.1\r\n\
+                     Content-Type: application/json; charset=UTF-8\r\n\r\n{json}\r\n"
+                ));
+            }
+            Request::Delete(id) => {
+                body.push_str(&format!(
+                    "DELETE {api_path}/files/{id}{query} HTTP/1.1\r\n\r\n"
+                ));
+            }
+        }
+    }
+    body.push_str(&format!("--{boundary}--\r\n"));
+    body
+}
+
+/// Parse a `multipart/mixed` batch response into the status and body of each item
+fn parse_response(content_type: &str, text: &str) -> Option<HashMap<usize, (StatusCode, String)>> {
+    let boundary = content_type
+        .split(';')
+        .filter_map(|param| param.trim().strip_prefix("boundary="))
+        .next()?
+        .trim_matches('"');
+    let text = text.replace("\r\n", "\n");
+    let delimiter = format!("--{boundary}");
+
+    let mut responses = HashMap::new();
+    for part in text.split(&delimiter).skip(1) {
+        if part.starts_with("--") {
+            break;
+        }
+        let (headers, http) = part.trim_start_matches('\n').split_once("\n\n")?;
+        let idx = headers
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-id"))
+            .and_then(|(_, value)| {
+                value
+                    .trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .strip_prefix("response-item-")?
+                    .parse::<usize>()
+                    .ok()
+            })?;
+        let (status_line, rest) = http.split_once('\n').unwrap_or((http, ""));
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .and_then(|code| StatusCode::from_u16(code).ok())?;
+        let body = rest
+            .split_once("\n\n")
+            .map(|(_, body)| body.trim().to_string())
+            .unwrap_or_default();
+        responses.insert(idx, (status, body));
+    }
+    Some(responses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(id: &str, parent: &str, name: &str) -> Item {
+        Item {
+            request: Request::Create(api::File {
+                id: Some(IdBuf::from(id)),
+                name: Some(name.to_string()),
+                parents: Some(vec![IdBuf::from(parent)]),
+                ..Default::default()
+            }),
+            progress: None,
+        }
+    }
+
+    fn delete(id: &str) -> Item {
+        Item {
+            request: Request::Delete(IdBuf::from(id)),
+            progress: None,
+        }
+    }
+
+    fn wave_ids(wave: &[Item]) -> Vec<&str> {
+        wave.iter()
+            .map(|item| match &item.request {
+                Request::Create(file) => file.id.as_deref().unwrap().as_str(),
+                Request::Delete(id) => id.as_str(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn waves_respect_dependencies() {
+        let items = vec![
+            create("a", "root", "a"),
+            delete("x"),
+            create("b", "a", "b"),
+            create("c", "root", "c"),
+            create("d", "b", "d"),
+            delete("b"),
+        ];
+        let waves = split_waves(items);
+        let waves: Vec<_> = waves.iter().map(|w| wave_ids(w)).collect();
+        assert_eq!(waves, vec![vec!["a", "x", "c"], vec!["b"], vec!["d", "b"]]);
+    }
+
+    #[test]
+    fn body() {
+        let items = vec![create("a", "root", "dir"), delete("x")];
+        let body = build_body("BND", "/drive/v3", true, &items);
+        let expected = "--BND\r\n\
+            Content-Type: application/http\r\n\
+            Content-ID: <item-0>\r\n\r\n\
+            POST /drive/v3/files?supportsAllDrives=true HTTP/1.1\r\n\
+            Content-Type: application/json; charset=UTF-8\r\n\r\n\
+            {\"id\":\"a\",\"name\":\"dir\",\"modifiedTime\":null,\"mimeType\":null,\"parents\":[\"root\"]}\r\n\
+            --BND\r\n\
+            Content-Type: application/http\r\n\
+            Content-ID: <item-1>\r\n\r\n\
+            DELETE /drive/v3/files/x?supportsAllDrives=true HTTP/1.1\r\n\r\n\
+            --BND--\r\n";
+        assert_eq!(body, expected);
+    }
+
+    #[test]
+    fn response() {
+        let text = "--batch_abc\r\n\
+            Content-Type: application/http\r\n\
+            Content-ID: <response-item-1>\r\n\r\n\
+            HTTP/1.1 404 Not Found\r\n\
+            Content-Type: application/json; charset=UTF-8\r\n\r\n\
+            {\"error\": {\"code\": 404}}\r\n\
+            --batch_abc\r\n\
+            Content-Type: application/http\r\n\
+            Content-ID: <response-item-0>\r\n\r\n\
+            HTTP/1.1 204 No Content\r\n\r\n\r\n\
+            --batch_abc--\r\n";
+        let responses = parse_response("multipart/mixed; boundary=batch_abc", text).unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[&0], (StatusCode::NO_CONTENT, String::new()));
+        assert_eq!(responses[&1].0, StatusCode::NOT_FOUND);
+        assert!(responses[&1].1.contains("404"));
+
+        // a delete not found was deleted with its parent
+        assert!(item_result(&delete("x").request, StatusCode::NOT_FOUND, "").is_ok());
+        let create = create("a", "root", "dir").request;
+        assert!(item_result(&create, StatusCode::NOT_FOUND, "").is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! Read-through cache of the content of the remote files.
+//!
+//! The content downloaded from Drive is kept in the [`DiskCache`], within its budget,
+//! under a key made of the id of the file and of the MD5 checksum reported by Drive.
+//! A cached content is served only if it still has this checksum, so that a corrupted
+//! entry is downloaded again. The entry of a file is invalidated when a listing reports
+//! another checksum or modification time for it.
+
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use chrono::{DateTime, Utc};
+use md5::Digest as _;
+use tokio::io::{self, AsyncRead, ReadBuf};
+
+use crate::{
+    disk_cache::DiskCache,
+    storage::id::{Id, IdBuf},
+};
+
+/// Files larger than this are not cached, as their content is buffered until it is verified
+pub const MAX_FILE_SIZE: u64 = 32 * 1024 * 1024;
+
+/// A file as last listed
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Version {
+    md5: String,
+    size: Option<u64>,
+    mtime: Option<DateTime<Utc>>,
+}
+
+impl Version {
+    fn key(&self, id: &Id) -> String {
+        let mut hasher = DefaultHasher::new();
+        (id.as_str(), self.md5.as_str()).hash(&mut hasher);
+        format!("drive-{:016x}", hasher.finish())
+    }
+
+    fn cacheable(&self) -> bool {
+        self.size.is_some_and(|size| size <= MAX_FILE_SIZE)
+    }
+}
+
+#[derive(Debug)]
+pub struct ContentCache {
+    disk: Arc<DiskCache>,
+    /// The files seen in the responses of Drive, by id
+    versions: Mutex<HashMap<IdBuf, Version>>,
+}
+
+impl ContentCache {
+    pub fn new(disk: Arc<DiskCache>) -> Self {
+        Self {
+            disk,
+            versions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keep the checksum of the file `id` as reported by Drive.
+    /// The content cached for another checksum or modification time is invalidated.
+    pub async fn record(
+        &self,
+        id: &Id,
+        md5: Option<&str>,
+        size: Option<u64>,
+        mtime: Option<DateTime<Utc>>,
+    ) {
+        let previous = {
+            let mut versions = self.versions.lock().unwrap();
+            match md5 {
+                Some(md5) => {
+                    let version = Version {
+                        md5: md5.to_owned(),
+                        size,
+                        mtime,
+                    };
+                    versions
+                        .insert(id.to_owned(), version.clone())
+                        .filter(|previous| *previous != version)
+                }
+                None => versions.remove(id),
+            }
+        };
+        if let Some(previous) = previous {
+            log::debug!("invalidating the cached content of {id}");
+            self.disk.remove(&previous.key(id)).await;
+        }
+    }
+
+    /// The checksum of the file `id` last reported by Drive
+    pub fn md5(&self, id: &Id) -> Option<String> {
+        let versions = self.versions.lock().unwrap();
+        versions.get(id).map(|version| version.md5.clone())
+    }
+
+    fn version(&self, id: &Id) -> Option<Version> {
+        let versions = self.versions.lock().unwrap();
+        versions
+            .get(id)
+            .filter(|version| version.cacheable())
+            .cloned()
+    }
+
+    /// The cached content of the file `id`, if it still has the checksum reported by Drive.
+    /// A content that does not is deleted from the cache.
+    pub async fn get(&self, id: &Id) -> Option<Vec<u8>> {
+        let version = self.version(id)?;
+        let key = version.key(id);
+        let data = match self.disk.get(&key).await {
+            Some(data) if hex::encode(md5::Md5::digest(&data)) == version.md5 => Some(data),
+            Some(_) => {
+                log::warn!("The cached content of {id} is corrupted, downloading it again");
+                self.disk.remove(&key).await;
+                None
+            }
+            None => None,
+        };
+        self.disk.record_lookup(data.is_some());
+        data
+    }
+
+    /// Cache the content of the file `id` as it is read from `read`
+    pub fn tee<R>(&self, id: &Id, read: R) -> Tee<R> {
+        let sink = self.version(id).map(|version| Sink {
+            disk: self.disk.clone(),
+            key: version.key(id),
+            md5: version.md5,
+            hasher: md5::Md5::new(),
+            data: Vec::new(),
+        });
+        Tee {
+            inner: Box::pin(read),
+            sink,
+        }
+    }
+}
+
+struct Sink {
+    disk: Arc<DiskCache>,
+    key: String,
+    md5: String,
+    hasher: md5::Md5,
+    data: Vec<u8>,
+}
+
+impl Sink {
+    /// Cache the data read if it has the expected checksum
+    fn finish(self) {
+        let md5 = hex::encode(self.hasher.finalize());
+        if md5 != self.md5 {
+            log::warn!(
+                "Downloaded content {} has checksum {md5}, expected {}, not caching it",
+                self.key,
+                self.md5
+            );
+            return;
+        }
+        let Self {
+            disk, key, data, ..
+        } = self;
+        tokio::spawn(async move {
+            if let Err(err) = disk.put(&key, &data).await {
+                log::warn!("Could not cache the content {key}: {err}");
+            }
+        });
+    }
+}
+
+/// A reader that passes the data of another and caches it once it is read entirely
+pub struct Tee<R> {
+    inner: Pin<Box<R>>,
+    sink: Option<Sink>,
+}
+
+impl<R> Tee<R> {
+    /// Pass the data of `read` without caching it
+    pub fn new(read: R) -> Self {
+        Self {
+            inner: Box::pin(read),
+            sink: None,
+        }
+    }
+}
+
+impl<R> AsyncRead for Tee<R>
+where
+    R: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let res = this.inner.as_mut().poll_read(cx, buf);
+        match &res {
+            // nothing read in a buffer with room left is the end of the data
+            Poll::Ready(Ok(())) if buf.filled().len() == filled && buf.remaining() > 0 => {
+                if let Some(sink) = this.sink.take() {
+                    sink.finish();
+                }
+            }
+            Poll::Ready(Ok(())) => {
+                let data = &buf.filled()[filled..];
+                if let Some(sink) = this.sink.as_mut() {
+                    sink.hasher.update(data);
+                    sink.data.extend_from_slice(data);
+                    if sink.data.len() as u64 > MAX_FILE_SIZE {
+                        this.sink = None;
+                    }
+                }
+            }
+            Poll::Ready(Err(_)) => this.sink = None,
+            Poll::Pending => (),
+        }
+        res
+    }
+}
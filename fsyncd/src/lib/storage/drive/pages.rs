@@ -0,0 +1,133 @@
+//! Prefetching of the pages of a listing.
+//!
+//! Drive pages are chained by their token, so they can only be fetched one after
+//! the other. They can however be fetched while the previous ones are consumed:
+//! a task fetches the pages ahead of the consumer, up to [`PREFETCH_PAGES`].
+
+use std::future::Future;
+
+use futures::Stream;
+use tokio::sync::mpsc;
+
+/// Number of pages fetched ahead of the consumer
+pub const PREFETCH_PAGES: usize = 1;
+
+/// Stream of the pages returned by `fetch`, which is called with the token of the
+/// page to fetch (`None` for the first one) and returns the page and the token
+/// of the next one, if any.
+///
+/// The pages are fetched in a spawned task, which stops when the stream is dropped.
+pub fn prefetched<P, F, Fut>(mut fetch: F) -> impl Stream<Item = fsync::Result<P>> + Send
+where
+    P: Send + 'static,
+    F: FnMut(Option<String>) -> Fut + Send + 'static,
+    Fut: Future<Output = fsync::Result<(P, Option<String>)>> + Send,
+{
+    let (tx, mut rx) = mpsc::channel(PREFETCH_PAGES);
+    let task = tokio::spawn(async move {
+        let mut token = None;
+        loop {
+            let (page, next) = match fetch(token).await {
+                Ok(res) => res,
+                Err(err) => {
+                    let _ = tx.send(Err(err)).await;
+                    break;
+                }
+            };
+            if tx.send(Ok(page)).await.is_err() || next.is_none() {
+                break;
+            }
+            token = next;
+        }
+    });
+    let task = AbortOnDrop(task);
+
+    async_stream::stream! {
+        let _task = task;
+        while let Some(page) = rx.recv().await {
+            yield page;
+        }
+    }
+}
+
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use futures::StreamExt;
+
+    use super::*;
+
+    const PAGES: usize = 10;
+    const PAGE_SZ: usize = 1000;
+    const LATENCY: Duration = Duration::from_millis(40);
+
+    async fn fetch(token: Option<String>) -> fsync::Result<(Vec<usize>, Option<String>)> {
+        tokio::time::sleep(LATENCY).await;
+        let page: usize = token.map(|t| t.parse().unwrap()).unwrap_or(0);
+        let entries = (page * PAGE_SZ..(page + 1) * PAGE_SZ).collect();
+        let next = (page + 1 < PAGES).then(|| (page + 1).to_string());
+        Ok((entries, next))
+    }
+
+    /// Mapping and inserting the entries of a page takes as long as fetching it
+    async fn consume(page: Vec<usize>, entries: &mut Vec<usize>) {
+        tokio::time::sleep(LATENCY).await;
+        entries.extend(page);
+    }
+
+    #[tokio::test]
+    async fn prefetched_pages_overlap_consumption() {
+        let start = Instant::now();
+        let mut sequential = Vec::new();
+        let mut token = None;
+        loop {
+            let (page, next) = fetch(token).await.unwrap();
+            consume(page, &mut sequential).await;
+            if next.is_none() {
+                break;
+            }
+            token = next;
+        }
+        let sequential_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut entries = Vec::new();
+        let pages = prefetched(fetch);
+        tokio::pin!(pages);
+        while let Some(page) = pages.next().await {
+            consume(page.unwrap(), &mut entries).await;
+        }
+        let prefetched_time = start.elapsed();
+
+        assert_eq!(entries.len(), PAGES * PAGE_SZ);
+        assert_eq!(entries, sequential);
+        // ideally (PAGES + 1) / (2 * PAGES) of the sequential time
+        assert!(
+            prefetched_time.as_secs_f64() < 0.7 * sequential_time.as_secs_f64(),
+            "prefetched listing took {prefetched_time:?}, sequential {sequential_time:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn prefetched_error_ends_the_stream() {
+        let pages = prefetched(|token: Option<String>| async move {
+            match token {
+                None => Ok((1, Some("next".to_string()))),
+                Some(_) => fsync::api_bail!("page failed"),
+            }
+        });
+        let pages: Vec<_> = pages.collect().await;
+        assert_eq!(pages.len(), 2);
+        assert_eq!(*pages[0].as_ref().unwrap(), 1);
+        assert!(pages[1].is_err());
+    }
+}
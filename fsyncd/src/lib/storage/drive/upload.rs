@@ -0,0 +1,172 @@
+//! Adaptive chunk size of the resumable uploads to Drive.
+//!
+//! The chunks start at [`INITIAL_CHUNK_SZ`], double when they are sent quickly
+//! at the first attempt, up to a configurable maximum, and are halved after a
+//! timeout or a server error. The resumable protocol requires that all chunks but
+//! the last one are multiples of [`CHUNK_GRANULARITY`], which every size keeps.
+
+use std::time::Duration;
+
+/// Chunks must be multiples of this size, except the last one of an upload
+pub const CHUNK_GRANULARITY: u64 = 256 * 1024;
+/// Size of the first chunk of an upload
+pub const INITIAL_CHUNK_SZ: u64 = 4 * CHUNK_GRANULARITY;
+/// Default maximum size of the chunks
+pub const DEFAULT_MAX_CHUNK_SZ: u64 = 128 * CHUNK_GRANULARITY;
+/// A chunk sent faster than this makes the next one larger
+const FAST_CHUNK: Duration = Duration::from_secs(2);
+
+/// Computes the size of the next chunk of an upload
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkSizer {
+    size: u64,
+    max: u64,
+}
+
+impl ChunkSizer {
+    /// A sizer growing up to `max`, rounded down to a multiple of [`CHUNK_GRANULARITY`]
+    pub fn new(max: u64) -> Self {
+        let max = (max / CHUNK_GRANULARITY).max(1) * CHUNK_GRANULARITY;
+        Self {
+            size: INITIAL_CHUNK_SZ.min(max),
+            max,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// A chunk of `len` bytes was sent in `elapsed` at the first attempt
+    pub fn on_success(&mut self, len: u64, elapsed: Duration) {
+        // the last chunk of an upload tells nothing about the link
+        if len == self.size && elapsed < FAST_CHUNK {
+            self.size = (self.size * 2).min(self.max);
+        }
+    }
+
+    /// Keep the chunks within `max` bytes, rounded down to a multiple of [`CHUNK_GRANULARITY`]
+    pub fn clamp(&mut self, max: u64) {
+        let max = (max / CHUNK_GRANULARITY).max(1) * CHUNK_GRANULARITY;
+        self.size = self.size.min(max);
+    }
+
+    /// A chunk failed with a timeout or a server error
+    pub fn on_failure(&mut self) {
+        self.size = (self.size / 2 / CHUNK_GRANULARITY).max(1) * CHUNK_GRANULARITY;
+    }
+}
+
+/// Statistics of the uploads, to observe the effect of the chunk size adaptation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UploadStats {
+    /// Number of completed uploads
+    pub files: u64,
+    /// Number of bytes of the completed uploads
+    pub bytes: u64,
+    /// Number of bytes sent more than once, after failures or partial receptions
+    pub retransmitted: u64,
+    /// The chunk size reached by the last upload
+    pub chunk_size: u64,
+}
+
+impl UploadStats {
+    pub fn record(&mut self, bytes: u64, retransmitted: u64, chunk_size: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+        self.retransmitted += retransmitted;
+        self.chunk_size = chunk_size;
+    }
+}
+
+/// Number of bytes received by Drive according to the `Range` header of a
+/// `308 Resume Incomplete` response. No header means that nothing was received.
+pub fn received_bytes(range: Option<&str>) -> Option<u64> {
+    let Some(range) = range else {
+        return Some(0);
+    };
+    let (first, last) = range.strip_prefix("bytes=")?.split_once('-')?;
+    if first.trim() != "0" {
+        return None;
+    }
+    last.trim().parse::<u64>().ok().map(|last| last + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: Duration = Duration::from_millis(100);
+    const SLOW: Duration = Duration::from_secs(10);
+
+    fn is_valid(size: u64) -> bool {
+        size >= CHUNK_GRANULARITY && size.is_multiple_of(CHUNK_GRANULARITY)
+    }
+
+    #[test]
+    fn grows_on_fast_chunks_up_to_max() {
+        let mut sizer = ChunkSizer::new(DEFAULT_MAX_CHUNK_SZ);
+        assert_eq!(sizer.size(), INITIAL_CHUNK_SZ);
+
+        sizer.on_success(sizer.size(), SLOW);
+        assert_eq!(sizer.size(), INITIAL_CHUNK_SZ);
+
+        sizer.on_success(sizer.size() / 2, FAST);
+        assert_eq!(sizer.size(), INITIAL_CHUNK_SZ);
+
+        sizer.on_success(sizer.size(), FAST);
+        assert_eq!(sizer.size(), 2 * INITIAL_CHUNK_SZ);
+
+        for _ in 0..20 {
+            sizer.on_success(sizer.size(), FAST);
+            assert!(is_valid(sizer.size()));
+        }
+        assert_eq!(sizer.size(), DEFAULT_MAX_CHUNK_SZ);
+    }
+
+    #[test]
+    fn shrinks_on_failure_down_to_granularity() {
+        let mut sizer = ChunkSizer::new(DEFAULT_MAX_CHUNK_SZ);
+        sizer.on_failure();
+        assert_eq!(sizer.size(), INITIAL_CHUNK_SZ / 2);
+        for _ in 0..10 {
+            sizer.on_failure();
+            assert!(is_valid(sizer.size()));
+        }
+        assert_eq!(sizer.size(), CHUNK_GRANULARITY);
+    }
+
+    #[test]
+    fn max_is_a_multiple_of_granularity() {
+        let mut sizer = ChunkSizer::new(3 * CHUNK_GRANULARITY + 1000);
+        assert_eq!(sizer.size(), 3 * CHUNK_GRANULARITY);
+        sizer.on_failure();
+        assert_eq!(sizer.size(), CHUNK_GRANULARITY);
+        sizer.on_success(sizer.size(), FAST);
+        sizer.on_success(sizer.size(), FAST);
+        assert_eq!(sizer.size(), 3 * CHUNK_GRANULARITY);
+
+        let sizer = ChunkSizer::new(1000);
+        assert_eq!(sizer.size(), CHUNK_GRANULARITY);
+    }
+
+    #[test]
+    fn clamped_to_the_memory_budget() {
+        let mut sizer = ChunkSizer::new(DEFAULT_MAX_CHUNK_SZ);
+        sizer.on_success(sizer.size(), FAST);
+        sizer.clamp(3 * CHUNK_GRANULARITY + 1000);
+        assert_eq!(sizer.size(), 3 * CHUNK_GRANULARITY);
+        sizer.clamp(1000);
+        assert_eq!(sizer.size(), CHUNK_GRANULARITY);
+        sizer.on_success(sizer.size(), FAST);
+        assert_eq!(sizer.size(), 2 * CHUNK_GRANULARITY);
+    }
+
+    #[test]
+    fn received_bytes_from_range() {
+        assert_eq!(received_bytes(None), Some(0));
+        assert_eq!(received_bytes(Some("bytes=0-524287")), Some(524288));
+        assert_eq!(received_bytes(Some("bytes=10-20")), None);
+        assert_eq!(received_bytes(Some("garbage")), None);
+    }
+}
@@ -1,16 +1,74 @@
-use async_stream::try_stream;
-use fsync::path::{FsPath, FsPathBuf, Path, PathBuf};
-use futures::Stream;
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_stream::stream;
+use dashmap::DashMap;
+use fsync::{
+    path::{FsPath, FsPathBuf, Path, PathBuf},
+    Mapping, MinFreeSpace,
+};
+use futures::{Stream, StreamExt};
 use tokio::{
     fs::{self, DirEntry},
-    io,
+    io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::Semaphore,
 };
+use tokio_stream::wrappers::ReadDirStream;
+
+use crate::{
+    buffers::{self, BufferPool},
+    maintenance, placeholders,
+    profile::{Elapsed, Phase, Span, TimedRead},
+    SharedProgress, Shutdown,
+};
+
+/// Size of the chunks copied during file writes
+const WRITE_CHUNK_SZ: usize = 64 * 1024;
+/// Free space is checked every this many chunks during file writes
+const SPACE_CHECK_CHUNKS: usize = 16;
+
+/// A guard that keeps a minimum of free space on the disk
+#[derive(Debug, Clone, Copy)]
+pub struct SpaceGuard {
+    pub min_free: MinFreeSpace,
+    /// How long to wait for space to be freed before aborting
+    pub timeout: Duration,
+    /// Interval between checks while waiting for space to be freed
+    pub retry_interval: Duration,
+}
 
-use crate::{SharedProgress, Shutdown};
+impl SpaceGuard {
+    pub fn new(min_free: MinFreeSpace) -> Self {
+        Self {
+            min_free,
+            timeout: Duration::from_secs(600),
+            retry_interval: Duration::from_secs(5),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct FileSystem {
     root: FsPathBuf,
+    space_guard: Option<SpaceGuard>,
+    /// First path seen for each (device, inode) pair of hard linked files
+    inodes: Arc<DashMap<(u64, u64), PathBuf>>,
+    hide_placeholders: bool,
+    mappings: Arc<Vec<Mapping>>,
+    /// Bounds the directories opened and the entries read concurrently, by all the listings
+    scan: Arc<Semaphore>,
+    /// Entries read ahead by a listing, so that a huge directory holds a bounded memory
+    scan_concurrency: usize,
+    /// The budget of the buffers of the file writes
+    buffers: Option<BufferPool>,
+}
+
+/// Number of local entries read concurrently by default, twice the number of CPUs
+pub fn default_scan_concurrency() -> usize {
+    std::thread::available_parallelism().map_or(8, |cpus| 2 * cpus.get())
 }
 
 impl FileSystem {
@@ -25,69 +83,436 @@ impl FileSystem {
         let root = root.canonicalize_utf8()?;
         log::info!("Initializing FS storage in {root}");
 
-        Ok(FileSystem { root })
+        Ok(FileSystem {
+            root,
+            space_guard: None,
+            inodes: Arc::new(DashMap::new()),
+            hide_placeholders: false,
+            mappings: Arc::new(Vec::new()),
+            scan: Arc::new(Semaphore::new(default_scan_concurrency())),
+            scan_concurrency: default_scan_concurrency(),
+            buffers: None,
+        })
+    }
+
+    /// Read at most `concurrency` entries at a time when listing directories
+    pub fn with_scan_concurrency(self, concurrency: usize) -> Self {
+        let concurrency = concurrency.max(1);
+        Self {
+            scan: Arc::new(Semaphore::new(concurrency)),
+            scan_concurrency: concurrency,
+            ..self
+        }
+    }
+
+    /// Rent the buffers of the file writes from `pool`
+    pub fn with_buffer_pool(self, pool: BufferPool) -> Self {
+        Self {
+            buffers: Some(pool),
+            ..self
+        }
+    }
+
+    /// Check free space before and during each file write.
+    pub fn with_space_guard(self, space_guard: SpaceGuard) -> Self {
+        Self {
+            space_guard: Some(space_guard),
+            ..self
+        }
+    }
+
+    /// Leave the placeholder files out of the directory listings.
+    /// They can still be created, read and deleted by path.
+    pub fn with_placeholders(self) -> Self {
+        Self {
+            hide_placeholders: true,
+            ..self
+        }
+    }
+
+    /// Translate the paths of the remote sub-trees of `mappings` to their local folders.
+    /// The missing local folders are created, as well as the parents of the remote sub-trees,
+    /// through which the local folders are listed.
+    /// Fails if a local entry is at the location of a remote sub-tree, where it would be hidden.
+    pub fn with_mappings(self, mappings: Vec<Mapping>) -> anyhow::Result<Self> {
+        Mapping::check_all(&mappings)?;
+        for mapping in &mappings {
+            let unmapped = self.root.join(mapping.remote.without_root().as_str());
+            if unmapped.symlink_metadata().is_ok() {
+                anyhow::bail!(
+                    "{unmapped} is hidden by the mapping of {} to {}. \
+                     Move its content to the mapped folder, or remove the mapping.",
+                    mapping.remote,
+                    mapping.local
+                );
+            }
+            if let Some(parent) = unmapped.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let local = self.root.join(mapping.local.without_root().as_str());
+            std::fs::create_dir_all(&local)?;
+            log::info!("Mapping {} to {local}", mapping.remote);
+        }
+        Ok(Self {
+            mappings: Arc::new(mappings),
+            ..self
+        })
     }
 
     pub fn root(&self) -> &FsPath {
         &self.root
     }
+
+    /// Whether the local entry at `path` is left out of the listings.
+    /// A mapped local folder is listed at the location of its remote sub-tree instead,
+    /// and an entry at the location of a remote sub-tree is hidden by the mapped folder.
+    fn is_hidden(&self, path: &Path) -> bool {
+        if self.mappings.iter().any(|m| m.remote == path) {
+            log::warn!("{path}: hidden by a mapping");
+            return true;
+        }
+        Mapping::map_path(&self.mappings, path).is_err()
+    }
+
+    /// The path in the filesystem of the entry at `path` of the tree
+    fn fs_path(&self, path: &Path) -> fsync::Result<FsPathBuf> {
+        let path = Mapping::map_path(&self.mappings, path)?;
+        Ok(self.root.join(path.without_root().as_str()))
+    }
 }
 
 impl FileSystem {
+    /// Set the link target of `metadata` if it is a hard link to a file already listed.
+    #[cfg(unix)]
+    fn check_hard_link(
+        &self,
+        metadata: fsync::Metadata,
+        fs_metadata: &std::fs::Metadata,
+    ) -> fsync::Metadata {
+        use std::os::unix::fs::MetadataExt;
+
+        if !fs_metadata.is_file() || fs_metadata.nlink() < 2 {
+            return metadata;
+        }
+        let first = self
+            .inodes
+            .entry((fs_metadata.dev(), fs_metadata.ino()))
+            .or_insert_with(|| metadata.path().to_owned())
+            .clone();
+        match metadata {
+            fsync::Metadata::Regular {
+                path, size, mtime, ..
+            } if path != first => {
+                log::debug!("{path} is a hard link to {first}");
+                fsync::Metadata::Regular {
+                    path,
+                    size,
+                    mtime,
+                    link_target: Some(first),
+                }
+            }
+            metadata => metadata,
+        }
+    }
+
+    /// Forget the hard links first seen at `path` or in its sub-tree
+    fn forget_links(&self, path: &Path) {
+        if !self.inodes.is_empty() {
+            self.inodes
+                .retain(|_, first| first != path && !path.is_ancestor_of(&*first));
+        }
+    }
+
+    /// Follow the hard links first seen at `src` or in its sub-tree to `dest`
+    fn move_links(&self, src: &Path, dest: &Path) {
+        for mut first in self.inodes.iter_mut() {
+            if *first == *src || src.is_ancestor_of(&*first) {
+                let rel = &first.as_str()[src.as_str().len()..];
+                *first = PathBuf::from(format!("{dest}{rel}"));
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn check_hard_link(
+        &self,
+        metadata: fsync::Metadata,
+        _fs_metadata: &std::fs::Metadata,
+    ) -> fsync::Metadata {
+        metadata
+    }
+
+    /// Wait until `required` bytes can be written while keeping the minimum free space.
+    /// Fails with [fsync::Error::InsufficientSpace] if the guard timeout is reached.
+    async fn ensure_free_space(
+        &self,
+        required: u64,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<()> {
+        let Some(guard) = &self.space_guard else {
+            return Ok(());
+        };
+        let start = Instant::now();
+        // the progress to restore once the space is available
+        let mut waited_from = None;
+        loop {
+            let available = fs2::available_space(&self.root)?;
+            let min_free = guard.min_free.bytes(fs2::total_space(&self.root)?);
+            let needed = required.saturating_add(min_free);
+            if available >= needed {
+                if let (Some(progress), Some(before)) = (progress, waited_from) {
+                    progress.set(before);
+                }
+                return Ok(());
+            }
+            if start.elapsed() >= guard.timeout {
+                log::error!(
+                    "{}: insufficient space to write {required} bytes",
+                    self.root
+                );
+                return Err(fsync::Error::InsufficientSpace {
+                    available,
+                    required: needed,
+                });
+            }
+            let reason = format!(
+                "Waiting for free space on {}: {available} bytes available, {needed} bytes needed",
+                self.root
+            );
+            log::warn!("{reason}");
+            if let Some(progress) = progress {
+                if waited_from.is_none() {
+                    waited_from = Some(progress.get());
+                }
+                progress.set(fsync::Progress::Waiting(reason));
+            }
+            tokio::time::sleep(guard.retry_interval).await;
+        }
+    }
+
+    /// Write `data` to a temporary sibling of `fs_path`, and rename it over `fs_path`
+    /// once complete, so that a failed write never leaves `fs_path` truncated
     async fn do_write(
         &self,
         fs_path: &FsPath,
         metadata: &fsync::Metadata,
         data: impl io::AsyncRead + Send,
+        progress: Option<&SharedProgress>,
     ) -> fsync::Result<fsync::Metadata> {
-        {
-            tokio::pin!(data);
+        self.do_write_with(fs_path, metadata, data, progress, tokio::fs::File::create)
+            .await
+    }
 
-            let mut f = tokio::fs::File::create(&fs_path).await?;
-            tokio::io::copy(&mut data, &mut f).await?;
+    /// Same as [`Self::do_write`], with the temporary file opened by `create`
+    async fn do_write_with<W, F, Fut>(
+        &self,
+        fs_path: &FsPath,
+        metadata: &fsync::Metadata,
+        data: impl io::AsyncRead + Send,
+        progress: Option<&SharedProgress>,
+        create: F,
+    ) -> fsync::Result<fsync::Metadata>
+    where
+        W: io::AsyncWrite + Send,
+        F: FnOnce(FsPathBuf) -> Fut,
+        Fut: Future<Output = io::Result<W>>,
+    {
+        let size = metadata.size().unwrap_or(0);
+        self.ensure_free_space(size, progress).await?;
 
+        let tmp_path = temp_sibling(fs_path);
+        let res = async {
+            let f = create(tmp_path.clone()).await.map_err(map_write_err)?;
+            let elapsed = Elapsed::new(Phase::LocalIo).with_detail(|| format!("write {fs_path}"));
+            self.copy_guarded(data, f, size, progress, elapsed).await?;
             if let Some(mtime) = metadata.mtime() {
-                let f = f.into_std().await;
+                let f = std::fs::File::options().write(true).open(&tmp_path)?;
                 f.set_modified(mtime.into())?;
             }
+            if let Ok(original) = fs::metadata(fs_path).await {
+                fs::set_permissions(&tmp_path, original.permissions()).await?;
+            }
+            fs::rename(&tmp_path, fs_path).await?;
+            Ok::<_, fsync::Error>(())
+        }
+        .await;
+        if let Err(err) = res {
+            if tmp_path.exists() {
+                log::info!("removing partially written {tmp_path}");
+                let _ = fs::remove_file(&tmp_path).await;
+            }
+            return Err(err);
         }
         let fs_metadata = tokio::fs::metadata(&fs_path).await?;
-        map_metadata(metadata.path().to_owned(), &fs_metadata, &fs_path).await
+        map_metadata(metadata.path().to_owned(), &fs_metadata, fs_path).await
+    }
+
+    /// Copy `data` to `f`, checking the free space along the way.
+    /// Only the writes to `f` are measured with `elapsed`, not the reads of `data`.
+    async fn copy_guarded(
+        &self,
+        data: impl io::AsyncRead + Send,
+        f: impl io::AsyncWrite + Send,
+        size: u64,
+        progress: Option<&SharedProgress>,
+        mut elapsed: Elapsed,
+    ) -> fsync::Result<()> {
+        tokio::pin!(data);
+        tokio::pin!(f);
+        let mut buf = buffers::rent(self.buffers.as_ref(), WRITE_CHUNK_SZ).await;
+        let mut written = 0u64;
+        let mut chunks = 0usize;
+        loop {
+            let n = data.read(&mut buf).await.map_err(map_write_err)?;
+            if n == 0 {
+                break;
+            }
+            let start = elapsed.start();
+            f.write_all(&buf[..n]).await.map_err(map_write_err)?;
+            elapsed.add(start, n as u64);
+            written += n as u64;
+            chunks += 1;
+            if chunks.is_multiple_of(SPACE_CHECK_CHUNKS) {
+                self.ensure_free_space(size.saturating_sub(written), progress)
+                    .await?;
+            }
+        }
+        let start = elapsed.start();
+        f.flush().await.map_err(map_write_err)?;
+        elapsed.add(start, 0);
+        Ok(())
     }
 }
 
-impl super::Exists for FileSystem
-{
+/// A path next to `fs_path` that does not exist yet, named after it with
+/// [`maintenance::TEMP_SUFFIX`] so that it is cleaned up if the daemon dies during the write
+fn temp_sibling(fs_path: &FsPath) -> FsPathBuf {
+    let name = fs_path.file_name().unwrap_or_default();
+    let first = fs_path.with_file_name(format!("{name}{}", maintenance::TEMP_SUFFIX));
+    let mut tmp = first.clone();
+    let mut i = 1;
+    while tmp.symlink_metadata().is_ok() {
+        tmp = FsPathBuf::from(format!("{first}.{i}"));
+        i += 1;
+    }
+    tmp
+}
+
+/// Report a full disk as [fsync::Error::InsufficientSpace] rather than a raw IO error
+fn map_write_err(err: io::Error) -> fsync::Error {
+    if err.kind() == io::ErrorKind::StorageFull {
+        fsync::Error::InsufficientSpace {
+            available: 0,
+            required: 0,
+        }
+    } else {
+        err.into()
+    }
+}
+
+impl super::Exists for FileSystem {
     async fn exists(&self, path: &Path) -> fsync::Result<bool> {
-        let fs_path = self.root.join(path.without_root().as_str());
+        let fs_path = self.fs_path(path)?;
         Ok(fs::metadata(fs_path).await.is_ok())
     }
 }
 
-
 impl super::DirEntries for FileSystem {
+    /// The entries are read concurrently, and listed in the order of the directory.
+    /// An entry that can't be read is listed as an error, and the listing goes on.
     fn dir_entries(
         &self,
         parent_path: &Path,
         _progress: Option<&SharedProgress>,
     ) -> impl Stream<Item = fsync::Result<fsync::Metadata>> + Send {
         debug_assert!(parent_path.is_absolute());
-        let fs_base = self.root.join(parent_path.without_root().as_str());
-        log::trace!("listing entries of {fs_base}");
-        try_stream! {
-            let mut read_dir = fs::read_dir(&fs_base).await?;
-            loop {
-                match read_dir.next_entry().await? {
-                    None => break,
-                    Some(direntry) => {
-                        yield map_direntry(&parent_path, &direntry).await?;
+        let fs_base = self.fs_path(parent_path);
+        stream! {
+            let read_dir = async {
+                let fs_base = fs_base?;
+                log::trace!("listing entries of {fs_base}");
+                // the hard links of the listed files are seen again, and the removed files are forgotten
+                if !self.inodes.is_empty() {
+                    self.inodes.retain(|_, first| first.parent() != Some(parent_path));
+                }
+                let _permit = self.scan.acquire().await;
+                Ok::<_, fsync::Error>(fs::read_dir(&fs_base).await?)
+            };
+            let read_dir = match read_dir.await {
+                Ok(read_dir) => read_dir,
+                Err(err) => {
+                    yield Err(err);
+                    return;
+                }
+            };
+            // in order, so that the first of the hard links is the same from one listing to the next
+            let entries = ReadDirStream::new(read_dir)
+                .map(|direntry| self.read_entry(parent_path, direntry))
+                .buffered(self.scan_concurrency);
+            futures::pin_mut!(entries);
+            while let Some(entry) = entries.next().await {
+                match entry {
+                    Ok(Some((metadata, _))) if self.is_hidden(metadata.path()) => (),
+                    Ok(Some((metadata, fs_metadata))) => {
+                        yield Ok(self.check_hard_link(metadata, &fs_metadata));
                     }
+                    Ok(None) => (),
+                    Err(err) => yield Err(err),
+                }
+            }
+            // the mapped local folders are listed at the location of their remote sub-tree
+            let mappings = self.mappings.iter().filter(|m| m.remote.parent() == Some(parent_path));
+            for mapping in mappings {
+                let fs_path = match self.fs_path(&mapping.remote) {
+                    Ok(fs_path) => fs_path,
+                    Err(err) => {
+                        yield Err(err);
+                        continue;
+                    }
+                };
+                if let Ok(fs_metadata) = fs::metadata(&fs_path).await {
+                    yield map_metadata(mapping.remote.clone(), &fs_metadata, &fs_path).await;
                 }
             }
         }
     }
 }
 
+impl FileSystem {
+    /// Read the metadata of an entry of the directory at `parent_path`.
+    /// `None` if the entry is left out: a placeholder, an entry that can't be synchronized,
+    /// or an entry removed since the directory was read.
+    async fn read_entry(
+        &self,
+        parent_path: &Path,
+        direntry: io::Result<DirEntry>,
+    ) -> fsync::Result<Option<(fsync::Metadata, std::fs::Metadata)>> {
+        let direntry = direntry?;
+        let _permit = self.scan.acquire().await;
+        let fs_metadata = match direntry.metadata().await {
+            Ok(fs_metadata) => fs_metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                let path = direntry.path();
+                return Err(fsync::Error::Io(format!("{}: {err}", path.display())));
+            }
+        };
+        if self.hide_placeholders && fs_metadata.is_file() && is_placeholder(&direntry) {
+            return Ok(None);
+        }
+        if !fs_metadata.is_file() && !fs_metadata.is_dir() {
+            // symlinks and special files
+            log::warn!(
+                "{}: not a file or a directory, skipped",
+                direntry.path().display()
+            );
+            return Ok(None);
+        }
+        let metadata = map_direntry(parent_path, &direntry, &fs_metadata).await?;
+        Ok(Some((metadata, fs_metadata)))
+    }
+}
+
 impl super::ReadFile for FileSystem {
     async fn read_file(
         &self,
@@ -95,9 +520,32 @@ impl super::ReadFile for FileSystem {
         _progress: Option<&SharedProgress>,
     ) -> fsync::Result<impl io::AsyncRead> {
         debug_assert!(path.is_absolute());
-        let fs_path = self.root.join(path.without_root().as_str());
+        let fs_path = self.fs_path(&path)?;
         log::trace!("reading {fs_path}");
-        Ok(tokio::fs::File::open(&fs_path).await?)
+        let elapsed = Elapsed::new(Phase::LocalIo).with_detail(|| format!("read {fs_path}"));
+        let file = tokio::fs::File::open(&fs_path).await?;
+        Ok(TimedRead::new(file, elapsed))
+    }
+}
+
+impl super::ReadFileRange for FileSystem {
+    async fn read_file_range(
+        &self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+        _progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead> {
+        debug_assert!(path.is_absolute());
+        let fs_path = self.fs_path(&path)?;
+        log::trace!(
+            "reading {fs_path} from {offset} to {}",
+            offset.saturating_add(len)
+        );
+        let elapsed = Elapsed::new(Phase::LocalIo).with_detail(|| format!("read {fs_path}"));
+        let mut file = tokio::fs::File::open(&fs_path).await?;
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        Ok(TimedRead::new(file.take(len), elapsed))
     }
 }
 
@@ -109,7 +557,7 @@ impl super::MkDir for FileSystem {
         _progress: Option<&SharedProgress>,
     ) -> fsync::Result<()> {
         debug_assert!(path.is_absolute());
-        let fs_path = self.root.join(path.without_root().as_str());
+        let fs_path = self.fs_path(path)?;
         log::info!("mkdir {}{}", if parents { "-p " } else { "" }, fs_path);
         if parents {
             tokio::fs::create_dir_all(&fs_path).await?;
@@ -125,10 +573,10 @@ impl super::CreateFile for FileSystem {
         &self,
         metadata: &fsync::Metadata,
         data: impl io::AsyncRead + Send,
-        _progress: Option<&SharedProgress>,
+        progress: Option<&SharedProgress>,
     ) -> fsync::Result<fsync::Metadata> {
         debug_assert!(metadata.path().is_absolute());
-        let fs_path = self.root.join(metadata.path().without_root().as_str());
+        let fs_path = self.fs_path(metadata.path())?;
         log::info!("creating {fs_path}");
         if fs_path.is_dir() {
             fsync::io_bail!("{} exists and is a direceory: {fs_path}", metadata.path());
@@ -136,7 +584,7 @@ impl super::CreateFile for FileSystem {
         if fs_path.exists() {
             fsync::io_bail!("{} already exists here: {fs_path}", metadata.path());
         }
-        Ok(self.do_write(&fs_path, metadata, data).await?)
+        self.do_write(&fs_path, metadata, data, progress).await
     }
 }
 
@@ -145,10 +593,10 @@ impl super::WriteFile for FileSystem {
         &self,
         metadata: &fsync::Metadata,
         data: impl io::AsyncRead + Send,
-        _progress: Option<&SharedProgress>,
+        progress: Option<&SharedProgress>,
     ) -> fsync::Result<fsync::Metadata> {
         debug_assert!(metadata.path().is_absolute());
-        let fs_path = self.root.join(metadata.path().without_root().as_str());
+        let fs_path = self.fs_path(metadata.path())?;
         log::info!("writing {fs_path}");
         if fs_path.is_dir() {
             fsync::io_bail!("{} is a direceory: {fs_path}", metadata.path());
@@ -156,7 +604,7 @@ impl super::WriteFile for FileSystem {
         if !fs_path.exists() {
             fsync::io_bail!("{} doesn't exists here: {fs_path}", metadata.path());
         }
-        Ok(self.do_write(&fs_path, metadata, data).await?)
+        self.do_write(&fs_path, metadata, data, progress).await
     }
 }
 
@@ -165,11 +613,11 @@ impl super::CopyFile for FileSystem {
         &self,
         src: &Path,
         dest: &Path,
-        _progress: Option<&SharedProgress>,
+        progress: Option<&SharedProgress>,
     ) -> fsync::Result<fsync::Metadata> {
         debug_assert!(src.is_absolute() && dest.is_absolute());
-        let fs_src = self.root.join(src.without_root().as_str());
-        let fs_dest = self.root.join(dest.without_root().as_str());
+        let fs_src = self.fs_path(src)?;
+        let fs_dest = self.fs_path(dest)?;
         log::info!("copying {fs_src} to {fs_dest}");
 
         if fs_src.is_dir() {
@@ -186,7 +634,14 @@ impl super::CopyFile for FileSystem {
             fsync::io_bail!("{fs_dest_dir}: No such directory");
         }
 
-        tokio::fs::copy(&fs_src, &fs_dest).await?;
+        let size = fs::metadata(&fs_src).await?.len();
+        self.ensure_free_space(size, progress).await?;
+        let _span = Span::enter(Phase::LocalIo)
+            .with_detail(|| format!("copy {fs_src}"))
+            .with_bytes(size);
+        tokio::fs::copy(&fs_src, &fs_dest)
+            .await
+            .map_err(map_write_err)?;
         let fs_metadata = tokio::fs::metadata(&fs_dest).await?;
         map_metadata(dest.to_owned(), &fs_metadata, &fs_dest).await
     }
@@ -200,8 +655,8 @@ impl super::MoveEntry for FileSystem {
         _progress: Option<&SharedProgress>,
     ) -> fsync::Result<fsync::Metadata> {
         debug_assert!(src.is_absolute() && dest.is_absolute());
-        let fs_src = self.root.join(src.without_root().as_str());
-        let fs_dest = self.root.join(dest.without_root().as_str());
+        let fs_src = self.fs_path(src)?;
+        let fs_dest = self.fs_path(dest)?;
         log::info!("moving {fs_src} to {fs_dest}");
 
         if !fs_src.exists() {
@@ -216,15 +671,35 @@ impl super::MoveEntry for FileSystem {
         }
 
         tokio::fs::rename(&fs_src, &fs_dest).await?;
+        self.move_links(src, dest);
         let fs_metadata = tokio::fs::metadata(&fs_dest).await?;
         map_metadata(dest.to_owned(), &fs_metadata, &fs_dest).await
     }
 }
 
+impl super::PatchMetadata for FileSystem {
+    async fn patch(
+        &self,
+        path: &Path,
+        patch: &super::MetadataPatch,
+    ) -> fsync::Result<fsync::Metadata> {
+        debug_assert!(path.is_absolute());
+        let fs_path = self.fs_path(path)?;
+
+        let f = std::fs::File::options().write(true).open(&fs_path)?;
+        if let Some(mtime) = patch.mtime {
+            log::info!("setting the modification time of {fs_path} to {mtime}");
+            f.set_modified(mtime.into())?;
+        }
+        let fs_metadata = f.metadata()?;
+        map_metadata(path.to_owned(), &fs_metadata, &fs_path).await
+    }
+}
+
 impl super::Delete for FileSystem {
     async fn delete(&self, path: &Path, _progress: Option<&SharedProgress>) -> fsync::Result<()> {
         debug_assert!(path.is_absolute());
-        let fs_path = self.root.join(path.without_root().as_str());
+        let fs_path = self.fs_path(path)?;
         log::info!("deleting {fs_path}");
         let md = fs::metadata(&fs_path).await;
         if md.is_err() {
@@ -241,21 +716,46 @@ impl super::Delete for FileSystem {
         } else {
             fs::remove_file(&fs_path).await?;
         }
+        self.forget_links(path);
         Ok(())
     }
 }
 
+impl super::ExistingChildren for FileSystem {}
+
+impl super::Quota for FileSystem {}
+
+impl super::Flush for FileSystem {}
+
+impl super::Shared for FileSystem {}
+
+impl super::Relist for FileSystem {}
+
+impl super::Revisions for FileSystem {}
+
+impl super::Checksums for FileSystem {}
+
 impl Shutdown for FileSystem {}
 
 impl super::Storage for FileSystem {}
 impl super::LocalStorage for FileSystem {}
 
-async fn map_direntry(parent_path: &Path, direntry: &DirEntry) -> fsync::Result<fsync::Metadata> {
+fn is_placeholder(direntry: &DirEntry) -> bool {
+    direntry
+        .file_name()
+        .to_str()
+        .is_some_and(placeholders::is_placeholder)
+}
+
+async fn map_direntry(
+    parent_path: &Path,
+    direntry: &DirEntry,
+    metadata: &std::fs::Metadata,
+) -> fsync::Result<fsync::Metadata> {
     let fs_path = FsPathBuf::try_from(direntry.path())?;
     let file_name = String::from_utf8(direntry.file_name().into_encoded_bytes())?;
     let path = parent_path.join(&file_name);
-    let metadata = direntry.metadata().await?;
-    map_metadata(path, &metadata, &fs_path).await
+    map_metadata(path, metadata, &fs_path).await
 }
 
 async fn map_metadata(
@@ -279,15 +779,367 @@ async fn map_metadata(
             path,
             size: metadata.len(),
             mtime: metadata.modified().map(|mt| mt.into())?,
+            link_target: None,
         }
     } else {
         assert!(metadata.is_dir());
-        fsync::Metadata::Directory { path, stat: None }
+        fsync::Metadata::Directory {
+            path,
+            stat: None,
+        }
     };
 
     Ok(metadata)
 }
 
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use fsync::path::PathBuf;
+
+    use super::*;
+    use crate::storage::CreateFile;
+
+    /// A writer that fails with a full disk error after `len` bytes
+    struct FailingWriter {
+        len: usize,
+    }
+
+    impl io::AsyncWrite for FailingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.len == 0 {
+                return Poll::Ready(Err(io::ErrorKind::StorageFull.into()));
+            }
+            let n = self.len.min(buf.len());
+            self.len -= n;
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Open the temporary file as the real write would, but write through a [`FailingWriter`]
+    async fn failing_create(path: FsPathBuf) -> io::Result<FailingWriter> {
+        tokio::fs::File::create(path).await?;
+        Ok(FailingWriter {
+            len: WRITE_CHUNK_SZ,
+        })
+    }
+
+    fn test_root(name: &str) -> FsPathBuf {
+        let root = std::env::temp_dir().join(format!("fsyncd-fs-{name}"));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        FsPathBuf::try_from(root).unwrap()
+    }
+
+    fn file_metadata(path: &str, size: u64) -> fsync::Metadata {
+        fsync::Metadata::Regular {
+            path: PathBuf::from(path),
+            size,
+            mtime: chrono::Utc::now(),
+            link_target: None,
+        }
+    }
+
+    fn dir_names(root: &FsPath) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(root)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn create_file_disk_full_cleans_up() {
+        let root = test_root("disk-full");
+        let fs = FileSystem::new(&root).unwrap();
+        let md = file_metadata("/file.bin", 3 * WRITE_CHUNK_SZ as u64);
+        let data = vec![0u8; 3 * WRITE_CHUNK_SZ];
+
+        let err = fs
+            .do_write_with(&root.join("file.bin"), &md, &data[..], None, failing_create)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, fsync::Error::InsufficientSpace { .. }));
+        assert!(dir_names(&root).is_empty());
+    }
+
+    #[tokio::test]
+    async fn write_file_disk_full_keeps_original() {
+        let root = test_root("disk-full-overwrite");
+        std::fs::write(root.join("file.bin"), b"original").unwrap();
+        let fs = FileSystem::new(&root).unwrap();
+        let md = file_metadata("/file.bin", 3 * WRITE_CHUNK_SZ as u64);
+        let data = vec![0u8; 3 * WRITE_CHUNK_SZ];
+
+        let err = fs
+            .do_write_with(&root.join("file.bin"), &md, &data[..], None, failing_create)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, fsync::Error::InsufficientSpace { .. }));
+        assert_eq!(dir_names(&root), ["file.bin"]);
+        assert_eq!(std::fs::read(root.join("file.bin")).unwrap(), b"original");
+
+        fs.do_write(&root.join("file.bin"), &md, &data[..], None)
+            .await
+            .unwrap();
+        assert_eq!(dir_names(&root), ["file.bin"]);
+        assert_eq!(std::fs::read(root.join("file.bin")).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn create_file_space_guard_timeout() {
+        let root = test_root("space-guard");
+        let fs = FileSystem::new(&root)
+            .unwrap()
+            .with_space_guard(SpaceGuard {
+                min_free: MinFreeSpace::Percent(100.0),
+                timeout: Duration::from_millis(50),
+                retry_interval: Duration::from_millis(10),
+            });
+        let md = file_metadata("/file.txt", 4);
+        let progress = SharedProgress::new();
+
+        let err = fs
+            .create_file(&md, &b"data"[..], Some(&progress))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, fsync::Error::InsufficientSpace { .. }));
+        assert!(matches!(progress.get(), fsync::Progress::Waiting(..)));
+        assert!(!root.join("file.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn dir_entries_detects_hard_links() {
+        use futures::TryStreamExt;
+
+        use crate::storage::DirEntries;
+
+        let root = test_root("hard-links");
+        std::fs::write(root.join("a.txt"), b"content").unwrap();
+        std::fs::hard_link(root.join("a.txt"), root.join("b.txt")).unwrap();
+        std::fs::write(root.join("c.txt"), b"content").unwrap();
+
+        let fs = FileSystem::new(&root).unwrap();
+        let entries: Vec<_> = fs
+            .dir_entries(fsync::path::Path::root(), None)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 3);
+
+        let links: Vec<_> = entries.iter().filter_map(|e| e.link_target()).collect();
+        assert_eq!(links.len(), 1);
+        assert!(matches!(links[0].as_str(), "/a.txt" | "/b.txt"));
+
+        let data: i64 = entries.iter().map(|e| e.stat().unwrap().data).sum();
+        assert_eq!(data, 14);
+    }
+
+    #[tokio::test]
+    async fn dir_entries_order_does_not_depend_on_concurrency() {
+        use futures::TryStreamExt;
+
+        use crate::storage::DirEntries;
+
+        let root = test_root("scan-order");
+        for i in 0..200 {
+            std::fs::write(root.join(format!("file{i}.txt")), "content").unwrap();
+        }
+        std::fs::create_dir(root.join("dir")).unwrap();
+
+        let list = |concurrency| {
+            let fs = FileSystem::new(&root)
+                .unwrap()
+                .with_scan_concurrency(concurrency);
+            async move {
+                fs.dir_entries(Path::root(), None)
+                    .map_ok(|entry| entry.path().to_owned())
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .unwrap()
+            }
+        };
+        let sequential = list(1).await;
+        assert_eq!(sequential.len(), 201);
+        assert_eq!(list(16).await, sequential);
+        assert_eq!(list(0).await, sequential);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn dir_entries_go_on_after_unreadable_entries() {
+        use std::os::unix::fs::PermissionsExt;
+
+        use crate::storage::DirEntries;
+
+        let root = test_root("scan-errors");
+        std::fs::write(root.join("a.txt"), "content").unwrap();
+        std::os::unix::fs::symlink(root.join("a.txt"), root.join("link")).unwrap();
+        std::os::unix::fs::symlink(root.join("missing"), root.join("broken")).unwrap();
+
+        // the symlinks are left out
+        let fs = FileSystem::new(&root).unwrap();
+        let entries: Vec<_> = fs.dir_entries(Path::root(), None).collect().await;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].as_ref().unwrap().path(), Path::new("/a.txt"));
+
+        // the entries of a directory that can be read but not traversed can't be stated
+        let locked = root.join("locked");
+        std::fs::create_dir(&locked).unwrap();
+        std::fs::write(locked.join("b.txt"), "content").unwrap();
+        std::fs::write(locked.join("c.txt"), "content").unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o444)).unwrap();
+        let enforced = locked.join("b.txt").symlink_metadata().is_err();
+        let entries: Vec<_> = fs.dir_entries(Path::new("/locked"), None).collect().await;
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // not as root
+        if enforced {
+            assert_eq!(entries.len(), 2);
+            assert!(entries.iter().all(|entry| entry.is_err()));
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hard_links_follow_delete_and_move() {
+        use futures::TryStreamExt;
+
+        use crate::storage::{Delete, DirEntries, MoveEntry};
+
+        let root = test_root("hard-links-prune");
+        std::fs::create_dir(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir/a.txt"), b"content").unwrap();
+        std::fs::hard_link(root.join("dir/a.txt"), root.join("dir/b.txt")).unwrap();
+        std::fs::hard_link(root.join("dir/a.txt"), root.join("c.txt")).unwrap();
+
+        let fs = FileSystem::new(&root).unwrap();
+        let firsts = || {
+            let mut firsts: Vec<_> = fs.inodes.iter().map(|e| e.value().to_string()).collect();
+            firsts.sort();
+            firsts
+        };
+        let list = |path: &'static str| {
+            fs.dir_entries(Path::new(path), None)
+                .try_collect::<Vec<_>>()
+        };
+        list("/dir").await.unwrap();
+        list("/").await.unwrap();
+        let first = firsts();
+        assert_eq!(first.len(), 1);
+        assert!(matches!(first[0].as_str(), "/dir/a.txt" | "/dir/b.txt"));
+
+        fs.move_entry(Path::new("/dir"), Path::new("/moved"), None)
+            .await
+            .unwrap();
+        assert!(firsts()[0].starts_with("/moved/"));
+
+        let first = PathBuf::from(firsts()[0].clone());
+        fs.delete(&first, None).await.unwrap();
+        assert!(firsts().is_empty());
+
+        // the remaining links are seen again, and forgotten once removed out of the daemon
+        list("/").await.unwrap();
+        assert_eq!(firsts(), ["/c.txt"]);
+        std::fs::remove_file(root.join("c.txt")).unwrap();
+        list("/").await.unwrap();
+        assert!(firsts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_file_range_seeks() {
+        let root = test_root("range");
+        std::fs::write(root.join("file.txt"), b"0123456789").unwrap();
+        let fs = FileSystem::new(&root).unwrap();
+
+        let read = |offset, len| {
+            let fs = &fs;
+            async move {
+                let read = super::super::ReadFileRange::read_file_range(
+                    fs,
+                    PathBuf::from("/file.txt"),
+                    offset,
+                    len,
+                    None,
+                )
+                .await
+                .unwrap();
+                tokio::pin!(read);
+                let mut data = Vec::new();
+                read.read_to_end(&mut data).await.unwrap();
+                data
+            }
+        };
+        assert_eq!(read(3, 4).await, b"3456");
+        assert_eq!(read(8, 4).await, b"89");
+        assert_eq!(read(12, 4).await, b"");
+    }
+
+    #[tokio::test]
+    async fn mappings_translate_paths() {
+        use futures::TryStreamExt;
+
+        use crate::storage::{DirEntries, Exists};
+
+        let root = test_root("mappings");
+        std::fs::create_dir_all(root.join("projects/x")).unwrap();
+        std::fs::write(root.join("projects/x/a.txt"), b"a").unwrap();
+        std::fs::write(root.join("projects/b.txt"), b"b").unwrap();
+        let mapping = Mapping {
+            remote: PathBuf::from("/Work/ProjectX"),
+            local: PathBuf::from("/projects/x"),
+        };
+
+        let fs = FileSystem::new(&root)
+            .unwrap()
+            .with_mappings(vec![mapping.clone()])
+            .unwrap();
+        let names = |parent: &'static str| {
+            let fs = &fs;
+            async move {
+                let entries: Vec<_> = fs
+                    .dir_entries(Path::new(parent), None)
+                    .try_collect()
+                    .await
+                    .unwrap();
+                let mut names: Vec<_> = entries.iter().map(|e| e.path().to_string()).collect();
+                names.sort();
+                names
+            }
+        };
+        // the parents of the remote sub-tree are created
+        assert_eq!(names("/").await, ["/Work", "/projects"]);
+        assert_eq!(names("/Work").await, ["/Work/ProjectX"]);
+        assert_eq!(names("/Work/ProjectX").await, ["/Work/ProjectX/a.txt"]);
+        // the mapped folder is not listed at its own location
+        assert_eq!(names("/projects").await, ["/projects/b.txt"]);
+        assert!(fs.exists(Path::new("/Work/ProjectX/a.txt")).await.unwrap());
+        assert!(fs.exists(Path::new("/projects/x/a.txt")).await.is_err());
+
+        // a local folder at the location of the remote sub-tree is reported
+        std::fs::create_dir_all(root.join("Work/ProjectX")).unwrap();
+        let fs = FileSystem::new(&root).unwrap();
+        assert!(fs.with_mappings(vec![mapping]).is_err());
+    }
+}
+
 // fn check_symlink<P1, P2>(link: P1, target: P2) -> fsync::Result<()>
 // where
 //     P1: AsRef<Path>,
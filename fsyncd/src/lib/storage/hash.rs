@@ -0,0 +1,337 @@
+//! Checksums of the content of the files.
+//!
+//! The files are hashed as they are read through [`ReadFile`], in buffers of [`BUF_LEN`] bytes,
+//! so that the memory used does not depend on their size.
+//! The checksums are kept in a [`Cache`], keyed by the path, the size and the modification time
+//! of the files, so that an unchanged file is not read again.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use fsync::{
+    path::{FsPathBuf, PathBuf},
+    Checksum, HashAlgo, Metadata,
+};
+use md5::Digest as _;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{self, AsyncReadExt},
+    sync::Mutex,
+};
+use tokio_util::sync::CancellationToken;
+
+use super::ReadFile;
+use crate::SharedProgress;
+
+/// Size of the buffer in which the files are read
+pub const BUF_LEN: usize = 64 * 1024;
+
+/// Maximum number of checksums kept by the cache.
+/// When exceeded, the least recently used checksum is dropped.
+pub const MAX_CACHED: usize = 10_000;
+
+enum Hasher {
+    Md5(md5::Md5),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    fn new(algo: HashAlgo) -> Self {
+        match algo {
+            HashAlgo::Md5 => Self::Md5(md5::Md5::new()),
+            HashAlgo::Sha256 => Self::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            Self::Md5(hasher) => hex::encode(hasher.finalize()),
+            Self::Sha256(hasher) => hex::encode(hasher.finalize()),
+        }
+    }
+}
+
+/// Compute the checksum of `file` in `storage`, or get it from `cache` if the file did not change.
+/// The computation stops with an error as soon as `cancel` is cancelled,
+/// and the bytes read are reported to `progress`.
+pub async fn hash_file<S>(
+    storage: &S,
+    file: &Metadata,
+    algo: HashAlgo,
+    cache: Option<&Cache>,
+    cancel: Option<&CancellationToken>,
+    progress: Option<&SharedProgress>,
+) -> fsync::Result<Checksum>
+where
+    S: ReadFile,
+{
+    let path = file.path();
+    let (Some(size), Some(mtime)) = (file.size(), file.mtime()) else {
+        fsync::io_bail!("{path} is not a file");
+    };
+    if let Some(checksum) = cache.and_then(|cache| cache.get(file, algo)) {
+        log::trace!("checksum of {path} found in cache");
+        return Ok(checksum);
+    }
+
+    let read = storage.read_file(path.to_owned(), progress).await?;
+    let mut read = std::pin::pin!(read);
+    let mut hasher = Hasher::new(algo);
+    let mut buf = vec![0; BUF_LEN];
+    let mut done = 0;
+    loop {
+        if cancel.is_some_and(|cancel| cancel.is_cancelled()) {
+            fsync::other_bail!("The checksum of {path} was cancelled");
+        }
+        let len = read.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        done += len as u64;
+        if let Some(progress) = progress {
+            progress.set(fsync::Progress::Progress {
+                progress: done,
+                total: size,
+            });
+        }
+    }
+    let checksum = Checksum {
+        algo,
+        hex: hasher.finalize(),
+    };
+    if let Some(cache) = cache {
+        if let Err(err) = cache.put(path.to_owned(), size, mtime, &checksum).await {
+            log::warn!("Could not cache the checksum of {path}: {err}");
+        }
+    }
+    Ok(checksum)
+}
+
+/// A cached checksum
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    path: PathBuf,
+    algo: HashAlgo,
+    size: u64,
+    mtime: DateTime<Utc>,
+    hex: String,
+    /// Last time the checksum was computed or read from the cache
+    used: DateTime<Utc>,
+}
+
+/// The checksums of the files, persisted as a JSON array if opened from a file
+#[derive(Debug, Default)]
+pub struct Cache {
+    path: Option<FsPathBuf>,
+    records: std::sync::Mutex<HashMap<(PathBuf, HashAlgo), Record>>,
+    /// Serializes the writes of the file
+    write: Mutex<()>,
+}
+
+impl Cache {
+    /// Open the checksums persisted at `path`, which does not need to exist.
+    /// A file that can't be read is discarded, as its checksums can be computed again.
+    pub async fn open(path: FsPathBuf) -> anyhow::Result<Self> {
+        let records: Vec<Record> = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+                log::warn!("Discarding the checksums of {path}: {err}");
+                Vec::new()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let records = records
+            .into_iter()
+            .map(|rec| ((rec.path.clone(), rec.algo), rec))
+            .collect();
+        Ok(Self {
+            path: Some(path),
+            records: std::sync::Mutex::new(records),
+            write: Mutex::new(()),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records
+            .lock()
+            .expect("Lock shouldn't be poisoned")
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The checksum of `file`, if it was computed with the same size and modification time
+    pub fn get(&self, file: &Metadata, algo: HashAlgo) -> Option<Checksum> {
+        let mut records = self.records.lock().expect("Lock shouldn't be poisoned");
+        let rec = records.get_mut(&(file.path().to_owned(), algo))?;
+        if Some(rec.size) != file.size() || Some(rec.mtime) != file.mtime() {
+            return None;
+        }
+        rec.used = Utc::now();
+        Some(Checksum {
+            algo,
+            hex: rec.hex.clone(),
+        })
+    }
+
+    /// Record the checksum of the file at `path`, and persist the cache
+    pub async fn put(
+        &self,
+        path: PathBuf,
+        size: u64,
+        mtime: DateTime<Utc>,
+        checksum: &Checksum,
+    ) -> anyhow::Result<()> {
+        let _write = self.write.lock().await;
+        let data = {
+            let mut records = self.records.lock().expect("Lock shouldn't be poisoned");
+            let rec = Record {
+                path: path.clone(),
+                algo: checksum.algo,
+                size,
+                mtime,
+                hex: checksum.hex.clone(),
+                used: Utc::now(),
+            };
+            records.insert((path, checksum.algo), rec);
+            if records.len() > MAX_CACHED {
+                let oldest = records
+                    .iter()
+                    .min_by_key(|(_, rec)| rec.used)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    records.remove(&oldest);
+                }
+            }
+            if self.path.is_none() {
+                return Ok(());
+            }
+            serde_json::to_vec(&records.values().collect::<Vec<_>>())?
+        };
+        let file = self.path.clone().expect("Checked above");
+        tokio::task::spawn_blocking(move || {
+            if let Some(dir) = file.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            crate::persist::atomic_write(&file, &data)
+        })
+        .await??;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fsync::path::Path;
+
+    use super::*;
+    use crate::storage::mem::MemStorage;
+
+    fn mtime(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    fn file(path: &str, size: u64, mtime: DateTime<Utc>) -> Metadata {
+        Metadata::Regular {
+            path: PathBuf::from(path),
+            size,
+            mtime,
+            link_target: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn hash_file_by_buffers() {
+        let storage = MemStorage::new();
+        storage.put_file(Path::new("/hello.txt"), b"hello world", mtime(1));
+        let hello = file("/hello.txt", 11, mtime(1));
+        let md5 = hash_file(&storage, &hello, HashAlgo::Md5, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(md5.hex, "5eb63bbbe01eeed093cb22bb8f5acdc3");
+        let sha256 = hash_file(&storage, &hello, HashAlgo::Sha256, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            sha256.hex,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+
+        let data: Vec<u8> = (0..3 * BUF_LEN + 7).map(|i| (i % 251) as u8).collect();
+        storage.put_file(Path::new("/large.bin"), &data, mtime(1));
+        let large = file("/large.bin", data.len() as u64, mtime(1));
+        let progress = SharedProgress::new();
+        let checksum = hash_file(
+            &storage,
+            &large,
+            HashAlgo::Sha256,
+            None,
+            None,
+            Some(&progress),
+        )
+        .await
+        .unwrap();
+        assert_eq!(checksum.hex, hex::encode(sha2::Sha256::digest(&data)));
+        assert!(matches!(
+            progress.get(),
+            fsync::Progress::Progress { progress, total } if progress == total && total == data.len() as u64
+        ));
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let res = hash_file(&storage, &large, HashAlgo::Md5, None, Some(&cancel), None).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn cache_invalidated_by_mtime() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-checksums-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let path = dir.join("checksums.json");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let storage = MemStorage::new();
+        storage.put_file(Path::new("/file.txt"), b"hello world", mtime(1));
+        let cache = Cache::open(path.clone()).await.unwrap();
+        let first = file("/file.txt", 11, mtime(1));
+        let checksum = hash_file(&storage, &first, HashAlgo::Md5, Some(&cache), None, None)
+            .await
+            .unwrap();
+        assert_eq!(checksum.hex, "5eb63bbbe01eeed093cb22bb8f5acdc3");
+
+        // same size and mtime: the file is not read again, even after reopening the cache
+        storage.put_file(Path::new("/file.txt"), b"HELLO WORLD", mtime(1));
+        let cache = Cache::open(path).await.unwrap();
+        assert_eq!(cache.len(), 1);
+        let cached = hash_file(&storage, &first, HashAlgo::Md5, Some(&cache), None, None)
+            .await
+            .unwrap();
+        assert_eq!(cached, checksum);
+        // the other algorithm is not cached
+        assert_eq!(cache.get(&first, HashAlgo::Sha256), None);
+
+        // a new mtime invalidates the cached checksum
+        storage.put_file(Path::new("/file.txt"), b"HELLO WORLD", mtime(2));
+        let second = file("/file.txt", 11, mtime(2));
+        assert_eq!(cache.get(&second, HashAlgo::Md5), None);
+        let checksum = hash_file(&storage, &second, HashAlgo::Md5, Some(&cache), None, None)
+            .await
+            .unwrap();
+        assert_eq!(checksum.hex, hex::encode(md5::Md5::digest(b"HELLO WORLD")));
+        assert_eq!(cache.get(&second, HashAlgo::Md5), Some(checksum));
+        assert_eq!(cache.get(&first, HashAlgo::Md5), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,12 +1,16 @@
 use std::{borrow::Borrow, fmt, ops::Deref};
 
 use fsync::{path::Path, Metadata};
-use futures::{Future, Stream};
+use futures::{future, Future, Stream};
 use serde::{Deserialize, Serialize};
 use tokio::io;
 
 use crate::{SharedProgress, Shutdown};
 
+pub trait Exists {
+    fn exists(&self, id: &Id) -> impl Future<Output = fsync::Result<bool>> + Send;
+}
+
 pub trait DirEntries {
     fn dir_entries(
         &self,
@@ -24,6 +28,24 @@ pub trait ReadFile {
     ) -> impl Future<Output = fsync::Result<impl io::AsyncRead + Send>> + Send;
 }
 
+/// A trait to read a part of a file
+pub trait ReadFileRange: ReadFile + Sync {
+    /// Reads at most `len` bytes of the file referred to by `id`, starting at `offset`.
+    /// The default implementation reads the whole file and skips the bytes before `offset`.
+    fn read_file_range(
+        &self,
+        id: IdBuf,
+        offset: u64,
+        len: u64,
+        progress: Option<&SharedProgress>,
+    ) -> impl Future<Output = fsync::Result<impl io::AsyncRead + Send>> + Send {
+        async move {
+            let read = self.read_file(id, progress).await?;
+            super::read_range(read, offset, len).await
+        }
+    }
+}
+
 pub trait MkDir {
     fn mkdir(
         &self,
@@ -64,6 +86,18 @@ pub trait CopyFile {
     ) -> impl Future<Output = fsync::Result<(IdBuf, Metadata)>> + Send;
 }
 
+/// A trait to change the metadata of files without transferring their content
+pub trait PatchMetadata {
+    /// Applies `patch` to the file referred to by `id`, at `path`,
+    /// and returns the metadata of the file as reported by the storage.
+    fn patch(
+        &self,
+        id: &Id,
+        path: &Path,
+        patch: &super::MetadataPatch,
+    ) -> impl Future<Output = fsync::Result<Metadata>> + Send;
+}
+
 /// A trait to delete files or folders
 pub trait Delete {
     /// Deletes the file or folder referred to by `id`.
@@ -75,16 +109,101 @@ pub trait Delete {
     ) -> impl Future<Output = fsync::Result<()>> + Send;
 }
 
+/// A trait to query how the entries are shared with other users
+pub trait Shared {
+    /// How the entry with `id` is shared, or `None` if it is not shared
+    /// or if the storage does not report it.
+    fn sharing(&self, id: &Id) -> Option<fsync::Sharing> {
+        let _ = id;
+        None
+    }
+
+    /// Whether the file with `id` can be viewed but not downloaded,
+    /// or `None` if it can or if the storage does not report it.
+    fn view_only(&self, id: &Id) -> Option<fsync::ViewOnly> {
+        let _ = id;
+        None
+    }
+
+    /// The links to the file with `id`.
+    /// The default implementation fails with [`fsync::Error::Unsupported`].
+    fn link(&self, id: &Id) -> impl Future<Output = fsync::Result<fsync::RemoteLink>> + Send {
+        let _ = id;
+        future::ready(Err(super::unsupported_links()))
+    }
+
+    /// Let anyone with the link read the file with `id`, and return its links.
+    /// The default implementation fails with [`fsync::Error::Unsupported`].
+    fn share_public(
+        &self,
+        id: &Id,
+    ) -> impl Future<Output = fsync::Result<fsync::RemoteLink>> + Send {
+        let _ = id;
+        future::ready(Err(super::unsupported_links()))
+    }
+}
+
+/// A trait to query the past versions of the files kept by the storage
+pub trait Revisions {
+    /// The revisions of the file with `id`, oldest first.
+    /// The default implementation fails with [`fsync::Error::Unsupported`].
+    fn revisions(
+        &self,
+        id: &Id,
+    ) -> impl Future<Output = fsync::Result<Vec<fsync::Revision>>> + Send {
+        let _ = id;
+        future::ready(Err(super::unsupported_revisions()))
+    }
+
+    /// Read the content of the revision `revision_id` of the file with `id`.
+    /// The default implementation fails with [`fsync::Error::Unsupported`].
+    fn read_revision(
+        &self,
+        id: IdBuf,
+        revision_id: &str,
+        progress: Option<&SharedProgress>,
+    ) -> impl Future<Output = fsync::Result<impl io::AsyncRead + Send>> + Send {
+        let _ = (id, revision_id, progress);
+        future::ready(Err::<io::Empty, _>(super::unsupported_revisions()))
+    }
+}
+
+/// A trait to query the checksums of the files computed by the storage
+pub trait Checksums {
+    /// The MD5 of the content of the file with `id`, in lowercase hexadecimal,
+    /// if the storage already reported it. Nothing is requested from the storage.
+    fn known_md5(&self, id: &Id) -> Option<String> {
+        let _ = id;
+        None
+    }
+
+    /// Request the MD5 of the content of the file with `id` from the storage,
+    /// in lowercase hexadecimal, or `None` if the storage has no checksum for it.
+    fn fetch_md5(&self, id: &Id) -> impl Future<Output = fsync::Result<Option<String>>> + Send {
+        let _ = id;
+        future::ready(Ok(None))
+    }
+}
+
 /// A trait for an ID-based storage
 pub trait Storage:
     Clone
+    + Exists
     + DirEntries
     + ReadFile
+    + ReadFileRange
     + MkDir
     + CreateFile
     + WriteFile
     + CopyFile
     + Delete
+    + super::Quota
+    + super::Flush
+    + Shared
+    + Revisions
+    + Checksums
+    + PatchMetadata
+    + super::cache::Provider
     + Shutdown
     + Send
     + Sync
@@ -92,6 +211,7 @@ pub trait Storage:
 {
 }
 
+#[derive(PartialEq, Eq, Hash)]
 #[repr(transparent)]
 pub struct Id {
     inner: str,
@@ -139,7 +259,7 @@ impl Default for &Id {
     }
 }
 
-#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 #[serde(transparent)]
 #[repr(transparent)]
 pub struct IdBuf {
@@ -0,0 +1,416 @@
+//! Lazy initialization of an ID-based storage.
+//!
+//! Initializing the Drive storage takes network round-trips (user and quota query,
+//! resolution of the root folder). [`Lazy`] performs them in a spawned task, retried
+//! until success, so that the daemon can serve its cached tree in the meantime.
+//! Until then, the operations that need the storage fail with
+//! [`fsync::Error::RemoteInitializing`].
+
+use std::{
+    future::Future,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use fsync::{path::Path, Metadata, RemotePhase};
+use futures::{future, stream, Stream, StreamExt};
+use tokio::{io, sync::watch};
+
+use super::id::{self, Id, IdBuf};
+use crate::{SharedProgress, Shutdown};
+
+/// Delay before the first retry of a failed initialization
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// The delay is doubled after each failure, up to this one
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// A storage initialized in a spawned task
+#[derive(Debug)]
+pub struct Lazy<S> {
+    storage: Arc<OnceLock<S>>,
+    phase: watch::Receiver<RemotePhase>,
+}
+
+impl<S> Clone for Lazy<S> {
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            phase: self.phase.clone(),
+        }
+    }
+}
+
+impl<S> Lazy<S>
+where
+    S: Send + Sync + 'static,
+{
+    /// Spawn the initialization of the storage with `init`,
+    /// which is called again after a delay each time it fails.
+    pub fn new<F, Fut>(init: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<S>> + Send,
+    {
+        Self::with_retry_delay(init, FIRST_RETRY_DELAY)
+    }
+
+    fn with_retry_delay<F, Fut>(mut init: F, first_delay: Duration) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<S>> + Send,
+    {
+        let storage = Arc::new(OnceLock::new());
+        let (tx, phase) = watch::channel(RemotePhase::Initializing);
+
+        let cell = storage.clone();
+        tokio::spawn(async move {
+            let mut delay = first_delay;
+            loop {
+                match init().await {
+                    Ok(storage) => {
+                        let _ = cell.set(storage);
+                        log::info!("Remote storage is ready");
+                        tx.send_replace(RemotePhase::Ready);
+                        break;
+                    }
+                    Err(err) => {
+                        log::error!("Could not initialize the remote storage, retrying in {delay:?}: {err:#}");
+                        tx.send_replace(RemotePhase::Retrying(format!("{err:#}")));
+                    }
+                }
+                if tx.is_closed() {
+                    // all the handles were dropped
+                    break;
+                }
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+        });
+
+        Self { storage, phase }
+    }
+}
+
+impl<S> Lazy<S> {
+    /// The current initialization phase, which can be watched for changes
+    pub fn phase(&self) -> watch::Receiver<RemotePhase> {
+        self.phase.clone()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.storage.get().is_some()
+    }
+
+    /// Wait until the storage is initialized
+    pub async fn ready(&self) -> fsync::Result<()> {
+        let mut phase = self.phase.clone();
+        phase
+            .wait_for(|phase| *phase == RemotePhase::Ready)
+            .await
+            .map_err(|_| fsync::Error::Bug("remote initialization task ended".into()))?;
+        Ok(())
+    }
+
+    fn get(&self) -> fsync::Result<&S> {
+        self.storage.get().ok_or(fsync::Error::RemoteInitializing)
+    }
+}
+
+impl<S> id::Exists for Lazy<S>
+where
+    S: id::Exists + Send + Sync,
+{
+    async fn exists(&self, id: &Id) -> fsync::Result<bool> {
+        self.get()?.exists(id).await
+    }
+}
+
+impl<S> id::DirEntries for Lazy<S>
+where
+    S: id::DirEntries + Send + Sync,
+{
+    fn dir_entries(
+        &self,
+        parent_id: Option<&Id>,
+        parent_path: &Path,
+        progress: Option<&SharedProgress>,
+    ) -> impl Stream<Item = fsync::Result<(IdBuf, Metadata)>> + Send {
+        match self.get() {
+            Ok(storage) => storage
+                .dir_entries(parent_id, parent_path, progress)
+                .left_stream(),
+            Err(err) => stream::once(future::ready(Err(err))).right_stream(),
+        }
+    }
+}
+
+impl<S> id::ReadFile for Lazy<S>
+where
+    S: id::ReadFile + Send + Sync,
+{
+    async fn read_file(
+        &self,
+        id: IdBuf,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead + Send> {
+        self.get()?.read_file(id, progress).await
+    }
+}
+
+impl<S> id::ReadFileRange for Lazy<S>
+where
+    S: id::ReadFileRange + Send + Sync,
+{
+    async fn read_file_range(
+        &self,
+        id: IdBuf,
+        offset: u64,
+        len: u64,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead + Send> {
+        self.get()?.read_file_range(id, offset, len, progress).await
+    }
+}
+
+impl<S> id::MkDir for Lazy<S>
+where
+    S: id::MkDir + Send + Sync,
+{
+    async fn mkdir(
+        &self,
+        parent_id: Option<&Id>,
+        name: &str,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<IdBuf> {
+        self.get()?.mkdir(parent_id, name, progress).await
+    }
+}
+
+impl<S> id::CreateFile for Lazy<S>
+where
+    S: id::CreateFile + Send + Sync,
+{
+    async fn create_file(
+        &self,
+        parent_id: Option<&Id>,
+        metadata: &Metadata,
+        data: impl io::AsyncRead + Send,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<(IdBuf, Metadata)> {
+        self.get()?
+            .create_file(parent_id, metadata, data, progress)
+            .await
+    }
+}
+
+impl<S> id::WriteFile for Lazy<S>
+where
+    S: id::WriteFile + Send + Sync,
+{
+    async fn write_file(
+        &self,
+        id: &Id,
+        parent_id: Option<&Id>,
+        metadata: &Metadata,
+        data: impl io::AsyncRead + Send,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<Metadata> {
+        self.get()?
+            .write_file(id, parent_id, metadata, data, progress)
+            .await
+    }
+}
+
+impl<S> id::PatchMetadata for Lazy<S>
+where
+    S: id::PatchMetadata + Send + Sync,
+{
+    async fn patch(
+        &self,
+        id: &Id,
+        path: &Path,
+        patch: &super::MetadataPatch,
+    ) -> fsync::Result<Metadata> {
+        self.get()?.patch(id, path, patch).await
+    }
+}
+
+impl<S> id::CopyFile for Lazy<S>
+where
+    S: id::CopyFile + Send + Sync,
+{
+    async fn copy_file(
+        &self,
+        src_id: &Id,
+        dest_parent_id: Option<&Id>,
+        dest_path: &Path,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<(IdBuf, Metadata)> {
+        self.get()?
+            .copy_file(src_id, dest_parent_id, dest_path, progress)
+            .await
+    }
+}
+
+impl<S> id::Delete for Lazy<S>
+where
+    S: id::Delete + Send + Sync,
+{
+    async fn delete(&self, id: &Id, progress: Option<&SharedProgress>) -> fsync::Result<()> {
+        self.get()?.delete(id, progress).await
+    }
+}
+
+impl<S> super::Quota for Lazy<S>
+where
+    S: super::Quota + Send + Sync,
+{
+    /// The quota is unknown until the storage is initialized
+    async fn quota(&self) -> fsync::Result<Option<fsync::stat::Quota>> {
+        match self.storage.get() {
+            Some(storage) => storage.quota().await,
+            None => Ok(None),
+        }
+    }
+}
+
+impl<S> super::Flush for Lazy<S>
+where
+    S: super::Flush + Send + Sync,
+{
+    async fn flush(&self) -> fsync::Result<()> {
+        match self.storage.get() {
+            Some(storage) => storage.flush().await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S> id::Shared for Lazy<S>
+where
+    S: id::Shared + Send + Sync,
+{
+    /// The sharing is unknown until the storage is initialized
+    fn sharing(&self, id: &Id) -> Option<fsync::Sharing> {
+        self.storage.get().and_then(|storage| storage.sharing(id))
+    }
+
+    fn view_only(&self, id: &Id) -> Option<fsync::ViewOnly> {
+        self.storage.get().and_then(|storage| storage.view_only(id))
+    }
+
+    async fn link(&self, id: &Id) -> fsync::Result<fsync::RemoteLink> {
+        self.get()?.link(id).await
+    }
+
+    async fn share_public(&self, id: &Id) -> fsync::Result<fsync::RemoteLink> {
+        self.get()?.share_public(id).await
+    }
+}
+
+impl<S> id::Revisions for Lazy<S>
+where
+    S: id::Revisions + Send + Sync,
+{
+    async fn revisions(&self, id: &Id) -> fsync::Result<Vec<fsync::Revision>> {
+        self.get()?.revisions(id).await
+    }
+
+    async fn read_revision(
+        &self,
+        id: IdBuf,
+        revision_id: &str,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead + Send> {
+        self.get()?.read_revision(id, revision_id, progress).await
+    }
+}
+
+impl<S> id::Checksums for Lazy<S>
+where
+    S: id::Checksums + Send + Sync,
+{
+    /// The checksums are unknown until the storage is initialized
+    fn known_md5(&self, id: &Id) -> Option<String> {
+        self.storage.get().and_then(|storage| storage.known_md5(id))
+    }
+
+    async fn fetch_md5(&self, id: &Id) -> fsync::Result<Option<String>> {
+        self.get()?.fetch_md5(id).await
+    }
+}
+
+impl<S> Shutdown for Lazy<S>
+where
+    S: Shutdown + Send + Sync,
+{
+    async fn shutdown(&self) -> anyhow::Result<()> {
+        match self.storage.get() {
+            Some(storage) => storage.shutdown().await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S> super::cache::Provider for Lazy<S>
+where
+    S: super::cache::Provider + Send + Sync,
+{
+    const DUPLICATE_NAMES: super::cache::DuplicateNames = S::DUPLICATE_NAMES;
+
+    fn valid_id(id: &Id) -> bool {
+        S::valid_id(id)
+    }
+
+    /// The changes are unknown until the storage is initialized
+    async fn change_token(&self) -> fsync::Result<Option<String>> {
+        match self.storage.get() {
+            Some(storage) => storage.change_token().await,
+            None => Ok(None),
+        }
+    }
+}
+
+impl<S> id::Storage for Lazy<S> where S: id::Storage {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Ready;
+
+    #[tokio::test]
+    async fn lazy_retries_until_ready() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counter = attempts.clone();
+        let lazy = Lazy::with_retry_delay(
+            move || {
+                let attempt = counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if attempt < 2 {
+                        anyhow::bail!("network is down");
+                    }
+                    Ok(Ready)
+                }
+            },
+            Duration::from_millis(5),
+        );
+
+        lazy.ready().await.unwrap();
+        assert!(lazy.is_ready());
+        assert_eq!(*lazy.phase().borrow(), RemotePhase::Ready);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn lazy_fails_until_ready() {
+        let lazy: Lazy<Ready> = Lazy::new(future::pending);
+
+        assert!(!lazy.is_ready());
+        assert_eq!(*lazy.phase().borrow(), RemotePhase::Initializing);
+        assert!(matches!(lazy.get(), Err(fsync::Error::RemoteInitializing)));
+    }
+}
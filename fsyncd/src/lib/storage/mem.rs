@@ -0,0 +1,523 @@
+//! An in-memory storage, for the tests of the service.
+//!
+//! The entries are kept in a map shared by the clones of the storage, and listed in
+//! the byte-wise order of their names. Failures and latency can be injected in the
+//! calls of the storage traits, to exercise the error paths of the operations.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use fsync::path::{Path, PathBuf};
+use futures::Stream;
+use md5::Digest as _;
+use tokio::io::{self, AsyncReadExt};
+
+use crate::{SharedProgress, Shutdown};
+
+pub mod id;
+
+type PathFilter = Arc<dyn Fn(&Path) -> bool + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Dir,
+    File { data: Vec<u8>, mtime: DateTime<Utc> },
+}
+
+#[derive(Default)]
+struct Faults {
+    /// Number of the next calls that fail
+    fail_next: usize,
+    /// Calls on the paths matching this filter fail
+    fail_paths: Option<PathFilter>,
+    /// Delay added to each call
+    latency: Duration,
+}
+
+struct Inner {
+    entries: BTreeMap<PathBuf, Node>,
+    faults: Faults,
+    /// Number of bytes of content read or written
+    transferred: u64,
+}
+
+#[derive(Clone)]
+pub struct MemStorage {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Default for MemStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for MemStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("MemStorage")
+            .field("entries", &inner.entries.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl MemStorage {
+    /// An empty storage, with only the root directory
+    pub fn new() -> Self {
+        let mut entries = BTreeMap::new();
+        entries.insert(PathBuf::root(), Node::Dir);
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                entries,
+                faults: Faults::default(),
+                transferred: 0,
+            })),
+        }
+    }
+
+    /// Write a file, creating its missing parents, as if done outside of fsyncd
+    pub fn put_file(&self, path: &Path, data: &[u8], mtime: DateTime<Utc>) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(parent) = path.parent() {
+            inner.create_dirs(parent);
+        }
+        let data = data.to_vec();
+        inner
+            .entries
+            .insert(path.to_owned(), Node::File { data, mtime });
+    }
+
+    /// Create a directory and its missing parents, as if done outside of fsyncd
+    pub fn put_dir(&self, path: &Path) {
+        self.inner.lock().unwrap().create_dirs(path);
+    }
+
+    /// Remove an entry and its descendants, as if done outside of fsyncd
+    pub fn remove(&self, path: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .entries
+            .retain(|p, _| p != path && !path.is_ancestor_of(p));
+    }
+
+    /// The content of the file at `path`, if there is one
+    pub fn content(&self, path: &Path) -> Option<Vec<u8>> {
+        match self.inner.lock().unwrap().entries.get(path) {
+            Some(Node::File { data, .. }) => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    /// The metadata of all the entries, parents first
+    pub fn entries(&self) -> Vec<fsync::Metadata> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .entries
+            .iter()
+            .map(|(path, node)| node.metadata(path.clone()))
+            .collect()
+    }
+
+    /// Number of bytes of content read from or written to the storage,
+    /// the files put from outside of fsyncd excluded
+    pub fn transferred(&self) -> u64 {
+        self.inner.lock().unwrap().transferred
+    }
+
+    /// Fail the next `count` calls to the storage
+    pub fn fail_next(&self, count: usize) {
+        self.inner.lock().unwrap().faults.fail_next = count;
+    }
+
+    /// Fail the calls on the paths for which `filter` returns true
+    pub fn fail_paths<F>(&self, filter: F)
+    where
+        F: Fn(&Path) -> bool + Send + Sync + 'static,
+    {
+        self.inner.lock().unwrap().faults.fail_paths = Some(Arc::new(filter));
+    }
+
+    /// Delay each call to the storage by `latency`
+    pub fn set_latency(&self, latency: Duration) {
+        self.inner.lock().unwrap().faults.latency = latency;
+    }
+
+    /// Stop injecting failures and latency
+    pub fn clear_faults(&self) {
+        self.inner.lock().unwrap().faults = Faults::default();
+    }
+
+    fn children(&self, parent_path: &Path) -> fsync::Result<Vec<fsync::Metadata>> {
+        let inner = self.inner.lock().unwrap();
+        if !matches!(inner.entries.get(parent_path), Some(Node::Dir)) {
+            fsync::io_bail!("{parent_path}: No such directory");
+        }
+        let children = inner
+            .entries
+            .iter()
+            .filter(|(path, _)| path.parent() == Some(parent_path))
+            .map(|(path, node)| node.metadata(path.clone()))
+            .collect();
+        Ok(children)
+    }
+
+    /// Apply the injected latency and failures to a call on `path`
+    async fn call(&self, path: &Path) -> fsync::Result<()> {
+        let (latency, fail) = {
+            let mut inner = self.inner.lock().unwrap();
+            let faults = &mut inner.faults;
+            let fail = if faults.fail_next > 0 {
+                faults.fail_next -= 1;
+                true
+            } else {
+                faults
+                    .fail_paths
+                    .as_ref()
+                    .is_some_and(|filter| filter(path))
+            };
+            (faults.latency, fail)
+        };
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+        if fail {
+            fsync::io_bail!("{path}: injected failure");
+        }
+        Ok(())
+    }
+}
+
+impl Node {
+    fn metadata(&self, path: PathBuf) -> fsync::Metadata {
+        match self {
+            Node::Dir => fsync::Metadata::Directory { path, stat: None },
+            Node::File { data, mtime } => fsync::Metadata::Regular {
+                path,
+                size: data.len() as u64,
+                mtime: *mtime,
+                link_target: None,
+            },
+        }
+    }
+}
+
+impl Inner {
+    fn create_dirs(&mut self, path: &Path) {
+        if let Some(parent) = path.parent() {
+            self.create_dirs(parent);
+        }
+        self.entries.entry(path.to_owned()).or_insert(Node::Dir);
+    }
+
+    fn check_parent_dir(&self, path: &Path) -> fsync::Result<()> {
+        let parent = path.parent().unwrap_or(Path::root());
+        match self.entries.get(parent) {
+            Some(Node::Dir) => Ok(()),
+            _ => fsync::io_bail!("{parent}: No such directory"),
+        }
+    }
+
+    fn file(&self, path: &Path) -> fsync::Result<(&Vec<u8>, DateTime<Utc>)> {
+        match self.entries.get(path) {
+            Some(Node::File { data, mtime }) => Ok((data, *mtime)),
+            Some(Node::Dir) => fsync::io_bail!("{path} is a directory"),
+            None => fsync::io_bail!("{path}: No such file"),
+        }
+    }
+}
+
+async fn read_data(data: impl io::AsyncRead + Send) -> fsync::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    Box::pin(data).read_to_end(&mut buf).await?;
+    Ok(buf)
+}
+
+impl super::Exists for MemStorage {
+    async fn exists(&self, path: &Path) -> fsync::Result<bool> {
+        self.call(path).await?;
+        Ok(self.inner.lock().unwrap().entries.contains_key(path))
+    }
+}
+
+impl super::DirEntries for MemStorage {
+    fn dir_entries(
+        &self,
+        parent_path: &Path,
+        _progress: Option<&SharedProgress>,
+    ) -> impl Stream<Item = fsync::Result<fsync::Metadata>> + Send {
+        let parent_path = parent_path.to_owned();
+        async_stream::try_stream! {
+            self.call(&parent_path).await?;
+            let children = self.children(&parent_path)?;
+            for child in children {
+                yield child;
+            }
+        }
+    }
+}
+
+impl super::ReadFile for MemStorage {
+    async fn read_file(
+        &self,
+        path: PathBuf,
+        _progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead> {
+        self.call(&path).await?;
+        let mut inner = self.inner.lock().unwrap();
+        let data = inner.file(&path)?.0.clone();
+        inner.transferred += data.len() as u64;
+        Ok(std::io::Cursor::new(data))
+    }
+}
+
+impl super::ReadFileRange for MemStorage {}
+
+impl super::MkDir for MemStorage {
+    async fn mkdir(
+        &self,
+        path: &Path,
+        parents: bool,
+        _progress: Option<&SharedProgress>,
+    ) -> fsync::Result<()> {
+        self.call(path).await?;
+        let mut inner = self.inner.lock().unwrap();
+        match inner.entries.get(path) {
+            Some(Node::Dir) if parents => return Ok(()),
+            Some(_) => fsync::io_bail!("{path} already exists"),
+            None => (),
+        }
+        if parents {
+            inner.create_dirs(path);
+        } else {
+            inner.check_parent_dir(path)?;
+            inner.entries.insert(path.to_owned(), Node::Dir);
+        }
+        Ok(())
+    }
+}
+
+impl super::CreateFile for MemStorage {
+    async fn create_file(
+        &self,
+        metadata: &fsync::Metadata,
+        data: impl io::AsyncRead + Send,
+        _progress: Option<&SharedProgress>,
+    ) -> fsync::Result<fsync::Metadata> {
+        let path = metadata.path();
+        self.call(path).await?;
+        let data = read_data(data).await?;
+        let mut inner = self.inner.lock().unwrap();
+        inner.transferred += data.len() as u64;
+        if inner.entries.contains_key(path) {
+            fsync::io_bail!("{path} already exists");
+        }
+        inner.check_parent_dir(path)?;
+        let mtime = metadata.mtime().unwrap_or_else(Utc::now);
+        let node = Node::File { data, mtime };
+        let metadata = node.metadata(path.to_owned());
+        inner.entries.insert(path.to_owned(), node);
+        Ok(metadata)
+    }
+}
+
+impl super::WriteFile for MemStorage {
+    async fn write_file(
+        &self,
+        metadata: &fsync::Metadata,
+        data: impl io::AsyncRead + Send,
+        _progress: Option<&SharedProgress>,
+    ) -> fsync::Result<fsync::Metadata> {
+        let path = metadata.path();
+        self.call(path).await?;
+        let data = read_data(data).await?;
+        let mut inner = self.inner.lock().unwrap();
+        inner.transferred += data.len() as u64;
+        inner.file(path)?;
+        let mtime = metadata.mtime().unwrap_or_else(Utc::now);
+        let node = Node::File { data, mtime };
+        let metadata = node.metadata(path.to_owned());
+        inner.entries.insert(path.to_owned(), node);
+        Ok(metadata)
+    }
+}
+
+impl super::CopyFile for MemStorage {
+    async fn copy_file(
+        &self,
+        src: &Path,
+        dest: &Path,
+        _progress: Option<&SharedProgress>,
+    ) -> fsync::Result<fsync::Metadata> {
+        self.call(src).await?;
+        let mut inner = self.inner.lock().unwrap();
+        let (data, mtime) = inner.file(src)?;
+        let node = Node::File {
+            data: data.clone(),
+            mtime,
+        };
+        if inner.entries.contains_key(dest) {
+            fsync::io_bail!("{dest} already exists");
+        }
+        inner.check_parent_dir(dest)?;
+        let metadata = node.metadata(dest.to_owned());
+        inner.entries.insert(dest.to_owned(), node);
+        Ok(metadata)
+    }
+}
+
+impl super::MoveEntry for MemStorage {
+    async fn move_entry(
+        &self,
+        src: &Path,
+        dest: &Path,
+        _progress: Option<&SharedProgress>,
+    ) -> fsync::Result<fsync::Metadata> {
+        self.call(src).await?;
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.entries.contains_key(src) {
+            fsync::io_bail!("{src}: No such entry");
+        }
+        if inner.entries.contains_key(dest) {
+            fsync::io_bail!("{dest} already exists");
+        }
+        inner.check_parent_dir(dest)?;
+        let moved: Vec<_> = inner
+            .entries
+            .keys()
+            .filter(|p| *p == src || src.is_ancestor_of(p))
+            .cloned()
+            .collect();
+        for path in moved {
+            let node = inner.entries.remove(&path).unwrap();
+            let rest = &path.as_str()[src.as_str().len()..];
+            inner
+                .entries
+                .insert(PathBuf::from(format!("{dest}{rest}")), node);
+        }
+        Ok(inner.entries[dest].metadata(dest.to_owned()))
+    }
+}
+
+impl super::PatchMetadata for MemStorage {
+    async fn patch(
+        &self,
+        path: &Path,
+        patch: &super::MetadataPatch,
+    ) -> fsync::Result<fsync::Metadata> {
+        self.call(path).await?;
+        let mut inner = self.inner.lock().unwrap();
+        match inner.entries.get_mut(path) {
+            Some(Node::File { mtime, .. }) => {
+                if let Some(patched) = patch.mtime {
+                    *mtime = patched;
+                }
+            }
+            Some(Node::Dir) => fsync::io_bail!("{path} is a directory"),
+            None => fsync::io_bail!("{path}: No such file"),
+        }
+        Ok(inner.entries[path].metadata(path.to_owned()))
+    }
+}
+
+impl super::Delete for MemStorage {
+    async fn delete(&self, path: &Path, _progress: Option<&SharedProgress>) -> fsync::Result<()> {
+        self.call(path).await?;
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.keys().any(|p| path.is_ancestor_of(p)) {
+            fsync::io_bail!("{path} is a non-empty folder");
+        }
+        inner.entries.remove(path);
+        Ok(())
+    }
+}
+
+impl super::ExistingChildren for MemStorage {}
+
+impl super::Quota for MemStorage {}
+
+impl super::Flush for MemStorage {}
+
+impl super::Shared for MemStorage {}
+
+impl super::Relist for MemStorage {}
+
+impl super::Revisions for MemStorage {}
+
+/// The checksums are computed on request, as if the storage reported them
+impl super::Checksums for MemStorage {
+    async fn fetch_md5(&self, path: &Path) -> fsync::Result<Option<String>> {
+        self.call(path).await?;
+        let inner = self.inner.lock().unwrap();
+        match inner.entries.get(path) {
+            Some(Node::File { data, .. }) => Ok(Some(hex::encode(md5::Md5::digest(data)))),
+            Some(Node::Dir) => fsync::io_bail!("{path} is a folder"),
+            None => fsync::io_bail!("{path}: No such file"),
+        }
+    }
+}
+
+impl Shutdown for MemStorage {}
+
+impl super::Storage for MemStorage {}
+impl super::LocalStorage for MemStorage {}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+
+    use super::*;
+    use crate::storage::{CopyFile, DirEntries, MoveEntry, ReadFile};
+
+    fn mtime(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn listing_is_ordered_and_faults_are_injected() {
+        let mem = MemStorage::new();
+        mem.put_file(Path::new("/dir/b.txt"), b"b", mtime(1));
+        mem.put_file(Path::new("/dir/a.txt"), b"a", mtime(2));
+        mem.put_dir(Path::new("/dir/C"));
+
+        let names = |entries: Vec<fsync::Metadata>| -> Vec<String> {
+            entries.iter().map(|md| md.name().to_string()).collect()
+        };
+        let entries: Vec<_> = mem
+            .dir_entries(Path::new("/dir"), None)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(names(entries), ["C", "a.txt", "b.txt"]);
+
+        mem.move_entry(Path::new("/dir"), Path::new("/moved"), None)
+            .await
+            .unwrap();
+        assert_eq!(mem.content(Path::new("/moved/a.txt")).unwrap(), b"a");
+        assert_eq!(mem.entries().len(), 5);
+
+        mem.fail_next(1);
+        let res = mem.read_file(PathBuf::from("/moved/a.txt"), None).await;
+        assert!(res.is_err());
+        assert!(mem
+            .read_file(PathBuf::from("/moved/a.txt"), None)
+            .await
+            .is_ok());
+
+        mem.fail_paths(|path| path.as_str().ends_with("b.txt"));
+        let res = mem
+            .copy_file(Path::new("/moved/b.txt"), Path::new("/b.txt"), None)
+            .await;
+        assert!(res.is_err());
+        mem.clear_faults();
+        let copied = mem
+            .copy_file(Path::new("/moved/b.txt"), Path::new("/b.txt"), None)
+            .await
+            .unwrap();
+        assert_eq!(copied.mtime(), Some(mtime(1)));
+    }
+}
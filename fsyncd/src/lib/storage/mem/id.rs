@@ -0,0 +1,362 @@
+//! An in-memory ID-based storage, for the tests of the cache.
+//!
+//! As in Drive, the entries are addressed by id, and a folder can have several entries
+//! of the same name. The ids are numbers, given in the order of creation, and the entries
+//! of a folder are listed in that order. The storage can report a change token, which
+//! changes with each entry written or removed.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use fsync::path::Path;
+use futures::Stream;
+use tokio::io;
+
+use super::read_data;
+use crate::{
+    storage::{
+        cache,
+        id::{self, Id, IdBuf},
+        MetadataPatch,
+    },
+    SharedProgress, Shutdown,
+};
+
+#[derive(Debug, Clone)]
+struct Node {
+    parent: Option<IdBuf>,
+    name: String,
+    /// `None` for a directory
+    data: Option<Vec<u8>>,
+    mtime: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// By id, which is the creation order
+    entries: BTreeMap<u64, Node>,
+    next_id: u64,
+    /// Incremented with each change
+    changes: u64,
+    reports_changes: bool,
+    /// Number of folders listed
+    listings: usize,
+}
+
+#[derive(Clone, Default)]
+pub struct MemIdStorage {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl fmt::Debug for MemIdStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("MemIdStorage")
+            .field("entries", &inner.entries.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl MemIdStorage {
+    /// An empty storage, that does not report a change token
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report a change token, see [`cache::Provider::change_token`]
+    pub fn report_changes(&self) {
+        self.inner.lock().unwrap().reports_changes = true;
+    }
+
+    /// Write a file in the folder with `parent` id, as if done outside of fsyncd.
+    /// A file of the same name is not replaced.
+    pub fn put_file(
+        &self,
+        parent: Option<&Id>,
+        name: &str,
+        data: &[u8],
+        mtime: DateTime<Utc>,
+    ) -> IdBuf {
+        self.insert(parent, name, Some(data.to_vec()), mtime)
+    }
+
+    /// Create a directory in the folder with `parent` id, as if done outside of fsyncd
+    pub fn put_dir(&self, parent: Option<&Id>, name: &str) -> IdBuf {
+        self.insert(parent, name, None, Utc::now())
+    }
+
+    /// Remove the entry with `id` and its descendants, as if done outside of fsyncd
+    pub fn remove(&self, id: &Id) {
+        self.inner.lock().unwrap().remove(id);
+    }
+
+    /// The number of folders listed since the storage was created
+    pub fn listings(&self) -> usize {
+        self.inner.lock().unwrap().listings
+    }
+
+    fn insert(
+        &self,
+        parent: Option<&Id>,
+        name: &str,
+        data: Option<Vec<u8>>,
+        mtime: DateTime<Utc>,
+    ) -> IdBuf {
+        let mut inner = self.inner.lock().unwrap();
+        inner.insert(Node {
+            parent: parent.map(Id::to_id_buf),
+            name: name.to_string(),
+            data,
+            mtime,
+        })
+    }
+}
+
+impl Node {
+    fn metadata(&self, parent_path: &Path) -> fsync::Metadata {
+        let path = parent_path.join(&self.name);
+        match &self.data {
+            None => fsync::Metadata::Directory { path, stat: None },
+            Some(data) => fsync::Metadata::Regular {
+                path,
+                size: data.len() as u64,
+                mtime: self.mtime,
+                link_target: None,
+            },
+        }
+    }
+}
+
+impl Inner {
+    fn insert(&mut self, node: Node) -> IdBuf {
+        self.next_id += 1;
+        self.changes += 1;
+        self.entries.insert(self.next_id, node);
+        IdBuf::from(self.next_id.to_string())
+    }
+
+    fn node(&self, id: &Id) -> fsync::Result<&Node> {
+        match key(id).and_then(|key| self.entries.get(&key)) {
+            Some(node) => Ok(node),
+            None => fsync::io_bail!("{id}: No such entry"),
+        }
+    }
+
+    fn check_dir(&self, id: Option<&Id>) -> fsync::Result<()> {
+        match id.map(|id| self.node(id)).transpose()? {
+            Some(Node { data: Some(_), .. }) => fsync::io_bail!("{}: Not a directory", id.unwrap()),
+            _ => Ok(()),
+        }
+    }
+
+    fn children(&self, parent: Option<&Id>) -> Vec<(IdBuf, &Node)> {
+        self.entries
+            .iter()
+            .filter(|(_, node)| node.parent.as_deref() == parent)
+            .map(|(key, node)| (IdBuf::from(key.to_string()), node))
+            .collect()
+    }
+
+    fn remove(&mut self, id: &Id) {
+        let children: Vec<_> = self
+            .children(Some(id))
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        for child in children {
+            self.remove(&child);
+        }
+        if let Some(key) = key(id) {
+            self.entries.remove(&key);
+            self.changes += 1;
+        }
+    }
+}
+
+fn key(id: &Id) -> Option<u64> {
+    id.as_str().parse().ok()
+}
+
+impl id::Exists for MemIdStorage {
+    async fn exists(&self, id: &Id) -> fsync::Result<bool> {
+        Ok(self.inner.lock().unwrap().node(id).is_ok())
+    }
+}
+
+impl id::DirEntries for MemIdStorage {
+    fn dir_entries(
+        &self,
+        parent_id: Option<&Id>,
+        parent_path: &Path,
+        _progress: Option<&SharedProgress>,
+    ) -> impl Stream<Item = fsync::Result<(IdBuf, fsync::Metadata)>> + Send {
+        let children = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.listings += 1;
+            inner.check_dir(parent_id).map(|_| {
+                inner
+                    .children(parent_id)
+                    .into_iter()
+                    .map(|(id, node)| Ok((id, node.metadata(parent_path))))
+                    .collect::<Vec<_>>()
+            })
+        };
+        let children = children.unwrap_or_else(|err| vec![Err(err)]);
+        futures::stream::iter(children)
+    }
+}
+
+impl id::ReadFile for MemIdStorage {
+    async fn read_file(
+        &self,
+        id: IdBuf,
+        _progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead + Send> {
+        let inner = self.inner.lock().unwrap();
+        match &inner.node(&id)?.data {
+            Some(data) => Ok(std::io::Cursor::new(data.clone())),
+            None => fsync::io_bail!("{id} is a directory"),
+        }
+    }
+}
+
+impl id::ReadFileRange for MemIdStorage {}
+
+impl id::MkDir for MemIdStorage {
+    async fn mkdir(
+        &self,
+        parent_id: Option<&Id>,
+        name: &str,
+        _progress: Option<&SharedProgress>,
+    ) -> fsync::Result<IdBuf> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.check_dir(parent_id)?;
+        Ok(inner.insert(Node {
+            parent: parent_id.map(Id::to_id_buf),
+            name: name.to_string(),
+            data: None,
+            mtime: Utc::now(),
+        }))
+    }
+}
+
+impl id::CreateFile for MemIdStorage {
+    async fn create_file(
+        &self,
+        parent_id: Option<&Id>,
+        metadata: &fsync::Metadata,
+        data: impl io::AsyncRead + Send,
+        _progress: Option<&SharedProgress>,
+    ) -> fsync::Result<(IdBuf, fsync::Metadata)> {
+        let data = read_data(data).await?;
+        let mut inner = self.inner.lock().unwrap();
+        inner.check_dir(parent_id)?;
+        let node = Node {
+            parent: parent_id.map(Id::to_id_buf),
+            name: metadata.name().to_string(),
+            data: Some(data),
+            mtime: metadata.mtime().unwrap_or_else(Utc::now),
+        };
+        let parent_path = metadata.path().parent().unwrap_or(Path::root());
+        let metadata = node.metadata(parent_path);
+        Ok((inner.insert(node), metadata))
+    }
+}
+
+impl id::WriteFile for MemIdStorage {
+    async fn write_file(
+        &self,
+        id: &Id,
+        _parent_id: Option<&Id>,
+        metadata: &fsync::Metadata,
+        data: impl io::AsyncRead + Send,
+        _progress: Option<&SharedProgress>,
+    ) -> fsync::Result<fsync::Metadata> {
+        let data = read_data(data).await?;
+        let mut inner = self.inner.lock().unwrap();
+        inner.node(id)?;
+        inner.changes += 1;
+        let node = inner.entries.get_mut(&key(id).unwrap()).unwrap();
+        node.data = Some(data);
+        node.mtime = metadata.mtime().unwrap_or_else(Utc::now);
+        let parent_path = metadata.path().parent().unwrap_or(Path::root());
+        Ok(node.metadata(parent_path))
+    }
+}
+
+impl id::CopyFile for MemIdStorage {
+    async fn copy_file(
+        &self,
+        src_id: &Id,
+        dest_parent_id: Option<&Id>,
+        dest_path: &Path,
+        _progress: Option<&SharedProgress>,
+    ) -> fsync::Result<(IdBuf, fsync::Metadata)> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.check_dir(dest_parent_id)?;
+        let src = inner.node(src_id)?.clone();
+        let node = Node {
+            parent: dest_parent_id.map(Id::to_id_buf),
+            name: dest_path.file_name().unwrap_or_default().to_string(),
+            ..src
+        };
+        let metadata = node.metadata(dest_path.parent().unwrap_or(Path::root()));
+        Ok((inner.insert(node), metadata))
+    }
+}
+
+impl id::PatchMetadata for MemIdStorage {
+    async fn patch(
+        &self,
+        id: &Id,
+        path: &Path,
+        patch: &MetadataPatch,
+    ) -> fsync::Result<fsync::Metadata> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.node(id)?;
+        inner.changes += 1;
+        let node = inner.entries.get_mut(&key(id).unwrap()).unwrap();
+        if let Some(mtime) = patch.mtime {
+            node.mtime = mtime;
+        }
+        Ok(node.metadata(path.parent().unwrap_or(Path::root())))
+    }
+}
+
+impl id::Delete for MemIdStorage {
+    async fn delete(&self, id: &Id, _progress: Option<&SharedProgress>) -> fsync::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.node(id)?;
+        inner.remove(id);
+        Ok(())
+    }
+}
+
+impl crate::storage::Quota for MemIdStorage {}
+
+impl crate::storage::Flush for MemIdStorage {}
+
+impl id::Shared for MemIdStorage {}
+
+impl id::Revisions for MemIdStorage {}
+
+impl id::Checksums for MemIdStorage {}
+
+impl cache::Provider for MemIdStorage {
+    fn valid_id(id: &Id) -> bool {
+        key(id).is_some()
+    }
+
+    async fn change_token(&self) -> fsync::Result<Option<String>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.reports_changes.then(|| inner.changes.to_string()))
+    }
+}
+
+impl Shutdown for MemIdStorage {}
+
+impl id::Storage for MemIdStorage {}
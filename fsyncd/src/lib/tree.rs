@@ -1,48 +1,60 @@
-use std::{cmp::Ordering, mem};
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    mem,
+};
 
 use dashmap::DashMap;
-pub use fsync::tree::{Entry, EntryNode};
+pub use fsync::tree::{Entry, EntryNode, CHILDREN_PAGE_LEN};
 use fsync::{
-    path::{Path, PathBuf},
-    stat, StorageLoc,
+    caps::FsCaps,
+    path::{NormalizedPath, NormalizedPathBuf, Path, PathBuf},
+    stat, Conflict, OrderBy, StorageLoc,
 };
 use futures::{
     future::{self, BoxFuture},
-    StreamExt, TryStreamExt,
+    TryStreamExt,
 };
+use tokio::io::AsyncReadExt;
 
-use crate::storage;
+use crate::{
+    ignore::{IgnoreRules, IGNORE_FILE},
+    profile::{self, Phase, Span},
+    storage,
+};
 
+/// The `mtime_granularity` arguments are the ones of [`Entry::new_sync_within`]
 trait EntryExt {
-    fn with(self, md: fsync::Metadata, loc: StorageLoc) -> Self;
-    fn with_local(self, local: fsync::Metadata) -> Self;
-    fn with_remote(self, remote: fsync::Metadata) -> Self;
+    fn with(self, md: fsync::Metadata, loc: StorageLoc, mtime_granularity: u32) -> Self;
+    fn with_local(self, local: fsync::Metadata, mtime_granularity: u32) -> Self;
+    fn with_remote(self, remote: fsync::Metadata, mtime_granularity: u32) -> Self;
     fn without(self, loc: StorageLoc) -> Self;
     fn without_local(self) -> Self;
     fn without_remote(self) -> Self;
 }
 
 impl EntryExt for Entry {
-    fn with(self, md: fsync::Metadata, loc: StorageLoc) -> Self {
+    fn with(self, md: fsync::Metadata, loc: StorageLoc, mtime_granularity: u32) -> Self {
         match loc {
-            StorageLoc::Local => self.with_local(md),
-            StorageLoc::Remote => self.with_remote(md),
+            StorageLoc::Local => self.with_local(md, mtime_granularity),
+            StorageLoc::Remote => self.with_remote(md, mtime_granularity),
         }
     }
 
-    fn with_local(self, local: fsync::Metadata) -> Self {
+    fn with_local(self, local: fsync::Metadata, mtime_granularity: u32) -> Self {
         match self {
-            Entry::Remote(remote) => Entry::new_sync(local, remote),
+            Entry::Remote(remote) => Entry::new_sync_within(local, remote, mtime_granularity),
             Entry::Local(..) => Entry::Local(local),
-            Entry::Sync { remote, .. } => Entry::new_sync(local, remote),
+            Entry::Sync { remote, .. } => Entry::new_sync_within(local, remote, mtime_granularity),
         }
     }
 
-    fn with_remote(self, remote: fsync::Metadata) -> Self {
+    fn with_remote(self, remote: fsync::Metadata, mtime_granularity: u32) -> Self {
         match self {
-            Entry::Local(local) => Entry::new_sync(local, remote),
+            Entry::Local(local) => Entry::new_sync_within(local, remote, mtime_granularity),
             Entry::Remote(..) => Entry::Remote(remote),
-            Entry::Sync { local, .. } => Entry::new_sync(local, remote),
+            Entry::Sync { local, .. } => Entry::new_sync_within(local, remote, mtime_granularity),
         }
     }
 
@@ -70,62 +82,296 @@ impl EntryExt for Entry {
     }
 }
 
+/// A step of [`Walk`]
+#[derive(Debug)]
+pub enum Step {
+    /// A directory, before its children
+    Enter(EntryNode),
+    /// A directory, after its children
+    Leave(EntryNode),
+    /// An entry without children
+    Leaf(EntryNode),
+}
+
+impl Step {
+    pub fn node(&self) -> &EntryNode {
+        match self {
+            Step::Enter(node) | Step::Leave(node) | Step::Leaf(node) => node,
+        }
+    }
+}
+
+/// A lazy depth-first walk of a sub-tree.
+/// Only the pending siblings of the current branch are held in memory.
+/// In tree order, they are read from the tree a page of [`CHILDREN_PAGE_LEN`] at a time.
+/// Entries removed from the tree during the walk are skipped.
+#[derive(Debug)]
+pub struct Walk {
+    order: OrderBy,
+    stack: Vec<Pending>,
+}
+
+/// An item of the stack of [`Walk`]
+#[derive(Debug)]
+enum Pending {
+    /// An entry to visit, and whether its children were already pushed
+    Entry(PathBuf, bool),
+    /// The children of `dir` that come after `after`, in tree order
+    Children { dir: PathBuf, after: Option<String> },
+    /// An entry to visit without its descendants
+    Single(PathBuf),
+}
+
+impl Walk {
+    pub fn new(path: PathBuf, order: OrderBy) -> Self {
+        Self {
+            order,
+            stack: vec![Pending::Entry(path, false)],
+        }
+    }
+
+    /// A walk in tree order of the sub-tree at `path`, resumed after the entry at `after`
+    /// and its descendants, as if a previous walk had stopped there.
+    /// The whole sub-tree is walked if `after` is not below `path`.
+    pub fn after(path: PathBuf, after: &Path) -> Self {
+        if !path.is_ancestor_of(after) {
+            return Self::new(path, OrderBy::TreeOrder);
+        }
+        let mut branch = Vec::new();
+        let mut entry = after;
+        while entry != path.as_path() {
+            branch.push(entry);
+            entry = entry
+                .parent()
+                .expect("entry below path should have a parent");
+        }
+        let mut stack = Vec::with_capacity(2 * branch.len());
+        for entry in branch.into_iter().rev() {
+            let dir = entry.parent().unwrap().to_owned();
+            stack.push(Pending::Entry(dir.clone(), true));
+            stack.push(Pending::Children {
+                dir,
+                after: entry.file_name().map(str::to_owned),
+            });
+        }
+        Self {
+            order: OrderBy::TreeOrder,
+            stack,
+        }
+    }
+
+    /// A walk of the entries at `paths` only, in this order, provided as leaves
+    /// with their children, if any, but without their descendants.
+    pub fn of_paths(paths: Vec<PathBuf>) -> Self {
+        Self {
+            order: OrderBy::TreeOrder,
+            stack: paths.into_iter().rev().map(Pending::Single).collect(),
+        }
+    }
+
+    pub fn next(&mut self, tree: &DiffTree) -> Option<Step> {
+        while let Some(pending) = self.stack.pop() {
+            let (path, entered) = match pending {
+                Pending::Entry(path, entered) => (path, entered),
+                Pending::Children { dir, after } => {
+                    self.push_page(tree, dir, after);
+                    continue;
+                }
+                Pending::Single(path) => match tree.entry(&path) {
+                    Some(node) => return Some(Step::Leaf(node)),
+                    None => continue,
+                },
+            };
+            let Some(node) = tree.entry(&path) else {
+                continue;
+            };
+            if entered {
+                return Some(Step::Leave(node));
+            }
+            if node.children().is_empty() {
+                return Some(Step::Leaf(node));
+            }
+            self.stack.push(Pending::Entry(path.clone(), true));
+            match self.order {
+                OrderBy::TreeOrder => {
+                    self.stack.push(Pending::Children {
+                        dir: path,
+                        after: None,
+                    });
+                }
+                order => {
+                    let mut children: Vec<EntryNode> = node
+                        .children()
+                        .iter()
+                        .filter_map(|child| tree.entry(&path.join(child)))
+                        .collect();
+                    order.sort(&mut children);
+                    let children = children.into_iter().rev();
+                    self.stack.extend(
+                        children.map(|child| Pending::Entry(child.path().to_owned(), false)),
+                    );
+                }
+            }
+            return Some(Step::Enter(node));
+        }
+        None
+    }
+
+    /// Push the next page of the children of `dir`, and what remains after it
+    fn push_page(&mut self, tree: &DiffTree, dir: PathBuf, after: Option<String>) {
+        let Some(node) = tree.entry(&dir) else {
+            return;
+        };
+        let page: Vec<&String> = node
+            .children()
+            .after(after.as_deref())
+            .take(CHILDREN_PAGE_LEN)
+            .collect();
+        if page.len() == CHILDREN_PAGE_LEN {
+            let after = page.last().map(|name| name.to_string());
+            self.stack.push(Pending::Children {
+                dir: dir.clone(),
+                after,
+            });
+        }
+        self.stack.extend(
+            page.into_iter()
+                .rev()
+                .map(|name| Pending::Entry(dir.join(name), false)),
+        );
+    }
+
+    /// Number of entries and pages pending
+    #[cfg(test)]
+    fn pending(&self) -> usize {
+        self.stack.len()
+    }
+}
+
+/// Options of [`DiffTree::build_with`]
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    /// Rules of the entries left out of the tree,
+    /// completed by the `.fsyncignore` files found along the way
+    pub ignore: IgnoreRules,
+    /// Size in bytes above which files that are not synchronized are marked as too large
+    pub max_file_size: Option<u64>,
+    /// Capabilities of the local filesystem, against which the names of
+    /// the remote entries are checked. If unknown, no clash is detected.
+    pub fs_caps: Option<FsCaps>,
+}
+
 #[derive(Debug)]
 pub struct DiffTree {
-    nodes: DashMap<PathBuf, EntryNode>,
+    nodes: DashMap<NormalizedPathBuf, EntryNode>,
+    /// Granularity of the local modification times, see [`FsCaps::mtime_granularity`]
+    mtime_granularity: u32,
 }
 
 impl DiffTree {
     pub async fn build<L, R>(local: &L, remote: &R) -> anyhow::Result<Self>
+    where
+        L: storage::Storage,
+        R: storage::Storage,
+    {
+        Self::build_with(local, remote, BuildOptions::default()).await
+    }
+
+    pub async fn build_with<L, R>(
+        local: &L,
+        remote: &R,
+        options: BuildOptions,
+    ) -> anyhow::Result<Self>
     where
         L: storage::Storage,
         R: storage::Storage,
     {
         let nodes = DashMap::new();
+        let fs_caps = options.fs_caps.unwrap_or_default();
 
         let build = DiffTreeBuild {
             local,
             remote,
+            max_file_size: options.max_file_size,
+            fs_caps,
             nodes: &nodes,
         };
         build
-            .sync(fsync::Metadata::root(), fsync::Metadata::root())
+            .sync(
+                fsync::Metadata::root(),
+                fsync::Metadata::root(),
+                options.ignore,
+            )
             .await?;
 
-        Ok(Self { nodes })
+        Ok(Self {
+            nodes,
+            mtime_granularity: fs_caps.mtime_granularity,
+        })
+    }
+
+    /// A tree with only the root directory, to be populated with [`Self::insert`]
+    pub fn new_root() -> Self {
+        let root = Entry::new_sync(fsync::Metadata::root(), fsync::Metadata::root());
+        let nodes = DashMap::new();
+        nodes.insert(
+            owned_key(PathBuf::root()),
+            EntryNode::new(root, vec![], stat::Tree::null()),
+        );
+        Self {
+            nodes,
+            mtime_granularity: 1,
+        }
+    }
+
+    /// Granularity in seconds with which the modification times are compared
+    pub fn mtime_granularity(&self) -> u32 {
+        self.mtime_granularity
+    }
+
+    /// Replace all the entries by the ones of `other`
+    pub fn replace_with(&self, other: DiffTree) {
+        self.nodes.clear();
+        for (key, node) in other.nodes {
+            self.nodes.insert(key, node);
+        }
     }
 
     pub fn has_entry(&self, path: &Path) -> bool {
-        self.nodes.get(path).is_some()
+        self.nodes.get(&*key(path)).is_some()
     }
 
     pub fn entry(&self, path: &Path) -> Option<EntryNode> {
-        self.nodes.get(path).map(|node| node.clone())
+        self.nodes.get(&*key(path)).map(|node| node.clone())
     }
 
     pub fn entries<'a>(
         &'a self,
-    ) -> impl Iterator<Item = dashmap::mapref::multiple::RefMulti<'a, PathBuf, EntryNode>> {
+    ) -> impl Iterator<Item = dashmap::mapref::multiple::RefMulti<'a, NormalizedPathBuf, EntryNode>>
+    {
         self.nodes.iter()
     }
 
+    /// Insert `entry` at `path`, that is not in the tree yet.
+    /// The stats of the entry are added to all its ancestors.
     pub fn insert(&self, path: &Path, entry: EntryNode) {
         debug_assert_eq!(path, entry.path());
         debug_assert!(!self.has_entry(path));
         let parent_path = path.parent().expect("This path should have a parent");
+        let stats = entry.stats();
         {
             let mut parent = self
                 .nodes
-                .get_mut(parent_path)
+                .get_mut(&*key(parent_path))
                 .expect("this parent should be valid");
             parent.add_child(
                 path.file_name()
                     .expect("this path should have a file name")
                     .to_string(),
             );
-            parent.add_stat(&entry.stats());
         }
-        self.nodes.insert(path.to_path_buf(), entry);
+        self.nodes.insert(owned_key(path.to_path_buf()), entry);
+        self.add_stat_to_ancestors(path, &stats);
     }
 
     pub fn add_to_storage_check_conflict(
@@ -134,12 +380,80 @@ impl DiffTree {
         metadata: fsync::Metadata,
         loc: StorageLoc,
     ) -> bool {
-        self.op_entry_check_conflict(path, |entry| entry.with(metadata, loc))
+        let granularity = self.mtime_granularity;
+        self.op_entry_check_conflict(path, |entry| entry.with(metadata, loc, granularity))
+    }
+
+    /// Add `metadatas` to `loc`, as [`Self::add_to_storage_check_conflict`] does for each of them.
+    /// The stats are added to the ancestors once per parent, rather than once per entry.
+    /// Returns the paths of the entries that are conflicts.
+    pub fn add_all_to_storage(
+        &self,
+        metadatas: Vec<fsync::Metadata>,
+        loc: StorageLoc,
+    ) -> Vec<PathBuf> {
+        let granularity = self.mtime_granularity;
+        let mut conflicts = Vec::new();
+        let mut diffs: BTreeMap<PathBuf, stat::Tree> = BTreeMap::new();
+        for metadata in metadatas {
+            let path = metadata.path().to_owned();
+            let (diff, is_conflict) = {
+                let mut node = self
+                    .nodes
+                    .get_mut(&*key(&path))
+                    .expect("this node should be valid");
+                let rem = node.stats();
+                node.op_entry(|entry| entry.with(metadata, loc, granularity));
+                (node.stats() - rem, node.entry().is_conflict())
+            };
+            if is_conflict {
+                conflicts.push(path.clone());
+            }
+            if !diff.is_null() {
+                let parent = path.parent().expect("this path should have a parent");
+                *diffs
+                    .entry(parent.to_owned())
+                    .or_insert_with(stat::Tree::null) += diff;
+            }
+        }
+        for (parent, diff) in diffs {
+            self.nodes
+                .get_mut(&*key(&parent))
+                .expect("parent of valid path should be valid as well")
+                .add_stat(&diff);
+            self.add_stat_to_ancestors(&parent, &diff);
+        }
+        conflicts
+    }
+
+    /// Mark the synchronized file at `path` as a [`Conflict::ContentMismatch`].
+    /// Returns whether it was marked, which is not the case if it already had a conflict.
+    pub fn mark_content_mismatch(&self, path: &Path) -> bool {
+        let mut marked = false;
+        self.op_entry_check_conflict(path, |entry| match entry {
+            Entry::Sync {
+                local,
+                remote,
+                conflict: None,
+            } => {
+                marked = true;
+                Entry::Sync {
+                    local,
+                    remote,
+                    conflict: Some(Conflict::ContentMismatch),
+                }
+            }
+            entry => entry,
+        });
+        marked
     }
 
     pub fn remove_from_storage(&self, path: &Path, loc: StorageLoc) {
         let stat_diff = {
-            let mut node = self.nodes.get_mut(path).expect("This node should be valid");
+            let mut node = self
+                .nodes
+                .get_mut(&*key(path))
+                .expect("This node should be valid");
             if node.is_sync() {
                 let rem = node.stats();
                 node.op_entry(|entry| entry.without(loc));
@@ -148,12 +462,12 @@ impl DiffTree {
             } else {
                 let stat = node.stats();
                 mem::drop(node);
-                self.nodes.remove(path);
+                self.nodes.remove(&*key(path));
                 let parent_path = path.parent().expect("This path should have a valid parent");
                 let file_name = path
                     .file_name()
                     .expect("This path should have a valid name");
-                let mut parent = self.nodes.get_mut(parent_path).unwrap();
+                let mut parent = self.nodes.get_mut(&*key(parent_path)).unwrap();
                 parent.remove_child(file_name);
                 -stat
             }
@@ -164,7 +478,10 @@ impl DiffTree {
     /// Apply `op` to entry and return whether it is a conflict
     fn op_entry_check_conflict<F: FnOnce(Entry) -> Entry>(&self, path: &Path, op: F) -> bool {
         let (stat_diff, is_conflict) = {
-            let mut node = self.nodes.get_mut(path).expect("this node should be valid");
+            let mut node = self
+                .nodes
+                .get_mut(&*key(path))
+                .expect("this node should be valid");
 
             let rem = node.stats();
             node.op_entry(op);
@@ -183,7 +500,7 @@ impl DiffTree {
         while let Some(path) = parent {
             let mut node = self
                 .nodes
-                .get_mut(path)
+                .get_mut(&*key(path))
                 .expect("parent of valid path should be valid as well");
             node.add_stat(diff);
             parent = path.parent();
@@ -191,7 +508,8 @@ impl DiffTree {
     }
 
     /// Ensure that parents of `path` are added in the tree for `loc`.
-    /// Also perform stats calculation.
+    /// Also perform stats calculation: each parent added gets the stats of the ones
+    /// below it, and the ancestors already in `loc` get the stats of all of them.
     /// Returns which of the parents are conflicts.
     pub fn ensure_parents(&self, path: &Path, loc: StorageLoc) -> Vec<(PathBuf, bool)> {
         debug_assert!(path.is_absolute());
@@ -205,7 +523,10 @@ impl DiffTree {
 
         let mut parent = path.parent();
         while let Some(path) = parent {
-            let mut node = self.nodes.get_mut(path).expect("this node should be valid");
+            let mut node = self
+                .nodes
+                .get_mut(&*key(path))
+                .expect("this node should be valid");
 
             if node.entry().is_at_loc(loc) {
                 node.add_stat(&tree_stat);
@@ -215,15 +536,26 @@ impl DiffTree {
                     stat: Some(dir_stat),
                 };
 
+                // the node counts of the parents added below, e.g. their conflicts
+                let below = stat::Tree {
+                    local: stat::Dir::null(),
+                    remote: stat::Dir::null(),
+                    node: tree_stat.node,
+                };
+                node.add_stat(&below);
+
                 let bef = node.stats();
-                node.op_entry(move |entry| entry.with(md, loc));
+                let granularity = self.mtime_granularity;
+                node.op_entry(move |entry| entry.with(md, loc, granularity));
                 let aft = node.stats();
 
                 let is_conflict = node.entry().is_conflict();
                 conflicts.push((path.to_path_buf(), is_conflict));
 
-                tree_stat = aft - bef;
-                dir_stat += *tree_stat.by_loc(loc);
+                // the node was not in `loc`, its stat there is the one of the whole sub-tree
+                let own = aft - bef;
+                dir_stat = *own.by_loc(loc);
+                tree_stat = own + below;
             }
             parent = path.parent();
         }
@@ -231,7 +563,158 @@ impl DiffTree {
     }
 
     pub fn remove(&self, path: &Path) {
-        self.nodes.remove(path);
+        self.nodes.remove(&*key(path));
+    }
+
+    /// Remove the entry at `path` and all its descendants, regardless of their storage.
+    /// Stats of the ancestors are updated accordingly.
+    /// Returns the paths of the removed entries.
+    pub fn remove_subtree(&self, path: &Path) -> Vec<PathBuf> {
+        debug_assert!(!path.is_root());
+        let Some((_, node)) = self.nodes.remove(&*key(path)) else {
+            return vec![];
+        };
+        let parent_path = path.parent().expect("This path should have a valid parent");
+        let file_name = path
+            .file_name()
+            .expect("This path should have a valid name");
+        self.nodes
+            .get_mut(&*key(parent_path))
+            .expect("parent of valid path should be valid as well")
+            .remove_child(file_name);
+        self.add_stat_to_ancestors(path, &-node.stats());
+
+        let mut removed = vec![path.to_path_buf()];
+        self.remove_descendants(path, &node, &mut removed);
+        removed
+    }
+
+    /// Remove the descendants of `node` and push their paths to `removed`
+    fn remove_descendants(&self, path: &Path, node: &EntryNode, removed: &mut Vec<PathBuf>) {
+        let mut stack: Vec<PathBuf> = node
+            .children()
+            .iter()
+            .map(|child| path.join(child))
+            .collect();
+        while let Some(path) = stack.pop() {
+            if let Some((_, node)) = self.nodes.remove(&*key(&path)) {
+                stack.extend(node.children().iter().map(|child| path.join(child)));
+                removed.push(path);
+            }
+        }
+    }
+
+    /// Replace the sub-tree at `path` with `node` and its `descendants`.
+    /// The entry keeps its place among the children of its parent,
+    /// and stats of the ancestors are updated accordingly.
+    fn replace_subtree(
+        &self,
+        path: &Path,
+        node: EntryNode,
+        descendants: DashMap<NormalizedPathBuf, EntryNode>,
+    ) {
+        let old = self.entry(path);
+        if let Some(old) = &old {
+            self.remove_descendants(path, old, &mut vec![]);
+        }
+        for (path, node) in descendants {
+            self.nodes.insert(path, node);
+        }
+        match old {
+            Some(old) => {
+                let diff = node.stats() - old.stats();
+                self.nodes.insert(owned_key(path.to_path_buf()), node);
+                self.add_stat_to_ancestors(path, &diff);
+            }
+            None => self.insert(path, node),
+        }
+    }
+
+    /// Re-list the local directory at `path`, and its descendant directories if `deep`,
+    /// to catch up with the changes made outside of fsyncd.
+    /// The entries whose local side changed are rebuilt the same way as
+    /// in [`Self::build_with`]. Only the local storage is listed, but rebuilt
+    /// directories are listed on both sides.
+    pub async fn rescan_local<L, R>(
+        &self,
+        local: &L,
+        remote: &R,
+        path: &Path,
+        deep: bool,
+        options: &BuildOptions,
+    ) -> anyhow::Result<fsync::RescanReport>
+    where
+        L: storage::Storage,
+        R: storage::Storage,
+    {
+        let build = Rescan {
+            tree: self,
+            local,
+            remote,
+            max_file_size: options.max_file_size,
+            fs_caps: options.fs_caps.unwrap_or_default(),
+        };
+        let ignore = build
+            .ancestors_ignore_rules(path, options.ignore.clone())
+            .await;
+
+        let mut report = fsync::RescanReport::default();
+        let mut stack = vec![(path.to_path_buf(), ignore)];
+        while let Some((dir, ignore)) = stack.pop() {
+            let subdirs = build.dir(&dir, ignore, &mut report).await?;
+            if deep {
+                stack.extend(subdirs);
+            }
+        }
+        report.added.sort_unstable();
+        report.removed.sort_unstable();
+        report.modified.sort_unstable();
+        Ok(report)
+    }
+
+    /// Compare the entry at `path` and its children, or its whole sub-tree if `deep`,
+    /// with fresh listings of the storages. The listed entries are paired by name
+    /// as in [`Self::build_with`], so that the tree is held to the same reference.
+    pub async fn check<L, R>(
+        &self,
+        local: &L,
+        remote: &R,
+        path: &Path,
+        deep: bool,
+        options: &BuildOptions,
+    ) -> anyhow::Result<Vec<fsync::Discrepancy>>
+    where
+        L: storage::Storage,
+        R: storage::Storage,
+    {
+        let check = Rescan {
+            tree: self,
+            local,
+            remote,
+            max_file_size: options.max_file_size,
+            fs_caps: options.fs_caps.unwrap_or_default(),
+        };
+        let ignore = check
+            .ancestors_ignore_rules(path, options.ignore.clone())
+            .await;
+
+        let mut found = Vec::new();
+        let (loc, rem) = if path.is_root() {
+            (Some(fsync::Metadata::root()), Some(fsync::Metadata::root()))
+        } else {
+            let (loc, rem) = tokio::join!(storage_entry(local, path), storage_entry(remote, path));
+            let (loc, rem) = (loc?, rem?);
+            check.compare(path, loc.clone(), rem.clone(), &mut found);
+            (loc, rem)
+        };
+        let mut stack = vec![(path.to_path_buf(), loc, rem, ignore)];
+        while let Some((dir, loc, rem, ignore)) = stack.pop() {
+            let subdirs = check.check_dir(&dir, loc, rem, ignore, &mut found).await?;
+            if deep {
+                stack.extend(subdirs);
+            }
+        }
+        Ok(found)
     }
 
     pub fn print_out<W>(&self, w: &mut W)
@@ -239,7 +722,7 @@ impl DiffTree {
         W: std::io::Write,
     {
         let rootp = Path::root();
-        let root = self.nodes.get(rootp);
+        let root = self.nodes.get(&*key(rootp));
         if let Some(root) = root {
             for child_name in root.children() {
                 let path = rootp.join(child_name);
@@ -252,7 +735,7 @@ impl DiffTree {
     where
         W: std::io::Write,
     {
-        let node = self.nodes.get(path).unwrap();
+        let node = self.nodes.get(&*key(path)).unwrap();
         let marker = match node.entry() {
             Entry::Sync { .. } => "S",
             Entry::Local { .. } => "L",
@@ -277,7 +760,9 @@ impl DiffTree {
 struct DiffTreeBuild<'a, L, R> {
     local: &'a L,
     remote: &'a R,
-    nodes: &'a DashMap<PathBuf, EntryNode>,
+    max_file_size: Option<u64>,
+    fs_caps: FsCaps,
+    nodes: &'a DashMap<NormalizedPathBuf, EntryNode>,
 }
 
 impl<'a, L, R> DiffTreeBuild<'a, L, R>
@@ -285,146 +770,623 @@ where
     L: storage::Storage,
     R: storage::Storage,
 {
+    fn is_too_large(&self, entry: &fsync::Metadata) -> bool {
+        match (entry, self.max_file_size) {
+            (fsync::Metadata::Regular { size, .. }, Some(max)) => *size > max,
+            _ => false,
+        }
+    }
+
+    /// The names among `names` that the local filesystem can't store,
+    /// or that collide there with another name
+    fn name_clashes<'n>(&self, names: impl Iterator<Item = &'n str>) -> HashSet<&'n str> {
+        let mut by_key: HashMap<Cow<str>, Vec<&str>> = HashMap::new();
+        let mut clashes = HashSet::new();
+        for name in names {
+            if !self.fs_caps.supports_name(name) {
+                clashes.insert(name);
+            }
+            by_key
+                .entry(self.fs_caps.name_key(name))
+                .or_default()
+                .push(name);
+        }
+        for names in by_key.into_values().filter(|names| names.len() > 1) {
+            clashes.extend(names);
+        }
+        clashes
+    }
+
     fn sync(
         &self,
         local: fsync::Metadata,
         remote: fsync::Metadata,
+        ignore: IgnoreRules,
     ) -> BoxFuture<'_, anyhow::Result<stat::Tree>> {
         Box::pin(async move {
             let loc_children = entry_children_sorted(&*self.local, &local);
             let rem_children = entry_children_sorted(&*self.remote, &remote);
             let (loc_children, rem_children) = tokio::join!(loc_children, rem_children);
+            let (loc_children, rem_children) = (loc_children?, rem_children?);
 
-            let loc_children = loc_children?;
-            let mut loc_children = loc_children.iter();
-            let mut loc_child = loc_children.next();
-
-            let rem_children = rem_children?;
-            let mut rem_children = rem_children.iter();
-            let mut rem_child = rem_children.next();
+            // the local ignore file takes precedence if both sides have one
+            let ignore = if loc_children.iter().any(is_ignore_file) {
+                dir_ignore_rules(&*self.local, &local, &loc_children, ignore).await
+            } else {
+                dir_ignore_rules(&*self.remote, &remote, &rem_children, ignore).await
+            };
+            let loc_children = not_ignored(loc_children, &ignore);
+            let rem_children = not_ignored(rem_children, &ignore);
 
             let mut children = Vec::new();
-            let mut joinvec = Vec::new();
-
-            loop {
-                match (loc_child, rem_child) {
-                    (None, None) => break,
-                    (Some(loc), Some(rem)) => match loc.name().cmp(rem.name()) {
-                        Ordering::Equal => {
-                            joinvec.push(self.sync(loc.clone(), rem.clone()));
-                            children.push(loc.name().to_string());
-                            loc_child = loc_children.next();
-                            rem_child = rem_children.next();
-                        }
-                        Ordering::Less => {
-                            joinvec.push(self.local(loc.clone()));
-                            children.push(loc.name().to_string());
-                            loc_child = loc_children.next();
-                        }
-                        Ordering::Greater => {
-                            joinvec.push(self.remote(rem.clone()));
-                            children.push(rem.name().to_string());
-                            rem_child = rem_children.next();
-                        }
-                    },
-                    (Some(loc), None) => {
-                        joinvec.push(self.local(loc.clone()));
-                        children.push(loc.name().to_string());
-                        loc_child = loc_children.next();
-                    }
-                    (None, Some(rem)) => {
-                        joinvec.push(self.remote(rem.clone()));
-                        children.push(rem.name().to_string());
-                        rem_child = rem_children.next();
-                    }
+            let mut joinvec: Vec<BoxFuture<'_, _>> = Vec::new();
+
+            let merged = merge_by_name(loc_children, rem_children);
+            let clashes = self.name_clashes(
+                merged
+                    .iter()
+                    .map(|(loc, rem)| loc.as_ref().or(rem.as_ref()).unwrap().name()),
+            );
+            let clashes: HashSet<String> = clashes.into_iter().map(str::to_owned).collect();
+            // the top level entries are measured apart, to tell which sub-tree is slow to build
+            let top_level = local.path().is_root();
+            for (loc, rem) in merged {
+                let name = loc.as_ref().or(rem.as_ref()).unwrap().name().to_string();
+                let name_clash = clashes.contains(&name);
+                let entry = self.entry(loc, rem, ignore.clone(), name_clash);
+                if top_level {
+                    let span = Span::enter(Phase::TreeBuild).with_detail(|| format!("/{name}"));
+                    joinvec.push(Box::pin(profile::instrument(span, entry)));
+                } else {
+                    joinvec.push(entry);
                 }
+                children.push(name);
             }
 
             let mut children_stat = stat::Tree::null();
             let stat_vec = future::try_join_all(joinvec).await?;
             for stat in stat_vec {
-                children_stat = children_stat + stat;
+                children_stat += stat;
             }
 
             assert_eq!(local.path(), remote.path());
             let path = local.path().to_owned();
-            let entry = Entry::new_sync(local, remote);
+            let entry = Entry::new_sync_within(local, remote, self.fs_caps.mtime_granularity);
             let node = EntryNode::new(entry, children, children_stat);
             let res = node.stats();
 
-            self.nodes.insert(path, node);
+            self.nodes.insert(owned_key(path), node);
 
             Ok(res)
         })
     }
 
-    fn local(&self, entry: fsync::Metadata) -> BoxFuture<'_, anyhow::Result<stat::Tree>> {
+    /// Build the sub-tree of an entry found in either or both storages.
+    /// `name_clash` marks a remote entry whose name clashes with the local filesystem.
+    fn entry(
+        &self,
+        local: Option<fsync::Metadata>,
+        remote: Option<fsync::Metadata>,
+        ignore: IgnoreRules,
+        name_clash: bool,
+    ) -> BoxFuture<'_, anyhow::Result<stat::Tree>> {
+        match (local, remote) {
+            (Some(local), Some(remote)) => self.sync(local, remote, ignore),
+            (Some(local), None) => self.local(local, ignore),
+            (None, Some(remote)) => self.remote(remote, ignore, name_clash),
+            (None, None) => unreachable!("entry should be in at least one storage"),
+        }
+    }
+
+    fn local(
+        &self,
+        entry: fsync::Metadata,
+        ignore: IgnoreRules,
+    ) -> BoxFuture<'_, anyhow::Result<stat::Tree>> {
         Box::pin(async move {
             let mut children_names = Vec::new();
             let mut children_stat = stat::Tree::null();
 
-            if let fsync::Metadata::Directory { path, .. } = &entry {
+            if entry.is_dir() {
                 let mut joinvec = Vec::new();
-                let children = self.local.dir_entries(&path, None);
-                tokio::pin!(children);
+                let children = self
+                    .local
+                    .dir_entries(entry.path(), None)
+                    .try_collect::<Vec<_>>()
+                    .await?;
+                let ignore = dir_ignore_rules(&*self.local, &entry, &children, ignore).await;
 
-                while let Some(child) = children.next().await {
-                    let child = child?;
+                for child in not_ignored(children, &ignore) {
                     children_names.push(child.name().to_owned());
-                    joinvec.push(self.local(child));
+                    joinvec.push(self.local(child, ignore.clone()));
                 }
 
                 let stat_vec = future::try_join_all(joinvec).await?;
                 for s in stat_vec {
-                    children_stat = children_stat + s;
+                    children_stat += s;
                 }
             }
 
             let path = entry.path().to_owned();
+            let too_large = self.is_too_large(&entry);
             let entry = Entry::Local(entry);
-            let node = EntryNode::new(entry, children_names, children_stat);
+            let node =
+                EntryNode::new(entry, children_names, children_stat).with_too_large(too_large);
             let res = node.stats();
 
-            self.nodes.insert(path, node);
+            self.nodes.insert(owned_key(path), node);
 
             Ok(res)
         })
     }
 
-    fn remote(&self, entry: fsync::Metadata) -> BoxFuture<'_, anyhow::Result<stat::Tree>> {
+    fn remote(
+        &self,
+        entry: fsync::Metadata,
+        ignore: IgnoreRules,
+        name_clash: bool,
+    ) -> BoxFuture<'_, anyhow::Result<stat::Tree>> {
         Box::pin(async move {
             let mut child_names = Vec::new();
             let mut children_stat = stat::Tree::null();
 
-            if let fsync::Metadata::Directory { path, .. } = &entry {
+            if entry.is_dir() {
                 let mut joinvec = Vec::new();
-                let children = self.remote.dir_entries(path, None);
-                tokio::pin!(children);
+                let children = self
+                    .remote
+                    .dir_entries(entry.path(), None)
+                    .try_collect::<Vec<_>>()
+                    .await?;
+                let ignore = dir_ignore_rules(&*self.remote, &entry, &children, ignore).await;
+                let children = not_ignored(children, &ignore);
+                let clashes: HashSet<String> = self
+                    .name_clashes(children.iter().map(|child| child.name()))
+                    .into_iter()
+                    .map(str::to_owned)
+                    .collect();
 
-                while let Some(child) = children.next().await {
-                    let child = child?;
+                for child in children {
+                    let name_clash = clashes.contains(child.name());
                     child_names.push(child.name().to_owned());
-                    joinvec.push(self.remote(child));
+                    joinvec.push(self.remote(child, ignore.clone(), name_clash));
                 }
 
                 let stat_vec = future::try_join_all(joinvec).await?;
                 for s in stat_vec {
-                    children_stat = children_stat + s;
+                    children_stat += s;
                 }
             }
 
             let path = entry.path().to_owned();
+            let too_large = self.is_too_large(&entry);
             let entry = Entry::Remote(entry);
-            let node = EntryNode::new(entry, child_names, children_stat);
+            let node = EntryNode::new(entry, child_names, children_stat)
+                .with_too_large(too_large)
+                .with_name_clash(name_clash);
             let res = node.stats();
 
-            self.nodes.insert(path, node);
+            self.nodes.insert(owned_key(path), node);
 
             Ok(res)
         })
     }
 }
 
+/// Merge two lists of entries sorted by name into pairs of same name entries, in name order.
+/// An entry found in only one of the lists is paired with `None`.
+fn merge_by_name(
+    left: Vec<fsync::Metadata>,
+    right: Vec<fsync::Metadata>,
+) -> Vec<(Option<fsync::Metadata>, Option<fsync::Metadata>)> {
+    let mut merged = Vec::with_capacity(left.len().max(right.len()));
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    loop {
+        let order = match (left.peek(), right.peek()) {
+            (None, None) => break,
+            (Some(l), Some(r)) => l.name().cmp(r.name()),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+        };
+        let pair = match order {
+            Ordering::Equal => (left.next(), right.next()),
+            Ordering::Less => (left.next(), None),
+            Ordering::Greater => (None, right.next()),
+        };
+        merged.push(pair);
+    }
+    merged
+}
+
+struct Rescan<'a, L, R> {
+    tree: &'a DiffTree,
+    local: &'a L,
+    remote: &'a R,
+    max_file_size: Option<u64>,
+    fs_caps: FsCaps,
+}
+
+impl<L, R> Rescan<'_, L, R>
+where
+    L: storage::Storage,
+    R: storage::Storage,
+{
+    /// The rules in effect for the entry at `path`, from the ignore files of its ancestors
+    async fn ancestors_ignore_rules(&self, path: &Path, ignore: IgnoreRules) -> IgnoreRules {
+        let mut ancestors = vec![];
+        let mut parent = path.parent();
+        while let Some(dir) = parent {
+            ancestors.push(dir);
+            parent = dir.parent();
+        }
+        let mut ignore = ignore;
+        for dir in ancestors.into_iter().rev() {
+            let Some(node) = self.tree.entry(dir) else {
+                break;
+            };
+            let children = self.tree_children(dir, &node);
+            ignore = self
+                .tree_ignore_rules(node.entry(), &children, ignore)
+                .await;
+        }
+        ignore
+    }
+
+    fn tree_children(&self, dir: &Path, node: &EntryNode) -> Vec<EntryNode> {
+        node.children()
+            .iter()
+            .filter_map(|child| self.tree.entry(&dir.join(child)))
+            .collect()
+    }
+
+    /// Add to `ignore` the rules of the ignore file of `dir` as recorded in the tree,
+    /// the local file taking precedence as in the build
+    async fn tree_ignore_rules(
+        &self,
+        dir: &Entry,
+        children: &[EntryNode],
+        ignore: IgnoreRules,
+    ) -> IgnoreRules {
+        let dir = without_stat(dir.clone().into_local_metadata().unwrap_or_else(|| {
+            dir.clone()
+                .into_remote_metadata()
+                .expect("entry should be in at least one storage")
+        }));
+        let children_at = |loc| -> Vec<fsync::Metadata> {
+            children
+                .iter()
+                .filter_map(|child| child.entry().clone().into_metadata(loc))
+                .collect()
+        };
+        let loc_children = children_at(StorageLoc::Local);
+        if loc_children.iter().any(is_ignore_file) {
+            dir_ignore_rules(self.local, &dir, &loc_children, ignore).await
+        } else {
+            let rem_children = children_at(StorageLoc::Remote);
+            dir_ignore_rules(self.remote, &dir, &rem_children, ignore).await
+        }
+    }
+
+    /// Rescan the local directory at `path`, with `ignore` the rules in effect for it.
+    /// Returns the unchanged sub-directories along with the rules in effect for them.
+    async fn dir(
+        &self,
+        path: &Path,
+        ignore: IgnoreRules,
+        report: &mut fsync::RescanReport,
+    ) -> anyhow::Result<Vec<(PathBuf, IgnoreRules)>> {
+        let node = self
+            .tree
+            .entry(path)
+            .ok_or_else(|| anyhow::anyhow!("{path} is not in the tree"))?;
+        let Some(dir) = node.entry().clone().into_local_metadata() else {
+            anyhow::bail!("{path} is not in the local storage");
+        };
+        anyhow::ensure!(dir.is_dir(), "{path} is not a local directory");
+        let dir = without_stat(dir);
+
+        let loc_children = entry_children_sorted(self.local, &dir).await?;
+        let tree_children = self.tree_children(path, &node);
+
+        let ignore = if loc_children.iter().any(is_ignore_file) {
+            dir_ignore_rules(self.local, &dir, &loc_children, ignore).await
+        } else {
+            let rem_children: Vec<_> = tree_children
+                .iter()
+                .filter_map(|child| child.entry().clone().into_remote_metadata())
+                .collect();
+            dir_ignore_rules(self.remote, &dir, &rem_children, ignore).await
+        };
+        let loc_children = not_ignored(loc_children, &ignore);
+
+        // entries that the rules now leave out of the tree
+        let mut tree_loc_children = Vec::new();
+        let mut remotes = BTreeMap::new();
+        for child in tree_children {
+            let entry = child.into_entry();
+            let child_path = entry.path().to_owned();
+            if ignore.is_ignored(&child_path, entry.is_safe_dir()) {
+                self.tree.remove_subtree(&child_path);
+                report.removed.push(child_path);
+                continue;
+            }
+            if let Some(local) = entry.clone().into_local_metadata() {
+                tree_loc_children.push(without_stat(local));
+            }
+            if let Some(remote) = entry.into_remote_metadata() {
+                remotes.insert(child_path, without_stat(remote));
+            }
+        }
+        tree_loc_children.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+
+        let mut subdirs = Vec::new();
+        for (old, new) in merge_by_name(tree_loc_children, loc_children) {
+            let child_path = old.as_ref().or(new.as_ref()).unwrap().path().to_owned();
+            match (&old, &new) {
+                (Some(old), Some(new)) if old.is_dir() && new.is_dir() => {
+                    subdirs.push((child_path, ignore.clone()));
+                    continue;
+                }
+                (Some(old), Some(new)) if old == new => continue,
+                (Some(_), Some(_)) => report.modified.push(child_path.clone()),
+                (None, Some(_)) => report.added.push(child_path.clone()),
+                (Some(_), None) => report.removed.push(child_path.clone()),
+                (None, None) => unreachable!(),
+            }
+            let remote = remotes.remove(&child_path);
+            self.rebuild(&child_path, new, remote, ignore.clone())
+                .await?;
+        }
+        Ok(subdirs)
+    }
+
+    /// Compare the children of the entry at `path` with the children of its `local`
+    /// and `remote` sides as listed by the storages, with `ignore` the rules in effect
+    /// for it. Returns the listed sub-directories along with the rules in effect for them.
+    async fn check_dir(
+        &self,
+        path: &Path,
+        local: Option<fsync::Metadata>,
+        remote: Option<fsync::Metadata>,
+        ignore: IgnoreRules,
+        found: &mut Vec<fsync::Discrepancy>,
+    ) -> anyhow::Result<Vec<CheckedDir>> {
+        let loc_children = match &local {
+            Some(dir) => entry_children_sorted(self.local, dir).await?,
+            None => vec![],
+        };
+        let rem_children = match &remote {
+            Some(dir) => entry_children_sorted(self.remote, dir).await?,
+            None => vec![],
+        };
+        let ignore = match (&local, &remote) {
+            (Some(dir), _) if loc_children.iter().any(is_ignore_file) => {
+                dir_ignore_rules(self.local, dir, &loc_children, ignore).await
+            }
+            (_, Some(dir)) => dir_ignore_rules(self.remote, dir, &rem_children, ignore).await,
+            _ => ignore,
+        };
+        let loc_children = not_ignored(loc_children, &ignore);
+        let rem_children = not_ignored(rem_children, &ignore);
+
+        let mut tree_children: HashSet<String> = self
+            .tree
+            .entry(path)
+            .map(|node| node.children().iter().cloned().collect())
+            .unwrap_or_default();
+        let mut subdirs = Vec::new();
+        for (loc, rem) in merge_by_name(loc_children, rem_children) {
+            let child = loc.as_ref().or(rem.as_ref()).unwrap();
+            let child_path = child.path().to_owned();
+            let is_dir = loc.iter().chain(rem.iter()).any(|md| md.is_dir());
+            if !tree_children.remove(child.name()) && self.tree.has_entry(&child_path) {
+                found.push(fsync::Discrepancy::new(
+                    path,
+                    "children",
+                    child.name(),
+                    "missing",
+                ));
+            }
+            self.compare(&child_path, loc.clone(), rem.clone(), found);
+            if is_dir {
+                subdirs.push((child_path, loc, rem, ignore.clone()));
+            }
+        }
+        for name in tree_children {
+            let child_path = path.join(name.as_str());
+            if self.tree.has_entry(&child_path) {
+                self.compare(&child_path, None, None, found);
+            } else {
+                found.push(fsync::Discrepancy::new(path, "children", "missing", &name));
+            }
+        }
+        Ok(subdirs)
+    }
+
+    /// Compare the entry at `path` in the tree with its `local` and `remote` metadata
+    /// as listed by the storages
+    fn compare(
+        &self,
+        path: &Path,
+        local: Option<fsync::Metadata>,
+        remote: Option<fsync::Metadata>,
+        found: &mut Vec<fsync::Discrepancy>,
+    ) {
+        let entry = self.tree.entry(path).map(EntryNode::into_entry);
+        let in_tree = |loc| {
+            entry
+                .clone()
+                .and_then(|entry| entry.into_metadata(loc))
+                .map(without_stat)
+        };
+        let (local, remote) = (local.map(without_stat), remote.map(without_stat));
+        for (field, expected, actual) in [
+            ("local", &local, in_tree(StorageLoc::Local)),
+            ("remote", &remote, in_tree(StorageLoc::Remote)),
+        ] {
+            if *expected != actual {
+                let (expected, actual) = describe(expected.as_ref(), actual.as_ref());
+                found.push(fsync::Discrepancy::new(path, field, &expected, &actual));
+            }
+        }
+        if let (Some(local), Some(remote), Some(Entry::Sync { conflict, .. })) =
+            (&local, &remote, &entry)
+        {
+            let expected = Conflict::check_within(local, remote, self.fs_caps.mtime_granularity);
+            // a content mismatch is only found by a verification
+            let verified = expected.is_none() && *conflict == Some(Conflict::ContentMismatch);
+            if expected != *conflict && !verified {
+                found.push(fsync::Discrepancy::new(
+                    path,
+                    "conflict",
+                    &format!("{expected:?}"),
+                    &format!("{conflict:?}"),
+                ));
+            }
+        }
+    }
+
+    /// Rebuild the sub-tree at `path` from the given metadata
+    async fn rebuild(
+        &self,
+        path: &Path,
+        local: Option<fsync::Metadata>,
+        remote: Option<fsync::Metadata>,
+        ignore: IgnoreRules,
+    ) -> anyhow::Result<()> {
+        if local.is_none() && remote.is_none() {
+            self.tree.remove_subtree(path);
+            return Ok(());
+        }
+        let nodes = DashMap::new();
+        let build = DiffTreeBuild {
+            local: self.local,
+            remote: self.remote,
+            max_file_size: self.max_file_size,
+            fs_caps: self.fs_caps,
+            nodes: &nodes,
+        };
+        // the clashes with the siblings are only checked when the parent is built
+        build.entry(local, remote, ignore, false).await?;
+        let (_, node) = nodes
+            .remove(&*key(path))
+            .expect("rebuilt entry should be in the nodes");
+        self.tree.replace_subtree(path, node, nodes);
+        Ok(())
+    }
+}
+
+/// A directory to check, with its metadata as listed by the storages
+/// and the ignore rules in effect for it
+type CheckedDir = (
+    PathBuf,
+    Option<fsync::Metadata>,
+    Option<fsync::Metadata>,
+    IgnoreRules,
+);
+
+/// Describe the `expected` and `actual` metadata of an entry,
+/// in full if the summaries do not tell them apart
+fn describe(
+    expected: Option<&fsync::Metadata>,
+    actual: Option<&fsync::Metadata>,
+) -> (String, String) {
+    let summary = |metadata: Option<&fsync::Metadata>| match metadata {
+        None => "absent".to_string(),
+        Some(fsync::Metadata::Directory { .. }) => "directory".to_string(),
+        Some(fsync::Metadata::Regular { size, mtime, .. }) => {
+            format!("file of {size} bytes modified at {mtime}")
+        }
+    };
+    let (exp, act) = (summary(expected), summary(actual));
+    if exp != act {
+        (exp, act)
+    } else {
+        (format!("{expected:?}"), format!("{actual:?}"))
+    }
+}
+
+/// The metadata of the entry at `path` in `storage`, if it exists
+pub async fn storage_entry<S>(storage: &S, path: &Path) -> fsync::Result<Option<fsync::Metadata>>
+where
+    S: storage::DirEntries,
+{
+    let parent = path.parent().expect("Non-root path should have a parent");
+    let entries = storage.dir_entries(parent, None);
+    futures::pin_mut!(entries);
+    while let Some(metadata) = entries.try_next().await? {
+        if metadata.path() == path {
+            return Ok(Some(metadata));
+        }
+    }
+    Ok(None)
+}
+
+/// The key of `path` in the nodes of a tree.
+/// The paths given to the tree are normalized by the service, so this seldom allocates.
+fn key(path: &Path) -> Cow<'_, NormalizedPath> {
+    match NormalizedPath::new(path) {
+        Some(key) => Cow::Borrowed(key),
+        None => Cow::Owned(owned_key(path.to_path_buf())),
+    }
+}
+
+fn owned_key(path: PathBuf) -> NormalizedPathBuf {
+    NormalizedPathBuf::new(path).expect("The paths of the tree should not escape the root")
+}
+
+/// The metadata of a directory as listed by a storage, without the stats computed in the tree
+fn without_stat(entry: fsync::Metadata) -> fsync::Metadata {
+    match entry {
+        fsync::Metadata::Directory { path, .. } => fsync::Metadata::Directory { path, stat: None },
+        entry => entry,
+    }
+}
+
+fn is_ignore_file(entry: &fsync::Metadata) -> bool {
+    entry.is_file() && entry.name() == IGNORE_FILE
+}
+
+/// Add to `ignore` the rules of the ignore file of `dir`, if `children` has one
+async fn dir_ignore_rules<S>(
+    storage: &S,
+    dir: &fsync::Metadata,
+    children: &[fsync::Metadata],
+    ignore: IgnoreRules,
+) -> IgnoreRules
+where
+    S: storage::Storage,
+{
+    let Some(file) = children.iter().find(|child| is_ignore_file(child)) else {
+        return ignore;
+    };
+    match read_to_string(storage, file.path()).await {
+        Ok(content) => ignore.with_file(dir.path(), &content),
+        Err(err) => {
+            log::warn!("Could not read {}: {err}", file.path());
+            ignore
+        }
+    }
+}
+
+async fn read_to_string<S>(storage: &S, path: &Path) -> anyhow::Result<String>
+where
+    S: storage::Storage,
+{
+    let read = storage.read_file(path.to_owned(), None).await?;
+    tokio::pin!(read);
+    let mut content = String::new();
+    read.read_to_string(&mut content).await?;
+    Ok(content)
+}
+
+fn not_ignored(children: Vec<fsync::Metadata>, ignore: &IgnoreRules) -> Vec<fsync::Metadata> {
+    children
+        .into_iter()
+        .filter(|child| !ignore.is_ignored(child.path(), child.is_dir()))
+        .collect()
+}
+
 async fn entry_children_sorted<S>(
     storage: &S,
     entry: &fsync::Metadata,
@@ -442,3 +1404,292 @@ where
 
     Ok(children)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str) -> fsync::Metadata {
+        fsync::Metadata::Regular {
+            path: PathBuf::from(path),
+            size: 0,
+            mtime: chrono::Utc::now(),
+            link_target: None,
+        }
+    }
+
+    fn dir(path: &str) -> fsync::Metadata {
+        fsync::Metadata::Directory {
+            path: PathBuf::from(path),
+            stat: None,
+        }
+    }
+
+    /// A directory of which the stats are counted in the tree
+    fn empty_dir(path: &str) -> fsync::Metadata {
+        fsync::Metadata::Directory {
+            path: PathBuf::from(path),
+            stat: Some(stat::Dir::null()),
+        }
+    }
+
+    fn insert(tree: &DiffTree, entry: Entry) {
+        let path = entry.path().to_owned();
+        tree.insert(&path, EntryNode::new(entry, vec![], stat::Tree::null()));
+    }
+
+    /// Check that the conflicts of each node are the sum of the ones of its children,
+    /// plus its own, and return the conflicts of the root
+    fn check_conflicts(tree: &DiffTree) -> i64 {
+        let mut count = 0;
+        for node in tree.entries() {
+            let below: i64 = node
+                .children()
+                .iter()
+                .map(|name| {
+                    let child = tree.entry(&node.path().join(name)).unwrap();
+                    child.stats().node.conflicts
+                })
+                .sum();
+            assert_eq!(node.conflicts_below(), below, "{}", node.path());
+            assert_eq!(
+                node.stats().node.conflicts,
+                node.conflicts_here() + node.conflicts_below(),
+                "{}",
+                node.path()
+            );
+            count += node.conflicts_here();
+        }
+        let root = tree.entry(Path::root()).unwrap();
+        assert_eq!(root.stats().node.conflicts, count);
+        count
+    }
+
+    fn names(
+        merged: &[(Option<fsync::Metadata>, Option<fsync::Metadata>)],
+    ) -> Vec<(Option<&str>, Option<&str>)> {
+        merged
+            .iter()
+            .map(|(l, r)| (l.as_ref().map(|l| l.name()), r.as_ref().map(|r| r.name())))
+            .collect()
+    }
+
+    #[test]
+    fn merge_by_name_pairs() {
+        let left = vec![file("/a"), dir("/b"), file("/d")];
+        let right = vec![file("/b"), file("/c"), file("/d"), dir("/e")];
+        let merged = merge_by_name(left, right);
+        assert_eq!(
+            names(&merged),
+            vec![
+                (Some("a"), None),
+                (Some("b"), Some("b")),
+                (None, Some("c")),
+                (Some("d"), Some("d")),
+                (None, Some("e")),
+            ]
+        );
+        // the kind of the entries is left to the caller
+        assert!(merged[1].0.as_ref().unwrap().is_dir());
+        assert!(merged[1].1.as_ref().unwrap().is_file());
+    }
+
+    #[test]
+    fn merge_by_name_empty() {
+        assert!(merge_by_name(vec![], vec![]).is_empty());
+        assert_eq!(
+            names(&merge_by_name(vec![file("/a")], vec![])),
+            vec![(Some("a"), None)]
+        );
+        assert_eq!(
+            names(&merge_by_name(vec![], vec![file("/a"), file("/b")])),
+            vec![(None, Some("a")), (None, Some("b"))]
+        );
+    }
+
+    #[test]
+    fn conflicts_counted_at_their_node() {
+        let tree = DiffTree::new_root();
+        // a local directory where the remote has a file, and a file differing on both sides
+        insert(&tree, Entry::new_sync(dir("/d"), file("/d")));
+        insert(&tree, Entry::Local(file("/d/a.txt")));
+        insert(&tree, Entry::Local(dir("/d/sub")));
+        insert(&tree, Entry::Local(file("/d/sub/b.txt")));
+        let remote = fsync::Metadata::Regular {
+            path: PathBuf::from("/f.txt"),
+            size: 1,
+            mtime: chrono::Utc::now(),
+            link_target: None,
+        };
+        insert(&tree, Entry::new_sync(file("/f.txt"), remote));
+
+        assert_eq!(check_conflicts(&tree), 2);
+        let d = tree.entry(Path::new("/d")).unwrap();
+        assert_eq!((d.conflicts_here(), d.conflicts_below()), (1, 0));
+        let root = tree.entry(Path::root()).unwrap();
+        assert_eq!((root.conflicts_here(), root.conflicts_below()), (0, 2));
+
+        tree.remove_from_storage(Path::new("/d"), StorageLoc::Remote);
+        assert_eq!(check_conflicts(&tree), 1);
+    }
+
+    #[test]
+    fn parents_added_above_a_conflict() {
+        let tree = DiffTree::new_root();
+        insert(&tree, Entry::Remote(dir("/x")));
+        insert(&tree, Entry::Remote(dir("/x/y")));
+        insert(&tree, Entry::Remote(file("/x/y/z")));
+
+        // the local parents of /x/y/z/f.txt, where the remote has the file /x/y/z
+        let conflicts = tree.ensure_parents(Path::new("/x/y/z/f.txt"), StorageLoc::Local);
+        assert_eq!(
+            conflicts,
+            vec![
+                (PathBuf::from("/x/y/z"), true),
+                (PathBuf::from("/x/y"), false),
+                (PathBuf::from("/x"), false),
+            ]
+        );
+
+        assert_eq!(check_conflicts(&tree), 1);
+        let x = tree.entry(Path::new("/x")).unwrap();
+        assert_eq!((x.conflicts_here(), x.conflicts_below()), (0, 1));
+
+        let stats = tree.entry(Path::root()).unwrap().stats();
+        assert_eq!(stats.node.nodes, 4);
+        assert_eq!(stats.node.sync, 4);
+        assert_eq!(stats.local.dirs, 4);
+        assert_eq!(stats.remote.dirs, 3);
+        assert_eq!(stats.remote.files, 1);
+        let y = tree.entry(Path::new("/x/y")).unwrap().stats();
+        assert_eq!(y.local.dirs, 2);
+    }
+
+    #[test]
+    fn added_in_bulk_as_one_by_one() {
+        let mtime = chrono::Utc::now();
+        let file = |path: &str| fsync::Metadata::Regular {
+            path: PathBuf::from(path),
+            size: 3,
+            mtime,
+            link_target: None,
+        };
+        let local_tree = || {
+            let tree = DiffTree::new_root();
+            insert(&tree, Entry::Local(dir("/x")));
+            insert(&tree, Entry::Local(dir("/x/y")));
+            insert(&tree, Entry::Local(file("/x/a.txt")));
+            insert(&tree, Entry::Local(file("/x/y/b.txt")));
+            insert(&tree, Entry::Local(file("/x/y/c.txt")));
+            tree
+        };
+        let added = || {
+            vec![
+                empty_dir("/x"),
+                empty_dir("/x/y"),
+                file("/x/a.txt"),
+                file("/x/y/b.txt"),
+                file("/x/y/c.txt"),
+            ]
+        };
+
+        let one_by_one = local_tree();
+        for metadata in added() {
+            let path = metadata.path().to_owned();
+            one_by_one.add_to_storage_check_conflict(&path, metadata, StorageLoc::Remote);
+        }
+        let bulk = local_tree();
+        let conflicts = bulk.add_all_to_storage(added(), StorageLoc::Remote);
+        assert!(conflicts.is_empty());
+
+        for path in ["/", "/x", "/x/y", "/x/a.txt", "/x/y/b.txt", "/x/y/c.txt"] {
+            let path = Path::new(path);
+            assert_eq!(bulk.entry(path), one_by_one.entry(path), "{path}");
+        }
+        let stats = bulk.entry(Path::root()).unwrap().stats();
+        assert_eq!(stats.node.sync, 6);
+        assert_eq!(stats.remote.dirs, 3);
+        assert_eq!(stats.remote.files, 3);
+        assert_eq!(stats.remote.data, 9);
+    }
+
+    #[test]
+    fn content_mismatch_is_counted() {
+        let tree = DiffTree::new_root();
+        insert(&tree, Entry::new_sync(file("/a.txt"), file("/a.txt")));
+        assert!(tree.mark_content_mismatch(Path::new("/a.txt")));
+        assert!(!tree.mark_content_mismatch(Path::new("/a.txt")));
+        assert_eq!(check_conflicts(&tree), 1);
+    }
+
+    #[test]
+    fn walk_resumed_after_an_entry() {
+        let tree = DiffTree::new_root();
+        for path in ["/a", "/a/b", "/d"] {
+            insert(&tree, Entry::Local(dir(path)));
+        }
+        for path in ["/a/b/c", "/a/b/d", "/a/e", "/b", "/d/a", "/e"] {
+            insert(&tree, Entry::Local(file(path)));
+        }
+        let steps = |mut walk: Walk| {
+            let mut steps = Vec::new();
+            while let Some(step) = walk.next(&tree) {
+                let kind = match step {
+                    Step::Enter(_) => "enter",
+                    Step::Leave(_) => "leave",
+                    Step::Leaf(_) => "leaf",
+                };
+                steps.push((kind, step.node().path().to_string()));
+            }
+            steps
+        };
+        let full = steps(Walk::new(PathBuf::root(), OrderBy::TreeOrder));
+        for (i, (_, path)) in full.iter().enumerate() {
+            // a walk stops at leaves or when entering directories, that it skips on resume
+            if tree.entry(Path::new(path)).unwrap().children().is_empty() {
+                let resumed = steps(Walk::after(PathBuf::root(), Path::new(path)));
+                assert_eq!(resumed, full[i + 1..], "after {path}");
+            }
+        }
+        let sub = steps(Walk::after(PathBuf::from("/a"), Path::new("/d/a")));
+        assert_eq!(
+            sub,
+            steps(Walk::new(PathBuf::from("/a"), OrderBy::TreeOrder))
+        );
+    }
+
+    #[test]
+    fn walk_large_dir_by_pages() {
+        const LEN: usize = 200_000;
+        let name = |i: usize| format!("photo{i:06}.jpg");
+        let tree = DiffTree::new_root();
+        for i in 0..LEN {
+            let path = PathBuf::from(format!("/{}", name(i)));
+            let node = EntryNode::new(
+                Entry::Local(file(path.as_str())),
+                vec![],
+                stat::Tree::null(),
+            );
+            tree.insert(&path, node);
+        }
+
+        let mut walk = Walk::new(PathBuf::root(), OrderBy::TreeOrder);
+        assert!(matches!(walk.next(&tree), Some(Step::Enter(_))));
+        let mut count = 0;
+        let mut max_pending = 0;
+        while let Some(step) = walk.next(&tree) {
+            max_pending = max_pending.max(walk.pending());
+            match step {
+                Step::Leaf(node) => {
+                    assert_eq!(node.name(), Some(name(count).as_str()));
+                    count += 1;
+                }
+                Step::Leave(node) => assert!(node.path().is_root()),
+                Step::Enter(_) => panic!("unexpected directory"),
+            }
+        }
+        assert_eq!(count, LEN);
+        // a page of children, the rest of the listing and the root
+        assert!(max_pending <= CHILDREN_PAGE_LEN + 2);
+    }
+}
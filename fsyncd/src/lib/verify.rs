@@ -0,0 +1,173 @@
+//! Verification of the content of the synchronized files, see [`fsync::Fsync::verify`].
+//!
+//! The local files are hashed again, bypassing the cache of the checksums, and compared to
+//! the checksums reported by the remote storage. The checksums that the storage did not
+//! report with the listings are requested one file at a time, spaced by [`FETCH_INTERVAL`],
+//! so that the verification of a whole share does not exhaust the request quota of the storage.
+//! The deep verification hashes the remote content instead, which also verifies the files
+//! of the storages that have no checksum, at the cost of downloading them.
+
+use std::time::Duration;
+
+use fsync::{HashAlgo, Metadata};
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::storage::{self, hash};
+
+/// Minimum time between two checksums requested from the remote storage
+pub const FETCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The result of the verification of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Same,
+    Differ,
+    /// The content could not be compared, for the given reason
+    Unverifiable(String),
+}
+
+/// Spaces the requests sent to a storage by a minimum interval
+#[derive(Debug)]
+pub struct Limiter {
+    interval: Duration,
+    next: Mutex<Option<Instant>>,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new(FETCH_INTERVAL)
+    }
+}
+
+impl Limiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Wait until the next request may be sent
+    pub async fn wait(&self) {
+        let mut next = self.next.lock().await;
+        if let Some(next) = *next {
+            tokio::time::sleep_until(next).await;
+        }
+        *next = Some(Instant::now() + self.interval);
+    }
+}
+
+/// Compare the content of the `local` and `remote` copies of a file.
+/// Unless `deep`, the checksum of the remote copy is the one reported by `remote_storage`,
+/// requested through `limiter` if it is not known yet.
+pub async fn verify_file<L, R>(
+    local_storage: &L,
+    remote_storage: &R,
+    local: &Metadata,
+    remote: &Metadata,
+    deep: bool,
+    limiter: &Limiter,
+) -> fsync::Result<Outcome>
+where
+    L: storage::ReadFile + Sync,
+    R: storage::ReadFile + storage::Checksums + Sync,
+{
+    let algo = HashAlgo::Md5;
+    let remote_md5 = if deep {
+        hash::hash_file(remote_storage, remote, algo, None, None, None)
+            .await?
+            .hex
+    } else {
+        let md5 = match remote_storage.known_md5(remote.path()) {
+            Some(md5) => Some(md5),
+            None => {
+                limiter.wait().await;
+                remote_storage.fetch_md5(remote.path()).await?
+            }
+        };
+        let Some(md5) = md5 else {
+            return Ok(Outcome::Unverifiable(
+                "the remote storage reports no checksum, verify with --deep".into(),
+            ));
+        };
+        md5
+    };
+    let local_md5 = hash::hash_file(local_storage, local, algo, None, None, None)
+        .await?
+        .hex;
+    if local_md5.eq_ignore_ascii_case(&remote_md5) {
+        Ok(Outcome::Same)
+    } else {
+        Ok(Outcome::Differ)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fsync::path::{FsPathBuf, Path};
+
+    use super::*;
+    use crate::storage::{fs::FileSystem, mem::MemStorage};
+
+    fn mtime() -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap()
+    }
+
+    async fn metadata<S: storage::DirEntries>(storage: &S, path: &str) -> Metadata {
+        crate::tree::storage_entry(storage, Path::new(path))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn same_size_and_time_but_different_content() {
+        let (local, remote) = (MemStorage::new(), MemStorage::new());
+        local.put_file(Path::new("/same.txt"), b"hello", mtime());
+        remote.put_file(Path::new("/same.txt"), b"hello", mtime());
+        local.put_file(Path::new("/rotten.txt"), b"hello", mtime());
+        remote.put_file(Path::new("/rotten.txt"), b"hellp", mtime());
+        let limiter = Limiter::new(Duration::ZERO);
+
+        for deep in [false, true] {
+            for (path, expected) in [
+                ("/same.txt", Outcome::Same),
+                ("/rotten.txt", Outcome::Differ),
+            ] {
+                let (l, r) = (metadata(&local, path).await, metadata(&remote, path).await);
+                let outcome = verify_file(&local, &remote, &l, &r, deep, &limiter)
+                    .await
+                    .unwrap();
+                assert_eq!(outcome, expected, "{path}, deep: {deep}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn no_remote_checksum() {
+        let dir = std::env::temp_dir().join(format!("fsyncd-verify-{}", std::process::id()));
+        let dir = FsPathBuf::try_from(dir).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), b"hello").unwrap();
+        let remote = FileSystem::new(&dir).unwrap();
+        let local = MemStorage::new();
+        local.put_file(Path::new("/a.txt"), b"hello", mtime());
+
+        let (l, r) = (
+            metadata(&local, "/a.txt").await,
+            metadata(&remote, "/a.txt").await,
+        );
+        let limiter = Limiter::default();
+        let outcome = verify_file(&local, &remote, &l, &r, false, &limiter)
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Outcome::Unverifiable(..)));
+        let outcome = verify_file(&local, &remote, &l, &r, true, &limiter)
+            .await
+            .unwrap();
+        assert_eq!(outcome, Outcome::Same);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
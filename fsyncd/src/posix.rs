@@ -6,16 +6,16 @@ use tokio::task::JoinHandle;
 use crate::{exit_program, ShutdownRef};
 
 pub fn main() -> ExitCode {
+    // both pass the records to the buffer read by the clients
     if connected_to_journal() {
-        JournalLog::new()
+        let journal = JournalLog::new()
             .unwrap()
-            .add_extra_field("VERSION", env!("CARGO_PKG_VERSION"))
-            .install()
-            .unwrap();
-
-        log::set_max_level(log::LevelFilter::Info);
+            .add_extra_field("VERSION", env!("CARGO_PKG_VERSION"));
+        fsyncd::logs::install(Box::new(journal), log::LevelFilter::Info).unwrap();
     } else {
-        env_logger::init();
+        let logger = env_logger::Builder::from_default_env().build();
+        let level = logger.filter();
+        fsyncd::logs::install(Box::new(logger), level).unwrap();
     }
 
     let shutdown_res = tokio::runtime::Builder::new_multi_thread()
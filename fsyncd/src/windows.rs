@@ -34,7 +34,8 @@ fn service_main(args: Vec<OsString>) {
     // The entry point where execution will start on a background thread after a call to
     // `service_dispatcher::start` from `main`.
 
-    eventlog::init("FSyncd", log::Level::Info).unwrap();
+    let eventlog = eventlog::EventLog::new("FSyncd", log::Level::Info).unwrap();
+    fsyncd::logs::install(Box::new(eventlog), log::LevelFilter::Info).unwrap();
 
     let shutdown_ref = ShutdownRef::new();
 
@@ -72,7 +73,9 @@ fn service_main(args: Vec<OsString>) {
 }
 
 fn console_main() -> ExitCode {
-    env_logger::init();
+    let logger = env_logger::Builder::from_default_env().build();
+    let level = logger.filter();
+    fsyncd::logs::install(Box::new(logger), level).unwrap();
 
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
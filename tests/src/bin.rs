@@ -3,10 +3,14 @@
 use std::sync::{Arc, Once};
 
 use dataset::Dataset;
-use fsyncd::{service::Service, storage::cache::CacheStorage};
+use fsyncd::{
+    ignore::IgnoreRules, placeholders::Placeholders, service::Service,
+    storage::cache::CacheStorage, tree::BuildOptions,
+};
 
 //mod config;
 mod dataset;
+mod e2e;
 mod harness;
 mod utils;
 mod stubs {
@@ -24,6 +28,20 @@ type CacheHarness = Harness<fs::Stub, CacheStorage<id::Stub>>;
 static LOG_INIT: Once = Once::new();
 
 async fn harness<D: Into<Dataset>>(dataset: D) -> CacheHarness {
+    harness_with_ignore(dataset, &[]).await
+}
+
+/// A harness with global ignore patterns, as from the `ignore` config field
+async fn harness_with_ignore<D: Into<Dataset>>(dataset: D, ignore: &[&str]) -> CacheHarness {
+    let options = BuildOptions {
+        ignore: IgnoreRules::global(ignore).unwrap(),
+        ..BuildOptions::default()
+    };
+    harness_with(dataset, options).await
+}
+
+/// A harness with the tree built according to `options`
+async fn harness_with<D: Into<Dataset>>(dataset: D, options: BuildOptions) -> CacheHarness {
     LOG_INIT.call_once(env_logger::init);
 
     let dataset = dataset.into();
@@ -33,7 +51,36 @@ async fn harness<D: Into<Dataset>>(dataset: D) -> CacheHarness {
 
     let (local, remote) = dataset.create_fs(&root).await;
 
-    let service = Arc::new(Service::new(local, remote, root).await.unwrap());
+    let service = Arc::new(
+        Service::new_with(local, remote, root, options)
+            .await
+            .unwrap()
+            .with_self_check(),
+    );
 
     Harness { service }
 }
+
+/// A harness in the placeholder mode, with placeholders created for the remote-only files
+async fn harness_with_placeholders<D: Into<Dataset>>(dataset: D) -> CacheHarness {
+    LOG_INIT.call_once(env_logger::init);
+
+    let dataset = dataset.into();
+
+    let root = utils::temp_path(Some("fsync-fs"), None);
+    tokio::fs::create_dir(&root).await.unwrap();
+
+    let (mut local, remote) = dataset.create_fs(&root).await;
+    local.hide_placeholders();
+
+    let service = Service::new_with(local, remote, root, BuildOptions::default())
+        .await
+        .unwrap()
+        .with_placeholders(Placeholders::default())
+        .with_self_check();
+    service.update_placeholders().await.unwrap();
+
+    Harness {
+        service: Arc::new(service),
+    }
+}
@@ -170,7 +170,7 @@ impl Entry {
                     .unwrap();
 
                 let mut f = tokio::fs::File::create(&path).await.unwrap();
-                f.write(content).await.unwrap();
+                f.write_all(content).await.unwrap();
 
                 // `now` can be set while `age` is not set.
                 // if `age` is set however, `now` must be set.
@@ -194,7 +194,7 @@ pub trait CreateFs {
 impl CreateFs for &[Entry] {
     async fn create_fs(&self, root: &FsPath, now: Option<SystemTime>) {
         for entry in self.iter() {
-            entry.create_fs(&root, now).await;
+            entry.create_fs(root, now).await;
         }
     }
 }
@@ -0,0 +1,309 @@
+//! End-to-end test of the user journey, from the instance creation to the first sync,
+//! driving an in-process daemon through the RPC client.
+
+use std::{sync::Arc, time::Duration};
+
+use fsync::{
+    loc::{inst, user},
+    path::{FsPath, FsPathBuf, PathBuf},
+    runtime::PortFile,
+    FsyncClient, Operation, Progress, ResolutionMethod,
+};
+use fsync_client::{
+    config::{self, ProviderOpts},
+    utils::ctx,
+    Connection, Instance,
+};
+use fsyncd::{
+    service::{RpcService, Service},
+    storage::fs::FileSystem,
+    Shutdown,
+};
+use futures::stream::AbortHandle;
+use tokio::task::JoinHandle;
+
+use crate::utils;
+
+const INSTANCE: &str = "e2e";
+
+/// A daemon running in-process, as started by fsyncd for the LocalFs provider
+struct Daemon {
+    service: Arc<Service<FileSystem, FileSystem>>,
+    abort_handle: AbortHandle,
+    join: JoinHandle<anyhow::Result<()>>,
+}
+
+impl Daemon {
+    async fn start(instance_name: &str) -> anyhow::Result<Self> {
+        let config = fsync::Config::load_from_file(&inst::config_file(instance_name)?).await?;
+        let fsync::ProviderConfig::LocalFs(remote_dir) = &config.provider else {
+            anyhow::bail!("Expected a LocalFs provider");
+        };
+        let local = FileSystem::new(&config.local_dir)?;
+        let remote = FileSystem::new(remote_dir)?;
+        let service = Arc::new(Service::new(local, remote, config.local_dir.clone()).await?);
+
+        let (abort_handle, abort_reg) = AbortHandle::new_pair();
+        let rpc = RpcService::new(service.clone(), abort_handle.clone()).await;
+        let name = instance_name.to_string();
+        let join = tokio::spawn(async move { rpc.start(&name, abort_reg).await });
+
+        Ok(Self {
+            service,
+            abort_handle,
+            join,
+        })
+    }
+
+    /// Wait for the daemon to publish its port
+    async fn wait_running(&self, instance_name: &str) -> anyhow::Result<()> {
+        for _ in 0..100 {
+            if Instance::get(instance_name)?.is_some_and(|inst| inst.running()) {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        anyhow::bail!("{instance_name} did not start");
+    }
+
+    /// Connect to the daemon once it has published its port
+    async fn connect(&self, instance_name: &str) -> anyhow::Result<Connection> {
+        self.wait_running(instance_name).await?;
+        Connection::open(instance_name).await
+    }
+
+    async fn shutdown(self) -> anyhow::Result<()> {
+        self.abort_handle.abort();
+        self.service.shutdown().await?;
+        self.join.await??;
+        Ok(())
+    }
+}
+
+/// Client that follows the restarts of the daemon
+type Client = FsyncClient<Connection>;
+
+async fn operate(client: &Client, operation: Operation) -> anyhow::Result<()> {
+    let path = operation.path().to_owned();
+    let mut progress = client.operate(ctx(), operation).await??;
+    loop {
+        match progress {
+            Progress::Err(err) => return Err(err.into()),
+            Progress::Failed(failure) => return Err(failure.error.into()),
+            progress if progress.is_done() => {
+                let failed = progress.report().map_or(0, |report| report.failed);
+                if failed == 0 {
+                    return Ok(());
+                }
+                let progresses = client.progresses(ctx(), path).await??;
+                let errors: Vec<_> = progresses
+                    .iter()
+                    .filter_map(|(path, prog)| prog.error().map(|err| format!("{path}: {err}")))
+                    .collect();
+                anyhow::bail!("{failed} entries failed: {}", errors.join(", "));
+            }
+            _ => (),
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        match client.progress(ctx(), path.clone()).await?? {
+            Some(p) => progress = p,
+            // removed from the running operations once done
+            None => return Ok(()),
+        }
+    }
+}
+
+/// The token presented by the clients is only readable by the user
+#[cfg(unix)]
+fn check_token_permissions(instance_name: &str) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let token_file = inst::runtime_token_file(instance_name).unwrap();
+    let mode = token_file.metadata().unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+async fn write(dir: &FsPath, path: &str, content: &str) {
+    let path = dir.join(path);
+    tokio::fs::create_dir_all(path.parent().unwrap())
+        .await
+        .unwrap();
+    tokio::fs::write(path, content).await.unwrap();
+}
+
+/// A port that nothing listens on
+fn closed_port() -> u16 {
+    let listener = std::net::TcpListener::bind("[::1]:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+async fn is_sync(client: &Client, path: &str) -> bool {
+    let node = client.entry_node(ctx(), PathBuf::from(path)).await.unwrap();
+    node.unwrap().unwrap().is_sync()
+}
+
+#[tokio::test]
+async fn create_sync_and_restart() {
+    let root = utils::temp_path(Some("fsync-e2e"), None);
+    let dir = |name: &str| -> FsPathBuf { root.join(name) };
+    for name in ["config", "runtime", "cache", "local", "remote"] {
+        tokio::fs::create_dir_all(dir(name)).await.unwrap();
+    }
+    // the only test of the binary that uses the user locations
+    user::init_root(Some(root.clone())).unwrap();
+
+    let local_dir = dir("local");
+    let remote_dir = dir("remote");
+    write(&local_dir, "local.txt", "Local content").await;
+    write(&local_dir, "dir/deep.txt", "Deep content").await;
+    write(&remote_dir, "remote.txt", "Remote content").await;
+    write(&local_dir, "conflict.txt", "Local version").await;
+    write(&remote_dir, "conflict.txt", "Remote version, longer").await;
+
+    config::create(
+        INSTANCE,
+        &local_dir,
+        &ProviderOpts::LocalFs(remote_dir.clone()),
+        false,
+    )
+    .await
+    .unwrap();
+    assert!(inst::config_file(INSTANCE)
+        .unwrap()
+        .starts_with(dir("config")));
+
+    // first start: the tree is built from both storages
+    let daemon = Daemon::start(INSTANCE).await.unwrap();
+    let conn = daemon.connect(INSTANCE).await.unwrap();
+    let mut generation = conn.subscribe();
+    let client = Client::from(conn.clone());
+
+    #[cfg(unix)]
+    check_token_permissions(INSTANCE);
+
+    let conflicts = client.conflicts(ctx(), None, 100).await.unwrap().unwrap();
+    assert_eq!(conflicts.len(), 1);
+    assert!(conflicts[0].is_conflict());
+
+    // a deep sync fails on the conflict, and completes the other entries
+    let err = operate(&client, Operation::SyncDeep(PathBuf::root()))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("conflict.txt"), "{err}");
+
+    let conflict = PathBuf::from("/conflict.txt");
+    operate(
+        &client,
+        Operation::Resolve(conflict.clone(), ResolutionMethod::ReplaceRemoteByLocal),
+    )
+    .await
+    .unwrap();
+    operate(&client, Operation::SyncDeep(PathBuf::root()))
+        .await
+        .unwrap();
+    assert!(client
+        .conflicts(ctx(), None, 100)
+        .await
+        .unwrap()
+        .unwrap()
+        .is_empty());
+    for path in ["/dir/deep.txt", "/local.txt", "/remote.txt"] {
+        assert!(is_sync(&client, path).await, "{path}");
+    }
+
+    let remote = tokio::fs::read_to_string(remote_dir.join("conflict.txt"))
+        .await
+        .unwrap();
+    assert_eq!(remote, "Local version");
+    let local = tokio::fs::read_to_string(local_dir.join("remote.txt"))
+        .await
+        .unwrap();
+    assert_eq!(local, "Remote content");
+
+    daemon.shutdown().await.unwrap();
+    assert!(!inst::runtime_port_file(INSTANCE).unwrap().exists());
+    assert!(!inst::runtime_token_file(INSTANCE).unwrap().exists());
+
+    // restart: the call on the dead connection fails, and the client reconnects
+    // on the port of the new daemon
+    let daemon = Daemon::start(INSTANCE).await.unwrap();
+    daemon.wait_running(INSTANCE).await.unwrap();
+    assert_eq!(conn.generation(), 0);
+    let _ = client.entry_node(ctx(), PathBuf::root()).await;
+    tokio::time::timeout(Duration::from_secs(10), generation.changed())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(conn.generation(), 1);
+
+    // the synchronized state survives
+
+    let root_node = client
+        .entry_node(ctx(), PathBuf::root())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let stats = root_node.stats();
+    assert_eq!(stats.node.nodes, stats.node.sync);
+    assert_eq!(stats.node.conflicts, 0);
+    assert_eq!(stats.local, stats.remote);
+    assert_eq!(stats.local.files, 4);
+    for path in [
+        "/conflict.txt",
+        "/dir",
+        "/dir/deep.txt",
+        "/local.txt",
+        "/remote.txt",
+    ] {
+        assert!(is_sync(&client, path).await, "{path}");
+    }
+
+    daemon.shutdown().await.unwrap();
+    let err = Connection::open(INSTANCE).await.unwrap_err();
+    assert!(err.to_string().contains("not running"), "{err}");
+
+    // a crash leaves the port file behind: the clients remove it
+    let port_path = inst::runtime_port_file(INSTANCE).unwrap();
+    let stale = PortFile::new(closed_port(), 42);
+    tokio::fs::write(&port_path, stale.to_json()).await.unwrap();
+    let err = Connection::open(INSTANCE).await.unwrap_err();
+    assert!(err.to_string().contains("not running"), "{err}");
+    assert!(!port_path.exists());
+
+    // the next daemon overwrites it, and a second daemon refuses to start
+    tokio::fs::write(&port_path, stale.to_json()).await.unwrap();
+    let daemon = Daemon::start(INSTANCE).await.unwrap();
+    for _ in 0..100 {
+        if PortFile::load(&port_path)
+            .unwrap()
+            .is_some_and(|file| file != stale)
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    // a connected client does not hide the running daemon from the probe
+    let _conn = Connection::open(INSTANCE).await.unwrap();
+    let (abort_handle, abort_reg) = AbortHandle::new_pair();
+    let second = dir("second");
+    tokio::fs::create_dir_all(&second).await.unwrap();
+    let service = Service::new(
+        FileSystem::new(&second).unwrap(),
+        FileSystem::new(&remote_dir).unwrap(),
+        second.clone(),
+    )
+    .await
+    .unwrap();
+    let rpc = RpcService::new(Arc::new(service), abort_handle).await;
+    let err = rpc.start(INSTANCE, abort_reg).await.unwrap_err();
+    assert!(err.to_string().contains("already running"), "{err}");
+    daemon.shutdown().await.unwrap();
+    assert!(!port_path.exists());
+
+    tokio::fs::remove_dir_all(dir("config")).await.unwrap();
+    let err = Connection::open(INSTANCE).await.unwrap_err();
+    assert!(err.to_string().contains("no longer exists"), "{err}");
+
+    tokio::fs::remove_dir_all(&root).await.unwrap();
+}
@@ -8,7 +8,10 @@ use fsync::{
     tree::{Entry, EntryNode},
     Location, Metadata, StorageLoc,
 };
-use fsyncd::{service::Service, storage::{LocalStorage, Storage}};
+use fsyncd::{
+    service::Service,
+    storage::{LocalStorage, Storage},
+};
 
 use crate::utils;
 
@@ -38,11 +41,37 @@ where
     }
 
     pub async fn operate(&self, operation: fsync::Operation) -> fsync::Progress {
-        self.service
+        self.operate_with(operation, fsync::OperateOptions::default())
+            .await
+    }
+
+    pub async fn operate_with(
+        &self,
+        operation: fsync::Operation,
+        options: fsync::OperateOptions,
+    ) -> fsync::Progress {
+        let progress = self
+            .service
             .clone()
-            .operate(operation)
+            .operate_with(operation, options)
             .await
-            .expect("Should not fail")
+            .expect("Should not fail");
+        self.assert_self_check().await;
+        progress
+    }
+
+    /// Check that the operations left the tree in line with the storages
+    pub async fn assert_self_check(&self) {
+        let discrepancies = self.service.take_discrepancies().await;
+        assert!(
+            discrepancies.is_empty(),
+            "Discrepancies in the tree:\n{}",
+            discrepancies
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
     }
 
     pub async fn metadata<P: AsRef<Path>>(&self, path: P, loc: StorageLoc) -> Option<Metadata> {
@@ -51,7 +80,7 @@ where
             .entry_node(path.as_ref())
             .await
             .expect("Should not fail");
-        e.map(|node| node.into_entry().into_metadata(loc)).flatten()
+        e.and_then(|node| node.into_entry().into_metadata(loc))
     }
 
     pub async fn has_dir<P: AsRef<Path>>(&self, path: P, loc: fsync::Location) -> bool {
@@ -63,7 +92,8 @@ where
             .await
             .expect("Should not fail")
             .map(|node| node.into_entry());
-        match (loc, e) {
+        matches!(
+            (loc, e),
             (
                 Location::Both,
                 Some(Entry::Sync {
@@ -71,25 +101,23 @@ where
                     remote: Directory { .. },
                     ..
                 }),
-            )
-            | (Location::Local, Some(Entry::Local(Directory { .. })))
-            | (
-                Location::Local,
-                Some(Entry::Sync {
-                    local: Directory { .. },
-                    ..
-                }),
-            )
-            | (Location::Remote, Some(Entry::Remote(Directory { .. })))
-            | (
-                Location::Remote,
-                Some(Entry::Sync {
-                    remote: Directory { .. },
-                    ..
-                }),
-            ) => true,
-            _ => false,
-        }
+            ) | (Location::Local, Some(Entry::Local(Directory { .. })))
+                | (
+                    Location::Local,
+                    Some(Entry::Sync {
+                        local: Directory { .. },
+                        ..
+                    }),
+                )
+                | (Location::Remote, Some(Entry::Remote(Directory { .. })))
+                | (
+                    Location::Remote,
+                    Some(Entry::Sync {
+                        remote: Directory { .. },
+                        ..
+                    }),
+                )
+        )
     }
 
     pub async fn has_file<P: AsRef<Path>>(&self, path: P, loc: Location) -> bool {
@@ -101,7 +129,8 @@ where
             .await
             .expect("Should not fail")
             .map(|node| node.into_entry());
-        match (loc, e) {
+        matches!(
+            (loc, e),
             (
                 Location::Both,
                 Some(Entry::Sync {
@@ -109,25 +138,23 @@ where
                     remote: Regular { .. },
                     ..
                 }),
-            )
-            | (Location::Local, Some(Entry::Local(Regular { .. })))
-            | (
-                Location::Local,
-                Some(Entry::Sync {
-                    local: Regular { .. },
-                    ..
-                }),
-            )
-            | (Location::Remote, Some(Entry::Remote(Regular { .. })))
-            | (
-                Location::Remote,
-                Some(Entry::Sync {
-                    remote: Regular { .. },
-                    ..
-                }),
-            ) => true,
-            _ => false,
-        }
+            ) | (Location::Local, Some(Entry::Local(Regular { .. })))
+                | (
+                    Location::Local,
+                    Some(Entry::Sync {
+                        local: Regular { .. },
+                        ..
+                    }),
+                )
+                | (Location::Remote, Some(Entry::Remote(Regular { .. })))
+                | (
+                    Location::Remote,
+                    Some(Entry::Sync {
+                        remote: Regular { .. },
+                        ..
+                    }),
+                )
+        )
     }
 
     pub async fn file_content<P: AsRef<Path>>(&self, path: P, loc: StorageLoc) -> Option<String> {
@@ -161,7 +188,7 @@ where
         loc: Location,
     ) -> bool {
         let path = path.as_ref();
-        if !self.has_file(path, loc.into()).await {
+        if !self.has_file(path, loc).await {
             return false;
         }
         match loc {
@@ -265,14 +292,14 @@ where
             .await
             .expect("Should not fail")
             .map(|node| node.into_entry());
-        match e {
+        matches!(
+            e,
             Some(Entry::Sync {
                 local: Directory { .. },
                 remote: Directory { .. },
                 ..
-            }) => true,
-            _ => false,
-        }
+            })
+        )
     }
 
     pub async fn has_sync_dir_no_conflict<P: AsRef<Path>>(&self, path: P) -> bool {
@@ -284,14 +311,14 @@ where
             .await
             .expect("Should not fail")
             .map(|node| node.into_entry());
-        match e {
+        matches!(
+            e,
             Some(Entry::Sync {
                 local: Directory { .. },
                 remote: Directory { .. },
                 conflict: None,
-            }) => true,
-            _ => false,
-        }
+            })
+        )
     }
 
     pub async fn has_sync_file<P: AsRef<Path>>(&self, path: P) -> bool {
@@ -303,14 +330,14 @@ where
             .await
             .expect("Should not fail")
             .map(|node| node.into_entry());
-        match e {
+        matches!(
+            e,
             Some(Entry::Sync {
                 local: Regular { .. },
                 remote: Regular { .. },
                 ..
-            }) => true,
-            _ => false,
-        }
+            })
+        )
     }
 
     pub async fn has_sync_file_no_conflict<P: AsRef<Path>>(&self, path: P) -> bool {
@@ -322,14 +349,14 @@ where
             .await
             .expect("Should not fail")
             .map(|node| node.into_entry());
-        match e {
+        matches!(
+            e,
             Some(Entry::Sync {
                 local: Regular { .. },
                 remote: Regular { .. },
                 conflict: None,
-            }) => true,
-            _ => false,
-        }
+            })
+        )
     }
 
     pub async fn has_sync_file_with_content<P: AsRef<Path>>(&self, path: P, content: &str) -> bool {
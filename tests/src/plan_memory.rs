@@ -0,0 +1,113 @@
+//! Checks that planning a deep operation uses a bounded amount of memory.
+//!
+//! This is a test binary of its own, as it replaces the global allocator
+//! to measure the memory allocated while planning.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+use chrono::Utc;
+use fsync::{path::PathBuf, stat, Metadata, Operation, SyncMode};
+use fsyncd::{
+    plan::Plan,
+    tree::{DiffTree, Entry, EntryNode},
+};
+
+thread_local! {
+    static LIVE: Cell<isize> = const { Cell::new(0) };
+    static PEAK: Cell<isize> = const { Cell::new(0) };
+}
+
+/// Allocator that tracks the peak of bytes allocated by each thread
+struct PeakAlloc;
+
+fn track(diff: isize) {
+    let _ = LIVE.try_with(|live| {
+        let live = live.replace(live.get() + diff) + diff;
+        let _ = PEAK.try_with(|peak| peak.set(peak.get().max(live)));
+    });
+}
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        track(layout.size() as isize);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        track(-(layout.size() as isize));
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        track(new_size as isize - layout.size() as isize);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOC: PeakAlloc = PeakAlloc;
+
+/// Peak of bytes allocated by the current thread while running `f`
+fn peak_alloc<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let start = LIVE.with(|live| live.get());
+    PEAK.with(|peak| peak.set(start));
+    let res = f();
+    let peak = PEAK.with(|peak| peak.get());
+    (res, (peak - start) as usize)
+}
+
+/// A tree of `dirs` local folders of `files` local files each
+fn local_tree(dirs: usize, files: usize) -> DiffTree {
+    let tree = DiffTree::new_root();
+    let mtime = Utc::now();
+    for d in 0..dirs {
+        let dir = PathBuf::from(format!("/dir-{d:03}"));
+        let metadata = Metadata::Directory {
+            path: dir.clone(),
+            stat: None,
+        };
+        let node = EntryNode::new(Entry::Local(metadata), vec![], stat::Tree::null());
+        tree.insert(&dir, node);
+        for f in 0..files {
+            let path = dir.join(format!("file-{f:04}.txt").as_str());
+            let metadata = Metadata::Regular {
+                path: path.clone(),
+                size: 1024,
+                mtime,
+                link_target: None,
+            };
+            let node = EntryNode::new(Entry::Local(metadata), vec![], stat::Tree::null());
+            tree.insert(&path, node);
+        }
+    }
+    tree
+}
+
+#[test]
+fn plan_memory_is_bounded() {
+    const DIRS: usize = 100;
+    const FILES: usize = 1000;
+    const BUDGET: usize = 1024 * 1024;
+
+    let tree = local_tree(DIRS, FILES);
+    let mut plan = Plan::new(Operation::SyncDeep(PathBuf::root()), SyncMode::default());
+
+    let (count, peak) = peak_alloc(|| {
+        let mut count = 0;
+        loop {
+            let actions = plan.next_actions(&tree, 100);
+            if actions.is_empty() {
+                break count;
+            }
+            count += actions.len();
+        }
+    });
+    assert_eq!(count, DIRS * (FILES + 1));
+    assert!(
+        peak < BUDGET,
+        "planning {count} actions allocated {peak} bytes"
+    );
+}
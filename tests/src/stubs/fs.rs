@@ -1,4 +1,4 @@
-use std::time::SystemTime;
+use std::{sync::Arc, time::SystemTime};
 
 use fsync::path::{FsPath, Path};
 use fsyncd::{
@@ -8,11 +8,15 @@ use fsyncd::{
 use futures::{Future, Stream};
 use tokio::{fs, io};
 
-use crate::dataset::{self, CreateFs};
+use crate::{
+    dataset::{self, CreateFs},
+    utils::TempDir,
+};
 
 #[derive(Debug, Clone)]
 pub struct Stub {
     inner: FileSystem,
+    _dir: Arc<TempDir>,
 }
 
 impl Stub {
@@ -22,20 +26,22 @@ impl Stub {
         now: Option<SystemTime>,
     ) -> anyhow::Result<Self> {
         tokio::fs::create_dir(&root).await.unwrap();
-        entries.create_fs(&root, now).await;
+        entries.create_fs(root, now).await;
 
-        let inner = FileSystem::new(&root)?;
-        Ok(Self { inner })
+        let inner = FileSystem::new(root)?;
+        Ok(Self {
+            inner,
+            _dir: Arc::new(TempDir::new(root)),
+        })
     }
 
-    fn root(&self) -> &FsPath {
+    pub fn root(&self) -> &FsPath {
         self.inner.root()
     }
-}
 
-impl Drop for Stub {
-    fn drop(&mut self) {
-        std::fs::remove_dir_all(self.inner.root()).unwrap();
+    /// Hide the placeholder files from the listings
+    pub fn hide_placeholders(&mut self) {
+        self.inner = self.inner.clone().with_placeholders();
     }
 }
 
@@ -65,6 +71,18 @@ impl storage::ReadFile for Stub {
     }
 }
 
+impl storage::ReadFileRange for Stub {
+    fn read_file_range(
+        &self,
+        path: fsync::path::PathBuf,
+        offset: u64,
+        len: u64,
+        progress: Option<&SharedProgress>,
+    ) -> impl Future<Output = fsync::Result<impl io::AsyncRead + Send>> + Send {
+        self.inner.read_file_range(path, offset, len, progress)
+    }
+}
+
 impl storage::MkDir for Stub {
     fn mkdir(
         &self,
@@ -120,6 +138,16 @@ impl storage::MoveEntry for Stub {
     }
 }
 
+impl storage::PatchMetadata for Stub {
+    fn patch(
+        &self,
+        path: &Path,
+        patch: &storage::MetadataPatch,
+    ) -> impl Future<Output = fsync::Result<fsync::Metadata>> + Send {
+        self.inner.patch(path, patch)
+    }
+}
+
 impl storage::Delete for Stub {
     fn delete(
         &self,
@@ -130,6 +158,20 @@ impl storage::Delete for Stub {
     }
 }
 
+impl storage::ExistingChildren for Stub {}
+
+impl storage::Quota for Stub {}
+
+impl storage::Flush for Stub {}
+
+impl storage::Shared for Stub {}
+
+impl storage::Relist for Stub {}
+
+impl storage::Revisions for Stub {}
+
+impl storage::Checksums for Stub {}
+
 impl fsyncd::Shutdown for Stub {
     async fn shutdown(&self) -> anyhow::Result<()> {
         let _ = fs::remove_dir_all(self.root()).await;
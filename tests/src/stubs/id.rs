@@ -1,18 +1,30 @@
-use std::time::SystemTime;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
 
 use fsync::path::{FsPath, Path, PathBuf};
 use fsyncd::{
     storage::{
+        cache,
         fs::FileSystem,
         id::{self, IdBuf},
-        CopyFile, CreateFile, Delete, DirEntries, MkDir, ReadFile, WriteFile,
+        CopyFile, CreateFile, Delete, DirEntries, Exists, Flush, MetadataPatch, MkDir,
+        PatchMetadata, Quota, ReadFile, ReadFileRange, WriteFile,
     },
     SharedProgress, Shutdown,
 };
 use futures::prelude::*;
 use tokio::io;
 
-use crate::dataset::{self, CreateFs};
+use crate::{
+    dataset::{self, CreateFs},
+    utils::TempDir,
+};
 
 /// Stub that fakes an Id based Storage with filesystem
 /// Ids are paths that are:
@@ -21,6 +33,11 @@ use crate::dataset::{self, CreateFs};
 #[derive(Clone)]
 pub struct Stub {
     inner: FileSystem,
+    quota: Arc<Mutex<Option<fsync::stat::Quota>>>,
+    quota_fails: Arc<AtomicBool>,
+    exists_calls: Arc<AtomicUsize>,
+    sharing: Arc<Mutex<HashMap<IdBuf, fsync::Sharing>>>,
+    _dir: Arc<TempDir>,
 }
 
 impl Stub {
@@ -30,16 +47,54 @@ impl Stub {
         now: Option<SystemTime>,
     ) -> anyhow::Result<Self> {
         tokio::fs::create_dir(&root).await.unwrap();
-        entries.create_fs(&root, now).await;
+        entries.create_fs(root, now).await;
+
+        let inner = FileSystem::new(root)?;
+        Ok(Self {
+            inner,
+            quota: Arc::new(Mutex::new(None)),
+            quota_fails: Arc::new(AtomicBool::new(false)),
+            exists_calls: Arc::new(AtomicUsize::new(0)),
+            sharing: Arc::default(),
+            _dir: Arc::new(TempDir::new(root)),
+        })
+    }
+
+    pub fn root(&self) -> &FsPath {
+        self.inner.root()
+    }
+
+    /// Set the quota reported by this storage
+    pub fn set_quota(&self, quota: Option<fsync::stat::Quota>) {
+        *self.quota.lock().unwrap() = quota;
+    }
+
+    /// The number of entries checked for existence one by one
+    pub fn exists_calls(&self) -> usize {
+        self.exists_calls.load(Ordering::Relaxed)
+    }
+
+    /// Set how the entry at `path` is shared
+    pub fn set_sharing(&self, path: &Path, sharing: Option<fsync::Sharing>) {
+        let id = IdBuf::from(path.as_str());
+        let mut map = self.sharing.lock().unwrap();
+        match sharing {
+            Some(sharing) => map.insert(id, sharing),
+            None => map.remove(&id),
+        };
+    }
 
-        let inner = FileSystem::new(&root)?;
-        Ok(Self { inner })
+    /// Make the quota requests fail, as when the storage is unreachable
+    pub fn set_quota_fails(&self, fails: bool) {
+        self.quota_fails.store(fails, Ordering::Relaxed);
     }
 }
 
-impl Drop for Stub {
-    fn drop(&mut self) {
-        std::fs::remove_dir_all(self.inner.root()).unwrap();
+impl id::Exists for Stub {
+    async fn exists(&self, id: &id::Id) -> fsync::Result<bool> {
+        self.exists_calls.fetch_add(1, Ordering::Relaxed);
+        let path = PathBuf::from(id.as_str());
+        self.inner.exists(&path).await
     }
 }
 
@@ -67,6 +122,21 @@ impl id::ReadFile for Stub {
     }
 }
 
+impl id::ReadFileRange for Stub {
+    async fn read_file_range(
+        &self,
+        id: IdBuf,
+        offset: u64,
+        len: u64,
+        progress: Option<&SharedProgress>,
+    ) -> fsync::Result<impl io::AsyncRead + Send> {
+        let path = PathBuf::from(id.into_string());
+        self.inner
+            .read_file_range(path, offset, len, progress)
+            .await
+    }
+}
+
 impl id::MkDir for Stub {
     async fn mkdir(
         &self,
@@ -124,6 +194,17 @@ impl id::CopyFile for Stub {
     }
 }
 
+impl id::PatchMetadata for Stub {
+    async fn patch(
+        &self,
+        _id: &id::Id,
+        path: &Path,
+        patch: &MetadataPatch,
+    ) -> fsync::Result<fsync::Metadata> {
+        self.inner.patch(path, patch).await
+    }
+}
+
 impl id::Delete for Stub {
     async fn delete(&self, id: &id::Id, progress: Option<&SharedProgress>) -> fsync::Result<()> {
         let path = PathBuf::from(id.as_str());
@@ -131,6 +212,29 @@ impl id::Delete for Stub {
     }
 }
 
+impl Quota for Stub {
+    async fn quota(&self) -> fsync::Result<Option<fsync::stat::Quota>> {
+        if self.quota_fails.load(Ordering::Relaxed) {
+            return Err(fsync::Error::Other("quota unavailable".to_string()));
+        }
+        Ok(*self.quota.lock().unwrap())
+    }
+}
+
+impl Flush for Stub {}
+
+impl id::Shared for Stub {
+    fn sharing(&self, id: &id::Id) -> Option<fsync::Sharing> {
+        self.sharing.lock().unwrap().get(id).copied()
+    }
+}
+
+impl id::Revisions for Stub {}
+
+impl id::Checksums for Stub {}
+
 impl Shutdown for Stub {}
 
+impl cache::Provider for Stub {}
+
 impl id::Storage for Stub {}
@@ -1,13 +1,16 @@
 use fsync::{
+    caps::FsCaps,
     path::{Path, PathBuf},
     stat,
     tree::Entry,
-    Conflict, DeletionMethod, Operation, ResolutionMethod,
+    Action, Conflict, DeletionMethod, OperateOptions, Operation, OperationReport, OrderBy,
+    Progress, ResolutionMethod, StorageDir, StorageLoc,
 };
+use fsyncd::tree::BuildOptions;
 
 use crate::{
     dataset::{self, Dataset},
-    harness,
+    harness, harness_with, harness_with_ignore, harness_with_placeholders,
     utils::UnwrapDisplay,
 };
 
@@ -203,6 +206,8 @@ async fn sync_remote_dir_deep_and_stats() {
                 nodes: 8,
                 sync: 1, // root
                 conflicts: 0,
+                too_large: 0,
+                implausible: 0,
             },
         },
     );
@@ -242,11 +247,77 @@ async fn sync_remote_dir_deep_and_stats() {
                 nodes: 8,
                 sync: 7,
                 conflicts: 0,
+                too_large: 0,
+                implausible: 0,
             },
         },
     );
 }
 
+#[tokio::test]
+async fn sync_remote_dir_deep_ordered() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![],
+            remote: vec![
+                Entry::txt_file("/dir/old.txt", "Old content").with_age(20),
+                Entry::txt_file("/dir/new.txt", "New content").with_age(0),
+                Entry::txt_file("/dir/dir/big.txt", "Some bigger content").with_age(10),
+            ],
+        })
+        .await
+    };
+
+    h.operate(Operation::SyncDeepOrdered(
+        PathBuf::from("/dir"),
+        OrderBy::NewestFirst,
+    ))
+    .await;
+
+    assert!(h.has_sync_dir("/dir").await);
+    assert!(h.has_sync_dir("/dir/dir").await);
+    assert!(h.has_sync_file("/dir/old.txt").await);
+    assert!(h.has_sync_file("/dir/new.txt").await);
+    assert!(h.has_sync_file("/dir/dir/big.txt").await);
+}
+
+#[tokio::test]
+async fn order_by_sorts_children() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![
+                Entry::txt_file("/a.txt", "Medium content").with_age(10),
+                Entry::txt_file("/b.txt", "Small").with_age(0),
+                Entry::txt_file("/c.txt", "Some much bigger content").with_age(20),
+            ],
+            remote: vec![],
+        })
+        .await
+    };
+
+    let root = h.service.entry_node(Path::root()).await.unwrap().unwrap();
+    let mut children = Vec::new();
+    for name in root.children() {
+        let path = Path::root().join(name);
+        children.push(h.service.entry_node(&path).await.unwrap().unwrap());
+    }
+    let names = |order: OrderBy| {
+        let mut children = children.clone();
+        order.sort(&mut children);
+        children
+            .iter()
+            .map(|c| c.entry().name().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(names(OrderBy::TreeOrder), ["a.txt", "b.txt", "c.txt"]);
+    assert_eq!(names(OrderBy::NewestFirst), ["b.txt", "a.txt", "c.txt"]);
+    assert_eq!(names(OrderBy::SmallestFirst), ["b.txt", "a.txt", "c.txt"]);
+    assert_eq!(names(OrderBy::LargestFirst), ["c.txt", "a.txt", "b.txt"]);
+}
+
 #[tokio::test]
 async fn detects_conflict() {
     let path = Path::new("/conflict.txt");
@@ -276,6 +347,8 @@ async fn detects_conflict() {
                 nodes: 2,
                 sync: 2, // sync include the conflicts
                 conflicts: 1,
+                too_large: 0,
+                implausible: 0,
             },
         }
     );
@@ -336,6 +409,8 @@ async fn resolve_keep_newer_local() {
                 nodes: 2,
                 sync: 2,
                 conflicts: 0,
+                too_large: 0,
+                implausible: 0,
             },
         }
     );
@@ -423,6 +498,8 @@ async fn resolve_create_local_copy() {
                 nodes: 3,
                 sync: 2,
                 conflicts: 0,
+                too_large: 0,
+                implausible: 0,
             },
         },
     );
@@ -470,6 +547,8 @@ async fn resolve_delete_local() {
                 nodes: 2,
                 sync: 1,
                 conflicts: 0,
+                too_large: 0,
+                implausible: 0,
             },
         },
     );
@@ -517,6 +596,8 @@ async fn resolve_delete_remote() {
                 nodes: 2,
                 sync: 1,
                 conflicts: 0,
+                too_large: 0,
+                implausible: 0,
             },
         },
     );
@@ -566,6 +647,8 @@ async fn resolve_delete_older() {
                 nodes: 2,
                 sync: 1,
                 conflicts: 0,
+                too_large: 0,
+                implausible: 0,
             },
         },
     );
@@ -613,6 +696,8 @@ async fn resolve_delete_newer() {
                 nodes: 2,
                 sync: 1,
                 conflicts: 0,
+                too_large: 0,
+                implausible: 0,
             },
         },
     );
@@ -671,3 +756,1464 @@ async fn delete_both() {
     assert!(!h.has_local_file(path).await);
     assert!(!h.has_remote_file(path).await);
 }
+
+#[tokio::test]
+async fn instance_stats_quota_warning() {
+    let h = harness(Dataset::empty()).await;
+
+    let stats = h.service.instance_stats().await.unwrap();
+    assert!(stats.quota.is_none());
+    assert!(!stats.quota_warning);
+
+    h.remote().storage().set_quota(Some(stat::Quota {
+        usage: 50,
+        limit: Some(100),
+    }));
+    let stats = h.service.instance_stats().await.unwrap();
+    assert_eq!(stats.quota.and_then(|q| q.remaining()), Some(50));
+    assert!(!stats.quota_warning);
+
+    h.remote().storage().set_quota(Some(stat::Quota {
+        usage: 95,
+        limit: Some(100),
+    }));
+    let stats = h.service.instance_stats().await.unwrap();
+    assert!(stats.quota_warning);
+}
+
+#[tokio::test]
+async fn sync_deep_quota_exceeded() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![
+                Entry::txt_file("/dir/file1.txt", "Test content"),
+                Entry::txt_file("/dir/file2.txt", "Test content"),
+            ],
+            remote: vec![Entry::Dir("/dir".into())],
+        })
+        .await
+    };
+    h.remote().storage().set_quota(Some(stat::Quota {
+        usage: 80,
+        limit: Some(100),
+    }));
+
+    let res = h
+        .service
+        .clone()
+        .operate(Operation::SyncDeep(PathBuf::from("/dir")))
+        .await;
+    assert!(matches!(
+        res,
+        Err(fsync::Error::QuotaExceeded {
+            remaining: 20,
+            required: 24
+        })
+    ));
+    assert!(!h.has_remote_file("/dir/file1.txt").await);
+
+    // a single file still fits
+    h.operate(Operation::Sync(PathBuf::from("/dir/file1.txt")))
+        .await;
+    assert!(h.has_remote_file("/dir/file1.txt").await);
+}
+
+#[tokio::test]
+async fn resolve_quota_counts_replaced_size() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![Entry::txt_file("/file.txt", "Local content, longer")],
+            remote: vec![Entry::txt_file("/file.txt", "Remote content")],
+        })
+        .await
+    };
+    h.remote().storage().set_quota(Some(stat::Quota {
+        usage: 96,
+        limit: Some(100),
+    }));
+
+    // the remote file is replaced by 7 more bytes
+    let operation = Operation::Resolve(
+        PathBuf::from("/file.txt"),
+        ResolutionMethod::ReplaceRemoteByLocal,
+    );
+    let res = h.service.clone().operate(operation.clone()).await;
+    assert!(matches!(
+        res,
+        Err(fsync::Error::QuotaExceeded {
+            remaining: 4,
+            required: 7
+        })
+    ));
+
+    h.remote().storage().set_quota(Some(stat::Quota {
+        usage: 90,
+        limit: Some(100),
+    }));
+    h.operate(operation).await;
+    assert!(
+        h.has_remote_file_with_content("/file.txt", "Local content, longer")
+            .await
+    );
+}
+
+#[tokio::test]
+async fn restore_remote_quota_counts_replaced_size() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![
+                Entry::txt_file("/dir/file1.txt", "Local content, longer"),
+                Entry::txt_file("/dir/file2.txt", "Test content"),
+            ],
+            remote: vec![Entry::txt_file("/dir/file1.txt", "Remote content")],
+        })
+        .await
+    };
+    h.remote().storage().set_quota(Some(stat::Quota {
+        usage: 85,
+        limit: Some(100),
+    }));
+
+    // file1.txt grows by 7 bytes, and file2.txt is 12 bytes
+    let res = h
+        .service
+        .clone()
+        .operate(Operation::RestoreRemote(PathBuf::from("/dir")))
+        .await;
+    assert!(matches!(
+        res,
+        Err(fsync::Error::QuotaExceeded {
+            remaining: 15,
+            required: 19
+        })
+    ));
+    assert!(!h.has_remote_file("/dir/file2.txt").await);
+
+    h.operate(Operation::RestoreRemote(PathBuf::from("/dir/file1.txt")))
+        .await;
+    assert!(
+        h.has_remote_file_with_content("/dir/file1.txt", "Local content, longer")
+            .await
+    );
+}
+
+#[tokio::test]
+async fn sync_deep_quota_unavailable() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![Entry::txt_file("/dir/file1.txt", "Test content")],
+            remote: vec![Entry::Dir("/dir".into())],
+        })
+        .await
+    };
+    h.remote().storage().set_quota_fails(true);
+
+    h.operate(Operation::SyncDeep(PathBuf::from("/dir"))).await;
+    assert!(h.has_remote_file("/dir/file1.txt").await);
+}
+
+#[tokio::test]
+async fn read_head_local_and_remote() {
+    let path = Path::new("/file.txt");
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![Entry::txt_file(path, "Local content")],
+            remote: vec![Entry::txt_file(path, "Remote content")],
+        })
+        .await
+    };
+
+    let local = h
+        .service
+        .read_head(path, StorageLoc::Local, 100)
+        .await
+        .unwrap();
+    assert_eq!(local, b"Local content");
+
+    let remote = h
+        .service
+        .read_head(path, StorageLoc::Remote, 6)
+        .await
+        .unwrap();
+    assert_eq!(remote, b"Remote");
+
+    let res = h
+        .service
+        .read_head(Path::new("/not-exists"), StorageLoc::Local, 100)
+        .await;
+    assert!(res.is_err());
+}
+
+#[tokio::test]
+async fn gc_tree_deleted_both_sides() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![
+                Entry::txt_file("/file.txt", "Test content"),
+                Entry::txt_file("/dir/file1.txt", "Local content"),
+                Entry::txt_file("/dir/file2.txt", "Test content"),
+            ],
+            remote: vec![
+                Entry::txt_file("/file.txt", "Test content"),
+                Entry::txt_file("/dir/file1.txt", "Remote content"),
+                Entry::txt_file("/dir/file2.txt", "Test content"),
+            ],
+        })
+        .await
+    };
+    assert!(h
+        .entry_node("/dir/file1.txt")
+        .await
+        .unwrap()
+        .entry()
+        .is_conflict());
+
+    for root in [h.local().root(), h.remote().storage().root()] {
+        std::fs::remove_dir_all(root.join("dir")).unwrap();
+    }
+
+    let removed = h.service.gc_tree().await.unwrap();
+    assert_eq!(removed, vec![PathBuf::from("/dir")]);
+    // the remote folders are listed, rather than each entry checked
+    assert_eq!(h.remote().storage().exists_calls(), 0);
+    assert!(h.entry_node("/dir").await.is_none());
+    assert!(h.entry_node("/dir/file1.txt").await.is_none());
+    assert!(h.service.conflicts(None, 10).await.unwrap().is_empty());
+    let root = h.entry_node("/").await.unwrap();
+    assert_eq!(root.children(), &["file.txt".to_string()]);
+    assert_eq!(root.stats().local.files, 1);
+    assert_eq!(root.stats().remote.files, 1);
+
+    let removed = h.service.gc_tree().await.unwrap();
+    assert!(removed.is_empty());
+}
+
+#[tokio::test]
+async fn rescan_local_changes() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![
+                Entry::txt_file("/dir/file1.txt", "Test content"),
+                Entry::txt_file("/dir/file2.txt", "Test content"),
+                Entry::txt_file("/dir/sub/file3.txt", "Test content"),
+            ],
+            remote: vec![
+                Entry::txt_file("/dir/file1.txt", "Test content"),
+                Entry::txt_file("/dir/file2.txt", "Test content"),
+            ],
+        })
+        .await
+    };
+    assert!(h.service.conflicts(None, 10).await.unwrap().is_empty());
+
+    let dir = h.local().root().join("dir");
+    std::fs::write(dir.join("file1.txt"), "Modified content").unwrap();
+    std::fs::remove_file(dir.join("file2.txt")).unwrap();
+    std::fs::write(dir.join("new.txt"), "New content").unwrap();
+    std::fs::write(dir.join("sub").join("file4.txt"), "New content").unwrap();
+
+    let report = h.service.rescan(Path::new("/dir"), false).await.unwrap();
+    assert_eq!(report.added, vec![PathBuf::from("/dir/new.txt")]);
+    assert_eq!(report.removed, vec![PathBuf::from("/dir/file2.txt")]);
+    assert_eq!(report.modified, vec![PathBuf::from("/dir/file1.txt")]);
+    assert!(h.entry_node("/dir/sub/file4.txt").await.is_none());
+
+    assert!(h
+        .entry_node("/dir/file1.txt")
+        .await
+        .unwrap()
+        .entry()
+        .is_conflict());
+    let conflicts = h.service.conflicts(None, 10).await.unwrap();
+    assert_eq!(conflicts.len(), 1);
+    assert!(h
+        .entry_node("/dir/file2.txt")
+        .await
+        .unwrap()
+        .entry()
+        .is_remote_only());
+    assert!(h
+        .entry_node("/dir/new.txt")
+        .await
+        .unwrap()
+        .entry()
+        .is_local_only());
+    let root = h.entry_node("/").await.unwrap();
+    assert_eq!(root.stats().local.files, 3);
+    assert_eq!(root.stats().remote.files, 2);
+
+    let report = h.service.rescan(Path::new("/dir"), true).await.unwrap();
+    assert_eq!(report.added, vec![PathBuf::from("/dir/sub/file4.txt")]);
+    assert!(report.removed.is_empty());
+    assert!(report.modified.is_empty());
+    assert_eq!(h.entry_node("/").await.unwrap().stats().local.files, 4);
+
+    let report = h.service.rescan(Path::root(), true).await.unwrap();
+    assert!(report.is_empty());
+
+    let res = h.service.rescan(Path::new("/dir/file1.txt"), false).await;
+    assert!(res.is_err());
+}
+
+#[tokio::test]
+async fn sync_vanished_entry() {
+    let path = Path::new("/file.txt");
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![Entry::txt_file(path, "Local content")],
+            remote: vec![],
+        })
+        .await
+    };
+
+    std::fs::remove_file(h.local().root().join("file.txt")).unwrap();
+
+    let progress = h.operate(Operation::Sync(path.to_owned())).await;
+    assert!(progress.is_done());
+    assert!(h.entry_node(path).await.is_none());
+}
+
+#[tokio::test]
+async fn plan_sync_deep() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![
+                Entry::txt_file("/dir/local.txt", "Local content"),
+                Entry::txt_file("/both.txt", "Test content"),
+            ],
+            remote: vec![
+                Entry::txt_file("/both.txt", "Test content"),
+                Entry::txt_file("/remote.txt", "Remote content"),
+            ],
+        })
+        .await
+    };
+
+    let plan = h
+        .service
+        .plan(Operation::SyncDeep(PathBuf::root()))
+        .await
+        .unwrap();
+    let mut actions = Vec::new();
+    loop {
+        let page = h.service.plan_next(plan, 2).await.unwrap();
+        if page.is_empty() {
+            break;
+        }
+        actions.extend(page);
+    }
+    let actions: Vec<_> = actions
+        .iter()
+        .map(|a| (a.path.as_str(), &a.action, a.size))
+        .collect();
+    assert!(matches!(
+        actions[..],
+        [
+            ("/dir", Action::Mkdir(StorageLoc::Remote), 0),
+            (
+                "/dir/local.txt",
+                Action::Copy(StorageDir::LocalToRemote),
+                13
+            ),
+            ("/remote.txt", Action::Copy(StorageDir::RemoteToLocal), 14),
+        ]
+    ));
+
+    // the plan is released when complete
+    assert!(h.service.plan_next(plan, 2).await.is_err());
+    // planning does not operate
+    assert!(h.entry_node("/remote.txt").await.unwrap().is_remote_only());
+}
+
+#[tokio::test]
+async fn ignore_files() {
+    let h = {
+        use dataset::Entry;
+        harness_with_ignore(
+            Dataset {
+                local: vec![
+                    Entry::txt_file("/.fsyncignore", "*.bak\nbuild/\n"),
+                    Entry::txt_file("/file.bak", "Backup"),
+                    Entry::txt_file("/build/out.txt", "Output"),
+                    Entry::txt_file("/dir/.fsyncignore", "!important.bak\n"),
+                    Entry::txt_file("/dir/important.bak", "Important"),
+                    Entry::txt_file("/dir/sub/important.bak", "Important"),
+                    Entry::txt_file("/dir/sub/other.bak", "Other"),
+                ],
+                remote: vec![
+                    Entry::txt_file("/remote.bak", "Backup"),
+                    Entry::txt_file("/dir/remote.bak", "Backup"),
+                    Entry::txt_file("/other/.fsyncignore", "*.txt\n"),
+                    Entry::txt_file("/other/file.txt", "Text"),
+                ],
+            },
+            &[],
+        )
+        .await
+    };
+
+    // ignore files themselves are synchronized as normal files
+    assert!(h.entry_node("/.fsyncignore").await.unwrap().is_local_only());
+    assert!(h
+        .entry_node("/dir/.fsyncignore")
+        .await
+        .unwrap()
+        .is_local_only());
+    assert!(h
+        .entry_node("/other/.fsyncignore")
+        .await
+        .unwrap()
+        .is_remote_only());
+
+    // rules apply to both sides and cascade down the tree
+    assert!(h.entry_node("/file.bak").await.is_none());
+    assert!(h.entry_node("/remote.bak").await.is_none());
+    assert!(h.entry_node("/build").await.is_none());
+    assert!(h.entry_node("/build/out.txt").await.is_none());
+    assert!(h.entry_node("/dir/remote.bak").await.is_none());
+    assert!(h.entry_node("/dir/sub/other.bak").await.is_none());
+
+    // negated patterns re-include entries in their directory and below
+    assert!(h.entry_node("/dir/important.bak").await.is_some());
+    assert!(h.entry_node("/dir/sub/important.bak").await.is_some());
+
+    // ignore files of remote-only directories are read from the remote
+    assert!(h.entry_node("/other/file.txt").await.is_none());
+
+    let root = h.entry_node("/").await.unwrap();
+    assert_eq!(root.children(), &[".fsyncignore", "dir", "other"]);
+}
+
+#[tokio::test]
+async fn ignore_files_precedence_over_config() {
+    let h = {
+        use dataset::Entry;
+        harness_with_ignore(
+            Dataset {
+                local: vec![
+                    Entry::txt_file("/debug.log", "Log"),
+                    Entry::txt_file("/keep.log", "Log"),
+                    Entry::txt_file("/dir/.fsyncignore", "!*.log\n"),
+                    Entry::txt_file("/dir/debug.log", "Log"),
+                    Entry::txt_file("/cache/data", "Data"),
+                ],
+                remote: vec![
+                    Entry::txt_file("/remote.log", "Log"),
+                    Entry::txt_file("/dir/remote.log", "Log"),
+                ],
+            },
+            &["*.log", "!keep.log", "/cache/"],
+        )
+        .await
+    };
+
+    // the global patterns apply, last match wins
+    assert!(h.entry_node("/debug.log").await.is_none());
+    assert!(h.entry_node("/remote.log").await.is_none());
+    assert!(h.entry_node("/keep.log").await.is_some());
+    assert!(h.entry_node("/cache").await.is_none());
+
+    // ignore files override the global patterns
+    assert!(h.entry_node("/dir/debug.log").await.is_some());
+    assert!(h.entry_node("/dir/remote.log").await.is_some());
+}
+
+#[tokio::test]
+async fn deep_operation_continues_after_permanent_failure() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![
+                Entry::txt_file("/a.txt", "A content"),
+                Entry::txt_file("/b.txt", "B content"),
+                Entry::txt_file("/c.txt", "C content"),
+            ],
+            remote: vec![],
+        })
+        .await
+    };
+    // replaced outside of fsyncd, the upload can't read it
+    let b_path = h.local().root().join("b.txt");
+    std::fs::remove_file(&b_path).unwrap();
+    std::fs::create_dir(&b_path).unwrap();
+
+    let progress = h
+        .service
+        .clone()
+        .operate(Operation::SyncDeep(PathBuf::root()))
+        .await
+        .unwrap();
+    assert_eq!(progress.report().unwrap().failed, 1);
+    // the self-check sees the replacement that the tree missed
+    let discrepancies = h.service.take_discrepancies().await;
+    assert!(discrepancies
+        .iter()
+        .all(|d| d.path == Path::new("/b.txt") && d.field == "local"));
+    assert!(!discrepancies.is_empty());
+    assert!(h.has_remote_file("/a.txt").await);
+    assert!(h.has_remote_file("/c.txt").await);
+    assert!(!h.has_remote_file("/b.txt").await);
+
+    let failed = h.service.progress(Path::new("/b.txt")).await.unwrap();
+    let Some(Progress::Failed(failure)) = failed else {
+        panic!("unexpected progress: {failed:?}");
+    };
+    assert!(!failure.transient);
+    assert_eq!(failure.attempts, 1);
+}
+
+#[tokio::test]
+async fn too_large_skipped_by_sync() {
+    let h = {
+        use dataset::Entry;
+        harness_with(
+            Dataset {
+                local: vec![
+                    // 12 bytes, at the limit
+                    Entry::txt_file("/dir/at-limit.txt", "Test content"),
+                    Entry::txt_file("/dir/above-limit.txt", "Test content!"),
+                ],
+                remote: vec![Entry::txt_file("/remote.txt", "Remote content")],
+            },
+            BuildOptions {
+                max_file_size: Some(12),
+                ..BuildOptions::default()
+            },
+        )
+        .await
+    };
+
+    assert!(!h
+        .entry_node("/dir/at-limit.txt")
+        .await
+        .unwrap()
+        .is_too_large());
+    assert!(h
+        .entry_node("/dir/above-limit.txt")
+        .await
+        .unwrap()
+        .is_too_large());
+    assert!(h.entry_node("/remote.txt").await.unwrap().is_too_large());
+    assert_eq!(h.entry_node("/").await.unwrap().stats().node.too_large, 2);
+    let paths = ["/", "/dir/above-limit.txt", "/dir/at-limit.txt"].map(PathBuf::from);
+    let stats = h.service.too_large_stats(&paths).unwrap();
+    let counts: Vec<_> = stats.iter().map(|s| (s.here, s.count)).collect();
+    assert_eq!(counts, [(false, 2), (true, 1), (false, 0)]);
+
+    let too_large = h.service.too_large(None, 100).await.unwrap();
+    let paths: Vec<_> = too_large.iter().map(|e| e.path().as_str()).collect();
+    assert_eq!(paths, ["/dir/above-limit.txt", "/remote.txt"]);
+    let too_large = h
+        .service
+        .too_large(Some(Path::new("/dir/b")), 100)
+        .await
+        .unwrap();
+    assert_eq!(too_large.len(), 1);
+
+    let progress = h.operate(Operation::SyncDeep(PathBuf::root())).await;
+    assert!(matches!(
+        progress,
+        Progress::DoneWithReport(OperationReport {
+            skipped_too_large: 2,
+            skipped_pinned: 0,
+            failed: 0,
+            skipped_vanished: 0,
+            skipped_withheld: 0,
+            skipped_not_downloadable: 0,
+            remaining: 0,
+        })
+    ));
+    assert!(h.entry_node("/dir/at-limit.txt").await.unwrap().is_sync());
+    assert!(h
+        .entry_node("/dir/above-limit.txt")
+        .await
+        .unwrap()
+        .is_local_only());
+    assert!(h.entry_node("/remote.txt").await.unwrap().is_remote_only());
+
+    // a unit sync also skips
+    let progress = h.operate(Operation::Sync("/remote.txt".into())).await;
+    assert_eq!(progress.report().unwrap().skipped_too_large, 1);
+    assert!(h.entry_node("/remote.txt").await.unwrap().is_remote_only());
+}
+
+#[tokio::test]
+async fn name_clashes_with_local_fs() {
+    let caps = FsCaps {
+        case_sensitive: false,
+        max_name_len: 16,
+        ..FsCaps::default()
+    };
+    let h = {
+        use dataset::Entry;
+        harness_with(
+            Dataset {
+                local: vec![Entry::txt_file("/Notes.txt", "Test content")],
+                remote: vec![
+                    Entry::txt_file("/notes.txt", "Test content"),
+                    Entry::txt_file("/dir/Readme.txt", "Test content"),
+                    Entry::txt_file("/dir/README.txt", "Test content"),
+                    Entry::txt_file("/dir/a-very-long-name.txt", "Test content"),
+                    Entry::txt_file("/dir/other.txt", "Test content"),
+                ],
+            },
+            BuildOptions {
+                fs_caps: Some(caps),
+                ..BuildOptions::default()
+            },
+        )
+        .await
+    };
+
+    for path in [
+        "/notes.txt",
+        "/dir/Readme.txt",
+        "/dir/README.txt",
+        "/dir/a-very-long-name.txt",
+    ] {
+        assert!(h.entry_node(path).await.unwrap().is_name_clash(), "{path}");
+    }
+    assert!(!h.entry_node("/Notes.txt").await.unwrap().is_name_clash());
+    assert!(!h
+        .entry_node("/dir/other.txt")
+        .await
+        .unwrap()
+        .is_name_clash());
+    let paths = ["/notes.txt", "/Notes.txt", "/missing.txt"].map(PathBuf::from);
+    assert_eq!(
+        h.service.name_clashes(&paths).unwrap(),
+        [true, false, false]
+    );
+
+    let res = h
+        .service
+        .clone()
+        .operate(Operation::Sync("/dir/README.txt".into()))
+        .await;
+    assert!(res.is_err());
+    assert!(h
+        .entry_node("/dir/README.txt")
+        .await
+        .unwrap()
+        .is_remote_only());
+
+    let progress = h.operate(Operation::Sync("/dir/other.txt".into())).await;
+    assert!(matches!(progress, Progress::Done));
+}
+
+#[tokio::test]
+async fn too_large_forced() {
+    let h = {
+        use dataset::Entry;
+        harness_with(
+            Dataset {
+                local: vec![Entry::txt_file("/dir/above-limit.txt", "Test content!")],
+                remote: vec![],
+            },
+            BuildOptions {
+                max_file_size: Some(12),
+                ..BuildOptions::default()
+            },
+        )
+        .await
+    };
+
+    let path = PathBuf::from("/dir/above-limit.txt");
+    let force = OperateOptions {
+        force_large: true,
+        ..Default::default()
+    };
+    let progress = h.operate_with(Operation::Sync(path.clone()), force).await;
+    assert!(matches!(progress, Progress::Done));
+
+    let node = h.entry_node(&path).await.unwrap();
+    assert!(node.is_sync());
+    assert!(!node.is_too_large());
+    assert_eq!(h.entry_node("/").await.unwrap().stats().node.too_large, 0);
+    assert!(h.service.too_large(None, 100).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn implausible_metadata_reported_apart() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![
+                // modified before 1980
+                Entry::txt_file("/dir/old.txt", "Old content").with_age(50 * 365 * 24 * 3600),
+                Entry::txt_file("/dir/new.txt", "New content"),
+            ],
+            remote: vec![],
+        })
+        .await
+    };
+
+    let paths = ["/", "/dir/old.txt", "/dir/new.txt", "/missing.txt"].map(PathBuf::from);
+    let stats = h.service.implausible_stats(&paths).unwrap();
+    let flagged = |here, count| stat::Flagged { here, count };
+    assert_eq!(
+        stats,
+        [
+            flagged(false, 1),
+            flagged(true, 1),
+            flagged(false, 0),
+            flagged(false, 0)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn pinned_entries_preserved() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![
+                Entry::txt_file("/conflict.txt", "Older test content").with_age(10),
+                Entry::txt_file("/dir/keep.txt", "Test content"),
+                Entry::txt_file("/dir/other.txt", "Test content"),
+            ],
+            remote: vec![
+                Entry::txt_file("/conflict.txt", "Newer test content").with_age(0),
+                Entry::txt_file("/dir/keep.txt", "Test content"),
+                Entry::txt_file("/dir/other.txt", "Test content"),
+            ],
+        })
+        .await
+    };
+
+    for path in ["/conflict.txt", "/dir/keep.txt"] {
+        h.service.set_pinned(Path::new(path), true).await.unwrap();
+    }
+    assert_eq!(
+        h.service.pinned().await,
+        [
+            PathBuf::from("/conflict.txt"),
+            PathBuf::from("/dir/keep.txt")
+        ]
+    );
+
+    // a unit operation fails before touching the storages
+    let res = h
+        .service
+        .clone()
+        .operate(Operation::Resolve(
+            "/conflict.txt".into(),
+            ResolutionMethod::ReplaceOlderByNewer,
+        ))
+        .await;
+    assert!(matches!(res, Err(fsync::Error::Pinned(..))));
+    assert!(
+        h.has_local_file_with_content("/conflict.txt", "Older test content")
+            .await
+    );
+
+    // a deep operation skips the pinned entry and the folders containing it
+    let progress = h
+        .operate(Operation::DeleteDeep("/dir".into(), DeletionMethod::Local))
+        .await;
+    assert_eq!(progress.report().unwrap().skipped_pinned, 2);
+    assert!(h.has_local_file("/dir/keep.txt").await);
+    assert!(!h.has_local_file("/dir/other.txt").await);
+    assert!(h.has_remote_file("/dir/other.txt").await);
+
+    h.service
+        .set_pinned(Path::new("/conflict.txt"), false)
+        .await
+        .unwrap();
+    h.operate(Operation::Resolve(
+        "/conflict.txt".into(),
+        ResolutionMethod::ReplaceOlderByNewer,
+    ))
+    .await;
+    assert!(
+        h.has_local_file_with_content("/conflict.txt", "Newer test content")
+            .await
+    );
+}
+
+#[tokio::test]
+async fn root_entry_of_empty_share() {
+    let h = harness(Dataset::empty()).await;
+    let root = h.entry_node("/").await.unwrap();
+    assert!(root.is_sync());
+    assert!(root.entry().is_safe_dir());
+    assert!(root.children().is_empty());
+
+    let progress = h
+        .operate(Operation::DeleteDeep(PathBuf::root(), DeletionMethod::All))
+        .await;
+    assert!(matches!(progress, Progress::Done));
+    assert!(h.entry_node("/").await.unwrap().is_sync());
+}
+
+#[tokio::test]
+async fn sync_deep_root() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![Entry::txt_file("/dir/local.txt", "Local content")],
+            remote: vec![Entry::txt_file("/remote.txt", "Remote content")],
+        })
+        .await
+    };
+    let progress = h.operate(Operation::SyncDeep(PathBuf::root())).await;
+    assert!(matches!(progress, Progress::Done));
+    assert!(h.entry_node("/dir/local.txt").await.unwrap().is_sync());
+    assert!(h.entry_node("/remote.txt").await.unwrap().is_sync());
+    assert!(h.entry_node("/").await.unwrap().is_sync());
+
+    // a unit operation on the root does nothing
+    let progress = h.operate(Operation::Sync(PathBuf::root())).await;
+    assert!(matches!(progress, Progress::Done));
+}
+
+#[tokio::test]
+async fn resolve_deep_root() {
+    let path = Path::new("/conflict.txt");
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![Entry::txt_file(path, "Older test content").with_age(10)],
+            remote: vec![Entry::txt_file(path, "Newer test content").with_age(0)],
+        })
+        .await
+    };
+    let progress = h
+        .operate(Operation::ResolveDeep(
+            PathBuf::root(),
+            ResolutionMethod::ReplaceOlderByNewer,
+        ))
+        .await;
+    assert!(matches!(progress, Progress::Done));
+    assert!(
+        h.has_local_file_with_content(path, "Newer test content")
+            .await
+    );
+    let root = h.entry_node("/").await.unwrap();
+    assert!(root.is_sync());
+    assert_eq!(root.stats().node.conflicts, 0);
+}
+
+#[tokio::test]
+async fn delete_deep_root() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![
+                Entry::txt_file("/dir/file.txt", "Test content"),
+                Entry::txt_file("/file.txt", "Test content"),
+            ],
+            remote: vec![
+                Entry::txt_file("/dir/file.txt", "Test content"),
+                Entry::txt_file("/file.txt", "Test content"),
+            ],
+        })
+        .await
+    };
+    let progress = h
+        .operate(Operation::DeleteDeep(PathBuf::root(), DeletionMethod::All))
+        .await;
+    assert!(matches!(progress, Progress::Done));
+    assert!(!h.has_local_file("/file.txt").await);
+    assert!(!h.has_remote_file("/dir/file.txt").await);
+    assert!(h.has_local_dir("/").await);
+    assert!(h.has_remote_dir("/").await);
+
+    let root = h.entry_node("/").await.unwrap();
+    assert!(root.is_sync());
+    assert!(root.children().is_empty());
+}
+
+#[tokio::test]
+async fn lazy_remote_serves_cached_tree() {
+    use crate::{stubs, utils};
+    use dataset::Entry;
+    use fsyncd::{
+        service::Service,
+        storage::{
+            cache::{CachePersist, CacheStorage},
+            lazy::Lazy,
+        },
+        PersistCache,
+    };
+
+    let root = utils::temp_path(Some("fsync-fs"), None);
+    tokio::fs::create_dir(&root).await.unwrap();
+    let local = vec![
+        Entry::txt_file("/file.txt", "Test content"),
+        Entry::txt_file("/local.txt", "Local content"),
+    ];
+    let remote = vec![Entry::txt_file("/file.txt", "Test content")];
+    let local = stubs::fs::Stub::new(&root.join("local"), &local, None)
+        .await
+        .unwrap();
+
+    // persist the cache of the remote storage, as the daemon does at shutdown
+    let persist = |ignore_initial_cache| CachePersist::MemoryAndDisk {
+        path: root.join("remote.cache"),
+        ignore_initial_cache,
+    };
+    {
+        let remote = stubs::id::Stub::new(&root.join("remote"), &remote, None)
+            .await
+            .unwrap();
+        let cache = CacheStorage::new(remote, persist(true)).await.unwrap();
+        cache.persist_cache().await.unwrap();
+    }
+
+    // restart with a remote storage that never gets ready, as with a cold network
+    let start = std::time::Instant::now();
+    let remote: Lazy<stubs::id::Stub> = Lazy::new(futures::future::pending);
+    let cache = CacheStorage::new(remote.clone(), persist(false))
+        .await
+        .unwrap();
+    let service = Service::new_with(local, cache, root.clone(), BuildOptions::default())
+        .await
+        .unwrap()
+        .with_remote_phase(remote.phase());
+    let tree = service.entry_node(Path::new("/")).await.unwrap().unwrap();
+    let elapsed = start.elapsed();
+
+    log::info!("tree served {elapsed:?} after start");
+    assert!(elapsed < std::time::Duration::from_secs(1));
+    assert_eq!(tree.children().len(), 2);
+    let file = service.entry_node(Path::new("/file.txt")).await.unwrap();
+    assert!(file.unwrap().is_sync());
+    assert_eq!(service.status().remote, fsync::RemotePhase::Initializing);
+
+    // the operation waits to be retried
+    let res = std::sync::Arc::new(service)
+        .operate(Operation::Sync(PathBuf::from("/local.txt")))
+        .await;
+    assert!(matches!(res, Ok(Progress::Waiting(..))), "{res:?}");
+
+    std::fs::remove_file(root.join("remote.cache")).unwrap();
+}
+
+#[tokio::test]
+async fn sharing_is_persisted_apart_from_the_cache() {
+    use crate::{stubs, utils};
+    use dataset::Entry;
+    use fsyncd::{
+        service::Service,
+        storage::{
+            cache::{CachePersist, CacheStorage},
+            lazy::Lazy,
+            Shared,
+        },
+        PersistCache,
+    };
+
+    let root = utils::temp_path(Some("fsync-fs"), None);
+    tokio::fs::create_dir(&root).await.unwrap();
+    let entries = vec![
+        Entry::txt_file("/public.txt", "Public content"),
+        Entry::txt_file("/private.txt", "Private content"),
+    ];
+    let public = fsync::Sharing {
+        anyone: Some(fsync::SharingRole::Reader),
+        ..Default::default()
+    };
+    let persist = |ignore_initial_cache| CachePersist::MemoryAndDisk {
+        path: root.join("remote.cache"),
+        ignore_initial_cache,
+    };
+    {
+        let remote = stubs::id::Stub::new(&root.join("remote"), &entries, None)
+            .await
+            .unwrap();
+        remote.set_sharing(Path::new("/public.txt"), Some(public));
+        let cache = CacheStorage::new(remote, persist(true)).await.unwrap();
+        assert_eq!(cache.sharing(Path::new("/public.txt")), Some(public));
+        assert_eq!(cache.sharing(Path::new("/private.txt")), None);
+        cache.persist_cache().await.unwrap();
+    }
+    assert!(root.join("remote.cache.sharing").exists());
+
+    // restart without the remote storage, the sharing is loaded with the cache
+    let remote: Lazy<stubs::id::Stub> = Lazy::new(futures::future::pending);
+    let cache = CacheStorage::new(remote, persist(false)).await.unwrap();
+    let local = stubs::fs::Stub::new(&root.join("local"), &entries, None)
+        .await
+        .unwrap();
+    let service = Service::new_with(local, cache, root.clone(), BuildOptions::default())
+        .await
+        .unwrap();
+    let shared = service.publicly_shared(Path::root(), 10).await.unwrap();
+    assert_eq!(shared.len(), 1);
+    assert_eq!(shared[0].path(), Path::new("/public.txt"));
+    let sharing = service
+        .sharing(&[PathBuf::from("/public.txt"), PathBuf::from("/private.txt")])
+        .unwrap();
+    assert_eq!(sharing, [Some(public), None]);
+}
+
+#[tokio::test]
+async fn remote_sizes_are_aggregated_after_loading_the_cache() {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{stubs, utils};
+    use dataset::Entry;
+    use fsyncd::{
+        aggregate::Aggregator,
+        service::Service,
+        storage::cache::{CachePersist, CacheStorage},
+        PersistCache,
+    };
+
+    let root = utils::temp_path(Some("fsync-fs"), None);
+    tokio::fs::create_dir(&root).await.unwrap();
+    let persist = |ignore_initial_cache| CachePersist::MemoryAndDisk {
+        path: root.join("remote.cache"),
+        ignore_initial_cache,
+    };
+    {
+        let entries = vec![
+            Entry::txt_file("/dir/a.txt", "Content A"),
+            Entry::txt_file("/dir/sub/b.txt", "Content B"),
+        ];
+        let remote = stubs::id::Stub::new(&root.join("remote"), &entries, None)
+            .await
+            .unwrap();
+        let cache = CacheStorage::new(remote, persist(true)).await.unwrap();
+        cache.persist_cache().await.unwrap();
+    }
+
+    // changed by another client while the daemon was stopped
+    let entries = vec![
+        Entry::txt_file("/dir/sub/b.txt", "Content B"),
+        Entry::txt_file("/dir/sub/c.txt", "Content C"),
+    ];
+    let remote = stubs::id::Stub::new(&root.join("remote"), &entries, None)
+        .await
+        .unwrap();
+    let cache = CacheStorage::new(remote, persist(false)).await.unwrap();
+    let local = stubs::fs::Stub::new(&root.join("local"), &[], None)
+        .await
+        .unwrap();
+    let service = Service::new_with(local, cache, root.clone(), BuildOptions::default())
+        .await
+        .unwrap()
+        .with_aggregation(Aggregator::new(Duration::ZERO));
+    let service = Arc::new(service);
+    let dir = PathBuf::from("/dir");
+    assert_eq!(
+        service.aggregation(std::slice::from_ref(&dir)).unwrap(),
+        [stat::Aggregation::Pending]
+    );
+    let stale = service.entry_node(&dir).await.unwrap().unwrap().stats();
+    assert_eq!(stale.remote.files, 2);
+
+    let task = tokio::spawn(service.clone().run_aggregation());
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while service.aggregation(&[PathBuf::root()]).unwrap() != [stat::Aggregation::Exact] {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the aggregation should complete");
+    task.abort();
+
+    let fresh = service.entry_node(&dir).await.unwrap().unwrap();
+    assert_eq!(fresh.stats().remote.files, 2);
+    assert_eq!(fresh.stats().remote.data, 9 * 2);
+    assert!(service
+        .entry_node(Path::new("/dir/a.txt"))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(service
+        .entry_node(Path::new("/dir/sub/c.txt"))
+        .await
+        .unwrap()
+        .is_some());
+    assert_eq!(
+        service
+            .aggregation(&[dir.clone(), PathBuf::from("/dir/sub")])
+            .unwrap(),
+        [stat::Aggregation::Exact, stat::Aggregation::Exact]
+    );
+
+    std::fs::remove_file(root.join("remote.cache")).unwrap();
+    std::fs::remove_file(root.join("remote.cache.sharing")).unwrap();
+}
+
+#[tokio::test]
+async fn corrupt_remote_cache_is_set_aside() {
+    use crate::{stubs, utils};
+    use dataset::Entry;
+    use fsyncd::{
+        service::Service,
+        storage::cache::{CachePersist, CacheStorage},
+        PersistCache,
+    };
+
+    let root = utils::temp_path(Some("fsync-fs"), None);
+    tokio::fs::create_dir(&root).await.unwrap();
+    let entries = vec![Entry::txt_file("/file.txt", "Test content")];
+    let cache_path = root.join("remote.cache");
+    let persist = |ignore_initial_cache| CachePersist::MemoryAndDisk {
+        path: cache_path.clone(),
+        ignore_initial_cache,
+    };
+    let valid = {
+        let cache = CacheStorage::new(
+            stubs::id::Stub::new(&root.join("remote-valid"), &entries, None)
+                .await
+                .unwrap(),
+            persist(true),
+        )
+        .await
+        .unwrap();
+        cache.persist_cache().await.unwrap();
+        std::fs::remove_file(root.join("remote.cache.bak")).ok();
+        std::fs::read(&cache_path).unwrap()
+    };
+
+    let inputs = [
+        ("empty file", Vec::new()),
+        ("truncated", valid[..valid.len() / 2].to_vec()),
+        ("garbage", b"not a cache".to_vec()),
+    ];
+    for (name, data) in inputs {
+        std::fs::write(&cache_path, data).unwrap();
+
+        let remote = stubs::id::Stub::new(&root.join(format!("remote-{name}")), &entries, None)
+            .await
+            .unwrap();
+        let cache = CacheStorage::new(remote, persist(false)).await.unwrap();
+        let corrupt = cache.corrupt_file().cloned().expect(name);
+        assert_eq!(corrupt.kind, "remote cache");
+        assert!(!cache_path.exists(), "{name}");
+        assert!(std::path::Path::new(&corrupt.moved_to).exists(), "{name}");
+
+        // the daemon still serves the tree, and reports the corrupt file
+        let local = stubs::fs::Stub::new(&root.join(format!("local-{name}")), &entries, None)
+            .await
+            .unwrap();
+        let service = Service::new_with(local, cache, root.clone(), BuildOptions::default())
+            .await
+            .unwrap()
+            .with_corrupt_files(vec![corrupt.clone()]);
+        let file = service.entry_node(Path::new("/file.txt")).await.unwrap();
+        assert!(file.unwrap().is_sync(), "{name}");
+        assert_eq!(service.status().corrupt_files, [corrupt]);
+    }
+}
+
+fn placeholders_dataset() -> Dataset {
+    use dataset::Entry;
+    Dataset {
+        local: vec![Entry::txt_file("/file.txt", "Test content")],
+        remote: vec![
+            Entry::txt_file("/file.txt", "Test content"),
+            Entry::txt_file("/remote.txt", "Remote content"),
+            Entry::txt_file("/dir/nested.txt", "Nested content"),
+        ],
+    }
+}
+
+#[tokio::test]
+async fn placeholders_of_remote_only_files() {
+    let h = harness_with_placeholders(placeholders_dataset()).await;
+    let root = h.local().root();
+
+    let descriptor = std::fs::read_to_string(root.join("remote.txt.fsyncph")).unwrap();
+    assert!(descriptor.contains(r#""path": "/remote.txt""#));
+    assert!(descriptor.contains(r#""size": 14"#));
+    // only in the folders that exist locally
+    assert!(!root.join("dir").exists());
+    assert!(!root.join("file.txt.fsyncph").exists());
+
+    // hidden from the tree, even after a rescan
+    let report = h.service.rescan(Path::root(), true).await.unwrap();
+    assert!(report.is_empty());
+    assert!(h.entry_node("/remote.txt.fsyncph").await.is_none());
+    assert!(h.has_remote_file("/remote.txt").await);
+    assert!(!h.has_local_file("/remote.txt").await);
+}
+
+#[tokio::test]
+async fn hydrate_placeholder() {
+    let h = harness_with_placeholders(placeholders_dataset()).await;
+    let placeholder = h.local().root().join("remote.txt.fsyncph");
+
+    let progress = h
+        .operate(Operation::Hydrate(PathBuf::from("/remote.txt")))
+        .await;
+    assert!(matches!(progress, Progress::Done));
+    assert!(
+        h.has_local_file_with_content("/remote.txt", "Remote content")
+            .await
+    );
+    assert!(h.entry_node("/remote.txt").await.unwrap().is_sync());
+    assert!(!placeholder.exists());
+
+    let res = h
+        .service
+        .clone()
+        .operate(Operation::Hydrate(PathBuf::from("/dir")))
+        .await;
+    assert!(res.is_err());
+    assert!(!h.has_local_dir("/dir").await);
+}
+
+#[tokio::test]
+async fn deleted_placeholder_is_not_a_deletion() {
+    use fsyncd::storage::Exists;
+
+    let h = harness_with_placeholders(placeholders_dataset()).await;
+    let placeholder = h.local().root().join("remote.txt.fsyncph");
+
+    std::fs::remove_file(&placeholder).unwrap();
+    let report = h.service.rescan(Path::root(), true).await.unwrap();
+    assert!(report.is_empty());
+
+    // not created again, and the remote file is kept
+    assert!(!placeholder.exists());
+    assert!(h.has_remote_file("/remote.txt").await);
+    assert!(h.remote().exists(Path::new("/remote.txt")).await.unwrap());
+
+    // it can still be downloaded
+    let progress = h
+        .operate(Operation::Sync(PathBuf::from("/remote.txt")))
+        .await;
+    assert!(matches!(progress, Progress::Done));
+    assert!(
+        h.has_local_file_with_content("/remote.txt", "Remote content")
+            .await
+    );
+}
+
+#[tokio::test]
+async fn placeholder_superseded_by_local_file() {
+    let h = harness_with_placeholders(placeholders_dataset()).await;
+    let root = h.local().root();
+    let placeholder = root.join("remote.txt.fsyncph");
+
+    std::fs::write(root.join("remote.txt"), "Local content").unwrap();
+    let report = h.service.rescan(Path::root(), false).await.unwrap();
+    assert_eq!(report.added, vec![PathBuf::from("/remote.txt")]);
+    assert!(!placeholder.exists());
+
+    let node = h.entry_node("/remote.txt").await.unwrap();
+    assert!(node.entry().is_conflict());
+    let res = h
+        .service
+        .clone()
+        .operate(Operation::Hydrate(PathBuf::from("/remote.txt")))
+        .await;
+    assert!(matches!(res, Err(fsync::Error::Conflict(..))));
+    assert!(
+        h.has_local_file_with_content("/remote.txt", "Local content")
+            .await
+    );
+    assert!(
+        h.has_remote_file_with_content("/remote.txt", "Remote content")
+            .await
+    );
+}
+
+#[tokio::test]
+async fn restore_local_is_non_destructive() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![
+                Entry::txt_file("/dir/synced.txt", "Test content"),
+                Entry::txt_file("/dir/local.txt", "Local content"),
+                Entry::txt_file("/dir/conflict.txt", "Older content").with_age(10),
+                Entry::txt_file("/other.txt", "Test content").with_age(10),
+            ],
+            remote: vec![
+                Entry::txt_file("/dir/synced.txt", "Test content"),
+                Entry::txt_file("/dir/sub/remote.txt", "Remote content"),
+                Entry::txt_file("/dir/conflict.txt", "Newer content").with_age(0),
+                Entry::txt_file("/other.txt", "Other content").with_age(0),
+            ],
+        })
+        .await
+    };
+
+    let operation = Operation::RestoreLocal("/dir".into());
+    let plan = h.service.plan(operation.clone()).await.unwrap();
+    let actions = h.service.plan_next(plan, 100).await.unwrap();
+    let planned: Vec<_> = actions
+        .iter()
+        .map(|planned| planned.path.as_str())
+        .collect();
+    assert_eq!(
+        planned,
+        ["/dir/conflict.txt", "/dir/sub", "/dir/sub/remote.txt"]
+    );
+
+    let progress = h.operate(operation).await;
+    assert!(progress.is_done());
+
+    assert!(
+        h.has_local_file_with_content("/dir/conflict.txt", "Newer content")
+            .await
+    );
+    assert!(
+        h.has_local_file_with_content("/dir/sub/remote.txt", "Remote content")
+            .await
+    );
+    assert!(h.entry_node("/dir/synced.txt").await.unwrap().is_sync());
+    // never uploaded nor deleted
+    assert!(h
+        .entry_node("/dir/local.txt")
+        .await
+        .unwrap()
+        .is_local_only());
+    // outside of the restored sub-tree
+    assert!(h
+        .entry_node("/other.txt")
+        .await
+        .unwrap()
+        .entry()
+        .is_conflict());
+}
+
+/// Check that a service started again over the storages of `h`, with the cache
+/// of the remote populated again, sees the directories of `dirs` on both sides
+async fn assert_sync_dirs_after_restart(h: &crate::CacheHarness, dirs: &[&str]) {
+    use fsyncd::{
+        service::Service,
+        storage::cache::{CachePersist, CacheStorage},
+    };
+
+    let cache = CacheStorage::new(h.remote().storage().clone(), CachePersist::Memory)
+        .await
+        .unwrap();
+    let service = Service::new(h.local().clone(), cache, h.local().root().to_owned())
+        .await
+        .unwrap();
+    for dir in dirs {
+        let node = service.entry_node(Path::new(dir)).await.unwrap();
+        assert!(node.is_some_and(|node| node.is_sync()), "{dir}");
+    }
+    let discrepancies = service.self_check().await.unwrap();
+    assert!(discrepancies.is_empty(), "{discrepancies:?}");
+}
+
+#[tokio::test]
+async fn empty_dir_chains_sync_both_ways() {
+    let h = {
+        use dataset::Entry;
+        harness(Dataset {
+            local: vec![Entry::dir("/local/a/b/c")],
+            remote: vec![Entry::dir("/remote/x/y/z")],
+        })
+        .await
+    };
+    let local_chain = ["/local", "/local/a", "/local/a/b", "/local/a/b/c"];
+    let remote_chain = ["/remote", "/remote/x", "/remote/x/y", "/remote/x/y/z"];
+    for dir in local_chain {
+        assert!(h.has_local_dir(dir).await, "{dir}");
+    }
+    for dir in remote_chain {
+        assert!(h.has_remote_dir(dir).await, "{dir}");
+    }
+
+    let progress = h.operate(Operation::SyncDeep(PathBuf::root())).await;
+    assert!(matches!(progress, Progress::Done), "{progress:?}");
+    for dir in local_chain.iter().chain(remote_chain.iter()) {
+        assert!(h.has_sync_dir_no_conflict(dir).await, "{dir}");
+    }
+    let root = h.entry_node("/").await.unwrap();
+    assert_eq!(root.stats().node.conflicts, 0);
+
+    // the empty folders are still there once the cache of the remote is populated again
+    let all: Vec<&str> = local_chain
+        .iter()
+        .chain(remote_chain.iter())
+        .copied()
+        .collect();
+    assert_sync_dirs_after_restart(&h, &all).await;
+
+    // syncing again does nothing
+    let progress = h.operate(Operation::SyncDeep(PathBuf::root())).await;
+    assert!(matches!(progress, Progress::Done), "{progress:?}");
+    assert_eq!(h.entry_node("/").await.unwrap().stats().node.conflicts, 0);
+}
+
+#[tokio::test]
+async fn delete_empty_dir_chains_keeps_the_parents() {
+    let h = {
+        use dataset::Entry;
+        let entries = vec![
+            Entry::dir("/both/a/b/c"),
+            Entry::dir("/local/x/y"),
+            Entry::dir("/remote/x/y"),
+            Entry::txt_file("/files/dir/last.txt", "Last content"),
+        ];
+        harness(Dataset {
+            local: entries.clone(),
+            remote: entries,
+        })
+        .await
+    };
+
+    // on both sides, deepest first
+    for dir in ["/both/a/b/c", "/both/a/b"] {
+        let progress = h
+            .operate(Operation::Delete(dir.into(), DeletionMethod::All))
+            .await;
+        assert!(progress.is_done(), "{dir}: {progress:?}");
+        assert!(h.entry_node(dir).await.is_none(), "{dir}");
+    }
+    assert!(h.has_sync_dir_no_conflict("/both/a").await);
+    assert!(h.entry_node("/both/a").await.unwrap().children().is_empty());
+    assert!(h.has_sync_dir_no_conflict("/both").await);
+
+    // on a single side, the chain is left on the other one
+    let progress = h
+        .operate(Operation::DeleteDeep(
+            "/local/x".into(),
+            DeletionMethod::Local,
+        ))
+        .await;
+    assert!(progress.is_done(), "{progress:?}");
+    assert!(!h.has_local_dir("/local/x").await);
+    assert!(h.has_remote_dir("/local/x/y").await);
+    assert!(h.has_sync_dir_no_conflict("/local").await);
+
+    let progress = h
+        .operate(Operation::DeleteDeep(
+            "/remote/x".into(),
+            DeletionMethod::Remote,
+        ))
+        .await;
+    assert!(progress.is_done(), "{progress:?}");
+    assert!(!h.has_remote_dir("/remote/x").await);
+    assert!(h.has_local_dir("/remote/x/y").await);
+    assert!(h.has_sync_dir_no_conflict("/remote").await);
+
+    // deleting the last child of a folder leaves the folder
+    let progress = h
+        .operate(Operation::Delete(
+            "/files/dir/last.txt".into(),
+            DeletionMethod::All,
+        ))
+        .await;
+    assert!(progress.is_done(), "{progress:?}");
+    assert!(h.has_sync_dir_no_conflict("/files/dir").await);
+
+    // and the chains are synchronized back
+    let progress = h.operate(Operation::SyncDeep(PathBuf::root())).await;
+    assert!(progress.is_done(), "{progress:?}");
+    for dir in ["/local/x/y", "/remote/x/y", "/files/dir", "/both/a"] {
+        assert!(h.has_sync_dir_no_conflict(dir).await, "{dir}");
+    }
+    assert_sync_dirs_after_restart(&h, &["/both/a", "/local/x/y", "/remote/x/y", "/files/dir"])
+        .await;
+}
@@ -51,6 +51,23 @@ pub fn temp_path(prefix: Option<&str>, ext: Option<&str>) -> FsPathBuf {
     p.try_into().unwrap()
 }
 
+/// Removes a temporary directory when dropped.
+/// Shared by the clones of a stub, so that the last one to be dropped removes the directory.
+#[derive(Debug)]
+pub struct TempDir(FsPathBuf);
+
+impl TempDir {
+    pub fn new(path: &FsPath) -> Self {
+        Self(path.to_owned())
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).unwrap();
+    }
+}
+
 pub fn _copy_dir_all<'a>(
     src: impl AsRef<FsPath>,
     dst: impl AsRef<FsPath>,
@@ -101,6 +118,7 @@ where
                     path: dst,
                     size: fs_metadata.len(),
                     mtime: fs_metadata.modified()?.into(),
+                    link_target: None,
                 };
                 let data = tokio::fs::File::open(&fs_src).await?;
                 storage.create_file(&metadata, data, None).await?;